@@ -2,18 +2,55 @@
 //!
 //! api for admin ui
 //!
+pub mod app_access_list_handler;
+pub mod app_answer_sinks_handler;
+pub mod app_api_key_usage_handler;
+pub mod app_artifacts_handler;
 pub mod app_delete_handler;
+pub mod app_encryption_key_handler;
+pub mod app_experiments_handler;
+pub mod app_generated_config_handler;
 pub mod app_get_handler;
 pub mod app_get_logs_handler;
+pub mod app_hints_handler;
+pub mod app_history_retention_handler;
+pub mod app_ingestion_control_handler;
+pub mod app_ingestion_retry_handler;
+pub mod app_ingestion_sla_handler;
+pub mod app_keys_handler;
+pub mod app_knowledge_node_detail_handler;
 pub mod app_knowledge_nodes_and_errors_count;
 pub mod app_knowledge_nodes_chart_handler;
 pub mod app_knowledge_nodes_errors_handler;
 pub mod app_knowledge_nodes_handler;
+pub mod app_knowledge_nodes_stats_handler;
 pub mod app_list_handler;
+pub mod app_prompt_templates_handler;
+pub mod app_residency_handler;
+pub mod app_retry_onboarding_handler;
 pub mod app_search_enabled_handler;
+pub mod app_shadow_traffic_handler;
+pub mod app_try_query_handler;
+pub mod app_user_pseudonyms_handler;
+pub mod app_verify_counts_handler;
 pub mod apps_and_calls_overview_handler;
+pub mod backfills_handler;
 pub mod capture_tc_handler;
+pub mod config_handler;
+pub mod dependencies_handler;
+pub mod error_catalog_handler;
+pub mod graph_query_handler;
+pub mod job_runs_handler;
 pub mod kub_generate_token_handler;
 pub mod metric_calls_handler;
 pub mod metric_error_handler;
+pub mod notifications_handler;
+pub mod onboarding_complexity_handler;
+pub mod overview_feed_handler;
+pub mod queued_jobs_handler;
 pub mod schema;
+pub mod scim_handler;
+pub mod selfcheck_handler;
+pub mod service_accounts_handler;
+pub mod token_usage_handler;
+pub mod trace_replay_handler;
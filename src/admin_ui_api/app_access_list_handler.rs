@@ -0,0 +1,233 @@
+/*
+ * Created Date:  Jul 01, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET and PUT handlers for the allowlist and denylist of the end users of an app.
+//! The handlers are mounted at `/api/v1.1/admin/apps/{app_name}/access-list`.
+//! The GET handler returns the access list stored in the app document, empty if not set.
+//! The PUT handler replaces the access list. Retrievals of rejected users are answered with a 403 status code.
+//! The handlers return a 200 status code if the access list is fetched/updated successfully.
+//! The handlers return a 400 status code if an entry of the access list is invalid.
+//! The handlers return a 404 status code if the app is not found.
+//! The handlers return a 500 status code if an error occurs while fetching/updating the access list.
+//!
+
+use crate::admin_ui_api::schema::UpdateResponse;
+use crate::service::ctx::Ctx;
+use crate::service::state::AppState;
+use crate::service::user_access::UserAccessList;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, to_bson};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// GET handler to get the allowlist and denylist of the end users of an app.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/access-list",
+    responses(
+        (status = 200, description = "Access list retrieved successfully.", body = UserAccessList),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_access_list_handler(
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let apps = app_state.apps();
+    if !apps.exists(&app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+    let access_list = apps.user_access_list(&app_name).await?.unwrap_or_default();
+    let success_message = format!("Access list of '{}' retrieved successfully.", app_name);
+    info!(app_name = app_name, message = success_message);
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "data": access_list}),
+    ))
+}
+
+/// PUT handler to replace the allowlist and denylist of the end users of an app.
+#[utoipa::path(
+    put,
+    path = "/api/v1.1/admin/apps/{app_name}/access-list",
+    request_body = UserAccessList,
+    responses(
+        (status = 200, description = "Access list updated successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn put_access_list_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(access_list): Json<UserAccessList>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    // Validate the entries of the access list
+    access_list.validate().map_err(|e| {
+        let error_message = e.to_string();
+        debug!(app_name = app_name, message = error_message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    })?;
+
+    let filter = doc! {"app_name": &app_name};
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let error_message = match to_bson(&access_list) {
+        Ok(access_list_bson) => match app_state
+            .db
+            .update_document(
+                collection_name,
+                filter,
+                doc! {"user_access_list": access_list_bson},
+            )
+            .await
+            .map_err(ErrorInterceptor::from)
+        {
+            Ok(json_result) => match serde_json::from_value::<UpdateResponse>(json_result) {
+                Ok(result) if result.matchedCount == 0 => {
+                    let error_message = format!("No app found with name '{}'.", app_name);
+                    debug!(message = error_message);
+                    return Err((
+                        StatusCode::NOT_FOUND,
+                        Json(json!({"status": "error", "message": error_message})),
+                    ));
+                }
+                Ok(_) => None,
+                Err(e) => Some(format!(
+                    "Failed to deserialize update response. Error: {:?}",
+                    e
+                )),
+            },
+            Err(e) => Some(format!(
+                "Failed to update access list of app '{}'. Error: {}",
+                app_name, e
+            )),
+        },
+        Err(e) => Some(format!(
+            "Failed to serialize access list to BSON. Error: {}",
+            e
+        )),
+    };
+    if let Some(error_message) = error_message {
+        let ext_message = ctx.ext_message(&app_state);
+        error!(
+            app_name = app_name,
+            task_id = ctx.task_id,
+            ext_message = ext_message,
+            message = error_message
+        );
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    let success_message = format!("Access list of '{}' updated successfully.", app_name);
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "Access list updated",
+        details = json!(access_list).to_string(),
+        message = success_message
+    );
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "app_name": app_name}),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_put_access_list_handler_invalid_entry() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+            let access_list = UserAccessList {
+                allowed: vec!["@".to_string()],
+                denied: vec![],
+            };
+
+            // Call the function
+            let result = put_access_list_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                State(app_state),
+                Json(access_list),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn test_failure_put_access_list_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let result = put_access_list_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                State(app_state),
+                Json(UserAccessList::default()),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+
+    #[test]
+    fn test_failure_get_access_list_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let result = get_access_list_handler(Path(app_name), State(app_state)).await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
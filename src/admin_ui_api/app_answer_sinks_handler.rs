@@ -0,0 +1,285 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the handlers for the answer sinks of an app, to which its history documents are mirrored.
+//! The handlers are mounted at `/api/v1.1/admin/apps/{app_name}/answer-sinks`.
+//! The GET handler returns the sinks stored in the app document, empty if not set.
+//! The PUT handler adds a sink, or replaces the sink with the same name.
+//! The DELETE handler of `/{sink_name}` removes a sink.
+//! The handlers return a 200 status code if the sinks are fetched/updated successfully.
+//! The handlers return a 400 status code if the sink is invalid, or the app has too many sinks.
+//! The handlers return a 404 status code if the app or the sink is not found.
+//! The handlers return a 500 status code if an error occurs while fetching/updating the sinks.
+//!
+
+use crate::admin_ui_api::schema::UpdateResponse;
+use crate::service::answer_sink::{
+    AnswerSink, AnswerSinkError, ANSWER_SINKS_FIELD, MAX_ANSWER_SINKS,
+};
+use crate::service::ctx::Ctx;
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, to_bson};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// GET handler to get the answer sinks of an app.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/answer-sinks",
+    responses(
+        (status = 200, description = "Answer sinks retrieved successfully.", body = [AnswerSink]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_answer_sinks_handler(
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let sinks = find_answer_sinks(&app_state, &app_name).await?;
+    let success_message = format!("Answer sinks of '{}' retrieved successfully.", app_name);
+    info!(app_name = app_name, message = success_message);
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "data": sinks}),
+    ))
+}
+
+/// PUT handler to add an answer sink to an app, or replace the sink with the same name.
+#[utoipa::path(
+    put,
+    path = "/api/v1.1/admin/apps/{app_name}/answer-sinks",
+    request_body = AnswerSink,
+    responses(
+        (status = 200, description = "Answer sink saved successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn put_answer_sink_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(sink): Json<AnswerSink>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    sink.validate()?;
+    let mut sinks = find_answer_sinks(&app_state, &app_name).await?;
+    match sinks.iter_mut().find(|stored| stored.name == sink.name) {
+        Some(stored) => *stored = sink.clone(),
+        None if sinks.len() >= MAX_ANSWER_SINKS => {
+            return Err(AnswerSinkError::TooManySinks(MAX_ANSWER_SINKS).into())
+        }
+        None => sinks.push(sink.clone()),
+    }
+    store_answer_sinks(&ctx, &app_state, &app_name, &sinks).await?;
+
+    let success_message = format!(
+        "Answer sink '{}' of '{}' saved successfully.",
+        sink.name, app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "Answer sink saved",
+        details = json!(sink).to_string(),
+        message = success_message
+    );
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "app_name": app_name}),
+    ))
+}
+
+/// DELETE handler to remove an answer sink of an app.
+#[utoipa::path(
+    delete,
+    path = "/api/v1.1/admin/apps/{app_name}/answer-sinks/{sink_name}",
+    responses(
+        (status = 200, description = "Answer sink deleted successfully."),
+        (status = StatusCode::NOT_FOUND, description = "App or answer sink not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn delete_answer_sink_handler(
+    ctx: Ctx,
+    Path((app_name, sink_name)): Path<(String, String)>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let mut sinks = find_answer_sinks(&app_state, &app_name).await?;
+    let count = sinks.len();
+    sinks.retain(|sink| sink.name != sink_name);
+    if sinks.len() == count {
+        return Err(AnswerSinkError::UnknownSink(sink_name).into());
+    }
+    store_answer_sinks(&ctx, &app_state, &app_name, &sinks).await?;
+
+    let success_message = format!(
+        "Answer sink '{}' of '{}' deleted successfully.",
+        sink_name, app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "Answer sink deleted",
+        details = sink_name,
+        message = success_message
+    );
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "app_name": app_name}),
+    ))
+}
+
+/// Returns the answer sinks of an app. Unknown apps are answered with a 404.
+async fn find_answer_sinks(
+    app_state: &AppState,
+    app_name: &str,
+) -> Result<Vec<AnswerSink>, (StatusCode, Json<serde_json::Value>)> {
+    let apps = app_state.apps();
+    if !apps.exists(app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        debug!(message = error_message);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+    Ok(apps.answer_sinks(app_name).await?)
+}
+
+/// Stores the answer sinks of an app on its app document.
+async fn store_answer_sinks(
+    ctx: &Ctx,
+    app_state: &AppState,
+    app_name: &str,
+    sinks: &[AnswerSink],
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let filter = doc! {"app_name": app_name};
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let error_message = match to_bson(sinks) {
+        Ok(sinks_bson) => match app_state
+            .db
+            .update_document(
+                collection_name,
+                filter,
+                doc! {ANSWER_SINKS_FIELD: sinks_bson},
+            )
+            .await
+            .map_err(ErrorInterceptor::from)
+        {
+            Ok(json_result) => match serde_json::from_value::<UpdateResponse>(json_result) {
+                Ok(result) if result.matchedCount == 0 => {
+                    let error_message = format!("No app found with name '{}'.", app_name);
+                    debug!(message = error_message);
+                    return Err((
+                        StatusCode::NOT_FOUND,
+                        Json(json!({"status": "error", "message": error_message})),
+                    ));
+                }
+                Ok(_) => None,
+                Err(e) => Some(format!(
+                    "Failed to deserialize update response. Error: {:?}",
+                    e
+                )),
+            },
+            Err(e) => Some(format!(
+                "Failed to update answer sinks of app '{}'. Error: {}",
+                app_name, e
+            )),
+        },
+        Err(e) => Some(format!(
+            "Failed to serialize answer sinks to BSON. Error: {}",
+            e
+        )),
+    };
+    if let Some(error_message) = error_message {
+        let ext_message = ctx.ext_message(app_state);
+        error!(
+            app_name = app_name,
+            task_id = ctx.task_id,
+            ext_message = ext_message,
+            message = error_message
+        );
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::answer_sink::{AnswerFilter, AnswerSinkTarget};
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_put_answer_sink_handler_invalid_sink() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+            let sink = AnswerSink {
+                name: "crm".to_string(),
+                target: AnswerSinkTarget::Sqs {
+                    queue_url: "https://crm.example.com/answers".to_string(),
+                },
+                filter: AnswerFilter::Successes,
+            };
+
+            // Call the function
+            let result = put_answer_sink_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                State(app_state),
+                Json(sink),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn test_failure_delete_answer_sink_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let result = delete_answer_sink_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path((app_name, "crm".to_string())),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
@@ -0,0 +1,114 @@
+/*
+ * Created Date:  Jul 18, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET handler for the API key usage of an app, the daily calls made with its API key in the
+//! internal API key mode, where the usage is tracked by the facade instead of the API Gateway usage plans.
+//! The handler is mounted at `/api/v1.1/admin/apps/{app_name}/api-key-usage`, with the optional `days` query
+//! parameter (30 by default, at most 366).
+//! The handler returns a 200 status code if the usage is fetched successfully.
+//! The handler returns a 400 status code if the API keys are issued by API Gateway or `days` is out of range.
+//! The handler returns a 404 status code if the app is not found.
+//! The handler returns a 500 status code if an error occurs while fetching the usage.
+//!
+
+use crate::admin_ui_api::schema::ApiKeyUsageParams;
+use crate::service::api_key::api_key_usage;
+use crate::service::state::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, info, instrument};
+
+/// Default number of days of the API key usage.
+const DEFAULT_USAGE_DAYS: u32 = 30;
+/// Maximum number of days of the API key usage.
+const MAX_USAGE_DAYS: u32 = 366;
+
+/// GET handler to fetch the daily API key usage of an app.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/api-key-usage",
+    params(
+        (
+            "days" = inline(Option<u32>),
+            Query,
+            description = "Number of days, today included. Defaults to 30.",
+        )
+    ),
+    responses(
+        (status = 200, description = "API key usage retrieved successfully.", body = [DailyApiKeyUsage]),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_api_key_usage_handler(
+    Path(app_name): Path<String>,
+    Query(params): Query<ApiKeyUsageParams>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let days = params.days.unwrap_or(DEFAULT_USAGE_DAYS);
+    if days == 0 || days > MAX_USAGE_DAYS {
+        let error_message = format!("Invalid days '{}'. Expected 1 to {}.", days, MAX_USAGE_DAYS);
+        debug!(message = error_message);
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+    if !app_state.apps().exists(&app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        debug!(message = error_message);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    let usage = api_key_usage(&app_state, &app_name, days).await?;
+    let total_calls: u64 = usage.iter().map(|day| day.calls).sum();
+    let success_message = format!("API key usage of '{}' retrieved successfully.", app_name);
+    info!(app_name = app_name, message = success_message);
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "app_name": app_name,
+        "days": days,
+        "total_calls": total_calls,
+        "usage": usage
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_get_api_key_usage_handler_invalid_days() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+
+            // Call the function
+            let params = ApiKeyUsageParams { days: Some(0) };
+            let result =
+                get_api_key_usage_handler(Path(app_name), Query(params), State(app_state)).await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+}
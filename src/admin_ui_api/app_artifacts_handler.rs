@@ -0,0 +1,253 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the handlers of the S3 artifacts of an app: the objects generated for it, such as the full
+//! responses of the oversized answers, its logs, audit and metric exports, and its parsed files.
+//! The handlers are mounted at `/api/v1.1/admin/apps/{app_name}/artifacts`.
+//! The GET handler lists a page of the objects of every artifact location of the app, with presigned download links,
+//! or of the location of the `category` query parameter, continued with the `cursor` query parameter.
+//! The DELETE handler deletes the object of the `key` query parameter under the location of the `category` query
+//! parameter. Every deletion is sent to the audit microservice.
+//! The handlers return a 200 status code if the artifacts are listed/deleted successfully.
+//! The handlers return a 400 status code if the category is unknown or the key is not an artifact of the app.
+//! The handlers return a 404 status code if the app is not found.
+//! The handlers return a 500 status code if an error occurs while listing/deleting the artifacts.
+//!
+
+use crate::admin_ui_api::schema::ArtifactParams;
+use crate::service::answer_offload::AnswerOffloadOptions;
+use crate::service::artifact::{
+    artifact_location, artifact_locations, Artifact, ArtifactError, ArtifactLocation,
+    ArtifactOptions,
+};
+use crate::service::ctx::Ctx;
+use crate::service::object_store::object_store;
+use crate::service::state::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// Returns the artifact locations of an app.
+async fn app_artifact_locations(
+    app_state: &AppState,
+    app_name: &str,
+) -> Result<Vec<ArtifactLocation>, (StatusCode, Json<serde_json::Value>)> {
+    let generated_config = app_state.apps().generated_config(app_name).await?;
+    Ok(artifact_locations(
+        app_name,
+        &generated_config,
+        &app_state.options::<AnswerOffloadOptions>(),
+    ))
+}
+
+/// Lists a page of the objects of an artifact location, with their download links.
+async fn list_artifacts(
+    app_state: &AppState,
+    location: &ArtifactLocation,
+    cursor: Option<String>,
+) -> Result<(Vec<Artifact>, Option<String>), ArtifactError> {
+    let store_error = |message: String| ArtifactError::Store {
+        bucket: location.bucket.clone(),
+        message,
+    };
+    let store = object_store(app_state)
+        .await
+        .connect(&location.bucket)
+        .await
+        .map_err(store_error)?;
+    let page = store
+        .list_objects(&location.bucket, &location.prefix, cursor)
+        .await
+        .map_err(store_error)?;
+    let download_url_ttl = app_state.options::<ArtifactOptions>().download_url_ttl;
+    let mut artifacts = Vec::with_capacity(page.objects.len());
+    for (key, size_bytes) in page.objects {
+        let download_url = match store
+            .presign_object(&location.bucket, &key, download_url_ttl)
+            .await
+        {
+            Ok(download_url) => Some(download_url),
+            Err(e) => {
+                error!(
+                    message = format!(
+                        "Failed to sign the download link of '{}'. Error: {}",
+                        key, e
+                    )
+                );
+                None
+            }
+        };
+        artifacts.push(Artifact {
+            key,
+            size_bytes,
+            download_url,
+        });
+    }
+    Ok((artifacts, page.next_continuation_token))
+}
+
+/// GET handler to list the artifacts of an app.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/artifacts",
+    params(
+        ("category" = inline(Option<String>), Query, description = "artifacts, knowledge_extraction, parsed_files, logs, audit or metrics. All the categories if unset."),
+        ("cursor" = inline(Option<String>), Query, description = "next_cursor of the category, to list its next page.")
+    ),
+    responses(
+        (status = 200, description = "Artifacts listed successfully.", body = [Artifact]),
+        (status = StatusCode::BAD_REQUEST, description = "Unknown category", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_app_artifacts_handler(
+    Path(app_name): Path<String>,
+    Query(params): Query<ArtifactParams>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let mut locations = app_artifact_locations(&app_state, &app_name).await?;
+    if let Some(category) = &params.category {
+        locations.retain(|location| &location.category == category);
+        if locations.is_empty() {
+            return Err(ArtifactError::UnknownCategory(category.clone()).into());
+        }
+    }
+
+    // A location that can't be listed is reported with its error, the other locations are still listed
+    let mut data = Vec::with_capacity(locations.len());
+    for location in locations {
+        let entry = match list_artifacts(&app_state, &location, params.cursor.clone()).await {
+            Ok((artifacts, next_cursor)) => json!({
+                "category": location.category,
+                "bucket": location.bucket,
+                "prefix": location.prefix,
+                "objects": artifacts,
+                "next_cursor": next_cursor
+            }),
+            Err(e) => {
+                error!(app_name = app_name, message = e.to_string());
+                json!({
+                    "category": location.category,
+                    "bucket": location.bucket,
+                    "prefix": location.prefix,
+                    "error": e.to_string()
+                })
+            }
+        };
+        data.push(entry);
+    }
+
+    let success_message = format!("Artifacts of '{}' listed successfully.", app_name);
+    debug!(app_name = app_name, message = success_message);
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "app_name": app_name,
+        "data": data
+    })))
+}
+
+/// DELETE handler to delete an artifact of an app.
+#[utoipa::path(
+    delete,
+    path = "/api/v1.1/admin/apps/{app_name}/artifacts",
+    params(
+        ("category" = inline(String), Query, description = "Category of the artifact."),
+        ("key" = inline(String), Query, description = "Key of the artifact.")
+    ),
+    responses(
+        (status = 200, description = "Artifact deleted successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn delete_app_artifact_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    Query(params): Query<ArtifactParams>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let (Some(category), Some(key)) = (&params.category, &params.key) else {
+        let error_message = "The category and key query parameters are required.".to_string();
+        debug!(message = error_message);
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    };
+    let locations = app_artifact_locations(&app_state, &app_name).await?;
+    let location = artifact_location(&locations, category, key)?;
+    let store_error = |message: String| ArtifactError::Store {
+        bucket: location.bucket.clone(),
+        message,
+    };
+    object_store(&app_state)
+        .await
+        .connect(&location.bucket)
+        .await
+        .map_err(store_error)?
+        .delete_object(&location.bucket, key)
+        .await
+        .map_err(store_error)?;
+
+    let success_message = format!(
+        "Artifact 's3://{}/{}' of '{}' deleted successfully.",
+        location.bucket, key, app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "Artifact deleted",
+        details = json!({"category": category, "bucket": location.bucket, "key": key}).to_string(),
+        message = success_message
+    );
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "app_name": app_name}),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_delete_app_artifact_handler_missing_key() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function without key
+            let result = delete_app_artifact_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path("app100".to_string()),
+                Query(ArtifactParams {
+                    category: Some("logs".to_string()),
+                    ..Default::default()
+                }),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+}
@@ -4,53 +4,72 @@
  * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
  */
 //! This module contains the DELETE handler for deleting an app from DocumentDB and other associated resources.
-//! The handler also deletes the API key for the app and notifies Kafka about the app deletion.
-//! The handler also deletes the collections associated with the app.
+//! The handler also deletes the API keys for the app, primary and additional, and notifies Kafka about the app
+//! deletion.
+//! The handler also deletes the collections associated with the app, and its Kafka topic if it has its own.
+//! The deletion of a large app is two-phase (see `service::deletion_confirmation`): without `confirmation_token`, the
+//! handler returns a 202 status code with a confirmation token and the summary of what the deletion destroys, and
+//! deletes nothing. The deletion is executed by a second call with the token, authenticated as a service account: the
+//! approver of the deletion.
+//! The handler returns a 400 status code if the confirmation token is invalid or expired, and a 403 status code if the
+//! confirmation is not authenticated as a service account.
 //! It is instrumented to capture traces using tracing.
 //!
 
-use crate::admin_ui_api::schema::DeleteResponse;
+use crate::admin_ui_api::schema::{DeleteAppParams, DeleteResponse};
 use crate::onboarding::schema::app_onboarding_request::FileStore;
+use crate::service::app_cache::invalidate_cached_app;
+use crate::service::app_repository::AppRepositoryError;
+use crate::service::app_topic::delete_app_topic;
+use crate::service::ctx::Ctx;
+use crate::service::deletion_confirmation::{
+    confirm_deletion, deletion_summary, request_deletion, DeletionError, DeletionOptions,
+};
+use crate::service::notification::{record_notification, Notification, NotificationKind};
 use crate::service::publish_to_kafka::app_deletion_notify_kafka;
+use crate::service::residency::drop_app_collections;
+use crate::service::service_account::ServiceAccount;
 use crate::service::state::AppState;
 use api_utils::errors::error_interceptor::ErrorInterceptor;
 use aws_config::meta::region::RegionProviderChain;
 use aws_config::{BehaviorVersion, Region};
-use axum::{extract::Path, extract::State, http::StatusCode, response::IntoResponse, Json};
-use chrono::Utc;
-use logging_utils::create_ref_id_helper::create_ref_id;
-use logging_utils::create_task_id_helper::create_task_id;
-use logging_utils::create_task_ref_id_helper::create_task_ref_collection;
-use mongodb::bson::{doc, from_bson};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use mongodb::bson::doc;
 use serde_json::json;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tracing::{debug, error, info, instrument};
 
-const COLLECTION_SUFFIXES_TO_DELETE: [&str; 8] = [
-    "audit-microservices",
-    "general",
-    "error",
-    "history",
-    "logs",
-    "metric",
-    "multimodal",
-    "text",
-];
-
 /// DELETE handler to delete an app and other associated resources.
 #[utoipa::path(
     delete,
     path = "/api/v1.1/admin/apps/{app_name}",
+    params(
+        (
+            "confirmation_token" = Option<String>,
+            Query,
+            description = "Confirmation token returned by the first deletion request of a large app.",
+        )
+    ),
     responses(
         (status = 200, description = "App deleted succesfully."),
+        (status = 202, description = "Deletion of a large app pending confirmation.", body = DeletionSummary),
         (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::FORBIDDEN, description = "Confirmation not authenticated as a service account", body = [ErrorResponse]),
         (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
     )
 )]
 #[instrument(skip_all)]
 pub async fn delete_app(
+    ctx: Ctx,
+    account: Option<Extension<ServiceAccount>>,
     Path(app_name): Path<String>,
+    Query(params): Query<DeleteAppParams>,
     State(app_state): State<Arc<AppState>>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
     let filter = doc! {"app_name": &app_name};
@@ -58,14 +77,77 @@ pub async fn delete_app(
 
     // Fetch the sqs_key and api_key_id for the app
     let (sqs_key, api_key_id, filestore) =
-        fetch_sqs_key_api_key_id_and_filestore(&app_state, &app_name).await?;
-    // Generate timestamp and a task_id for the deletion task
-    let deletion_timestamp = Utc::now();
-    let random_num: u32 = (rand::random::<u32>() % 90000) + 10000;
-    let task_id = format!(
-        "{}-{}-{}-{}-{}",
-        "TSK", random_num, &app_name, "Deletion", deletion_timestamp
-    );
+        fetch_sqs_key_api_key_id_and_filestore(&ctx, &app_state, &app_name).await?;
+    // Resolve the cluster of the app collections and the Kafka topic before the app document is deleted
+    let app_db = app_state.app_db(&app_name).await?;
+    let kafka_topic = app_state.apps().kafka_topic(&app_name).await?;
+    let app_keys = app_state.apps().app_keys(&app_name).await?;
+
+    // A large app is deleted by a second call confirming the token returned by the first one
+    let deletion_options = app_state.options::<DeletionOptions>();
+    match params.confirmation_token {
+        Some(token) => {
+            // The approver is the authenticated service account, never an identity claimed by the request
+            let Some(Extension(approver)) = account else {
+                return Err(DeletionError::MissingApprover(app_name.clone()).into());
+            };
+            let summary =
+                confirm_deletion(&app_state, &deletion_options, &app_name, &token).await?;
+            let details = format!(
+                "Deletion of app '{}' ({} knowledge nodes) confirmed by service account '{}' ({}).",
+                app_name, summary.node_count, approver.name, approver.client_id
+            );
+            info!(
+                service = "audit_microservice",
+                task_id = ctx.task_id,
+                app_name = app_name,
+                action = "Confirm app deletion",
+                approver = approver.client_id,
+                details = details,
+                message = details
+            );
+        }
+        None => {
+            let summary = deletion_summary(
+                &app_state,
+                &app_name,
+                &filestore,
+                app_keys.len() + 1,
+                kafka_topic.clone(),
+            )
+            .await?;
+            if deletion_options.requires_confirmation(&summary) {
+                let (token, expires_at) =
+                    request_deletion(&app_state, &deletion_options, &app_name, summary.clone())
+                        .await?;
+                let message = format!(
+                    "App '{}' holds {} knowledge nodes. Confirm its deletion with the confirmation token, as a service account, before {}.",
+                    app_name,
+                    summary.node_count,
+                    expires_at.to_rfc3339()
+                );
+                info!(
+                    service = "audit_microservice",
+                    task_id = ctx.task_id,
+                    app_name = app_name,
+                    action = "Request app deletion",
+                    details = message,
+                    message = message
+                );
+                return Ok((
+                    StatusCode::ACCEPTED,
+                    Json(json!({
+                        "status": "pending",
+                        "message": message,
+                        "app_name": app_name,
+                        "confirmation_token": token,
+                        "expires_at": expires_at.to_rfc3339(),
+                        "summary": summary
+                    })),
+                ));
+            }
+        }
+    }
     match app_state
         .db
         .delete_document(collection_name, filter)
@@ -94,72 +176,62 @@ pub async fn delete_app(
                     Json(json!({"status": "error", "message": error_message})),
                 ))
             } else {
-                for suffix in COLLECTION_SUFFIXES_TO_DELETE {
-                    let collection = format!("{}-{}", app_name, suffix);
-                    match app_state
-                        .db
-                        .drop_collection(&collection)
-                        .await
-                        .map_err(ErrorInterceptor::from)
-                    {
-                        Ok(_) => {
-                            let success_message =
-                                format!("Collection '{}' deleted successfully.", collection);
-                            debug!(message = success_message);
-                        }
-                        Err(e) => {
-                            let error_message = format!(
-                                "Failed to delete collection '{}'. Error: {}",
-                                collection, e
-                            );
-                            debug!(message = error_message);
-                        }
-                    }
-                }
+                invalidate_cached_app(&app_state, &app_name).await;
+                drop_app_collections(app_db, &app_name).await;
 
-                // Delete API key for the app
-                delete_api_key(&app_state, &app_name, &api_key_id).await?;
+                // Delete API keys for the app
+                delete_api_key(&ctx, &app_state, &app_name, &api_key_id).await?;
+                for app_key in &app_keys {
+                    delete_api_key(&ctx, &app_state, &app_name, &app_key.key_id).await?;
+                }
 
                 // Notify Kafka about app deletion. Pass it the sqs key for the app as well.
-                app_deletion_notify_kafka(&app_state, &app_name, &sqs_key, &filestore, task_id)
-                    .await?;
+                app_deletion_notify_kafka(
+                    &app_state,
+                    &app_name,
+                    &sqs_key,
+                    &filestore,
+                    ctx.task_id.clone(),
+                )
+                .await?;
+
+                // Delete the Kafka topic of the app. The app is gone, a leftover topic is only logged.
+                if let Some(kafka_topic) = kafka_topic {
+                    if let Err(e) = delete_app_topic(&app_state, &app_name, &kafka_topic).await {
+                        let error_message = e.to_string();
+                        error!(
+                            app_name = app_name,
+                            ext_message = error_message,
+                            message = error_message
+                        );
+                    }
+                }
 
                 let success_message = format!("App '{}' deleted successfully.", app_name);
                 debug!(message = success_message);
-                Ok(Json(
-                    json!({"status": "success", "message": success_message, "app_name": app_name}),
+                record_notification(
+                    &app_state,
+                    Notification::new(
+                        NotificationKind::DeletionFinished,
+                        &app_name,
+                        success_message.clone(),
+                    ),
+                )
+                .await;
+                Ok((
+                    StatusCode::OK,
+                    Json(
+                        json!({"status": "success", "message": success_message, "app_name": app_name}),
+                    ),
                 ))
             }
         }
         Err(e) => {
             let error_message = format!("Failed to delete app '{}'. Error: {:?}", app_name, e);
-            let ref_id = create_ref_id();
-            let mongo_url = app_state.app_settings.mongo_db.mongo_db_url.clone();
-            let mongo_db_name = app_state
-                .app_settings
-                .mongo_db
-                .mongo_db_database_name
-                .clone();
-            let id_collection = app_state
-                .app_settings
-                .mongo_db
-                .mongo_db_id_collection
-                .clone();
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.clone(),
-                task_id,
-                ref_id.clone(),
-            )
-            .await;
-            let ext_message = format!(
-                "{} Use reference ID: {}",
-                app_state.app_settings.general_message, ref_id
-            );
+            let ext_message = ctx.ext_message(&app_state);
             error!(
                 app_name = app_name,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
@@ -175,129 +247,51 @@ pub type FetchError = (StatusCode, Json<serde_json::Value>);
 /// Asynchronous function to fetch the sqs key for an app.
 #[instrument(skip_all)]
 pub async fn fetch_sqs_key_api_key_id_and_filestore(
+    ctx: &Ctx,
     app_state: &Arc<AppState>,
     app_name: &String,
 ) -> Result<FetchResult, FetchError> {
-    let filter = doc! {"app_name": app_name};
-    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
-
-    match app_state
-        .db
-        .get_document(collection_name, filter)
-        .await
-        .map_err(ErrorInterceptor::from)
-    {
-        Ok(Some(response)) => {
-            if let (Some(sqs_key), Some(api_key_id), Some(filestore_bson)) = (
-                response.get("sqs_key").and_then(|sqs_key| sqs_key.as_str()),
-                response
-                    .get("api_key_id")
-                    .and_then(|api_key_id| api_key_id.as_str()),
-                response
-                    .get("app_datasource")
-                    .and_then(|app_datasource| app_datasource.get("filestore")),
-            ) {
-                // Convert the Bson object to a HashMap<String, Vec<FileStore>>
-                let filestore_bson = bson::to_bson(&filestore_bson).map_err(|_| {
-                    let error_message = "Failed to convert filestore to Bson.".to_string();
-                    debug!(message = error_message);
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(serde_json::json!({ "status": "error","message": error_message})),
-                    )
-                })?;
-
-                let filestore_result: Result<HashMap<String, Vec<FileStore>>, _> =
-                    from_bson(filestore_bson);
-
-                let filestore = match filestore_result {
-                    Ok(filestore) => filestore,
-                    Err(_) => {
-                        let error_message = "Failed to deserialize filestore.".to_string();
-                        debug!(message = error_message);
-                        return Err((
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            Json(serde_json::json!({ "status": "error","message": error_message})),
-                        ));
-                    }
-                };
-
-                let success_message =
-                    "Sqs_key, api_key_id and filestore fetched successfully for given app_name."
-                        .to_string();
-                info!(app_name = app_name, message = success_message);
-                Ok((
-                    sqs_key.to_string(),
-                    api_key_id.to_string(),
-                    filestore.clone(),
-                ))
-            } else {
-                let error_message = format!("Failed to fetch SQS key, API key id and/or filestore. No such key(s) found for app '{}'.", app_name);
-                debug!(message = error_message);
-                Err((
-                    StatusCode::NOT_FOUND,
-                    Json(json!({"status": "error", "message": error_message})),
-                ))
-            }
-        }
-        Ok(None) => {
-            let error_message = format!("No app found with name '{}'.", app_name);
-            debug!(message = error_message);
-            Err((
-                StatusCode::NOT_FOUND,
-                Json(json!({"status": "error", "message": error_message})),
-            ))
+    match app_state.apps().deletion_details(app_name).await {
+        Ok(details) => {
+            let success_message =
+                "Sqs_key, api_key_id and filestore fetched successfully for given app_name."
+                    .to_string();
+            info!(app_name = app_name, message = success_message);
+            Ok((details.sqs_key, details.api_key_id, details.filestore))
         }
-        Err(e) => {
+        Err(AppRepositoryError::Db(e)) => {
             let error_message = format!(
                 "Failed to fetch SQS key, API key id and/or filestore for the app {}. Error: {:?}",
                 app_name, e
             );
-            let ref_id = create_ref_id();
-            let service_type = "FetchSqsKey".to_string();
-            let task_id = create_task_id(app_name, service_type);
-            let mongo_url = app_state.app_settings.mongo_db.mongo_db_url.clone();
-            let mongo_db_name = app_state
-                .app_settings
-                .mongo_db
-                .mongo_db_database_name
-                .clone();
-            let id_collection = app_state
-                .app_settings
-                .mongo_db
-                .mongo_db_id_collection
-                .clone();
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.clone(),
-                task_id.clone(),
-                ref_id.clone(),
-            )
-            .await;
-            let ext_message = format!(
-                "{} Use reference ID: {}",
-                app_state.app_settings.general_message, ref_id
-            );
+            let ext_message = ctx.ext_message(app_state);
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
             Err(e.intercept_error().await)
         }
+        Err(e) => Err(e.into()),
     }
 }
 
 /// Asynchronous function to delete an API key for the app. The API key name is same as the app name.
 #[instrument(skip_all)]
 pub async fn delete_api_key(
+    ctx: &Ctx,
     app_state: &Arc<AppState>,
     app_name: &String,
     api_key_id: &String,
 ) -> Result<String, (StatusCode, Json<serde_json::Value>)> {
+    // In the internal API key mode the key is only stored on the app document, deleted with it
+    if app_state.api_key_options().is_internal() {
+        return Ok(format!(
+            "API key of the app {} is deleted with the app.",
+            app_name
+        ));
+    }
     debug!("Deleting api key for the app.");
     let region = app_state.app_settings.aws_api_gateway.region.clone();
     let region_provider = RegionProviderChain::first_try(Region::new(region));
@@ -316,33 +310,9 @@ pub async fn delete_api_key(
         }
         Err(e) => {
             let error_message = format!("API key deletion failed. Error: {}", e);
-            let ref_id = create_ref_id();
-            let service_type = "DeleteApiKey".to_string();
-            let task_id = create_task_id(app_name, service_type);
-            let mongo_url = app_state.app_settings.mongo_db.mongo_db_url.clone();
-            let mongo_db_name = app_state
-                .app_settings
-                .mongo_db
-                .mongo_db_database_name
-                .clone();
-            let id_collection = app_state
-                .app_settings
-                .mongo_db
-                .mongo_db_id_collection
-                .clone();
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.clone(),
-                task_id.clone(),
-                ref_id.clone(),
-            )
-            .await;
-            let ext_message = format!(
-                "{} Use reference ID: {}",
-                app_state.app_settings.general_message, ref_id
-            );
+            let ext_message = ctx.ext_message(app_state);
+            // The failures of this step keep their own service type
+            let task_id = app_state.id_generator.task_id(app_name, "DeleteApiKey");
             error!(
                 app_name = app_name,
                 task_id = task_id,
@@ -367,7 +337,15 @@ pub mod tests {
         let app_state = crate::tests::test_get_appstate().await.unwrap();
 
         // Call the function
-        let _result = delete_app(Path(app_name), State(app_state)).await;
+        let ctx = Ctx::new(&app_state, &app_name, "Test");
+        let _result = delete_app(
+            ctx,
+            None,
+            Path(app_name),
+            Query(DeleteAppParams::default()),
+            State(app_state),
+        )
+        .await;
     }
 
     #[test]
@@ -380,7 +358,15 @@ pub mod tests {
             let app_name = "non_existent_app".to_string();
 
             // Call the function
-            let result = delete_app(Path(app_name), State(app_state.clone())).await;
+            let ctx = Ctx::new(&app_state, &app_name, "Test");
+            let result = delete_app(
+                ctx,
+                None,
+                Path(app_name),
+                Query(DeleteAppParams::default()),
+                State(app_state.clone()),
+            )
+            .await;
 
             // If the function returns Err, check the status code and message
             let (status_code, Json(message)) = result.err().unwrap();
@@ -404,8 +390,10 @@ pub mod tests {
             let app_state = crate::tests::test_get_appstate().await.unwrap();
 
             // Call the function
+            let ctx = Ctx::new(&app_state, "app100", "Test");
             let result =
-                fetch_sqs_key_api_key_id_and_filestore(&app_state, &"app100".to_string()).await;
+                fetch_sqs_key_api_key_id_and_filestore(&ctx, &app_state, &"app100".to_string())
+                    .await;
 
             // Check if the function returns Ok
             assert!(result.is_ok());
@@ -421,9 +409,13 @@ pub mod tests {
             let app_state = crate::tests::test_get_appstate().await.unwrap();
 
             // Call the function
-            let result =
-                fetch_sqs_key_api_key_id_and_filestore(&app_state, &"non_existent_app".to_string())
-                    .await;
+            let ctx = Ctx::new(&app_state, "non_existent_app", "Test");
+            let result = fetch_sqs_key_api_key_id_and_filestore(
+                &ctx,
+                &app_state,
+                &"non_existent_app".to_string(),
+            )
+            .await;
 
             // If the function returns Err, check the status code and message
             let (status_code, Json(message)) = result.err().unwrap();
@@ -447,7 +439,9 @@ pub mod tests {
             let app_state = crate::tests::test_get_appstate().await.unwrap();
 
             // Call the function
+            let ctx = Ctx::new(&app_state, "non_existent_app", "Test");
             let result = delete_api_key(
+                &ctx,
                 &app_state,
                 &"non_existent_app".to_string(),
                 &"non_existent_api_key_id".to_string(),
@@ -0,0 +1,129 @@
+/*
+ * Created Date:  Jun 25, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the POST handler to rotate the data key of an app.
+//! The handler is mounted at `/api/v1.1/admin/apps/{app_name}/encryption-key/rotate`.
+//! New documents of the app are encrypted with the new key version. Documents encrypted with
+//! older versions stay readable, since the older data keys are kept.
+//! The handler returns a 200 status code if the data key is rotated successfully.
+//! The handler returns a 400 status code if encryption is not configured.
+//! The handler returns a 404 status code if the app is not found.
+//! The handler returns a 500 status code if an error occurs while rotating the data key.
+//!
+
+use crate::service::check_app_existence::check_app_existence;
+use crate::service::ctx::Ctx;
+use crate::service::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// POST handler to rotate the data key of an app.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/apps/{app_name}/encryption-key/rotate",
+    responses(
+        (status = 200, description = "Data key rotated successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Encryption is not configured", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_rotate_encryption_key_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let Some(encryptor) = app_state.encryptor.as_ref() else {
+        let error_message = "Encryption is not configured.".to_string();
+        debug!(app_name = app_name, message = error_message);
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    };
+
+    if !check_app_existence(&app_state, &app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    match encryptor.rotate_key(&app_state.db, &app_name).await {
+        Ok(key_version) => {
+            let success_message = format!(
+                "Data key of '{}' rotated to version {}.",
+                app_name, key_version
+            );
+            info!(app_name = app_name, message = success_message);
+            info!(
+                service = "audit_microservice",
+                app_name = app_name,
+                action = "Data key rotated",
+                details = success_message,
+                message = success_message
+            );
+            Ok(Json(
+                json!({"status": "success", "message": success_message, "app_name": app_name, "key_version": key_version}),
+            ))
+        }
+        Err(e) => {
+            let error_message = format!(
+                "Failed to rotate data key of app '{}'. Error: {}",
+                app_name, e
+            );
+            let ext_message = ctx.ext_message(&app_state);
+            error!(
+                app_name = app_name,
+                task_id = ctx.task_id,
+                ext_message = ext_message,
+                message = error_message
+            );
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"status": "error", "message": error_message})),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_rotate_encryption_key_not_configured() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState, without key provider
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+
+            // Call the function
+            let result = post_rotate_encryption_key_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, Json(message)) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+            assert_eq!(message.get("status").unwrap().as_str().unwrap(), "error");
+        });
+    }
+}
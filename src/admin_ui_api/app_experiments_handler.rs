@@ -0,0 +1,400 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the handlers for the A/B experiments of an app.
+//! The handlers are mounted at `/api/v1.1/admin/apps/{app_name}/experiments`.
+//! The GET handler returns the experiments stored in the app document, empty if not set.
+//! The PUT handler adds an experiment, or replaces the experiment with the same name. Replacing the variants of an
+//! experiment reassigns its users, so a new split should be a new experiment.
+//! The DELETE handler of `/{experiment_name}` removes an experiment; its results stay in the history documents.
+//! The GET handler of `/{experiment_name}/results` aggregates the history documents of the app by variant of the
+//! experiment: retrievals, failed retrievals, average confidence and tokens used.
+//! The handlers return a 200 status code if the experiments are fetched/updated successfully.
+//! The handlers return a 400 status code if the experiment is invalid.
+//! The handlers return a 404 status code if the app or the experiment is not found.
+//! The handlers return a 500 status code if an error occurs while fetching/updating the experiments.
+//!
+
+use crate::admin_ui_api::schema::UpdateResponse;
+use crate::retrieval::replay::REPLAY_OF_FIELD;
+use crate::retrieval::schema::history_document::RETRIEVAL_FAILED_TIMESTAMP;
+use crate::service::ctx::Ctx;
+use crate::service::experiment::{Experiment, ExperimentError, VariantResults, EXPERIMENTS_FIELD};
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, to_bson, Document};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// GET handler to get the experiments of an app.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/experiments",
+    responses(
+        (status = 200, description = "Experiments retrieved successfully.", body = [Experiment]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_experiments_handler(
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let experiments = find_experiments(&app_state, &app_name).await?;
+    let success_message = format!("Experiments of '{}' retrieved successfully.", app_name);
+    info!(app_name = app_name, message = success_message);
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "data": experiments}),
+    ))
+}
+
+/// PUT handler to add an experiment to an app, or replace the experiment with the same name.
+#[utoipa::path(
+    put,
+    path = "/api/v1.1/admin/apps/{app_name}/experiments",
+    request_body = Experiment,
+    responses(
+        (status = 200, description = "Experiment saved successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn put_experiment_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(experiment): Json<Experiment>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    experiment.validate()?;
+    let mut experiments = find_experiments(&app_state, &app_name).await?;
+    match experiments
+        .iter_mut()
+        .find(|stored| stored.name == experiment.name)
+    {
+        Some(stored) => *stored = experiment.clone(),
+        None => experiments.push(experiment.clone()),
+    }
+    store_experiments(&ctx, &app_state, &app_name, &experiments).await?;
+
+    let success_message = format!(
+        "Experiment '{}' of '{}' saved successfully.",
+        experiment.name, app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "Experiment saved",
+        details = json!(experiment).to_string(),
+        message = success_message
+    );
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "app_name": app_name}),
+    ))
+}
+
+/// DELETE handler to remove an experiment of an app.
+#[utoipa::path(
+    delete,
+    path = "/api/v1.1/admin/apps/{app_name}/experiments/{experiment_name}",
+    responses(
+        (status = 200, description = "Experiment deleted successfully."),
+        (status = StatusCode::NOT_FOUND, description = "App or experiment not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn delete_experiment_handler(
+    ctx: Ctx,
+    Path((app_name, experiment_name)): Path<(String, String)>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let mut experiments = find_experiments(&app_state, &app_name).await?;
+    let count = experiments.len();
+    experiments.retain(|experiment| experiment.name != experiment_name);
+    if experiments.len() == count {
+        return Err(ExperimentError::UnknownExperiment(experiment_name).into());
+    }
+    store_experiments(&ctx, &app_state, &app_name, &experiments).await?;
+
+    let success_message = format!(
+        "Experiment '{}' of '{}' deleted successfully.",
+        experiment_name, app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "Experiment deleted",
+        details = experiment_name,
+        message = success_message
+    );
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "app_name": app_name}),
+    ))
+}
+
+/// GET handler to get the results of an experiment of an app, by variant.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/experiments/{experiment_name}/results",
+    responses(
+        (status = 200, description = "Experiment results retrieved successfully.", body = [VariantResults]),
+        (status = StatusCode::NOT_FOUND, description = "App or experiment not found", body = [ErrorResponse]),
+        (status = StatusCode::GATEWAY_TIMEOUT, description = "The aggregation exceeded its time limit", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_experiment_results_handler(
+    Path((app_name, experiment_name)): Path<(String, String)>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let experiments = find_experiments(&app_state, &app_name).await?;
+    if !experiments
+        .iter()
+        .any(|experiment| experiment.name == experiment_name)
+    {
+        return Err(ExperimentError::UnknownExperiment(experiment_name).into());
+    }
+
+    // The aggregation runs on the analytics connection of the app residency, when configured
+    let history_collection_name = format!("{}-history", app_name);
+    let analytics_db = app_state.app_analytics_db(&app_name).await?;
+    let results = analytics_db
+        .aggregate(
+            &history_collection_name,
+            experiment_results_pipeline(&experiment_name),
+            &app_state.options::<QueryOptions>(),
+        )
+        .await?
+        .into_iter()
+        .map(serde_json::from_value::<VariantResults>)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            let error_message = format!(
+                "Failed to deserialize experiment results of app '{}'. Error: {}",
+                app_name, e
+            );
+            error!(app_name = app_name, message = error_message);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"status": "error", "message": error_message})),
+            )
+        })?;
+
+    let success_message = format!(
+        "Results of experiment '{}' of '{}' retrieved successfully.",
+        experiment_name, app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "data": results}),
+    ))
+}
+
+/// Pipeline grouping the history documents assigned to an experiment by variant.
+fn experiment_results_pipeline(experiment_name: &str) -> Vec<Document> {
+    let variant_field = format!("experiment_variants.{}", experiment_name);
+    vec![
+        doc! {
+            "$match": { &variant_field: { "$exists": true }, REPLAY_OF_FIELD: { "$exists": false } }
+        },
+        doc! {
+            "$group": {
+                "_id": format!("${}", variant_field),
+                "retrievals": { "$sum": 1 },
+                "failed_retrievals": {
+                    "$sum": { "$cond": [ { "$eq": [ "$timestamp", RETRIEVAL_FAILED_TIMESTAMP ] }, 1, 0 ] }
+                },
+                "average_confidence": { "$avg": "$confidence" },
+                "total_tokens": { "$sum": { "$ifNull": [ "$token_usage.total_tokens", 0 ] } },
+            }
+        },
+        doc! { "$sort": { "_id": 1 } },
+        doc! {
+            "$project": {
+                "_id": 0,
+                "variant": "$_id",
+                "retrievals": 1,
+                "failed_retrievals": 1,
+                "average_confidence": 1,
+                "total_tokens": 1,
+            }
+        },
+    ]
+}
+
+/// Returns the experiments of an app. Unknown apps are answered with a 404.
+async fn find_experiments(
+    app_state: &AppState,
+    app_name: &str,
+) -> Result<Vec<Experiment>, (StatusCode, Json<serde_json::Value>)> {
+    let apps = app_state.apps();
+    if !apps.exists(app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        debug!(message = error_message);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+    Ok(apps.experiments(app_name).await?)
+}
+
+/// Stores the experiments of an app on its app document.
+async fn store_experiments(
+    ctx: &Ctx,
+    app_state: &AppState,
+    app_name: &str,
+    experiments: &[Experiment],
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let filter = doc! {"app_name": app_name};
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let error_message = match to_bson(experiments) {
+        Ok(experiments_bson) => match app_state
+            .db
+            .update_document(
+                collection_name,
+                filter,
+                doc! {EXPERIMENTS_FIELD: experiments_bson},
+            )
+            .await
+            .map_err(ErrorInterceptor::from)
+        {
+            Ok(json_result) => match serde_json::from_value::<UpdateResponse>(json_result) {
+                Ok(result) if result.matchedCount == 0 => {
+                    let error_message = format!("No app found with name '{}'.", app_name);
+                    debug!(message = error_message);
+                    return Err((
+                        StatusCode::NOT_FOUND,
+                        Json(json!({"status": "error", "message": error_message})),
+                    ));
+                }
+                Ok(_) => None,
+                Err(e) => Some(format!(
+                    "Failed to deserialize update response. Error: {:?}",
+                    e
+                )),
+            },
+            Err(e) => Some(format!(
+                "Failed to update experiments of app '{}'. Error: {}",
+                app_name, e
+            )),
+        },
+        Err(e) => Some(format!(
+            "Failed to serialize experiments to BSON. Error: {}",
+            e
+        )),
+    };
+    if let Some(error_message) = error_message {
+        let ext_message = ctx.ext_message(app_state);
+        error!(
+            app_name = app_name,
+            task_id = ctx.task_id,
+            ext_message = ext_message,
+            message = error_message
+        );
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::experiment::ExperimentVariant;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_experiment_results_pipeline() {
+        let pipeline = experiment_results_pipeline("prompt-v2");
+        assert_eq!(pipeline.len(), 4);
+        assert!(pipeline[0]
+            .get_document("$match")
+            .unwrap()
+            .contains_key("experiment_variants.prompt-v2"));
+        assert_eq!(
+            pipeline[1]
+                .get_document("$group")
+                .unwrap()
+                .get_str("_id")
+                .unwrap(),
+            "$experiment_variants.prompt-v2"
+        );
+    }
+
+    #[test]
+    fn test_failure_put_experiment_handler_invalid_experiment() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+            let experiment = Experiment {
+                name: "prompt-v2".to_string(),
+                description: None,
+                enabled: true,
+                variants: vec![ExperimentVariant {
+                    name: "control".to_string(),
+                    weight: 100,
+                    parameters: None,
+                }],
+            };
+
+            // Call the function
+            let result = put_experiment_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                State(app_state),
+                Json(experiment),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn test_failure_get_experiment_results_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let result = get_experiment_results_handler(
+                Path((app_name, "prompt-v2".to_string())),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
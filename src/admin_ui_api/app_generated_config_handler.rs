@@ -0,0 +1,460 @@
+/*
+ * Created Date:  Jun 21, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET and PATCH handlers for the generated config of an app.
+//! The handlers are mounted at `/api/v1.1/admin/apps/{app_name}/generated-config`.
+//! The GET handler returns the generated config stored in the app document.
+//! The PATCH handler updates the retention values and collection prefixes of the generated config,
+//! and notifies Kafka about the change. The S3 prefixes cannot be edited.
+//! The handlers return a 200 status code if the generated config is fetched/updated successfully.
+//! The handlers return a 400 status code if the requested change is invalid.
+//! The handlers return a 404 status code if the app is not found.
+//! The handlers return a 500 status code if an error occurs while fetching/updating the generated config.
+//!
+
+use crate::admin_ui_api::schema::{GeneratedConfigPatch, ServiceConfigPatch, UpdateResponse};
+use crate::service::ctx::Ctx;
+use crate::service::publish_to_kafka::app_config_change_notify_kafka;
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, Document};
+use regex::Regex;
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// Maximum length of a collection prefix.
+const MAX_COLLECTION_PREFIX_LENGTH: usize = 64;
+
+/// Validates a retention value, a positive number of seconds.
+fn validate_retention(field: &str, retention: &str) -> Result<(), String> {
+    match retention.parse::<u64>() {
+        Ok(seconds) if seconds > 0 => Ok(()),
+        _ => Err(format!(
+            "Invalid '{}' value '{}'. Retention must be a positive number of seconds.",
+            field, retention
+        )),
+    }
+}
+
+/// Validates a collection prefix. Prefixes of app specific collections must start with the app name.
+fn validate_collection_prefix(
+    field: &str,
+    prefix: &str,
+    required_start: Option<&str>,
+) -> Result<(), String> {
+    let pattern = Regex::new(r"^[A-Za-z0-9_-]+$").unwrap();
+    if prefix.len() > MAX_COLLECTION_PREFIX_LENGTH || !pattern.is_match(prefix) {
+        return Err(format!(
+            "Invalid '{}' value '{}'. Collection prefixes must be 1 to {} characters of letters, digits, '-' or '_'.",
+            field, prefix, MAX_COLLECTION_PREFIX_LENGTH
+        ));
+    }
+    if let Some(required_start) = required_start {
+        if !prefix.starts_with(required_start) || prefix.len() == required_start.len() {
+            return Err(format!(
+                "Invalid '{}' value '{}'. The collection prefix must start with '{}'.",
+                field, prefix, required_start
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Validates the patch and flattens it into the `$set` fields of the app document.
+pub fn generated_config_update_fields(
+    app_name: &str,
+    patch: &GeneratedConfigPatch,
+) -> Result<Document, String> {
+    let mut fields = Document::new();
+
+    if let Some(vectordb_config) = &patch.vectordb_config {
+        let path = "generated_config.knowledge_graph_config.vectordb_config";
+        let prefixes = [
+            (
+                "text_collection_name_prefix",
+                &vectordb_config.text_collection_name_prefix,
+            ),
+            (
+                "multimodal_collection_name_prefix",
+                &vectordb_config.multimodal_collection_name_prefix,
+            ),
+            (
+                "general_collection_name_prefix",
+                &vectordb_config.general_collection_name_prefix,
+            ),
+            (
+                "session_history_collection_name_prefix",
+                &vectordb_config.session_history_collection_name_prefix,
+            ),
+            (
+                "error_collection_name_prefix",
+                &vectordb_config.error_collection_name_prefix,
+            ),
+            (
+                "insight_collection_name_prefix",
+                &vectordb_config.insight_collection_name_prefix,
+            ),
+        ];
+        for (field, prefix) in prefixes {
+            if let Some(prefix) = prefix {
+                validate_collection_prefix(field, prefix, None)?;
+                fields.insert(format!("{}.{}", path, field), prefix.as_str());
+            }
+        }
+        if let Some(retention) = &vectordb_config.retention {
+            validate_retention("vectordb_config.retention", retention)?;
+            fields.insert(format!("{}.retention", path), retention.as_str());
+        }
+    }
+
+    let app_prefix = format!("{}-", app_name);
+    let services: [(&str, &Option<ServiceConfigPatch>); 3] = [
+        ("logging", &patch.logging),
+        ("audit", &patch.audit),
+        ("metric", &patch.metric),
+    ];
+    for (service, service_patch) in services {
+        let Some(service_patch) = service_patch else {
+            continue;
+        };
+        if let Some(prefix) = &service_patch.collection_name_prefix {
+            let field = format!("{}.collection_name_prefix", service);
+            validate_collection_prefix(&field, prefix, Some(&app_prefix))?;
+            fields.insert(format!("generated_config.{}", field), prefix.as_str());
+        }
+        if let Some(retention) = &service_patch.retention {
+            let field = format!("{}.retention", service);
+            validate_retention(&field, retention)?;
+            fields.insert(format!("generated_config.{}", field), retention.as_str());
+        }
+    }
+
+    if fields.is_empty() {
+        return Err("No generated config changes provided.".to_string());
+    }
+    Ok(fields)
+}
+
+/// Asynchronous function to fetch the generated config of an app.
+#[instrument(skip_all)]
+async fn fetch_generated_config(
+    app_state: &Arc<AppState>,
+    app_name: &String,
+) -> Result<serde_json::Value, (StatusCode, Json<serde_json::Value>)> {
+    let filter = doc! {"app_name": app_name};
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+
+    match app_state
+        .db
+        .get_document(collection_name, filter)
+        .await
+        .map_err(ErrorInterceptor::from)
+    {
+        Ok(Some(app)) => match app.get("generated_config") {
+            Some(generated_config) => Ok(generated_config.clone()),
+            None => {
+                let error_message = format!(
+                    "Failed to fetch generated_config of app '{}'. No such key found in document.",
+                    app_name
+                );
+                error!(
+                    app_name = app_name,
+                    ext_message = error_message,
+                    message = error_message
+                );
+                Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"status": "error", "message": error_message})),
+                ))
+            }
+        },
+        Ok(None) => {
+            let error_message = format!("No app found with name '{}'.", app_name);
+            debug!(message = error_message);
+            Err((
+                StatusCode::NOT_FOUND,
+                Json(json!({"status": "error", "message": error_message})),
+            ))
+        }
+        Err(e) => {
+            let error_message = format!("Failed to fetch app '{}'. Error: {}", app_name, e);
+            error!(
+                app_name = app_name,
+                ext_message = error_message,
+                message = error_message
+            );
+            Err(e.intercept_error().await)
+        }
+    }
+}
+
+/// GET handler to get the generated config of an app.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/generated-config",
+    responses(
+        (status = 200, description = "Generated config retrieved successfully."),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_generated_config_handler(
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let generated_config = fetch_generated_config(&app_state, &app_name).await?;
+    let success_message = format!("Generated config of '{}' retrieved successfully.", app_name);
+    info!(app_name = app_name, message = success_message);
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "data": generated_config}),
+    ))
+}
+
+/// PATCH handler to update the retention values and collection prefixes of the generated config of an app.
+#[utoipa::path(
+    patch,
+    path = "/api/v1.1/admin/apps/{app_name}/generated-config",
+    request_body = GeneratedConfigPatch,
+    responses(
+        (status = 200, description = "Generated config updated successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn patch_generated_config_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(patch): Json<GeneratedConfigPatch>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    // Validate the requested change
+    let update_fields =
+        generated_config_update_fields(&app_name, &patch).map_err(|error_message| {
+            debug!(app_name = app_name, message = error_message);
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"status": "error", "message": error_message})),
+            )
+        })?;
+
+    let previous_config = fetch_generated_config(&app_state, &app_name).await?;
+
+    let filter = doc! {"app_name": &app_name};
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let update_result = app_state
+        .db
+        .update_document(collection_name, filter, update_fields)
+        .await
+        .map_err(ErrorInterceptor::from);
+    let error_message = match update_result {
+        Ok(json_result) => match serde_json::from_value::<UpdateResponse>(json_result) {
+            Ok(result) if result.matchedCount == 0 => {
+                let error_message = format!("No app found with name '{}'.", app_name);
+                debug!(message = error_message);
+                return Err((
+                    StatusCode::NOT_FOUND,
+                    Json(json!({"status": "error", "message": error_message})),
+                ));
+            }
+            Ok(_) => None,
+            Err(e) => Some(format!(
+                "Failed to deserialize update response. Error: {:?}",
+                e
+            )),
+        },
+        Err(e) => Some(format!(
+            "Failed to update generated config of app '{}'. Error: {}",
+            app_name, e
+        )),
+    };
+    if let Some(error_message) = error_message {
+        let ext_message = ctx.ext_message(&app_state);
+        error!(
+            app_name = app_name,
+            task_id = ctx.task_id,
+            ext_message = ext_message,
+            message = error_message
+        );
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    // Notify Kafka about the config change
+    let changed_fields = json!(patch);
+    app_config_change_notify_kafka(
+        &app_state,
+        &app_name,
+        &previous_config,
+        &changed_fields,
+        ctx.task_id.clone(),
+    )
+    .await?;
+
+    let success_message = format!("Generated config of '{}' updated successfully.", app_name);
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "Generated config updated",
+        details = changed_fields.to_string(),
+        message = success_message
+    );
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "app_name": app_name}),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin_ui_api::schema::VectorDbConfigPatch;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_generated_config_update_fields() {
+        let patch = GeneratedConfigPatch {
+            vectordb_config: Some(VectorDbConfigPatch {
+                retention: Some("172800".to_string()),
+                ..Default::default()
+            }),
+            logging: Some(ServiceConfigPatch {
+                collection_name_prefix: Some("app100-logs-v2".to_string()),
+                retention: Some("3600".to_string()),
+            }),
+            ..Default::default()
+        };
+
+        let fields = generated_config_update_fields("app100", &patch).unwrap();
+        assert_eq!(
+            fields
+                .get_str("generated_config.knowledge_graph_config.vectordb_config.retention")
+                .unwrap(),
+            "172800"
+        );
+        assert_eq!(
+            fields
+                .get_str("generated_config.logging.collection_name_prefix")
+                .unwrap(),
+            "app100-logs-v2"
+        );
+        assert_eq!(
+            fields
+                .get_str("generated_config.logging.retention")
+                .unwrap(),
+            "3600"
+        );
+        assert_eq!(fields.len(), 3);
+    }
+
+    #[test]
+    fn test_failure_generated_config_update_fields() {
+        // No change
+        assert!(
+            generated_config_update_fields("app100", &GeneratedConfigPatch::default()).is_err()
+        );
+
+        // Invalid retention
+        let patch = GeneratedConfigPatch {
+            audit: Some(ServiceConfigPatch {
+                retention: Some("-1".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(generated_config_update_fields("app100", &patch)
+            .unwrap_err()
+            .contains("audit.retention"));
+
+        // Prefix of another app
+        let patch = GeneratedConfigPatch {
+            metric: Some(ServiceConfigPatch {
+                collection_name_prefix: Some("app200-metric".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(generated_config_update_fields("app100", &patch)
+            .unwrap_err()
+            .contains("must start with 'app100-'"));
+
+        // Invalid characters
+        let patch = GeneratedConfigPatch {
+            vectordb_config: Some(VectorDbConfigPatch {
+                text_collection_name_prefix: Some("text.$".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(generated_config_update_fields("app100", &patch).is_err());
+
+        // S3 prefixes cannot be edited
+        assert!(serde_json::from_value::<GeneratedConfigPatch>(
+            json!({"logging": {"s3_storage_prefix": "logs"}})
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_success_get_generated_config_handler() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+
+            // Call the function
+            let result = get_generated_config_handler(Path(app_name), State(app_state)).await;
+
+            // Check if the function returns Ok
+            assert!(result.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_failure_patch_generated_config_handler_no_app_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+            let patch = GeneratedConfigPatch {
+                metric: Some(ServiceConfigPatch {
+                    retention: Some("3600".to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+
+            // Call the function
+            let result = patch_generated_config_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                State(app_state),
+                Json(patch),
+            )
+            .await;
+
+            // If the function returns Err, check the status code
+            let (status_code, Json(message)) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+            assert_eq!(message.get("status").unwrap().as_str().unwrap(), "error");
+        });
+    }
+}
@@ -8,23 +8,34 @@
 //! The handler is mounted at `/api/v1.1/admin/apps/{app_name}`.
 //! The handler is called by the admin UI to fetch an app by its name.
 //! The handler returns the app document if it exists, else returns an error message.
+//! The ingestion state of the app is always returned, `running` if the ingestion was never paused.
+//! The readiness of the app is returned with it, the share of its sources indexed by the ingestion.
+//! The optional `fields` query parameter restricts the returned app to the requested fields, so the list views of
+//! the admin UI do not fetch the table schemas of the datastores they do not render.
 //! The handler returns a 200 status code if the app is fetched successfully.
 //! The handler returns a 404 status code if the app is not found.
 //! The handler returns a 500 status code if an error occurs while fetching the app.
 //! The handler returns a JSON response with the status and message.
 //!
 
+use crate::admin_ui_api::schema::QueryParams;
+use crate::service::column_classification::merge_column_classifications;
+use crate::service::ctx::Ctx;
+use crate::service::field_projection::FieldProjection;
+use crate::service::ingestion_control::IngestionControl;
+use crate::service::query_options::{AggregateExt, QueryError, QueryOptions};
+use crate::service::readiness::app_readiness;
+use crate::service::row_filter::merge_row_filters;
+use crate::service::source_label::merge_source_labels;
 use crate::service::state::AppState;
+use crate::service::timestamp::serve_timestamps;
 use api_utils::errors::error_interceptor::ErrorInterceptor;
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     response::IntoResponse,
     Json,
 };
-use logging_utils::create_ref_id_helper::create_ref_id;
-use logging_utils::create_task_id_helper::create_task_id;
-use logging_utils::create_task_ref_id_helper::create_task_ref_collection;
 use mongodb::bson::doc;
 use serde_json::json;
 use std::sync::Arc;
@@ -34,6 +45,13 @@ use tracing::{debug, error, info, instrument};
 #[utoipa::path(
     get,
     path = "/api/v1.1/admin/apps/{app_name}",
+    params(
+        (
+            "fields" = inline(Option<String>), 
+            Query,
+            description = "comma separated fields of the app to return, the whole app by default.",
+        )
+    ),
     responses(
         (status = 200, description = "App retrieved succesfully."),
         (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
@@ -42,19 +60,84 @@ use tracing::{debug, error, info, instrument};
 )]
 #[instrument(skip_all)]
 pub async fn get_app(
+    ctx: Ctx,
     Path(app_name): Path<String>,
+    Query(params): Query<QueryParams>,
     State(app_state): State<Arc<AppState>>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
     let filter = doc! {"app_name": &app_name};
     let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let fields = FieldProjection::parse(params.fields.as_deref())?;
 
-    match app_state
-        .db
-        .get_document(collection_name, filter)
-        .await
-        .map_err(ErrorInterceptor::from)
-    {
-        Ok(Some(app)) => {
+    let app = match &fields {
+        // Only the requested fields are read from DocumentDB
+        Some(fields) => {
+            let app_pipeline = vec![
+                doc! { "$match": filter },
+                doc! { "$limit": 1 },
+                doc! { "$project": fields.projection(&doc! {}) },
+            ];
+            match app_state
+                .db
+                .aggregate(
+                    collection_name,
+                    app_pipeline,
+                    &app_state.options::<QueryOptions>(),
+                )
+                .await
+            {
+                Ok(apps) => Ok(apps.into_iter().next()),
+                Err(QueryError::Db(e)) => Err(e),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        None => app_state
+            .db
+            .get_document(collection_name, filter)
+            .await
+            .map_err(ErrorInterceptor::from),
+    };
+
+    match app {
+        Ok(Some(mut app)) => {
+            // Decrypt the datasource descriptions with the data key of the app
+            if let Err(e) = app_state.decrypt_fields(&app_name, &mut app).await {
+                let error_message = format!("Failed to decrypt app '{}'. Error: {}", app_name, e);
+                error!(
+                    app_name = app_name,
+                    ext_message = error_message,
+                    message = error_message
+                );
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"status": "error", "message": error_message})),
+                ));
+            }
+            // Show the PII tags on the columns, the row filters on the tables and the labels on the sources, next to
+            // their stored lists
+            merge_column_classifications(&mut app);
+            merge_row_filters(&mut app);
+            merge_source_labels(&mut app);
+            // `create_timestamp` is stored as a BSON date
+            serve_timestamps(&mut app);
+            // Apps never paused have no ingestion control stored
+            let ingestion_requested = fields.as_ref().map_or(true, |f| f.contains("ingestion"));
+            if let Some(app) = app.as_object_mut().filter(|_| ingestion_requested) {
+                app.entry("ingestion")
+                    .or_insert_with(|| json!(IngestionControl::default()));
+            }
+            // The readiness is computed from the ingestion status of the sources, it is omitted if it can't be read
+            let readiness_requested = fields.as_ref().map_or(true, |f| f.contains("readiness"));
+            if readiness_requested {
+                match app_readiness(&app_state, &app_state.apps(), &app_name).await {
+                    Ok(readiness) => {
+                        if let Some(app) = app.as_object_mut() {
+                            app.insert("readiness".to_string(), json!(readiness));
+                        }
+                    }
+                    Err(e) => error!(app_name = app_name, message = e.to_string()),
+                }
+            }
             let success_message = format!("{} retrieved successfully.", app_name);
             info!(app_name = app_name, message = success_message);
             Ok(Json(
@@ -71,36 +154,10 @@ pub async fn get_app(
         }
         Err(e) => {
             let error_message = format!("Failed to retrieve app '{}'. Error: {}", app_name, e);
-            let ref_id = create_ref_id();
-            let service_type = "GetApp".to_string();
-            let task_id = create_task_id(&app_name, service_type);
-            let mongo_url = app_state.app_settings.mongo_db.mongo_db_url.clone();
-            let mongo_db_name = app_state
-                .app_settings
-                .mongo_db
-                .mongo_db_database_name
-                .clone();
-            let id_collection = app_state
-                .app_settings
-                .mongo_db
-                .mongo_db_id_collection
-                .clone();
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.clone(),
-                task_id.clone(),
-                ref_id.clone(),
-            )
-            .await;
-            let ext_message = format!(
-                "{} Use reference ID: {}",
-                app_state.app_settings.general_message, ref_id
-            );
+            let ext_message = ctx.ext_message(&app_state);
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
@@ -124,7 +181,13 @@ mod tests {
             let app_name = "app100".to_string();
 
             // Call the function
-            let result = get_app(Path(app_name), State(app_state)).await;
+            let result = get_app(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                Query(QueryParams::default()),
+                State(app_state),
+            )
+            .await;
 
             // Check if the function returns Ok
             assert!(result.is_ok());
@@ -141,7 +204,13 @@ mod tests {
             let app_name = "non_existent_app".to_string();
 
             // Call the function
-            let result = get_app(Path(app_name), State(app_state.clone())).await;
+            let result = get_app(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                Query(QueryParams::default()),
+                State(app_state.clone()),
+            )
+            .await;
 
             // If the function returns Err, check the status code and message
             let (status_code, Json(message)) = result.err().unwrap();
@@ -156,6 +225,39 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_failure_get_app_invalid_fields() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+
+            // Call the function
+            let result = get_app(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                Query(QueryParams {
+                    fields: Some("app_name,$where".to_string()),
+                    ..Default::default()
+                }),
+                State(app_state),
+            )
+            .await;
+
+            // If the function returns Err, check the status code and message
+            let (status_code, Json(message)) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+            assert!(message
+                .get("message")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .contains("Invalid field '$where'"));
+        });
+    }
+
     #[test]
     #[ignore = "until get_document returns an error"]
     fn test_failure_get_app() {
@@ -167,7 +269,13 @@ mod tests {
             let app_name = "app100".to_string();
 
             // Call the function
-            let result = get_app(Path(app_name), State(app_state.clone())).await;
+            let result = get_app(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                Query(QueryParams::default()),
+                State(app_state.clone()),
+            )
+            .await;
 
             // If the function returns Err, check the status code and message
             let (status_code, Json(message)) = result.err().unwrap();
@@ -12,8 +12,11 @@
 //! The handler returns a 400 status code if an error occurs while fetching the logging data.
 //! The handler returns a 500 status code if an error occurs while fetching the logging data.
 //! The handler returns a JSON response with the status and message.
+//! The call to the logging microservice carries the remaining deadline of the request, see `deadline`.
 //!
 
+use crate::service::ctx::Ctx;
+use crate::service::deadline::with_deadline;
 use crate::service::state::AppState;
 use axum::body::Body;
 use axum::http::Request;
@@ -52,6 +55,7 @@ const METRIC_CALLS_ENDPOINT: &str = "api/all-logs/";
 )]
 #[instrument(skip_all)]
 pub async fn get_logs(
+    ctx: Ctx,
     State(app_state): State<Arc<AppState>>,
     request: Request<Body>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
@@ -74,12 +78,14 @@ pub async fn get_logs(
         "Making a Get request to the log microservice at URL: {}",
         url
     );
-    let client = reqwest::Client::new();
+    let client = app_state.http_clients.for_url(&url);
 
-    let response = client
-        .get(url)
-        .header("accept", "application/json")
-        .send()
+    let response = app_state
+        .http_clients
+        .send(with_deadline(
+            client.get(url).header("accept", "application/json"),
+            ctx.deadline.as_ref(),
+        ))
         .await;
 
     match response {
@@ -121,7 +127,12 @@ mod tests {
                 .body(Body::empty())
                 .unwrap();
             // Call the function
-            let result = get_logs(State(app_state), request).await;
+            let result = get_logs(
+                Ctx::new(&app_state, "test_app", "Test"),
+                State(app_state),
+                request,
+            )
+            .await;
 
             // Check that the result is as expected
             assert!(result.is_ok());
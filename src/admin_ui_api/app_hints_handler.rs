@@ -0,0 +1,390 @@
+/*
+ * Created Date:  Jul 14, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET, POST, PUT and DELETE handlers for the hints of the filestores of an app, so the
+//! prefix descriptions can be managed after onboarding without resubmitting the whole datasource map.
+//! The handlers are mounted at `/api/v1.1/admin/apps/{app_name}/hints`.
+//! The GET handler lists the hints of every filestore of the app.
+//! The POST handler adds a hint, the PUT handler replaces the descriptions of a hint and the DELETE handler removes
+//! a hint identified by the `source_type`, `url` and `prefix` query parameters. The changed filestore is stored with
+//! its hint descriptions encrypted, then the change is published to the hint change Kafka topic so the ingestion
+//! pipeline re-summarizes the prefix.
+//! The handlers return a 200 status code if the hints are fetched/changed successfully.
+//! The handlers return a 400 status code if the hint is invalid.
+//! The handlers return a 404 status code if the app, the filestore or the hint is not found.
+//! The handlers return a 409 status code if a hint with the same prefix already exists.
+//! The handlers return a 500 status code if an error occurs while fetching/storing/publishing the hints.
+//!
+
+use crate::admin_ui_api::schema::{HintKeyParams, UpdateResponse};
+use crate::service::ctx::Ctx;
+use crate::service::encryption::EncryptionError;
+use crate::service::filestore_hint::{
+    apply_hint_change, filestore_hints, FileStoreHint, HintChange, HintChangeAction,
+};
+use crate::service::publish_to_kafka::app_hint_change_notify_kafka;
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, to_bson, Document};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// GET handler to list the hints of the filestores of an app.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/hints",
+    responses(
+        (status = 200, description = "Hints retrieved successfully.", body = [FileStoreHint]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_hints_handler(
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let filestores = app_state.apps().filestores(&app_name).await?;
+    let hints = filestore_hints(&filestores);
+    let success_message = format!("Hints of '{}' retrieved successfully.", app_name);
+    info!(app_name = app_name, message = success_message);
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "data": hints}),
+    ))
+}
+
+/// POST handler to add a hint to a filestore of an app.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/apps/{app_name}/hints",
+    request_body = FileStoreHint,
+    responses(
+        (status = 200, description = "Hint added successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App or filestore not found", body = [ErrorResponse]),
+        (status = StatusCode::CONFLICT, description = "Hint already exists", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_hint_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(hint): Json<FileStoreHint>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    hint.validate()?;
+    change_hint(&ctx, &app_state, app_name, HintChangeAction::Added, hint).await
+}
+
+/// PUT handler to replace the descriptions of a hint of a filestore of an app.
+#[utoipa::path(
+    put,
+    path = "/api/v1.1/admin/apps/{app_name}/hints",
+    request_body = FileStoreHint,
+    responses(
+        (status = 200, description = "Hint updated successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App, filestore or hint not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn put_hint_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(hint): Json<FileStoreHint>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    hint.validate()?;
+    change_hint(&ctx, &app_state, app_name, HintChangeAction::Updated, hint).await
+}
+
+/// DELETE handler to remove a hint of a filestore of an app.
+#[utoipa::path(
+    delete,
+    path = "/api/v1.1/admin/apps/{app_name}/hints",
+    params(
+        ("source_type" = inline(String), Query, description = "source type of the filestore, e.g. s3."),
+        ("url" = inline(String), Query, description = "url of the filestore."),
+        ("prefix" = inline(String), Query, description = "prefix of the hint.")
+    ),
+    responses(
+        (status = 200, description = "Hint deleted successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App, filestore or hint not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn delete_hint_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    Query(params): Query<HintKeyParams>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let hint = FileStoreHint {
+        source_type: params.source_type,
+        url: params.url,
+        prefix: params.prefix,
+        descriptions: String::new(),
+    };
+    hint.validate_key()?;
+    change_hint(&ctx, &app_state, app_name, HintChangeAction::Deleted, hint).await
+}
+
+/// Applies a hint change to the filestore of an app, stores the filestore and publishes the change to Kafka.
+async fn change_hint(
+    ctx: &Ctx,
+    app_state: &Arc<AppState>,
+    app_name: String,
+    action: HintChangeAction,
+    hint: FileStoreHint,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let mut filestores = app_state.apps().filestores(&app_name).await?;
+    let changed_hint = apply_hint_change(&mut filestores, action, &hint)?;
+
+    let action_message = match action {
+        HintChangeAction::Added => "Hint added",
+        HintChangeAction::Updated => "Hint updated",
+        HintChangeAction::Deleted => "Hint deleted",
+    };
+
+    // Only the filestores of the source type of the hint are written back
+    let mut source_filestores = filestores.remove(&hint.source_type).unwrap_or_default();
+    let filter = doc! {"app_name": &app_name};
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let error_message = match encrypt_hints(app_state, &app_name, &mut source_filestores).await {
+        Ok(()) => match to_bson(&source_filestores) {
+            Ok(filestores_bson) => {
+                let mut fields = Document::new();
+                fields.insert(
+                    format!("app_datasource.filestore.{}", hint.source_type),
+                    filestores_bson,
+                );
+                match app_state
+                    .db
+                    .update_document(collection_name, filter, fields)
+                    .await
+                    .map_err(ErrorInterceptor::from)
+                {
+                    Ok(json_result) => {
+                        match serde_json::from_value::<UpdateResponse>(json_result) {
+                            Ok(result) if result.matchedCount == 0 => {
+                                let error_message =
+                                    format!("No app found with name '{}'.", app_name);
+                                debug!(message = error_message);
+                                return Err((
+                                    StatusCode::NOT_FOUND,
+                                    Json(json!({"status": "error", "message": error_message})),
+                                ));
+                            }
+                            Ok(_) => None,
+                            Err(e) => Some(format!(
+                                "Failed to deserialize update response. Error: {:?}",
+                                e
+                            )),
+                        }
+                    }
+                    Err(e) => Some(format!(
+                        "Failed to update hints of app '{}'. Error: {}",
+                        app_name, e
+                    )),
+                }
+            }
+            Err(e) => Some(format!(
+                "Failed to serialize filestores to BSON. Error: {}",
+                e
+            )),
+        },
+        Err(e) => Some(format!(
+            "Failed to encrypt hint descriptions of app '{}'. Error: {}",
+            app_name, e
+        )),
+    };
+    if let Some(error_message) = error_message {
+        let ext_message = ctx.ext_message(app_state);
+        error!(
+            app_name = app_name,
+            task_id = ctx.task_id,
+            ext_message = ext_message,
+            message = error_message
+        );
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    // Publish once stored, the re-summarization reads the stored hints
+    let change = HintChange {
+        action,
+        source_type: hint.source_type.clone(),
+        url: hint.url.clone(),
+        hint: changed_hint,
+    };
+    app_hint_change_notify_kafka(app_state, &app_name, &change, ctx.task_id.clone()).await?;
+
+    let success_message = format!(
+        "{} for prefix '{}' of '{}'.",
+        action_message, hint.prefix, app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    // The descriptions may be encrypted at rest, keep them out of the audit trail
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = action_message,
+        details = json!({"source_type": hint.source_type, "url": hint.url, "prefix": hint.prefix})
+            .to_string(),
+        message = success_message
+    );
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "app_name": app_name,
+        "hint": change
+    })))
+}
+
+/// Encrypts, in place, the hint descriptions of the stored filestores of a source type.
+async fn encrypt_hints(
+    app_state: &Arc<AppState>,
+    app_name: &str,
+    filestores: &mut serde_json::Value,
+) -> Result<(), EncryptionError> {
+    let hints = filestores
+        .as_array_mut()
+        .into_iter()
+        .flatten()
+        .filter_map(|filestore| filestore.get_mut("hints"))
+        .filter_map(serde_json::Value::as_array_mut)
+        .flatten();
+    for hint in hints {
+        if let Some(descriptions) = hint.get("descriptions").and_then(|d| d.as_str()) {
+            let encrypted = app_state.encrypt_field(app_name, descriptions).await?;
+            hint["descriptions"] = json!(encrypted);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    fn hint(descriptions: &str) -> FileStoreHint {
+        FileStoreHint {
+            source_type: "s3".to_string(),
+            url: "s3://tresleai-dev-unittest/*".to_string(),
+            prefix: "s3://tresleai-dev-unittest/*-hint-3".to_string(),
+            descriptions: descriptions.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_failure_get_hints_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let result = get_hints_handler(Path(app_name), State(app_state)).await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+
+    #[test]
+    fn test_failure_post_hint_handler_invalid_hint() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+
+            // Call the function
+            let result = post_hint_handler(
+                Ctx::new(&app_state, "app100", "Test"),
+                Path(app_name),
+                State(app_state),
+                Json(hint(" ")),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn test_failure_put_hint_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let result = put_hint_handler(
+                Ctx::new(&app_state, "non-existing-app", "Test"),
+                Path(app_name),
+                State(app_state),
+                Json(hint("zzz")),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+
+    #[test]
+    fn test_failure_delete_hint_handler_missing_prefix() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+            let params = HintKeyParams {
+                source_type: "s3".to_string(),
+                url: "s3://tresleai-dev-unittest/*".to_string(),
+                prefix: String::new(),
+            };
+
+            // Call the function
+            let result = delete_hint_handler(
+                Ctx::new(&app_state, "app100", "Test"),
+                Path(app_name),
+                Query(params),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+}
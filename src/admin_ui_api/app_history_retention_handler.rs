@@ -0,0 +1,411 @@
+/*
+ * Created Date:  Jul 23, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the handlers for the history retention of an app and its legal hold.
+//! The handlers are mounted at `/api/v1.1/admin/apps/{app_name}/history-retention`.
+//! The GET handler returns the retention override, the effective retention, the legal hold and the last changes of
+//! the legal hold.
+//! The PUT handler sets the retention override of the app, a `null` retention falls back to the default retention.
+//! The POST and DELETE handlers of `/holds` add reference IDs or end users to the legal hold and remove them from it.
+//! Every change of the legal hold is recorded in the hold audit collection and sent to the audit microservice.
+//! The handlers return a 200 status code if the retention is fetched/updated successfully.
+//! The handlers return a 400 status code if the retention or the legal hold change is invalid.
+//! The handlers return a 404 status code if the app is not found.
+//! The handlers return a 500 status code if an error occurs while fetching/updating the retention.
+//!
+
+use crate::admin_ui_api::schema::{HistoryRetentionRequest, UpdateResponse};
+use crate::service::ctx::Ctx;
+use crate::service::history_retention::{
+    record_hold_change, validate_retention_days, HistoryRetention, HistoryRetentionOptions,
+    HoldChange, HoldChangeAction, LegalHold, HISTORY_RETENTION_FIELD,
+};
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::Utc;
+use mongodb::bson::{doc, to_bson};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// Number of legal hold changes returned by the GET handler.
+const HOLD_CHANGES_LIMIT: i64 = 100;
+
+/// GET handler to get the history retention of an app and the last changes of its legal hold.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/history-retention",
+    responses(
+        (status = 200, description = "History retention retrieved successfully.", body = HistoryRetention),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_history_retention_handler(
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let retention = find_history_retention(&app_state, &app_name).await?;
+    let options = app_state.options::<HistoryRetentionOptions>();
+    let pipeline = vec![
+        doc! {"$match": {"app_name": &app_name}},
+        doc! {"$sort": {"_id": -1}},
+        doc! {"$limit": HOLD_CHANGES_LIMIT},
+        doc! {"$project": {"_id": 0}},
+    ];
+    let hold_changes = app_state
+        .db
+        .aggregate(
+            &options.hold_audit_collection,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await?;
+
+    let success_message = format!(
+        "History retention of '{}' retrieved successfully.",
+        app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "data": {
+            "retention_days": retention.retention_days,
+            "effective_retention_days": options.retention_days(Some(&retention)),
+            "legal_hold": retention.legal_hold,
+            "hold_changes": hold_changes
+        }
+    })))
+}
+
+/// PUT handler to set the history retention override of an app.
+#[utoipa::path(
+    put,
+    path = "/api/v1.1/admin/apps/{app_name}/history-retention",
+    request_body = HistoryRetentionRequest,
+    responses(
+        (status = 200, description = "History retention updated successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn put_history_retention_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(body): Json<HistoryRetentionRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    validate_retention_days(body.retention_days)?;
+    let mut retention = find_history_retention(&app_state, &app_name).await?;
+    retention.retention_days = body.retention_days;
+    store_history_retention(&ctx, &app_state, &app_name, &retention).await?;
+
+    let success_message = format!("History retention of '{}' updated successfully.", app_name);
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "History retention updated",
+        details = json!(body).to_string(),
+        message = success_message
+    );
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "app_name": app_name}),
+    ))
+}
+
+/// POST handler to put reference IDs or end users of an app on legal hold.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/apps/{app_name}/history-retention/holds",
+    request_body = LegalHold,
+    responses(
+        (status = 200, description = "Legal hold set successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_legal_hold_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(hold): Json<LegalHold>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    change_legal_hold(&ctx, &app_state, app_name, HoldChangeAction::Set, hold).await
+}
+
+/// DELETE handler to release reference IDs or end users of an app from legal hold.
+#[utoipa::path(
+    delete,
+    path = "/api/v1.1/admin/apps/{app_name}/history-retention/holds",
+    request_body = LegalHold,
+    responses(
+        (status = 200, description = "Legal hold cleared successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn delete_legal_hold_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(hold): Json<LegalHold>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    change_legal_hold(&ctx, &app_state, app_name, HoldChangeAction::Cleared, hold).await
+}
+
+/// Applies a change to the legal hold of an app, stores it and records the change in the hold audit collection.
+async fn change_legal_hold(
+    ctx: &Ctx,
+    app_state: &Arc<AppState>,
+    app_name: String,
+    action: HoldChangeAction,
+    hold: LegalHold,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    hold.validate()?;
+    let mut retention = find_history_retention(app_state, &app_name).await?;
+    let action_message = match action {
+        HoldChangeAction::Set => {
+            retention.legal_hold.add(&hold);
+            "Legal hold set"
+        }
+        HoldChangeAction::Cleared => {
+            retention.legal_hold.remove(&hold);
+            "Legal hold cleared"
+        }
+    };
+    store_history_retention(ctx, app_state, &app_name, &retention).await?;
+
+    let change = HoldChange {
+        app_name: app_name.clone(),
+        task_id: ctx.task_id.clone(),
+        action,
+        reference_ids: hold.reference_ids,
+        user_ids: hold.user_ids,
+        timestamp: Utc::now().to_rfc3339(),
+    };
+    // The hold is stored, a failure to record the change is logged
+    if let Err(e) = record_hold_change(app_state, &change).await {
+        error!(
+            app_name = app_name,
+            task_id = ctx.task_id,
+            message = format!("Failed to record the legal hold change. Error: {}", e)
+        );
+    }
+
+    let success_message = format!("{} for '{}'.", action_message, app_name);
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = action_message,
+        details =
+            json!({"reference_ids": change.reference_ids, "user_ids": change.user_ids}).to_string(),
+        message = success_message
+    );
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "app_name": app_name,
+        "legal_hold": retention.legal_hold
+    })))
+}
+
+/// Returns the history retention of an app, the default one if unset. Unknown apps are answered with a 404.
+async fn find_history_retention(
+    app_state: &AppState,
+    app_name: &str,
+) -> Result<HistoryRetention, (StatusCode, Json<serde_json::Value>)> {
+    let apps = app_state.apps();
+    if !apps.exists(app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        debug!(message = error_message);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+    Ok(apps.history_retention(app_name).await?.unwrap_or_default())
+}
+
+/// Stores the history retention of an app on its app document.
+async fn store_history_retention(
+    ctx: &Ctx,
+    app_state: &AppState,
+    app_name: &str,
+    retention: &HistoryRetention,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let filter = doc! {"app_name": app_name};
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let error_message = match to_bson(retention) {
+        Ok(retention_bson) => match app_state
+            .db
+            .update_document(
+                collection_name,
+                filter,
+                doc! {HISTORY_RETENTION_FIELD: retention_bson},
+            )
+            .await
+            .map_err(ErrorInterceptor::from)
+        {
+            Ok(json_result) => match serde_json::from_value::<UpdateResponse>(json_result) {
+                Ok(result) if result.matchedCount == 0 => {
+                    let error_message = format!("No app found with name '{}'.", app_name);
+                    debug!(message = error_message);
+                    return Err((
+                        StatusCode::NOT_FOUND,
+                        Json(json!({"status": "error", "message": error_message})),
+                    ));
+                }
+                Ok(_) => None,
+                Err(e) => Some(format!(
+                    "Failed to deserialize update response. Error: {:?}",
+                    e
+                )),
+            },
+            Err(e) => Some(format!(
+                "Failed to update history retention of app '{}'. Error: {}",
+                app_name, e
+            )),
+        },
+        Err(e) => Some(format!(
+            "Failed to serialize history retention to BSON. Error: {}",
+            e
+        )),
+    };
+    if let Some(error_message) = error_message {
+        let ext_message = ctx.ext_message(app_state);
+        error!(
+            app_name = app_name,
+            task_id = ctx.task_id,
+            ext_message = ext_message,
+            message = error_message
+        );
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_put_history_retention_handler_invalid_retention() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+
+            // Call the function
+            let result = put_history_retention_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                State(app_state),
+                Json(HistoryRetentionRequest {
+                    retention_days: Some(0),
+                }),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn test_failure_post_legal_hold_handler_empty_hold() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+
+            // Call the function
+            let result = post_legal_hold_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                State(app_state),
+                Json(LegalHold::default()),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn test_failure_delete_legal_hold_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+            let hold = LegalHold {
+                reference_ids: vec!["14b1456d-2708-45bc-8989-eac2d2eba4db".to_string()],
+                user_ids: vec![],
+            };
+
+            // Call the function
+            let result = delete_legal_hold_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                State(app_state),
+                Json(hold),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+
+    #[test]
+    fn test_failure_get_history_retention_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let result = get_history_retention_handler(Path(app_name), State(app_state)).await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
@@ -0,0 +1,216 @@
+/*
+ * Created Date:  Jul 05, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the POST handlers to pause and resume the ingestion of an app, so the operators can halt a
+//! runaway ingestion without deleting the app.
+//! The handlers are mounted at `/api/v1.1/admin/apps/{app_name}/ingestion/pause` and
+//! `/api/v1.1/admin/apps/{app_name}/ingestion/resume`.
+//! The handlers publish the requested ingestion state to the ingestion control Kafka topic, then store it on the app
+//! document.
+//! The handlers return a 200 status code if the ingestion is paused/resumed successfully.
+//! The handlers return a 404 status code if the app is not found.
+//! The handlers return a 500 status code if an error occurs while publishing/storing the ingestion state.
+//!
+
+use crate::admin_ui_api::schema::UpdateResponse;
+use crate::service::ctx::Ctx;
+use crate::service::ingestion_control::{IngestionControl, IngestionState};
+use crate::service::publish_to_kafka::app_ingestion_control_notify_kafka;
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::Utc;
+use mongodb::bson::{doc, to_bson};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// POST handler to pause the ingestion of an app.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/apps/{app_name}/ingestion/pause",
+    responses(
+        (status = 200, description = "Ingestion paused successfully."),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_pause_ingestion_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    set_ingestion_state(&ctx, &app_state, app_name, IngestionState::Paused).await
+}
+
+/// POST handler to resume the ingestion of an app.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/apps/{app_name}/ingestion/resume",
+    responses(
+        (status = 200, description = "Ingestion resumed successfully."),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_resume_ingestion_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    set_ingestion_state(&ctx, &app_state, app_name, IngestionState::Running).await
+}
+
+/// Publishes the ingestion state of an app to Kafka and stores it on the app document.
+async fn set_ingestion_state(
+    ctx: &Ctx,
+    app_state: &Arc<AppState>,
+    app_name: String,
+    state: IngestionState,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    if !app_state.apps().exists(&app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    let action = match state {
+        IngestionState::Paused => "Ingestion paused",
+        IngestionState::Running => "Ingestion resumed",
+    };
+
+    // Notify the ingestion pipeline first, the stored state must not claim a pause the pipeline never received
+    app_ingestion_control_notify_kafka(app_state, &app_name, state, ctx.task_id.clone()).await?;
+
+    let ingestion_control = IngestionControl {
+        state,
+        updated_at: Some(Utc::now().to_rfc3339()),
+    };
+    let filter = doc! {"app_name": &app_name};
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let error_message = match to_bson(&ingestion_control) {
+        Ok(ingestion_bson) => match app_state
+            .db
+            .update_document(collection_name, filter, doc! {"ingestion": ingestion_bson})
+            .await
+            .map_err(ErrorInterceptor::from)
+        {
+            Ok(json_result) => match serde_json::from_value::<UpdateResponse>(json_result) {
+                Ok(result) if result.matchedCount == 0 => {
+                    let error_message = format!("No app found with name '{}'.", app_name);
+                    debug!(message = error_message);
+                    return Err((
+                        StatusCode::NOT_FOUND,
+                        Json(json!({"status": "error", "message": error_message})),
+                    ));
+                }
+                Ok(_) => None,
+                Err(e) => Some(format!(
+                    "Failed to deserialize update response. Error: {:?}",
+                    e
+                )),
+            },
+            Err(e) => Some(format!(
+                "Failed to update ingestion state of app '{}'. Error: {}",
+                app_name, e
+            )),
+        },
+        Err(e) => Some(format!(
+            "Failed to serialize ingestion state to BSON. Error: {}",
+            e
+        )),
+    };
+    if let Some(error_message) = error_message {
+        let ext_message = ctx.ext_message(app_state);
+        error!(
+            app_name = app_name,
+            task_id = ctx.task_id,
+            ext_message = ext_message,
+            message = error_message
+        );
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    let success_message = format!("{} for '{}'.", action, app_name);
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = action,
+        details = json!(ingestion_control).to_string(),
+        message = success_message
+    );
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "app_name": app_name,
+        "ingestion_state": state
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_post_pause_ingestion_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let result = post_pause_ingestion_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+
+    #[test]
+    fn test_failure_post_resume_ingestion_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let result = post_resume_ingestion_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
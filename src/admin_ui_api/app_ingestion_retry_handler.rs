@@ -0,0 +1,237 @@
+/*
+ * Created Date:  Jul 29, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the handlers for the automatic ingestion retry policy of an app (see
+//! `crate::service::ingestion_retry`).
+//! The handlers are mounted at `/api/v1.1/admin/apps/{app_name}/ingestion/retry-policy`.
+//! The GET handler returns the retry policy of the app, the disabled default policy if unset, with the counts of its
+//! failed sources by retry status.
+//! The PUT handler sets the retry policy of the app. The excluded file types are stored lowercase, without leading dot.
+//! The handlers return a 200 status code if the policy is fetched/updated successfully.
+//! The handlers return a 400 status code if the policy is invalid.
+//! The handlers return a 404 status code if the app is not found.
+//! The handlers return a 500 status code if an error occurs while fetching/updating the policy.
+//!
+
+use crate::admin_ui_api::schema::UpdateResponse;
+use crate::service::ctx::Ctx;
+use crate::service::ingestion_retry::{retry_counts, IngestionRetryPolicy, INGESTION_RETRY_FIELD};
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, to_bson};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// GET handler to get the ingestion retry policy of an app and the counts of its failed sources.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/ingestion/retry-policy",
+    responses(
+        (status = 200, description = "Ingestion retry policy retrieved successfully.", body = IngestionRetryPolicy),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_ingestion_retry_policy_handler(
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let apps = app_state.apps();
+    if !apps.exists(&app_name).await? {
+        return Err(app_not_found(&app_name));
+    }
+    let policy = apps
+        .ingestion_retry_policy(&app_name)
+        .await?
+        .unwrap_or_default();
+    let counts = retry_counts(&app_state, &app_name).await?;
+
+    let success_message = format!(
+        "Ingestion retry policy of '{}' retrieved successfully.",
+        app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "data": {
+            "policy": policy,
+            "retry_counts": counts
+        }
+    })))
+}
+
+/// PUT handler to set the ingestion retry policy of an app.
+#[utoipa::path(
+    put,
+    path = "/api/v1.1/admin/apps/{app_name}/ingestion/retry-policy",
+    request_body = IngestionRetryPolicy,
+    responses(
+        (status = 200, description = "Ingestion retry policy updated successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn put_ingestion_retry_policy_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(body): Json<IngestionRetryPolicy>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let policy = body.validated()?;
+    if !app_state.apps().exists(&app_name).await? {
+        return Err(app_not_found(&app_name));
+    }
+    store_ingestion_retry_policy(&ctx, &app_state, &app_name, &policy).await?;
+
+    let success_message = format!(
+        "Ingestion retry policy of '{}' updated successfully.",
+        app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "Ingestion retry policy updated",
+        details = json!(policy).to_string(),
+        message = success_message
+    );
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "app_name": app_name,
+        "policy": policy
+    })))
+}
+
+fn app_not_found(app_name: &str) -> (StatusCode, Json<serde_json::Value>) {
+    let error_message = format!("No app found with name '{}'.", app_name);
+    debug!(app_name = app_name, message = error_message);
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({"status": "error", "message": error_message})),
+    )
+}
+
+/// Stores the ingestion retry policy of an app on its app document.
+async fn store_ingestion_retry_policy(
+    ctx: &Ctx,
+    app_state: &AppState,
+    app_name: &str,
+    policy: &IngestionRetryPolicy,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let filter = doc! {"app_name": app_name};
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let error_message = match to_bson(policy) {
+        Ok(policy_bson) => match app_state
+            .db
+            .update_document(
+                collection_name,
+                filter,
+                doc! {INGESTION_RETRY_FIELD: policy_bson},
+            )
+            .await
+            .map_err(ErrorInterceptor::from)
+        {
+            Ok(json_result) => match serde_json::from_value::<UpdateResponse>(json_result) {
+                Ok(result) if result.matchedCount == 0 => return Err(app_not_found(app_name)),
+                Ok(_) => None,
+                Err(e) => Some(format!(
+                    "Failed to deserialize update response. Error: {:?}",
+                    e
+                )),
+            },
+            Err(e) => Some(format!(
+                "Failed to update ingestion retry policy of app '{}'. Error: {}",
+                app_name, e
+            )),
+        },
+        Err(e) => Some(format!(
+            "Failed to serialize ingestion retry policy to BSON. Error: {}",
+            e
+        )),
+    };
+    if let Some(error_message) = error_message {
+        let ext_message = ctx.ext_message(app_state);
+        error!(
+            app_name = app_name,
+            task_id = ctx.task_id,
+            ext_message = ext_message,
+            message = error_message
+        );
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_put_ingestion_retry_policy_handler_invalid_policy() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+            let policy = IngestionRetryPolicy {
+                enabled: true,
+                max_attempts: 0,
+                ..IngestionRetryPolicy::default()
+            };
+
+            // Call the function
+            let result = put_ingestion_retry_policy_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                State(app_state),
+                Json(policy),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn test_failure_get_ingestion_retry_policy_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let result = get_ingestion_retry_policy_handler(
+                Path("app_not_onboarded".to_string()),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
@@ -0,0 +1,88 @@
+/*
+ * Created Date:  Jul 29, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET handler for the ingestion SLA of an app, answering "when will my new files be
+//! searchable?": the latency from each recent datasource publish to the first knowledge node of each new source,
+//! the sources breaching the SLA target and the latency percentiles (see `crate::service::ingestion_sla`).
+//! The handler is mounted at `/api/v1.1/admin/apps/{app_name}/ingestion/sla`.
+//! The handler returns a 200 status code if the ingestion SLA is fetched successfully.
+//! The handler returns a 404 status code if the app is not found.
+//! The handler returns a 500 status code if an error occurs while reading the publishes or the knowledge nodes.
+//!
+
+use crate::service::ingestion_sla::app_ingestion_sla;
+use crate::service::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, info, instrument};
+
+/// GET handler to fetch the ingestion SLA of an app.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/ingestion/sla",
+    responses(
+        (status = 200, description = "Ingestion SLA fetched successfully.", body = IngestionSlaReport),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_ingestion_sla_handler(
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    if !app_state.apps().exists(&app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        debug!(app_name = app_name, message = error_message);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    let report = app_ingestion_sla(&app_state, &app_name).await?;
+    let success_message = format!(
+        "Ingestion SLA of app '{}' fetched successfully: {} of {} tracked source(s) breaching the {} minute target.",
+        app_name,
+        report.stats.breached_sources,
+        report.stats.tracked_sources,
+        report.target_minutes
+    );
+    info!(app_name = app_name, message = success_message);
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "data": report}),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_get_ingestion_sla_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let result =
+                get_ingestion_sla_handler(Path("app_not_onboarded".to_string()), State(app_state))
+                    .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
@@ -0,0 +1,395 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the handlers for the additional API keys of an app, e.g. one per consuming service.
+//! The handlers are mounted at `/api/v1.1/admin/apps/{app_name}/keys`.
+//! The GET handler returns the additional keys of the app, without the keys themselves, with the ID and the expiry of
+//! its primary key.
+//! The POST handler creates a labelled key, with an optional expiry date, and returns it. The key is only returned
+//! here. Outside the internal API key mode the key is created in API Gateway and associated with the usage plan of
+//! the tier of the app.
+//! The DELETE handler of `/{key_id}` revokes an additional key. The primary key of the app is only deleted with the
+//! app.
+//! The PUT handler of `/{key_id}/expiry` sets or clears the expiry date of a key, primary or additional, and resets its
+//! expiry warning. A deactivated key keeps its expiry, a new key must be created instead.
+//! The handlers return a 200 status code if the keys are fetched/updated successfully.
+//! The handlers return a 400 status code if the label or the expiry date is invalid.
+//! The handlers return a 404 status code if the app or the key is not found.
+//! The handlers return a 409 status code if a key with the same label already exists, or if the key is deactivated.
+//! The handlers return a 500 status code if an error occurs while creating/storing/deleting the keys.
+//!
+
+use crate::admin_ui_api::app_delete_handler::delete_api_key;
+use crate::onboarding::create_api_key::create_api_key;
+use crate::onboarding::update_api_key_usage::update_api_key_with_usage_plan;
+use crate::service::app_keys::{
+    store_app_keys, store_key_expiry_fields, AppKey, AppKeyError, AppKeySummary,
+    CreateAppKeyRequest, KeyExpiry, KeyExpiryRequest,
+};
+use crate::service::ctx::Ctx;
+use crate::service::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::Utc;
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{error, info, instrument};
+
+/// GET handler to get the additional API keys of an app.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/keys",
+    responses(
+        (status = 200, description = "API keys retrieved successfully.", body = [AppKeySummary]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_app_keys_handler(
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let apps = app_state.apps();
+    let primary_key = apps.api_key(&app_name).await?;
+    let primary_key_expiry = apps.primary_key_expiry(&app_name).await?;
+    let now = Utc::now();
+    let app_keys: Vec<AppKeySummary> = apps
+        .app_keys(&app_name)
+        .await?
+        .iter()
+        .map(|app_key| AppKeySummary::new(app_key, now))
+        .collect();
+
+    let success_message = format!("API keys of '{}' retrieved successfully.", app_name);
+    info!(app_name = app_name, message = success_message);
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "primary_key_id": primary_key.api_key_id,
+        "primary_key_expiry": primary_key_expiry,
+        "primary_key_active": primary_key_expiry.is_active(now),
+        "data": app_keys,
+    })))
+}
+
+/// POST handler to create an additional API key of an app.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/apps/{app_name}/keys",
+    request_body = CreateAppKeyRequest,
+    responses(
+        (status = 200, description = "API key created successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::CONFLICT, description = "An API key with the same label already exists", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn create_app_key_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(request): Json<CreateAppKeyRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let apps = app_state.apps();
+    // Unknown apps are answered with a 404
+    apps.api_key(&app_name).await?;
+    let mut app_keys = apps.app_keys(&app_name).await?;
+    let now = Utc::now();
+    request.validate(&app_keys, now)?;
+
+    let (api_key, key_id) = create_api_key(&app_state, &app_name, Some(&request.label)).await?;
+    let tier = apps.tier(&app_name).await?;
+    update_api_key_with_usage_plan(
+        &app_state,
+        key_id.clone(),
+        ctx.task_id.clone(),
+        &app_name,
+        tier.as_deref(),
+        false,
+    )
+    .await?;
+    let app_key = AppKey {
+        key_id: key_id.clone(),
+        label: request.label.clone(),
+        api_key: app_state.api_key_options().stored_api_key(&api_key),
+        created_at: now,
+        expiry: KeyExpiry {
+            expires_at: request.expires_at,
+            ..Default::default()
+        },
+        last_used_at: None,
+    };
+    app_keys.push(app_key.clone());
+    if let Err(error_message) = store_app_keys(&app_state, &app_name, &app_keys).await {
+        // The key is not stored, so it is not left enabled in API Gateway
+        delete_api_key(&ctx, &app_state, &app_name, &key_id).await?;
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    let success_message = format!(
+        "API key '{}' of '{}' created successfully.",
+        request.label, app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "API key created",
+        details = json!(AppKeySummary::new(&app_key, now)).to_string(),
+        message = success_message
+    );
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "app_name": app_name,
+        "key_id": key_id,
+        "api_key": api_key,
+        "data": AppKeySummary::new(&app_key, now),
+    })))
+}
+
+/// DELETE handler to revoke an additional API key of an app.
+#[utoipa::path(
+    delete,
+    path = "/api/v1.1/admin/apps/{app_name}/keys/{key_id}",
+    responses(
+        (status = 200, description = "API key revoked successfully."),
+        (status = StatusCode::NOT_FOUND, description = "App or API key not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn delete_app_key_handler(
+    ctx: Ctx,
+    Path((app_name, key_id)): Path<(String, String)>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let apps = app_state.apps();
+    apps.api_key(&app_name).await?;
+    let mut app_keys = apps.app_keys(&app_name).await?;
+    let Some(position) = app_keys.iter().position(|app_key| app_key.key_id == key_id) else {
+        return Err(AppKeyError::UnknownKey(key_id).into());
+    };
+    let app_key = app_keys.remove(position);
+
+    // The key stops resolving the app before it is deleted from API Gateway
+    if let Err(error_message) = store_app_keys(&app_state, &app_name, &app_keys).await {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+    if let Err(e) = delete_api_key(&ctx, &app_state, &app_name, &key_id).await {
+        error!(
+            app_name = app_name,
+            message = format!(
+                "API key '{}' of '{}' is revoked but not deleted from API Gateway.",
+                app_key.label, app_name
+            )
+        );
+        return Err(e);
+    }
+
+    let success_message = format!(
+        "API key '{}' of '{}' revoked successfully.",
+        app_key.label, app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "API key revoked",
+        details = json!(AppKeySummary::new(&app_key, Utc::now())).to_string(),
+        message = success_message
+    );
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "app_name": app_name}),
+    ))
+}
+
+/// PUT handler to set or clear the expiry date of an API key of an app, primary or additional.
+#[utoipa::path(
+    put,
+    path = "/api/v1.1/admin/apps/{app_name}/keys/{key_id}/expiry",
+    request_body = KeyExpiryRequest,
+    responses(
+        (status = 200, description = "API key expiry updated successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App or API key not found", body = [ErrorResponse]),
+        (status = StatusCode::CONFLICT, description = "The API key is deactivated", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn put_app_key_expiry_handler(
+    ctx: Ctx,
+    Path((app_name, key_id)): Path<(String, String)>,
+    State(app_state): State<Arc<AppState>>,
+    Json(request): Json<KeyExpiryRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let apps = app_state.apps();
+    let primary_key = apps.api_key(&app_name).await?;
+    // The primary key is addressed by its ID, its expiry stored apart from the additional keys
+    let (additional_key_id, expiry) = if primary_key.api_key_id == key_id {
+        (None, apps.primary_key_expiry(&app_name).await?)
+    } else {
+        let app_key = apps
+            .app_keys(&app_name)
+            .await?
+            .into_iter()
+            .find(|app_key| app_key.key_id == key_id)
+            .ok_or_else(|| AppKeyError::UnknownKey(key_id.clone()))?;
+        (Some(key_id.as_str()), app_key.expiry)
+    };
+    request.validate(&key_id, &expiry, Utc::now())?;
+    if let Err(error_message) = store_key_expiry_fields(
+        &app_state,
+        &app_name,
+        additional_key_id,
+        &[("expires_at", request.expires_at), ("warned_at", None)],
+    )
+    .await
+    {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    let success_message = format!(
+        "Expiry of API key '{}' of '{}' updated successfully.",
+        key_id, app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "API key expiry updated",
+        details = json!({"key_id": key_id, "expires_at": request.expires_at}).to_string(),
+        message = success_message
+    );
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "app_name": app_name,
+        "key_id": key_id,
+        "expires_at": request.expires_at,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_get_app_keys_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let result = get_app_keys_handler(Path(app_name), State(app_state)).await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+
+    #[test]
+    fn test_failure_create_app_key_handler_invalid_label() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+            let request = CreateAppKeyRequest {
+                label: "search service".to_string(),
+                expires_at: None,
+            };
+
+            // Call the function
+            let result = create_app_key_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                State(app_state),
+                Json(request),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn test_failure_put_app_key_expiry_handler_key_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+            let request = KeyExpiryRequest {
+                expires_at: Some(Utc::now() + chrono::Duration::days(30)),
+            };
+
+            // Call the function
+            let result = put_app_key_expiry_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path((app_name, "non-existing-key".to_string())),
+                State(app_state),
+                Json(request),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+
+    #[test]
+    fn test_failure_delete_app_key_handler_key_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+
+            // Call the function
+            let result = delete_app_key_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path((app_name, "non-existing-key".to_string())),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
@@ -0,0 +1,257 @@
+/*
+ * Created Date:  Jul 09, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET handler for fetching the full document of a single knowledge node of an app.
+//! The handler is mounted at `/api/v1.1/admin/nodes/{app_name}/detail`.
+//! Unlike the nodes listing, which only projects `indexed_at`, `source` and `total_page_num`, the handler returns
+//! every stored field of the node (fact phrases, summaries, ...) to power the node drill-down view of the admin UI.
+//! The node is looked up either by its source URI or by its node id. A source indexed more than once resolves to
+//! the latest indexed node.
+//! The optional `fields` query parameter restricts the returned node to the requested fields.
+//! The handler returns a 200 status code if the knowledge node is fetched successfully.
+//! The handler returns a 400 status code if neither or both of `source` and `node_id` are provided.
+//! The handler returns a 404 status code if the app or the knowledge node is not found.
+//! The handler returns a 500 status code if an error occurs while fetching the knowledge node.
+//!
+
+use crate::admin_ui_api::schema::KnowledgeNodeDetailParams;
+use crate::service::check_app_existence::check_app_existence;
+use crate::service::field_projection::FieldProjection;
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::state::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+use percent_encoding::percent_decode_str;
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, info, instrument};
+
+/// GET handler to fetch the full document of a knowledge node by source URI or node id.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/nodes/{app_name}/detail",
+    params(
+        (
+            "source" = inline(Option<String>),
+            Query,
+            description = "percent-encoded source URI of the knowledge node.",
+        ),
+        (
+            "node_id" = inline(Option<String>),
+            Query,
+            description = "id of the knowledge node.",
+        ),
+        (
+            "fields" = inline(Option<String>),
+            Query,
+            description = "comma separated fields of the node to return, the whole node by default.",
+        )
+    ),
+    responses(
+        (status = 200, description = "Knowledge node fetched successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App or knowledge node not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_knowledge_node_detail_handler(
+    Path(app_name): Path<String>,
+    Query(params): Query<KnowledgeNodeDetailParams>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let match_stage = node_match_stage(&params).map_err(|error_message| {
+        debug!(app_name = app_name, message = error_message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    })?;
+    let fields = FieldProjection::parse(params.fields.as_deref())?;
+
+    // Check if the app exists
+    if !check_app_existence(&app_state, &app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        debug!(message = error_message);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    let collection_name = format!("{}-general", app_name);
+    // The app specific collections are stored in the cluster of the app residency
+    let app_db = app_state.app_db(&app_name).await?;
+
+    // Keep every field of the node, only the ObjectId is turned into a plain string for the UI
+    let mut node_pipeline = vec![
+        doc! { "$match": match_stage },
+        doc! { "$sort": { "indexed_at": -1, "_id": -1 } },
+        doc! { "$limit": 1 },
+        doc! { "$addFields": { "_id": { "$toString": "$_id" } } },
+    ];
+    if let Some(fields) = &fields {
+        node_pipeline.push(doc! { "$project": fields.projection(&doc! {}) });
+    }
+    let node = app_db
+        .aggregate(
+            &collection_name,
+            node_pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await?
+        .into_iter()
+        .next();
+
+    match node {
+        Some(node) => {
+            let success_message = format!(
+                "Knowledge node fetched successfully for app '{}'.",
+                app_name
+            );
+            info!(app_name = app_name, message = success_message);
+            Ok(Json(
+                json!({"status": "success", "message": success_message, "node": node}),
+            ))
+        }
+        None => {
+            let error_message = format!("No knowledge node found for app '{}'.", app_name);
+            debug!(app_name = app_name, message = error_message);
+            Err((
+                StatusCode::NOT_FOUND,
+                Json(json!({"status": "error", "message": error_message})),
+            ))
+        }
+    }
+}
+
+/// Builds the `$match` stage selecting the requested node. Exactly one of `source` and `node_id` must be given.
+fn node_match_stage(params: &KnowledgeNodeDetailParams) -> Result<Document, String> {
+    match (params.source.as_deref(), params.node_id.as_deref()) {
+        (Some(source), None) if !source.is_empty() => {
+            // Decode the percent-encoded source URI
+            let source = percent_decode_str(source).decode_utf8_lossy().to_string();
+            Ok(doc! { "source": source })
+        }
+        (None, Some(node_id)) if !node_id.is_empty() => {
+            let id = match ObjectId::parse_str(node_id) {
+                Ok(oid) => Bson::ObjectId(oid),
+                Err(_) => Bson::String(node_id.to_string()),
+            };
+            Ok(doc! { "_id": id })
+        }
+        (Some(_), Some(_)) => Err("Provide either source or node_id, not both.".to_string()),
+        _ => Err("source or node_id is required.".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_node_match_stage() {
+        let by_source = KnowledgeNodeDetailParams {
+            source: Some("s3%3A%2F%2Fbucket%2Fdoc%20one.pdf".to_string()),
+            node_id: None,
+            fields: None,
+        };
+        assert_eq!(
+            node_match_stage(&by_source).unwrap(),
+            doc! { "source": "s3://bucket/doc one.pdf" }
+        );
+
+        let by_object_id = KnowledgeNodeDetailParams {
+            source: None,
+            node_id: Some("65f1c2a4e4b0a1b2c3d4e5f6".to_string()),
+            fields: None,
+        };
+        assert_eq!(
+            node_match_stage(&by_object_id).unwrap(),
+            doc! { "_id": ObjectId::parse_str("65f1c2a4e4b0a1b2c3d4e5f6").unwrap() }
+        );
+
+        let by_string_id = KnowledgeNodeDetailParams {
+            source: None,
+            node_id: Some("node-1".to_string()),
+            fields: None,
+        };
+        assert_eq!(
+            node_match_stage(&by_string_id).unwrap(),
+            doc! { "_id": "node-1" }
+        );
+
+        assert!(node_match_stage(&KnowledgeNodeDetailParams::default()).is_err());
+        assert!(node_match_stage(&KnowledgeNodeDetailParams {
+            source: Some("s3://bucket/doc.pdf".to_string()),
+            node_id: Some("node-1".to_string()),
+            fields: None,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_failure_get_knowledge_node_detail_handler_missing_lookup() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+
+            // Call the function
+            let result = get_knowledge_node_detail_handler(
+                Path(app_name),
+                Query(KnowledgeNodeDetailParams::default()),
+                State(app_state),
+            )
+            .await;
+
+            // If the function returns Err, check the status code and message
+            let (status_code, Json(message)) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+            assert_eq!(message.get("status").unwrap().as_str().unwrap(), "error");
+        });
+    }
+
+    #[test]
+    fn test_failure_get_knowledge_node_detail_handler_no_app_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non_existent_app".to_string();
+
+            // Call the function
+            let result = get_knowledge_node_detail_handler(
+                Path(app_name),
+                Query(KnowledgeNodeDetailParams {
+                    source: None,
+                    node_id: Some("node-1".to_string()),
+                    fields: None,
+                }),
+                State(app_state),
+            )
+            .await;
+
+            // If the function returns Err, check the status code and message
+            let (status_code, Json(message)) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+            assert!(message
+                .get("message")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .contains("No app found with name "));
+        });
+    }
+}
@@ -15,20 +15,21 @@
 
 use crate::admin_ui_api::schema::{Counts, QueryParams};
 use crate::service::check_app_existence::check_app_existence;
+use crate::service::ctx::Ctx;
+use crate::service::etag::{CollectionVersion, ETag};
+use crate::service::query_options::{AggregateExt, QueryOptions};
 use crate::service::state::AppState;
 use axum::{
     extract::{Path, Query, State},
-    http::StatusCode,
-    response::IntoResponse,
+    http::{HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
     Json,
 };
 use chrono::DateTime;
-use logging_utils::create_ref_id_helper::create_ref_id;
-use logging_utils::create_task_id_helper::create_task_id;
-use logging_utils::create_task_ref_id_helper::create_task_ref_collection;
 use mongodb::bson::doc;
 use percent_encoding::percent_decode_str;
 use serde_json::json;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use tracing::{debug, error, info, instrument};
 
@@ -56,26 +57,14 @@ use tracing::{debug, error, info, instrument};
 )]
 #[instrument(skip_all)]
 pub async fn get_knowledge_nodes_and_errors_count(
+    ctx: Ctx,
     Path(app_name): Path<String>,
     Query(params): Query<QueryParams>,
     State(app_state): State<Arc<AppState>>,
-) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
-    // Create a reference ID ,task ID and initialize the documentdb variables
-    let ref_id = create_ref_id();
-    let service_type = "GetNodeCount".to_string();
-    let task_id = create_task_id(&app_name, service_type);
-    let mongo_url = app_state.app_settings.mongo_db.mongo_db_url.clone();
-    let mongo_db_name = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_database_name
-        .clone();
-    let id_collection = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_id_collection
-        .clone();
-    //let ext_message = format!("{} Use reference ID: {}", app_state.app_settings.general_message, ref_id);
+    request_headers: HeaderMap,
+    uri: Uri,
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
+    //let ext_message = ctx.ext_message(&app_state);
     // Check if the start timestamp is provided
     let start_timestamp_encoded = params.start_timestamp.ok_or_else(|| {
         let error_message = "start_timestamp is required.".to_string();
@@ -91,18 +80,9 @@ pub async fn get_knowledge_nodes_and_errors_count(
         Err(err) => {
             let error_message = "start_timestamp is required.".to_string();
             let ext_message = "Please provide the start_timestamp.";
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.clone(),
-                task_id.clone(),
-                ref_id.clone(),
-            )
-            .await;
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
@@ -122,18 +102,9 @@ pub async fn get_knowledge_nodes_and_errors_count(
         Err(_) => {
             let error_message = format!("Invalid start timestamp '{}'.", start_timestamp);
             let ext_message = "Please provide the valid start_timestamp in RFC3339 format.";
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.clone(),
-                task_id.clone(),
-                ref_id.clone(),
-            )
-            .await;
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
@@ -157,18 +128,9 @@ pub async fn get_knowledge_nodes_and_errors_count(
         Err(err) => {
             let error_message = "end_timestamp is required.".to_string();
             let ext_message = "Please provide the end timestamp.";
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.clone(),
-                task_id.clone(),
-                ref_id.clone(),
-            )
-            .await;
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
@@ -198,18 +160,9 @@ pub async fn get_knowledge_nodes_and_errors_count(
         let error_message = format!("No app found with name '{}'.", app_name);
         let ext_message = "Please provide a valid app name.";
         debug!(message = error_message);
-        let _ = create_task_ref_collection(
-            mongo_url,
-            mongo_db_name,
-            id_collection,
-            app_name.clone(),
-            task_id.clone(),
-            ref_id.clone(),
-        )
-        .await;
         error!(
             app_name = app_name,
-            task_id = task_id,
+            task_id = ctx.task_id,
             ext_message = ext_message,
             message = error_message
         );
@@ -221,6 +174,31 @@ pub async fn get_knowledge_nodes_and_errors_count(
 
     let nodes_collection_name = format!("{}-general", app_name);
     let errors_collection_name = format!("{}-error", app_name);
+    // The aggregations run on the analytics connection of the app residency, when configured
+    let analytics_db = app_state.app_analytics_db(&app_name).await?;
+
+    // Answer with a 304 if neither the nodes nor the errors changed since the last poll
+    let query_options = app_state.options::<QueryOptions>();
+    let nodes_version = CollectionVersion::fetch(
+        analytics_db,
+        &nodes_collection_name,
+        doc! { "indexed_at": { "$gte": start_timestamp.clone(), "$lte": end_timestamp.clone() } },
+        "indexed_at",
+        &query_options,
+    )
+    .await?;
+    let errors_version = CollectionVersion::fetch(
+        analytics_db,
+        &errors_collection_name,
+        doc! { "event_time": { "$gte": start_timestamp.clone(), "$lte": end_timestamp.clone() } },
+        "event_time",
+        &query_options,
+    )
+    .await?;
+    let etag = ETag::new(&uri, &[nodes_version, errors_version.clone()]);
+    if etag.matches(&request_headers) {
+        return Ok(etag.not_modified());
+    }
 
     // Pipeline to get the count of knowledge nodes
     let nodes_count_pipeline = vec![
@@ -243,71 +221,35 @@ pub async fn get_knowledge_nodes_and_errors_count(
     ];
 
     // Call the aggregation operation to get the count of knowledge nodes
-    let nodes_result = app_state
-        .db
-        .aggregation_ops_on_documents(&nodes_collection_name, nodes_count_pipeline)
-        .await
-        .map_err(|err| {
-            (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                axum::Json(json!({"status": "error", "message": err.to_string()})),
-            )
-        })?;
+    let nodes_result = analytics_db
+        .aggregate(&nodes_collection_name, nodes_count_pipeline, &query_options)
+        .await?;
 
-    let mut knowledge_node_file_store = 0;
-    let mut knowledge_node_data_store = 0;
+    // Every registered node type is counted, the nodes of the unregistered labels are left out
+    let knowledge_node_types = app_state.knowledge_node_types();
+    let mut knowledge_nodes: BTreeMap<String, u64> = knowledge_node_types
+        .iter()
+        .map(|node_type| (node_type.name.clone(), 0))
+        .collect();
 
     // Extract the count of knowledge nodes
     for result in nodes_result {
-        if let Some(label) = result.get("_id") {
+        if let Some(label) = result.get("_id").and_then(|label| label.as_str()) {
             if let Some(count) = result.get("count").and_then(|c| c.as_u64()) {
-                if label == "FileObject" {
-                    knowledge_node_file_store = count;
-                } else if label == "DatabaseObjectNode" {
-                    knowledge_node_data_store = count;
+                if let Some(node_type) = knowledge_node_types.by_label(label) {
+                    knowledge_nodes.insert(node_type.name.clone(), count);
                 }
             }
         }
     }
 
-    // Pipeline to get the count of errors while processing/extracting knowledge nodes
-    let errors_count_pipeline = vec![
-        doc! {
-            "$match": {
-                "event_time": {
-                    "$gte": start_timestamp.clone(),
-                    "$lte": end_timestamp.clone(),
-                }
-            }
-        },
-        doc! {
-            "$count": "count"
-        },
-    ];
-
-    // Call the aggregation operation to get the count of errors
-    let errors_result = app_state
-        .db
-        .aggregation_ops_on_documents(&errors_collection_name, errors_count_pipeline)
-        .await
-        .map_err(|err| {
-            (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                axum::Json(json!({"status": "error", "message": err.to_string()})),
-            )
-        })?;
-
-    // Extract the count of errors
-    let knowledge_node_errors = errors_result
-        .first()
-        .and_then(|doc| doc.get("count").and_then(serde_json::Value::as_u64))
-        .unwrap_or(0);
+    // The count of errors while processing/extracting knowledge nodes comes with the version used for the ETag
+    let knowledge_node_errors = errors_version.count.max(0) as u64;
 
     // Set all the counts in the response
     let counts = Counts {
         knowledge_node_errors,
-        knowledge_node_file_store,
-        knowledge_node_data_store,
+        knowledge_nodes,
     };
 
     let success_message = format!(
@@ -315,10 +257,16 @@ pub async fn get_knowledge_nodes_and_errors_count(
         app_name, start_timestamp, end_timestamp
     );
     info!(app_name = app_name, message = success_message);
-    Ok(Json(
-        json!({"status": "success", "message": success_message, "counts": counts,
-        }),
-    ))
+    let mut headers = HeaderMap::new();
+    etag.insert_into(&mut headers);
+    Ok((
+        headers,
+        Json(
+            json!({"status": "success", "message": success_message, "counts": counts,
+            }),
+        ),
+    )
+        .into_response())
 }
 
 #[cfg(test)]
@@ -337,6 +285,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_and_errors_count(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -350,8 +299,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes/count"),
             )
             .await;
 
@@ -360,6 +322,55 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_success_get_knowledge_nodes_and_errors_count_not_modified() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+            let query = || QueryParams {
+                start_timestamp: Some("2024-05-02T00%3A00%3A00Z".to_string()),
+                end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
+                ..Default::default()
+            };
+
+            // First call returns the ETag
+            let response = get_knowledge_nodes_and_errors_count(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name.clone()),
+                Query(query()),
+                State(app_state.clone()),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes/count"),
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let etag = response
+                .headers()
+                .get(axum::http::header::ETAG)
+                .unwrap()
+                .clone();
+
+            // Second call with If-None-Match returns 304
+            let mut request_headers = HeaderMap::new();
+            request_headers.insert(axum::http::header::IF_NONE_MATCH, etag);
+            let response = get_knowledge_nodes_and_errors_count(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name.clone()),
+                Query(query()),
+                State(app_state),
+                request_headers,
+                Uri::from_static("/api/v1.1/admin/nodes/count"),
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        });
+    }
+
     #[test]
     fn test_failure_get_knowledge_nodes_and_errors_count_no_app_found() {
         let rt = Runtime::new().unwrap();
@@ -371,6 +382,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_and_errors_count(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -384,8 +396,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes/count"),
             )
             .await;
 
@@ -413,6 +438,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_and_errors_count(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -426,8 +452,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes/count"),
             )
             .await;
 
@@ -455,6 +494,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_and_errors_count(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -468,8 +508,21 @@ mod tests {
                     end_timestamp: None,
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes/count"),
             )
             .await;
 
@@ -497,6 +550,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_and_errors_count(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -510,8 +564,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes/count"),
             )
             .await;
 
@@ -539,6 +606,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_and_errors_count(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -552,8 +620,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A000Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes/count"),
             )
             .await;
 
@@ -8,6 +8,10 @@
 //! The handler is mounted at `/api/v1.1/admin/nodes/chart/{app_name}`.
 //! The handler is called by the admin UI to fetch the data for knowledge nodes for an app
 //! between two timestamps.
+//! The counts are bucketed in the timezone given by the optional `tz` query parameter (IANA name),
+//! defaulting to UTC. Day and month boundaries follow the DST rules of that timezone.
+//! The optional `interval` query parameter (hour/day/week/month) overrides the automatic granularity,
+//! as long as the selected time range does not produce more than `MAX_CHART_BUCKETS` buckets.
 //! The handler returns the data for knowledge nodes for an app if it exists, else returns an error message.
 //! The handler returns a 200 status code if the data is fetched successfully.
 //! The handler returns a 400 status code if an error occurs while fetching the data.
@@ -19,6 +23,8 @@ use crate::admin_ui_api::schema::{
     GraphItem, KnowledgeNodeChartCount, NodesChartApiResponse, QueryParams,
 };
 use crate::service::check_app_existence::check_app_existence;
+use crate::service::ctx::Ctx;
+use crate::service::query_options::{AggregateExt, QueryError, QueryOptions};
 use crate::service::state::AppState;
 use api_utils::errors::error_interceptor::ErrorInterceptor;
 use axum::{
@@ -28,9 +34,7 @@ use axum::{
     Json,
 };
 use chrono::{DateTime, Utc};
-use logging_utils::create_ref_id_helper::create_ref_id;
-use logging_utils::create_task_id_helper::create_task_id;
-use logging_utils::create_task_ref_id_helper::create_task_ref_collection;
+use chrono_tz::Tz;
 use mongodb::bson::doc;
 use mongodb::bson::Document;
 use serde_json::json;
@@ -38,6 +42,9 @@ use std::fmt::Debug;
 use std::sync::Arc;
 use tracing::{debug, error, instrument};
 
+/// Maximum number of buckets a chart may be split into with an explicit interval.
+pub const MAX_CHART_BUCKETS: i64 = 1000;
+
 /// GET handler to fetch the data for knowledge nodes for an app between two timestamps. The data is then displayed on a chart on admin UI.
 #[utoipa::path(
     get,
@@ -52,6 +59,16 @@ use tracing::{debug, error, instrument};
             "utc_end_timestamp" = inline(Option<DateTime<Utc>>), 
             Query,
             description = "UTC end timestamp.",
+        ),
+        (
+            "tz" = inline(Option<String>),
+            Query,
+            description = "IANA timezone to bucket the counts in, e.g. America/New_York. Defaults to UTC.",
+        ),
+        (
+            "interval" = inline(Option<String>),
+            Query,
+            description = "Chart granularity: hour, day, week or month. Defaults to a granularity based on the time range.",
         )
     ),
     responses(
@@ -62,43 +79,42 @@ use tracing::{debug, error, instrument};
 )]
 #[instrument(skip_all)]
 pub async fn get_knowledge_nodes_chart_handler(
+    ctx: Ctx,
     Path(app_name): Path<String>,
     Query(params): Query<QueryParams>,
     State(app_state): State<Arc<AppState>>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
-    // Create a reference ID ,task ID and initialize the documentdb variables
-    let ref_id = create_ref_id();
-    let service_type = "GetNodeChart".to_string();
-    let task_id = create_task_id(&app_name, service_type);
-    let mongo_url = app_state.app_settings.mongo_db.mongo_db_url.clone();
-    let mongo_db_name = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_database_name
-        .clone();
-    let id_collection = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_id_collection
-        .clone();
+    // Parse the requested timezone, defaulting to UTC
+    let tz = match params.tz.as_deref() {
+        None => Tz::UTC,
+        Some(name) => match name.parse::<Tz>() {
+            Ok(tz) => tz,
+            Err(_) => {
+                let error_message = format!("Invalid timezone '{}'.", name);
+                let ext_message = "Please provide a valid IANA timezone name.";
+                error!(
+                    app_name = app_name,
+                    task_id = ctx.task_id,
+                    ext_message = ext_message,
+                    message = error_message
+                );
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"status": "error", "message": error_message})),
+                ));
+            }
+        },
+    };
+
     // Check if the app exists
     let app_exists = check_app_existence(&app_state, &app_name).await?;
     if !app_exists {
         let error_message = format!("No app found with name '{}'.", app_name);
         let ext_message = "Please provide a valid app name.";
         debug!(message = error_message);
-        let _ = create_task_ref_collection(
-            mongo_url,
-            mongo_db_name,
-            id_collection,
-            app_name.clone(),
-            task_id.clone(),
-            ref_id.clone(),
-        )
-        .await;
         error!(
             app_name = app_name,
-            task_id = task_id,
+            task_id = ctx.task_id,
             ext_message = ext_message,
             message = error_message
         );
@@ -128,7 +144,29 @@ pub async fn get_knowledge_nodes_chart_handler(
     let mut pipeline_doc = base_pipeline_doc;
 
     let (start_timestamp, end_timestamp, timestamp_interval, timestamp_group_doc) =
-        process_timestamp_data(params.utc_start_timestamp, params.utc_end_timestamp).await;
+        match process_timestamp_data(
+            params.utc_start_timestamp,
+            params.utc_end_timestamp,
+            tz,
+            params.interval.as_deref(),
+        )
+        .await
+        {
+            Ok(timestamp_data) => timestamp_data,
+            Err(error_message) => {
+                let ext_message = "Please provide a valid interval for the selected time range.";
+                error!(
+                    app_name = app_name,
+                    task_id = ctx.task_id,
+                    ext_message = ext_message,
+                    message = error_message
+                );
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"status": "error", "message": error_message})),
+                ));
+            }
+        };
 
     let query_doc = doc! {
         "indexed_at": doc! {
@@ -146,16 +184,21 @@ pub async fn get_knowledge_nodes_chart_handler(
     pipeline_doc.insert(2, timestamp_group_doc);
 
     let collection_name = format!("{}-general", app_name);
+    // The aggregations run on the analytics connection of the app residency, when configured
+    let analytics_db = app_state.app_analytics_db(&app_name).await?;
 
     let mut resp = NodesChartApiResponse {
         graph_interval: timestamp_interval,
+        graph_timezone: tz.name().to_string(),
         ..Default::default()
     };
-    match app_state
-        .db
-        .aggregation_ops_on_documents(&collection_name, pipeline_doc.clone())
+    match analytics_db
+        .aggregate(
+            &collection_name,
+            pipeline_doc.clone(),
+            &app_state.options::<QueryOptions>(),
+        )
         .await
-        .map_err(ErrorInterceptor::from)
     {
         Ok(res) => {
             let mut knowledge_nodes_data: Vec<GraphItem> = Vec::new();
@@ -165,11 +208,11 @@ pub async fn get_knowledge_nodes_chart_handler(
             }
             resp.graph_items = knowledge_nodes_data;
         }
-        Err(e) => return Err(e.intercept_error().await),
+        Err(QueryError::Db(e)) => return Err(e.intercept_error().await),
+        Err(e) => return Err(e.into()),
     }
 
-    match app_state
-        .db
+    match analytics_db
         .get_document_count(&collection_name, query_doc)
         .await
         .map_err(ErrorInterceptor::from)
@@ -183,11 +226,15 @@ pub async fn get_knowledge_nodes_chart_handler(
 }
 
 /// (Helper fn) process timestamp related data
-/// returning start and end timestamps, interval, and group doc based on the input timestamps
+/// returning start and end timestamps, interval, and group doc based on the input timestamps.
+/// The buckets are computed in the given timezone. An explicit interval overrides the automatic one,
+/// returning an error message if it is unknown or yields more than `MAX_CHART_BUCKETS` buckets.
 pub async fn process_timestamp_data(
     start_ts: Option<DateTime<Utc>>,
     end_ts: Option<DateTime<Utc>>,
-) -> (String, String, String, Document) {
+    tz: Tz,
+    requested_interval: Option<&str>,
+) -> Result<(String, String, String, Document), String> {
     let end_timestamp = match end_ts {
         Some(ts) => ts,
         None => Utc::now(),
@@ -205,12 +252,46 @@ pub async fn process_timestamp_data(
     let num_days = duration.num_days();
 
     // Determine the interval and timestamp grouping document based on the number of days
-    let (interval, format) = if num_days < 3 {
-        ("hour", "%Y-%m-%dT%H:00:00Z")
-    } else if num_days < 60 {
-        ("day", "%Y-%m-%dT00:00:00Z")
+    // UTC buckets keep the `Z` suffix. Local hours carry their offset as they repeat when DST ends,
+    // local days and months are labelled with the local date.
+    let (utc_suffix, hour_suffix) = if tz == Tz::UTC {
+        ("Z", "Z")
     } else {
-        ("month", "%Y-%m-00T00:00:00Z")
+        ("", "%z")
+    };
+    let interval = match requested_interval {
+        Some(requested) => {
+            let bucket_seconds = match requested {
+                "hour" => 3600,
+                "day" => 86_400,
+                "week" => 7 * 86_400,
+                "month" => 30 * 86_400,
+                _ => {
+                    return Err(format!(
+                        "Invalid interval '{}'. Supported intervals are hour, day, week and month.",
+                        requested
+                    ))
+                }
+            };
+            let num_buckets = duration.num_seconds() / bucket_seconds + 1;
+            if num_buckets > MAX_CHART_BUCKETS {
+                return Err(format!(
+                    "Interval '{}' yields {} buckets for the selected time range, the maximum is {}.",
+                    requested, num_buckets, MAX_CHART_BUCKETS
+                ));
+            }
+            requested
+        }
+        None if num_days < 3 => "hour",
+        None if num_days < 60 => "day",
+        None => "month",
+    };
+    let format = match interval {
+        "hour" => format!("%Y-%m-%dT%H:00:00{}", hour_suffix),
+        "day" => format!("%Y-%m-%dT00:00:00{}", utc_suffix),
+        // ISO week of the local date, e.g. 2024-W19
+        "week" => "%G-W%V".to_string(),
+        _ => format!("%Y-%m-00T00:00:00{}", utc_suffix),
     };
 
     let group_doc = doc! {
@@ -218,7 +299,8 @@ pub async fn process_timestamp_data(
             "_id": doc! {
                 "$dateToString": doc! {
                     "format": format,
-                    "date": "$date"
+                    "date": "$date",
+                    "timezone": tz.name()
                 }
             },
             "count": doc! {
@@ -227,12 +309,12 @@ pub async fn process_timestamp_data(
         }
     };
 
-    (
+    Ok((
         start_timestamp.to_rfc3339().to_string(),
         end_timestamp.to_rfc3339().to_string(),
         interval.to_string(),
         group_doc,
-    )
+    ))
 }
 
 /// Converts a json value to rust type
@@ -268,6 +350,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_chart_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -281,6 +364,17 @@ mod tests {
                     end_timestamp: None,
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
             )
@@ -302,6 +396,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_chart_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -315,6 +410,17 @@ mod tests {
                     end_timestamp: None,
                     utc_start_timestamp: Some(Utc::now()),
                     utc_end_timestamp: Some(Utc::now()),
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
             )
@@ -344,6 +450,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_chart_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -357,6 +464,17 @@ mod tests {
                     end_timestamp: None,
                     utc_start_timestamp: Some(Utc::now()),
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
             )
@@ -378,6 +496,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_chart_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -391,6 +510,17 @@ mod tests {
                     end_timestamp: None,
                     utc_start_timestamp: None,
                     utc_end_timestamp: Some(Utc::now()),
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
             )
@@ -401,6 +531,136 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_failure_get_knowledge_nodes_chart_handler_invalid_tz() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+
+            // Call the function
+            let result = get_knowledge_nodes_chart_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name.clone()),
+                Query(QueryParams {
+                    tz: Some("Mars/Olympus_Mons".to_string()),
+                    ..Default::default()
+                }),
+                State(app_state),
+            )
+            .await;
+
+            // If the function returns Err, check the status code and message
+            let (status_code, Json(message)) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+            assert!(message
+                .get("message")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .contains("Invalid timezone "));
+        });
+    }
+
+    #[test]
+    fn test_success_process_timestamp_data_utc() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let end = Utc::now();
+            let start = end - chrono::Duration::days(10);
+            let (_, _, interval, group_doc) =
+                process_timestamp_data(Some(start), Some(end), Tz::UTC, None)
+                    .await
+                    .unwrap();
+            assert_eq!(interval, "day");
+            let date_to_string = group_doc
+                .get_document("$group")
+                .unwrap()
+                .get_document("_id")
+                .unwrap()
+                .get_document("$dateToString")
+                .unwrap();
+            assert_eq!(
+                date_to_string.get_str("format").unwrap(),
+                "%Y-%m-%dT00:00:00Z"
+            );
+            assert_eq!(date_to_string.get_str("timezone").unwrap(), "UTC");
+        });
+    }
+
+    #[test]
+    fn test_success_process_timestamp_data_tz() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let end = Utc::now();
+            let start = end - chrono::Duration::hours(12);
+            let (_, _, interval, group_doc) =
+                process_timestamp_data(Some(start), Some(end), chrono_tz::America::New_York, None)
+                    .await
+                    .unwrap();
+            assert_eq!(interval, "hour");
+            let date_to_string = group_doc
+                .get_document("$group")
+                .unwrap()
+                .get_document("_id")
+                .unwrap()
+                .get_document("$dateToString")
+                .unwrap();
+            assert_eq!(
+                date_to_string.get_str("format").unwrap(),
+                "%Y-%m-%dT%H:00:00%z"
+            );
+            assert_eq!(
+                date_to_string.get_str("timezone").unwrap(),
+                "America/New_York"
+            );
+        });
+    }
+
+    #[test]
+    fn test_success_process_timestamp_data_interval_override() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let end = Utc::now();
+            let start = end - chrono::Duration::days(90);
+            let (_, _, interval, group_doc) =
+                process_timestamp_data(Some(start), Some(end), Tz::UTC, Some("week"))
+                    .await
+                    .unwrap();
+            assert_eq!(interval, "week");
+            let date_to_string = group_doc
+                .get_document("$group")
+                .unwrap()
+                .get_document("_id")
+                .unwrap()
+                .get_document("$dateToString")
+                .unwrap();
+            assert_eq!(date_to_string.get_str("format").unwrap(), "%G-W%V");
+        });
+    }
+
+    #[test]
+    fn test_failure_process_timestamp_data_interval() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let end = Utc::now();
+            let start = end - chrono::Duration::days(365);
+            let result =
+                process_timestamp_data(Some(start), Some(end), Tz::UTC, Some("hour")).await;
+            assert!(result.unwrap_err().contains("the maximum is"));
+
+            let result =
+                process_timestamp_data(Some(start), Some(end), Tz::UTC, Some("fortnight")).await;
+            assert!(result.unwrap_err().contains("Invalid interval 'fortnight'"));
+        });
+    }
+
     use serde_json::json;
 
     #[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
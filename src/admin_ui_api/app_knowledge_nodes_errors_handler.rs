@@ -6,7 +6,8 @@
 //! This module contains the GET handler for fetching errors while processing/extracting knowledge nodes for an app
 //! between two timestamps.
 //! The handler is mounted at `/api/v1.1/admin/nodes/errors/{app_name}`.
-//! The handler returns the errors if they exist, else returns an error message.
+//! The handler returns the errors if they exist, else returns an error message, with the counts of the failed sources
+//! of the app by automatic retry status (see `crate::service::ingestion_retry`).
 //! The handler returns a 200 status code if the errors are fetched successfully.
 //! The handler returns a 400 status code if an error occurs while fetching the errors.
 //! The handler returns a 500 status code if an error occurs while fetching the errors.
@@ -15,20 +16,24 @@
 
 use crate::admin_ui_api::schema::QueryParams;
 use crate::service::check_app_existence::check_app_existence;
+use crate::service::ctx::Ctx;
+use crate::service::etag::{CollectionVersion, ETag};
+use crate::service::ingestion_retry::{retry_counts, IngestionRetryOptions};
+use crate::service::pagination::{
+    page_limit, split_cursor_page, Cursor, Pagination, CURSOR_ID_FIELD, DEFAULT_PAGE_LIMIT,
+};
+use crate::service::query_options::{AggregateExt, QueryOptions};
 use crate::service::state::AppState;
 use axum::{
     extract::{Path, Query, State},
-    http::StatusCode,
-    response::IntoResponse,
+    http::{HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
     Json,
 };
 use chrono::DateTime;
-use logging_utils::create_ref_id_helper::create_ref_id;
-use logging_utils::create_task_id_helper::create_task_id;
-use logging_utils::create_task_ref_id_helper::create_task_ref_collection;
 use mongodb::bson::doc;
 use percent_encoding::percent_decode_str;
-use serde_json::{json, Value};
+use serde_json::json;
 use std::sync::Arc;
 use tracing::{error, info, instrument};
 
@@ -56,6 +61,11 @@ use tracing::{error, info, instrument};
             "limit" = inline(Option<usize>), 
             Query,
             description = "page limit.",
+        ),
+        (
+            "cursor" = inline(Option<String>), 
+            Query,
+            description = "cursor returned as next_cursor. Pass an empty cursor to start cursor pagination.",
         )
     ),
     responses(
@@ -66,32 +76,19 @@ use tracing::{error, info, instrument};
 )]
 #[instrument(skip_all)]
 pub async fn get_knowledge_nodes_errors_handler(
+    ctx: Ctx,
     Path(app_name): Path<String>,
     Query(params): Query<QueryParams>,
     State(app_state): State<Arc<AppState>>,
-) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
-    // Create a reference ID ,task ID and initialize the documentdb variables
-    let ref_id = create_ref_id();
-    let service_type = "GetNodeChart".to_string();
-    let task_id = create_task_id(&app_name, service_type);
-    let mongo_url = app_state.app_settings.mongo_db.mongo_db_url.clone();
-    let mongo_db_name = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_database_name
-        .clone();
-    let id_collection = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_id_collection
-        .clone();
-
+    request_headers: HeaderMap,
+    uri: Uri,
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
     let start_timestamp_encoded = params.start_timestamp.ok_or_else(|| {
         let error_message = "start_timestamp is required.".to_string();
         let ext_message = "Please provide the start_timestamp.".to_string();
         error!(
             app_name = app_name,
-            task_id = task_id,
+            task_id = ctx.task_id,
             ext_message = ext_message,
             message = error_message
         );
@@ -114,7 +111,7 @@ pub async fn get_knowledge_nodes_errors_handler(
             let ext_message = "Please enter valid start timestamp.".to_string();
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
@@ -129,7 +126,7 @@ pub async fn get_knowledge_nodes_errors_handler(
         let error_message = "end_timestamp is required.".to_string();
         let ext_message = "Please enter end timestamp.".to_string();
         error!(
-            task_id = task_id,
+            task_id = ctx.task_id,
             ext_message = ext_message,
             message = error_message
         );
@@ -148,18 +145,9 @@ pub async fn get_knowledge_nodes_errors_handler(
         Err(_) => {
             let error_message = format!("Invalid end timestamp '{}'.", end_timestamp);
             let ext_message = "Please enter valid end timestamp.".to_string();
-            let _ = create_task_ref_collection(
-                mongo_url.clone(),
-                mongo_db_name.clone(),
-                id_collection.clone(),
-                app_name.clone(),
-                task_id.clone(),
-                ref_id.clone(),
-            )
-            .await;
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
@@ -175,18 +163,9 @@ pub async fn get_knowledge_nodes_errors_handler(
     if !app_exists {
         let error_message = format!("No app found with name '{}'.", app_name);
         let ext_message = "Please provide a valid app name.".to_string();
-        let _ = create_task_ref_collection(
-            mongo_url.clone(),
-            mongo_db_name.clone(),
-            id_collection.clone(),
-            app_name.clone(),
-            task_id.clone(),
-            ref_id.clone(),
-        )
-        .await;
         error!(
             app_name = app_name,
-            task_id = task_id,
+            task_id = ctx.task_id,
             ext_message = ext_message,
             message = error_message
         );
@@ -196,116 +175,158 @@ pub async fn get_knowledge_nodes_errors_handler(
         ));
     }
 
-    let limit = params.limit.unwrap_or(10) as i64;
-    let mut page = params.page.unwrap_or(1) as i64;
-
     let collection_name = format!("{}-error", app_name);
+    // The app specific collections are stored in the cluster of the app residency
+    let app_db = app_state.app_db(&app_name).await?;
 
-    // First query to get the count of errors
-    let count_pipeline = vec![
-        doc! {
-            "$match": {
-                "event_time": {
-                    "$gte": start_timestamp.clone(),
-                    "$lte": end_timestamp.clone(),
-                },
+    let match_stage = doc! {
+        "event_time": {
+            "$gte": start_timestamp.clone(),
+            "$lte": end_timestamp.clone(),
+        },
+    };
+    let added_fields = doc! {
+        "ingestion": {
+            "$cond": {
+                "if": { "$eq": [ "$full_filed_failed", true ] },
+                "then": "failed",
+                "else": "partially failed"
             }
         },
-        doc! {
-            "$count": "count"
+        "error_log_count": {
+            "$size": "$error_log"
         },
-    ];
-
-    let count_result = app_state
-        .db
-        .aggregation_ops_on_documents(&collection_name, count_pipeline)
-        .await
-        .map_err(|err| {
-            (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                axum::Json(json!({"status": "error", "message": err.to_string()})),
-            )
-        })?;
+    };
+    let projection = doc! {
+        "_id": 0,
+        "query": 1,
+        "event_time": 1,
+        "error_log_count": 1,
+        "ingestion":1,
+    };
 
-    let total_count = count_result.first().map_or(0, |doc| {
-        doc.get("count")
-            .and_then(serde_json::Value::as_i64)
-            .unwrap_or(0)
-    });
+    // Reject absurd page limits before querying
+    let query_options = app_state.options::<QueryOptions>();
+    query_options.check_limit(params.limit)?;
+
+    // Cursor (keyset) pagination on (event_time, _id) - avoids $skip on large apps
+    if let Some(cursor) = params.cursor.as_deref() {
+        let limit = page_limit(params.limit, DEFAULT_PAGE_LIMIT);
+        let mut cursor_match_stage = match_stage;
+        if !cursor.is_empty() {
+            let cursor = Cursor::decode(cursor).ok_or_else(|| {
+                let error_message = format!("Invalid cursor '{}'.", cursor);
+                error!(app_name = app_name, message = error_message);
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"status": "error", "message": error_message})),
+                )
+            })?;
+            cursor_match_stage.extend(cursor.after("event_time"));
+        }
+        let mut cursor_projection = projection;
+        cursor_projection.insert(CURSOR_ID_FIELD, doc! { "$toString": "$_id" });
+
+        let errors_pipeline = vec![
+            doc! { "$match": cursor_match_stage },
+            doc! { "$sort": { "event_time": 1, "_id": 1 } },
+            doc! { "$limit": limit + 1 },
+            doc! { "$addFields": added_fields },
+            doc! { "$project": cursor_projection },
+        ];
+
+        let errors_result = app_db
+            .aggregate(&collection_name, errors_pipeline, &query_options)
+            .await?;
+        let (errors, next_cursor) = split_cursor_page(errors_result, limit, "event_time");
+        let counts = retry_counts(&app_state, &app_name).await?;
+        // The page is tagged by its content, so polling a cursor page never scans the matched errors
+        let etag = ETag::for_page(
+            &uri,
+            &json!({"errors": errors, "next_cursor": next_cursor, "retry_counts": counts}),
+        );
+        if etag.matches(&request_headers) {
+            return Ok(etag.not_modified());
+        }
 
-    // Pagination calculation - Determine total pages, page(if needed) and skip value
-    let total_pages = (total_count as f64 / limit as f64).ceil() as i64;
+        let success_message = format!(
+            "Error logs for knowledge nodes processing fetched successfully for app '{}' between '{}' and '{}'.",
+            app_name, start_timestamp, end_timestamp
+        );
+        info!(app_name = app_name, message = success_message);
+        let mut headers = HeaderMap::new();
+        etag.insert_into(&mut headers);
+        return Ok((
+            headers,
+            Json(
+                json!({"status": "success", "message": success_message, "errors": errors, "next_cursor": next_cursor, "retry_counts": counts}),
+            ),
+        )
+            .into_response());
+    }
 
-    // If page is negative or total_pages is 0, set page to 1. If page is > total_pages, set page to total_pages
-    if page < 1 || total_pages == 0 {
-        page = 1;
-    } else if page > total_pages {
-        page = total_pages;
+    // Answer with a 304 if the matched errors did not change since the last poll
+    let version = CollectionVersion::fetch(
+        app_db,
+        &collection_name,
+        match_stage.clone(),
+        "event_time",
+        &query_options,
+    )
+    .await?;
+    // The retry counts of the failed sources change the response too
+    let retry_version = CollectionVersion::fetch(
+        &app_state.db,
+        &app_state.options::<IngestionRetryOptions>().collection,
+        doc! { "app_name": &app_name },
+        "last_retry_at",
+        &query_options,
+    )
+    .await?;
+    let etag = ETag::new(&uri, &[version.clone(), retry_version]);
+    if etag.matches(&request_headers) {
+        return Ok(etag.not_modified());
     }
-    let skip = (page - 1) * limit;
+    let counts = retry_counts(&app_state, &app_name).await?;
 
-    let count_str = total_count.to_string();
-    let json_count = serde_json::from_str(&count_str).unwrap_or(Value::Number(0.into()));
-    json_count.as_i64().unwrap_or(0);
+    // The count of documents comes with the version used for the ETag
+    let total_count = version.count;
 
-    // Second query to get the errors subject to $skip and $limit
-    let errors_pipeline = vec![
-        doc! {
-            "$match": {
-                "event_time": {
-                    "$gte": start_timestamp.clone(),
-                    "$lte": end_timestamp.clone(),
-                }
-            }
-        },
-        doc! {
-            "$addFields": {
-                "ingestion": {
-                    "$cond": {
-                        "if": { "$eq": [ "$full_filed_failed", true ] },
-                        "then": "failed",
-                        "else": "partially failed"
-                    }
-                },
-                "error_log_count": {
-                    "$size": "$error_log"
-                },
+    // Pagination calculation - Determine total pages, page(if needed) and skip value
+    let pagination = Pagination::new(params.page, params.limit, DEFAULT_PAGE_LIMIT, total_count);
+    let skip = pagination.skip();
+    // Deep pages are served by the cursor mode, $skip scans every skipped document
+    query_options.check_offset(skip)?;
+    let limit = pagination.limit;
 
-            }
-        },
-        doc! {
-            "$project": {
-                "_id": 0,
-                "query": 1,
-                "event_time": 1,
-                "error_log_count": 1,
-                "ingestion":1,
-            }
-        },
+    // Query to get the errors subject to $skip and $limit
+    let errors_pipeline = vec![
+        doc! { "$match": match_stage },
+        doc! { "$addFields": added_fields },
+        doc! { "$project": projection },
         doc! { "$skip": skip },
         doc! { "$limit": limit },
     ];
 
-    let errors_result = app_state
-        .db
-        .aggregation_ops_on_documents(&collection_name, errors_pipeline)
-        .await
-        .map_err(|err| {
-            (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                axum::Json(json!({"status": "error", "message": err.to_string()})),
-            )
-        })?;
+    let errors_result = app_db
+        .aggregate(&collection_name, errors_pipeline, &query_options)
+        .await?;
 
     let success_message = format!(
         "Error logs for knowledge nodes processing fetched successfully for app '{}' between '{}' and '{}'.",
         app_name, start_timestamp, end_timestamp
     );
     info!(app_name = app_name, message = success_message);
-    Ok(Json(
-        json!({"status": "success", "message": success_message, "errors": errors_result, 
-        "total_pages": total_pages, "total_results": total_count}),
-    ))
+    let mut headers = pagination.headers(&uri);
+    etag.insert_into(&mut headers);
+    Ok((
+        headers,
+        Json(
+            json!({"status": "success", "message": success_message, "errors": errors_result, 
+        "total_pages": pagination.total_pages, "total_results": pagination.total_count, "retry_counts": counts}),
+        ),
+    )
+        .into_response())
 }
 
 #[cfg(test)]
@@ -324,6 +345,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_errors_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -337,8 +359,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes/errors"),
             )
             .await;
 
@@ -358,6 +393,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_errors_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -371,8 +407,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes/errors"),
             )
             .await;
 
@@ -400,6 +449,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_errors_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -413,8 +463,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes/errors"),
             )
             .await;
 
@@ -442,6 +505,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_errors_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -455,8 +519,21 @@ mod tests {
                     end_timestamp: None,
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes/errors"),
             )
             .await;
 
@@ -484,6 +561,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_errors_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -497,8 +575,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes/errors"),
             )
             .await;
 
@@ -526,6 +617,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_errors_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -539,8 +631,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A000Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes/errors"),
             )
             .await;
 
@@ -569,6 +674,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_errors_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: Some(page as usize),
@@ -582,8 +688,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes/errors"),
             )
             .await;
 
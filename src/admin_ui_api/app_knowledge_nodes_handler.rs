@@ -14,20 +14,24 @@
 
 use crate::admin_ui_api::schema::QueryParams;
 use crate::service::check_app_existence::check_app_existence;
+use crate::service::ctx::Ctx;
+use crate::service::etag::{CollectionVersion, ETag};
+use crate::service::field_projection::FieldProjection;
+use crate::service::pagination::{
+    page_limit, split_cursor_page, Cursor, Pagination, CURSOR_ID_FIELD, DEFAULT_PAGE_LIMIT,
+};
+use crate::service::query_options::{AggregateExt, QueryOptions};
 use crate::service::state::AppState;
 use axum::{
     extract::{Path, Query, State},
-    http::StatusCode,
-    response::IntoResponse,
+    http::{HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
     Json,
 };
 use chrono::DateTime;
-use logging_utils::create_ref_id_helper::create_ref_id;
-use logging_utils::create_task_id_helper::create_task_id;
-use logging_utils::create_task_ref_id_helper::create_task_ref_collection;
 use mongodb::bson::doc;
 use percent_encoding::percent_decode_str;
-use serde_json::{json, Value};
+use serde_json::json;
 use std::sync::Arc;
 use tracing::{debug, error, info, instrument};
 
@@ -49,7 +53,7 @@ use tracing::{debug, error, info, instrument};
         (
             "knowledge_node_type" = inline(String), 
             Query,
-            description = "knowledge node type.",
+            description = "knowledge node type, e.g. knowledge_node_file_store, knowledge_node_data_store, knowledge_node_web_page, knowledge_node_image or knowledge_node_email.",
         ),
         (
             "page" = inline(Option<usize>), 
@@ -60,6 +64,16 @@ use tracing::{debug, error, info, instrument};
             "limit" = inline(Option<usize>), 
             Query,
             description = "page limit.",
+        ),
+        (
+            "cursor" = inline(Option<String>), 
+            Query,
+            description = "cursor returned as next_cursor. Pass an empty cursor to start cursor pagination.",
+        ),
+        (
+            "fields" = inline(Option<String>), 
+            Query,
+            description = "comma separated fields of the nodes to return, the listed fields by default.",
         )
     ),
     responses(
@@ -70,26 +84,13 @@ use tracing::{debug, error, info, instrument};
 )]
 #[instrument(skip_all)]
 pub async fn get_knowledge_nodes_handler(
+    ctx: Ctx,
     Path(app_name): Path<String>,
     Query(params): Query<QueryParams>,
     State(app_state): State<Arc<AppState>>,
-) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
-    // Create a reference ID ,task ID and initialize the documentdb variables
-    let ref_id = create_ref_id();
-    let service_type = "GetKNodeHandler".to_string();
-    let task_id = create_task_id(&app_name, service_type);
-    let mongo_url = app_state.app_settings.mongo_db.mongo_db_url.clone();
-    let mongo_db_name = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_database_name
-        .clone();
-    let id_collection = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_id_collection
-        .clone();
-
+    request_headers: HeaderMap,
+    uri: Uri,
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
     let start_timestamp_encoded = params.start_timestamp.ok_or_else(|| {
         let error_message = "start_timestamp is required.".to_string();
         error!(message = error_message);
@@ -110,18 +111,9 @@ pub async fn get_knowledge_nodes_handler(
         Err(_) => {
             let error_message = format!("Invalid start timestamp '{}'.", start_timestamp);
             let ext_message = "Please provide a valid start timestamp".to_string();
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.clone(),
-                task_id.clone(),
-                ref_id,
-            )
-            .await;
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
@@ -150,18 +142,9 @@ pub async fn get_knowledge_nodes_handler(
         Err(_) => {
             let error_message = format!("Invalid end timestamp '{}'.", end_timestamp);
             let ext_message = "Please provide a valid end timestamp".to_string();
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.clone(),
-                task_id.clone(),
-                ref_id,
-            )
-            .await;
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
@@ -177,18 +160,9 @@ pub async fn get_knowledge_nodes_handler(
     if !app_exists {
         let error_message = format!("No app found with name '{}'.", app_name);
         let ext_message = "Please provide a valid app name".to_string();
-        let _ = create_task_ref_collection(
-            mongo_url,
-            mongo_db_name,
-            id_collection,
-            app_name.clone(),
-            task_id.clone(),
-            ref_id,
-        )
-        .await;
         error!(
             app_name = app_name,
-            task_id = task_id,
+            task_id = ctx.task_id,
             ext_message = ext_message,
             message = error_message
         );
@@ -208,119 +182,138 @@ pub async fn get_knowledge_nodes_handler(
         )
     })?;
 
-    let node_label = match knowledge_node_type.as_str() {
-        "knowledge_node_file_store" => "FileObject",
-        "knowledge_node_data_store" => "DatabaseObjectNode",
-        _ => {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                Json(json!({"status": "error", "message": "Invalid knowledge_node_type."})),
-            ))
-        }
-    };
-
-    let limit = params.limit.unwrap_or(10) as i64;
-    let mut page = params.page.unwrap_or(1) as i64;
+    // The node types are resolved from the registry, built-in or configured
+    let knowledge_node_types = app_state.knowledge_node_types();
+    let node_type = knowledge_node_types.by_name(&knowledge_node_type)?;
 
     let collection_name = format!("{}-general", app_name);
+    // The app specific collections are stored in the cluster of the app residency
+    let app_db = app_state.app_db(&app_name).await?;
 
-    // First query to get the count of documents
-    let count_pipeline = vec![
-        doc! {
-            "$match": {
-                "indexed_at": {
-                    "$gte": start_timestamp.clone(),
-                    "$lte": end_timestamp.clone(),
-                },
-                "_node_label": node_label,
-            }
+    let match_stage = doc! {
+        "indexed_at": {
+            "$gte": start_timestamp.clone(),
+            "$lte": end_timestamp.clone(),
         },
-        doc! {
-            "$count": "count"
-        },
-    ];
-
-    let count_result = app_state
-        .db
-        .aggregation_ops_on_documents(&collection_name, count_pipeline)
-        .await
-        .map_err(|err| {
-            (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                axum::Json(json!({"status": "error", "message": err.to_string()})),
-            )
-        })?;
+        "_node_label": node_type.node_label.as_str(),
+    };
+    // Any stored field of the nodes can be requested, the listing only projects the fields of the type by default
+    let fields = FieldProjection::parse(params.fields.as_deref())?;
+    let projection = match &fields {
+        Some(fields) => fields.projection(&doc! {}),
+        None => node_type.projection(),
+    };
 
-    let total_count = count_result.first().map_or(0, |doc| {
-        doc.get("count")
-            .and_then(serde_json::Value::as_i64)
-            .unwrap_or(0)
-    });
+    // Reject absurd page limits before querying
+    let query_options = app_state.options::<QueryOptions>();
+    query_options.check_limit(params.limit)?;
+
+    // Cursor (keyset) pagination on (indexed_at, _id) - avoids $skip on large apps
+    if let Some(cursor) = params.cursor.as_deref() {
+        let limit = page_limit(params.limit, DEFAULT_PAGE_LIMIT);
+        let mut cursor_match_stage = match_stage;
+        if !cursor.is_empty() {
+            let cursor = Cursor::decode(cursor).ok_or_else(|| {
+                let error_message = format!("Invalid cursor '{}'.", cursor);
+                error!(app_name = app_name, message = error_message);
+                (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"status": "error", "message": error_message})),
+                )
+            })?;
+            cursor_match_stage.extend(cursor.after("indexed_at"));
+        }
+        let mut cursor_projection = projection;
+        // The next cursor is built from indexed_at, it is returned even if not requested
+        if !cursor_projection.contains_key("indexed_at") {
+            cursor_projection.insert("indexed_at", 1);
+        }
+        cursor_projection.insert(CURSOR_ID_FIELD, doc! { "$toString": "$_id" });
+
+        let nodes_pipeline = vec![
+            doc! { "$match": cursor_match_stage },
+            doc! { "$sort": { "indexed_at": 1, "_id": 1 } },
+            doc! { "$limit": limit + 1 },
+            doc! { "$project": cursor_projection },
+        ];
+
+        let nodes_result = app_db
+            .aggregate(&collection_name, nodes_pipeline, &query_options)
+            .await?;
+        let (nodes, next_cursor) = split_cursor_page(nodes_result, limit, "indexed_at");
+        // The page is tagged by its content, so polling a cursor page never scans the matched nodes
+        let etag = ETag::for_page(&uri, &json!({"nodes": nodes, "next_cursor": next_cursor}));
+        if etag.matches(&request_headers) {
+            return Ok(etag.not_modified());
+        }
 
-    // Pagination calculation - Determine total pages, page(if needed) and skip value
-    let total_pages = (total_count as f64 / limit as f64).ceil() as i64;
+        let success_message = format!(
+            "Knowledge nodes fetched successfully for app '{}' between '{}' and '{}'.",
+            app_name, start_timestamp, end_timestamp
+        );
+        info!(app_name = app_name, message = success_message);
+        let mut headers = HeaderMap::new();
+        etag.insert_into(&mut headers);
+        return Ok((
+            headers,
+            Json(
+                json!({"status": "success", "message": success_message, "nodes": nodes, "next_cursor": next_cursor}),
+            ),
+        )
+            .into_response());
+    }
 
-    // If page is negative or total_pages is 0, set page to 1. If page is > total_pages, set page to total_pages
-    if page < 1 || total_pages == 0 {
-        page = 1;
-    } else if page > total_pages {
-        page = total_pages;
+    // Answer with a 304 if the matched nodes did not change since the last poll
+    let version = CollectionVersion::fetch(
+        app_db,
+        &collection_name,
+        match_stage.clone(),
+        "indexed_at",
+        &query_options,
+    )
+    .await?;
+    let etag = ETag::new(&uri, &[version.clone()]);
+    if etag.matches(&request_headers) {
+        return Ok(etag.not_modified());
     }
-    let skip = (page - 1) * limit;
 
-    let count_str = total_count.to_string();
-    let json_count = serde_json::from_str(&count_str).unwrap_or(Value::Number(0.into()));
-    json_count.as_i64().unwrap_or(0);
+    // The count of documents comes with the version used for the ETag
+    let total_count = version.count;
 
-    // Second query to get the nodes subject to $skip and $limit
+    // Pagination calculation - Determine total pages, page(if needed) and skip value
+    let pagination = Pagination::new(params.page, params.limit, DEFAULT_PAGE_LIMIT, total_count);
+    let skip = pagination.skip();
+    // Deep pages are served by the cursor mode, $skip scans every skipped document
+    query_options.check_offset(skip)?;
+    let limit = pagination.limit;
+
+    // Query to get the nodes subject to $skip and $limit
     let nodes_pipeline = vec![
-        doc! {
-            "$match": {
-                "indexed_at": {
-                    "$gte": start_timestamp.clone(),
-                    "$lte": end_timestamp.clone(),
-                },
-                "_node_label": node_label,
-            }
-        },
-        doc! {
-            "$project": {
-                "_id": 0,
-                "indexed_at": 1,
-                "source": 1,
-                "total_page_num": {
-                    "$cond": {
-                        "if": { "$eq": [ "$_node_label", "FileObject" ] },
-                        "then": "$total_page_num",
-                        "else": null
-                    }
-                },
-            }
-        },
+        doc! { "$match": match_stage },
+        doc! { "$project": projection },
         doc! { "$skip": skip },
         doc! { "$limit": limit },
     ];
 
-    let nodes_result = app_state
-        .db
-        .aggregation_ops_on_documents(&collection_name, nodes_pipeline)
-        .await
-        .map_err(|err| {
-            (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                axum::Json(json!({"status": "error", "message": err.to_string()})),
-            )
-        })?;
+    let nodes_result = app_db
+        .aggregate(&collection_name, nodes_pipeline, &query_options)
+        .await?;
 
     let success_message = format!(
         "Knowledge nodes fetched successfully for app '{}' between '{}' and '{}'.",
         app_name, start_timestamp, end_timestamp
     );
     info!(app_name = app_name, message = success_message);
-    Ok(Json(
-        json!({"status": "success", "message": success_message, "nodes": nodes_result, 
-        "total_pages": total_pages, "total_results": total_count}),
-    ))
+    let mut headers = pagination.headers(&uri);
+    etag.insert_into(&mut headers);
+    Ok((
+        headers,
+        Json(
+            json!({"status": "success", "message": success_message, "nodes": nodes_result, 
+        "total_pages": pagination.total_pages, "total_results": pagination.total_count}),
+        ),
+    )
+        .into_response())
 }
 
 #[cfg(test)]
@@ -339,6 +332,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -352,8 +346,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes"),
             )
             .await;
 
@@ -373,6 +380,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -386,8 +394,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes"),
             )
             .await;
 
@@ -415,6 +436,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -428,8 +450,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes"),
             )
             .await;
 
@@ -457,6 +492,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -470,8 +506,21 @@ mod tests {
                     end_timestamp: None,
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes"),
             )
             .await;
 
@@ -499,6 +548,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -512,8 +562,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes"),
             )
             .await;
 
@@ -541,6 +604,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -554,8 +618,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes"),
             )
             .await;
 
@@ -583,6 +660,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -596,8 +674,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A000Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes"),
             )
             .await;
 
@@ -625,6 +716,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: None,
@@ -638,8 +730,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes"),
             )
             .await;
 
@@ -656,6 +761,52 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_failure_get_knowledge_nodes_handler_cursor_invalid() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+
+            // Call the function
+            let result = get_knowledge_nodes_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name.clone()),
+                Query(QueryParams {
+                    page: None,
+                    limit: None,
+                    app_name: None,
+                    is_update: None,
+                    search_enabled: None,
+                    reference_id: None,
+                    knowledge_node_type: Some("knowledge_node_file_store".to_string()),
+                    start_timestamp: Some("2024-05-02T00%3A00%3A00Z".to_string()),
+                    end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
+                    utc_start_timestamp: None,
+                    utc_end_timestamp: None,
+                    cursor: Some("not-a-cursor".to_string()),
+                }),
+                State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes"),
+            )
+            .await;
+
+            // If the function returns Err, check the status code and message
+            let (status_code, Json(message)) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+            assert_eq!(message.get("status").unwrap().as_str().unwrap(), "error");
+            assert!(message
+                .get("message")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .contains("Invalid cursor "));
+        });
+    }
+
     #[test]
     fn test_success_get_knowledge_nodes_handler_negative_page() {
         let rt = Runtime::new().unwrap();
@@ -668,6 +819,7 @@ mod tests {
 
             // Call the function
             let result = get_knowledge_nodes_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Path(app_name.clone()),
                 Query(QueryParams {
                     page: Some(page as usize),
@@ -681,8 +833,21 @@ mod tests {
                     end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                HeaderMap::new(),
+                Uri::from_static("/api/v1.1/admin/nodes"),
             )
             .await;
 
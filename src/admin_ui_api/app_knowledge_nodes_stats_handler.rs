@@ -0,0 +1,353 @@
+/*
+ * Created Date:   Jun 14, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET handler for fetching per-source statistics of the knowledge nodes for an app
+//! between two timestamps.
+//! The handler is mounted at `/api/v1.1/admin/nodes/stats/{app_name}`.
+//! The knowledge nodes are grouped by source bucket/prefix for filestores and by table for datastores, with the
+//! node count, the last indexed timestamp and the count of errors while processing the source.
+//! The handler returns a 200 status code if the statistics are fetched successfully.
+//! The handler returns a 400 status code if the request is invalid or the app does not exist.
+//! The handler returns a 500 status code if an error occurs while running the aggregation.
+//! The handler returns a JSON response with the status and message.
+//!
+
+use crate::admin_ui_api::schema::QueryParams;
+use crate::service::check_app_existence::check_app_existence;
+use crate::service::ctx::Ctx;
+use crate::service::knowledge_node_types::KnowledgeNodeTypes;
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::state::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::DateTime;
+use mongodb::bson::{doc, Document};
+use percent_encoding::percent_decode_str;
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{error, info, instrument};
+
+/// GET handler to fetch per-source statistics of the knowledge nodes for an app between two timestamps.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/nodes/stats/{app_name}",
+    params(
+        (
+            "start_timestamp" = inline(String),
+            Query,
+            description = "start timestamp.",
+        ),
+        (
+            "end_timestamp" = inline(String),
+            Query,
+            description = "end timestamp.",
+        )
+    ),
+    responses(
+        (status = 200, description = "Knowledge node statistics per source for app fetched successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_knowledge_nodes_stats_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    Query(params): Query<QueryParams>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let mut timestamps = Vec::new();
+    for (name, encoded) in [
+        ("start", params.start_timestamp),
+        ("end", params.end_timestamp),
+    ] {
+        let encoded = encoded.ok_or_else(|| {
+            let error_message = format!("{}_timestamp is required.", name);
+            error!(message = error_message);
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"status": "error", "message": error_message})),
+            )
+        })?;
+
+        // Decode the percent-encoded timestamp and check if it is valid in RFC3339 format
+        let timestamp = percent_decode_str(&encoded).decode_utf8_lossy().to_string();
+        if DateTime::parse_from_rfc3339(&timestamp).is_err() {
+            let error_message = format!("Invalid {} timestamp '{}'.", name, timestamp);
+            let ext_message = format!("Please provide a valid {} timestamp", name);
+            error!(
+                app_name = app_name,
+                task_id = ctx.task_id,
+                ext_message = ext_message,
+                message = error_message
+            );
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"status": "error", "message": error_message})),
+            ));
+        }
+        timestamps.push(timestamp);
+    }
+    let (start_timestamp, end_timestamp) = (timestamps[0].clone(), timestamps[1].clone());
+
+    // Check if the app exists
+    let app_exists = check_app_existence(&app_state, &app_name).await?;
+    if !app_exists {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        let ext_message = "Please provide a valid app name".to_string();
+        error!(
+            app_name = app_name,
+            task_id = ctx.task_id,
+            ext_message = ext_message,
+            message = error_message
+        );
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    let nodes_collection_name = format!("{}-general", app_name);
+    let errors_collection_name = format!("{}-error", app_name);
+    // The aggregations run on the analytics connection of the app residency, when configured
+    let analytics_db = app_state.app_analytics_db(&app_name).await?;
+    let stats_pipeline = source_stats_pipeline(
+        &errors_collection_name,
+        &start_timestamp,
+        &end_timestamp,
+        &app_state.knowledge_node_types(),
+    );
+
+    let stats_result = analytics_db
+        .aggregate(
+            &nodes_collection_name,
+            stats_pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await?;
+
+    let success_message = format!(
+        "Knowledge node statistics fetched successfully for app '{}' between '{}' and '{}'.",
+        app_name, start_timestamp, end_timestamp
+    );
+    info!(app_name = app_name, message = success_message);
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "sources": stats_result}),
+    ))
+}
+
+/// Expression deriving the source of a knowledge node. Filestore sources are reduced to
+/// `s3://bucket/prefix`, datastore sources (tables) are kept as they are.
+pub(crate) fn source_key_expression() -> Document {
+    doc! {
+        "$let": {
+            "vars": { "parts": { "$split": [ { "$ifNull": [ "$source", "" ] }, "/" ] } },
+            "in": {
+                "$cond": {
+                    "if": {
+                        "$and": [
+                            { "$eq": [ "$_node_label", "FileObject" ] },
+                            { "$gt": [ { "$size": "$$parts" }, 4 ] }
+                        ]
+                    },
+                    "then": {
+                        "$concat": [
+                            { "$arrayElemAt": [ "$$parts", 0 ] },
+                            "//",
+                            { "$arrayElemAt": [ "$$parts", 2 ] },
+                            "/",
+                            { "$arrayElemAt": [ "$$parts", 3 ] }
+                        ]
+                    },
+                    "else": { "$ifNull": [ "$source", "unknown" ] }
+                }
+            }
+        }
+    }
+}
+
+/// Single aggregation pipeline on the nodes collection grouping the nodes per source and looking up
+/// the errors of each source in the errors collection. Sources indexed least recently come first.
+fn source_stats_pipeline(
+    errors_collection_name: &str,
+    start_timestamp: &str,
+    end_timestamp: &str,
+    knowledge_node_types: &KnowledgeNodeTypes,
+) -> Vec<Document> {
+    vec![
+        doc! {
+            "$match": {
+                "indexed_at": {
+                    "$gte": start_timestamp,
+                    "$lte": end_timestamp,
+                },
+            }
+        },
+        doc! {
+            "$group": {
+                "_id": {
+                    "source": source_key_expression(),
+                    "node_label": "$_node_label",
+                },
+                "node_count": { "$sum": 1 },
+                "last_indexed_at": { "$max": "$indexed_at" },
+            }
+        },
+        doc! {
+            "$lookup": {
+                "from": errors_collection_name,
+                "let": { "source": "$_id.source" },
+                "pipeline": [
+                    {
+                        "$match": {
+                            "event_time": {
+                                "$gte": start_timestamp,
+                                "$lte": end_timestamp,
+                            },
+                            "$expr": {
+                                "$eq": [ { "$indexOfCP": [ { "$ifNull": [ "$query", "" ] }, "$$source" ] }, 0 ]
+                            }
+                        }
+                    },
+                    { "$count": "count" }
+                ],
+                "as": "errors",
+            }
+        },
+        doc! {
+            "$project": {
+                "_id": 0,
+                "source": "$_id.source",
+                "knowledge_node_type": knowledge_node_types.name_expression("$_id.node_label"),
+                "node_count": 1,
+                "last_indexed_at": 1,
+                "error_count": { "$ifNull": [ { "$arrayElemAt": [ "$errors.count", 0 ] }, 0 ] },
+            }
+        },
+        doc! { "$sort": { "last_indexed_at": 1, "source": 1 } },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_source_stats_pipeline() {
+        let pipeline = source_stats_pipeline(
+            "app100-error",
+            "2024-05-02T00:00:00Z",
+            "2024-05-09T00:00:00Z",
+            &KnowledgeNodeTypes::from_settings(None),
+        );
+        assert_eq!(pipeline.len(), 5);
+        let lookup = pipeline[2].get_document("$lookup").unwrap();
+        assert_eq!(lookup.get_str("from").unwrap(), "app100-error");
+        let project = pipeline[3].get_document("$project").unwrap();
+        assert!(project
+            .get_document("knowledge_node_type")
+            .unwrap()
+            .contains_key("$switch"));
+    }
+
+    #[test]
+    fn test_success_get_knowledge_nodes_stats_handler() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+
+            // Call the function
+            let result = get_knowledge_nodes_stats_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name.clone()),
+                Query(QueryParams {
+                    start_timestamp: Some("2024-05-02T00%3A00%3A00Z".to_string()),
+                    end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
+                    ..Default::default()
+                }),
+                State(app_state),
+            )
+            .await;
+
+            // Check if the function returns Ok
+            assert!(result.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_failure_get_knowledge_nodes_stats_handler_no_app_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non_existent_app".to_string();
+
+            // Call the function
+            let result = get_knowledge_nodes_stats_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name.clone()),
+                Query(QueryParams {
+                    start_timestamp: Some("2024-05-02T00%3A00%3A00Z".to_string()),
+                    end_timestamp: Some("2024-05-09T00%3A00%3A00Z".to_string()),
+                    ..Default::default()
+                }),
+                State(app_state),
+            )
+            .await;
+
+            // If the function returns Err, check the status code and message
+            let (status_code, Json(message)) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+            assert!(message
+                .get("message")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .contains("No app found with name "));
+        });
+    }
+
+    #[test]
+    fn test_failure_get_knowledge_nodes_stats_handler_end_timestamp_missing() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+
+            // Call the function
+            let result = get_knowledge_nodes_stats_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name.clone()),
+                Query(QueryParams {
+                    start_timestamp: Some("2024-05-02T00%3A00%3A00Z".to_string()),
+                    ..Default::default()
+                }),
+                State(app_state),
+            )
+            .await;
+
+            // If the function returns Err, check the status code and message
+            let (status_code, Json(message)) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+            assert!(message
+                .get("message")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .contains("end_timestamp is required."));
+        });
+    }
+}
@@ -7,25 +7,36 @@
 //! The handler is mounted at `/api/v1.1/admin/apps`.
 //! The handler is called by the admin UI to fetch the list of onboarded apps.
 //! The handler returns the list of onboarded apps if they exist, else returns an error message.    
+//! The optional `metadata` query parameter, e.g. `team:search,env:prod`, restricts the list to the apps with every
+//! listed metadata entry, and the metadata of the apps is returned with them.
 //! The handler returns a 200 status code if the list of onboarded apps is fetched successfully.
 //! The handler returns a 500 status code if an error occurs while fetching the list of onboarded apps.
 //! The handler returns a JSON response with the status and message.
 //!
 
 use crate::admin_ui_api::schema::{AppListFetchSchema, QueryParams};
+use crate::service::app_metadata::{app_list_filter, METADATA_FIELD};
+use crate::service::ctx::Ctx;
+use crate::service::pagination::Pagination;
+use crate::service::query_options::QueryOptions;
 use crate::service::state::AppState;
+use crate::service::timestamp::serve_timestamps;
 use api_utils::app_model::App;
 use api_utils::errors::error_interceptor::ErrorInterceptor;
-use axum::{extract::Query, extract::State, http::StatusCode, response::IntoResponse, Json};
-use logging_utils::create_ref_id_helper::create_ref_id;
-use logging_utils::create_task_id_helper::create_task_id;
-use logging_utils::create_task_ref_id_helper::create_task_ref_collection;
-use mongodb::bson::doc;
+use axum::{
+    extract::Query,
+    extract::State,
+    http::{StatusCode, Uri},
+    response::IntoResponse,
+    Json,
+};
 use serde_json::json;
 use std::fmt::Debug;
 use std::sync::Arc;
 use tracing::{debug, error, instrument};
 
+const DEFAULT_APP_LIST_LIMIT: i64 = 100;
+
 /// GET handler to fetch the list of apps.
 #[utoipa::path(
     get,
@@ -40,6 +51,11 @@ use tracing::{debug, error, instrument};
             "limit" = inline(Option<usize>), 
             Query,
             description = "page limit.",
+        ),
+        (
+            "metadata" = inline(Option<String>),
+            Query,
+            description = "comma separated key:value metadata entries the apps must all have.",
         )
     ),
 
@@ -51,20 +67,39 @@ use tracing::{debug, error, instrument};
 )]
 #[instrument(skip_all)]
 pub async fn get_app_list(
+    ctx: Ctx,
     Query(params): Query<QueryParams>,
     State(app_state): State<Arc<AppState>>,
+    uri: Uri,
 ) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
-    let filter = doc! {};
-    // TODO: just 100?
-    // Extract the page and limit from the query params
-    let limit = params.limit.unwrap_or(100) as i64;
-    let page = params.page.unwrap_or(1) as i64;
+    let filter = app_list_filter(params.metadata.as_deref())?;
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let query_options = app_state.options::<QueryOptions>();
+    query_options.check_limit(params.limit)?;
+
+    // Count the onboarded apps to clamp the requested page
+    let total_count = app_state
+        .db
+        .get_document_count(collection_name, filter.clone())
+        .await
+        .map_err(|err| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"status": "error", "message": err.to_string()})),
+            )
+        })?;
+    let pagination = Pagination::new(
+        params.page,
+        params.limit,
+        DEFAULT_APP_LIST_LIMIT,
+        total_count as i64,
+    );
+    query_options.check_offset(pagination.skip())?;
 
     // Get list of all onboarded apps from DocumentDB
-    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
     match app_state
         .db
-        .get_all_documents(collection_name, limit, page, filter)
+        .get_all_documents(collection_name, pagination.limit, pagination.page, filter)
         .await
         .map_err(ErrorInterceptor::from)
     {
@@ -72,7 +107,13 @@ pub async fn get_app_list(
             let mut app_list = Vec::new();
             let mut errors = Vec::new();
 
-            for app in apps {
+            for mut app in apps {
+                // `create_timestamp` is stored as a BSON date
+                serve_timestamps(&mut app);
+                // The app model does not hold the custom metadata
+                let metadata = app
+                    .get(METADATA_FIELD)
+                    .and_then(|metadata| serde_json::from_value(metadata.clone()).ok());
                 match doc_to_type::<App>(app) {
                     // If the app is successfully fetched, add it to the app_list
                     Ok(app_model) => {
@@ -82,6 +123,7 @@ pub async fn get_app_list(
                             api_key: app_model.api_key,
                             onboarding_status: app_model.onboarding_status,
                             search_enabled: app_model.search_enabled,
+                            metadata,
                         });
                     }
                     // If the app is not fetched due to incorrect schema, add it to the errors list
@@ -102,8 +144,12 @@ pub async fn get_app_list(
                 message = format!(" {} app(s) fetched successfully.", app_list.len());
                 debug!(message = message);
             }
-            Ok(Json(
-                json!({ "status": "success","message": message,"app_count": app_list.len(),"data": app_list}),
+            Ok((
+                pagination.headers(&uri),
+                Json(
+                    json!({ "status": "success","message": message,"app_count": app_list.len(),"data": app_list,
+                    "total_pages": pagination.total_pages, "total_results": pagination.total_count}),
+                ),
             ))
         }
         Err(e) => {
@@ -111,34 +157,7 @@ pub async fn get_app_list(
                 "Failed to fetch list of onboarded apps from DocumentDB. Error: {:?}",
                 e
             );
-            let ref_id = create_ref_id();
-            let service_type = "GetAppList".to_string();
-            let app_name = &app_state.app_settings.tracing_layer_system_app_name;
-            let task_id = create_task_id(app_name, service_type);
-            let mongo_url = app_state.app_settings.mongo_db.mongo_db_url.clone();
-            let mongo_db_name = app_state
-                .app_settings
-                .mongo_db
-                .mongo_db_database_name
-                .clone();
-            let id_collection = app_state
-                .app_settings
-                .mongo_db
-                .mongo_db_id_collection
-                .clone();
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.clone(),
-                task_id,
-                ref_id.clone(),
-            )
-            .await;
-            let ext_message = format!(
-                "{} Use reference ID: {}",
-                app_state.app_settings.general_message, ref_id
-            );
+            let ext_message = ctx.ext_message(&app_state);
             error!(ext_message = ext_message, message = error_message);
             error!(message = error_message);
             Err(e.intercept_error().await)
@@ -178,6 +197,7 @@ mod tests {
 
             // Call the function
             let result = get_app_list(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Query(QueryParams {
                     page: Some(1),
                     limit: Some(10),
@@ -190,8 +210,20 @@ mod tests {
                     end_timestamp: None,
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                Uri::from_static("/api/v1.1/admin/apps"),
             )
             .await;
 
@@ -200,6 +232,59 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_failure_get_app_list_limit_too_large() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let result = get_app_list(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Query(QueryParams {
+                    limit: Some(1_000_000),
+                    ..Default::default()
+                }),
+                State(app_state),
+                Uri::from_static("/api/v1.1/admin/apps"),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn test_failure_get_app_list_invalid_metadata_filter() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let result = get_app_list(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Query(QueryParams {
+                    metadata: Some("team".to_string()),
+                    federated: None,
+                    ..Default::default()
+                }),
+                State(app_state),
+                Uri::from_static("/api/v1.1/admin/apps"),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+
     #[test]
     fn test_success_get_app_list_missing_page() {
         let rt = Runtime::new().unwrap();
@@ -210,6 +295,7 @@ mod tests {
 
             // Call the function
             let result = get_app_list(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Query(QueryParams {
                     page: None,
                     limit: Some(10),
@@ -222,8 +308,20 @@ mod tests {
                     end_timestamp: None,
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                Uri::from_static("/api/v1.1/admin/apps"),
             )
             .await;
 
@@ -242,6 +340,7 @@ mod tests {
 
             // Call the function
             let result = get_app_list(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Query(QueryParams {
                     page: Some(1),
                     limit: None,
@@ -254,8 +353,20 @@ mod tests {
                     end_timestamp: None,
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 State(app_state),
+                Uri::from_static("/api/v1.1/admin/apps"),
             )
             .await;
 
@@ -274,7 +385,7 @@ mod tests {
             let app_state = crate::tests::test_get_appstate().await.unwrap();
 
             // Call the function
-            let result = get_app_list(Query(QueryParams{page: Some(1), limit: Some(10), app_name: None, is_update: None, search_enabled: None}), State(app_state.clone())).await;
+            let result = get_app_list(Ctx::new(&app_state, "test_app", "Test"), Query(QueryParams{page: Some(1), limit: Some(10), app_name: None, is_update: None, search_enabled: None}), State(app_state.clone())).await;
 
             // If the function returns Err, check the status code and message
             let (status_code, Json(message)) = result.err().unwrap();
@@ -0,0 +1,301 @@
+/*
+ * Created Date:  Jul 25, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the handlers for the named prompt templates of an app.
+//! The handlers are mounted at `/api/v1.1/admin/apps/{app_name}/prompt-templates`.
+//! The GET handler returns the templates stored in the app document, empty if not set.
+//! The PUT handler adds a template, or replaces the template with the same name.
+//! The DELETE handler of `/{template_name}` removes a template. Once an app has templates, its retrievals can't send
+//! a free-form `additional_prompt`.
+//! The handlers return a 200 status code if the templates are fetched/updated successfully.
+//! The handlers return a 400 status code if the template is invalid.
+//! The handlers return a 404 status code if the app or the template is not found.
+//! The handlers return a 500 status code if an error occurs while fetching/updating the templates.
+//!
+
+use crate::admin_ui_api::schema::UpdateResponse;
+use crate::service::ctx::Ctx;
+use crate::service::prompt_template::{
+    PromptTemplate, PromptTemplateError, PROMPT_TEMPLATES_FIELD,
+};
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, to_bson};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// GET handler to get the prompt templates of an app.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/prompt-templates",
+    responses(
+        (status = 200, description = "Prompt templates retrieved successfully.", body = [PromptTemplate]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_prompt_templates_handler(
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let templates = find_prompt_templates(&app_state, &app_name).await?;
+    let success_message = format!("Prompt templates of '{}' retrieved successfully.", app_name);
+    info!(app_name = app_name, message = success_message);
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "data": templates}),
+    ))
+}
+
+/// PUT handler to add a prompt template to an app, or replace the template with the same name.
+#[utoipa::path(
+    put,
+    path = "/api/v1.1/admin/apps/{app_name}/prompt-templates",
+    request_body = PromptTemplate,
+    responses(
+        (status = 200, description = "Prompt template saved successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn put_prompt_template_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(template): Json<PromptTemplate>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    template.validate()?;
+    let mut templates = find_prompt_templates(&app_state, &app_name).await?;
+    match templates
+        .iter_mut()
+        .find(|stored| stored.name == template.name)
+    {
+        Some(stored) => *stored = template.clone(),
+        None => templates.push(template.clone()),
+    }
+    store_prompt_templates(&ctx, &app_state, &app_name, &templates).await?;
+
+    let success_message = format!(
+        "Prompt template '{}' of '{}' saved successfully.",
+        template.name, app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "Prompt template saved",
+        details = json!(template).to_string(),
+        message = success_message
+    );
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "app_name": app_name}),
+    ))
+}
+
+/// DELETE handler to remove a prompt template of an app.
+#[utoipa::path(
+    delete,
+    path = "/api/v1.1/admin/apps/{app_name}/prompt-templates/{template_name}",
+    responses(
+        (status = 200, description = "Prompt template deleted successfully."),
+        (status = StatusCode::NOT_FOUND, description = "App or prompt template not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn delete_prompt_template_handler(
+    ctx: Ctx,
+    Path((app_name, template_name)): Path<(String, String)>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let mut templates = find_prompt_templates(&app_state, &app_name).await?;
+    let count = templates.len();
+    templates.retain(|template| template.name != template_name);
+    if templates.len() == count {
+        return Err(PromptTemplateError::UnknownTemplate(template_name).into());
+    }
+    store_prompt_templates(&ctx, &app_state, &app_name, &templates).await?;
+
+    let success_message = format!(
+        "Prompt template '{}' of '{}' deleted successfully.",
+        template_name, app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "Prompt template deleted",
+        details = template_name,
+        message = success_message
+    );
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "app_name": app_name}),
+    ))
+}
+
+/// Returns the prompt templates of an app. Unknown apps are answered with a 404.
+async fn find_prompt_templates(
+    app_state: &AppState,
+    app_name: &str,
+) -> Result<Vec<PromptTemplate>, (StatusCode, Json<serde_json::Value>)> {
+    let apps = app_state.apps();
+    if !apps.exists(app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        debug!(message = error_message);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+    Ok(apps.prompt_templates(app_name).await?)
+}
+
+/// Stores the prompt templates of an app on its app document.
+async fn store_prompt_templates(
+    ctx: &Ctx,
+    app_state: &AppState,
+    app_name: &str,
+    templates: &[PromptTemplate],
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let filter = doc! {"app_name": app_name};
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let error_message = match to_bson(templates) {
+        Ok(templates_bson) => match app_state
+            .db
+            .update_document(
+                collection_name,
+                filter,
+                doc! {PROMPT_TEMPLATES_FIELD: templates_bson},
+            )
+            .await
+            .map_err(ErrorInterceptor::from)
+        {
+            Ok(json_result) => match serde_json::from_value::<UpdateResponse>(json_result) {
+                Ok(result) if result.matchedCount == 0 => {
+                    let error_message = format!("No app found with name '{}'.", app_name);
+                    debug!(message = error_message);
+                    return Err((
+                        StatusCode::NOT_FOUND,
+                        Json(json!({"status": "error", "message": error_message})),
+                    ));
+                }
+                Ok(_) => None,
+                Err(e) => Some(format!(
+                    "Failed to deserialize update response. Error: {:?}",
+                    e
+                )),
+            },
+            Err(e) => Some(format!(
+                "Failed to update prompt templates of app '{}'. Error: {}",
+                app_name, e
+            )),
+        },
+        Err(e) => Some(format!(
+            "Failed to serialize prompt templates to BSON. Error: {}",
+            e
+        )),
+    };
+    if let Some(error_message) = error_message {
+        let ext_message = ctx.ext_message(app_state);
+        error!(
+            app_name = app_name,
+            task_id = ctx.task_id,
+            ext_message = ext_message,
+            message = error_message
+        );
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_put_prompt_template_handler_invalid_template() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+            let template = PromptTemplate {
+                name: "policy-scope".to_string(),
+                description: None,
+                template: "Answer for the {{ region office.".to_string(),
+            };
+
+            // Call the function
+            let result = put_prompt_template_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                State(app_state),
+                Json(template),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn test_failure_delete_prompt_template_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let result = delete_prompt_template_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path((app_name, "policy-scope".to_string())),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+
+    #[test]
+    fn test_failure_get_prompt_templates_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let result = get_prompt_templates_handler(Path(app_name), State(app_state)).await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
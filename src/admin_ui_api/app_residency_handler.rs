@@ -0,0 +1,169 @@
+/*
+ * Created Date:  Jun 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the POST handler to migrate an app to another residency.
+//! The handler is mounted at `/api/v1.1/admin/apps/{app_name}/residency`.
+//! The app specific collections are copied to the cluster of the requested residency, the residency of the
+//! app document is switched, and the collections are then dropped from the previous cluster.
+//! Documents written by the app while the collections are copied are not migrated, so the migration should
+//! be run while the app is idle.
+//! The handler returns a 200 status code if the app is migrated successfully.
+//! The handler returns a 400 status code if the requested residency is not configured.
+//! The handler returns a 404 status code if the app is not found.
+//! The handler returns a 500 status code if an error occurs while migrating the app.
+//!
+
+use crate::admin_ui_api::schema::{ResidencyMigrationRequest, UpdateResponse};
+use crate::service::check_app_existence::check_app_existence;
+use crate::service::residency::{copy_app_collections, drop_app_collections, residency_name};
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::doc;
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{error, info, instrument};
+
+/// POST handler to migrate the app specific collections of an app to another residency.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/apps/{app_name}/residency",
+    request_body = ResidencyMigrationRequest,
+    responses(
+        (status = 200, description = "App migrated successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_app_residency_handler(
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(body): Json<ResidencyMigrationRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let target_db = app_state.residency_db(body.residency.as_deref())?;
+
+    if !check_app_existence(&app_state, &app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    let existing_residency = app_state.app_residency(&app_name).await?;
+    let target_residency = residency_name(body.residency.as_deref());
+    if existing_residency == body.residency {
+        let success_message = format!(
+            "App '{}' already resides in '{}'.",
+            app_name, target_residency
+        );
+        info!(app_name = app_name, message = success_message);
+        return Ok(Json(
+            json!({"status": "success", "message": success_message, "app_name": app_name, "documents_migrated": 0}),
+        ));
+    }
+    let source_db = app_state.residency_db(existing_residency.as_deref())?;
+
+    // Copy the collections, then switch the residency so that new documents go to the target cluster
+    let documents_migrated = copy_app_collections(source_db, target_db, &app_name).await?;
+
+    let filter = doc! {"app_name": &app_name};
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let update_result = app_state
+        .db
+        .update_document(
+            collection_name,
+            filter,
+            doc! {"residency": body.residency.clone()},
+        )
+        .await
+        .map_err(ErrorInterceptor::from);
+    let error_message = match update_result {
+        Ok(json_result) => match serde_json::from_value::<UpdateResponse>(json_result) {
+            Ok(_) => None,
+            Err(e) => Some(format!(
+                "Failed to deserialize update response. Error: {:?}",
+                e
+            )),
+        },
+        Err(e) => Some(format!(
+            "Failed to update residency of app '{}'. Error: {}",
+            app_name, e
+        )),
+    };
+    if let Some(error_message) = error_message {
+        // The source collections are kept, the migration can be retried
+        error!(
+            app_name = app_name,
+            ext_message = error_message,
+            message = error_message
+        );
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    drop_app_collections(source_db, &app_name).await;
+
+    let success_message = format!(
+        "App '{}' migrated from '{}' to '{}'.",
+        app_name,
+        residency_name(existing_residency.as_deref()),
+        target_residency
+    );
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        app_name = app_name,
+        action = "App residency migrated",
+        details = success_message,
+        message = success_message
+    );
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "app_name": app_name, "documents_migrated": documents_migrated}),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_post_app_residency_handler_unknown_residency() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+            let body = ResidencyMigrationRequest {
+                residency: Some("unknown-region".to_string()),
+            };
+
+            // Call the function
+            let result =
+                post_app_residency_handler(Path(app_name), State(app_state), Json(body)).await;
+
+            // Check the status code
+            let (status_code, Json(message)) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+            assert!(message
+                .get("message")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .contains("unknown-region"));
+        });
+    }
+}
@@ -0,0 +1,153 @@
+/*
+ * Created Date:  Jul 17, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the POST handler to retry a failed onboarding/update of an app, resuming its background
+//! steps from the failed one instead of leaving a half-created app.
+//! The handler is mounted at `/api/v1.1/admin/apps/{app_name}/retry-onboarding`.
+//! The request is rebuilt from the stored app document, with its stored API key. The failed step is claimed by
+//! storing its running state before the steps are queued in the onboarding job queue, so concurrent retries are
+//! rejected.
+//! The handler returns a 202 status code if the onboarding is resumed.
+//! The handler returns a 404 status code if the app is not found.
+//! The handler returns a 409 status code if the onboarding of the app has not failed.
+//! The handler returns a 500 status code if an error occurs while reading the app or storing its state.
+//!
+
+use crate::onboarding::apply::fetch_existing_app;
+use crate::onboarding::fetch_api_key::fetch_api_key;
+use crate::onboarding::handler::{enqueue_onboarding, OnboardingJob, OnboardingRun};
+use crate::service::ctx::Ctx;
+use crate::service::onboarding_state::{record_onboarding_state, OnboardingState};
+use crate::service::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, info, instrument};
+
+/// POST handler to retry the failed onboarding/update of an app from the failed step.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/apps/{app_name}/retry-onboarding",
+    responses(
+        (status = 202, description = "Onboarding resumed from the failed step."),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::CONFLICT, description = "Onboarding not failed", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_retry_onboarding_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let state = app_state.apps().onboarding_state(&app_name).await?;
+    let Some(failed_step) = state.and_then(|state| state.failed_step()) else {
+        let state = state.unwrap_or(OnboardingState::Complete);
+        let error_message = format!(
+            "Onboarding of app '{}' has not failed (state: {}), nothing to retry.",
+            app_name,
+            json!(state).as_str().unwrap_or_default()
+        );
+        debug!(message = error_message);
+        return Err((
+            StatusCode::CONFLICT,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    };
+
+    let Some(body) = fetch_existing_app(&app_state, &app_name).await? else {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        debug!(message = error_message);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    };
+    let (api_key, api_key_id, app_id) = fetch_api_key(&app_state, &app_name).await?;
+
+    // Claim the failed step, a concurrent retry finds the onboarding running
+    record_onboarding_state(&app_state, &app_name, OnboardingState::running(failed_step))
+        .await
+        .map_err(|error_message| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"status": "error", "message": error_message})),
+            )
+        })?;
+
+    let run = OnboardingRun {
+        app_id,
+        api_key,
+        api_key_id,
+        task_id: ctx.task_id.clone(),
+        is_update: true,
+        is_retry: true,
+    };
+    let job = OnboardingJob {
+        body,
+        run,
+        reference_id: None,
+        from: failed_step,
+        request_timestamp: ctx.start,
+        schema_previews: Vec::new(),
+    };
+    enqueue_onboarding(&app_state, &job).await?;
+
+    let success_message = format!(
+        "Onboarding of '{}' resumed from step '{}'.",
+        app_name,
+        json!(failed_step).as_str().unwrap_or_default()
+    );
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "App Onboarding retried",
+        details = success_message,
+        message = success_message
+    );
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "status": "success",
+            "message": success_message,
+            "app_name": app_name,
+            "resumed_from": failed_step,
+            "reference_id": ctx.reference_id
+        })),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_post_retry_onboarding_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let ctx = Ctx::new(&app_state, &app_name, "Test");
+            let result = post_retry_onboarding_handler(ctx, Path(app_name), State(app_state)).await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
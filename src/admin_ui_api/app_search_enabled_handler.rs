@@ -13,6 +13,7 @@
 //!
 
 use crate::admin_ui_api::schema::{QueryParams, UpdateResponse};
+use crate::service::ctx::Ctx;
 use crate::service::state::AppState;
 use api_utils::errors::error_interceptor::ErrorInterceptor;
 use axum::{
@@ -21,9 +22,6 @@ use axum::{
     response::IntoResponse,
     Json,
 };
-use logging_utils::create_ref_id_helper::create_ref_id;
-use logging_utils::create_task_id_helper::create_task_id;
-use logging_utils::create_task_ref_id_helper::create_task_ref_collection;
 use mongodb::bson::doc;
 use serde_json::json;
 use std::sync::Arc;
@@ -48,26 +46,11 @@ use tracing::{debug, error, info, instrument};
 )]
 #[instrument(skip_all)]
 pub async fn update_search_enabled_handler(
+    ctx: Ctx,
     Query(params): Query<QueryParams>,
     Path(app_name): Path<String>,
     State(app_state): State<Arc<AppState>>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
-    // Create a reference ID ,task ID and initialize the documentdb variables
-    let ref_id = create_ref_id();
-    let service_type = "UpdateSearch".to_string();
-    let task_id = create_task_id(&app_name, service_type);
-    let mongo_url = app_state.app_settings.mongo_db.mongo_db_url.clone();
-    let mongo_db_name = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_database_name
-        .clone();
-    let id_collection = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_id_collection
-        .clone();
-
     let filter = doc! {"app_name": &app_name};
     let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
 
@@ -89,22 +72,10 @@ pub async fn update_search_enabled_handler(
                 Err(e) => {
                     let error_message =
                         format!("Failed to deserialize update response. Error: {:?}", e);
-                    let ext_message = format!(
-                        "{} Use reference ID: {}",
-                        app_state.app_settings.general_message, ref_id
-                    );
-                    let _ = create_task_ref_collection(
-                        mongo_url,
-                        mongo_db_name,
-                        id_collection,
-                        app_name.clone(),
-                        task_id.clone(),
-                        ref_id,
-                    )
-                    .await;
+                    let ext_message = ctx.ext_message(&app_state);
                     error!(
                         app_name = app_name,
-                        task_id = task_id,
+                        task_id = ctx.task_id,
                         ext_message = ext_message,
                         message = error_message
                     );
@@ -135,22 +106,10 @@ pub async fn update_search_enabled_handler(
         }
         Err(e) => {
             let error_message = format!("Failed to update app '{}'. Error: {}", app_name, e);
-            let ext_message = format!(
-                "{} Use reference ID: {}",
-                app_state.app_settings.general_message, ref_id
-            );
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.clone(),
-                task_id.clone(),
-                ref_id,
-            )
-            .await;
+            let ext_message = ctx.ext_message(&app_state);
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
@@ -175,6 +134,7 @@ mod tests {
 
             // Call the function
             let result = update_search_enabled_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Query(QueryParams {
                     page: Some(1),
                     limit: Some(10),
@@ -187,6 +147,17 @@ mod tests {
                     end_timestamp: None,
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 Path(app_name),
                 State(app_state),
@@ -209,6 +180,7 @@ mod tests {
 
             // Call the function
             let result = update_search_enabled_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Query(QueryParams {
                     page: Some(1),
                     limit: Some(10),
@@ -221,6 +193,17 @@ mod tests {
                     end_timestamp: None,
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 Path(app_name),
                 State(app_state),
@@ -243,6 +226,7 @@ mod tests {
 
             // Call the function
             let result = update_search_enabled_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Query(QueryParams {
                     page: Some(1),
                     limit: Some(10),
@@ -255,6 +239,17 @@ mod tests {
                     end_timestamp: None,
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 Path(app_name),
                 State(app_state),
@@ -285,6 +280,7 @@ mod tests {
 
             // Call the function
             let result = update_search_enabled_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Query(QueryParams {
                     page: Some(1),
                     limit: Some(10),
@@ -297,6 +293,17 @@ mod tests {
                     end_timestamp: None,
                     utc_start_timestamp: None,
                     utc_end_timestamp: None,
+                    cursor: None,
+                    tz: None,
+                    interval: None,
+                    format: None,
+                    dry_run: None,
+                    async_validation: None,
+                    override_limits: None,
+                    fields: None,
+                    full_content: None,
+                    metadata: None,
+                    federated: None,
                 }),
                 Path(app_name),
                 State(app_state),
@@ -0,0 +1,319 @@
+/*
+ * Created Date:  Aug 4, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the handlers for the shadow traffic of an app (see `crate::service::shadow_traffic`).
+//! The handlers are mounted at `/api/v1.1/admin/apps/{app_name}/shadow-traffic`.
+//! The GET handler returns the shadow traffic stored in the app document, null if not set.
+//! The PUT handler sets the shadow traffic, replacing the previous one. The results of the previous alternate engine
+//! stay in the report, under its URL.
+//! The DELETE handler stops the shadow traffic; its results stay in the `-shadow` collection of the app.
+//! The GET handler of `/report` aggregates the shadow results of the app by URL of the alternate engine: shadowed
+//! retrievals, failures, same answer rate, overlap of the cited sources, latencies and token delta.
+//! The handlers return a 200 status code if the shadow traffic is fetched/updated successfully.
+//! The handlers return a 400 status code if the shadow traffic is invalid.
+//! The handlers return a 404 status code if the app, or the shadow traffic to delete, is not found.
+//! The handlers return a 500 status code if an error occurs while fetching/updating the shadow traffic.
+//!
+
+use crate::admin_ui_api::schema::UpdateResponse;
+use crate::service::ctx::Ctx;
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::shadow_traffic::{
+    shadow_report_pipeline, ShadowReport, ShadowTraffic, ShadowTrafficError,
+    SHADOW_COLLECTION_SUFFIX, SHADOW_TRAFFIC_FIELD,
+};
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, to_bson};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// GET handler to get the shadow traffic of an app.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/shadow-traffic",
+    responses(
+        (status = 200, description = "Shadow traffic retrieved successfully.", body = ShadowTraffic),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_shadow_traffic_handler(
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let shadow_traffic = find_shadow_traffic(&app_state, &app_name).await?;
+    let success_message = format!("Shadow traffic of '{}' retrieved successfully.", app_name);
+    info!(app_name = app_name, message = success_message);
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "data": shadow_traffic}),
+    ))
+}
+
+/// PUT handler to set the shadow traffic of an app.
+#[utoipa::path(
+    put,
+    path = "/api/v1.1/admin/apps/{app_name}/shadow-traffic",
+    request_body = ShadowTraffic,
+    responses(
+        (status = 200, description = "Shadow traffic saved successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn put_shadow_traffic_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(shadow_traffic): Json<ShadowTraffic>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    shadow_traffic.validate()?;
+    find_shadow_traffic(&app_state, &app_name).await?;
+    store_shadow_traffic(&ctx, &app_state, &app_name, Some(&shadow_traffic)).await?;
+
+    let success_message = format!("Shadow traffic of '{}' saved successfully.", app_name);
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "Shadow traffic saved",
+        details = json!(shadow_traffic).to_string(),
+        message = success_message
+    );
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "app_name": app_name}),
+    ))
+}
+
+/// DELETE handler to stop the shadow traffic of an app.
+#[utoipa::path(
+    delete,
+    path = "/api/v1.1/admin/apps/{app_name}/shadow-traffic",
+    responses(
+        (status = 200, description = "Shadow traffic deleted successfully."),
+        (status = StatusCode::NOT_FOUND, description = "App or shadow traffic not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn delete_shadow_traffic_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let Some(shadow_traffic) = find_shadow_traffic(&app_state, &app_name).await? else {
+        return Err(ShadowTrafficError::NotConfigured(app_name).into());
+    };
+    store_shadow_traffic(&ctx, &app_state, &app_name, None).await?;
+
+    let success_message = format!("Shadow traffic of '{}' deleted successfully.", app_name);
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "Shadow traffic deleted",
+        details = shadow_traffic.url,
+        message = success_message
+    );
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "app_name": app_name}),
+    ))
+}
+
+/// GET handler to get the comparison report of the shadow traffic of an app, by alternate engine.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/shadow-traffic/report",
+    responses(
+        (status = 200, description = "Shadow traffic report retrieved successfully.", body = [ShadowReport]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::GATEWAY_TIMEOUT, description = "The aggregation exceeded its time limit", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_shadow_traffic_report_handler(
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    find_shadow_traffic(&app_state, &app_name).await?;
+
+    // The aggregation runs on the analytics connection of the app residency, when configured
+    let shadow_collection_name = format!("{}{}", app_name, SHADOW_COLLECTION_SUFFIX);
+    let analytics_db = app_state.app_analytics_db(&app_name).await?;
+    let report = analytics_db
+        .aggregate(
+            &shadow_collection_name,
+            shadow_report_pipeline(),
+            &app_state.options::<QueryOptions>(),
+        )
+        .await?
+        .into_iter()
+        .map(serde_json::from_value::<ShadowReport>)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            let error_message = format!(
+                "Failed to deserialize shadow traffic report of app '{}'. Error: {}",
+                app_name, e
+            );
+            error!(app_name = app_name, message = error_message);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"status": "error", "message": error_message})),
+            )
+        })?;
+
+    let success_message = format!(
+        "Shadow traffic report of '{}' retrieved successfully.",
+        app_name
+    );
+    info!(app_name = app_name, message = success_message);
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "data": report}),
+    ))
+}
+
+/// Returns the shadow traffic of an app. Unknown apps are answered with a 404.
+async fn find_shadow_traffic(
+    app_state: &AppState,
+    app_name: &str,
+) -> Result<Option<ShadowTraffic>, (StatusCode, Json<serde_json::Value>)> {
+    let apps = app_state.apps();
+    if !apps.exists(app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        debug!(message = error_message);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+    Ok(apps.shadow_traffic(app_name).await?)
+}
+
+/// Stores the shadow traffic of an app on its app document, null to stop it.
+async fn store_shadow_traffic(
+    ctx: &Ctx,
+    app_state: &AppState,
+    app_name: &str,
+    shadow_traffic: Option<&ShadowTraffic>,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let filter = doc! {"app_name": app_name};
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let error_message = match to_bson(&shadow_traffic) {
+        Ok(shadow_traffic_bson) => match app_state
+            .db
+            .update_document(
+                collection_name,
+                filter,
+                doc! {SHADOW_TRAFFIC_FIELD: shadow_traffic_bson},
+            )
+            .await
+            .map_err(ErrorInterceptor::from)
+        {
+            Ok(json_result) => match serde_json::from_value::<UpdateResponse>(json_result) {
+                Ok(result) if result.matchedCount == 0 => {
+                    let error_message = format!("No app found with name '{}'.", app_name);
+                    debug!(message = error_message);
+                    return Err((
+                        StatusCode::NOT_FOUND,
+                        Json(json!({"status": "error", "message": error_message})),
+                    ));
+                }
+                Ok(_) => None,
+                Err(e) => Some(format!(
+                    "Failed to deserialize update response. Error: {:?}",
+                    e
+                )),
+            },
+            Err(e) => Some(format!(
+                "Failed to update shadow traffic of app '{}'. Error: {}",
+                app_name, e
+            )),
+        },
+        Err(e) => Some(format!(
+            "Failed to serialize shadow traffic to BSON. Error: {}",
+            e
+        )),
+    };
+    if let Some(error_message) = error_message {
+        let ext_message = ctx.ext_message(app_state);
+        error!(
+            app_name = app_name,
+            task_id = ctx.task_id,
+            ext_message = ext_message,
+            message = error_message
+        );
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_put_shadow_traffic_handler_invalid_shadow_traffic() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+            let shadow_traffic = ShadowTraffic {
+                url: "http://engine-v2:8000/retrieve".to_string(),
+                percent: 0,
+                enabled: true,
+            };
+
+            // Call the function
+            let result = put_shadow_traffic_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                State(app_state),
+                Json(shadow_traffic),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn test_failure_get_shadow_traffic_report_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let result = get_shadow_traffic_report_handler(Path(app_name), State(app_state)).await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
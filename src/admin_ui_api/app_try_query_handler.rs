@@ -0,0 +1,124 @@
+/*
+ * Created Date:  Jul 30, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the POST handler of the API explorer of the admin UI, running a sandbox retrieval of an app
+//! to verify it answers right after its onboarding (see `crate::retrieval::sandbox`).
+//! The handler is mounted at `/api/v1.1/admin/apps/{app_name}/try-query`. It takes the body of a retrieval request,
+//! runs it as the app without exposing its API key, and returns the history document of the retrieval, flagged as
+//! `sandbox`. The sandbox retrieval is sent to the audit microservice.
+//! The handler returns a 200 status code if the sandbox retrieval ran, even if the knowledge engine failed: the
+//! history document then holds the error.
+//! The handler returns a 400 status code if the query or the user ID is empty.
+//! The handler returns a 404 status code if the app is not found.
+//! The handler returns a 500 status code if an error occurs while running the sandbox retrieval.
+//!
+
+use crate::retrieval::sandbox::{run_sandbox_retrieval, validate_sandbox_request};
+use crate::service::ctx::Ctx;
+use crate::service::state::AppState;
+use crate::service::timestamp::serve_timestamps;
+use api_utils::retrieval_model::RetrievalRequest;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, info, instrument};
+
+/// POST handler to run a sandbox retrieval of an app.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/apps/{app_name}/try-query",
+    request_body = RetrievalRequest,
+    responses(
+        (status = 200, description = "Sandbox retrieval ran.", body = HistoryDocument),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_try_query_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(body): Json<RetrievalRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    validate_sandbox_request(&body)?;
+    if !app_state.apps().exists(&app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        debug!(app_name = app_name, message = error_message);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    let history_document = run_sandbox_retrieval(&app_state, &app_name, body, ctx.deadline).await?;
+    let outcome = match history_document.timestamp.is_failed() {
+        true => "failed",
+        false => "answered",
+    };
+    let success_message = format!(
+        "Sandbox retrieval '{}' of app '{}' {}.",
+        history_document.reference_id, app_name, outcome
+    );
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "Sandbox retrieval",
+        details = success_message,
+        message = success_message
+    );
+    let mut body = json!({
+        "status": "success",
+        "message": success_message,
+        "app_name": app_name,
+        "reference_id": history_document.reference_id,
+        "data": history_document
+    });
+    serve_timestamps(&mut body);
+    Ok(Json(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Read;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_post_try_query_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and a retrieval request
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let mut file = File::open("src/test/retrieval_request.json").unwrap();
+            let mut buff = String::new();
+            file.read_to_string(&mut buff).unwrap();
+            let body: RetrievalRequest = serde_json::from_str(&buff).unwrap();
+
+            // Call the function
+            let result = post_try_query_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path("app_not_onboarded".to_string()),
+                State(app_state),
+                Json(body),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
@@ -0,0 +1,142 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET handler revealing the end user ID behind a pseudonym of an app, for the apps
+//! onboarded with `pseudonymize_user_ids: true`.
+//! The handler is mounted at `/api/v1.1/admin/apps/{app_name}/user-pseudonyms/{pseudonym}`.
+//! Only the service accounts granted the `identity:reveal` scope can reveal a user ID, the scope being taken from the
+//! account authenticated by `authorize_service_account`, never from a header of the request. Every reveal is audited
+//! with the account.
+//! The handler returns a 200 status code with the user ID behind the pseudonym.
+//! The handler returns a 403 status code if the request is not authenticated as a service account granted the scope.
+//! The handler returns a 404 status code if the pseudonym is unknown.
+//! The handler returns a 500 status code if an error occurs while reading or decrypting the user ID.
+//!
+
+use crate::service::ctx::Ctx;
+use crate::service::pseudonymization::{can_reveal, reveal_user_id};
+use crate::service::service_account::{Scope, ServiceAccount};
+use crate::service::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// GET handler to reveal the end user ID behind a pseudonym of an app.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/apps/{app_name}/user-pseudonyms/{pseudonym}",
+    responses(
+        (status = 200, description = "User ID revealed."),
+        (status = StatusCode::FORBIDDEN, description = "Reveal scope missing", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "Pseudonym not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_user_pseudonym_handler(
+    ctx: Ctx,
+    account: Option<Extension<ServiceAccount>>,
+    Path((app_name, pseudonym)): Path<(String, String)>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let account = account.map(|Extension(account)| account);
+    if !can_reveal(account.as_ref()) {
+        let error_message = format!(
+            "A service account granted the '{}' scope is required to reveal the user ID behind a pseudonym.",
+            Scope::IdentityReveal.as_str()
+        );
+        debug!(message = error_message);
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    let user_id = match reveal_user_id(&app_state, &app_name, &pseudonym).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => {
+            let error_message = format!(
+                "No user found with pseudonym '{}' for app '{}'.",
+                pseudonym, app_name
+            );
+            debug!(message = error_message);
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(json!({"status": "error", "message": error_message})),
+            ));
+        }
+        Err(e) => {
+            let error_message = format!(
+                "Failed to reveal the user ID behind pseudonym '{}'. Error: {}",
+                pseudonym, e
+            );
+            error!(app_name = app_name, message = error_message);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"status": "error", "message": error_message})),
+            ));
+        }
+    };
+
+    // The pseudonym is audited, never the revealed user ID
+    let success_message = format!(
+        "User ID behind pseudonym '{}' revealed to service account '{}'.",
+        pseudonym,
+        account.map(|account| account.client_id).unwrap_or_default()
+    );
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "User ID revealed",
+        details = success_message,
+        message = success_message
+    );
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "app_name": app_name,
+        "pseudonym": pseudonym,
+        "user_id": user_id,
+        "reference_id": ctx.reference_id
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_get_user_pseudonym_handler_forbidden() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+
+            // Call the function without an authenticated service account
+            let ctx = Ctx::new(&app_state, &app_name, "Test");
+            let result = get_user_pseudonym_handler(
+                ctx,
+                None,
+                Path((app_name, "psn_unknown".to_string())),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::FORBIDDEN);
+        });
+    }
+}
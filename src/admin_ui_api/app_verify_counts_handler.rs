@@ -0,0 +1,171 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the POST handler cross-checking the knowledge node counts of an app in DocumentDB against
+//! the counts reported by the knowledge engine for the vector store, to diagnose apps whose dashboard shows nodes
+//! that the retrievals do not find.
+//! The handler is mounted at `/api/v1.1/admin/apps/{app_name}/verify-counts`.
+//! The stored nodes are counted by source like the node statistics, and every source is reported with both counts
+//! and its status. Inconsistent apps are counted by the `Node Count Discrepancy Counter` metric.
+//! The handler returns a 200 status code with the per-source report, consistent or not.
+//! The handler returns a 404 status code if the app is not found.
+//! The handler returns a 500 status code if an error occurs while counting the stored nodes.
+//! The handler returns a 502 status code if the counts of the knowledge engine cannot be fetched.
+//!
+
+use crate::admin_ui_api::app_knowledge_nodes_stats_handler::source_key_expression;
+use crate::service::ctx::Ctx;
+use crate::service::metrics::{MetricRecord, APP_NAME_DIMENSION};
+use crate::service::node_count_check::{
+    compare_counts, engine_node_counts, CountStatus, SourceNodeCount,
+};
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::{doc, Document};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, info, instrument, warn};
+
+/// POST handler to cross-check the knowledge node counts of an app against the knowledge engine.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/apps/{app_name}/verify-counts",
+    responses(
+        (status = 200, description = "Node counts cross-checked.", body = [SourceCountCheck]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support."),
+        (status = StatusCode::BAD_GATEWAY, description = "Knowledge engine counts unavailable", body = [ErrorResponse])
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_verify_counts_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    if !app_state.apps().exists(&app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        debug!(message = error_message);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    // The aggregation runs on the analytics connection of the app residency, when configured
+    let analytics_db = app_state.app_analytics_db(&app_name).await?;
+    let stored_counts: Vec<SourceNodeCount> = analytics_db
+        .aggregate(
+            &format!("{}-general", app_name),
+            source_counts_pipeline(),
+            &app_state.options::<QueryOptions>(),
+        )
+        .await?
+        .into_iter()
+        .filter_map(|count| serde_json::from_value(count).ok())
+        .collect();
+    let engine_counts = engine_node_counts(&app_state, &app_name).await?;
+
+    let sources = compare_counts(&stored_counts, &engine_counts);
+    let stored_total: i64 = sources.iter().map(|source| source.stored_count).sum();
+    let engine_total: i64 = sources.iter().map(|source| source.engine_count).sum();
+    let discrepancies = sources
+        .iter()
+        .filter(|source| source.status != CountStatus::Match)
+        .count();
+
+    let message = if discrepancies == 0 {
+        let message = format!(
+            "Node counts of app '{}' are consistent: {} nodes in {} sources.",
+            app_name,
+            stored_total,
+            sources.len()
+        );
+        info!(app_name = app_name, message = message);
+        message
+    } else {
+        let message = format!(
+            "Node counts of app '{}' differ in {} of {} sources: {} stored nodes, {} in the knowledge engine.",
+            app_name,
+            discrepancies,
+            sources.len(),
+            stored_total,
+            engine_total
+        );
+        warn!(
+            app_name = app_name,
+            task_id = ctx.task_id,
+            message = message
+        );
+        app_state
+            .record_metric(
+                MetricRecord::counter("Node Count Discrepancy Counter")
+                    .dimension(APP_NAME_DIMENSION, &app_name),
+            )
+            .await;
+        message
+    };
+
+    Ok(Json(json!({
+        "status": "success",
+        "message": message,
+        "app_name": app_name,
+        "consistent": discrepancies == 0,
+        "stored_total": stored_total,
+        "engine_total": engine_total,
+        "sources": sources,
+        "reference_id": ctx.reference_id
+    })))
+}
+
+/// Aggregation pipeline on the nodes collection counting the nodes per source.
+fn source_counts_pipeline() -> Vec<Document> {
+    vec![
+        doc! {
+            "$group": {
+                "_id": source_key_expression(),
+                "node_count": { "$sum": 1 },
+            }
+        },
+        doc! {
+            "$project": {
+                "_id": 0,
+                "source": "$_id",
+                "node_count": 1,
+            }
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_post_verify_counts_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let ctx = Ctx::new(&app_state, &app_name, "Test");
+            let result = post_verify_counts_handler(ctx, Path(app_name), State(app_state)).await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
@@ -3,28 +3,66 @@
  * -----
  * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
  */
-//! This module contains the GET handler for fetching the overview of calls made from different apps during the last 6 months.
-//! The handler is used by the admin UI to fetch the overview of calls made from different apps during the last 6 months.
+//! This module contains the GET handler for fetching the overview of calls made from different apps.
+//! The handler is used by the admin UI to fetch the overview of calls made from different apps during the last 6 months,
+//! or between the optional `utc_start_timestamp` and `utc_end_timestamp` query parameters.
 //! The handler is mounted at `/api/v1.1/admin/overview`.
+//! The monthly overview and the per-day call series are computed with a single faceted aggregation.
+//! With `format=csv` the per-day call series is returned as a CSV export.
+//! The JSON overview also lists the apps whose ingestion is paused, and the labels of the deployment.
+//! With `federated=true` the overviews of the peer deployments (`federation.peers`) are fetched and summed with the
+//! local overview, and the status of every deployment is listed (see `crate::service::federation`).
 //! The handler returns the overview of apps and calls if it exists, else returns an error message.
 //! The handler returns a 200 status code if the overview is fetched successfully.
 //! The handler returns a 400 status code if an error occurs while fetching the overview.
 //! The handler returns a 500 status code if an error occurs while fetching the overview.
 //! The handler returns a JSON response with the status and message.
 //!
+use crate::admin_ui_api::schema::QueryParams;
+use crate::service::federation::{
+    daily_key, fetch_peer_overviews, merge_series, monthly_key, DeploymentOverview,
+};
+use crate::service::query_options::{AggregateExt, QueryError, QueryOptions};
 use crate::service::state::AppState;
-use api_utils::errors::error_interceptor::ErrorInterceptor;
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
-use chrono::{Duration, Utc};
+use crate::service::timestamp::timestamp_range;
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
 use mongodb::bson::doc;
 use serde_json::json;
 use std::sync::Arc;
 use tracing::{debug, instrument};
 
-/// GET handler to fetch the overview of calls made from different apps during the last 6 months.
+/// GET handler to fetch the overview of calls made from different apps during the last 6 months or the given date range.
 #[utoipa::path(
     get,
     path = "/api/v1.1/admin/overview",
+    params(
+        (
+            "utc_start_timestamp" = inline(Option<DateTime<Utc>>),
+            Query,
+            description = "UTC start timestamp. Defaults to 6 months before the end timestamp.",
+        ),
+        (
+            "utc_end_timestamp" = inline(Option<DateTime<Utc>>),
+            Query,
+            description = "UTC end timestamp. Defaults to now.",
+        ),
+        (
+            "format" = inline(Option<String>),
+            Query,
+            description = "Response format: json (default) or csv for the per-day call series.",
+        ),
+        (
+            "federated" = inline(Option<bool>),
+            Query,
+            description = "Whether to aggregate the overviews of the peer deployments. Defaults to false.",
+        )
+    ),
     responses(
         (status = 200, description = "Overview of apps and calls fetched successfully."),
         (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
@@ -33,110 +71,234 @@ use tracing::{debug, instrument};
 )]
 #[instrument(skip_all)]
 pub async fn get_apps_and_calls_overview_handler(
+    Query(params): Query<QueryParams>,
     State(app_state): State<Arc<AppState>>,
-) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
     let collection_name = &app_state
         .app_settings
         .mongo_db
         .mongo_db_ui_summary_collection;
-    let iso_date_6_months_ago = match Utc::now().checked_sub_signed(Duration::days(180)) {
-        Some(date) => date,
-        None => {
-            let error_message = "Failed to calculate the date 6 months ago from the current date.";
+
+    let export_csv = match params.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(format) => {
+            let error_message = format!(
+                "Invalid format '{}'. Supported formats are json and csv.",
+                format
+            );
             debug!(message = error_message);
             return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::BAD_REQUEST,
                 Json(json!({"status": "error", "message": error_message})),
             ));
         }
     };
-    let date_string = iso_date_6_months_ago.to_rfc3339();
+
+    let end_timestamp = params.utc_end_timestamp.unwrap_or_else(Utc::now);
+    let start_timestamp = match params.utc_start_timestamp {
+        Some(start_timestamp) => start_timestamp,
+        None => match end_timestamp.checked_sub_signed(Duration::days(180)) {
+            Some(date) => date,
+            None => {
+                let error_message = "Failed to calculate the date 6 months ago from the end date.";
+                debug!(message = error_message);
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"status": "error", "message": error_message})),
+                ));
+            }
+        },
+    };
+    if start_timestamp > end_timestamp {
+        let error_message = format!(
+            "utc_start_timestamp '{}' is after utc_end_timestamp '{}'.",
+            start_timestamp.to_rfc3339(),
+            end_timestamp.to_rfc3339()
+        );
+        debug!(message = error_message);
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
 
     // Create an aggregation pipeline
     let aggregation_pipeline = vec![
-        // Filter out the documents with timestamp within the last 6 months from the current date
+        // Filter out the documents with timestamp within the date range
+        // The timestamps are stored as dates, see `crate::service::timestamp`
         doc! {
             "$match": {
-                "timestamp": {
-                    "$gte": date_string,
-                }
+                "timestamp": timestamp_range(start_timestamp, end_timestamp)
             }
         },
-        // Group by month and year. Then for each group, get all the unique apps and total calls made by those apps.
         doc! {
-            "$group": {
-                "_id": {
-                    "month": {
-                        "$month": {
-                            "$dateFromString": {
-                                "dateString": "$timestamp",
-                            },
-                        },
-                    },
-                    "year": {
-                        "$year": {
-                            "$dateFromString": {
-                                "dateString": "$timestamp",
-                            },
-                        },
-                    },
-                },
-                "app_names": {
-                    "$addToSet": "$app_name",
-                },
-                "total_calls": {
-                    "$sum": 1,
-                },
+            "$addFields": {
+                "date": "$timestamp",
             }
         },
-        // Get total no. of unique apps. Value '1' for 'total_count' indicates copying the field as is from the previous doc/stage
-        doc! {
-            "$project": {
-                "total_apps": {
-                    "$size": "$app_names"
-                },
-                "total_calls": 1
-            },
-        },
-        // Sort by 'year' first and then by 'month' (in case of same year). Value '1' for ascending order and '-1' for descending order
+        // Compute the monthly overview and the per-day series in a single pass over the matched documents
         doc! {
-            "$sort": {
-                "_id.year": 1,
-                "_id.month": 1
+            "$facet": {
+                // Group by month and year. Then for each group, get all the unique apps and total calls made by those apps.
+                "monthly": [
+                    {
+                        "$group": {
+                            "_id": {
+                                "month": { "$month": "$date" },
+                                "year": { "$year": "$date" },
+                            },
+                            "app_names": { "$addToSet": "$app_name" },
+                            "total_calls": { "$sum": 1 },
+                        }
+                    },
+                    // Get total no. of unique apps. Value '1' for 'total_count' indicates copying the field as is from the previous doc/stage
+                    {
+                        "$project": {
+                            "total_apps": { "$size": "$app_names" },
+                            "total_calls": 1
+                        }
+                    },
+                    // Sort by 'year' first and then by 'month' (in case of same year)
+                    { "$sort": { "_id.year": 1, "_id.month": 1 } },
+                ],
+                // Group by day, with the unique apps and total calls for each day
+                "daily": [
+                    {
+                        "$group": {
+                            "_id": { "$dateToString": { "format": "%Y-%m-%d", "date": "$date" } },
+                            "app_names": { "$addToSet": "$app_name" },
+                            "total_calls": { "$sum": 1 },
+                        }
+                    },
+                    {
+                        "$project": {
+                            "_id": 0,
+                            "date": "$_id",
+                            "total_apps": { "$size": "$app_names" },
+                            "total_calls": 1
+                        }
+                    },
+                    { "$sort": { "date": 1 } },
+                ],
             }
         },
     ];
 
+    // The overview runs on the analytics connection of the primary cluster, when configured
     match app_state
-        .db
-        .aggregation_ops_on_documents(collection_name, aggregation_pipeline)
+        .analytics_db(None)?
+        .aggregate(
+            collection_name,
+            aggregation_pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
         .await
-        .map_err(ErrorInterceptor::from)
     {
         Ok(results) => {
-            let success_message = format!(
-                "Overview of apps and calls fetched successfully from {} onwards",
-                iso_date_6_months_ago
-            );
+            let facets = results.into_iter().next().unwrap_or_default();
+            let mut monthly = facets.get("monthly").cloned().unwrap_or(json!([]));
+            let mut daily = facets.get("daily").cloned().unwrap_or(json!([]));
+
+            // Sum the overviews of the peer deployments, the failed peers being left out
+            let peers = if params.federated.unwrap_or(false) {
+                let peers = fetch_peer_overviews(&app_state, start_timestamp, end_timestamp).await;
+                monthly = merge_series(
+                    std::iter::once(&monthly).chain(peers.iter().map(|peer| &peer.monthly)),
+                    monthly_key,
+                );
+                daily = merge_series(
+                    std::iter::once(&daily).chain(peers.iter().map(|peer| &peer.daily)),
+                    daily_key,
+                );
+                Some(peers)
+            } else {
+                None
+            };
+
+            let success_message = match &peers {
+                Some(peers) => format!(
+                    "Overview of apps and calls fetched successfully from {} to {} across {} of {} deployments",
+                    start_timestamp,
+                    end_timestamp,
+                    peers
+                        .iter()
+                        .filter(|peer| peer.deployment.error.is_none())
+                        .count()
+                        + 1,
+                    peers.len() + 1
+                ),
+                None => format!(
+                    "Overview of apps and calls fetched successfully from {} to {}",
+                    start_timestamp, end_timestamp
+                ),
+            };
             debug!(message = success_message);
-            Ok(Json(
-                json!({"status": "success", "message": success_message, "data": results}),
-            ))
+            if export_csv {
+                return Ok((
+                    [
+                        (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+                        (
+                            header::CONTENT_DISPOSITION,
+                            "attachment; filename=\"apps_and_calls_overview.csv\"",
+                        ),
+                    ],
+                    daily_series_csv(&daily),
+                )
+                    .into_response());
+            }
+            let paused_apps = app_state.apps().paused_apps().await?;
+            let labels = app_state.deployment_labels();
+            let mut overview = json!({
+                "status": "success",
+                "message": success_message,
+                "data": monthly,
+                "daily": daily,
+                "paused_apps": paused_apps,
+                "deployment": labels
+            });
+            if let Some(peers) = peers {
+                // The local totals are read from the local series, before they were summed with the peers
+                let local_monthly = facets.get("monthly").cloned().unwrap_or(json!([]));
+                let deployments: Vec<DeploymentOverview> = std::iter::once(
+                    DeploymentOverview::local(&labels, &local_monthly, paused_apps),
+                )
+                .chain(peers.into_iter().map(|peer| peer.deployment))
+                .collect();
+                overview["deployments"] = json!(deployments);
+            }
+            Ok(Json(overview).into_response())
         }
-        Err(e) => {
+        Err(QueryError::Db(e)) => {
             let error_message = format!(
-                "Failed to fetch the overview of apps and calls from {} onwards. Error: {}",
-                iso_date_6_months_ago, e
+                "Failed to fetch the overview of apps and calls from {} to {}. Error: {}",
+                start_timestamp, end_timestamp, e
             );
             debug!(message = error_message);
             Err(e.intercept_error().await)
         }
+        Err(e) => Err(e.into()),
     }
 }
 
+/// Renders the per-day call series as CSV with a header row.
+fn daily_series_csv(daily: &serde_json::Value) -> String {
+    let mut csv = String::from("date,total_apps,total_calls\n");
+    for day in daily.as_array().into_iter().flatten() {
+        csv.push_str(&format!(
+            "{},{},{}\n",
+            day.get("date").and_then(|v| v.as_str()).unwrap_or_default(),
+            day.get("total_apps").and_then(|v| v.as_i64()).unwrap_or(0),
+            day.get("total_calls").and_then(|v| v.as_i64()).unwrap_or(0),
+        ));
+    }
+    csv
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use axum::body::to_bytes;
     use tokio::runtime::Runtime;
 
     #[test]
@@ -148,12 +310,109 @@ mod tests {
             let app_state = crate::tests::test_get_appstate().await.unwrap();
 
             // Call the function
-            let result = get_apps_and_calls_overview_handler(State(app_state)).await;
+            let result = get_apps_and_calls_overview_handler(
+                Query(QueryParams::default()),
+                State(app_state),
+            )
+            .await;
 
             // Check if the function returns Ok
             assert!(result.is_ok());
         });
     }
+
+    #[test]
+    fn test_success_apps_and_calls_overview_handler_csv() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let response = get_apps_and_calls_overview_handler(
+                Query(QueryParams {
+                    utc_start_timestamp: Some(Utc::now() - Duration::days(7)),
+                    format: Some("csv".to_string()),
+                    ..Default::default()
+                }),
+                State(app_state),
+            )
+            .await
+            .unwrap();
+
+            // Check the content type of the export
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().get(header::CONTENT_TYPE).unwrap(),
+                "text/csv; charset=utf-8"
+            );
+        });
+    }
+
+    #[test]
+    fn test_success_apps_and_calls_overview_handler_federated() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let response = get_apps_and_calls_overview_handler(
+                Query(QueryParams {
+                    federated: Some(true),
+                    ..Default::default()
+                }),
+                State(app_state),
+            )
+            .await
+            .unwrap();
+
+            // Check the local deployment is listed
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let overview: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(overview["deployments"][0]["status"], "ok");
+        });
+    }
+
+    #[test]
+    fn test_failure_apps_and_calls_overview_handler_invalid_range() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let result = get_apps_and_calls_overview_handler(
+                Query(QueryParams {
+                    utc_start_timestamp: Some(Utc::now()),
+                    utc_end_timestamp: Some(Utc::now() - Duration::days(1)),
+                    ..Default::default()
+                }),
+                State(app_state),
+            )
+            .await;
+
+            // If the function returns Err, check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn test_success_daily_series_csv() {
+        let daily = json!([
+            {"date": "2024-05-01", "total_apps": 2, "total_calls": 10},
+            {"date": "2024-05-02", "total_apps": 1, "total_calls": 3},
+        ]);
+        assert_eq!(
+            daily_series_csv(&daily),
+            "date,total_apps,total_calls\n2024-05-01,2,10\n2024-05-02,1,3\n"
+        );
+    }
     /*  todo : fix this test
     #[test]
     #[ignore="until aggregation_ops_on_documents returns an error"]
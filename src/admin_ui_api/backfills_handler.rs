@@ -0,0 +1,222 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the handlers for the backfills, see `backfill`.
+//! The handlers are mounted at `/api/v1.1/admin/backfills`.
+//! The GET handler returns the backfills which can be run, whether they are enabled and the latest backfill jobs.
+//! The POST handler of `/{backfill}` starts a backfill as a background job and returns it with a 202 status code.
+//! With `dry_run=true` the job only counts the documents to backfill.
+//! The GET handler of `/jobs/{job_id}` returns the status and progress of a backfill job.
+//! The handlers return a 403 status code if the backfills are disabled.
+//! The handlers return a 404 status code if the backfill or the job is not found.
+//! The handlers return a 409 status code if the backfill is already running.
+//! The handlers return a 500 status code if an error occurs while storing/fetching the jobs.
+//!
+
+use crate::admin_ui_api::schema::QueryParams;
+use crate::service::backfill::{
+    backfill_job, backfill_jobs, start_backfill, Backfill, BackfillError, BackfillJob,
+    BackfillOptions,
+};
+use crate::service::ctx::Ctx;
+use crate::service::state::AppState;
+use crate::service::timestamp::serve_timestamps;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{info, instrument};
+
+/// GET handler to get the backfills and the latest backfill jobs.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/backfills",
+    responses(
+        (status = 200, description = "Backfills retrieved successfully.", body = [BackfillJob]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_backfills_handler(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let backfills: Vec<serde_json::Value> = Backfill::ALL
+        .iter()
+        .map(|backfill| json!({"backfill": backfill, "description": backfill.description()}))
+        .collect();
+    let mut jobs = json!(backfill_jobs(&app_state).await?);
+    serve_timestamps(&mut jobs);
+
+    let success_message = "Backfills retrieved successfully.".to_string();
+    info!(message = success_message);
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "enabled": app_state.options::<BackfillOptions>().enabled,
+        "backfills": backfills,
+        "data": jobs,
+    })))
+}
+
+/// POST handler to start a backfill.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/backfills/{backfill}",
+    params(
+        (
+            "dry_run" = inline(Option<bool>),
+            Query,
+            description = "Only count the documents to backfill.",
+        )
+    ),
+    responses(
+        (status = 202, description = "Backfill started.", body = [BackfillJob]),
+        (status = StatusCode::FORBIDDEN, description = "Backfills disabled", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "Unknown backfill", body = [ErrorResponse]),
+        (status = StatusCode::CONFLICT, description = "Backfill already running", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_backfill_handler(
+    ctx: Ctx,
+    Path(backfill): Path<String>,
+    Query(params): Query<QueryParams>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let backfill = Backfill::parse(&backfill).ok_or(BackfillError::UnknownBackfill(backfill))?;
+    let dry_run = params.dry_run.unwrap_or(false);
+    let job = start_backfill(&app_state, backfill, dry_run).await?;
+    let mut data = json!(job);
+    serve_timestamps(&mut data);
+
+    let success_message = format!(
+        "Backfill '{}' started as job '{}'.",
+        backfill.as_str(),
+        job.job_id
+    );
+    info!(message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        action = "Backfill started",
+        details =
+            json!({"backfill": backfill, "job_id": job.job_id, "dry_run": dry_run}).to_string(),
+        message = success_message
+    );
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "status": "success",
+            "message": success_message,
+            "data": data,
+        })),
+    ))
+}
+
+/// GET handler to get the status and progress of a backfill job.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/backfills/jobs/{job_id}",
+    responses(
+        (status = 200, description = "Backfill job retrieved successfully.", body = [BackfillJob]),
+        (status = StatusCode::NOT_FOUND, description = "Backfill job not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_backfill_job_handler(
+    Path(job_id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let mut data = json!(backfill_job(&app_state, &job_id).await?);
+    serve_timestamps(&mut data);
+
+    let success_message = format!("Backfill job '{}' retrieved successfully.", job_id);
+    info!(message = success_message);
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "data": data,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_post_backfill_handler_unknown_backfill() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let result = post_backfill_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path("rollups".to_string()),
+                Query(QueryParams::default()),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+
+    #[test]
+    fn test_failure_post_backfill_handler_disabled() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let result = post_backfill_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path("timestamps".to_string()),
+                Query(QueryParams {
+                    dry_run: Some(true),
+                    ..Default::default()
+                }),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::FORBIDDEN);
+        });
+    }
+
+    #[test]
+    fn test_failure_get_backfill_job_handler_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let result =
+                get_backfill_job_handler(Path("non-existing-job".to_string()), State(app_state))
+                    .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
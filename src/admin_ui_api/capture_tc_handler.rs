@@ -13,12 +13,10 @@
 //!
 
 use crate::admin_ui_api::schema::{CaptureTcSchema, CaptureUserSchema};
+use crate::service::ctx::Ctx;
 use crate::service::state::AppState;
 use axum::extract::Query;
 use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
-use logging_utils::create_ref_id_helper::create_ref_id;
-use logging_utils::create_task_id_helper::create_task_id;
-use logging_utils::create_task_ref_id_helper::create_task_ref_collection;
 use mongodb::bson::doc;
 use serde_json::json;
 use std::sync::Arc;
@@ -45,25 +43,12 @@ use tracing::{info, instrument};
 // This method captures the T & C and other user information from the admin UI , and stores it in the database through audit microservice.
 #[instrument(skip_all)]
 pub async fn post_capture_tc_handler(
+    ctx: Ctx,
     Query(params): Query<CaptureTcSchema>,
     State(app_state): State<Arc<AppState>>,
     Json(body): Json<CaptureUserSchema>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
-    let ref_id = create_ref_id();
     let app_name = app_state.app_settings.tracing_layer_system_app_name.clone();
-    let service_type = "CaptureT&C".to_string();
-    let task_id = create_task_id(&app_name, service_type);
-    let mongo_url = app_state.app_settings.mongo_db.mongo_db_url.clone();
-    let mongo_db_name = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_database_name
-        .clone();
-    let id_collection = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_id_collection
-        .clone();
     let user_name = body.user_name;
     let ui_type = body.ui_type;
     let is_tc = params.is_tc;
@@ -72,25 +57,17 @@ pub async fn post_capture_tc_handler(
         true => {
             // user has accepted the T & C
             let msg = format!("User :{} accepted the T&C from '{}' UI", user_name, ui_type);
-            info!(app_name = app_name, task_id = task_id, message = msg);
+            info!(app_name = app_name, task_id = ctx.task_id, message = msg);
             info!(
                 app_name = &app_name,
                 service = "audit_microservice",
-                task_id = task_id,
+                task_id = ctx.task_id,
                 user_id = "tresleai",
                 action = "Capture T&C information",
                 details = "User accepted the T&C",
                 message = msg,
             );
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.clone(),
-                task_id.clone(),
-                ref_id.clone(),
-            )
-            .await;
+            ctx.record(&app_state).await;
             Ok(Json(json!({"status": "success", "message":msg})))
         }
         false => {
@@ -99,25 +76,17 @@ pub async fn post_capture_tc_handler(
                 "User :{} did not accept the T&C from '{}' UI",
                 user_name, ui_type
             );
-            info!(app_name = app_name, task_id = task_id, message = msg);
+            info!(app_name = app_name, task_id = ctx.task_id, message = msg);
             info!(
                 app_name = &app_name,
                 service = "audit_microservice",
-                task_id = task_id,
+                task_id = ctx.task_id,
                 user_id = "tresleai",
                 action = "Capture T&C information",
                 details = "User accepted the T&C",
                 message = msg,
             );
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.clone(),
-                task_id.clone(),
-                ref_id.clone(),
-            )
-            .await;
+            ctx.record(&app_state).await;
             Ok(Json(json!({"status": "success", "message":msg})))
         }
     }
@@ -164,6 +133,7 @@ pub mod tests {
 
             // Call the function
             let result = post_capture_tc_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Query(query_params),
                 State(app_state),
                 axum::Json(app_config),
@@ -209,6 +179,7 @@ pub mod tests {
 
             // Call the function
             let result = post_capture_tc_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
                 Query(query_params),
                 State(app_state),
                 axum::Json(app_config),
@@ -0,0 +1,92 @@
+/*
+ * Created Date:  Jul 22, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET handler to inspect the effective settings of the service.
+//! The handler is mounted at `/api/v1.1/admin/config`.
+//! The settings are returned sanitized: no secrets, the MongoDB URLs reduced to their hosts. The `config_sources`
+//! lists the yaml files the settings were loaded from, including the profile overriding the local settings.
+//! The handler returns a 200 status code with the settings.
+//! The handler returns a 500 status code if the settings cannot be serialized.
+//!
+
+use crate::configuration::sanitized::sanitized_settings;
+use crate::service::ctx::Ctx;
+use crate::service::state::AppState;
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{error, info, instrument};
+
+/// GET handler to fetch the sanitized effective settings of the service.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/config",
+    responses(
+        (status = 200, description = "Settings fetched successfully."),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_config_handler(
+    ctx: Ctx,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    match sanitized_settings(&app_state.app_settings) {
+        Ok(settings) => {
+            let success_message = "Settings fetched successfully.".to_string();
+            info!(app_name = ctx.app_name, message = success_message);
+            Ok(Json(
+                json!({"status": "success", "message": success_message, "data": settings}),
+            ))
+        }
+        Err(e) => {
+            let error_message = format!("Failed to serialize the settings. Error: {}", e);
+            let ext_message = ctx.ext_message(&app_state);
+            error!(
+                app_name = ctx.app_name,
+                task_id = ctx.task_id,
+                ext_message = ext_message,
+                message = error_message
+            );
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"status": "error", "message": ext_message})),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_get_config_handler() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let ctx = Ctx::new(&app_state, "test_app", "Test");
+            let response = get_config_handler(ctx, State(app_state))
+                .await
+                .unwrap()
+                .into_response();
+
+            // Check the status code and that no credentials are returned
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["status"], "success");
+            assert!(body["data"]["mongo_db"]["mongo_db_url"]
+                .as_str()
+                .is_some_and(|url| !url.contains('@')));
+        });
+    }
+}
@@ -0,0 +1,92 @@
+/*
+ * Created Date:  Aug 2, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET handler of the health of the downstream Tresleai services.
+//! The handler is mounted at `/api/v1.1/admin/dependencies`.
+//! It probes the health endpoints of the logging, audit and metric microservices and of the knowledge engine
+//! concurrently (see `crate::service::dependency_health`), and returns their status, status code and latency.
+//! The handler returns a 200 status code with the health of the services, `status` being the worst of them.
+//!
+
+use crate::service::dependency_health::{
+    dependencies_health, DependenciesHealth, DependencyStatus,
+};
+use crate::service::state::AppState;
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, instrument, warn};
+
+/// GET handler to probe the health of the downstream Tresleai services.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/dependencies",
+    responses(
+        (status = 200, description = "Downstream services probed.", body = [DependenciesHealth]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_dependencies_handler(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let health: DependenciesHealth = dependencies_health(&app_state).await;
+    let unhealthy: Vec<&str> = health
+        .dependencies
+        .iter()
+        .filter(|dependency| dependency.status != DependencyStatus::Healthy)
+        .map(|dependency| dependency.name.as_str())
+        .collect();
+    let message = if unhealthy.is_empty() {
+        let message = "Every downstream service is healthy.".to_string();
+        debug!(message = message);
+        message
+    } else {
+        let message = format!("Unhealthy downstream services: {}.", unhealthy.join(", "));
+        warn!(message = message);
+        message
+    };
+    Ok(Json(
+        json!({"status": "success", "message": message, "data": health}),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_get_dependencies_handler() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let response = get_dependencies_handler(State(app_state))
+                .await
+                .unwrap()
+                .into_response();
+
+            // Check the status code and the probes
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let names: Vec<&str> = body["data"]["dependencies"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|dependency| dependency["name"].as_str().unwrap())
+                .collect();
+            assert_eq!(
+                names,
+                vec!["logging", "audit", "metric", "knowledge_engine"]
+            );
+        });
+    }
+}
@@ -0,0 +1,51 @@
+/*
+ * Created Date:  Jul 27, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET handler for the catalog of the error codes carried by the error responses of the
+//! service (see `crate::service::error_code`).
+//! The handler is mounted at `/api/v1.1/admin/errors/catalog`. The catalog is generated from the error codes, with
+//! the code, the name, the status code (unset if it varies) and the description of every error.
+//! The handler returns a 200 status code with the catalog.
+//!
+
+use crate::service::error_code::error_catalog;
+use axum::{response::IntoResponse, Json};
+use serde_json::json;
+use tracing::{info, instrument};
+
+/// GET handler to fetch the catalog of the error codes.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/errors/catalog",
+    responses(
+        (status = 200, description = "Error catalog fetched successfully.", body = [ErrorCatalogEntry]),
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_error_catalog_handler() -> impl IntoResponse {
+    let success_message = "Error catalog fetched successfully.".to_string();
+    info!(message = success_message);
+    Json(json!({"status": "success", "message": success_message, "data": error_catalog()}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_get_error_catalog_handler() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let response = get_error_catalog_handler().await.into_response();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["data"][0]["code"], "TRESLE-1001");
+            assert_eq!(body["data"][0]["status"], 404);
+        });
+    }
+}
@@ -0,0 +1,139 @@
+/*
+ * Created Date:  Aug 6, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the POST handler exploring the knowledge graph of an app, forwarding a graph query to the
+//! graph API of the knowledge engine.
+//! The handler is mounted at `/api/v1.1/admin/graph/{app_name}/query`.
+//! The body is a `neighbors` query, e.g. `{"type": "neighbors", "node_id": "...", "relationship": "CONTAINS",
+//! "depth": 2}`, or a `path` query, e.g. `{"type": "path", "from": "s3://bucket/hr", "to": "employees"}`.
+//! The handler returns a 200 status code with the nodes, edges and paths found.
+//! The handler returns a 400 status code if the query is invalid.
+//! The handler returns a 404 status code if the app is not found.
+//! The handler returns a 502 status code if the knowledge engine cannot answer the query.
+//!
+
+use crate::service::ctx::Ctx;
+use crate::service::graph_query::{query_graph, GraphQuery, GraphQueryOptions, GraphQueryResult};
+use crate::service::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, info, instrument};
+
+/// POST handler to query the knowledge graph of an app.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/graph/{app_name}/query",
+    request_body = GraphQuery,
+    responses(
+        (status = 200, description = "Knowledge graph queried.", body = [GraphQueryResult]),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid graph query", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support."),
+        (status = StatusCode::BAD_GATEWAY, description = "Knowledge engine graph unavailable", body = [ErrorResponse])
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_graph_query_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(query): Json<GraphQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let query = query.validated(&app_state.options::<GraphQueryOptions>())?;
+    if !app_state.apps().exists(&app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        debug!(message = error_message);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    let result: GraphQueryResult = query_graph(&app_state, &app_name, &query).await?;
+    let message = format!(
+        "Knowledge graph of app '{}' queried: {} nodes, {} edges and {} paths{}.",
+        app_name,
+        result.nodes.len(),
+        result.edges.len(),
+        result.paths.len(),
+        if result.truncated { ", truncated" } else { "" }
+    );
+    info!(
+        app_name = app_name,
+        task_id = ctx.task_id,
+        message = message
+    );
+
+    Ok(Json(json!({
+        "status": "success",
+        "message": message,
+        "app_name": app_name,
+        "query": query,
+        "data": result,
+        "reference_id": ctx.reference_id
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_post_graph_query_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+            let query = GraphQuery::Path {
+                from: "s3://bucket/hr".to_string(),
+                to: "employees".to_string(),
+                max_depth: None,
+            };
+
+            // Call the function
+            let ctx = Ctx::new(&app_state, &app_name, "Test");
+            let result =
+                post_graph_query_handler(ctx, Path(app_name), State(app_state), Json(query)).await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+
+    #[test]
+    fn test_failure_post_graph_query_handler_invalid_query() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+            let query = GraphQuery::Path {
+                from: "employees".to_string(),
+                to: "employees".to_string(),
+                max_depth: None,
+            };
+
+            // Call the function
+            let ctx = Ctx::new(&app_state, &app_name, "Test");
+            let result =
+                post_graph_query_handler(ctx, Path(app_name), State(app_state), Json(query)).await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+}
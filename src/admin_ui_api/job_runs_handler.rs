@@ -0,0 +1,111 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET handler for the runs of the background jobs (history retention, dead retrieval
+//! sweeper, log sink), run by the replica holding the lease of each job.
+//! The handler is mounted at `/api/v1.1/admin/jobs/runs`, with the optional `job` and `limit` (50 by default) query
+//! parameters. It returns the current leases of the jobs and their latest runs, the latest first.
+//! The handler returns a 200 status code if the runs are fetched successfully.
+//! The handler returns a 400 status code if the job is unknown or the limit too large.
+//! The handler returns a 500 status code if an error occurs while fetching the runs.
+//!
+
+use crate::admin_ui_api::schema::JobRunsParams;
+use crate::service::query_options::QueryOptions;
+use crate::service::scheduler::{job_leases, job_runs, Job};
+use crate::service::state::AppState;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, info, instrument};
+
+/// Default number of job runs.
+const DEFAULT_JOB_RUNS_LIMIT: usize = 50;
+
+/// GET handler to fetch the leases and the latest runs of the background jobs.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/jobs/runs",
+    params(
+        (
+            "job" = inline(Option<String>),
+            Query,
+            description = "Name of a job: history_retention, retrieval_sweeper or log_sink. All the jobs if unset.",
+        ),
+        (
+            "limit" = inline(Option<usize>),
+            Query,
+            description = "Number of runs, the latest first. Defaults to 50.",
+        )
+    ),
+    responses(
+        (status = 200, description = "Job runs retrieved successfully.", body = [JobRun]),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_job_runs_handler(
+    Query(params): Query<JobRunsParams>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    if let Some(job) = params.job.as_deref() {
+        if Job::parse(job).is_none() {
+            let error_message = format!("Unknown job '{}'.", job);
+            debug!(message = error_message);
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"status": "error", "message": error_message})),
+            ));
+        }
+    }
+    app_state
+        .options::<QueryOptions>()
+        .check_limit(params.limit)?;
+    let limit = params.limit.unwrap_or(DEFAULT_JOB_RUNS_LIMIT) as i64;
+
+    let leases = job_leases(&app_state).await?;
+    let runs = job_runs(&app_state, params.job.as_deref(), limit).await?;
+    let success_message = "Job runs retrieved successfully.".to_string();
+    info!(message = success_message);
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "leases": leases,
+        "data": runs,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_get_job_runs_handler_unknown_job() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let params = JobRunsParams {
+                job: Some("rollups".to_string()),
+                limit: None,
+            };
+
+            // Call the function
+            let result = get_job_runs_handler(Query(params), State(app_state)).await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+}
@@ -13,13 +13,11 @@
 //! The handler returns a JSON response with the status and message.
 //!
 
+use crate::service::ctx::Ctx;
 use crate::service::state::AppState;
 use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
 use k8s_openapi::api::core::v1::Secret;
 use kube::{api::Api, Client};
-use logging_utils::create_ref_id_helper::create_ref_id;
-use logging_utils::create_task_id_helper::create_task_id;
-use logging_utils::create_task_ref_id_helper::create_task_ref_collection;
 use serde_json::json;
 use std::str;
 use std::sync::Arc;
@@ -37,49 +35,23 @@ use tracing::{debug, error, instrument};
 )]
 #[instrument(skip_all)]
 pub async fn get_kubernetes_token(
+    ctx: Ctx,
     State(app_state): State<Arc<AppState>>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
-    // Create a reference ID ,task ID and initialize the documentdb variables
-    let ref_id = create_ref_id();
-    let service_type = "GetKubToken".to_string();
     let app_name = &app_state.app_settings.tracing_layer_system_app_name;
-    let task_id = create_task_id(app_name, service_type);
-    let mongo_url = app_state.app_settings.mongo_db.mongo_db_url.clone();
-    let mongo_db_name = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_database_name
-        .clone();
-    let id_collection = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_id_collection
-        .clone();
 
     // Create a kubernetes client
     let client = match Client::try_default().await {
         Ok(client) => client,
         Err(_) => {
             let error_message = "Failed to create Kubernetes client.";
-            let ext_message = format!(
-                "{} Use reference ID: {}",
-                app_state.app_settings.general_message, ref_id
-            );
+            let ext_message = ctx.ext_message(&app_state);
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.to_string(),
-                task_id,
-                ref_id,
-            )
-            .await;
             return Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(json!({ "error": error_message })),
@@ -108,25 +80,13 @@ pub async fn get_kubernetes_token(
                         }
                         Err(_) => {
                             let error_message = "Failed to convert kubernetes token to string.";
-                            let ext_message = format!(
-                                "{} Use reference ID: {}",
-                                app_state.app_settings.general_message, ref_id
-                            );
+                            let ext_message = ctx.ext_message(&app_state);
                             error!(
                                 app_name = app_name,
-                                task_id = task_id,
+                                task_id = ctx.task_id,
                                 ext_message = ext_message,
                                 message = error_message
                             );
-                            let _ = create_task_ref_collection(
-                                mongo_url,
-                                mongo_db_name,
-                                id_collection,
-                                app_name.to_string(),
-                                task_id,
-                                ref_id,
-                            )
-                            .await;
                             Err((
                                 StatusCode::INTERNAL_SERVER_ERROR,
                                 Json(json!({"status": "error", "message": error_message})),
@@ -137,25 +97,13 @@ pub async fn get_kubernetes_token(
                 None => {
                     let error_message =
                         format!("Failed to find 'token' key in '{}' secret.", secret_name);
-                    let ext_message = format!(
-                        "{} Use reference ID: {}",
-                        app_state.app_settings.general_message, ref_id
-                    );
+                    let ext_message = ctx.ext_message(&app_state);
                     error!(
                         app_name = app_name,
-                        task_id = task_id,
+                        task_id = ctx.task_id,
                         ext_message = ext_message,
                         message = error_message
                     );
-                    let _ = create_task_ref_collection(
-                        mongo_url,
-                        mongo_db_name,
-                        id_collection,
-                        app_name.to_string(),
-                        task_id,
-                        ref_id,
-                    )
-                    .await;
                     Err((
                         StatusCode::INTERNAL_SERVER_ERROR,
                         Json(json!({"status": "error", "message": error_message})),
@@ -165,25 +113,13 @@ pub async fn get_kubernetes_token(
         }
         Err(_) => {
             let error_message = format!("Failed to find '{}' secret.", secret_name);
-            let ext_message = format!(
-                "{} Use reference ID: {}",
-                app_state.app_settings.general_message, ref_id
-            );
+            let ext_message = ctx.ext_message(&app_state);
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.to_string(),
-                task_id,
-                ref_id,
-            )
-            .await;
             Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(json!({"status": "error", "message": error_message})),
@@ -207,7 +143,9 @@ mod tests {
             let app_state = crate::tests::test_get_appstate().await.unwrap();
 
             // Call the function
-            let result = get_kubernetes_token(State(app_state)).await;
+            let result =
+                get_kubernetes_token(Ctx::new(&app_state, "test_app", "Test"), State(app_state))
+                    .await;
 
             // Check if the function returns Ok
             assert!(result.is_ok());
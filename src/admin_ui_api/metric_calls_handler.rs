@@ -6,26 +6,40 @@
 //! This module contains the asynchronous GET handler for fetching the number of metric calls made to the app.
 //! The handler is used by the admin UI to fetch the number of metric calls made to the app.
 //! The handler is mounted at `/api/v1.1/admin/metric/calls`.
+//! Along with the calls from the metric microservice, the handler computes the p50/p95/p99 retrieval
+//! durations from the numeric duration metrics and the error ratio of the retrievals from the history
+//! collection of the app, over the given timestamps or a selectable `window` (e.g. `1h`, `24h`, `7d`).
 //! The handler returns the number of metric calls if it exists, else returns an error message.
 //! The handler returns a 200 status code if the metric calls are fetched successfully.
 //! The handler returns a 400 status code if an error occurs while fetching the metric calls.
 //! The handler returns a 500 status code if an error occurs while fetching the metric calls.
 //! The handler returns a JSON response with the status and message.
+//! The call to the metric microservice carries the remaining deadline of the request, see `deadline`.
 //!
 
+use crate::retrieval::replay::REPLAY_OF_FIELD;
+use crate::retrieval::sandbox::SANDBOX_FIELD;
+use crate::service::ctx::Ctx;
+use crate::service::deadline::with_deadline;
+use crate::service::metric_migration::METRIC_DURATION_MS_FIELD;
+use crate::service::query_options::{AggregateExt, QueryOptions};
 use crate::service::state::AppState;
 use axum::body::Body;
 use axum::extract::Query;
 use axum::http::Request;
 use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
-use logging_utils::create_ref_id_helper::create_ref_id;
-use logging_utils::create_task_id_helper::create_task_id;
-use logging_utils::create_task_ref_id_helper::create_task_ref_collection;
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::doc;
 use serde::Deserialize;
+use serde_json::json;
 use std::sync::Arc;
 use tracing::{debug, error, instrument};
 
 const METRIC_CALLS_ENDPOINT: &str = "metrics/api-call-count";
+const RETRIEVAL_DURATION_METRIC: &str = "Data Retrieval Duration";
+const METRIC_TIMESTAMP_FIELD: &str = "timestamp";
+const HISTORY_COLLECTION_SUFFIX: &str = "-history";
+const RETRIEVAL_FAILED_RESPONSE: &str = "Retrieval failed.";
 
 /// GET handler to fetch the number of metric calls made to the app.
 #[utoipa::path(
@@ -38,14 +52,19 @@ const METRIC_CALLS_ENDPOINT: &str = "metrics/api-call-count";
             description = "app name.",
         ),
         (
-            "start_timestamp" = inline(String), 
+            "start_timestamp" = inline(Option<String>), 
             Query,
-            description = "start timestamp.",
+            description = "start timestamp. Required unless window is given.",
         ),
         (
-            "end_timestamp" = inline(String), 
+            "end_timestamp" = inline(Option<String>), 
             Query,
-            description = "end timestamp.",
+            description = "end timestamp. Required unless window is given.",
+        ),
+        (
+            "window" = inline(Option<String>),
+            Query,
+            description = "window ending now, in minutes (m), hours (h) or days (d), e.g. 24h.",
         )
     ),
     responses(
@@ -56,66 +75,51 @@ const METRIC_CALLS_ENDPOINT: &str = "metrics/api-call-count";
 )]
 #[instrument(skip_all)]
 pub async fn get_metric_calls(
+    ctx: Ctx,
     State(app_state): State<Arc<AppState>>,
     request: Request<Body>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
     #[derive(Deserialize)]
     struct GetCallsParams {
         app_name: String,
-        start_timestamp: String,
-        end_timestamp: String,
+        start_timestamp: Option<String>,
+        end_timestamp: Option<String>,
+        window: Option<String>,
     }
-    // Create a reference ID ,task ID and initialize the documentdb variables
-    let ref_id = create_ref_id();
-    let service_type = "GetMetricCall".to_string();
     let app_name = &app_state.app_settings.tracing_layer_system_app_name;
-    let task_id = create_task_id(app_name, service_type);
-    let mongo_url = app_state.app_settings.mongo_db.mongo_db_url.clone();
-    let mongo_db_name = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_database_name
-        .clone();
-    let id_collection = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_id_collection
-        .clone();
 
-    let param: Query<GetCallsParams> = match Query::try_from_uri(request.uri()) {
-        Ok(param) => param,
-        Err(e) => {
+    let param: Option<(String, DateTime<Utc>, DateTime<Utc>)> =
+        match Query::<GetCallsParams>::try_from_uri(request.uri()) {
+            Ok(Query(param)) => resolve_window(
+                param.start_timestamp.as_deref(),
+                param.end_timestamp.as_deref(),
+                param.window.as_deref(),
+                Utc::now(),
+            )
+            .map(|(start, end)| (param.app_name, start, end)),
+            Err(_) => None,
+        };
+    let (metric_app_name, start_timestamp, end_timestamp) = match param {
+        Some(param) => param,
+        None => {
             // Handle the error here. You might want to log the error and return a default value or an error response.
-            let error_message = format!("Failed to parse query parameters: {:?}", e);
-            let ext_message = format!(
-                "{} Use reference ID: {}",
-                app_state.app_settings.general_message, ref_id
+            let error_message = format!(
+                "Failed to parse query parameters: {:?}",
+                request.uri().query()
             );
+            let ext_message = ctx.ext_message(&app_state);
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.to_string(),
-                task_id,
-                ref_id,
-            )
-            .await;
             return Err((
                 axum::http::StatusCode::BAD_REQUEST,
                 axum::Json(serde_json::json!({ "error": "Bad request" })),
             ));
         }
     };
-    println!("{}", param.app_name);
-    println!("{}", param.start_timestamp);
-    println!("{}", param.end_timestamp);
-    println!("{}", request.uri().path());
 
     debug!("Retrieving data from the metric microservice.");
     let url = format!(
@@ -126,23 +130,27 @@ pub async fn get_metric_calls(
             .metric_service_url
             .clone(),
         METRIC_CALLS_ENDPOINT,
-        param.app_name.clone()
+        metric_app_name
     );
 
     debug!(
         "Making a Get request to the metric microservice at URL: {}",
         url
     );
-    let client = reqwest::Client::new();
-
-    let response = client
-        .get(url)
-        .header("accept", "application/json")
-        .query(&[
-            ("start_timestamp", param.start_timestamp.clone()),
-            ("end_timestamp", param.end_timestamp.clone()),
-        ])
-        .send()
+    let client = app_state.http_clients.for_url(&url);
+
+    let response = app_state
+        .http_clients
+        .send(with_deadline(
+            client
+                .get(url)
+                .header("accept", "application/json")
+                .query(&[
+                    ("start_timestamp", start_timestamp.to_rfc3339()),
+                    ("end_timestamp", end_timestamp.to_rfc3339()),
+                ]),
+            ctx.deadline.as_ref(),
+        ))
         .await;
 
     match response {
@@ -151,31 +159,32 @@ pub async fn get_metric_calls(
                 .text()
                 .await
                 .unwrap_or_else(|_| String::from("Failed to read response body"));
-            let body = axum::body::Body::from(body);
+            let retrieval_stats =
+                retrieval_stats(&app_state, &metric_app_name, start_timestamp, end_timestamp)
+                    .await?;
+
+            // Add the retrieval statistics to the calls returned by the metric microservice
+            let body = match serde_json::from_str::<serde_json::Value>(&body) {
+                Ok(serde_json::Value::Object(mut calls)) => {
+                    calls.insert("retrieval_stats".to_string(), retrieval_stats);
+                    serde_json::Value::Object(calls)
+                }
+                Ok(calls) => json!({"calls": calls, "retrieval_stats": retrieval_stats}),
+                Err(_) => json!({"calls": body, "retrieval_stats": retrieval_stats}),
+            };
+            let body = axum::body::Body::from(body.to_string());
             let response = axum::response::Response::new(body);
             Ok(response)
         }
         Err(_) => {
             let error_message = "Failed to send request".to_string();
-            let ext_message = format!(
-                "{} Use reference ID: {}",
-                app_state.app_settings.general_message, ref_id
-            );
+            let ext_message = ctx.ext_message(&app_state);
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.to_string(),
-                task_id,
-                ref_id,
-            )
-            .await;
             let body = axum::body::Body::from("Failed to send request");
             let response = axum::response::Response::new(body);
             Ok(response)
@@ -183,6 +192,156 @@ pub async fn get_metric_calls(
     }
 }
 
+/// Resolves the start and end of the statistics. A window (e.g. `30m`, `24h`, `7d`) ends at `now`
+/// and takes precedence over the timestamps, which must otherwise both be valid RFC3339 timestamps.
+fn resolve_window(
+    start_timestamp: Option<&str>,
+    end_timestamp: Option<&str>,
+    window: Option<&str>,
+    now: DateTime<Utc>,
+) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    if let Some(window) = window {
+        let window = window.trim();
+        let amount: i64 = window.get(..window.len().checked_sub(1)?)?.parse().ok()?;
+        let duration = match window.chars().last()? {
+            'm' => Duration::minutes(amount),
+            'h' => Duration::hours(amount),
+            'd' => Duration::days(amount),
+            _ => return None,
+        };
+        return (amount > 0).then(|| (now - duration, now));
+    }
+    let start = DateTime::parse_from_rfc3339(start_timestamp?).ok()?;
+    let end = DateTime::parse_from_rfc3339(end_timestamp?).ok()?;
+    Some((start.with_timezone(&Utc), end.with_timezone(&Utc)))
+}
+
+/// Nearest-rank percentile of sorted values.
+fn percentile(sorted_values: &[i64], percentile: f64) -> Option<i64> {
+    if sorted_values.is_empty() {
+        return None;
+    }
+    let rank = ((percentile / 100.0) * sorted_values.len() as f64).ceil() as usize;
+    sorted_values
+        .get(rank.clamp(1, sorted_values.len()) - 1)
+        .copied()
+}
+
+/// Computes the retrieval duration percentiles from the numeric duration metrics and the error ratio
+/// of the retrievals from the history collection of the app, on the analytics connections.
+async fn retrieval_stats(
+    app_state: &AppState,
+    app_name: &str,
+    start_timestamp: DateTime<Utc>,
+    end_timestamp: DateTime<Utc>,
+) -> Result<serde_json::Value, (StatusCode, Json<serde_json::Value>)> {
+    let metric_collection_name = &app_state
+        .app_settings
+        .app_generated_config
+        .knowledge_graph_config
+        .metric
+        .collection;
+    let durations_pipeline = vec![
+        doc! {
+            "$match": {
+                "app_name": app_name,
+                "metrics_name": RETRIEVAL_DURATION_METRIC,
+                METRIC_DURATION_MS_FIELD: { "$exists": true },
+                METRIC_TIMESTAMP_FIELD: {
+                    "$gte": start_timestamp.to_rfc3339(),
+                    "$lte": end_timestamp.to_rfc3339(),
+                },
+            }
+        },
+        doc! { "$sort": { METRIC_DURATION_MS_FIELD: 1 } },
+        doc! {
+            "$group": {
+                "_id": null,
+                "durations": { "$push": format!("${}", METRIC_DURATION_MS_FIELD) },
+            }
+        },
+    ];
+    let durations_result = app_state
+        .analytics_db(None)?
+        .aggregate(
+            metric_collection_name,
+            durations_pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await?;
+    let durations: Vec<i64> = durations_result
+        .first()
+        .and_then(|doc| doc.get("durations"))
+        .and_then(serde_json::Value::as_array)
+        .map(|durations| {
+            durations
+                .iter()
+                .filter_map(|duration| duration.as_f64().map(|duration| duration as i64))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // History documents store their timestamp with the `DateTime<Utc>` display format
+    let history_collection_name = format!("{}{}", app_name, HISTORY_COLLECTION_SUFFIX);
+    let errors_pipeline = vec![
+        doc! {
+            "$match": {
+                "timestamp": {
+                    "$gte": start_timestamp.to_string(),
+                    "$lte": end_timestamp.to_string(),
+                },
+                REPLAY_OF_FIELD: { "$exists": false },
+                SANDBOX_FIELD: { "$ne": true },
+            }
+        },
+        doc! {
+            "$group": {
+                "_id": null,
+                "total": { "$sum": 1 },
+                "failed": {
+                    "$sum": { "$cond": [ { "$eq": [ "$response", RETRIEVAL_FAILED_RESPONSE ] }, 1, 0 ] }
+                },
+            }
+        },
+    ];
+    let errors_result = app_state
+        .app_analytics_db(app_name)
+        .await?
+        .aggregate(
+            &history_collection_name,
+            errors_pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await?;
+    let count = |field: &str| {
+        errors_result
+            .first()
+            .and_then(|doc| doc.get(field))
+            .and_then(serde_json::Value::as_i64)
+            .unwrap_or(0)
+    };
+    let (total, failed) = (count("total"), count("failed"));
+    let error_ratio = if total > 0 {
+        failed as f64 / total as f64
+    } else {
+        0.0
+    };
+
+    Ok(json!({
+        "start_timestamp": start_timestamp.to_rfc3339(),
+        "end_timestamp": end_timestamp.to_rfc3339(),
+        "duration_ms": {
+            "count": durations.len(),
+            "p50": percentile(&durations, 50.0),
+            "p95": percentile(&durations, 95.0),
+            "p99": percentile(&durations, 99.0),
+        },
+        "retrievals": total,
+        "failed_retrievals": failed,
+        "error_ratio": error_ratio,
+    }))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -204,10 +363,99 @@ mod tests {
                 .body(Body::empty())
                 .unwrap();
             // Call the function
-            let result = get_metric_calls(State(app_state), request).await;
+            let result = get_metric_calls(Ctx::new(&app_state, "test_app", "Test"), State(app_state), request).await;
 
             // Check that the result is as expected
             assert!(result.is_ok());
         });
     }
+
+    #[test]
+    fn test_success_get_metric_calls_window() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            let request = Request::builder()
+                .uri("/metriccalls?app_name=app100&window=24h")
+                .header("accept", "application/json")
+                .body(Body::empty())
+                .unwrap();
+            // Call the function
+            let result = get_metric_calls(
+                Ctx::new(&app_state, "test_app", "Test"),
+                State(app_state),
+                request,
+            )
+            .await;
+
+            // Check that the result is as expected
+            assert!(result.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_failure_get_metric_calls_invalid_window() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            let request = Request::builder()
+                .uri("/metriccalls?app_name=app100&window=fortnight")
+                .header("accept", "application/json")
+                .body(Body::empty())
+                .unwrap();
+            // Call the function
+            let result = get_metric_calls(
+                Ctx::new(&app_state, "test_app", "Test"),
+                State(app_state),
+                request,
+            )
+            .await;
+
+            // If the function returns Err, check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn test_success_resolve_window() {
+        let now = Utc::now();
+        assert_eq!(
+            resolve_window(None, None, Some("24h"), now),
+            Some((now - Duration::hours(24), now))
+        );
+        assert_eq!(
+            resolve_window(None, None, Some("7d"), now),
+            Some((now - Duration::days(7), now))
+        );
+        assert_eq!(resolve_window(None, None, Some("0h"), now), None);
+        assert_eq!(resolve_window(None, None, Some("d"), now), None);
+        assert!(resolve_window(
+            Some("2024-02-23T00:00:00Z"),
+            Some("2024-02-23T23:59:59Z"),
+            None,
+            now
+        )
+        .is_some());
+        assert_eq!(
+            resolve_window(Some("2024-02-23T00:00:00Z"), None, None, now),
+            None
+        );
+    }
+
+    #[test]
+    fn test_success_percentile() {
+        let values: Vec<i64> = (1..=100).collect();
+        assert_eq!(percentile(&values, 50.0), Some(50));
+        assert_eq!(percentile(&values, 95.0), Some(95));
+        assert_eq!(percentile(&values, 99.0), Some(99));
+        assert_eq!(percentile(&[7], 99.0), Some(7));
+        assert_eq!(percentile(&[], 50.0), None);
+    }
 }
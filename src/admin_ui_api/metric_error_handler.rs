@@ -11,16 +11,16 @@
 //! The handler returns a 400 status code if an error occurs while fetching the errors.
 //! The handler returns a 500 status code if an error occurs while fetching the errors.
 //! The handler returns a JSON response with the status and message.
+//! The call to the metric microservice carries the remaining deadline of the request, see `deadline`.
 //!
 
+use crate::service::ctx::Ctx;
+use crate::service::deadline::with_deadline;
 use crate::service::state::AppState;
 use axum::body::Body;
 use axum::extract::Query;
 use axum::http::Request;
 use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
-use logging_utils::create_ref_id_helper::create_ref_id;
-use logging_utils::create_task_id_helper::create_task_id;
-use logging_utils::create_task_ref_id_helper::create_task_ref_collection;
 use serde::Deserialize;
 use std::sync::Arc;
 use tracing::{debug, error, instrument};
@@ -66,25 +66,11 @@ const METRIC_ERRORS_ENDPOINT: &str = "api/log/severity-count";
 )]
 #[instrument(skip_all)]
 pub async fn get_metric_errors(
+    ctx: Ctx,
     State(app_state): State<Arc<AppState>>,
     request: Request<Body>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
-    // Create a reference ID ,task ID and initialize the documentdb variables
-    let ref_id = create_ref_id();
-    let service_type = "GetMetricError".to_string();
     let app_name = &app_state.app_settings.tracing_layer_system_app_name;
-    let task_id = create_task_id(app_name, service_type);
-    let mongo_url = app_state.app_settings.mongo_db.mongo_db_url.clone();
-    let mongo_db_name = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_database_name
-        .clone();
-    let id_collection = app_state
-        .app_settings
-        .mongo_db
-        .mongo_db_id_collection
-        .clone();
 
     #[derive(Deserialize)]
     struct GetCallsParams {
@@ -99,25 +85,13 @@ pub async fn get_metric_errors(
         Err(e) => {
             // Handle the error here. You might want to log the error and return a default value or an error response.
             let error_message = format!("Failed to parse query parameters: {:?}", e);
-            let ext_message = format!(
-                "{} Use reference ID: {}",
-                app_state.app_settings.general_message, ref_id
-            );
+            let ext_message = ctx.ext_message(&app_state);
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.to_string(),
-                task_id,
-                ref_id,
-            )
-            .await;
             return Err((
                 axum::http::StatusCode::BAD_REQUEST,
                 axum::Json(serde_json::json!({ "error": "Bad request" })),
@@ -146,17 +120,21 @@ pub async fn get_metric_errors(
         "Making a Get request to the logging microservice at URL: {}",
         url
     );
-    let client = reqwest::Client::new();
+    let client = app_state.http_clients.for_url(&url);
 
-    let response = client
-        .get(url.clone())
-        .header("accept", "application/json")
-        .query(&[
-            ("start_timestamp", param.start_timestamp.clone()),
-            ("end_timestamp", param.end_timestamp.clone()),
-            ("count_only", param.count_only.unwrap_or(false).to_string()),
-        ])
-        .send()
+    let response = app_state
+        .http_clients
+        .send(with_deadline(
+            client
+                .get(url.clone())
+                .header("accept", "application/json")
+                .query(&[
+                    ("start_timestamp", param.start_timestamp.clone()),
+                    ("end_timestamp", param.end_timestamp.clone()),
+                    ("count_only", param.count_only.unwrap_or(false).to_string()),
+                ]),
+            ctx.deadline.as_ref(),
+        ))
         .await;
 
     match response {
@@ -171,25 +149,13 @@ pub async fn get_metric_errors(
         }
         Err(_) => {
             let error_message = "Failed to send request".to_string();
-            let ext_message = format!(
-                "{} Use reference ID: {}",
-                app_state.app_settings.general_message, ref_id
-            );
+            let ext_message = ctx.ext_message(&app_state);
             error!(
                 app_name = app_name,
-                task_id = task_id,
+                task_id = ctx.task_id,
                 ext_message = ext_message,
                 message = error_message
             );
-            let _ = create_task_ref_collection(
-                mongo_url,
-                mongo_db_name,
-                id_collection,
-                app_name.to_string(),
-                task_id,
-                ref_id,
-            )
-            .await;
             let body = axum::body::Body::from("Failed to send request");
             let response = axum::response::Response::new(body);
             Ok(response)
@@ -218,7 +184,7 @@ mod tests {
                 .body(Body::empty())
                 .unwrap();
             // Call the function
-            let result = get_metric_errors(State(app_state), request).await;
+            let result = get_metric_errors(Ctx::new(&app_state, "test_app", "Test"), State(app_state), request).await;
 
             // Check that the result is as expected
             assert!(result.is_ok());
@@ -0,0 +1,228 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the handlers of the notification feed of the admin console (the bell icon): the platform
+//! events recorded by the subsystems, such as completed onboardings, finished deletions, error spikes and quota
+//! warnings.
+//! The GET handler is mounted at `/api/v1.1/admin/notifications`, with the optional `page`, `limit` (20 by default),
+//! `unread_only`, `app_name` and `kind` query parameters. It returns a page of notifications, the latest first, with
+//! the number of unread notifications, and the pagination headers.
+//! The POST handler of `/{id}/read` marks a notification as read, the POST handler of `/read` marks all the unread
+//! notifications as read, of the `app_name` query parameter if set.
+//! The handlers return a 200 status code if the notifications are fetched/marked successfully.
+//! The handlers return a 400 status code if the limit or page is too large.
+//! The handlers return a 404 status code if the notification is not found.
+//! The handlers return a 500 status code if an error occurs while fetching/marking the notifications.
+//!
+
+use crate::admin_ui_api::schema::NotificationParams;
+use crate::service::notification::{
+    mark_all_read, mark_read, notifications_filter, Notification, NotificationOptions,
+};
+use crate::service::pagination::Pagination;
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::state::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::{StatusCode, Uri},
+    response::IntoResponse,
+    Json,
+};
+use mongodb::bson::doc;
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// Default number of notifications per page.
+const DEFAULT_NOTIFICATIONS_LIMIT: i64 = 20;
+
+/// GET handler to fetch a page of the notification feed.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/notifications",
+    params(
+        ("page" = inline(Option<usize>), Query, description = "page number."),
+        ("limit" = inline(Option<usize>), Query, description = "Number of notifications, the latest first. Defaults to 20."),
+        ("unread_only" = inline(Option<bool>), Query, description = "Only the unread notifications if true."),
+        ("app_name" = inline(Option<String>), Query, description = "Notifications of an app."),
+        ("kind" = inline(Option<String>), Query, description = "onboarding_completed, onboarding_failed, deletion_finished, error_spike or quota_warning.")
+    ),
+    responses(
+        (status = 200, description = "Notifications retrieved successfully.", body = [Notification]),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_notifications_handler(
+    Query(params): Query<NotificationParams>,
+    State(app_state): State<Arc<AppState>>,
+    uri: Uri,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let query_options = app_state.options::<QueryOptions>();
+    query_options.check_limit(params.limit)?;
+    let collection = app_state.options::<NotificationOptions>().collection;
+    let filter = notifications_filter(
+        params.unread_only.unwrap_or_default(),
+        params.app_name.as_deref(),
+        params.kind,
+    );
+
+    // Count the matching and the unread notifications in a single pass
+    let unread_filter = notifications_filter(true, params.app_name.as_deref(), None);
+    let count_pipeline = vec![doc! {
+        "$facet": {
+            "total": [{"$match": filter.clone()}, {"$count": "count"}],
+            "unread": [{"$match": unread_filter}, {"$count": "count"}],
+        }
+    }];
+    let counts = app_state
+        .db
+        .aggregate(&collection, count_pipeline, &query_options)
+        .await?;
+    let count = |facet: &str| {
+        counts
+            .first()
+            .and_then(|counts| counts[facet][0]["count"].as_i64())
+            .unwrap_or_default()
+    };
+    let (total_count, unread_count) = (count("total"), count("unread"));
+    let pagination = Pagination::new(
+        params.page,
+        params.limit,
+        DEFAULT_NOTIFICATIONS_LIMIT,
+        total_count,
+    );
+    query_options.check_offset(pagination.skip())?;
+
+    let page_pipeline = vec![
+        doc! {"$match": filter},
+        doc! {"$sort": {"created_at": -1, "_id": -1}},
+        doc! {"$skip": pagination.skip()},
+        doc! {"$limit": pagination.limit},
+        doc! {"$project": {"_id": 0}},
+    ];
+    let notifications: Vec<Notification> = app_state
+        .db
+        .aggregate(&collection, page_pipeline, &query_options)
+        .await?
+        .into_iter()
+        .filter_map(|notification| serde_json::from_value(notification).ok())
+        .collect();
+
+    let success_message = format!(
+        "{} notification(s) fetched successfully.",
+        notifications.len()
+    );
+    debug!(message = success_message);
+    Ok((
+        pagination.headers(&uri),
+        Json(json!({
+            "status": "success",
+            "message": success_message,
+            "data": notifications,
+            "unread_count": unread_count,
+            "total_pages": pagination.total_pages,
+            "total_results": pagination.total_count
+        })),
+    ))
+}
+
+/// POST handler to mark a notification as read.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/notifications/{id}/read",
+    responses(
+        (status = 200, description = "Notification marked as read."),
+        (status = StatusCode::NOT_FOUND, description = "Notification not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_notification_read_handler(
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    match mark_read(&app_state, &id).await {
+        Ok(true) => {
+            let success_message = format!("Notification '{}' marked as read.", id);
+            debug!(message = success_message);
+            Ok(Json(
+                json!({"status": "success", "message": success_message, "id": id}),
+            ))
+        }
+        Ok(false) => {
+            let error_message = format!("No notification found with id '{}'.", id);
+            debug!(message = error_message);
+            Err((
+                StatusCode::NOT_FOUND,
+                Json(json!({"status": "error", "message": error_message})),
+            ))
+        }
+        Err(e) => {
+            let error_message =
+                format!("Failed to mark notification '{}' as read. Error: {}", id, e);
+            error!(message = error_message);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"status": "error", "message": error_message})),
+            ))
+        }
+    }
+}
+
+/// POST handler to mark all the unread notifications as read, of an app if `app_name` is set.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/notifications/read",
+    params(
+        ("app_name" = inline(Option<String>), Query, description = "Notifications of an app. All the notifications if unset.")
+    ),
+    responses(
+        (status = 200, description = "Notifications marked as read."),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_notifications_read_handler(
+    Query(params): Query<NotificationParams>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let marked = mark_all_read(&app_state, params.app_name.as_deref()).await?;
+    let success_message = format!("{} notification(s) marked as read.", marked);
+    info!(message = success_message);
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "marked_count": marked
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_post_notification_read_handler_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function with an unknown id
+            let result = post_notification_read_handler(
+                Path("non-existing-notification".to_string()),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
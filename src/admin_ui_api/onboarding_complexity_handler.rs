@@ -0,0 +1,162 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET handler for fetching the complexity of the onboarding requests, to follow the growth
+//! of the app configurations and plan their limits.
+//! The handler is mounted at `/api/v1.1/admin/onboarding/complexity`.
+//! The complexity metrics recorded per onboarding request (filestore URLs, hints, datastores, tables, columns and
+//! payload bytes) are rolled up over the last 180 days, or between the optional `utc_start_timestamp` and
+//! `utc_end_timestamp` query parameters, for all the apps or the optional `app_name`, into the statistics per metric,
+//! their monthly series and the apps with the largest payloads.
+//! The handler returns a 200 status code if the complexity is fetched successfully.
+//! The handler returns a 400 status code if the time range is invalid.
+//! The handler returns a 500 status code if an error occurs while running the aggregation.
+//!
+
+use crate::admin_ui_api::schema::QueryParams;
+use crate::onboarding::complexity::complexity_pipeline;
+use crate::service::ctx::Ctx;
+use crate::service::metrics::records_collection;
+use crate::service::query_options::{AggregateExt, QueryError, QueryOptions};
+use crate::service::state::AppState;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{Duration, Utc};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// Default time range of the onboarding complexity, in days.
+const DEFAULT_COMPLEXITY_DAYS: i64 = 180;
+
+/// GET handler to fetch the complexity of the onboarding requests, per metric and per month.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/onboarding/complexity",
+    params(
+        (
+            "app_name" = Option<String>,
+            Query,
+            description = "Name of the app. Defaults to all the apps.",
+        ),
+        (
+            "utc_start_timestamp" = Option<String>,
+            Query,
+            description = "UTC start timestamp (RFC 3339). Defaults to 180 days before the end timestamp.",
+        ),
+        (
+            "utc_end_timestamp" = Option<String>,
+            Query,
+            description = "UTC end timestamp (RFC 3339). Defaults to now.",
+        )
+    ),
+    responses(
+        (status = 200, description = "Onboarding complexity fetched successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_onboarding_complexity_handler(
+    ctx: Ctx,
+    Query(params): Query<QueryParams>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let end_timestamp = params.utc_end_timestamp.unwrap_or_else(Utc::now);
+    let start_timestamp = params
+        .utc_start_timestamp
+        .unwrap_or(end_timestamp - Duration::days(DEFAULT_COMPLEXITY_DAYS));
+    if start_timestamp > end_timestamp {
+        let error_message = format!(
+            "utc_start_timestamp '{}' is after utc_end_timestamp '{}'.",
+            start_timestamp.to_rfc3339(),
+            end_timestamp.to_rfc3339()
+        );
+        debug!(message = error_message);
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    let collection_name = records_collection(&app_state.app_settings);
+    let app_name = params.app_name.as_deref();
+    let pipeline = complexity_pipeline(app_name, start_timestamp, end_timestamp);
+
+    // The rollup runs on the analytics connection of the primary cluster, when configured
+    match app_state
+        .analytics_db(None)?
+        .aggregate(
+            &collection_name,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await
+    {
+        Ok(results) => {
+            let facets = results.into_iter().next().unwrap_or_default();
+            let success_message = "Onboarding complexity fetched successfully.".to_string();
+            info!(message = success_message);
+            Ok(Json(json!({
+                "status": "success",
+                "message": success_message,
+                "app_name": app_name,
+                "utc_start_timestamp": start_timestamp.to_rfc3339(),
+                "utc_end_timestamp": end_timestamp.to_rfc3339(),
+                "totals": facets.get("totals").cloned().unwrap_or(json!([])),
+                "by_month": facets.get("by_month").cloned().unwrap_or(json!([])),
+                "largest_apps": facets.get("largest_apps").cloned().unwrap_or(json!([])),
+            })))
+        }
+        Err(QueryError::Db(e)) => {
+            let error_message = format!("Failed to fetch onboarding complexity. Error: {}", e);
+            let ext_message = ctx.ext_message(&app_state);
+            error!(
+                task_id = ctx.task_id,
+                ext_message = ext_message,
+                message = error_message
+            );
+            Err(e.intercept_error().await)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_get_onboarding_complexity_handler_invalid_time_range() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let params = QueryParams {
+                utc_start_timestamp: Some(Utc::now()),
+                utc_end_timestamp: Some(Utc::now() - Duration::days(1)),
+                ..Default::default()
+            };
+
+            // Call the function
+            let result = get_onboarding_complexity_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Query(params),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+}
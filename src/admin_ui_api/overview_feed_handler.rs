@@ -0,0 +1,154 @@
+/*
+ * Created Date:  Aug 2, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the websocket handler of the live updates of the admin dashboard.
+//! The handler is mounted at `/api/v1.1/admin/overview/ws`.
+//! After the upgrade, the server pushes a `overview_update` message with the new calls, new errors and node count
+//! deltas of the changed apps every flush interval (see `crate::service::overview_feed`), optionally only for the
+//! app of the `app_name` query parameter. The dashboard fetches the overview once, then applies the deltas.
+//! A client too slow to keep up receives a `resync` message and refetches the overview before applying new deltas.
+//! The handler returns a 503 status code if the live updates are disabled, the dashboard then polls the overview.
+//!
+
+use crate::admin_ui_api::schema::OverviewFeedParams;
+use crate::service::overview_feed::{OverviewFeedOptions, OverviewUpdate};
+use crate::service::state::AppState;
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::{debug, instrument};
+
+/// GET handler to open the websocket of the live updates of the admin dashboard.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/overview/ws",
+    params(
+        (
+            "app_name" = Option<String>,
+            Query,
+            description = "Only sends the deltas of this app.",
+        )
+    ),
+    responses(
+        (status = 101, description = "Switched to the websocket of the live updates.", body = OverviewUpdate),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "The live updates are disabled.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_overview_feed_handler(
+    ws: WebSocketUpgrade,
+    Query(params): Query<OverviewFeedParams>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    if !app_state.options::<OverviewFeedOptions>().enabled || app_state.local_dev.is_some() {
+        let error_message = "The live updates of the overview are disabled.";
+        debug!(message = error_message);
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+    let receiver = app_state.overview_feed.subscribe();
+    Ok(ws.on_upgrade(move |socket| stream_updates(socket, receiver, params.app_name)))
+}
+
+/// Pushes the overview updates to a websocket client until it disconnects.
+async fn stream_updates(
+    mut socket: WebSocket,
+    mut receiver: Receiver<OverviewUpdate>,
+    app_name: Option<String>,
+) {
+    loop {
+        let message = tokio::select! {
+            update = receiver.recv() => match update {
+                Ok(update) => match update_message(&update, app_name.as_deref()) {
+                    Some(message) => message,
+                    None => continue,
+                },
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!(message = format!("Overview websocket client lagged by {} updates.", skipped));
+                    json!({"type": "resync"}).to_string()
+                }
+                Err(RecvError::Closed) => break,
+            },
+            received = socket.recv() => match received {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // The pings are answered by the websocket itself
+                Some(Ok(_)) => continue,
+            },
+        };
+        if socket.send(Message::Text(message)).await.is_err() {
+            break;
+        }
+    }
+    debug!(message = "Overview websocket client disconnected.");
+}
+
+/// Builds the message of an update, `None` if no delta is left for the app of the client.
+pub fn update_message(update: &OverviewUpdate, app_name: Option<&str>) -> Option<String> {
+    let deltas: Vec<_> = update
+        .deltas
+        .iter()
+        .filter(|delta| app_name.map_or(true, |app_name| delta.app_name == app_name))
+        .collect();
+    if deltas.is_empty() {
+        return None;
+    }
+    Some(
+        json!({
+            "type": "overview_update",
+            "timestamp": update.timestamp,
+            "deltas": deltas,
+        })
+        .to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::overview_feed::OverviewDelta;
+    use chrono::Utc;
+
+    #[test]
+    fn test_success_update_message() {
+        let update = OverviewUpdate {
+            timestamp: Utc::now(),
+            deltas: vec![
+                OverviewDelta {
+                    app_name: "app1".to_string(),
+                    calls: 2,
+                    ..Default::default()
+                },
+                OverviewDelta {
+                    app_name: "app2".to_string(),
+                    node_delta: -3,
+                    ..Default::default()
+                },
+            ],
+        };
+
+        let message: serde_json::Value =
+            serde_json::from_str(&update_message(&update, None).unwrap()).unwrap();
+        assert_eq!(message["type"], "overview_update");
+        assert_eq!(message["deltas"].as_array().unwrap().len(), 2);
+
+        let message: serde_json::Value =
+            serde_json::from_str(&update_message(&update, Some("app2")).unwrap()).unwrap();
+        assert_eq!(message["deltas"][0]["app_name"], "app2");
+        assert_eq!(message["deltas"][0]["node_delta"], -3);
+
+        assert!(update_message(&update, Some("app3")).is_none());
+    }
+}
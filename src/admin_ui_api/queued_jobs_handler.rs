@@ -0,0 +1,128 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET handler for the jobs of the persistent job queues (onboarding, retrieval), to
+//! follow the background work in flight and the dead-lettered jobs.
+//! The handler is mounted at `/api/v1.1/admin/jobs/queued`, with the optional `queue`, `status` and `limit` (50 by
+//! default) query parameters. It returns the number of jobs by queue and status, and the latest updated jobs, without
+//! their payload.
+//! The handler returns a 200 status code if the jobs are fetched successfully.
+//! The handler returns a 400 status code if the queue or the status is unknown, or the limit too large.
+//! The handler returns a 500 status code if an error occurs while fetching the jobs.
+//!
+
+use crate::admin_ui_api::schema::QueuedJobsParams;
+use crate::service::job_queue::{queued_job_counts, queued_jobs, JobQueue, QueuedJobStatus};
+use crate::service::query_options::QueryOptions;
+use crate::service::state::AppState;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, info, instrument};
+
+/// Default number of queued jobs.
+const DEFAULT_QUEUED_JOBS_LIMIT: usize = 50;
+
+/// Returns a 400 error for an unknown query parameter value.
+fn unknown(kind: &str, name: &str) -> (StatusCode, Json<serde_json::Value>) {
+    let error_message = format!("Unknown {} '{}'.", kind, name);
+    debug!(message = error_message);
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({"status": "error", "message": error_message})),
+    )
+}
+
+/// GET handler to fetch the jobs of the persistent job queues.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/jobs/queued",
+    params(
+        (
+            "queue" = inline(Option<String>),
+            Query,
+            description = "Name of a queue: onboarding or retrieval. All the queues if unset.",
+        ),
+        (
+            "status" = inline(Option<String>),
+            Query,
+            description = "Status of the jobs: queued, running, succeeded or dead_lettered. All the statuses if unset.",
+        ),
+        (
+            "limit" = inline(Option<usize>),
+            Query,
+            description = "Number of jobs, the latest updated first. Defaults to 50.",
+        )
+    ),
+    responses(
+        (status = 200, description = "Queued jobs retrieved successfully.", body = [QueuedJob]),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_queued_jobs_handler(
+    Query(params): Query<QueuedJobsParams>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let queue = match params.queue.as_deref() {
+        Some(queue) => Some(JobQueue::parse(queue).ok_or_else(|| unknown("queue", queue))?),
+        None => None,
+    };
+    let status = match params.status.as_deref() {
+        Some(status) => {
+            Some(QueuedJobStatus::parse(status).ok_or_else(|| unknown("job status", status))?)
+        }
+        None => None,
+    };
+    app_state
+        .options::<QueryOptions>()
+        .check_limit(params.limit)?;
+    let limit = params.limit.unwrap_or(DEFAULT_QUEUED_JOBS_LIMIT) as i64;
+
+    let counts = queued_job_counts(&app_state).await?;
+    let jobs = queued_jobs(&app_state, queue, status, limit).await?;
+    let success_message = "Queued jobs retrieved successfully.".to_string();
+    info!(message = success_message);
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "counts": counts,
+        "data": jobs,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_get_queued_jobs_handler_unknown_status() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let params = QueuedJobsParams {
+                queue: Some("retrieval".to_string()),
+                status: Some("failed".to_string()),
+                limit: None,
+            };
+
+            // Call the function
+            let result = get_queued_jobs_handler(Query(params), State(app_state)).await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+}
@@ -7,8 +7,10 @@
 //! The schema is used to define the request and response bodies for the different admin_ui_api handlers.
 //!
 
+use crate::service::notification::NotificationKind;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use utoipa::ToSchema;
 
 //schema to capture user & t&c information
@@ -37,6 +39,109 @@ pub struct QueryParams {
     pub end_timestamp: Option<String>,
     pub utc_start_timestamp: Option<DateTime<Utc>>,
     pub utc_end_timestamp: Option<DateTime<Utc>>,
+    pub cursor: Option<String>,
+    pub tz: Option<String>,
+    pub interval: Option<String>,
+    pub format: Option<String>,
+    pub dry_run: Option<bool>,
+    pub async_validation: Option<bool>,
+    pub override_limits: Option<bool>,
+    pub fields: Option<String>,
+    pub full_content: Option<bool>,
+    pub metadata: Option<String>,
+    pub federated: Option<bool>,
+}
+
+/// Query parameters to look up a single knowledge node, either by its source URI or by its node id
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct KnowledgeNodeDetailParams {
+    pub source: Option<String>,
+    pub node_id: Option<String>,
+    pub fields: Option<String>,
+}
+
+/// Query parameters identifying a hint of a filestore of an app
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HintKeyParams {
+    pub source_type: String,
+    pub url: String,
+    pub prefix: String,
+}
+
+/// Query parameters of the deletion of an app
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DeleteAppParams {
+    /// Confirmation token returned by the first deletion request of a large app.
+    pub confirmation_token: Option<String>,
+}
+
+/// Query parameters of the live updates of the admin dashboard
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct OverviewFeedParams {
+    /// Only sends the deltas of this app.
+    pub app_name: Option<String>,
+}
+
+/// Query parameters of the API key usage of an app
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ApiKeyUsageParams {
+    /// Number of days, today included. Defaults to 30.
+    pub days: Option<u32>,
+}
+
+/// Query parameters of the artifacts of an app
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ArtifactParams {
+    /// Category of the artifacts, e.g. `logs`. All the categories if unset when listing.
+    pub category: Option<String>,
+    /// Continuation token of the category, returned as `next_cursor`.
+    pub cursor: Option<String>,
+    /// Key of the object to delete.
+    pub key: Option<String>,
+}
+
+/// Query parameters of the runs of the background jobs
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct JobRunsParams {
+    /// Name of a job, e.g. `history_retention`. All the jobs if unset.
+    pub job: Option<String>,
+    /// Number of runs, the latest first. Defaults to 50.
+    pub limit: Option<usize>,
+}
+
+/// Query parameters of the jobs of the persistent job queues
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct QueuedJobsParams {
+    /// Name of a queue, e.g. `retrieval`. All the queues if unset.
+    pub queue: Option<String>,
+    /// Status of the jobs, e.g. `dead_lettered`. All the statuses if unset.
+    pub status: Option<String>,
+    /// Number of jobs, the latest updated first. Defaults to 50.
+    pub limit: Option<usize>,
+}
+
+/// Query parameters of the notifications feed
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct NotificationParams {
+    pub page: Option<usize>,
+    /// Number of notifications per page, the latest first. Defaults to 20.
+    pub limit: Option<usize>,
+    /// Only the unread notifications if true.
+    pub unread_only: Option<bool>,
+    pub app_name: Option<String>,
+    pub kind: Option<NotificationKind>,
+}
+
+/// Query parameters of the SCIM list endpoints
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimListParams {
+    /// SCIM filter `<attribute> eq "<value>"`, e.g. `userName eq "user@example.com"`.
+    pub filter: Option<String>,
+    /// 1-based index of the first resource. Defaults to 1.
+    pub start_index: Option<usize>,
+    /// Number of resources. Defaults to 100.
+    pub count: Option<usize>,
 }
 
 /// Schema for the fetched apps
@@ -47,6 +152,8 @@ pub struct AppListFetchSchema {
     pub api_key: String,
     pub onboarding_status: String,
     pub search_enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<BTreeMap<String, String>>,
 }
 
 /// Schema for deletion response
@@ -68,8 +175,9 @@ pub struct UpdateResponse {
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Counts {
     pub knowledge_node_errors: u64,
-    pub knowledge_node_file_store: u64,
-    pub knowledge_node_data_store: u64,
+    /// Count of the knowledge nodes by `knowledge_node_type`, e.g. `knowledge_node_file_store`.
+    #[serde(flatten)]
+    pub knowledge_nodes: BTreeMap<String, u64>,
 }
 
 /// Schema for the knowledge nodes chart count
@@ -93,6 +201,54 @@ pub struct NodesChartApiResponse {
     pub count: String,
     pub graph_items: Vec<GraphItem>,
     pub graph_interval: String,
+    pub graph_timezone: String,
+}
+
+/// Schema for the editable part of the vectordb config of an app's generated config
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VectorDbConfigPatch {
+    pub text_collection_name_prefix: Option<String>,
+    pub multimodal_collection_name_prefix: Option<String>,
+    pub general_collection_name_prefix: Option<String>,
+    pub session_history_collection_name_prefix: Option<String>,
+    pub error_collection_name_prefix: Option<String>,
+    pub insight_collection_name_prefix: Option<String>,
+    pub retention: Option<String>,
+}
+
+/// Schema for the editable part of the logging/audit/metric config of an app's generated config
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfigPatch {
+    pub collection_name_prefix: Option<String>,
+    pub retention: Option<String>,
+}
+
+/// Schema for the PATCH request of an app's generated config. Only retention values and collection
+/// prefixes can be edited, the S3 prefixes are not.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct GeneratedConfigPatch {
+    pub vectordb_config: Option<VectorDbConfigPatch>,
+    pub logging: Option<ServiceConfigPatch>,
+    pub audit: Option<ServiceConfigPatch>,
+    pub metric: Option<ServiceConfigPatch>,
+}
+
+/// Schema for the residency migration request of an app. A `null` residency moves the app
+/// specific collections back to the primary cluster.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ResidencyMigrationRequest {
+    pub residency: Option<String>,
+}
+
+/// Schema for the history retention override of an app. A `null` retention falls back to the default retention.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryRetentionRequest {
+    pub retention_days: Option<u32>,
 }
 
 impl From<KnowledgeNodeChartCount> for GraphItem {
@@ -123,6 +279,17 @@ mod tests {
             end_timestamp: Some("end_timestamp".to_string()),
             utc_start_timestamp: Some(Utc::now()),
             utc_end_timestamp: Some(Utc::now()),
+            cursor: None,
+            tz: None,
+            interval: None,
+            format: None,
+            dry_run: None,
+            async_validation: None,
+            override_limits: None,
+            fields: None,
+            full_content: None,
+            metadata: None,
+            federated: None,
         };
         assert_eq!(qp.app_name, Some("app_name".to_string()));
         assert_eq!(qp.page, Some(1));
@@ -150,6 +317,17 @@ mod tests {
             end_timestamp: None,
             utc_start_timestamp: None,
             utc_end_timestamp: None,
+            cursor: None,
+            tz: None,
+            interval: None,
+            format: None,
+            dry_run: None,
+            async_validation: None,
+            override_limits: None,
+            fields: None,
+            full_content: None,
+            metadata: None,
+            federated: None,
         };
         assert_eq!(qp.app_name, None);
         assert_eq!(qp.page, None);
@@ -171,6 +349,7 @@ mod tests {
             api_key: "api_key".to_string(),
             onboarding_status: "onboarding_status".to_string(),
             search_enabled: false,
+            metadata: None,
         };
         assert_eq!(appList.app_name, "app_name".to_string());
 
@@ -218,14 +397,18 @@ mod tests {
     fn test_success_Counts() {
         let counts = Counts {
             knowledge_node_errors: 1,
-            knowledge_node_file_store: 1,
-            knowledge_node_data_store: 1,
+            knowledge_nodes: BTreeMap::from([
+                ("knowledge_node_file_store".to_string(), 1),
+                ("knowledge_node_data_store".to_string(), 1),
+            ]),
         };
         assert_eq!(counts.knowledge_node_errors, 1);
 
         let json_string = serde_json::to_string(&counts).unwrap();
+        assert!(json_string.contains("\"knowledge_node_file_store\":1"));
         let deserialized: Counts = serde_json::from_str(&json_string).unwrap();
         assert_eq!(deserialized.knowledge_node_errors, 1);
+        assert_eq!(deserialized.knowledge_nodes["knowledge_node_data_store"], 1);
         println!("Now {:?} will print!", counts);
     }
 
@@ -271,6 +454,7 @@ mod tests {
                 indexed_at: "indexed_at".to_string(),
             }],
             graph_interval: "graph_interval".to_string(),
+            graph_timezone: "UTC".to_string(),
         };
         assert_eq!(nca.count, "1".to_string());
 
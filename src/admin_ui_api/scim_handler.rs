@@ -0,0 +1,443 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the SCIM 2.0 handlers through which the IdP of an enterprise customer pushes the users and
+//! groups entitled to query its apps.
+//! The handlers are mounted at `/api/v1.1/admin/scim/Users` and `/api/v1.1/admin/scim/Groups`: GET lists the resources
+//! (with the optional `filter`, `startIndex` and `count` query parameters) and POST creates one; GET, PUT, PATCH and
+//! DELETE of `/{id}` read, replace, patch and delete one. Every change is audited.
+//! The requests must carry `scim.bearer_token` as `Authorization: Bearer <token>`. The handlers are only mounted with a
+//! token configured, and refuse every request without one.
+//! The handlers return a 200 status code (201 on creation, 204 on deletion) with an `application/scim+json` body.
+//! The handlers return a 400 status code if the resource, filter or patch is invalid.
+//! The handlers return a 401 status code if the bearer token is missing or invalid.
+//! The handlers return a 404 status code if the resource is not found or no bearer token is configured.
+//! The handlers return a 409 status code if the `userName` or `displayName` is already taken.
+//! The handlers return a 500 status code if an error occurs while reading or writing the entitlements.
+//! The errors are SCIM error messages.
+//!
+
+use crate::admin_ui_api::schema::ScimListParams;
+use crate::service::ctx::Ctx;
+use crate::service::scim::{
+    create_resource, delete_resource, get_resource, list_resources, patch_resource,
+    replace_resource, PatchRequest, ScimGroup, ScimOptions, ScimResource, ScimUser,
+};
+use crate::service::state::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::{info, instrument};
+
+/// Content type of the SCIM responses.
+const SCIM_CONTENT_TYPE: &str = "application/scim+json";
+
+/// SCIM response with a body.
+fn scim_response(status_code: StatusCode, body: impl Serialize) -> Response {
+    (status_code, [(CONTENT_TYPE, SCIM_CONTENT_TYPE)], Json(body)).into_response()
+}
+
+/// Audits a change of a SCIM resource pushed by the IdP.
+fn audit_change<R: ScimResource>(ctx: &Ctx, action: &str, id: &str) {
+    let success_message = format!("SCIM {} '{}' {}.", R::RESOURCE_TYPE, id, action);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        action = format!("SCIM {} {}", R::RESOURCE_TYPE, action),
+        details = success_message,
+        message = success_message
+    );
+}
+
+/// GET handler to list the SCIM users.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/scim/Users",
+    params(
+        ("filter" = inline(Option<String>), Query, description = "Filter 'userName eq \"<value>\"' or 'externalId eq \"<value>\"'."),
+        ("startIndex" = inline(Option<usize>), Query, description = "1-based index of the first user. Defaults to 1."),
+        ("count" = inline(Option<usize>), Query, description = "Number of users, at most 100. Defaults to 100.")
+    ),
+    responses(
+        (status = 200, description = "Users listed.", body = [ScimUser]),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid filter"),
+        (status = StatusCode::UNAUTHORIZED, description = "Missing or invalid bearer token"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Entitlements unavailable")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_scim_users_handler(
+    headers: HeaderMap,
+    Query(params): Query<ScimListParams>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    app_state.options::<ScimOptions>().authorize(&headers)?;
+    let users = list_resources::<ScimUser>(
+        &app_state,
+        params.filter.as_deref(),
+        params.start_index,
+        params.count,
+    )
+    .await?;
+    Ok(scim_response(StatusCode::OK, users))
+}
+
+/// POST handler to create a SCIM user.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/scim/Users",
+    request_body = ScimUser,
+    responses(
+        (status = 201, description = "User created.", body = [ScimUser]),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid user"),
+        (status = StatusCode::UNAUTHORIZED, description = "Missing or invalid bearer token"),
+        (status = StatusCode::CONFLICT, description = "userName already taken"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Entitlements unavailable")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_scim_user_handler(
+    ctx: Ctx,
+    headers: HeaderMap,
+    State(app_state): State<Arc<AppState>>,
+    Json(user): Json<ScimUser>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    app_state.options::<ScimOptions>().authorize(&headers)?;
+    let user = create_resource(&app_state, user).await?;
+    audit_change::<ScimUser>(&ctx, "created", &user.id);
+    Ok(scim_response(StatusCode::CREATED, user))
+}
+
+/// GET handler to read a SCIM user.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/scim/Users/{id}",
+    responses(
+        (status = 200, description = "User retrieved.", body = [ScimUser]),
+        (status = StatusCode::UNAUTHORIZED, description = "Missing or invalid bearer token"),
+        (status = StatusCode::NOT_FOUND, description = "User not found"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Entitlements unavailable")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_scim_user_handler(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    app_state.options::<ScimOptions>().authorize(&headers)?;
+    let user: ScimUser = get_resource(&app_state, &id).await?;
+    Ok(scim_response(StatusCode::OK, user))
+}
+
+/// PUT handler to replace a SCIM user.
+#[utoipa::path(
+    put,
+    path = "/api/v1.1/admin/scim/Users/{id}",
+    request_body = ScimUser,
+    responses(
+        (status = 200, description = "User replaced.", body = [ScimUser]),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid user"),
+        (status = StatusCode::UNAUTHORIZED, description = "Missing or invalid bearer token"),
+        (status = StatusCode::NOT_FOUND, description = "User not found"),
+        (status = StatusCode::CONFLICT, description = "userName already taken"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Entitlements unavailable")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn put_scim_user_handler(
+    ctx: Ctx,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(user): Json<ScimUser>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    app_state.options::<ScimOptions>().authorize(&headers)?;
+    let user = replace_resource(&app_state, &id, user).await?;
+    audit_change::<ScimUser>(&ctx, "replaced", &id);
+    Ok(scim_response(StatusCode::OK, user))
+}
+
+/// PATCH handler to patch a SCIM user, e.g. to deactivate it or change its entitlements.
+#[utoipa::path(
+    patch,
+    path = "/api/v1.1/admin/scim/Users/{id}",
+    request_body = PatchRequest,
+    responses(
+        (status = 200, description = "User patched.", body = [ScimUser]),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid patch"),
+        (status = StatusCode::UNAUTHORIZED, description = "Missing or invalid bearer token"),
+        (status = StatusCode::NOT_FOUND, description = "User not found"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Entitlements unavailable")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn patch_scim_user_handler(
+    ctx: Ctx,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(patch): Json<PatchRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    app_state.options::<ScimOptions>().authorize(&headers)?;
+    let user: ScimUser = patch_resource(&app_state, &id, &patch).await?;
+    audit_change::<ScimUser>(&ctx, "patched", &id);
+    Ok(scim_response(StatusCode::OK, user))
+}
+
+/// DELETE handler to delete a SCIM user.
+#[utoipa::path(
+    delete,
+    path = "/api/v1.1/admin/scim/Users/{id}",
+    responses(
+        (status = 204, description = "User deleted."),
+        (status = StatusCode::UNAUTHORIZED, description = "Missing or invalid bearer token"),
+        (status = StatusCode::NOT_FOUND, description = "User not found"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Entitlements unavailable")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn delete_scim_user_handler(
+    ctx: Ctx,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    app_state.options::<ScimOptions>().authorize(&headers)?;
+    delete_resource::<ScimUser>(&app_state, &id).await?;
+    audit_change::<ScimUser>(&ctx, "deleted", &id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET handler to list the SCIM groups.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/scim/Groups",
+    params(
+        ("filter" = inline(Option<String>), Query, description = "Filter 'displayName eq \"<value>\"' or 'externalId eq \"<value>\"'."),
+        ("startIndex" = inline(Option<usize>), Query, description = "1-based index of the first group. Defaults to 1."),
+        ("count" = inline(Option<usize>), Query, description = "Number of groups, at most 100. Defaults to 100.")
+    ),
+    responses(
+        (status = 200, description = "Groups listed.", body = [ScimGroup]),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid filter"),
+        (status = StatusCode::UNAUTHORIZED, description = "Missing or invalid bearer token"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Entitlements unavailable")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_scim_groups_handler(
+    headers: HeaderMap,
+    Query(params): Query<ScimListParams>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    app_state.options::<ScimOptions>().authorize(&headers)?;
+    let groups = list_resources::<ScimGroup>(
+        &app_state,
+        params.filter.as_deref(),
+        params.start_index,
+        params.count,
+    )
+    .await?;
+    Ok(scim_response(StatusCode::OK, groups))
+}
+
+/// POST handler to create a SCIM group. A group named with the group prefix and an app name restricts the app to its
+/// members.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/scim/Groups",
+    request_body = ScimGroup,
+    responses(
+        (status = 201, description = "Group created.", body = [ScimGroup]),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid group"),
+        (status = StatusCode::UNAUTHORIZED, description = "Missing or invalid bearer token"),
+        (status = StatusCode::CONFLICT, description = "displayName already taken"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Entitlements unavailable")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_scim_group_handler(
+    ctx: Ctx,
+    headers: HeaderMap,
+    State(app_state): State<Arc<AppState>>,
+    Json(group): Json<ScimGroup>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    app_state.options::<ScimOptions>().authorize(&headers)?;
+    let group = create_resource(&app_state, group).await?;
+    audit_change::<ScimGroup>(&ctx, "created", &group.id);
+    Ok(scim_response(StatusCode::CREATED, group))
+}
+
+/// GET handler to read a SCIM group.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/scim/Groups/{id}",
+    responses(
+        (status = 200, description = "Group retrieved.", body = [ScimGroup]),
+        (status = StatusCode::UNAUTHORIZED, description = "Missing or invalid bearer token"),
+        (status = StatusCode::NOT_FOUND, description = "Group not found"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Entitlements unavailable")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_scim_group_handler(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    app_state.options::<ScimOptions>().authorize(&headers)?;
+    let group: ScimGroup = get_resource(&app_state, &id).await?;
+    Ok(scim_response(StatusCode::OK, group))
+}
+
+/// PUT handler to replace a SCIM group.
+#[utoipa::path(
+    put,
+    path = "/api/v1.1/admin/scim/Groups/{id}",
+    request_body = ScimGroup,
+    responses(
+        (status = 200, description = "Group replaced.", body = [ScimGroup]),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid group"),
+        (status = StatusCode::UNAUTHORIZED, description = "Missing or invalid bearer token"),
+        (status = StatusCode::NOT_FOUND, description = "Group not found"),
+        (status = StatusCode::CONFLICT, description = "displayName already taken"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Entitlements unavailable")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn put_scim_group_handler(
+    ctx: Ctx,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(group): Json<ScimGroup>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    app_state.options::<ScimOptions>().authorize(&headers)?;
+    let group = replace_resource(&app_state, &id, group).await?;
+    audit_change::<ScimGroup>(&ctx, "replaced", &id);
+    Ok(scim_response(StatusCode::OK, group))
+}
+
+/// PATCH handler to patch a SCIM group, e.g. to add or remove members.
+#[utoipa::path(
+    patch,
+    path = "/api/v1.1/admin/scim/Groups/{id}",
+    request_body = PatchRequest,
+    responses(
+        (status = 200, description = "Group patched.", body = [ScimGroup]),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid patch"),
+        (status = StatusCode::UNAUTHORIZED, description = "Missing or invalid bearer token"),
+        (status = StatusCode::NOT_FOUND, description = "Group not found"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Entitlements unavailable")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn patch_scim_group_handler(
+    ctx: Ctx,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    Json(patch): Json<PatchRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    app_state.options::<ScimOptions>().authorize(&headers)?;
+    let group: ScimGroup = patch_resource(&app_state, &id, &patch).await?;
+    audit_change::<ScimGroup>(&ctx, "patched", &id);
+    Ok(scim_response(StatusCode::OK, group))
+}
+
+/// DELETE handler to delete a SCIM group. Deleting the group of an app lifts the restriction of the app.
+#[utoipa::path(
+    delete,
+    path = "/api/v1.1/admin/scim/Groups/{id}",
+    responses(
+        (status = 204, description = "Group deleted."),
+        (status = StatusCode::UNAUTHORIZED, description = "Missing or invalid bearer token"),
+        (status = StatusCode::NOT_FOUND, description = "Group not found"),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Entitlements unavailable")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn delete_scim_group_handler(
+    ctx: Ctx,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    app_state.options::<ScimOptions>().authorize(&headers)?;
+    delete_resource::<ScimGroup>(&app_state, &id).await?;
+    audit_change::<ScimGroup>(&ctx, "deleted", &id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header::AUTHORIZATION;
+    use tokio::runtime::Runtime;
+
+    /// Headers carrying the SCIM bearer token of the settings.
+    fn scim_headers(app_state: &AppState) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let bearer_token = app_state.options::<ScimOptions>().bearer_token.unwrap();
+        headers.insert(
+            AUTHORIZATION,
+            format!("Bearer {}", bearer_token).parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_failure_get_scim_user_handler_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function with an unknown id
+            let result = get_scim_user_handler(
+                scim_headers(&app_state),
+                Path("non-existing-user".to_string()),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code and the SCIM error
+            let (status_code, Json(body)) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+            assert_eq!(body["status"], "404");
+        });
+    }
+
+    #[test]
+    fn test_failure_get_scim_groups_handler_invalid_filter() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let params = ScimListParams {
+                filter: Some("displayName co \"app\"".to_string()),
+                ..Default::default()
+            };
+
+            // Call the function
+            let result =
+                get_scim_groups_handler(scim_headers(&app_state), Query(params), State(app_state))
+                    .await;
+
+            // Check the status code and the SCIM error
+            let (status_code, Json(body)) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+            assert_eq!(body["scimType"], "invalidFilter");
+        });
+    }
+}
@@ -0,0 +1,84 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET handler of the selfcheck of the service.
+//! The handler is mounted at `/api/v1.1/admin/selfcheck`.
+//! It returns the resolved knowledge engine URLs and AWS regions of the validated settings, and probes the
+//! reachability of every Tresleai URL, with the timeout and retries of its calls.
+//! The handler returns a 200 status code with the selfcheck, `reachable` being false if a URL is unreachable.
+//!
+
+use crate::service::selfcheck::{settings_check, SettingsCheck};
+use crate::service::state::AppState;
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, instrument, warn};
+
+/// GET handler to run the selfcheck of the service.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/selfcheck",
+    responses(
+        (status = 200, description = "Selfcheck completed.", body = [SettingsCheck]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_selfcheck_handler(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let check: SettingsCheck = settings_check(&app_state).await;
+    let unreachable: Vec<&str> = check
+        .urls
+        .iter()
+        .filter(|probe| !probe.reachable)
+        .map(|probe| probe.name.as_str())
+        .collect();
+    let message = if unreachable.is_empty() {
+        let message = "Selfcheck completed, every Tresleai URL is reachable.".to_string();
+        debug!(message = message);
+        message
+    } else {
+        let message = format!(
+            "Selfcheck completed, unreachable Tresleai URLs: {}.",
+            unreachable.join(", ")
+        );
+        warn!(message = message);
+        message
+    };
+    Ok(Json(
+        json!({"status": "success", "message": message, "data": check}),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_get_selfcheck_handler() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let response = get_selfcheck_handler(State(app_state))
+                .await
+                .unwrap()
+                .into_response();
+
+            // Check the status code and the probes
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["data"]["urls"].as_array().unwrap().len(), 9);
+        });
+    }
+}
@@ -0,0 +1,312 @@
+/*
+ * Created Date:  Aug 2, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the handlers of the service accounts of the machine-to-machine admin operations (see
+//! `crate::service::service_account`).
+//! The handlers are mounted at `/api/v1.1/admin/service-accounts`.
+//! The GET handler returns the service accounts, revoked ones included, without their secrets.
+//! The POST handler creates a service account with a name and scopes, and returns its client ID and secret. The secret
+//! is only returned here. The caller is a service account granted `service_accounts:manage`, granting only the scopes
+//! it holds, or carries the bootstrap token (see `crate::service::service_account`).
+//! The DELETE handler of `/{client_id}` revokes a service account.
+//! The POST handler of `/{client_id}/secret` rotates the secret of a service account and returns the new secret.
+//! The handlers return a 200 status code if the service accounts are fetched/updated successfully.
+//! The handlers return a 400 status code if the name or a scope is invalid.
+//! The handlers return a 401 status code if the creation is requested without a service account or the bootstrap token.
+//! The handlers return a 403 status code if the caller can't grant a requested scope.
+//! The handlers return a 404 status code if the service account is not found.
+//! The handlers return a 409 status code if an active account has the same name, or if the account is revoked.
+//! The handlers return a 500 status code if an error occurs while accessing the service accounts.
+//!
+
+use crate::service::ctx::Ctx;
+use crate::service::service_account::{
+    create_service_account, granter_scopes, list_service_accounts, revoke_service_account,
+    rotate_service_account_secret, CreateServiceAccountRequest, ServiceAccount,
+    ServiceAccountOptions, ServiceAccountSummary,
+};
+use crate::service::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{info, instrument};
+
+/// GET handler to list the service accounts.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/service-accounts",
+    responses(
+        (status = 200, description = "Service accounts retrieved successfully.", body = [ServiceAccountSummary]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_service_accounts_handler(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let accounts = list_service_accounts(&app_state).await?;
+    let success_message = format!(
+        "{} service accounts retrieved successfully.",
+        accounts.len()
+    );
+    info!(message = success_message);
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "data": accounts,
+    })))
+}
+
+/// POST handler to create a service account.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/service-accounts",
+    request_body = CreateServiceAccountRequest,
+    responses(
+        (status = 200, description = "Service account created successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::UNAUTHORIZED, description = "No service account or bootstrap token", body = [ErrorResponse]),
+        (status = StatusCode::FORBIDDEN, description = "Scope not grantable by the caller", body = [ErrorResponse]),
+        (status = StatusCode::CONFLICT, description = "An active service account with the same name already exists", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_service_account_handler(
+    ctx: Ctx,
+    granter: Option<Extension<ServiceAccount>>,
+    headers: HeaderMap,
+    State(app_state): State<Arc<AppState>>,
+    Json(request): Json<CreateServiceAccountRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let granter = granter.map(|Extension(granter)| granter);
+    let granted = granter_scopes(
+        &app_state.options::<ServiceAccountOptions>(),
+        granter.as_ref(),
+        &headers,
+    )?;
+    let (account, client_secret) = create_service_account(&app_state, &request, &granted).await?;
+    let summary = ServiceAccountSummary::from(&account);
+
+    let success_message = format!(
+        "Service account '{}' created successfully by {}.",
+        account.name,
+        granter
+            .map(|granter| format!("service account '{}'", granter.client_id))
+            .unwrap_or_else(|| "the bootstrap token".to_string())
+    );
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        action = "Service account created",
+        details = json!(summary).to_string(),
+        message = success_message
+    );
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "client_id": account.client_id,
+        "client_secret": client_secret,
+        "data": summary,
+    })))
+}
+
+/// DELETE handler to revoke a service account.
+#[utoipa::path(
+    delete,
+    path = "/api/v1.1/admin/service-accounts/{client_id}",
+    responses(
+        (status = 200, description = "Service account revoked successfully."),
+        (status = StatusCode::NOT_FOUND, description = "Service account not found", body = [ErrorResponse]),
+        (status = StatusCode::CONFLICT, description = "Service account already revoked", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn delete_service_account_handler(
+    ctx: Ctx,
+    Path(client_id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let account = revoke_service_account(&app_state, &client_id).await?;
+
+    let success_message = format!(
+        "Service account '{}' ({}) revoked successfully.",
+        account.name, client_id
+    );
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        action = "Service account revoked",
+        details = success_message,
+        message = success_message
+    );
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "data": ServiceAccountSummary::from(&account),
+    })))
+}
+
+/// POST handler to rotate the secret of a service account.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/service-accounts/{client_id}/secret",
+    responses(
+        (status = 200, description = "Secret rotated successfully."),
+        (status = StatusCode::NOT_FOUND, description = "Service account not found", body = [ErrorResponse]),
+        (status = StatusCode::CONFLICT, description = "Service account revoked", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_service_account_secret_handler(
+    ctx: Ctx,
+    Path(client_id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let (account, client_secret) = rotate_service_account_secret(&app_state, &client_id).await?;
+
+    let success_message = format!(
+        "Secret of service account '{}' ({}) rotated successfully.",
+        account.name, client_id
+    );
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        action = "Service account secret rotated",
+        details = success_message,
+        message = success_message
+    );
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "client_id": client_id,
+        "client_secret": client_secret,
+        "data": ServiceAccountSummary::from(&account),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::api_key::hash_api_key;
+    use crate::service::service_account::{Scope, ServiceAccountError};
+    use chrono::Utc;
+    use tokio::runtime::Runtime;
+
+    fn granter(scopes: Vec<Scope>) -> Option<Extension<ServiceAccount>> {
+        Some(Extension(ServiceAccount {
+            client_id: "sa_ci".to_string(),
+            name: "ci".to_string(),
+            scopes,
+            secret_hash: hash_api_key("secret"),
+            created_at: Utc::now(),
+            secret_rotated_at: None,
+            revoked_at: None,
+        }))
+    }
+
+    fn request(scopes: &[&str]) -> CreateServiceAccountRequest {
+        CreateServiceAccountRequest {
+            name: "ci-onboarding".to_string(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_failure_post_service_account_handler_invalid_scope() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let ctx = Ctx::new(&app_state, "test_app", "Test");
+            let result = post_service_account_handler(
+                ctx,
+                granter(vec![Scope::Admin]),
+                HeaderMap::new(),
+                State(app_state),
+                Json(request(&["apps:everything"])),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, body) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+            assert_eq!(
+                body["message"],
+                ServiceAccountError::InvalidScope("apps:everything".to_string()).to_string()
+            );
+        });
+    }
+
+    #[test]
+    fn test_failure_post_service_account_handler_missing_granter() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function without a service account nor the bootstrap token
+            let ctx = Ctx::new(&app_state, "test_app", "Test");
+            let result = post_service_account_handler(
+                ctx,
+                None,
+                HeaderMap::new(),
+                State(app_state),
+                Json(request(&["apps:read"])),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, body) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::UNAUTHORIZED);
+            assert_eq!(
+                body["message"],
+                ServiceAccountError::MissingGranter.to_string()
+            );
+        });
+    }
+
+    #[test]
+    fn test_failure_post_service_account_handler_scope_escalation() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // An account only granted service_accounts:manage can't mint admin nor identity:reveal accounts
+            for scope in ["admin", "identity:reveal"] {
+                let ctx = Ctx::new(&app_state, "test_app", "Test");
+                let result = post_service_account_handler(
+                    ctx,
+                    granter(vec![Scope::ServiceAccountsManage]),
+                    HeaderMap::new(),
+                    State(app_state.clone()),
+                    Json(request(&[scope])),
+                )
+                .await;
+
+                // Check the status code
+                let (status_code, body) = result.err().unwrap();
+                assert_eq!(status_code, StatusCode::FORBIDDEN);
+                assert_eq!(
+                    body["message"],
+                    ServiceAccountError::NotGrantable(scope.to_string()).to_string()
+                );
+            }
+        });
+    }
+}
@@ -0,0 +1,289 @@
+/*
+ * Created Date:  Jun 28, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the GET handler for fetching the token usage of an app, for capacity planning and billing.
+//! The handler is mounted at `/api/v1.1/admin/usage/tokens/{app_name}`.
+//! The token usage documents stored per retrieval are rolled up over the last 30 days, or between the optional
+//! `utc_start_timestamp` and `utc_end_timestamp` query parameters, into the totals of the app and the
+//! per-user and per-model breakdowns, with a single faceted aggregation.
+//! The handler returns a 200 status code if the token usage is fetched successfully.
+//! The handler returns a 400 status code if the time range is invalid.
+//! The handler returns a 404 status code if the app is not found.
+//! The handler returns a 500 status code if an error occurs while running the aggregation.
+//!
+
+use crate::admin_ui_api::schema::QueryParams;
+use crate::service::check_app_existence::check_app_existence;
+use crate::service::ctx::Ctx;
+use crate::service::query_options::{AggregateExt, QueryError, QueryOptions};
+use crate::service::state::AppState;
+use crate::service::timestamp::timestamp_range;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::{doc, Bson, Document};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// Default time range of the token usage, in days.
+const DEFAULT_USAGE_DAYS: i64 = 30;
+
+/// GET handler to fetch the token usage of an app, per user and per model.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/usage/tokens/{app_name}",
+    params(
+        (
+            "utc_start_timestamp" = Option<String>,
+            Query,
+            description = "UTC start timestamp (RFC 3339). Defaults to 30 days before the end timestamp.",
+        ),
+        (
+            "utc_end_timestamp" = Option<String>,
+            Query,
+            description = "UTC end timestamp (RFC 3339). Defaults to now.",
+        )
+    ),
+    responses(
+        (status = 200, description = "Token usage of the app fetched successfully."),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "App not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_token_usage_handler(
+    ctx: Ctx,
+    Path(app_name): Path<String>,
+    Query(params): Query<QueryParams>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let end_timestamp = params.utc_end_timestamp.unwrap_or_else(Utc::now);
+    let start_timestamp = params
+        .utc_start_timestamp
+        .unwrap_or(end_timestamp - Duration::days(DEFAULT_USAGE_DAYS));
+    if start_timestamp > end_timestamp {
+        let error_message = format!(
+            "utc_start_timestamp '{}' is after utc_end_timestamp '{}'.",
+            start_timestamp.to_rfc3339(),
+            end_timestamp.to_rfc3339()
+        );
+        debug!(message = error_message);
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    if !check_app_existence(&app_state, &app_name).await? {
+        let error_message = format!("No app found with name '{}'.", app_name);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    }
+
+    let collection_name = &app_state
+        .app_settings
+        .mongo_db
+        .mongo_db_token_usage_collection;
+    let pipeline = token_usage_pipeline(&app_name, start_timestamp, end_timestamp);
+
+    // The rollup runs on the analytics connection of the primary cluster, when configured
+    match app_state
+        .analytics_db(None)?
+        .aggregate(
+            collection_name,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await
+    {
+        Ok(results) => {
+            let facets = results.into_iter().next().unwrap_or_default();
+            let totals = facets
+                .get("totals")
+                .and_then(|totals| totals.get(0))
+                .cloned()
+                .unwrap_or(json!({"retrievals": 0, "prompt_tokens": 0, "completion_tokens": 0, "total_tokens": 0}));
+            let success_message =
+                format!("Token usage of app '{}' fetched successfully.", app_name);
+            info!(app_name = app_name, message = success_message);
+            Ok(Json(json!({
+                "status": "success",
+                "message": success_message,
+                "app_name": app_name,
+                "utc_start_timestamp": start_timestamp.to_rfc3339(),
+                "utc_end_timestamp": end_timestamp.to_rfc3339(),
+                "totals": totals,
+                "by_user": facets.get("by_user").cloned().unwrap_or(json!([])),
+                "by_model": facets.get("by_model").cloned().unwrap_or(json!([])),
+            })))
+        }
+        Err(QueryError::Db(e)) => {
+            let error_message = format!(
+                "Failed to fetch token usage of app '{}'. Error: {}",
+                app_name, e
+            );
+            let ext_message = ctx.ext_message(&app_state);
+            error!(
+                app_name = app_name,
+                task_id = ctx.task_id,
+                ext_message = ext_message,
+                message = error_message
+            );
+            Err(e.intercept_error().await)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Group stage summing the token counts of the documents with the same key.
+fn token_usage_group(key: Bson) -> Document {
+    doc! {
+        "$group": {
+            "_id": key,
+            "retrievals": { "$sum": 1 },
+            "prompt_tokens": { "$sum": "$prompt_tokens" },
+            "completion_tokens": { "$sum": "$completion_tokens" },
+            "total_tokens": { "$sum": "$total_tokens" },
+        }
+    }
+}
+
+/// Stages of the usage breakdown per user or per model. The largest consumers come first.
+fn token_usage_breakdown(field: &str) -> Vec<Document> {
+    let mut project = doc! {
+        "_id": 0,
+        "retrievals": 1,
+        "prompt_tokens": 1,
+        "completion_tokens": 1,
+        "total_tokens": 1,
+    };
+    project.insert(field, "$_id");
+    vec![
+        token_usage_group(Bson::String(format!("${}", field))),
+        doc! { "$project": project },
+        doc! { "$sort": { "total_tokens": -1 } },
+    ]
+}
+
+/// Aggregation pipeline rolling up the token usage of an app into the totals, the per-user and the per-model usage.
+fn token_usage_pipeline(
+    app_name: &str,
+    start_timestamp: DateTime<Utc>,
+    end_timestamp: DateTime<Utc>,
+) -> Vec<Document> {
+    vec![
+        doc! {
+            "$match": {
+                "app_name": app_name,
+                "timestamp": timestamp_range(start_timestamp, end_timestamp),
+            }
+        },
+        doc! {
+            "$facet": {
+                "totals": [
+                    token_usage_group(Bson::Null),
+                    { "$project": { "_id": 0 } },
+                ],
+                "by_user": token_usage_breakdown("user_id"),
+                "by_model": token_usage_breakdown("model_used"),
+            }
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_token_usage_pipeline() {
+        let end_timestamp = Utc::now();
+        let start_timestamp = end_timestamp - Duration::days(DEFAULT_USAGE_DAYS);
+        let pipeline = token_usage_pipeline("app100", start_timestamp, end_timestamp);
+
+        assert_eq!(pipeline.len(), 2);
+        // The stored timestamps are compared as dates
+        let filter = pipeline[0].get_document("$match").unwrap();
+        let range = filter.get_document("timestamp").unwrap();
+        assert!(range.get_datetime("$gte").is_ok());
+        let facet = pipeline[1].get_document("$facet").unwrap();
+        let by_user = facet.get_array("by_user").unwrap();
+        let group = by_user[0]
+            .as_document()
+            .unwrap()
+            .get_document("$group")
+            .unwrap();
+        assert_eq!(group.get_str("_id").unwrap(), "$user_id");
+        assert!(group.contains_key("total_tokens"));
+        let project = by_user[1]
+            .as_document()
+            .unwrap()
+            .get_document("$project")
+            .unwrap();
+        assert_eq!(project.get_str("user_id").unwrap(), "$_id");
+    }
+
+    #[test]
+    fn test_failure_get_token_usage_handler_invalid_time_range() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "app100".to_string();
+            let params = QueryParams {
+                utc_start_timestamp: Some(Utc::now()),
+                utc_end_timestamp: Some(Utc::now() - Duration::days(1)),
+                ..Default::default()
+            };
+
+            // Call the function
+            let result = get_token_usage_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                Query(params),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn test_failure_get_token_usage_handler_app_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and app_name
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_name = "non-existing-app".to_string();
+
+            // Call the function
+            let result = get_token_usage_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path(app_name),
+                Query(QueryParams::default()),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
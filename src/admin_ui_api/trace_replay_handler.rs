@@ -0,0 +1,304 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the handlers of the replays of the retrievals, to let engineers reproduce a bad answer.
+//! The POST handler is mounted at `/api/v1.1/admin/trace/{reference_id}/replay`. It re-executes the stored request of
+//! the retrieval against the knowledge engine in the background, under a new reference ID, and returns it as
+//! `replay_reference_id`. The replay is sent to the audit microservice.
+//! The GET handler of `/api/v1.1/admin/trace/{reference_id}/replays` returns the history document of the retrieval
+//! and the history documents of its last replays, the latest first.
+//! Both handlers first reconcile the history documents of the retrieval: duplicates stored for the reference ID before
+//! the unique index of the history collection existed are deleted, keeping the latest answer. The outcome is returned
+//! as `reconciliation`.
+//! The handlers return a 200 status code if the retrieval is replayed/the replays are fetched successfully.
+//! The handlers return a 400 status code if a label of the search scope of the retrieval no longer labels a source.
+//! The handlers return a 404 status code if the reference ID is unknown.
+//! The handlers return a 409 status code if the retrieval has no history document yet, or no stored request.
+//! The handlers return a 500 status code if an error occurs while replaying the retrieval/fetching the replays.
+//!
+
+use crate::retrieval::handler::{start_retrieval_job, RetrievalJob};
+use crate::retrieval::replay::{
+    find_id_document, replay_assignments, ReplayError, StoredRequest, REPLAY_OF_FIELD,
+    REPLAY_SERVICE_TYPE,
+};
+use crate::retrieval::schema::history_document::HistoryDocument;
+use crate::retrieval::stage_timings::StageTimings;
+use crate::service::ctx::Ctx;
+use crate::service::generate_and_insert_document::generate_id_document;
+use crate::service::history_upsert::{reconcile_history_documents, HistoryReconciliation};
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::source_label::RetrievalScope;
+use crate::service::state::AppState;
+use crate::service::timestamp::serve_timestamps;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::Utc;
+use mongodb::bson::{doc, to_document};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, info, instrument};
+
+/// Number of replays returned by the GET handler.
+const REPLAYS_LIMIT: i64 = 20;
+
+/// Reads a stored history document of an app, decrypted, without its request.
+async fn read_history_document(
+    app_state: &AppState,
+    app_name: &str,
+    reference_id: &str,
+    mut history_document: serde_json::Value,
+) -> Result<HistoryDocument, ReplayError> {
+    let store_error = |message: String| ReplayError::Store {
+        reference_id: reference_id.to_string(),
+        message,
+    };
+    app_state
+        .decrypt_fields(app_name, &mut history_document)
+        .await
+        .map_err(|e| store_error(e.to_string()))?;
+    HistoryDocument::from_stored(history_document).map_err(|e| store_error(e.to_string()))
+}
+
+/// Deletes the duplicate history documents of a retrieval.
+async fn reconcile(
+    app_state: &AppState,
+    app_name: &str,
+    reference_id: &str,
+) -> Result<HistoryReconciliation, ReplayError> {
+    reconcile_history_documents(app_state, app_name, reference_id)
+        .await
+        .map_err(|e| ReplayError::Store {
+            reference_id: reference_id.to_string(),
+            message: e.to_string(),
+        })
+}
+
+/// Returns the history document of a retrieval, decrypted.
+async fn find_history_document(
+    app_state: &AppState,
+    app_name: &str,
+    reference_id: &str,
+) -> Result<HistoryDocument, ReplayError> {
+    let store_error = |message: String| ReplayError::Store {
+        reference_id: reference_id.to_string(),
+        message,
+    };
+    let history_document = app_state
+        .app_db(app_name)
+        .await
+        .map_err(|e| store_error(e.to_string()))?
+        .get_document(
+            &format!("{}-history", app_name),
+            doc! {"reference_id": reference_id},
+        )
+        .await
+        .map_err(|e| store_error(e.to_string()))?
+        .ok_or_else(|| ReplayError::InProgress(reference_id.to_string()))?;
+    read_history_document(app_state, app_name, reference_id, history_document).await
+}
+
+/// POST handler to replay a retrieval.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/trace/{reference_id}/replay",
+    responses(
+        (status = 200, description = "Replay started."),
+        (status = StatusCode::BAD_REQUEST, description = "Search scope label no longer labeling a source", body = [ErrorResponse]),
+        (status = StatusCode::NOT_FOUND, description = "Unknown reference ID", body = [ErrorResponse]),
+        (status = StatusCode::CONFLICT, description = "Retrieval without history document or stored request", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_trace_replay_handler(
+    ctx: Ctx,
+    Path(reference_id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let app_name = find_id_document(&app_state, &reference_id).await?.app_name;
+    let reconciliation = reconcile(&app_state, &app_name, &reference_id).await?;
+    let history_document = find_history_document(&app_state, &app_name, &reference_id).await?;
+    let stored_request: StoredRequest = history_document
+        .request
+        .as_deref()
+        .ok_or_else(|| ReplayError::NotReplayable(reference_id.clone()))
+        .and_then(|request| {
+            serde_json::from_str(request).map_err(|e| ReplayError::Store {
+                reference_id: reference_id.clone(),
+                message: e.to_string(),
+            })
+        })?;
+
+    // Replay with the variants assigned to the retrieval and the current row filters and source labels of the app
+    let experiments = app_state.apps().experiments(&app_name).await?;
+    let assignments = replay_assignments(&experiments, &history_document.experiment_variants);
+    let row_filters = app_state.apps().row_filters(&app_name).await?;
+    let search_scope = RetrievalScope {
+        search_scope: stored_request.search_scope.clone(),
+    }
+    .resolve(&app_state.apps().source_labels(&app_name).await?)?;
+
+    // Record the new reference ID, so the replay can be traced and replayed in turn
+    let replay_reference_id = app_state.id_generator.reference_id();
+    let task_id = app_state
+        .id_generator
+        .task_id(&app_name, REPLAY_SERVICE_TYPE);
+    let id_document =
+        generate_id_document(&app_name, replay_reference_id.clone(), task_id.clone()).await;
+    let store_error = |message: String| ReplayError::Store {
+        reference_id: reference_id.clone(),
+        message,
+    };
+    let id_document = to_document(&id_document).map_err(|e| store_error(e.to_string()))?;
+    app_state
+        .db
+        .create_document(
+            &app_state.app_settings.mongo_db.mongo_db_id_collection,
+            id_document,
+        )
+        .await
+        .map_err(|e| store_error(e.to_string()))?;
+
+    let user_id = stored_request.body.user_details.user_id.clone();
+    start_retrieval_job(
+        &app_state,
+        RetrievalJob {
+            app_name: app_name.clone(),
+            user_id,
+            body: stored_request.body,
+            sub_queries: stored_request.sub_queries,
+            experiments: assignments,
+            row_filters,
+            search_scope,
+            reference_id: replay_reference_id.clone(),
+            task_id,
+            request_timestamp: Utc::now(),
+            replay_of: Some(reference_id.clone()),
+            sandbox: false,
+            deadline: ctx.deadline,
+            timings: StageTimings::default(),
+        },
+    )
+    .await;
+
+    let success_message = format!(
+        "Replay '{}' of retrieval '{}' started.",
+        replay_reference_id, reference_id
+    );
+    info!(app_name = app_name, message = success_message);
+    info!(
+        service = "audit_microservice",
+        task_id = ctx.task_id,
+        app_name = app_name,
+        action = "Retrieval replayed",
+        details = success_message,
+        message = success_message
+    );
+    Ok(Json(json!({
+        "status": "success",
+        "message": success_message,
+        "app_name": app_name,
+        "reference_id": reference_id,
+        "replay_reference_id": replay_reference_id,
+        "reconciliation": reconciliation
+    })))
+}
+
+/// GET handler to fetch a retrieval and its replays.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/trace/{reference_id}/replays",
+    responses(
+        (status = 200, description = "Replays retrieved successfully.", body = [HistoryDocument]),
+        (status = StatusCode::NOT_FOUND, description = "Unknown reference ID", body = [ErrorResponse]),
+        (status = StatusCode::CONFLICT, description = "Retrieval without history document", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_trace_replays_handler(
+    Path(reference_id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let app_name = find_id_document(&app_state, &reference_id).await?.app_name;
+    let reconciliation = reconcile(&app_state, &app_name, &reference_id).await?;
+    let mut original = find_history_document(&app_state, &app_name, &reference_id).await?;
+    original.request = None;
+
+    let pipeline = vec![
+        doc! {"$match": {REPLAY_OF_FIELD: &reference_id}},
+        doc! {"$sort": {"_id": -1}},
+        doc! {"$limit": REPLAYS_LIMIT},
+        doc! {"$project": {"_id": 0}},
+    ];
+    let stored_replays = app_state
+        .app_db(&app_name)
+        .await?
+        .aggregate(
+            &format!("{}-history", app_name),
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await?;
+    let mut replays = Vec::with_capacity(stored_replays.len());
+    for replay in stored_replays {
+        let mut replay =
+            read_history_document(&app_state, &app_name, &reference_id, replay).await?;
+        replay.request = None;
+        replays.push(replay);
+    }
+
+    let success_message = format!(
+        "{} replay(s) of retrieval '{}' fetched successfully.",
+        replays.len(),
+        reference_id
+    );
+    debug!(app_name = app_name, message = success_message);
+    let mut body = json!({
+        "status": "success",
+        "message": success_message,
+        "app_name": app_name,
+        "data": {
+            "original": original,
+            "replays": replays,
+            "reconciliation": reconciliation
+        }
+    });
+    serve_timestamps(&mut body);
+    Ok(Json(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failure_post_trace_replay_handler_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function with an unknown reference ID
+            let result = post_trace_replay_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                Path("non-existing-reference-id".to_string()),
+                State(app_state),
+            )
+            .await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
@@ -0,0 +1,164 @@
+/*
+ * Created Date:  Jun 21, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the HTTP client of the facade used by the CLI commands.
+//! The requests carry the credentials of a service account as `Authorization: Basic` when they are given.
+//! Non-2xx responses are turned into `CliError::Api` carrying the `message` of the facade error body.
+//!
+
+use crate::app_onboarding_request::OnboardingRequest;
+use crate::apply_plan::ApplyResponse;
+use crate::response::AppCreateResponse;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CliError {
+    #[error("Request to the facade failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Facade returned {status}: {message}")]
+    Api { status: StatusCode, message: String },
+    #[error("Failed to read '{path}': {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("Invalid app descriptor: {0}")]
+    Descriptor(String),
+    #[error("{0}")]
+    Usage(String),
+}
+
+/// Client of the facade admin APIs.
+pub struct FacadeClient {
+    base_url: String,
+    http_client: Client,
+}
+
+impl FacadeClient {
+    /// Builds the client, authenticating its requests with the client ID and secret of a service account if given.
+    pub fn new(base_url: &str, credentials: Option<(String, String)>) -> Result<Self, CliError> {
+        let mut headers = HeaderMap::new();
+        if let Some((client_id, client_secret)) = credentials {
+            let basic = STANDARD.encode(format!("{}:{}", client_id, client_secret));
+            let mut authorization = HeaderValue::from_str(&format!("Basic {}", basic))
+                .map_err(|e| CliError::Usage(format!("Invalid credentials: {}", e)))?;
+            authorization.set_sensitive(true);
+            headers.insert(AUTHORIZATION, authorization);
+        }
+        Ok(FacadeClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http_client: Client::builder().default_headers(headers).build()?,
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// Sends the request and deserializes the JSON body of a successful response.
+    async fn send<T: DeserializeOwned>(request: RequestBuilder) -> Result<T, CliError> {
+        let response = request.header("accept", "application/json").send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response.json().await?);
+        }
+        let body = response.text().await.unwrap_or_default();
+        let message = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|error| {
+                error
+                    .get("message")
+                    .and_then(serde_json::Value::as_str)
+                    .map(str::to_string)
+            })
+            .unwrap_or(body);
+        Err(CliError::Api { status, message })
+    }
+
+    pub async fn onboard(
+        &self,
+        request: &OnboardingRequest,
+        is_update: bool,
+    ) -> Result<AppCreateResponse, CliError> {
+        Self::send(
+            self.http_client
+                .post(self.url("/api/v1.1/admin/apps/onboard"))
+                .query(&[("is_update", is_update)])
+                .json(request),
+        )
+        .await
+    }
+
+    pub async fn apply(
+        &self,
+        descriptor: String,
+        dry_run: bool,
+    ) -> Result<ApplyResponse, CliError> {
+        Self::send(
+            self.http_client
+                .post(self.url("/api/v1.1/admin/apps/apply"))
+                .query(&[("dry_run", dry_run)])
+                .header("content-type", "application/yaml")
+                .body(descriptor),
+        )
+        .await
+    }
+
+    pub async fn get_app(&self, app_name: &str) -> Result<serde_json::Value, CliError> {
+        Self::send(
+            self.http_client
+                .get(self.url(&format!("/api/v1.1/admin/apps/{}", app_name))),
+        )
+        .await
+    }
+
+    pub async fn migrate_residency(
+        &self,
+        app_name: &str,
+        residency: Option<&str>,
+    ) -> Result<serde_json::Value, CliError> {
+        Self::send(
+            self.http_client
+                .post(self.url(&format!("/api/v1.1/admin/apps/{}/residency", app_name)))
+                .json(&serde_json::json!({ "residency": residency })),
+        )
+        .await
+    }
+
+    pub async fn delete_app(
+        &self,
+        app_name: &str,
+        confirmation_token: Option<&str>,
+    ) -> Result<serde_json::Value, CliError> {
+        Self::send(
+            self.http_client
+                .delete(self.url(&format!("/api/v1.1/admin/apps/{}", app_name)))
+                .query(&[("confirmation_token", confirmation_token)]),
+        )
+        .await
+    }
+
+    pub async fn logs(
+        &self,
+        app_name: &str,
+        start_timestamp: &str,
+        end_timestamp: &str,
+    ) -> Result<serde_json::Value, CliError> {
+        Self::send(
+            self.http_client
+                .get(self.url("/api/v1.1/admin/logs"))
+                .query(&[
+                    ("app_name", app_name),
+                    ("start_timestamp", start_timestamp),
+                    ("end_timestamp", end_timestamp),
+                ]),
+        )
+        .await
+    }
+}
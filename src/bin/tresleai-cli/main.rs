@@ -0,0 +1,264 @@
+/*
+ * Created Date:  Jun 21, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! `tresleai-cli` - command line companion of the facade for common operator workflows:
+//! onboarding from a file, checking the onboarding status, tailing the logs of an app, migrating an app to
+//! another data residency and deleting an app.
+//! The request/response schema modules of the service are compiled into the CLI, so the payloads
+//! never drift from the server.
+//!
+
+mod client;
+
+#[allow(dead_code)]
+#[path = "../../onboarding/schema/app_onboarding_request.rs"]
+mod app_onboarding_request;
+#[allow(dead_code)]
+#[path = "../../onboarding/schema/apply_plan.rs"]
+mod apply_plan;
+#[allow(dead_code)]
+#[path = "../../onboarding/schema/response.rs"]
+mod response;
+#[allow(dead_code)]
+#[path = "../../onboarding/schema/schema_preview.rs"]
+mod schema_preview;
+
+use app_onboarding_request::OnboardingRequest;
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::{Parser, Subcommand};
+use client::{CliError, FacadeClient};
+use serde::Serialize;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(
+    name = "tresleai-cli",
+    version,
+    about = "Operator CLI of the tresleai facade"
+)]
+struct Cli {
+    /// Base URL of the facade.
+    #[arg(
+        long,
+        env = "TRESLEAI_FACADE_URL",
+        default_value = "http://localhost:8000"
+    )]
+    url: String,
+    /// Client ID of the service account the requests are authenticated as.
+    #[arg(long, env = "TRESLEAI_CLIENT_ID", requires = "client_secret")]
+    client_id: Option<String>,
+    /// Secret of the service account the requests are authenticated as.
+    #[arg(
+        long,
+        env = "TRESLEAI_CLIENT_SECRET",
+        hide_env_values = true,
+        requires = "client_id"
+    )]
+    client_secret: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Onboard (or update) an app from a JSON or YAML onboarding request file.
+    Onboard {
+        file: String,
+        /// Update an existing app instead of onboarding a new one.
+        #[arg(long)]
+        update: bool,
+    },
+    /// Apply a declarative app descriptor and print the computed plan.
+    Apply {
+        file: String,
+        /// Only compute the plan.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Print the onboarding status of an app.
+    Status { app_name: String },
+    /// Print the logs of an app, optionally following new entries.
+    Logs {
+        app_name: String,
+        /// Start of the logs (RFC 3339), defaults to one hour ago.
+        #[arg(long)]
+        since: Option<String>,
+        /// Keep polling for new logs.
+        #[arg(long, short)]
+        follow: bool,
+        /// Polling interval in seconds when following.
+        #[arg(long, default_value_t = 5)]
+        interval_secs: u64,
+    },
+    /// Migrate the collections of an app to another residency cluster.
+    Migrate {
+        app_name: String,
+        /// Target residency, the primary cluster if not set.
+        #[arg(long)]
+        residency: Option<String>,
+    },
+    /// Delete an app and its associated resources.
+    Delete {
+        app_name: String,
+        /// Confirm the deletion.
+        #[arg(long)]
+        yes: bool,
+        /// Confirmation token returned by the first deletion of a large app. The service account of the
+        /// credentials is the approver of the deletion.
+        #[arg(long, requires = "client_id")]
+        confirmation_token: Option<String>,
+    },
+}
+
+fn read_file(path: &str) -> Result<String, CliError> {
+    std::fs::read_to_string(path).map_err(|source| CliError::Io {
+        path: path.to_string(),
+        source,
+    })
+}
+
+/// Parses an onboarding request file. `.json` files are parsed as JSON, anything else as YAML.
+fn parse_onboarding_request(path: &str, contents: &str) -> Result<OnboardingRequest, CliError> {
+    let is_json = Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+    if is_json {
+        serde_json::from_str(contents).map_err(|e| CliError::Descriptor(e.to_string()))
+    } else {
+        serde_yaml::from_str(contents).map_err(|e| CliError::Descriptor(e.to_string()))
+    }
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).unwrap_or_default()
+    );
+}
+
+/// Prints the log entries of a logs response, one JSON line per entry.
+fn print_logs(logs: &serde_json::Value) {
+    let entries = logs
+        .as_array()
+        .or_else(|| logs.get("data").and_then(serde_json::Value::as_array));
+    match entries {
+        Some(entries) => entries.iter().for_each(|entry| println!("{}", entry)),
+        None => println!("{}", logs),
+    }
+}
+
+fn format_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+async fn run(cli: Cli) -> Result<(), CliError> {
+    let credentials = cli.client_id.zip(cli.client_secret);
+    let client = FacadeClient::new(&cli.url, credentials)?;
+
+    match cli.command {
+        Command::Onboard { file, update } => {
+            let request = parse_onboarding_request(&file, &read_file(&file)?)?;
+            print_json(&client.onboard(&request, update).await?);
+        }
+        Command::Apply { file, dry_run } => {
+            print_json(&client.apply(read_file(&file)?, dry_run).await?);
+        }
+        Command::Status { app_name } => {
+            let app = client.get_app(&app_name).await?;
+            let onboarding_status = app
+                .pointer("/data/onboarding_status")
+                .and_then(serde_json::Value::as_str)
+                .unwrap_or("unknown");
+            println!("{}: {}", app_name, onboarding_status);
+        }
+        Command::Logs {
+            app_name,
+            since,
+            follow,
+            interval_secs,
+        } => {
+            let mut start_timestamp =
+                since.unwrap_or_else(|| format_timestamp(Utc::now() - chrono::Duration::hours(1)));
+            loop {
+                let end_timestamp = format_timestamp(Utc::now());
+                print_logs(
+                    &client
+                        .logs(&app_name, &start_timestamp, &end_timestamp)
+                        .await?,
+                );
+                if !follow {
+                    break;
+                }
+                start_timestamp = end_timestamp;
+                tokio::time::sleep(Duration::from_secs(interval_secs)).await;
+            }
+        }
+        Command::Migrate {
+            app_name,
+            residency,
+        } => {
+            print_json(
+                &client
+                    .migrate_residency(&app_name, residency.as_deref())
+                    .await?,
+            );
+        }
+        Command::Delete {
+            app_name,
+            yes,
+            confirmation_token,
+        } => {
+            if !yes {
+                return Err(CliError::Usage(format!(
+                    "Refusing to delete app '{}' without --yes.",
+                    app_name
+                )));
+            }
+            print_json(
+                &client
+                    .delete_app(&app_name, confirmation_token.as_deref())
+                    .await?,
+            );
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Cli::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_success_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_success_parse_onboarding_request() {
+        let contents = read_file("src/test/app_config.json").unwrap();
+        let from_json = parse_onboarding_request("app_config.json", &contents).unwrap();
+
+        // The same request as YAML
+        let yaml = serde_yaml::to_string(&from_json).unwrap();
+        let from_yaml = parse_onboarding_request("app_config.yaml", &yaml).unwrap();
+        assert_eq!(from_json, from_yaml);
+
+        assert!(parse_onboarding_request("app_config.json", "{").is_err());
+    }
+}
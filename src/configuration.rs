@@ -10,4 +10,7 @@
 // todo: move to utils
 
 pub mod environment;
+pub mod options;
+pub mod sanitized;
 pub mod settings;
+pub mod typed;
@@ -38,17 +38,29 @@ pub fn init_environment_and_get_settings(
             )));
         }
     };
+    let config_sources = settings::ConfigSources {
+        config_dir: config_dir.clone(),
+        global_yaml: global_yaml.clone(),
+        local_yaml: local_yaml.clone(),
+        profile_yaml: std::env::var("PROFILE_YAML").ok(),
+    };
     let config_dir = base_dir.join(config_dir);
 
-    let settings_loader = config::Config::builder()
+    let mut settings_builder = config::Config::builder()
         .add_source(config::File::from(config_dir.join(global_yaml)))
-        .add_source(config::File::from(config_dir.join(local_yaml)))
-        .build()
-        .map_err(SettingsError::Config)?;
+        .add_source(config::File::from(config_dir.join(local_yaml)));
+    // An optional profile, e.g. `local_dev.yaml`, overrides the local settings
+    if let Some(profile_yaml) = &config_sources.profile_yaml {
+        settings_builder =
+            settings_builder.add_source(config::File::from(config_dir.join(profile_yaml)));
+    }
+    let settings_loader = settings_builder.build().map_err(SettingsError::Config)?;
 
-    settings_loader
+    let mut settings = settings_loader
         .try_deserialize::<settings::TresleFacadeServiceSettings>()
-        .map_err(SettingsError::Config)
+        .map_err(SettingsError::Config)?;
+    settings.config_sources = config_sources;
+    Ok(settings)
 }
 
 #[cfg(test)]
@@ -76,6 +88,26 @@ mod tests {
         env::remove_var("LOCAL_YAML");
     }
 
+    #[test]
+    fn test_success_init_environment_with_profile() {
+        dotenv::dotenv().ok();
+        let _guard = crate::tests::TEST_ENV_MUTEX.lock().unwrap();
+        env::set_var("PROFILE_YAML", "local_dev.yaml");
+
+        let result = init_environment_and_get_settings();
+        env::remove_var("PROFILE_YAML");
+        let settings = result.unwrap();
+        assert!(settings
+            .local_dev
+            .is_some_and(|local_dev| local_dev.enabled));
+        assert_eq!(
+            settings.config_sources.profile_yaml.as_deref(),
+            Some("local_dev.yaml")
+        );
+        // The settings left out of the profile come from the local settings
+        assert_eq!(settings.application.name, "tresle-facade-service");
+    }
+
     #[test]
     fn test_fail_missing_environment_variables() {
         let _guard = crate::tests::TEST_ENV_MUTEX.lock().unwrap();
@@ -0,0 +1,48 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the settings-backed options. An options type reads its optional section of the settings and
+//! falls back to the `DEFAULT_` constants of its module for anything unset. Handlers read them with
+//! `AppState::options`, e.g. `app_state.options::<QueryOptions>()`.
+//!
+
+use crate::configuration::settings::TresleFacadeServiceSettings;
+
+/// Options resolved from a section of the settings.
+pub trait SettingsOptions: Sized {
+    /// Section of the settings holding the options.
+    type Settings;
+
+    /// Returns the section of the options, if set.
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&Self::Settings>;
+
+    /// Builds the options from their section, with defaults for anything unset.
+    fn from_settings(settings: Option<&Self::Settings>) -> Self;
+
+    /// Resolves the options from the settings.
+    fn resolve(settings: &TresleFacadeServiceSettings) -> Self {
+        Self::from_settings(Self::section(settings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::query_options::QueryOptions;
+    use crate::tests::test_get_appstate;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_settings_options_resolve() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let app_state = test_get_appstate().await.unwrap();
+            assert_eq!(
+                app_state.options::<QueryOptions>(),
+                QueryOptions::from_settings(app_state.app_settings.query_options.as_ref())
+            );
+        });
+    }
+}
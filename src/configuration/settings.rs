@@ -5,8 +5,15 @@
  */
 //! This module contains the setting
 
+use crate::configuration::typed::{AwsRegion, EndpointPath, ServiceUrl};
+use crate::onboarding::sample_rows::MaskingRule;
+use crate::onboarding::schema::app_onboarding_request::{FileTypeMode, Sensitivity};
+use crate::retrieval::cost_estimate::ModelPrice;
+use crate::retrieval::query_classification::{ClassificationRule, ClassifierMode};
+use crate::service::vector_store::VectorBackend;
 use secrecy::Secret;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
 
 #[derive(Debug, thiserror::Error)]
@@ -16,7 +23,7 @@ pub enum SettingsError {
 }
 
 /// Top level settings for the Tresle Facade Service
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct TresleFacadeServiceSettings {
     // Global settings
     pub tresle_version: String,
@@ -44,6 +51,8 @@ pub struct TresleFacadeServiceSettings {
     pub aws: Option<AWSSettings>,
     pub aws_s3: AWSS3Settings,
     pub aws_iam: AWSIAMSettings,
+    /// Unused in the internal API key mode, which can leave it out.
+    #[serde(default)]
     pub aws_api_gateway: AWSApiGatewaySettings,
     pub kafka_client: KafkaClientSettings,
     pub kubernetes: KubernetesSettings,
@@ -55,54 +64,166 @@ pub struct TresleFacadeServiceSettings {
     pub onboard_complete_status: String,
     pub sqs_key_value: String,
     pub retrieval_progress_msg: String,
+    pub tls: Option<TlsSettings>,
+    pub http_client: Option<HttpClientSettings>,
+    pub compression: Option<CompressionSettings>,
+    pub metrics: Option<MetricsSettings>,
+    pub vector_store: Option<VectorStoreSettings>,
+    pub encryption: Option<EncryptionSettings>,
+    pub residency: Option<ResidencySettings>,
+    pub rate_limit: Option<RateLimitSettings>,
+    pub onboarding_limits: Option<OnboardingLimitsSettings>,
+    pub log_sink: Option<LogSinkSettings>,
+    pub query_options: Option<QueryOptionsSettings>,
+    pub sample_rows: Option<SampleRowsSettings>,
+    pub api_keys: Option<ApiKeySettings>,
+    pub local_dev: Option<LocalDevSettings>,
+    pub webhooks: Option<WebhookSettings>,
+    pub history_retention: Option<HistoryRetentionSettings>,
+    pub query_classification: Option<QueryClassificationSettings>,
+    pub query_normalization: Option<QueryNormalizationSettings>,
+    pub retrieval_sweeper: Option<RetrievalSweeperSettings>,
+    pub fan_out: Option<FanOutSettings>,
+    pub migrations: Option<MigrationSettings>,
+    pub scheduler: Option<SchedulerSettings>,
+    pub pseudonymization: Option<PseudonymizationSettings>,
+    pub scim: Option<ScimSettings>,
+    pub notifications: Option<NotificationSettings>,
+    pub answer_offload: Option<AnswerOffloadSettings>,
+    pub artifacts: Option<ArtifactSettings>,
+    pub query_loop: Option<QueryLoopSettings>,
+    pub readiness: Option<ReadinessSettings>,
+    pub backfills: Option<BackfillSettings>,
+    pub access_log: Option<AccessLogSettings>,
+    pub api_docs: Option<ApiDocsSettings>,
+    pub deadlines: Option<DeadlineSettings>,
+    pub job_queue: Option<JobQueueSettings>,
+    pub ingestion_sla: Option<IngestionSlaSettings>,
+    pub ingestion_retry: Option<IngestionRetrySettings>,
+    pub history_polling: Option<HistoryPollingSettings>,
+    pub file_types: Option<FileTypesSettings>,
+    pub schema_preview: Option<SchemaPreviewSettings>,
+    pub deletion: Option<DeletionSettings>,
+    pub overview_feed: Option<OverviewFeedSettings>,
+    pub app_cache: Option<AppCacheSettings>,
+    pub dependencies: Option<DependencyHealthSettings>,
+    pub service_accounts: Option<ServiceAccountSettings>,
+    pub retrieval_estimate: Option<RetrievalEstimateSettings>,
+    pub shadow_traffic: Option<ShadowTrafficSettings>,
+    pub graph_query: Option<GraphQuerySettings>,
+    pub deployment: Option<DeploymentSettings>,
+    pub federation: Option<FederationSettings>,
+    /// Knowledge node types by `knowledge_node_type`, added to or overriding the built-in types.
+    pub knowledge_node_types: Option<HashMap<String, KnowledgeNodeTypeSettings>>,
+
+    /// Files and environment variables the settings were loaded from, set by the loader.
+    #[serde(skip_deserializing)]
+    pub config_sources: ConfigSources,
+}
+
+/// Sources of the loaded settings, later sources overriding the earlier ones.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ConfigSources {
+    /// Directory of the yaml files (`CONFIG_DIR`).
+    pub config_dir: String,
+    /// Global settings (`GLOBAL_YAML`).
+    pub global_yaml: String,
+    /// Local settings overriding the global ones (`LOCAL_YAML`).
+    pub local_yaml: String,
+    /// Optional profile overriding the local settings (`PROFILE_YAML`).
+    pub profile_yaml: Option<String>,
 }
 
 /// Supported data source types.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SupportedDataSourceTypes {
     pub file_store: Vec<String>,
     pub data_store: Vec<String>,
 }
 
 /// Supported file types.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SupportedFileTypes {
     pub image: Vec<String>,
     pub text: Vec<String>,
 }
 
 /// MongoDB specific settings.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct MongoDBSettings {
     pub mongo_db_url: String,
     pub mongo_db_database_name: String,
     pub mongo_db_app_collection: String,
     pub mongo_db_id_collection: String,
     pub mongo_db_ui_summary_collection: String,
+    pub mongo_db_token_usage_collection: String,
+    pub mongo_db_validation_job_collection: String,
+    /// Connection of the heavy admin aggregations, e.g. with `readPreference=secondaryPreferred`.
+    pub mongo_db_analytics_url: Option<String>,
 }
 
 /// Knowledge Engine specific settings.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct KnowledgeEngineSettings {
-    pub endpoint: String,
+    pub endpoint: EndpointPath,
+    /// Endpoint of the node counts of an app, `nodes/counts` if unset.
+    #[serde(default)]
+    pub counts_endpoint: Option<EndpointPath>,
+    /// Endpoint of the graph queries of an app, `graph/query` if unset.
+    #[serde(default)]
+    pub graph_endpoint: Option<EndpointPath>,
 }
 
 /// Tresleai specific URLs.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct TresleaiUrls {
-    pub admin_ui_url: String,
-    pub audit_service_url: String,
-    pub core_service_url: String,
-    pub event_processor_service_url: String,
-    pub facade_service_url: String,
-    pub knowledge_extraction_url: String,
-    pub logging_service_url: String,
-    pub metric_service_url: String,
-    pub product_app_url: String,
+    pub admin_ui_url: ServiceUrl,
+    pub audit_service_url: ServiceUrl,
+    pub core_service_url: ServiceUrl,
+    pub event_processor_service_url: ServiceUrl,
+    pub facade_service_url: ServiceUrl,
+    pub knowledge_extraction_url: ServiceUrl,
+    pub logging_service_url: ServiceUrl,
+    pub metric_service_url: ServiceUrl,
+    pub product_app_url: ServiceUrl,
+    /// Timeout, retries and probe path of the calls to the URLs, by URL name (e.g. `core_service_url`).
+    #[serde(default)]
+    pub policies: HashMap<String, ServiceUrlPolicySettings>,
+}
+
+impl TresleaiUrls {
+    /// Returns the URLs by name.
+    pub fn named(&self) -> [(&'static str, &ServiceUrl); 9] {
+        [
+            ("admin_ui_url", &self.admin_ui_url),
+            ("audit_service_url", &self.audit_service_url),
+            ("core_service_url", &self.core_service_url),
+            (
+                "event_processor_service_url",
+                &self.event_processor_service_url,
+            ),
+            ("facade_service_url", &self.facade_service_url),
+            ("knowledge_extraction_url", &self.knowledge_extraction_url),
+            ("logging_service_url", &self.logging_service_url),
+            ("metric_service_url", &self.metric_service_url),
+            ("product_app_url", &self.product_app_url),
+        ]
+    }
+}
+
+/// Call policy of a Tresleai URL. Unset options fall back to the defaults of `UrlPolicy`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ServiceUrlPolicySettings {
+    /// Timeout of the calls, in milliseconds. The calls are not timed out if unset.
+    pub timeout_ms: Option<u64>,
+    /// Number of retries of the calls failing to connect.
+    pub retries: Option<u32>,
+    /// Path probed at startup and by the selfcheck, the root of the URL if unset.
+    pub probe_path: Option<String>,
 }
 
 /// Tresleai Tracing Layer Levels
-#[derive(Debug, Deserialize, Clone, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct TracingLayerLevels {
     pub fmt_layer_level: String,
     pub fmt_layer_service_exception_level: String,
@@ -110,12 +231,14 @@ pub struct TracingLayerLevels {
 }
 
 /// Application and HTTP server specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ApplicationSettings {
     pub name: String,
     pub address: IpAddr,
     pub port: u16,
     pub cors: Cors,
+    /// Format of the `create_timestamp` of the app documents stored before the timestamps were stored as dates,
+    /// read by the `timestamps_as_dates` migration.
     pub timestamp_format: String,
 }
 
@@ -126,7 +249,7 @@ impl ApplicationSettings {
 }
 
 /// CORS specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Cors {
     pub enabled: bool,
     pub allowed_methods: Vec<String>,
@@ -135,58 +258,82 @@ pub struct Cors {
 }
 
 /// AWS specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct AWSSettings {
+    #[serde(skip_serializing)]
     pub access_key_id: Option<Secret<String>>,
+    #[serde(skip_serializing)]
     pub secret_access_key: Option<Secret<String>>,
-    pub default_region: Option<String>,
+    pub default_region: Option<AwsRegion>,
 }
 
 /// AWS S3 specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct AWSS3Settings {
     pub max_concurrent_requests: usize,
 }
 
 /// AWS IAM specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct AWSIAMSettings {
-    pub region: String,
+    pub region: AwsRegion,
 }
 
 /// AWS API Gateway specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct AWSApiGatewaySettings {
     pub region: String,
+    /// Usage plan of the API keys of the apps without tier.
     pub usage_plan_id: String,
     pub usage_plan_key_type: String,
+    /// Usage plans by tier name, selected by the `tier` of the onboarding request.
+    pub usage_plan_tiers: Option<HashMap<String, String>>,
 }
 
 /// Kafka client specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct KafkaClientSettings {
     pub group_id: String,
     pub onboarding_topic: String,
     pub deletion_topic: String,
+    pub config_change_topic: String,
+    pub ingestion_control_topic: String,
+    pub hint_change_topic: String,
+    /// Topic of the failed sources re-published by the automatic ingestion retries.
+    pub ingestion_retry_topic: String,
     pub kafka_enable_partition_eof: String,
     pub kafka_auto_offset_reset: String,
+    pub app_topics: Option<AppTopicSettings>,
+}
+
+/// Per-app Kafka topic settings. When enabled, the onboarding/update events of each app are published to its own
+/// topic `{topic_prefix}{app_name}` instead of the shared onboarding topic.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppTopicSettings {
+    pub enabled: bool,
+    /// Prefix of the app topics, `app-` by default.
+    pub topic_prefix: Option<String>,
+    /// Number of partitions of an app topic, 1 by default.
+    pub partitions: Option<i32>,
+    /// Replication factor of an app topic, 1 by default.
+    pub replication_factor: Option<i32>,
 }
 
 /// Kubernetes specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct KubernetesSettings {
     pub namespace: String,
     pub secret_name: String,
 }
 
 /// App generated config specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct AppGeneratedConfigSettings {
     pub knowledge_graph_config: KnowledgeGraphConfigSettings,
 }
 
 /// Knowledge Graph specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct KnowledgeGraphConfigSettings {
     pub vectordb_config: VectorDBConfigSettings,
     pub parser_config: ParserConfigSettings,
@@ -196,7 +343,7 @@ pub struct KnowledgeGraphConfigSettings {
 }
 
 /// VectorDB specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct VectorDBConfigSettings {
     pub text_collection_name_prefix: String,
     pub multimodal_collection_name_prefix: String,
@@ -209,13 +356,13 @@ pub struct VectorDBConfigSettings {
 }
 
 /// Parser specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ParserConfigSettings {
     pub s3_storage_prefix: String,
 }
 
 /// Logging specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct LoggingSettings {
     pub collection: String,
     pub retention: String,
@@ -223,7 +370,7 @@ pub struct LoggingSettings {
 }
 
 /// Audit specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct AuditSettings {
     pub collection: String,
     pub retention: String,
@@ -231,15 +378,706 @@ pub struct AuditSettings {
 }
 
 /// Metric specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct MetricSettings {
     pub collection: String,
     pub retention: String,
     pub s3_prefix: String,
 }
 
+/// Mutual TLS specific settings
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TlsSettings {
+    pub server: ServerTlsSettings,
+    pub client: ClientTlsSettings,
+}
+
+/// TLS settings for the axum listener. The certificate material is read either from the PEM file
+/// paths or from an AWS Secrets Manager secret holding `certificate`, `private_key` and `ca_bundle`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerTlsSettings {
+    pub enabled: bool,
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    pub client_ca_path: Option<String>,
+    pub secret_id: Option<String>,
+}
+
+/// TLS settings for outbound calls. The client certificate is only presented to URLs starting
+/// with one of the `mtls_urls` prefixes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientTlsSettings {
+    pub enabled: bool,
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    pub secret_id: Option<String>,
+    pub mtls_urls: Vec<String>,
+}
+
+/// Outbound HTTP client specific settings
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct HttpClientSettings {
+    pub proxy_url: Option<String>,
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+    #[serde(default)]
+    pub ca_bundle_paths: Vec<String>,
+    #[serde(default)]
+    pub hosts: Vec<HostTlsSettings>,
+}
+
+/// Per-host TLS settings for outbound calls
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct HostTlsSettings {
+    pub host: String,
+    pub ca_bundle_path: Option<String>,
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    #[serde(default)]
+    pub client_cert: bool,
+}
+
+/// Response compression and request decompression settings
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompressionSettings {
+    pub enabled: bool,
+    pub gzip: bool,
+    pub br: bool,
+    pub min_size_bytes: u16,
+    /// Maximum size of a request body once decompressed, 2 MiB by default.
+    pub max_request_body_bytes: Option<usize>,
+}
+
+/// Typed metrics settings
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetricsSettings {
+    pub records_collection: Option<String>,
+    pub legacy_string_events: bool,
+    pub cloudwatch_emf: Option<CloudWatchEmfSettings>,
+    pub prometheus: Option<PrometheusSettings>,
+}
+
+/// CloudWatch Embedded Metric Format output of the typed metrics. The records are written to stdout, picked up by
+/// the log agent of the cluster, or sent to the EMF endpoint of the CloudWatch agent if `agent_address` is set.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CloudWatchEmfSettings {
+    pub namespace: String,
+    /// Log group of the records, used by the CloudWatch agent.
+    pub log_group: Option<String>,
+    /// UDP address of the EMF endpoint of the CloudWatch agent, e.g. `127.0.0.1:25888`.
+    pub agent_address: Option<String>,
+}
+
+/// Prometheus export of the duration metrics, served at `/metrics`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PrometheusSettings {
+    /// Upper bounds of the histogram buckets, in milliseconds.
+    pub buckets_ms: Option<Vec<f64>>,
+}
+
+/// Vector store settings. The backend applies to the whole deployment.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VectorStoreSettings {
+    pub backend: VectorBackend,
+}
+
+/// Envelope encryption settings of the sensitive fields stored in DocumentDB
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EncryptionSettings {
+    pub enabled: bool,
+    pub kms_key_id: String,
+    pub region: String,
+    pub data_keys_collection: Option<String>,
+    /// Number of seconds the active key version of an app is cached, 60 by default.
+    pub latest_version_cache_seconds: Option<u64>,
+}
+
+/// Data residency settings. Each cluster holds the app specific collections of the apps with its residency.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResidencySettings {
+    pub clusters: Vec<ResidencyClusterSettings>,
+}
+
+/// A named DocumentDB cluster, e.g. `eu-west-1`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResidencyClusterSettings {
+    pub name: String,
+    pub mongo_db_url: String,
+    pub mongo_db_database_name: String,
+    pub analytics_url: Option<String>,
+}
+
+/// API key settings. Unset options fall back to the defaults of `ApiKeyOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKeySettings {
+    pub mode: Option<ApiKeyMode>,
+    pub usage_collection: Option<String>,
+    /// Interval of the job deactivating the expired keys.
+    pub expiry_interval_seconds: Option<u64>,
+    /// How long before its expiry date the expiry of a key is warned.
+    pub expiry_warning_days: Option<i64>,
+}
+
+/// Issuer of the API keys of the apps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyMode {
+    /// Keys created in AWS API Gateway, which validates them and meters their usage through usage plans.
+    #[default]
+    ApiGateway,
+    /// Keys generated, hashed and validated by the facade, without API Gateway.
+    Internal,
+}
+
+/// Health probes of the downstream Tresleai services. Unset options fall back to the defaults of
+/// `DependencyHealthOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DependencyHealthSettings {
+    /// Health path of the services, `health` by default.
+    pub health_path: Option<String>,
+    /// Health paths by URL name (e.g. `core_service_url`), overriding `health_path`.
+    pub health_paths: Option<HashMap<String, String>>,
+    /// Timeout of a probe, in milliseconds.
+    pub timeout_ms: Option<u64>,
+    /// Latency from which a service answering is degraded, in milliseconds.
+    pub slow_threshold_ms: Option<u64>,
+}
+
+/// In-memory cache of the API key lookups of the retrievals. Unset options fall back to the defaults of
+/// `AppCacheOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppCacheSettings {
+    pub enabled: Option<bool>,
+    /// Lifetime of a cached lookup, in seconds, bounding the staleness if an invalidation is missed.
+    pub ttl_seconds: Option<u64>,
+    /// Maximum number of cached API keys, the cache is emptied when it is full.
+    pub max_entries: Option<usize>,
+    pub invalidation: Option<AppCacheInvalidation>,
+    /// Collection of the version document bumped on every change of the API keys of the apps.
+    pub version_collection: Option<String>,
+    /// Interval between two reads of the version document, in milliseconds.
+    pub version_poll_interval_ms: Option<u64>,
+}
+
+/// Invalidation of the cached API key lookups across the replicas.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AppCacheInvalidation {
+    /// A change stream on the app collection, falling back to the version document if it can't be opened.
+    #[default]
+    ChangeStream,
+    /// The version document only, for clusters without change streams.
+    Version,
+}
+
+/// Local development mode settings, swapping the AWS and Kafka integrations for in-process fakes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LocalDevSettings {
+    pub enabled: bool,
+    /// Directory of the local buckets, a folder per bucket.
+    pub object_store_dir: Option<String>,
+    /// Number of published events kept in memory.
+    pub max_recorded_events: Option<usize>,
+}
+
+/// Onboarding webhook settings. Unset options fall back to the defaults of `WebhookOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookSettings {
+    /// Secret of the HMAC-SHA256 signature of the payloads. The notifications are not delivered without it.
+    #[serde(skip_serializing)]
+    pub signing_secret: Option<Secret<String>>,
+    pub max_attempts: Option<u32>,
+    /// Delay before the first retry, doubled for each further retry.
+    pub retry_backoff_ms: Option<u64>,
+    pub timeout_seconds: Option<u64>,
+    pub delivery_collection: Option<String>,
+}
+
+/// History retention settings. Unset options fall back to the defaults of `HistoryRetentionOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryRetentionSettings {
+    /// Retention of the history documents of the apps without override. The documents are kept if not set.
+    pub default_retention_days: Option<u32>,
+    pub interval_seconds: Option<u64>,
+    pub hold_audit_collection: Option<String>,
+}
+
+/// Dead retrieval sweeper settings. Unset options fall back to the defaults of `RetrievalSweeperOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RetrievalSweeperSettings {
+    /// Time given to a retrieval to store its history document before it is expired.
+    pub deadline_seconds: Option<u64>,
+    pub interval_seconds: Option<u64>,
+    /// How far past the deadline the retrievals are looked up.
+    pub lookback_seconds: Option<u64>,
+    pub batch_size: Option<i64>,
+}
+
+/// Startup migration settings. Unset options fall back to the defaults of `MigrationOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MigrationSettings {
+    /// Set to false to skip the migrations on startup, e.g. when they run from a release job instead.
+    pub enabled: Option<bool>,
+    /// Collection of the applied migrations and of the migration lock.
+    pub collection: Option<String>,
+    /// Time after which the lock of a crashed instance is taken over.
+    pub lock_ttl_seconds: Option<u64>,
+}
+
+/// Background job scheduler settings. Unset options fall back to the defaults of `SchedulerOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SchedulerSettings {
+    pub lease_collection: Option<String>,
+    pub run_collection: Option<String>,
+    /// Time a lease outlives the interval of its job before another replica takes it over.
+    pub lease_grace_seconds: Option<u64>,
+    pub run_retention_days: Option<i64>,
+}
+
+/// User ID pseudonymization settings. Unset options fall back to the defaults of `PseudonymizationOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PseudonymizationSettings {
+    pub key_collection: Option<String>,
+    pub mapping_collection: Option<String>,
+}
+
+/// Service account settings. Unset options fall back to the defaults of `ServiceAccountOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceAccountSettings {
+    /// Collection of the service accounts.
+    pub collection: Option<String>,
+    /// Bearer token allowed to create service accounts of any scope, e.g. the first `admin` account.
+    #[serde(skip_serializing)]
+    pub bootstrap_token: Option<Secret<String>>,
+}
+
+/// Cost estimation of the retrievals. Unset options fall back to the defaults of `RetrievalEstimateOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RetrievalEstimateSettings {
+    /// Prices of the models per 1000 tokens, by model ID. The estimates of a model without price have no cost.
+    pub model_prices: Option<HashMap<String, ModelPrice>>,
+    /// Currency of the prices, `USD` by default.
+    pub currency: Option<String>,
+    /// Number of the latest answered retrievals of the app sampled.
+    pub history_sample_size: Option<i64>,
+    /// Minimum number of samples an estimate is based on.
+    pub min_samples: Option<usize>,
+    /// Tokens of the retrieved context added to the query without enough samples.
+    pub context_tokens: Option<i64>,
+    /// Completion tokens without enough samples.
+    pub completion_tokens: Option<i64>,
+    /// Engine call duration up to which a retrieval is fast, in milliseconds.
+    pub fast_latency_ms: Option<u64>,
+    /// Engine call duration from which a retrieval is slow, in milliseconds.
+    pub slow_latency_ms: Option<u64>,
+}
+
+/// Shadow traffic settings. Unset options fall back to the defaults of `ShadowTrafficOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShadowTrafficSettings {
+    /// Timeout of a call to an alternate knowledge engine, in milliseconds.
+    pub timeout_ms: Option<u64>,
+}
+
+/// Labels of the deployment of the facade, added to the metrics and log events.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeploymentSettings {
+    /// Name of the deployment, e.g. `prod-us-east`.
+    pub name: Option<String>,
+    /// Environment of the deployment, e.g. `prod`.
+    pub environment: Option<String>,
+    /// Region of the deployment, `aws.default_region` if unset.
+    pub region: Option<String>,
+}
+
+/// Peer deployments of the facade aggregated by the federated overview. Unset options fall back to the defaults of
+/// `FederationOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FederationSettings {
+    pub peers: Option<Vec<FederationPeerSettings>>,
+    /// Timeout of a call to a peer, in milliseconds.
+    pub timeout_ms: Option<u64>,
+}
+
+/// Peer deployment of the facade, called with the credentials of one of its service accounts granted `metrics:read`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FederationPeerSettings {
+    pub name: String,
+    pub url: ServiceUrl,
+    pub client_id: Option<String>,
+    #[serde(skip_serializing)]
+    pub client_secret: Option<Secret<String>>,
+}
+
+/// Knowledge graph query settings. Unset options fall back to the defaults of `GraphQueryOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GraphQuerySettings {
+    /// Maximum number of hops of a graph query.
+    pub max_depth: Option<u32>,
+    /// Maximum number of nodes returned by a graph query.
+    pub max_results: Option<usize>,
+}
+
+/// SCIM entitlement settings. Unset options fall back to the defaults of `ScimOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScimSettings {
+    pub collection: Option<String>,
+    /// Prefix of the names of the IdP groups granting access to an app.
+    pub group_prefix: Option<String>,
+    /// Bearer token the IdP authenticates with. The SCIM endpoints are disabled without it.
+    #[serde(skip_serializing)]
+    pub bearer_token: Option<Secret<String>>,
+}
+
+/// Admin notification settings. Unset options fall back to the defaults of `NotificationOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NotificationSettings {
+    pub collection: Option<String>,
+    /// Number of dead retrievals of an app in a sweep notified as an error spike.
+    pub error_spike_threshold: Option<usize>,
+}
+
+/// Answer offload settings. Unset options fall back to the defaults of `AnswerOffloadOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AnswerOffloadSettings {
+    /// Size of a history document above which its response is offloaded, in bytes.
+    pub max_answer_bytes: Option<usize>,
+    /// Size the texts of an offloaded history document are truncated to, in bytes.
+    pub truncated_bytes: Option<usize>,
+    /// Bucket of the full responses.
+    pub bucket: Option<String>,
+    /// Prefix of the artifacts of the apps in the bucket.
+    pub prefix: Option<String>,
+}
+
+/// Artifact settings. Unset options fall back to the defaults of `ArtifactOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArtifactSettings {
+    /// Validity of the download links of the artifacts, in seconds.
+    pub download_url_ttl_seconds: Option<u64>,
+}
+
+/// Query loop circuit settings. Unset options fall back to the defaults of `QueryLoopOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryLoopSettings {
+    pub enabled: Option<bool>,
+    /// Length of the windows of the identical retrievals, in seconds.
+    pub window_seconds: Option<u64>,
+    /// Number of identical retrievals executed in a window, the next ones are short-circuited.
+    pub max_repeats: Option<u32>,
+    pub collection: Option<String>,
+}
+
+/// App readiness settings. Unset options fall back to the defaults of `ReadinessOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadinessSettings {
+    pub mode: Option<ReadinessMode>,
+    /// Percentage of the sources of an app to index before the app is ready.
+    pub threshold_percent: Option<u8>,
+    /// Kafka topic of the ingestion status events, consumed with the `kafka_client.group_id`. Not consumed if unset.
+    pub status_topic: Option<String>,
+    pub collection: Option<String>,
+}
+
+/// Persistent job queue settings. Unset options fall back to the defaults of `JobQueueOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobQueueSettings {
+    pub collection: Option<String>,
+    /// Milliseconds between two polls of an idle queue.
+    pub poll_interval_ms: Option<u64>,
+    /// Jobs run at once by a replica, per queue.
+    pub concurrency: Option<usize>,
+    /// Seconds between two heartbeats of a running job.
+    pub heartbeat_seconds: Option<u64>,
+    /// Seconds without heartbeat after which a running job is claimed again, e.g. after a crash.
+    pub claim_timeout_seconds: Option<u64>,
+    /// Attempts of a job before it is dead-lettered.
+    pub max_attempts: Option<u32>,
+    /// Delay before the first retry of a failed job, doubled on every further retry.
+    pub backoff_base_seconds: Option<u64>,
+    pub backoff_max_seconds: Option<u64>,
+    /// Days the succeeded jobs are kept.
+    pub retention_days: Option<i64>,
+}
+
+/// Ingestion SLA settings. Unset options fall back to the defaults of `IngestionSlaOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IngestionSlaSettings {
+    /// Minutes from the publish of a datasource to the first node of each of its new sources.
+    pub target_minutes: Option<u64>,
+    pub collection: Option<String>,
+    /// Most recent publishes of an app returned by the ingestion SLA endpoint.
+    pub max_requests: Option<usize>,
+}
+
+/// Automatic ingestion retry settings. Unset options fall back to the defaults of `IngestionRetryOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IngestionRetrySettings {
+    pub interval_seconds: Option<u64>,
+    pub collection: Option<String>,
+    /// Sources re-published per app and run.
+    pub batch_size: Option<usize>,
+    /// How far back the ingestion errors are looked up.
+    pub lookback_hours: Option<i64>,
+}
+
+/// History polling settings. Unset options fall back to the defaults of `HistoryPollingOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryPollingSettings {
+    /// `Retry-After` of the history requests of the retrievals in progress, in seconds.
+    pub retry_after_seconds: Option<u64>,
+}
+
+/// File type registry settings, on top of `supported_file_types`. Unset options fall back to the defaults of
+/// `FileTypes`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileTypesSettings {
+    /// Handling of the URLs of unsupported file types, `error` if unset.
+    pub mode: Option<FileTypeMode>,
+    /// Number of extensionless objects per URL whose content is sniffed, 0 disabling the sniffing.
+    pub sniff_sample_size: Option<usize>,
+    /// Maximum size in MB of the files by extension, e.g. `pdf: 200`. Unlimited if unset.
+    pub max_size_mb: Option<HashMap<String, u64>>,
+}
+
+/// Two-phase deletion settings of the large apps. Unset options fall back to the defaults of `DeletionOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeletionSettings {
+    /// Number of knowledge nodes from which the deletion of an app needs a confirmation token, 0 for every app.
+    pub confirmation_threshold_nodes: Option<u64>,
+    /// Validity of the confirmation tokens, in minutes.
+    pub token_ttl_minutes: Option<u64>,
+    /// Collection of the pending deletions.
+    pub collection: Option<String>,
+}
+
+/// Live update settings of the admin dashboard. Unset options fall back to the defaults of `OverviewFeedOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OverviewFeedSettings {
+    /// Whether the change-stream watcher runs. The change streams have to be enabled on the app collections.
+    pub enabled: Option<bool>,
+    /// Number of milliseconds between two broadcasts of the accumulated deltas.
+    pub flush_interval_ms: Option<u64>,
+    /// Number of updates buffered for the websocket clients before a slow client has to refetch the overview.
+    pub channel_capacity: Option<usize>,
+}
+
+/// Request deadline settings. The requests have no deadline without timeout.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeadlineSettings {
+    /// Timeout of the requests of the routes without their own, in milliseconds.
+    pub default_timeout_ms: Option<u64>,
+    /// Timeouts by matched route, e.g. `/api/v1.0/retrieval`, in milliseconds.
+    pub route_timeouts_ms: Option<HashMap<String, u64>>,
+}
+
+/// API docs settings. Unset options fall back to the defaults of `ApiDocsOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiDocsSettings {
+    pub mode: Option<ApiDocsMode>,
+    pub basic_auth_username: Option<String>,
+    #[serde(skip_serializing)]
+    pub basic_auth_password: Option<Secret<String>>,
+    /// Secret of the HS256 JWTs accepted as bearer tokens.
+    #[serde(skip_serializing)]
+    pub jwt_secret: Option<Secret<String>>,
+}
+
+/// Serving of the Swagger UI and of the OpenAPI document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiDocsMode {
+    /// Served without credentials.
+    Public,
+    /// Served to the requests with basic credentials or a JWT.
+    Protected,
+    /// Not served.
+    Disabled,
+}
+
+/// Knowledge node type served by the admin node endpoints.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KnowledgeNodeTypeSettings {
+    /// `_node_label` of the stored nodes of the type.
+    pub node_label: String,
+    /// Fields of the nodes listed by default, `indexed_at` and `source` if unset.
+    pub projection: Option<Vec<String>>,
+}
+
+/// Access log settings. Unset options fall back to the defaults of `AccessLogOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccessLogSettings {
+    pub enabled: Option<bool>,
+    /// Share of the requests of a route group which are logged, between 0 and 1, e.g. `retrieval: 0.1`.
+    pub sample_rates: Option<HashMap<AccessLogGroup, f64>>,
+}
+
+/// Route group of the access log, sampled at its own rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogGroup {
+    /// The retrievals.
+    Retrieval,
+    /// The history polling of the retrievals.
+    History,
+    /// The admin reads (GET, HEAD and OPTIONS).
+    AdminRead,
+    /// The other admin requests, always logged.
+    AdminMutation,
+    /// The other routes, e.g. the Swagger UI.
+    Other,
+}
+
+impl AccessLogGroup {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccessLogGroup::Retrieval => "retrieval",
+            AccessLogGroup::History => "history",
+            AccessLogGroup::AdminRead => "admin_read",
+            AccessLogGroup::AdminMutation => "admin_mutation",
+            AccessLogGroup::Other => "other",
+        }
+    }
+}
+
+/// Backfill settings of the admin endpoints. Unset options fall back to the defaults of `BackfillOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackfillSettings {
+    /// The backfills can not be run unless enabled.
+    pub enabled: Option<bool>,
+    pub collection: Option<String>,
+    /// Seconds after which a job not updated no longer blocks its backfill.
+    pub stale_after_seconds: Option<u64>,
+}
+
+/// Handling of the retrievals of the apps which are not ready.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadinessMode {
+    /// The retrievals are not gated.
+    #[default]
+    Off,
+    /// The retrievals are executed, with a warning in their response.
+    Warn,
+    /// The retrievals are rejected.
+    Reject,
+}
+
+/// Fan-out retrieval settings. Unset options fall back to the defaults of `FanOutOptions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FanOutSettings {
+    pub max_sub_queries: Option<usize>,
+    /// Number of sub-queries sent to the knowledge engine at a time.
+    pub max_concurrency: Option<usize>,
+}
+
+/// Query classification settings of the retrievals.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryClassificationSettings {
+    #[serde(default)]
+    pub mode: ClassifierMode,
+    /// Keyword rules replacing the built-in ones.
+    pub rules: Option<Vec<ClassificationRule>>,
+    /// Endpoint of the classification model, called in the `model` mode.
+    pub model_url: Option<String>,
+    pub model_timeout_ms: Option<u64>,
+}
+
+/// Query normalization settings. The normalization itself is enabled per app at onboarding.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryNormalizationSettings {
+    /// Spell-check service correcting the normalized queries. The queries are not corrected if not set.
+    pub spell_check_url: Option<String>,
+    pub spell_check_timeout_ms: Option<u64>,
+}
+
+/// Per-user rate limit settings. The limits themselves are configured per app at onboarding.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RateLimitSettings {
+    pub store: RateLimitStoreKind,
+    pub collection: Option<String>,
+}
+
+/// Store of the per-user rate limit counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitStoreKind {
+    /// Counters kept in the process, per replica.
+    Memory,
+    /// Counters shared by the replicas in a DocumentDB collection.
+    DocumentDb,
+}
+
+/// Guardrails of the datasources of an onboarding request, enforced during the connectivity validation.
+/// Unset limits are not enforced. Admins can override them with the `override_limits` query parameter.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct OnboardingLimitsSettings {
+    /// Maximum number of files matched by the filestore URLs of an app.
+    pub max_objects: Option<u64>,
+    /// Maximum total size in bytes of the files matched by the filestore URLs of an app.
+    pub max_total_bytes: Option<u64>,
+    /// Maximum number of tables of a datastore.
+    pub max_tables: Option<usize>,
+    /// Maximum number of columns of a table.
+    pub max_columns: Option<usize>,
+}
+
+/// OpenSearch/Elasticsearch log sink settings. The per-app log documents are mirrored into daily indices
+/// `{index_prefix}-{app_name}-{yyyy.MM.dd}`, matched by the index templates and lifecycle policies of the cluster.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogSinkSettings {
+    /// Base URL of the cluster, e.g. `https://search-logs.us-west-2.es.amazonaws.com`.
+    pub url: String,
+    pub index_prefix: Option<String>,
+    pub username: Option<String>,
+    #[serde(skip_serializing)]
+    pub password: Option<Secret<String>>,
+    /// Seconds between two shipping rounds.
+    pub interval_seconds: Option<u64>,
+    /// Maximum number of log documents per bulk request.
+    pub batch_size: Option<i64>,
+    /// Collection of the shipping cursor.
+    pub cursor_collection: Option<String>,
+}
+
+/// Time budget of the DocumentDB aggregations and limits of the paginated endpoints. Unset options fall back to the
+/// defaults of `QueryOptions`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct QueryOptionsSettings {
+    /// Time budget of an aggregation in milliseconds.
+    pub max_time_ms: Option<u64>,
+    /// Maximum `limit` of a page.
+    pub max_page_limit: Option<usize>,
+    /// Maximum number of documents skipped to reach a page.
+    pub max_page_offset: Option<i64>,
+    /// Whether the aggregations may write temporary files on the cluster.
+    pub allow_disk_use: Option<bool>,
+}
+
+/// Sample row capture settings of the datastore tables declaring `sample_rows`. Unset options fall back to the
+/// defaults of `SampleRowsOptions`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SampleRowsSettings {
+    /// Number of rows of each of the top, random and bottom samples.
+    pub row_count: Option<usize>,
+    /// Masking of the values of the PII columns and of the columns of a masked sensitivity.
+    pub masking: Option<MaskingRule>,
+    /// Sensitivities whose columns are masked, in addition to the PII columns.
+    pub masked_sensitivities: Option<Vec<Sensitivity>>,
+}
+
+/// Schema preview settings of the tabular files of the filestore datasources. Unset options fall back to the defaults
+/// of `SchemaPreviewOptions`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SchemaPreviewSettings {
+    /// Whether the CSV and Parquet files are previewed during the connectivity checks, `true` if unset.
+    pub enabled: Option<bool>,
+    /// Number of bytes read from the start of a CSV file or from the end of a Parquet file.
+    pub sample_bytes: Option<u64>,
+}
+
 /// RDS specific settings
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct DatastoreSettings {
     pub connection_timeout_seconds: String,
     pub max_concurrent_requests: usize,
@@ -0,0 +1,240 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the typed values of the settings, validated when the settings are loaded so that a malformed
+//! value fails the startup instead of the first request using it:
+//! - `ServiceUrl`, an absolute `http`/`https` URL with a host and without query or fragment, e.g. the `tresleai_urls`.
+//! - `EndpointPath`, a relative path appended to a service URL, e.g. the `knowledge_engine` endpoints.
+//! - `AwsRegion`, an AWS region code such as `us-west-2`, e.g. the regions of the `aws` settings.
+//!
+//! The values are (de)serialized as plain strings and dereference to `str`.
+//!
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Deref;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidSetting {
+    #[error("Invalid URL '{value}': {reason}")]
+    Url { value: String, reason: String },
+    #[error("Invalid endpoint path '{value}': {reason}")]
+    EndpointPath { value: String, reason: String },
+    #[error("Invalid AWS region '{0}', expected a region code such as 'us-west-2'.")]
+    AwsRegion(String),
+}
+
+/// Absolute `http`/`https` URL of a service, stored without trailing slash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ServiceUrl(String);
+
+impl ServiceUrl {
+    /// Returns the URL of a path of the service.
+    pub fn join(&self, path: &str) -> String {
+        match path.trim_start_matches('/') {
+            "" => self.0.clone(),
+            path => format!("{}/{}", self.0, path),
+        }
+    }
+}
+
+impl TryFrom<String> for ServiceUrl {
+    type Error = InvalidSetting;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = |reason: &str| InvalidSetting::Url {
+            value: value.clone(),
+            reason: reason.to_string(),
+        };
+        let url = url::Url::parse(value.trim()).map_err(|e| invalid(&e.to_string()))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(invalid("the scheme must be http or https"));
+        }
+        if url.host_str().unwrap_or_default().is_empty() {
+            return Err(invalid("the host is missing"));
+        }
+        if url.query().is_some() || url.fragment().is_some() {
+            return Err(invalid("a query or fragment is not allowed"));
+        }
+        Ok(ServiceUrl(value.trim().trim_end_matches('/').to_string()))
+    }
+}
+
+/// Relative path of an endpoint of a service, stored without leading slash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct EndpointPath(String);
+
+impl TryFrom<String> for EndpointPath {
+    type Error = InvalidSetting;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = |reason: &str| InvalidSetting::EndpointPath {
+            value: value.clone(),
+            reason: reason.to_string(),
+        };
+        let path = value.trim().trim_start_matches('/');
+        if path.is_empty() {
+            return Err(invalid("the path is empty"));
+        }
+        if path.contains("://") {
+            return Err(invalid("expected a path relative to the service URL"));
+        }
+        if path.chars().any(char::is_whitespace) {
+            return Err(invalid("whitespaces are not allowed"));
+        }
+        url::Url::parse("http://localhost/")
+            .and_then(|base| base.join(path))
+            .map_err(|e| invalid(&e.to_string()))?;
+        Ok(EndpointPath(path.to_string()))
+    }
+}
+
+/// AWS region code, e.g. `us-west-2` or `us-gov-east-1`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct AwsRegion(String);
+
+impl TryFrom<String> for AwsRegion {
+    type Error = InvalidSetting;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let parts: Vec<&str> = value.split('-').collect();
+        let valid = parts.len() >= 3
+            && parts[0].len() == 2
+            && parts[..parts.len() - 1]
+                .iter()
+                .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_lowercase()))
+            && parts[parts.len() - 1].chars().all(|c| c.is_ascii_digit())
+            && !parts[parts.len() - 1].is_empty();
+        if !valid {
+            return Err(InvalidSetting::AwsRegion(value));
+        }
+        Ok(AwsRegion(value))
+    }
+}
+
+impl From<ServiceUrl> for String {
+    fn from(value: ServiceUrl) -> Self {
+        value.0
+    }
+}
+
+impl Deref for ServiceUrl {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ServiceUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<EndpointPath> for String {
+    fn from(value: EndpointPath) -> Self {
+        value.0
+    }
+}
+
+impl Deref for EndpointPath {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for EndpointPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<AwsRegion> for String {
+    fn from(value: AwsRegion) -> Self {
+        value.0
+    }
+}
+
+impl Deref for AwsRegion {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for AwsRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_typed_settings() {
+        let url =
+            ServiceUrl::try_from("http://tresleai-core-service.tresleai/".to_string()).unwrap();
+        assert_eq!(&*url, "http://tresleai-core-service.tresleai");
+        assert_eq!(
+            url.join("/query/full"),
+            "http://tresleai-core-service.tresleai/query/full"
+        );
+        let endpoint = EndpointPath::try_from("/query/full".to_string()).unwrap();
+        assert_eq!(endpoint.to_string(), "query/full");
+        for region in ["us-west-2", "us-gov-east-1", "ap-southeast-2"] {
+            assert!(AwsRegion::try_from(region.to_string()).is_ok());
+        }
+
+        // Deserialized from the yaml strings
+        let url: ServiceUrl = serde_json::from_str(r#""https://admin-ui.dev.tresle.ai""#).unwrap();
+        assert_eq!(
+            serde_json::to_string(&url).unwrap(),
+            r#""https://admin-ui.dev.tresle.ai""#
+        );
+    }
+
+    #[test]
+    fn test_failure_typed_settings() {
+        for url in [
+            "tresleai-core-service.tresleai",
+            "ftp://core",
+            "http://",
+            "http://core?debug=true",
+            "",
+        ] {
+            assert!(ServiceUrl::try_from(url.to_string()).is_err(), "{}", url);
+        }
+        for endpoint in ["", "http://core/query", "query /full"] {
+            assert!(
+                EndpointPath::try_from(endpoint.to_string()).is_err(),
+                "{}",
+                endpoint
+            );
+        }
+        for region in [
+            "us_west_2",
+            "US-WEST-2",
+            "uswest2",
+            "us-west-",
+            "useast-west-2",
+        ] {
+            assert!(
+                AwsRegion::try_from(region.to_string()).is_err(),
+                "{}",
+                region
+            );
+        }
+        assert!(serde_json::from_str::<ServiceUrl>(r#""core:8003""#).is_err());
+    }
+}
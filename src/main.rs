@@ -22,35 +22,81 @@ mod retrieval;
 mod service;
 
 use utoipa::OpenApi;
-use utoipa_swagger_ui::SwaggerUi;
 
+use crate::admin_ui_api::app_access_list_handler::*;
+use crate::admin_ui_api::app_answer_sinks_handler::*;
+use crate::admin_ui_api::app_api_key_usage_handler::*;
+use crate::admin_ui_api::app_artifacts_handler::*;
 use crate::admin_ui_api::app_delete_handler::*;
+use crate::admin_ui_api::app_encryption_key_handler::*;
+use crate::admin_ui_api::app_experiments_handler::*;
+use crate::admin_ui_api::app_generated_config_handler::*;
 use crate::admin_ui_api::app_get_handler::*;
 use crate::admin_ui_api::app_get_logs_handler::*;
+use crate::admin_ui_api::app_hints_handler::*;
+use crate::admin_ui_api::app_history_retention_handler::*;
+use crate::admin_ui_api::app_ingestion_control_handler::*;
+use crate::admin_ui_api::app_ingestion_retry_handler::*;
+use crate::admin_ui_api::app_ingestion_sla_handler::*;
+use crate::admin_ui_api::app_keys_handler::*;
+use crate::admin_ui_api::app_knowledge_node_detail_handler::*;
 use crate::admin_ui_api::app_knowledge_nodes_and_errors_count::*;
 use crate::admin_ui_api::app_knowledge_nodes_chart_handler::*;
 use crate::admin_ui_api::app_knowledge_nodes_errors_handler::*;
 use crate::admin_ui_api::app_knowledge_nodes_handler::*;
+use crate::admin_ui_api::app_knowledge_nodes_stats_handler::*;
 use crate::admin_ui_api::app_list_handler::*;
+use crate::admin_ui_api::app_prompt_templates_handler::*;
+use crate::admin_ui_api::app_residency_handler::*;
+use crate::admin_ui_api::app_retry_onboarding_handler::*;
 use crate::admin_ui_api::app_search_enabled_handler::*;
+use crate::admin_ui_api::app_shadow_traffic_handler::*;
+use crate::admin_ui_api::app_try_query_handler::*;
+use crate::admin_ui_api::app_user_pseudonyms_handler::*;
+use crate::admin_ui_api::app_verify_counts_handler::*;
 use crate::admin_ui_api::apps_and_calls_overview_handler::*;
+use crate::admin_ui_api::backfills_handler::*;
 use crate::admin_ui_api::capture_tc_handler::*;
+use crate::admin_ui_api::config_handler::*;
+use crate::admin_ui_api::dependencies_handler::*;
+use crate::admin_ui_api::error_catalog_handler::*;
+use crate::admin_ui_api::graph_query_handler::*;
+use crate::admin_ui_api::job_runs_handler::*;
 use crate::admin_ui_api::kub_generate_token_handler::*;
 use crate::admin_ui_api::metric_calls_handler::*;
 use crate::admin_ui_api::metric_error_handler::*;
+use crate::admin_ui_api::notifications_handler::*;
+use crate::admin_ui_api::onboarding_complexity_handler::*;
+use crate::admin_ui_api::overview_feed_handler::*;
+use crate::admin_ui_api::queued_jobs_handler::*;
+use crate::admin_ui_api::scim_handler::*;
+use crate::admin_ui_api::selfcheck_handler::*;
+use crate::admin_ui_api::service_accounts_handler::*;
+use crate::admin_ui_api::token_usage_handler::*;
+use crate::admin_ui_api::trace_replay_handler::*;
+use crate::onboarding::apply::*;
 use crate::onboarding::handler::*;
+use crate::onboarding::validation_job::*;
+use crate::retrieval::estimate_handler::*;
 use crate::retrieval::handler::*;
 use crate::retrieval::history_handler::*;
 
+use crate::service::access_log::{AccessLogOptions, ACCESS_LOG_TARGET};
+use crate::service::api_docs::api_docs_router;
+use crate::service::app_cache::AppCacheOptions;
+use crate::service::migration::MigrationOptions;
+use crate::service::overview_feed::OverviewFeedOptions;
+use crate::service::readiness::ReadinessOptions;
 use crate::service::state::AppState;
 use axum::http::{HeaderName, HeaderValue, Method};
 use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
 use dotenv::dotenv;
 use logging_utils::layer::TresleaiLoggingLayer;
 use logging_utils::worker::TresleaiBackgroundWorker;
 use mongodb_utils::mongodb_client::DBTrait;
 use mongodb_utils::mongodb_client::DB;
-use service::route::create_router;
+use service::route::{apply_compression, apply_request_metrics, create_router};
 use std::sync::Arc;
 use tower_http::cors::CorsLayer;
 use tracing::{debug, instrument};
@@ -62,21 +108,104 @@ use tracing_subscriber::{fmt, layer::*, EnvFilter};
 #[openapi(
     paths(
         post_app_onboarding_handler,
+        post_app_apply_handler,
+        get_validation_job_handler,
         post_retrieval_handler,
+        post_retrieval_estimate_handler,
         get_history_handler,
         delete_app,
         get_app,
+        get_generated_config_handler,
+        patch_generated_config_handler,
+        post_rotate_encryption_key_handler,
+        post_app_residency_handler,
+        get_access_list_handler,
+        get_api_key_usage_handler,
+        get_app_artifacts_handler,
+        delete_app_artifact_handler,
+        put_access_list_handler,
+        get_hints_handler,
+        post_hint_handler,
+        put_hint_handler,
+        delete_hint_handler,
+        get_history_retention_handler,
+        put_history_retention_handler,
+        post_legal_hold_handler,
+        delete_legal_hold_handler,
+        get_prompt_templates_handler,
+        put_prompt_template_handler,
+        delete_prompt_template_handler,
+        get_answer_sinks_handler,
+        put_answer_sink_handler,
+        delete_answer_sink_handler,
+        get_experiments_handler,
+        put_experiment_handler,
+        delete_experiment_handler,
+        get_experiment_results_handler,
+        get_shadow_traffic_handler,
+        put_shadow_traffic_handler,
+        delete_shadow_traffic_handler,
+        get_shadow_traffic_report_handler,
+        get_app_keys_handler,
+        create_app_key_handler,
+        delete_app_key_handler,
+        put_app_key_expiry_handler,
+        post_pause_ingestion_handler,
+        post_resume_ingestion_handler,
+        get_ingestion_retry_policy_handler,
+        put_ingestion_retry_policy_handler,
+        get_ingestion_sla_handler,
+        post_retry_onboarding_handler,
+        post_try_query_handler,
+        get_user_pseudonym_handler,
+        post_verify_counts_handler,
+        post_graph_query_handler,
         get_kubernetes_token,
+        get_job_runs_handler,
+        get_queued_jobs_handler,
+        get_backfills_handler,
+        post_backfill_handler,
+        get_backfill_job_handler,
+        get_scim_users_handler,
+        post_scim_user_handler,
+        get_scim_user_handler,
+        put_scim_user_handler,
+        patch_scim_user_handler,
+        delete_scim_user_handler,
+        get_scim_groups_handler,
+        post_scim_group_handler,
+        get_scim_group_handler,
+        put_scim_group_handler,
+        patch_scim_group_handler,
+        delete_scim_group_handler,
+        get_config_handler,
+        get_error_catalog_handler,
+        get_selfcheck_handler,
+        get_dependencies_handler,
+        get_service_accounts_handler,
+        post_service_account_handler,
+        delete_service_account_handler,
+        post_service_account_secret_handler,
+        get_notifications_handler,
+        post_notification_read_handler,
+        post_notifications_read_handler,
         get_app_list,
         get_metric_calls,
         get_metric_errors,
         get_logs,
         get_apps_and_calls_overview_handler,
+        get_overview_feed_handler,
         update_search_enabled_handler,
         get_knowledge_nodes_handler,
+        get_knowledge_node_detail_handler,
         get_knowledge_nodes_chart_handler,
         get_knowledge_nodes_errors_handler,
         get_knowledge_nodes_and_errors_count,
+        get_knowledge_nodes_stats_handler,
+        get_token_usage_handler,
+        get_onboarding_complexity_handler,
+        post_trace_replay_handler,
+        get_trace_replays_handler,
         post_capture_tc_handler
     ),
     components(schemas(
@@ -84,15 +213,131 @@ use tracing_subscriber::{fmt, layer::*, EnvFilter};
         crate::onboarding::schema::app_onboarding_request::AppDataSource,
         crate::onboarding::schema::app_onboarding_request::LlmModel,
         crate::onboarding::schema::app_onboarding_request::FileStore,
+        crate::onboarding::schema::app_onboarding_request::UserRateLimit,
+        crate::onboarding::schema::app_onboarding_request::ValidationMode,
+        crate::onboarding::schema::app_onboarding_request::ListingMode,
+        crate::service::user_access::UserAccessList,
+        crate::service::ingestion_control::IngestionState,
+        crate::service::ingestion_control::IngestionControl,
+        crate::service::ingestion_retry::IngestionRetryPolicy,
+        crate::service::ingestion_retry::RetryCounts,
+        crate::service::ingestion_retry::RetryStatus,
+        crate::service::ingestion_sla::IngestionSlaReport,
+        crate::service::ingestion_sla::IngestionLatencyStats,
+        crate::service::ingestion_sla::RequestIngestion,
+        crate::service::ingestion_sla::SourceIngestion,
+        crate::service::onboarding_state::OnboardingState,
+        crate::service::onboarding_state::OnboardingStep,
+        crate::service::onboarding_state::OnboardingProgress,
+        crate::service::api_key::DailyApiKeyUsage,
+        crate::service::filestore_hint::FileStoreHint,
+        crate::service::filestore_hint::HintChange,
+        crate::service::filestore_hint::HintChangeAction,
+        crate::retrieval::query_classification::QueryCategory,
+        crate::service::artifact::Artifact,
+        crate::service::artifact::ArtifactLocation,
+        crate::service::history_retention::HistoryRetention,
+        crate::service::history_upsert::HistoryReconciliation,
+        crate::service::history_retention::LegalHold,
+        crate::service::history_retention::HoldChange,
+        crate::service::history_retention::HoldChangeAction,
+        crate::service::prompt_template::PromptTemplate,
+        crate::service::answer_sink::AnswerSink,
+        crate::service::answer_sink::AnswerSinkTarget,
+        crate::service::answer_sink::AnswerFilter,
+        crate::service::prompt_template::PromptTemplateReference,
+        crate::service::experiment::Experiment,
+        crate::service::experiment::ExperimentVariant,
+        crate::service::experiment::VariantResults,
+        crate::service::shadow_traffic::ShadowTraffic,
+        crate::service::shadow_traffic::ShadowReport,
+        crate::service::app_keys::AppKeySummary,
+        crate::service::app_keys::CreateAppKeyRequest,
+        crate::service::app_keys::KeyExpiry,
+        crate::service::app_keys::KeyExpiryRequest,
+        crate::service::scheduler::JobRun,
+        crate::service::scheduler::JobLease,
+        crate::service::scheduler::JobStatus,
+        crate::service::job_queue::QueuedJob,
+        crate::service::job_queue::JobQueue,
+        crate::service::job_queue::QueuedJobStatus,
+        crate::service::backfill::Backfill,
+        crate::service::backfill::BackfillJob,
+        crate::service::backfill::BackfillStatus,
+        crate::service::node_count_check::SourceCountCheck,
+        crate::service::node_count_check::CountStatus,
+        crate::service::graph_query::GraphQuery,
+        crate::service::graph_query::Direction,
+        crate::service::graph_query::GraphQueryResult,
+        crate::service::graph_query::GraphNode,
+        crate::service::graph_query::GraphEdge,
+        crate::service::notification::Notification,
+        crate::service::notification::NotificationKind,
+        crate::service::notification::NotificationSeverity,
+        crate::service::scim::ScimUser,
+        crate::service::scim::ScimGroup,
+        crate::service::scim::ScimValue,
+        crate::service::scim::ScimMeta,
+        crate::service::scim::PatchRequest,
+        crate::service::scim::PatchOperation,
+        crate::service::selfcheck::SettingsCheck,
+        crate::service::selfcheck::KnowledgeEngineCheck,
+        crate::service::selfcheck::AwsRegionsCheck,
+        crate::service::selfcheck::UrlProbe,
+        crate::service::error_code::ErrorCatalogEntry,
+        crate::service::readiness::AppReadiness,
+        crate::service::readiness::SourceReadiness,
+        crate::service::readiness::SourceStatus,
         crate::onboarding::schema::app_onboarding_request::DataStore,
         crate::onboarding::schema::app_onboarding_request::Hint,
         crate::onboarding::schema::app_onboarding_request::Table,
         crate::onboarding::schema::app_onboarding_request::SampleRows,
         crate::onboarding::schema::app_onboarding_request::Column,
+        crate::onboarding::schema::app_onboarding_request::Sensitivity,
+        crate::service::column_classification::ColumnClassification,
+        crate::service::row_filter::RowFilter,
+        crate::onboarding::sample_rows::TableSampleRows,
+        crate::onboarding::sample_rows::MaskingRule,
+        crate::onboarding::schema::schema_preview::SchemaPreview,
+        crate::onboarding::schema::schema_preview::ColumnPreview,
+        crate::onboarding::schema::schema_preview::TabularFormat,
         crate::onboarding::schema::response::AppCreateResponse,
         crate::onboarding::schema::response::ErrorResponse,
+        crate::onboarding::schema::response::ValidationJobCreateResponse,
+        crate::onboarding::validation_job::ValidationJobDocument,
+        crate::onboarding::validation_job::ValidationJobStatus,
+        crate::onboarding::datasource_connectivity::report::DatasourceResult,
+        crate::onboarding::datasource_connectivity::report::ValidationOutcome,
+        crate::service::deletion_confirmation::DeletionSummary,
+        crate::service::deletion_confirmation::CollectionSummary,
+        crate::service::service_account::ServiceAccountSummary,
+        crate::service::service_account::CreateServiceAccountRequest,
+        crate::service::service_account::Scope,
+        crate::service::dependency_health::DependenciesHealth,
+        crate::service::dependency_health::DependencyHealth,
+        crate::service::dependency_health::DependencyStatus,
+        crate::service::overview_feed::OverviewUpdate,
+        crate::service::overview_feed::OverviewDelta,
+        crate::onboarding::schema::apply_plan::ApplyResponse,
+        crate::onboarding::schema::apply_plan::ApplyPlan,
+        crate::onboarding::schema::apply_plan::DatasourceChange,
+        crate::onboarding::schema::apply_plan::PlanAction,
+        crate::onboarding::schema::apply_plan::DatasourceKind,
         crate::retrieval::schema::history_document::HistoryDocument,
+        crate::retrieval::schema::history_document::Citation,
+        crate::retrieval::schema::history_document::SubQueryAnswer,
+        crate::retrieval::schema::history_document::TokenUsage,
+        crate::retrieval::schema::history_document::FullContent,
+        crate::retrieval::cost_estimate::RetrievalEstimateRequest,
+        crate::retrieval::cost_estimate::RetrievalEstimate,
+        crate::retrieval::cost_estimate::LatencyClass,
+        crate::retrieval::cost_estimate::EstimateBasis,
         crate::admin_ui_api::schema::CaptureUserSchema,
+        crate::admin_ui_api::schema::GeneratedConfigPatch,
+        crate::admin_ui_api::schema::VectorDbConfigPatch,
+        crate::admin_ui_api::schema::ServiceConfigPatch,
+        crate::admin_ui_api::schema::ResidencyMigrationRequest,
+        crate::admin_ui_api::schema::HistoryRetentionRequest,
         api_utils::retrieval_model::RetrievalRequest,
         api_utils::retrieval_model::UserDetails,
         api_utils::retrieval_model::AccessDetails,
@@ -137,12 +382,124 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
+    // Load the client certificate presented on outbound calls when mTLS is enabled
+    let client_tls_material = match settings.tls.as_ref().filter(|tls| tls.client.enabled) {
+        Some(tls) => match service::tls::load_client_material(&tls.client).await {
+            Ok(material) => Some(material),
+            Err(e) => {
+                eprintln!("Failed to load client TLS material: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    // Load the server certificate for the listener when mTLS is enabled
+    let server_tls_config = match settings.tls.as_ref().filter(|tls| tls.server.enabled) {
+        Some(tls) => match service::tls::load_server_material(&tls.server)
+            .await
+            .and_then(|material| service::tls::build_server_config(&material))
+        {
+            Ok(config) => Some(config),
+            Err(e) => {
+                eprintln!("Failed to load server TLS config: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // Set up the KMS key provider of the data keys when encryption is configured
+    let key_provider: Option<Box<dyn service::encryption::KeyProvider>> =
+        match settings.encryption.as_ref() {
+            Some(encryption) => Some(Box::new(
+                service::encryption::KmsKeyProvider::from_settings(encryption).await,
+            )),
+            None => None,
+        };
+
     // Set up AppState struct instance
-    let app_state = match AppState::builder()
+    let mut app_state_builder = AppState::builder()
         .mongodb_client(mongodb)
-        .set_application_settings(settings)
-        .build()
+        .client_tls_material(client_tls_material)
+        .key_provider(key_provider);
+
+    // Initialize a connection to each residency cluster
+    for cluster in settings
+        .residency
+        .iter()
+        .flat_map(|residency| &residency.clusters)
+    {
+        match DB::init(
+            cluster.mongo_db_url.clone(),
+            cluster.mongo_db_database_name.clone(),
+        )
+        .await
+        {
+            Ok(db) => app_state_builder = app_state_builder.residency_db(cluster.name.clone(), db),
+            Err(e) => {
+                eprintln!(
+                    "Failed to initialize connection to the '{}' residency cluster: {}",
+                    cluster.name, e
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Initialize the driver connections shared by the aggregations, indexes, change streams and atomic writes
+    match service::driver::DriverDatabases::connect(&settings).await {
+        Ok(driver_databases) => {
+            app_state_builder = app_state_builder.driver_databases(driver_databases)
+        }
+        Err(e) => {
+            eprintln!("Failed to initialize driver connections: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // Initialize the analytics connections of the heavy admin aggregations, when configured
+    if let Some(analytics_url) = settings.mongo_db.mongo_db_analytics_url.as_ref() {
+        match DB::init(
+            analytics_url.clone(),
+            settings.mongo_db.mongo_db_database_name.clone(),
+        )
+        .await
+        {
+            Ok(db) => app_state_builder = app_state_builder.analytics_db(None, db),
+            Err(e) => {
+                eprintln!("Failed to initialize analytics database connection: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    for cluster in settings
+        .residency
+        .iter()
+        .flat_map(|residency| &residency.clusters)
     {
+        let Some(analytics_url) = cluster.analytics_url.as_ref() else {
+            continue;
+        };
+        match DB::init(
+            analytics_url.clone(),
+            cluster.mongo_db_database_name.clone(),
+        )
+        .await
+        {
+            Ok(db) => {
+                app_state_builder = app_state_builder.analytics_db(Some(cluster.name.clone()), db)
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to initialize analytics connection to the '{}' residency cluster: {}",
+                    cluster.name, e
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let app_state = match app_state_builder.set_application_settings(settings).build() {
         Ok(app_state) => app_state,
         Err(e) => {
             eprintln!("Failed to build AppState: {}", e);
@@ -150,6 +507,17 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
+    // Create the unique index of the data keys, so the replicas never store two keys under the same version
+    if let (Some(encryptor), Some(driver_databases)) = (
+        app_state.encryptor.as_ref(),
+        app_state.driver_databases.as_ref(),
+    ) {
+        if let Err(e) = encryptor.ensure_index(driver_databases).await {
+            eprintln!("Failed to create the index of the data keys: {}", e);
+            std::process::exit(1);
+        }
+    }
+
     let app_state_arc = Arc::new(app_state);
 
     // Initialize tracing subscriber
@@ -161,6 +529,82 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
+    // Probe the reachability of the Tresleai URLs in the background, the unreachable ones are logged
+    tokio::spawn(service::selfcheck::probe_service_urls_on_startup(
+        app_state_arc.clone(),
+    ));
+
+    // Apply the pending migrations of the stored documents in the background, unless disabled
+    if app_state_arc.options::<MigrationOptions>().enabled {
+        tokio::spawn(service::migration::run_migrations(app_state_arc.clone()));
+    }
+
+    // Mirror the log documents into OpenSearch/Elasticsearch in the background, when configured
+    if app_state_arc.app_settings.log_sink.is_some() {
+        tokio::spawn(service::log_sink::ship_logs(app_state_arc.clone()));
+    }
+
+    // Delete the expired history documents of the apps with a retention in the background
+    tokio::spawn(service::history_retention::enforce_history_retention(
+        app_state_arc.clone(),
+    ));
+
+    // Warn and deactivate the expiring API keys of the apps in the background
+    tokio::spawn(service::key_expiry::enforce_key_expiry(
+        app_state_arc.clone(),
+    ));
+
+    // Consume the ingestion status of the sources of the apps in the background, when configured
+    if app_state_arc
+        .options::<ReadinessOptions>()
+        .status_topic
+        .is_some()
+        && app_state_arc.local_dev.is_none()
+    {
+        tokio::spawn(service::readiness::consume_ingestion_status(
+            app_state_arc.clone(),
+        ));
+    }
+
+    // Expire the retrievals left without history document in the background, when configured
+    if app_state_arc.app_settings.retrieval_sweeper.is_some() {
+        tokio::spawn(service::retrieval_sweeper::sweep_dead_retrievals(
+            app_state_arc.clone(),
+        ));
+    }
+
+    // Re-publish the failed ingestions of the apps with an enabled retry policy in the background, when configured
+    if app_state_arc.app_settings.ingestion_retry.is_some() {
+        tokio::spawn(service::ingestion_retry::retry_ingestion_errors(
+            app_state_arc.clone(),
+        ));
+    }
+
+    // Evict the cached API key lookups changed on any replica, when the app cache is enabled
+    if app_state_arc.options::<AppCacheOptions>().enabled && app_state_arc.local_dev.is_none() {
+        tokio::spawn(service::app_cache::invalidate_app_cache(
+            app_state_arc.clone(),
+        ));
+    }
+
+    // Push the live updates of the overview to the admin dashboard, when enabled
+    if app_state_arc.options::<OverviewFeedOptions>().enabled && app_state_arc.local_dev.is_none() {
+        tokio::spawn(service::overview_feed::watch_overview_changes(
+            app_state_arc.clone(),
+        ));
+    }
+
+    // Run the queued background steps of the onboarding/update requests, and resume the retrievals abandoned by a
+    // crashed replica
+    tokio::spawn(service::job_queue::consume_queue(
+        app_state_arc.clone(),
+        service::job_queue::JobQueue::Onboarding,
+    ));
+    tokio::spawn(service::job_queue::consume_queue(
+        app_state_arc.clone(),
+        service::job_queue::JobQueue::Retrieval,
+    ));
+
     // Set up CORS (Cross-Origin Resource Sharing) settings
     let origins: Vec<HeaderValue> = app_state_arc
         .app_settings
@@ -202,11 +646,37 @@ async fn main() -> std::io::Result<()> {
     // Create a router with the AppState instance and apply the CORS settings to it
     let app = Router::new()
         .merge(create_router(app_state_arc.clone())) // Application routes
-        .merge(SwaggerUi::new("/swagger-ui").url("/api-doc/openapi.json", ApiDoc::openapi())) // Swagger UI
+        .merge(api_docs_router(app_state_arc.clone(), ApiDoc::openapi())) // Swagger UI, guarded
         .layer(cors);
+    let app = apply_compression(app, app_state_arc.app_settings.compression.as_ref());
+    let app = apply_request_metrics(app, app_state_arc.clone());
 
     debug!("🚀 Server started successfully.");
 
+    // Serve over TLS if configured, verifying client certificates when a client CA is set
+    if let Some(server_tls_config) = server_tls_config {
+        let rustls_config = RustlsConfig::from_config(server_tls_config);
+        match axum_server::bind_rustls(
+            app_state_arc.app_settings.application.address(),
+            rustls_config,
+        )
+        .serve(app.into_make_service())
+        .await
+        {
+            Ok(_) => {
+                debug!("Server started successfully.");
+            }
+            Err(e) => {
+                debug!("Failed to start server: {}", e);
+                std::process::exit(1);
+            }
+        }
+        if let Some(worker) = tresleai_background_worker {
+            worker.shutdown().await;
+        }
+        return Ok(());
+    }
+
     // Start a server that listens on 0.0.0.0:8000 and serves the application
     let listener =
         match tokio::net::TcpListener::bind(app_state_arc.app_settings.application.address()).await
@@ -259,6 +729,12 @@ pub async fn tracing_initialization(
             )
             .parse()?,
         );
+    // The access log is written by the fmt layer whatever its level, see `service::access_log`
+    let fmt_filter = if app_state_arc.options::<AccessLogOptions>().enabled {
+        fmt_filter.add_directive(format!("{}=info", ACCESS_LOG_TARGET).parse()?)
+    } else {
+        fmt_filter
+    };
 
     if app_state_arc.app_settings.tracing_layer_debug_mode {
         let subscriber = tracing_subscriber::registry().with(fmt_layer.with_filter(fmt_filter));
@@ -273,17 +749,17 @@ pub async fn tracing_initialization(
             .app_settings
             .tresleai_urls
             .logging_service_url
-            .clone();
+            .to_string();
         let audit_api_url = app_state_arc
             .app_settings
             .tresleai_urls
             .audit_service_url
-            .clone();
+            .to_string();
         let metrics_api_url = app_state_arc
             .app_settings
             .tresleai_urls
             .metric_service_url
-            .clone();
+            .to_string();
         let system_app_name = app_state_arc
             .app_settings
             .tracing_layer_system_app_name
@@ -298,13 +774,17 @@ pub async fn tracing_initialization(
         let subscriber = tracing_subscriber::registry()
             .with(fmt_layer.with_filter(fmt_filter))
             .with(
-                tresleai_layer.with_filter(EnvFilter::try_new(
-                    app_state_arc
-                        .app_settings
-                        .tracing_layer_levels
-                        .peripheral_services_layer_level
-                        .clone(),
-                )?),
+                tresleai_layer.with_filter(
+                    EnvFilter::try_new(
+                        app_state_arc
+                            .app_settings
+                            .tracing_layer_levels
+                            .peripheral_services_layer_level
+                            .clone(),
+                    )?
+                    // The access log is not sent to the peripheral services
+                    .add_directive(format!("{}=off", ACCESS_LOG_TARGET).parse()?),
+                ),
             );
 
         // Set the global tracing subscriber
@@ -5,12 +5,17 @@
  */
 //! Onboarding module and associated functions.
 
+pub mod apply;
 mod check_connectivity;
 mod check_datasource_change;
+pub mod complexity;
 pub mod create_api_key;
-mod datasource_connectivity;
+pub mod datasource_connectivity;
 mod fetch_api_key;
 pub mod handler;
+pub mod sample_rows;
 pub mod schema;
-mod update_api_key_usage;
+pub mod schema_preview;
+pub mod update_api_key_usage;
 mod update_app;
+pub mod validation_job;
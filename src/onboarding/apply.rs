@@ -0,0 +1,493 @@
+/*
+ * Created Date:  Jun 20, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the POST handler applying a declarative (YAML) app descriptor.
+//! The descriptor has the shape of the onboarding request and describes the desired state of the app.
+//! The handler diffs the descriptor against the existing app document, reports the computed plan
+//! (app created/updated, datasources created/updated/removed) and applies it through the onboarding flow.
+//! With `dry_run=true` only the plan is returned.
+//! The handler returns a 201 status code if the plan is being applied and a 200 status code if nothing is applied.
+//! The handler returns a 400 status code if the descriptor is invalid.
+//! The handler returns a 500 status code if an error occurs while fetching the existing app document.
+//!
+
+use crate::admin_ui_api::schema::QueryParams;
+use crate::onboarding::handler::start_onboarding;
+use crate::onboarding::schema::app_onboarding_request::{
+    AppDataSource, ListingMode, OnboardingRequest, ValidationMode,
+};
+use crate::onboarding::schema::apply_plan::*;
+use crate::service::column_classification::merge_column_classifications;
+use crate::service::row_filter::merge_row_filters;
+use crate::service::source_label::merge_source_labels;
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use axum::{extract::Query, extract::State, http::StatusCode, response::IntoResponse, Json};
+use chrono::Utc;
+use mongodb::bson::doc;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{error, info, instrument};
+
+/// Parses an app descriptor. YAML is a superset of JSON, so JSON descriptors are accepted as well.
+pub fn parse_descriptor(descriptor: &str) -> Result<OnboardingRequest, String> {
+    serde_yaml::from_str(descriptor).map_err(|e| format!("Invalid app descriptor. Error: {}", e))
+}
+
+/// Resets the validation settings of the datasources (validation mode, listing mode, inventory manifest and file type
+/// overrides). They only apply to the onboarding request and are not stored with the app, so they never make a plan.
+fn without_validation_modes(app_datasource: &AppDataSource) -> AppDataSource {
+    let mut app_datasource = app_datasource.clone();
+    for filestore in app_datasource.filestore.values_mut().flatten() {
+        filestore.validation_mode = ValidationMode::Strict;
+        filestore.listing_mode = ListingMode::Objects;
+        filestore.inventory_manifest = None;
+    }
+    for datastore in app_datasource.datastore.values_mut().flatten() {
+        datastore.validation_mode = ValidationMode::Strict;
+    }
+    app_datasource.file_types = None;
+    app_datasource
+}
+
+/// Flattens the datasources into entries keyed by kind, source type and identifier (url or connection).
+fn datasource_entries(
+    app_datasource: &AppDataSource,
+) -> BTreeMap<(DatasourceKind, String, String), serde_json::Value> {
+    let mut entries = BTreeMap::new();
+    for (source_type, filestores) in &app_datasource.filestore {
+        for filestore in filestores {
+            entries.insert(
+                (
+                    DatasourceKind::Filestore,
+                    source_type.clone(),
+                    filestore.url.clone(),
+                ),
+                json!(filestore),
+            );
+        }
+    }
+    for (source_type, datastores) in &app_datasource.datastore {
+        for datastore in datastores {
+            let identifier = format!(
+                "{}://{}:{}/{}",
+                datastore.db_type, datastore.host, datastore.port, datastore.database
+            );
+            entries.insert(
+                (DatasourceKind::Datastore, source_type.clone(), identifier),
+                json!(datastore),
+            );
+        }
+    }
+    entries
+}
+
+/// Computes the plan turning the existing app (if any) into the desired one.
+pub fn compute_plan(
+    existing: Option<&OnboardingRequest>,
+    desired: &OnboardingRequest,
+) -> ApplyPlan {
+    let existing_datasource =
+        existing.map(|existing| without_validation_modes(&existing.app_datasource));
+    let desired_datasource = without_validation_modes(&desired.app_datasource);
+    let existing_entries = existing_datasource
+        .as_ref()
+        .map(datasource_entries)
+        .unwrap_or_default();
+    let desired_entries = datasource_entries(&desired_datasource);
+
+    let mut datasource_changes = Vec::new();
+    for (key, desired_entry) in &desired_entries {
+        let action = match existing_entries.get(key) {
+            None => PlanAction::Create,
+            Some(existing_entry) if existing_entry != desired_entry => PlanAction::Update,
+            Some(_) => continue,
+        };
+        datasource_changes.push(DatasourceChange {
+            action,
+            kind: key.0,
+            source_type: key.1.clone(),
+            identifier: key.2.clone(),
+        });
+    }
+    for key in existing_entries.keys() {
+        if !desired_entries.contains_key(key) {
+            datasource_changes.push(DatasourceChange {
+                action: PlanAction::Remove,
+                kind: key.0,
+                source_type: key.1.clone(),
+                identifier: key.2.clone(),
+            });
+        }
+    }
+
+    let (app_action, settings_changed) = match existing {
+        None => (PlanAction::Create, true),
+        Some(existing) => {
+            let settings_changed = existing.app_description != desired.app_description
+                || existing.text_embedding_model != desired.text_embedding_model
+                || existing.multimodal_embedding_model != desired.multimodal_embedding_model
+                || existing.csv_append_same_schema != desired.csv_append_same_schema
+                || existing.allowed_models != desired.allowed_models
+                || (desired.residency.is_some() && existing.residency != desired.residency)
+                || existing.user_rate_limit != desired.user_rate_limit
+                || existing.tier != desired.tier
+                || existing.notification_url != desired.notification_url
+                || existing.query_normalization != desired.query_normalization
+                || existing.pseudonymize_user_ids != desired.pseudonymize_user_ids
+                || existing.metadata != desired.metadata;
+            // Reordering the entries of a source type is an update of the datasource without entry changes
+            let datasource_changed = existing_datasource.as_ref() != Some(&desired_datasource);
+            if settings_changed || datasource_changed {
+                (PlanAction::Update, settings_changed)
+            } else {
+                (PlanAction::NoChange, false)
+            }
+        }
+    };
+
+    ApplyPlan {
+        app_name: desired.app_name.clone(),
+        app_action,
+        settings_changed,
+        datasource_changes,
+    }
+}
+
+/// Asynchronous function to fetch the existing app in the shape of the descriptor.
+#[instrument(skip_all)]
+pub(crate) async fn fetch_existing_app(
+    app_state: &Arc<AppState>,
+    app_name: &String,
+) -> Result<Option<OnboardingRequest>, (StatusCode, Json<serde_json::Value>)> {
+    let filter = doc! {"app_name": app_name};
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+
+    match app_state
+        .db
+        .get_document(collection_name, filter)
+        .await
+        .map_err(ErrorInterceptor::from)
+    {
+        Ok(Some(mut response)) => {
+            app_state
+                .decrypt_fields(app_name, &mut response)
+                .await
+                .map_err(|e| {
+                    let error_message = format!(
+                        "Failed to decrypt existing app '{}'. Error: {}",
+                        app_name, e
+                    );
+                    error!(
+                        app_name = app_name,
+                        ext_message = error_message,
+                        message = error_message
+                    );
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(json!({"status": "error", "message": error_message})),
+                    )
+                })?;
+            // The PII tags of the columns, the row filters of the tables and the labels of the sources are stored
+            // apart from the datasource
+            merge_column_classifications(&mut response);
+            merge_row_filters(&mut response);
+            merge_source_labels(&mut response);
+            serde_json::from_value(response).map(Some).map_err(|e| {
+                let error_message = format!(
+                    "Failed to deserialize existing app '{}'. Error: {}",
+                    app_name, e
+                );
+                error!(
+                    app_name = app_name,
+                    ext_message = error_message,
+                    message = error_message
+                );
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"status": "error", "message": error_message})),
+                )
+            })
+        }
+        Ok(None) => Ok(None),
+        Err(e) => {
+            let error_message = format!("Failed to fetch app '{}'. Error: {}", app_name, e);
+            error!(
+                app_name = app_name,
+                ext_message = error_message,
+                message = error_message
+            );
+            Err(e.intercept_error().await)
+        }
+    }
+}
+
+/// POST handler to apply a declarative app descriptor.
+#[utoipa::path(
+    post,
+    path = "/api/v1.1/admin/apps/apply",
+    request_body(content = String, description = "App descriptor (YAML) in the shape of the onboarding request.", content_type = "application/yaml"),
+    params(
+        (
+            "dry_run" = inline(Option<bool>),
+            Query,
+            description = "Only compute the plan.",
+        )
+    ),
+    responses(
+        (status = 200, description = "Plan computed, nothing applied.", body = [ApplyResponse]),
+        (status = 201, description = "Plan computed and being applied.", body = [ApplyResponse]),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn post_app_apply_handler(
+    Query(params): Query<QueryParams>,
+    State(app_state): State<Arc<AppState>>,
+    descriptor: String,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let request_timestamp = Utc::now();
+
+    let desired = parse_descriptor(&descriptor).map_err(|error_message| {
+        error!(ext_message = error_message, message = error_message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    })?;
+
+    let existing = fetch_existing_app(&app_state, &desired.app_name).await?;
+    let plan = compute_plan(existing.as_ref(), &desired);
+    let dry_run = params.dry_run.unwrap_or(false);
+
+    if dry_run || plan.is_empty() {
+        let message = if dry_run {
+            "Plan computed. Nothing applied (dry run).".to_string()
+        } else {
+            format!("App '{}' is up to date.", &desired.app_name)
+        };
+        info!(app_name = &desired.app_name, message = message);
+        return Ok((
+            StatusCode::OK,
+            Json(ApplyResponse {
+                status: "success".to_string(),
+                message,
+                dry_run,
+                plan,
+                api_key: None,
+                app_id: None,
+                reference_id: None,
+                warnings: Vec::new(),
+                estimated_ingestion_size: None,
+            }),
+        ));
+    }
+
+    let is_update = existing.is_some();
+    let response = start_onboarding(
+        &app_state,
+        desired,
+        is_update,
+        request_timestamp,
+        params.override_limits.unwrap_or(false),
+    )
+    .await?;
+    info!(
+        app_name = &plan.app_name,
+        message = format!(
+            "Applying descriptor of app '{}' with {} datasource change(s).",
+            &plan.app_name,
+            plan.datasource_changes.len()
+        )
+    );
+
+    Ok((
+        StatusCode::CREATED,
+        Json(ApplyResponse {
+            status: response.status,
+            message: response.message,
+            dry_run,
+            plan,
+            api_key: Some(response.api_key),
+            app_id: Some(response.app_id),
+            reference_id: Some(response.reference_id),
+            warnings: response.warnings,
+            estimated_ingestion_size: response.estimated_ingestion_size,
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onboarding::schema::app_onboarding_request::{EmbeddingModel, FileStore, Hint};
+    use std::collections::HashMap;
+    use tokio::runtime::Runtime;
+
+    fn descriptor(app_name: &str, urls: &[&str]) -> OnboardingRequest {
+        let embedding_model = EmbeddingModel {
+            dimension: 1024,
+            model_id: "model".to_string(),
+            platform: "platform".to_string(),
+        };
+        let filestores = urls
+            .iter()
+            .map(|url| FileStore {
+                url: url.to_string(),
+                hints: vec![],
+                validation_mode: ValidationMode::default(),
+                listing_mode: ListingMode::default(),
+                inventory_manifest: None,
+                labels: None,
+            })
+            .collect();
+        OnboardingRequest {
+            app_name: app_name.to_string(),
+            app_description: "test app".to_string(),
+            text_embedding_model: embedding_model.clone(),
+            multimodal_embedding_model: embedding_model,
+            csv_append_same_schema: false,
+            allowed_models: vec![],
+            app_datasource: AppDataSource {
+                filestore: HashMap::from([("aws_s3".to_string(), filestores)]),
+                datastore: HashMap::new(),
+                file_types: None,
+            },
+            residency: None,
+            user_rate_limit: None,
+            tier: None,
+            notification_url: None,
+            query_normalization: None,
+            pseudonymize_user_ids: None,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn test_success_parse_descriptor() {
+        let yaml = r#"
+app_name: app100
+app_description: test app
+text_embedding_model: { dimension: 1024, model_id: model, platform: platform }
+multimodal_embedding_model: { dimension: 1024, model_id: model, platform: platform }
+csv_append_same_schema: false
+allowed_models: []
+app_datasource:
+  filestore:
+    aws_s3:
+      - url: s3://bucket-a
+        hints: []
+  datastore: {}
+"#;
+        let parsed = parse_descriptor(yaml).unwrap();
+        assert_eq!(parsed, descriptor("app100", &["s3://bucket-a"]));
+
+        assert!(parse_descriptor("app_name: [")
+            .unwrap_err()
+            .contains("Invalid app descriptor"));
+    }
+
+    #[test]
+    fn test_success_compute_plan() {
+        let desired = descriptor("app100", &["s3://bucket-a", "s3://bucket-b"]);
+
+        // New app
+        let plan = compute_plan(None, &desired);
+        assert_eq!(plan.app_action, PlanAction::Create);
+        assert_eq!(plan.datasource_changes.len(), 2);
+        assert!(plan
+            .datasource_changes
+            .iter()
+            .all(|change| change.action == PlanAction::Create));
+
+        // No drift
+        let plan = compute_plan(Some(&desired), &desired);
+        assert!(plan.is_empty());
+        assert!(plan.datasource_changes.is_empty());
+
+        // The validation mode is not stored, so it is no drift either
+        let mut relaxed = desired.clone();
+        relaxed.app_datasource.filestore.get_mut("aws_s3").unwrap()[0].validation_mode =
+            ValidationMode::Warn;
+        assert!(compute_plan(Some(&desired), &relaxed).is_empty());
+
+        // One datasource added, one removed and one updated
+        let mut existing = descriptor("app100", &["s3://bucket-a", "s3://bucket-c"]);
+        existing.app_datasource.filestore.get_mut("aws_s3").unwrap()[0]
+            .hints
+            .push(Hint {
+                prefix: "docs/".to_string(),
+                descriptions: "docs".to_string(),
+            });
+        let plan = compute_plan(Some(&existing), &desired);
+        assert_eq!(plan.app_action, PlanAction::Update);
+        assert!(!plan.settings_changed);
+        let actions: Vec<(PlanAction, &str)> = plan
+            .datasource_changes
+            .iter()
+            .map(|change| (change.action, change.identifier.as_str()))
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                (PlanAction::Update, "s3://bucket-a"),
+                (PlanAction::Create, "s3://bucket-b"),
+                (PlanAction::Remove, "s3://bucket-c"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_success_post_app_apply_handler_dry_run() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let descriptor =
+                serde_yaml::to_string(&descriptor("non-existing-app", &["s3://bucket-a"])).unwrap();
+
+            // Call the function
+            let result = post_app_apply_handler(
+                Query(QueryParams {
+                    dry_run: Some(true),
+                    ..Default::default()
+                }),
+                State(app_state),
+                descriptor,
+            )
+            .await;
+
+            // Check that only the plan is returned
+            let response = result.unwrap().into_response();
+            assert_eq!(response.status(), StatusCode::OK);
+        });
+    }
+
+    #[test]
+    fn test_failure_post_app_apply_handler_invalid_descriptor() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let result = post_app_apply_handler(
+                Query(QueryParams::default()),
+                State(app_state),
+                "app_name: [".to_string(),
+            )
+            .await;
+
+            // If the function returns Err, check the status code
+            let (status_code, Json(message)) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+            assert_eq!(message.get("status").unwrap().as_str().unwrap(), "error");
+        });
+    }
+}
@@ -7,7 +7,9 @@
 //! This module checks the connectivity to the different data sources. It calls the appropriate
 //! connectivity check logic based on the data source being checked.
 //! The module is used by the onboarding service to check the connectivity to the different data sources.
-//! The module returns an error if the connectivity check fails, else returns a success message.
+//! The module returns an error if the connectivity check fails, else returns the report of the non-fatal findings
+//! (warnings) and the estimated size of the files to ingest.
+//! The datasources are also checked against the configured onboarding limits, unless an admin overrides them.
 //! The module returns a 400 status code if an error occurs while checking the connectivity.
 //! The module returns a 500 status code if an error occurs while checking the connectivity.
 //! The module returns a JSON response with the status and message.
@@ -16,13 +18,15 @@
 use crate::onboarding::datasource_connectivity::checker::{
     CheckerTrait, DatastoreChecker, FilestoreChecker,
 };
+use crate::onboarding::datasource_connectivity::limits::limit_violations;
+use crate::onboarding::datasource_connectivity::report::ConnectivityReport;
 use crate::onboarding::schema::app_onboarding_request::AppDataSource;
 use crate::onboarding::schema::response::ErrorResponse;
 use crate::service::state::AppState;
 use axum::{http::StatusCode, Json};
 use serde_json::json;
 use std::sync::Arc;
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
 
 // Main caller to the connectivity checks for different data sources
 #[instrument(skip_all)]
@@ -30,8 +34,88 @@ pub async fn check_datasource_connectivity(
     app_state: &Arc<AppState>,
     app_datasource: &AppDataSource,
     app_name: &String,
-) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    override_limits: bool,
+) -> Result<ConnectivityReport, (StatusCode, Json<serde_json::Value>)> {
+    let mut connectivity_report = run_connectivity_checks(app_state, app_datasource).await?;
+    let connectivity_errors = std::mem::take(&mut connectivity_report.errors);
+
+    // Return error if any connectivity errors found
+    if !connectivity_errors.is_empty() {
+        let error_message = format!(
+            "Connectivity check failed. Errors: {:?}",
+            connectivity_errors
+        );
+        error!(ext_message = error_message, message = error_message);
+
+        return Err(bad_request(
+            "Connectivity check failed. Please check and try again.",
+            connectivity_errors,
+        ));
+    }
+
+    // Return error if the datasources exceed the onboarding limits
+    let limit_errors = check_onboarding_limits(
+        app_state,
+        &mut connectivity_report,
+        app_datasource,
+        app_name,
+        override_limits,
+    );
+    if !limit_errors.is_empty() {
+        let error_message = format!("Onboarding limits exceeded. Errors: {:?}", limit_errors);
+        error!(ext_message = error_message, message = error_message);
+        return Err(bad_request(
+            "Datasources exceed the onboarding limits. Narrow the datasources or ask an admin to override the limits.",
+            limit_errors,
+        ));
+    }
+    if !connectivity_report.warnings.is_empty() {
+        warn!(
+            app_name = app_name,
+            message = format!(
+                "Connectivity check warnings: {:?}",
+                connectivity_report.warnings
+            )
+        );
+    }
+    info!(app_name = app_name, "Connectivity check successful.");
+    Ok(connectivity_report)
+}
+
+/// Checks that the data sources are supported and runs their connectivity checks. Returns the report of all
+/// findings, connectivity errors included. Unsupported data sources are rejected with a 400 status code.
+#[instrument(skip_all)]
+pub async fn run_connectivity_checks(
+    app_state: &Arc<AppState>,
+    app_datasource: &AppDataSource,
+) -> Result<ConnectivityReport, (StatusCode, Json<serde_json::Value>)> {
     debug!("Starting connectivity check for the data sources.");
+    check_supported_data_sources(app_state, app_datasource)?;
+
+    let mut connectivity_report = ConnectivityReport::default();
+
+    // Check the connectivity for 'filestore' and 'datastore' data sources by calling their respective checkers
+    for data_source in app_datasource.filestore.keys() {
+        let report = FilestoreChecker
+            .connectivity(data_source.as_str(), app_state, app_datasource)
+            .await?;
+        connectivity_report.merge(report);
+    }
+    for data_source in app_datasource.datastore.keys() {
+        let report = DatastoreChecker
+            .connectivity(data_source.as_str(), app_state, app_datasource)
+            .await?;
+        connectivity_report.merge(report);
+    }
+    Ok(connectivity_report)
+}
+
+/// Checks that all filestore and datastore data sources are supported by the application. Unsupported data
+/// sources are rejected with a 400 status code.
+pub fn check_supported_data_sources(
+    app_state: &Arc<AppState>,
+    app_datasource: &AppDataSource,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
     let supported_data_sources: Vec<&String> = app_state
         .app_settings
         .supported_data_source_types
@@ -68,73 +152,77 @@ pub async fn check_datasource_connectivity(
         );
         error!(ext_message = error_message, message = error_message);
 
-        let error_response = ErrorResponse {
-            status: "error".to_string(),
-            message: "Unsupported data sources. Please check and try again.".to_string(),
-            errors: unsupported_data_sources,
-        };
-
-        match serde_json::to_value(error_response) {
-            Ok(json_response) => {
-                return Err((StatusCode::BAD_REQUEST, Json(json_response)));
-            }
-            Err(e) => {
-                let error_message = format!("Failed to serialize error response: {}", e);
-                error!(ext_message = error_message, message = error_message);
-                return Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({ "error": "Internal server error" })),
-                ));
-            }
-        }
+        return Err(bad_request(
+            "Unsupported data sources. Please check and try again.",
+            unsupported_data_sources,
+        ));
     }
+    Ok(())
+}
 
-    let mut connectivity_errors = Vec::new();
-
-    // Check the connectivity for 'filestore' and 'datastore' data sources by calling their respective checkers
-    for data_source in &filestore_data_sources {
-        let mut errors = FilestoreChecker
-            .connectivity(data_source.as_str(), app_state, app_datasource)
-            .await?;
-        connectivity_errors.append(&mut errors)
-    }
-    for data_source in &datastore_data_sources {
-        let mut errors = DatastoreChecker
-            .connectivity(data_source.as_str(), app_state, app_datasource)
-            .await?;
-        connectivity_errors.append(&mut errors);
+/// Checks the datasources against the onboarding limits and returns the exceeded ones. With `override_limits` the
+/// exceeded limits are audited and added to the warnings of the report instead.
+pub fn check_onboarding_limits(
+    app_state: &Arc<AppState>,
+    connectivity_report: &mut ConnectivityReport,
+    app_datasource: &AppDataSource,
+    app_name: &String,
+    override_limits: bool,
+) -> Vec<String> {
+    let Some(limits) = app_state.app_settings.onboarding_limits.as_ref() else {
+        return Vec::new();
+    };
+    let violations = limit_violations(limits, connectivity_report, app_datasource);
+    if violations.is_empty() || !override_limits {
+        return violations;
     }
 
-    // Return error if any connectivity errors found
-    if !connectivity_errors.is_empty() {
-        let error_message = format!(
-            "Connectivity check failed. Errors: {:?}",
-            connectivity_errors
-        );
-        error!(ext_message = error_message, message = error_message);
+    let task_id = app_state
+        .id_generator
+        .task_id(app_name, "OverrideOnboardingLimits");
+    let message = format!(
+        "Onboarding limits of app '{}' overridden: {:?}",
+        app_name, violations
+    );
+    warn!(app_name = app_name, message = message);
+    info!(
+        service = "audit_microservice",
+        task_id = task_id,
+        app_name = app_name,
+        action = "Onboarding limits overridden",
+        details = json!(violations).to_string(),
+        message = message
+    );
+    connectivity_report
+        .warnings
+        .extend(violations.into_iter().map(|violation| {
+            format!(
+                "Warning: Onboarding limit overridden: {}",
+                violation.trim_start_matches("Error: ")
+            )
+        }));
+    Vec::new()
+}
 
-        let error_response = ErrorResponse {
-            status: "error".to_string(),
-            message: "Connectivity check failed. Please check and try again.".to_string(),
-            errors: connectivity_errors,
-        };
-
-        match serde_json::to_value(error_response) {
-            Ok(json_response) => {
-                return Err((StatusCode::BAD_REQUEST, Json(json_response)));
-            }
-            Err(e) => {
-                let error_message = format!("Failed to serialize error response: {}", e);
-                error!(ext_message = error_message, message = error_message);
-                return Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({ "error": "Internal server error" })),
-                ));
-            }
+/// Builds the 400 response listing the errors of the datasources.
+fn bad_request(message: &str, errors: Vec<String>) -> (StatusCode, Json<serde_json::Value>) {
+    let error_response = ErrorResponse {
+        status: "error".to_string(),
+        message: message.to_string(),
+        errors,
+    };
+
+    match serde_json::to_value(error_response) {
+        Ok(json_response) => (StatusCode::BAD_REQUEST, Json(json_response)),
+        Err(e) => {
+            let error_message = format!("Failed to serialize error response: {}", e);
+            error!(ext_message = error_message, message = error_message);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Internal server error" })),
+            )
         }
     }
-    info!(app_name = app_name, "Connectivity check successful.");
-    Ok(())
 }
 
 #[cfg(test)]
@@ -161,7 +249,7 @@ mod tests {
 
             // Call the function
             let result =
-                check_datasource_connectivity(&app_state, &app_data_source, &app_name).await;
+                check_datasource_connectivity(&app_state, &app_data_source, &app_name, false).await;
 
             // Check that the result is as expected
             assert!(result.is_ok());
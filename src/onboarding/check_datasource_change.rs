@@ -14,6 +14,9 @@
 //!
 
 use crate::onboarding::schema::app_onboarding_request::AppDataSource;
+use crate::service::column_classification::merge_column_classifications;
+use crate::service::row_filter::merge_row_filters;
+use crate::service::source_label::merge_source_labels;
 use crate::service::state::AppState;
 use api_utils::errors::error_interceptor::ErrorInterceptor;
 use axum::{http::StatusCode, Json};
@@ -37,7 +40,29 @@ pub async fn check_datasource_change(
         .await
         .map_err(ErrorInterceptor::from)
     {
-        Ok(Some(response)) => {
+        Ok(Some(mut response)) => {
+            // Decrypt the descriptions so they can be compared with the new datasource
+            app_state
+                .decrypt_fields(app_name, &mut response)
+                .await
+                .map_err(|e| {
+                    let error_message =
+                        format!("Failed to decrypt existing datasource. Error: {}", e);
+                    error!(
+                        app_name = app_name,
+                        ext_message = error_message,
+                        message = error_message
+                    );
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(serde_json::json!({ "status": "error","message": error_message})),
+                    )
+                })?;
+            // The PII tags of the columns, the row filters of the tables and the labels of the sources are stored
+            // apart from the datasource
+            merge_column_classifications(&mut response);
+            merge_row_filters(&mut response);
+            merge_source_labels(&mut response);
             if let Some(existing_app_datasource_value) = response.get("app_datasource") {
                 let existing_app_datasource: AppDataSource = serde_json::from_value(
                     existing_app_datasource_value.clone(),
@@ -0,0 +1,198 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the complexity metrics of the onboarding requests, to follow the growth of the app
+//! configurations and plan their limits.
+//! Each accepted onboarding or update records, with the app and task dimensions, the number of filestore URLs, of
+//! hints, of datastores, of tables and of columns of its data sources, and the size of its JSON payload in bytes.
+//! The records are stored with the other typed metrics and rolled up by the onboarding complexity admin endpoint.
+//!
+
+use crate::onboarding::schema::app_onboarding_request::OnboardingRequest;
+use crate::service::metrics::{MetricRecord, APP_NAME_DIMENSION, TASK_ID_DIMENSION};
+use crate::service::state::AppState;
+use chrono::{DateTime, Utc};
+use mongodb::bson::{doc, Document};
+
+pub const FILESTORE_URLS_METRIC: &str = "Onboarding Filestore URLs";
+pub const HINTS_METRIC: &str = "Onboarding Hints";
+pub const DATASTORES_METRIC: &str = "Onboarding Datastores";
+pub const TABLES_METRIC: &str = "Onboarding Tables";
+pub const COLUMNS_METRIC: &str = "Onboarding Columns";
+pub const PAYLOAD_BYTES_METRIC: &str = "Onboarding Payload Bytes";
+
+/// Names of the complexity metrics of the onboarding requests.
+pub const COMPLEXITY_METRICS: [&str; 6] = [
+    FILESTORE_URLS_METRIC,
+    HINTS_METRIC,
+    DATASTORES_METRIC,
+    TABLES_METRIC,
+    COLUMNS_METRIC,
+    PAYLOAD_BYTES_METRIC,
+];
+
+/// Structural complexity of an onboarding request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OnboardingComplexity {
+    pub filestore_urls: usize,
+    pub hints: usize,
+    pub datastores: usize,
+    pub tables: usize,
+    pub columns: usize,
+    pub payload_bytes: usize,
+}
+
+impl OnboardingComplexity {
+    /// Measures an onboarding request.
+    pub fn of(body: &OnboardingRequest) -> Self {
+        let filestores = body.app_datasource.filestore.values().flatten();
+        let datastores = body.app_datasource.datastore.values().flatten();
+        let tables = datastores.clone().flat_map(|datastore| &datastore.tables);
+        OnboardingComplexity {
+            filestore_urls: filestores.clone().count(),
+            hints: filestores.map(|filestore| filestore.hints.len()).sum(),
+            datastores: datastores.count(),
+            tables: tables.clone().count(),
+            columns: tables
+                .map(|table| table.columns.as_ref().map_or(0, Vec::len))
+                .sum(),
+            payload_bytes: serde_json::to_vec(body).map_or(0, |payload| payload.len()),
+        }
+    }
+
+    /// Returns the metric records of the complexity.
+    pub fn records(&self, app_name: &str, task_id: &str) -> Vec<MetricRecord> {
+        vec![
+            MetricRecord::count(FILESTORE_URLS_METRIC, self.filestore_urls),
+            MetricRecord::count(HINTS_METRIC, self.hints),
+            MetricRecord::count(DATASTORES_METRIC, self.datastores),
+            MetricRecord::count(TABLES_METRIC, self.tables),
+            MetricRecord::count(COLUMNS_METRIC, self.columns),
+            MetricRecord::bytes(PAYLOAD_BYTES_METRIC, self.payload_bytes),
+        ]
+        .into_iter()
+        .map(|record| {
+            record
+                .dimension(APP_NAME_DIMENSION, app_name)
+                .dimension(TASK_ID_DIMENSION, task_id)
+        })
+        .collect()
+    }
+}
+
+/// Records the complexity metrics of an onboarding request.
+pub async fn record_onboarding_complexity(
+    app_state: &AppState,
+    body: &OnboardingRequest,
+    task_id: &str,
+) {
+    for record in OnboardingComplexity::of(body).records(&body.app_name, task_id) {
+        app_state.record_metric(record).await;
+    }
+}
+
+/// Group stage of the statistics of the complexity metrics with the same key.
+fn complexity_group(key: Document) -> Document {
+    doc! {
+        "$group": {
+            "_id": key,
+            "onboardings": { "$sum": 1 },
+            "total": { "$sum": "$value" },
+            "avg": { "$avg": "$value" },
+            "max": { "$max": "$value" },
+        }
+    }
+}
+
+/// Aggregation pipeline rolling up the complexity metrics, of an app or of all the apps, into the statistics per
+/// metric, their monthly series and the apps with the largest payloads.
+pub fn complexity_pipeline(
+    app_name: Option<&str>,
+    start_timestamp: DateTime<Utc>,
+    end_timestamp: DateTime<Utc>,
+) -> Vec<Document> {
+    let mut filter = doc! {
+        "name": { "$in": COMPLEXITY_METRICS.to_vec() },
+        "timestamp": {
+            "$gte": start_timestamp.to_rfc3339(),
+            "$lte": end_timestamp.to_rfc3339(),
+        },
+    };
+    if let Some(app_name) = app_name {
+        filter.insert(format!("dimensions.{}", APP_NAME_DIMENSION), app_name);
+    }
+    vec![
+        doc! { "$match": filter },
+        doc! {
+            "$facet": {
+                "totals": [
+                    complexity_group(doc! { "metric": "$name" }),
+                    { "$project": { "_id": 0, "metric": "$_id.metric", "onboardings": 1, "avg": 1, "max": 1 } },
+                    { "$sort": { "metric": 1 } },
+                ],
+                "by_month": [
+                    complexity_group(doc! {
+                        "month": { "$substrCP": ["$timestamp", 0, 7] },
+                        "metric": "$name",
+                    }),
+                    { "$project": { "_id": 0, "month": "$_id.month", "metric": "$_id.metric", "onboardings": 1, "avg": 1, "max": 1 } },
+                    { "$sort": { "month": 1, "metric": 1 } },
+                ],
+                "largest_apps": [
+                    { "$match": { "name": PAYLOAD_BYTES_METRIC } },
+                    complexity_group(doc! { "app_name": format!("$dimensions.{}", APP_NAME_DIMENSION) }),
+                    { "$project": { "_id": 0, "app_name": "$_id.app_name", "onboardings": 1, "max_payload_bytes": "$max" } },
+                    { "$sort": { "max_payload_bytes": -1 } },
+                    { "$limit": 10 },
+                ],
+            }
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::metrics::MetricUnit;
+
+    #[test]
+    fn test_success_onboarding_complexity() {
+        let body: OnboardingRequest =
+            serde_json::from_str(&std::fs::read_to_string("src/test/update_request.json").unwrap())
+                .unwrap();
+        let complexity = OnboardingComplexity::of(&body);
+        assert_eq!(complexity.filestore_urls, 3);
+        assert_eq!(complexity.hints, 6);
+        assert_eq!(complexity.datastores, 1);
+        assert_eq!(complexity.tables, 1);
+        assert_eq!(complexity.columns, 2);
+        assert!(complexity.payload_bytes > 0);
+
+        let records = complexity.records(&body.app_name, "task_id");
+        assert_eq!(records.len(), COMPLEXITY_METRICS.len());
+        assert_eq!(records[5].name, PAYLOAD_BYTES_METRIC);
+        assert_eq!(records[5].unit, MetricUnit::Bytes);
+        assert_eq!(
+            records[0].dimensions.get(APP_NAME_DIMENSION),
+            Some(&body.app_name)
+        );
+    }
+
+    #[test]
+    fn test_success_complexity_pipeline() {
+        let end_timestamp = Utc::now();
+        let start_timestamp = end_timestamp - chrono::Duration::days(30);
+
+        let pipeline = complexity_pipeline(Some("app100"), start_timestamp, end_timestamp);
+        let filter = pipeline[0].get_document("$match").unwrap();
+        assert_eq!(filter.get_str("dimensions.app_name").unwrap(), "app100");
+
+        let pipeline = complexity_pipeline(None, start_timestamp, end_timestamp);
+        let filter = pipeline[0].get_document("$match").unwrap();
+        assert!(!filter.contains_key("dimensions.app_name"));
+        let facet = pipeline[1].get_document("$facet").unwrap();
+        assert!(facet.contains_key("largest_apps"));
+    }
+}
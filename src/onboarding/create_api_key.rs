@@ -4,12 +4,15 @@
  * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
  */
 //! This module contains the function to create an API key for the app.
-//! The function is used by the onboarding service to create an API key for the app.
+//! The function is used by the onboarding service to create the primary API key of the app, and by the app keys
+//! endpoints to create its additional keys, whose API Gateway name is suffixed with their label.
+//! In the internal API key mode the API key is generated by the facade, without API Gateway.
 //! The function returns the API key and API key ID if the API key is created successfully.
 //! The function returns a 500 status code if an error occurs while creating the API key.
 //! The function returns a JSON response with the status and message.
 //!
 
+use crate::service::api_key::generate_api_key;
 use crate::service::state::AppState;
 use aws_config::meta::region::RegionProviderChain;
 use aws_config::{BehaviorVersion, Region};
@@ -23,14 +26,24 @@ use tracing::{debug, error, info, instrument};
 pub async fn create_api_key(
     app_state: &Arc<AppState>,
     app_name: &String,
+    label: Option<&str>,
 ) -> Result<(String, String), (StatusCode, Json<serde_json::Value>)> {
     debug!("Creating an API key for the app.");
-    let api_key_name = format!(
+    if app_state.api_key_options().is_internal() {
+        let (api_key, api_key_id) = generate_api_key();
+        let success_message = format!("API key generated successfully for app {}.", app_name);
+        info!(app_name = app_name, message = success_message);
+        return Ok((api_key, api_key_id));
+    }
+    let mut api_key_name = format!(
         "{}-{}-{}",
         app_state.app_settings.product_name.clone(),
         app_state.app_settings.env_identifier.clone(),
         app_name
     );
+    if let Some(label) = label {
+        api_key_name = format!("{}-{}", api_key_name, label);
+    }
 
     let region = app_state.app_settings.aws_api_gateway.region.clone();
     let region_provider = RegionProviderChain::first_try(Region::new(region));
@@ -94,7 +107,7 @@ mod tests {
             let app_name = "test".to_string();
 
             // Call the function
-            let result = create_api_key(&app_state, &app_name).await;
+            let result = create_api_key(&app_state, &app_name, None).await;
 
             // If the function returns Ok, check the API key
             assert!(result.is_ok());
@@ -114,7 +127,7 @@ mod tests {
             let app_name = "test".to_string();
 
             // Call the function
-            let result = create_api_key(&app_state, &app_name).await;
+            let result = create_api_key(&app_state, &app_name, None).await;
 
             // If the function returns Err, check the status code and message
             let (status_code, Json(message)) = result.unwrap_err();
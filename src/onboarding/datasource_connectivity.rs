@@ -6,3 +6,5 @@
 pub mod checker;
 pub mod datastore;
 pub mod filestore;
+pub mod limits;
+pub mod report;
@@ -6,7 +6,8 @@
 
 //! This module defines the Checker structs for the 'filestore' and 'datastore' data sources and the implementation
 //! of the CheckerTrait for these structs.
-//! The CheckerTrait defines the connectivity function that checks the connectivity to the data source.
+//! The CheckerTrait defines the connectivity function that checks the connectivity to the data source, and returns
+//! the report of its findings.
 //! The CheckerTrait is implemented for the FilestoreChecker and DatastoreChecker structs.
 //! The FilestoreChecker struct is used to check the connectivity to 'filestore' data sources.
 //! The DatastoreChecker struct is used to check the connectivity to 'datastore' data sources.
@@ -14,6 +15,7 @@
 
 use crate::onboarding::datasource_connectivity::datastore::datastore_check_connectivity;
 use crate::onboarding::datasource_connectivity::filestore::filestore_check_connectivity;
+use crate::onboarding::datasource_connectivity::report::ConnectivityReport;
 use crate::onboarding::schema::app_onboarding_request::AppDataSource;
 use crate::service::state::AppState;
 use axum::{http::StatusCode, Json};
@@ -31,7 +33,7 @@ pub trait CheckerTrait {
         key: &str,
         app_state: &Arc<AppState>,
         app_data_source: &AppDataSource,
-    ) -> Result<Vec<String>, (StatusCode, Json<serde_json::Value>)>;
+    ) -> Result<ConnectivityReport, (StatusCode, Json<serde_json::Value>)>;
 }
 
 impl CheckerTrait for FilestoreChecker {
@@ -40,7 +42,7 @@ impl CheckerTrait for FilestoreChecker {
         key: &str,
         app_state: &Arc<AppState>,
         app_data_source: &AppDataSource,
-    ) -> Result<Vec<String>, (StatusCode, Json<serde_json::Value>)> {
+    ) -> Result<ConnectivityReport, (StatusCode, Json<serde_json::Value>)> {
         filestore_check_connectivity(key, app_state, app_data_source).await
     }
 }
@@ -51,7 +53,7 @@ impl CheckerTrait for DatastoreChecker {
         key: &str,
         app_state: &Arc<AppState>,
         app_data_source: &AppDataSource,
-    ) -> Result<Vec<String>, (StatusCode, Json<serde_json::Value>)> {
+    ) -> Result<ConnectivityReport, (StatusCode, Json<serde_json::Value>)> {
         datastore_check_connectivity(key, app_state, app_data_source).await
     }
 }
@@ -89,10 +91,8 @@ mod tests {
         println!("results:{:?}\n", result);
 
         let result = result.unwrap();
-        // Assert that the result is a Vec of length 0
-        for res in result.iter() {
-            assert_eq!(res.len(), 0);
-        }
+        // Assert that no connectivity error is found
+        assert_eq!(result.errors.len(), 0);
     }
 
     #[tokio::test]
@@ -118,8 +118,8 @@ mod tests {
             .connectivity("opensearch", &app_state, &app_data_source)
             .await;
 
-        let error_count_rds = result_rds.unwrap().len();
-        let error_count_opensearch = result_opensearch.unwrap().len();
+        let error_count_rds = result_rds.unwrap().errors.len();
+        let error_count_opensearch = result_opensearch.unwrap().errors.len();
         let total_error_count = error_count_rds + error_count_opensearch;
 
         // Assert that the result is an empty Vec
@@ -150,8 +150,8 @@ mod tests {
             .connectivity("opensearch", &app_state, &app_data_source)
             .await;
 
-        let error_count_rds = result_rds.unwrap().len();
-        let error_count_opensearch = result_opensearch.unwrap().len();
+        let error_count_rds = result_rds.unwrap().errors.len();
+        let error_count_opensearch = result_opensearch.unwrap().errors.len();
         let total_error_count = error_count_rds + error_count_opensearch;
 
         assert_ne!(total_error_count, 0);
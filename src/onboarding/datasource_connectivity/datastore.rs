@@ -8,8 +8,9 @@
 //! The functions are used by the onboarding service to check the connectivity to the RDS databases and to get the data for sending to Kafka.
 //!
 
+use crate::onboarding::datasource_connectivity::report::ConnectivityReport;
 use crate::onboarding::schema::app_onboarding_request::AppDataSource;
-use crate::onboarding::schema::app_onboarding_request::DataStore;
+use crate::onboarding::schema::app_onboarding_request::{DataStore, ValidationMode};
 use crate::service::state::AppState;
 use authentication_utils::AwsAuthentication;
 use axum::{http::StatusCode, Json};
@@ -20,13 +21,13 @@ use std::sync::Arc;
 use tracing::{debug, instrument};
 
 #[instrument(skip_all)]
-/// Function to check the connectivity to the RDS databases. Returns a vector of strings representing
-/// the connectivity check failures, if any.
+/// Function to check the connectivity to the RDS databases. Returns the report of the connectivity check
+/// failures, relaxed by the validation mode of each database.
 pub async fn datastore_check_connectivity(
     data_source: &str,
     app_state: &Arc<AppState>,
     app_datasource: &AppDataSource,
-) -> Result<Vec<String>, (StatusCode, Json<serde_json::Value>)> {
+) -> Result<ConnectivityReport, (StatusCode, Json<serde_json::Value>)> {
     // Get the databases of a particular type like mysql, postgres etc.
     let datastore = &app_datasource.datastore;
     let databases = match datastore.get(data_source) {
@@ -53,7 +54,7 @@ pub async fn datastore_check_connectivity(
         Some(aws) => aws_auth_builder
             .set_aws_access_key_id(aws.access_key_id.clone())
             .set_aws_secret_access_key(aws.secret_access_key.clone())
-            .set_aws_default_region(aws.default_region.clone()),
+            .set_aws_default_region(aws.default_region.clone().map(String::from)),
         None => aws_auth_builder,
     };
 
@@ -80,17 +81,31 @@ pub async fn datastore_check_connectivity(
         .clone();
 
     // TODO: Check if cloning can be avoided. also check for potential improvements
-    let connectivity_errors = futures::stream::iter(
-        databases
-            .clone()
-            .into_iter()
-            .map(|db| process_database(timeout_sec.clone(), aws_auth.clone(), db)),
-    )
+    let connectivity_report = futures::stream::iter(databases.clone().into_iter().map(|db| {
+        let timeout_sec = timeout_sec.clone();
+        let aws_auth = aws_auth.clone();
+        async move {
+            let identifier = format!("{}://{}:{}/{}", db.db_type, db.host, db.port, db.database);
+            let validation_mode = db.validation_mode;
+            if validation_mode == ValidationMode::Skip {
+                return ConnectivityReport::skipped(&identifier);
+            }
+            let errors = process_database(timeout_sec, aws_auth, db).await;
+            ConnectivityReport::from_errors(errors.into_iter().collect())
+                .with_validation_mode(validation_mode, &identifier)
+                .recorded(&identifier)
+        }
+    }))
     .buffer_unordered(app_state.app_settings.datastore.max_concurrent_requests)
-    .filter_map(|result| async move { result })
-    .collect::<Vec<_>>()
+    .fold(
+        ConnectivityReport::default(),
+        |mut report, result| async move {
+            report.merge(result);
+            report
+        },
+    )
     .await;
-    Ok(connectivity_errors)
+    Ok(connectivity_report)
 }
 
 /// Function to process the each database. Returns connectivity check failure as a string, if any.
@@ -6,79 +6,325 @@
 
 //! This module contains the functions to check the connectivity to the filestore URLs concurrently. For s3, it checks the
 //! bucket and object connectivity (wildcard and non-wildcard) and generates the data for sending to Kafka.
-//! It returns the connectivity check failures, if any.
+//! It returns the connectivity check failures, the warnings (empty paths, files with unsupported extensions) and
+//! the estimated size of the files to ingest.
+//! The `listing_mode` of a URL selects how its objects are validated: `objects` fetches the object or lists the
+//! objects matched by a wildcard, `summary` summarizes all the ListObjectsV2 pages under the prefix and `inventory`
+//! reads the CSV S3 Inventory report of the bucket, so buckets with millions of objects are validated without
+//! per-object requests.
+//! The file types are checked against the registry of `crate::service::file_types`, with the `file_types` overrides
+//! of the app: the type of the files without extension is sniffed from their content, and the files above the
+//! maximum size of their type are skipped. In the `warn` file type mode, a URL of an unsupported file type is reported
+//! as a warning instead of an error.
+//! The schema of the first CSV and Parquet files of each URL is previewed (see `crate::onboarding::schema_preview`).
 //!
 
-use crate::onboarding::schema::app_onboarding_request::{AppDataSource, FileStore};
+use crate::onboarding::datasource_connectivity::report::ConnectivityReport;
+use crate::onboarding::schema::app_onboarding_request::{
+    AppDataSource, FileStore, FileTypeMode, ListingMode, ValidationMode,
+};
+use crate::onboarding::schema::schema_preview::TabularFormat;
+use crate::onboarding::schema_preview::{
+    preview_object, SchemaPreviewOptions, MAX_PREVIEWED_FILES,
+};
+use crate::service::file_types::{
+    file_extension, sniff_mime_type, FileTypeCheck, FileTypes, SNIFF_LENGTH,
+};
+use crate::service::object_store::{object_store, ObjectStore};
 use crate::service::state::AppState;
-use aws_config::meta::region::RegionProviderChain;
-use aws_config::{BehaviorVersion, Region};
 use axum::{http::StatusCode, Json};
+use flate2::read::GzDecoder;
 use futures::stream::StreamExt;
 use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use std::io::Read;
 use std::sync::Arc;
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
 use url::Url;
 
+/// Maximum number of objects listed per wildcard URL to estimate the ingestion size.
+const MAX_LISTED_OBJECTS: usize = 10_000;
+
+/// Prefix of the ARN of the destination bucket in an S3 Inventory manifest.
+const S3_ARN_PREFIX: &str = "arn:aws:s3:::";
+
 #[instrument(skip_all)]
-/// Function to check the connectivity to the filestore URLs concurrently. Returns a vector of strings representing
-/// the connectivity check failures, if any.
+/// Function to check the connectivity to the filestore URLs concurrently. Returns the report of the connectivity
+/// check failures, the warnings and the estimated size of the files to ingest.
 pub async fn filestore_check_connectivity(
     data_source: &str,
     app_state: &Arc<AppState>,
     app_datasource: &AppDataSource,
-) -> Result<Vec<String>, (StatusCode, Json<serde_json::Value>)> {
+) -> Result<ConnectivityReport, (StatusCode, Json<serde_json::Value>)> {
     // Get the URLs for a particular 'filestore' data source.
     let data = filestore_get_data(data_source, app_datasource);
-    let mut s3_urls = Vec::new();
-    for s3 in data {
-        s3_urls.push(s3.url.clone());
-    }
+    let s3_urls: Vec<&String> = data.iter().map(|s3| &s3.url).collect();
     info!("Checking connectivity for: {:?}", s3_urls);
 
-    // Instantiating S3 client. If more data sources are added to 'filestore' in future, may need to create new client for each.
-    let s3_client = create_s3_client(None).await;
+    // Instantiating S3 client, the local buckets in the local development mode. If more data sources are added to
+    // 'filestore' in future, may need to create new client for each.
+    let s3_client = object_store(app_state).await;
+    let file_types = app_state
+        .file_types()
+        .with_overrides(app_datasource.file_types.as_ref());
+    let file_types = &file_types;
+    let schema_preview = &app_state.options::<SchemaPreviewOptions>();
 
-    // Process the URLs concurrently using a buffer_unordered stream.
-    // Process the URLs concurrently using a buffer_unordered stream.
-    let connectivity_errors = futures::stream::iter(
-        s3_urls
-            .into_iter()
-            .map(|s3_url| process_url(s3_client.clone(), app_state, s3_url)),
-    )
-    .buffer_unordered(app_state.app_settings.aws_s3.max_concurrent_requests)
-    .filter_map(|result| async move {
-        match &result {
-            Some(e) => error!("{}", e),
-            None => debug!("No connectivity errors found"),
+    // Process the URLs concurrently using a buffer_unordered stream, with the validation mode of each URL.
+    let connectivity_report = futures::stream::iter(data.into_iter().map(|s3| {
+        let s3_client = s3_client.clone();
+        async move {
+            if s3.validation_mode == ValidationMode::Skip {
+                return ConnectivityReport::skipped(&s3.url);
+            }
+            let report = match s3.listing_mode {
+                ListingMode::Objects => {
+                    process_url(s3_client, file_types, schema_preview, s3.url.clone()).await
+                }
+                ListingMode::Summary => {
+                    process_url_summary(s3_client, file_types, schema_preview, &s3.url).await
+                }
+                ListingMode::Inventory => {
+                    process_url_inventory(
+                        s3_client,
+                        file_types,
+                        schema_preview,
+                        &s3.url,
+                        s3.inventory_manifest.as_deref(),
+                    )
+                    .await
+                }
+            };
+            report
+                .with_validation_mode(s3.validation_mode, &s3.url)
+                .recorded(&s3.url)
         }
-        result
-    })
-    .collect::<Vec<_>>()
+    }))
+    .buffer_unordered(app_state.app_settings.aws_s3.max_concurrent_requests)
+    .fold(
+        ConnectivityReport::default(),
+        |mut report, result| async move {
+            result.errors.iter().for_each(|e| error!("{}", e));
+            result.warnings.iter().for_each(|w| warn!("{}", w));
+            if result.errors.is_empty() {
+                debug!("No connectivity errors found");
+            }
+            report.merge(result);
+            report
+        },
+    )
     .await;
-    Ok(connectivity_errors)
+    Ok(connectivity_report)
 }
 
-/// Create an S3 client with the specified region. If region is not provided, it uses the default region.
-async fn create_s3_client(region_str: Option<String>) -> Arc<aws_sdk_s3::Client> {
-    let region_provider = match region_str {
-        Some(region) => RegionProviderChain::first_try(Region::new(region)),
-        None => RegionProviderChain::default_provider(),
-    };
+/// Report of a URL of an unsupported file type or an oversized file: an error in the `error` file type mode, a
+/// warning in the `warn` mode, the URL being skipped.
+fn unsupported_file_type(file_types: &FileTypes, message: String) -> ConnectivityReport {
+    match file_types.mode {
+        FileTypeMode::Error => ConnectivityReport::error(format!("Error: {}", message)),
+        FileTypeMode::Warn => {
+            ConnectivityReport::warning(format!("Warning: {} The URL is skipped.", message))
+        }
+    }
+}
 
-    let s3_config = aws_config::defaults(BehaviorVersion::latest())
-        .region(region_provider)
-        .load()
-        .await;
-    Arc::new(aws_sdk_s3::Client::new(&s3_config))
+/// Sniffs the file type of an object without extension from its first bytes. Returns the supported extension of its
+/// content, or the reason it is not supported.
+async fn sniff_object(
+    s3_client: &Arc<dyn ObjectStore>,
+    file_types: &FileTypes,
+    bucket: &str,
+    key: &str,
+) -> Result<&'static str, String> {
+    if file_types.sniff_sample_size == 0 {
+        return Err("the files without extension are not sniffed".to_string());
+    }
+    let head = s3_client
+        .read_object_head(bucket, key, SNIFF_LENGTH)
+        .await
+        .map_err(|e| format!("failed to read its content: {}", e))?;
+    let mime_type =
+        sniff_mime_type(&head).ok_or_else(|| "its content is not recognized".to_string())?;
+    file_types
+        .sniffed_extension(mime_type)
+        .ok_or_else(|| format!("its content type '{}' is not supported", mime_type))
 }
 
-/// Function to process each S3 URL. Returns connectivity check failure as a string, if any.
-async fn process_url(
-    s3_client: Arc<aws_sdk_s3::Client>,
-    app_state: &Arc<AppState>,
-    s3_url: String,
-) -> Option<String> {
+/// Previews the schema of the tabular files of a URL. The files which can't be previewed are reported as warnings.
+async fn preview_schemas(
+    s3_client: &Arc<dyn ObjectStore>,
+    schema_preview: &SchemaPreviewOptions,
+    s3_url: &str,
+    bucket: &str,
+    files: Vec<(String, u64, TabularFormat)>,
+) -> ConnectivityReport {
+    let mut report = ConnectivityReport::default();
+    if !schema_preview.enabled {
+        return report;
+    }
+    for (key, size, format) in files {
+        match preview_object(
+            s3_client,
+            schema_preview,
+            s3_url,
+            bucket,
+            &key,
+            size,
+            format,
+        )
+        .await
+        {
+            Ok(preview) => report.schema_previews.push(preview),
+            Err(e) => report.merge(ConnectivityReport::warning(format!(
+                "Warning: The schema of '{}' in URL '{}' could not be previewed: {}.",
+                key, s3_url, e
+            ))),
+        }
+    }
+    report
+}
+
+/// Objects matched by a filestore URL, accumulated page by page.
+#[derive(Debug, Default, PartialEq)]
+struct MatchedObjects {
+    /// Number of listed objects, folders and files of other extensions included.
+    listed: u64,
+    /// Number of matched files with unsupported extensions.
+    unsupported: u64,
+    /// Number of matched files above the maximum size of their file type.
+    oversized: u64,
+    /// Number of matched files with supported extensions.
+    files: u64,
+    /// Total size in bytes of the matched files with supported extensions.
+    size: u64,
+    /// Keys and sizes of the matched files without extension to sniff, at most `sniff_sample_size` of them.
+    extensionless: Vec<(String, u64)>,
+    /// Number of matched files without extension beyond the sniffed ones, counted as supported.
+    unsniffed: u64,
+    /// Keys, sizes and formats of the first matched tabular files, at most `MAX_PREVIEWED_FILES` of them.
+    tabular: Vec<(String, u64, TabularFormat)>,
+}
+
+impl MatchedObjects {
+    /// Adds a listed object. Only the files with the `extension` of the URL, if any, are matched. The files without
+    /// extension are kept to be sniffed.
+    fn add(&mut self, key: &str, size: u64, extension: &str, file_types: &FileTypes) {
+        self.listed += 1;
+        if key.ends_with('/') {
+            return;
+        }
+        let file_type = file_extension(key);
+        if !extension.is_empty() && file_type != Some(extension) {
+            return;
+        }
+        match file_type {
+            Some(file_type) => self.add_file(key, file_type, size, file_types),
+            None if file_types.sniff_sample_size == 0 => self.unsupported += 1,
+            None if self.extensionless.len() < file_types.sniff_sample_size => {
+                self.extensionless.push((key.to_string(), size))
+            }
+            None => {
+                self.unsniffed += 1;
+                self.files += 1;
+                self.size += size;
+            }
+        }
+    }
+
+    /// Adds a matched file of a known file type. The first tabular files are kept to be previewed.
+    fn add_file(&mut self, key: &str, file_type: &str, size: u64, file_types: &FileTypes) {
+        match file_types.check(file_type, size) {
+            FileTypeCheck::Supported => {
+                self.files += 1;
+                self.size += size;
+                if let Some(format) = TabularFormat::of(file_type) {
+                    if self.tabular.len() < MAX_PREVIEWED_FILES {
+                        self.tabular.push((key.to_string(), size, format));
+                    }
+                }
+            }
+            FileTypeCheck::Unsupported => self.unsupported += 1,
+            FileTypeCheck::TooLarge { .. } => self.oversized += 1,
+        }
+    }
+
+    /// Sniffs the file type of the matched files without extension.
+    async fn sniff(
+        &mut self,
+        s3_client: &Arc<dyn ObjectStore>,
+        bucket: &str,
+        file_types: &FileTypes,
+    ) {
+        for (key, size) in std::mem::take(&mut self.extensionless) {
+            match sniff_object(s3_client, file_types, bucket, &key).await {
+                Ok(file_type) => self.add_file(&key, file_type, size, file_types),
+                Err(e) => {
+                    debug!("Skipping '{}' in bucket '{}': {}", key, bucket, e);
+                    self.unsupported += 1;
+                }
+            }
+        }
+    }
+
+    /// Previews the schema of the matched tabular files.
+    async fn preview(
+        &mut self,
+        s3_client: &Arc<dyn ObjectStore>,
+        bucket: &str,
+        s3_url: &str,
+        schema_preview: &SchemaPreviewOptions,
+    ) -> ConnectivityReport {
+        let files = std::mem::take(&mut self.tabular);
+        preview_schemas(s3_client, schema_preview, s3_url, bucket, files).await
+    }
+
+    /// Report of the matched objects. Empty paths, files with unsupported extensions and oversized files are reported
+    /// as warnings.
+    fn into_report(self, s3_url: &str, folder: &str, bucket: &str) -> ConnectivityReport {
+        if self.listed == 0 {
+            return ConnectivityReport::warning(format!(
+                "Warning: Path '{}' is empty in bucket '{}'. Files added later are ingested on the next sync.",
+                folder, bucket
+            ));
+        }
+        let mut report = ConnectivityReport::files(self.files, self.size);
+        // We are not failing on unsupported file types existing under s3://bucket/*, s3://bucket/folder/*, etc.
+        if self.unsupported > 0 {
+            report.merge(ConnectivityReport::warning(format!(
+                "Warning: {} file(s) with unsupported extensions found in URL '{}' are skipped.",
+                self.unsupported, s3_url
+            )));
+        }
+        if self.oversized > 0 {
+            report.merge(ConnectivityReport::warning(format!(
+                "Warning: {} file(s) above the maximum size of their file type found in URL '{}' are skipped.",
+                self.oversized, s3_url
+            )));
+        }
+        if self.unsniffed > 0 {
+            report.merge(ConnectivityReport::warning(format!(
+                "Warning: The file type of {} file(s) without extension found in URL '{}' was not sniffed. They are ingested if supported.",
+                self.unsniffed, s3_url
+            )));
+        }
+        report
+    }
+}
+
+/// Splits the object of a URL into the path up to the wildcard and the extension after it, if any.
+fn split_wildcard(object: &str) -> (&str, &str) {
+    let parts: Vec<&str> = object.split('*').collect();
+    let folder = parts.first().unwrap_or(&"");
+    let extension = parts.get(1).unwrap_or(&"").trim_start_matches('.');
+    (folder, extension)
+}
+
+/// Function to connect to the bucket of an S3 URL. Returns the S3 client of the region of the bucket, the bucket
+/// and the decoded object key, or the report of the connectivity failure.
+async fn connect_to_bucket(
+    s3_client: Arc<dyn ObjectStore>,
+    s3_url: &str,
+) -> Result<(Arc<dyn ObjectStore>, String, String), ConnectivityReport> {
     // URL encode the s3_url string
     let encoded_url = s3_url.replace(' ', "%20");
     info!("Processing S3 URL: '{}'", encoded_url);
@@ -87,16 +333,16 @@ async fn process_url(
         Err(e) => {
             let url_result = format!("Error: Failed to parse S3 URL '{}': {}\n", encoded_url, e);
             debug!("{}", url_result);
-            return Some(url_result);
+            return Err(ConnectivityReport::error(url_result));
         }
     };
 
     let bucket = if let Some(host) = parsed_url.host_str() {
-        host
+        host.to_string()
     } else {
         let bucket_parse_result = format!("Failed to get bucket name from S3 URL '{}'", s3_url);
         debug!("{}", bucket_parse_result);
-        return Some(bucket_parse_result);
+        return Err(ConnectivityReport::error(bucket_parse_result));
     };
 
     // URL decode the object key
@@ -109,35 +355,12 @@ async fn process_url(
         bucket, object
     );
 
-    // Check the connectivity by fetching region of S3 bucket
-    match s3_client.get_bucket_location().bucket(bucket).send().await {
-        Ok(response) => {
+    // Check the connectivity to the bucket, getting the client of the region of the bucket
+    match s3_client.connect(&bucket).await {
+        Ok(s3_client) => {
             let bucket_result = format!("Successfully connected to S3 bucket: {}", bucket);
             debug!("{}", bucket_result);
-
-            // Extract the region from the response
-            let region = match &response.location_constraint {
-                Some(region) if !region.to_string().is_empty() => region.to_string(),
-                _ => "us-east-1".to_string(),
-            };
-
-            // Create a new S3 client if the region of the bucket is different from the region of the first S3 client
-            let s3_client = if s3_client
-                .config()
-                .region()
-                .unwrap_or(&Region::new("us-east-1"))
-                != &Region::new(region.clone())
-            {
-                create_s3_client(Some(region)).await
-            } else {
-                s3_client
-            };
-
-            if object.contains('*') {
-                handle_wildcard_object(s3_client, app_state, s3_url, bucket, &object).await
-            } else {
-                handle_non_wildcard_object(s3_client, app_state, s3_url, bucket, &object).await
-            }
+            Ok((s3_client, bucket, object))
         }
         Err(e) => {
             let bucket_result = format!(
@@ -145,111 +368,347 @@ async fn process_url(
                 bucket, s3_url, e
             );
             debug!("{}", bucket_result);
-            Some(bucket_result)
+            Err(ConnectivityReport::error(bucket_result))
+        }
+    }
+}
+
+/// Function to process each S3 URL. Returns the report of the connectivity check of the URL.
+async fn process_url(
+    s3_client: Arc<dyn ObjectStore>,
+    file_types: &FileTypes,
+    schema_preview: &SchemaPreviewOptions,
+    s3_url: String,
+) -> ConnectivityReport {
+    let (s3_client, bucket, object) = match connect_to_bucket(s3_client, &s3_url).await {
+        Ok(connection) => connection,
+        Err(report) => return report,
+    };
+    if object.contains('*') {
+        handle_wildcard_object(
+            s3_client,
+            file_types,
+            schema_preview,
+            s3_url,
+            &bucket,
+            &object,
+        )
+        .await
+    } else {
+        handle_non_wildcard_object(
+            s3_client,
+            file_types,
+            schema_preview,
+            s3_url,
+            &bucket,
+            &object,
+        )
+        .await
+    }
+}
+
+/// Function to process an S3 URL in the `summary` listing mode. All the ListObjectsV2 pages under the path up to the
+/// wildcard are summarized, without fetching any object nor keeping the keys in memory.
+async fn process_url_summary(
+    s3_client: Arc<dyn ObjectStore>,
+    file_types: &FileTypes,
+    schema_preview: &SchemaPreviewOptions,
+    s3_url: &str,
+) -> ConnectivityReport {
+    let (s3_client, bucket, object) = match connect_to_bucket(s3_client, s3_url).await {
+        Ok(connection) => connection,
+        Err(report) => return report,
+    };
+    let (folder, extension) = split_wildcard(&object);
+    if !extension.is_empty() && !file_types.is_supported(extension) {
+        return unsupported_file_type(
+            file_types,
+            format!(
+                "Unsupported file extension(s) found in URL '{}': .{}",
+                s3_url, extension
+            ),
+        );
+    }
+
+    let mut matched_objects = MatchedObjects::default();
+    let mut continuation_token = None;
+    loop {
+        let output = match s3_client
+            .list_objects(&bucket, folder, continuation_token)
+            .await
+        {
+            Ok(output) => output,
+            Err(e) => {
+                return ConnectivityReport::error(format!(
+                    "Error: Failed to list objects in bucket '{}': {}",
+                    bucket, e
+                ));
+            }
+        };
+        for (key, size) in output.objects {
+            matched_objects.add(&key, size, extension, file_types);
+        }
+        continuation_token = output.next_continuation_token;
+        if continuation_token.is_none() {
+            break;
         }
     }
+    matched_objects.sniff(&s3_client, &bucket, file_types).await;
+    let previews = matched_objects
+        .preview(&s3_client, &bucket, s3_url, schema_preview)
+        .await;
+    info!(
+        "Summarized {} object(s) in URL '{}'.",
+        matched_objects.listed, s3_url
+    );
+    let mut report = matched_objects.into_report(s3_url, folder, &bucket);
+    report.merge(previews);
+    report
+}
+
+/// Manifest of an S3 Inventory report.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct InventoryManifest {
+    source_bucket: String,
+    destination_bucket: String,
+    file_format: String,
+    file_schema: String,
+    files: Vec<InventoryFile>,
 }
 
+/// Data file of an S3 Inventory report, relative to its destination bucket.
+#[derive(Deserialize, Debug)]
+struct InventoryFile {
+    key: String,
+}
+
+/// Parses a line of a CSV S3 Inventory data file into the key and size of the object. The keys of the report are
+/// URL encoded and the size of delete markers is empty.
+fn parse_inventory_line(line: &str, key_index: usize, size_index: usize) -> Option<(String, u64)> {
+    let fields: Vec<&str> = line.trim().trim_matches('"').split("\",\"").collect();
+    let key = fields.get(key_index)?.replace('+', " ");
+    let key = percent_decode_str(&key).decode_utf8_lossy().into_owned();
+    let size = fields
+        .get(size_index)
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(0);
+    Some((key, size))
+}
+
+/// Function to process an S3 URL in the `inventory` listing mode. The objects under the path up to the wildcard are
+/// read from the CSV data files of the S3 Inventory report of the bucket, without any listing of the bucket.
+async fn process_url_inventory(
+    s3_client: Arc<dyn ObjectStore>,
+    file_types: &FileTypes,
+    schema_preview: &SchemaPreviewOptions,
+    s3_url: &str,
+    inventory_manifest: Option<&str>,
+) -> ConnectivityReport {
+    let Some(inventory_manifest) = inventory_manifest else {
+        return ConnectivityReport::error(format!(
+            "Error: No inventory_manifest provided for URL '{}' in the inventory listing mode.",
+            s3_url
+        ));
+    };
+    let (source_client, bucket, object) = match connect_to_bucket(s3_client.clone(), s3_url).await {
+        Ok(connection) => connection,
+        Err(report) => return report,
+    };
+    let (folder, extension) = split_wildcard(&object);
+    if !extension.is_empty() && !file_types.is_supported(extension) {
+        return unsupported_file_type(
+            file_types,
+            format!(
+                "Unsupported file extension(s) found in URL '{}': .{}",
+                s3_url, extension
+            ),
+        );
+    }
+
+    // Read the manifest of the inventory report
+    let (inventory_client, manifest_bucket, manifest_key) =
+        match connect_to_bucket(s3_client, inventory_manifest).await {
+            Ok(connection) => connection,
+            Err(report) => return report,
+        };
+    let manifest = match inventory_client
+        .read_object(&manifest_bucket, &manifest_key)
+        .await
+        .and_then(|bytes| {
+            serde_json::from_slice::<InventoryManifest>(&bytes).map_err(|e| e.to_string())
+        }) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            return ConnectivityReport::error(format!(
+                "Error: Failed to read inventory manifest '{}': {}",
+                inventory_manifest, e
+            ));
+        }
+    };
+    if manifest.source_bucket != bucket {
+        return ConnectivityReport::error(format!(
+            "Error: Inventory manifest '{}' reports bucket '{}', not the bucket of URL '{}'.",
+            inventory_manifest, manifest.source_bucket, s3_url
+        ));
+    }
+    let columns: Vec<&str> = manifest.file_schema.split(',').map(str::trim).collect();
+    let (Some(key_index), Some(size_index)) = (
+        columns.iter().position(|column| *column == "Key"),
+        columns.iter().position(|column| *column == "Size"),
+    ) else {
+        return ConnectivityReport::error(format!(
+            "Error: Inventory manifest '{}' must report the Key and Size fields.",
+            inventory_manifest
+        ));
+    };
+    if manifest.file_format != "CSV" {
+        return ConnectivityReport::error(format!(
+            "Error: Unsupported format '{}' of inventory manifest '{}'. Only CSV inventory reports are supported.",
+            manifest.file_format, inventory_manifest
+        ));
+    }
+
+    // Read the data files of the report, one at a time
+    let destination_bucket = manifest
+        .destination_bucket
+        .trim_start_matches(S3_ARN_PREFIX);
+    let mut matched_objects = MatchedObjects::default();
+    for file in &manifest.files {
+        let mut csv = String::new();
+        if let Err(e) = inventory_client
+            .read_object(destination_bucket, &file.key)
+            .await
+            .and_then(|bytes| {
+                GzDecoder::new(bytes.as_slice())
+                    .read_to_string(&mut csv)
+                    .map_err(|e| e.to_string())
+            })
+        {
+            return ConnectivityReport::error(format!(
+                "Error: Failed to read inventory file '{}' in bucket '{}': {}",
+                file.key, destination_bucket, e
+            ));
+        }
+        for (key, size) in csv
+            .lines()
+            .filter_map(|line| parse_inventory_line(line, key_index, size_index))
+            .filter(|(key, _)| key.starts_with(folder))
+        {
+            matched_objects.add(&key, size, extension, file_types);
+        }
+    }
+    matched_objects
+        .sniff(&source_client, &bucket, file_types)
+        .await;
+    let previews = matched_objects
+        .preview(&source_client, &bucket, s3_url, schema_preview)
+        .await;
+    info!(
+        "Read {} object(s) of URL '{}' from inventory manifest '{}'.",
+        matched_objects.listed, s3_url, inventory_manifest
+    );
+    let mut report = matched_objects.into_report(s3_url, folder, &bucket);
+    report.merge(previews);
+    report
+}
+
+/// Function to handle wildcard object. Lists the objects under the path up to the wildcard to estimate the size
+/// of the files to ingest. Empty paths and files with unsupported extensions are reported as warnings.
 async fn handle_wildcard_object(
-    s3_client: Arc<aws_sdk_s3::Client>,
-    app_state: &Arc<AppState>,
+    s3_client: Arc<dyn ObjectStore>,
+    file_types: &FileTypes,
+    schema_preview: &SchemaPreviewOptions,
     s3_url: String,
     bucket: &str,
     object: &str,
-) -> Option<String> {
-    let parts: Vec<&str> = object.split('*').collect();
-    let folder = parts.first().unwrap_or(&"");
-    let extension = parts.get(1).unwrap_or(&"").trim_start_matches('.');
+) -> ConnectivityReport {
+    let (folder, extension) = split_wildcard(object);
+
+    // Check any unsupported file type in url of the form s3://bucket/*.ext, s3://bucket/folder/*.ext, s3://bucket/folder/subfolder/*.ext, etc.
+    if !extension.is_empty() && !file_types.is_supported(extension) {
+        return unsupported_file_type(
+            file_types,
+            format!(
+                "Unsupported file extension(s) found in URL '{}': .{}",
+                s3_url, extension
+            ),
+        );
+    }
 
-    // If folder is not empty, check if the path up to the wildcard exists in the bucket
-    if !folder.is_empty() {
+    // List the objects under the path up to the wildcard, at most MAX_LISTED_OBJECTS of them
+    let mut matched_objects = MatchedObjects::default();
+    let mut continuation_token = None;
+    loop {
         let response = s3_client
-            .list_objects_v2()
-            .bucket(bucket)
-            .prefix(*folder)
-            .send()
+            .list_objects(bucket, folder, continuation_token)
             .await;
         match response {
             Ok(output) => {
-                println!("output: {:?}", output);
-                if output.contents.unwrap_or_else(Vec::new).is_empty() {
-                    return Some(format!(
-                        "Error: Path '{}' does not exist in bucket '{}'",
-                        folder, bucket
-                    ));
+                for (key, size) in output.objects {
+                    matched_objects.add(&key, size, extension, file_types);
                 }
+                continuation_token = output.next_continuation_token;
             }
             Err(e) => {
-                return Some(format!(
+                return ConnectivityReport::error(format!(
                     "Error: Failed to list objects in bucket '{}': {}",
                     bucket, e
                 ));
             }
         }
+        if continuation_token.is_none() || matched_objects.listed >= MAX_LISTED_OBJECTS as u64 {
+            break;
+        }
     }
 
-    let supported_file_types: Vec<&str> = app_state
-        .app_settings
-        .supported_file_types
-        .image
-        .iter()
-        .chain(app_state.app_settings.supported_file_types.text.iter())
-        .map(|file_type| file_type.as_str())
-        .collect();
-
-    // Check any unsupported file type in url of the form s3://bucket/*.ext, s3://bucket/folder/*.ext, s3://bucket/folder/subfolder/*.ext, etc.
-    // We are not checking any unsupported file types existing under s3://bucket/*, s3://bucket/folder/*, s3://bucket/folder/subfolder/*, etc.
-    if !extension.is_empty() && !supported_file_types.contains(&extension) {
-        Some(format!(
-            "Error: Unsupported file extension(s) found in URL '{}': .{}",
-            s3_url, extension
-        ))
-    } else {
-        None
+    matched_objects.sniff(&s3_client, bucket, file_types).await;
+    let previews = matched_objects
+        .preview(&s3_client, bucket, &s3_url, schema_preview)
+        .await;
+    let mut report = matched_objects.into_report(&s3_url, folder, bucket);
+    report.merge(previews);
+    if continuation_token.is_some() {
+        report.merge(ConnectivityReport::warning(format!(
+            "Warning: More than {} objects found in URL '{}'. The estimated ingestion size covers the first {}. Use the summary or inventory listing_mode for large buckets.",
+            MAX_LISTED_OBJECTS, s3_url, MAX_LISTED_OBJECTS
+        )));
     }
+    report
 }
 
-/// Function to handle non-wildcard object. Returns connectivity check failure as a string, if any.
+/// Function to handle non-wildcard object. The file type of an object without extension is sniffed from its content,
+/// and the schema of a tabular file is previewed. Returns the report of the connectivity check of the object.
 async fn handle_non_wildcard_object(
-    s3_client: Arc<aws_sdk_s3::Client>,
-    app_state: &Arc<AppState>,
+    s3_client: Arc<dyn ObjectStore>,
+    file_types: &FileTypes,
+    schema_preview: &SchemaPreviewOptions,
     s3_url: String,
     bucket: &str,
     object: &str,
-) -> Option<String> {
-    let extension = object.split('.').last().unwrap_or("");
-    let supported_file_types: Vec<&str> = app_state
-        .app_settings
-        .supported_file_types
-        .image
-        .iter()
-        .chain(app_state.app_settings.supported_file_types.text.iter())
-        .map(|file_type| file_type.as_str())
-        .collect();
+) -> ConnectivityReport {
+    let extension = file_extension(object);
 
     // Check if the extension is supported
-    if !extension.is_empty() && !supported_file_types.contains(&extension) {
-        return Some(format!(
-            "Error: Unsupported file extension(s) found in URL '{}': .{}",
-            s3_url, extension
-        ));
+    if let Some(extension) = extension.filter(|extension| !file_types.is_supported(extension)) {
+        return unsupported_file_type(
+            file_types,
+            format!(
+                "Unsupported file extension(s) found in URL '{}': .{}",
+                s3_url, extension
+            ),
+        );
     }
 
     // Check the connectivity to the S3 object
-    match s3_client
-        .get_object()
-        .bucket(bucket)
-        .key(object)
-        .send()
-        .await
-    {
-        Ok(_) => {
+    let size = match s3_client.object_size(bucket, object).await {
+        Ok(size) => {
             let object_result = format!(
                 "Successfully accessed '{}' in bucket '{}'\n",
                 object, bucket
             );
             debug!("{}", object_result);
-            None
+            size
         }
         Err(e) => {
             let object_result = format!(
@@ -257,7 +716,40 @@ async fn handle_non_wildcard_object(
                 object, bucket, s3_url, e
             );
             debug!("{}", object_result);
-            Some(object_result)
+            return ConnectivityReport::error(object_result);
+        }
+    };
+
+    let file_type = match extension {
+        Some(extension) => extension,
+        None => match sniff_object(&s3_client, file_types, bucket, object).await {
+            Ok(file_type) => file_type,
+            Err(e) => {
+                return unsupported_file_type(
+                    file_types,
+                    format!(
+                        "Unsupported file without extension in URL '{}': {}.",
+                        s3_url, e
+                    ),
+                );
+            }
+        },
+    };
+    match file_types.check(file_type, size) {
+        FileTypeCheck::TooLarge { max_size_bytes } => unsupported_file_type(
+            file_types,
+            format!(
+                "File '{}' in URL '{}' is {} bytes, above the maximum size of {} bytes of the .{} files.",
+                object, s3_url, size, max_size_bytes, file_type
+            ),
+        ),
+        _ => {
+            let mut report = ConnectivityReport::files(1, size);
+            if let Some(format) = TabularFormat::of(file_type) {
+                let files = vec![(object.to_string(), size, format)];
+                report.merge(preview_schemas(&s3_client, schema_preview, &s3_url, bucket, files).await);
+            }
+            report
         }
     }
 }
@@ -277,19 +769,19 @@ pub fn filestore_get_data(data_source: &str, app_datasource: &AppDataSource) ->
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::configuration::options::SettingsOptions;
+    use crate::configuration::settings::{FileTypesSettings, SupportedFileTypes};
+    use crate::onboarding::schema::app_onboarding_request::FileTypeOverrides;
+    use crate::service::object_store::{LocalObjectStore, S3ObjectStore};
     use crate::tests::test_get_appstate;
+    use std::collections::HashMap;
     use std::error::Error;
+    use tempfile::tempdir;
     use tokio::runtime::Runtime;
     use tracing_test::traced_test;
 
-    async fn test_get_s3_client() -> Result<Arc<aws_sdk_s3::Client>, Box<dyn Error>> {
-        let region_provider = RegionProviderChain::default_provider();
-        let s3_config = aws_config::defaults(BehaviorVersion::latest())
-            .region(region_provider)
-            .load()
-            .await;
-
-        let s3_client = aws_sdk_s3::Client::new(&s3_config);
+    async fn test_get_s3_client() -> Result<Arc<dyn ObjectStore>, Box<dyn Error>> {
+        let s3_client = S3ObjectStore::new(None).await;
         return Ok(Arc::new(s3_client));
     }
 
@@ -316,10 +808,9 @@ mod tests {
                 serde_json::from_str(app_data_source_json).unwrap();
 
             let result = filestore_check_connectivity("s3", &app_state, &app_data_source).await;
-            for res in result.clone().unwrap() {
-                assert!(!res.contains("Error"));
-            }
-            assert!(result.is_ok())
+            let report = result.unwrap();
+            assert!(report.errors.is_empty());
+            assert!(report.estimated_ingestion_size.unwrap() > 0)
         });
     }
 
@@ -346,11 +837,9 @@ mod tests {
                 serde_json::from_str(app_data_source_json).unwrap();
 
             let result = filestore_check_connectivity("s3", &app_state, &app_data_source).await;
-            for res in result.clone().unwrap() {
-                info!("res: {}", res);
-                assert!(!res.contains("Error"));
-            }
-            assert!(result.is_ok())
+            let report = result.unwrap();
+            info!("report: {:?}", report);
+            assert!(report.errors.is_empty())
         });
     }
 
@@ -363,9 +852,15 @@ mod tests {
             let s3_client = test_get_s3_client().await.unwrap();
             let s3_url =
                 "s3://tresleai-dev-unittest/2021-Laboratory-Procedures-508.pdf".to_string();
-            let result = process_url(s3_client, &app_state, s3_url).await;
+            let result = process_url(
+                s3_client,
+                &app_state.file_types(),
+                &SchemaPreviewOptions::from_settings(None),
+                s3_url,
+            )
+            .await;
 
-            assert!(result.is_none())
+            assert!(result.errors.is_empty())
         });
     }
 
@@ -380,10 +875,17 @@ mod tests {
             let bucket = "tresleai-dev-unittest";
             let s3_url = "s3://tresleai-dev-unittest/*.pdf".to_string();
             let object = "*.pdf";
-            let result =
-                handle_wildcard_object(s3_client, &app_state, s3_url, bucket, object).await;
+            let result = handle_wildcard_object(
+                s3_client,
+                &app_state.file_types(),
+                &SchemaPreviewOptions::from_settings(None),
+                s3_url,
+                bucket,
+                object,
+            )
+            .await;
 
-            assert!(result.is_none())
+            assert!(result.errors.is_empty())
         });
     }
 
@@ -398,10 +900,17 @@ mod tests {
             let bucket = "tresleai-dev-unittest";
             let s3_url = "s3://tresleai-dev-unittest/*.xxx".to_string();
             let object = "*.xxx";
-            let result =
-                handle_wildcard_object(s3_client, &app_state, s3_url, bucket, object).await;
+            let result = handle_wildcard_object(
+                s3_client,
+                &app_state.file_types(),
+                &SchemaPreviewOptions::from_settings(None),
+                s3_url,
+                bucket,
+                object,
+            )
+            .await;
 
-            assert!(result.is_some())
+            assert!(!result.errors.is_empty())
         });
     }
 
@@ -417,10 +926,17 @@ mod tests {
                 "s3://tresleai-dev-unittest/2020-Laboratory-Procedures-508.pdf".to_string();
             let bucket = "tresleai-dev-unittest";
             let object = "2021-Laboratory-Procedures-508.pdf";
-            let result =
-                handle_non_wildcard_object(s3_client, &app_state, s3_url, bucket, object).await;
+            let result = handle_non_wildcard_object(
+                s3_client,
+                &app_state.file_types(),
+                &SchemaPreviewOptions::from_settings(None),
+                s3_url,
+                bucket,
+                object,
+            )
+            .await;
 
-            assert!(result.is_none())
+            assert!(result.errors.is_empty())
         });
     }
 
@@ -434,13 +950,252 @@ mod tests {
             let s3_url = "s3://tresleai-dev-unittest/FileNotFound.pdf".to_string();
             let bucket = "tresleai-dev-unittest";
             let object = "FileNotFound.pdf";
-            let result =
-                handle_non_wildcard_object(s3_client, &app_state, s3_url, bucket, object).await;
+            let result = handle_non_wildcard_object(
+                s3_client,
+                &app_state.file_types(),
+                &SchemaPreviewOptions::from_settings(None),
+                s3_url,
+                bucket,
+                object,
+            )
+            .await;
 
-            assert!(result.is_some())
+            assert!(!result.errors.is_empty())
         });
     }
 
+    fn test_file_types(settings: Option<&FileTypesSettings>) -> FileTypes {
+        let supported_file_types = SupportedFileTypes {
+            image: vec!["png".to_string()],
+            text: vec!["pdf".to_string()],
+        };
+        FileTypes::from_settings(&supported_file_types, settings)
+    }
+
+    #[test]
+    fn test_success_matched_objects_into_report() {
+        let supported_file_types = test_file_types(None);
+        let mut matched_objects = MatchedObjects::default();
+        matched_objects.add("folder/", 0, "", &supported_file_types);
+        matched_objects.add("folder/a.pdf", 1024, "", &supported_file_types);
+        matched_objects.add("folder/b.png", 512, "", &supported_file_types);
+        matched_objects.add("folder/c.xxx", 2048, "", &supported_file_types);
+
+        let report = matched_objects.into_report("s3://bucket/folder/*", "folder/", "bucket");
+        assert!(report.errors.is_empty());
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.estimated_ingestion_size, Some(1536));
+        assert_eq!(report.object_count, Some(2));
+
+        // Only the files with the extension of the URL are matched
+        let mut matched_objects = MatchedObjects::default();
+        matched_objects.add("folder/a.pdf", 1024, "png", &supported_file_types);
+        assert_eq!(
+            matched_objects
+                .into_report("s3://bucket/folder/*.png", "folder/", "bucket")
+                .estimated_ingestion_size,
+            Some(0)
+        );
+
+        let report =
+            MatchedObjects::default().into_report("s3://bucket/empty/*", "empty/", "bucket");
+        assert_eq!(report.estimated_ingestion_size, None);
+        assert_eq!(report.warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_success_matched_objects_file_types() {
+        let settings = FileTypesSettings {
+            mode: None,
+            sniff_sample_size: Some(1),
+            max_size_mb: Some(HashMap::from([("pdf".to_string(), 1)])),
+        };
+        let file_types = test_file_types(Some(&settings));
+        let mut matched_objects = MatchedObjects::default();
+        matched_objects.add("folder/a.pdf", 1024, "", &file_types);
+        matched_objects.add("folder/big.pdf", 2 * 1024 * 1024, "", &file_types);
+        matched_objects.add("folder/report", 2048, "", &file_types);
+        matched_objects.add("folder/notes", 512, "", &file_types);
+        assert_eq!(
+            matched_objects.extensionless,
+            vec![("folder/report".to_string(), 2048)]
+        );
+        assert_eq!(matched_objects.unsniffed, 1);
+
+        let report = matched_objects.into_report("s3://bucket/folder/*", "folder/", "bucket");
+        assert_eq!(report.object_count, Some(2));
+        assert_eq!(report.warnings.len(), 2);
+    }
+
+    #[test]
+    fn test_success_handle_non_wildcard_object_sniffed() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let root = tempdir().unwrap();
+            std::fs::create_dir_all(root.path().join("bucket/reports")).unwrap();
+            std::fs::write(root.path().join("bucket/reports/2024-q1"), b"%PDF-1.7\n").unwrap();
+            std::fs::write(root.path().join("bucket/reports/logo"), b"GIF89a\x01\0").unwrap();
+            let s3_client: Arc<dyn ObjectStore> = Arc::new(LocalObjectStore::new(root.path()));
+
+            // The extensionless PDF is supported
+            let result = handle_non_wildcard_object(
+                s3_client.clone(),
+                &test_file_types(None),
+                &SchemaPreviewOptions::from_settings(None),
+                "s3://bucket/reports/2024-q1".to_string(),
+                "bucket",
+                "reports/2024-q1",
+            )
+            .await;
+            assert!(result.errors.is_empty());
+            assert_eq!(result.object_count, Some(1));
+
+            // The GIF is not, an error in the error mode and a warning in the warn mode
+            let result = handle_non_wildcard_object(
+                s3_client.clone(),
+                &test_file_types(None),
+                &SchemaPreviewOptions::from_settings(None),
+                "s3://bucket/reports/logo".to_string(),
+                "bucket",
+                "reports/logo",
+            )
+            .await;
+            assert_eq!(result.errors.len(), 1);
+
+            let overrides = FileTypeOverrides {
+                mode: Some(FileTypeMode::Warn),
+                ..Default::default()
+            };
+            let warn_file_types = test_file_types(None).with_overrides(Some(&overrides));
+            let result = handle_non_wildcard_object(
+                s3_client.clone(),
+                &warn_file_types,
+                &SchemaPreviewOptions::from_settings(None),
+                "s3://bucket/reports/logo".to_string(),
+                "bucket",
+                "reports/logo",
+            )
+            .await;
+            assert!(result.errors.is_empty());
+            assert_eq!(result.warnings.len(), 1);
+            assert_eq!(result.object_count, None);
+
+            // A per-app override adds the GIF files
+            let overrides = FileTypeOverrides {
+                extensions: vec!["gif".to_string()],
+                ..Default::default()
+            };
+            let result = handle_non_wildcard_object(
+                s3_client,
+                &test_file_types(None).with_overrides(Some(&overrides)),
+                &SchemaPreviewOptions::from_settings(None),
+                "s3://bucket/reports/logo".to_string(),
+                "bucket",
+                "reports/logo",
+            )
+            .await;
+            assert!(result.errors.is_empty());
+            assert_eq!(result.object_count, Some(1));
+        });
+    }
+
+    #[test]
+    fn test_success_handle_wildcard_object_schema_previews() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let root = tempdir().unwrap();
+            std::fs::create_dir_all(root.path().join("bucket/tables")).unwrap();
+            std::fs::write(root.path().join("bucket/tables/a.csv"), b"id,name\n1,x\n").unwrap();
+            std::fs::write(root.path().join("bucket/tables/b.csv"), b"").unwrap();
+            let s3_client: Arc<dyn ObjectStore> = Arc::new(LocalObjectStore::new(root.path()));
+            let supported_file_types = SupportedFileTypes {
+                image: Vec::new(),
+                text: vec!["csv".to_string()],
+            };
+
+            // The empty file has no header row, reported as a warning
+            let result = handle_wildcard_object(
+                s3_client.clone(),
+                &FileTypes::from_settings(&supported_file_types, None),
+                &SchemaPreviewOptions::from_settings(None),
+                "s3://bucket/tables/*".to_string(),
+                "bucket",
+                "tables/*",
+            )
+            .await;
+            assert!(result.errors.is_empty());
+            assert_eq!(result.object_count, Some(2));
+            assert_eq!(result.schema_previews.len(), 1);
+            assert_eq!(result.schema_previews[0].object, "s3://bucket/tables/a.csv");
+            assert_eq!(result.warnings.len(), 1);
+
+            // Disabled previews
+            let result = handle_wildcard_object(
+                s3_client,
+                &FileTypes::from_settings(&supported_file_types, None),
+                &SchemaPreviewOptions {
+                    enabled: false,
+                    sample_bytes: 1024,
+                },
+                "s3://bucket/tables/*".to_string(),
+                "bucket",
+                "tables/*",
+            )
+            .await;
+            assert!(result.schema_previews.is_empty());
+            assert!(result.warnings.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_success_parse_inventory_line() {
+        assert_eq!(
+            parse_inventory_line(
+                "\"bucket\",\"folder/annual+report%282023%29.pdf\",\"1024\"\n",
+                1,
+                2
+            ),
+            Some(("folder/annual report(2023).pdf".to_string(), 1024))
+        );
+        // Delete markers have no size
+        assert_eq!(
+            parse_inventory_line("\"bucket\",\"folder/a.pdf\",\"\"", 1, 2),
+            Some(("folder/a.pdf".to_string(), 0))
+        );
+        assert_eq!(parse_inventory_line("\"bucket\"", 1, 2), None);
+    }
+
+    #[test]
+    fn test_success_inventory_manifest_deserialization() {
+        let manifest_json = r#"
+        {
+            "sourceBucket": "example-bucket",
+            "destinationBucket": "arn:aws:s3:::example-inventory-bucket",
+            "version": "2016-11-30",
+            "creationTimestamp": "1719795600000",
+            "fileFormat": "CSV",
+            "fileSchema": "Bucket, Key, Size, LastModifiedDate",
+            "files": [
+                {
+                    "key": "example-bucket/config/data/inventory.csv.gz",
+                    "size": 2147,
+                    "MD5checksum": "f11166069f1990abeb9c97ace9cdfabc"
+                }
+            ]
+        }"#;
+        let manifest: InventoryManifest = serde_json::from_str(manifest_json).unwrap();
+        assert_eq!(manifest.source_bucket, "example-bucket");
+        assert_eq!(
+            manifest
+                .destination_bucket
+                .trim_start_matches(S3_ARN_PREFIX),
+            "example-inventory-bucket"
+        );
+        assert_eq!(manifest.files.len(), 1);
+    }
+
     #[test]
     /// Positive test case for filestore_get_data
     fn test_success_filestore_get_data() {
@@ -0,0 +1,106 @@
+/*
+ * Created Date:  Jul 04, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+
+//! This module checks the datasources of an onboarding request against the configured onboarding limits: the number
+//! and total size of the files matched by the filestore URLs, the number of tables of a datastore and the number of
+//! columns of a table. A mis-scoped wildcard like `s3://datalake/*` is rejected instead of flooding the ingestion
+//! pipeline. The files are counted during the connectivity checks; with the `objects` listing mode a wildcard URL is
+//! listed up to 10 000 objects, the `summary` and `inventory` listing modes count all of them.
+//!
+
+use crate::configuration::settings::OnboardingLimitsSettings;
+use crate::onboarding::datasource_connectivity::report::ConnectivityReport;
+use crate::onboarding::schema::app_onboarding_request::AppDataSource;
+
+/// Returns the limits exceeded by the datasources, as error messages.
+pub fn limit_violations(
+    limits: &OnboardingLimitsSettings,
+    report: &ConnectivityReport,
+    app_datasource: &AppDataSource,
+) -> Vec<String> {
+    let mut violations = Vec::new();
+    if let (Some(max_objects), Some(object_count)) = (limits.max_objects, report.object_count) {
+        if object_count > max_objects {
+            violations.push(format!(
+                "Error: The filestore URLs match {} files, above the limit of {} files. Narrow the URLs, e.g. replace a bucket wide wildcard with the prefixes to ingest.",
+                object_count, max_objects
+            ));
+        }
+    }
+    if let (Some(max_total_bytes), Some(total_bytes)) =
+        (limits.max_total_bytes, report.estimated_ingestion_size)
+    {
+        if total_bytes > max_total_bytes {
+            violations.push(format!(
+                "Error: The files matched by the filestore URLs total {} bytes, above the limit of {} bytes.",
+                total_bytes, max_total_bytes
+            ));
+        }
+    }
+    for datastore in app_datasource.datastore.values().flatten() {
+        let identifier = format!(
+            "{}://{}:{}/{}",
+            datastore.db_type, datastore.host, datastore.port, datastore.database
+        );
+        if let Some(max_tables) = limits.max_tables {
+            if datastore.tables.len() > max_tables {
+                violations.push(format!(
+                    "Error: Datastore '{}' has {} tables, above the limit of {} tables.",
+                    identifier,
+                    datastore.tables.len(),
+                    max_tables
+                ));
+            }
+        }
+        if let Some(max_columns) = limits.max_columns {
+            for table in &datastore.tables {
+                let column_count = table.columns.as_ref().map_or(0, Vec::len);
+                if column_count > max_columns {
+                    violations.push(format!(
+                        "Error: Table '{}' of datastore '{}' has {} columns, above the limit of {} columns.",
+                        table.name, identifier, column_count, max_columns
+                    ));
+                }
+            }
+        }
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_success_limit_violations() {
+        let limits = OnboardingLimitsSettings {
+            max_objects: Some(100),
+            max_total_bytes: Some(1024),
+            max_tables: Some(10),
+            max_columns: Some(10),
+        };
+        let app_datasource = AppDataSource {
+            filestore: HashMap::new(),
+            datastore: HashMap::new(),
+            file_types: None,
+        };
+
+        let report = ConnectivityReport::files(100, 1024);
+        assert!(limit_violations(&limits, &report, &app_datasource).is_empty());
+
+        let report = ConnectivityReport::files(101, 2048);
+        assert_eq!(limit_violations(&limits, &report, &app_datasource).len(), 2);
+
+        // Unset limits are not enforced
+        let violations = limit_violations(
+            &OnboardingLimitsSettings::default(),
+            &report,
+            &app_datasource,
+        );
+        assert!(violations.is_empty());
+    }
+}
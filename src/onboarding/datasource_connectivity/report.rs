@@ -0,0 +1,246 @@
+/*
+ * Created Date:  Jul 02, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+
+//! This module defines the ConnectivityReport, the findings of the connectivity checks of the data sources.
+//! Errors fail the onboarding request. Warnings (empty prefixes, files with unsupported extensions, ...) are
+//! non-fatal and returned to the caller with the estimated size of the files to ingest.
+//! The `validation_mode` of a data source relaxes its findings: in `warn` mode its errors become warnings, and
+//! in `skip` mode it is not checked at all.
+//! The report keeps the outcome of every checked URL/database, reported by the validation jobs, and the schema
+//! previews of the tabular files of the filestore URLs.
+//!
+
+use crate::onboarding::schema::app_onboarding_request::ValidationMode;
+use crate::onboarding::schema::schema_preview::SchemaPreview;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Outcome of the connectivity check of a single URL/database.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidationOutcome {
+    Ok,
+    Warning,
+    Error,
+    Skipped,
+}
+
+/// Result of the connectivity check of a single URL/database.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct DatasourceResult {
+    pub identifier: String,
+    pub outcome: ValidationOutcome,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<String>,
+}
+
+/// Findings of the connectivity checks of one or more data sources.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ConnectivityReport {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+    /// Estimated size in bytes of the files to ingest, `None` if no file was listed.
+    pub estimated_ingestion_size: Option<u64>,
+    /// Number of files to ingest, `None` if no file was listed.
+    pub object_count: Option<u64>,
+    /// Outcome of every checked URL/database.
+    pub results: Vec<DatasourceResult>,
+    /// Schema previews of the tabular files of the filestore URLs.
+    pub schema_previews: Vec<SchemaPreview>,
+}
+
+impl ConnectivityReport {
+    /// Report holding the given errors.
+    pub fn from_errors(errors: Vec<String>) -> Self {
+        ConnectivityReport {
+            errors,
+            ..Default::default()
+        }
+    }
+
+    /// Report holding a single error.
+    pub fn error(error: String) -> Self {
+        Self::from_errors(vec![error])
+    }
+
+    /// Report holding a single warning.
+    pub fn warning(warning: String) -> Self {
+        ConnectivityReport {
+            warnings: vec![warning],
+            ..Default::default()
+        }
+    }
+
+    /// Report of files of the given size in bytes, without findings.
+    pub fn ingestion_size(bytes: u64) -> Self {
+        ConnectivityReport {
+            estimated_ingestion_size: Some(bytes),
+            ..Default::default()
+        }
+    }
+
+    /// Report of `count` files of the given total size in bytes, without findings.
+    pub fn files(count: u64, bytes: u64) -> Self {
+        ConnectivityReport {
+            estimated_ingestion_size: Some(bytes),
+            object_count: Some(count),
+            ..Default::default()
+        }
+    }
+
+    /// Report of a data source whose connectivity is not checked (`skip` validation mode).
+    pub fn skipped(identifier: &str) -> Self {
+        let warning = format!(
+            "Warning: Connectivity of '{}' not checked (validation_mode: skip).",
+            identifier
+        );
+        ConnectivityReport {
+            warnings: vec![warning.clone()],
+            results: vec![DatasourceResult {
+                identifier: identifier.to_string(),
+                outcome: ValidationOutcome::Skipped,
+                messages: vec![warning],
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Records the findings of the report as the result of a single URL/database.
+    pub fn recorded(mut self, identifier: &str) -> Self {
+        let outcome = if !self.errors.is_empty() {
+            ValidationOutcome::Error
+        } else if !self.warnings.is_empty() {
+            ValidationOutcome::Warning
+        } else {
+            ValidationOutcome::Ok
+        };
+        let messages = self
+            .errors
+            .iter()
+            .chain(self.warnings.iter())
+            .map(|message| message.trim_end().to_string())
+            .collect();
+        self.results.push(DatasourceResult {
+            identifier: identifier.to_string(),
+            outcome,
+            messages,
+        });
+        self
+    }
+
+    /// Applies the validation mode of a data source to its report. In `warn` mode the errors become warnings.
+    pub fn with_validation_mode(
+        mut self,
+        validation_mode: ValidationMode,
+        identifier: &str,
+    ) -> Self {
+        if validation_mode != ValidationMode::Strict {
+            let errors = std::mem::take(&mut self.errors);
+            self.warnings.extend(errors.into_iter().map(|error| {
+                format!(
+                    "Warning: Connectivity of '{}' failed (validation_mode: {}): {}",
+                    identifier,
+                    validation_mode.as_str(),
+                    error.trim_end()
+                )
+            }));
+        }
+        self
+    }
+
+    /// Adds the findings of another report. The estimated sizes and the object counts are summed.
+    pub fn merge(&mut self, other: ConnectivityReport) {
+        self.errors.extend(other.errors);
+        self.warnings.extend(other.warnings);
+        self.results.extend(other.results);
+        self.schema_previews.extend(other.schema_previews);
+        self.estimated_ingestion_size = sum(
+            self.estimated_ingestion_size,
+            other.estimated_ingestion_size,
+        );
+        self.object_count = sum(self.object_count, other.object_count);
+    }
+}
+
+/// Sums two optional totals, `None` if both are unknown.
+fn sum(total: Option<u64>, other_total: Option<u64>) -> Option<u64> {
+    match (total, other_total) {
+        (Some(total), Some(other_total)) => Some(total + other_total),
+        (total, other_total) => total.or(other_total),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_connectivity_report_merge() {
+        let mut report = ConnectivityReport::default();
+        report.merge(ConnectivityReport::warning("Prefix is empty.".to_string()));
+        assert_eq!(report.estimated_ingestion_size, None);
+
+        report.merge(ConnectivityReport::ingestion_size(1024));
+        report.merge(ConnectivityReport::ingestion_size(512));
+        report.merge(ConnectivityReport::error("Access denied.".to_string()));
+        assert_eq!(report.object_count, None);
+        report.merge(ConnectivityReport::files(2, 256));
+        report.merge(ConnectivityReport::files(3, 256));
+
+        assert_eq!(report.errors, vec!["Access denied.".to_string()]);
+        assert_eq!(report.warnings, vec!["Prefix is empty.".to_string()]);
+        assert_eq!(report.estimated_ingestion_size, Some(2048));
+        assert_eq!(report.object_count, Some(5));
+    }
+
+    #[test]
+    fn test_success_connectivity_report_with_validation_mode() {
+        let report = ConnectivityReport::error("Error: Access denied.".to_string());
+        assert_eq!(
+            report
+                .clone()
+                .with_validation_mode(ValidationMode::Strict, "s3://bucket-a"),
+            report
+        );
+
+        let report = report.with_validation_mode(ValidationMode::Warn, "s3://bucket-a");
+        assert!(report.errors.is_empty());
+        assert_eq!(
+            report.warnings,
+            vec![
+                "Warning: Connectivity of 's3://bucket-a' failed (validation_mode: warn): Error: Access denied."
+                    .to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_success_connectivity_report_recorded() {
+        let mut report = ConnectivityReport::default();
+        report.merge(ConnectivityReport::ingestion_size(1024).recorded("s3://bucket-a"));
+        report.merge(
+            ConnectivityReport::error("Error: Access denied.".to_string())
+                .with_validation_mode(ValidationMode::Warn, "s3://bucket-b")
+                .recorded("s3://bucket-b"),
+        );
+        report.merge(ConnectivityReport::skipped("s3://bucket-c"));
+
+        let outcomes: Vec<_> = report
+            .results
+            .iter()
+            .map(|result| (result.identifier.as_str(), result.outcome))
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("s3://bucket-a", ValidationOutcome::Ok),
+                ("s3://bucket-b", ValidationOutcome::Warning),
+                ("s3://bucket-c", ValidationOutcome::Skipped),
+            ]
+        );
+        assert_eq!(report.warnings.len(), 2);
+    }
+}
@@ -12,10 +12,9 @@
 //! The function returns a JSON response with the status and message.
 //!
 
+use crate::service::app_repository::AppRepositoryError;
 use crate::service::state::AppState;
-use api_utils::errors::error_interceptor::ErrorInterceptor;
 use axum::{http::StatusCode, Json};
-use mongodb::bson::doc;
 use std::sync::Arc;
 use tracing::{error, info, instrument};
 
@@ -25,54 +24,19 @@ pub async fn fetch_api_key(
     app_state: &Arc<AppState>,
     app_name: &String,
 ) -> Result<(String, String, String), (StatusCode, Json<serde_json::Value>)> {
-    let filter = doc! {"app_name": app_name};
-    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
-
-    match app_state
-        .db
-        .get_document(collection_name, filter)
-        .await
-        .map_err(ErrorInterceptor::from)
-    {
-        Ok(Some(response)) => {
-            if let (Some(api_key), Some(api_key_id), Some(app_id)) = (
-                response.get("api_key").and_then(|api_key| api_key.as_str()),
-                response
-                    .get("api_key_id")
-                    .and_then(|api_key_id| api_key_id.as_str()),
-                response.get("app_id").and_then(|app_id| app_id.as_str()),
-            ) {
-                let success_message =
-                    "Api_key, api_key_id and app_id fetched successfully for given app_name."
-                        .to_string();
-                info!(app_name = app_name, message = success_message);
-                Ok((
-                    api_key.to_string(),
-                    api_key_id.to_string(),
-                    app_id.to_string(),
-                ))
-            } else {
-                let error_message = "Failed to fetch api_key and/or api_key_id and/or app_id. No such key(s) found in document.".to_string();
-                error!(
-                    app_name = app_name,
-                    ext_message = error_message,
-                    message = error_message
-                );
-                Err((
-                    StatusCode::NOT_FOUND,
-                    Json(serde_json::json!({ "status": "error","message": error_message})),
-                ))
-            }
-        }
-        Ok(None) => {
-            let error_message = format!("No document found for app {}", app_name);
-            error!(ext_message = error_message, message = error_message);
-            Err((
-                StatusCode::NOT_FOUND,
-                Json(serde_json::json!({ "status": "error","message": error_message})),
+    match app_state.apps().api_key(app_name).await {
+        Ok(app_api_key) => {
+            let success_message =
+                "Api_key, api_key_id and app_id fetched successfully for given app_name."
+                    .to_string();
+            info!(app_name = app_name, message = success_message);
+            Ok((
+                app_api_key.api_key,
+                app_api_key.api_key_id,
+                app_api_key.app_id,
             ))
         }
-        Err(e) => {
+        Err(AppRepositoryError::Db(e)) => {
             let error_message = format!(
                 "Failed to fetch api_key, api_key_id and app_id from DocumentDB. Error: {}",
                 e
@@ -83,6 +47,7 @@ pub async fn fetch_api_key(
                 Json(serde_json::json!({ "status": "error","message": error_message})),
             ))
         }
+        Err(e) => Err(e.into()),
     }
 }
 
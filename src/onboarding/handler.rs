@@ -9,172 +9,416 @@
 //! The handler returns a 201 status code if the app is onboarded/updated successfully.
 //! The handler returns a 400 status code if the app already exists or doesn't exist for an update request.
 //! The handler returns a 500 status code if an error occurs while performing operations with DocumentDB and Kafka.
-//! The handler returns a JSON response with the status, message, api_key, app_id and reference_id.
+//! The handler returns a JSON response with the status, message, api_key, app_id and reference_id, and the schema
+//! previews of the tabular files of the file stores (see `onboarding::schema_preview`).
+//! With `async_validation=true` the datasources are validated by a background job: the handler returns a 202
+//! status code with the `validation_job_id`, and the job completes the onboarding once the validation succeeds.
+//! Datasources exceeding the configured onboarding limits are rejected with a 400 status code, unless an admin
+//! overrides the limits with `override_limits=true`.
+//! The background steps store the onboarding state of the app (see `service::onboarding_state`), so a failed
+//! onboarding is resumed from the failed step through the retry endpoint.
+//! When the background steps end, their outcome is POSTed to the `notification_url` of the app, if set (see
+//! `service::onboarding_webhook`).
+//! The background steps are queued in the persistent onboarding job queue (see `service::job_queue`), so a replica
+//! crashing mid-onboarding doesn't lose them: the job is retried from the step recorded on the app document.
 //!
 
 use crate::admin_ui_api::schema::QueryParams;
+use crate::onboarding::complexity::record_onboarding_complexity;
 use crate::onboarding::create_api_key::create_api_key;
-use crate::onboarding::update_api_key_usage::update_api_key_with_usage_plan;
+use crate::onboarding::datasource_connectivity::report::ConnectivityReport;
+use crate::onboarding::sample_rows::sample_datasource;
+use crate::onboarding::schema::schema_preview::SchemaPreview;
+use crate::onboarding::schema_preview::{csv_schema_warnings, record_schema_previews};
+use crate::onboarding::update_api_key_usage::{tier_usage_plan_id, update_api_key_with_usage_plan};
+use crate::onboarding::validation_job::enqueue_validation_job;
 use crate::onboarding::{
     check_connectivity::check_datasource_connectivity,
     check_datasource_change::check_datasource_change, fetch_api_key::fetch_api_key,
     schema::app_onboarding_request::OnboardingRequest, schema::response::*, update_app::update_app,
 };
+use crate::service::app_metadata::validate_metadata;
+use crate::service::app_topic::{app_topic, create_app_topic};
+use crate::service::column_classification::validate_column_tags;
 use crate::service::generate_and_insert_document::*;
+use crate::service::job_queue::{enqueue_job, JobQueue, QueuedJob};
+use crate::service::metrics::{MetricRecord, APP_NAME_DIMENSION, TASK_ID_DIMENSION};
+use crate::service::notification::{record_notification, Notification, NotificationKind};
+use crate::service::onboarding_state::{
+    record_onboarding_state, OnboardingProgress, OnboardingState, OnboardingStep,
+};
+use crate::service::onboarding_webhook::{
+    notify_onboarding, validate_notification_url, OnboardingNotification,
+};
 use crate::service::publish_to_kafka::app_onboard_or_update_notify_kafka;
+use crate::service::residency::{residency_name, ResidencyError};
+use crate::service::row_filter::validate_row_filters;
+use crate::service::source_label::validate_source_labels;
+use crate::service::vector_store::VectorStoreConfig;
 use crate::service::{check_app_existence::check_app_existence, state::AppState};
 use axum::{extract::Query, extract::State, http::StatusCode, response::IntoResponse, Json};
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::sync::Arc;
 use tracing::{error, info, instrument};
 use uuid::Uuid;
 
+/// Identifiers of an onboarding/update request, carried through its background steps.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct OnboardingRun {
+    pub app_id: String,
+    pub api_key: String,
+    pub api_key_id: String,
+    pub task_id: String,
+    pub is_update: bool,
+    /// Resumed through the retry endpoint: the datasources are checked again and published as a whole.
+    pub is_retry: bool,
+}
+
+/// Background steps of an onboarding/update request, queued in the onboarding job queue (see `service::job_queue`).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct OnboardingJob {
+    pub body: OnboardingRequest,
+    pub run: OnboardingRun,
+    /// Reference ID of a new request, whose ID and app documents are inserted first. Unset for a retry.
+    pub reference_id: Option<String>,
+    /// Step the background steps start from.
+    pub from: OnboardingStep,
+    pub request_timestamp: DateTime<Utc>,
+    /// Schema previews of the tabular files of a new request, stored on the app document.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schema_previews: Vec<SchemaPreview>,
+}
+
+/// Queues the background steps of an onboarding/update request, run by a worker of one of the replicas.
+pub(crate) async fn enqueue_onboarding(
+    app_state: &AppState,
+    job: &OnboardingJob,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    enqueue_job(app_state, JobQueue::Onboarding, &job.body.app_name, job).await?;
+    Ok(())
+}
+
+/// Runs a job of the onboarding queue. A redelivered job, whose previous attempt failed or was abandoned by a crashed
+/// replica, resumes from the step recorded on the app document, like the retry endpoint. The outcome is notified
+/// once the steps succeed or at the last attempt of the job.
 #[instrument(skip_all)]
-/// Asynchronous function to perform background operations with DocumentDB and Kafka.
-#[allow(clippy::too_many_arguments)]
-async fn background_tasks(
-    app_state: Arc<AppState>,
-    body: OnboardingRequest,
-    app_id: String,
-    api_key: String,
-    api_key_id: String,
-    reference_id: String,
-    task_id: String,
-    request_timestamp: DateTime<Utc>,
-    is_update: bool,
-) {
-    // Generate the ID document and insert it in DocumentDB
+pub(crate) async fn run_onboarding_job(
+    app_state: &Arc<AppState>,
+    job: &QueuedJob,
+) -> Result<(), String> {
+    let mut onboarding: OnboardingJob = job
+        .payload(app_state)
+        .await
+        .map_err(|e| format!("Malformed onboarding job. Error: {}", e))?;
+    if job.attempts > 1 {
+        match app_state
+            .apps()
+            .onboarding_state(&onboarding.body.app_name)
+            .await
+        {
+            Ok(Some(OnboardingState::Complete)) => return Ok(()),
+            Ok(Some(state)) => {
+                if let Some(step) = state.step() {
+                    onboarding.from = step;
+                    onboarding.run.is_retry = true;
+                    onboarding.reference_id = None;
+                }
+            }
+            // The app document of an onboarding request is missing, its steps start over
+            Ok(None) | Err(_) => {}
+        }
+    }
+
+    let OnboardingJob {
+        body,
+        run,
+        reference_id,
+        from,
+        request_timestamp,
+        schema_previews,
+    } = onboarding;
+    let result = async {
+        // Generate the ID document and insert it in DocumentDB. A failure of the documents is notified as a failed
+        // provisioning.
+        if let Some(reference_id) = &reference_id {
+            insert_request_documents(app_state, &body, &run, reference_id)
+                .await
+                .map_err(|_| OnboardingStep::Provisioning)?;
+            // The previews of an update replace the stored ones. They are informational, a failure to store them is
+            // logged only
+            if !schema_previews.is_empty() || run.is_update {
+                let _ = record_schema_previews(app_state, &body.app_name, &schema_previews).await;
+            }
+        }
+        run_onboarding_steps(app_state, &body, &run, from).await
+    }
+    .await;
+    if result.is_ok() || job.is_last_attempt() {
+        finish_onboarding(app_state, &body, &run, result, request_timestamp).await;
+    }
+    result.map_err(|step| {
+        format!(
+            "Onboarding/update of app '{}' failed at the {} step.",
+            body.app_name,
+            format!("{:?}", step).to_lowercase()
+        )
+    })
+}
+
+/// Fails a job of the onboarding queue abandoned at its last attempt at the step recorded on the app document, and
+/// notifies it, unless the app is onboarded already.
+pub(crate) async fn abandon_onboarding_job(app_state: &Arc<AppState>, job: &QueuedJob) {
+    let Ok(onboarding) = job.payload::<OnboardingJob>(app_state).await else {
+        return;
+    };
+    let app_name = &onboarding.body.app_name;
+    let step = match app_state.apps().onboarding_state(app_name).await {
+        Ok(Some(OnboardingState::Complete)) => return,
+        Ok(Some(state)) => state.step().unwrap_or(onboarding.from),
+        Ok(None) | Err(_) => onboarding.from,
+    };
+    let _ = record_onboarding_state(app_state, app_name, OnboardingState::failed(step)).await;
+    finish_onboarding(
+        app_state,
+        &onboarding.body,
+        &onboarding.run,
+        Err(step),
+        onboarding.request_timestamp,
+    )
+    .await;
+}
+
+/// Inserts the ID document of the request and, for an onboarding request, the app document in DocumentDB.
+async fn insert_request_documents(
+    app_state: &Arc<AppState>,
+    body: &OnboardingRequest,
+    run: &OnboardingRun,
+    reference_id: &String,
+) -> Result<(), ()> {
+    let task_id = &run.task_id;
     let id_document =
         generate_id_document(&body.app_name, reference_id.clone(), task_id.clone()).await;
-    if create_document_in_db(
-        &app_state,
+    create_document_in_db(
+        app_state,
         &id_document,
         DocType::ID,
         &app_state.app_settings.mongo_db.mongo_db_id_collection,
         &body.app_name,
-        &reference_id,
-        &task_id,
+        reference_id,
+        task_id,
     )
     .await
-    .is_err()
-    {
-        return;
-    };
+    .map_err(|_| ())?;
 
-    // CASE 1: If it's an onboarding request
-    // 1. Generate the app document and insert it in DocumentDB.
-    // 2. Publish the datasources to Kafka
-    if !is_update {
+    // If it's an onboarding request, generate the app document and insert it in DocumentDB first, so the state of
+    // the steps is recorded on it. A failure here leaves no app document, the request is resubmitted.
+    if !run.is_update {
         // has_datasource_changed is set to true for onboarding requests
         let has_datasource_changed = true;
-        let app = match generate_app_document(
-            &app_state,
+        let mut app = generate_app_document(
+            app_state,
             body.clone(),
-            app_id,
-            api_key,
-            api_key_id,
+            run.app_id.clone(),
+            run.api_key.clone(),
+            run.api_key_id.clone(),
             has_datasource_changed,
         )
         .await
-        {
-            Ok(app) => app,
-            Err(_) => return,
-        };
-        if create_document_in_db(
-            &app_state,
+        .map_err(|_| ())?;
+        app.onboarding_state = Some(OnboardingProgress::new(OnboardingState::Validating));
+        create_document_in_db(
+            app_state,
             &app,
             DocType::App,
             &app_state.app_settings.mongo_db.mongo_db_app_collection,
             &body.app_name,
-            &reference_id,
-            &task_id,
-        )
-        .await
-        .is_err()
-        {
-            return;
-        };
-        if app_onboard_or_update_notify_kafka(
-            &app_state,
-            &body.app_name,
-            &body.app_datasource,
-            None,
-            task_id.clone(),
+            reference_id,
+            task_id,
         )
         .await
-        .is_err()
-        {
-            return;
-        };
+        .map_err(|_| ())?;
+    }
+    Ok(())
+}
 
-    // CASE 2: If it's an update request
-    // 1. Check if the datasources have changed. If yes, update the app document in DocumentDB and publish both the new and existing datasources to Kafka.
-    // 2. If the datasources are identical, just update the app document in DocumentDB (since fields other than datasources may have changed)
-    // but don't publish to Kafka.
-    } else {
-        let (has_datasource_changed, existing_app_datasource) =
-            match check_datasource_change(&app_state, &body.app_name, &body.app_datasource).await {
-                Ok(result) => result,
-                Err(_) => return,
-            };
-        if update_app(
-            &app_state,
-            &body,
-            app_id,
-            api_key,
-            api_key_id,
-            has_datasource_changed,
-        )
-        .await
-        .is_err()
-        {
-            return;
-        };
-        // if the datasources have changed, publish the new datasources to Kafka
-        if has_datasource_changed {
-            if let Some(existing_app_datasource) = existing_app_datasource {
-                if app_onboard_or_update_notify_kafka(
-                    &app_state,
+/// Records the completion of the background steps and notifies their outcome to the notification URL of the app.
+async fn finish_onboarding(
+    app_state: &AppState,
+    body: &OnboardingRequest,
+    run: &OnboardingRun,
+    result: Result<(), OnboardingStep>,
+    request_timestamp: DateTime<Utc>,
+) {
+    let (state, admin_notification) = match result {
+        Ok(()) => {
+            record_onboarding_success(app_state, &body.app_name, &run.task_id, request_timestamp)
+                .await;
+            (
+                OnboardingState::Complete,
+                Notification::new(
+                    NotificationKind::OnboardingCompleted,
                     &body.app_name,
-                    &body.app_datasource,
-                    Some(&existing_app_datasource),
-                    task_id.clone(),
+                    format!("App '{}' onboarded/updated successfully.", body.app_name),
+                ),
+            )
+        }
+        Err(step) => (
+            OnboardingState::failed(step),
+            Notification::new(
+                NotificationKind::OnboardingFailed,
+                &body.app_name,
+                format!(
+                    "Onboarding/update of app '{}' failed at the {} step. Retry it from the admin console.",
+                    body.app_name,
+                    format!("{:?}", step).to_lowercase()
+                ),
+            ),
+        ),
+    };
+    record_notification(app_state, admin_notification).await;
+    if let Some(notification_url) = &body.notification_url {
+        let notification = OnboardingNotification {
+            app_name: body.app_name.clone(),
+            app_id: run.app_id.clone(),
+            task_id: run.task_id.clone(),
+            is_update: run.is_update,
+            state,
+            timestamp: Utc::now().to_rfc3339(),
+        };
+        notify_onboarding(app_state, notification_url, &notification).await;
+    }
+}
+
+/// Runs the background steps of an onboarding/update request from the given step, storing the onboarding state of
+/// the app before each step. Returns the failed step, stored as `failed_at_<step>`.
+/// 1. Validating: for an update, check if the datasources have changed. For a retry, check their connectivity again.
+/// 2. Provisioning: create the Kafka topic of the app and, for an update or a retry, update the app document in
+/// DocumentDB (since fields other than datasources may have changed).
+/// 3. Notifying: if the datasources have changed, capture the sample rows of the tables and publish both the new
+/// and existing datasources to Kafka. A retry publishes the datasources as new, the existing ones are gone.
+#[instrument(skip_all)]
+async fn run_onboarding_steps(
+    app_state: &Arc<AppState>,
+    body: &OnboardingRequest,
+    run: &OnboardingRun,
+    from: OnboardingStep,
+) -> Result<(), OnboardingStep> {
+    let app_name = &body.app_name;
+    let result = async {
+        // has_datasource_changed is set to true for onboarding requests and retries
+        let mut has_datasource_changed = true;
+        let mut existing_app_datasource = None;
+
+        if from <= OnboardingStep::Validating {
+            start_step(app_state, app_name, OnboardingStep::Validating).await?;
+            if run.is_retry {
+                check_datasource_connectivity(app_state, &body.app_datasource, app_name, true)
+                    .await
+                    .map_err(|_| OnboardingStep::Validating)?;
+            } else if run.is_update {
+                (has_datasource_changed, existing_app_datasource) =
+                    check_datasource_change(app_state, app_name, &body.app_datasource)
+                        .await
+                        .map_err(|_| OnboardingStep::Validating)?;
+            }
+        }
+
+        if from <= OnboardingStep::Provisioning {
+            start_step(app_state, app_name, OnboardingStep::Provisioning).await?;
+            create_app_topic(app_state, app_name).await.map_err(|e| {
+                let error_message = e.to_string();
+                error!(
+                    app_name = app_name,
+                    task_id = run.task_id,
+                    ext_message = error_message,
+                    message = error_message
+                );
+                OnboardingStep::Provisioning
+            })?;
+            if run.is_update || run.is_retry {
+                update_app(
+                    app_state,
+                    body,
+                    run.app_id.clone(),
+                    run.api_key.clone(),
+                    run.api_key_id.clone(),
+                    has_datasource_changed,
                 )
                 .await
-                .is_err()
-                {
-                    return;
-                };
+                .map_err(|_| OnboardingStep::Provisioning)?;
             }
         }
+
+        if has_datasource_changed {
+            start_step(app_state, app_name, OnboardingStep::Notifying).await?;
+            let sampled_app_datasource =
+                sample_datasource(app_state, app_name, &body.app_datasource).await;
+            app_onboard_or_update_notify_kafka(
+                app_state,
+                app_name,
+                &sampled_app_datasource,
+                existing_app_datasource.as_ref(),
+                run.task_id.clone(),
+            )
+            .await
+            .map_err(|_| OnboardingStep::Notifying)?;
+        }
+        Ok::<(), OnboardingStep>(())
     }
+    .await;
 
+    let state = match result {
+        Ok(()) => OnboardingState::Complete,
+        Err(step) => OnboardingState::failed(step),
+    };
+    // The failure to store the state is logged, the outcome of the steps stands
+    let _ = record_onboarding_state(app_state, app_name, state).await;
+    result
+}
+
+/// Stores the state of a step about to run. A step whose state can't be stored fails.
+async fn start_step(
+    app_state: &AppState,
+    app_name: &str,
+    step: OnboardingStep,
+) -> Result<(), OnboardingStep> {
+    record_onboarding_state(app_state, app_name, OnboardingState::running(step))
+        .await
+        .map_err(|_| step)
+}
+
+/// Sends the completion of an onboarding/update to the logs, audit and metrics microservices.
+async fn record_onboarding_success(
+    app_state: &AppState,
+    app_name: &str,
+    task_id: &str,
+    request_timestamp: DateTime<Utc>,
+) {
     // Calculate the time taken to onboard the app
     let onboarding_success_timestamp = Utc::now();
-    let onboarding_duration = format!(
-        "{} ms",
-        (onboarding_success_timestamp - request_timestamp).num_milliseconds()
-    );
-    let success_message: String = format!("'{}' onboarded/updated successfully.", &body.app_name);
+    let onboarding_duration_ms =
+        (onboarding_success_timestamp - request_timestamp).num_milliseconds();
+    let success_message: String = format!("'{}' onboarded/updated successfully.", app_name);
 
     // Sending data to logs, audit and metrics microservices
-    info!(app_name = &body.app_name, message = success_message);
+    info!(app_name = app_name, message = success_message);
     info!(
         service = "audit_microservice",
         task_id = task_id,
-        app_name = &body.app_name,
+        app_name = app_name,
         action = "App Onboarded/updated",
         details = success_message,
         message = success_message
     );
-    info!(
-        service = "metric",
-        task_id = task_id,
-        app_name = &body.app_name,
-        metrics_name = "App Onboarding/update Duration",
-        metrics_value = onboarding_duration
-    );
+    app_state
+        .record_metric(
+            MetricRecord::duration_ms("App Onboarding/update Duration", onboarding_duration_ms)
+                .dimension(APP_NAME_DIMENSION, app_name)
+                .dimension(TASK_ID_DIMENSION, task_id),
+        )
+        .await;
 }
 
 /// POST handler to onboard/update an application to the product/ platform.
@@ -187,10 +431,21 @@ async fn background_tasks(
             "is_update" = inline(Option<String>),
             Query,
             description = "Onboarding or update request.",
+        ),
+        (
+            "async_validation" = inline(Option<bool>),
+            Query,
+            description = "Validate the datasources in a background job, polled at /api/v1.1/admin/validation/{job_id}.",
+        ),
+        (
+            "override_limits" = inline(Option<bool>),
+            Query,
+            description = "Admin override of the onboarding limits (max files, bytes, tables and columns).",
         )
     ),
     responses(
         (status = 200, description = "Onboarding/update initiated successfully.", body = [AppCreateResponse]),
+        (status = 202, description = "Datasource validation job queued.", body = [ValidationJobCreateResponse]),
         (status = StatusCode::BAD_REQUEST, description = "Invalid Request", body = [ErrorResponse]),
         (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
     )
@@ -228,16 +483,144 @@ pub async fn post_app_onboarding_handler(
         ));
     }
 
-    // Call to 'Onboarding' - generate the UI summary document and insert it in DocumentDB
-    let ui_summary_document = generate_ui_summary_document(
+    // Admins can override the onboarding limits of the datasources
+    let override_limits = params.override_limits.unwrap_or(false);
+
+    // Validate the datasources in a background job for large apps, whose synchronous check can exceed client timeouts
+    if params.async_validation.unwrap_or(false) {
+        let response = enqueue_validation_job(
+            &app_state,
+            body,
+            is_update,
+            request_timestamp,
+            override_limits,
+        )
+        .await?;
+        return Ok((StatusCode::ACCEPTED, Json(response)).into_response());
+    }
+
+    let response = start_onboarding(
+        &app_state,
+        body,
+        is_update,
+        request_timestamp,
+        override_limits,
+    )
+    .await?;
+    Ok((StatusCode::CREATED, Json(response)).into_response())
+}
+
+/// Asynchronous function to validate the datasources of an onboarding/update request and spawn the background
+/// operations. The caller is responsible for checking the app existence against `is_update`.
+#[instrument(skip_all)]
+pub(crate) async fn start_onboarding(
+    app_state: &Arc<AppState>,
+    mut body: OnboardingRequest,
+    is_update: bool,
+    request_timestamp: DateTime<Utc>,
+    override_limits: bool,
+) -> Result<AppCreateResponse, (StatusCode, Json<serde_json::Value>)> {
+    prepare_onboarding(app_state, &mut body, is_update, request_timestamp).await?;
+
+    // Check the connectivity to the provided data sources and the onboarding limits
+    let connectivity_report = check_datasource_connectivity(
+        app_state,
+        &body.app_datasource,
         &body.app_name,
-        "Onboarding",
-        1,
-        request_timestamp.to_string(),
+        override_limits,
     )
-    .await;
+    .await?;
+
+    complete_onboarding(
+        app_state,
+        body,
+        is_update,
+        request_timestamp,
+        connectivity_report,
+    )
+    .await
+}
+
+/// Asynchronous function to validate an onboarding/update request, without its datasource connectivity, and
+/// record the onboarding call in the UI summary.
+#[instrument(skip_all)]
+pub(crate) async fn prepare_onboarding(
+    app_state: &Arc<AppState>,
+    body: &mut OnboardingRequest,
+    is_update: bool,
+    request_timestamp: DateTime<Utc>,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    // Validate the app against the naming rules and limits of the vector backend
+    VectorStoreConfig::from_settings(&app_state.app_settings, &body.app_name)
+        .validate(
+            body.text_embedding_model.dimension,
+            body.multimodal_embedding_model.dimension,
+        )
+        .map_err(|e| {
+            let error_message = e.to_string();
+            error!(ext_message = error_message, message = error_message);
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"status": "error", "message": error_message})),
+            )
+        })?;
+
+    // Validate the rate limit of the end users of the app
+    if let Some(user_rate_limit) = &body.user_rate_limit {
+        if user_rate_limit.max_requests == 0 || user_rate_limit.window_seconds == 0 {
+            let error_message = format!(
+                "Invalid user_rate_limit of app '{}'. max_requests and window_seconds must be greater than 0.",
+                body.app_name
+            );
+            error!(ext_message = error_message, message = error_message);
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"status": "error", "message": error_message})),
+            ));
+        }
+    }
+
+    // Validate the PII tags of the datastore columns, the row filters of the tables and the labels of the sources
+    validate_column_tags(&body.app_datasource)?;
+    validate_row_filters(&body.app_datasource)?;
+    validate_source_labels(&body.app_datasource)?;
+
+    // Validate the tier of the app, selecting the usage plan of its API key
+    tier_usage_plan_id(
+        &app_state.app_settings.aws_api_gateway,
+        body.tier.as_deref(),
+    )?;
+
+    // Validate the URL notified of the outcome of the background steps
+    validate_notification_url(body.notification_url.as_deref())?;
+
+    // Validate the custom metadata of the app
+    validate_metadata(body.metadata.as_ref())?;
+
+    // Validate the name of the Kafka topic of the app, when the apps have their own topics
+    app_topic(app_state, &body.app_name)?;
+
+    // Validate the residency of the app. Updates keep the existing residency, moving an app to another
+    // residency requires a migration of its collections.
+    app_state.residency_db(body.residency.as_deref())?;
+    if is_update {
+        let existing_residency = app_state.app_residency(&body.app_name).await?;
+        if body.residency.is_some() && body.residency != existing_residency {
+            return Err(ResidencyError::ResidencyChange {
+                app_name: body.app_name.clone(),
+                existing: residency_name(existing_residency.as_deref()).to_string(),
+                requested: residency_name(body.residency.as_deref()).to_string(),
+            }
+            .into());
+        }
+        body.residency = existing_residency;
+    }
+
+    // Call to 'Onboarding' - generate the UI summary document and insert it in DocumentDB
+    let ui_summary_document =
+        generate_ui_summary_document(&body.app_name, "Onboarding", 1, request_timestamp).await;
     create_document_in_db(
-        &app_state,
+        app_state,
         &ui_summary_document,
         DocType::UiSummary,
         &app_state
@@ -255,68 +638,110 @@ pub async fn post_app_onboarding_handler(
             Json(json!({"status": "error", "message": e.to_string()})),
         )
     })?;
+    Ok(())
+}
 
-    // Check the connectivity to the provided data sources
-    check_datasource_connectivity(&app_state, &body.app_datasource, &body.app_name).await?;
-
-    // If it's an onboarding request, create an API key, else fetch the given app's api key and app_id from DocumentDB
-    let (api_key, api_key_id, app_id) = if !is_update {
-        let (api_key, api_key_id) = create_api_key(&app_state, &body.app_name).await?;
+/// Asynchronous function to complete an onboarding/update request whose datasources are validated: creates or
+/// fetches the API key, updates its usage plan and spawns the background operations.
+#[instrument(skip_all)]
+pub(crate) async fn complete_onboarding(
+    app_state: &Arc<AppState>,
+    body: OnboardingRequest,
+    is_update: bool,
+    request_timestamp: DateTime<Utc>,
+    connectivity_report: ConnectivityReport,
+) -> Result<AppCreateResponse, (StatusCode, Json<serde_json::Value>)> {
+    // If it's an onboarding request, create an API key, else fetch the given app's api key and app_id from DocumentDB.
+    // In the internal API key mode the app document stores the hash of the key, so the key is only returned here
+    // on onboarding.
+    let api_key_options = app_state.api_key_options();
+    let (api_key, stored_api_key, api_key_id, app_id) = if !is_update {
+        let (api_key, api_key_id) = create_api_key(app_state, &body.app_name, None).await?;
+        let stored_api_key = api_key_options.stored_api_key(&api_key);
         let app_id = Uuid::new_v4().to_string();
-        (api_key, api_key_id, app_id)
+        (api_key, stored_api_key, api_key_id, app_id)
     } else {
-        fetch_api_key(&app_state, &body.app_name).await?
+        let (stored_api_key, api_key_id, app_id) = fetch_api_key(app_state, &body.app_name).await?;
+        let api_key = match api_key_options.is_internal() {
+            true => String::new(),
+            false => stored_api_key.clone(),
+        };
+        (api_key, stored_api_key, api_key_id, app_id)
     };
 
     // Generate the app ID, reference ID and task ID
-    let random_num: u32 = (rand::random::<u32>() % 90000) + 10000;
-    let task_id = format!(
-        "{}-{}-{}-{}-{}",
-        "TSK", random_num, &body.app_name, "Onboarding", request_timestamp
-    );
-    let reference_id = Uuid::new_v4().to_string();
+    let task_id = app_state.id_generator.task_id(&body.app_name, "Onboarding");
+    let reference_id = app_state.id_generator.reference_id();
 
     //function to update the usage plan for the api key
     update_api_key_with_usage_plan(
-        &app_state,
+        app_state,
         api_key_id.clone(),
         task_id.clone(),
         &body.app_name,
+        body.tier.as_deref(),
+        is_update,
     )
     .await?;
 
     // Instrument function call counter
-    info!(
-        service = "metric",
-        app_name = &body.app_name,
-        task_id = task_id,
-        metrics_name = "App Onboarding Counter",
-        metrics_value = "1"
-    );
+    app_state
+        .record_metric(
+            MetricRecord::counter("App Onboarding Counter")
+                .dimension(APP_NAME_DIMENSION, &body.app_name)
+                .dimension(TASK_ID_DIMENSION, &task_id),
+        )
+        .await;
+    record_onboarding_complexity(app_state, &body, &task_id).await;
+
+    // The CSV files of a URL appended to a single table must share their columns
+    let ConnectivityReport {
+        mut warnings,
+        estimated_ingestion_size,
+        schema_previews,
+        ..
+    } = connectivity_report;
+    warnings.extend(csv_schema_warnings(
+        &schema_previews,
+        body.csv_append_same_schema,
+    ));
 
-    // Spawn a background task to perform operations with DocumentDB and Kafka
-    tokio::spawn(background_tasks(
-        Arc::clone(&app_state),
+    // Queue the background operations with DocumentDB and Kafka, so they survive a crash of the replica
+    let job = OnboardingJob {
         body,
-        app_id.clone(),
-        api_key.clone(),
-        api_key_id.clone(),
-        reference_id.clone(),
-        task_id,
+        run: OnboardingRun {
+            app_id: app_id.clone(),
+            api_key: stored_api_key,
+            api_key_id: api_key_id.clone(),
+            task_id,
+            is_update,
+            is_retry: false,
+        },
+        reference_id: Some(reference_id.clone()),
+        from: OnboardingStep::Validating,
         request_timestamp,
-        is_update,
-    ));
+        schema_previews: schema_previews.clone(),
+    };
+    enqueue_onboarding(app_state, &job).await?;
 
-    Ok((
-        StatusCode::CREATED,
-        Json(AppCreateResponse {
-            status: "success".to_string(),
-            message: "Datasource validation done. Onboarding in progress.".to_string(),
-            api_key,
-            app_id,
-            reference_id,
-        }),
-    ))
+    let message = if warnings.is_empty() {
+        "Datasource validation done. Onboarding in progress.".to_string()
+    } else {
+        format!(
+            "Datasource validation done with {} warning(s). Onboarding in progress.",
+            warnings.len()
+        )
+    };
+    Ok(AppCreateResponse {
+        status: "success".to_string(),
+        message,
+        api_key,
+        app_id,
+        reference_id,
+        warnings,
+        estimated_ingestion_size,
+        schema_previews,
+    })
 }
 
 #[cfg(test)]
@@ -0,0 +1,599 @@
+/*
+ * Created Date:  Jul 15, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the capture of the sample rows of the datastore tables of an app during onboarding.
+//! A table declaring `sample_rows` in the onboarding request gets its empty `top_rows`, `random_rows` and
+//! `bottom_rows` lists filled from the database, lists declared with rows are kept as-is. Each captured row is a
+//! JSON object of the columns of the table, with the values of the PII columns and of the columns of a masked
+//! sensitivity replaced according to the configured masking rule.
+//! The samples are captured in the onboarding background task, only for new or changed datasources. They are
+//! published with the datasource in the onboarding Kafka event and stored, encrypted, on the app document as a flat
+//! `sample_rows` list for the knowledge engine. The stored datasource keeps the rows as declared, so the datasource
+//! change detection of the updates is not affected by the captured rows.
+//! Only the relational databases (`mysql`, `postgres`) are sampled. A failing table is logged and left unsampled,
+//! the samples never fail an onboarding.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{SampleRowsSettings, TresleFacadeServiceSettings};
+use crate::onboarding::schema::app_onboarding_request::{
+    AppDataSource, DataStore, SampleRows, Sensitivity, Table,
+};
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use authentication_utils::AwsAuthentication;
+use mongodb::bson::{doc, to_bson};
+use relational_db_utils::RelationalDbClient;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tracing::{debug, error, instrument};
+use utoipa::ToSchema;
+
+/// Name of the field of the app document holding the sample rows.
+pub const SAMPLE_ROWS_FIELD: &str = "sample_rows";
+/// Value of a masked column with the `redact` masking rule.
+pub const REDACTED_VALUE: &str = "***";
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum SampleRowsError {
+    #[error(
+        "Invalid table name '{0}'. Only letters, digits, '_' and '$' separated by '.' are sampled."
+    )]
+    InvalidTableName(String),
+    #[error("Sample rows are not supported for database type '{0}'.")]
+    UnsupportedDatabase(String),
+    #[error("Failed to read the sample rows of table '{table}'. Error: {message}")]
+    Query { table: String, message: String },
+}
+
+/// Masking of the values of the sensitive columns of the sample rows.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskingRule {
+    /// Replaces the value with `***`.
+    #[default]
+    Redact,
+    /// Keeps the last 4 characters of the value, e.g. `*******1234`.
+    Partial,
+    /// Replaces the value with `null`.
+    Null,
+}
+
+impl MaskingRule {
+    /// Masks a value of a sensitive column. `null` values stay `null`.
+    pub fn mask(&self, value: &serde_json::Value) -> serde_json::Value {
+        if value.is_null() {
+            return serde_json::Value::Null;
+        }
+        match self {
+            MaskingRule::Redact => json!(REDACTED_VALUE),
+            MaskingRule::Partial => {
+                let value = match value {
+                    serde_json::Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                let chars: Vec<char> = value.chars().collect();
+                let masked_count = chars.len().saturating_sub(4).max(chars.len() / 2);
+                let masked: String = std::iter::repeat('*')
+                    .take(masked_count)
+                    .chain(chars[masked_count..].iter().copied())
+                    .collect();
+                json!(masked)
+            }
+            MaskingRule::Null => serde_json::Value::Null,
+        }
+    }
+}
+
+/// Sample row options: row count and masking.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleRowsOptions {
+    /// Number of rows of each of the top, random and bottom samples.
+    pub row_count: usize,
+    pub masking: MaskingRule,
+    /// Sensitivities whose columns are masked, in addition to the PII columns.
+    pub masked_sensitivities: Vec<Sensitivity>,
+}
+
+impl Default for SampleRowsOptions {
+    fn default() -> Self {
+        SampleRowsOptions {
+            row_count: 3,
+            masking: MaskingRule::default(),
+            masked_sensitivities: vec![Sensitivity::Confidential, Sensitivity::Restricted],
+        }
+    }
+}
+
+impl SettingsOptions for SampleRowsOptions {
+    type Settings = SampleRowsSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&SampleRowsSettings> {
+        settings.sample_rows.as_ref()
+    }
+
+    fn from_settings(settings: Option<&SampleRowsSettings>) -> Self {
+        let defaults = SampleRowsOptions::default();
+        let Some(settings) = settings else {
+            return defaults;
+        };
+        SampleRowsOptions {
+            row_count: settings.row_count.unwrap_or(defaults.row_count),
+            masking: settings.masking.unwrap_or(defaults.masking),
+            masked_sensitivities: settings
+                .masked_sensitivities
+                .clone()
+                .unwrap_or(defaults.masked_sensitivities),
+        }
+    }
+}
+
+impl SampleRowsOptions {
+    /// Returns the names of the columns of a table to mask.
+    pub fn masked_columns<'a>(&self, table: &'a Table) -> HashSet<&'a str> {
+        table
+            .columns
+            .iter()
+            .flatten()
+            .filter(|column| {
+                column.pii == Some(true)
+                    || column
+                        .sensitivity
+                        .is_some_and(|s| self.masked_sensitivities.contains(&s))
+            })
+            .map(|column| column.name.as_str())
+            .collect()
+    }
+}
+
+/// Sample rows of a datastore table, identified by the source type, host and database of its datastore.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct TableSampleRows {
+    pub source_type: String,
+    pub host: String,
+    pub database: String,
+    pub table: String,
+    pub sample_rows: SampleRows,
+}
+
+/// Queries of the samples of a table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleQueries {
+    pub count: String,
+    pub top: String,
+    pub random: String,
+    /// Query of the bottom rows, `{offset}` is replaced by the number of rows of the table minus the row count.
+    pub bottom: String,
+}
+
+/// Builds the queries of the samples of a table. The table name is quoted for the database type, so it is never
+/// interpolated as SQL.
+pub fn sample_queries(
+    db_type: &str,
+    table: &str,
+    row_count: usize,
+) -> Result<SampleQueries, SampleRowsError> {
+    let (quote, random) = match db_type {
+        "mysql" => ('`', "RAND()"),
+        "postgres" => ('"', "RANDOM()"),
+        _ => return Err(SampleRowsError::UnsupportedDatabase(db_type.to_string())),
+    };
+    let is_valid_name = |name: &str| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+    };
+    if !table.split('.').all(is_valid_name) {
+        return Err(SampleRowsError::InvalidTableName(table.to_string()));
+    }
+    let table = table
+        .split('.')
+        .map(|name| format!("{quote}{name}{quote}"))
+        .collect::<Vec<_>>()
+        .join(".");
+    Ok(SampleQueries {
+        count: format!("SELECT COUNT(*) AS row_count FROM {}", table),
+        top: format!("SELECT * FROM {} LIMIT {}", table, row_count),
+        random: format!(
+            "SELECT * FROM {} ORDER BY {} LIMIT {}",
+            table, random, row_count
+        ),
+        bottom: format!(
+            "SELECT * FROM {} LIMIT {} OFFSET {{offset}}",
+            table, row_count
+        ),
+    })
+}
+
+/// Masks the sensitive columns of a row and returns the row as a JSON object string.
+pub fn mask_row(
+    mut row: serde_json::Map<String, serde_json::Value>,
+    masked_columns: &HashSet<&str>,
+    masking: MaskingRule,
+) -> String {
+    for (column, value) in row.iter_mut() {
+        if masked_columns.contains(column.as_str()) {
+            *value = masking.mask(value);
+        }
+    }
+    serde_json::Value::Object(row).to_string()
+}
+
+/// Returns a copy of a datasource with the captured sample rows filled in its tables.
+pub fn with_sample_rows(
+    datasource: &AppDataSource,
+    sample_rows: &[TableSampleRows],
+) -> AppDataSource {
+    let mut datasource = datasource.clone();
+    for captured in sample_rows {
+        let tables = datasource
+            .datastore
+            .get_mut(&captured.source_type)
+            .into_iter()
+            .flatten()
+            .filter(|ds| ds.host == captured.host && ds.database == captured.database)
+            .flat_map(|ds| ds.tables.iter_mut())
+            .filter(|table| table.name == captured.table);
+        for table in tables {
+            table.sample_rows = Some(captured.sample_rows.clone());
+        }
+    }
+    datasource
+}
+
+/// Captures the sample rows of the tables of a datasource declaring `sample_rows`.
+#[instrument(skip_all)]
+pub async fn capture_sample_rows(
+    app_state: &Arc<AppState>,
+    app_name: &str,
+    datasource: &AppDataSource,
+) -> Vec<TableSampleRows> {
+    let options = app_state.options::<SampleRowsOptions>();
+    let mut sample_rows = Vec::new();
+    for (source_type, datastores) in &datasource.datastore {
+        for datastore in datastores {
+            let tables: Vec<&Table> = datastore
+                .tables
+                .iter()
+                .filter(|table| table.sample_rows.is_some())
+                .collect();
+            if tables.is_empty() {
+                continue;
+            }
+            if !matches!(datastore.db_type.as_str(), "mysql" | "postgres") {
+                debug!(
+                    app_name = app_name,
+                    message = format!(
+                        "Skipping sample rows of '{}' database '{}'.",
+                        datastore.db_type, datastore.database
+                    )
+                );
+                continue;
+            }
+            let client = match relational_client(app_state, datastore).await {
+                Ok(client) => client,
+                Err(error_message) => {
+                    error!(
+                        app_name = app_name,
+                        ext_message = error_message,
+                        message = error_message
+                    );
+                    continue;
+                }
+            };
+            for table in tables {
+                match capture_table(&client, datastore, table, &options).await {
+                    Ok(table_sample_rows) => sample_rows.push(TableSampleRows {
+                        source_type: source_type.clone(),
+                        host: datastore.host.clone(),
+                        database: datastore.database.clone(),
+                        table: table.name.clone(),
+                        sample_rows: table_sample_rows,
+                    }),
+                    Err(e) => {
+                        let error_message = e.to_string();
+                        error!(
+                            app_name = app_name,
+                            ext_message = error_message,
+                            message = error_message
+                        );
+                    }
+                }
+            }
+        }
+    }
+    // The datastore map has no order, keep the stored list stable across updates
+    sample_rows.sort_by(|a, b| {
+        (&a.source_type, &a.host, &a.database, &a.table).cmp(&(
+            &b.source_type,
+            &b.host,
+            &b.database,
+            &b.table,
+        ))
+    });
+    sample_rows
+}
+
+/// Captures and stores the sample rows of a new or changed datasource. Returns the datasource with the captured
+/// rows filled in, to publish to Kafka.
+#[instrument(skip_all)]
+pub async fn sample_datasource(
+    app_state: &Arc<AppState>,
+    app_name: &str,
+    datasource: &AppDataSource,
+) -> AppDataSource {
+    let sample_rows = capture_sample_rows(app_state, app_name, datasource).await;
+    if let Err(error_message) = store_sample_rows(app_state, app_name, sample_rows.clone()).await {
+        error!(
+            app_name = app_name,
+            ext_message = error_message,
+            message = error_message
+        );
+    }
+    with_sample_rows(datasource, &sample_rows)
+}
+
+/// Stores the captured sample rows on the app document, with the rows encrypted. The previous samples are replaced.
+#[instrument(skip_all)]
+pub async fn store_sample_rows(
+    app_state: &Arc<AppState>,
+    app_name: &str,
+    mut sample_rows: Vec<TableSampleRows>,
+) -> Result<(), String> {
+    for table_sample_rows in sample_rows.iter_mut() {
+        let samples = &mut table_sample_rows.sample_rows;
+        let rows = samples
+            .top_rows
+            .iter_mut()
+            .chain(samples.random_rows.iter_mut())
+            .chain(samples.bottom_rows.iter_mut());
+        for row in rows {
+            *row = app_state
+                .encrypt_field(app_name, row)
+                .await
+                .map_err(|e| format!("Failed to encrypt sample rows. Error: {}", e))?;
+        }
+    }
+    let sample_rows_bson = to_bson(&sample_rows)
+        .map_err(|e| format!("Failed to serialize sample rows to BSON. Error: {}", e))?;
+    app_state
+        .db
+        .update_document(
+            &app_state.app_settings.mongo_db.mongo_db_app_collection,
+            doc! {"app_name": app_name},
+            doc! {SAMPLE_ROWS_FIELD: sample_rows_bson},
+        )
+        .await
+        .map_err(|e| {
+            format!(
+                "Failed to store sample rows of app '{}'. Error: {}",
+                app_name,
+                ErrorInterceptor::from(e)
+            )
+        })?;
+    Ok(())
+}
+
+/// Connects to a relational database with the credentials of its secret.
+async fn relational_client(
+    app_state: &Arc<AppState>,
+    datastore: &DataStore,
+) -> Result<RelationalDbClient, String> {
+    let mut aws_auth_builder = AwsAuthentication::builder();
+    aws_auth_builder = match &app_state.app_settings.aws {
+        Some(aws) => aws_auth_builder
+            .set_aws_access_key_id(aws.access_key_id.clone())
+            .set_aws_secret_access_key(aws.secret_access_key.clone())
+            .set_aws_default_region(aws.default_region.clone().map(String::from)),
+        None => aws_auth_builder,
+    };
+    let aws_auth = aws_auth_builder
+        .build()
+        .await
+        .map_err(|e| format!("Failed to create AWS authentication: {}", e))?;
+    RelationalDbClient::builder()
+        .set_database_type(&datastore.db_type)
+        .set_secret_name(datastore.secret_name.clone())
+        .set_host(&datastore.host)
+        .set_port(&datastore.port)
+        .set_database(&datastore.database)
+        .set_timeout(&app_state.app_settings.datastore.connection_timeout_seconds)
+        .set_aws_auth(aws_auth)
+        .build()
+        .await
+        .map_err(|e| {
+            format!(
+                "Failed to connect to '{}' database '{}' for sample rows: {}",
+                datastore.db_type, datastore.host, e
+            )
+        })
+}
+
+/// Captures the declared empty samples of a table.
+async fn capture_table(
+    client: &RelationalDbClient,
+    datastore: &DataStore,
+    table: &Table,
+    options: &SampleRowsOptions,
+) -> Result<SampleRows, SampleRowsError> {
+    let queries = sample_queries(&datastore.db_type, &table.name, options.row_count)?;
+    let masked_columns = options.masked_columns(table);
+    let fetch = |query: String| async move {
+        client
+            .fetch_rows(&query)
+            .await
+            .map_err(|e| SampleRowsError::Query {
+                table: table.name.clone(),
+                message: e.to_string(),
+            })
+    };
+    let masked = |rows: Vec<serde_json::Map<String, serde_json::Value>>| -> Vec<String> {
+        rows.into_iter()
+            .map(|row| mask_row(row, &masked_columns, options.masking))
+            .collect()
+    };
+
+    let mut sample_rows = table.sample_rows.clone().unwrap_or(SampleRows {
+        top_rows: vec![],
+        random_rows: vec![],
+        bottom_rows: vec![],
+    });
+    if sample_rows.top_rows.is_empty() {
+        sample_rows.top_rows = masked(fetch(queries.top).await?);
+    }
+    if sample_rows.random_rows.is_empty() {
+        sample_rows.random_rows = masked(fetch(queries.random).await?);
+    }
+    if sample_rows.bottom_rows.is_empty() {
+        let row_count = fetch(queries.count)
+            .await?
+            .first()
+            .and_then(|row| row.get("row_count"))
+            .and_then(serde_json::Value::as_u64)
+            .unwrap_or_default();
+        let offset = row_count.saturating_sub(options.row_count as u64);
+        let query = queries.bottom.replace("{offset}", &offset.to_string());
+        sample_rows.bottom_rows = masked(fetch(query).await?);
+    }
+    Ok(sample_rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> Table {
+        serde_json::from_value(json!({
+            "name": "customers",
+            "descriptions": "customers",
+            "schema": null,
+            "schema_json": null,
+            "sample_rows": {"top_rows": [], "random_rows": [], "bottom_rows": []},
+            "fact_phrases": null,
+            "fact_words": null,
+            "search_keywords": null,
+            "summary": null,
+            "columns": [
+                {"name": "id", "descriptions": "id"},
+                {"name": "email", "descriptions": "email", "pii": true},
+                {"name": "salary", "descriptions": "salary", "sensitivity": "restricted"},
+                {"name": "city", "descriptions": "city", "sensitivity": "internal"}
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_masking_rule() {
+        assert_eq!(MaskingRule::Redact.mask(&json!("a@b.com")), json!("***"));
+        assert_eq!(
+            MaskingRule::Partial.mask(&json!("4111111111111234")),
+            json!("************1234")
+        );
+        // Short values keep at most half of their characters
+        assert_eq!(MaskingRule::Partial.mask(&json!("abcd")), json!("**cd"));
+        assert_eq!(MaskingRule::Partial.mask(&json!(123456)), json!("***456"));
+        assert_eq!(MaskingRule::Null.mask(&json!("a@b.com")), json!(null));
+        assert_eq!(MaskingRule::Redact.mask(&json!(null)), json!(null));
+    }
+
+    #[test]
+    fn test_masked_columns() {
+        let table = table();
+        let masked_columns = SampleRowsOptions::default().masked_columns(&table);
+        assert_eq!(masked_columns, HashSet::from(["email", "salary"]));
+    }
+
+    #[test]
+    fn test_mask_row() {
+        let table = table();
+        let masked_columns = SampleRowsOptions::default().masked_columns(&table);
+        let row = json!({"id": 1, "email": "a@b.com", "salary": 100, "city": "Austin"});
+        let masked = mask_row(
+            row.as_object().unwrap().clone(),
+            &masked_columns,
+            MaskingRule::Redact,
+        );
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&masked).unwrap(),
+            json!({"id": 1, "email": "***", "salary": "***", "city": "Austin"})
+        );
+    }
+
+    #[test]
+    fn test_sample_queries() {
+        let queries = sample_queries("postgres", "sales.orders", 3).unwrap();
+        assert_eq!(
+            queries.top,
+            "SELECT * FROM \"sales\".\"orders\" LIMIT 3".to_string()
+        );
+        assert_eq!(
+            queries.random,
+            "SELECT * FROM \"sales\".\"orders\" ORDER BY RANDOM() LIMIT 3".to_string()
+        );
+        let queries = sample_queries("mysql", "orders", 5).unwrap();
+        assert_eq!(
+            queries.bottom,
+            "SELECT * FROM `orders` LIMIT 5 OFFSET {offset}".to_string()
+        );
+        assert_eq!(
+            sample_queries("mysql", "orders; DROP TABLE orders", 5),
+            Err(SampleRowsError::InvalidTableName(
+                "orders; DROP TABLE orders".to_string()
+            ))
+        );
+        assert!(sample_queries("opensearch", "orders", 5).is_err());
+    }
+
+    #[test]
+    fn test_with_sample_rows() {
+        let datasource: AppDataSource = serde_json::from_value(json!({
+            "filestore": {},
+            "datastore": {
+                "aws_rds": [{
+                    "host": "db.example.com",
+                    "port": "5432",
+                    "username": null,
+                    "secret_name": null,
+                    "aws_service_name": null,
+                    "database": "crm",
+                    "db_type": "postgres",
+                    "descriptions": null,
+                    "region": null,
+                    "fact_phrases": null,
+                    "fact_words": null,
+                    "search_keywords": null,
+                    "summary": null,
+                    "tables": [table()]
+                }]
+            }
+        }))
+        .unwrap();
+        let captured = SampleRows {
+            top_rows: vec![json!({"id": 1}).to_string()],
+            random_rows: vec![],
+            bottom_rows: vec![],
+        };
+        let sample_rows = vec![TableSampleRows {
+            source_type: "aws_rds".to_string(),
+            host: "db.example.com".to_string(),
+            database: "crm".to_string(),
+            table: "customers".to_string(),
+            sample_rows: captured.clone(),
+        }];
+
+        let sampled = with_sample_rows(&datasource, &sample_rows);
+
+        assert_eq!(
+            sampled.datastore["aws_rds"][0].tables[0].sample_rows,
+            Some(captured)
+        );
+        // The declared datasource is left untouched
+        assert_ne!(sampled, datasource);
+    }
+}
@@ -4,4 +4,6 @@
  * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
  */
 pub mod app_onboarding_request;
+pub mod apply_plan;
 pub mod response;
+pub mod schema_preview;
@@ -6,7 +6,7 @@
 //! This module contains the schema for the app onboarding request
 
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use utoipa::ToSchema;
 
 #[derive(Serialize, Deserialize, Debug, Clone, ToSchema, PartialEq)]
@@ -18,6 +18,28 @@ pub struct OnboardingRequest {
     pub csv_append_same_schema: bool,
     pub allowed_models: Vec<LlmModel>,
     pub app_datasource: AppDataSource,
+    /// Residency (DocumentDB cluster name) of the app specific collections, the primary cluster if not set.
+    pub residency: Option<String>,
+    /// Rate limit of the retrievals of each end user of the app, unlimited if not set.
+    pub user_rate_limit: Option<UserRateLimit>,
+    /// Tier of the app, selecting the usage plan of its API key. The default usage plan if not set.
+    pub tier: Option<String>,
+    /// URL the outcome of the background onboarding steps is POSTed to, signed with the webhook secret.
+    pub notification_url: Option<String>,
+    /// Normalizes the retrieval queries of the app before they are sent to the knowledge engine. Disabled if not set.
+    pub query_normalization: Option<bool>,
+    /// Stores the `user_details.user_id` of the retrievals of the app as pseudonyms. Disabled if not set.
+    pub pseudonymize_user_ids: Option<bool>,
+    /// Custom key/value metadata of the app, e.g. its owner team, cost center or Slack channel. At most 20 entries,
+    /// keys of 1 to 64 letters, digits, `_` or `-` and values of at most 256 characters.
+    pub metadata: Option<BTreeMap<String, String>>,
+}
+
+/// Sliding window rate limit of the retrievals of an end user, keyed by `user_details.user_id`.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema, PartialEq)]
+pub struct UserRateLimit {
+    pub max_requests: u32,
+    pub window_seconds: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, ToSchema, PartialEq)]
@@ -41,12 +63,99 @@ pub struct LlmModel {
 pub struct AppDataSource {
     pub filestore: HashMap<String, Vec<FileStore>>,
     pub datastore: HashMap<String, Vec<DataStore>>,
+    /// Overrides of the supported file types for the filestores of the app.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_types: Option<FileTypeOverrides>,
+}
+
+/// Per-app overrides of the file type registry, applied to the connectivity checks of the filestores.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema, PartialEq)]
+pub struct FileTypeOverrides {
+    /// Extensions supported for the app on top of the supported file types.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extensions: Vec<String>,
+    /// Supported file types excluded for the app.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded_extensions: Vec<String>,
+    /// Handling of the URLs of unsupported file types, the `file_types.mode` of the settings if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<FileTypeMode>,
+    /// Maximum size in MB of the files by extension, overriding the `file_types.max_size_mb` of the settings.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub max_size_mb: BTreeMap<String, u64>,
+}
+
+/// Handling of the filestore URLs of unsupported file types or oversized files during onboarding.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileTypeMode {
+    /// A URL of an unsupported file type or an oversized file fails the onboarding.
+    #[default]
+    Error,
+    /// The URLs of unsupported file types and the oversized files are reported as warnings and skipped.
+    Warn,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, ToSchema, PartialEq)]
 pub struct FileStore {
     pub url: String,
     pub hints: Vec<Hint>,
+    #[serde(default, skip_serializing_if = "ValidationMode::is_strict")]
+    pub validation_mode: ValidationMode,
+    #[serde(default, skip_serializing_if = "ListingMode::is_objects")]
+    pub listing_mode: ListingMode,
+    /// S3 URL of the `manifest.json` of an S3 Inventory report of the bucket, for the `inventory` listing mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inventory_manifest: Option<String>,
+    /// Labels of the filestore, e.g. `hr-docs`, naming it in the search scope of a retrieval.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Vec<String>>,
+}
+
+/// Listing of the objects of a filestore URL during its connectivity check.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListingMode {
+    /// The object is fetched, or the objects matched by a wildcard are listed (up to 10 000 of them).
+    #[default]
+    Objects,
+    /// All the ListObjectsV2 pages under the prefix are summarized (count and size), without fetching objects.
+    Summary,
+    /// The objects are read from the S3 Inventory report at `inventory_manifest`, for buckets with millions of objects.
+    Inventory,
+}
+
+impl ListingMode {
+    pub fn is_objects(&self) -> bool {
+        *self == ListingMode::Objects
+    }
+}
+
+/// Handling of the connectivity failures of a data source during onboarding.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidationMode {
+    /// Connectivity failures abort the onboarding.
+    #[default]
+    Strict,
+    /// Connectivity failures are returned as warnings, e.g. for buckets whose permissions are granted after onboarding.
+    Warn,
+    /// The connectivity is not checked.
+    Skip,
+}
+
+impl ValidationMode {
+    pub fn is_strict(&self) -> bool {
+        *self == ValidationMode::Strict
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ValidationMode::Strict => "strict",
+            ValidationMode::Warn => "warn",
+            ValidationMode::Skip => "skip",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, ToSchema, PartialEq)]
@@ -71,6 +180,11 @@ pub struct DataStore {
     pub fact_words: Option<Vec<String>>,
     pub search_keywords: Option<Vec<String>>,
     pub summary: Option<String>,
+    #[serde(default, skip_serializing_if = "ValidationMode::is_strict")]
+    pub validation_mode: ValidationMode,
+    /// Labels of the datastore, e.g. `finance-db`, naming it in the search scope of a retrieval.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, ToSchema, PartialEq)]
@@ -85,12 +199,31 @@ pub struct Table {
     pub fact_words: Option<Vec<String>>,
     pub search_keywords: Option<Vec<String>>,
     pub summary: Option<String>,
+    /// Row-level security filter template, e.g. `tenant_id = :user_tenant`, bound by the knowledge engine from the
+    /// access details of the user of each retrieval.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub row_filter: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, ToSchema, PartialEq)]
 pub struct Column {
     pub name: String,
     pub descriptions: String,
+    /// Whether the column holds personally identifiable information, masked by the ingestion/retrieval layers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pii: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensitivity: Option<Sensitivity>,
+}
+
+/// Sensitivity level of the data of a column. A PII column cannot be `public`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Sensitivity {
+    Public,
+    Internal,
+    Confidential,
+    Restricted,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, ToSchema, PartialEq)]
@@ -125,7 +258,18 @@ mod tests {
             app_datasource: AppDataSource {
                 filestore: HashMap::new(),
                 datastore: HashMap::new(),
+                file_types: None,
             },
+            residency: None,
+            user_rate_limit: Some(UserRateLimit {
+                max_requests: 10,
+                window_seconds: 60,
+            }),
+            tier: Some("standard".to_string()),
+            notification_url: None,
+            query_normalization: None,
+            pseudonymize_user_ids: None,
+            metadata: None,
         };
 
         let serialized = serde_json::to_string(&onboarding_request).unwrap();
@@ -164,6 +308,11 @@ mod tests {
         let app_datasource = AppDataSource {
             filestore,
             datastore,
+            file_types: Some(FileTypeOverrides {
+                extensions: vec!["md".to_string()],
+                mode: Some(FileTypeMode::Warn),
+                ..Default::default()
+            }),
         };
 
         let serialized = serde_json::to_string(&app_datasource).unwrap();
@@ -177,6 +326,12 @@ mod tests {
         let filestore = FileStore {
             url: "https://example.com".to_string(),
             hints: vec![],
+            validation_mode: ValidationMode::Warn,
+            listing_mode: ListingMode::Inventory,
+            inventory_manifest: Some(
+                "s3://inventory-bucket/example/config/2024-07-01T01-00Z/manifest.json".to_string(),
+            ),
+            labels: Some(vec!["hr-docs".to_string()]),
         };
 
         let serialized = serde_json::to_string(&filestore).unwrap();
@@ -215,6 +370,8 @@ mod tests {
             fact_words: None,
             search_keywords: None,
             summary: None,
+            validation_mode: ValidationMode::default(),
+            labels: None,
         };
 
         let serialized = serde_json::to_string(&datastore).unwrap();
@@ -236,6 +393,7 @@ mod tests {
             fact_words: None,
             search_keywords: None,
             summary: None,
+            row_filter: Some("tenant_id = :user_tenant".to_string()),
         };
 
         let serialized = serde_json::to_string(&table).unwrap();
@@ -249,12 +407,29 @@ mod tests {
         let column = Column {
             name: "test_column".to_string(),
             descriptions: "This is a test column".to_string(),
+            pii: Some(true),
+            sensitivity: Some(Sensitivity::Restricted),
         };
 
         let serialized = serde_json::to_string(&column).unwrap();
         let deserialized: Column = serde_json::from_str(&serialized).unwrap();
 
         assert_eq!(column, deserialized);
+        assert!(serialized.contains(r#""sensitivity":"restricted""#));
+    }
+
+    #[test]
+    fn test_success_untagged_column_deserialization() {
+        let column: Column =
+            serde_json::from_str(r#"{"name": "id", "descriptions": "identifier"}"#).unwrap();
+
+        assert_eq!(column.pii, None);
+        assert_eq!(column.sensitivity, None);
+        // Untagged columns are serialized without the tags, e.g. in the Kafka events
+        assert_eq!(
+            serde_json::to_string(&column).unwrap(),
+            r#"{"name":"id","descriptions":"identifier"}"#
+        );
     }
 
     #[test]
@@ -0,0 +1,93 @@
+/*
+ * Created Date:  Jun 20, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the schema of the plan computed when applying an app descriptor.
+//!
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, ToSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanAction {
+    Create,
+    Update,
+    Remove,
+    NoChange,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, ToSchema, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum DatasourceKind {
+    Filestore,
+    Datastore,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema, PartialEq)]
+pub struct DatasourceChange {
+    pub action: PlanAction,
+    pub kind: DatasourceKind,
+    pub source_type: String,
+    pub identifier: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema, PartialEq)]
+pub struct ApplyPlan {
+    pub app_name: String,
+    pub app_action: PlanAction,
+    pub settings_changed: bool,
+    pub datasource_changes: Vec<DatasourceChange>,
+}
+
+impl ApplyPlan {
+    /// Returns true if applying the plan changes nothing.
+    pub fn is_empty(&self) -> bool {
+        self.app_action == PlanAction::NoChange
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ApplyResponse {
+    pub status: String,
+    pub message: String,
+    pub dry_run: bool,
+    pub plan: ApplyPlan,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub estimated_ingestion_size: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_apply_plan_serialization() {
+        let plan = ApplyPlan {
+            app_name: "app100".to_string(),
+            app_action: PlanAction::Update,
+            settings_changed: false,
+            datasource_changes: vec![DatasourceChange {
+                action: PlanAction::Remove,
+                kind: DatasourceKind::Filestore,
+                source_type: "aws_s3".to_string(),
+                identifier: "s3://bucket".to_string(),
+            }],
+        };
+
+        let serialized = serde_json::to_value(&plan).unwrap();
+        assert_eq!(serialized["app_action"], "update");
+        assert_eq!(serialized["datasource_changes"][0]["action"], "remove");
+        assert_eq!(serialized["datasource_changes"][0]["kind"], "filestore");
+        assert!(!plan.is_empty());
+    }
+}
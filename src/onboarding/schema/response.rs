@@ -3,17 +3,35 @@
  * -----
  * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
  */
-use serde::Serialize;
+use super::schema_preview::SchemaPreview;
+use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
 #[allow(non_snake_case)]
-#[derive(Serialize, Debug, ToSchema)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct AppCreateResponse {
     pub status: String,
     pub message: String,
     pub api_key: String,
     pub app_id: String,
     pub reference_id: String,
+    /// Non-fatal findings of the datasource validation, e.g. empty prefixes or skipped files.
+    #[serde(default)]
+    pub warnings: Vec<String>,
+    /// Estimated size in bytes of the files to ingest, if any file store was listed.
+    #[serde(default)]
+    pub estimated_ingestion_size: Option<u64>,
+    /// Schema previews of the CSV and Parquet files of the file stores.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schema_previews: Vec<SchemaPreview>,
+}
+
+/// Response of an onboarding/update request whose datasources are validated by a background job.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ValidationJobCreateResponse {
+    pub status: String,
+    pub message: String,
+    pub validation_job_id: String,
 }
 
 #[derive(Serialize, Debug, ToSchema)]
@@ -36,6 +54,9 @@ mod tests {
             api_key: "api_key".to_string(),
             app_id: "app_id".to_string(),
             reference_id: "reference_id".to_string(),
+            warnings: vec!["warning".to_string()],
+            estimated_ingestion_size: Some(1024),
+            schema_previews: Vec::new(),
         };
         assert_eq!(app_create_response.status, "status".to_string());
         assert_eq!(app_create_response.message, "message".to_string());
@@ -0,0 +1,51 @@
+/*
+ * Created Date:  Aug 01, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the schema of the schema previews of the tabular files of the filestore datasources,
+//! returned by the onboarding request (see `crate::onboarding::schema_preview`). It is also compiled into the CLI, so
+//! it only depends on serde and utoipa.
+//!
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Format of a tabular file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TabularFormat {
+    Csv,
+    Parquet,
+}
+
+impl TabularFormat {
+    /// Format of the files of an extension, `None` for the non tabular files.
+    pub fn of(extension: &str) -> Option<Self> {
+        match extension.to_lowercase().as_str() {
+            "csv" => Some(TabularFormat::Csv),
+            "parquet" => Some(TabularFormat::Parquet),
+            _ => None,
+        }
+    }
+}
+
+/// Column of a previewed file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ColumnPreview {
+    pub name: String,
+    pub data_type: String,
+}
+
+/// Schema preview of a tabular file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct SchemaPreview {
+    /// Filestore URL matching the file.
+    pub url: String,
+    /// `s3://` URI of the file.
+    pub object: String,
+    pub format: TabularFormat,
+    pub columns: Vec<ColumnPreview>,
+    /// Number of rows, read from the footer of a Parquet file and estimated from the sampled rows of a CSV file.
+    pub estimated_rows: u64,
+}
@@ -0,0 +1,998 @@
+/*
+ * Created Date:  Aug 01, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the schema previews of the tabular files (CSV and Parquet) of the filestore datasources,
+//! inferred during their connectivity check so the users confirm the files are interpreted as expected.
+//! A CSV file is previewed from its first `schema_preview.sample_bytes` (64 KiB): the columns of its header row, the
+//! type of each column inferred from the sampled rows (`integer`, `float`, `boolean`, `date`, `timestamp` or
+//! `string`) and its row count, estimated from the size of the sampled rows. A Parquet file is previewed from the
+//! schema and the row count of its footer. Up to 3 files are previewed per URL.
+//! The previews are returned by the onboarding request and stored on the app document as `schema_previews`. With
+//! `csv_append_same_schema`, the CSV files of a URL are appended to a single table, so the previews of a URL with
+//! different columns are returned as warnings.
+//! A file which can't be previewed is reported as a warning, the previews never fail an onboarding.
+//!
+
+use crate::admin_ui_api::schema::UpdateResponse;
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{SchemaPreviewSettings, TresleFacadeServiceSettings};
+use crate::onboarding::schema::schema_preview::{ColumnPreview, SchemaPreview, TabularFormat};
+use crate::service::object_store::ObjectStore;
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use mongodb::bson::{doc, to_bson};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{error, instrument};
+use utoipa::ToSchema;
+
+/// Name of the field of the app document holding the schema previews.
+pub const SCHEMA_PREVIEWS_FIELD: &str = "schema_previews";
+/// Maximum number of tabular files previewed per URL.
+pub const MAX_PREVIEWED_FILES: usize = 3;
+/// Default number of bytes sampled from a CSV file or from the end of a Parquet file.
+const DEFAULT_SAMPLE_BYTES: u64 = 64 * 1024;
+/// Maximum size of the footer of a previewed Parquet file.
+const MAX_PARQUET_FOOTER_BYTES: u64 = 4 * 1024 * 1024;
+/// Magic number of the Parquet files, at their start and end.
+const PARQUET_MAGIC: &[u8] = b"PAR1";
+/// Maximum nesting of the values of the footer of a Parquet file, and of the columns of its schema. A nesting level
+/// costs about a byte, so deeper footers are rejected instead of overflowing the stack.
+const MAX_PARQUET_NESTING: usize = 64;
+/// Delimiters of the CSV files, the most frequent one in the header row is used, the first one on a tie.
+const CSV_DELIMITERS: [char; 4] = [',', ';', '\t', '|'];
+
+/// Schema preview options: activation and sample size.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaPreviewOptions {
+    pub enabled: bool,
+    pub sample_bytes: u64,
+}
+
+impl SettingsOptions for SchemaPreviewOptions {
+    type Settings = SchemaPreviewSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&SchemaPreviewSettings> {
+        settings.schema_preview.as_ref()
+    }
+
+    fn from_settings(settings: Option<&SchemaPreviewSettings>) -> Self {
+        SchemaPreviewOptions {
+            enabled: settings
+                .and_then(|settings| settings.enabled)
+                .unwrap_or(true),
+            sample_bytes: settings
+                .and_then(|settings| settings.sample_bytes)
+                .filter(|sample_bytes| *sample_bytes > 0)
+                .unwrap_or(DEFAULT_SAMPLE_BYTES),
+        }
+    }
+}
+
+/// Previews the schema of a tabular file of `size` bytes matched by the filestore URL `url`.
+pub async fn preview_object(
+    s3_client: &Arc<dyn ObjectStore>,
+    options: &SchemaPreviewOptions,
+    url: &str,
+    bucket: &str,
+    key: &str,
+    size: u64,
+    format: TabularFormat,
+) -> Result<SchemaPreview, String> {
+    let (columns, estimated_rows) = match format {
+        TabularFormat::Csv => {
+            let head = s3_client
+                .read_object_head(bucket, key, options.sample_bytes)
+                .await?;
+            preview_csv(&head, size)?
+        }
+        TabularFormat::Parquet => preview_parquet(s3_client, options, bucket, key, size).await?,
+    };
+    Ok(SchemaPreview {
+        url: url.to_string(),
+        object: format!("s3://{}/{}", bucket, key),
+        format,
+        columns,
+        estimated_rows,
+    })
+}
+
+/// Infers the columns of a CSV file from its first bytes, and estimates its row count from its size.
+fn preview_csv(head: &[u8], size: u64) -> Result<(Vec<ColumnPreview>, u64), String> {
+    // The last row of a partial sample is cut
+    let partial = (head.len() as u64) < size;
+    let head = head.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(head);
+    let text = String::from_utf8_lossy(head);
+    let text = match partial {
+        true => text.rsplit_once('\n').map_or("", |(rows, _)| rows),
+        false => text.as_ref(),
+    };
+    let header = text.lines().next().unwrap_or_default();
+    let delimiter = CSV_DELIMITERS
+        .into_iter()
+        .rev()
+        .max_by_key(|delimiter| header.matches(*delimiter).count())
+        .unwrap_or(',');
+    let mut records = parse_csv_records(text, delimiter).into_iter();
+    let Some(header) = records
+        .next()
+        .filter(|header| header.iter().any(|name| !name.is_empty()))
+    else {
+        return Err("no header row found".to_string());
+    };
+
+    let mut data_types: Vec<Option<&'static str>> = vec![None; header.len()];
+    let mut rows = 0u64;
+    for record in records {
+        rows += 1;
+        for (data_type, value) in data_types.iter_mut().zip(record.iter()) {
+            *data_type = merge_data_types(*data_type, infer_data_type(value));
+        }
+    }
+    let columns = header
+        .into_iter()
+        .zip(data_types)
+        .enumerate()
+        .map(|(index, (name, data_type))| ColumnPreview {
+            name: match name.is_empty() {
+                true => format!("column_{}", index + 1),
+                false => name,
+            },
+            data_type: data_type.unwrap_or("string").to_string(),
+        })
+        .collect();
+    let estimated_rows = match (partial, text.len() as u64) {
+        (true, sampled_bytes) if sampled_bytes > 0 => rows * size / sampled_bytes,
+        _ => rows,
+    };
+    Ok((columns, estimated_rows))
+}
+
+/// Parses the records of a CSV text, with the quoted fields holding delimiters, line breaks and escaped quotes
+/// (`""`). The values are trimmed.
+fn parse_csv_records(text: &str, delimiter: char) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            c if c == delimiter && !quoted => {
+                record.push(std::mem::take(&mut field).trim().to_string())
+            }
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field).trim().to_string());
+                records.push(std::mem::take(&mut record));
+            }
+            '\r' if !quoted => {}
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field.trim().to_string());
+        records.push(record);
+    }
+    records
+        .into_iter()
+        .filter(|record| record.iter().any(|value| !value.is_empty()))
+        .collect()
+}
+
+/// Type of a CSV value, `None` for an empty value.
+fn infer_data_type(value: &str) -> Option<&'static str> {
+    if value.is_empty() {
+        return None;
+    }
+    let data_type = if value.parse::<i64>().is_ok() {
+        "integer"
+    } else if value.parse::<f64>().is_ok() && value.chars().any(|c| c.is_ascii_digit()) {
+        "float"
+    } else if value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("false") {
+        "boolean"
+    } else if NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok() {
+        "date"
+    } else if DateTime::parse_from_rfc3339(value).is_ok()
+        || NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f").is_ok()
+        || NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f").is_ok()
+    {
+        "timestamp"
+    } else {
+        "string"
+    };
+    Some(data_type)
+}
+
+/// Type of a column holding values of both types: the integers widen to floats, other mixes are strings.
+fn merge_data_types(
+    data_type: Option<&'static str>,
+    other: Option<&'static str>,
+) -> Option<&'static str> {
+    match (data_type, other) {
+        (None, other) => other,
+        (data_type, None) => data_type,
+        (Some(data_type), Some(other)) if data_type == other => Some(data_type),
+        (Some("integer"), Some("float")) | (Some("float"), Some("integer")) => Some("float"),
+        _ => Some("string"),
+    }
+}
+
+/// Reads the columns and the row count of a Parquet file from its footer.
+async fn preview_parquet(
+    s3_client: &Arc<dyn ObjectStore>,
+    options: &SchemaPreviewOptions,
+    bucket: &str,
+    key: &str,
+    size: u64,
+) -> Result<(Vec<ColumnPreview>, u64), String> {
+    let magic_length = PARQUET_MAGIC.len() as u64;
+    if size < 2 * magic_length + 4 {
+        return Err("not a Parquet file".to_string());
+    }
+    let tail_length = options.sample_bytes.clamp(magic_length + 4, size);
+    let tail = s3_client
+        .read_object_range(bucket, key, size - tail_length, tail_length)
+        .await?;
+    let footer_length = parquet_footer_length(&tail)?;
+    if footer_length + magic_length + 4 > size - magic_length {
+        return Err("the footer length exceeds the file size".to_string());
+    }
+    if footer_length > MAX_PARQUET_FOOTER_BYTES {
+        return Err(format!(
+            "the footer of {} bytes is above the previewed maximum of {} bytes",
+            footer_length, MAX_PARQUET_FOOTER_BYTES
+        ));
+    }
+    let footer_end = tail.len() - (magic_length + 4) as usize;
+    let metadata = match footer_length as usize <= footer_end {
+        true => tail[footer_end - footer_length as usize..footer_end].to_vec(),
+        false => {
+            s3_client
+                .read_object_range(
+                    bucket,
+                    key,
+                    size - magic_length - 4 - footer_length,
+                    footer_length,
+                )
+                .await?
+        }
+    };
+    let (schema, num_rows) = parse_parquet_metadata(&metadata)?;
+    Ok((parquet_columns(&schema)?, num_rows.max(0) as u64))
+}
+
+/// Length of the footer of a Parquet file, from the last 8 bytes of the file.
+fn parquet_footer_length(tail: &[u8]) -> Result<u64, String> {
+    if tail.len() < 8 || !tail.ends_with(PARQUET_MAGIC) {
+        return Err("not a Parquet file".to_string());
+    }
+    let length_bytes: [u8; 4] = tail[tail.len() - 8..tail.len() - 4]
+        .try_into()
+        .map_err(|_| "not a Parquet file".to_string())?;
+    Ok(u32::from_le_bytes(length_bytes) as u64)
+}
+
+/// Element of the schema of a Parquet file.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct SchemaElement {
+    name: String,
+    physical_type: Option<i32>,
+    num_children: Option<i32>,
+    converted_type: Option<i32>,
+    /// ID of the field of the logical type union.
+    logical_type: Option<i16>,
+}
+
+/// Types of the Thrift compact protocol.
+const THRIFT_TRUE: u8 = 1;
+const THRIFT_FALSE: u8 = 2;
+const THRIFT_BYTE: u8 = 3;
+const THRIFT_I16: u8 = 4;
+const THRIFT_I32: u8 = 5;
+const THRIFT_I64: u8 = 6;
+const THRIFT_DOUBLE: u8 = 7;
+const THRIFT_BINARY: u8 = 8;
+const THRIFT_LIST: u8 = 9;
+const THRIFT_SET: u8 = 10;
+const THRIFT_MAP: u8 = 11;
+const THRIFT_STRUCT: u8 = 12;
+
+/// Reader of the Thrift compact protocol, the encoding of the footer of the Parquet files.
+struct CompactReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    /// Nesting of the skipped value, up to `MAX_PARQUET_NESTING`.
+    depth: usize,
+}
+
+impl<'a> CompactReader<'a> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self
+            .data
+            .get(self.position)
+            .ok_or_else(|| "truncated footer".to_string())?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("invalid varint in footer".to_string())
+    }
+
+    fn integer(&mut self) -> Result<i64, String> {
+        let value = self.varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn binary(&mut self) -> Result<&'a [u8], String> {
+        let length = self.varint()? as usize;
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| "truncated footer".to_string())?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    /// Reads the header of the next field of a struct. `None` at the end of the struct.
+    fn field_header(&mut self, last_id: &mut i16) -> Result<Option<(i16, u8)>, String> {
+        let header = self.byte()?;
+        let field_type = header & 0x0F;
+        if field_type == 0 {
+            return Ok(None);
+        }
+        *last_id = match header >> 4 {
+            0 => self.integer()? as i16,
+            delta => *last_id + delta as i16,
+        };
+        Ok(Some((*last_id, field_type)))
+    }
+
+    /// Reads the header of a list or set: its size and the type of its elements.
+    fn list_header(&mut self) -> Result<(usize, u8), String> {
+        let header = self.byte()?;
+        let size = match header >> 4 {
+            15 => self.varint()? as usize,
+            size => size as usize,
+        };
+        Ok((size, header & 0x0F))
+    }
+
+    /// Skips a value of a type. Nested values deeper than `MAX_PARQUET_NESTING` are rejected.
+    fn skip(&mut self, value_type: u8) -> Result<(), String> {
+        if matches!(
+            value_type,
+            THRIFT_LIST | THRIFT_SET | THRIFT_MAP | THRIFT_STRUCT
+        ) {
+            if self.depth >= MAX_PARQUET_NESTING {
+                return Err(format!(
+                    "the footer is nested deeper than {} levels",
+                    MAX_PARQUET_NESTING
+                ));
+            }
+            self.depth += 1;
+            let skipped = self.skip_nested(value_type);
+            self.depth -= 1;
+            return skipped;
+        }
+        match value_type {
+            THRIFT_TRUE | THRIFT_FALSE => Ok(()),
+            THRIFT_BYTE => self.byte().map(|_| ()),
+            THRIFT_I16 | THRIFT_I32 | THRIFT_I64 => self.integer().map(|_| ()),
+            THRIFT_DOUBLE => (0..8).try_for_each(|_| self.byte().map(|_| ())),
+            THRIFT_BINARY => self.binary().map(|_| ()),
+            value_type => Err(format!("invalid type {} in footer", value_type)),
+        }
+    }
+
+    /// Skips a list, set, map or struct.
+    fn skip_nested(&mut self, value_type: u8) -> Result<(), String> {
+        match value_type {
+            THRIFT_LIST | THRIFT_SET => {
+                let (size, element_type) = self.list_header()?;
+                (0..size).try_for_each(|_| self.skip_element(element_type))
+            }
+            THRIFT_MAP => {
+                let size = self.varint()? as usize;
+                if size == 0 {
+                    return Ok(());
+                }
+                let types = self.byte()?;
+                (0..size).try_for_each(|_| {
+                    self.skip_element(types >> 4)?;
+                    self.skip_element(types & 0x0F)
+                })
+            }
+            THRIFT_STRUCT => {
+                let mut last_id = 0;
+                while let Some((_, field_type)) = self.field_header(&mut last_id)? {
+                    self.skip(field_type)?;
+                }
+                Ok(())
+            }
+            value_type => Err(format!("invalid type {} in footer", value_type)),
+        }
+    }
+
+    /// Skips an element of a list, set or map, whose booleans are encoded as a byte.
+    fn skip_element(&mut self, element_type: u8) -> Result<(), String> {
+        match element_type {
+            THRIFT_TRUE | THRIFT_FALSE => self.byte().map(|_| ()),
+            element_type => self.skip(element_type),
+        }
+    }
+}
+
+/// Parses the `FileMetaData` footer of a Parquet file into its schema and row count.
+fn parse_parquet_metadata(metadata: &[u8]) -> Result<(Vec<SchemaElement>, i64), String> {
+    let mut reader = CompactReader {
+        data: metadata,
+        position: 0,
+        depth: 0,
+    };
+    let mut schema = Vec::new();
+    let mut num_rows = 0;
+    let mut last_id = 0;
+    while let Some((id, field_type)) = reader.field_header(&mut last_id)? {
+        match (id, field_type) {
+            (2, THRIFT_LIST) => {
+                let (size, element_type) = reader.list_header()?;
+                for _ in 0..size {
+                    match element_type {
+                        THRIFT_STRUCT => schema.push(parse_schema_element(&mut reader)?),
+                        element_type => reader.skip_element(element_type)?,
+                    }
+                }
+            }
+            (3, THRIFT_I64) => num_rows = reader.integer()?,
+            (_, field_type) => reader.skip(field_type)?,
+        }
+    }
+    if schema.is_empty() {
+        return Err("no schema found in footer".to_string());
+    }
+    Ok((schema, num_rows))
+}
+
+/// Parses a `SchemaElement` of the schema of a Parquet file.
+fn parse_schema_element(reader: &mut CompactReader) -> Result<SchemaElement, String> {
+    let mut element = SchemaElement::default();
+    let mut last_id = 0;
+    while let Some((id, field_type)) = reader.field_header(&mut last_id)? {
+        match (id, field_type) {
+            (1, THRIFT_I32) => element.physical_type = Some(reader.integer()? as i32),
+            (4, THRIFT_BINARY) => {
+                element.name = String::from_utf8_lossy(reader.binary()?).into_owned()
+            }
+            (5, THRIFT_I32) => element.num_children = Some(reader.integer()? as i32),
+            (6, THRIFT_I32) => element.converted_type = Some(reader.integer()? as i32),
+            (10, THRIFT_STRUCT) => {
+                // The logical type is a union, its single field names the type
+                let mut union_id = 0;
+                if let Some((id, field_type)) = reader.field_header(&mut union_id)? {
+                    element.logical_type = Some(id);
+                    reader.skip(field_type)?;
+                    while let Some((_, field_type)) = reader.field_header(&mut union_id)? {
+                        reader.skip(field_type)?;
+                    }
+                }
+            }
+            (_, field_type) => reader.skip(field_type)?,
+        }
+    }
+    Ok(element)
+}
+
+/// Columns of the schema of a Parquet file, the nested columns named by their path. The lists and maps are single
+/// columns. Schemas nested deeper than `MAX_PARQUET_NESTING` are rejected.
+fn parquet_columns(schema: &[SchemaElement]) -> Result<Vec<ColumnPreview>, String> {
+    let mut columns = Vec::new();
+    let mut index = 1;
+    let root_children = schema
+        .first()
+        .and_then(|root| root.num_children)
+        .unwrap_or(0);
+    // The children are bounded by the schema, whatever their declared number
+    for _ in 0..root_children {
+        if index >= schema.len() {
+            break;
+        }
+        collect_parquet_columns(schema, &mut index, "", 1, &mut columns)?;
+    }
+    Ok(columns)
+}
+
+fn collect_parquet_columns(
+    schema: &[SchemaElement],
+    index: &mut usize,
+    prefix: &str,
+    depth: usize,
+    columns: &mut Vec<ColumnPreview>,
+) -> Result<(), String> {
+    if depth > MAX_PARQUET_NESTING {
+        return Err(format!(
+            "the schema is nested deeper than {} levels",
+            MAX_PARQUET_NESTING
+        ));
+    }
+    let Some(element) = schema.get(*index) else {
+        return Ok(());
+    };
+    *index += 1;
+    let name = match prefix.is_empty() {
+        true => element.name.clone(),
+        false => format!("{}.{}", prefix, element.name),
+    };
+    let num_children = element.num_children.unwrap_or(0);
+    if num_children <= 0 {
+        columns.push(ColumnPreview {
+            name,
+            data_type: parquet_data_type(element).to_string(),
+        });
+        return Ok(());
+    }
+    // Logical types and converted types of the lists and maps
+    let nested_type = match (element.logical_type, element.converted_type) {
+        (Some(2), _) | (_, Some(1)) | (_, Some(2)) => Some("map"),
+        (Some(3), _) | (_, Some(3)) => Some("list"),
+        _ => None,
+    };
+    match nested_type {
+        Some(data_type) => {
+            let mut children = Vec::new();
+            for _ in 0..num_children {
+                if *index >= schema.len() {
+                    break;
+                }
+                collect_parquet_columns(schema, index, &name, depth + 1, &mut children)?;
+            }
+            columns.push(ColumnPreview {
+                name,
+                data_type: data_type.to_string(),
+            });
+        }
+        None => {
+            for _ in 0..num_children {
+                if *index >= schema.len() {
+                    break;
+                }
+                collect_parquet_columns(schema, index, &name, depth + 1, columns)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Type of a leaf column of a Parquet file, from its logical, converted or physical type.
+fn parquet_data_type(element: &SchemaElement) -> &'static str {
+    let logical_type = match element.logical_type {
+        Some(1) | Some(4) | Some(12) | Some(14) => Some("string"),
+        Some(5) => Some("decimal"),
+        Some(6) => Some("date"),
+        Some(7) => Some("time"),
+        Some(8) => Some("timestamp"),
+        Some(10) => Some("integer"),
+        Some(15) => Some("float"),
+        _ => None,
+    };
+    let converted_type = match element.converted_type {
+        Some(0) | Some(4) | Some(19) => Some("string"),
+        Some(5) => Some("decimal"),
+        Some(6) => Some("date"),
+        Some(7) | Some(8) => Some("time"),
+        Some(9) | Some(10) => Some("timestamp"),
+        Some(11..=18) => Some("integer"),
+        _ => None,
+    };
+    logical_type
+        .or(converted_type)
+        .unwrap_or(match element.physical_type {
+            Some(0) => "boolean",
+            Some(1) | Some(2) => "integer",
+            Some(3) => "timestamp",
+            Some(4) | Some(5) => "float",
+            _ => "binary",
+        })
+}
+
+/// Warnings of the CSV previews of the URLs whose files have different columns, when `csv_append_same_schema` appends
+/// the CSV files of a URL to a single table.
+pub fn csv_schema_warnings(
+    previews: &[SchemaPreview],
+    csv_append_same_schema: bool,
+) -> Vec<String> {
+    if !csv_append_same_schema {
+        return Vec::new();
+    }
+    let mut previews_by_url: BTreeMap<&str, Vec<&SchemaPreview>> = BTreeMap::new();
+    for preview in previews
+        .iter()
+        .filter(|preview| preview.format == TabularFormat::Csv)
+    {
+        previews_by_url
+            .entry(&preview.url)
+            .or_default()
+            .push(preview);
+    }
+    previews_by_url
+        .into_iter()
+        .filter_map(|(url, previews)| {
+            let column_names = |preview: &SchemaPreview| {
+                preview
+                    .columns
+                    .iter()
+                    .map(|column| column.name.clone())
+                    .collect::<Vec<_>>()
+            };
+            let first = previews.first()?;
+            let different = previews
+                .iter()
+                .find(|preview| column_names(preview) != column_names(first))?;
+            Some(format!(
+                "Warning: The CSV files of URL '{}' have different columns, and csv_append_same_schema appends them to the same table: {:?} in '{}', {:?} in '{}'.",
+                url,
+                column_names(first),
+                first.object,
+                column_names(different),
+                different.object
+            ))
+        })
+        .collect()
+}
+
+/// Stores the schema previews of the datasources of an app on its app document.
+#[instrument(skip_all)]
+pub async fn record_schema_previews(
+    app_state: &AppState,
+    app_name: &str,
+    previews: &[SchemaPreview],
+) -> Result<(), String> {
+    let previews = to_bson(previews)
+        .map_err(|e| format!("Failed to serialize schema previews to BSON. Error: {}", e))?;
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let result = app_state
+        .db
+        .update_document(
+            collection_name,
+            doc! {"app_name": app_name},
+            doc! {SCHEMA_PREVIEWS_FIELD: previews},
+        )
+        .await
+        .map_err(ErrorInterceptor::from)
+        .map_err(|e| {
+            format!(
+                "Failed to store schema previews of app '{}'. Error: {}",
+                app_name, e
+            )
+        })
+        .and_then(|json_result| {
+            serde_json::from_value::<UpdateResponse>(json_result)
+                .map_err(|e| format!("Failed to deserialize update response. Error: {:?}", e))
+        })
+        .and_then(|result| match result.matchedCount {
+            0 => Err(format!("No app found with name '{}'.", app_name)),
+            _ => Ok(()),
+        });
+    if let Err(error_message) = &result {
+        error!(
+            app_name = app_name,
+            ext_message = error_message,
+            message = error_message
+        );
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::object_store::LocalObjectStore;
+    use tempfile::tempdir;
+    use tokio::runtime::Runtime;
+
+    fn varint(out: &mut Vec<u8>, mut value: u64) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+
+    fn integer(out: &mut Vec<u8>, value: i64) {
+        varint(out, ((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    /// Encodes a `SchemaElement` with the Thrift compact protocol.
+    fn schema_element(
+        out: &mut Vec<u8>,
+        name: &str,
+        physical_type: Option<i64>,
+        num_children: Option<i64>,
+        converted_type: Option<i64>,
+    ) {
+        let mut last_id = 0;
+        let mut field = |out: &mut Vec<u8>, id: i16, field_type: u8| {
+            out.push((((id - last_id) as u8) << 4) | field_type);
+            last_id = id;
+        };
+        if let Some(physical_type) = physical_type {
+            field(out, 1, THRIFT_I32);
+            integer(out, physical_type);
+        }
+        field(out, 4, THRIFT_BINARY);
+        varint(out, name.len() as u64);
+        out.extend_from_slice(name.as_bytes());
+        if let Some(num_children) = num_children {
+            field(out, 5, THRIFT_I32);
+            integer(out, num_children);
+        }
+        if let Some(converted_type) = converted_type {
+            field(out, 6, THRIFT_I32);
+            integer(out, converted_type);
+        }
+        out.push(0);
+    }
+
+    /// A Parquet file of the columns `id` (INT64), `name` (UTF8), `price` (DOUBLE) and `tags` (LIST), and 42 rows.
+    fn parquet_file() -> Vec<u8> {
+        let mut metadata = Vec::new();
+        // version
+        metadata.push(0x10 | THRIFT_I32);
+        integer(&mut metadata, 1);
+        // schema
+        metadata.push(0x10 | THRIFT_LIST);
+        metadata.push((7 << 4) | THRIFT_STRUCT);
+        schema_element(&mut metadata, "schema", None, Some(4), None);
+        schema_element(&mut metadata, "id", Some(2), None, None);
+        schema_element(&mut metadata, "name", Some(6), None, Some(0));
+        schema_element(&mut metadata, "price", Some(5), None, None);
+        schema_element(&mut metadata, "tags", None, Some(1), Some(3));
+        schema_element(&mut metadata, "list", None, Some(1), None);
+        schema_element(&mut metadata, "element", Some(6), None, Some(0));
+        // num_rows
+        metadata.push(0x10 | THRIFT_I64);
+        integer(&mut metadata, 42);
+        // row_groups, skipped
+        metadata.push(0x10 | THRIFT_LIST);
+        metadata.push(THRIFT_STRUCT);
+        metadata.push(0);
+
+        let mut file = PARQUET_MAGIC.to_vec();
+        file.extend_from_slice(&[0; 128]);
+        file.extend_from_slice(&metadata);
+        file.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+        file.extend_from_slice(PARQUET_MAGIC);
+        file
+    }
+
+    #[test]
+    fn test_success_schema_preview_options() {
+        let options = SchemaPreviewOptions::from_settings(None);
+        assert!(options.enabled);
+        assert_eq!(options.sample_bytes, 64 * 1024);
+
+        let settings = SchemaPreviewSettings {
+            enabled: Some(false),
+            sample_bytes: Some(0),
+        };
+        let options = SchemaPreviewOptions::from_settings(Some(&settings));
+        assert!(!options.enabled);
+        assert_eq!(options.sample_bytes, 64 * 1024);
+    }
+
+    #[test]
+    fn test_success_preview_csv() {
+        let csv = "\u{feff}id,name,price,in_stock,added_on,updated_at\n\
+            1,\"Widget, large\",9.99,true,2024-07-01,2024-07-01T10:00:00Z\n\
+            2,Gadget,10,false,2024-07-02,2024-07-02 11:00:00\n\
+            3,,,TRUE,,\n";
+        let (columns, estimated_rows) = preview_csv(csv.as_bytes(), csv.len() as u64).unwrap();
+        let data_types: Vec<(&str, &str)> = columns
+            .iter()
+            .map(|column| (column.name.as_str(), column.data_type.as_str()))
+            .collect();
+        assert_eq!(
+            data_types,
+            vec![
+                ("id", "integer"),
+                ("name", "string"),
+                ("price", "float"),
+                ("in_stock", "boolean"),
+                ("added_on", "date"),
+                ("updated_at", "timestamp"),
+            ]
+        );
+        assert_eq!(estimated_rows, 3);
+
+        // A partial sample estimates the row count from the size of the file, without its cut row
+        let head = "a;b\n1;x\n2;y\n3;z";
+        let (columns, estimated_rows) = preview_csv(head.as_bytes(), 1100).unwrap();
+        assert_eq!(columns.len(), 2);
+        assert_eq!(columns[0].data_type, "integer");
+        assert_eq!(estimated_rows, 2 * 1100 / 11);
+
+        assert!(preview_csv(b"", 0).is_err());
+    }
+
+    #[test]
+    fn test_success_parse_csv_records() {
+        assert_eq!(
+            parse_csv_records("a,b\n\"x, \"\"y\"\"\",\"multi\nline\"\r\n\n", ','),
+            vec![
+                vec!["a".to_string(), "b".to_string()],
+                vec!["x, \"y\"".to_string(), "multi\nline".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_success_parse_parquet_metadata() {
+        let file = parquet_file();
+        let footer_length = parquet_footer_length(&file).unwrap() as usize;
+        let metadata = &file[file.len() - 8 - footer_length..file.len() - 8];
+        let (schema, num_rows) = parse_parquet_metadata(metadata).unwrap();
+        assert_eq!(num_rows, 42);
+        assert_eq!(schema.len(), 7);
+
+        let columns: Vec<(String, String)> = parquet_columns(&schema)
+            .unwrap()
+            .into_iter()
+            .map(|column| (column.name, column.data_type))
+            .collect();
+        assert_eq!(
+            columns,
+            vec![
+                ("id".to_string(), "integer".to_string()),
+                ("name".to_string(), "string".to_string()),
+                ("price".to_string(), "float".to_string()),
+                ("tags".to_string(), "list".to_string()),
+            ]
+        );
+
+        assert!(parquet_footer_length(b"not parquet").is_err());
+        assert!(parse_parquet_metadata(&metadata[..10]).is_err());
+    }
+
+    #[test]
+    fn test_failure_parse_parquet_metadata_nested_too_deeply() {
+        // Field 1 of the footer holds 10 000 nested structs, one byte each
+        let mut metadata = vec![0x1C; 10_000];
+        metadata.extend(vec![0; 10_001]);
+        assert!(parse_parquet_metadata(&metadata)
+            .unwrap_err()
+            .contains("nested deeper"));
+
+        // A schema of 10 000 groups nested in each other
+        let mut schema = vec![
+            SchemaElement {
+                num_children: Some(1),
+                ..Default::default()
+            };
+            10_000
+        ];
+        schema.push(SchemaElement {
+            name: "leaf".to_string(),
+            ..Default::default()
+        });
+        assert!(parquet_columns(&schema)
+            .unwrap_err()
+            .contains("nested deeper"));
+
+        // A declared number of children above the schema is bounded by the schema
+        let schema = vec![SchemaElement {
+            num_children: Some(i32::MAX),
+            ..Default::default()
+        }];
+        assert!(parquet_columns(&schema).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_success_preview_object() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let root = tempdir().unwrap();
+            std::fs::create_dir_all(root.path().join("bucket/data")).unwrap();
+            let parquet = parquet_file();
+            std::fs::write(root.path().join("bucket/data/a.parquet"), &parquet).unwrap();
+            std::fs::write(root.path().join("bucket/data/b.csv"), b"id,name\n1,x\n").unwrap();
+            let s3_client: Arc<dyn ObjectStore> = Arc::new(LocalObjectStore::new(root.path()));
+
+            // A footer beyond the sampled tail is read on its own
+            let options = SchemaPreviewOptions {
+                enabled: true,
+                sample_bytes: 16,
+            };
+            let preview = preview_object(
+                &s3_client,
+                &options,
+                "s3://bucket/data/*",
+                "bucket",
+                "data/a.parquet",
+                parquet.len() as u64,
+                TabularFormat::Parquet,
+            )
+            .await
+            .unwrap();
+            assert_eq!(preview.object, "s3://bucket/data/a.parquet");
+            assert_eq!(preview.columns.len(), 4);
+            assert_eq!(preview.estimated_rows, 42);
+
+            let preview = preview_object(
+                &s3_client,
+                &SchemaPreviewOptions::from_settings(None),
+                "s3://bucket/data/*",
+                "bucket",
+                "data/b.csv",
+                12,
+                TabularFormat::Csv,
+            )
+            .await
+            .unwrap();
+            assert_eq!(preview.format, TabularFormat::Csv);
+            assert_eq!(preview.estimated_rows, 1);
+
+            assert!(preview_object(
+                &s3_client,
+                &options,
+                "s3://bucket/data/*",
+                "bucket",
+                "data/b.csv",
+                12,
+                TabularFormat::Parquet,
+            )
+            .await
+            .is_err());
+        });
+    }
+
+    #[test]
+    fn test_success_csv_schema_warnings() {
+        let preview = |object: &str, columns: &[&str]| SchemaPreview {
+            url: "s3://bucket/data/*".to_string(),
+            object: object.to_string(),
+            format: TabularFormat::Csv,
+            columns: columns
+                .iter()
+                .map(|name| ColumnPreview {
+                    name: name.to_string(),
+                    data_type: "string".to_string(),
+                })
+                .collect(),
+            estimated_rows: 1,
+        };
+        let previews = vec![
+            preview("s3://bucket/data/a.csv", &["id", "name"]),
+            preview("s3://bucket/data/b.csv", &["id", "name"]),
+        ];
+        assert!(csv_schema_warnings(&previews, true).is_empty());
+
+        let mut previews = previews;
+        previews.push(preview("s3://bucket/data/c.csv", &["id", "label"]));
+        assert_eq!(csv_schema_warnings(&previews, true).len(), 1);
+        assert!(csv_schema_warnings(&previews, false).is_empty());
+    }
+}
@@ -5,21 +5,82 @@
  */
 //! This module contains the function to check if the api key is associated with the usage plan.
 //! If not, associates the api key with the usage plan.
+//! The usage plan is selected by the `tier` of the app among the usage plans configured by tier name in
+//! `aws_api_gateway.usage_plan_tiers`, the default `usage_plan_id` without tier. When the tier of an app changes on
+//! update, its api key is moved out of the usage plans of the other tiers before the association.
+//! An unknown tier is rejected with a 400 status code. The usage plans are not used in the internal API key mode.
 //! The function returns a boolean value indicating if the api key is associated with the usage plan.
 //! The function returns a 500 status code if an error occurs while checking the usage plan.
 //! The function returns a JSON response with the status and message.
 //!
 
+use crate::configuration::settings::AWSApiGatewaySettings;
 use crate::service::state::AppState;
 use aws_config::meta::region::RegionProviderChain;
 use aws_config::{BehaviorVersion, Region};
 use axum::{http::StatusCode, Json};
-use logging_utils::create_ref_id_helper::create_ref_id;
 use logging_utils::create_task_ref_id_helper::create_task_ref_collection;
 use serde_json::json;
 use std::sync::Arc;
 use tracing::{debug, error, info, instrument};
 
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum UsagePlanError {
+    #[error("Unknown tier '{tier}'. Configured tiers: {tiers:?}.")]
+    UnknownTier { tier: String, tiers: Vec<String> },
+}
+
+impl From<UsagePlanError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: UsagePlanError) -> Self {
+        let error_message = e.to_string();
+        error!(ext_message = error_message, message = error_message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Returns the usage plan of a tier, the default usage plan without tier.
+pub fn tier_usage_plan_id<'a>(
+    settings: &'a AWSApiGatewaySettings,
+    tier: Option<&str>,
+) -> Result<&'a str, UsagePlanError> {
+    let Some(tier) = tier else {
+        return Ok(settings.usage_plan_id.as_str());
+    };
+    let tiers = settings.usage_plan_tiers.as_ref();
+    match tiers.and_then(|tiers| tiers.get(tier)) {
+        Some(usage_plan_id) => Ok(usage_plan_id.as_str()),
+        None => {
+            let mut tiers: Vec<String> = tiers
+                .into_iter()
+                .flatten()
+                .map(|(tier, _)| tier.clone())
+                .collect();
+            tiers.sort();
+            Err(UsagePlanError::UnknownTier {
+                tier: tier.to_string(),
+                tiers,
+            })
+        }
+    }
+}
+
+/// Returns the usage plans an api key is moved between: the default usage plan and the usage plans of the tiers.
+fn usage_plan_ids(settings: &AWSApiGatewaySettings) -> Vec<&str> {
+    let mut usage_plan_ids: Vec<&str> = settings
+        .usage_plan_tiers
+        .iter()
+        .flatten()
+        .map(|(_, usage_plan_id)| usage_plan_id.as_str())
+        .chain(std::iter::once(settings.usage_plan_id.as_str()))
+        .collect();
+    usage_plan_ids.sort();
+    usage_plan_ids.dedup();
+    usage_plan_ids
+}
+
 /// Asynchronous function to check if the api key is associated with the usage plan.
 #[instrument(skip_all)]
 pub async fn check_usage_plan_with_api_key(
@@ -118,6 +179,53 @@ pub async fn associate_api_key_with_usage_plan(
     }
 }
 
+/// Asynchronous function to remove the api key from the usage plan.
+#[instrument(skip_all)]
+pub async fn remove_api_key_from_usage_plan(
+    client: aws_sdk_apigateway::Client,
+    usage_plan_id: String,
+    api_key_id: String,
+    task_id: String,
+    app_name: String,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    match client
+        .delete_usage_plan_key()
+        .usage_plan_id(usage_plan_id.clone())
+        .key_id(api_key_id.clone())
+        .send()
+        .await
+    {
+        Ok(_) => {
+            let success_message = format!(
+                "'API key:{}' removed from the 'usage plan id:{}'.",
+                api_key_id, usage_plan_id
+            );
+            info!(
+                app_name = app_name,
+                task_id = task_id,
+                message = success_message
+            );
+            Ok(())
+        }
+        Err(e) => {
+            let error_message = format!(
+                "Failed to remove 'API key:{}' from 'usage plan id:{}': {:?}",
+                api_key_id, usage_plan_id, e
+            );
+            error!(
+                app_name = app_name,
+                task_id = task_id,
+                ext_message = error_message,
+                message = error_message
+            );
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"status": "error","message": error_message})),
+            ))
+        }
+    }
+}
+
 /// Asynchronous function to check if the usage plan exists.
 #[instrument(skip_all)]
 pub async fn check_usage_plan_exists(
@@ -149,16 +257,24 @@ pub async fn check_usage_plan_exists(
     }
 }
 
-/// Asynchronous function to update the API key usage plan for the app.
+/// Asynchronous function to update the API key usage plan for the app, selected by its tier. On update, the API
+/// key is first removed from the usage plans of the other tiers.
 #[instrument(skip_all)]
 pub async fn update_api_key_with_usage_plan(
     app_state: &Arc<AppState>,
     api_key_id: String,
     task_id: String,
     app_name: &String,
+    tier: Option<&str>,
+    is_update: bool,
 ) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    // In the internal API key mode the keys are not known to API Gateway
+    if app_state.api_key_options().is_internal() {
+        debug!("Internal API key mode, no usage plan to update.");
+        return Ok(());
+    }
     // Create a reference ID ,task ID and initialize the documentdb variables
-    let ref_id = create_ref_id();
+    let ref_id = app_state.id_generator.reference_id();
     let mongo_url = app_state.app_settings.mongo_db.mongo_db_url.clone();
     let mongo_db_name = app_state
         .app_settings
@@ -172,7 +288,8 @@ pub async fn update_api_key_with_usage_plan(
         .clone();
     debug!("Updating the API key usage plan for the app.");
     let region = app_state.app_settings.aws_api_gateway.region.clone();
-    let usage_plan_id = app_state.app_settings.aws_api_gateway.usage_plan_id.clone();
+    let usage_plan_id =
+        tier_usage_plan_id(&app_state.app_settings.aws_api_gateway, tier)?.to_string();
     let key_type = app_state
         .app_settings
         .aws_api_gateway
@@ -234,6 +351,30 @@ pub async fn update_api_key_with_usage_plan(
             Json(json!({"status": "error","message": error_message})),
         ));
     }
+    //move the api key out of the usage plans of the other tiers, a key can't be in two plans of the same stage
+    if is_update {
+        for other_usage_plan_id in usage_plan_ids(&app_state.app_settings.aws_api_gateway) {
+            if other_usage_plan_id == usage_plan_id {
+                continue;
+            }
+            let is_associated = check_usage_plan_with_api_key(
+                client.clone(),
+                other_usage_plan_id.to_string(),
+                api_key_id.clone(),
+            )
+            .await?;
+            if is_associated {
+                remove_api_key_from_usage_plan(
+                    client.clone(),
+                    other_usage_plan_id.to_string(),
+                    api_key_id.clone(),
+                    task_id.clone(),
+                    app_name.clone(),
+                )
+                .await?;
+            }
+        }
+    }
     //check if the api key is already associated with the usage plan
     let is_associated = match check_usage_plan_with_api_key(
         client.clone(),
@@ -286,6 +427,31 @@ pub async fn update_api_key_with_usage_plan(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_success_tier_usage_plan_id() {
+        let settings = AWSApiGatewaySettings {
+            region: "us-west-2".to_string(),
+            usage_plan_id: "bqpvmk".to_string(),
+            usage_plan_key_type: "API_KEY".to_string(),
+            usage_plan_tiers: Some(HashMap::from([
+                ("standard".to_string(), "bqpvmk".to_string()),
+                ("premium".to_string(), "x7k2pq".to_string()),
+            ])),
+        };
+        assert_eq!(tier_usage_plan_id(&settings, None), Ok("bqpvmk"));
+        assert_eq!(tier_usage_plan_id(&settings, Some("premium")), Ok("x7k2pq"));
+        assert_eq!(
+            tier_usage_plan_id(&settings, Some("gold")),
+            Err(UsagePlanError::UnknownTier {
+                tier: "gold".to_string(),
+                tiers: vec!["premium".to_string(), "standard".to_string()],
+            })
+        );
+        assert_eq!(usage_plan_ids(&settings), vec!["bqpvmk", "x7k2pq"]);
+    }
+
     #[tokio::test]
     async fn test_success_check_usage_plan_with_api_key() {
         let region_provider = RegionProviderChain::default_provider();
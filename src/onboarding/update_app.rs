@@ -12,6 +12,7 @@
 
 use crate::admin_ui_api::schema::UpdateResponse;
 use crate::onboarding::schema::app_onboarding_request::OnboardingRequest;
+use crate::service::app_cache::invalidate_cached_app;
 use crate::service::generate_and_insert_document::generate_app_document;
 use crate::service::state::AppState;
 use api_utils::errors::error_interceptor::ErrorInterceptor;
@@ -127,6 +128,7 @@ pub async fn update_app(
                     Json(json!({"status": "error", "message": error_message})),
                 ));
             } else {
+                invalidate_cached_app(app_state, app_name).await;
                 let success_message = format!("App '{}' updated successfully.", &body.app_name);
                 info!(app_name = app_name, message = success_message);
                 return Ok(());
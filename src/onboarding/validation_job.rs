@@ -0,0 +1,484 @@
+/*
+ * Created Date:  Jul 03, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the asynchronous validation of the datasources of an onboarding/update request.
+//! Apps with thousands of S3 URLs can exceed the client timeouts when their connectivity is checked synchronously.
+//! With `async_validation=true` the onboarding handler stores a validation job document and returns its
+//! `validation_job_id` right away. The job checks the datasources in chunks, recording its progress and the
+//! outcome of every URL/database after each chunk, and completes the onboarding once the validation succeeds.
+//! The GET handler mounted at `/api/v1.1/admin/validation/{job_id}` reports the job.
+//! The handler returns a 200 status code if the job is found.
+//! The handler returns a 404 status code if the job is not found.
+//! The handler returns a 500 status code if an error occurs while fetching the job.
+//!
+
+use crate::onboarding::check_connectivity::{
+    check_onboarding_limits, check_supported_data_sources, run_connectivity_checks,
+};
+use crate::onboarding::datasource_connectivity::report::{ConnectivityReport, DatasourceResult};
+use crate::onboarding::handler::{complete_onboarding, prepare_onboarding};
+use crate::onboarding::schema::app_onboarding_request::{AppDataSource, OnboardingRequest};
+use crate::onboarding::schema::response::ValidationJobCreateResponse;
+use crate::service::ctx::Ctx;
+use crate::service::generate_and_insert_document::{create_document_in_db, DocType};
+use crate::service::metrics::{MetricRecord, APP_NAME_DIMENSION, STATUS_DIMENSION};
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use mongodb::bson::{doc, to_bson, Document};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{error, info, instrument, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Number of URLs/databases checked between two progress updates of a validation job.
+const VALIDATION_CHUNK_SIZE: usize = 200;
+
+/// Status of a validation job.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidationJobStatus {
+    Queued,
+    Running,
+    /// The datasources are validated and the onboarding is in progress.
+    Succeeded,
+    Failed,
+}
+
+/// Validation job document, stored in the validation job collection.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ValidationJobDocument {
+    pub job_id: String,
+    pub app_name: String,
+    pub is_update: bool,
+    pub status: ValidationJobStatus,
+    pub message: String,
+    /// Number of URLs/databases to check.
+    pub total: u64,
+    /// Number of URLs/databases checked so far.
+    pub checked: u64,
+    pub results: Vec<DatasourceResult>,
+    pub warnings: Vec<String>,
+    pub errors: Vec<String>,
+    pub estimated_ingestion_size: Option<u64>,
+    /// Set once the job succeeded, as returned by a synchronous onboarding request.
+    pub api_key: Option<String>,
+    pub app_id: Option<String>,
+    pub reference_id: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// Number of URLs/databases of the datasources.
+fn entry_count(app_datasource: &AppDataSource) -> usize {
+    app_datasource
+        .filestore
+        .values()
+        .map(Vec::len)
+        .sum::<usize>()
+        + app_datasource
+            .datastore
+            .values()
+            .map(Vec::len)
+            .sum::<usize>()
+}
+
+/// Splits the datasources into subsets of at most `chunk_size` entries of a single data source type.
+fn chunk_datasource(app_datasource: &AppDataSource, chunk_size: usize) -> Vec<AppDataSource> {
+    let mut chunks = Vec::new();
+    for (data_source, entries) in &app_datasource.filestore {
+        for chunk in entries.chunks(chunk_size) {
+            chunks.push(AppDataSource {
+                filestore: HashMap::from([(data_source.clone(), chunk.to_vec())]),
+                datastore: HashMap::new(),
+                file_types: app_datasource.file_types.clone(),
+            });
+        }
+    }
+    for (data_source, entries) in &app_datasource.datastore {
+        for chunk in entries.chunks(chunk_size) {
+            chunks.push(AppDataSource {
+                filestore: HashMap::new(),
+                datastore: HashMap::from([(data_source.clone(), chunk.to_vec())]),
+                file_types: None,
+            });
+        }
+    }
+    chunks
+}
+
+/// Asynchronous function to validate an onboarding/update request, store its validation job and spawn it.
+/// The caller is responsible for checking the app existence against `is_update`.
+#[instrument(skip_all)]
+pub(crate) async fn enqueue_validation_job(
+    app_state: &Arc<AppState>,
+    mut body: OnboardingRequest,
+    is_update: bool,
+    request_timestamp: DateTime<Utc>,
+    override_limits: bool,
+) -> Result<ValidationJobCreateResponse, (StatusCode, Json<serde_json::Value>)> {
+    prepare_onboarding(app_state, &mut body, is_update, request_timestamp).await?;
+    check_supported_data_sources(app_state, &body.app_datasource)?;
+
+    let job_id = Uuid::new_v4().to_string();
+    let total = entry_count(&body.app_datasource) as u64;
+    let now = Utc::now().to_rfc3339();
+    let job = ValidationJobDocument {
+        job_id: job_id.clone(),
+        app_name: body.app_name.clone(),
+        is_update,
+        status: ValidationJobStatus::Queued,
+        message: "Datasource validation queued.".to_string(),
+        total,
+        checked: 0,
+        results: vec![],
+        warnings: vec![],
+        errors: vec![],
+        estimated_ingestion_size: None,
+        api_key: None,
+        app_id: None,
+        reference_id: None,
+        created_at: now.clone(),
+        updated_at: now,
+    };
+    create_document_in_db(
+        app_state,
+        &job,
+        DocType::ValidationJob,
+        &app_state
+            .app_settings
+            .mongo_db
+            .mongo_db_validation_job_collection,
+        &body.app_name,
+        &job_id,
+        &"".to_string(),
+    )
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": e.to_string()})),
+        )
+    })?;
+
+    info!(
+        app_name = &body.app_name,
+        message = format!(
+            "Validation job '{}' queued for {} datasource(s).",
+            job_id, total
+        )
+    );
+    tokio::spawn(run_validation_job(
+        Arc::clone(app_state),
+        job_id.clone(),
+        body,
+        is_update,
+        request_timestamp,
+        override_limits,
+    ));
+
+    Ok(ValidationJobCreateResponse {
+        status: "success".to_string(),
+        message: "Datasource validation queued. Onboarding starts once the validation succeeds."
+            .to_string(),
+        validation_job_id: job_id,
+    })
+}
+
+/// Asynchronous function to check the datasources of a validation job chunk by chunk and complete the onboarding.
+#[instrument(skip_all)]
+async fn run_validation_job(
+    app_state: Arc<AppState>,
+    job_id: String,
+    body: OnboardingRequest,
+    is_update: bool,
+    request_timestamp: DateTime<Utc>,
+    override_limits: bool,
+) {
+    let start = Instant::now();
+    update_validation_job(
+        &app_state,
+        &job_id,
+        doc! {"status": "running", "message": "Datasource validation in progress."},
+    )
+    .await;
+
+    let mut connectivity_report = ConnectivityReport::default();
+    let mut checked = 0;
+    for chunk in chunk_datasource(&body.app_datasource, VALIDATION_CHUNK_SIZE) {
+        checked += entry_count(&chunk);
+        match run_connectivity_checks(&app_state, &chunk).await {
+            Ok(report) => connectivity_report.merge(report),
+            Err((_, Json(error_response))) => {
+                connectivity_report.errors.push(
+                    error_response
+                        .get("message")
+                        .and_then(serde_json::Value::as_str)
+                        .unwrap_or("Connectivity check failed.")
+                        .to_string(),
+                );
+            }
+        }
+        update_validation_job(
+            &app_state,
+            &job_id,
+            doc! {
+                "checked": checked as i64,
+                "results": to_bson(&connectivity_report.results).unwrap_or_default(),
+            },
+        )
+        .await;
+    }
+
+    // Fail the job on connectivity errors, or if the datasources exceed the onboarding limits
+    let failure_message = if !connectivity_report.errors.is_empty() {
+        Some("Connectivity check failed. Please check and try again.")
+    } else {
+        let limit_errors = check_onboarding_limits(
+            &app_state,
+            &mut connectivity_report,
+            &body.app_datasource,
+            &body.app_name,
+            override_limits,
+        );
+        connectivity_report.errors = limit_errors;
+        (!connectivity_report.errors.is_empty()).then_some(
+            "Datasources exceed the onboarding limits. Narrow the datasources or ask an admin to override the limits.",
+        )
+    };
+    let mut fields = doc! {
+        "warnings": connectivity_report.warnings.clone(),
+        "errors": connectivity_report.errors.clone(),
+        "estimated_ingestion_size": connectivity_report.estimated_ingestion_size.map(|size| size as i64),
+    };
+    if let Some(failure_message) = failure_message {
+        let error_message = format!(
+            "Datasource validation failed. Errors: {:?}",
+            connectivity_report.errors
+        );
+        error!(
+            app_name = &body.app_name,
+            ext_message = error_message,
+            message = error_message
+        );
+        fields.insert("status", "failed");
+        fields.insert("message", failure_message);
+        update_validation_job(&app_state, &job_id, fields).await;
+        record_validation_job_metric(&app_state, &body.app_name, "failed", start).await;
+        return;
+    }
+    if !connectivity_report.warnings.is_empty() {
+        warn!(
+            app_name = &body.app_name,
+            message = format!(
+                "Connectivity check warnings: {:?}",
+                connectivity_report.warnings
+            )
+        );
+    }
+
+    let app_name = body.app_name.clone();
+    let status = match complete_onboarding(
+        &app_state,
+        body,
+        is_update,
+        request_timestamp,
+        connectivity_report,
+    )
+    .await
+    {
+        Ok(response) => {
+            fields.insert("status", "succeeded");
+            fields.insert("message", response.message);
+            fields.insert("api_key", response.api_key);
+            fields.insert("app_id", response.app_id);
+            fields.insert("reference_id", response.reference_id);
+            "succeeded"
+        }
+        Err((_, Json(error_response))) => {
+            let error_message = error_response
+                .get("message")
+                .and_then(serde_json::Value::as_str)
+                .unwrap_or("Failed to start the onboarding.")
+                .to_string();
+            error!(
+                app_name = app_name,
+                ext_message = error_message,
+                message = error_message
+            );
+            fields.insert("status", "failed");
+            fields.insert("message", error_message);
+            "failed"
+        }
+    };
+    update_validation_job(&app_state, &job_id, fields).await;
+    record_validation_job_metric(&app_state, &app_name, status, start).await;
+}
+
+/// Asynchronous function to record the duration of a validation job, by final status.
+async fn record_validation_job_metric(
+    app_state: &Arc<AppState>,
+    app_name: &str,
+    status: &str,
+    start: Instant,
+) {
+    app_state
+        .record_metric(
+            MetricRecord::duration_ms(
+                "Validation Job Duration",
+                start.elapsed().as_millis() as i64,
+            )
+            .dimension(APP_NAME_DIMENSION, app_name)
+            .dimension(STATUS_DIMENSION, status),
+        )
+        .await;
+}
+
+/// Asynchronous function to update the fields of a validation job. Failures are logged, the job carries on.
+async fn update_validation_job(app_state: &Arc<AppState>, job_id: &str, mut fields: Document) {
+    fields.insert("updated_at", Utc::now().to_rfc3339());
+    if let Err(e) = app_state
+        .db
+        .update_document(
+            &app_state
+                .app_settings
+                .mongo_db
+                .mongo_db_validation_job_collection,
+            doc! {"job_id": job_id},
+            fields,
+        )
+        .await
+        .map_err(ErrorInterceptor::from)
+    {
+        let error_message = format!("Failed to update validation job '{}'. Error: {}", job_id, e);
+        error!(ext_message = error_message, message = error_message);
+    }
+}
+
+/// GET handler to fetch the progress and the per-URL results of a datasource validation job.
+#[utoipa::path(
+    get,
+    path = "/api/v1.1/admin/validation/{job_id}",
+    responses(
+        (status = 200, description = "Validation job fetched successfully.", body = ValidationJobDocument),
+        (status = StatusCode::NOT_FOUND, description = "Validation job not found", body = [ErrorResponse]),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Tresle error occurred. Please save reference id: {} and contact support.")
+    )
+)]
+#[instrument(skip_all)]
+pub async fn get_validation_job_handler(
+    ctx: Ctx,
+    Path(job_id): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let collection_name = &app_state
+        .app_settings
+        .mongo_db
+        .mongo_db_validation_job_collection;
+    let error_message = match app_state
+        .db
+        .get_document(collection_name, doc! {"job_id": &job_id})
+        .await
+        .map_err(ErrorInterceptor::from)
+    {
+        Ok(None) => {
+            let error_message = format!("No validation job found with ID '{}'.", job_id);
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(json!({"status": "error", "message": error_message})),
+            ));
+        }
+        Ok(Some(job)) => match serde_json::from_value::<ValidationJobDocument>(job) {
+            Ok(job) => {
+                let success_message = format!("Validation job '{}' fetched successfully.", job_id);
+                info!(app_name = job.app_name, message = success_message);
+                return Ok(Json(
+                    json!({"status": "success", "message": success_message, "data": job}),
+                ));
+            }
+            Err(e) => format!("Failed to deserialize validation job. Error: {:?}", e),
+        },
+        Err(e) => format!("Failed to fetch validation job '{}'. Error: {}", job_id, e),
+    };
+
+    let ext_message = ctx.ext_message(&app_state);
+    error!(
+        app_name = ctx.app_name,
+        task_id = ctx.task_id,
+        ext_message = ext_message,
+        message = error_message
+    );
+    Err((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"status": "error", "message": error_message})),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onboarding::schema::app_onboarding_request::{
+        FileStore, ListingMode, ValidationMode,
+    };
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_chunk_datasource() {
+        let file_store = |url: &str| FileStore {
+            url: url.to_string(),
+            hints: vec![],
+            validation_mode: ValidationMode::Strict,
+            listing_mode: ListingMode::Objects,
+            inventory_manifest: None,
+            labels: None,
+        };
+        let app_datasource = AppDataSource {
+            filestore: HashMap::from([(
+                "s3".to_string(),
+                vec![
+                    file_store("s3://bucket-a"),
+                    file_store("s3://bucket-b"),
+                    file_store("s3://bucket-c"),
+                ],
+            )]),
+            datastore: HashMap::new(),
+            file_types: None,
+        };
+
+        let chunks = chunk_datasource(&app_datasource, 2);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].filestore["s3"].len(), 2);
+        assert_eq!(chunks[1].filestore["s3"], vec![file_store("s3://bucket-c")]);
+    }
+
+    #[test]
+    fn test_failure_get_validation_job_handler_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState and job_id
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let job_id = "non-existing-job".to_string();
+
+            // Call the function
+            let ctx = Ctx::new(&app_state, "test_app", "Test");
+            let result = get_validation_job_handler(ctx, Path(job_id), State(app_state)).await;
+
+            // Check the status code
+            let (status_code, _) = result.err().unwrap();
+            assert_eq!(status_code, StatusCode::NOT_FOUND);
+        });
+    }
+}
@@ -5,9 +5,17 @@
 */
 //! Retrieval module and associated functions.
 
+pub mod cost_estimate;
+pub mod estimate_handler;
+pub mod fan_out;
 pub mod fetch_app_name;
 mod fetch_from_knowledge_engine;
 pub mod handler;
 pub mod history_handler;
+pub mod query_classification;
+pub mod query_normalization;
+pub mod replay;
+pub mod sandbox;
 pub mod schema;
+pub mod stage_timings;
 mod update_task_id;
@@ -0,0 +1,598 @@
+/*
+ * Created Date:  Aug 3, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the cost estimation of the retrievals, served by the retrieval estimate handler without
+//! executing the retrieval, so the integrators can warn their users before an expensive request.
+//! The model of the estimate is the `model_id` of the request, which must be one of the `allowed_models` of the app,
+//! or the first allowed model of the app.
+//! The tokens are estimated from the recent history documents of the app answered by the model (at most
+//! `retrieval_estimate.history_sample_size`, 200 by default, replays and sandbox retrievals excluded): the medians of
+//! the prompt and completion tokens of the similar queries, of the same category (see
+//! `crate::retrieval::query_classification`) and of a length within a factor of 2 of the query, or of all the sampled
+//! queries if fewer than `min_samples` (3) are similar. Without enough samples, the prompt tokens are the tokens of
+//! the query and additional prompt (4 characters per token) plus `context_tokens` (2000), and the completion tokens
+//! are `completion_tokens` (500).
+//! The cost is priced with the `model_prices` of the model, per 1000 tokens, and unset for a model without price.
+//! The latency class is the class of the median engine call duration of the samples: `fast` up to `fast_latency_ms`
+//! (3000), `moderate` up to `slow_latency_ms` (10000), `slow` beyond, and `unknown` without timed samples.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{RetrievalEstimateSettings, TresleFacadeServiceSettings};
+use crate::onboarding::schema::app_onboarding_request::LlmModel;
+use crate::retrieval::query_classification::{
+    classify_with_rules, default_rules, ClassificationRule, QueryCategory,
+};
+use crate::retrieval::replay::REPLAY_OF_FIELD;
+use crate::retrieval::sandbox::SANDBOX_FIELD;
+use crate::service::app_repository::AppRepositoryError;
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::state::AppState;
+use mongodb::bson::{doc, Document};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::{error, instrument};
+use utoipa::ToSchema;
+
+const DEFAULT_CURRENCY: &str = "USD";
+const DEFAULT_HISTORY_SAMPLE_SIZE: i64 = 200;
+const DEFAULT_MIN_SAMPLES: usize = 3;
+const DEFAULT_CONTEXT_TOKENS: i64 = 2_000;
+const DEFAULT_COMPLETION_TOKENS: i64 = 500;
+const DEFAULT_FAST_LATENCY_MS: u64 = 3_000;
+const DEFAULT_SLOW_LATENCY_MS: u64 = 10_000;
+/// Characters per token of the token count of a text.
+const CHARS_PER_TOKEN: usize = 4;
+/// Maximum ratio between the lengths of two similar queries.
+const SIMILAR_LENGTH_RATIO: f64 = 2.0;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RetrievalEstimateError {
+    #[error("Query cannot be empty.")]
+    EmptyQuery,
+    #[error("Model '{0}' is not an allowed model of the app.")]
+    ModelNotAllowed(String),
+    #[error("{0}")]
+    AppLookup(#[from] AppRepositoryError),
+}
+
+/// Price of a model, per 1000 tokens.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+pub struct ModelPrice {
+    pub prompt_per_1k: f64,
+    pub completion_per_1k: f64,
+}
+
+/// Retrieval estimate options: model prices, history sampling and latency classes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetrievalEstimateOptions {
+    pub model_prices: HashMap<String, ModelPrice>,
+    pub currency: String,
+    pub history_sample_size: i64,
+    pub min_samples: usize,
+    pub context_tokens: i64,
+    pub completion_tokens: i64,
+    pub fast_latency: Duration,
+    pub slow_latency: Duration,
+}
+
+impl SettingsOptions for RetrievalEstimateOptions {
+    type Settings = RetrievalEstimateSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&RetrievalEstimateSettings> {
+        settings.retrieval_estimate.as_ref()
+    }
+
+    fn from_settings(settings: Option<&RetrievalEstimateSettings>) -> Self {
+        RetrievalEstimateOptions {
+            model_prices: settings
+                .and_then(|settings| settings.model_prices.clone())
+                .unwrap_or_default(),
+            currency: settings
+                .and_then(|settings| settings.currency.clone())
+                .unwrap_or_else(|| DEFAULT_CURRENCY.to_string()),
+            history_sample_size: settings
+                .and_then(|settings| settings.history_sample_size)
+                .filter(|size| *size > 0)
+                .unwrap_or(DEFAULT_HISTORY_SAMPLE_SIZE),
+            min_samples: settings
+                .and_then(|settings| settings.min_samples)
+                .filter(|min_samples| *min_samples > 0)
+                .unwrap_or(DEFAULT_MIN_SAMPLES),
+            context_tokens: settings
+                .and_then(|settings| settings.context_tokens)
+                .unwrap_or(DEFAULT_CONTEXT_TOKENS),
+            completion_tokens: settings
+                .and_then(|settings| settings.completion_tokens)
+                .unwrap_or(DEFAULT_COMPLETION_TOKENS),
+            fast_latency: Duration::from_millis(
+                settings
+                    .and_then(|settings| settings.fast_latency_ms)
+                    .unwrap_or(DEFAULT_FAST_LATENCY_MS),
+            ),
+            slow_latency: Duration::from_millis(
+                settings
+                    .and_then(|settings| settings.slow_latency_ms)
+                    .unwrap_or(DEFAULT_SLOW_LATENCY_MS),
+            ),
+        }
+    }
+}
+
+/// Request of a retrieval estimate: the query and additional prompt of the retrieval, and the model to estimate it
+/// for, the first allowed model of the app if unset.
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+pub struct RetrievalEstimateRequest {
+    pub query: String,
+    #[serde(default)]
+    pub additional_prompt: Option<String>,
+    #[serde(default)]
+    pub model_id: Option<String>,
+}
+
+impl RetrievalEstimateRequest {
+    /// Tokens of the query and additional prompt.
+    pub fn query_tokens(&self) -> i64 {
+        let chars = self.query.chars().count()
+            + self
+                .additional_prompt
+                .as_deref()
+                .map_or(0, |prompt| prompt.chars().count());
+        chars.div_ceil(CHARS_PER_TOKEN) as i64
+    }
+}
+
+/// Expected latency of a retrieval.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LatencyClass {
+    Fast,
+    Moderate,
+    Slow,
+    /// No answered retrieval of the app is timed.
+    Unknown,
+}
+
+impl LatencyClass {
+    /// Classifies an expected engine call duration, `None` if unknown.
+    pub fn classify(latency_ms: Option<u64>, options: &RetrievalEstimateOptions) -> Self {
+        match latency_ms.map(Duration::from_millis) {
+            None => LatencyClass::Unknown,
+            Some(latency) if latency <= options.fast_latency => LatencyClass::Fast,
+            Some(latency) if latency <= options.slow_latency => LatencyClass::Moderate,
+            Some(_) => LatencyClass::Slow,
+        }
+    }
+}
+
+/// Retrievals an estimate is based on.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EstimateBasis {
+    /// The answered retrievals of similar queries.
+    SimilarQueries,
+    /// The answered retrievals of the app, too few queries being similar.
+    AppHistory,
+    /// None, too few retrievals of the app being answered.
+    Heuristic,
+}
+
+/// Answered retrieval of the history of an app, sampled for the estimates.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct EstimateSample {
+    pub query: String,
+    #[serde(default)]
+    pub query_category: Option<QueryCategory>,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    #[serde(default)]
+    pub engine_call_ms: Option<u64>,
+}
+
+/// Estimate of the tokens, cost and latency of a retrieval.
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct RetrievalEstimate {
+    /// Model of the estimate, unset if the app has no allowed model.
+    pub model_id: Option<String>,
+    pub query_category: QueryCategory,
+    /// Tokens of the query and additional prompt.
+    pub query_tokens: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+    /// Cost of the tokens, unset if the model has no price.
+    pub estimated_cost: Option<f64>,
+    pub currency: String,
+    pub latency_class: LatencyClass,
+    /// Median engine call duration of the samples, in milliseconds.
+    pub expected_latency_ms: Option<u64>,
+    pub basis: EstimateBasis,
+    /// Number of answered retrievals the estimate is based on.
+    pub samples: usize,
+}
+
+/// Returns the model of an estimate: the requested model, matched by model ID or name, or the first allowed model.
+pub fn select_model<'a>(
+    requested: Option<&str>,
+    allowed_models: &'a [LlmModel],
+) -> Result<Option<&'a LlmModel>, RetrievalEstimateError> {
+    match requested {
+        None => Ok(allowed_models.first()),
+        Some(requested) => allowed_models
+            .iter()
+            .find(|model| model.model_id == requested || model.name == requested)
+            .map(Some)
+            .ok_or_else(|| RetrievalEstimateError::ModelNotAllowed(requested.to_string())),
+    }
+}
+
+/// Returns the samples of the queries similar to a query: of the same category and of a length within a factor of
+/// 2. The samples stored without category are classified with the rules.
+pub fn similar_samples<'a>(
+    query: &str,
+    category: QueryCategory,
+    samples: &'a [EstimateSample],
+    rules: &[ClassificationRule],
+) -> Vec<&'a EstimateSample> {
+    let length = query.chars().count().max(1) as f64;
+    samples
+        .iter()
+        .filter(|sample| {
+            let ratio = sample.query.chars().count().max(1) as f64 / length;
+            (1.0 / SIMILAR_LENGTH_RATIO..=SIMILAR_LENGTH_RATIO).contains(&ratio)
+        })
+        .filter(|sample| {
+            sample
+                .query_category
+                .unwrap_or_else(|| classify_with_rules(&sample.query, rules))
+                == category
+        })
+        .collect()
+}
+
+fn median<T: Ord + Copy>(mut values: Vec<T>) -> Option<T> {
+    values.sort_unstable();
+    values.get(values.len() / 2).copied()
+}
+
+/// Estimates a retrieval from the samples of the history of the app.
+pub fn estimate(
+    request: &RetrievalEstimateRequest,
+    category: QueryCategory,
+    model_id: Option<&str>,
+    samples: &[EstimateSample],
+    rules: &[ClassificationRule],
+    options: &RetrievalEstimateOptions,
+) -> RetrievalEstimate {
+    let query_tokens = request.query_tokens();
+    let similar = similar_samples(&request.query, category, samples, rules);
+    let (basis, based_on) = if similar.len() >= options.min_samples {
+        (EstimateBasis::SimilarQueries, similar)
+    } else if samples.len() >= options.min_samples {
+        (EstimateBasis::AppHistory, samples.iter().collect())
+    } else {
+        (EstimateBasis::Heuristic, Vec::new())
+    };
+
+    let (prompt_tokens, completion_tokens) = match basis {
+        EstimateBasis::Heuristic => (
+            query_tokens + options.context_tokens,
+            options.completion_tokens,
+        ),
+        _ => (
+            median(based_on.iter().map(|sample| sample.prompt_tokens).collect()).unwrap_or(0),
+            median(
+                based_on
+                    .iter()
+                    .map(|sample| sample.completion_tokens)
+                    .collect(),
+            )
+            .unwrap_or(0),
+        ),
+    };
+    let expected_latency_ms = median(
+        based_on
+            .iter()
+            .filter_map(|sample| sample.engine_call_ms)
+            .collect(),
+    );
+    let estimated_cost = model_id
+        .and_then(|model_id| options.model_prices.get(model_id))
+        .map(|price| {
+            let cost = prompt_tokens as f64 / 1000.0 * price.prompt_per_1k
+                + completion_tokens as f64 / 1000.0 * price.completion_per_1k;
+            (cost * 1e6).round() / 1e6
+        });
+
+    RetrievalEstimate {
+        model_id: model_id.map(str::to_string),
+        query_category: category,
+        query_tokens,
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: prompt_tokens + completion_tokens,
+        estimated_cost,
+        currency: options.currency.clone(),
+        latency_class: LatencyClass::classify(expected_latency_ms, options),
+        expected_latency_ms,
+        basis,
+        samples: based_on.len(),
+    }
+}
+
+/// Pipeline sampling the latest answered retrievals of an app with their token usage, answered by a model if set.
+fn samples_pipeline(model: Option<&LlmModel>, sample_size: i64) -> Vec<Document> {
+    let mut filter = doc! {
+        "token_usage.total_tokens": { "$gt": 0 },
+        REPLAY_OF_FIELD: { "$exists": false },
+        SANDBOX_FIELD: { "$ne": true },
+    };
+    if let Some(model) = model {
+        filter.insert(
+            "model_used",
+            doc! { "$in": [ model.model_id.as_str(), model.name.as_str() ] },
+        );
+    }
+    vec![
+        doc! { "$match": filter },
+        doc! { "$sort": { "timestamp": -1 } },
+        doc! { "$limit": sample_size },
+        doc! {
+            "$project": {
+                "_id": 0,
+                "query": 1,
+                "query_category": 1,
+                "prompt_tokens": "$token_usage.prompt_tokens",
+                "completion_tokens": "$token_usage.completion_tokens",
+                "engine_call_ms": "$timings.engine_call_ms",
+            }
+        },
+    ]
+}
+
+/// Samples the latest answered retrievals of an app. A failed lookup is logged and yields no sample, the estimate
+/// falling back to the heuristic.
+async fn history_samples(
+    app_state: &AppState,
+    app_name: &str,
+    model: Option<&LlmModel>,
+    options: &RetrievalEstimateOptions,
+) -> Vec<EstimateSample> {
+    // The aggregation runs on the analytics connection of the app residency, when configured
+    let analytics_db = match app_state.app_analytics_db(app_name).await {
+        Ok(analytics_db) => analytics_db,
+        Err(e) => {
+            error!(app_name = app_name, message = e.to_string());
+            return Vec::new();
+        }
+    };
+    match analytics_db
+        .aggregate(
+            &format!("{}-history", app_name),
+            samples_pipeline(model, options.history_sample_size),
+            &app_state.options::<QueryOptions>(),
+        )
+        .await
+    {
+        Ok(documents) => documents
+            .into_iter()
+            .filter_map(|document| serde_json::from_value(document).ok())
+            .collect(),
+        Err(e) => {
+            let error_message = format!(
+                "Failed to sample the history of app '{}' for the retrieval estimate. Error: {}",
+                app_name, e
+            );
+            error!(app_name = app_name, message = error_message);
+            Vec::new()
+        }
+    }
+}
+
+/// Estimates the tokens, cost and latency of a retrieval of an app, without executing it.
+#[instrument(skip_all)]
+pub async fn estimate_retrieval(
+    app_state: &AppState,
+    app_name: &str,
+    request: &RetrievalEstimateRequest,
+) -> Result<RetrievalEstimate, RetrievalEstimateError> {
+    if request.query.trim().is_empty() {
+        return Err(RetrievalEstimateError::EmptyQuery);
+    }
+    let options = app_state.options::<RetrievalEstimateOptions>();
+    let allowed_models = app_state.apps().allowed_models(app_name).await?;
+    let model = select_model(request.model_id.as_deref(), &allowed_models)?;
+
+    // The query is classified with the rules only, the estimate never calls the classification model
+    let rules = app_state
+        .app_settings
+        .query_classification
+        .as_ref()
+        .and_then(|settings| settings.rules.clone())
+        .unwrap_or_else(default_rules);
+    let category = classify_with_rules(&request.query, &rules);
+
+    let samples = history_samples(app_state, app_name, model, &options).await;
+    Ok(estimate(
+        request,
+        category,
+        model.map(|model| model.model_id.as_str()),
+        &samples,
+        &rules,
+        &options,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(query: &str) -> RetrievalEstimateRequest {
+        RetrievalEstimateRequest {
+            query: query.to_string(),
+            additional_prompt: None,
+            model_id: None,
+        }
+    }
+
+    fn sample(query: &str, prompt_tokens: i64, engine_call_ms: u64) -> EstimateSample {
+        EstimateSample {
+            query: query.to_string(),
+            query_category: None,
+            prompt_tokens,
+            completion_tokens: prompt_tokens / 4,
+            engine_call_ms: Some(engine_call_ms),
+        }
+    }
+
+    fn model(model_id: &str, name: &str) -> LlmModel {
+        LlmModel {
+            name: name.to_string(),
+            description: "description".to_string(),
+            model_id: model_id.to_string(),
+            model_type: "llm".to_string(),
+            secret_name: None,
+            secret_region: None,
+        }
+    }
+
+    #[test]
+    fn test_success_retrieval_estimate_options() {
+        let options = RetrievalEstimateOptions::from_settings(None);
+        assert_eq!(options.currency, "USD");
+        assert_eq!(options.history_sample_size, 200);
+        assert_eq!(options.min_samples, 3);
+        assert_eq!(options.fast_latency, Duration::from_secs(3));
+
+        let options = RetrievalEstimateOptions::from_settings(Some(&RetrievalEstimateSettings {
+            model_prices: None,
+            currency: Some("EUR".to_string()),
+            history_sample_size: Some(0),
+            min_samples: Some(5),
+            context_tokens: None,
+            completion_tokens: Some(100),
+            fast_latency_ms: None,
+            slow_latency_ms: Some(5_000),
+        }));
+        assert_eq!(options.currency, "EUR");
+        assert_eq!(options.history_sample_size, 200);
+        assert_eq!(options.min_samples, 5);
+        assert_eq!(options.completion_tokens, 100);
+        assert_eq!(options.slow_latency, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_success_select_model() {
+        let models = vec![model("model-1", "Model 1"), model("model-2", "Model 2")];
+        assert_eq!(
+            select_model(None, &models).unwrap().unwrap().model_id,
+            "model-1"
+        );
+        assert_eq!(
+            select_model(Some("Model 2"), &models)
+                .unwrap()
+                .unwrap()
+                .model_id,
+            "model-2"
+        );
+        assert!(select_model(None, &[]).unwrap().is_none());
+        assert!(matches!(
+            select_model(Some("model-3"), &models),
+            Err(RetrievalEstimateError::ModelNotAllowed(_))
+        ));
+    }
+
+    #[test]
+    fn test_success_estimate_from_similar_queries() {
+        let rules = default_rules();
+        let mut options = RetrievalEstimateOptions::from_settings(None);
+        options.model_prices.insert(
+            "model-1".to_string(),
+            ModelPrice {
+                prompt_per_1k: 0.01,
+                completion_per_1k: 0.03,
+            },
+        );
+        let samples = vec![
+            sample("What is the parental leave policy?", 1_000, 2_000),
+            sample("What is the remote work policy?", 1_200, 2_500),
+            sample("What is the travel expense policy?", 1_400, 4_000),
+            // Of another category, and too long
+            sample("How many orders per region in 2023?", 9_000, 20_000),
+            sample(&"What is the policy? ".repeat(10), 9_000, 20_000),
+        ];
+        let request = request("What is the sick leave policy?");
+        let priced = estimate(
+            &request,
+            QueryCategory::Document,
+            Some("model-1"),
+            &samples,
+            &rules,
+            &options,
+        );
+        assert_eq!(priced.basis, EstimateBasis::SimilarQueries);
+        assert_eq!(priced.samples, 3);
+        assert_eq!(priced.prompt_tokens, 1_200);
+        assert_eq!(priced.completion_tokens, 300);
+        assert_eq!(priced.total_tokens, 1_500);
+        assert_eq!(priced.estimated_cost, Some(0.021));
+        assert_eq!(priced.expected_latency_ms, Some(2_500));
+        assert_eq!(priced.latency_class, LatencyClass::Fast);
+
+        // Without price, the cost is unset
+        let unpriced = estimate(
+            &request,
+            QueryCategory::Document,
+            Some("model-2"),
+            &samples,
+            &rules,
+            &options,
+        );
+        assert!(unpriced.estimated_cost.is_none());
+    }
+
+    #[test]
+    fn test_success_estimate_fallbacks() {
+        let rules = default_rules();
+        let options = RetrievalEstimateOptions::from_settings(None);
+        let samples = vec![
+            sample("How many orders per region?", 3_000, 12_000),
+            sample("Total revenue per quarter?", 3_000, 12_000),
+            sample("Average basket size per store?", 3_000, 12_000),
+        ];
+
+        // Too few similar queries, the whole sample is used
+        let estimate_request = request("What is the parental leave policy?");
+        let app_history = estimate(
+            &estimate_request,
+            QueryCategory::Document,
+            None,
+            &samples,
+            &rules,
+            &options,
+        );
+        assert_eq!(app_history.basis, EstimateBasis::AppHistory);
+        assert_eq!(app_history.prompt_tokens, 3_000);
+        assert_eq!(app_history.latency_class, LatencyClass::Slow);
+
+        // Too few answered retrievals, the tokens of the query are counted
+        let heuristic = estimate(
+            &estimate_request,
+            QueryCategory::Document,
+            None,
+            &samples[..1],
+            &rules,
+            &options,
+        );
+        assert_eq!(heuristic.basis, EstimateBasis::Heuristic);
+        assert_eq!(heuristic.samples, 0);
+        assert_eq!(heuristic.query_tokens, 9);
+        assert_eq!(heuristic.prompt_tokens, 2_009);
+        assert_eq!(heuristic.completion_tokens, 500);
+        assert_eq!(heuristic.latency_class, LatencyClass::Unknown);
+        assert!(heuristic.model_id.is_none());
+    }
+}
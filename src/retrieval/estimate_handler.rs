@@ -0,0 +1,215 @@
+/*
+ * Created Date:  Aug 3, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the asynchronous POST handler estimating the tokens, cost and latency of a retrieval without
+//! executing it (see `crate::retrieval::cost_estimate`).
+
+use crate::retrieval::cost_estimate::{
+    estimate_retrieval, RetrievalEstimate, RetrievalEstimateError, RetrievalEstimateRequest,
+};
+use crate::retrieval::fetch_app_name::fetch_app_name;
+use crate::service::api_key::record_api_key_usage;
+use crate::service::ctx::Ctx;
+use crate::service::error::{FacadeApiError, TresleFacadeCommonError};
+use crate::service::generate_and_insert_document::*;
+use crate::service::state::AppState;
+use axum::body::{to_bytes, Body};
+use axum::http::Request;
+use axum::{extract::State, response::IntoResponse, Json};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{info, instrument};
+
+#[utoipa::path(
+    post,
+    path = "/api/v1.0/retrieval/estimate",
+    request_body = RetrievalEstimateRequest,
+    responses(
+        (status = 200, description = "Retrieval estimated successfully.", body = RetrievalEstimate),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid request body, empty query or model not allowed for the app. Use reference ID: "),
+        (status = StatusCode::NOT_FOUND, description = "Internal Error. Please contact tresleai support team. Use reference ID: "),
+        (status = StatusCode::UNAUTHORIZED, description = "The API key expired on {}. Use an active API key of the app. Use reference ID: "),
+        (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal Error. Please contact tresleai support team. Use reference ID: ")
+    )
+)]
+
+/// POST handler to estimate the token usage, cost and expected latency of a retrieval, without executing it.
+///
+/// The estimate lets the integrators warn their users before an expensive request. It is based on the query and
+/// additional prompt of the retrieval, the allowed models of the app, and the answered retrievals of the app.
+///
+/// #### Estimate
+/// - The model of the estimate is the 'model_id' field, the model ID or name of one of the allowed models of the app,
+///   or the first allowed model of the app if unset. Other models are rejected with a 400 status code.
+/// - The tokens are the medians of the latest answered retrievals of the app by the model for similar queries, of the
+///   same category and of a similar length (`basis` is `similar_queries`), or for any query if too few are similar
+///   (`app_history`). Without enough answered retrievals, the tokens are counted from the query (`heuristic`).
+/// - The cost is priced with the configured price of the model, per 1000 tokens. It is unset for a model without
+///   price.
+/// - The latency class (`fast`, `moderate` or `slow`) is the class of the median duration of the knowledge engine
+///   calls of the same retrievals, `unknown` without timed retrievals.
+///
+/// #### API Key
+/// - The application's API key is required to authenticate the request.
+/// - It must be included in the `x-api-key` header of the request to associate it with an application.
+///
+/// #### Example
+///
+/// ```
+/// POST /api/v1.0/retrieval/estimate
+/// x-api-key: a8VYYvaey38pajBi4jrMt8pGNdw5w0pn8oCytuQB
+///
+/// {
+///     "query": "provide a list of all accessible documents",
+///     "additional_prompt": "related to policy1"
+/// }
+/// ```
+///
+/// Upon success, the response would be returned as follows:
+///
+/// ```
+/// {
+///     "status": "success",
+///     "message": "Retrieval estimated successfully.",
+///     "reference_id": "14b1456d-2708-45bc-8989-eac2d2eba4db",
+///     "data": {
+///         "model_id": "<model_id>",
+///         "query_category": "document",
+///         "query_tokens": 16,
+///         "prompt_tokens": 1840,
+///         "completion_tokens": 310,
+///         "total_tokens": 2150,
+///         "estimated_cost": 0.02771,
+///         "currency": "USD",
+///         "latency_class": "moderate",
+///         "expected_latency_ms": 4200,
+///         "basis": "similar_queries",
+///         "samples": 37
+///     }
+/// }
+/// ```
+
+#[instrument(skip_all)]
+pub async fn post_retrieval_estimate_handler(
+    ctx: Ctx,
+    State(app_state): State<Arc<AppState>>,
+    request: Request<Body>,
+) -> Result<impl IntoResponse, FacadeApiError> {
+    // Take the reference ID and task ID of the request and initialize the app_name (generic app_name = "tresleai-system")
+    let app_name = app_state.app_settings.tracing_layer_system_app_name.clone();
+    let reference_id = ctx.reference_id.clone();
+    let task_id = ctx.task_id.clone();
+
+    // Fetch general message to be returned to client, in case of an error
+    let ext_message = app_state.app_settings.general_message.clone();
+
+    // Generate and insert the ID document
+    let id_document = generate_id_document(&app_name, reference_id.clone(), task_id.clone()).await;
+    create_document_in_db(
+        &app_state,
+        &id_document,
+        DocType::ID,
+        &app_state.app_settings.mongo_db.mongo_db_id_collection,
+        &app_name,
+        &reference_id,
+        &task_id,
+    )
+    .await?;
+    ctx.mark_recorded();
+
+    // Extract the API key from the request headers
+    let headers = request.headers();
+    let api_key = headers
+        .get("x-api-key")
+        .ok_or_else(|| {
+            TresleFacadeCommonError::missing_api_key(&reference_id, &task_id, &ext_message)
+        })?
+        .to_str()
+        .map_err(|_| {
+            TresleFacadeCommonError::invalid_api_key(&reference_id, &task_id, &ext_message)
+        })?;
+
+    // Fetch the app name corresponding to the API key
+    let app_name =
+        fetch_app_name(&app_state, &api_key.to_string(), &task_id, &reference_id).await?;
+    record_api_key_usage(&app_state, &app_name, "estimate").await;
+
+    // Extract the request body and deserialize it
+    let body_bytes = to_bytes(request.into_body(), app_state.max_request_body_bytes())
+        .await
+        .map_err(|_| {
+            TresleFacadeCommonError::failed_to_read_retrieval_request_body(
+                &reference_id,
+                &task_id,
+                &ext_message,
+            )
+        })?;
+    let body: RetrievalEstimateRequest = serde_json::from_slice(&body_bytes).map_err(|e| {
+        TresleFacadeCommonError::failed_to_parse_retrieval_request_body(
+            &reference_id,
+            &task_id,
+            e,
+            &ext_message,
+        )
+    })?;
+
+    let estimate = estimate_retrieval(&app_state, &app_name, &body)
+        .await
+        .map_err(|e| match e {
+            RetrievalEstimateError::AppLookup(e) => {
+                TresleFacadeCommonError::failed_to_fetch_allowed_models(
+                    &reference_id,
+                    &task_id,
+                    e,
+                    &ext_message,
+                )
+            }
+            e => TresleFacadeCommonError::retrieval_estimate_rejected(&reference_id, &task_id, e),
+        })?;
+
+    let success_message = "Retrieval estimated successfully.".to_string();
+    info!(
+        app_name = app_name,
+        task_id = task_id,
+        message = format!(
+            "{} Basis: {:?}, total tokens: {}.",
+            success_message, estimate.basis, estimate.total_tokens
+        )
+    );
+    Ok(Json(
+        json!({"status": "success", "message": success_message, "reference_id": reference_id, "data": estimate}),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_failed_post_retrieval_estimate_handler_missing_api_key() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            let body = Body::from(json!({"query": "What is the leave policy?"}).to_string());
+            let request = Request::post("/").body(body).unwrap();
+
+            let result = post_retrieval_estimate_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                State(app_state),
+                request,
+            )
+            .await;
+
+            match result.err().unwrap().inner {
+                TresleFacadeCommonError::ApiKeyError { .. } => {}
+                _ => panic!("Expected ApiKeyError"),
+            }
+        });
+    }
+}
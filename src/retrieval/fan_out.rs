@@ -0,0 +1,210 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the fan-out retrievals. A retrieval request with `sub_queries` is decomposed by the client
+//! into sub-queries: each one is normalized and classified like a query, and sent to the knowledge engine as its own
+//! request, at most `fan_out.max_concurrency` at a time. The answers are aggregated into a single history document,
+//! with the answer and citations of every sub-query.
+//! A fan-out retrieval fails only if all its sub-queries fail; the failed sub-queries are kept with their error.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{FanOutSettings, TresleFacadeServiceSettings};
+use crate::retrieval::fetch_from_knowledge_engine::{
+    retrieve_from_knowledge_engine, TresleFacadeRetrievalError,
+};
+use crate::retrieval::query_classification::classify_query;
+use crate::retrieval::query_normalization::normalize_retrieval_query;
+use crate::retrieval::schema::history_document::composite_response;
+use crate::service::deadline::Deadline;
+use crate::service::experiment::ExperimentAssignment;
+use crate::service::row_filter::RowFilter;
+use crate::service::source_label::SearchScope;
+use crate::service::state::AppState;
+use api_utils::retrieval_model::RetrievalRequest;
+use futures::stream::StreamExt;
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::{error, instrument};
+
+/// Default maximum number of sub-queries of a retrieval.
+const DEFAULT_MAX_SUB_QUERIES: usize = 8;
+/// Default number of sub-queries sent to the knowledge engine at a time.
+const DEFAULT_MAX_CONCURRENCY: usize = 4;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum FanOutError {
+    #[error("Too many sub_queries: {count}. A retrieval has at most {max} sub-queries.")]
+    TooManySubQueries { count: usize, max: usize },
+    #[error("Sub-query {0} is empty.")]
+    EmptySubQuery(usize),
+}
+
+/// Fan-out options: sub-query cap and engine call concurrency.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FanOutOptions {
+    pub max_sub_queries: usize,
+    pub max_concurrency: usize,
+}
+
+impl SettingsOptions for FanOutOptions {
+    type Settings = FanOutSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&FanOutSettings> {
+        settings.fan_out.as_ref()
+    }
+
+    fn from_settings(settings: Option<&FanOutSettings>) -> Self {
+        FanOutOptions {
+            max_sub_queries: settings
+                .and_then(|settings| settings.max_sub_queries)
+                .unwrap_or(DEFAULT_MAX_SUB_QUERIES),
+            max_concurrency: settings
+                .and_then(|settings| settings.max_concurrency)
+                .filter(|max_concurrency| *max_concurrency > 0)
+                .unwrap_or(DEFAULT_MAX_CONCURRENCY),
+        }
+    }
+}
+
+/// Fan-out fields of a retrieval request body, read next to the `RetrievalRequest`.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RetrievalFanOut {
+    #[serde(default)]
+    pub sub_queries: Option<Vec<String>>,
+}
+
+impl RetrievalFanOut {
+    /// Returns the trimmed sub-queries of a fan-out retrieval, `None` for a regular retrieval.
+    pub fn sub_queries(&self, options: &FanOutOptions) -> Result<Option<Vec<String>>, FanOutError> {
+        let Some(sub_queries) = self
+            .sub_queries
+            .as_ref()
+            .filter(|sub_queries| !sub_queries.is_empty())
+        else {
+            return Ok(None);
+        };
+        if sub_queries.len() > options.max_sub_queries {
+            return Err(FanOutError::TooManySubQueries {
+                count: sub_queries.len(),
+                max: options.max_sub_queries,
+            });
+        }
+        sub_queries
+            .iter()
+            .enumerate()
+            .map(|(index, sub_query)| match sub_query.trim() {
+                "" => Err(FanOutError::EmptySubQuery(index)),
+                sub_query => Ok(sub_query.to_string()),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Some)
+    }
+}
+
+/// Sends the sub-queries of a retrieval to the knowledge engine, at most `max_concurrency` at a time, and returns
+/// the composite response of their answers. Fails if all the sub-queries fail.
+#[instrument(skip_all)]
+#[allow(clippy::too_many_arguments)]
+pub async fn retrieve_sub_queries(
+    app_state: &Arc<AppState>,
+    body: &RetrievalRequest,
+    sub_queries: &[String],
+    app_name: &str,
+    task_id: &str,
+    row_filters: &[RowFilter],
+    experiments: &[ExperimentAssignment],
+    search_scope: Option<&SearchScope>,
+    deadline: Option<&Deadline>,
+) -> Result<String, TresleFacadeRetrievalError> {
+    let options = app_state.options::<FanOutOptions>();
+    let sub_responses: Vec<(String, Result<String, String>)> =
+        futures::stream::iter(sub_queries.iter().map(|sub_query| async move {
+            let mut sub_body = body.clone();
+            sub_body.query = normalize_retrieval_query(app_state, app_name, sub_query)
+                .await
+                .unwrap_or_else(|| sub_query.clone());
+            let query_category = classify_query(app_state, app_name, &sub_body.query).await;
+            let response = retrieve_from_knowledge_engine(
+                app_state,
+                sub_body,
+                app_name,
+                task_id,
+                row_filters,
+                query_category,
+                experiments,
+                search_scope,
+                deadline,
+            )
+            .await
+            .map_err(|e| {
+                error!(
+                    app_name = app_name,
+                    message = format!("Sub-query '{}' failed. Error: {}", sub_query, e)
+                );
+                e.to_string()
+            });
+            (sub_query.clone(), response)
+        }))
+        .buffered(options.max_concurrency)
+        .collect()
+        .await;
+
+    if sub_responses.iter().all(|(_, response)| response.is_err()) {
+        let errors: Vec<String> = sub_responses
+            .into_iter()
+            .filter_map(|(_, response)| response.err())
+            .collect();
+        return Err(TresleFacadeRetrievalError::SubQueriesFailed(
+            errors.join(" "),
+        ));
+    }
+    Ok(composite_response(&sub_responses).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fan_out(sub_queries: &[&str]) -> RetrievalFanOut {
+        RetrievalFanOut {
+            sub_queries: Some(sub_queries.iter().map(|query| query.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn test_success_retrieval_fan_out_sub_queries() {
+        let options = FanOutOptions::from_settings(None);
+        assert_eq!(RetrievalFanOut::default().sub_queries(&options), Ok(None));
+        assert_eq!(fan_out(&[]).sub_queries(&options), Ok(None));
+        assert_eq!(
+            fan_out(&[" leave policy ", "travel policy"]).sub_queries(&options),
+            Ok(Some(vec![
+                "leave policy".to_string(),
+                "travel policy".to_string()
+            ]))
+        );
+        let body: RetrievalFanOut =
+            serde_json::from_str(r#"{"query": "HR policies", "sub_queries": ["leave policy"]}"#)
+                .unwrap();
+        assert_eq!(body, fan_out(&["leave policy"]));
+    }
+
+    #[test]
+    fn test_failure_retrieval_fan_out_sub_queries() {
+        let options = FanOutOptions {
+            max_sub_queries: 2,
+            max_concurrency: 1,
+        };
+        assert_eq!(
+            fan_out(&["a", "b", "c"]).sub_queries(&options),
+            Err(FanOutError::TooManySubQueries { count: 3, max: 2 })
+        );
+        assert_eq!(
+            fan_out(&["a", " "]).sub_queries(&options),
+            Err(FanOutError::EmptySubQuery(1))
+        );
+    }
+}
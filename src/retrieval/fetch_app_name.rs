@@ -5,13 +5,16 @@
  */
 //! This module contains the function to fetch app name from DocumentDB corresponding to the input API key
 //! during the information retrieval process.
+//! Any active key of the app resolves it: its primary key, or one of its additional keys before its expiry date, whose
+//! last use is recorded. An expired key is answered with a 401 "key expired" error, distinct from an unknown key.
 //!
 //!
 
+use crate::service::app_keys::record_app_key_use;
+use crate::service::app_repository::AppRepositoryError;
 use crate::service::error::TresleFacadeCommonError;
 use crate::service::state::AppState;
 use error_utils::AxumApiError;
-use mongodb::bson::doc;
 use std::sync::Arc;
 use tracing::{info, instrument};
 
@@ -23,42 +26,30 @@ pub async fn fetch_app_name(
     task_id: &String,
     reference_id: &String,
 ) -> Result<String, AxumApiError<TresleFacadeCommonError>> {
-    let filter = doc! {"api_key": api_key};
-    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
     let ext_message = app_state.app_settings.general_message.clone();
 
-    match app_state
-        .db
-        .get_document(collection_name, filter)
-        .await
-        .map_err(|e| {
-            TresleFacadeCommonError::failed_to_fetch_app_name_from_db(
+    match app_state.apps().api_key_owner(api_key).await {
+        Ok(owner) => {
+            if let Some(app_key) = &owner.app_key {
+                record_app_key_use(app_state, &owner.app_name, app_key).await;
+            }
+            let success_message = "App name fetched successfully for given api_key.".to_string();
+            info!(app_name = owner.app_name, message = success_message);
+            Ok(owner.app_name)
+        }
+        Err(AppRepositoryError::MissingField(_)) => Err(error_utils::AxumApiError {
+            inner: TresleFacadeCommonError::no_app_name_key_found(
                 reference_id,
                 task_id,
-                e,
                 &ext_message,
-            )
-        }) {
-        Ok(Some(response)) => {
-            if let Some(app_name) = response
-                .get("app_name")
-                .and_then(|app_name| app_name.as_str())
-            {
-                let success_message =
-                    "App name fetched successfully for given api_key.".to_string();
-                info!(app_name = app_name, message = success_message);
-                Ok(app_name.to_string())
-            } else {
-                Err(error_utils::AxumApiError {
-                    inner: TresleFacadeCommonError::no_app_name_key_found(
-                        reference_id,
-                        task_id,
-                        &ext_message,
-                    ),
-                })
-            }
+            ),
+        }),
+        Err(AppRepositoryError::ApiKeyExpired { expired_at, .. }) => {
+            Err(error_utils::AxumApiError {
+                inner: TresleFacadeCommonError::api_key_expired(reference_id, task_id, &expired_at),
+            })
         }
-        Ok(None) => Err(error_utils::AxumApiError {
+        Err(AppRepositoryError::ApiKeyNotFound) => Err(error_utils::AxumApiError {
             inner: TresleFacadeCommonError::no_app_name_found_for_given_api_key(
                 reference_id,
                 task_id,
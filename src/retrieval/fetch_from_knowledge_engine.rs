@@ -7,12 +7,27 @@
 //! This module makes a POST request to the core microservice with a request body and receives
 //! a response from it.
 //! The function is used by the retrieval service to fetch data from the core microservice.
+//! The row filters of the datastore tables of the app are sent with the request, next to the user details, for the
+//! knowledge engine to scope the rows read by the user.
+//! The category of the query, when classified, is sent as the `routing_hint` of the request. The same payload is
+//! sent to the alternate knowledge engine of the shadow traffic of the app (see `crate::service::shadow_traffic`).
+//! The variants of the experiments of the app assigned to the retrieval, if any, are sent as its `experiments`.
+//! The search scope of the retrieval, when restricted to some source labels, is sent as its `search_scope`.
+//! The remaining budget of the retrieval, when it has a deadline, is sent in the `x-deadline-remaining-ms` header,
+//! and the knowledge engine is not called once the deadline is past.
 //! The function returns a 500 status code if an error occurs while fetching data from the core microservice.
 //!
 
+use crate::retrieval::query_classification::QueryCategory;
+use crate::service::deadline::{with_deadline, Deadline, DeadlineError};
+use crate::service::experiment::ExperimentAssignment;
+use crate::service::row_filter::RowFilter;
+use crate::service::source_label::SearchScope;
 use crate::service::state::AppState;
 use api_utils::retrieval_model::RetrievalRequest;
+use chrono::Utc;
 use reqwest::header::CONTENT_TYPE;
+use serde_json::json;
 use std::sync::Arc;
 use tracing::{debug, instrument};
 
@@ -23,20 +38,60 @@ pub enum TresleFacadeRetrievalError {
     ReqwestError(#[from] reqwest::Error),
     #[error("Error in serializing the request body.")]
     SerdeJsonError(#[from] serde_json::Error),
+    #[error("All the sub-queries failed. {0}")]
+    SubQueriesFailed(String),
+    #[error("{0}")]
+    DeadlineExceeded(#[from] DeadlineError),
+}
+
+/// Builds the payload of a knowledge engine request: the retrieval request, with the row filters, routing hint,
+/// experiments and search scope of the retrieval if any.
+pub(crate) fn engine_payload(
+    body: &RetrievalRequest,
+    row_filters: &[RowFilter],
+    query_category: Option<QueryCategory>,
+    experiments: &[ExperimentAssignment],
+    search_scope: Option<&SearchScope>,
+) -> Result<serde_json::Value, serde_json::Error> {
+    let mut payload = serde_json::to_value(body)?;
+    if !row_filters.is_empty() {
+        payload["row_filters"] = json!(row_filters);
+    }
+    if let Some(query_category) = query_category {
+        payload["routing_hint"] = json!({"query_category": query_category});
+    }
+    if !experiments.is_empty() {
+        payload["experiments"] = json!(experiments);
+    }
+    if let Some(search_scope) = search_scope {
+        payload["search_scope"] = json!(search_scope);
+    }
+    Ok(payload)
 }
 
 /// Function to make a POST request to the core with the request body and receive a response from it.
 #[instrument(skip_all)]
+#[allow(clippy::too_many_arguments)]
 pub async fn retrieve_from_knowledge_engine(
     app_state: &Arc<AppState>,
     mut body: RetrievalRequest,
     app_name: &str,
     task_id: &str,
+    row_filters: &[RowFilter],
+    query_category: Option<QueryCategory>,
+    experiments: &[ExperimentAssignment],
+    search_scope: Option<&SearchScope>,
+    deadline: Option<&Deadline>,
 ) -> Result<String, TresleFacadeRetrievalError> {
     // Add app_name and task_id to the body
     body.app_name = Some(app_name.to_owned());
     body.task_id = Some(task_id.to_owned());
 
+    // Don't call the knowledge engine for a retrieval the facade has given up on
+    if let Some(deadline) = deadline {
+        deadline.check(Utc::now())?;
+    }
+
     debug!("Retrieving data from the core microservice.");
     let url = format!(
         "{}/{}",
@@ -52,16 +107,27 @@ pub async fn retrieve_from_knowledge_engine(
         "Making a POST request to the core microservice at URL: {}",
         url
     );
-    let client = reqwest::Client::new();
-
-    // Send serialized body as request payload to the core
-    let serialized_body = serde_json::to_string(&body)?;
-
-    let response = client
-        .post(url)
-        .header(CONTENT_TYPE, "application/json")
-        .body(serialized_body)
-        .send()
+    let client = app_state.http_clients.for_url(&url);
+
+    // Send serialized body as request payload to the core, with the row filters of the app if any
+    let payload = engine_payload(
+        &body,
+        row_filters,
+        query_category,
+        experiments,
+        search_scope,
+    )?;
+    let serialized_body = serde_json::to_string(&payload)?;
+
+    let response = app_state
+        .http_clients
+        .send(with_deadline(
+            client
+                .post(url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(serialized_body),
+            deadline,
+        ))
         .await?
         .text()
         .await?;
@@ -106,9 +172,18 @@ mod tests {
                 String::from("TSK-47829-app_223-Onboarding-2024-04-04 05:52:22.755295 UTC");
 
             // Call the function
-            let result =
-                retrieve_from_knowledge_engine(&app_state, retrieval_request, &app_name, &task_id)
-                    .await;
+            let result = retrieve_from_knowledge_engine(
+                &app_state,
+                retrieval_request,
+                &app_name,
+                &task_id,
+                &[],
+                Some(QueryCategory::Document),
+                &[],
+                None,
+                None,
+            )
+            .await;
 
             println!("results:{:?}\n", result);
             // Check that the result is as expected
@@ -6,74 +6,509 @@
 //! This module contains the asynchronous POST handler for information retrieval and calls helper functions
 //! to validate IAM policies and fetch data from the knowledge engine microservice.
 
+use crate::configuration::settings::ReadinessMode;
+use crate::retrieval::fan_out::{retrieve_sub_queries, FanOutOptions, RetrievalFanOut};
 use crate::retrieval::fetch_app_name::fetch_app_name;
-use crate::retrieval::fetch_from_knowledge_engine::retrieve_from_knowledge_engine;
+use crate::retrieval::fetch_from_knowledge_engine::{
+    engine_payload, retrieve_from_knowledge_engine,
+};
+use crate::retrieval::query_classification::classify_query;
+use crate::retrieval::query_normalization::normalize_retrieval_query;
+use crate::retrieval::replay::StoredRequest;
+use crate::retrieval::schema::history_document::HistoryDocument;
+use crate::retrieval::stage_timings::{
+    record_stage_timings, store_history_write_timing, RetrievalStage, StageTimings,
+};
 use crate::retrieval::update_task_id::update_task_id;
-use crate::service::error::TresleFacadeCommonError;
+use crate::service::answer_offload::offload_oversized_answer;
+use crate::service::answer_sink::mirror_answer;
+use crate::service::api_key::record_api_key_usage;
+use crate::service::ctx::Ctx;
+use crate::service::deadline::Deadline;
+use crate::service::error::{FacadeApiError, TresleFacadeCommonError};
+use crate::service::experiment::{assign_variants, experiment_variants, ExperimentAssignment};
 use crate::service::generate_and_insert_document::DocType;
 use crate::service::generate_and_insert_document::*;
+use crate::service::history_upsert::{upsert_history_document, HistoryUpsert};
+use crate::service::job_queue::{start_job, JobQueue, QueuedJob};
+use crate::service::metrics::{
+    MetricRecord, APP_NAME_DIMENSION, EXPERIMENT_DIMENSION, QUERY_CATEGORY_DIMENSION,
+    TASK_ID_DIMENSION, VARIANT_DIMENSION,
+};
+use crate::service::notification::{
+    daily_period, record_notification, Notification, NotificationKind,
+};
+use crate::service::prompt_template::{
+    resolve_additional_prompt, with_additional_prompt, RetrievalPrompt,
+};
+use crate::service::pseudonymization::pseudonymize_user_id;
+use crate::service::query_loop::{check_query_loop, QueryLoopDecision};
+use crate::service::rate_limit::RateLimitDecision;
+use crate::service::readiness::{app_readiness, ReadinessOptions};
+use crate::service::row_filter::RowFilter;
+use crate::service::scim::{check_entitlement, EntitlementDecision, ScimError};
+use crate::service::shadow_traffic::{shadow_retrieval, ShadowOutcome, ShadowRequest};
+use crate::service::source_label::{RetrievalScope, SearchScope};
 use crate::AppState;
 use api_utils::retrieval_model::RetrievalRequest;
 use axum::body::{to_bytes, Body};
-use axum::http::Request;
+use axum::http::{header::RETRY_AFTER, Request, StatusCode};
 use axum::{extract::State, response::IntoResponse, Json};
 use chrono::{DateTime, Utc};
-use error_utils::AxumApiError;
-use logging_utils::create_ref_id_helper::create_ref_id;
-use logging_utils::create_task_id_helper::create_task_id;
 use logging_utils::create_task_ref_id_helper::create_task_ref_collection;
+use mongodb::bson::doc;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::sync::Arc;
-use tracing::{error, info, instrument};
+use std::time::Instant;
+use tracing::{error, info, instrument, warn};
 
-const HISTORY_COLLECTION_SUFFIX: &str = "-history";
+#[instrument(skip_all)]
+/// Asynchronous function to encrypt the query, the normalized query, the response, the answer, the citation
+/// snippets and the request of a history document, and of its sub-queries, with the data key of the app.
+/// Returns `None` if the encryption fails, so that the history document is never stored in plaintext.
+async fn encrypt_history_document(
+    app_state: &Arc<AppState>,
+    app_name: &str,
+    mut history_document: HistoryDocument,
+) -> Option<HistoryDocument> {
+    let mut fields = vec![&mut history_document.query, &mut history_document.response];
+    fields.extend(history_document.normalized_query.as_mut());
+    fields.extend(history_document.answer.as_mut());
+    fields.extend(history_document.request.as_mut());
+    fields.extend(
+        history_document
+            .citations
+            .iter_mut()
+            .filter_map(|citation| citation.snippet.as_mut()),
+    );
+    for sub_query in history_document.sub_queries.iter_mut() {
+        fields.push(&mut sub_query.query);
+        fields.extend(sub_query.answer.as_mut());
+        fields.extend(
+            sub_query
+                .citations
+                .iter_mut()
+                .filter_map(|citation| citation.snippet.as_mut()),
+        );
+    }
+    for field in fields {
+        match app_state.encrypt_field(app_name, field).await {
+            Ok(encrypted_field) => *field = encrypted_field,
+            Err(e) => {
+                let error_message = format!("Failed to encrypt history document. Error: {}", e);
+                error!(app_name = app_name, message = error_message);
+                return None;
+            }
+        }
+    }
+    Some(history_document)
+}
+
+/// Background operations of a retrieval, stored in the retrieval job queue (see `service::job_queue`).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RetrievalJob {
+    pub app_name: String,
+    /// User ID as stored in the history document.
+    pub user_id: String,
+    pub body: RetrievalRequest,
+    pub sub_queries: Option<Vec<String>>,
+    pub experiments: Vec<ExperimentAssignment>,
+    pub row_filters: Vec<RowFilter>,
+    /// Search scope of the retrieval, when restricted to some source labels.
+    #[serde(default)]
+    pub search_scope: Option<SearchScope>,
+    pub reference_id: String,
+    pub task_id: String,
+    pub request_timestamp: DateTime<Utc>,
+    /// Reference ID of the replayed retrieval, for a replay.
+    pub replay_of: Option<String>,
+    /// Whether the retrieval is a sandbox retrieval of the admin UI, run synchronously.
+    #[serde(default)]
+    pub sandbox: bool,
+    pub deadline: Option<Deadline>,
+    /// Stages timed by the POST handler.
+    #[serde(default)]
+    pub timings: StageTimings,
+}
+
+impl RetrievalJob {
+    /// Job as stored in the retrieval job queue: the request carries the user ID as stored in the history document,
+    /// never the user ID behind a pseudonym.
+    fn queued(&self) -> RetrievalJob {
+        let mut job = self.clone();
+        job.body.user_details.user_id = self.user_id.clone();
+        job
+    }
+
+    /// Failed history document of a retrieval interrupted before its answer.
+    fn interrupted_history_document(
+        &self,
+        error: &str,
+        disclaimer_text: String,
+    ) -> HistoryDocument {
+        HistoryDocument::failed(
+            self.reference_id.clone(),
+            self.task_id.clone(),
+            self.body.query.clone(),
+            format!("Retrieval interrupted before its answer. {}", error),
+            disclaimer_text,
+        )
+        .with_user_id(&self.user_id)
+        .with_experiment_variants(experiment_variants(&self.experiments))
+        .with_request(
+            StoredRequest::new(&self.body, &self.user_id, self.sub_queries.clone())
+                .with_search_scope(self.search_scope.as_ref())
+                .to_field(),
+        )
+        .with_replay_of(self.replay_of.clone())
+        .with_sandbox(self.sandbox)
+    }
+}
 
+/// Stores the job of a retrieval and runs its background operations right away. A retrieval whose job can't be
+/// stored still runs, without recovery from a crash.
+pub(crate) async fn start_retrieval_job(app_state: &Arc<AppState>, job: RetrievalJob) {
+    if let Err(e) = start_job(
+        app_state,
+        JobQueue::Retrieval,
+        &job.app_name,
+        &job,
+        &job.queued(),
+    )
+    .await
+    {
+        error!(app_name = &job.app_name, message = e.to_string());
+        tokio::spawn(background_tasks(Arc::clone(app_state), job));
+    }
+}
+
+/// Runs a job of the retrieval queue. A job redelivered after the replica running it crashed is resumed, unless the
+/// retrieval is answered already or past its deadline, in which case it fails with a terminal history document.
 #[instrument(skip_all)]
-/// Asynchronous function to perform background operations with knowledge engine/core microservice and DocumentDB
-async fn background_tasks(
-    app_state: Arc<AppState>,
-    app_name: String,
-    user_id: String,
-    body: RetrievalRequest,
-    reference_id: String,
-    task_id: String,
-    request_timestamp: DateTime<Utc>,
+pub(crate) async fn run_retrieval_job(
+    app_state: &Arc<AppState>,
+    job: &QueuedJob,
+) -> Result<(), String> {
+    let retrieval: RetrievalJob = job
+        .payload(app_state)
+        .await
+        .map_err(|e| format!("Malformed retrieval job. Error: {}", e))?;
+    if job.attempts > 1 {
+        if is_answered(app_state, &retrieval).await {
+            return Ok(());
+        }
+        if let Some(Err(e)) = retrieval
+            .deadline
+            .as_ref()
+            .map(|deadline| deadline.check(Utc::now()))
+        {
+            store_failed_history_document(
+                app_state,
+                &retrieval.app_name,
+                &retrieval.task_id,
+                retrieval.interrupted_history_document(
+                    &e.to_string(),
+                    app_state.app_settings.disclaimer_text.clone(),
+                ),
+            )
+            .await;
+            return Ok(());
+        }
+        info!(
+            app_name = &retrieval.app_name,
+            message = format!(
+                "Resuming retrieval '{}' at attempt {}.",
+                retrieval.reference_id, job.attempts
+            )
+        );
+    }
+    background_tasks(Arc::clone(app_state), retrieval).await;
+    Ok(())
+}
+
+/// Fails a job of the retrieval queue abandoned at its last attempt with a terminal history document, unless the
+/// retrieval is answered already.
+pub(crate) async fn abandon_retrieval_job(app_state: &Arc<AppState>, job: &QueuedJob, error: &str) {
+    let Ok(retrieval) = job.payload::<RetrievalJob>(app_state).await else {
+        return;
+    };
+    if is_answered(app_state, &retrieval).await {
+        return;
+    }
+    let history_document = retrieval
+        .interrupted_history_document(error, app_state.app_settings.disclaimer_text.clone());
+    store_failed_history_document(
+        app_state,
+        &retrieval.app_name,
+        &retrieval.task_id,
+        history_document,
+    )
+    .await;
+}
+
+/// Returns whether the history document of a retrieval is stored. A lookup failure counts as unanswered.
+async fn is_answered(app_state: &AppState, retrieval: &RetrievalJob) -> bool {
+    let db = match app_state.app_db(&retrieval.app_name).await {
+        Ok(db) => db,
+        Err(e) => {
+            error!(app_name = &retrieval.app_name, message = e.to_string());
+            return false;
+        }
+    };
+    matches!(
+        db.get_document(
+            &format!("{}-history", retrieval.app_name),
+            doc! {"reference_id": &retrieval.reference_id},
+        )
+        .await,
+        Ok(Some(_))
+    )
+}
+
+/// Stores the failed history document of a retrieval, encrypted for the apps with a data key.
+async fn store_failed_history_document(
+    app_state: &Arc<AppState>,
+    app_name: &str,
+    task_id: &str,
+    history_document: HistoryDocument,
 ) {
-    // Retrieve data from the knowledge engine microservice
-    match retrieve_from_knowledge_engine(&app_state, body.clone(), &app_name, &task_id).await {
+    let answer = history_document.clone();
+    let Some(history_document) =
+        encrypt_history_document(app_state, app_name, history_document).await
+    else {
+        return;
+    };
+    match upsert_history_document(app_state, app_name, &history_document).await {
+        Ok(HistoryUpsert::Created) => mirror_answer(app_state, app_name, answer),
+        Ok(_) => {}
+        Err(e) => error!(
+            app_name = app_name,
+            task_id = task_id,
+            message = e.to_string()
+        ),
+    }
+}
+
+#[instrument(skip_all)]
+/// Asynchronous function to perform background operations with knowledge engine/core microservice and DocumentDB.
+/// A replay (`replay_of` set) or a sandbox retrieval stores its history document flagged, and doesn't account its
+/// tokens.
+/// A retrieval past its deadline fails without calling the knowledge engine.
+pub(crate) async fn background_tasks(app_state: Arc<AppState>, job: RetrievalJob) {
+    let RetrievalJob {
+        app_name,
+        user_id,
+        body,
+        sub_queries,
+        experiments,
+        row_filters,
+        search_scope,
+        reference_id,
+        task_id,
+        request_timestamp,
+        replay_of,
+        sandbox,
+        deadline,
+        mut timings,
+    } = job;
+    // Keep the request, as sent by the app, to replay the retrieval
+    let stored_request = StoredRequest::new(&body, &user_id, sub_queries.clone())
+        .with_search_scope(search_scope.as_ref())
+        .to_field();
+
+    // Normalize the query when enabled for the app, the history document keeps the original query
+    let normalized_query = normalize_retrieval_query(&app_state, &app_name, &body.query).await;
+    let mut engine_body = body.clone();
+    if let Some(normalized_query) = &normalized_query {
+        engine_body.query = normalized_query.clone();
+    }
+
+    // Classify the query, passed to the knowledge engine as a routing hint
+    let query_category = classify_query(&app_state, &app_name, &engine_body.query).await;
+    if let Some(query_category) = query_category {
+        app_state
+            .record_metric(
+                MetricRecord::counter("Query Classification Counter")
+                    .dimension(APP_NAME_DIMENSION, &app_name)
+                    .dimension(QUERY_CATEGORY_DIMENSION, query_category.as_str()),
+            )
+            .await;
+    }
+
+    // Keep the payload of the knowledge engine to shadow the retrieval. Fan-out retrievals, replays and sandbox
+    // retrievals are not shadowed.
+    let shadow_payload = if sub_queries.is_none() && replay_of.is_none() && !sandbox {
+        let mut shadow_body = engine_body.clone();
+        shadow_body.app_name = Some(app_name.clone());
+        shadow_body.task_id = Some(task_id.clone());
+        engine_payload(
+            &shadow_body,
+            &row_filters,
+            query_category,
+            &experiments,
+            search_scope.as_ref(),
+        )
+        .ok()
+    } else {
+        None
+    };
+
+    // Retrieve data from the knowledge engine microservice, sub-query by sub-query for a fan-out retrieval
+    let engine_call_start = Instant::now();
+    let retrieval = match &sub_queries {
+        Some(sub_queries) => {
+            app_state
+                .record_metric(
+                    MetricRecord::count("Retrieval Sub-Queries", sub_queries.len())
+                        .dimension(APP_NAME_DIMENSION, &app_name),
+                )
+                .await;
+            retrieve_sub_queries(
+                &app_state,
+                &engine_body,
+                sub_queries,
+                &app_name,
+                &task_id,
+                &row_filters,
+                &experiments,
+                search_scope.as_ref(),
+                deadline.as_ref(),
+            )
+            .await
+        }
+        None => {
+            retrieve_from_knowledge_engine(
+                &app_state,
+                engine_body,
+                &app_name,
+                &task_id,
+                &row_filters,
+                query_category,
+                &experiments,
+                search_scope.as_ref(),
+                deadline.as_ref(),
+            )
+            .await
+        }
+    };
+    let engine_call = engine_call_start.elapsed();
+    timings.set(RetrievalStage::EngineCall, engine_call);
+
+    // Shadow the retrieval to the alternate knowledge engine of the app, never delaying the answer
+    if let Some(payload) = shadow_payload {
+        let primary =
+            ShadowOutcome::new(retrieval.as_deref().map_err(|e| e.to_string()), engine_call);
+        tokio::spawn(shadow_retrieval(
+            Arc::clone(&app_state),
+            ShadowRequest {
+                app_name: app_name.clone(),
+                reference_id: reference_id.clone(),
+                task_id: task_id.clone(),
+                payload,
+                primary,
+            },
+        ));
+    }
+    match retrieval {
         Ok(response) => {
             let retrieval_success_timestamp = Utc::now();
-            let history_collection_name = format!("{}{}", &app_name, HISTORY_COLLECTION_SUFFIX);
             // Generate the history document and insert it in the history collection of that app in DocumentDB
             let history_document = generate_history_document(
                 reference_id.clone(),
                 task_id.clone(),
                 &body.query,
                 &response,
-                retrieval_success_timestamp.to_string(),
+                retrieval_success_timestamp,
                 app_state.app_settings.disclaimer_text.clone(),
             )
-            .await;
-            if create_document_in_db(
-                &app_state,
-                &history_document,
-                DocType::History,
-                &history_collection_name,
+            .await
+            .with_user_id(&user_id)
+            .with_normalized_query(normalized_query.clone())
+            .with_query_category(query_category)
+            .with_experiment_variants(experiment_variants(&experiments))
+            .with_request(stored_request)
+            .with_replay_of(replay_of.clone())
+            .with_sandbox(sandbox)
+            .with_timings(timings);
+            // Offload the full response of an oversized answer, the history document keeps it truncated
+            let history_document =
+                offload_oversized_answer(&app_state, &app_name, history_document).await;
+            let answer = history_document.clone();
+            let Some(history_document) =
+                encrypt_history_document(&app_state, &app_name, history_document).await
+            else {
+                return;
+            };
+            let history_write_start = Instant::now();
+            match upsert_history_document(&app_state, &app_name, &history_document).await {
+                Ok(HistoryUpsert::Created) => {
+                    // Complete the timings once the history document is stored
+                    timings.set(RetrievalStage::HistoryWrite, history_write_start.elapsed());
+                    store_history_write_timing(&app_state, &app_name, &reference_id, &timings)
+                        .await;
+                    record_stage_timings(&app_state, &app_name, &timings).await;
+                    // Mirror the answer to the sinks of the app
+                    mirror_answer(&app_state, &app_name, answer)
+                }
+                // The retrieval was already answered, by a retried task or a duplicate callback
+                Ok(_) => return,
+                Err(e) => {
+                    error!(
+                        app_name = &app_name,
+                        task_id = task_id,
+                        message = e.to_string()
+                    );
+                    return;
+                }
+            }
+
+            // Account the tokens used by the retrieval, when reported by the knowledge engine. Replays and sandbox
+            // retrievals are not accounted.
+            if sandbox {
+                info!(
+                    app_name = &app_name,
+                    message = format!("Sandbox retrieval '{}' stored.", reference_id)
+                );
+                return;
+            }
+            if replay_of.is_some() {
+                info!(
+                    app_name = &app_name,
+                    message = format!(
+                        "Replay '{}' of retrieval '{}' stored.",
+                        reference_id,
+                        replay_of.unwrap_or_default()
+                    )
+                );
+                return;
+            }
+            if let Some(token_usage_document) = generate_token_usage_document(
                 &app_name,
-                &reference_id,
-                &task_id,
+                &user_id,
+                &history_document,
+                retrieval_success_timestamp,
             )
             .await
-            .is_err()
             {
-                return;
+                let _ = create_document_in_db(
+                    &app_state,
+                    &token_usage_document,
+                    DocType::TokenUsage,
+                    &app_state
+                        .app_settings
+                        .mongo_db
+                        .mongo_db_token_usage_collection,
+                    &app_name,
+                    &reference_id,
+                    &task_id,
+                )
+                .await;
             }
 
             // Calculate the time taken to retrieve the data
-            let retrieval_duration = format!(
-                "{} ms",
-                (retrieval_success_timestamp - request_timestamp).num_milliseconds()
-            );
+            let retrieval_duration_ms =
+                (retrieval_success_timestamp - request_timestamp).num_milliseconds();
             let success_message = "Data retrieved successfully.".to_string();
 
             // Sending data to logs, audit and metrics microservices
@@ -87,13 +522,13 @@ async fn background_tasks(
                 details = success_message,
                 message = success_message
             );
-            info!(
-                service = "metric",
-                task_id = task_id,
-                app_name = &app_name,
-                metrics_name = "Data Retrieval Duration",
-                metrics_value = retrieval_duration
-            );
+            app_state
+                .record_metric(
+                    MetricRecord::duration_ms("Data Retrieval Duration", retrieval_duration_ms)
+                        .dimension(APP_NAME_DIMENSION, &app_name)
+                        .dimension(TASK_ID_DIMENSION, &task_id),
+                )
+                .await;
         }
         Err(error) => {
             let error_message = format!(
@@ -103,30 +538,24 @@ async fn background_tasks(
             error!(app_name = &app_name, message = error_message);
 
             // Send error to history collection
-            let history_collection_name = format!("{}{}", &app_name, HISTORY_COLLECTION_SUFFIX);
-            let history_document = generate_history_document(
+            let history_document = generate_failed_history_document(
                 reference_id.clone(),
                 task_id.clone(),
                 &body.query,
                 &error.to_string(),
-                "Retrieval failed.".to_string(),
                 app_state.app_settings.disclaimer_text.clone(),
             )
-            .await;
-            if create_document_in_db(
-                &app_state,
-                &history_document,
-                DocType::History,
-                &history_collection_name,
-                &app_name,
-                &reference_id,
-                &task_id,
-            )
             .await
-            .is_err()
-            {
-                return;
-            }
+            .with_user_id(&user_id)
+            .with_normalized_query(normalized_query.clone())
+            .with_query_category(query_category)
+            .with_experiment_variants(experiment_variants(&experiments))
+            .with_request(stored_request)
+            .with_replay_of(replay_of)
+            .with_sandbox(sandbox)
+            .with_timings(timings);
+            store_failed_history_document(&app_state, &app_name, &task_id, history_document).await;
+            record_stage_timings(&app_state, &app_name, &timings).await;
         }
     }
 }
@@ -139,6 +568,10 @@ async fn background_tasks(
         (status = 200, description = "Retrieval in progress."),
         (status = StatusCode::BAD_REQUEST, description = "Internal Error. Please contact tresleai support team. Use reference ID: "),
         (status = StatusCode::NOT_FOUND, description = "Internal Error. Please contact tresleai support team. Use reference ID: "),
+        (status = StatusCode::UNAUTHORIZED, description = "The API key expired on {}. Use an active API key of the app. Use reference ID: "),
+        (status = StatusCode::FORBIDDEN, description = "Access denied for the user. Use reference ID: "),
+        (status = StatusCode::TOO_MANY_REQUESTS, description = "Rate limit of the user exceeded, or identical query repeated in a loop. Retry after the seconds of the Retry-After header."),
+        (status = StatusCode::SERVICE_UNAVAILABLE, description = "The sources of the app are not indexed yet. Retry once the app is ready."),
         (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal Error. Please contact tresleai support team. Use reference ID: "),
     )
 )]
@@ -154,12 +587,41 @@ async fn background_tasks(
 /// - The 'access_details' field holds the details of the IAM policy and database policy.
 /// - The IAM policies tied to the user outline their permissions and access rights to resources in the AWS environment. These policies are validated before proceeding with retrieval, else the request is terminated.
 /// - The database policy details include the name of the database and table.
+/// - The row filters defined on the datastore tables of the app at onboarding (e.g. `tenant_id = :user_tenant`) are
+///   passed to the engine along with the user details, which binds their parameters to scope the rows of the user.
 /// - Each policy is linked with a unique name and ARN (Amazon Resource Name).
+/// - For apps onboarded with `pseudonymize_user_ids: true`, the user ID is stored in the history and token usage
+///   documents, and sent to the audit logs, as a pseudonym (`psn_` and the HMAC of the user ID under a secret of the
+///   app), and the rate limit of the user is keyed by the pseudonym. Only the service accounts granted the `identity:reveal`
+///   scope can look up the user ID behind a pseudonym.
+/// - For apps managed by the IdP through SCIM (a `tresleai-app-<app_name>` group exists), only the active users who are
+///   members of the group or entitled to the app may query it. Other users are rejected with a 403 status code.
 /// - For instance, a policy might allow the user access to certain S3 buckets, or grant permissions to operate on other AWS resources. This would shape a tailored response based on the resources the user can access.
 ///
 /// #### Query and additional prompt
+/// - Identical requests of a user repeated in a tight loop (more than `query_loop.max_repeats`, 10 by default, within
+///   `query_loop.window_seconds`, 60) are not executed again: they are rejected with a 429 status code, a Retry-After
+///   header until the window ends, and the reference ID of the last executed one, whose history document holds their
+///   result.
+/// - With `readiness.mode` set, the retrievals of an app with less than `readiness.threshold_percent` (80% by default)
+///   of its sources indexed are rejected with a 503 status code (`reject`), or executed with a `warning` in the
+///   response (`warn`), instead of returning empty answers right after onboarding.
 /// - The 'query' field contains the query to initiate the retrieval.
 /// - For enhanced context, the 'additional_prompt' field can be utilized.
+/// - Alternatively, the 'prompt_template' field references a prompt template of the app by `name`, with the `variables`
+///   of its placeholders. The facade validates and renders it into the additional prompt. Apps with prompt templates
+///   reject free-form additional prompts.
+/// - For an advanced retrieval, the optional 'sub_queries' field decomposes the query into sub-queries (at most
+///   `fan_out.max_sub_queries`, 8 by default). They are sent to the engine concurrently, and their answers aggregated
+///   into a single history document, with the answer and citations of each sub-query in its `sub_queries`.
+/// - The optional 'search_scope' field restricts the search to the filestores and datastores of the app carrying some
+///   of its labels, e.g. `["hr-docs"]`. The labels no source of the app carries are rejected with a 400 status code.
+///   The labels and their sources are forwarded to the engine in `search_scope`.
+///
+/// #### Experiments
+/// - The retrieval is assigned a variant of every enabled experiment of the app, sticky by user ID. The assignments
+///   are forwarded to the engine in `experiments`, with the parameters of the variants, and stored in the
+///   `experiment_variants` of the history document.
 ///
 /// #### API Key
 /// - The application's API key is required to authenticate the request.
@@ -225,32 +687,40 @@ async fn background_tasks(
 
 #[instrument(skip_all)]
 pub async fn post_retrieval_handler(
+    ctx: Ctx,
     State(app_state): State<Arc<AppState>>,
     request: Request<Body>,
-) -> Result<impl IntoResponse, AxumApiError<TresleFacadeCommonError>> {
-    let request_timestamp = Utc::now();
+) -> Result<impl IntoResponse, FacadeApiError> {
+    let request_timestamp = ctx.start;
 
-    // Generate reference ID and task ID and initialize the app_name (generic app_name = "tresleai-system")
-    let reference_id = create_ref_id();
+    // Take the reference ID and task ID of the request and initialize the app_name (generic app_name = "tresleai-system")
+    let reference_id = ctx.reference_id.clone();
     let mut app_name = app_state.app_settings.tracing_layer_system_app_name.clone();
-    let service_type = "Retrieval".to_string();
-    let initial_task_id = create_task_id(&app_name, service_type);
+    let initial_task_id = ctx.task_id.clone();
     // Fetch general message to be returned to client, in case of an error
     let ext_message = app_state.app_settings.general_message.clone();
 
+    // Time the stages of the retrieval pipeline, stored in the history document
+    let mut timings = StageTimings::default();
+
     // Generate and insert the initial ID document in DocumentDB
     let id_document =
         generate_id_document(&app_name, reference_id.clone(), initial_task_id.clone()).await;
-    create_document_in_db(
-        &app_state,
-        &id_document,
-        DocType::ID,
-        &app_state.app_settings.mongo_db.mongo_db_id_collection,
-        &app_name,
-        &reference_id,
-        &initial_task_id,
-    )
-    .await?;
+    timings
+        .time(
+            RetrievalStage::IdDocumentWrite,
+            create_document_in_db(
+                &app_state,
+                &id_document,
+                DocType::ID,
+                &app_state.app_settings.mongo_db.mongo_db_id_collection,
+                &app_name,
+                &reference_id,
+                &initial_task_id,
+            ),
+        )
+        .await?;
+    ctx.mark_recorded();
 
     // Extract the API key from the request headers
     let headers = request.headers();
@@ -265,16 +735,22 @@ pub async fn post_retrieval_handler(
         })?;
 
     // Fetch and update the app name corresponding to the API key
-    app_name = fetch_app_name(
-        &app_state,
-        &api_key.to_string(),
-        &initial_task_id,
-        &reference_id,
-    )
-    .await?;
+    app_name = timings
+        .time(
+            RetrievalStage::ApiKeyLookup,
+            fetch_app_name(
+                &app_state,
+                &api_key.to_string(),
+                &initial_task_id,
+                &reference_id,
+            ),
+        )
+        .await?;
+    record_api_key_usage(&app_state, &app_name, "retrieval").await;
 
     // Extract the request body and deserialize it
-    let body_bytes = to_bytes(request.into_body(), usize::MAX)
+    let body_parse_start = Instant::now();
+    let body_bytes = to_bytes(request.into_body(), app_state.max_request_body_bytes())
         .await
         .map_err(|_| {
             TresleFacadeCommonError::failed_to_read_retrieval_request_body(
@@ -292,6 +768,39 @@ pub async fn post_retrieval_handler(
             &ext_message,
         )
     })?;
+    // Read the prompt template referenced by the request, rendered once the templates of the app are fetched
+    let retrieval_prompt: RetrievalPrompt = serde_json::from_slice(&body_bytes).map_err(|e| {
+        TresleFacadeCommonError::failed_to_parse_retrieval_request_body(
+            &reference_id,
+            &initial_task_id,
+            e,
+            &ext_message,
+        )
+    })?;
+    // Read the sub-queries of a fan-out retrieval
+    let retrieval_fan_out: RetrievalFanOut = serde_json::from_slice(&body_bytes).map_err(|e| {
+        TresleFacadeCommonError::failed_to_parse_retrieval_request_body(
+            &reference_id,
+            &initial_task_id,
+            e,
+            &ext_message,
+        )
+    })?;
+    let sub_queries = retrieval_fan_out
+        .sub_queries(&app_state.options::<FanOutOptions>())
+        .map_err(|e| {
+            TresleFacadeCommonError::sub_queries_rejected(&reference_id, &initial_task_id, e)
+        })?;
+    // Read the source labels the search is restricted to, resolved once the labels of the app are fetched
+    let retrieval_scope: RetrievalScope = serde_json::from_slice(&body_bytes).map_err(|e| {
+        TresleFacadeCommonError::failed_to_parse_retrieval_request_body(
+            &reference_id,
+            &initial_task_id,
+            e,
+            &ext_message,
+        )
+    })?;
+    timings.set(RetrievalStage::BodyParse, body_parse_start.elapsed());
     //Verify if both access_details in the request body are empty, if so, return an error
     let access_details = &body.user_details.access_details;
     if access_details.iam_policy_details.is_none() && access_details.db_policy_details.is_none() {
@@ -325,13 +834,318 @@ pub async fn post_retrieval_handler(
         .await;
         return Ok(Json(
             json!({"status": "failed", "message": ext_message, "reference_id": reference_id}),
-        ));
+        )
+        .into_response());
     }
 
+    // Read the settings of the app from its document, fetched once for the whole request
+    let apps = app_state.request_apps();
+
+    // Pseudonymize the user ID stored in the history and token usage documents and sent to the audit logs, when
+    // enabled for the app. The retrieval fails rather than store the user ID in plaintext.
+    let pseudonymize_user_ids = apps.pseudonymize_user_ids(&app_name).await.map_err(|e| {
+        TresleFacadeCommonError::failed_to_pseudonymize_user_id(
+            &reference_id,
+            &initial_task_id,
+            e,
+            &ext_message,
+        )
+    })?;
+    let stored_user_id = if pseudonymize_user_ids {
+        pseudonymize_user_id(&app_state, &app_name, &body.user_details.user_id)
+            .await
+            .map_err(|e| {
+                TresleFacadeCommonError::failed_to_pseudonymize_user_id(
+                    &reference_id,
+                    &initial_task_id,
+                    e,
+                    &ext_message,
+                )
+            })?
+    } else {
+        body.user_details.user_id.clone()
+    };
+
+    // Enforce the allowlist and denylist of the app before any engine call
+    let user_access_list = apps.user_access_list(&app_name).await.map_err(|e| {
+        TresleFacadeCommonError::failed_to_fetch_user_access_list(
+            &reference_id,
+            &initial_task_id,
+            e,
+            &ext_message,
+        )
+    })?;
+    if let Some(Err(e)) = user_access_list
+        .as_ref()
+        .map(|user_access_list| user_access_list.check(&body.user_details.user_id))
+    {
+        let details = e.to_string();
+        info!(
+            service = "audit_microservice",
+            task_id = &initial_task_id,
+            app_name = &app_name,
+            user_id = &stored_user_id,
+            action = "Retrieval rejected",
+            details = details,
+            message = details
+        );
+        return Err(FacadeApiError {
+            inner: TresleFacadeCommonError::user_access_rejected(
+                &reference_id,
+                &initial_task_id,
+                e,
+            ),
+        });
+    }
+
+    // Enforce the entitlements pushed by the IdP for the apps it manages. The retrieval fails closed if they can't be
+    // read.
+    let entitlement = check_entitlement(&app_state, &app_name, &body.user_details.user_id)
+        .await
+        .map_err(|e| {
+            TresleFacadeCommonError::failed_to_fetch_entitlements(
+                &reference_id,
+                &initial_task_id,
+                e,
+                &ext_message,
+            )
+        })?;
+    if entitlement == EntitlementDecision::NotEntitled {
+        let e = ScimError::NotEntitled(app_name.clone());
+        let details = e.to_string();
+        info!(
+            service = "audit_microservice",
+            task_id = &initial_task_id,
+            app_name = &app_name,
+            user_id = &stored_user_id,
+            action = "Retrieval rejected",
+            details = details,
+            message = details
+        );
+        return Err(FacadeApiError {
+            inner: TresleFacadeCommonError::user_access_rejected(
+                &reference_id,
+                &initial_task_id,
+                e,
+            ),
+        });
+    }
+
+    // Short-circuit the runaway loops of identical retrievals to the last executed one. The retrieval is let through
+    // if the window can't be read.
+    match check_query_loop(&app_state, &app_name, &stored_user_id, &body, &reference_id).await {
+        Ok(QueryLoopDecision::ShortCircuited {
+            reference_id: last_reference_id,
+            repeats,
+            retry_after_secs,
+        }) => {
+            let ext_message = format!(
+                "Identical query repeated {} times, use the result of reference ID {} or retry after {} seconds.",
+                repeats, last_reference_id, retry_after_secs
+            );
+            let msg = format!(
+                "Query loop of user '{}' detected, retrieval short-circuited to '{}' after {} identical queries.",
+                stored_user_id, last_reference_id, repeats
+            );
+            // An expected client behaviour, not a failure of the service
+            warn!(
+                app_name = &app_name,
+                task_id = &initial_task_id,
+                ext_message = ext_message,
+                message = msg
+            );
+            app_state
+                .record_metric(
+                    MetricRecord::counter("Query Loop Counter")
+                        .dimension(APP_NAME_DIMENSION, &app_name),
+                )
+                .await;
+            let notification = Notification::new(
+                NotificationKind::QuotaWarning,
+                &app_name,
+                format!(
+                    "A client of app '{}' repeats the same query in a loop, its retrievals are short-circuited.",
+                    app_name
+                ),
+            )
+            .once_per(&daily_period(Utc::now()));
+            record_notification(&app_state, notification).await;
+            return Ok((
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, retry_after_secs.to_string())],
+                Json(json!({
+                    "status": "duplicate",
+                    "message": ext_message,
+                    "reference_id": last_reference_id
+                })),
+            )
+                .into_response());
+        }
+        Ok(QueryLoopDecision::Allowed) => {}
+        Err(e) => {
+            let msg = format!("Failed to check the query loop. Error: {}", e);
+            error!(
+                app_name = &app_name,
+                task_id = &initial_task_id,
+                message = msg
+            );
+        }
+    }
+
+    // Enforce the rate limit of the end user. The retrieval is let through if the counters can't be read.
+    match app_state
+        .check_user_rate_limit(&apps, &app_name, &stored_user_id)
+        .await
+    {
+        Ok(RateLimitDecision::Limited { retry_after_secs }) => {
+            let ext_message = format!(
+                "Rate limit exceeded. Retry after {} seconds.",
+                retry_after_secs
+            );
+            let msg = format!(
+                "Rate limit of user '{}' exceeded. Retry after {} seconds.",
+                stored_user_id, retry_after_secs
+            );
+            error!(
+                app_name = &app_name,
+                task_id = &initial_task_id,
+                ext_message = ext_message,
+                message = msg
+            );
+            let notification = Notification::new(
+                NotificationKind::QuotaWarning,
+                &app_name,
+                format!(
+                    "End users of app '{}' are exhausting their rate limit, their retrievals are rejected.",
+                    app_name
+                ),
+            )
+            .once_per(&daily_period(Utc::now()));
+            record_notification(&app_state, notification).await;
+            return Ok((
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, retry_after_secs.to_string())],
+                Json(
+                    json!({"status": "failed", "message": ext_message, "reference_id": reference_id}),
+                ),
+            )
+                .into_response());
+        }
+        Ok(RateLimitDecision::Allowed { .. }) => {}
+        Err(e) => {
+            let msg = format!("Failed to check the user rate limit. Error: {}", e);
+            error!(
+                app_name = &app_name,
+                task_id = &initial_task_id,
+                message = msg
+            );
+        }
+    }
+
+    // Gate the retrievals of the apps whose sources are not indexed yet, when enabled. The retrieval is let through if
+    // the readiness can't be read.
+    let readiness_mode = app_state.options::<ReadinessOptions>().mode;
+    let mut readiness_warning = None;
+    if readiness_mode != ReadinessMode::Off {
+        match app_readiness(&app_state, &apps, &app_name).await {
+            Ok(readiness) if !readiness.ready => {
+                let ext_message = readiness.message(&app_name);
+                if readiness_mode == ReadinessMode::Reject {
+                    error!(
+                        app_name = &app_name,
+                        task_id = &initial_task_id,
+                        ext_message = ext_message,
+                        message = ext_message
+                    );
+                    return Ok((
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        Json(json!({
+                            "status": "failed",
+                            "message": ext_message,
+                            "reference_id": reference_id,
+                            "readiness": readiness
+                        })),
+                    )
+                        .into_response());
+                }
+                readiness_warning = Some(ext_message);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!(
+                    app_name = &app_name,
+                    task_id = &initial_task_id,
+                    message = e.to_string()
+                );
+            }
+        }
+    }
+
+    // Fetch the row filters of the app, the retrieval must not run unscoped if they can't be read
+    let row_filters = apps.row_filters(&app_name).await.map_err(|e| {
+        TresleFacadeCommonError::failed_to_fetch_row_filters(
+            &reference_id,
+            &initial_task_id,
+            e,
+            &ext_message,
+        )
+    })?;
+
+    // Resolve the search scope of the request against the labeled sources of the app. Unknown labels are rejected,
+    // the retrieval must not silently search all the sources.
+    let search_scope = match retrieval_scope.search_scope {
+        Some(_) => {
+            let source_labels = apps.source_labels(&app_name).await.map_err(|e| {
+                TresleFacadeCommonError::failed_to_fetch_source_labels(
+                    &reference_id,
+                    &initial_task_id,
+                    e,
+                    &ext_message,
+                )
+            })?;
+            retrieval_scope.resolve(&source_labels).map_err(|e| {
+                TresleFacadeCommonError::search_scope_rejected(&reference_id, &initial_task_id, e)
+            })?
+        }
+        None => None,
+    };
+
+    // Render the prompt template of the request into its additional prompt. Apps with templates reject free-form
+    // prompts.
+    let prompt_templates = apps.prompt_templates(&app_name).await.map_err(|e| {
+        TresleFacadeCommonError::failed_to_fetch_prompt_templates(
+            &reference_id,
+            &initial_task_id,
+            e,
+            &ext_message,
+        )
+    })?;
+    let body = match resolve_additional_prompt(&prompt_templates, &retrieval_prompt) {
+        Ok(Some(additional_prompt)) => {
+            with_additional_prompt(body, additional_prompt).map_err(|e| {
+                TresleFacadeCommonError::failed_to_parse_retrieval_request_body(
+                    &reference_id,
+                    &initial_task_id,
+                    e,
+                    &ext_message,
+                )
+            })?
+        }
+        Ok(None) => body,
+        Err(e) => {
+            return Err(FacadeApiError {
+                inner: TresleFacadeCommonError::prompt_template_rejected(
+                    &reference_id,
+                    &initial_task_id,
+                    e,
+                ),
+            });
+        }
+    };
+
     // Call to 'Retrieval' - generate the UI summary document and insert it in DocumentDB
     let ui_summary_document =
-        generate_ui_summary_document(&app_name, "Retrieval", 1, request_timestamp.to_string())
-            .await;
+        generate_ui_summary_document(&app_name, "Retrieval", 1, request_timestamp).await;
     create_document_in_db(
         &app_state,
         &ui_summary_document,
@@ -349,8 +1163,32 @@ pub async fn post_retrieval_handler(
     let user_id = &body.user_details.user_id;
     let _iam_policy_details = &body.user_details.access_details.iam_policy_details;
 
+    // Assign the variants of the experiments of the app, sticky by user. The retrieval runs without experiments if
+    // they can't be read.
+    let experiments = match apps.experiments(&app_name).await {
+        Ok(experiments) => assign_variants(&experiments, user_id),
+        Err(e) => {
+            error!(
+                app_name = &app_name,
+                task_id = &initial_task_id,
+                message = format!("Failed to fetch experiments. Error: {}", e)
+            );
+            Vec::new()
+        }
+    };
+    for assignment in &experiments {
+        app_state
+            .record_metric(
+                MetricRecord::counter("Experiment Assignment Counter")
+                    .dimension(APP_NAME_DIMENSION, &app_name)
+                    .dimension(EXPERIMENT_DIMENSION, &assignment.experiment)
+                    .dimension(VARIANT_DIMENSION, &assignment.variant),
+            )
+            .await;
+    }
+
     // Generate task ID
-    let updated_task_id = create_task_id(&app_name, "Retrieval".to_string());
+    let updated_task_id = app_state.id_generator.task_id(&app_name, "Retrieval");
 
     // Now that we have the app_name, update id_document with new task_id and app_name
     update_task_id(
@@ -363,28 +1201,42 @@ pub async fn post_retrieval_handler(
     .await?;
 
     // Instrument function call counter
-    info!(
-        service = "metric",
-        app_name = app_name,
-        task_id = updated_task_id,
-        metrics_name = "Data Retrieval Counter",
-        metrics_value = "1"
-    );
+    app_state
+        .record_metric(
+            MetricRecord::counter("Data Retrieval Counter")
+                .dimension(APP_NAME_DIMENSION, &app_name)
+                .dimension(TASK_ID_DIMENSION, &updated_task_id),
+        )
+        .await;
 
     // Spawn a background async task to perform operations with knowledge engine/core microservice and DocumentDB
-    tokio::spawn(background_tasks(
-        Arc::clone(&app_state),
-        app_name,
-        user_id.clone(),
-        body,
-        reference_id.clone(),
-        updated_task_id,
-        request_timestamp,
-    ));
+    // Store the background operations as a job and run them, so a crash of the replica doesn't orphan the retrieval
+    start_retrieval_job(
+        &app_state,
+        RetrievalJob {
+            app_name,
+            user_id: stored_user_id,
+            body,
+            sub_queries,
+            experiments,
+            row_filters,
+            search_scope,
+            reference_id: reference_id.clone(),
+            task_id: updated_task_id,
+            request_timestamp,
+            replay_of: None,
+            sandbox: false,
+            deadline: ctx.deadline,
+            timings,
+        },
+    )
+    .await;
 
-    Ok(Json(
-        json!({"status": "success", "message": "Retrieval in progress.","reference_id": reference_id}),
-    ))
+    let mut response = json!({"status": "success", "message": "Retrieval in progress.","reference_id": reference_id});
+    if let Some(warning) = readiness_warning {
+        response["warning"] = json!(warning);
+    }
+    Ok(Json(response).into_response())
 }
 
 #[cfg(test)]
@@ -433,7 +1285,12 @@ pub mod tests {
             );
 
             // Call the function
-            let result = post_retrieval_handler(State(app_state), request).await;
+            let result = post_retrieval_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                State(app_state),
+                request,
+            )
+            .await;
 
             // Check that the result is as expected
             println!("{:?}", result.err());
@@ -460,18 +1317,60 @@ pub mod tests {
             // Call the function
             background_tasks(
                 Arc::clone(&app_state),
-                "test".to_string(),
-                "test".to_string(),
-                app_config,
-                "test".to_string(),
-                "test".to_string(),
-                Utc::now(),
+                RetrievalJob {
+                    app_name: "test".to_string(),
+                    user_id: "test".to_string(),
+                    body: app_config,
+                    sub_queries: None,
+                    experiments: vec![],
+                    row_filters: vec![],
+                    search_scope: None,
+                    reference_id: "test".to_string(),
+                    task_id: "test".to_string(),
+                    request_timestamp: Utc::now(),
+                    replay_of: None,
+                    sandbox: false,
+                    deadline: None,
+                    timings: StageTimings::default(),
+                },
             )
             .await;
             std::thread::sleep(std::time::Duration::from_secs(2));
         });
     }
 
+    #[test]
+    fn test_success_retrieval_job_queued() {
+        let mut file = File::open("src/test/retrieval_request.json").unwrap();
+        let mut buff = String::new();
+        file.read_to_string(&mut buff).unwrap();
+        let body: RetrievalRequest = serde_json::from_str(&buff).unwrap();
+
+        let job = RetrievalJob {
+            app_name: "test".to_string(),
+            user_id: "psn_123".to_string(),
+            body,
+            sub_queries: None,
+            experiments: vec![],
+            row_filters: vec![],
+            search_scope: None,
+            reference_id: "test".to_string(),
+            task_id: "test".to_string(),
+            request_timestamp: Utc::now(),
+            replay_of: None,
+            sandbox: false,
+            deadline: None,
+            timings: StageTimings::default(),
+        };
+        // The stored job never carries the user ID behind the pseudonym
+        let queued = job.queued();
+        assert_eq!(queued.body.user_details.user_id, "psn_123");
+        assert_eq!(queued.body.query, job.body.query);
+        assert!(!serde_json::to_string(&queued)
+            .unwrap()
+            .contains(&format!("\"{}\"", job.body.user_details.user_id)));
+    }
+
     #[test]
     fn test_failed_post_retrieval_handler_missing_api_key() {
         let rt = Runtime::new().unwrap();
@@ -495,7 +1394,12 @@ pub mod tests {
             let request = Request::post("/").body(body).unwrap();
 
             // Call the function
-            let result = post_retrieval_handler(State(app_state), request).await;
+            let result = post_retrieval_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                State(app_state),
+                request,
+            )
+            .await;
 
             // Check that the result is as expected
             assert!(result.is_err());
@@ -532,7 +1436,12 @@ pub mod tests {
                 .insert("x-api-key", "🚀  bad key".parse().unwrap());
 
             // Call the function
-            let result = post_retrieval_handler(State(app_state), request).await;
+            let result = post_retrieval_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                State(app_state),
+                request,
+            )
+            .await;
 
             // Check that the result is as expected
             assert!(result.is_err());
@@ -559,7 +1468,12 @@ pub mod tests {
             );
 
             // Call the function
-            let result = post_retrieval_handler(State(app_state), request).await;
+            let result = post_retrieval_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                State(app_state),
+                request,
+            )
+            .await;
 
             // Check that the result is as expected
             assert!(result.is_err());
@@ -8,19 +8,26 @@
 
 use crate::admin_ui_api::schema::QueryParams;
 use crate::retrieval::fetch_app_name::fetch_app_name;
-use crate::service::error::TresleFacadeCommonError;
+use crate::retrieval::replay::REPLAY_OF_FIELD;
+use crate::retrieval::sandbox::SANDBOX_FIELD;
+use crate::retrieval::schema::history_document::HistoryDocument;
+use crate::service::answer_offload::read_full_content;
+use crate::service::api_key::record_api_key_usage;
+use crate::service::ctx::Ctx;
+use crate::service::error::{FacadeApiError, TresleFacadeCommonError};
+use crate::service::etag::ETag;
 use crate::service::generate_and_insert_document::*;
+use crate::service::history_polling::{insert_last_modified, responded_at, HistoryPollingOptions};
 use crate::service::state::AppState;
+use crate::service::timestamp::serve_timestamps;
 use axum::body::Body;
 use axum::extract::Query;
-use axum::http::Request;
+use axum::http::{header, HeaderMap, Request};
 use axum::{extract::State, response::IntoResponse, Json};
-use error_utils::AxumApiError;
 use mongodb::bson::doc;
 use serde_json::json;
 use std::sync::Arc;
-use tracing::{info, instrument};
-use uuid::Uuid;
+use tracing::{debug, info, instrument};
 
 const HISTORY_COLLECTION_SUFFIX: &str = "-history";
 
@@ -32,13 +39,16 @@ const HISTORY_COLLECTION_SUFFIX: &str = "-history";
             "reference_id" = inline(String), 
             Query,
             description = "Reference id.",
-        )
+        ),
+        ("full_content" = inline(Option<bool>), Query, description = "Serve the full response of a truncated history document if true.")
     ),
     responses(
         (status = 200, description = "History document retrieved successfully."),
         (status = StatusCode::BAD_REQUEST, description = "Internal Error. Please contact tresleai support team. Use reference ID: "),
         (status = StatusCode::NOT_FOUND, description = "Internal Error. Please contact tresleai support team. Use reference ID: "),
+        (status = StatusCode::NOT_MODIFIED, description = "History document not modified since the If-None-Match ETag."),
         (status = StatusCode::ACCEPTED, description = "Internal Error. Please contact tresleai support team. Use reference ID: "),
+        (status = StatusCode::UNAUTHORIZED, description = "The API key expired on {}. Use an active API key of the app. Use reference ID: "),
         (status = StatusCode::INTERNAL_SERVER_ERROR, description = "Internal Error. Please contact tresleai support team. Use reference ID: ")
     )
 )]
@@ -72,18 +82,37 @@ const HISTORY_COLLECTION_SUFFIX: &str = "-history";
 ///    "message": "History document with reference ID: "14b1456d-2708-45bc-8989-eac2d2eba4db" retrieved successfully.",
 ///    "app_name": "test_app"
 ///    "data": {
-///        "_id": {
-///           "$oid": "<id of the document in DocumentDB>"
-///        },
+///        "schema_version": 2,
 ///        "reference_id": "14b1456d-2708-45bc-8989-eac2d2eba4db",
 ///        "task_id": "<task_id>",
 ///        "query": "<query>",
-///        "response": "<response>",
-///        "timestamp": "<timestamp>"
+///        "response": "<raw response of the knowledge engine>",
+///        "answer": "<answer>",
+///        "citations": [
+///            {
+///                "source": "<source>",
+///                "title": "<title>",
+///                "snippet": "<cited text>",
+///                "score": 0.87
+///            }
+///        ],
+///        "confidence": 0.92,
+///        "model_used": "<model>",
+///        "token_usage": {
+///            "prompt_tokens": 1024,
+///            "completion_tokens": 256,
+///            "total_tokens": 1280
+///        },
+///        "timestamp": "<timestamp>",
+///        "disclaimer_text": "<disclaimer>"
 ///    }
 /// }
 /// ```
 ///
+/// The typed fields are parsed from the response of the knowledge engine. Documents stored before the typed fields
+/// are served with `schema_version` 1, with their typed fields parsed from the raw `response`.
+/// `timestamp` is served in RFC 3339, or "Retrieval failed." for a failed retrieval.
+///
 /// Please note that the document is created in the database only upon successful generation of the response.
 /// Until this point, the document is in the 'processing' state, indicated by a 202 (ACCEPTED) status code,
 /// as demonstrated in the following example response (including a sample reference ID):
@@ -95,17 +124,28 @@ const HISTORY_COLLECTION_SUFFIX: &str = "-history";
 ///     "error_code": 202
 /// }
 /// ```
+///
+/// The 202 response carries a `Retry-After` header, in seconds, to pace the polling. The history document is served
+/// with an `ETag`, and a `Last-Modified` unless the retrieval failed; polling with `If-None-Match` returns a 304
+/// while the document is unchanged.
+///
+/// With the dead retrieval sweeper enabled, a retrieval left without response past its deadline gets a failed
+/// history document, whose `response` explains the retrieval timed out.
+///
+/// The texts of an oversized answer are truncated, with `"truncated": true` and a `full_content` pointer to the full
+/// response. With `full_content=true`, the handler serves the full response itself, with its content type.
 
 #[instrument(skip_all)]
 pub async fn get_history_handler(
+    ctx: Ctx,
     State(app_state): State<Arc<AppState>>,
     Query(params): Query<QueryParams>,
     request: Request<Body>,
-) -> Result<impl IntoResponse, AxumApiError<TresleFacadeCommonError>> {
-    // Generate reference ID and task ID and initialize the app_name (generic app_name = "tresleai-system")
-    let reference_id = Uuid::new_v4().to_string();
-    let task_id = Uuid::new_v4().to_string();
+) -> Result<impl IntoResponse, FacadeApiError> {
+    // Take the reference ID and task ID of the request and initialize the app_name (generic app_name = "tresleai-system")
     let app_name = app_state.app_settings.tracing_layer_system_app_name.clone();
+    let reference_id = ctx.reference_id.clone();
+    let task_id = ctx.task_id.clone();
 
     // Fetch general message to be returned to client, in case of an error
     let ext_message = app_state.app_settings.general_message.clone();
@@ -123,6 +163,7 @@ pub async fn get_history_handler(
         &task_id,
     )
     .await?;
+    ctx.mark_recorded();
 
     // Extract the API key from the request headers
     let headers = request.headers();
@@ -139,12 +180,13 @@ pub async fn get_history_handler(
     // Fetch the app name corresponding to the API key
     let app_name =
         fetch_app_name(&app_state, &api_key.to_string(), &task_id, &reference_id).await?;
+    record_api_key_usage(&app_state, &app_name, "history").await;
 
     // Extract the reference_id from the query params
     let reference_id_query_param = match params.reference_id {
         Some(reference_id_query_param) => reference_id_query_param,
         None => {
-            return Err(error_utils::AxumApiError {
+            return Err(FacadeApiError {
                 inner: TresleFacadeCommonError::missing_reference_id_in_history_retrieval_request(
                     &reference_id,
                     &task_id,
@@ -153,11 +195,25 @@ pub async fn get_history_handler(
             })
         }
     };
-    let filter = doc! {"reference_id": &reference_id_query_param};
+    // The history documents of the replays and of the sandbox retrievals are never delivered to the end user
+    let filter = doc! {
+        "reference_id": &reference_id_query_param,
+        REPLAY_OF_FIELD: {"$exists": false},
+        SANDBOX_FIELD: {"$ne": true},
+    };
     let history_collection_name = format!("{}{}", &app_name, HISTORY_COLLECTION_SUFFIX);
+    let app_db = app_state.app_db(&app_name).await.map_err(|e| {
+        TresleFacadeCommonError::failed_to_retrieve_history_document(
+            &app_name,
+            &reference_id_query_param,
+            &reference_id,
+            &task_id,
+            e,
+            &ext_message,
+        )
+    })?;
 
-    match app_state
-        .db
+    match app_db
         .get_document(&history_collection_name, filter)
         .await
         .map_err(|e| {
@@ -170,27 +226,109 @@ pub async fn get_history_handler(
                 &ext_message,
             )
         }) {
-        Ok(Some(history_document)) => {
+        Ok(Some(mut history_document)) => {
+            // Answer with a 304 if the stored history document did not change since the last poll
+            let etag = ETag::for_document(request.uri(), &history_document);
+            if etag.matches(request.headers()) {
+                debug!(
+                    app_name = app_name,
+                    message = format!(
+                        "History document with reference ID: '{}' not modified.",
+                        reference_id_query_param
+                    )
+                );
+                return Ok(etag.not_modified());
+            }
+            let mut response_headers = HeaderMap::new();
+            etag.insert_into(&mut response_headers);
+            insert_last_modified(&mut response_headers, responded_at(&history_document));
+
+            // Decrypt the query and the response with the data key of the app
+            app_state
+                .decrypt_fields(&app_name, &mut history_document)
+                .await
+                .map_err(|e| {
+                    TresleFacadeCommonError::failed_to_retrieve_history_document(
+                        &app_name,
+                        &reference_id_query_param,
+                        &reference_id,
+                        &task_id,
+                        e,
+                        &ext_message,
+                    )
+                })?;
+            // Serve the typed shape, parsed from the raw response for the documents stored before it
+            let mut history_document =
+                HistoryDocument::from_stored(history_document).map_err(|e| {
+                    TresleFacadeCommonError::failed_to_retrieve_history_document(
+                        &app_name,
+                        &reference_id_query_param,
+                        &reference_id,
+                        &task_id,
+                        e,
+                        &ext_message,
+                    )
+                })?;
             let success_message = format!(
                 "History document with reference ID: '{}' retrieved successfully.",
                 reference_id_query_param
             );
             info!(app_name = app_name, message = success_message);
-            Ok(Json(
-                json!({"status": "success", "message": success_message, "app_name": app_name, "data": history_document}),
-            ))
+            history_document.request = None;
+
+            // Serve the full response on demand, read from S3 for a truncated history document
+            if params.full_content.unwrap_or_default() {
+                let (content, content_type) = match &history_document.full_content {
+                    Some(full_content) => (
+                        read_full_content(&app_state, &app_name, full_content)
+                            .await
+                            .map_err(|e| {
+                                TresleFacadeCommonError::failed_to_retrieve_history_document(
+                                    &app_name,
+                                    &reference_id_query_param,
+                                    &reference_id,
+                                    &task_id,
+                                    e,
+                                    &ext_message,
+                                )
+                            })?,
+                        full_content.content_type.clone(),
+                    ),
+                    None => (
+                        history_document.response,
+                        "text/plain; charset=utf-8".to_string(),
+                    ),
+                };
+                return Ok((
+                    response_headers,
+                    [(header::CONTENT_TYPE, content_type)],
+                    content,
+                )
+                    .into_response());
+            }
+            let mut body = json!({"status": "success", "message": success_message, "app_name": app_name, "data": history_document});
+            serve_timestamps(&mut body);
+            Ok((response_headers, Json(body)).into_response())
+        }
+        Ok(None) => {
+            // Tell the polling clients when to come back
+            let mut response = FacadeApiError {
+                inner: TresleFacadeCommonError::no_history_document_found_but_request_accepted(
+                    &app_name,
+                    &reference_id_query_param,
+                    &reference_id,
+                    &task_id,
+                    &ext_msg_inprogress,
+                ),
+            }
+            .into_response();
+            app_state
+                .options::<HistoryPollingOptions>()
+                .insert_retry_after(&mut response);
+            Ok(response)
         }
-        Ok(None) => Err(error_utils::AxumApiError {
-            inner: TresleFacadeCommonError::no_history_document_found_but_request_accepted(
-                &app_name,
-                &reference_id_query_param,
-                &reference_id,
-                &task_id,
-                &ext_msg_inprogress,
-            ),
-        }),
         Err(e) => {
-            return Err(error_utils::AxumApiError {
+            return Err(FacadeApiError {
                 inner: TresleFacadeCommonError::failed_to_retrieve_history_document(
                     &app_name,
                     &reference_id_query_param,
@@ -253,7 +391,13 @@ pub mod tests {
             query_params.reference_id = Some("reference_id".to_string());
 
             // Call the function
-            let result = get_history_handler(State(app_state), Query(query_params), request).await;
+            let result = get_history_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                State(app_state),
+                Query(query_params),
+                request,
+            )
+            .await;
 
             // Check that the result is as expected
             println!("{:?}", result.err());
@@ -292,7 +436,13 @@ pub mod tests {
             query_params.reference_id = Some("reference_id".to_string());
 
             // Call the function
-            let result = get_history_handler(State(app_state), Query(query_params), request).await;
+            let result = get_history_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                State(app_state),
+                Query(query_params),
+                request,
+            )
+            .await;
 
             // Check that the result is as expected
             assert!(result.is_err());
@@ -330,7 +480,13 @@ pub mod tests {
             query_params.reference_id = Some("reference_id".to_string());
 
             // Call the function
-            let result = get_history_handler(State(app_state), Query(query_params), request).await;
+            let result = get_history_handler(
+                Ctx::new(&app_state, "test_app", "Test"),
+                State(app_state),
+                Query(query_params),
+                request,
+            )
+            .await;
 
             // Check that the result is as expected
             assert!(result.is_err());
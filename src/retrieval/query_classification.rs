@@ -0,0 +1,246 @@
+/*
+ * Created Date:  Jul 24, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the pre-classification of the retrieval queries, tagging a query as sql-oriented,
+//! document-oriented or multimodal before it is sent to the knowledge engine.
+//! The category is passed to the knowledge engine as a routing hint, stored in the history document and counted in
+//! the metrics. The classification runs only when `query_classification` is configured.
+//! In the `rules` mode the query is matched against keyword rules, the built-in ones unless `rules` are
+//! configured: the category with the most matched keywords wins, the first listed on a tie, and a query matching no
+//! keyword is document-oriented. In the `model` mode the query is posted to `model_url`, which answers with
+//! `{"category": "sql" | "document" | "multimodal"}`; the rules are used if the model call fails.
+//!
+
+use crate::configuration::settings::QueryClassificationSettings;
+use crate::service::state::AppState;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+use tracing::{debug, error, instrument};
+use utoipa::ToSchema;
+
+/// Default timeout of the classification model call.
+const DEFAULT_MODEL_TIMEOUT_MS: u64 = 500;
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueryClassificationError {
+    #[error("Query classification model call failed: {0}")]
+    Model(#[from] reqwest::Error),
+}
+
+/// Category of a retrieval query.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryCategory {
+    /// Answered from the tables of the datastores.
+    Sql,
+    /// Answered from the text of the documents of the filestores.
+    Document,
+    /// Answered from the images of the documents.
+    Multimodal,
+}
+
+impl QueryCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueryCategory::Sql => "sql",
+            QueryCategory::Document => "document",
+            QueryCategory::Multimodal => "multimodal",
+        }
+    }
+}
+
+/// Classifier of the retrieval queries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClassifierMode {
+    /// Keyword rules, evaluated in the process.
+    #[default]
+    Rules,
+    /// Classification model behind `model_url`, falling back to the rules.
+    Model,
+}
+
+/// Keywords tagging a query with a category. A keyword is matched case-insensitively on word boundaries.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClassificationRule {
+    pub category: QueryCategory,
+    pub keywords: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ModelClassification {
+    category: QueryCategory,
+}
+
+/// Returns the built-in keyword rules.
+pub fn default_rules() -> Vec<ClassificationRule> {
+    let rule = |category, keywords: &[&str]| ClassificationRule {
+        category,
+        keywords: keywords.iter().map(|keyword| keyword.to_string()).collect(),
+    };
+    vec![
+        rule(
+            QueryCategory::Sql,
+            &[
+                "how many", "count", "total", "sum", "average", "avg", "maximum", "minimum", "top",
+                "per", "group by", "rows", "table", "column", "between",
+            ],
+        ),
+        rule(
+            QueryCategory::Multimodal,
+            &[
+                "image",
+                "images",
+                "picture",
+                "photo",
+                "diagram",
+                "chart",
+                "figure",
+                "screenshot",
+                "logo",
+                "drawing",
+            ],
+        ),
+    ]
+}
+
+/// Lowercases a text and replaces its non alphanumeric characters with spaces, padded with a space on both ends so
+/// the keywords are matched on word boundaries.
+fn normalize(text: &str) -> String {
+    let words = text
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(" {} ", words)
+}
+
+/// Classifies a query with keyword rules.
+pub fn classify_with_rules(query: &str, rules: &[ClassificationRule]) -> QueryCategory {
+    let query = normalize(query);
+    let mut best = (0, QueryCategory::Document);
+    for rule in rules {
+        let matches = rule
+            .keywords
+            .iter()
+            .filter(|keyword| query.contains(&normalize(keyword)))
+            .count();
+        if matches > best.0 {
+            best = (matches, rule.category);
+        }
+    }
+    best.1
+}
+
+/// Classifies a query with the classification model.
+async fn classify_with_model(
+    app_state: &AppState,
+    settings: &QueryClassificationSettings,
+    model_url: &str,
+    app_name: &str,
+    query: &str,
+) -> Result<QueryCategory, QueryClassificationError> {
+    let timeout = Duration::from_millis(
+        settings
+            .model_timeout_ms
+            .unwrap_or(DEFAULT_MODEL_TIMEOUT_MS),
+    );
+    let classification = app_state
+        .http_clients
+        .for_url(model_url)
+        .post(model_url)
+        .timeout(timeout)
+        .json(&json!({"app_name": app_name, "query": query}))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<ModelClassification>()
+        .await?;
+    Ok(classification.category)
+}
+
+/// Classifies a retrieval query. Returns `None` if the classification is not configured.
+#[instrument(skip_all)]
+pub async fn classify_query(
+    app_state: &AppState,
+    app_name: &str,
+    query: &str,
+) -> Option<QueryCategory> {
+    let settings = app_state.app_settings.query_classification.as_ref()?;
+    if let (ClassifierMode::Model, Some(model_url)) = (settings.mode, &settings.model_url) {
+        match classify_with_model(app_state, settings, model_url, app_name, query).await {
+            Ok(category) => {
+                debug!(
+                    app_name = app_name,
+                    message = "Query classified by the model."
+                );
+                return Some(category);
+            }
+            Err(e) => {
+                error!(
+                    app_name = app_name,
+                    message = format!("{} Falling back to the classification rules.", e)
+                );
+            }
+        }
+    }
+    let category = match &settings.rules {
+        Some(rules) => classify_with_rules(query, rules),
+        None => classify_with_rules(query, &default_rules()),
+    };
+    Some(category)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_classify_with_default_rules() {
+        let rules = default_rules();
+        assert_eq!(
+            classify_with_rules("How many orders per region in 2023?", &rules),
+            QueryCategory::Sql
+        );
+        assert_eq!(
+            classify_with_rules("Show the architecture diagram of the gateway", &rules),
+            QueryCategory::Multimodal
+        );
+        assert_eq!(
+            classify_with_rules("What is the parental leave policy?", &rules),
+            QueryCategory::Document
+        );
+        // Keywords are matched on word boundaries
+        assert_eq!(
+            classify_with_rules("Summarize the topology document", &rules),
+            QueryCategory::Document
+        );
+    }
+
+    #[test]
+    fn test_success_classify_with_configured_rules() {
+        let rules = vec![
+            ClassificationRule {
+                category: QueryCategory::Multimodal,
+                keywords: vec!["floor plan".to_string()],
+            },
+            ClassificationRule {
+                category: QueryCategory::Sql,
+                keywords: vec!["Floor".to_string()],
+            },
+        ];
+        // Ties go to the first listed rule
+        assert_eq!(
+            classify_with_rules("Where is the floor plan of building 2?", &rules),
+            QueryCategory::Multimodal
+        );
+        assert_eq!(
+            serde_json::to_value(QueryCategory::Sql).unwrap(),
+            json!("sql")
+        );
+    }
+}
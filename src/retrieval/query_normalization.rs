@@ -0,0 +1,142 @@
+/*
+ * Created Date:  Jul 24, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the normalization of the retrieval queries, enabled per app with `query_normalization` at
+//! onboarding. The query is NFKC normalized, its control characters replaced and its whitespace collapsed and
+//! trimmed, then spell-corrected by the external service of `query_normalization.spell_check_url`, when configured.
+//! The normalized query is sent to the knowledge engine; the history document keeps both the original and the
+//! normalized query. A failed spell-check is logged and the query is sent without correction.
+//!
+
+use crate::configuration::settings::QueryNormalizationSettings;
+use crate::service::state::AppState;
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
+use tracing::{error, instrument};
+use unicode_normalization::UnicodeNormalization;
+
+/// Default timeout of the spell-check call.
+const DEFAULT_SPELL_CHECK_TIMEOUT_MS: u64 = 500;
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueryNormalizationError {
+    #[error("Spell-check call failed: {0}")]
+    SpellCheck(#[from] reqwest::Error),
+}
+
+#[derive(Deserialize)]
+struct SpellCheckResponse {
+    corrected_query: String,
+}
+
+/// Normalizes a query: NFKC normalization, control characters replaced with spaces, whitespace collapsed and
+/// trimmed.
+pub fn normalize_query(query: &str) -> String {
+    query
+        .nfkc()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Spell-corrects a query with the spell-check service.
+async fn spell_check(
+    app_state: &AppState,
+    settings: &QueryNormalizationSettings,
+    spell_check_url: &str,
+    app_name: &str,
+    query: &str,
+) -> Result<String, QueryNormalizationError> {
+    let timeout = Duration::from_millis(
+        settings
+            .spell_check_timeout_ms
+            .unwrap_or(DEFAULT_SPELL_CHECK_TIMEOUT_MS),
+    );
+    let response = app_state
+        .http_clients
+        .for_url(spell_check_url)
+        .post(spell_check_url)
+        .timeout(timeout)
+        .json(&json!({"app_name": app_name, "query": query}))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<SpellCheckResponse>()
+        .await?;
+    Ok(response.corrected_query)
+}
+
+/// Normalizes a retrieval query of an app. Returns `None` if the normalization is not enabled for the app.
+#[instrument(skip_all)]
+pub async fn normalize_retrieval_query(
+    app_state: &AppState,
+    app_name: &str,
+    query: &str,
+) -> Option<String> {
+    match app_state.apps().query_normalization(app_name).await {
+        Ok(true) => {}
+        Ok(false) => return None,
+        Err(e) => {
+            // The query is sent as-is
+            error!(
+                app_name = app_name,
+                message = format!(
+                    "Failed to read the query normalization of the app. Error: {}",
+                    e
+                )
+            );
+            return None;
+        }
+    }
+    let normalized_query = normalize_query(query);
+    let Some((settings, spell_check_url)) = app_state
+        .app_settings
+        .query_normalization
+        .as_ref()
+        .and_then(|settings| Some((settings, settings.spell_check_url.as_deref()?)))
+    else {
+        return Some(normalized_query);
+    };
+    match spell_check(
+        app_state,
+        settings,
+        spell_check_url,
+        app_name,
+        &normalized_query,
+    )
+    .await
+    {
+        Ok(corrected_query) => Some(normalize_query(&corrected_query)),
+        Err(e) => {
+            error!(
+                app_name = app_name,
+                message = format!("{} The query is sent without correction.", e)
+            );
+            Some(normalized_query)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_normalize_query() {
+        assert_eq!(
+            normalize_query("  What is the\tleave\n\npolicy?  "),
+            "What is the leave policy?"
+        );
+        // Compatibility characters are folded: full-width letters, ligatures and non-breaking spaces
+        assert_eq!(
+            normalize_query("Ｑ３ revenue of the ﬁnance\u{a0}team"),
+            "Q3 revenue of the finance team"
+        );
+        assert_eq!(normalize_query("\u{7}\u{0}"), "");
+    }
+}
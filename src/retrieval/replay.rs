@@ -0,0 +1,200 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the replay of the retrievals, to reproduce a bad answer of the knowledge engine.
+//! The history document of a retrieval keeps its request in `request`, encrypted like the other fields and never
+//! served by the history endpoint: the `RetrievalRequest` as sent to the engine, before the query normalization, the
+//! sub-queries of a fan-out retrieval and the labels of its search scope. For the apps pseudonymizing the user IDs,
+//! the request keeps the pseudonym, so a replay binds the row filters of the user to it.
+//! A replay re-executes the stored request against the knowledge engine under a new reference ID, with the variants of
+//! the experiments assigned to the original retrieval and the current row filters and source labels of the app. Its
+//! history document is stored in the history collection of the app with `replay_of` set to the original reference
+//! ID; the history endpoint, the experiment results and the error counts ignore it, so it is never delivered to the
+//! end user, and its tokens are not accounted.
+//!
+
+use crate::service::experiment::{Experiment, ExperimentAssignment};
+use crate::service::id_document::IdDocument;
+use crate::service::source_label::SearchScope;
+use crate::service::state::AppState;
+use api_utils::retrieval_model::RetrievalRequest;
+use axum::{http::StatusCode, Json};
+use mongodb::bson::doc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use tracing::{debug, error};
+
+/// Field of the history documents of the replays, holding the reference ID of the replayed retrieval.
+pub const REPLAY_OF_FIELD: &str = "replay_of";
+/// Service type of the task IDs of the replays, out of reach of the dead retrieval sweeper.
+pub const REPLAY_SERVICE_TYPE: &str = "Replay";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplayError {
+    #[error("No retrieval found with reference ID '{0}'.")]
+    NotFound(String),
+    #[error("Retrieval '{0}' has no history document yet.")]
+    InProgress(String),
+    #[error("Retrieval '{0}' can't be replayed, its request was not stored.")]
+    NotReplayable(String),
+    #[error("Failed to replay retrieval '{reference_id}'. Error: {message}")]
+    Store {
+        reference_id: String,
+        message: String,
+    },
+}
+
+impl From<ReplayError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: ReplayError) -> Self {
+        let status_code = match e {
+            ReplayError::NotFound(_) => StatusCode::NOT_FOUND,
+            ReplayError::InProgress(_) | ReplayError::NotReplayable(_) => StatusCode::CONFLICT,
+            ReplayError::Store { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let error_message = e.to_string();
+        match status_code {
+            StatusCode::INTERNAL_SERVER_ERROR => {
+                error!(ext_message = error_message, message = error_message)
+            }
+            _ => debug!(message = error_message),
+        }
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Request of a retrieval, stored with its history document to replay it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredRequest {
+    pub body: RetrievalRequest,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub_queries: Option<Vec<String>>,
+    /// Labels of the search scope of the retrieval, resolved again against the source labels of the app on replay.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub search_scope: Option<Vec<String>>,
+}
+
+impl StoredRequest {
+    /// Creates the stored request of a retrieval, with the user ID as stored in its history document.
+    pub fn new(body: &RetrievalRequest, user_id: &str, sub_queries: Option<Vec<String>>) -> Self {
+        let mut body = body.clone();
+        body.user_details.user_id = user_id.to_string();
+        StoredRequest {
+            body,
+            sub_queries,
+            search_scope: None,
+        }
+    }
+
+    /// Keeps the labels of the search scope of the retrieval, if restricted.
+    pub fn with_search_scope(mut self, search_scope: Option<&SearchScope>) -> Self {
+        self.search_scope = search_scope.map(|search_scope| search_scope.labels.clone());
+        self
+    }
+
+    /// Serializes the stored request into the `request` field of a history document.
+    pub fn to_field(&self) -> Option<String> {
+        serde_json::to_string(self)
+            .map_err(|e| error!(message = format!("Failed to serialize the request. Error: {}", e)))
+            .ok()
+    }
+}
+
+/// Returns the assignments of the variants of a replayed retrieval, with the current parameters of the variants.
+/// The variants of the experiments deleted since are replayed without parameters.
+pub fn replay_assignments(
+    experiments: &[Experiment],
+    experiment_variants: &BTreeMap<String, String>,
+) -> Vec<ExperimentAssignment> {
+    experiment_variants
+        .iter()
+        .map(|(experiment, variant)| ExperimentAssignment {
+            experiment: experiment.clone(),
+            variant: variant.clone(),
+            parameters: experiments
+                .iter()
+                .find(|candidate| &candidate.name == experiment)
+                .and_then(|experiment| {
+                    experiment
+                        .variants
+                        .iter()
+                        .find(|candidate| &candidate.name == variant)
+                })
+                .and_then(|variant| variant.parameters.clone()),
+        })
+        .collect()
+}
+
+/// Returns the ID document of a reference ID, holding the app of the request.
+pub async fn find_id_document(
+    app_state: &AppState,
+    reference_id: &str,
+) -> Result<IdDocument, ReplayError> {
+    let store_error = |message: String| ReplayError::Store {
+        reference_id: reference_id.to_string(),
+        message,
+    };
+    let id_document = app_state
+        .db
+        .get_document(
+            &app_state.app_settings.mongo_db.mongo_db_id_collection,
+            doc! {"reference_id": reference_id},
+        )
+        .await
+        .map_err(|e| store_error(e.to_string()))?
+        .ok_or_else(|| ReplayError::NotFound(reference_id.to_string()))?;
+    serde_json::from_value(id_document).map_err(|e| store_error(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::experiment::ExperimentVariant;
+
+    #[test]
+    fn test_success_replay_assignments() {
+        let experiments = vec![Experiment {
+            name: "prompt".to_string(),
+            description: None,
+            enabled: false,
+            variants: vec![ExperimentVariant {
+                name: "v2".to_string(),
+                weight: 100,
+                parameters: Some(json!({"prompt": "Be concise."})),
+            }],
+        }];
+        let experiment_variants = BTreeMap::from([
+            ("prompt".to_string(), "v2".to_string()),
+            ("deleted".to_string(), "control".to_string()),
+        ]);
+
+        let assignments = replay_assignments(&experiments, &experiment_variants);
+        assert_eq!(assignments.len(), 2);
+        assert_eq!(assignments[0].experiment, "deleted");
+        assert_eq!(assignments[0].parameters, None);
+        assert_eq!(
+            assignments[1].parameters,
+            Some(json!({"prompt": "Be concise."}))
+        );
+    }
+
+    #[test]
+    fn test_success_stored_request() {
+        let body: RetrievalRequest = serde_json::from_str(
+            &std::fs::read_to_string("src/test/retrieval_request.json").unwrap(),
+        )
+        .unwrap();
+        let stored_request = StoredRequest::new(&body, "psn_123", None);
+        let field = stored_request.to_field().unwrap();
+
+        let stored_request: StoredRequest = serde_json::from_str(&field).unwrap();
+        assert_eq!(stored_request.body.user_details.user_id, "psn_123");
+        assert_eq!(stored_request.body.query, body.query);
+        assert!(!field.contains("sub_queries"));
+    }
+}
@@ -0,0 +1,210 @@
+/*
+ * Created Date:  Jul 30, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the sandbox retrievals, test retrievals run from the admin UI to verify an app answers right
+//! after its onboarding.
+//! A sandbox retrieval runs as the app, without its API key ever leaving the facade, through the pipeline of the
+//! retrievals (query normalization and classification, row filters of the user, knowledge engine call), but
+//! synchronously: the history document is returned by the request. The access list, rate limits, query loop circuit,
+//! readiness gate and experiments of the app are not applied.
+//! Its history document is stored in the history collection of the app with `sandbox` set; the history endpoint, the
+//! answer sinks and the error counts ignore it, and its tokens are not accounted.
+//!
+
+use crate::retrieval::handler::{background_tasks, RetrievalJob};
+use crate::retrieval::schema::history_document::HistoryDocument;
+use crate::retrieval::stage_timings::StageTimings;
+use crate::service::deadline::Deadline;
+use crate::service::generate_and_insert_document::generate_id_document;
+use crate::service::pseudonymization::pseudonymize_user_id;
+use crate::service::state::AppState;
+use api_utils::retrieval_model::RetrievalRequest;
+use axum::{http::StatusCode, Json};
+use chrono::Utc;
+use mongodb::bson::{doc, to_document};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error};
+
+/// Field of the history documents of the sandbox retrievals.
+pub const SANDBOX_FIELD: &str = "sandbox";
+/// Service type of the task IDs of the sandbox retrievals, out of reach of the dead retrieval sweeper.
+pub const SANDBOX_SERVICE_TYPE: &str = "Sandbox";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SandboxError {
+    #[error("Invalid sandbox retrieval. {0}")]
+    InvalidRequest(String),
+    #[error("Sandbox retrieval '{0}' stored no history document.")]
+    NoAnswer(String),
+    #[error("Failed to run sandbox retrieval '{reference_id}'. Error: {message}")]
+    Store {
+        reference_id: String,
+        message: String,
+    },
+}
+
+impl From<SandboxError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: SandboxError) -> Self {
+        let status_code = match e {
+            SandboxError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            SandboxError::NoAnswer(_) | SandboxError::Store { .. } => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        let error_message = e.to_string();
+        match status_code {
+            StatusCode::INTERNAL_SERVER_ERROR => {
+                error!(ext_message = error_message, message = error_message)
+            }
+            _ => debug!(message = error_message),
+        }
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Validates the request of a sandbox retrieval.
+pub fn validate_sandbox_request(body: &RetrievalRequest) -> Result<(), SandboxError> {
+    if body.query.trim().is_empty() {
+        return Err(SandboxError::InvalidRequest(
+            "The query is empty.".to_string(),
+        ));
+    }
+    if body.user_details.user_id.trim().is_empty() {
+        return Err(SandboxError::InvalidRequest(
+            "The user ID is empty.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Runs a sandbox retrieval of an app and returns its history document, decrypted, without its request.
+pub async fn run_sandbox_retrieval(
+    app_state: &Arc<AppState>,
+    app_name: &str,
+    body: RetrievalRequest,
+    deadline: Option<Deadline>,
+) -> Result<HistoryDocument, SandboxError> {
+    let reference_id = app_state.id_generator.reference_id();
+    let store_error = |message: String| SandboxError::Store {
+        reference_id: reference_id.clone(),
+        message,
+    };
+
+    // Store the user ID as the retrievals of the app do, and bind the row filters of the user
+    let user_id = match app_state
+        .apps()
+        .pseudonymize_user_ids(app_name)
+        .await
+        .map_err(|e| store_error(e.to_string()))?
+    {
+        true => pseudonymize_user_id(app_state, app_name, &body.user_details.user_id)
+            .await
+            .map_err(|e| store_error(e.to_string()))?,
+        false => body.user_details.user_id.clone(),
+    };
+    let row_filters = app_state
+        .apps()
+        .row_filters(app_name)
+        .await
+        .map_err(|e| store_error(e.to_string()))?;
+
+    // Record the reference ID, so the sandbox retrieval can be traced
+    let task_id = app_state
+        .id_generator
+        .task_id(app_name, SANDBOX_SERVICE_TYPE);
+    let id_document =
+        generate_id_document(&app_name.to_string(), reference_id.clone(), task_id.clone()).await;
+    let id_document = to_document(&id_document).map_err(|e| store_error(e.to_string()))?;
+    app_state
+        .db
+        .create_document(
+            &app_state.app_settings.mongo_db.mongo_db_id_collection,
+            id_document,
+        )
+        .await
+        .map_err(|e| store_error(e.to_string()))?;
+
+    // Run the pipeline in the request, the history document is stored by the pipeline
+    background_tasks(
+        Arc::clone(app_state),
+        RetrievalJob {
+            app_name: app_name.to_string(),
+            user_id,
+            body,
+            sub_queries: None,
+            experiments: Vec::new(),
+            row_filters,
+            search_scope: None,
+            reference_id: reference_id.clone(),
+            task_id,
+            request_timestamp: Utc::now(),
+            replay_of: None,
+            sandbox: true,
+            deadline,
+            timings: StageTimings::default(),
+        },
+    )
+    .await;
+
+    let mut history_document = app_state
+        .app_db(app_name)
+        .await
+        .map_err(|e| store_error(e.to_string()))?
+        .get_document(
+            &format!("{}-history", app_name),
+            doc! {"reference_id": &reference_id, SANDBOX_FIELD: true},
+        )
+        .await
+        .map_err(|e| store_error(e.to_string()))?
+        .ok_or_else(|| SandboxError::NoAnswer(reference_id.clone()))?;
+    app_state
+        .decrypt_fields(app_name, &mut history_document)
+        .await
+        .map_err(|e| store_error(e.to_string()))?;
+    let mut history_document =
+        HistoryDocument::from_stored(history_document).map_err(|e| store_error(e.to_string()))?;
+    history_document.request = None;
+    Ok(history_document)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Read;
+
+    fn retrieval_request() -> RetrievalRequest {
+        let mut file = File::open("src/test/retrieval_request.json").unwrap();
+        let mut buff = String::new();
+        file.read_to_string(&mut buff).unwrap();
+        serde_json::from_str(&buff).unwrap()
+    }
+
+    #[test]
+    fn test_success_validate_sandbox_request() {
+        assert!(validate_sandbox_request(&retrieval_request()).is_ok());
+    }
+
+    #[test]
+    fn test_failure_validate_sandbox_request() {
+        let mut body = retrieval_request();
+        body.query = "  ".to_string();
+        assert!(matches!(
+            validate_sandbox_request(&body),
+            Err(SandboxError::InvalidRequest(_))
+        ));
+
+        let mut body = retrieval_request();
+        body.user_details.user_id = String::new();
+        assert!(matches!(
+            validate_sandbox_request(&body),
+            Err(SandboxError::InvalidRequest(_))
+        ));
+    }
+}
@@ -4,38 +4,470 @@
  * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
  */
 //! This module contains the schema for the history document.
+//! The response of the knowledge engine is parsed at write time into typed fields (answer, citations,
+//! confidence, model used and token usage), next to the raw `response`. JSON responses are read field by
+//! field; plain text responses are split into the answer and a trailing `Sources:`/`Citations:` block.
+//! `schema_version` is 2 for typed documents. Documents stored before the typed fields are served with
+//! `schema_version` 1 and their typed fields parsed on read.
+//! The history document of a fan-out retrieval holds the answer of each of its sub-queries in `sub_queries`; its
+//! response is composed from the responses of the sub-queries by `composite_response`.
+//! `experiment_variants` holds the variant of every experiment of the app assigned to the retrieval, by experiment.
+//! The texts of an oversized history document are stored truncated, with `truncated` set and the `full_content`
+//! pointer to the full response in S3 (see `crate::service::answer_offload`).
+//! `request` keeps the request of the retrieval to replay it, and `replay_of` marks the history document of a replay
+//! (see `crate::retrieval::replay`). `sandbox` marks the history document of a test retrieval run from the admin UI
+//! (see `crate::retrieval::sandbox`).
+//! `timings` holds the duration of every stage of the retrieval pipeline (see `crate::retrieval::stage_timings`).
+//! `timestamp` is the time of the response, stored as a BSON date (see `crate::service::timestamp`), or
+//! `RETRIEVAL_FAILED_TIMESTAMP` for a failed retrieval.
+//!
 
-use serde::{Deserialize, Serialize};
+use crate::retrieval::query_classification::QueryCategory;
+use crate::retrieval::stage_timings::StageTimings;
+use crate::service::timestamp::{timestamp_from_bson, to_bson_datetime};
+use chrono::{DateTime, Utc};
+use mongodb::bson::Bson;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::json;
+use std::collections::BTreeMap;
 use utoipa::ToSchema;
 
+/// Schema version of the history documents with typed fields.
+pub const HISTORY_SCHEMA_VERSION: i32 = 2;
+/// Schema version of the history documents stored with the raw response only.
+pub const LEGACY_HISTORY_SCHEMA_VERSION: i32 = 1;
+/// Timestamp of the history documents of failed retrievals.
+pub const RETRIEVAL_FAILED_TIMESTAMP: &str = "Retrieval failed.";
+/// Headers of the citation block of a plain text response.
+const CITATION_HEADERS: [&str; 3] = ["sources:", "citations:", "references:"];
+
+fn legacy_schema_version() -> i32 {
+    LEGACY_HISTORY_SCHEMA_VERSION
+}
+
+/// A source cited by the answer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Citation {
+    #[serde(alias = "uri", alias = "url")]
+    pub source: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(
+        default,
+        alias = "text",
+        alias = "content",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub snippet: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+}
+
+/// Tokens used by the model to generate the answer.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct TokenUsage {
+    #[serde(default, alias = "input_tokens")]
+    pub prompt_tokens: i64,
+    #[serde(default, alias = "output_tokens")]
+    pub completion_tokens: i64,
+    #[serde(default)]
+    pub total_tokens: i64,
+}
+
+/// Typed fields of a knowledge engine response.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct EngineResponse {
+    #[serde(default, alias = "response", alias = "text")]
+    pub answer: Option<String>,
+    #[serde(default, alias = "sources")]
+    pub citations: Vec<Citation>,
+    #[serde(default)]
+    pub confidence: Option<f64>,
+    #[serde(default, alias = "model")]
+    pub model_used: Option<String>,
+    #[serde(default, alias = "usage")]
+    pub token_usage: Option<TokenUsage>,
+    #[serde(default)]
+    pub sub_queries: Vec<SubQueryAnswer>,
+}
+
+impl EngineResponse {
+    /// Parses a knowledge engine response, as JSON if possible, else as plain text.
+    pub(crate) fn parse(response: &str) -> Self {
+        if let Ok(engine_response) = serde_json::from_str::<EngineResponse>(response) {
+            return engine_response;
+        }
+        let (answer, citations) = split_citations(response);
+        EngineResponse {
+            answer: Some(answer),
+            citations,
+            ..Default::default()
+        }
+    }
+}
+
+/// Answer of a sub-query of a fan-out retrieval.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct SubQueryAnswer {
+    pub query: String,
+    #[serde(default)]
+    pub answer: Option<String>,
+    #[serde(default)]
+    pub citations: Vec<Citation>,
+    /// Error of the knowledge engine, when the sub-query failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Composes the response of a fan-out retrieval from the responses of its sub-queries, in the order of the
+/// sub-queries: the answers are joined under their sub-query, the citations merged by source and the token usages
+/// summed. Failed sub-queries are kept with their error.
+pub fn composite_response(sub_responses: &[(String, Result<String, String>)]) -> serde_json::Value {
+    let mut sub_queries = Vec::with_capacity(sub_responses.len());
+    let mut answers = Vec::new();
+    let mut citations: Vec<Citation> = Vec::new();
+    let mut model_used = None;
+    let mut token_usage: Option<TokenUsage> = None;
+    for (query, response) in sub_responses {
+        let response = match response {
+            Ok(response) => EngineResponse::parse(response),
+            Err(error) => {
+                sub_queries.push(SubQueryAnswer {
+                    query: query.clone(),
+                    answer: None,
+                    citations: Vec::new(),
+                    error: Some(error.clone()),
+                });
+                continue;
+            }
+        };
+        if let Some(answer) = &response.answer {
+            answers.push(format!("{}\n{}", query, answer));
+        }
+        for citation in &response.citations {
+            if !citations
+                .iter()
+                .any(|merged| merged.source == citation.source)
+            {
+                citations.push(citation.clone());
+            }
+        }
+        model_used = model_used.or(response.model_used);
+        if let Some(usage) = response.token_usage {
+            let total = token_usage.get_or_insert_with(TokenUsage::default);
+            total.prompt_tokens += usage.prompt_tokens;
+            total.completion_tokens += usage.completion_tokens;
+            total.total_tokens += usage.total_tokens;
+        }
+        sub_queries.push(SubQueryAnswer {
+            query: query.clone(),
+            answer: response.answer,
+            citations: response.citations,
+            error: None,
+        });
+    }
+    json!({
+        "answer": answers.join("\n\n"),
+        "citations": citations,
+        "model_used": model_used,
+        "token_usage": token_usage,
+        "sub_queries": sub_queries,
+    })
+}
+
+/// Splits a plain text response into the answer and the sources listed after a citation header.
+fn split_citations(response: &str) -> (String, Vec<Citation>) {
+    let lines: Vec<&str> = response.lines().collect();
+    let Some(header) = lines
+        .iter()
+        .rposition(|line| CITATION_HEADERS.contains(&line.trim().to_lowercase().as_str()))
+    else {
+        return (response.trim().to_string(), Vec::new());
+    };
+    let citations = lines[header + 1..]
+        .iter()
+        .copied()
+        .map(strip_list_marker)
+        .filter(|source| !source.is_empty())
+        .map(|source| Citation {
+            source: source.to_string(),
+            title: None,
+            snippet: None,
+            score: None,
+        })
+        .collect();
+    (lines[..header].join("\n").trim().to_string(), citations)
+}
+
+/// Strips the list marker (`-`, `*`, `[1]`, `1.` or `1)`) of a line of the citation block.
+fn strip_list_marker(line: &str) -> &str {
+    let line = line.trim();
+    if let Some(rest) = line.strip_prefix(['-', '*']) {
+        return rest.trim();
+    }
+    if let Some((number, rest)) = line.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+        if number.chars().all(|c| c.is_ascii_digit()) {
+            return rest.trim();
+        }
+    }
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    if digits > 0 {
+        if let Some(rest) = line[digits..].strip_prefix(['.', ')']) {
+            return rest.trim();
+        }
+    }
+    line
+}
+
+/// Timestamp of a history document.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryTimestamp {
+    /// Time of the response of the knowledge engine.
+    Responded(DateTime<Utc>),
+    /// The retrieval failed, stored as `RETRIEVAL_FAILED_TIMESTAMP`.
+    Failed,
+}
+
+impl HistoryTimestamp {
+    pub fn is_failed(&self) -> bool {
+        matches!(self, HistoryTimestamp::Failed)
+    }
+}
+
+impl Serialize for HistoryTimestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            HistoryTimestamp::Responded(timestamp) => {
+                to_bson_datetime(*timestamp).serialize(serializer)
+            }
+            HistoryTimestamp::Failed => serializer.serialize_str(RETRIEVAL_FAILED_TIMESTAMP),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for HistoryTimestamp {
+    /// Reads the BSON dates and the timestamps stored as strings before them.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Bson::deserialize(deserializer)?;
+        if value.as_str() == Some(RETRIEVAL_FAILED_TIMESTAMP) {
+            return Ok(HistoryTimestamp::Failed);
+        }
+        timestamp_from_bson(&value, None)
+            .map(HistoryTimestamp::Responded)
+            .ok_or_else(|| {
+                serde::de::Error::custom(format!("invalid history timestamp: {}", value))
+            })
+    }
+}
+
+/// Pointer to the full response of a truncated history document.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct FullContent {
+    /// Location of the object, `s3://bucket/key`.
+    pub uri: String,
+    /// Size of the full response, in bytes.
+    pub size_bytes: u64,
+    /// Content type of the full response.
+    pub content_type: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct HistoryDocument {
+    #[serde(default = "legacy_schema_version")]
+    pub schema_version: i32,
     pub reference_id: String,
     pub task_id: String,
     pub query: String,
+    /// Query sent to the knowledge engine, when the queries of the app are normalized.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normalized_query: Option<String>,
     pub response: String,
-    pub timestamp: String,
+    #[serde(default)]
+    pub answer: Option<String>,
+    #[serde(default)]
+    pub citations: Vec<Citation>,
+    #[serde(default)]
+    pub confidence: Option<f64>,
+    #[serde(default)]
+    pub model_used: Option<String>,
+    #[serde(default)]
+    pub token_usage: Option<TokenUsage>,
+    /// Answers of the sub-queries of a fan-out retrieval.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sub_queries: Vec<SubQueryAnswer>,
+    /// End user of the retrieval, matched by the legal holds of the history retention.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    /// Category of the query, passed to the knowledge engine as a routing hint.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_category: Option<QueryCategory>,
+    /// Variants of the experiments of the app assigned to the retrieval, by experiment.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub experiment_variants: BTreeMap<String, String>,
+    /// Whether the response, answer and snippets are truncated by the max-answer-size policy.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// Pointer to the full response of a truncated history document, unset if it couldn't be stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub full_content: Option<FullContent>,
+    /// Request of the retrieval, a serialized `StoredRequest`. Never served by the history endpoint.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<String>,
+    /// Reference ID of the retrieval replayed by this history document.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replay_of: Option<String>,
+    /// Whether the retrieval is a sandbox retrieval run from the admin UI.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sandbox: bool,
+    /// Durations of the stages of the retrieval pipeline.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<StageTimings>,
+    #[schema(value_type = String)]
+    pub timestamp: HistoryTimestamp,
     disclaimer_text: String,
 }
 
 impl HistoryDocument {
+    /// Creates a history document, with the typed fields parsed from the engine response.
     pub fn new(
         reference_id: String,
         task_id: String,
         query: String,
         response: String,
-        timestamp: String,
+        timestamp: DateTime<Utc>,
         disclaimer_text: String,
     ) -> Self {
+        let engine_response = EngineResponse::parse(&response);
         Self {
+            schema_version: HISTORY_SCHEMA_VERSION,
             reference_id,
             task_id,
             query,
+            normalized_query: None,
             response,
-            timestamp,
+            answer: engine_response.answer,
+            citations: engine_response.citations,
+            confidence: engine_response.confidence,
+            model_used: engine_response.model_used,
+            token_usage: engine_response.token_usage,
+            sub_queries: engine_response.sub_queries,
+            user_id: None,
+            query_category: None,
+            experiment_variants: BTreeMap::new(),
+            truncated: false,
+            full_content: None,
+            request: None,
+            replay_of: None,
+            sandbox: false,
+            timings: None,
+            timestamp: HistoryTimestamp::Responded(timestamp),
             disclaimer_text,
         }
     }
+
+    /// Creates the history document of a failed retrieval. The error is stored as the response, without answer.
+    pub fn failed(
+        reference_id: String,
+        task_id: String,
+        query: String,
+        error: String,
+        disclaimer_text: String,
+    ) -> Self {
+        Self {
+            schema_version: HISTORY_SCHEMA_VERSION,
+            reference_id,
+            task_id,
+            query,
+            normalized_query: None,
+            response: error,
+            answer: None,
+            citations: Vec::new(),
+            confidence: None,
+            model_used: None,
+            token_usage: None,
+            sub_queries: Vec::new(),
+            user_id: None,
+            query_category: None,
+            experiment_variants: BTreeMap::new(),
+            truncated: false,
+            full_content: None,
+            request: None,
+            replay_of: None,
+            sandbox: false,
+            timings: None,
+            timestamp: HistoryTimestamp::Failed,
+            disclaimer_text,
+        }
+    }
+
+    /// Sets the end user of the retrieval.
+    pub fn with_user_id(mut self, user_id: &str) -> Self {
+        self.user_id = Some(user_id.to_string());
+        self
+    }
+
+    /// Sets the query sent to the knowledge engine. `None` if the query was not normalized.
+    pub fn with_normalized_query(mut self, normalized_query: Option<String>) -> Self {
+        self.normalized_query = normalized_query;
+        self
+    }
+
+    /// Sets the category of the query. `None` if the query was not classified.
+    pub fn with_query_category(mut self, query_category: Option<QueryCategory>) -> Self {
+        self.query_category = query_category;
+        self
+    }
+
+    /// Sets the variants of the experiments assigned to the retrieval.
+    pub fn with_experiment_variants(
+        mut self,
+        experiment_variants: BTreeMap<String, String>,
+    ) -> Self {
+        self.experiment_variants = experiment_variants;
+        self
+    }
+
+    /// Sets the request of the retrieval, kept to replay it.
+    pub fn with_request(mut self, request: Option<String>) -> Self {
+        self.request = request;
+        self
+    }
+
+    /// Sets the reference ID of the retrieval replayed. `None` if the retrieval is not a replay.
+    pub fn with_replay_of(mut self, replay_of: Option<String>) -> Self {
+        self.replay_of = replay_of;
+        self
+    }
+
+    /// Flags the history document of a sandbox retrieval.
+    pub fn with_sandbox(mut self, sandbox: bool) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// Sets the durations of the stages of the retrieval pipeline.
+    pub fn with_timings(mut self, timings: StageTimings) -> Self {
+        self.timings = Some(timings);
+        self
+    }
+
+    /// Reads a stored history document. The typed fields of the documents stored before
+    /// `HISTORY_SCHEMA_VERSION` are parsed from the raw response.
+    pub fn from_stored(document: serde_json::Value) -> Result<Self, serde_json::Error> {
+        let mut history_document: HistoryDocument = serde_json::from_value(document)?;
+        if history_document.schema_version < HISTORY_SCHEMA_VERSION
+            && !history_document.timestamp.is_failed()
+        {
+            let engine_response = EngineResponse::parse(&history_document.response);
+            history_document.answer = engine_response.answer;
+            history_document.citations = engine_response.citations;
+            history_document.confidence = engine_response.confidence;
+            history_document.model_used = engine_response.model_used;
+            history_document.token_usage = engine_response.token_usage;
+            history_document.sub_queries = engine_response.sub_queries;
+        }
+        Ok(history_document)
+    }
 }
 
 #[cfg(test)]
@@ -46,14 +478,16 @@ mod tests {
 
     #[test]
     fn test_history_document_traits() {
-        let doc = HistoryDocument {
-            reference_id: "123".to_string(),
-            task_id: "456".to_string(),
-            query: "query".to_string(),
-            response: "response".to_string(),
-            timestamp: "timestamp".to_string(),
-            disclaimer_text: "disclaimer_text".to_string(),
-        };
+        // Stored with millisecond precision
+        let timestamp = DateTime::from_timestamp_millis(1721988000123).unwrap();
+        let doc = HistoryDocument::new(
+            "123".to_string(),
+            "456".to_string(),
+            "query".to_string(),
+            "response".to_string(),
+            timestamp,
+            "disclaimer_text".to_string(),
+        );
 
         // Test Clone
         let cloned_doc = doc.clone();
@@ -70,4 +504,118 @@ mod tests {
         let deserialized_doc: HistoryDocument = serde_json::from_str(&serialized_doc).unwrap();
         assert_eq!(doc.timestamp, deserialized_doc.timestamp);
     }
+
+    #[test]
+    fn test_success_history_document_json_response() {
+        let response = r#"{"answer": "42", "citations": [{"uri": "s3://bucket/doc.pdf", "text": "the answer is 42", "score": 0.9}], "confidence": 0.8, "model": "claude", "usage": {"input_tokens": 10, "output_tokens": 2, "total_tokens": 12}}"#;
+        let doc = HistoryDocument::new(
+            "123".to_string(),
+            "456".to_string(),
+            "query".to_string(),
+            response.to_string(),
+            Utc::now(),
+            "disclaimer_text".to_string(),
+        );
+        assert_eq!(doc.schema_version, HISTORY_SCHEMA_VERSION);
+        assert_eq!(doc.answer.as_deref(), Some("42"));
+        assert_eq!(doc.citations.len(), 1);
+        assert_eq!(doc.citations[0].source, "s3://bucket/doc.pdf");
+        assert_eq!(
+            doc.citations[0].snippet.as_deref(),
+            Some("the answer is 42")
+        );
+        assert_eq!(doc.confidence, Some(0.8));
+        assert_eq!(doc.model_used.as_deref(), Some("claude"));
+        assert_eq!(doc.token_usage.unwrap().total_tokens, 12);
+        assert_eq!(doc.response, response);
+    }
+
+    #[test]
+    fn test_success_history_document_text_response() {
+        let doc = HistoryDocument::new(
+            "123".to_string(),
+            "456".to_string(),
+            "query".to_string(),
+            "The answer is 42.\n\nSources:\n[1] s3://bucket/doc.pdf\n2. https://tresle.ai/faq\n"
+                .to_string(),
+            Utc::now(),
+            "disclaimer_text".to_string(),
+        );
+        assert_eq!(doc.answer.as_deref(), Some("The answer is 42."));
+        let sources: Vec<&str> = doc.citations.iter().map(|c| c.source.as_str()).collect();
+        assert_eq!(
+            sources,
+            vec!["s3://bucket/doc.pdf", "https://tresle.ai/faq"]
+        );
+        assert_eq!(doc.confidence, None);
+    }
+
+    #[test]
+    fn test_success_history_document_composite_response() {
+        let sub_responses = vec![
+            (
+                "leave policy".to_string(),
+                Ok(r#"{"answer": "20 days", "citations": [{"source": "s3://hr/leave.pdf"}], "token_usage": {"prompt_tokens": 10, "completion_tokens": 2, "total_tokens": 12}}"#.to_string()),
+            ),
+            (
+                "travel policy".to_string(),
+                Ok("Economy class.\n\nSources:\n- s3://hr/leave.pdf\n- s3://hr/travel.pdf".to_string()),
+            ),
+            ("expense policy".to_string(), Err("timeout".to_string())),
+        ];
+        let doc = HistoryDocument::new(
+            "123".to_string(),
+            "456".to_string(),
+            "HR policies".to_string(),
+            composite_response(&sub_responses).to_string(),
+            Utc::now(),
+            "disclaimer_text".to_string(),
+        );
+        assert_eq!(
+            doc.answer.as_deref(),
+            Some("leave policy\n20 days\n\ntravel policy\nEconomy class.")
+        );
+        // The citations are merged by source, and kept per sub-query
+        assert_eq!(doc.citations.len(), 2);
+        assert_eq!(doc.sub_queries.len(), 3);
+        assert_eq!(doc.sub_queries[1].citations.len(), 2);
+        assert_eq!(doc.sub_queries[2].error.as_deref(), Some("timeout"));
+        assert_eq!(doc.token_usage.unwrap().total_tokens, 12);
+    }
+
+    #[test]
+    fn test_success_history_document_from_stored_legacy() {
+        let stored = serde_json::json!({
+            "_id": {"$oid": "6650e4a1f1b2c3d4e5f60718"},
+            "reference_id": "123",
+            "task_id": "456",
+            "query": "query",
+            "response": "{\"answer\": \"42\"}",
+            "timestamp": "2024-07-26 10:00:00.123 UTC",
+            "disclaimer_text": "disclaimer_text",
+        });
+        let doc = HistoryDocument::from_stored(stored).unwrap();
+        assert_eq!(doc.schema_version, LEGACY_HISTORY_SCHEMA_VERSION);
+        assert_eq!(doc.answer.as_deref(), Some("42"));
+        assert_eq!(
+            doc.timestamp,
+            HistoryTimestamp::Responded(
+                DateTime::parse_from_rfc3339("2024-07-26T10:00:00.123Z")
+                    .unwrap()
+                    .with_timezone(&Utc)
+            )
+        );
+
+        let failed = HistoryDocument::failed(
+            "123".to_string(),
+            "456".to_string(),
+            "query".to_string(),
+            "timeout".to_string(),
+            "disclaimer_text".to_string(),
+        );
+        let doc = HistoryDocument::from_stored(serde_json::to_value(failed).unwrap()).unwrap();
+        assert_eq!(doc.answer, None);
+        assert_eq!(doc.response, "timeout");
+        assert!(doc.timestamp.is_failed());
+    }
 }
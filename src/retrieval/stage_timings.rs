@@ -0,0 +1,205 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the latency budget of the retrieval pipeline, timed stage by stage: the write of the ID
+//! document, the lookup of the API key and the parsing of the body by the POST handler, then the call of the knowledge
+//! engine and the write of the history document by the background task.
+//! The timings are stored in the `timings` field of the history document, the history write once the document is
+//! stored, and every stage is recorded by the `Retrieval Stage Duration` metric with the stage as dimension, exported
+//! as a Prometheus histogram when enabled (see `crate::service::prometheus`).
+//!
+
+use crate::service::metrics::{MetricRecord, APP_NAME_DIMENSION};
+use crate::service::state::AppState;
+use mongodb::bson::doc;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tracing::error;
+use utoipa::ToSchema;
+
+/// Field of the timings in the history document.
+pub const TIMINGS_FIELD: &str = "timings";
+/// Dimension holding the stage of the retrieval pipeline.
+pub const STAGE_DIMENSION: &str = "stage";
+
+/// Stage of the retrieval pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetrievalStage {
+    IdDocumentWrite,
+    ApiKeyLookup,
+    BodyParse,
+    EngineCall,
+    HistoryWrite,
+}
+
+impl RetrievalStage {
+    pub const ALL: [RetrievalStage; 5] = [
+        RetrievalStage::IdDocumentWrite,
+        RetrievalStage::ApiKeyLookup,
+        RetrievalStage::BodyParse,
+        RetrievalStage::EngineCall,
+        RetrievalStage::HistoryWrite,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RetrievalStage::IdDocumentWrite => "id_document_write",
+            RetrievalStage::ApiKeyLookup => "api_key_lookup",
+            RetrievalStage::BodyParse => "body_parse",
+            RetrievalStage::EngineCall => "engine_call",
+            RetrievalStage::HistoryWrite => "history_write",
+        }
+    }
+
+    /// Field of the stage in the timings of the history document.
+    pub fn field(&self) -> String {
+        format!("{}.{}_ms", TIMINGS_FIELD, self.as_str())
+    }
+}
+
+/// Durations of the stages of a retrieval, in milliseconds. Unset for the stages not reached.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, ToSchema)]
+pub struct StageTimings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_document_write_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_lookup_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_parse_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub engine_call_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_write_ms: Option<u64>,
+}
+
+impl StageTimings {
+    fn slot(&mut self, stage: RetrievalStage) -> &mut Option<u64> {
+        match stage {
+            RetrievalStage::IdDocumentWrite => &mut self.id_document_write_ms,
+            RetrievalStage::ApiKeyLookup => &mut self.api_key_lookup_ms,
+            RetrievalStage::BodyParse => &mut self.body_parse_ms,
+            RetrievalStage::EngineCall => &mut self.engine_call_ms,
+            RetrievalStage::HistoryWrite => &mut self.history_write_ms,
+        }
+    }
+
+    /// Duration of a stage, in milliseconds.
+    pub fn get(&self, stage: RetrievalStage) -> Option<u64> {
+        match stage {
+            RetrievalStage::IdDocumentWrite => self.id_document_write_ms,
+            RetrievalStage::ApiKeyLookup => self.api_key_lookup_ms,
+            RetrievalStage::BodyParse => self.body_parse_ms,
+            RetrievalStage::EngineCall => self.engine_call_ms,
+            RetrievalStage::HistoryWrite => self.history_write_ms,
+        }
+    }
+
+    /// Sets the duration of a stage.
+    pub fn set(&mut self, stage: RetrievalStage, duration: Duration) {
+        *self.slot(stage) = Some(duration.as_millis() as u64);
+    }
+
+    /// Awaits a future, timed as a stage.
+    pub async fn time<T>(&mut self, stage: RetrievalStage, future: impl Future<Output = T>) -> T {
+        let start = Instant::now();
+        let output = future.await;
+        self.set(stage, start.elapsed());
+        output
+    }
+
+    /// Durations of the timed stages, in pipeline order.
+    pub fn iter(&self) -> impl Iterator<Item = (RetrievalStage, u64)> + '_ {
+        RetrievalStage::ALL
+            .into_iter()
+            .filter_map(|stage| Some((stage, self.get(stage)?)))
+    }
+}
+
+/// Stores the duration of the history write in the stored history document of a retrieval, written once the
+/// document is stored. A failure is logged, the history document keeps the other stages.
+pub async fn store_history_write_timing(
+    app_state: &AppState,
+    app_name: &str,
+    reference_id: &str,
+    timings: &StageTimings,
+) {
+    let Some(history_write_ms) = timings.history_write_ms else {
+        return;
+    };
+    let db = match app_state.app_db(app_name).await {
+        Ok(db) => db,
+        Err(e) => {
+            error!(app_name = app_name, message = e.to_string());
+            return;
+        }
+    };
+    if let Err(e) = db
+        .update_document(
+            &format!("{}-history", app_name),
+            doc! {"reference_id": reference_id},
+            doc! {RetrievalStage::HistoryWrite.field(): history_write_ms as i64},
+        )
+        .await
+    {
+        let error_message = format!(
+            "Failed to store the history write timing of retrieval '{}'. Error: {}",
+            reference_id, e
+        );
+        error!(app_name = app_name, message = error_message);
+    }
+}
+
+/// Records the duration of every timed stage of a retrieval of an app.
+pub async fn record_stage_timings(app_state: &AppState, app_name: &str, timings: &StageTimings) {
+    for (stage, duration_ms) in timings.iter() {
+        app_state
+            .record_metric(
+                MetricRecord::duration_ms("Retrieval Stage Duration", duration_ms as i64)
+                    .dimension(APP_NAME_DIMENSION, app_name)
+                    .dimension(STAGE_DIMENSION, stage.as_str()),
+            )
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_stage_timings() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let mut timings = StageTimings::default();
+            let output = timings
+                .time(RetrievalStage::EngineCall, async {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    "answer"
+                })
+                .await;
+            assert_eq!(output, "answer");
+            assert!(timings.get(RetrievalStage::EngineCall).unwrap() >= 20);
+
+            timings.set(RetrievalStage::IdDocumentWrite, Duration::from_millis(3));
+            let stages: Vec<RetrievalStage> = timings.iter().map(|(stage, _)| stage).collect();
+            assert_eq!(
+                stages,
+                vec![RetrievalStage::IdDocumentWrite, RetrievalStage::EngineCall]
+            );
+
+            // The stages not reached are not stored
+            let stored = serde_json::to_value(timings).unwrap();
+            assert_eq!(stored["id_document_write_ms"], 3);
+            assert!(stored.get("history_write_ms").is_none());
+            assert_eq!(
+                RetrievalStage::HistoryWrite.field(),
+                "timings.history_write_ms"
+            );
+        });
+    }
+}
@@ -5,12 +5,85 @@
  */
 //! Functions common across multiple modules and/or admin UI.
 
+pub mod access_log;
+pub mod answer_offload;
+pub mod answer_sink;
+pub mod api_docs;
+pub mod api_key;
+pub mod app_cache;
 pub mod app_document;
+pub mod app_keys;
+pub mod app_metadata;
+pub mod app_repository;
+pub mod app_topic;
+pub mod artifact;
+pub mod backfill;
 pub mod check_app_existence;
+pub mod column_classification;
+pub mod ctx;
+pub mod deadline;
+pub mod deletion_confirmation;
+pub mod dependency_health;
+pub mod deployment;
+pub mod driver;
+pub mod encryption;
 pub mod error;
+pub mod error_code;
+pub mod etag;
+pub mod event_producer;
+pub mod experiment;
+pub mod federation;
+pub mod field_projection;
+pub mod file_types;
+pub mod filestore_hint;
 pub mod generate_and_insert_document;
+pub mod graph_query;
+pub mod history_polling;
+pub mod history_retention;
+pub mod history_upsert;
+pub mod http_client;
 pub mod id_document;
+pub mod id_generator;
+pub mod ingestion_control;
+pub mod ingestion_retry;
+pub mod ingestion_sla;
+pub mod job_queue;
+pub mod key_expiry;
+pub mod knowledge_node_types;
+pub mod local_dev;
+pub mod log_sink;
+pub mod metric_migration;
+pub mod metrics;
+pub mod migration;
+pub mod node_count_check;
+pub mod notification;
+pub mod object_store;
+pub mod onboarding_state;
+pub mod onboarding_webhook;
+pub mod overview_feed;
+pub mod pagination;
+pub mod prometheus;
+pub mod prompt_template;
+pub mod pseudonymization;
 pub mod publish_to_kafka;
+pub mod query_loop;
+pub mod query_options;
+pub mod rate_limit;
+pub mod readiness;
+pub mod residency;
+pub mod retrieval_sweeper;
 pub mod route;
+pub mod row_filter;
+pub mod scheduler;
+pub mod scim;
+pub mod selfcheck;
+pub mod service_account;
+pub mod shadow_traffic;
+pub mod source_label;
 pub mod state;
+pub mod timestamp;
+pub mod tls;
+pub mod token_usage_document;
 pub mod ui_summary_document;
+pub mod user_access;
+pub mod vector_store;
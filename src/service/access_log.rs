@@ -0,0 +1,207 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the access log of the service: one structured summary of every request, with its route,
+//! method, status, latency, app, reference ID, task ID and deployment labels, logged on the `access_log` tracing
+//! target.
+//! The access log is separate from the peripheral logging layer, which never receives the `access_log` target, and
+//! is written by the fmt layer at the `info` level whatever `tracing_layer_levels.fmt_layer_level`.
+//! The requests are grouped by route (`retrieval`, `history`, `admin_read`, `admin_mutation` and `other`), and each
+//! group is sampled at its rate of `access_log.sample_rates` (1.0 by default), so the high-volume retrieval traffic
+//! can be sampled. The admin mutations and the server errors are always logged.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{
+    AccessLogGroup, AccessLogSettings, TresleFacadeServiceSettings,
+};
+use crate::service::ctx::Ctx;
+use crate::service::state::AppState;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::Method,
+    middleware::Next,
+    response::Response,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::info;
+
+/// Tracing target of the access log.
+pub const ACCESS_LOG_TARGET: &str = "access_log";
+/// Default sample rate of the route groups.
+const DEFAULT_SAMPLE_RATE: f64 = 1.0;
+
+/// Access log options: activation and sample rates by route group. The access log is enabled once
+/// `access_log` is set; the sample rates are clamped between 0 and 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessLogOptions {
+    pub enabled: bool,
+    pub sample_rates: HashMap<AccessLogGroup, f64>,
+}
+
+impl SettingsOptions for AccessLogOptions {
+    type Settings = AccessLogSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&AccessLogSettings> {
+        settings.access_log.as_ref()
+    }
+
+    fn from_settings(settings: Option<&AccessLogSettings>) -> Self {
+        AccessLogOptions {
+            enabled: settings.is_some_and(|settings| settings.enabled.unwrap_or(true)),
+            sample_rates: settings
+                .and_then(|settings| settings.sample_rates.as_ref())
+                .map(|sample_rates| {
+                    sample_rates
+                        .iter()
+                        .map(|(group, rate)| (*group, rate.clamp(0.0, 1.0)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl AccessLogOptions {
+    /// Sample rate of a route group. The admin mutations are always logged.
+    pub fn sample_rate(&self, group: AccessLogGroup) -> f64 {
+        match group {
+            AccessLogGroup::AdminMutation => 1.0,
+            _ => self
+                .sample_rates
+                .get(&group)
+                .copied()
+                .unwrap_or(DEFAULT_SAMPLE_RATE),
+        }
+    }
+}
+
+/// Returns the route group of a request.
+pub fn route_group(method: &Method, route: &str) -> AccessLogGroup {
+    if route.starts_with("/api/v1.0/retrieval") {
+        AccessLogGroup::Retrieval
+    } else if route.starts_with("/api/v1.0/history") {
+        AccessLogGroup::History
+    } else if route.starts_with("/api/v1.1/admin") {
+        match *method {
+            Method::GET | Method::HEAD | Method::OPTIONS => AccessLogGroup::AdminRead,
+            _ => AccessLogGroup::AdminMutation,
+        }
+    } else {
+        AccessLogGroup::Other
+    }
+}
+
+/// Whether a request is logged, for a draw in `[0, 1)`. The server errors are always logged.
+fn is_sampled(sample_rate: f64, draw: f64, server_error: bool) -> bool {
+    server_error || draw < sample_rate
+}
+
+/// Middleware logging the summary of the sampled requests, once the response is built.
+pub async fn log_access(
+    ctx: Ctx,
+    State(app_state): State<Arc<AppState>>,
+    matched_path: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let options = app_state.options::<AccessLogOptions>();
+    if !options.enabled {
+        return next.run(request).await;
+    }
+    let route = matched_path
+        .map(|matched_path| matched_path.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let method = request.method().clone();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+
+    let group = route_group(&method, &route);
+    let status = response.status();
+    if is_sampled(
+        options.sample_rate(group),
+        rand::random::<f64>(),
+        status.is_server_error(),
+    ) {
+        let labels = app_state.deployment_labels();
+        info!(
+            target: ACCESS_LOG_TARGET,
+            route = route,
+            method = method.as_str(),
+            status = status.as_u16(),
+            latency_ms = latency_ms,
+            group = group.as_str(),
+            app_name = ctx.app_name,
+            reference_id = ctx.reference_id,
+            task_id = ctx.task_id,
+            deployment = labels.deployment,
+            environment = labels.environment,
+            region = labels.region,
+            message = format!("{} {} {} in {} ms", method, route, status.as_u16(), latency_ms)
+        );
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_route_group() {
+        assert_eq!(
+            route_group(&Method::POST, "/api/v1.0/retrieval"),
+            AccessLogGroup::Retrieval
+        );
+        assert_eq!(
+            route_group(&Method::GET, "/api/v1.0/history/retrieval"),
+            AccessLogGroup::History
+        );
+        assert_eq!(
+            route_group(&Method::GET, "/api/v1.1/admin/apps/:app_name"),
+            AccessLogGroup::AdminRead
+        );
+        assert_eq!(
+            route_group(&Method::DELETE, "/api/v1.1/admin/apps/:app_name"),
+            AccessLogGroup::AdminMutation
+        );
+        assert_eq!(
+            route_group(&Method::GET, "/api-doc/openapi.json"),
+            AccessLogGroup::Other
+        );
+    }
+
+    #[test]
+    fn test_success_access_log_options_from_settings() {
+        assert!(!AccessLogOptions::from_settings(None).enabled);
+
+        let settings = AccessLogSettings {
+            enabled: None,
+            sample_rates: Some(HashMap::from([
+                (AccessLogGroup::Retrieval, 0.1),
+                (AccessLogGroup::History, 2.0),
+                (AccessLogGroup::AdminMutation, 0.0),
+            ])),
+        };
+        let options = AccessLogOptions::from_settings(Some(&settings));
+        assert!(options.enabled);
+        assert_eq!(options.sample_rate(AccessLogGroup::Retrieval), 0.1);
+        assert_eq!(options.sample_rate(AccessLogGroup::History), 1.0);
+        assert_eq!(options.sample_rate(AccessLogGroup::AdminRead), 1.0);
+        // The admin mutations can't be sampled
+        assert_eq!(options.sample_rate(AccessLogGroup::AdminMutation), 1.0);
+    }
+
+    #[test]
+    fn test_success_is_sampled() {
+        assert!(is_sampled(0.1, 0.05, false));
+        assert!(!is_sampled(0.1, 0.5, false));
+        assert!(!is_sampled(0.0, 0.0, false));
+        assert!(is_sampled(0.0, 0.5, true));
+    }
+}
@@ -0,0 +1,284 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the max-answer-size policy of the history documents. A knowledge engine response whose
+//! history document is larger than `answer_offload.max_answer_bytes` (1 MiB by default, well below the 16 MiB limit
+//! of a DocumentDB document) is offloaded: the full response is stored in `answer_offload.bucket`, under the artifact
+//! prefix of the app (`{prefix}/{app_name}/`, `artifacts/{app_name}/` by default), encrypted like the history documents
+//! when encryption is enabled. The history document keeps the response, answer and snippets truncated to
+//! `answer_offload.truncated_bytes` (16 KiB by default), with `truncated: true` and the `full_content` pointer to the
+//! object. The history endpoint serves the full response with `full_content=true`.
+//! Without bucket, or if the object can't be stored, the history document is truncated without pointer rather than
+//! failing to be stored.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{AnswerOffloadSettings, TresleFacadeServiceSettings};
+use crate::retrieval::schema::history_document::{FullContent, HistoryDocument};
+use crate::service::encryption::EncryptionError;
+use crate::service::metrics::{MetricRecord, APP_NAME_DIMENSION};
+use crate::service::object_store::object_store;
+use crate::service::state::AppState;
+use tracing::{error, warn};
+
+/// Default size of a history document above which its response is offloaded, in bytes.
+const DEFAULT_MAX_ANSWER_BYTES: usize = 1_048_576;
+/// Default size the response, answer and snippets of an offloaded history document are truncated to, in bytes.
+const DEFAULT_TRUNCATED_BYTES: usize = 16_384;
+/// Default prefix of the artifacts of the apps in the bucket.
+const DEFAULT_ARTIFACT_PREFIX: &str = "artifacts";
+/// Marker appended to the truncated texts.
+const TRUNCATION_MARKER: &str = "…";
+
+#[derive(Debug, thiserror::Error)]
+pub enum AnswerOffloadError {
+    #[error("Invalid full content URI '{0}'.")]
+    InvalidUri(String),
+    #[error("Failed to access the full content: {0}")]
+    Store(String),
+    #[error("{0}")]
+    Encryption(#[from] EncryptionError),
+}
+
+/// Answer offload options: size limits and artifact location.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnswerOffloadOptions {
+    pub max_answer_bytes: usize,
+    pub truncated_bytes: usize,
+    /// Bucket of the full responses. The oversized history documents are only truncated without it.
+    pub bucket: Option<String>,
+    pub artifact_prefix: String,
+}
+
+impl SettingsOptions for AnswerOffloadOptions {
+    type Settings = AnswerOffloadSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&AnswerOffloadSettings> {
+        settings.answer_offload.as_ref()
+    }
+
+    fn from_settings(settings: Option<&AnswerOffloadSettings>) -> Self {
+        AnswerOffloadOptions {
+            max_answer_bytes: settings
+                .and_then(|settings| settings.max_answer_bytes)
+                .unwrap_or(DEFAULT_MAX_ANSWER_BYTES),
+            truncated_bytes: settings
+                .and_then(|settings| settings.truncated_bytes)
+                .unwrap_or(DEFAULT_TRUNCATED_BYTES),
+            bucket: settings.and_then(|settings| settings.bucket.clone()),
+            artifact_prefix: settings
+                .and_then(|settings| settings.prefix.clone())
+                .unwrap_or_else(|| DEFAULT_ARTIFACT_PREFIX.to_string()),
+        }
+    }
+}
+
+impl AnswerOffloadOptions {
+    /// Returns the prefix of the artifacts of an app in the bucket.
+    pub fn app_prefix(&self, app_name: &str) -> String {
+        format!(
+            "{}/{}/",
+            self.artifact_prefix.trim_end_matches('/'),
+            app_name
+        )
+    }
+
+    /// Returns the key of the full response of a retrieval.
+    fn answer_key(&self, app_name: &str, reference_id: &str) -> String {
+        format!("{}answers/{}", self.app_prefix(app_name), reference_id)
+    }
+}
+
+/// Truncates a text to at most `max_bytes` bytes, on a character boundary, with a trailing marker.
+pub fn truncate_text(text: &str, max_bytes: usize) -> String {
+    if text.len() <= max_bytes {
+        return text.to_string();
+    }
+    let mut end = max_bytes.saturating_sub(TRUNCATION_MARKER.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &text[..end], TRUNCATION_MARKER)
+}
+
+/// Returns the size of a history document as stored, in bytes.
+fn document_size(history_document: &HistoryDocument) -> usize {
+    serde_json::to_vec(history_document)
+        .map(|document| document.len())
+        .unwrap_or_default()
+}
+
+/// Truncates the texts of a history document: response, answer and citation snippets, also of its sub-queries.
+fn truncate_history_document(history_document: &mut HistoryDocument, max_bytes: usize) {
+    let mut texts = vec![&mut history_document.response];
+    texts.extend(history_document.answer.as_mut());
+    texts.extend(
+        history_document
+            .citations
+            .iter_mut()
+            .filter_map(|citation| citation.snippet.as_mut()),
+    );
+    for sub_query in history_document.sub_queries.iter_mut() {
+        texts.extend(sub_query.answer.as_mut());
+        texts.extend(
+            sub_query
+                .citations
+                .iter_mut()
+                .filter_map(|citation| citation.snippet.as_mut()),
+        );
+    }
+    for text in texts {
+        *text = truncate_text(text, max_bytes);
+    }
+    history_document.truncated = true;
+}
+
+/// Stores the full response of a history document in the bucket. Returns the pointer to the object.
+async fn store_full_response(
+    app_state: &AppState,
+    app_name: &str,
+    options: &AnswerOffloadOptions,
+    bucket: &str,
+    history_document: &HistoryDocument,
+) -> Result<FullContent, AnswerOffloadError> {
+    let response = &history_document.response;
+    let content_type = if serde_json::from_str::<serde_json::Value>(response).is_ok() {
+        "application/json"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+    let content = app_state.encrypt_field(app_name, response).await?;
+    let key = options.answer_key(app_name, &history_document.reference_id);
+    object_store(app_state)
+        .await
+        .connect(bucket)
+        .await
+        .map_err(AnswerOffloadError::Store)?
+        .write_object(bucket, &key, content.into_bytes(), content_type)
+        .await
+        .map_err(AnswerOffloadError::Store)?;
+    Ok(FullContent {
+        uri: format!("s3://{}/{}", bucket, key),
+        size_bytes: response.len() as u64,
+        content_type: content_type.to_string(),
+    })
+}
+
+/// Applies the max-answer-size policy to a history document before it is encrypted and stored.
+pub async fn offload_oversized_answer(
+    app_state: &AppState,
+    app_name: &str,
+    mut history_document: HistoryDocument,
+) -> HistoryDocument {
+    let options = app_state.options::<AnswerOffloadOptions>();
+    let size = document_size(&history_document);
+    if size <= options.max_answer_bytes {
+        return history_document;
+    }
+    app_state
+        .record_metric(
+            MetricRecord::counter("Oversized Answer Counter")
+                .dimension(APP_NAME_DIMENSION, app_name),
+        )
+        .await;
+
+    match &options.bucket {
+        Some(bucket) => {
+            match store_full_response(app_state, app_name, &options, bucket, &history_document)
+                .await
+            {
+                Ok(full_content) => history_document.full_content = Some(full_content),
+                Err(e) => error!(
+                    app_name = app_name,
+                    task_id = history_document.task_id,
+                    message = format!(
+                        "Failed to offload the {} bytes answer of retrieval '{}', it is stored truncated. Error: {}",
+                        size, history_document.reference_id, e
+                    )
+                ),
+            }
+        }
+        None => warn!(
+            app_name = app_name,
+            task_id = history_document.task_id,
+            message = format!(
+                "The {} bytes answer of retrieval '{}' is stored truncated, set answer_offload.bucket to keep it.",
+                size, history_document.reference_id
+            )
+        ),
+    }
+    truncate_history_document(&mut history_document, options.truncated_bytes);
+    history_document
+}
+
+/// Reads the full response of a history document from the bucket, decrypted.
+pub async fn read_full_content(
+    app_state: &AppState,
+    app_name: &str,
+    full_content: &FullContent,
+) -> Result<String, AnswerOffloadError> {
+    let (bucket, key) = full_content
+        .uri
+        .strip_prefix("s3://")
+        .and_then(|location| location.split_once('/'))
+        .ok_or_else(|| AnswerOffloadError::InvalidUri(full_content.uri.clone()))?;
+    let content = object_store(app_state)
+        .await
+        .connect(bucket)
+        .await
+        .map_err(AnswerOffloadError::Store)?
+        .read_object(bucket, key)
+        .await
+        .map_err(AnswerOffloadError::Store)?;
+    let content =
+        String::from_utf8(content).map_err(|e| AnswerOffloadError::Store(e.to_string()))?;
+    let mut content = serde_json::Value::String(content);
+    app_state.decrypt_fields(app_name, &mut content).await?;
+    Ok(content.as_str().unwrap_or_default().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_truncate_text() {
+        assert_eq!(truncate_text("short", 16), "short");
+        let truncated = truncate_text(&"a".repeat(100), 20);
+        assert!(truncated.len() <= 20);
+        assert!(truncated.ends_with(TRUNCATION_MARKER));
+        // Never splits a multi-byte character
+        let truncated = truncate_text(&"é".repeat(50), 20);
+        assert!(truncated.len() <= 20);
+        assert!(truncated
+            .trim_end_matches(TRUNCATION_MARKER)
+            .chars()
+            .all(|c| c == 'é'));
+    }
+
+    #[test]
+    fn test_success_truncate_history_document() {
+        let response = format!(r#"{{"answer": "{}"}}"#, "x".repeat(5_000));
+        let mut history_document = HistoryDocument::new(
+            "reference_id".to_string(),
+            "task_id".to_string(),
+            "query".to_string(),
+            response,
+            chrono::Utc::now(),
+            "disclaimer_text".to_string(),
+        );
+        truncate_history_document(&mut history_document, 1_000);
+        assert!(history_document.truncated);
+        assert!(history_document.response.len() <= 1_000);
+        assert!(history_document.answer.unwrap().len() <= 1_000);
+
+        let options = AnswerOffloadOptions::from_settings(None);
+        assert_eq!(options.app_prefix("app100"), "artifacts/app100/");
+        assert_eq!(
+            options.answer_key("app100", "ref-1"),
+            "artifacts/app100/answers/ref-1"
+        );
+    }
+}
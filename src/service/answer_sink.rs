@@ -0,0 +1,428 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the answer sinks of an app, stored on the app document and managed through the answer sink
+//! endpoints. Once the history document of a retrieval is created, it is mirrored to every sink of the app, so the
+//! customer systems consume the answers without polling the history endpoint.
+//! A sink is an SQS queue (`queue_url`), a Kafka topic (`topic`) or a webhook (`url`, signed and retried like the
+//! onboarding webhook). Its `filter` selects the answers it receives: `all` (by default), `successes` or `failures`.
+//! The mirrored message holds the app name, the outcome and the history document, without the stored request; the
+//! replays are not mirrored. A delivery failure is logged and counted by the `Answer Sink Delivery Counter` metric,
+//! it never fails the retrieval.
+//!
+
+use crate::retrieval::schema::history_document::HistoryDocument;
+use crate::service::metrics::{MetricRecord, APP_NAME_DIMENSION, STATUS_DIMENSION};
+use crate::service::onboarding_webhook::{deliver_webhook, validate_notification_url};
+use crate::service::publish_to_kafka::create_kafka_client;
+use crate::service::state::AppState;
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::{BehaviorVersion, Region};
+use axum::{http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+use utoipa::ToSchema;
+
+/// Field of the answer sinks of an app in the app document.
+pub const ANSWER_SINKS_FIELD: &str = "answer_sinks";
+/// Dimension holding the type of an answer sink.
+pub const SINK_TYPE_DIMENSION: &str = "sink_type";
+/// Maximum number of answer sinks of an app.
+pub const MAX_ANSWER_SINKS: usize = 10;
+/// Maximum length of a Kafka topic name.
+const MAX_TOPIC_NAME_LENGTH: usize = 249;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum AnswerSinkError {
+    #[error("Invalid answer sink name '{0}'. Names are made of letters, digits, '-' and '_'.")]
+    InvalidName(String),
+    #[error("Invalid answer sink '{name}': {message}")]
+    InvalidSink { name: String, message: String },
+    #[error("An app has at most {0} answer sinks.")]
+    TooManySinks(usize),
+    #[error("No answer sink found with name '{0}'.")]
+    UnknownSink(String),
+}
+
+impl From<AnswerSinkError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: AnswerSinkError) -> Self {
+        let status_code = match e {
+            AnswerSinkError::UnknownSink(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        let error_message = e.to_string();
+        debug!(message = error_message);
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Answers mirrored to a sink.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AnswerFilter {
+    #[default]
+    All,
+    /// The answered retrievals only.
+    Successes,
+    /// The failed retrievals only.
+    Failures,
+}
+
+impl AnswerFilter {
+    /// Whether an answer, failed or not, is mirrored.
+    pub fn matches(&self, failed: bool) -> bool {
+        match self {
+            AnswerFilter::All => true,
+            AnswerFilter::Successes => !failed,
+            AnswerFilter::Failures => failed,
+        }
+    }
+}
+
+/// Destination of an answer sink.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnswerSinkTarget {
+    /// SQS queue, e.g. `https://sqs.eu-west-1.amazonaws.com/123456789012/answers`.
+    Sqs {
+        queue_url: String,
+    },
+    Kafka {
+        topic: String,
+    },
+    Webhook {
+        url: String,
+    },
+}
+
+impl AnswerSinkTarget {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnswerSinkTarget::Sqs { .. } => "sqs",
+            AnswerSinkTarget::Kafka { .. } => "kafka",
+            AnswerSinkTarget::Webhook { .. } => "webhook",
+        }
+    }
+}
+
+/// Answer sink of an app.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct AnswerSink {
+    pub name: String,
+    #[serde(flatten)]
+    pub target: AnswerSinkTarget,
+    #[serde(default)]
+    pub filter: AnswerFilter,
+}
+
+impl AnswerSink {
+    /// Validates the name and the destination of the sink.
+    pub fn validate(&self) -> Result<(), AnswerSinkError> {
+        let valid_name = !self.name.is_empty()
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name {
+            return Err(AnswerSinkError::InvalidName(self.name.clone()));
+        }
+        let invalid = |message: &str| AnswerSinkError::InvalidSink {
+            name: self.name.clone(),
+            message: message.to_string(),
+        };
+        match &self.target {
+            AnswerSinkTarget::Sqs { queue_url } => {
+                if sqs_region(queue_url).is_none() {
+                    return Err(invalid(
+                        "queue_url must be an https SQS queue URL, e.g. https://sqs.<region>.amazonaws.com/<account>/<queue>.",
+                    ));
+                }
+            }
+            AnswerSinkTarget::Kafka { topic } => {
+                let valid_topic = !topic.is_empty()
+                    && topic.len() <= MAX_TOPIC_NAME_LENGTH
+                    && topic != "."
+                    && topic != ".."
+                    && topic
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+                if !valid_topic {
+                    return Err(invalid(
+                        "topic must be a Kafka topic name, made of letters, digits, '.', '_' and '-'.",
+                    ));
+                }
+            }
+            AnswerSinkTarget::Webhook { url } => {
+                validate_notification_url(Some(url))
+                    .map_err(|_| invalid("url must be an https URL of a public host."))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the region of an SQS queue URL, `None` if it isn't one.
+pub fn sqs_region(queue_url: &str) -> Option<String> {
+    let url = url::Url::parse(queue_url).ok()?;
+    if url.scheme() != "https" || url.path_segments()?.filter(|s| !s.is_empty()).count() != 2 {
+        return None;
+    }
+    let host = url.host_str()?;
+    let region = host.strip_prefix("sqs.")?.split('.').next()?;
+    (!region.is_empty() && host.contains(".amazonaws.com")).then(|| region.to_string())
+}
+
+/// Message mirrored to the answer sinks.
+#[derive(Debug, Clone, Serialize)]
+pub struct AnswerMessage<'a> {
+    pub app_name: &'a str,
+    pub reference_id: &'a str,
+    /// `succeeded` or `failed`.
+    pub status: &'static str,
+    pub history: &'a HistoryDocument,
+}
+
+/// Mirrors a created history document to the answer sinks of its app, in the background. The replays and the
+/// sandbox retrievals are not mirrored.
+pub fn mirror_answer(app_state: &Arc<AppState>, app_name: &str, history_document: HistoryDocument) {
+    if history_document.replay_of.is_some() || history_document.sandbox {
+        return;
+    }
+    tokio::spawn(deliver_answer(
+        Arc::clone(app_state),
+        app_name.to_string(),
+        history_document,
+    ));
+}
+
+/// Delivers a history document to the answer sinks of its app whose filter matches it.
+#[instrument(skip_all)]
+async fn deliver_answer(
+    app_state: Arc<AppState>,
+    app_name: String,
+    mut history_document: HistoryDocument,
+) {
+    let sinks = match app_state.apps().answer_sinks(&app_name).await {
+        Ok(sinks) => sinks,
+        Err(e) => {
+            error!(
+                app_name = app_name,
+                message = format!("Failed to fetch the answer sinks. Error: {}", e)
+            );
+            return;
+        }
+    };
+    let failed = history_document.timestamp.is_failed();
+    let sinks: Vec<&AnswerSink> = sinks
+        .iter()
+        .filter(|sink| sink.filter.matches(failed))
+        .collect();
+    if sinks.is_empty() {
+        return;
+    }
+
+    // The stored request is kept for the replays only
+    history_document.request = None;
+    let message = AnswerMessage {
+        app_name: &app_name,
+        reference_id: &history_document.reference_id,
+        status: if failed { "failed" } else { "succeeded" },
+        history: &history_document,
+    };
+    let payload = match serde_json::to_string(&message) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!(
+                app_name = app_name,
+                message = format!("Failed to serialize the answer. Error: {}", e)
+            );
+            return;
+        }
+    };
+    for sink in sinks {
+        let result = match &sink.target {
+            AnswerSinkTarget::Sqs { queue_url } => send_to_sqs(queue_url, &payload).await,
+            AnswerSinkTarget::Kafka { topic } => {
+                match create_kafka_client(&app_state, &app_name).await {
+                    Ok(producer) => producer
+                        .produce(topic, &history_document.reference_id, &payload)
+                        .await
+                        .map(|_| ()),
+                    Err((_, Json(body))) => {
+                        Err(body["message"].as_str().unwrap_or_default().to_string())
+                    }
+                }
+            }
+            AnswerSinkTarget::Webhook { url } => {
+                if deliver_webhook(
+                    &app_state,
+                    url,
+                    &app_name,
+                    &history_document.task_id,
+                    "answer",
+                    &message,
+                )
+                .await
+                {
+                    Ok(())
+                } else {
+                    Err(format!("Failed to deliver the answer to '{}'.", url))
+                }
+            }
+        };
+        let status = match &result {
+            Ok(()) => {
+                info!(
+                    app_name = app_name,
+                    task_id = history_document.task_id,
+                    message = format!(
+                        "Answer '{}' mirrored to sink '{}'.",
+                        history_document.reference_id, sink.name
+                    )
+                );
+                "delivered"
+            }
+            Err(e) => {
+                let error_message = format!(
+                    "Failed to mirror answer '{}' to sink '{}'. Error: {}",
+                    history_document.reference_id, sink.name, e
+                );
+                error!(
+                    app_name = app_name,
+                    task_id = history_document.task_id,
+                    ext_message = error_message,
+                    message = error_message
+                );
+                "failed"
+            }
+        };
+        app_state
+            .record_metric(
+                MetricRecord::counter("Answer Sink Delivery Counter")
+                    .dimension(APP_NAME_DIMENSION, &app_name)
+                    .dimension(SINK_TYPE_DIMENSION, sink.target.as_str())
+                    .dimension(STATUS_DIMENSION, status),
+            )
+            .await;
+    }
+}
+
+/// Sends a message to an SQS queue, with a client of the region of the queue.
+async fn send_to_sqs(queue_url: &str, payload: &str) -> Result<(), String> {
+    let region =
+        sqs_region(queue_url).ok_or_else(|| format!("Invalid queue URL '{}'.", queue_url))?;
+    let region_provider = RegionProviderChain::first_try(Region::new(region));
+    let config = aws_config::defaults(BehaviorVersion::latest())
+        .region(region_provider)
+        .load()
+        .await;
+    aws_sdk_sqs::Client::new(&config)
+        .send_message()
+        .queue_url(queue_url)
+        .message_body(payload)
+        .send()
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sink(target: AnswerSinkTarget) -> AnswerSink {
+        AnswerSink {
+            name: "crm".to_string(),
+            target,
+            filter: AnswerFilter::All,
+        }
+    }
+
+    #[test]
+    fn test_success_answer_sink_validate() {
+        assert!(sink(AnswerSinkTarget::Sqs {
+            queue_url: "https://sqs.eu-west-1.amazonaws.com/123456789012/answers".to_string()
+        })
+        .validate()
+        .is_ok());
+        assert!(sink(AnswerSinkTarget::Kafka {
+            topic: "crm.answers".to_string()
+        })
+        .validate()
+        .is_ok());
+        assert!(sink(AnswerSinkTarget::Webhook {
+            url: "https://crm.example.com/hooks/answers".to_string()
+        })
+        .validate()
+        .is_ok());
+
+        // The sinks are stored with their type
+        let stored: AnswerSink = serde_json::from_value(json!({
+            "name": "crm",
+            "type": "kafka",
+            "topic": "crm.answers",
+            "filter": "failures"
+        }))
+        .unwrap();
+        assert_eq!(stored.filter, AnswerFilter::Failures);
+        assert_eq!(stored.target.as_str(), "kafka");
+    }
+
+    #[test]
+    fn test_failure_answer_sink_validate() {
+        let mut invalid = sink(AnswerSinkTarget::Kafka {
+            topic: "crm answers".to_string(),
+        });
+        assert!(matches!(
+            invalid.validate(),
+            Err(AnswerSinkError::InvalidSink { .. })
+        ));
+        invalid.name = "crm sink".to_string();
+        assert_eq!(
+            invalid.validate(),
+            Err(AnswerSinkError::InvalidName("crm sink".to_string()))
+        );
+        assert!(sink(AnswerSinkTarget::Sqs {
+            queue_url: "https://example.com/123456789012/answers".to_string()
+        })
+        .validate()
+        .is_err());
+        assert!(sink(AnswerSinkTarget::Webhook {
+            url: "ftp://crm.example.com".to_string()
+        })
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_success_answer_filter_matches() {
+        assert!(AnswerFilter::All.matches(true));
+        assert!(AnswerFilter::Successes.matches(false));
+        assert!(!AnswerFilter::Successes.matches(true));
+        assert!(AnswerFilter::Failures.matches(true));
+        assert!(!AnswerFilter::Failures.matches(false));
+    }
+
+    #[test]
+    fn test_success_sqs_region() {
+        assert_eq!(
+            sqs_region("https://sqs.us-east-2.amazonaws.com/123456789012/answers"),
+            Some("us-east-2".to_string())
+        );
+        assert_eq!(
+            sqs_region("http://sqs.us-east-2.amazonaws.com/1/answers"),
+            None
+        );
+        assert_eq!(
+            sqs_region("https://sqs.us-east-2.amazonaws.com/answers"),
+            None
+        );
+    }
+}
@@ -0,0 +1,356 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the guarded sub-router of the API docs, the Swagger UI (`/swagger-ui`) and the OpenAPI
+//! document (`/api-doc/openapi.json`).
+//! `api_docs.mode` selects how they are served: `public`, `protected` or `disabled`. Without it, they are public in
+//! the local and dev environments (an `environment` or `env_identifier` starting with `local` or `dev`) and protected
+//! elsewhere, so an unknown or misspelled environment never exposes them.
+//! In the `protected` mode, the requests must carry the basic credentials `api_docs.basic_auth_username` and
+//! `api_docs.basic_auth_password`, or a bearer JWT signed with HS256 by `api_docs.jwt_secret`, with an `exp` claim not
+//! passed yet. The API docs are not mounted when protected without credentials.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{ApiDocsMode, ApiDocsSettings, TresleFacadeServiceSettings};
+use crate::service::state::AppState;
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use secrecy::ExposeSecret;
+use serde_json::json;
+use sha2::Sha256;
+use std::sync::Arc;
+use tracing::{debug, error, info};
+use utoipa_swagger_ui::SwaggerUi;
+
+/// Path of the Swagger UI.
+pub const SWAGGER_UI_PATH: &str = "/swagger-ui";
+/// Path of the OpenAPI document.
+pub const OPENAPI_PATH: &str = "/api-doc/openapi.json";
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ApiDocsError {
+    #[error("Authentication required to access the API docs.")]
+    Unauthorized,
+}
+
+impl From<ApiDocsError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: ApiDocsError) -> Self {
+        let error_message = e.to_string();
+        debug!(message = error_message);
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// API docs options: mode and credentials.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiDocsOptions {
+    pub mode: ApiDocsMode,
+    pub basic_auth: Option<(String, String)>,
+    pub jwt_secret: Option<String>,
+}
+
+/// Mode of the API docs without `api_docs.mode`: public in the local and dev environments, protected elsewhere.
+fn default_mode(environment: &str, env_identifier: &str) -> ApiDocsMode {
+    let development = [environment, env_identifier].iter().any(|name| {
+        let name = name.to_lowercase();
+        name.starts_with("local") || name.starts_with("dev")
+    });
+    if development {
+        ApiDocsMode::Public
+    } else {
+        ApiDocsMode::Protected
+    }
+}
+
+impl SettingsOptions for ApiDocsOptions {
+    type Settings = ApiDocsSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&ApiDocsSettings> {
+        settings.api_docs.as_ref()
+    }
+
+    /// Without mode, the API docs are protected; `resolve` makes them public in the local and dev environments.
+    fn from_settings(settings: Option<&ApiDocsSettings>) -> Self {
+        ApiDocsOptions {
+            mode: settings
+                .and_then(|settings| settings.mode)
+                .unwrap_or(ApiDocsMode::Protected),
+            basic_auth: settings.and_then(|settings| {
+                Some((
+                    settings.basic_auth_username.clone()?,
+                    settings
+                        .basic_auth_password
+                        .as_ref()?
+                        .expose_secret()
+                        .clone(),
+                ))
+            }),
+            jwt_secret: settings
+                .and_then(|settings| settings.jwt_secret.as_ref())
+                .map(|secret| secret.expose_secret().clone()),
+        }
+    }
+
+    fn resolve(app_settings: &TresleFacadeServiceSettings) -> Self {
+        let settings = Self::section(app_settings);
+        let mut options = Self::from_settings(settings);
+        if settings.and_then(|settings| settings.mode).is_none() {
+            options.mode = default_mode(&app_settings.environment, &app_settings.env_identifier);
+        }
+        options
+    }
+}
+
+impl ApiDocsOptions {
+    /// Whether the API docs are mounted. The protected API docs need credentials.
+    pub fn is_mounted(&self) -> bool {
+        match self.mode {
+            ApiDocsMode::Public => true,
+            ApiDocsMode::Protected => self.basic_auth.is_some() || self.jwt_secret.is_some(),
+            ApiDocsMode::Disabled => false,
+        }
+    }
+
+    /// Checks the credentials of an API docs request, in the protected mode.
+    pub fn authorize(&self, headers: &HeaderMap) -> Result<(), ApiDocsError> {
+        if self.mode != ApiDocsMode::Protected {
+            return Ok(());
+        }
+        let authorization = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let authorized = match (
+            authorization.split_once(' '),
+            &self.basic_auth,
+            &self.jwt_secret,
+        ) {
+            (Some(("Basic", credentials)), Some((username, password)), _) => STANDARD
+                .decode(credentials.trim())
+                .ok()
+                .and_then(|credentials| String::from_utf8(credentials).ok())
+                .is_some_and(|credentials| {
+                    constant_time_eq(
+                        credentials.as_bytes(),
+                        format!("{}:{}", username, password).as_bytes(),
+                    )
+                }),
+            (Some(("Bearer", token)), _, Some(jwt_secret)) => verify_jwt(token.trim(), jwt_secret),
+            _ => false,
+        };
+        if authorized {
+            Ok(())
+        } else {
+            Err(ApiDocsError::Unauthorized)
+        }
+    }
+}
+
+/// Compares two byte strings in a time independent of their content.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Verifies a JWT signed with HS256 by `secret`. The token must carry an `exp` claim, and is valid until then.
+fn verify_jwt(token: &str, secret: &str) -> bool {
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+    let decode_json = |part: &str| {
+        URL_SAFE_NO_PAD
+            .decode(part)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<serde_json::Value>(&bytes).ok())
+    };
+    let is_hs256 = decode_json(header).is_some_and(|header| header["alg"] == "HS256");
+    let Ok(signature) = URL_SAFE_NO_PAD.decode(signature) else {
+        return false;
+    };
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(format!("{}.{}", header, payload).as_bytes());
+    if !is_hs256 || mac.verify_slice(&signature).is_err() {
+        return false;
+    }
+    decode_json(payload).is_some_and(|claims| {
+        claims["exp"]
+            .as_i64()
+            .is_some_and(|exp| exp > Utc::now().timestamp())
+    })
+}
+
+/// Middleware rejecting the API docs requests without valid credentials.
+async fn authorize_api_docs(
+    State(app_state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let options = app_state.options::<ApiDocsOptions>();
+    match options.authorize(request.headers()) {
+        Ok(()) => next.run(request).await,
+        Err(e) => {
+            let mut response = <(StatusCode, Json<serde_json::Value>)>::from(e).into_response();
+            if options.basic_auth.is_some() {
+                response.headers_mut().insert(
+                    WWW_AUTHENTICATE,
+                    HeaderValue::from_static("Basic realm=\"api-docs\""),
+                );
+            }
+            response
+        }
+    }
+}
+
+/// Builds the sub-router of the API docs, empty when they are not mounted.
+pub fn api_docs_router(app_state: Arc<AppState>, openapi: utoipa::openapi::OpenApi) -> Router {
+    let options = app_state.options::<ApiDocsOptions>();
+    if !options.is_mounted() {
+        if options.mode == ApiDocsMode::Protected {
+            error!(
+                message =
+                    "API docs protected without basic credentials or JWT secret, not mounted."
+            );
+        } else {
+            info!(message = "API docs disabled.");
+        }
+        return Router::new();
+    }
+    let router: Router = SwaggerUi::new(SWAGGER_UI_PATH)
+        .url(OPENAPI_PATH, openapi)
+        .into();
+    match options.mode {
+        ApiDocsMode::Protected => router.layer(middleware::from_fn_with_state(
+            app_state,
+            authorize_api_docs,
+        )),
+        _ => router,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protected_options() -> ApiDocsOptions {
+        ApiDocsOptions {
+            mode: ApiDocsMode::Protected,
+            basic_auth: Some(("docs".to_string(), "s3cret".to_string())),
+            jwt_secret: Some("jwt-secret".to_string()),
+        }
+    }
+
+    fn jwt(secret: &str, claims: serde_json::Value) -> String {
+        let header = URL_SAFE_NO_PAD.encode(json!({"alg": "HS256", "typ": "JWT"}).to_string());
+        let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{}.{}", header, payload).as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{}.{}.{}", header, payload, signature)
+    }
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_success_api_docs_authorize() {
+        let options = protected_options();
+        let basic = format!("Basic {}", STANDARD.encode("docs:s3cret"));
+        assert!(options.authorize(&headers(&basic)).is_ok());
+        let token = jwt(
+            "jwt-secret",
+            json!({"sub": "qa", "exp": Utc::now().timestamp() + 60}),
+        );
+        assert!(options
+            .authorize(&headers(&format!("Bearer {}", token)))
+            .is_ok());
+
+        // The public API docs need no credentials
+        let options = ApiDocsOptions {
+            mode: ApiDocsMode::Public,
+            ..protected_options()
+        };
+        assert!(options.authorize(&HeaderMap::new()).is_ok());
+    }
+
+    #[test]
+    fn test_failure_api_docs_authorize() {
+        let options = protected_options();
+        assert_eq!(
+            options.authorize(&HeaderMap::new()),
+            Err(ApiDocsError::Unauthorized)
+        );
+        let basic = format!("Basic {}", STANDARD.encode("docs:wrong"));
+        assert!(options.authorize(&headers(&basic)).is_err());
+        let expired = jwt("jwt-secret", json!({"exp": Utc::now().timestamp() - 60}));
+        assert!(options
+            .authorize(&headers(&format!("Bearer {}", expired)))
+            .is_err());
+        // A token without expiry is rejected
+        let unexpiring = jwt("jwt-secret", json!({"sub": "qa"}));
+        assert!(options
+            .authorize(&headers(&format!("Bearer {}", unexpiring)))
+            .is_err());
+        let forged = jwt(
+            "other-secret",
+            json!({"sub": "qa", "exp": Utc::now().timestamp() + 60}),
+        );
+        assert!(options
+            .authorize(&headers(&format!("Bearer {}", forged)))
+            .is_err());
+    }
+
+    #[test]
+    fn test_success_api_docs_default_mode() {
+        assert_eq!(default_mode("Development", "dev"), ApiDocsMode::Public);
+        assert_eq!(default_mode("Local", "qa"), ApiDocsMode::Public);
+        assert_eq!(default_mode("Staging", "stg"), ApiDocsMode::Protected);
+        assert_eq!(default_mode("Production", "prod"), ApiDocsMode::Protected);
+        // An unknown or misspelled environment is protected too
+        assert_eq!(default_mode("", "prd"), ApiDocsMode::Protected);
+        // Without the environment, e.g. resolved from the section only
+        assert_eq!(
+            ApiDocsOptions::from_settings(None).mode,
+            ApiDocsMode::Protected
+        );
+    }
+
+    #[test]
+    fn test_success_api_docs_is_mounted() {
+        let options = ApiDocsOptions {
+            mode: ApiDocsMode::Protected,
+            basic_auth: None,
+            jwt_secret: None,
+        };
+        assert!(!options.is_mounted());
+        assert!(protected_options().is_mounted());
+        let options = ApiDocsOptions {
+            mode: ApiDocsMode::Disabled,
+            ..protected_options()
+        };
+        assert!(!options.is_mounted());
+    }
+}
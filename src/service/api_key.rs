@@ -0,0 +1,262 @@
+/*
+ * Created Date:  Jul 18, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the issuance of the API keys of the apps. By default (`api_keys.mode: api_gateway`) the keys
+//! are created in AWS API Gateway, which validates them and meters their usage through its usage plans.
+//! With `api_keys.mode: internal` the facade issues the keys itself, so the service runs in non-AWS environments and
+//! in local development without API Gateway: a key is generated randomly, only its SHA-256 hash is stored as the
+//! `api_key` of the app document, and the retrieval endpoints find the app of a key by its hash. The usage plans are
+//! not used, the calls made with the keys are tracked in the `api_keys.usage_collection` collection instead, one
+//! document per call.
+//! The plain key is only returned when the app is onboarded.
+//! In both modes the keys can expire: every `api_keys.expiry_interval_seconds` the expired keys are deactivated, after
+//! their expiry was warned `api_keys.expiry_warning_days` ahead (see `key_expiry`).
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{ApiKeyMode, ApiKeySettings, TresleFacadeServiceSettings};
+use crate::service::query_options::{AggregateExt, QueryError, QueryOptions};
+use crate::service::state::AppState;
+use axum::{http::StatusCode, Json};
+use chrono::{Duration, Utc};
+use mongodb::bson::doc;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::time::Duration as StdDuration;
+use tracing::error;
+use utoipa::ToSchema;
+
+/// Default collection of the API key usage documents.
+pub const DEFAULT_USAGE_COLLECTION: &str = "api-key-usage";
+/// Prefix of the stored hashes of the API keys.
+pub const API_KEY_HASH_PREFIX: &str = "sha256:";
+/// Default number of seconds between two runs of the key expiry job.
+const DEFAULT_EXPIRY_INTERVAL_SECONDS: u64 = 3_600;
+/// Default number of days before its expiry date the expiry of a key is warned.
+const DEFAULT_EXPIRY_WARNING_DAYS: i64 = 7;
+/// Length of a generated API key, as the keys of API Gateway.
+const API_KEY_LENGTH: usize = 40;
+/// Length of a generated API key ID, as the key IDs of API Gateway.
+const API_KEY_ID_LENGTH: usize = 10;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiKeyUsageError {
+    #[error("API key usage is only tracked in the internal API key mode, API Gateway meters it otherwise.")]
+    NotTracked,
+    #[error("{0}")]
+    Query(QueryError),
+}
+
+impl From<ApiKeyUsageError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: ApiKeyUsageError) -> Self {
+        let status_code = match e {
+            ApiKeyUsageError::NotTracked => StatusCode::BAD_REQUEST,
+            ApiKeyUsageError::Query(ref e) => e.status_code(),
+        };
+        let error_message = e.to_string();
+        error!(ext_message = error_message, message = error_message);
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// API key options: issuing mode, usage collection and expiry job timing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKeyOptions {
+    pub mode: ApiKeyMode,
+    pub usage_collection: String,
+    pub expiry_interval: StdDuration,
+    pub expiry_warning: Duration,
+}
+
+impl Default for ApiKeyOptions {
+    fn default() -> Self {
+        ApiKeyOptions {
+            mode: ApiKeyMode::default(),
+            usage_collection: DEFAULT_USAGE_COLLECTION.to_string(),
+            expiry_interval: StdDuration::from_secs(DEFAULT_EXPIRY_INTERVAL_SECONDS),
+            expiry_warning: Duration::days(DEFAULT_EXPIRY_WARNING_DAYS),
+        }
+    }
+}
+
+impl SettingsOptions for ApiKeyOptions {
+    type Settings = ApiKeySettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&ApiKeySettings> {
+        settings.api_keys.as_ref()
+    }
+
+    fn from_settings(settings: Option<&ApiKeySettings>) -> Self {
+        let defaults = ApiKeyOptions::default();
+        let Some(settings) = settings else {
+            return defaults;
+        };
+        ApiKeyOptions {
+            mode: settings.mode.unwrap_or(defaults.mode),
+            usage_collection: settings
+                .usage_collection
+                .clone()
+                .unwrap_or(defaults.usage_collection),
+            expiry_interval: settings
+                .expiry_interval_seconds
+                .filter(|seconds| *seconds > 0)
+                .map(StdDuration::from_secs)
+                .unwrap_or(defaults.expiry_interval),
+            expiry_warning: settings
+                .expiry_warning_days
+                .map(|days| Duration::days(days.max(0)))
+                .unwrap_or(defaults.expiry_warning),
+        }
+    }
+}
+
+impl ApiKeyOptions {
+    /// True if the facade issues the API keys itself.
+    pub fn is_internal(&self) -> bool {
+        self.mode == ApiKeyMode::Internal
+    }
+
+    /// Returns the `api_key` of the app document matching a key presented by a caller.
+    pub fn stored_api_key(&self, api_key: &str) -> String {
+        match self.mode {
+            ApiKeyMode::ApiGateway => api_key.to_string(),
+            ApiKeyMode::Internal => hash_api_key(api_key),
+        }
+    }
+}
+
+/// Generates an API key and its ID.
+pub fn generate_api_key() -> (String, String) {
+    let mut rng = rand::thread_rng();
+    let api_key = Alphanumeric.sample_string(&mut rng, API_KEY_LENGTH);
+    let api_key_id = Alphanumeric
+        .sample_string(&mut rng, API_KEY_ID_LENGTH)
+        .to_lowercase();
+    (api_key, api_key_id)
+}
+
+/// Returns the stored hash of an API key.
+pub fn hash_api_key(api_key: &str) -> String {
+    format!(
+        "{}{:x}",
+        API_KEY_HASH_PREFIX,
+        Sha256::digest(api_key.as_bytes())
+    )
+}
+
+/// Number of calls made with the API key of an app on a day.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct DailyApiKeyUsage {
+    /// Day (UTC, `YYYY-MM-DD`).
+    pub day: String,
+    pub calls: u64,
+}
+
+/// Records a call made with the API key of an app, in the internal API key mode. Failures are logged and never fail
+/// the caller.
+pub async fn record_api_key_usage(app_state: &AppState, app_name: &str, endpoint: &str) {
+    let options = app_state.api_key_options();
+    if !options.is_internal() {
+        return;
+    }
+    let now = Utc::now();
+    let document = doc! {
+        "app_name": app_name,
+        "endpoint": endpoint,
+        "day": now.format("%Y-%m-%d").to_string(),
+        "timestamp": now.to_rfc3339(),
+    };
+    if let Err(e) = app_state
+        .db
+        .create_document(&options.usage_collection, document)
+        .await
+    {
+        error!(
+            app_name = app_name,
+            message = format!("Failed to record the API key usage. Error: {}", e)
+        );
+    }
+}
+
+/// Returns the daily calls made with the API key of an app over the last `days` days, oldest first. Days without
+/// calls are left out.
+pub async fn api_key_usage(
+    app_state: &AppState,
+    app_name: &str,
+    days: u32,
+) -> Result<Vec<DailyApiKeyUsage>, ApiKeyUsageError> {
+    let options = app_state.api_key_options();
+    if !options.is_internal() {
+        return Err(ApiKeyUsageError::NotTracked);
+    }
+    let first_day = (Utc::now() - Duration::days(days.saturating_sub(1) as i64))
+        .format("%Y-%m-%d")
+        .to_string();
+    let pipeline = vec![
+        doc! {"$match": {"app_name": app_name, "day": {"$gte": first_day}}},
+        doc! {"$group": {"_id": "$day", "calls": {"$sum": 1}}},
+        doc! {"$project": {"_id": 0, "day": "$_id", "calls": 1}},
+        doc! {"$sort": {"day": 1}},
+    ];
+    let usage = app_state
+        .db
+        .aggregate(
+            &options.usage_collection,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await
+        .map_err(ApiKeyUsageError::Query)?;
+    Ok(usage
+        .into_iter()
+        .filter_map(|day| serde_json::from_value(day).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_generate_and_hash_api_key() {
+        let (api_key, api_key_id) = generate_api_key();
+        assert_eq!(api_key.len(), API_KEY_LENGTH);
+        assert_eq!(api_key_id.len(), API_KEY_ID_LENGTH);
+        assert_ne!(generate_api_key().0, api_key);
+
+        let hash = hash_api_key("1ytmOsUYKI2ZGg7WzzSfH3YU87i6UtZ50uMgVCc5");
+        assert!(hash.starts_with(API_KEY_HASH_PREFIX));
+        assert_eq!(hash.len(), API_KEY_HASH_PREFIX.len() + 64);
+        assert_eq!(
+            hash,
+            hash_api_key("1ytmOsUYKI2ZGg7WzzSfH3YU87i6UtZ50uMgVCc5")
+        );
+    }
+
+    #[test]
+    fn test_success_api_key_options_from_settings() {
+        assert_eq!(ApiKeyOptions::from_settings(None), ApiKeyOptions::default());
+        let options = ApiKeyOptions::from_settings(Some(&ApiKeySettings {
+            mode: Some(ApiKeyMode::Internal),
+            usage_collection: None,
+            expiry_interval_seconds: Some(0),
+            expiry_warning_days: Some(14),
+        }));
+        assert!(options.is_internal());
+        assert_eq!(options.usage_collection, DEFAULT_USAGE_COLLECTION);
+        assert_eq!(
+            options.expiry_interval,
+            StdDuration::from_secs(DEFAULT_EXPIRY_INTERVAL_SECONDS)
+        );
+        assert_eq!(options.expiry_warning, Duration::days(14));
+        assert_eq!(options.stored_api_key("key"), hash_api_key("key"));
+        assert_eq!(ApiKeyOptions::default().stored_api_key("key"), "key");
+    }
+}
@@ -0,0 +1,422 @@
+/*
+ * Created Date:  Aug 2, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the in-memory cache of the API key lookups of the retrievals and its invalidation across the
+//! replicas.
+//! With `app_cache.enabled`, the app document matched by an API key is kept for `ttl_seconds`, so the retrievals no
+//! longer query the app collection for every request. The expiry dates of the keys are still checked on every lookup,
+//! and unknown keys are never cached, so a new key works right away.
+//! A rotated, revoked, deactivated or deleted key must not be served from the cache of another replica:
+//! - every replica watches the app collection through a change stream, evicting the keys of an updated app and
+//!   emptying the cache on a delete, or on a failure of the change stream since changes may have been missed;
+//! - every change of the API keys of an app (`invalidate_cached_app`) bumps a version document, polled by the replicas
+//!   whose change stream can't be opened, or all of them with `invalidation: version`; a new version empties the cache.
+//!
+//! The TTL bounds the staleness if an invalidation is missed altogether.
+//!
+
+use crate::admin_ui_api::schema::UpdateResponse;
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{
+    AppCacheInvalidation, AppCacheSettings, TresleFacadeServiceSettings,
+};
+use crate::service::driver::DriverError;
+use crate::service::state::AppState;
+use futures::StreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::change_stream::event::{OperationType, ResumeToken};
+use mongodb::options::{ChangeStreamOptions, FullDocumentType};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// Default collection of the version document of the cache.
+pub const DEFAULT_VERSION_COLLECTION: &str = "app-cache-version";
+/// `_id` of the version document of the cache.
+const VERSION_DOCUMENT_ID: &str = "app_cache";
+const DEFAULT_TTL_SECONDS: u64 = 60;
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+const DEFAULT_VERSION_POLL_INTERVAL_MS: u64 = 5_000;
+/// Delay before a failed change stream is opened again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// API key lookup cache options: activation, TTL, capacity and invalidation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppCacheOptions {
+    pub enabled: bool,
+    pub ttl: Duration,
+    pub max_entries: usize,
+    pub invalidation: AppCacheInvalidation,
+    pub version_collection: String,
+    pub version_poll_interval: Duration,
+}
+
+impl SettingsOptions for AppCacheOptions {
+    type Settings = AppCacheSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&AppCacheSettings> {
+        settings.app_cache.as_ref()
+    }
+
+    fn from_settings(settings: Option<&AppCacheSettings>) -> Self {
+        AppCacheOptions {
+            enabled: settings
+                .and_then(|settings| settings.enabled)
+                .unwrap_or(false),
+            ttl: Duration::from_secs(
+                settings
+                    .and_then(|settings| settings.ttl_seconds)
+                    .unwrap_or(DEFAULT_TTL_SECONDS),
+            ),
+            max_entries: settings
+                .and_then(|settings| settings.max_entries)
+                .filter(|max_entries| *max_entries > 0)
+                .unwrap_or(DEFAULT_MAX_ENTRIES),
+            invalidation: settings
+                .and_then(|settings| settings.invalidation)
+                .unwrap_or_default(),
+            version_collection: settings
+                .and_then(|settings| settings.version_collection.clone())
+                .unwrap_or_else(|| DEFAULT_VERSION_COLLECTION.to_string()),
+            version_poll_interval: Duration::from_millis(
+                settings
+                    .and_then(|settings| settings.version_poll_interval_ms)
+                    .filter(|interval| *interval > 0)
+                    .unwrap_or(DEFAULT_VERSION_POLL_INTERVAL_MS),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CachedApp {
+    app_name: String,
+    document: serde_json::Value,
+    cached_at: Instant,
+}
+
+/// App documents by the stored form of the API keys matching them.
+#[derive(Debug)]
+pub struct AppCache {
+    enabled: bool,
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, CachedApp>>,
+}
+
+impl AppCache {
+    pub fn new(options: &AppCacheOptions) -> Self {
+        AppCache {
+            enabled: options.enabled && !options.ttl.is_zero(),
+            ttl: options.ttl,
+            max_entries: options.max_entries,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the app document cached for a stored API key, unless it expired.
+    pub fn get(&self, stored_api_key: &str) -> Option<serde_json::Value> {
+        if !self.enabled {
+            return None;
+        }
+        let mut entries = self.entries.lock().ok()?;
+        match entries.get(stored_api_key) {
+            Some(cached) if cached.cached_at.elapsed() < self.ttl => Some(cached.document.clone()),
+            Some(_) => {
+                entries.remove(stored_api_key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Caches the app document matched by a stored API key. A full cache is emptied first.
+    pub fn insert(&self, stored_api_key: &str, app_name: &str, document: &serde_json::Value) {
+        if !self.enabled {
+            return;
+        }
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.len() >= self.max_entries {
+                entries.clear();
+            }
+            entries.insert(
+                stored_api_key.to_string(),
+                CachedApp {
+                    app_name: app_name.to_string(),
+                    document: document.clone(),
+                    cached_at: Instant::now(),
+                },
+            );
+        }
+    }
+
+    /// Evicts the keys of an app.
+    pub fn invalidate_app(&self, app_name: &str) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.retain(|_, cached| cached.app_name != app_name);
+        }
+    }
+
+    /// Evicts every key.
+    pub fn clear(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.clear();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .map(|entries| entries.len())
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Applies a change of the app collection to the cache: the keys of an updated app are evicted, any other change
+/// empties the cache.
+pub fn apply_app_change(
+    cache: &AppCache,
+    operation_type: &OperationType,
+    full_document: Option<&Document>,
+) {
+    let app_name = full_document.and_then(|document| document.get_str("app_name").ok());
+    match (operation_type, app_name) {
+        (
+            OperationType::Insert | OperationType::Update | OperationType::Replace,
+            Some(app_name),
+        ) => cache.invalidate_app(app_name),
+        _ => cache.clear(),
+    }
+}
+
+/// Evicts the keys of an app from the cache of this replica and bumps the version document for the other replicas.
+/// Called on every change of the API keys of an app. A failure is logged, the TTL bounding the staleness.
+pub async fn invalidate_cached_app(app_state: &AppState, app_name: &str) {
+    let options = app_state.options::<AppCacheOptions>();
+    if !options.enabled {
+        return;
+    }
+    app_state.app_cache.invalidate_app(app_name);
+    if let Err(error_message) = bump_version(app_state, &options).await {
+        error!(
+            app_name = app_name,
+            ext_message = error_message,
+            message = error_message
+        );
+    }
+}
+
+/// Stores a new version in the version document, created on the first bump.
+async fn bump_version(app_state: &AppState, options: &AppCacheOptions) -> Result<(), String> {
+    let version = app_state.id_generator.next_uuid().to_string();
+    let version_error = |e: String| format!("Failed to bump the app cache version. Error: {}", e);
+    let result = app_state
+        .db
+        .update_document(
+            &options.version_collection,
+            doc! {"_id": VERSION_DOCUMENT_ID},
+            doc! {"version": &version},
+        )
+        .await
+        .map_err(|e| version_error(e.to_string()))?;
+    let matched = serde_json::from_value::<UpdateResponse>(result)
+        .map(|result| result.matchedCount > 0)
+        .unwrap_or(false);
+    if !matched {
+        app_state
+            .db
+            .create_document(
+                &options.version_collection,
+                doc! {"_id": VERSION_DOCUMENT_ID, "version": &version},
+            )
+            .await
+            .map_err(|e| version_error(e.to_string()))?;
+    }
+    Ok(())
+}
+
+async fn read_version(app_state: &AppState, options: &AppCacheOptions) -> Option<String> {
+    match app_state
+        .db
+        .get_document(
+            &options.version_collection,
+            doc! {"_id": VERSION_DOCUMENT_ID},
+        )
+        .await
+    {
+        Ok(document) => {
+            document.and_then(|document| document["version"].as_str().map(str::to_string))
+        }
+        Err(e) => {
+            warn!(message = format!("Failed to read the app cache version. Error: {}", e));
+            None
+        }
+    }
+}
+
+/// Watches the app collection and applies its changes to the cache, resuming after the last seen change. Returns the
+/// error if the change stream can't be opened.
+async fn watch_app_collection(app_state: &AppState) -> Result<(), DriverError> {
+    let collection = app_state
+        .driver_databases
+        .as_ref()
+        .ok_or(DriverError::NotConnected)?
+        .database(None)?
+        .collection::<Document>(&app_state.app_settings.mongo_db.mongo_db_app_collection);
+    let mut resume_token: Option<ResumeToken> = None;
+    loop {
+        let options = ChangeStreamOptions::builder()
+            .full_document(Some(FullDocumentType::UpdateLookup))
+            .resume_after(resume_token.clone())
+            .build();
+        let mut change_stream = collection.watch(Vec::<Document>::new(), options).await?;
+        info!(message = "App cache change stream opened.");
+        while let Some(event) = change_stream.next().await {
+            match event {
+                Ok(event) => {
+                    resume_token = Some(event.id.clone());
+                    apply_app_change(
+                        &app_state.app_cache,
+                        &event.operation_type,
+                        event.full_document.as_ref(),
+                    );
+                }
+                Err(e) => {
+                    error!(message = format!("App cache change stream failed. Error: {}", e));
+                    break;
+                }
+            }
+        }
+        // Changes may have been missed until the change stream is reopened
+        app_state.app_cache.clear();
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Polls the version document, emptying the cache when another replica bumped it.
+async fn poll_version(app_state: &AppState, options: &AppCacheOptions) {
+    app_state.app_cache.clear();
+    let mut interval = tokio::time::interval(options.version_poll_interval);
+    let mut seen_version: Option<String> = None;
+    loop {
+        interval.tick().await;
+        let version = read_version(app_state, options).await;
+        if version.is_some() && version != seen_version {
+            if seen_version.is_some() {
+                debug!(message = "App cache version bumped, emptying the cache.");
+                app_state.app_cache.clear();
+            }
+            seen_version = version;
+        }
+    }
+}
+
+/// Keeps the cache of this replica in sync with the app collection, until the process exits.
+pub async fn invalidate_app_cache(app_state: Arc<AppState>) {
+    let options = app_state.options::<AppCacheOptions>();
+    if options.invalidation == AppCacheInvalidation::ChangeStream {
+        if let Err(e) = watch_app_collection(&app_state).await {
+            warn!(
+                message = format!(
+                    "Failed to open the app cache change stream, polling the version document instead. Error: {}",
+                    e
+                )
+            );
+        }
+    }
+    poll_version(&app_state, &options).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn enabled_cache(ttl: Duration, max_entries: usize) -> AppCache {
+        AppCache::new(&AppCacheOptions {
+            enabled: true,
+            ttl,
+            max_entries,
+            ..AppCacheOptions::from_settings(None)
+        })
+    }
+
+    #[test]
+    fn test_success_app_cache_options() {
+        let options = AppCacheOptions::from_settings(None);
+        assert!(!options.enabled);
+        assert_eq!(options.ttl, Duration::from_secs(60));
+        assert_eq!(options.max_entries, 10_000);
+        assert_eq!(options.invalidation, AppCacheInvalidation::ChangeStream);
+        assert_eq!(options.version_collection, DEFAULT_VERSION_COLLECTION);
+        assert_eq!(options.version_poll_interval, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_success_app_cache() {
+        let document = json!({"app_name": "app1", "api_key": "key1"});
+
+        // A disabled cache caches nothing
+        let cache = AppCache::new(&AppCacheOptions::from_settings(None));
+        cache.insert("key1", "app1", &document);
+        assert!(cache.get("key1").is_none());
+
+        let cache = enabled_cache(Duration::from_secs(60), 10);
+        cache.insert("key1", "app1", &document);
+        cache.insert("key2", "app1", &document);
+        cache.insert("key3", "app2", &document);
+        assert_eq!(cache.get("key1"), Some(document.clone()));
+        assert!(cache.get("unknown").is_none());
+
+        cache.invalidate_app("app1");
+        assert!(cache.get("key1").is_none());
+        assert!(cache.get("key3").is_some());
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_success_app_cache_expiry_and_capacity() {
+        let document = json!({"app_name": "app1"});
+        let cache = enabled_cache(Duration::from_millis(1), 10);
+        cache.insert("key1", "app1", &document);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(cache.get("key1").is_none());
+        assert!(cache.is_empty());
+
+        let cache = enabled_cache(Duration::from_secs(60), 2);
+        cache.insert("key1", "app1", &document);
+        cache.insert("key2", "app1", &document);
+        cache.insert("key3", "app1", &document);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get("key3").is_some());
+    }
+
+    #[test]
+    fn test_success_apply_app_change() {
+        let document = json!({"app_name": "app1"});
+        let cache = enabled_cache(Duration::from_secs(60), 10);
+        cache.insert("key1", "app1", &document);
+        cache.insert("key2", "app2", &document);
+
+        apply_app_change(
+            &cache,
+            &OperationType::Update,
+            Some(&doc! {"app_name": "app1"}),
+        );
+        assert!(cache.get("key1").is_none());
+        assert!(cache.get("key2").is_some());
+
+        // The app of a deleted document is unknown, every key is evicted
+        apply_app_change(&cache, &OperationType::Delete, None);
+        assert!(cache.is_empty());
+    }
+}
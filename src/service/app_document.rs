@@ -9,13 +9,26 @@
 
 use crate::onboarding::schema::app_onboarding_request::{
     AppDataSource as OnboardingAppDataSource, EmbeddingModel as OnboardingEmbeddingModel,
-    LlmModel as OnboardingLlmModel,
+    LlmModel as OnboardingLlmModel, UserRateLimit,
 };
+use crate::onboarding::schema::schema_preview::SchemaPreview;
+use crate::service::column_classification::ColumnClassification;
+use crate::service::experiment::Experiment;
+use crate::service::history_retention::HistoryRetention;
+use crate::service::ingestion_control::IngestionControl;
+use crate::service::ingestion_retry::IngestionRetryPolicy;
+use crate::service::onboarding_state::OnboardingProgress;
+use crate::service::prompt_template::PromptTemplate;
+use crate::service::row_filter::RowFilter;
+use crate::service::source_label::LabeledSource;
 use crate::service::state::AppState;
+use crate::service::user_access::UserAccessList;
+use crate::service::vector_store::VectorStoreConfig;
 use api_utils::app_model::*;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use llm_chain::llm_models::LlmModel;
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
 #[derive(Debug, thiserror::Error, Serialize, PartialEq)]
@@ -53,6 +66,10 @@ pub enum AppDocumentCreationError {
     SearchEnabledNotProvided,
     #[error("Multimodal Search enabled value not provided")]
     MMSearchEnabledNotProvided,
+    #[error("Vector store config not provided")]
+    VectorStoreNotProvided,
+    #[error("Failed to encrypt app datasource: {0}")]
+    DataSourceEncryptionFailed(String),
 }
 
 /// Struct to represent the AppDocument
@@ -69,8 +86,58 @@ pub struct AppDocument {
     pub sqs_key: String,
     pub csv_append_same_schema: bool,
     pub allowed_models: Vec<LlmModel>,
-    pub create_timestamp: String,
+    #[serde(with = "crate::service::timestamp::bson_datetime")]
+    pub create_timestamp: DateTime<Utc>,
     pub generated_config: GeneratedConfig,
+    pub vector_store: VectorStoreConfig,
+    pub residency: Option<String>,
+    pub user_rate_limit: Option<UserRateLimit>,
+    /// Tier of the app, selecting the usage plan of its API key.
+    pub tier: Option<String>,
+    /// URL the outcome of the background onboarding steps is POSTed to.
+    pub notification_url: Option<String>,
+    /// Normalization of the retrieval queries of the app.
+    pub query_normalization: Option<bool>,
+    /// Pseudonymization of the end user IDs stored for the retrievals of the app.
+    pub pseudonymize_user_ids: Option<bool>,
+    /// Custom key/value metadata of the app, e.g. its owner team or cost center.
+    pub metadata: Option<BTreeMap<String, String>>,
+    /// PII tags of the datastore columns, the stored datasource only keeps their names and descriptions.
+    pub column_classifications: Vec<ColumnClassification>,
+    /// Row-level security filter templates of the datastore tables, passed to the knowledge engine on retrieval.
+    pub row_filters: Vec<RowFilter>,
+    /// Labels of the filestores and datastores, restricting the search scope of the retrievals that name them.
+    pub source_labels: Vec<LabeledSource>,
+    /// Kafka topic of the app, when the apps have their own topics. Skipped when unset, so the topic of an app
+    /// onboarded with per-app topics is kept, and deleted with the app, after the mode is turned off.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kafka_topic: Option<String>,
+    /// Managed through the access list endpoints. Skipped when unset, so onboarding updates keep it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_access_list: Option<UserAccessList>,
+    /// Managed through the history retention endpoints. Skipped when unset, so onboarding updates keep it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_retention: Option<HistoryRetention>,
+    /// Managed through the prompt template endpoints. Skipped when unset, so onboarding updates keep them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_templates: Option<Vec<PromptTemplate>>,
+    /// Managed through the experiment endpoints. Skipped when unset, so onboarding updates keep them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub experiments: Option<Vec<Experiment>>,
+    /// Managed through the ingestion pause/resume endpoints. Skipped when unset, so onboarding updates keep it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ingestion: Option<IngestionControl>,
+    /// Managed through the ingestion retry policy endpoints. Skipped when unset, so onboarding updates keep it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ingestion_retry: Option<IngestionRetryPolicy>,
+    /// Progress of the background onboarding steps. Skipped when unset, so onboarding updates keep the state
+    /// stored by the steps.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub onboarding_state: Option<OnboardingProgress>,
+    /// Schema previews of the tabular files of the filestore datasources, stored by the background onboarding steps.
+    /// Skipped when unset, so onboarding updates keep them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema_previews: Option<Vec<SchemaPreview>>,
     pub onboarding_status: String,
     pub search_enabled: bool,
     pub mm_search_enabled: bool,
@@ -90,8 +157,20 @@ impl AppDocument {
         sqs_key: String,
         csv_append_same_schema: bool,
         allowed_models: Vec<LlmModel>,
-        create_timestamp: String,
+        create_timestamp: DateTime<Utc>,
         generated_config: GeneratedConfig,
+        vector_store: VectorStoreConfig,
+        residency: Option<String>,
+        user_rate_limit: Option<UserRateLimit>,
+        tier: Option<String>,
+        notification_url: Option<String>,
+        query_normalization: Option<bool>,
+        pseudonymize_user_ids: Option<bool>,
+        metadata: Option<BTreeMap<String, String>>,
+        column_classifications: Vec<ColumnClassification>,
+        row_filters: Vec<RowFilter>,
+        source_labels: Vec<LabeledSource>,
+        kafka_topic: Option<String>,
         onboarding_status: String,
         search_enabled: bool,
         mm_search_enabled: bool,
@@ -110,6 +189,26 @@ impl AppDocument {
             allowed_models,
             create_timestamp,
             generated_config,
+            vector_store,
+            residency,
+            user_rate_limit,
+            tier,
+            notification_url,
+            query_normalization,
+            pseudonymize_user_ids,
+            metadata,
+            column_classifications,
+            row_filters,
+            source_labels,
+            kafka_topic,
+            user_access_list: None,
+            history_retention: None,
+            prompt_templates: None,
+            experiments: None,
+            ingestion: None,
+            ingestion_retry: None,
+            onboarding_state: None,
+            schema_previews: None,
             onboarding_status,
             search_enabled,
             mm_search_enabled,
@@ -131,6 +230,18 @@ impl AppDocument {
             allowed_models: None,
             create_timestamp: None,
             generated_config: None,
+            vector_store: None,
+            residency: None,
+            user_rate_limit: None,
+            tier: None,
+            notification_url: None,
+            query_normalization: None,
+            pseudonymize_user_ids: None,
+            metadata: None,
+            column_classifications: None,
+            row_filters: None,
+            source_labels: None,
+            kafka_topic: None,
             onboarding_status: None,
             search_enabled: None,
             mm_search_enabled: None,
@@ -151,8 +262,20 @@ pub struct AppDocumentBuilder {
     sqs_key: Option<String>,
     csv_append_same_schema: Option<bool>,
     allowed_models: Option<Vec<LlmModel>>,
-    create_timestamp: Option<String>,
+    create_timestamp: Option<DateTime<Utc>>,
     generated_config: Option<GeneratedConfig>,
+    vector_store: Option<VectorStoreConfig>,
+    residency: Option<String>,
+    user_rate_limit: Option<UserRateLimit>,
+    tier: Option<String>,
+    notification_url: Option<String>,
+    query_normalization: Option<bool>,
+    pseudonymize_user_ids: Option<bool>,
+    metadata: Option<BTreeMap<String, String>>,
+    column_classifications: Option<Vec<ColumnClassification>>,
+    row_filters: Option<Vec<RowFilter>>,
+    source_labels: Option<Vec<LabeledSource>>,
+    kafka_topic: Option<String>,
     onboarding_status: Option<String>,
     search_enabled: Option<bool>,
     mm_search_enabled: Option<bool>,
@@ -328,8 +451,8 @@ impl AppDocumentBuilder {
         self
     }
 
-    pub fn set_create_timestamp(mut self, timestamp_format: String) -> Self {
-        self.create_timestamp = Some(Utc::now().format(&timestamp_format).to_string());
+    pub fn set_create_timestamp(mut self, create_timestamp: DateTime<Utc>) -> Self {
+        self.create_timestamp = Some(create_timestamp);
         self
     }
 
@@ -496,6 +619,83 @@ impl AppDocumentBuilder {
         }
     }
 
+    pub fn set_vector_store(mut self, app_state: &Arc<AppState>, app_name: &str) -> Self {
+        self.vector_store = Some(VectorStoreConfig::from_settings(
+            &app_state.app_settings,
+            app_name,
+        ));
+        self
+    }
+
+    /// Sets the residency of the app specific collections. `None` keeps them in the primary cluster.
+    pub fn set_residency(mut self, residency: Option<String>) -> Self {
+        self.residency = residency;
+        self
+    }
+
+    /// Sets the rate limit of the retrievals of each end user. `None` leaves them unlimited.
+    pub fn set_user_rate_limit(mut self, user_rate_limit: Option<UserRateLimit>) -> Self {
+        self.user_rate_limit = user_rate_limit;
+        self
+    }
+
+    /// Sets the tier of the app. `None` selects the default usage plan.
+    pub fn set_tier(mut self, tier: Option<String>) -> Self {
+        self.tier = tier;
+        self
+    }
+
+    /// Sets the URL notified of the outcome of the background onboarding steps. `None` disables the notifications.
+    pub fn set_notification_url(mut self, notification_url: Option<String>) -> Self {
+        self.notification_url = notification_url;
+        self
+    }
+
+    /// Sets the normalization of the retrieval queries. `None` leaves it disabled.
+    pub fn set_query_normalization(mut self, query_normalization: Option<bool>) -> Self {
+        self.query_normalization = query_normalization;
+        self
+    }
+
+    /// Sets the pseudonymization of the end user IDs. `None` leaves it disabled.
+    pub fn set_pseudonymize_user_ids(mut self, pseudonymize_user_ids: Option<bool>) -> Self {
+        self.pseudonymize_user_ids = pseudonymize_user_ids;
+        self
+    }
+
+    /// Sets the custom metadata of the app. `None` leaves it without metadata.
+    pub fn set_metadata(mut self, metadata: Option<BTreeMap<String, String>>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Sets the PII tags of the datastore columns. Not setting them leaves all the columns untagged.
+    pub fn set_column_classifications(
+        mut self,
+        column_classifications: Vec<ColumnClassification>,
+    ) -> Self {
+        self.column_classifications = Some(column_classifications);
+        self
+    }
+
+    /// Sets the row filter templates of the datastore tables. Not setting them leaves all the rows readable.
+    pub fn set_row_filters(mut self, row_filters: Vec<RowFilter>) -> Self {
+        self.row_filters = Some(row_filters);
+        self
+    }
+
+    /// Sets the labels of the filestores and datastores. Not setting them leaves the sources unlabeled.
+    pub fn set_source_labels(mut self, source_labels: Vec<LabeledSource>) -> Self {
+        self.source_labels = Some(source_labels);
+        self
+    }
+
+    /// Sets the Kafka topic of the app. `None` publishes its events to the shared onboarding topic.
+    pub fn set_kafka_topic(mut self, kafka_topic: Option<String>) -> Self {
+        self.kafka_topic = kafka_topic;
+        self
+    }
+
     pub fn set_onboarding_status(mut self, onboarding_status: String) -> Self {
         self.onboarding_status = Some(onboarding_status);
         self
@@ -539,6 +739,19 @@ impl AppDocumentBuilder {
                 .ok_or(AppDocumentCreationError::CreateTimestampNotProvided)?,
             self.generated_config
                 .ok_or(AppDocumentCreationError::GeneratedConfigNotProvided)?,
+            self.vector_store
+                .ok_or(AppDocumentCreationError::VectorStoreNotProvided)?,
+            self.residency,
+            self.user_rate_limit,
+            self.tier,
+            self.notification_url,
+            self.query_normalization,
+            self.pseudonymize_user_ids,
+            self.metadata,
+            self.column_classifications.unwrap_or_default(),
+            self.row_filters.unwrap_or_default(),
+            self.source_labels.unwrap_or_default(),
+            self.kafka_topic,
             self.onboarding_status
                 .ok_or(AppDocumentCreationError::OnboardingStatusNotProvided)?,
             self.search_enabled
@@ -706,17 +919,18 @@ mod tests {
 
     #[test]
     fn test_success_set_create_timestamp() {
-        let builder = AppDocument::builder().set_create_timestamp("%Y-%m-%d".to_string());
-        assert_eq!(
-            builder.create_timestamp,
-            Some(Utc::now().format("%Y-%m-%d").to_string())
-        );
+        let create_timestamp = Utc::now();
+        let builder = AppDocument::builder().set_create_timestamp(create_timestamp);
+        assert_eq!(builder.create_timestamp, Some(create_timestamp));
     }
 
     #[test]
     fn test_failure_set_create_timestamp() {
-        let builder = AppDocument::builder().set_create_timestamp("%Y-%m-%d".to_string());
-        assert_ne!(builder.create_timestamp, Some("WrongTimestamp".to_string()));
+        let builder = AppDocument::builder().set_create_timestamp(Utc::now());
+        assert_ne!(
+            builder.create_timestamp,
+            Some(Utc::now() - chrono::Duration::days(1))
+        );
     }
 
     #[test]
@@ -734,6 +948,21 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_success_set_vector_store() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function and check if the vector store config is set
+            let builder = AppDocument::builder().set_vector_store(&app_state, "test_app");
+            let vector_store = builder.vector_store.unwrap();
+            assert!(vector_store.text_collection_name.starts_with("test_app"));
+        });
+    }
+
     #[test]
     fn test_success_set_onboarding_status() {
         let builder = AppDocument::builder().set_onboarding_status("In Progress".to_string());
@@ -1026,11 +1255,49 @@ mod tests {
             .set_sqs_key("TestSqsKey".to_string())
             .set_csv_append_same_schema(true)
             .set_allowed_models(vec![])
-            .set_create_timestamp("TestTimestamp".to_string());
+            .set_create_timestamp(Utc::now());
         let result = builder.build();
         assert_eq!(
             result.unwrap_err(),
             AppDocumentCreationError::GeneratedConfigNotProvided
         );
     }
+
+    #[test]
+    fn test_failure_build_missing_vector_store() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let app_data_source = read_app_datasource_from_file().unwrap();
+            let builder = AppDocument::builder()
+                .set_app_name("TestApp".to_string())
+                .set_app_description("TestDescription".to_string())
+                .set_text_embedding_model(OnboardingEmbeddingModel {
+                    dimension: 100,
+                    model_id: "TestModelId".to_string(),
+                    platform: "TestPlatform".to_string(),
+                })
+                .set_multimodal_embedding_model(OnboardingEmbeddingModel {
+                    dimension: 100,
+                    model_id: "TestModelId".to_string(),
+                    platform: "TestPlatform".to_string(),
+                })
+                .set_app_datasource(app_data_source)
+                .set_app_id("TestAppId".to_string())
+                .set_api_key("TestApiKey".to_string())
+                .set_api_key_id("TestApiKeyId".to_string())
+                .set_sqs_key("TestSqsKey".to_string())
+                .set_csv_append_same_schema(true)
+                .set_allowed_models(vec![])
+                .set_create_timestamp(Utc::now())
+                .set_generated_config(&app_state, "TestApp".to_string());
+            let result = builder.build();
+            assert_eq!(
+                result.unwrap_err(),
+                AppDocumentCreationError::VectorStoreNotProvided
+            );
+        });
+    }
 }
@@ -0,0 +1,459 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the additional API keys of an app, e.g. one per consuming service, so a leaked key is revoked
+//! without rotating the key of every other service of the customer.
+//! The key created at onboarding stays the primary key of the app (`api_key` of the app document). The additional
+//! keys are stored in the `api_keys` field of the app document, with their label (unique within the app), creation
+//! date, optional expiry date and last use. They are issued like the primary key: created in API Gateway and
+//! associated with the usage plan of the tier of the app, or generated and stored hashed in the internal API key mode.
+//! The plain key is only returned when it is created.
+//! The retrieval endpoints resolve the app of any active key: the primary key, or an additional key before its expiry
+//! date. The last use of an additional key is recorded at most every 5 minutes.
+//! The primary key can expire too: its expiry is stored in the `api_key_expiry` field of the app document. The expiry
+//! of a key holds its expiry date, when its expiry was warned and when it was deactivated (see `key_expiry`). An
+//! expired or deactivated key is answered with a "key expired" error instead of the invalid key error.
+//!
+
+use crate::admin_ui_api::schema::UpdateResponse;
+use crate::service::app_cache::invalidate_cached_app;
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use axum::{http::StatusCode, Json};
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::{doc, to_bson, Document};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, error, instrument};
+use utoipa::ToSchema;
+
+/// Field of the additional API keys of an app in the app document.
+pub const APP_KEYS_FIELD: &str = "api_keys";
+/// Field of the expiry of the primary API key of an app in the app document.
+pub const PRIMARY_KEY_EXPIRY_FIELD: &str = "api_key_expiry";
+/// Maximum length of the label of a key.
+const MAX_LABEL_LENGTH: usize = 64;
+/// Interval between two records of the last use of a key, in minutes.
+const LAST_USED_RESOLUTION_MINUTES: i64 = 5;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum AppKeyError {
+    #[error("Invalid label '{0}'. Labels are 1 to 64 letters, digits, '.', '-' and '_'.")]
+    InvalidLabel(String),
+    #[error("An API key labelled '{0}' already exists.")]
+    DuplicateLabel(String),
+    #[error("Invalid expiry date '{0}', it must be in the future.")]
+    InvalidExpiry(String),
+    #[error("No API key found with ID '{0}'.")]
+    UnknownKey(String),
+    #[error("API key '{0}' is deactivated. Create a new key instead.")]
+    Deactivated(String),
+}
+
+impl From<AppKeyError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: AppKeyError) -> Self {
+        let status_code = match e {
+            AppKeyError::DuplicateLabel(_) | AppKeyError::Deactivated(_) => StatusCode::CONFLICT,
+            AppKeyError::UnknownKey(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        let error_message = e.to_string();
+        debug!(message = error_message);
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Expiry of an API key, never expiring by default.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct KeyExpiry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// When the coming expiry was warned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warned_at: Option<DateTime<Utc>>,
+    /// When the expired key was deactivated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deactivated_at: Option<DateTime<Utc>>,
+}
+
+impl KeyExpiry {
+    /// Returns when the key expired, `None` if it is active at `now`.
+    pub fn expired_at(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.expires_at
+            .filter(|expires_at| *expires_at <= now)
+            .or(self.deactivated_at)
+    }
+
+    /// True if the key resolves its app at `now`.
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.expired_at(now).is_none()
+    }
+
+    /// True if the key expires within `warning` of `now` and its expiry was not warned yet.
+    pub fn needs_warning(&self, now: DateTime<Utc>, warning: Duration) -> bool {
+        self.warned_at.is_none()
+            && self.is_active(now)
+            && self
+                .expires_at
+                .is_some_and(|expires_at| expires_at - warning <= now)
+    }
+
+    /// True if the key is expired at `now` and not deactivated yet.
+    pub fn needs_deactivation(&self, now: DateTime<Utc>) -> bool {
+        self.deactivated_at.is_none() && self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Additional API key of an app, as stored on the app document.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AppKey {
+    pub key_id: String,
+    pub label: String,
+    /// Key as matched by the retrieval endpoints: the key of API Gateway, or its hash in the internal API key mode.
+    pub api_key: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub expiry: KeyExpiry,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl AppKey {
+    /// True if the key resolves its app at `now`.
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.expiry.is_active(now)
+    }
+
+    /// True if the last use of the key is not recorded or older than the resolution of the records.
+    pub fn is_last_use_stale(&self, now: DateTime<Utc>) -> bool {
+        match self.last_used_at {
+            Some(last_used_at) => {
+                now - last_used_at >= Duration::minutes(LAST_USED_RESOLUTION_MINUTES)
+            }
+            None => true,
+        }
+    }
+}
+
+/// Additional API key of an app, as served by the key endpoints.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct AppKeySummary {
+    pub key_id: String,
+    pub label: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub deactivated_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    /// Whether the key resolves its app, i.e. is neither expired nor deactivated.
+    pub active: bool,
+}
+
+impl AppKeySummary {
+    pub fn new(key: &AppKey, now: DateTime<Utc>) -> Self {
+        AppKeySummary {
+            key_id: key.key_id.clone(),
+            label: key.label.clone(),
+            created_at: key.created_at,
+            expires_at: key.expiry.expires_at,
+            deactivated_at: key.expiry.deactivated_at,
+            last_used_at: key.last_used_at,
+            active: key.is_active(now),
+        }
+    }
+}
+
+/// Request to create an additional API key of an app.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct CreateAppKeyRequest {
+    /// Label of the key, e.g. the consuming service. Unique within the app.
+    pub label: String,
+    /// Expiry date of the key, never expires if not set.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl CreateAppKeyRequest {
+    /// Validates the request against the keys of the app.
+    pub fn validate(&self, keys: &[AppKey], now: DateTime<Utc>) -> Result<(), AppKeyError> {
+        let label = &self.label;
+        if label.is_empty()
+            || label.len() > MAX_LABEL_LENGTH
+            || !label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        {
+            return Err(AppKeyError::InvalidLabel(label.clone()));
+        }
+        if keys.iter().any(|key| &key.label == label) {
+            return Err(AppKeyError::DuplicateLabel(label.clone()));
+        }
+        if let Some(expires_at) = self.expires_at.filter(|expires_at| *expires_at <= now) {
+            return Err(AppKeyError::InvalidExpiry(expires_at.to_rfc3339()));
+        }
+        Ok(())
+    }
+}
+
+/// Request to change the expiry date of an API key of an app.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct KeyExpiryRequest {
+    /// New expiry date of the key, never expires if not set.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl KeyExpiryRequest {
+    /// Validates the request against the expiry of the key.
+    pub fn validate(
+        &self,
+        key_id: &str,
+        expiry: &KeyExpiry,
+        now: DateTime<Utc>,
+    ) -> Result<(), AppKeyError> {
+        if expiry.deactivated_at.is_some() {
+            return Err(AppKeyError::Deactivated(key_id.to_string()));
+        }
+        if let Some(expires_at) = self.expires_at.filter(|expires_at| *expires_at <= now) {
+            return Err(AppKeyError::InvalidExpiry(expires_at.to_rfc3339()));
+        }
+        Ok(())
+    }
+}
+
+/// Updates the app document of an app. Returns the error message if the update failed or matched no app.
+async fn update_app(
+    app_state: &AppState,
+    app_name: &str,
+    filter: Document,
+    update: Document,
+) -> Result<(), String> {
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let result = app_state
+        .db
+        .update_document(collection_name, filter, update)
+        .await
+        .map_err(ErrorInterceptor::from)
+        .map_err(|e| {
+            format!(
+                "Failed to store API keys of app '{}'. Error: {}",
+                app_name, e
+            )
+        })
+        .and_then(|json_result| {
+            serde_json::from_value::<UpdateResponse>(json_result)
+                .map_err(|e| format!("Failed to deserialize update response. Error: {:?}", e))
+        })
+        .and_then(|result| match result.matchedCount {
+            0 => Err(format!("No app found with name '{}'.", app_name)),
+            _ => Ok(()),
+        });
+    if let Err(error_message) = &result {
+        error!(
+            app_name = app_name,
+            ext_message = error_message,
+            message = error_message
+        );
+    }
+    result
+}
+
+/// Stores the additional API keys of an app on its app document. Returns the error message if the keys could not be
+/// stored.
+#[instrument(skip_all)]
+pub async fn store_app_keys(
+    app_state: &AppState,
+    app_name: &str,
+    app_keys: &[AppKey],
+) -> Result<(), String> {
+    let app_keys = to_bson(app_keys)
+        .map_err(|e| format!("Failed to serialize API keys to BSON. Error: {}", e))?;
+    update_app(
+        app_state,
+        app_name,
+        doc! {"app_name": app_name},
+        doc! {APP_KEYS_FIELD: app_keys},
+    )
+    .await?;
+    invalidate_cached_app(app_state, app_name).await;
+    Ok(())
+}
+
+/// Sets fields of the expiry of an API key of an app: of its primary key without `key_id`, else of its additional
+/// key. Returns the error message if the fields could not be stored.
+#[instrument(skip_all)]
+pub async fn store_key_expiry_fields(
+    app_state: &AppState,
+    app_name: &str,
+    key_id: Option<&str>,
+    fields: &[(&str, Option<DateTime<Utc>>)],
+) -> Result<(), String> {
+    let (filter, prefix) = match key_id {
+        None => (
+            doc! {"app_name": app_name},
+            PRIMARY_KEY_EXPIRY_FIELD.to_string(),
+        ),
+        Some(key_id) => (
+            doc! {"app_name": app_name, format!("{}.key_id", APP_KEYS_FIELD): key_id},
+            format!("{}.$", APP_KEYS_FIELD),
+        ),
+    };
+    let mut update = Document::new();
+    for (field, value) in fields {
+        update.insert(
+            format!("{}.{}", prefix, field),
+            value.map(|value| value.to_rfc3339()),
+        );
+    }
+    update_app(app_state, app_name, filter, update).await?;
+    invalidate_cached_app(app_state, app_name).await;
+    Ok(())
+}
+
+/// Records the use of an additional API key, unless its last use was recorded less than 5 minutes ago. A failure is
+/// logged without failing the request.
+#[instrument(skip_all)]
+pub async fn record_app_key_use(app_state: &AppState, app_name: &str, app_key: &AppKey) {
+    let now = Utc::now();
+    if !app_key.is_last_use_stale(now) {
+        return;
+    }
+    // Only the matched key is updated, so a concurrent change of the other keys is kept
+    let _ = update_app(
+        app_state,
+        app_name,
+        doc! {"app_name": app_name, format!("{}.key_id", APP_KEYS_FIELD): &app_key.key_id},
+        doc! {format!("{}.$.last_used_at", APP_KEYS_FIELD): now.to_rfc3339()},
+    )
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app_key(label: &str, expires_at: Option<DateTime<Utc>>) -> AppKey {
+        AppKey {
+            key_id: format!("{}-id", label),
+            label: label.to_string(),
+            api_key: "sha256:abc".to_string(),
+            created_at: Utc::now(),
+            expiry: KeyExpiry {
+                expires_at,
+                ..Default::default()
+            },
+            last_used_at: None,
+        }
+    }
+
+    #[test]
+    fn test_success_app_key() {
+        let now = Utc::now();
+        let key = app_key("billing", Some(now + Duration::days(1)));
+        assert!(key.is_active(now));
+        assert!(!key.is_active(now + Duration::days(2)));
+        assert!(app_key("search", None).is_active(now));
+
+        assert!(key.is_last_use_stale(now));
+        let key = AppKey {
+            last_used_at: Some(now),
+            ..key
+        };
+        assert!(!key.is_last_use_stale(now + Duration::minutes(1)));
+        assert!(key.is_last_use_stale(now + Duration::minutes(5)));
+        assert!(AppKeySummary::new(&key, now).active);
+    }
+
+    #[test]
+    fn test_success_key_expiry() {
+        let now = Utc::now();
+        let warning = Duration::days(7);
+        let expiry = KeyExpiry::default();
+        assert!(expiry.is_active(now));
+        assert!(!expiry.needs_warning(now, warning));
+        assert!(!expiry.needs_deactivation(now));
+
+        let expiry = KeyExpiry {
+            expires_at: Some(now + Duration::days(3)),
+            ..Default::default()
+        };
+        assert!(expiry.needs_warning(now, warning));
+        assert!(!expiry.needs_warning(now - Duration::days(5), warning));
+        let warned = KeyExpiry {
+            warned_at: Some(now),
+            ..expiry.clone()
+        };
+        assert!(!warned.needs_warning(now, warning));
+
+        let later = now + Duration::days(4);
+        assert_eq!(expiry.expired_at(later), expiry.expires_at);
+        assert!(expiry.needs_deactivation(later));
+        let deactivated = KeyExpiry {
+            deactivated_at: Some(later),
+            ..expiry
+        };
+        assert!(!deactivated.needs_deactivation(later));
+        assert!(!deactivated.is_active(now));
+
+        // The expiry fields are stored at the top level of an additional key
+        let key = app_key("billing", Some(now));
+        let value = serde_json::to_value(&key).unwrap();
+        assert!(value.get("expires_at").is_some());
+        assert!(value.get("deactivated_at").is_none());
+        assert_eq!(serde_json::from_value::<AppKey>(value).unwrap(), key);
+    }
+
+    #[test]
+    fn test_failure_key_expiry_request() {
+        let now = Utc::now();
+        let request = KeyExpiryRequest {
+            expires_at: Some(now + Duration::days(30)),
+        };
+        assert!(request.validate("key", &KeyExpiry::default(), now).is_ok());
+        let deactivated = KeyExpiry {
+            deactivated_at: Some(now),
+            ..Default::default()
+        };
+        assert_eq!(
+            request.validate("key", &deactivated, now),
+            Err(AppKeyError::Deactivated("key".to_string()))
+        );
+        let request = KeyExpiryRequest {
+            expires_at: Some(now - Duration::days(1)),
+        };
+        assert!(matches!(
+            request.validate("key", &KeyExpiry::default(), now),
+            Err(AppKeyError::InvalidExpiry(_))
+        ));
+    }
+
+    #[test]
+    fn test_failure_create_app_key_request() {
+        let now = Utc::now();
+        let keys = vec![app_key("billing", None)];
+        let request = |label: &str, expires_at: Option<DateTime<Utc>>| CreateAppKeyRequest {
+            label: label.to_string(),
+            expires_at,
+        };
+
+        assert!(request("search-service", None).validate(&keys, now).is_ok());
+        assert_eq!(
+            request("billing", None).validate(&keys, now),
+            Err(AppKeyError::DuplicateLabel("billing".to_string()))
+        );
+        for label in ["", "search service", &"a".repeat(65)] {
+            assert!(matches!(
+                request(label, None).validate(&keys, now),
+                Err(AppKeyError::InvalidLabel(_))
+            ));
+        }
+        assert!(matches!(
+            request("search", Some(now - Duration::days(1))).validate(&keys, now),
+            Err(AppKeyError::InvalidExpiry(_))
+        ));
+    }
+}
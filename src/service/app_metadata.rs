@@ -0,0 +1,174 @@
+/*
+ * Created Date:  Jul 29, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the custom metadata of the apps: free key/value pairs set by the onboarding/update request,
+//! e.g. the owner team, the cost center or the Slack channel of an app, stored in the `metadata` field of the app
+//! document. The metadata is limited to 20 entries, the keys to 64 letters, digits, `_` or `-` and the values to
+//! 256 characters, so an entry is stored as a plain field of the app document.
+//! The list of the apps is filtered by metadata with the `metadata` query parameter, e.g. `team:search,env:prod`,
+//! matching the apps with every listed entry.
+//!
+
+use axum::{http::StatusCode, Json};
+use mongodb::bson::{doc, Document};
+use serde_json::json;
+use std::collections::BTreeMap;
+use tracing::debug;
+
+/// Field of the metadata in the app document.
+pub const METADATA_FIELD: &str = "metadata";
+/// Maximum number of metadata entries of an app.
+pub const MAX_METADATA_ENTRIES: usize = 20;
+/// Maximum length of a metadata key.
+pub const MAX_METADATA_KEY_LENGTH: usize = 64;
+/// Maximum length of a metadata value.
+pub const MAX_METADATA_VALUE_LENGTH: usize = 256;
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum AppMetadataError {
+    #[error("Too many metadata entries: {0}. At most 20 entries are allowed.")]
+    TooManyEntries(usize),
+    #[error("Invalid metadata key '{0}'. Keys are 1 to 64 letters, digits, '_' or '-'.")]
+    InvalidKey(String),
+    #[error("Metadata value of key '{0}' is too long. Values are at most 256 characters.")]
+    ValueTooLong(String),
+    #[error("Invalid metadata filter '{0}'. A comma separated list of key:value is expected.")]
+    InvalidFilter(String),
+}
+
+impl From<AppMetadataError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: AppMetadataError) -> Self {
+        let error_message = e.to_string();
+        debug!(message = error_message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_METADATA_KEY_LENGTH
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Validates the metadata of an onboarding/update request.
+pub fn validate_metadata(
+    metadata: Option<&BTreeMap<String, String>>,
+) -> Result<(), AppMetadataError> {
+    let Some(metadata) = metadata else {
+        return Ok(());
+    };
+    if metadata.len() > MAX_METADATA_ENTRIES {
+        return Err(AppMetadataError::TooManyEntries(metadata.len()));
+    }
+    for (key, value) in metadata {
+        if !is_valid_key(key) {
+            return Err(AppMetadataError::InvalidKey(key.clone()));
+        }
+        if value.chars().count() > MAX_METADATA_VALUE_LENGTH {
+            return Err(AppMetadataError::ValueTooLong(key.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Filter of the apps with every entry of a `key:value,key:value` metadata filter.
+pub fn metadata_filter(filter: &str) -> Result<Document, AppMetadataError> {
+    let mut document = Document::new();
+    for entry in filter.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((key, value)) = entry.split_once(':') else {
+            return Err(AppMetadataError::InvalidFilter(filter.to_string()));
+        };
+        let key = key.trim();
+        if !is_valid_key(key) {
+            return Err(AppMetadataError::InvalidKey(key.to_string()));
+        }
+        document.insert(format!("{}.{}", METADATA_FIELD, key), value.trim());
+    }
+    if document.is_empty() {
+        return Err(AppMetadataError::InvalidFilter(filter.to_string()));
+    }
+    Ok(document)
+}
+
+/// Filter of the app list, on the metadata when a `metadata` query parameter is given.
+pub fn app_list_filter(metadata: Option<&str>) -> Result<Document, AppMetadataError> {
+    match metadata {
+        Some(metadata) => metadata_filter(metadata),
+        None => Ok(doc! {}),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_validate_metadata() {
+        assert!(validate_metadata(None).is_ok());
+        let metadata = BTreeMap::from([
+            ("owner-team".to_string(), "search".to_string()),
+            ("cost_center".to_string(), "CC-42".to_string()),
+        ]);
+        assert!(validate_metadata(Some(&metadata)).is_ok());
+    }
+
+    #[test]
+    fn test_failure_validate_metadata() {
+        let metadata = BTreeMap::from([("owner.team".to_string(), "search".to_string())]);
+        assert_eq!(
+            validate_metadata(Some(&metadata)),
+            Err(AppMetadataError::InvalidKey("owner.team".to_string()))
+        );
+
+        let metadata = BTreeMap::from([("team".to_string(), "a".repeat(257))]);
+        assert_eq!(
+            validate_metadata(Some(&metadata)),
+            Err(AppMetadataError::ValueTooLong("team".to_string()))
+        );
+
+        let metadata: BTreeMap<String, String> = (0..21)
+            .map(|i| (format!("key{}", i), "value".to_string()))
+            .collect();
+        assert_eq!(
+            validate_metadata(Some(&metadata)),
+            Err(AppMetadataError::TooManyEntries(21))
+        );
+    }
+
+    #[test]
+    fn test_success_metadata_filter() {
+        assert_eq!(app_list_filter(None).unwrap(), doc! {});
+        assert_eq!(
+            app_list_filter(Some("team:search, env:prod")).unwrap(),
+            doc! {"metadata.team": "search", "metadata.env": "prod"}
+        );
+        // A value may hold a colon
+        assert_eq!(
+            metadata_filter("slack:#search:alerts").unwrap(),
+            doc! {"metadata.slack": "#search:alerts"}
+        );
+    }
+
+    #[test]
+    fn test_failure_metadata_filter() {
+        assert!(matches!(
+            metadata_filter("team"),
+            Err(AppMetadataError::InvalidFilter(_))
+        ));
+        assert!(matches!(
+            metadata_filter(""),
+            Err(AppMetadataError::InvalidFilter(_))
+        ));
+        assert!(matches!(
+            metadata_filter("$where:1"),
+            Err(AppMetadataError::InvalidKey(_))
+        ));
+    }
+}
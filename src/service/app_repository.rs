@@ -0,0 +1,982 @@
+/*
+ * Created Date:  Jun 27, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the `AppRepository`, the typed lookups of the app documents.
+//! The lookups (existence, app names, app name by api_key, api keys, deletion details, residency, user rate limit,
+//! user access list, paused apps, row filters, source labels, filestore hints, Kafka topic, onboarding state, history retention,
+//! ingestion retry policies, query normalization, user ID pseudonymization, prompt templates, answer sinks, experiments, shadow traffic, generated config, ingestion
+//! sources, tier, allowed models, additional API keys, expiry of the primary API key, expiring API keys, owner of an API key) query the app collection in a single place and return
+//! domain structs, so the handlers no longer build raw filters or read the fields of the documents by name.
+//! Every lookup goes through `find_app`, which times the query.
+//! The repository of a request (`AppRepository::per_request`) fetches the document of an app once and serves the
+//! following lookups of the app from it, e.g. the retrieval handler reading the settings of the app. The other
+//! repositories fetch the document on every lookup, so the handlers read back their own updates.
+//!
+
+use crate::onboarding::schema::app_onboarding_request::{FileStore, LlmModel, UserRateLimit};
+use crate::service::answer_sink::{AnswerSink, ANSWER_SINKS_FIELD};
+use crate::service::app_keys::{AppKey, KeyExpiry, APP_KEYS_FIELD, PRIMARY_KEY_EXPIRY_FIELD};
+use crate::service::app_topic::KAFKA_TOPIC_FIELD;
+use crate::service::experiment::{Experiment, EXPERIMENTS_FIELD};
+use crate::service::history_retention::{HistoryRetention, HISTORY_RETENTION_FIELD};
+use crate::service::ingestion_control::IngestionState;
+use crate::service::ingestion_retry::{IngestionRetryPolicy, INGESTION_RETRY_FIELD};
+use crate::service::key_expiry::{expiring_keys, ExpiringKey};
+use crate::service::onboarding_state::{
+    OnboardingProgress, OnboardingState, ONBOARDING_STATE_FIELD,
+};
+use crate::service::prompt_template::{PromptTemplate, PROMPT_TEMPLATES_FIELD};
+use crate::service::query_options::{AggregateExt, QueryError, QueryOptions};
+use crate::service::readiness::app_sources;
+use crate::service::row_filter::{RowFilter, ROW_FILTERS_FIELD};
+use crate::service::shadow_traffic::{ShadowTraffic, SHADOW_TRAFFIC_FIELD};
+use crate::service::source_label::{LabeledSource, SOURCE_LABELS_FIELD};
+use crate::service::state::AppState;
+use crate::service::user_access::UserAccessList;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use axum::{http::StatusCode, Json};
+use mongodb::bson::{doc, Document};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::{debug, error, info, instrument};
+
+#[derive(Debug, thiserror::Error)]
+pub enum AppRepositoryError {
+    #[error("DocumentDB lookup failed: {0}")]
+    Db(ErrorInterceptor),
+    #[error("{0}")]
+    Query(QueryError),
+    #[error("No app found with name '{0}'.")]
+    AppNotFound(String),
+    #[error("No document found for the given api_key.")]
+    ApiKeyNotFound,
+    #[error("The API key of app '{app_name}' expired on {expired_at}.")]
+    ApiKeyExpired {
+        app_name: String,
+        expired_at: String,
+    },
+    #[error("Failed to read app document. No '{0}' key found in document.")]
+    MissingField(&'static str),
+    #[error("Failed to deserialize '{field}' of app '{app_name}'. Error: {message}")]
+    Malformed {
+        app_name: String,
+        field: &'static str,
+        message: String,
+    },
+    #[error("Failed to decrypt app '{app_name}'. Error: {message}")]
+    Decryption { app_name: String, message: String },
+}
+
+impl From<AppRepositoryError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: AppRepositoryError) -> Self {
+        let status_code = match e {
+            AppRepositoryError::AppNotFound(_)
+            | AppRepositoryError::ApiKeyNotFound
+            | AppRepositoryError::MissingField(_) => StatusCode::NOT_FOUND,
+            AppRepositoryError::ApiKeyExpired { .. } => StatusCode::UNAUTHORIZED,
+            AppRepositoryError::Query(ref e) => e.status_code(),
+            AppRepositoryError::Db(_)
+            | AppRepositoryError::Malformed { .. }
+            | AppRepositoryError::Decryption { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let error_message = e.to_string();
+        error!(ext_message = error_message, message = error_message);
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// API key of an app, with the ids of the key and of the app.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppApiKey {
+    pub api_key: String,
+    pub api_key_id: String,
+    pub app_id: String,
+}
+
+/// App owning an API key, with the additional key matched, `None` for the primary key of the app.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKeyOwner {
+    pub app_name: String,
+    pub app_key: Option<AppKey>,
+}
+
+/// Resources of an app released on deletion.
+#[derive(Debug, Clone)]
+pub struct AppDeletionDetails {
+    pub sqs_key: String,
+    pub api_key_id: String,
+    pub filestore: HashMap<String, Vec<FileStore>>,
+}
+
+/// Typed lookups of the app documents.
+pub struct AppRepository<'a> {
+    app_state: &'a AppState,
+    /// Documents fetched by app name, `None` for an unknown app, kept by the repository of a request.
+    documents: Option<Mutex<HashMap<String, Option<serde_json::Value>>>>,
+}
+
+impl<'a> AppRepository<'a> {
+    pub fn new(app_state: &'a AppState) -> Self {
+        AppRepository {
+            app_state,
+            documents: None,
+        }
+    }
+
+    /// Returns the repository of a request, fetching the document of an app once for all its lookups.
+    pub fn per_request(app_state: &'a AppState) -> Self {
+        AppRepository {
+            app_state,
+            documents: Some(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn collection_name(&self) -> &str {
+        &self.app_state.app_settings.mongo_db.mongo_db_app_collection
+    }
+
+    /// Fetches the app document matching a filter. `lookup` names the lookup in the logs.
+    async fn find_app(
+        &self,
+        lookup: &str,
+        filter: Document,
+    ) -> Result<Option<serde_json::Value>, AppRepositoryError> {
+        let start = Instant::now();
+        let result = self
+            .app_state
+            .db
+            .get_document(self.collection_name(), filter)
+            .await
+            .map_err(|e| AppRepositoryError::Db(ErrorInterceptor::from(e)));
+        debug!(
+            message = format!(
+                "App lookup '{}' took {} ms.",
+                lookup,
+                start.elapsed().as_millis()
+            )
+        );
+        result
+    }
+
+    /// Fetches the document of an app by name, once per request for the repository of a request.
+    async fn find_app_by_name(
+        &self,
+        lookup: &str,
+        app_name: &str,
+    ) -> Result<Option<serde_json::Value>, AppRepositoryError> {
+        let Some(documents) = &self.documents else {
+            return self.find_app(lookup, doc! {"app_name": app_name}).await;
+        };
+        if let Some(app) = documents
+            .lock()
+            .ok()
+            .and_then(|documents| documents.get(app_name).cloned())
+        {
+            return Ok(app);
+        }
+        let app = self.find_app(lookup, doc! {"app_name": app_name}).await?;
+        if let Ok(mut documents) = documents.lock() {
+            documents.insert(app_name.to_string(), app.clone());
+        }
+        Ok(app)
+    }
+
+    /// Returns true if an app with the given name exists.
+    #[instrument(skip_all)]
+    pub async fn exists(&self, app_name: &str) -> Result<bool, AppRepositoryError> {
+        let start = Instant::now();
+        let app_count = self
+            .app_state
+            .db
+            .get_document_count(self.collection_name(), doc! {"app_name": app_name})
+            .await
+            .map_err(|e| AppRepositoryError::Db(ErrorInterceptor::from(e)))?;
+        debug!(
+            message = format!(
+                "App lookup 'exists' took {} ms.",
+                start.elapsed().as_millis()
+            )
+        );
+        if app_count > 0 {
+            info!(
+                app_name = app_name,
+                message = format!("App {} exists in DocumentDB.", app_name)
+            );
+            Ok(true)
+        } else {
+            debug!(message = format!("No app found with name '{}'.", app_name));
+            Ok(false)
+        }
+    }
+
+    /// Returns the name of the app owning an active API key. In the internal API key mode the key is looked up by its
+    /// hash.
+    #[instrument(skip_all)]
+    pub async fn app_name_by_api_key(&self, api_key: &str) -> Result<String, AppRepositoryError> {
+        Ok(self.api_key_owner(api_key).await?.app_name)
+    }
+
+    /// Returns the app owning an active API key, its primary key or one of its additional keys, before its expiry date
+    /// and not deactivated. An expired key is answered with `ApiKeyExpired`. In the internal API key mode the key is
+    /// looked up by its hash. The matched app document is served from the app cache when enabled (see `app_cache`).
+    #[instrument(skip_all)]
+    pub async fn api_key_owner(&self, api_key: &str) -> Result<ApiKeyOwner, AppRepositoryError> {
+        let stored_api_key = self.app_state.api_key_options().stored_api_key(api_key);
+        let filter = doc! {
+            "$or": [
+                {"api_key": &stored_api_key},
+                {format!("{}.api_key", APP_KEYS_FIELD): &stored_api_key},
+            ]
+        };
+        let app = match self.app_state.app_cache.get(&stored_api_key) {
+            Some(app) => app,
+            None => {
+                let app = self
+                    .find_app("api_key_owner", filter)
+                    .await?
+                    .ok_or(AppRepositoryError::ApiKeyNotFound)?;
+                if let Some(app_name) = app.get("app_name").and_then(serde_json::Value::as_str) {
+                    self.app_state
+                        .app_cache
+                        .insert(&stored_api_key, app_name, &app);
+                }
+                app
+            }
+        };
+        let app_name = str_field(&app, "app_name")?;
+        let now = chrono::Utc::now();
+        let expired =
+            |expired_at: chrono::DateTime<chrono::Utc>| AppRepositoryError::ApiKeyExpired {
+                app_name: app_name.clone(),
+                expired_at: expired_at.to_rfc3339(),
+            };
+        if app.get("api_key").and_then(serde_json::Value::as_str) == Some(stored_api_key.as_str()) {
+            let expiry = match app.get(PRIMARY_KEY_EXPIRY_FIELD) {
+                None | Some(serde_json::Value::Null) => KeyExpiry::default(),
+                Some(expiry) => {
+                    KeyExpiry::deserialize(expiry).map_err(|e| AppRepositoryError::Malformed {
+                        app_name: app_name.clone(),
+                        field: PRIMARY_KEY_EXPIRY_FIELD,
+                        message: e.to_string(),
+                    })?
+                }
+            };
+            if let Some(expired_at) = expiry.expired_at(now) {
+                return Err(expired(expired_at));
+            }
+            return Ok(ApiKeyOwner {
+                app_name,
+                app_key: None,
+            });
+        }
+        let app_keys = match app.get(APP_KEYS_FIELD) {
+            Some(app_keys) => {
+                Vec::<AppKey>::deserialize(app_keys).map_err(|e| AppRepositoryError::Malformed {
+                    app_name: app_name.clone(),
+                    field: APP_KEYS_FIELD,
+                    message: e.to_string(),
+                })?
+            }
+            None => Vec::new(),
+        };
+        let app_key = app_keys
+            .into_iter()
+            .find(|app_key| app_key.api_key == stored_api_key)
+            .ok_or(AppRepositoryError::ApiKeyNotFound)?;
+        if let Some(expired_at) = app_key.expiry.expired_at(now) {
+            return Err(expired(expired_at));
+        }
+        Ok(ApiKeyOwner {
+            app_name,
+            app_key: Some(app_key),
+        })
+    }
+
+    /// Returns the API key of an app.
+    #[instrument(skip_all)]
+    pub async fn api_key(&self, app_name: &str) -> Result<AppApiKey, AppRepositoryError> {
+        let app = self
+            .find_app_by_name("api_key", app_name)
+            .await?
+            .ok_or_else(|| AppRepositoryError::AppNotFound(app_name.to_string()))?;
+        Ok(AppApiKey {
+            api_key: str_field(&app, "api_key")?,
+            api_key_id: str_field(&app, "api_key_id")?,
+            app_id: str_field(&app, "app_id")?,
+        })
+    }
+
+    /// Returns the resources of an app released on deletion, with the filestore decrypted.
+    #[instrument(skip_all)]
+    pub async fn deletion_details(
+        &self,
+        app_name: &str,
+    ) -> Result<AppDeletionDetails, AppRepositoryError> {
+        let mut app = self
+            .find_app_by_name("deletion_details", app_name)
+            .await?
+            .ok_or_else(|| AppRepositoryError::AppNotFound(app_name.to_string()))?;
+        // Decrypt the hint descriptions of the filestores published for deletion
+        self.app_state
+            .decrypt_fields(app_name, &mut app)
+            .await
+            .map_err(|e| AppRepositoryError::Decryption {
+                app_name: app_name.to_string(),
+                message: e.to_string(),
+            })?;
+        let filestore = app
+            .get("app_datasource")
+            .and_then(|app_datasource| app_datasource.get("filestore"))
+            .ok_or(AppRepositoryError::MissingField("filestore"))?;
+        let filestore = HashMap::<String, Vec<FileStore>>::deserialize(filestore).map_err(|e| {
+            AppRepositoryError::Malformed {
+                app_name: app_name.to_string(),
+                field: "filestore",
+                message: e.to_string(),
+            }
+        })?;
+        Ok(AppDeletionDetails {
+            sqs_key: str_field(&app, "sqs_key")?,
+            api_key_id: str_field(&app, "api_key_id")?,
+            filestore,
+        })
+    }
+
+    /// Returns the stored filestores of an app, keyed by source type, with the hint descriptions decrypted.
+    /// The filestores are returned as stored, so a caller writing them back keeps the fields it does not know.
+    #[instrument(skip_all)]
+    pub async fn filestores(
+        &self,
+        app_name: &str,
+    ) -> Result<serde_json::Map<String, serde_json::Value>, AppRepositoryError> {
+        let mut app = self
+            .find_app_by_name("filestores", app_name)
+            .await?
+            .ok_or_else(|| AppRepositoryError::AppNotFound(app_name.to_string()))?;
+        self.app_state
+            .decrypt_fields(app_name, &mut app)
+            .await
+            .map_err(|e| AppRepositoryError::Decryption {
+                app_name: app_name.to_string(),
+                message: e.to_string(),
+            })?;
+        match app.pointer_mut("/app_datasource/filestore") {
+            Some(serde_json::Value::Object(filestores)) => Ok(std::mem::take(filestores)),
+            _ => Err(AppRepositoryError::MissingField("filestore")),
+        }
+    }
+
+    /// Returns the sources of the datasource of an app, keyed like their ingestion status.
+    #[instrument(skip_all)]
+    pub async fn ingestion_sources(
+        &self,
+        app_name: &str,
+    ) -> Result<Vec<String>, AppRepositoryError> {
+        let app = self
+            .find_app_by_name("ingestion_sources", app_name)
+            .await?
+            .ok_or_else(|| AppRepositoryError::AppNotFound(app_name.to_string()))?;
+        Ok(app_sources(&app))
+    }
+
+    /// Returns the generated config of an app, with its collection and S3 prefixes.
+    #[instrument(skip_all)]
+    pub async fn generated_config(
+        &self,
+        app_name: &str,
+    ) -> Result<serde_json::Value, AppRepositoryError> {
+        let mut app = self
+            .find_app_by_name("generated_config", app_name)
+            .await?
+            .ok_or_else(|| AppRepositoryError::AppNotFound(app_name.to_string()))?;
+        app.get_mut("generated_config")
+            .map(serde_json::Value::take)
+            .ok_or(AppRepositoryError::MissingField("generated_config"))
+    }
+
+    /// Returns the residency of an app, `None` for the primary cluster or an unknown app.
+    #[instrument(skip_all)]
+    pub async fn residency(&self, app_name: &str) -> Result<Option<String>, AppRepositoryError> {
+        let app = self.find_app_by_name("residency", app_name).await?;
+        Ok(app
+            .as_ref()
+            .and_then(|app| app.get("residency"))
+            .and_then(serde_json::Value::as_str)
+            .map(str::to_string))
+    }
+
+    /// Returns the rate limit of the end users of an app, `None` if unlimited or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn user_rate_limit(
+        &self,
+        app_name: &str,
+    ) -> Result<Option<UserRateLimit>, AppRepositoryError> {
+        self.optional_field(app_name, "user_rate_limit").await
+    }
+
+    /// Returns the allowlist and denylist of the end users of an app, `None` if unset or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn user_access_list(
+        &self,
+        app_name: &str,
+    ) -> Result<Option<UserAccessList>, AppRepositoryError> {
+        self.optional_field(app_name, "user_access_list").await
+    }
+
+    /// Returns the row filter templates of the datastore tables of an app, empty if unset or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn row_filters(&self, app_name: &str) -> Result<Vec<RowFilter>, AppRepositoryError> {
+        Ok(self
+            .optional_field(app_name, ROW_FILTERS_FIELD)
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Returns the labeled filestores and datastores of an app, empty if unset or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn source_labels(
+        &self,
+        app_name: &str,
+    ) -> Result<Vec<LabeledSource>, AppRepositoryError> {
+        Ok(self
+            .optional_field(app_name, SOURCE_LABELS_FIELD)
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Returns the Kafka topic of an app, `None` if it shares the onboarding topic or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn kafka_topic(&self, app_name: &str) -> Result<Option<String>, AppRepositoryError> {
+        self.optional_field(app_name, KAFKA_TOPIC_FIELD).await
+    }
+
+    /// Returns the onboarding state of an app, `None` if onboarded before the states were introduced.
+    #[instrument(skip_all)]
+    pub async fn onboarding_state(
+        &self,
+        app_name: &str,
+    ) -> Result<Option<OnboardingState>, AppRepositoryError> {
+        let app = self
+            .find_app_by_name(ONBOARDING_STATE_FIELD, app_name)
+            .await?
+            .ok_or_else(|| AppRepositoryError::AppNotFound(app_name.to_string()))?;
+        match app.get(ONBOARDING_STATE_FIELD) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(value) => OnboardingProgress::deserialize(value)
+                .map(|progress| Some(progress.state))
+                .map_err(|e| AppRepositoryError::Malformed {
+                    app_name: app_name.to_string(),
+                    field: ONBOARDING_STATE_FIELD,
+                    message: e.to_string(),
+                }),
+        }
+    }
+
+    /// Returns the names of all the apps.
+    #[instrument(skip_all)]
+    pub async fn app_names(&self) -> Result<Vec<String>, AppRepositoryError> {
+        let start = Instant::now();
+        let pipeline = vec![
+            doc! {"$project": {"_id": 0, "app_name": 1}},
+            doc! {"$sort": {"app_name": 1}},
+        ];
+        let apps = self
+            .app_state
+            .db
+            .aggregate(
+                self.collection_name(),
+                pipeline,
+                &self.app_state.options::<QueryOptions>(),
+            )
+            .await
+            .map_err(AppRepositoryError::Query)?;
+        debug!(
+            message = format!(
+                "App lookup 'app_names' took {} ms.",
+                start.elapsed().as_millis()
+            )
+        );
+        Ok(apps
+            .iter()
+            .filter_map(|app| app.get("app_name").and_then(serde_json::Value::as_str))
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Returns the names of the apps whose ingestion is paused.
+    #[instrument(skip_all)]
+    pub async fn paused_apps(&self) -> Result<Vec<String>, AppRepositoryError> {
+        let start = Instant::now();
+        let pipeline = vec![
+            doc! {"$match": {"ingestion.state": IngestionState::Paused.as_str()}},
+            doc! {"$project": {"_id": 0, "app_name": 1}},
+            doc! {"$sort": {"app_name": 1}},
+        ];
+        let apps = self
+            .app_state
+            .db
+            .aggregate(
+                self.collection_name(),
+                pipeline,
+                &self.app_state.options::<QueryOptions>(),
+            )
+            .await
+            .map_err(AppRepositoryError::Query)?;
+        debug!(
+            message = format!(
+                "App lookup 'paused_apps' took {} ms.",
+                start.elapsed().as_millis()
+            )
+        );
+        Ok(apps
+            .iter()
+            .filter_map(|app| app.get("app_name").and_then(serde_json::Value::as_str))
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Returns true if the retrieval queries of an app are normalized, false if unset or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn query_normalization(&self, app_name: &str) -> Result<bool, AppRepositoryError> {
+        Ok(self
+            .optional_field(app_name, "query_normalization")
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Returns true if the end user IDs of an app are pseudonymized, false if unset or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn pseudonymize_user_ids(&self, app_name: &str) -> Result<bool, AppRepositoryError> {
+        Ok(self
+            .optional_field(app_name, "pseudonymize_user_ids")
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Returns the prompt templates of an app, empty if unset or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn prompt_templates(
+        &self,
+        app_name: &str,
+    ) -> Result<Vec<PromptTemplate>, AppRepositoryError> {
+        Ok(self
+            .optional_field(app_name, PROMPT_TEMPLATES_FIELD)
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Returns the answer sinks of an app, empty if unset or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn answer_sinks(
+        &self,
+        app_name: &str,
+    ) -> Result<Vec<AnswerSink>, AppRepositoryError> {
+        Ok(self
+            .optional_field(app_name, ANSWER_SINKS_FIELD)
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Returns the experiments of an app, empty if unset or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn experiments(&self, app_name: &str) -> Result<Vec<Experiment>, AppRepositoryError> {
+        Ok(self
+            .optional_field(app_name, EXPERIMENTS_FIELD)
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Returns the shadow traffic of an app, `None` if unset or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn shadow_traffic(
+        &self,
+        app_name: &str,
+    ) -> Result<Option<ShadowTraffic>, AppRepositoryError> {
+        self.optional_field(app_name, SHADOW_TRAFFIC_FIELD).await
+    }
+
+    /// Returns the additional API keys of an app, empty if unset or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn app_keys(&self, app_name: &str) -> Result<Vec<AppKey>, AppRepositoryError> {
+        Ok(self
+            .optional_field(app_name, APP_KEYS_FIELD)
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Returns the expiry of the primary API key of an app, never expiring if unset or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn primary_key_expiry(
+        &self,
+        app_name: &str,
+    ) -> Result<KeyExpiry, AppRepositoryError> {
+        Ok(self
+            .optional_field(app_name, PRIMARY_KEY_EXPIRY_FIELD)
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Returns the API keys with an expiry date of all the apps, primary and additional.
+    #[instrument(skip_all)]
+    pub async fn expiring_keys(&self) -> Result<Vec<ExpiringKey>, AppRepositoryError> {
+        let start = Instant::now();
+        let pipeline = vec![
+            doc! {"$match": {"$or": [
+                {format!("{}.expires_at", PRIMARY_KEY_EXPIRY_FIELD): {"$type": "string"}},
+                {format!("{}.expires_at", APP_KEYS_FIELD): {"$type": "string"}},
+            ]}},
+            doc! {"$project": {
+                "_id": 0,
+                "app_name": 1,
+                "api_key_id": 1,
+                "notification_url": 1,
+                PRIMARY_KEY_EXPIRY_FIELD: 1,
+                APP_KEYS_FIELD: 1,
+            }},
+            doc! {"$sort": {"app_name": 1}},
+        ];
+        let apps = self
+            .app_state
+            .db
+            .aggregate(
+                self.collection_name(),
+                pipeline,
+                &self.app_state.options::<QueryOptions>(),
+            )
+            .await
+            .map_err(AppRepositoryError::Query)?;
+        debug!(
+            message = format!(
+                "App lookup 'expiring_keys' took {} ms.",
+                start.elapsed().as_millis()
+            )
+        );
+        Ok(apps.iter().flat_map(expiring_keys).collect())
+    }
+
+    /// Returns the tier of an app, `None` if unset or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn tier(&self, app_name: &str) -> Result<Option<String>, AppRepositoryError> {
+        self.optional_field(app_name, "tier").await
+    }
+
+    /// Returns the LLMs allowed for the retrievals of an app, empty if unset or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn allowed_models(
+        &self,
+        app_name: &str,
+    ) -> Result<Vec<LlmModel>, AppRepositoryError> {
+        Ok(self
+            .optional_field(app_name, "allowed_models")
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Returns the history retention of an app, `None` if unset or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn history_retention(
+        &self,
+        app_name: &str,
+    ) -> Result<Option<HistoryRetention>, AppRepositoryError> {
+        self.optional_field(app_name, HISTORY_RETENTION_FIELD).await
+    }
+
+    /// Returns the names of all the apps with their history retention, `None` if unset.
+    #[instrument(skip_all)]
+    pub async fn history_retentions(
+        &self,
+    ) -> Result<Vec<(String, Option<HistoryRetention>)>, AppRepositoryError> {
+        let start = Instant::now();
+        let pipeline = vec![
+            doc! {"$project": {"_id": 0, "app_name": 1, HISTORY_RETENTION_FIELD: 1}},
+            doc! {"$sort": {"app_name": 1}},
+        ];
+        let apps = self
+            .app_state
+            .db
+            .aggregate(
+                self.collection_name(),
+                pipeline,
+                &self.app_state.options::<QueryOptions>(),
+            )
+            .await
+            .map_err(AppRepositoryError::Query)?;
+        debug!(
+            message = format!(
+                "App lookup 'history_retentions' took {} ms.",
+                start.elapsed().as_millis()
+            )
+        );
+        let mut retentions = Vec::with_capacity(apps.len());
+        for app in &apps {
+            let Some(app_name) = app.get("app_name").and_then(serde_json::Value::as_str) else {
+                continue;
+            };
+            let retention = match app.get(HISTORY_RETENTION_FIELD) {
+                None | Some(serde_json::Value::Null) => None,
+                Some(value) => Some(HistoryRetention::deserialize(value).map_err(|e| {
+                    AppRepositoryError::Malformed {
+                        app_name: app_name.to_string(),
+                        field: HISTORY_RETENTION_FIELD,
+                        message: e.to_string(),
+                    }
+                })?),
+            };
+            retentions.push((app_name.to_string(), retention));
+        }
+        Ok(retentions)
+    }
+
+    /// Returns the automatic ingestion retry policy of an app, `None` if unset or for an unknown app.
+    #[instrument(skip_all)]
+    pub async fn ingestion_retry_policy(
+        &self,
+        app_name: &str,
+    ) -> Result<Option<IngestionRetryPolicy>, AppRepositoryError> {
+        self.optional_field(app_name, INGESTION_RETRY_FIELD).await
+    }
+
+    /// Returns the names of the apps with an enabled automatic ingestion retry policy, with their policy.
+    #[instrument(skip_all)]
+    pub async fn ingestion_retry_policies(
+        &self,
+    ) -> Result<Vec<(String, IngestionRetryPolicy)>, AppRepositoryError> {
+        let start = Instant::now();
+        let enabled_field = format!("{}.enabled", INGESTION_RETRY_FIELD);
+        let pipeline = vec![
+            doc! {"$match": {enabled_field: true}},
+            doc! {"$project": {"_id": 0, "app_name": 1, INGESTION_RETRY_FIELD: 1}},
+            doc! {"$sort": {"app_name": 1}},
+        ];
+        let apps = self
+            .app_state
+            .db
+            .aggregate(
+                self.collection_name(),
+                pipeline,
+                &self.app_state.options::<QueryOptions>(),
+            )
+            .await
+            .map_err(AppRepositoryError::Query)?;
+        debug!(
+            message = format!(
+                "App lookup 'ingestion_retry_policies' took {} ms.",
+                start.elapsed().as_millis()
+            )
+        );
+        let mut policies = Vec::with_capacity(apps.len());
+        for app in &apps {
+            let Some(app_name) = app.get("app_name").and_then(serde_json::Value::as_str) else {
+                continue;
+            };
+            let Some(value) = app.get(INGESTION_RETRY_FIELD) else {
+                continue;
+            };
+            let policy = IngestionRetryPolicy::deserialize(value).map_err(|e| {
+                AppRepositoryError::Malformed {
+                    app_name: app_name.to_string(),
+                    field: INGESTION_RETRY_FIELD,
+                    message: e.to_string(),
+                }
+            })?;
+            policies.push((app_name.to_string(), policy));
+        }
+        Ok(policies)
+    }
+
+    /// Reads an optional typed field of an app document, `None` if unset or for an unknown app.
+    async fn optional_field<T: DeserializeOwned>(
+        &self,
+        app_name: &str,
+        field: &'static str,
+    ) -> Result<Option<T>, AppRepositoryError> {
+        let app = self.find_app_by_name(field, app_name).await?;
+        match app.as_ref().and_then(|app| app.get(field)) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(value) => {
+                T::deserialize(value)
+                    .map(Some)
+                    .map_err(|e| AppRepositoryError::Malformed {
+                        app_name: app_name.to_string(),
+                        field,
+                        message: e.to_string(),
+                    })
+            }
+        }
+    }
+}
+
+/// Reads a string field of an app document.
+fn str_field(app: &serde_json::Value, field: &'static str) -> Result<String, AppRepositoryError> {
+    app.get(field)
+        .and_then(serde_json::Value::as_str)
+        .map(str::to_string)
+        .ok_or(AppRepositoryError::MissingField(field))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_str_field() {
+        let app = json!({"app_name": "app100", "app_id": 1});
+        assert_eq!(str_field(&app, "app_name").unwrap(), "app100");
+        assert!(matches!(
+            str_field(&app, "app_id"),
+            Err(AppRepositoryError::MissingField("app_id"))
+        ));
+    }
+
+    #[test]
+    fn test_success_app_repository_error_status_code() {
+        let (status_code, _) = AppRepositoryError::AppNotFound("app100".to_string()).into();
+        assert_eq!(status_code, StatusCode::NOT_FOUND);
+        let (status_code, _) = AppRepositoryError::Decryption {
+            app_name: "app100".to_string(),
+            message: "no data key".to_string(),
+        }
+        .into();
+        assert_eq!(status_code, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_success_app_repository_per_request() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let cached = |apps: &AppRepository| {
+                apps.documents
+                    .as_ref()
+                    .map(|documents| documents.lock().unwrap().len())
+            };
+
+            // The lookups of a request share the documents, unknown apps included
+            let apps = app_state.request_apps();
+            assert!(apps.pseudonymize_user_ids("app100").await.is_ok());
+            assert!(apps.row_filters("app100").await.is_ok());
+            assert_eq!(apps.tier("non-existing-app").await.unwrap(), None);
+            assert_eq!(cached(&apps), Some(2));
+            assert_eq!(
+                apps.api_key("app100").await.unwrap().api_key,
+                app_state.apps().api_key("app100").await.unwrap().api_key
+            );
+
+            // The other repositories fetch the document on every lookup
+            let apps = app_state.apps();
+            assert!(apps.row_filters("app100").await.is_ok());
+            assert_eq!(cached(&apps), None);
+        });
+    }
+
+    #[test]
+    fn test_success_app_repository_lookups() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let apps = app_state.apps();
+
+            assert!(apps.exists("app100").await.unwrap());
+            assert!(!apps.exists("non-existing-app").await.unwrap());
+            assert!(matches!(
+                apps.api_key("non-existing-app").await,
+                Err(AppRepositoryError::AppNotFound(_))
+            ));
+            assert!(matches!(
+                apps.app_name_by_api_key("non_existent_api_key").await,
+                Err(AppRepositoryError::ApiKeyNotFound)
+            ));
+            assert_eq!(
+                apps.api_key_owner("1ytmOsUYKI2ZGg7WzzSfH3YU87i6UtZ50uMgVCc5")
+                    .await
+                    .unwrap(),
+                ApiKeyOwner {
+                    app_name: "app100".to_string(),
+                    app_key: None,
+                }
+            );
+            assert!(apps.app_keys("non-existing-app").await.unwrap().is_empty());
+            assert_eq!(
+                apps.primary_key_expiry("non-existing-app").await.unwrap(),
+                KeyExpiry::default()
+            );
+            assert_eq!(apps.tier("non-existing-app").await.unwrap(), None);
+            assert!(apps.expiring_keys().await.is_ok());
+            assert_eq!(apps.residency("non-existing-app").await.unwrap(), None);
+            assert_eq!(
+                apps.user_rate_limit("non-existing-app").await.unwrap(),
+                None
+            );
+            assert_eq!(
+                apps.user_access_list("non-existing-app").await.unwrap(),
+                None
+            );
+            assert!(apps.paused_apps().await.is_ok());
+            assert!(apps.app_names().await.is_ok());
+            assert_eq!(apps.kafka_topic("non-existing-app").await.unwrap(), None);
+            assert_eq!(
+                apps.history_retention("non-existing-app").await.unwrap(),
+                None
+            );
+            assert!(apps.history_retentions().await.is_ok());
+            assert!(apps
+                .ingestion_retry_policy("non-existing-app")
+                .await
+                .unwrap()
+                .is_none());
+            assert!(apps.ingestion_retry_policies().await.is_ok());
+            assert!(!apps.query_normalization("non-existing-app").await.unwrap());
+            assert!(!apps
+                .pseudonymize_user_ids("non-existing-app")
+                .await
+                .unwrap());
+            assert!(apps
+                .prompt_templates("non-existing-app")
+                .await
+                .unwrap()
+                .is_empty());
+            assert!(apps
+                .answer_sinks("non-existing-app")
+                .await
+                .unwrap()
+                .is_empty());
+            assert!(apps
+                .experiments("non-existing-app")
+                .await
+                .unwrap()
+                .is_empty());
+            assert!(apps
+                .source_labels("non-existing-app")
+                .await
+                .unwrap()
+                .is_empty());
+            assert!(apps
+                .shadow_traffic("non-existing-app")
+                .await
+                .unwrap()
+                .is_none());
+            assert!(matches!(
+                apps.onboarding_state("non-existing-app").await,
+                Err(AppRepositoryError::AppNotFound(_))
+            ));
+            assert!(matches!(
+                apps.filestores("non-existing-app").await,
+                Err(AppRepositoryError::AppNotFound(_))
+            ));
+        });
+    }
+}
@@ -0,0 +1,225 @@
+/*
+ * Created Date:  Jul 16, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the per-app Kafka topics, an alternative deployment mode to the shared onboarding topic.
+//! With `kafka_client.app_topics.enabled`, each app gets its own topic `{topic_prefix}{app_name}`, created through
+//! the Kafka admin API at onboarding and deleted at app deletion. The onboarding/update events of the app are
+//! published to its topic, the other events (deletion, config change, ingestion control, hint change) stay on their
+//! shared topics. The topic is recorded on the app document, so it is deleted with the app even after the mode is
+//! turned off.
+//! In the local development mode the topics are not created nor deleted, the in-memory event producer takes any
+//! topic.
+//!
+
+use crate::configuration::settings::AppTopicSettings;
+use crate::service::state::AppState;
+use axum::{http::StatusCode, Json};
+use rdkafka::admin::{AdminClient, AdminOptions, NewTopic, TopicReplication};
+use rdkafka::client::DefaultClientContext;
+use rdkafka::config::ClientConfig;
+use rdkafka::types::RDKafkaErrorCode;
+use serde_json::json;
+use std::time::Duration;
+use tracing::{error, info, instrument};
+
+/// Name of the field of the app document holding the topic of the app.
+pub const KAFKA_TOPIC_FIELD: &str = "kafka_topic";
+/// Maximum length of a Kafka topic name.
+pub const MAX_TOPIC_NAME_LENGTH: usize = 249;
+/// Default prefix of the app topics.
+pub const DEFAULT_TOPIC_PREFIX: &str = "app-";
+/// Time budget of a topic creation/deletion.
+const ADMIN_OPERATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum AppTopicError {
+    #[error("Invalid Kafka topic name '{0}'. Topic names are at most 249 letters, digits, '.', '_' and '-'.")]
+    InvalidName(String),
+    #[error("Failed to create Kafka topic '{topic}'. Error: {message}")]
+    Create { topic: String, message: String },
+    #[error("Failed to delete Kafka topic '{topic}'. Error: {message}")]
+    Delete { topic: String, message: String },
+}
+
+impl From<AppTopicError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: AppTopicError) -> Self {
+        let status_code = match e {
+            AppTopicError::InvalidName(_) => StatusCode::BAD_REQUEST,
+            AppTopicError::Create { .. } | AppTopicError::Delete { .. } => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        let error_message = e.to_string();
+        error!(ext_message = error_message, message = error_message);
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Returns the topic name of an app.
+pub fn app_topic_name(
+    settings: &AppTopicSettings,
+    app_name: &str,
+) -> Result<String, AppTopicError> {
+    let prefix = settings
+        .topic_prefix
+        .as_deref()
+        .unwrap_or(DEFAULT_TOPIC_PREFIX);
+    let topic = format!("{}{}", prefix, app_name);
+    let is_valid = topic.len() <= MAX_TOPIC_NAME_LENGTH
+        && topic != "."
+        && topic != ".."
+        && topic
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if !is_valid {
+        return Err(AppTopicError::InvalidName(topic));
+    }
+    Ok(topic)
+}
+
+/// Returns the topic of an app, `None` if the apps share the onboarding topic.
+pub fn app_topic(app_state: &AppState, app_name: &str) -> Result<Option<String>, AppTopicError> {
+    match &app_state.app_settings.kafka_client.app_topics {
+        Some(settings) if settings.enabled => app_topic_name(settings, app_name).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Creates the topic of an app, if the apps have their own topics. An existing topic is kept, so onboarding retries
+/// and updates of apps onboarded before the mode was turned on are handled alike.
+#[instrument(skip_all)]
+pub async fn create_app_topic(
+    app_state: &AppState,
+    app_name: &str,
+) -> Result<Option<String>, AppTopicError> {
+    let Some(settings) = app_state
+        .app_settings
+        .kafka_client
+        .app_topics
+        .as_ref()
+        .filter(|settings| settings.enabled)
+    else {
+        return Ok(None);
+    };
+    let topic = app_topic_name(settings, app_name)?;
+    if app_state.local_dev.is_some() {
+        return Ok(Some(topic));
+    }
+    let create_error = |message: String| AppTopicError::Create {
+        topic: topic.clone(),
+        message,
+    };
+    let new_topic = NewTopic::new(
+        &topic,
+        settings.partitions.unwrap_or(1),
+        TopicReplication::Fixed(settings.replication_factor.unwrap_or(1)),
+    );
+    let results = admin_client(app_state)
+        .map_err(create_error)?
+        .create_topics(&[new_topic], &admin_options())
+        .await
+        .map_err(|e| create_error(e.to_string()))?;
+    for result in results {
+        match result {
+            Ok(_) | Err((_, RDKafkaErrorCode::TopicAlreadyExists)) => {}
+            Err((_, code)) => return Err(create_error(code.to_string())),
+        }
+    }
+    info!(
+        app_name = app_name,
+        message = format!("Kafka topic '{}' ready.", topic)
+    );
+    Ok(Some(topic))
+}
+
+/// Deletes the topic of an app. A topic already gone is not an error.
+#[instrument(skip_all)]
+pub async fn delete_app_topic(
+    app_state: &AppState,
+    app_name: &str,
+    topic: &str,
+) -> Result<(), AppTopicError> {
+    if app_state.local_dev.is_some() {
+        return Ok(());
+    }
+    let delete_error = |message: String| AppTopicError::Delete {
+        topic: topic.to_string(),
+        message,
+    };
+    let results = admin_client(app_state)
+        .map_err(delete_error)?
+        .delete_topics(&[topic], &admin_options())
+        .await
+        .map_err(|e| delete_error(e.to_string()))?;
+    for result in results {
+        match result {
+            Ok(_) | Err((_, RDKafkaErrorCode::UnknownTopicOrPartition)) => {}
+            Err((_, code)) => return Err(delete_error(code.to_string())),
+        }
+    }
+    info!(
+        app_name = app_name,
+        message = format!("Kafka topic '{}' deleted.", topic)
+    );
+    Ok(())
+}
+
+fn admin_client(app_state: &AppState) -> Result<AdminClient<DefaultClientContext>, String> {
+    ClientConfig::new()
+        .set("bootstrap.servers", &app_state.app_settings.kafka_brokers)
+        .create()
+        .map_err(|e| format!("Failed to build Kafka admin client. Error: {}", e))
+}
+
+fn admin_options() -> AdminOptions {
+    AdminOptions::new().operation_timeout(Some(ADMIN_OPERATION_TIMEOUT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(topic_prefix: Option<&str>) -> AppTopicSettings {
+        AppTopicSettings {
+            enabled: true,
+            topic_prefix: topic_prefix.map(str::to_string),
+            partitions: None,
+            replication_factor: None,
+        }
+    }
+
+    #[test]
+    fn test_app_topic_name() {
+        assert_eq!(
+            app_topic_name(&settings(None), "app100"),
+            Ok("app-app100".to_string())
+        );
+        assert_eq!(
+            app_topic_name(&settings(Some("tresleai.ingest.")), "app100"),
+            Ok("tresleai.ingest.app100".to_string())
+        );
+        assert_eq!(
+            app_topic_name(&settings(None), "my app"),
+            Err(AppTopicError::InvalidName("app-my app".to_string()))
+        );
+        let long_name = "a".repeat(MAX_TOPIC_NAME_LENGTH);
+        assert!(app_topic_name(&settings(None), &long_name).is_err());
+    }
+
+    #[test]
+    fn test_app_topic_error_status_code() {
+        let (status_code, _) = AppTopicError::InvalidName("app-my app".to_string()).into();
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        let (status_code, _) = AppTopicError::Create {
+            topic: "app-app100".to_string(),
+            message: "broker down".to_string(),
+        }
+        .into();
+        assert_eq!(status_code, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
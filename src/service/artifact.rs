@@ -0,0 +1,243 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the artifact locations of an app, the S3 prefixes holding the objects generated for it:
+//! - `artifacts`, the artifact prefix of the app in `answer_offload.bucket` (`artifacts/{app_name}/` by default),
+//!   holding the full responses of the oversized answers. Unset without `answer_offload.bucket`.
+//! - `knowledge_extraction`, `parsed_files`, `logs`, `audit` and `metrics`, the storage prefixes of the generated
+//!   config of the app under its `s3_prefix`, scoped to the app: `{s3_prefix}{s3_storage_prefix}/{app_name}/`.
+//!
+//! The objects are listed with download links presigned for `artifacts.download_url_ttl_seconds` (900 by default).
+//! Only the objects under a location of the app can be deleted.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{ArtifactSettings, TresleFacadeServiceSettings};
+use crate::service::answer_offload::AnswerOffloadOptions;
+use axum::{http::StatusCode, Json};
+use serde::Serialize;
+use serde_json::json;
+use std::path::{Component, Path};
+use std::time::Duration;
+use tracing::error;
+use utoipa::ToSchema;
+
+/// Default validity of the download links, in seconds.
+const DEFAULT_DOWNLOAD_URL_TTL_SECONDS: u64 = 900;
+/// Category of the artifact prefix of the app.
+pub const ARTIFACTS_CATEGORY: &str = "artifacts";
+/// Categories of the storage prefixes of the generated config, with their pointer in the generated config.
+const GENERATED_STORAGE_PREFIXES: [(&str, &str); 5] = [
+    (
+        "knowledge_extraction",
+        "/knowledge_graph_config/vectordb_config/s3_storage_prefix",
+    ),
+    ("parsed_files", "/parser_config/s3_storage_prefix"),
+    ("logs", "/logging/s3_storage_prefix"),
+    ("audit", "/audit/s3_storage_prefix"),
+    ("metrics", "/metric/s3_storage_prefix"),
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArtifactError {
+    #[error("Unknown artifact category '{0}'.")]
+    UnknownCategory(String),
+    #[error("Object '{0}' is not an artifact of the app.")]
+    InvalidKey(String),
+    #[error("Failed to access the artifacts in bucket '{bucket}'. Error: {message}")]
+    Store { bucket: String, message: String },
+}
+
+impl From<ArtifactError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: ArtifactError) -> Self {
+        let status_code = match e {
+            ArtifactError::UnknownCategory(_) | ArtifactError::InvalidKey(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ArtifactError::Store { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let error_message = e.to_string();
+        error!(ext_message = error_message, message = error_message);
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Artifact options: download link validity.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArtifactOptions {
+    pub download_url_ttl: Duration,
+}
+
+impl SettingsOptions for ArtifactOptions {
+    type Settings = ArtifactSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&ArtifactSettings> {
+        settings.artifacts.as_ref()
+    }
+
+    fn from_settings(settings: Option<&ArtifactSettings>) -> Self {
+        ArtifactOptions {
+            download_url_ttl: Duration::from_secs(
+                settings
+                    .and_then(|settings| settings.download_url_ttl_seconds)
+                    .unwrap_or(DEFAULT_DOWNLOAD_URL_TTL_SECONDS),
+            ),
+        }
+    }
+}
+
+/// Prefix of a bucket holding artifacts of an app.
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ArtifactLocation {
+    pub category: String,
+    pub bucket: String,
+    pub prefix: String,
+}
+
+/// Object under an artifact location.
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Artifact {
+    pub key: String,
+    pub size_bytes: u64,
+    /// Presigned download link, unset if it couldn't be signed.
+    pub download_url: Option<String>,
+}
+
+/// Splits an `s3://bucket/prefix` URI into its bucket and prefix.
+pub fn parse_s3_uri(uri: &str) -> Option<(String, String)> {
+    let location = uri.strip_prefix("s3://")?;
+    let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+    if bucket.is_empty() {
+        return None;
+    }
+    Some((bucket.to_string(), prefix.to_string()))
+}
+
+/// Returns the artifact locations of an app, from its generated config and the answer offload options.
+pub fn artifact_locations(
+    app_name: &str,
+    generated_config: &serde_json::Value,
+    answer_offload: &AnswerOffloadOptions,
+) -> Vec<ArtifactLocation> {
+    let mut locations = Vec::new();
+    if let Some(bucket) = &answer_offload.bucket {
+        locations.push(ArtifactLocation {
+            category: ARTIFACTS_CATEGORY.to_string(),
+            bucket: bucket.clone(),
+            prefix: answer_offload.app_prefix(app_name),
+        });
+    }
+    let Some((bucket, base)) = generated_config
+        .get("s3_prefix")
+        .and_then(serde_json::Value::as_str)
+        .and_then(parse_s3_uri)
+    else {
+        return locations;
+    };
+    let base = match base.trim_matches('/') {
+        "" => String::new(),
+        base => format!("{}/", base),
+    };
+    for (category, pointer) in GENERATED_STORAGE_PREFIXES {
+        if let Some(storage_prefix) = generated_config
+            .pointer(pointer)
+            .and_then(serde_json::Value::as_str)
+            .filter(|storage_prefix| !storage_prefix.trim_matches('/').is_empty())
+        {
+            locations.push(ArtifactLocation {
+                category: category.to_string(),
+                bucket: bucket.clone(),
+                prefix: format!("{}{}/{}/", base, storage_prefix.trim_matches('/'), app_name),
+            });
+        }
+    }
+    locations
+}
+
+/// Returns the location of the category holding an object, refusing the keys outside of it.
+pub fn artifact_location<'a>(
+    locations: &'a [ArtifactLocation],
+    category: &str,
+    key: &str,
+) -> Result<&'a ArtifactLocation, ArtifactError> {
+    let location = locations
+        .iter()
+        .find(|location| location.category == category)
+        .ok_or_else(|| ArtifactError::UnknownCategory(category.to_string()))?;
+    let inside = key.starts_with(&location.prefix)
+        && key.len() > location.prefix.len()
+        && Path::new(key)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !inside {
+        return Err(ArtifactError::InvalidKey(key.to_string()));
+    }
+    Ok(location)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_artifact_locations() {
+        let generated_config = json!({
+            "s3_prefix": "s3://tresleai-knowledgebase-test/temp/",
+            "knowledge_graph_config": {"vectordb_config": {"s3_storage_prefix": "knowledge-extraction"}},
+            "parser_config": {"s3_storage_prefix": "parsed-files"},
+            "logging": {"s3_storage_prefix": "logs"},
+            "audit": {"s3_storage_prefix": ""},
+        });
+        let mut answer_offload = AnswerOffloadOptions::from_settings(None);
+        answer_offload.bucket = Some("tresleai-artifacts".to_string());
+
+        let locations = artifact_locations("app100", &generated_config, &answer_offload);
+        assert_eq!(
+            locations
+                .iter()
+                .map(|location| (location.category.as_str(), location.prefix.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("artifacts", "artifacts/app100/"),
+                ("knowledge_extraction", "temp/knowledge-extraction/app100/"),
+                ("parsed_files", "temp/parsed-files/app100/"),
+                ("logs", "temp/logs/app100/"),
+            ]
+        );
+        assert_eq!(locations[1].bucket, "tresleai-knowledgebase-test");
+    }
+
+    #[test]
+    fn test_failure_artifact_location() {
+        let locations = vec![ArtifactLocation {
+            category: "logs".to_string(),
+            bucket: "bucket".to_string(),
+            prefix: "temp/logs/app100/".to_string(),
+        }];
+        assert!(artifact_location(&locations, "logs", "temp/logs/app100/run.log").is_ok());
+        assert!(matches!(
+            artifact_location(&locations, "audit", "temp/logs/app100/run.log"),
+            Err(ArtifactError::UnknownCategory(_))
+        ));
+        for key in [
+            "temp/logs/app200/run.log",
+            "temp/logs/app100/",
+            "temp/logs/app100/../app200/run.log",
+        ] {
+            assert!(matches!(
+                artifact_location(&locations, "logs", key),
+                Err(ArtifactError::InvalidKey(_))
+            ));
+        }
+        assert_eq!(
+            parse_s3_uri("s3://bucket"),
+            Some(("bucket".to_string(), String::new()))
+        );
+        assert_eq!(parse_s3_uri("https://bucket/key"), None);
+    }
+}
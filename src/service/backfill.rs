@@ -0,0 +1,605 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the backfills run on demand from the admin endpoints, instead of ad-hoc scripts run against
+//! the production DocumentDB:
+//! - `ui_summary_counts` sets the `count` of the UI summary documents stored without a positive count to 1, each of
+//!   them records a single call;
+//! - `metric_rollups` rebuilds the numeric `metrics_value_ms` of the duration metrics stored as strings, which the
+//!   metric rollups aggregate (see `metric_migration`);
+//! - `timestamps` converts the timestamps still stored as strings into BSON dates, like the `timestamps_as_dates`
+//!   migration (see `timestamp`).
+//!
+//! The backfills are guarded: they are disabled unless `backfills.enabled` is set, and a backfill runs once at a time.
+//! A backfill runs in the background as a tracked job stored in `backfills.collection`, with its step, progress and
+//! outcome. With `dry_run`, the documents to backfill are only counted. A job not updated for
+//! `backfills.stale_after_seconds`, e.g. on a crashed replica, no longer blocks its backfill.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{BackfillSettings, TresleFacadeServiceSettings};
+use crate::service::metric_migration::{
+    count_unmigrated_duration_metrics, migrate_duration_metrics,
+};
+use crate::service::metrics::{MetricRecord, BACKFILL_DIMENSION, STATUS_DIMENSION};
+use crate::service::migration::{migrate_timestamps, timestamp_fields};
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::state::AppState;
+use crate::service::timestamp::to_bson_datetime;
+use axum::{http::StatusCode, Json};
+use chrono::{DateTime, Utc};
+use mongodb::bson::{doc, oid::ObjectId, to_document, Document};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Default collection of the backfill jobs.
+pub const DEFAULT_BACKFILL_COLLECTION: &str = "backfill_jobs";
+/// Default number of seconds after which a job not updated no longer blocks its backfill.
+const DEFAULT_STALE_AFTER_SECONDS: u64 = 900;
+/// Number of documents backfilled per batch.
+const BACKFILL_BATCH_SIZE: i64 = 500;
+/// Number of jobs returned by the listing.
+pub const BACKFILL_JOBS_LIMIT: i64 = 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackfillError {
+    #[error("Backfills are disabled. Set backfills.enabled to run them.")]
+    Disabled,
+    #[error("Unknown backfill '{0}'. Supported backfills are ui_summary_counts, metric_rollups and timestamps.")]
+    UnknownBackfill(String),
+    #[error("Backfill '{backfill}' is already running as job '{job_id}'.")]
+    AlreadyRunning { backfill: String, job_id: String },
+    #[error("No backfill job found with ID '{0}'.")]
+    UnknownJob(String),
+    #[error("Failed to store the backfill job. Error: {0}")]
+    Store(String),
+}
+
+impl From<BackfillError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: BackfillError) -> Self {
+        let status_code = match e {
+            BackfillError::Disabled => StatusCode::FORBIDDEN,
+            BackfillError::UnknownBackfill(_) | BackfillError::UnknownJob(_) => {
+                StatusCode::NOT_FOUND
+            }
+            BackfillError::AlreadyRunning { .. } => StatusCode::CONFLICT,
+            BackfillError::Store(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let error_message = e.to_string();
+        match status_code {
+            StatusCode::INTERNAL_SERVER_ERROR => {
+                error!(ext_message = error_message, message = error_message)
+            }
+            _ => debug!(message = error_message),
+        }
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Backfill options: activation, job collection and staleness.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackfillOptions {
+    pub enabled: bool,
+    pub collection: String,
+    pub stale_after: Duration,
+}
+
+impl SettingsOptions for BackfillOptions {
+    type Settings = BackfillSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&BackfillSettings> {
+        settings.backfills.as_ref()
+    }
+
+    fn from_settings(settings: Option<&BackfillSettings>) -> Self {
+        BackfillOptions {
+            enabled: settings
+                .and_then(|settings| settings.enabled)
+                .unwrap_or(false),
+            collection: settings
+                .and_then(|settings| settings.collection.clone())
+                .unwrap_or_else(|| DEFAULT_BACKFILL_COLLECTION.to_string()),
+            stale_after: Duration::from_secs(
+                settings
+                    .and_then(|settings| settings.stale_after_seconds)
+                    .unwrap_or(DEFAULT_STALE_AFTER_SECONDS),
+            ),
+        }
+    }
+}
+
+/// A backfill run from the admin endpoints.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Backfill {
+    UiSummaryCounts,
+    MetricRollups,
+    Timestamps,
+}
+
+impl Backfill {
+    pub const ALL: [Backfill; 3] = [
+        Backfill::UiSummaryCounts,
+        Backfill::MetricRollups,
+        Backfill::Timestamps,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Backfill::UiSummaryCounts => "ui_summary_counts",
+            Backfill::MetricRollups => "metric_rollups",
+            Backfill::Timestamps => "timestamps",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Backfill> {
+        Backfill::ALL
+            .into_iter()
+            .find(|backfill| backfill.as_str() == name)
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Backfill::UiSummaryCounts => {
+                "Sets the count of the UI summary documents stored without a positive count to 1."
+            }
+            Backfill::MetricRollups => {
+                "Rebuilds the numeric value of the duration metrics stored as strings, aggregated by the metric rollups."
+            }
+            Backfill::Timestamps => {
+                "Converts the timestamps of the app, UI summary, token usage and history documents stored as strings into dates."
+            }
+        }
+    }
+}
+
+/// Status of a backfill job.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BackfillStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl BackfillStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            BackfillStatus::Queued => "queued",
+            BackfillStatus::Running => "running",
+            BackfillStatus::Succeeded => "succeeded",
+            BackfillStatus::Failed => "failed",
+        }
+    }
+}
+
+/// Backfill job document, stored in the backfill job collection.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct BackfillJob {
+    pub job_id: String,
+    pub backfill: Backfill,
+    /// The documents to backfill are only counted.
+    pub dry_run: bool,
+    pub status: BackfillStatus,
+    pub message: String,
+    /// Step in progress, e.g. the collection being backfilled.
+    #[serde(default)]
+    pub step: Option<String>,
+    #[serde(default)]
+    pub steps_done: u64,
+    #[serde(default)]
+    pub steps_total: u64,
+    /// Number of documents backfilled so far, or to backfill with `dry_run`.
+    #[serde(default)]
+    pub documents: u64,
+    #[serde(with = "crate::service::timestamp::bson_datetime")]
+    #[schema(value_type = String)]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "crate::service::timestamp::bson_datetime")]
+    #[schema(value_type = String)]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl BackfillJob {
+    fn new(backfill: Backfill, dry_run: bool) -> Self {
+        let now = Utc::now();
+        BackfillJob {
+            job_id: Uuid::new_v4().to_string(),
+            backfill,
+            dry_run,
+            status: BackfillStatus::Queued,
+            message: format!("Backfill '{}' queued.", backfill.as_str()),
+            step: None,
+            steps_done: 0,
+            steps_total: 0,
+            documents: 0,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    /// Message of the outcome of a successful job.
+    fn success_message(&self) -> String {
+        if self.dry_run {
+            format!(
+                "Dry run of backfill '{}': {} document(s) to backfill.",
+                self.backfill.as_str(),
+                self.documents
+            )
+        } else {
+            format!(
+                "Backfill '{}' backfilled {} document(s).",
+                self.backfill.as_str(),
+                self.documents
+            )
+        }
+    }
+}
+
+/// Filter of the jobs of a backfill still running at `now`.
+fn running_jobs_filter(
+    backfill: Backfill,
+    options: &BackfillOptions,
+    now: DateTime<Utc>,
+) -> Document {
+    let stale_before = now - chrono::Duration::from_std(options.stale_after).unwrap_or_default();
+    doc! {
+        "backfill": backfill.as_str(),
+        "status": {"$in": ["queued", "running"]},
+        "updated_at": {"$gt": to_bson_datetime(stale_before)},
+    }
+}
+
+/// Stores a job of a backfill and runs it in the background. Fails if backfills are disabled or if the backfill is
+/// already running.
+#[instrument(skip_all)]
+pub async fn start_backfill(
+    app_state: &Arc<AppState>,
+    backfill: Backfill,
+    dry_run: bool,
+) -> Result<BackfillJob, BackfillError> {
+    let options = app_state.options::<BackfillOptions>();
+    if !options.enabled {
+        return Err(BackfillError::Disabled);
+    }
+    let running = app_state
+        .db
+        .get_document(
+            &options.collection,
+            running_jobs_filter(backfill, &options, Utc::now()),
+        )
+        .await
+        .map_err(|e| BackfillError::Store(e.to_string()))?;
+    if let Some(running) = running {
+        return Err(BackfillError::AlreadyRunning {
+            backfill: backfill.as_str().to_string(),
+            job_id: running["job_id"].as_str().unwrap_or_default().to_string(),
+        });
+    }
+
+    let job = BackfillJob::new(backfill, dry_run);
+    let document = to_document(&job).map_err(|e| BackfillError::Store(e.to_string()))?;
+    app_state
+        .db
+        .create_document(&options.collection, document)
+        .await
+        .map_err(|e| BackfillError::Store(e.to_string()))?;
+    info!(
+        message = format!(
+            "Backfill '{}' queued as job '{}'{}.",
+            backfill.as_str(),
+            job.job_id,
+            if dry_run { " (dry run)" } else { "" }
+        )
+    );
+    tokio::spawn(run_backfill(Arc::clone(app_state), job.clone()));
+    Ok(job)
+}
+
+/// Returns a backfill job.
+pub async fn backfill_job(
+    app_state: &AppState,
+    job_id: &str,
+) -> Result<BackfillJob, BackfillError> {
+    let options = app_state.options::<BackfillOptions>();
+    let job = app_state
+        .db
+        .get_document(&options.collection, doc! {"job_id": job_id})
+        .await
+        .map_err(|e| BackfillError::Store(e.to_string()))?
+        .ok_or_else(|| BackfillError::UnknownJob(job_id.to_string()))?;
+    serde_json::from_value(job).map_err(|e| BackfillError::Store(e.to_string()))
+}
+
+/// Returns the latest backfill jobs, the latest first.
+pub async fn backfill_jobs(app_state: &AppState) -> Result<Vec<BackfillJob>, BackfillError> {
+    let options = app_state.options::<BackfillOptions>();
+    let pipeline = vec![
+        doc! {"$sort": {"created_at": -1}},
+        doc! {"$limit": BACKFILL_JOBS_LIMIT},
+        doc! {"$project": {"_id": 0}},
+    ];
+    let jobs = app_state
+        .db
+        .aggregate(
+            &options.collection,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await
+        .map_err(|e| BackfillError::Store(e.to_string()))?;
+    jobs.into_iter()
+        .map(|job| serde_json::from_value(job).map_err(|e| BackfillError::Store(e.to_string())))
+        .collect()
+}
+
+/// Runs a backfill job, recording its progress and outcome.
+#[instrument(skip_all)]
+async fn run_backfill(app_state: Arc<AppState>, mut job: BackfillJob) {
+    let start = Instant::now();
+    job.status = BackfillStatus::Running;
+    job.message = format!("Backfill '{}' in progress.", job.backfill.as_str());
+    store_progress(&app_state, &mut job).await;
+
+    let result = match job.backfill {
+        Backfill::UiSummaryCounts => backfill_ui_summary_counts(&app_state, &mut job).await,
+        Backfill::MetricRollups => backfill_metric_rollups(&app_state, &mut job).await,
+        Backfill::Timestamps => backfill_timestamps(&app_state, &mut job).await,
+    };
+    match result {
+        Ok(()) => {
+            job.status = BackfillStatus::Succeeded;
+            job.message = job.success_message();
+            info!(message = job.message);
+        }
+        Err(message) => {
+            job.status = BackfillStatus::Failed;
+            job.message = format!(
+                "Backfill '{}' failed after {} document(s). Error: {}",
+                job.backfill.as_str(),
+                job.documents,
+                message
+            );
+            error!(ext_message = job.message, message = job.message);
+        }
+    }
+    store_progress(&app_state, &mut job).await;
+    let status = match job.status {
+        BackfillStatus::Succeeded => "success",
+        _ => "failure",
+    };
+    app_state
+        .record_metric(
+            MetricRecord::duration_ms("Backfill Duration", start.elapsed().as_millis() as i64)
+                .dimension(BACKFILL_DIMENSION, job.backfill.as_str())
+                .dimension(STATUS_DIMENSION, status),
+        )
+        .await;
+}
+
+/// Stores the status and progress of a job. Failures are logged, the job carries on.
+async fn store_progress(app_state: &AppState, job: &mut BackfillJob) {
+    job.updated_at = Utc::now();
+    let fields = doc! {
+        "status": job.status.as_str(),
+        "message": &job.message,
+        "step": job.step.as_deref(),
+        "steps_done": job.steps_done as i64,
+        "steps_total": job.steps_total as i64,
+        "documents": job.documents as i64,
+        "updated_at": to_bson_datetime(job.updated_at),
+    };
+    if let Err(e) = app_state
+        .db
+        .update_document(
+            &app_state.options::<BackfillOptions>().collection,
+            doc! {"job_id": &job.job_id},
+            fields,
+        )
+        .await
+    {
+        let error_message = format!(
+            "Failed to update backfill job '{}'. Error: {}",
+            job.job_id, e
+        );
+        error!(ext_message = error_message, message = error_message);
+    }
+}
+
+/// Filter of the UI summary documents without a positive count.
+fn uncounted_ui_summaries() -> Document {
+    doc! {"count": {"$not": {"$gte": 1}}}
+}
+
+/// Sets the count of the UI summary documents stored without a positive count to 1, batch by batch.
+async fn backfill_ui_summary_counts(
+    app_state: &AppState,
+    job: &mut BackfillJob,
+) -> Result<(), String> {
+    let collection_name = &app_state
+        .app_settings
+        .mongo_db
+        .mongo_db_ui_summary_collection;
+    job.step = Some(collection_name.clone());
+    job.steps_total = 1;
+    if job.dry_run {
+        let pipeline = vec![
+            doc! {"$match": uncounted_ui_summaries()},
+            doc! {"$count": "count"},
+        ];
+        let counts = app_state
+            .db
+            .aggregate(
+                collection_name,
+                pipeline,
+                &app_state.options::<QueryOptions>(),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        job.documents = counts
+            .first()
+            .and_then(|count| count.get("count"))
+            .and_then(serde_json::Value::as_u64)
+            .unwrap_or_default();
+        job.steps_done = 1;
+        return Ok(());
+    }
+    loop {
+        let batch_pipeline = vec![
+            doc! {"$match": uncounted_ui_summaries()},
+            doc! {"$limit": BACKFILL_BATCH_SIZE},
+            doc! {"$project": {"_id": {"$toString": "$_id"}}},
+        ];
+        let batch = app_state
+            .db
+            .aggregate(
+                collection_name,
+                batch_pipeline,
+                &app_state.options::<QueryOptions>(),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        let ids: Vec<ObjectId> = batch
+            .iter()
+            .filter_map(|document| document.get("_id").and_then(serde_json::Value::as_str))
+            .filter_map(|id| ObjectId::parse_str(id).ok())
+            .collect();
+        if ids.is_empty() {
+            job.steps_done = 1;
+            return Ok(());
+        }
+        let mut batch_backfilled = 0;
+        for id in ids {
+            match app_state
+                .db
+                .update_document(collection_name, doc! {"_id": id}, doc! {"count": 1_i64})
+                .await
+            {
+                Ok(_) => batch_backfilled += 1,
+                Err(e) => debug!(message = format!("Failed to backfill UI summary {}: {}", id, e)),
+            }
+        }
+        // Stop if nothing in the batch could be backfilled, otherwise the same batch is fetched again
+        if batch_backfilled == 0 {
+            return Err(format!(
+                "no UI summary document of '{}' could be backfilled.",
+                collection_name
+            ));
+        }
+        job.documents += batch_backfilled;
+        store_progress(app_state, job).await;
+    }
+}
+
+/// Rebuilds the numeric value of the duration metrics stored as strings.
+async fn backfill_metric_rollups(
+    app_state: &Arc<AppState>,
+    job: &mut BackfillJob,
+) -> Result<(), String> {
+    job.step = Some(
+        app_state
+            .app_settings
+            .app_generated_config
+            .knowledge_graph_config
+            .metric
+            .collection
+            .clone(),
+    );
+    job.steps_total = 1;
+    job.documents = if job.dry_run {
+        count_unmigrated_duration_metrics(app_state).await?
+    } else {
+        migrate_duration_metrics(Arc::clone(app_state)).await
+    } as u64;
+    job.steps_done = 1;
+    Ok(())
+}
+
+/// Converts the timestamps stored as strings into BSON dates, collection by collection.
+async fn backfill_timestamps(app_state: &AppState, job: &mut BackfillJob) -> Result<(), String> {
+    let timestamp_fields = timestamp_fields(app_state).await?;
+    job.steps_total = timestamp_fields.len() as u64;
+    for timestamp_field in timestamp_fields {
+        job.step = Some(format!(
+            "{}.{}",
+            timestamp_field.collection_name, timestamp_field.field
+        ));
+        store_progress(app_state, job).await;
+        job.documents += migrate_timestamps(app_state, &timestamp_field, job.dry_run).await? as u64;
+        job.steps_done += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_backfill_parse() {
+        for backfill in Backfill::ALL {
+            assert_eq!(Backfill::parse(backfill.as_str()), Some(backfill));
+            assert_eq!(serde_json::to_value(backfill).unwrap(), backfill.as_str());
+        }
+        assert_eq!(Backfill::parse("rollups"), None);
+    }
+
+    #[test]
+    fn test_success_backfill_options_from_settings() {
+        let options = BackfillOptions::from_settings(None);
+        assert!(!options.enabled);
+        assert_eq!(options.collection, DEFAULT_BACKFILL_COLLECTION);
+        let settings = BackfillSettings {
+            enabled: Some(true),
+            collection: None,
+            stale_after_seconds: Some(60),
+        };
+        let options = BackfillOptions::from_settings(Some(&settings));
+        assert!(options.enabled);
+        assert_eq!(options.stale_after, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_success_backfill_job() {
+        let mut job = BackfillJob::new(Backfill::Timestamps, true);
+        job.documents = 3;
+        assert_eq!(job.status, BackfillStatus::Queued);
+        assert!(job.success_message().starts_with("Dry run"));
+
+        // The job is stored with dates, and read back
+        let document = to_document(&job).unwrap();
+        assert!(document.get_datetime("created_at").is_ok());
+        let read: BackfillJob = mongodb::bson::from_document(document).unwrap();
+        assert_eq!(read.job_id, job.job_id);
+
+        let options = BackfillOptions::from_settings(None);
+        let filter = running_jobs_filter(Backfill::Timestamps, &options, Utc::now());
+        assert_eq!(filter.get_str("backfill").unwrap(), "timestamps");
+    }
+
+    #[test]
+    fn test_failure_start_backfill_disabled() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Backfills are disabled by default
+            let result = start_backfill(&app_state, Backfill::UiSummaryCounts, true).await;
+            assert!(matches!(result, Err(BackfillError::Disabled)));
+        });
+    }
+}
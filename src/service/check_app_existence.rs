@@ -7,11 +7,9 @@
 //! process.
 
 use crate::service::state::AppState;
-use api_utils::errors::error_interceptor::ErrorInterceptor;
 use axum::{http::StatusCode, Json};
-use mongodb::bson::doc;
 use std::sync::Arc;
-use tracing::{debug, error, info, instrument};
+use tracing::instrument;
 
 /// Asynchronous function to check the existence of an app in DocumentDB.
 #[instrument(skip_all)]
@@ -19,38 +17,16 @@ pub async fn check_app_existence(
     app_state: &Arc<AppState>,
     app_name: &String,
 ) -> Result<bool, (StatusCode, Json<serde_json::Value>)> {
-    let filter = doc! {"app_name": app_name};
-    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
-
-    match app_state
-        .db
-        .get_document_count(collection_name, filter)
-        .await
-        .map_err(ErrorInterceptor::from)
-    {
-        Ok(app_count) => {
-            if app_count > 0 {
-                let message = format!("App {} exists in DocumentDB.", app_name);
-                info!(app_name = app_name, message = message);
-                Ok(true)
-            } else {
-                let message = format!("No app found with name '{}'.", app_name);
-                debug!(message = message);
-                Ok(false)
-            }
-        }
-        Err(e) => {
-            let error_message = format!(
-                "Failed to check existence of app '{}' in DocumentDB. Error: {}",
-                app_name, e
-            );
-            error!(ext_message = error_message, message = error_message);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({ "status": "error","message": error_message})),
-            ))
-        }
-    }
+    app_state.apps().exists(app_name).await.map_err(|e| {
+        let error_message = format!(
+            "Failed to check existence of app '{}' in DocumentDB. Error: {}",
+            app_name, e
+        );
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "status": "error","message": error_message})),
+        )
+    })
 }
 
 #[cfg(test)]
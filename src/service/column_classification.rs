@@ -0,0 +1,255 @@
+/*
+ * Created Date:  Jul 12, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the column-level PII tagging of the datastore tables of an app.
+//! The `pii` and `sensitivity` tags of the columns are part of the onboarding request and are forwarded as-is in
+//! the onboarding Kafka events, so the ingestion and retrieval layers can mask the tagged columns. The stored
+//! datasource only keeps the name and description of the columns, so the tags are persisted on the app document as
+//! a flat `column_classifications` list, which is also what the governance review reads in the app GET.
+//! When an app is read back in the shape of the onboarding request, the tags are merged into its columns.
+//!
+
+use crate::onboarding::schema::app_onboarding_request::{AppDataSource, Sensitivity};
+use axum::{http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::error;
+use utoipa::ToSchema;
+
+/// Name of the field of the app document holding the column classifications.
+pub const COLUMN_CLASSIFICATIONS_FIELD: &str = "column_classifications";
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ColumnClassificationError {
+    #[error("Column '{column}' of table '{table}' is tagged as PII and cannot have the sensitivity 'public'.")]
+    PublicPii { table: String, column: String },
+}
+
+impl From<ColumnClassificationError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: ColumnClassificationError) -> Self {
+        let error_message = e.to_string();
+        error!(ext_message = error_message, message = error_message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Tags of a column of a datastore table, identified by the source type, host and database of its datastore.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ColumnClassification {
+    pub source_type: String,
+    pub host: String,
+    pub database: String,
+    pub table: String,
+    pub column: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pii: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensitivity: Option<Sensitivity>,
+}
+
+/// Validates the tags of the columns of a datasource. A PII column cannot be public.
+pub fn validate_column_tags(datasource: &AppDataSource) -> Result<(), ColumnClassificationError> {
+    for classification in column_classifications(datasource) {
+        if classification.pii == Some(true)
+            && classification.sensitivity == Some(Sensitivity::Public)
+        {
+            return Err(ColumnClassificationError::PublicPii {
+                table: classification.table,
+                column: classification.column,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Returns the classifications of the tagged columns of a datasource. Untagged columns are left out.
+pub fn column_classifications(datasource: &AppDataSource) -> Vec<ColumnClassification> {
+    let mut classifications = Vec::new();
+    for (source_type, datastores) in &datasource.datastore {
+        for datastore in datastores {
+            for table in &datastore.tables {
+                for column in table.columns.iter().flatten() {
+                    if column.pii.is_none() && column.sensitivity.is_none() {
+                        continue;
+                    }
+                    classifications.push(ColumnClassification {
+                        source_type: source_type.clone(),
+                        host: datastore.host.clone(),
+                        database: datastore.database.clone(),
+                        table: table.name.clone(),
+                        column: column.name.clone(),
+                        pii: column.pii,
+                        sensitivity: column.sensitivity,
+                    });
+                }
+            }
+        }
+    }
+    // The datastore map has no order, keep the stored list stable across updates
+    classifications.sort_by(|a, b| {
+        (&a.source_type, &a.host, &a.database, &a.table, &a.column).cmp(&(
+            &b.source_type,
+            &b.host,
+            &b.database,
+            &b.table,
+            &b.column,
+        ))
+    });
+    classifications
+}
+
+/// Merges the stored column classifications of an app document into the columns of its `app_datasource`, so the
+/// document can be read in the shape of the onboarding request. Documents without classifications are left as-is.
+pub fn merge_column_classifications(app: &mut serde_json::Value) {
+    let Some(classifications) = app
+        .get(COLUMN_CLASSIFICATIONS_FIELD)
+        .cloned()
+        .and_then(|value| serde_json::from_value::<Vec<ColumnClassification>>(value).ok())
+    else {
+        return;
+    };
+    for classification in classifications {
+        let tables = datastore_tables_mut(
+            app,
+            &classification.source_type,
+            &classification.host,
+            &classification.database,
+            &classification.table,
+        );
+        let columns = tables
+            .filter_map(|table| {
+                table
+                    .get_mut("columns")
+                    .and_then(serde_json::Value::as_array_mut)
+            })
+            .flatten()
+            .filter(|column| column["name"] == classification.column.as_str());
+        for column in columns {
+            if let Some(column) = column.as_object_mut() {
+                if let Some(pii) = classification.pii {
+                    column.insert("pii".to_string(), json!(pii));
+                }
+                if let Some(sensitivity) = classification.sensitivity {
+                    column.insert("sensitivity".to_string(), json!(sensitivity));
+                }
+            }
+        }
+    }
+}
+
+/// Returns the tables of the stored `app_datasource` of an app document matching a datastore table, identified by
+/// the source type, host and database of its datastore.
+pub fn datastore_tables_mut<'a>(
+    app: &'a mut serde_json::Value,
+    source_type: &'a str,
+    host: &'a str,
+    database: &'a str,
+    table: &'a str,
+) -> impl Iterator<Item = &'a mut serde_json::Value> + 'a {
+    app.pointer_mut("/app_datasource/datastore")
+        .and_then(|datastore| datastore.get_mut(source_type))
+        .and_then(serde_json::Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter(move |ds| ds["host"] == host && ds["database"] == database)
+        .filter_map(|ds| {
+            ds.get_mut("tables")
+                .and_then(serde_json::Value::as_array_mut)
+        })
+        .flatten()
+        .filter(move |t| t["name"] == table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datasource(pii: Option<bool>, sensitivity: Option<&str>) -> AppDataSource {
+        serde_json::from_value(json!({
+            "filestore": {},
+            "datastore": {
+                "aws_rds": [{
+                    "host": "db.example.com",
+                    "port": "5432",
+                    "username": null,
+                    "secret_name": null,
+                    "aws_service_name": null,
+                    "database": "crm",
+                    "db_type": "postgres",
+                    "descriptions": null,
+                    "region": null,
+                    "fact_phrases": null,
+                    "fact_words": null,
+                    "search_keywords": null,
+                    "summary": null,
+                    "tables": [{
+                        "name": "customers",
+                        "descriptions": "customers",
+                        "schema": null,
+                        "schema_json": null,
+                        "sample_rows": null,
+                        "fact_phrases": null,
+                        "fact_words": null,
+                        "search_keywords": null,
+                        "summary": null,
+                        "columns": [
+                            {"name": "id", "descriptions": "id"},
+                            {"name": "email", "descriptions": "email", "pii": pii, "sensitivity": sensitivity}
+                        ]
+                    }]
+                }]
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_column_classifications() {
+        let classifications = column_classifications(&datasource(Some(true), Some("confidential")));
+        assert_eq!(
+            classifications,
+            vec![ColumnClassification {
+                source_type: "aws_rds".to_string(),
+                host: "db.example.com".to_string(),
+                database: "crm".to_string(),
+                table: "customers".to_string(),
+                column: "email".to_string(),
+                pii: Some(true),
+                sensitivity: Some(Sensitivity::Confidential),
+            }]
+        );
+        assert!(column_classifications(&datasource(None, None)).is_empty());
+    }
+
+    #[test]
+    fn test_validate_column_tags() {
+        assert!(validate_column_tags(&datasource(Some(true), Some("restricted"))).is_ok());
+        assert!(validate_column_tags(&datasource(Some(false), Some("public"))).is_ok());
+        assert_eq!(
+            validate_column_tags(&datasource(Some(true), Some("public"))),
+            Err(ColumnClassificationError::PublicPii {
+                table: "customers".to_string(),
+                column: "email".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_merge_column_classifications() {
+        let tagged = datasource(Some(true), Some("restricted"));
+        let mut app = json!({
+            "app_datasource": datasource(None, None),
+            "column_classifications": column_classifications(&tagged),
+        });
+
+        merge_column_classifications(&mut app);
+
+        let merged: AppDataSource = serde_json::from_value(app["app_datasource"].clone()).unwrap();
+        assert_eq!(merged, tagged);
+    }
+}
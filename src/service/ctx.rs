@@ -0,0 +1,364 @@
+/*
+ * Created Date:  Jul 20, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the request context `Ctx`: the reference ID, task ID, app and start timestamp of a request,
+//! built once per request by the `record_request_context` middleware and stored in the request extensions.
+//! The handlers extract it instead of generating their own IDs. The app is the `app_name` path parameter, else the
+//! app of the `x-api-key` header when the app cache holds it, else the system app: building the context never costs
+//! a DocumentDB lookup, the handlers identifying the app by its API key resolve it themselves.
+//! The routes served before the context existed keep the service type of their task IDs (`GetApp`, `GetNodeCount`,
+//! `Retrieval`, ...), which the dashboards and alerts filter on. The service type of the other routes is derived
+//! from the method and the matched route, e.g. `GetAdminAppsHints` for `GET /api/v1.1/admin/apps/:app_name/hints`.
+//! The middleware records the ID document of every error response, unless the handler already recorded it, and
+//! returns the reference ID of every response in the `x-reference-id` header.
+//! The context carries the deadline of the request, when its route has a timeout (see `deadline`).
+//!
+
+use crate::service::deadline::{Deadline, DeadlineOptions};
+use crate::service::generate_and_insert_document::generate_id_document;
+use crate::service::state::AppState;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, MatchedPath, Path, Request, State},
+    http::{request::Parts, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use mongodb::bson::to_document;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::error;
+
+/// Header of the responses holding the reference ID of the request.
+pub const REFERENCE_ID_HEADER: &str = "x-reference-id";
+/// Header of the requests holding the API key of the app.
+const API_KEY_HEADER: &str = "x-api-key";
+/// Path parameter holding the name of the app.
+const APP_NAME_PARAM: &str = "app_name";
+/// Service types of the task IDs of the routes served before the context existed, by method and route.
+const LEGACY_SERVICE_TYPES: &[(&str, &str, &str)] = &[
+    ("POST", "/api/v1.0/retrieval", "Retrieval"),
+    ("GET", "/api/v1.1/admin/token", "GetKubToken"),
+    ("GET", "/api/v1.1/admin/apps", "GetAppList"),
+    ("GET", "/api/v1.1/admin/apps/:app_name", "GetApp"),
+    // The task ID of the deletion is the one of its first step, fetching the SQS key of the app
+    ("DELETE", "/api/v1.1/admin/apps/:app_name", "FetchSqsKey"),
+    (
+        "PATCH",
+        "/api/v1.1/admin/search/apps/:app_name",
+        "UpdateSearch",
+    ),
+    ("POST", "/api/v1.1/admin/apps/onboard", "Onboarding"),
+    ("POST", "/api/v1.1/admin/capture_tc", "CaptureT&C"),
+    ("GET", "/api/v1.1/admin/nodes/:app_name", "GetKNodeHandler"),
+    (
+        "GET",
+        "/api/v1.1/admin/nodes/errors/:app_name",
+        "GetNodeChart",
+    ),
+    (
+        "GET",
+        "/api/v1.1/admin/nodes/count/:app_name",
+        "GetNodeCount",
+    ),
+    (
+        "GET",
+        "/api/v1.1/admin/nodes/chart/:app_name",
+        "GetNodeChart",
+    ),
+    ("GET", "/api/v1.1/admin/metric/calls", "GetMetricCall"),
+    ("GET", "/api/v1.1/admin/metric/logs", "GetMetricError"),
+];
+
+/// Context of a request.
+#[derive(Debug, Clone)]
+pub struct Ctx {
+    pub reference_id: String,
+    pub task_id: String,
+    pub app_name: String,
+    pub service_type: String,
+    pub start: DateTime<Utc>,
+    /// Deadline of the request, forwarded to the downstream services.
+    pub deadline: Option<Deadline>,
+    recorded: Arc<AtomicBool>,
+}
+
+impl Ctx {
+    /// Builds the context of a request of an app, with new IDs.
+    pub fn new(app_state: &AppState, app_name: &str, service_type: &str) -> Self {
+        Ctx {
+            reference_id: app_state.id_generator.reference_id(),
+            task_id: app_state.id_generator.task_id(app_name, service_type),
+            app_name: app_name.to_string(),
+            service_type: service_type.to_string(),
+            start: Utc::now(),
+            deadline: None,
+            recorded: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Returns the message of the errors returned to the callers, with the reference ID of the request.
+    pub fn ext_message(&self, app_state: &AppState) -> String {
+        format!(
+            "{} Use reference ID: {}",
+            app_state.app_settings.general_message, self.reference_id
+        )
+    }
+
+    /// Marks the ID document of the request as recorded, when the handler wrote it itself.
+    pub fn mark_recorded(&self) {
+        self.recorded.store(true, Ordering::Relaxed);
+    }
+
+    /// Records the ID document of the request, once. Failures are logged and never fail the caller.
+    pub async fn record(&self, app_state: &AppState) {
+        if self.recorded.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let id_document = generate_id_document(
+            &self.app_name,
+            self.reference_id.clone(),
+            self.task_id.clone(),
+        )
+        .await;
+        let result = match to_document(&id_document) {
+            Ok(document) => app_state
+                .db
+                .create_document(
+                    &app_state.app_settings.mongo_db.mongo_db_id_collection,
+                    document,
+                )
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = result {
+            error!(
+                app_name = self.app_name,
+                task_id = self.task_id,
+                message = format!("Failed to record the ID document. Error: {}", e)
+            );
+        }
+    }
+}
+
+/// Returns the service type of a route: its legacy service type, else the one derived from the method and the route,
+/// e.g. `GetAdminAppsHints` for `GET /api/v1.1/admin/apps/:app_name/hints`.
+pub fn service_type(method: &Method, route: &str) -> String {
+    if let Some((_, _, legacy)) =
+        LEGACY_SERVICE_TYPES
+            .iter()
+            .find(|(legacy_method, legacy_route, _)| {
+                method.as_str() == *legacy_method && route == *legacy_route
+            })
+    {
+        return legacy.to_string();
+    }
+    let pascal_case = |word: &str| -> String {
+        word.split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                let mut chars = part.chars();
+                chars
+                    .next()
+                    .map(|first| {
+                        first.to_ascii_uppercase().to_string() + &chars.as_str().to_lowercase()
+                    })
+                    .unwrap_or_default()
+            })
+            .collect()
+    };
+    let is_version = |segment: &str| {
+        segment
+            .strip_prefix('v')
+            .is_some_and(|version| version.starts_with(|c: char| c.is_ascii_digit()))
+    };
+    let segments: String = route
+        .split('/')
+        .filter(|segment| {
+            !segment.is_empty()
+                && *segment != "api"
+                && !segment.starts_with(':')
+                && !is_version(segment)
+        })
+        .map(pascal_case)
+        .collect();
+    pascal_case(method.as_str()) + &segments
+}
+
+/// Resolves the app of a request: the `app_name` path parameter, else the app of the API key held by the app cache,
+/// else the system app.
+async fn request_app_name(parts: &mut Parts, app_state: &Arc<AppState>) -> String {
+    if let Ok(Path(params)) =
+        Path::<HashMap<String, String>>::from_request_parts(parts, app_state).await
+    {
+        if let Some(app_name) = params.get(APP_NAME_PARAM) {
+            return app_name.clone();
+        }
+    }
+    if let Some(api_key) = parts
+        .headers
+        .get(API_KEY_HEADER)
+        .and_then(|api_key| api_key.to_str().ok())
+    {
+        let stored_api_key = app_state.api_key_options().stored_api_key(api_key);
+        if let Some(app_name) = app_state
+            .app_cache
+            .get(&stored_api_key)
+            .and_then(|app| app.get("app_name")?.as_str().map(str::to_string))
+        {
+            return app_name;
+        }
+    }
+    app_state.app_settings.tracing_layer_system_app_name.clone()
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Ctx {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        app_state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(ctx) = parts.extensions.get::<Ctx>() {
+            return Ok(ctx.clone());
+        }
+        let route = parts
+            .extensions
+            .get::<MatchedPath>()
+            .map(|matched_path| matched_path.as_str().to_string())
+            .unwrap_or_else(|| parts.uri.path().to_string());
+        let app_name = request_app_name(parts, app_state).await;
+        let mut ctx = Ctx::new(app_state, &app_name, &service_type(&parts.method, &route));
+        ctx.deadline = app_state
+            .options::<DeadlineOptions>()
+            .deadline(&route, ctx.start);
+        parts.extensions.insert(ctx.clone());
+        Ok(ctx)
+    }
+}
+
+/// Middleware building the context of every request. The ID document of the error responses is recorded, and the
+/// reference ID is returned in the `x-reference-id` header.
+pub async fn record_request_context(
+    State(app_state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (mut parts, body) = request.into_parts();
+    let ctx = match Ctx::from_request_parts(&mut parts, &app_state).await {
+        Ok(ctx) => ctx,
+        Err(infallible) => match infallible {},
+    };
+    let mut response = next.run(Request::from_parts(parts, body)).await;
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        ctx.record(&app_state).await;
+    }
+    if let Ok(reference_id) = HeaderValue::from_str(&ctx.reference_id) {
+        response
+            .headers_mut()
+            .insert(REFERENCE_ID_HEADER, reference_id);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_service_type() {
+        assert_eq!(
+            service_type(&Method::GET, "/api/v1.1/admin/apps/:app_name/hints"),
+            "GetAdminAppsHints"
+        );
+        assert_eq!(
+            service_type(
+                &Method::PATCH,
+                "/api/v1.1/admin/apps/:app_name/generated-config"
+            ),
+            "PatchAdminAppsGeneratedConfig"
+        );
+        assert_eq!(
+            service_type(&Method::POST, "/api/v1.1/admin/trace/:reference_id/replay"),
+            "PostAdminTraceReplay"
+        );
+        // The legacy routes keep their service type
+        assert_eq!(
+            service_type(&Method::POST, "/api/v1.0/retrieval"),
+            "Retrieval"
+        );
+        assert_eq!(
+            service_type(&Method::GET, "/api/v1.1/admin/metric/calls"),
+            "GetMetricCall"
+        );
+        assert_eq!(
+            service_type(&Method::GET, "/api/v1.1/admin/apps/:app_name"),
+            "GetApp"
+        );
+        assert_eq!(
+            service_type(&Method::DELETE, "/api/v1.1/admin/apps/:app_name"),
+            "FetchSqsKey"
+        );
+        assert_eq!(
+            service_type(&Method::GET, "/api/v1.1/admin/nodes/count/:app_name"),
+            "GetNodeCount"
+        );
+        assert_eq!(
+            service_type(&Method::PATCH, "/api/v1.1/admin/search/apps/:app_name"),
+            "UpdateSearch"
+        );
+    }
+
+    #[test]
+    fn test_success_ctx_from_request_parts() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let (mut parts, _) = axum::http::Request::builder()
+                .uri("/api/v1.1/admin/metric/calls")
+                .body(())
+                .unwrap()
+                .into_parts();
+
+            // The context is built once and reused by the next extractions
+            let ctx = Ctx::from_request_parts(&mut parts, &app_state)
+                .await
+                .unwrap();
+            let again = Ctx::from_request_parts(&mut parts, &app_state)
+                .await
+                .unwrap();
+            assert_eq!(ctx.reference_id, again.reference_id);
+            assert_eq!(
+                ctx.app_name,
+                app_state.app_settings.tracing_layer_system_app_name
+            );
+            assert!(ctx.task_id.ends_with("-GetMetricCall"));
+        });
+    }
+
+    #[test]
+    fn test_success_ctx_mark_recorded() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let ctx = Ctx::new(&app_state, "app100", "Test");
+            assert!(ctx.task_id.ends_with("-app100-Test"));
+
+            // The clones share the recorded flag, the ID document is written once
+            ctx.clone().mark_recorded();
+            assert!(ctx.recorded.load(Ordering::Relaxed));
+        });
+    }
+}
@@ -0,0 +1,181 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the deadlines of the requests, forwarded to the downstream services.
+//! With `deadlines.route_timeouts_ms` (by matched route, e.g. `/api/v1.0/retrieval`) or
+//! `deadlines.default_timeout_ms`, a request gets a deadline its timeout after it started, carried by its `Ctx`.
+//! The calls made for the request to the knowledge engine and to the peripheral services carry the remaining budget
+//! in milliseconds in the `x-deadline-remaining-ms` header, and time out once it is spent, so the downstream services
+//! can stop the work the facade has given up on. The retrieval background task doesn't call the knowledge engine past
+//! the deadline of the retrieval, which is answered as failed instead.
+//! Without timeout, the requests have no deadline and the calls carry no budget.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{DeadlineSettings, TresleFacadeServiceSettings};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Header of the remaining budget of a call, in milliseconds.
+pub const DEADLINE_HEADER: &str = "x-deadline-remaining-ms";
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum DeadlineError {
+    #[error("Deadline of the request exceeded by {0} ms before calling the knowledge engine.")]
+    Exceeded(i64),
+}
+
+/// Deadline options: route timeouts bounding the forwarded deadlines. Requests have no deadline by default.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeadlineOptions {
+    pub default_timeout: Option<Duration>,
+    pub route_timeouts: HashMap<String, Duration>,
+}
+
+impl SettingsOptions for DeadlineOptions {
+    type Settings = DeadlineSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&DeadlineSettings> {
+        settings.deadlines.as_ref()
+    }
+
+    fn from_settings(settings: Option<&DeadlineSettings>) -> Self {
+        DeadlineOptions {
+            default_timeout: settings
+                .and_then(|settings| settings.default_timeout_ms)
+                .map(Duration::from_millis),
+            route_timeouts: settings
+                .and_then(|settings| settings.route_timeouts_ms.as_ref())
+                .map(|route_timeouts| {
+                    route_timeouts
+                        .iter()
+                        .map(|(route, timeout_ms)| {
+                            (route.clone(), Duration::from_millis(*timeout_ms))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl DeadlineOptions {
+    /// Returns the deadline of a request of a matched route, started at `start`.
+    pub fn deadline(&self, route: &str, start: DateTime<Utc>) -> Option<Deadline> {
+        let timeout = self
+            .route_timeouts
+            .get(route)
+            .copied()
+            .or(self.default_timeout)?;
+        Some(Deadline::after(start, timeout))
+    }
+}
+
+/// Deadline of a request.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline {
+    pub at: DateTime<Utc>,
+}
+
+impl Deadline {
+    /// Deadline `timeout` after `start`.
+    pub fn after(start: DateTime<Utc>, timeout: Duration) -> Self {
+        Deadline {
+            at: chrono::Duration::from_std(timeout)
+                .ok()
+                .and_then(|timeout| start.checked_add_signed(timeout))
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        }
+    }
+
+    /// Budget left at `now`, zero once the deadline is past.
+    pub fn remaining(&self, now: DateTime<Utc>) -> Duration {
+        (self.at - now).to_std().unwrap_or(Duration::ZERO)
+    }
+
+    /// Fails once the deadline is past, with the milliseconds it is exceeded by.
+    pub fn check(&self, now: DateTime<Utc>) -> Result<(), DeadlineError> {
+        if now < self.at {
+            Ok(())
+        } else {
+            Err(DeadlineError::Exceeded((now - self.at).num_milliseconds()))
+        }
+    }
+
+    /// Adds the remaining budget to a call, which times out once it is spent.
+    pub fn apply(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let remaining = self.remaining(Utc::now());
+        request
+            .header(DEADLINE_HEADER, remaining.as_millis().to_string())
+            .timeout(remaining)
+    }
+}
+
+/// Adds the remaining budget of an optional deadline to a call.
+pub fn with_deadline(
+    request: reqwest::RequestBuilder,
+    deadline: Option<&Deadline>,
+) -> reqwest::RequestBuilder {
+    match deadline {
+        Some(deadline) => deadline.apply(request),
+        None => request,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_deadline_options() {
+        let start = Utc::now();
+        assert!(DeadlineOptions::from_settings(None)
+            .deadline("/api/v1.0/retrieval", start)
+            .is_none());
+
+        let settings = DeadlineSettings {
+            default_timeout_ms: Some(30_000),
+            route_timeouts_ms: Some(HashMap::from([(
+                "/api/v1.0/retrieval".to_string(),
+                120_000,
+            )])),
+        };
+        let options = DeadlineOptions::from_settings(Some(&settings));
+        let deadline = options.deadline("/api/v1.0/retrieval", start).unwrap();
+        assert_eq!(deadline.remaining(start), Duration::from_secs(120));
+        let deadline = options.deadline("/api/v1.1/admin/apps", start).unwrap();
+        assert_eq!(deadline.remaining(start), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_failure_deadline_check() {
+        let start = Utc::now();
+        let deadline = Deadline::after(start, Duration::from_millis(500));
+        assert!(deadline.check(start).is_ok());
+        let late = start + chrono::Duration::milliseconds(700);
+        assert_eq!(deadline.check(late), Err(DeadlineError::Exceeded(200)));
+        assert_eq!(deadline.remaining(late), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_success_deadline_apply() {
+        let deadline = Deadline::after(Utc::now(), Duration::from_secs(10));
+        let request = with_deadline(
+            reqwest::Client::new().get("http://localhost/health"),
+            Some(&deadline),
+        )
+        .build()
+        .unwrap();
+        let remaining: u64 = request.headers()[DEADLINE_HEADER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(remaining > 9_000 && remaining <= 10_000);
+        assert!(request.timeout().is_some());
+    }
+}
@@ -0,0 +1,382 @@
+/*
+ * Created Date:  Aug 02, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the two-phase deletion of the large apps, so a single call can't destroy an app holding
+//! `deletion.confirmation_threshold_nodes` (10 000) knowledge nodes or more.
+//! The first `DELETE` of such an app deletes nothing: it returns a confirmation token with the summary of what the
+//! deletion destroys (the collections of the app and their document counts, its knowledge node count, its S3
+//! prefixes, API keys and Kafka topic). A second `DELETE` with the token, within `deletion.token_ttl_minutes` (15),
+//! executes the deletion. It must carry the credentials of a service account, its approver, which is sent to the
+//! audit microservice.
+//! A token is valid once, for its app, and a new request replaces the pending token of the app. The token is consumed
+//! with a single `findOneAndDelete` on the driver connection, so two concurrent confirmations can't both succeed.
+//! The pending deletions are stored in `deletion.collection` (`deletion-confirmations` by default), which is expected
+//! to carry a TTL index on `expires_at`, and hold the hash of the token rather than the token itself.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{DeletionSettings, TresleFacadeServiceSettings};
+use crate::onboarding::schema::app_onboarding_request::FileStore;
+use crate::service::driver::DriverError;
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::residency::APP_COLLECTION_SUFFIXES;
+use crate::service::state::AppState;
+use axum::{http::StatusCode, Json};
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::{self, doc, Document};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tracing::{debug, error};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Default number of knowledge nodes from which the deletion of an app is confirmed.
+const DEFAULT_CONFIRMATION_THRESHOLD_NODES: u64 = 10_000;
+/// Default validity of the confirmation tokens, in minutes.
+const DEFAULT_TOKEN_TTL_MINUTES: u64 = 15;
+/// Default collection of the pending deletions.
+const DEFAULT_DELETION_COLLECTION: &str = "deletion-confirmations";
+/// Suffix of the collection of the knowledge nodes of an app.
+const NODES_COLLECTION_SUFFIX: &str = "general";
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeletionError {
+    #[error("The deletion of app '{0}' is confirmed with the credentials of a service account, its approver.")]
+    MissingApprover(String),
+    #[error(
+        "Invalid or expired confirmation token for the deletion of app '{0}'. Request a new one."
+    )]
+    InvalidToken(String),
+    #[error("Failed to summarize the deletion of app '{app_name}'. Error: {message}")]
+    Summary { app_name: String, message: String },
+    #[error("Failed to store the pending deletion of app '{app_name}'. Error: {message}")]
+    Store { app_name: String, message: String },
+}
+
+impl From<DeletionError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: DeletionError) -> Self {
+        let status_code = match e {
+            DeletionError::MissingApprover(_) => StatusCode::FORBIDDEN,
+            DeletionError::InvalidToken(_) => StatusCode::BAD_REQUEST,
+            DeletionError::Summary { .. } | DeletionError::Store { .. } => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        let error_message = e.to_string();
+        match status_code {
+            StatusCode::INTERNAL_SERVER_ERROR => {
+                error!(ext_message = error_message, message = error_message)
+            }
+            _ => debug!(message = error_message),
+        }
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Two-phase deletion options: confirmation threshold, token validity and collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeletionOptions {
+    pub confirmation_threshold_nodes: u64,
+    pub token_ttl: Duration,
+    pub collection: String,
+}
+
+impl SettingsOptions for DeletionOptions {
+    type Settings = DeletionSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&DeletionSettings> {
+        settings.deletion.as_ref()
+    }
+
+    fn from_settings(settings: Option<&DeletionSettings>) -> Self {
+        DeletionOptions {
+            confirmation_threshold_nodes: settings
+                .and_then(|settings| settings.confirmation_threshold_nodes)
+                .unwrap_or(DEFAULT_CONFIRMATION_THRESHOLD_NODES),
+            token_ttl: Duration::minutes(
+                settings
+                    .and_then(|settings| settings.token_ttl_minutes)
+                    .unwrap_or(DEFAULT_TOKEN_TTL_MINUTES) as i64,
+            ),
+            collection: settings
+                .and_then(|settings| settings.collection.clone())
+                .unwrap_or_else(|| DEFAULT_DELETION_COLLECTION.to_string()),
+        }
+    }
+}
+
+impl DeletionOptions {
+    /// Whether the deletion of an app of the summary needs a confirmation token.
+    pub fn requires_confirmation(&self, summary: &DeletionSummary) -> bool {
+        summary.node_count >= self.confirmation_threshold_nodes
+    }
+}
+
+/// Collection of an app destroyed by its deletion.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct CollectionSummary {
+    pub name: String,
+    pub document_count: u64,
+}
+
+/// Summary of what the deletion of an app destroys.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct DeletionSummary {
+    pub collections: Vec<CollectionSummary>,
+    /// Number of knowledge nodes of the app.
+    pub node_count: u64,
+    /// Filestore URLs of the app, whose ingested data is deleted by the downstream services.
+    pub s3_prefixes: Vec<String>,
+    /// Number of API keys of the app, the primary key included.
+    pub api_key_count: usize,
+    pub kafka_topic: Option<String>,
+}
+
+/// Pending deletion of an app, as stored.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingDeletion {
+    pub app_name: String,
+    /// Hex SHA-256 of the confirmation token.
+    pub token_hash: String,
+    pub summary: DeletionSummary,
+    pub requested_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Returns the hex SHA-256 of a confirmation token.
+pub fn token_hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Summarizes what the deletion of an app destroys. The collections are counted on the cluster of the app.
+pub async fn deletion_summary(
+    app_state: &AppState,
+    app_name: &str,
+    filestore: &HashMap<String, Vec<FileStore>>,
+    api_key_count: usize,
+    kafka_topic: Option<String>,
+) -> Result<DeletionSummary, DeletionError> {
+    let summary_error = |message: String| DeletionError::Summary {
+        app_name: app_name.to_string(),
+        message,
+    };
+    let app_db = app_state
+        .app_db(app_name)
+        .await
+        .map_err(|e| summary_error(e.to_string()))?;
+    let query_options = app_state.options::<QueryOptions>();
+    let mut collections = Vec::new();
+    let mut node_count = 0;
+    for suffix in APP_COLLECTION_SUFFIXES {
+        let name = format!("{}-{}", app_name, suffix);
+        let document_count = app_db
+            .aggregate(&name, vec![doc! {"$count": "count"}], &query_options)
+            .await
+            .map_err(|e| summary_error(e.to_string()))?
+            .first()
+            .and_then(|count| count.get("count"))
+            .and_then(|count| count.as_u64())
+            .unwrap_or(0);
+        if suffix == NODES_COLLECTION_SUFFIX {
+            node_count = document_count;
+        }
+        collections.push(CollectionSummary {
+            name,
+            document_count,
+        });
+    }
+    let mut s3_prefixes: Vec<String> = filestore
+        .values()
+        .flatten()
+        .map(|filestore| filestore.url.clone())
+        .collect();
+    s3_prefixes.sort();
+    Ok(DeletionSummary {
+        collections,
+        node_count,
+        s3_prefixes,
+        api_key_count,
+        kafka_topic,
+    })
+}
+
+/// Stores the pending deletion of an app, replacing its pending one. Returns the confirmation token and its expiry.
+pub async fn request_deletion(
+    app_state: &AppState,
+    options: &DeletionOptions,
+    app_name: &str,
+    summary: DeletionSummary,
+) -> Result<(String, DateTime<Utc>), DeletionError> {
+    let store_error = |message: String| DeletionError::Store {
+        app_name: app_name.to_string(),
+        message,
+    };
+    let token = Uuid::new_v4().to_string();
+    let requested_at = Utc::now();
+    let pending = PendingDeletion {
+        app_name: app_name.to_string(),
+        token_hash: token_hash(&token),
+        summary,
+        requested_at,
+        expires_at: requested_at + options.token_ttl,
+    };
+    let mut document = bson::to_document(&pending).map_err(|e| store_error(e.to_string()))?;
+    // The BSON date of the TTL index
+    document.insert(
+        "expires_at",
+        bson::DateTime::from_millis(pending.expires_at.timestamp_millis()),
+    );
+    app_state
+        .db
+        .delete_document(&options.collection, doc! {"app_name": app_name})
+        .await
+        .map_err(|e| store_error(e.to_string()))?;
+    app_state
+        .db
+        .create_document(&options.collection, document)
+        .await
+        .map_err(|e| store_error(e.to_string()))?;
+    Ok((token, pending.expires_at))
+}
+
+/// Consumes the confirmation token of the pending deletion of an app. Returns the summary of the pending deletion.
+pub async fn confirm_deletion(
+    app_state: &AppState,
+    options: &DeletionOptions,
+    app_name: &str,
+    token: &str,
+) -> Result<DeletionSummary, DeletionError> {
+    let store_error = |message: String| DeletionError::Store {
+        app_name: app_name.to_string(),
+        message,
+    };
+    let collection = app_state
+        .driver_databases
+        .as_ref()
+        .ok_or(DriverError::NotConnected)
+        .and_then(|driver_databases| driver_databases.database(None))
+        .map_err(|e| store_error(e.to_string()))?
+        .collection::<Document>(&options.collection);
+    // The token is consumed even if expired, the TTL index may not have removed it yet
+    let pending = collection
+        .find_one_and_delete(
+            doc! {"app_name": app_name, "token_hash": token_hash(token)},
+            None,
+        )
+        .await
+        .map_err(|e| store_error(e.to_string()))?
+        .ok_or_else(|| DeletionError::InvalidToken(app_name.to_string()))?;
+    let expires_at = pending
+        .get_datetime("expires_at")
+        .map_err(|e| store_error(e.to_string()))?;
+    if expires_at.timestamp_millis() <= Utc::now().timestamp_millis() {
+        return Err(DeletionError::InvalidToken(app_name.to_string()));
+    }
+    pending_summary(&pending).map_err(store_error)
+}
+
+/// Returns the summary of a stored pending deletion.
+fn pending_summary(pending: &Document) -> Result<DeletionSummary, String> {
+    let summary = pending
+        .get("summary")
+        .cloned()
+        .ok_or_else(|| "The pending deletion has no summary.".to_string())?;
+    bson::from_bson(summary).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(node_count: u64) -> DeletionSummary {
+        DeletionSummary {
+            collections: vec![CollectionSummary {
+                name: "app100-general".to_string(),
+                document_count: node_count,
+            }],
+            node_count,
+            s3_prefixes: vec!["s3://bucket/folder/*".to_string()],
+            api_key_count: 1,
+            kafka_topic: None,
+        }
+    }
+
+    #[test]
+    fn test_success_deletion_options() {
+        let options = DeletionOptions::from_settings(None);
+        assert_eq!(options.confirmation_threshold_nodes, 10_000);
+        assert_eq!(options.token_ttl, Duration::minutes(15));
+        assert_eq!(options.collection, "deletion-confirmations");
+        assert!(!options.requires_confirmation(&summary(9_999)));
+        assert!(options.requires_confirmation(&summary(10_000)));
+
+        let settings = DeletionSettings {
+            confirmation_threshold_nodes: Some(0),
+            token_ttl_minutes: Some(5),
+            collection: None,
+        };
+        let options = DeletionOptions::from_settings(Some(&settings));
+        assert!(options.requires_confirmation(&summary(0)));
+        assert_eq!(options.token_ttl, Duration::minutes(5));
+    }
+
+    #[test]
+    fn test_success_token_hash() {
+        assert_eq!(token_hash("token"), token_hash("token"));
+        assert_ne!(token_hash("token"), token_hash("other-token"));
+        assert_eq!(token_hash("token").len(), 64);
+    }
+
+    #[test]
+    fn test_success_pending_deletion_serialization() {
+        let requested_at = Utc::now();
+        let pending = PendingDeletion {
+            app_name: "app100".to_string(),
+            token_hash: token_hash("token"),
+            summary: summary(20_000),
+            requested_at,
+            expires_at: requested_at + Duration::minutes(15),
+        };
+        let value = serde_json::to_value(&pending).unwrap();
+        assert_eq!(
+            serde_json::from_value::<PendingDeletion>(value).unwrap(),
+            pending
+        );
+    }
+
+    #[test]
+    fn test_success_pending_summary() {
+        let requested_at = Utc::now();
+        let pending = PendingDeletion {
+            app_name: "app100".to_string(),
+            token_hash: token_hash("token"),
+            summary: summary(20_000),
+            requested_at,
+            expires_at: requested_at + Duration::minutes(15),
+        };
+        let document = bson::to_document(&pending).unwrap();
+        assert_eq!(pending_summary(&document).unwrap(), summary(20_000));
+        assert!(pending_summary(&doc! {"app_name": "app100"}).is_err());
+    }
+
+    #[test]
+    fn test_success_deletion_error_status_code() {
+        let (status_code, _) = DeletionError::InvalidToken("app100".to_string()).into();
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        let (status_code, _) = DeletionError::MissingApprover("app100".to_string()).into();
+        assert_eq!(status_code, StatusCode::FORBIDDEN);
+        let (status_code, _) = DeletionError::Store {
+            app_name: "app100".to_string(),
+            message: "timeout".to_string(),
+        }
+        .into();
+        assert_eq!(status_code, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
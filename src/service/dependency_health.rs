@@ -0,0 +1,272 @@
+/*
+ * Created Date:  Aug 2, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the health of the downstream Tresleai services the facade depends on: the logging, audit and
+//! metric microservices and the knowledge engine (`core_service_url`).
+//! Their health endpoints (`dependencies.health_path`, `health` by default, overridden per URL name with
+//! `dependencies.health_paths`) are probed concurrently with a GET, each within `dependencies.timeout_ms`, so a
+//! facade incident is told apart from a downstream outage at a glance.
+//! A service is `healthy` if it answers with a 2xx status code within `dependencies.slow_threshold_ms`, `degraded` if
+//! it answers slower or with a 3xx/4xx status code, and `down` if it answers with a 5xx status code or not at all.
+//! The overall status is the worst status of the services.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{DependencyHealthSettings, TresleFacadeServiceSettings};
+use crate::service::state::AppState;
+use futures::future::join_all;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+const DEFAULT_HEALTH_PATH: &str = "health";
+const DEFAULT_TIMEOUT_MS: u64 = 2_000;
+const DEFAULT_SLOW_THRESHOLD_MS: u64 = 1_000;
+
+/// Downstream services probed, by name, with the name of their URL in `tresleai_urls`.
+const DEPENDENCIES: [(&str, &str); 4] = [
+    ("logging", "logging_service_url"),
+    ("audit", "audit_service_url"),
+    ("metric", "metric_service_url"),
+    ("knowledge_engine", "core_service_url"),
+];
+
+/// Dependency probe options: health paths, timeout and slow threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DependencyHealthOptions {
+    pub health_path: String,
+    pub health_paths: HashMap<String, String>,
+    pub timeout: Duration,
+    pub slow_threshold: Duration,
+}
+
+impl SettingsOptions for DependencyHealthOptions {
+    type Settings = DependencyHealthSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&DependencyHealthSettings> {
+        settings.dependencies.as_ref()
+    }
+
+    fn from_settings(settings: Option<&DependencyHealthSettings>) -> Self {
+        let millis = |value: Option<u64>, default: u64| {
+            Duration::from_millis(value.filter(|value| *value > 0).unwrap_or(default))
+        };
+        DependencyHealthOptions {
+            health_path: settings
+                .and_then(|settings| settings.health_path.clone())
+                .unwrap_or_else(|| DEFAULT_HEALTH_PATH.to_string()),
+            health_paths: settings
+                .and_then(|settings| settings.health_paths.clone())
+                .unwrap_or_default(),
+            timeout: millis(
+                settings.and_then(|settings| settings.timeout_ms),
+                DEFAULT_TIMEOUT_MS,
+            ),
+            slow_threshold: millis(
+                settings.and_then(|settings| settings.slow_threshold_ms),
+                DEFAULT_SLOW_THRESHOLD_MS,
+            ),
+        }
+    }
+}
+
+impl DependencyHealthOptions {
+    /// Returns the health path of a URL name.
+    pub fn health_path(&self, url_name: &str) -> &str {
+        self.health_paths.get(url_name).unwrap_or(&self.health_path)
+    }
+}
+
+/// Health of a downstream service, ordered from the best to the worst.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DependencyStatus {
+    Healthy,
+    Degraded,
+    Down,
+}
+
+impl DependencyStatus {
+    /// Classifies the answer of a health endpoint, `None` if it did not answer.
+    pub fn classify(status_code: Option<u16>, latency: Duration, slow_threshold: Duration) -> Self {
+        match status_code {
+            None => DependencyStatus::Down,
+            Some(status_code) if status_code >= 500 => DependencyStatus::Down,
+            Some(status_code) if (200..300).contains(&status_code) && latency <= slow_threshold => {
+                DependencyStatus::Healthy
+            }
+            Some(_) => DependencyStatus::Degraded,
+        }
+    }
+}
+
+/// Probe of the health endpoint of a downstream service.
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct DependencyHealth {
+    /// Name of the service: `logging`, `audit`, `metric` or `knowledge_engine`.
+    pub name: String,
+    pub url: String,
+    pub health_url: String,
+    pub status: DependencyStatus,
+    pub status_code: Option<u16>,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+/// Health of the downstream services.
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct DependenciesHealth {
+    /// Worst status of the services.
+    pub status: DependencyStatus,
+    pub dependencies: Vec<DependencyHealth>,
+}
+
+impl DependenciesHealth {
+    pub fn new(dependencies: Vec<DependencyHealth>) -> Self {
+        DependenciesHealth {
+            status: dependencies
+                .iter()
+                .map(|dependency| dependency.status)
+                .max()
+                .unwrap_or(DependencyStatus::Healthy),
+            dependencies,
+        }
+    }
+}
+
+/// Probes the health endpoint of a downstream service.
+async fn probe_dependency(
+    app_state: &AppState,
+    options: &DependencyHealthOptions,
+    name: &str,
+    url_name: &str,
+    url: &str,
+) -> DependencyHealth {
+    let health_url = match options.health_path(url_name).trim_start_matches('/') {
+        "" => url.to_string(),
+        path => format!("{}/{}", url, path),
+    };
+    let request = app_state
+        .http_clients
+        .for_url(&health_url)
+        .get(&health_url)
+        .timeout(options.timeout);
+
+    let start = Instant::now();
+    let response = request.send().await;
+    let latency = start.elapsed();
+    let (status_code, error) = match response {
+        Ok(response) => (Some(response.status().as_u16()), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    DependencyHealth {
+        name: name.to_string(),
+        url: url.to_string(),
+        health_url,
+        status: DependencyStatus::classify(status_code, latency, options.slow_threshold),
+        status_code,
+        latency_ms: latency.as_millis() as u64,
+        error,
+    }
+}
+
+/// Probes the health endpoints of the downstream services concurrently.
+pub async fn dependencies_health(app_state: &AppState) -> DependenciesHealth {
+    let options = app_state.options::<DependencyHealthOptions>();
+    let urls: HashMap<&str, String> = app_state
+        .app_settings
+        .tresleai_urls
+        .named()
+        .into_iter()
+        .map(|(url_name, url)| (url_name, url.to_string()))
+        .collect();
+    let probes = DEPENDENCIES.iter().filter_map(|(name, url_name)| {
+        urls.get(url_name)
+            .map(|url| probe_dependency(app_state, &options, name, url_name, url))
+    });
+    DependenciesHealth::new(join_all(probes).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_dependency_health_options() {
+        let options = DependencyHealthOptions::from_settings(None);
+        assert_eq!(options.health_path("core_service_url"), "health");
+        assert_eq!(options.timeout, Duration::from_secs(2));
+        assert_eq!(options.slow_threshold, Duration::from_secs(1));
+
+        let options = DependencyHealthOptions::from_settings(Some(&DependencyHealthSettings {
+            health_path: Some("/status".to_string()),
+            health_paths: Some(HashMap::from([(
+                "core_service_url".to_string(),
+                "ping".to_string(),
+            )])),
+            timeout_ms: Some(0),
+            slow_threshold_ms: Some(250),
+        }));
+        assert_eq!(options.health_path("core_service_url"), "ping");
+        assert_eq!(options.health_path("audit_service_url"), "/status");
+        assert_eq!(options.timeout, Duration::from_secs(2));
+        assert_eq!(options.slow_threshold, Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_success_dependency_status() {
+        let threshold = Duration::from_millis(500);
+        let fast = Duration::from_millis(10);
+        let slow = Duration::from_secs(1);
+        assert_eq!(
+            DependencyStatus::classify(Some(200), fast, threshold),
+            DependencyStatus::Healthy
+        );
+        assert_eq!(
+            DependencyStatus::classify(Some(204), slow, threshold),
+            DependencyStatus::Degraded
+        );
+        assert_eq!(
+            DependencyStatus::classify(Some(404), fast, threshold),
+            DependencyStatus::Degraded
+        );
+        assert_eq!(
+            DependencyStatus::classify(Some(503), fast, threshold),
+            DependencyStatus::Down
+        );
+        assert_eq!(
+            DependencyStatus::classify(None, fast, threshold),
+            DependencyStatus::Down
+        );
+    }
+
+    #[test]
+    fn test_success_dependencies_health_status() {
+        let dependency = |name: &str, status: DependencyStatus| DependencyHealth {
+            name: name.to_string(),
+            url: format!("http://{}", name),
+            health_url: format!("http://{}/health", name),
+            status,
+            status_code: Some(200),
+            latency_ms: 1,
+            error: None,
+        };
+        assert_eq!(
+            DependenciesHealth::new(Vec::new()).status,
+            DependencyStatus::Healthy
+        );
+        let health = DependenciesHealth::new(vec![
+            dependency("logging", DependencyStatus::Healthy),
+            dependency("audit", DependencyStatus::Degraded),
+        ]);
+        assert_eq!(health.status, DependencyStatus::Degraded);
+        let health = DependenciesHealth::new(vec![
+            dependency("logging", DependencyStatus::Down),
+            dependency("audit", DependencyStatus::Degraded),
+        ]);
+        assert_eq!(health.status, DependencyStatus::Down);
+    }
+}
@@ -0,0 +1,141 @@
+/*
+ * Created Date:  Aug 7, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the deployment labels of the facade, `deployment`, `environment` and `region`, to tell the
+//! metrics and logs of several deployments (e.g. the prod regions) apart once collected in the same place.
+//! The labels are set with `deployment.name`, `deployment.environment` and `deployment.region`, the region falling
+//! back to `aws.default_region`; unset labels are left out.
+//! Every metric record gets the labels as dimensions when recorded, so they reach every metric sink (DocumentDB,
+//! legacy tracing events, CloudWatch EMF, Prometheus), and the access log events and the log documents shipped to the
+//! log sink carry them as fields. A dimension or field already set is never overwritten.
+//!
+
+use crate::configuration::settings::{AWSSettings, DeploymentSettings};
+use crate::service::metrics::{
+    MetricRecord, DEPLOYMENT_DIMENSION, ENVIRONMENT_DIMENSION, REGION_DIMENSION,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Labels of the deployment of the facade.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct DeploymentLabels {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deployment: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+}
+
+impl DeploymentLabels {
+    /// Builds the deployment labels from the settings. Blank labels are left out.
+    pub fn from_settings(settings: Option<&DeploymentSettings>, aws: Option<&AWSSettings>) -> Self {
+        let label = |value: Option<&String>| {
+            value
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        DeploymentLabels {
+            deployment: label(settings.and_then(|settings| settings.name.as_ref())),
+            environment: label(settings.and_then(|settings| settings.environment.as_ref())),
+            region: label(settings.and_then(|settings| settings.region.as_ref())).or_else(|| {
+                aws.and_then(|aws| aws.default_region.as_ref())
+                    .map(|region| region.to_string())
+            }),
+        }
+    }
+
+    /// Labels that are set, by name.
+    pub fn labels(&self) -> Vec<(&'static str, &str)> {
+        [
+            (DEPLOYMENT_DIMENSION, &self.deployment),
+            (ENVIRONMENT_DIMENSION, &self.environment),
+            (REGION_DIMENSION, &self.region),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|value| (name, value)))
+        .collect()
+    }
+
+    /// Adds the labels to the dimensions of a metric record.
+    pub fn label_record(&self, mut record: MetricRecord) -> MetricRecord {
+        for (name, value) in self.labels() {
+            record
+                .dimensions
+                .entry(name.to_string())
+                .or_insert_with(|| value.to_string());
+        }
+        record
+    }
+
+    /// Adds the labels to the fields of a log document.
+    pub fn label_log(&self, mut log: serde_json::Value) -> serde_json::Value {
+        if let Some(fields) = log.as_object_mut() {
+            for (name, value) in self.labels() {
+                fields
+                    .entry(name)
+                    .or_insert_with(|| serde_json::Value::String(value.to_string()));
+            }
+        }
+        log
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::metrics::APP_NAME_DIMENSION;
+    use serde_json::json;
+
+    fn labels() -> DeploymentLabels {
+        DeploymentLabels::from_settings(
+            Some(&DeploymentSettings {
+                name: Some("prod-us-east".to_string()),
+                environment: Some("prod".to_string()),
+                region: Some(" ".to_string()),
+            }),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_success_deployment_labels() {
+        assert_eq!(
+            DeploymentLabels::from_settings(None, None),
+            DeploymentLabels::default()
+        );
+        assert!(DeploymentLabels::default().labels().is_empty());
+        assert_eq!(
+            labels().labels(),
+            vec![("deployment", "prod-us-east"), ("environment", "prod")]
+        );
+    }
+
+    #[test]
+    fn test_success_label_record() {
+        let record = labels().label_record(
+            MetricRecord::counter("Data Retrieval Counter")
+                .dimension(APP_NAME_DIMENSION, "app100")
+                .dimension(ENVIRONMENT_DIMENSION, "staging"),
+        );
+        assert_eq!(record.dimensions.get("deployment").unwrap(), "prod-us-east");
+        assert_eq!(record.dimensions.get("environment").unwrap(), "staging");
+        assert!(!record.dimensions.contains_key("region"));
+        assert_eq!(record.dimensions.len(), 3);
+    }
+
+    #[test]
+    fn test_success_label_log() {
+        assert_eq!(
+            labels().label_log(json!({"app_name": "app100", "deployment": "other"})),
+            json!({"app_name": "app100", "deployment": "other", "environment": "prod"})
+        );
+        assert_eq!(
+            labels().label_log(json!("not an object")),
+            json!("not an object")
+        );
+    }
+}
@@ -0,0 +1,188 @@
+/*
+ * Created Date:  Aug 8, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the driver connections of the primary cluster and of the residency clusters.
+//! The document clients (`DBTrait`) don't manage indexes, watch change streams, upsert or pass aggregate options, so
+//! these go through the MongoDB driver. A single driver client is connected per cluster (and per analytics connection)
+//! when the service starts and shared by every feature needing one, instead of each feature opening its own connection
+//! pool.
+//! `ClusterDb` pairs the document client of a cluster with the driver database of the same cluster, so the
+//! aggregations run through the driver with their options while the other operations keep the document client.
+//! The unique indexes are created once per process and collection.
+//!
+
+use crate::configuration::settings::TresleFacadeServiceSettings;
+use mongodb::bson::Document;
+use mongodb::options::IndexOptions;
+use mongodb::{Client, Database, IndexModel};
+use mongodb_utils::mongodb_client::DBTrait;
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::sync::Mutex;
+use tracing::debug;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DriverError {
+    #[error("No driver connections to the clusters")]
+    NotConnected,
+    #[error("No driver connection to the '{0}' residency cluster")]
+    UnknownResidency(String),
+    #[error("{0}")]
+    Mongo(#[from] mongodb::error::Error),
+}
+
+/// Driver connections by residency (`None` for the primary cluster).
+pub struct DriverDatabases {
+    databases: HashMap<Option<String>, Database>,
+    analytics_databases: HashMap<Option<String>, Database>,
+    ensured_indexes: Mutex<HashSet<(Option<String>, String, String)>>,
+}
+
+impl DriverDatabases {
+    /// Connects to the primary cluster and to every residency cluster of the settings, and to their analytics
+    /// connections when configured.
+    pub async fn connect(
+        settings: &TresleFacadeServiceSettings,
+    ) -> Result<Self, mongodb::error::Error> {
+        let database_name = &settings.mongo_db.mongo_db_database_name;
+        let mut databases = HashMap::new();
+        let mut analytics_databases = HashMap::new();
+        let client = Client::with_uri_str(&settings.mongo_db.mongo_db_url).await?;
+        databases.insert(None, client.database(database_name));
+        if let Some(analytics_url) = settings.mongo_db.mongo_db_analytics_url.as_ref() {
+            let client = Client::with_uri_str(analytics_url).await?;
+            analytics_databases.insert(None, client.database(database_name));
+        }
+        for cluster in settings
+            .residency
+            .iter()
+            .flat_map(|residency| &residency.clusters)
+        {
+            let client = Client::with_uri_str(&cluster.mongo_db_url).await?;
+            databases.insert(
+                Some(cluster.name.clone()),
+                client.database(&cluster.mongo_db_database_name),
+            );
+            if let Some(analytics_url) = cluster.analytics_url.as_ref() {
+                let client = Client::with_uri_str(analytics_url).await?;
+                analytics_databases.insert(
+                    Some(cluster.name.clone()),
+                    client.database(&cluster.mongo_db_database_name),
+                );
+            }
+        }
+        Ok(DriverDatabases {
+            databases,
+            analytics_databases,
+            ensured_indexes: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Returns the database of a residency, the primary database if the residency is `None`.
+    pub fn database(&self, residency: Option<&str>) -> Result<&Database, DriverError> {
+        self.databases
+            .get(&residency.map(str::to_string))
+            .ok_or_else(|| DriverError::UnknownResidency(residency.unwrap_or_default().to_string()))
+    }
+
+    /// Returns the analytics database of a residency, if an analytics connection is configured for it.
+    pub fn analytics_database(&self, residency: Option<&str>) -> Option<&Database> {
+        self.analytics_databases.get(&residency.map(str::to_string))
+    }
+
+    /// Returns the databases of all the clusters, with their residency.
+    pub fn databases(&self) -> impl Iterator<Item = (Option<&str>, &Database)> {
+        self.databases
+            .iter()
+            .map(|(residency, database)| (residency.as_deref(), database))
+    }
+
+    /// Creates a unique index on the keys of a collection, once per process.
+    pub async fn ensure_unique_index(
+        &self,
+        residency: Option<&str>,
+        collection_name: &str,
+        index_name: &str,
+        keys: Document,
+    ) -> Result<(), DriverError> {
+        let ensured_key = (
+            residency.map(str::to_string),
+            collection_name.to_string(),
+            index_name.to_string(),
+        );
+        let ensured = self
+            .ensured_indexes
+            .lock()
+            .map(|ensured| ensured.contains(&ensured_key))
+            .unwrap_or(false);
+        if ensured {
+            return Ok(());
+        }
+        let index = IndexModel::builder()
+            .keys(keys)
+            .options(
+                IndexOptions::builder()
+                    .name(index_name.to_string())
+                    .unique(true)
+                    .build(),
+            )
+            .build();
+        self.database(residency)?
+            .collection::<Document>(collection_name)
+            .create_index(index, None)
+            .await?;
+        if let Ok(mut ensured) = self.ensured_indexes.lock() {
+            ensured.insert(ensured_key);
+        }
+        debug!(
+            message = format!(
+                "Unique index '{}' of '{}' ensured.",
+                index_name, collection_name
+            )
+        );
+        Ok(())
+    }
+}
+
+/// Document client of a cluster, with the driver database of the same cluster once the driver connections are set.
+/// It dereferences to the document client.
+pub struct ClusterDb {
+    client: Box<dyn DBTrait + Sync + Send>,
+    database: Option<Database>,
+}
+
+impl ClusterDb {
+    pub fn new(client: Box<dyn DBTrait + Sync + Send>) -> Self {
+        ClusterDb {
+            client,
+            database: None,
+        }
+    }
+
+    /// Sets the driver database of the same cluster.
+    pub fn with_database(mut self, database: Option<Database>) -> Self {
+        self.database = database;
+        self
+    }
+
+    /// Returns the driver database of the cluster, if connected.
+    pub fn database(&self) -> Option<&Database> {
+        self.database.as_ref()
+    }
+}
+
+impl Deref for ClusterDb {
+    type Target = dyn DBTrait + Sync + Send;
+
+    fn deref(&self) -> &Self::Target {
+        self.client.as_ref()
+    }
+}
+
+impl AsRef<dyn DBTrait + Sync + Send> for ClusterDb {
+    fn as_ref(&self) -> &(dyn DBTrait + Sync + Send) {
+        self.client.as_ref()
+    }
+}
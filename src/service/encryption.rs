@@ -0,0 +1,643 @@
+/*
+ * Created Date:  Jun 25, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the envelope encryption of the sensitive fields stored in DocumentDB.
+//! Every app has its own data keys, generated by KMS and stored encrypted in the data keys collection.
+//! The KMS encryption context and the AES-GCM associated data are bound to the app name, so the fields of
+//! an app can never be decrypted with the key of another app.
+//! Encrypted fields are stored as `enc:v<key version>:<base64(nonce || ciphertext)>:<checksum>`, the checksum being
+//! the first 4 bytes of the SHA-256 of the rest of the value, in hex. Only the values of that exact shape are
+//! decrypted, the other values are plaintext, e.g. a query starting with `enc:v`. Rotating the key of an app creates
+//! a new active version. Older versions are kept to decrypt the fields written before the rotation.
+//! The data keys collection has a unique index on `(app_name, key_version)`: a replica losing the race to store a
+//! version reads the key of the winner, so a version never has two keys.
+//! The active version of an app is cached for `latest_version_cache_seconds`, so a rotation done by another replica
+//! applies to the new fields of this replica within that delay.
+//!
+
+use crate::configuration::settings::EncryptionSettings;
+use crate::service::driver::{ClusterDb, DriverDatabases, DriverError};
+use crate::service::history_upsert::is_duplicate_key_error;
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use async_trait::async_trait;
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_kms::primitives::Blob;
+use aws_sdk_kms::types::DataKeySpec;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use mongodb::bson::doc;
+use mongodb_utils::mongodb_client::DBTrait;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Default collection of the encrypted data keys.
+pub const DEFAULT_DATA_KEYS_COLLECTION: &str = "app-data-keys";
+/// Prefix of the encrypted field values.
+const ENCRYPTED_VALUE_PREFIX: &str = "enc:v";
+/// KMS encryption context key holding the app name.
+const APP_NAME_CONTEXT_KEY: &str = "app_name";
+/// Length of the AES-GCM nonce.
+const NONCE_LENGTH: usize = 12;
+/// Length of the AES-GCM authentication tag.
+const TAG_LENGTH: usize = 16;
+/// Number of bytes of the SHA-256 checksum of an encrypted value.
+const CHECKSUM_LENGTH: usize = 4;
+/// Name of the unique index on `(app_name, key_version)` of the data keys collection.
+const DATA_KEY_VERSION_INDEX: &str = "app_name_key_version_unique";
+/// Default number of seconds the active key version of an app is cached.
+pub const DEFAULT_LATEST_VERSION_CACHE_SECONDS: u64 = 60;
+
+#[derive(Debug, thiserror::Error)]
+pub enum EncryptionError {
+    #[error("KMS request failed: {0}")]
+    Kms(String),
+    #[error("Failed to access the data keys: {0}")]
+    Store(String),
+    #[error("No data key version {version} found for app '{app_name}'")]
+    MissingDataKey { app_name: String, version: i32 },
+    #[error("Malformed encrypted value")]
+    Malformed,
+    #[error("Failed to encrypt or decrypt the value")]
+    Cipher,
+}
+
+/// A data key in plaintext and encrypted under the KMS key.
+pub struct DataKey {
+    pub plaintext: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+/// Generates and decrypts the data keys of the apps.
+#[async_trait]
+pub trait KeyProvider: Send + Sync {
+    async fn generate_data_key(&self, app_name: &str) -> Result<DataKey, EncryptionError>;
+    async fn decrypt_data_key(
+        &self,
+        app_name: &str,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, EncryptionError>;
+}
+
+/// AWS KMS key provider. The data keys are AES-256 keys encrypted under `kms_key_id`.
+pub struct KmsKeyProvider {
+    client: aws_sdk_kms::Client,
+    key_id: String,
+}
+
+impl KmsKeyProvider {
+    pub async fn from_settings(encryption_settings: &EncryptionSettings) -> Self {
+        let region_provider =
+            RegionProviderChain::first_try(Region::new(encryption_settings.region.clone()));
+        let config = aws_config::defaults(BehaviorVersion::latest())
+            .region(region_provider)
+            .load()
+            .await;
+        KmsKeyProvider {
+            client: aws_sdk_kms::Client::new(&config),
+            key_id: encryption_settings.kms_key_id.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl KeyProvider for KmsKeyProvider {
+    async fn generate_data_key(&self, app_name: &str) -> Result<DataKey, EncryptionError> {
+        let output = self
+            .client
+            .generate_data_key()
+            .key_id(&self.key_id)
+            .key_spec(DataKeySpec::Aes256)
+            .encryption_context(APP_NAME_CONTEXT_KEY, app_name)
+            .send()
+            .await
+            .map_err(|e| EncryptionError::Kms(e.to_string()))?;
+        match (output.plaintext(), output.ciphertext_blob()) {
+            (Some(plaintext), Some(ciphertext)) => Ok(DataKey {
+                plaintext: plaintext.as_ref().to_vec(),
+                ciphertext: ciphertext.as_ref().to_vec(),
+            }),
+            _ => Err(EncryptionError::Kms(
+                "GenerateDataKey returned no key".to_string(),
+            )),
+        }
+    }
+
+    async fn decrypt_data_key(
+        &self,
+        app_name: &str,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, EncryptionError> {
+        let output = self
+            .client
+            .decrypt()
+            .key_id(&self.key_id)
+            .ciphertext_blob(Blob::new(ciphertext))
+            .encryption_context(APP_NAME_CONTEXT_KEY, app_name)
+            .send()
+            .await
+            .map_err(|e| EncryptionError::Kms(e.to_string()))?;
+        output
+            .plaintext()
+            .map(|plaintext| plaintext.as_ref().to_vec())
+            .ok_or_else(|| EncryptionError::Kms("Decrypt returned no key".to_string()))
+    }
+}
+
+/// Encrypts and decrypts the field values of an app with its data keys.
+/// The plaintext data keys are cached per app and version, and the active version per app for
+/// `latest_version_ttl`.
+pub struct FieldEncryptor {
+    key_provider: Box<dyn KeyProvider>,
+    collection_name: String,
+    query_options: QueryOptions,
+    latest_version_ttl: Duration,
+    data_keys: RwLock<HashMap<(String, i32), Arc<Vec<u8>>>>,
+    latest_versions: RwLock<HashMap<String, (i32, Instant)>>,
+}
+
+impl FieldEncryptor {
+    pub fn new(key_provider: Box<dyn KeyProvider>, collection_name: String) -> Self {
+        FieldEncryptor {
+            key_provider,
+            collection_name,
+            query_options: QueryOptions::default(),
+            latest_version_ttl: Duration::from_secs(DEFAULT_LATEST_VERSION_CACHE_SECONDS),
+            data_keys: RwLock::new(HashMap::new()),
+            latest_versions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the time budget of the data key lookups.
+    pub fn with_query_options(mut self, query_options: QueryOptions) -> Self {
+        self.query_options = query_options;
+        self
+    }
+
+    /// Sets how long the active key version of an app is cached.
+    pub fn with_latest_version_ttl(mut self, latest_version_ttl: Duration) -> Self {
+        self.latest_version_ttl = latest_version_ttl;
+        self
+    }
+
+    /// Creates the unique index on `(app_name, key_version)` of the data keys collection.
+    pub async fn ensure_index(
+        &self,
+        driver_databases: &DriverDatabases,
+    ) -> Result<(), DriverError> {
+        driver_databases
+            .ensure_unique_index(
+                None,
+                &self.collection_name,
+                DATA_KEY_VERSION_INDEX,
+                doc! {"app_name": 1, "key_version": 1},
+            )
+            .await
+    }
+
+    fn cached_data_key(&self, app_name: &str, version: i32) -> Option<Arc<Vec<u8>>> {
+        self.data_keys
+            .read()
+            .ok()?
+            .get(&(app_name.to_string(), version))
+            .cloned()
+    }
+
+    fn cache_data_key(&self, app_name: &str, version: i32, data_key: Vec<u8>) -> Arc<Vec<u8>> {
+        let data_key = Arc::new(data_key);
+        if let Ok(mut data_keys) = self.data_keys.write() {
+            data_keys.insert((app_name.to_string(), version), data_key.clone());
+        }
+        data_key
+    }
+
+    fn cached_latest_version(&self, app_name: &str) -> Option<i32> {
+        self.latest_versions
+            .read()
+            .ok()?
+            .get(app_name)
+            .filter(|(_, cached_at)| cached_at.elapsed() < self.latest_version_ttl)
+            .map(|(version, _)| *version)
+    }
+
+    fn cache_latest_version(&self, app_name: &str, version: i32) {
+        if let Ok(mut latest_versions) = self.latest_versions.write() {
+            latest_versions.insert(app_name.to_string(), (version, Instant::now()));
+        }
+    }
+
+    /// Returns the latest key version of an app stored in the data keys collection, if the app has a data key.
+    async fn latest_version(
+        &self,
+        db: &ClusterDb,
+        app_name: &str,
+    ) -> Result<Option<i32>, EncryptionError> {
+        let latest_pipeline = vec![
+            doc! { "$match": { "app_name": app_name } },
+            doc! { "$group": { "_id": null, "key_version": { "$max": "$key_version" } } },
+        ];
+        let latest = db
+            .aggregate(&self.collection_name, latest_pipeline, &self.query_options)
+            .await
+            .map_err(|e| EncryptionError::Store(e.to_string()))?;
+        Ok(latest
+            .first()
+            .and_then(|doc| doc.get("key_version"))
+            .and_then(serde_json::Value::as_i64)
+            .map(|version| version as i32))
+    }
+
+    /// Generates a new data key version for an app and stores it encrypted. If another replica stored the version
+    /// first, its key is returned instead.
+    async fn create_data_key(
+        &self,
+        db: &(dyn DBTrait + Sync + Send),
+        app_name: &str,
+        version: i32,
+    ) -> Result<Arc<Vec<u8>>, EncryptionError> {
+        let data_key = self.key_provider.generate_data_key(app_name).await?;
+        let data_key_document = doc! {
+            "app_name": app_name,
+            "key_version": version,
+            "encrypted_data_key": STANDARD.encode(&data_key.ciphertext),
+            "created_at": Utc::now().to_rfc3339(),
+        };
+        match db
+            .create_document(&self.collection_name, data_key_document)
+            .await
+        {
+            Ok(_) => Ok(self.cache_data_key(app_name, version, data_key.plaintext)),
+            Err(e) if is_duplicate_key_error(&e.to_string()) => {
+                debug!(
+                    app_name = app_name,
+                    message = format!("Data key version {} already stored, reading it.", version)
+                );
+                self.data_key(db, app_name, version).await
+            }
+            Err(e) => Err(EncryptionError::Store(e.to_string())),
+        }
+    }
+
+    /// Returns the plaintext data key of an app for a version.
+    async fn data_key(
+        &self,
+        db: &(dyn DBTrait + Sync + Send),
+        app_name: &str,
+        version: i32,
+    ) -> Result<Arc<Vec<u8>>, EncryptionError> {
+        if let Some(data_key) = self.cached_data_key(app_name, version) {
+            return Ok(data_key);
+        }
+        let data_key_document = db
+            .get_document(
+                &self.collection_name,
+                doc! { "app_name": app_name, "key_version": version },
+            )
+            .await
+            .map_err(|e| EncryptionError::Store(e.to_string()))?
+            .ok_or_else(|| EncryptionError::MissingDataKey {
+                app_name: app_name.to_string(),
+                version,
+            })?;
+        let encrypted_data_key = data_key_document
+            .get("encrypted_data_key")
+            .and_then(serde_json::Value::as_str)
+            .and_then(|encoded| STANDARD.decode(encoded).ok())
+            .ok_or(EncryptionError::Malformed)?;
+        let data_key = self
+            .key_provider
+            .decrypt_data_key(app_name, &encrypted_data_key)
+            .await?;
+        Ok(self.cache_data_key(app_name, version, data_key))
+    }
+
+    /// Encrypts a value with the active data key of an app, creating the first data key if needed.
+    pub async fn encrypt(
+        &self,
+        db: &ClusterDb,
+        app_name: &str,
+        plaintext: &str,
+    ) -> Result<String, EncryptionError> {
+        let latest_version = match self.cached_latest_version(app_name) {
+            Some(version) => Some(version),
+            None => self.latest_version(db, app_name).await?,
+        };
+        let (version, data_key) = match latest_version {
+            Some(version) => (version, self.data_key(db, app_name, version).await?),
+            None => (1, self.create_data_key(db, app_name, 1).await?),
+        };
+        self.cache_latest_version(app_name, version);
+        seal(&data_key, app_name, version, plaintext)
+    }
+
+    /// Decrypts a value of an app. Values that are not encrypted are returned as-is.
+    pub async fn decrypt(
+        &self,
+        db: &(dyn DBTrait + Sync + Send),
+        app_name: &str,
+        value: &str,
+    ) -> Result<String, EncryptionError> {
+        let Some(version) = encrypted_value_version(value) else {
+            return Ok(value.to_string());
+        };
+        let data_key = self.data_key(db, app_name, version).await?;
+        open(&data_key, app_name, value)
+    }
+
+    /// Decrypts, in place, every encrypted string of a JSON document of an app.
+    pub async fn decrypt_json(
+        &self,
+        db: &(dyn DBTrait + Sync + Send),
+        app_name: &str,
+        value: &mut serde_json::Value,
+    ) -> Result<(), EncryptionError> {
+        for field in encrypted_strings(value) {
+            *field = self.decrypt(db, app_name, field).await?;
+        }
+        Ok(())
+    }
+
+    /// Rotates the data key of an app and returns the new key version. Concurrent rotations create a single version.
+    pub async fn rotate_key(&self, db: &ClusterDb, app_name: &str) -> Result<i32, EncryptionError> {
+        let version = self.latest_version(db, app_name).await?.unwrap_or(0) + 1;
+        self.create_data_key(db, app_name, version).await?;
+        self.cache_latest_version(app_name, version);
+        info!(
+            app_name = app_name,
+            message = format!("Rotated data key to version {}.", version)
+        );
+        Ok(version)
+    }
+}
+
+/// Key version and nonce followed by the ciphertext of an encrypted value.
+#[derive(Debug, PartialEq)]
+struct Envelope {
+    version: i32,
+    sealed: Vec<u8>,
+}
+
+/// Hex checksum of an encrypted value without its checksum.
+fn envelope_checksum(value: &str) -> String {
+    Sha256::digest(value.as_bytes())[..CHECKSUM_LENGTH]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Parses an encrypted value, `None` if the value doesn't have the shape of an encrypted value.
+fn parse_envelope(value: &str) -> Option<Envelope> {
+    let (value, checksum) = value.rsplit_once(':')?;
+    if checksum != envelope_checksum(value) {
+        return None;
+    }
+    let (version, encoded) = value
+        .strip_prefix(ENCRYPTED_VALUE_PREFIX)?
+        .split_once(':')?;
+    if version.is_empty() || !version.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let sealed = STANDARD.decode(encoded).ok()?;
+    (sealed.len() >= NONCE_LENGTH + TAG_LENGTH).then_some(Envelope {
+        version: version.parse().ok()?,
+        sealed,
+    })
+}
+
+/// Returns the key version of an encrypted value, `None` if the value is not encrypted.
+fn encrypted_value_version(value: &str) -> Option<i32> {
+    parse_envelope(value).map(|envelope| envelope.version)
+}
+
+/// Returns the encrypted strings of a JSON value.
+fn encrypted_strings(value: &mut serde_json::Value) -> Vec<&mut String> {
+    match value {
+        serde_json::Value::String(field) if encrypted_value_version(field).is_some() => {
+            vec![field]
+        }
+        serde_json::Value::Array(values) => values.iter_mut().flat_map(encrypted_strings).collect(),
+        serde_json::Value::Object(fields) => {
+            fields.values_mut().flat_map(encrypted_strings).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Encrypts a value with AES-256-GCM, using the app name as associated data.
+fn seal(
+    data_key: &[u8],
+    app_name: &str,
+    version: i32,
+    plaintext: &str,
+) -> Result<String, EncryptionError> {
+    let cipher = Aes256Gcm::new_from_slice(data_key).map_err(|_| EncryptionError::Cipher)?;
+    let nonce: [u8; NONCE_LENGTH] = rand::random();
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext.as_bytes(),
+                aad: app_name.as_bytes(),
+            },
+        )
+        .map_err(|_| EncryptionError::Cipher)?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    let value = format!(
+        "{}{}:{}",
+        ENCRYPTED_VALUE_PREFIX,
+        version,
+        STANDARD.encode(sealed)
+    );
+    let checksum = envelope_checksum(&value);
+    Ok(format!("{}:{}", value, checksum))
+}
+
+/// Decrypts a value encrypted by `seal`.
+fn open(data_key: &[u8], app_name: &str, value: &str) -> Result<String, EncryptionError> {
+    let envelope = parse_envelope(value).ok_or(EncryptionError::Malformed)?;
+    let (nonce, ciphertext) = envelope.sealed.split_at(NONCE_LENGTH);
+    let cipher = Aes256Gcm::new_from_slice(data_key).map_err(|_| EncryptionError::Cipher)?;
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: app_name.as_bytes(),
+            },
+        )
+        .map_err(|_| EncryptionError::Cipher)?;
+    String::from_utf8(plaintext).map_err(|_| EncryptionError::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::runtime::Runtime;
+
+    /// Key provider returning the app name padded to 32 bytes as data key, "encrypted" as its reverse.
+    struct TestKeyProvider;
+
+    fn test_data_key(app_name: &str) -> Vec<u8> {
+        let mut data_key = app_name.as_bytes().to_vec();
+        data_key.resize(32, 0);
+        data_key
+    }
+
+    #[async_trait]
+    impl KeyProvider for TestKeyProvider {
+        async fn generate_data_key(&self, app_name: &str) -> Result<DataKey, EncryptionError> {
+            let plaintext = test_data_key(app_name);
+            let ciphertext = plaintext.iter().rev().cloned().collect();
+            Ok(DataKey {
+                plaintext,
+                ciphertext,
+            })
+        }
+
+        async fn decrypt_data_key(
+            &self,
+            _app_name: &str,
+            ciphertext: &[u8],
+        ) -> Result<Vec<u8>, EncryptionError> {
+            Ok(ciphertext.iter().rev().cloned().collect())
+        }
+    }
+
+    #[test]
+    fn test_success_seal_and_open() {
+        let data_key = test_data_key("app100");
+        let sealed = seal(&data_key, "app100", 3, "what is tresleai?").unwrap();
+        assert!(sealed.starts_with("enc:v3:"));
+        assert_eq!(encrypted_value_version(&sealed), Some(3));
+        assert_eq!(
+            open(&data_key, "app100", &sealed).unwrap(),
+            "what is tresleai?"
+        );
+
+        // The value is bound to the app
+        assert!(matches!(
+            open(&data_key, "app200", &sealed),
+            Err(EncryptionError::Cipher)
+        ));
+        assert!(matches!(
+            open(&data_key, "app100", "enc:v3:not-base64"),
+            Err(EncryptionError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn test_success_encrypted_strings() {
+        let data_key = test_data_key("app100");
+        let sealed = seal(&data_key, "app100", 1, "secret").unwrap();
+        let mut document = json!({
+            "query": sealed,
+            "timestamp": "2024-06-25",
+            "hints": [{"prefix": "docs/", "descriptions": sealed}],
+        });
+        assert_eq!(encrypted_strings(&mut document).len(), 2);
+        assert_eq!(encrypted_value_version("plain text"), None);
+        assert_eq!(encrypted_value_version("enc:vx:abc"), None);
+    }
+
+    #[test]
+    fn test_success_plaintext_with_encrypted_prefix() {
+        let data_key = test_data_key("app100");
+        let sealed = seal(&data_key, "app100", 1, "secret").unwrap();
+        let (value, checksum) = sealed.rsplit_once(':').unwrap();
+        let (_, encoded) = value.split_at("enc:v1:".len());
+        // Plaintext values starting like an encrypted value are not decrypted
+        for plaintext in [
+            "enc:v1:hello".to_string(),
+            value.to_string(),
+            format!("{}:00000000", value),
+            format!("enc:v+1:{}:{}", encoded, checksum),
+        ] {
+            assert_eq!(encrypted_value_version(&plaintext), None, "{}", plaintext);
+            let mut document = json!({"query": plaintext});
+            assert!(encrypted_strings(&mut document).is_empty());
+        }
+        // Too short for a nonce and a tag, a value is plaintext. A value of the shape is decrypted, and fails without
+        // the data key it was sealed with.
+        let short = format!("enc:v1:{}", STANDARD.encode([0_u8; 8]));
+        let short = format!("{}:{}", short, envelope_checksum(&short));
+        assert_eq!(encrypted_value_version(&short), None);
+        let forged = format!("enc:v1:{}", STANDARD.encode([0_u8; 40]));
+        let forged = format!("{}:{}", forged, envelope_checksum(&forged));
+        assert_eq!(encrypted_value_version(&forged), Some(1));
+        assert!(matches!(
+            open(&data_key, "app100", &forged),
+            Err(EncryptionError::Cipher)
+        ));
+    }
+
+    #[test]
+    fn test_success_encrypt_decrypt_and_rotate() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let db = &app_state.db;
+            let collection_name = "test-app-data-keys".to_string();
+            let encryptor = FieldEncryptor::new(Box::new(TestKeyProvider), collection_name.clone());
+            let app_name = "app100";
+
+            let encrypted = encryptor.encrypt(db, app_name, "secret").await.unwrap();
+            assert_eq!(encrypted_value_version(&encrypted), Some(1));
+
+            // Values encrypted before a rotation stay readable
+            assert_eq!(encryptor.rotate_key(db, app_name).await.unwrap(), 2);
+            let rotated = encryptor.encrypt(db, app_name, "secret").await.unwrap();
+            assert_eq!(encrypted_value_version(&rotated), Some(2));
+
+            let mut document = json!({"query": encrypted, "response": rotated, "plain": "text"});
+            encryptor
+                .decrypt_json(db, app_name, &mut document)
+                .await
+                .unwrap();
+            assert_eq!(
+                document,
+                json!({"query": "secret", "response": "secret", "plain": "text"})
+            );
+
+            // clean up
+            let _ = db.drop_collection(&collection_name).await;
+        });
+    }
+
+    #[test]
+    fn test_success_latest_version_cached() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let db = &app_state.db;
+            let collection_name = "test-app-data-keys-cached".to_string();
+            let cached = FieldEncryptor::new(Box::new(TestKeyProvider), collection_name.clone());
+            let uncached = FieldEncryptor::new(Box::new(TestKeyProvider), collection_name.clone())
+                .with_latest_version_ttl(Duration::ZERO);
+            let app_name = "app100";
+
+            let encrypted = cached.encrypt(db, app_name, "secret").await.unwrap();
+            assert_eq!(encrypted_value_version(&encrypted), Some(1));
+
+            // A rotation by another replica applies once the cached version expires
+            assert_eq!(uncached.rotate_key(db, app_name).await.unwrap(), 2);
+            let encrypted = cached.encrypt(db, app_name, "secret").await.unwrap();
+            assert_eq!(encrypted_value_version(&encrypted), Some(1));
+            let encrypted = uncached.encrypt(db, app_name, "secret").await.unwrap();
+            assert_eq!(encrypted_value_version(&encrypted), Some(2));
+
+            // clean up
+            let _ = db.drop_collection(&collection_name).await;
+        });
+    }
+}
@@ -5,10 +5,14 @@
  */
 //! This module contains error handling functions for the retrieval module. The external errors are sent to user and
 //! internal errors are persisted in DocumentDB through the logging microservice.
+//! The handlers answer the errors through `FacadeApiError`, which tags the response with the error code of the
+//! error (see `crate::service::error_code`).
 
-use axum::http::StatusCode;
+use crate::service::error_code::ErrorCode;
+use axum::http::{Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
 use chrono::Utc;
-use error_utils::TresleAppError;
+use error_utils::{AxumApiError, TresleAppError};
 use std::error::Error as StdError;
 use tracing::{debug, error};
 
@@ -38,6 +42,13 @@ pub enum TresleFacadeCommonError {
         ext_message: String,
     },
     #[error("{ext_message}")]
+    ApiKeyExpiredError {
+        time_stamp: String,
+        error_code: StatusCode,
+        reference_id: String,
+        ext_message: String,
+    },
+    #[error("{ext_message}")]
     RetrievalRequestBodyError {
         time_stamp: String,
         error_code: StatusCode,
@@ -73,6 +84,13 @@ pub enum TresleFacadeCommonError {
         ext_message: String,
     },
     #[error("{ext_message}")]
+    UserAccessError {
+        time_stamp: String,
+        error_code: StatusCode,
+        reference_id: String,
+        ext_message: String,
+    },
+    #[error("{ext_message}")]
     TaskIdUpdateError {
         time_stamp: String,
         error_code: StatusCode,
@@ -82,6 +100,58 @@ pub enum TresleFacadeCommonError {
 }
 
 impl TresleFacadeCommonError {
+    /// Error code of the error, by kind and status.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            TresleFacadeCommonError::RouteNotFound { error_code, .. }
+                if *error_code == StatusCode::METHOD_NOT_ALLOWED =>
+            {
+                ErrorCode::MethodNotAllowed
+            }
+            TresleFacadeCommonError::RouteNotFound { .. } => ErrorCode::RouteNotFound,
+            TresleFacadeCommonError::ApiKeyError { .. } => ErrorCode::InvalidApiKey,
+            TresleFacadeCommonError::FetchAppNameError { error_code, .. }
+                if *error_code == StatusCode::NOT_FOUND =>
+            {
+                ErrorCode::AppNotFoundForApiKey
+            }
+            TresleFacadeCommonError::FetchAppNameError { .. } => ErrorCode::AppLookupFailed,
+            TresleFacadeCommonError::ApiKeyExpiredError { .. } => ErrorCode::ApiKeyExpired,
+            TresleFacadeCommonError::RetrievalRequestBodyError { error_code, .. }
+                if error_code.is_server_error() =>
+            {
+                ErrorCode::PromptTemplateLookupFailed
+            }
+            TresleFacadeCommonError::RetrievalRequestBodyError { .. } => {
+                ErrorCode::InvalidRetrievalRequest
+            }
+            TresleFacadeCommonError::DocumentCreationError { .. } => ErrorCode::DocumentWriteFailed,
+            TresleFacadeCommonError::HistoryDocRetrievalInProgress { .. } => {
+                ErrorCode::RetrievalInProgress
+            }
+            TresleFacadeCommonError::HistoryDocRetrievalError { error_code, .. }
+                if error_code.is_client_error() =>
+            {
+                ErrorCode::InvalidHistoryRequest
+            }
+            TresleFacadeCommonError::HistoryDocRetrievalError { .. } => {
+                ErrorCode::HistoryLookupFailed
+            }
+            TresleFacadeCommonError::UserAccessError { error_code, .. }
+                if *error_code == StatusCode::FORBIDDEN =>
+            {
+                ErrorCode::UserAccessRejected
+            }
+            TresleFacadeCommonError::UserAccessError { .. } => ErrorCode::UserAccessCheckFailed,
+            TresleFacadeCommonError::TaskIdUpdateError { error_code, .. }
+                if *error_code == StatusCode::NOT_FOUND =>
+            {
+                ErrorCode::TaskNotFound
+            }
+            TresleFacadeCommonError::TaskIdUpdateError { .. } => ErrorCode::TaskUpdateFailed,
+        }
+    }
+
     #[tracing::instrument(skip_all)]
     pub fn missing_api_key(reference_id: &String, task_id: &String, ext_message: &String) -> Self {
         let ext_message = format!("{} Use reference ID: {}", ext_message, reference_id);
@@ -181,6 +251,26 @@ impl TresleFacadeCommonError {
         }
     }
 
+    #[tracing::instrument(skip_all)]
+    pub fn api_key_expired(reference_id: &String, task_id: &String, expired_at: &String) -> Self {
+        let ext_message = format!(
+            "The API key expired on {}. Use an active API key of the app. Use reference ID: {}",
+            expired_at, reference_id
+        );
+        error!(
+            task_id = task_id,
+            ext_message = ext_message,
+            "The given api_key is expired."
+        );
+        let time_stamp = Utc::now().to_rfc3339();
+        TresleFacadeCommonError::ApiKeyExpiredError {
+            time_stamp,
+            error_code: StatusCode::UNAUTHORIZED,
+            reference_id: reference_id.to_string(),
+            ext_message,
+        }
+    }
+
     #[tracing::instrument(skip_all)]
     pub fn failed_to_read_retrieval_request_body(
         reference_id: &String,
@@ -434,6 +524,279 @@ impl TresleFacadeCommonError {
         }
     }
 
+    #[tracing::instrument(skip_all)]
+    pub fn user_access_rejected(reference_id: &String, task_id: &String, e: impl StdError) -> Self {
+        let ext_message = format!(
+            "Access denied for the user. Use reference ID: {}",
+            reference_id
+        );
+        error!(
+            task_id = task_id,
+            ext_message = ext_message,
+            message = e.to_string()
+        );
+        let time_stamp = Utc::now().to_rfc3339();
+        TresleFacadeCommonError::UserAccessError {
+            time_stamp,
+            error_code: StatusCode::FORBIDDEN,
+            reference_id: reference_id.to_string(),
+            ext_message,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn failed_to_fetch_user_access_list(
+        reference_id: &String,
+        task_id: &String,
+        e: impl StdError,
+        ext_message: &String,
+    ) -> Self {
+        let ext_message = format!("{} Use reference ID: {}", ext_message, reference_id);
+        let internal_message = format!(
+            "Failed to fetch user access list from DocumentDB. Error: {}",
+            e
+        );
+        error!(
+            task_id = task_id,
+            ext_message = ext_message,
+            message = &internal_message
+        );
+        let time_stamp = Utc::now().to_rfc3339();
+        TresleFacadeCommonError::UserAccessError {
+            time_stamp,
+            error_code: StatusCode::INTERNAL_SERVER_ERROR,
+            reference_id: reference_id.to_string(),
+            ext_message,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn failed_to_fetch_row_filters(
+        reference_id: &String,
+        task_id: &String,
+        e: impl StdError,
+        ext_message: &String,
+    ) -> Self {
+        let ext_message = format!("{} Use reference ID: {}", ext_message, reference_id);
+        let internal_message = format!("Failed to fetch row filters from DocumentDB. Error: {}", e);
+        error!(
+            task_id = task_id,
+            ext_message = ext_message,
+            message = &internal_message
+        );
+        let time_stamp = Utc::now().to_rfc3339();
+        TresleFacadeCommonError::UserAccessError {
+            time_stamp,
+            error_code: StatusCode::INTERNAL_SERVER_ERROR,
+            reference_id: reference_id.to_string(),
+            ext_message,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn failed_to_pseudonymize_user_id(
+        reference_id: &String,
+        task_id: &String,
+        e: impl StdError,
+        ext_message: &String,
+    ) -> Self {
+        let ext_message = format!("{} Use reference ID: {}", ext_message, reference_id);
+        let internal_message = format!("Failed to pseudonymize the user ID. Error: {}", e);
+        error!(
+            task_id = task_id,
+            ext_message = ext_message,
+            message = &internal_message
+        );
+        let time_stamp = Utc::now().to_rfc3339();
+        TresleFacadeCommonError::UserAccessError {
+            time_stamp,
+            error_code: StatusCode::INTERNAL_SERVER_ERROR,
+            reference_id: reference_id.to_string(),
+            ext_message,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn failed_to_fetch_entitlements(
+        reference_id: &String,
+        task_id: &String,
+        e: impl StdError,
+        ext_message: &String,
+    ) -> Self {
+        let ext_message = format!("{} Use reference ID: {}", ext_message, reference_id);
+        let internal_message = format!("Failed to fetch the SCIM entitlements. Error: {}", e);
+        error!(
+            task_id = task_id,
+            ext_message = ext_message,
+            message = &internal_message
+        );
+        let time_stamp = Utc::now().to_rfc3339();
+        TresleFacadeCommonError::UserAccessError {
+            time_stamp,
+            error_code: StatusCode::INTERNAL_SERVER_ERROR,
+            reference_id: reference_id.to_string(),
+            ext_message,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn failed_to_fetch_prompt_templates(
+        reference_id: &String,
+        task_id: &String,
+        e: impl StdError,
+        ext_message: &String,
+    ) -> Self {
+        let ext_message = format!("{} Use reference ID: {}", ext_message, reference_id);
+        let internal_message = format!(
+            "Failed to fetch prompt templates from DocumentDB. Error: {}",
+            e
+        );
+        error!(
+            task_id = task_id,
+            ext_message = ext_message,
+            message = &internal_message
+        );
+        let time_stamp = Utc::now().to_rfc3339();
+        TresleFacadeCommonError::RetrievalRequestBodyError {
+            time_stamp,
+            error_code: StatusCode::INTERNAL_SERVER_ERROR,
+            reference_id: reference_id.to_string(),
+            ext_message,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn prompt_template_rejected(
+        reference_id: &String,
+        task_id: &String,
+        e: impl StdError,
+    ) -> Self {
+        let ext_message = format!("{} Use reference ID: {}", e, reference_id);
+        debug!(
+            task_id = task_id,
+            ext_message = ext_message,
+            message = e.to_string()
+        );
+        let time_stamp = Utc::now().to_rfc3339();
+        TresleFacadeCommonError::RetrievalRequestBodyError {
+            time_stamp,
+            error_code: StatusCode::BAD_REQUEST,
+            reference_id: reference_id.to_string(),
+            ext_message,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn sub_queries_rejected(reference_id: &String, task_id: &String, e: impl StdError) -> Self {
+        let ext_message = format!("{} Use reference ID: {}", e, reference_id);
+        debug!(
+            task_id = task_id,
+            ext_message = ext_message,
+            message = e.to_string()
+        );
+        let time_stamp = Utc::now().to_rfc3339();
+        TresleFacadeCommonError::RetrievalRequestBodyError {
+            time_stamp,
+            error_code: StatusCode::BAD_REQUEST,
+            reference_id: reference_id.to_string(),
+            ext_message,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn retrieval_estimate_rejected(
+        reference_id: &String,
+        task_id: &String,
+        e: impl StdError,
+    ) -> Self {
+        let ext_message = format!("{} Use reference ID: {}", e, reference_id);
+        debug!(
+            task_id = task_id,
+            ext_message = ext_message,
+            message = e.to_string()
+        );
+        let time_stamp = Utc::now().to_rfc3339();
+        TresleFacadeCommonError::RetrievalRequestBodyError {
+            time_stamp,
+            error_code: StatusCode::BAD_REQUEST,
+            reference_id: reference_id.to_string(),
+            ext_message,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn search_scope_rejected(
+        reference_id: &String,
+        task_id: &String,
+        e: impl StdError,
+    ) -> Self {
+        let ext_message = format!("{} Use reference ID: {}", e, reference_id);
+        debug!(
+            task_id = task_id,
+            ext_message = ext_message,
+            message = e.to_string()
+        );
+        let time_stamp = Utc::now().to_rfc3339();
+        TresleFacadeCommonError::RetrievalRequestBodyError {
+            time_stamp,
+            error_code: StatusCode::BAD_REQUEST,
+            reference_id: reference_id.to_string(),
+            ext_message,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn failed_to_fetch_source_labels(
+        reference_id: &String,
+        task_id: &String,
+        e: impl StdError,
+        ext_message: &String,
+    ) -> Self {
+        let ext_message = format!("{} Use reference ID: {}", ext_message, reference_id);
+        let internal_message = format!(
+            "Failed to fetch source labels from DocumentDB. Error: {}",
+            e
+        );
+        error!(
+            task_id = task_id,
+            ext_message = ext_message,
+            message = &internal_message
+        );
+        let time_stamp = Utc::now().to_rfc3339();
+        TresleFacadeCommonError::FetchAppNameError {
+            time_stamp,
+            error_code: StatusCode::INTERNAL_SERVER_ERROR,
+            reference_id: reference_id.to_string(),
+            ext_message,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn failed_to_fetch_allowed_models(
+        reference_id: &String,
+        task_id: &String,
+        e: impl StdError,
+        ext_message: &String,
+    ) -> Self {
+        let ext_message = format!("{} Use reference ID: {}", ext_message, reference_id);
+        let internal_message = format!(
+            "Failed to fetch allowed models from DocumentDB. Error: {}",
+            e
+        );
+        error!(
+            task_id = task_id,
+            ext_message = ext_message,
+            message = &internal_message
+        );
+        let time_stamp = Utc::now().to_rfc3339();
+        TresleFacadeCommonError::FetchAppNameError {
+            time_stamp,
+            error_code: StatusCode::INTERNAL_SERVER_ERROR,
+            reference_id: reference_id.to_string(),
+            ext_message,
+        }
+    }
+
     #[tracing::instrument(skip_all)]
     pub fn failed_to_deserialize_update_response(
         reference_id: &String,
@@ -459,6 +822,62 @@ impl TresleFacadeCommonError {
             ext_message: ext_message.to_string(),
         }
     }
+
+    #[tracing::instrument(skip_all)]
+    pub fn route_not_found(
+        reference_id: &String,
+        task_id: &String,
+        method: &Method,
+        uri: &Uri,
+        ext_message: &String,
+    ) -> Self {
+        let ext_message = format!("{} Use reference ID: {}", ext_message, reference_id);
+        let internal_message = format!("Route not found: {} {}", method, uri);
+        debug!(
+            task_id = task_id,
+            ext_message = ext_message,
+            message = &internal_message
+        );
+        TresleFacadeCommonError::RouteNotFound {
+            task_id: task_id.to_string(),
+            time_stamp: Utc::now().to_rfc3339(),
+            error_code: StatusCode::NOT_FOUND,
+            source: Box::new(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                internal_message,
+            )),
+            reference_id: reference_id.to_string(),
+            ext_message,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub fn method_not_allowed(
+        reference_id: &String,
+        task_id: &String,
+        method: &Method,
+        uri: &Uri,
+        ext_message: &String,
+    ) -> Self {
+        let ext_message = format!("{} Use reference ID: {}", ext_message, reference_id);
+        let internal_message = format!("Method not allowed: {} {}", method, uri);
+        debug!(
+            task_id = task_id,
+            ext_message = ext_message,
+            message = &internal_message
+        );
+        TresleFacadeCommonError::RouteNotFound {
+            task_id: task_id.to_string(),
+            time_stamp: Utc::now().to_rfc3339(),
+            error_code: StatusCode::METHOD_NOT_ALLOWED,
+            source: Box::new(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                internal_message,
+            )),
+            reference_id: reference_id.to_string(),
+            ext_message,
+        }
+    }
 }
 
 impl TresleAppError for TresleFacadeCommonError {
@@ -479,6 +898,11 @@ impl TresleAppError for TresleFacadeCommonError {
                 reference_id,
                 ..
             } => (*error_code, reference_id),
+            TresleFacadeCommonError::ApiKeyExpiredError {
+                error_code,
+                reference_id,
+                ..
+            } => (*error_code, reference_id),
             TresleFacadeCommonError::RetrievalRequestBodyError {
                 error_code,
                 reference_id,
@@ -504,6 +928,11 @@ impl TresleAppError for TresleFacadeCommonError {
                 reference_id,
                 ..
             } => (*error_code, reference_id),
+            TresleFacadeCommonError::UserAccessError {
+                error_code,
+                reference_id,
+                ..
+            } => (*error_code, reference_id),
             TresleFacadeCommonError::TaskIdUpdateError {
                 error_code,
                 reference_id,
@@ -536,6 +965,33 @@ impl TresleAppError for TresleFacadeCommonError {
     }
 }
 
+/// Error answered by the facade handlers: the standard error body of the error, tagged with its error code.
+#[derive(Debug)]
+pub struct FacadeApiError {
+    pub inner: TresleFacadeCommonError,
+}
+
+impl From<TresleFacadeCommonError> for FacadeApiError {
+    fn from(inner: TresleFacadeCommonError) -> Self {
+        FacadeApiError { inner }
+    }
+}
+
+impl From<AxumApiError<TresleFacadeCommonError>> for FacadeApiError {
+    fn from(error: AxumApiError<TresleFacadeCommonError>) -> Self {
+        FacadeApiError { inner: error.inner }
+    }
+}
+
+impl IntoResponse for FacadeApiError {
+    fn into_response(self) -> Response {
+        let error_code = self.inner.code();
+        let mut response = AxumApiError::from(self.inner).into_response();
+        response.extensions_mut().insert(error_code);
+        response
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -583,6 +1039,144 @@ mod tests {
             .contains("Internal Error. Please contact tresleai support team. Use reference ID:"));
     }
 
+    #[test]
+    fn test_success_api_key_expired() {
+        let reference_id = "test_reference_id".to_string();
+        let task_id = "test_task_id".to_string();
+        let expired_at = "2024-07-01T00:00:00+00:00".to_string();
+        let error = TresleFacadeCommonError::api_key_expired(&reference_id, &task_id, &expired_at);
+        assert!(error
+            .to_string()
+            .contains("The API key expired on 2024-07-01T00:00:00+00:00."));
+        assert_eq!(error.error_response().error_code(), 401);
+    }
+
+    #[test]
+    fn test_success_user_access_rejected() {
+        let reference_id = "test_reference_id".to_string();
+        let task_id = "test_task_id".to_string();
+        let e = io::Error::new(ErrorKind::PermissionDenied, "User is denied.".to_string());
+        let error = TresleFacadeCommonError::user_access_rejected(&reference_id, &task_id, e);
+        assert!(error
+            .to_string()
+            .contains("Access denied for the user. Use reference ID:"));
+        assert_eq!(error.error_response().error_code(), 403);
+    }
+
+    #[test]
+    fn test_success_failed_to_fetch_row_filters() {
+        let reference_id = "test_reference_id".to_string();
+        let task_id = "test_task_id".to_string();
+        let ext_message = "Internal Error. Please contact tresleai support team.".to_string();
+        let e = io::Error::new(ErrorKind::Other, "Malformed row filters.".to_string());
+        let error = TresleFacadeCommonError::failed_to_fetch_row_filters(
+            &reference_id,
+            &task_id,
+            e,
+            &ext_message,
+        );
+        assert!(error
+            .to_string()
+            .contains("Internal Error. Please contact tresleai support team. Use reference ID:"));
+        assert_eq!(error.error_response().error_code(), 500);
+    }
+
+    #[test]
+    fn test_success_failed_to_pseudonymize_user_id() {
+        let reference_id = "test_reference_id".to_string();
+        let task_id = "test_task_id".to_string();
+        let ext_message = "Internal Error. Please contact tresleai support team.".to_string();
+        let e = io::Error::new(ErrorKind::Other, "Key collection unreachable.".to_string());
+        let error = TresleFacadeCommonError::failed_to_pseudonymize_user_id(
+            &reference_id,
+            &task_id,
+            e,
+            &ext_message,
+        );
+        assert!(error
+            .to_string()
+            .contains("Internal Error. Please contact tresleai support team. Use reference ID:"));
+        assert_eq!(error.error_response().error_code(), 500);
+    }
+
+    #[test]
+    fn test_success_failed_to_fetch_entitlements() {
+        let reference_id = "test_reference_id".to_string();
+        let task_id = "test_task_id".to_string();
+        let ext_message = "Internal Error. Please contact tresleai support team.".to_string();
+        let e = io::Error::new(ErrorKind::Other, "Entitlements unreachable.".to_string());
+        let error = TresleFacadeCommonError::failed_to_fetch_entitlements(
+            &reference_id,
+            &task_id,
+            e,
+            &ext_message,
+        );
+        assert!(error
+            .to_string()
+            .contains("Internal Error. Please contact tresleai support team. Use reference ID:"));
+        assert_eq!(error.error_response().error_code(), 500);
+    }
+
+    #[test]
+    fn test_success_prompt_template_rejected() {
+        let reference_id = "test_reference_id".to_string();
+        let task_id = "test_task_id".to_string();
+        let e = io::Error::new(
+            ErrorKind::InvalidInput,
+            "Unknown prompt template.".to_string(),
+        );
+        let error = TresleFacadeCommonError::prompt_template_rejected(&reference_id, &task_id, e);
+        assert!(error
+            .to_string()
+            .contains("Unknown prompt template. Use reference ID:"));
+        assert_eq!(error.error_response().error_code(), 400);
+    }
+
+    #[test]
+    fn test_success_sub_queries_rejected() {
+        let reference_id = "test_reference_id".to_string();
+        let task_id = "test_task_id".to_string();
+        let e = io::Error::new(ErrorKind::InvalidInput, "Sub-query 1 is empty.".to_string());
+        let error = TresleFacadeCommonError::sub_queries_rejected(&reference_id, &task_id, e);
+        assert!(error
+            .to_string()
+            .contains("Sub-query 1 is empty. Use reference ID:"));
+        assert_eq!(error.error_response().error_code(), 400);
+    }
+
+    #[test]
+    fn test_success_retrieval_estimate_rejected() {
+        let reference_id = "test_reference_id".to_string();
+        let task_id = "test_task_id".to_string();
+        let e = io::Error::new(
+            ErrorKind::InvalidInput,
+            "Model 'model-3' is not an allowed model of the app.".to_string(),
+        );
+        let error =
+            TresleFacadeCommonError::retrieval_estimate_rejected(&reference_id, &task_id, e);
+        assert!(error
+            .to_string()
+            .contains("not an allowed model of the app. Use reference ID:"));
+        assert_eq!(error.error_response().error_code(), 400);
+        assert_eq!(error.code(), ErrorCode::InvalidRetrievalRequest);
+    }
+
+    #[test]
+    fn test_success_search_scope_rejected() {
+        let reference_id = "test_reference_id".to_string();
+        let task_id = "test_task_id".to_string();
+        let e = io::Error::new(
+            ErrorKind::InvalidInput,
+            "No source of the app is labeled 'legal-docs'.".to_string(),
+        );
+        let error = TresleFacadeCommonError::search_scope_rejected(&reference_id, &task_id, e);
+        assert!(error
+            .to_string()
+            .contains("labeled 'legal-docs'. Use reference ID:"));
+        assert_eq!(error.error_response().error_code(), 400);
+        assert_eq!(error.code(), ErrorCode::InvalidRetrievalRequest);
+    }
+
     #[test]
     fn test_success_no_app_name_key_found() {
         let reference_id = "test_reference_id".to_string();
@@ -801,6 +1395,42 @@ mod tests {
             .contains("Internal Error. Please contact tresleai support team. Use reference ID:"));
     }
 
+    #[test]
+    fn test_success_route_not_found() {
+        let reference_id = "test_reference_id".to_string();
+        let task_id = "test_task_id".to_string();
+        let ext_message = "Internal Error. Please contact tresleai support team.".to_string();
+        let uri: Uri = "/api/v1.1/admin/unknown".parse().unwrap();
+        let error = TresleFacadeCommonError::route_not_found(
+            &reference_id,
+            &task_id,
+            &Method::GET,
+            &uri,
+            &ext_message,
+        );
+        assert_eq!(error.error_response().error_code(), 404);
+        assert_eq!(error.task_id(), task_id);
+        assert!(error_utils::TresleAppError::source(&error).contains("/api/v1.1/admin/unknown"));
+
+        let error = TresleFacadeCommonError::method_not_allowed(
+            &reference_id,
+            &task_id,
+            &Method::PUT,
+            &uri,
+            &ext_message,
+        );
+        assert_eq!(error.error_response().error_code(), 405);
+        assert!(error
+            .to_string()
+            .contains("Internal Error. Please contact tresleai support team. Use reference ID:"));
+        assert_eq!(error.code(), ErrorCode::MethodNotAllowed);
+        let response = FacadeApiError::from(error).into_response();
+        assert_eq!(
+            response.extensions().get::<ErrorCode>(),
+            Some(&ErrorCode::MethodNotAllowed)
+        );
+    }
+
     #[test]
     fn test_error_response() {
         let error = TresleFacadeCommonError::RouteNotFound {
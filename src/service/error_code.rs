@@ -0,0 +1,379 @@
+/*
+ * Created Date:  Jul 27, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the catalog of the error codes of the service. Every error response carries a stable,
+//! machine-readable code (e.g. `TRESLE-1003`) in the `code` field of its JSON body and in the `x-error-code` header,
+//! so the clients branch on the codes instead of the messages.
+//! The errors of the retrieval and history APIs (`TresleFacadeCommonError`) and of the routing are coded by kind, in
+//! the `TRESLE-1xxx` range. The admin errors are coded by status, in the `TRESLE-2xxx` range, unless their handler
+//! tags the response with a more specific `ErrorCode` extension. A code is never renumbered nor reused.
+//! The catalog is served by the `error_catalog_handler`.
+//!
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+        HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use serde_json::json;
+use utoipa::ToSchema;
+
+/// Header of the error code of an error response.
+pub const ERROR_CODE_HEADER: &str = "x-error-code";
+/// Field of the error code in the JSON body of an error response.
+pub const ERROR_CODE_FIELD: &str = "code";
+
+/// Code of an error of the service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    RouteNotFound,
+    MethodNotAllowed,
+    InvalidApiKey,
+    ApiKeyExpired,
+    AppNotFoundForApiKey,
+    AppLookupFailed,
+    InvalidRetrievalRequest,
+    PromptTemplateLookupFailed,
+    DocumentWriteFailed,
+    RetrievalInProgress,
+    InvalidHistoryRequest,
+    HistoryLookupFailed,
+    UserAccessRejected,
+    UserAccessCheckFailed,
+    TaskNotFound,
+    TaskUpdateFailed,
+    Unclassified,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+    TooManyRequests,
+    InternalError,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 27] = [
+        ErrorCode::RouteNotFound,
+        ErrorCode::MethodNotAllowed,
+        ErrorCode::InvalidApiKey,
+        ErrorCode::ApiKeyExpired,
+        ErrorCode::AppNotFoundForApiKey,
+        ErrorCode::AppLookupFailed,
+        ErrorCode::InvalidRetrievalRequest,
+        ErrorCode::PromptTemplateLookupFailed,
+        ErrorCode::DocumentWriteFailed,
+        ErrorCode::RetrievalInProgress,
+        ErrorCode::InvalidHistoryRequest,
+        ErrorCode::HistoryLookupFailed,
+        ErrorCode::UserAccessRejected,
+        ErrorCode::UserAccessCheckFailed,
+        ErrorCode::TaskNotFound,
+        ErrorCode::TaskUpdateFailed,
+        ErrorCode::Unclassified,
+        ErrorCode::BadRequest,
+        ErrorCode::Unauthorized,
+        ErrorCode::Forbidden,
+        ErrorCode::NotFound,
+        ErrorCode::Conflict,
+        ErrorCode::TooManyRequests,
+        ErrorCode::InternalError,
+        ErrorCode::BadGateway,
+        ErrorCode::ServiceUnavailable,
+        ErrorCode::GatewayTimeout,
+    ];
+
+    /// Stable code of the error, as served to the clients.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorCode::RouteNotFound => "TRESLE-1001",
+            ErrorCode::MethodNotAllowed => "TRESLE-1002",
+            ErrorCode::InvalidApiKey => "TRESLE-1003",
+            ErrorCode::ApiKeyExpired => "TRESLE-1004",
+            ErrorCode::AppNotFoundForApiKey => "TRESLE-1005",
+            ErrorCode::AppLookupFailed => "TRESLE-1006",
+            ErrorCode::InvalidRetrievalRequest => "TRESLE-1007",
+            ErrorCode::PromptTemplateLookupFailed => "TRESLE-1008",
+            ErrorCode::DocumentWriteFailed => "TRESLE-1009",
+            ErrorCode::RetrievalInProgress => "TRESLE-1010",
+            ErrorCode::InvalidHistoryRequest => "TRESLE-1011",
+            ErrorCode::HistoryLookupFailed => "TRESLE-1012",
+            ErrorCode::UserAccessRejected => "TRESLE-1013",
+            ErrorCode::UserAccessCheckFailed => "TRESLE-1014",
+            ErrorCode::TaskNotFound => "TRESLE-1015",
+            ErrorCode::TaskUpdateFailed => "TRESLE-1016",
+            ErrorCode::Unclassified => "TRESLE-2000",
+            ErrorCode::BadRequest => "TRESLE-2400",
+            ErrorCode::Unauthorized => "TRESLE-2401",
+            ErrorCode::Forbidden => "TRESLE-2403",
+            ErrorCode::NotFound => "TRESLE-2404",
+            ErrorCode::Conflict => "TRESLE-2409",
+            ErrorCode::TooManyRequests => "TRESLE-2429",
+            ErrorCode::InternalError => "TRESLE-2500",
+            ErrorCode::BadGateway => "TRESLE-2502",
+            ErrorCode::ServiceUnavailable => "TRESLE-2503",
+            ErrorCode::GatewayTimeout => "TRESLE-2504",
+        }
+    }
+
+    /// Status code of the responses of the error, `None` if it varies.
+    pub fn status(&self) -> Option<StatusCode> {
+        let status = match self {
+            ErrorCode::RouteNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::InvalidApiKey => StatusCode::BAD_REQUEST,
+            ErrorCode::ApiKeyExpired => StatusCode::UNAUTHORIZED,
+            ErrorCode::AppNotFoundForApiKey => StatusCode::NOT_FOUND,
+            ErrorCode::AppLookupFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InvalidRetrievalRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::PromptTemplateLookupFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::DocumentWriteFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::RetrievalInProgress => StatusCode::ACCEPTED,
+            ErrorCode::InvalidHistoryRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::HistoryLookupFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::UserAccessRejected => StatusCode::FORBIDDEN,
+            ErrorCode::UserAccessCheckFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::TaskNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::TaskUpdateFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::Unclassified => return None,
+            ErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::BadGateway => StatusCode::BAD_GATEWAY,
+            ErrorCode::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::GatewayTimeout => StatusCode::GATEWAY_TIMEOUT,
+        };
+        Some(status)
+    }
+
+    /// Description of the error, for the catalog.
+    pub fn description(&self) -> &'static str {
+        match self {
+            ErrorCode::RouteNotFound => "No route matches the path of the request.",
+            ErrorCode::MethodNotAllowed => {
+                "The route doesn't support the method of the request, see the Allow header."
+            }
+            ErrorCode::InvalidApiKey => "The x-api-key header is missing or not a valid value.",
+            ErrorCode::ApiKeyExpired => "The API key is expired, use an active API key of the app.",
+            ErrorCode::AppNotFoundForApiKey => "No app is onboarded with the API key.",
+            ErrorCode::AppLookupFailed => "The app of the API key couldn't be fetched.",
+            ErrorCode::InvalidRetrievalRequest => {
+                "The body of the retrieval request is invalid: malformed JSON, rejected prompt template, sub-queries, model or search scope."
+            }
+            ErrorCode::PromptTemplateLookupFailed => {
+                "The prompt templates of the app couldn't be fetched."
+            }
+            ErrorCode::DocumentWriteFailed => "A document of the request couldn't be stored.",
+            ErrorCode::RetrievalInProgress => {
+                "The retrieval is not answered yet, poll the history again later."
+            }
+            ErrorCode::InvalidHistoryRequest => {
+                "The history request is invalid, e.g. without reference_id."
+            }
+            ErrorCode::HistoryLookupFailed => "The history document couldn't be fetched.",
+            ErrorCode::UserAccessRejected => {
+                "The user is not allowed to query the app: access list, entitlements or access details."
+            }
+            ErrorCode::UserAccessCheckFailed => {
+                "The access of the user couldn't be checked: access list, entitlements, row filters or pseudonym."
+            }
+            ErrorCode::TaskNotFound => "The ID document of the request couldn't be found.",
+            ErrorCode::TaskUpdateFailed => "The ID document of the request couldn't be updated.",
+            ErrorCode::Unclassified => "Error with a status code without dedicated code.",
+            ErrorCode::BadRequest => "The admin request is invalid, see the message.",
+            ErrorCode::Unauthorized => "The request is not authenticated.",
+            ErrorCode::Forbidden => "The request is not allowed for the caller.",
+            ErrorCode::NotFound => "The app or resource of the request doesn't exist.",
+            ErrorCode::Conflict => "The request conflicts with the current state of the resource.",
+            ErrorCode::TooManyRequests => "Rate limit exceeded, retry after the Retry-After header.",
+            ErrorCode::InternalError => {
+                "Internal error, contact the support team with the reference ID."
+            }
+            ErrorCode::BadGateway => "A downstream service answered with an error.",
+            ErrorCode::ServiceUnavailable => {
+                "The service or the resource is not available yet, retry later."
+            }
+            ErrorCode::GatewayTimeout => "The request or a downstream call exceeded its time budget.",
+        }
+    }
+
+    /// Code of an error response without dedicated code, by status.
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => ErrorCode::BadRequest,
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::CONFLICT => ErrorCode::Conflict,
+            StatusCode::TOO_MANY_REQUESTS => ErrorCode::TooManyRequests,
+            StatusCode::INTERNAL_SERVER_ERROR => ErrorCode::InternalError,
+            StatusCode::BAD_GATEWAY => ErrorCode::BadGateway,
+            StatusCode::SERVICE_UNAVAILABLE => ErrorCode::ServiceUnavailable,
+            StatusCode::GATEWAY_TIMEOUT => ErrorCode::GatewayTimeout,
+            _ => ErrorCode::Unclassified,
+        }
+    }
+
+    /// Entry of the error in the catalog.
+    pub fn catalog_entry(&self) -> ErrorCatalogEntry {
+        ErrorCatalogEntry {
+            code: self.code().to_string(),
+            name: format!("{:?}", self),
+            status: self.status().map(|status| status.as_u16()),
+            description: self.description().to_string(),
+        }
+    }
+}
+
+/// Entry of the error code catalog.
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ErrorCatalogEntry {
+    pub code: String,
+    pub name: String,
+    /// Status code of the responses of the error, unset if it varies.
+    pub status: Option<u16>,
+    pub description: String,
+}
+
+/// Catalog of the error codes, by code.
+pub fn error_catalog() -> Vec<ErrorCatalogEntry> {
+    ErrorCode::ALL
+        .iter()
+        .map(|error_code| error_code.catalog_entry())
+        .collect()
+}
+
+/// Middleware adding the error code to the error responses, and to the responses tagged with an `ErrorCode`
+/// extension. The code is added to the JSON object bodies that don't carry one already.
+pub async fn attach_error_code(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    let status = response.status();
+    let error_code = match response.extensions().get::<ErrorCode>() {
+        Some(error_code) => *error_code,
+        None if status.is_client_error() || status.is_server_error() => {
+            ErrorCode::from_status(status)
+        }
+        None => return response,
+    };
+    with_error_code(response, error_code).await
+}
+
+/// Adds an error code to a response. A code already in the body is kept, and set in the header.
+async fn with_error_code(response: Response, error_code: ErrorCode) -> Response {
+    let (mut parts, body) = response.into_parts();
+    parts.headers.insert(
+        ERROR_CODE_HEADER,
+        HeaderValue::from_static(error_code.code()),
+    );
+    let is_json = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    if !is_json {
+        return Response::from_parts(parts, body);
+    }
+    let Ok(bytes) = to_bytes(body, usize::MAX).await else {
+        return Response::from_parts(parts, Body::empty());
+    };
+    let body = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(serde_json::Value::Object(mut object)) => {
+            let code = object
+                .entry(ERROR_CODE_FIELD)
+                .or_insert_with(|| json!(error_code.code()));
+            if let Some(code) = code
+                .as_str()
+                .and_then(|code| HeaderValue::from_str(code).ok())
+            {
+                parts.headers.insert(ERROR_CODE_HEADER, code);
+            }
+            parts.headers.remove(CONTENT_LENGTH);
+            Body::from(serde_json::Value::Object(object).to_string())
+        }
+        _ => Body::from(bytes),
+    };
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+    use axum::Json;
+    use std::collections::HashSet;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_error_catalog() {
+        let catalog = error_catalog();
+        assert_eq!(catalog.len(), ErrorCode::ALL.len());
+        // The codes are unique
+        let codes: HashSet<&str> = catalog.iter().map(|entry| entry.code.as_str()).collect();
+        assert_eq!(codes.len(), catalog.len());
+        assert_eq!(
+            ErrorCode::MethodNotAllowed.catalog_entry(),
+            ErrorCatalogEntry {
+                code: "TRESLE-1002".to_string(),
+                name: "MethodNotAllowed".to_string(),
+                status: Some(405),
+                description:
+                    "The route doesn't support the method of the request, see the Allow header."
+                        .to_string(),
+            }
+        );
+        assert_eq!(
+            ErrorCode::from_status(StatusCode::CONFLICT),
+            ErrorCode::Conflict
+        );
+        assert_eq!(
+            ErrorCode::from_status(StatusCode::PAYLOAD_TOO_LARGE),
+            ErrorCode::Unclassified
+        );
+    }
+
+    #[test]
+    fn test_success_with_error_code() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let response = (
+                StatusCode::NOT_FOUND,
+                Json(json!({"status": "error", "message": "App not found."})),
+            )
+                .into_response();
+            let response = with_error_code(response, ErrorCode::NotFound).await;
+            assert_eq!(response.headers()[ERROR_CODE_HEADER], "TRESLE-2404");
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["code"], "TRESLE-2404");
+            assert_eq!(body["message"], "App not found.");
+
+            // A code set by the handler is kept
+            let response = (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"status": "error", "code": "TRESLE-1007"})),
+            )
+                .into_response();
+            let response = with_error_code(response, ErrorCode::BadRequest).await;
+            assert_eq!(response.headers()[ERROR_CODE_HEADER], "TRESLE-1007");
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["code"], "TRESLE-1007");
+        });
+    }
+}
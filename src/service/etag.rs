@@ -0,0 +1,203 @@
+/*
+ * Created Date:  Jun 17, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the ETag helper for the read-heavy admin GET endpoints.
+//! The ETag is computed from the request URI and the count and latest timestamp
+//! (`indexed_at`/`event_time`) of the matched documents, which is a single cheap `$group`.
+//! When the request carries a matching `If-None-Match` header the handler answers with a
+//! 304 instead of running its aggregations.
+//! A single stored document, e.g. a history document, is tagged by its stored content instead, and so is a cursor page:
+//! the page is read anyway, while the version would scan every matched document on each poll.
+//!
+
+use crate::service::driver::ClusterDb;
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+use mongodb::bson::{doc, Document};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Count and latest timestamp of the documents matched in a collection.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct CollectionVersion {
+    pub count: i64,
+    pub latest: String,
+}
+
+impl CollectionVersion {
+    /// Fetches the version of the documents of `collection_name` matching `match_stage`.
+    pub async fn fetch(
+        db: &ClusterDb,
+        collection_name: &str,
+        match_stage: Document,
+        time_field: &str,
+        query_options: &QueryOptions,
+    ) -> Result<Self, (StatusCode, Json<serde_json::Value>)> {
+        let version_pipeline = vec![
+            doc! { "$match": match_stage },
+            doc! {
+                "$group": {
+                    "_id": null,
+                    "count": { "$sum": 1 },
+                    "latest": { "$max": format!("${}", time_field) },
+                }
+            },
+        ];
+
+        let version_result = db
+            .aggregate(collection_name, version_pipeline, query_options)
+            .await?;
+
+        Ok(version_result
+            .first()
+            .map(|doc| CollectionVersion {
+                count: doc
+                    .get("count")
+                    .and_then(serde_json::Value::as_i64)
+                    .unwrap_or(0),
+                latest: doc
+                    .get("latest")
+                    .and_then(serde_json::Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+            })
+            .unwrap_or_default())
+    }
+}
+
+/// Weak entity tag of an admin GET response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ETag(HeaderValue);
+
+impl ETag {
+    /// Computes the ETag for the request URI and the versions of the collections the response is built from.
+    pub fn new(uri: &Uri, versions: &[CollectionVersion]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        uri.to_string().hash(&mut hasher);
+        versions.hash(&mut hasher);
+        ETag(HeaderValue::from_str(&format!("W/\"{:016x}\"", hasher.finish())).unwrap())
+    }
+
+    /// Computes the ETag for the request URI and a single stored document, as read from the collection.
+    pub fn for_document(uri: &Uri, document: &serde_json::Value) -> Self {
+        let mut hasher = DefaultHasher::new();
+        uri.to_string().hash(&mut hasher);
+        document.to_string().hash(&mut hasher);
+        ETag(HeaderValue::from_str(&format!("W/\"{:016x}\"", hasher.finish())).unwrap())
+    }
+
+    /// Computes the ETag for the request URI and the content of a cursor page.
+    pub fn for_page(uri: &Uri, page: &serde_json::Value) -> Self {
+        Self::for_document(uri, page)
+    }
+
+    /// Returns true if the `If-None-Match` request header matches this ETag.
+    pub fn matches(&self, request_headers: &HeaderMap) -> bool {
+        let etag = self.0.to_str().unwrap_or_default();
+        let opaque = etag.trim_start_matches("W/");
+        request_headers
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == opaque)
+    }
+
+    /// Adds the ETag and revalidation headers to the response headers.
+    pub fn insert_into(&self, headers: &mut HeaderMap) {
+        headers.insert(header::ETAG, self.0.clone());
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("private, no-cache"),
+        );
+    }
+
+    /// 304 response for a matching `If-None-Match`.
+    pub fn not_modified(&self) -> Response {
+        let mut headers = HeaderMap::new();
+        self.insert_into(&mut headers);
+        (StatusCode::NOT_MODIFIED, headers).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_etag_changes_with_version() {
+        let uri = Uri::from_static("/api/v1.1/admin/nodes/count/app100");
+        let version = CollectionVersion {
+            count: 3,
+            latest: "2024-05-02T00:00:00Z".to_string(),
+        };
+        let etag = ETag::new(&uri, &[version.clone()]);
+        assert_eq!(etag, ETag::new(&uri, &[version.clone()]));
+
+        let newer = CollectionVersion {
+            count: 4,
+            ..version.clone()
+        };
+        assert_ne!(etag, ETag::new(&uri, &[newer]));
+
+        let other_page = Uri::from_static("/api/v1.1/admin/nodes/count/app100?page=2");
+        assert_ne!(etag, ETag::new(&other_page, &[version]));
+    }
+
+    #[test]
+    fn test_success_etag_matches_if_none_match() {
+        let uri = Uri::from_static("/api/v1.1/admin/nodes/app100");
+        let etag = ETag::new(&uri, &[CollectionVersion::default()]);
+        let mut response_headers = HeaderMap::new();
+        etag.insert_into(&mut response_headers);
+
+        let mut request_headers = HeaderMap::new();
+        assert!(!etag.matches(&request_headers));
+
+        request_headers.insert(
+            header::IF_NONE_MATCH,
+            response_headers.get(header::ETAG).unwrap().clone(),
+        );
+        assert!(etag.matches(&request_headers));
+
+        request_headers.insert(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_static("\"other\", *"),
+        );
+        assert!(etag.matches(&request_headers));
+
+        request_headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        assert!(!etag.matches(&request_headers));
+    }
+
+    #[test]
+    fn test_success_etag_for_document() {
+        let uri = Uri::from_static("/api/v1.0/history/retrieval?reference_id=ref1");
+        let document = serde_json::json!({"reference_id": "ref1", "response": "timed out"});
+        let etag = ETag::for_document(&uri, &document);
+        assert_eq!(etag, ETag::for_document(&uri, &document));
+
+        // A late answer replacing the document changes the ETag
+        let replaced = serde_json::json!({"reference_id": "ref1", "response": "answer"});
+        assert_ne!(etag, ETag::for_document(&uri, &replaced));
+
+        let full_content =
+            Uri::from_static("/api/v1.0/history/retrieval?reference_id=ref1&full_content=true");
+        assert_ne!(etag, ETag::for_document(&full_content, &document));
+    }
+
+    #[test]
+    fn test_success_etag_not_modified() {
+        let uri = Uri::from_static("/api/v1.1/admin/nodes/app100");
+        let response = ETag::new(&uri, &[]).not_modified();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(response.headers().contains_key(header::ETAG));
+    }
+}
@@ -0,0 +1,141 @@
+/*
+ * Created Date:  Jul 19, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the producers of the events published by the facade (onboarding, deletion, config change,
+//! ingestion control, hint change). `KafkaEventProducer` publishes them to the Kafka brokers of the settings.
+//! `InMemoryEventProducer` keeps them in the process and logs them, it stands in for Kafka in the local development
+//! mode so the onboarding flow completes without any broker.
+//!
+
+use async_trait::async_trait;
+use kafka_utils::kafka_producer_client::KafkaProClient;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tracing::info;
+
+/// Default number of events kept by the in-memory producer.
+pub const DEFAULT_MAX_RECORDED_EVENTS: usize = 1000;
+
+/// Producer of the events of the facade.
+#[async_trait]
+pub trait EventProducer: Send + Sync {
+    /// Publishes a message to a topic. Returns the partition and the offset of the message.
+    async fn produce(&self, topic: &str, key: &str, message: &str) -> Result<(i32, i64), String>;
+}
+
+/// Publishes the events to Kafka.
+pub struct KafkaEventProducer {
+    pub client: KafkaProClient,
+}
+
+#[async_trait]
+impl EventProducer for KafkaEventProducer {
+    async fn produce(&self, topic: &str, key: &str, message: &str) -> Result<(i32, i64), String> {
+        self.client
+            .produce(topic, key, message)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Event kept by the in-memory producer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedEvent {
+    pub topic: String,
+    pub key: String,
+    pub message: String,
+}
+
+/// Keeps the last published events in memory, oldest first. The offset of an event is its position among all the
+/// events published since startup, the partition is always 0.
+#[derive(Debug)]
+pub struct InMemoryEventProducer {
+    max_events: usize,
+    events: Mutex<(i64, VecDeque<RecordedEvent>)>,
+}
+
+impl Default for InMemoryEventProducer {
+    fn default() -> Self {
+        InMemoryEventProducer::new(DEFAULT_MAX_RECORDED_EVENTS)
+    }
+}
+
+impl InMemoryEventProducer {
+    pub fn new(max_events: usize) -> Self {
+        InMemoryEventProducer {
+            max_events,
+            events: Mutex::new((0, VecDeque::new())),
+        }
+    }
+
+    /// Returns the recorded events of a topic, oldest first.
+    pub fn events(&self, topic: &str) -> Vec<RecordedEvent> {
+        self.events
+            .lock()
+            .map(|events| {
+                events
+                    .1
+                    .iter()
+                    .filter(|event| event.topic == topic)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl EventProducer for InMemoryEventProducer {
+    async fn produce(&self, topic: &str, key: &str, message: &str) -> Result<(i32, i64), String> {
+        let mut events = self.events.lock().map_err(|e| e.to_string())?;
+        let offset = events.0;
+        events.0 += 1;
+        events.1.push_back(RecordedEvent {
+            topic: topic.to_string(),
+            key: key.to_string(),
+            message: message.to_string(),
+        });
+        while events.1.len() > self.max_events {
+            events.1.pop_front();
+        }
+        info!(
+            message = format!(
+                "Local development event published to topic '{}' with key '{}': {}",
+                topic, key, message
+            )
+        );
+        Ok((0, offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_in_memory_event_producer() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let producer = InMemoryEventProducer::new(2);
+            assert_eq!(
+                producer.produce("onboarding", "app1", "{}").await,
+                Ok((0, 0))
+            );
+            assert_eq!(producer.produce("deletion", "app1", "{}").await, Ok((0, 1)));
+            assert_eq!(
+                producer.produce("onboarding", "app2", "{}").await,
+                Ok((0, 2))
+            );
+
+            // The oldest event is dropped past the maximum
+            let events = producer.events("onboarding");
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].key, "app2");
+            assert_eq!(producer.events("deletion").len(), 1);
+        });
+    }
+}
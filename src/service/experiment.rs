@@ -0,0 +1,258 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the A/B experiments of an app, stored on the app document and managed through the
+//! experiment endpoints.
+//! An experiment splits the retrievals of the app between its variants by weight, the weights summing to 100. The
+//! variant of a retrieval is assigned from the SHA-256 of the experiment name and the user ID, so an end user
+//! always gets the same variant of an experiment. The assignments are forwarded to the knowledge engine with the
+//! `parameters` of the variants (e.g. a prompt or a model), stored in the `experiment_variants` of the history
+//! document and counted by the `Experiment Assignment Counter` metric, for the results to be compared by variant.
+//!
+
+use axum::{http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use tracing::debug;
+use utoipa::ToSchema;
+
+/// Field of the experiments of an app in the app document.
+pub const EXPERIMENTS_FIELD: &str = "experiments";
+/// Sum of the weights of the variants of an experiment.
+const TOTAL_WEIGHT: u32 = 100;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ExperimentError {
+    #[error("Invalid name '{0}'. Names are made of letters, digits, '-' and '_'.")]
+    InvalidName(String),
+    #[error("Invalid experiment '{name}': {message}")]
+    InvalidExperiment { name: String, message: String },
+    #[error("No experiment found with name '{0}'.")]
+    UnknownExperiment(String),
+}
+
+impl From<ExperimentError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: ExperimentError) -> Self {
+        let status_code = match e {
+            ExperimentError::UnknownExperiment(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        let error_message = e.to_string();
+        debug!(message = error_message);
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Variant of an experiment, receiving `weight` percent of the traffic.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema, PartialEq)]
+pub struct ExperimentVariant {
+    pub name: String,
+    pub weight: u32,
+    /// Parameters forwarded to the knowledge engine with the assignment, e.g. a prompt or a model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+}
+
+/// A/B experiment of an app.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema, PartialEq)]
+pub struct Experiment {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Disabled experiments keep their results but assign no variant.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub variants: Vec<ExperimentVariant>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Variant of an experiment assigned to a retrieval, forwarded to the knowledge engine.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExperimentAssignment {
+    pub experiment: String,
+    pub variant: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+}
+
+/// Results of a variant of an experiment, read from the history documents of the app.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema, PartialEq)]
+pub struct VariantResults {
+    pub variant: String,
+    pub retrievals: i64,
+    pub failed_retrievals: i64,
+    pub average_confidence: Option<f64>,
+    pub total_tokens: i64,
+}
+
+impl Experiment {
+    /// Validates the names of the experiment and of its variants, and the traffic split.
+    pub fn validate(&self) -> Result<(), ExperimentError> {
+        validate_name(&self.name)?;
+        if self.variants.len() < 2 {
+            return Err(self.invalid("an experiment needs at least two variants."));
+        }
+        let mut names = HashSet::new();
+        for variant in &self.variants {
+            validate_name(&variant.name)?;
+            if !names.insert(variant.name.as_str()) {
+                return Err(self.invalid(&format!("duplicate variant '{}'.", variant.name)));
+            }
+        }
+        let total_weight: u32 = self.variants.iter().map(|variant| variant.weight).sum();
+        if total_weight != TOTAL_WEIGHT {
+            return Err(self.invalid(&format!(
+                "the weights of the variants sum to {} instead of {}.",
+                total_weight, TOTAL_WEIGHT
+            )));
+        }
+        Ok(())
+    }
+
+    /// Assigns the variant of a user, stable for the same experiment and user.
+    pub fn assign(&self, user_id: &str) -> Option<&ExperimentVariant> {
+        let bucket = bucket(&self.name, user_id);
+        let mut upper = 0;
+        self.variants.iter().find(|variant| {
+            upper += variant.weight;
+            bucket < upper
+        })
+    }
+
+    fn invalid(&self, message: &str) -> ExperimentError {
+        ExperimentError::InvalidExperiment {
+            name: self.name.clone(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Validates the name of an experiment or of a variant.
+fn validate_name(name: &str) -> Result<(), ExperimentError> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(ExperimentError::InvalidName(name.to_string()))
+    }
+}
+
+/// Bucket of a user in an experiment, between 0 and 99.
+fn bucket(experiment_name: &str, user_id: &str) -> u32 {
+    let digest = Sha256::digest(format!("{}:{}", experiment_name, user_id).as_bytes());
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(prefix) % u64::from(TOTAL_WEIGHT)) as u32
+}
+
+/// Assigns a variant of every enabled experiment of an app to a retrieval of a user.
+pub fn assign_variants(experiments: &[Experiment], user_id: &str) -> Vec<ExperimentAssignment> {
+    experiments
+        .iter()
+        .filter(|experiment| experiment.enabled)
+        .filter_map(|experiment| {
+            experiment
+                .assign(user_id)
+                .map(|variant| ExperimentAssignment {
+                    experiment: experiment.name.clone(),
+                    variant: variant.name.clone(),
+                    parameters: variant.parameters.clone(),
+                })
+        })
+        .collect()
+}
+
+/// Variant by experiment of the assignments, as stored in the history document.
+pub fn experiment_variants(assignments: &[ExperimentAssignment]) -> BTreeMap<String, String> {
+    assignments
+        .iter()
+        .map(|assignment| (assignment.experiment.clone(), assignment.variant.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn experiment() -> Experiment {
+        Experiment {
+            name: "prompt-v2".to_string(),
+            description: None,
+            enabled: true,
+            variants: vec![
+                ExperimentVariant {
+                    name: "control".to_string(),
+                    weight: 50,
+                    parameters: None,
+                },
+                ExperimentVariant {
+                    name: "concise".to_string(),
+                    weight: 50,
+                    parameters: Some(json!({"additional_prompt": "Answer in one sentence."})),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_success_experiment_assign() {
+        let experiment = experiment();
+        assert!(experiment.validate().is_ok());
+        // The assignment is sticky by user
+        let variant = experiment.assign("user@example.com").unwrap().name.clone();
+        for _ in 0..10 {
+            assert_eq!(
+                experiment.assign("user@example.com").map(|v| &v.name),
+                Some(&variant)
+            );
+        }
+        // Both variants get traffic
+        let variants: HashSet<String> = (0..100)
+            .filter_map(|user| experiment.assign(&format!("user-{}", user)))
+            .map(|variant| variant.name.clone())
+            .collect();
+        assert_eq!(variants.len(), 2);
+
+        let mut disabled = experiment.clone();
+        disabled.enabled = false;
+        let assignments = assign_variants(&[experiment, disabled], "user@example.com");
+        assert_eq!(assignments.len(), 1);
+        assert_eq!(
+            experiment_variants(&assignments).get("prompt-v2"),
+            Some(&variant)
+        );
+    }
+
+    #[test]
+    fn test_failure_experiment_validate() {
+        let mut experiment = experiment();
+        experiment.variants[1].weight = 40;
+        assert!(matches!(
+            experiment.validate(),
+            Err(ExperimentError::InvalidExperiment { .. })
+        ));
+        experiment.variants[1].weight = 50;
+        experiment.variants[1].name = "control".to_string();
+        assert!(experiment.validate().is_err());
+        experiment.variants.truncate(1);
+        assert!(experiment.validate().is_err());
+        experiment.name = "prompt v2".to_string();
+        assert_eq!(
+            experiment.validate(),
+            Err(ExperimentError::InvalidName("prompt v2".to_string()))
+        );
+    }
+}
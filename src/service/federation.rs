@@ -0,0 +1,383 @@
+/*
+ * Created Date:  Aug 7, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the federation of the overview across the deployments of the facade, e.g. the prod regions,
+//! for a single pane of glass.
+//! The peer deployments are configured in `federation.peers`, each with its name, its URL and the credentials of one
+//! of its service accounts granted `metrics:read`. With `federated=true`, the overview fetches the overview of every
+//! peer concurrently, over the same date range, each within `federation.timeout_ms` (5 000 by default), and sums the
+//! monthly and per-day series. The apps of different deployments are assumed distinct, so their counts are summed.
+//! The status of every deployment is returned with its labels: an unreachable peer is reported as `failed` and left
+//! out of the totals, it never fails the overview. The peers are called without `federated`, so they never call
+//! their own peers.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{FederationSettings, TresleFacadeServiceSettings};
+use crate::service::deployment::DeploymentLabels;
+use crate::service::state::AppState;
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use secrecy::ExposeSecret;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// Path of the overview on a peer deployment.
+const OVERVIEW_PATH: &str = "api/v1.1/admin/overview";
+const DEFAULT_TIMEOUT_MS: u64 = 5_000;
+
+/// Peer deployment of the facade.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FederationPeer {
+    pub name: String,
+    pub url: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+/// Federated overview options: peer deployments and timeout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FederationOptions {
+    pub peers: Vec<FederationPeer>,
+    pub timeout: Duration,
+}
+
+impl SettingsOptions for FederationOptions {
+    type Settings = FederationSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&FederationSettings> {
+        settings.federation.as_ref()
+    }
+
+    fn from_settings(settings: Option<&FederationSettings>) -> Self {
+        FederationOptions {
+            peers: settings
+                .and_then(|settings| settings.peers.as_ref())
+                .into_iter()
+                .flatten()
+                .map(|peer| FederationPeer {
+                    name: peer.name.clone(),
+                    url: peer.url.to_string(),
+                    client_id: peer.client_id.clone(),
+                    client_secret: peer
+                        .client_secret
+                        .as_ref()
+                        .map(|client_secret| client_secret.expose_secret().clone()),
+                })
+                .collect(),
+            timeout: Duration::from_millis(
+                settings
+                    .and_then(|settings| settings.timeout_ms)
+                    .filter(|timeout_ms| *timeout_ms > 0)
+                    .unwrap_or(DEFAULT_TIMEOUT_MS),
+            ),
+        }
+    }
+}
+
+/// Status of the overview of a deployment.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FederationStatus {
+    Ok,
+    Failed,
+}
+
+/// Deployment aggregated by the federated overview.
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct DeploymentOverview {
+    /// Name of the deployment, its configured name for a peer.
+    pub name: String,
+    /// URL of a peer, unset for the local deployment.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    pub status: FederationStatus,
+    pub total_calls: i64,
+    pub paused_apps: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl DeploymentOverview {
+    /// Overview of the local deployment.
+    pub fn local(
+        labels: &DeploymentLabels,
+        monthly: &serde_json::Value,
+        paused_apps: Vec<String>,
+    ) -> Self {
+        DeploymentOverview {
+            name: labels
+                .deployment
+                .clone()
+                .unwrap_or_else(|| "local".to_string()),
+            url: None,
+            environment: labels.environment.clone(),
+            region: labels.region.clone(),
+            status: FederationStatus::Ok,
+            total_calls: total_calls(monthly),
+            paused_apps,
+            error: None,
+        }
+    }
+}
+
+/// Overview fetched from a peer deployment. The series are empty if the peer failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerOverview {
+    pub deployment: DeploymentOverview,
+    pub monthly: serde_json::Value,
+    pub daily: serde_json::Value,
+}
+
+/// Sum of the calls of a series.
+fn total_calls(series: &serde_json::Value) -> i64 {
+    series
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.get("total_calls").and_then(serde_json::Value::as_i64))
+        .sum()
+}
+
+/// Key of an entry of the monthly series, e.g. `2024-05`.
+pub fn monthly_key(entry: &serde_json::Value) -> Option<String> {
+    Some(format!(
+        "{:04}-{:02}",
+        entry.get("_id")?.get("year")?.as_i64()?,
+        entry.get("_id")?.get("month")?.as_i64()?
+    ))
+}
+
+/// Key of an entry of the per-day series, e.g. `2024-05-01`.
+pub fn daily_key(entry: &serde_json::Value) -> Option<String> {
+    entry.get("date")?.as_str().map(str::to_string)
+}
+
+/// Merges several series, summing the `total_apps` and `total_calls` of the entries with the same key, sorted by
+/// key. Entries without a key are left out.
+pub fn merge_series<'a>(
+    series: impl IntoIterator<Item = &'a serde_json::Value>,
+    key: fn(&serde_json::Value) -> Option<String>,
+) -> serde_json::Value {
+    let mut merged: BTreeMap<String, serde_json::Value> = BTreeMap::new();
+    for entry in series
+        .into_iter()
+        .flat_map(|series| series.as_array().into_iter().flatten())
+    {
+        let Some(entry_key) = key(entry) else {
+            continue;
+        };
+        match merged.get_mut(&entry_key) {
+            Some(merged_entry) => {
+                for total in ["total_apps", "total_calls"] {
+                    let sum = merged_entry
+                        .get(total)
+                        .and_then(serde_json::Value::as_i64)
+                        .unwrap_or(0)
+                        + entry
+                            .get(total)
+                            .and_then(serde_json::Value::as_i64)
+                            .unwrap_or(0);
+                    merged_entry[total] = sum.into();
+                }
+            }
+            None => {
+                merged.insert(entry_key, entry.clone());
+            }
+        }
+    }
+    serde_json::Value::Array(merged.into_values().collect())
+}
+
+/// Fetches the overview of a peer deployment over a date range.
+async fn fetch_peer_overview(
+    app_state: &AppState,
+    options: &FederationOptions,
+    peer: &FederationPeer,
+    start_timestamp: DateTime<Utc>,
+    end_timestamp: DateTime<Utc>,
+) -> PeerOverview {
+    let url = format!("{}/{}", peer.url, OVERVIEW_PATH);
+    let mut request = app_state
+        .http_clients
+        .for_url(&url)
+        .get(&url)
+        .query(&[
+            ("utc_start_timestamp", start_timestamp.to_rfc3339()),
+            ("utc_end_timestamp", end_timestamp.to_rfc3339()),
+        ])
+        .timeout(options.timeout);
+    if let Some(client_id) = &peer.client_id {
+        request = request.basic_auth(client_id, peer.client_secret.as_ref());
+    }
+    let response = match request.send().await {
+        Ok(response) => match response.error_for_status() {
+            Ok(response) => response
+                .json::<serde_json::Value>()
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        },
+        Err(e) => Err(e.to_string()),
+    };
+
+    let mut deployment = DeploymentOverview {
+        name: peer.name.clone(),
+        url: Some(peer.url.clone()),
+        environment: None,
+        region: None,
+        status: FederationStatus::Failed,
+        total_calls: 0,
+        paused_apps: Vec::new(),
+        error: None,
+    };
+    match response {
+        Ok(overview) => {
+            let labels: DeploymentLabels = overview
+                .get("deployment")
+                .and_then(|labels| serde_json::from_value(labels.clone()).ok())
+                .unwrap_or_default();
+            let monthly = overview.get("data").cloned().unwrap_or_default();
+            deployment.environment = labels.environment;
+            deployment.region = labels.region;
+            deployment.status = FederationStatus::Ok;
+            deployment.total_calls = total_calls(&monthly);
+            deployment.paused_apps = overview
+                .get("paused_apps")
+                .and_then(|paused_apps| serde_json::from_value(paused_apps.clone()).ok())
+                .unwrap_or_default();
+            PeerOverview {
+                deployment,
+                monthly,
+                daily: overview.get("daily").cloned().unwrap_or_default(),
+            }
+        }
+        Err(error) => {
+            deployment.error = Some(error);
+            PeerOverview {
+                deployment,
+                monthly: serde_json::Value::Array(Vec::new()),
+                daily: serde_json::Value::Array(Vec::new()),
+            }
+        }
+    }
+}
+
+/// Fetches the overviews of the peer deployments concurrently over a date range.
+pub async fn fetch_peer_overviews(
+    app_state: &AppState,
+    start_timestamp: DateTime<Utc>,
+    end_timestamp: DateTime<Utc>,
+) -> Vec<PeerOverview> {
+    let options = app_state.options::<FederationOptions>();
+    join_all(
+        options.peers.iter().map(|peer| {
+            fetch_peer_overview(app_state, &options, peer, start_timestamp, end_timestamp)
+        }),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::settings::FederationPeerSettings;
+    use secrecy::Secret;
+    use serde_json::json;
+
+    #[test]
+    fn test_success_federation_options() {
+        let options = FederationOptions::from_settings(None);
+        assert!(options.peers.is_empty());
+        assert_eq!(options.timeout, Duration::from_secs(5));
+
+        let options = FederationOptions::from_settings(Some(&FederationSettings {
+            peers: Some(vec![FederationPeerSettings {
+                name: "prod-eu-west".to_string(),
+                url: "https://facade.eu-west.example.com/"
+                    .to_string()
+                    .try_into()
+                    .unwrap(),
+                client_id: Some("sa_federation".to_string()),
+                client_secret: Some(Secret::new("secret".to_string())),
+            }]),
+            timeout_ms: Some(0),
+        }));
+        assert_eq!(options.timeout, Duration::from_secs(5));
+        assert_eq!(
+            options.peers,
+            vec![FederationPeer {
+                name: "prod-eu-west".to_string(),
+                url: "https://facade.eu-west.example.com".to_string(),
+                client_id: Some("sa_federation".to_string()),
+                client_secret: Some("secret".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_success_merge_series() {
+        let local = json!([
+            {"_id": {"month": 4, "year": 2024}, "total_apps": 2, "total_calls": 10},
+            {"_id": {"month": 5, "year": 2024}, "total_apps": 1, "total_calls": 3},
+        ]);
+        let peer = json!([
+            {"_id": {"month": 12, "year": 2023}, "total_apps": 1, "total_calls": 1},
+            {"_id": {"month": 5, "year": 2024}, "total_apps": 2, "total_calls": 4},
+            {"total_apps": 1, "total_calls": 100},
+        ]);
+        assert_eq!(
+            merge_series([&local, &peer], monthly_key),
+            json!([
+                {"_id": {"month": 12, "year": 2023}, "total_apps": 1, "total_calls": 1},
+                {"_id": {"month": 4, "year": 2024}, "total_apps": 2, "total_calls": 10},
+                {"_id": {"month": 5, "year": 2024}, "total_apps": 3, "total_calls": 7},
+            ])
+        );
+
+        let local = json!([{"date": "2024-05-02", "total_apps": 1, "total_calls": 3}]);
+        let peer = json!([
+            {"date": "2024-05-01", "total_apps": 1, "total_calls": 2},
+            {"date": "2024-05-02", "total_apps": 1, "total_calls": 1},
+        ]);
+        assert_eq!(
+            merge_series([&local, &peer], daily_key),
+            json!([
+                {"date": "2024-05-01", "total_apps": 1, "total_calls": 2},
+                {"date": "2024-05-02", "total_apps": 2, "total_calls": 4},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_success_local_deployment_overview() {
+        let labels = DeploymentLabels {
+            deployment: None,
+            environment: Some("prod".to_string()),
+            region: Some("us-east-1".to_string()),
+        };
+        let monthly = json!([{"total_calls": 10}, {"total_calls": 3}]);
+        let deployment = DeploymentOverview::local(&labels, &monthly, vec!["app100".to_string()]);
+        assert_eq!(deployment.name, "local");
+        assert_eq!(deployment.total_calls, 13);
+        assert_eq!(
+            serde_json::to_value(&deployment).unwrap(),
+            json!({
+                "name": "local",
+                "environment": "prod",
+                "region": "us-east-1",
+                "status": "ok",
+                "total_calls": 13,
+                "paused_apps": ["app100"]
+            })
+        );
+    }
+}
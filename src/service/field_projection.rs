@@ -0,0 +1,185 @@
+/*
+ * Created Date:  Jul 11, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the field projection of the admin read endpoints, requested with the `fields` query
+//! parameter as a comma separated list of (dotted) field paths, e.g. `fields=app_name,onboarding_status`.
+//! The requested fields are turned into a `$project` stage, so only what the admin UI renders is read from
+//! DocumentDB and shipped in the response. Without `fields` the endpoints return their full documents.
+//! A field nested in another requested field is dropped, DocumentDB rejects projections with path collisions.
+//!
+
+use axum::{http::StatusCode, Json};
+use mongodb::bson::{Bson, Document};
+use serde_json::json;
+use tracing::debug;
+
+/// Maximum number of fields of a projection.
+pub const MAX_PROJECTED_FIELDS: usize = 50;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum FieldProjectionError {
+    #[error("Invalid field '{0}' in fields. Fields are comma separated (dotted) field names.")]
+    InvalidField(String),
+    #[error("Too many fields requested ({requested}). At most {max} fields can be requested.")]
+    TooManyFields { requested: usize, max: usize },
+}
+
+impl From<FieldProjectionError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: FieldProjectionError) -> Self {
+        let error_message = e.to_string();
+        debug!(message = error_message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Fields requested with the `fields` query parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldProjection {
+    fields: Vec<String>,
+}
+
+impl FieldProjection {
+    /// Parses the `fields` query parameter. Returns `None` if no field is requested.
+    pub fn parse(fields: Option<&str>) -> Result<Option<Self>, FieldProjectionError> {
+        let Some(fields) = fields else {
+            return Ok(None);
+        };
+        let mut requested: Vec<String> = Vec::new();
+        for field in fields.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            if !is_valid_field(field) {
+                return Err(FieldProjectionError::InvalidField(field.to_string()));
+            }
+            if !requested.iter().any(|f| f == field) {
+                requested.push(field.to_string());
+            }
+        }
+        if requested.len() > MAX_PROJECTED_FIELDS {
+            return Err(FieldProjectionError::TooManyFields {
+                requested: requested.len(),
+                max: MAX_PROJECTED_FIELDS,
+            });
+        }
+        // Drop the fields nested in another requested field
+        let fields: Vec<String> = requested
+            .iter()
+            .filter(|field| {
+                !requested
+                    .iter()
+                    .any(|parent| field.starts_with(&format!("{}.", parent)))
+            })
+            .cloned()
+            .collect();
+        if fields.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(FieldProjection { fields }))
+    }
+
+    /// Returns the requested fields.
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// Returns true if the field, or its parent, is requested.
+    pub fn contains(&self, field: &str) -> bool {
+        self.fields
+            .iter()
+            .any(|f| f == field || field.starts_with(&format!("{}.", f)))
+    }
+
+    /// Builds the `$project` stage of the requested fields. Fields with an expression in `expressions` are computed
+    /// with it, the others are returned as stored. `_id` is only returned when requested.
+    pub fn projection(&self, expressions: &Document) -> Document {
+        let mut projection = Document::new();
+        if !self.contains("_id") {
+            projection.insert("_id", 0);
+        }
+        for field in &self.fields {
+            let value = expressions.get(field).cloned().unwrap_or(Bson::Int32(1));
+            projection.insert(field.clone(), value);
+        }
+        projection
+    }
+}
+
+/// A field is a dotted path of names made of alphanumeric characters, `_` and `-`.
+fn is_valid_field(field: &str) -> bool {
+    field.split('.').all(|name| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    #[test]
+    fn test_parse_fields() {
+        assert_eq!(FieldProjection::parse(None), Ok(None));
+        assert_eq!(FieldProjection::parse(Some(" , ")), Ok(None));
+
+        let projection = FieldProjection::parse(Some("app_name, datasource.file_store,app_name"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            projection.fields(),
+            &["app_name".to_string(), "datasource.file_store".to_string()]
+        );
+        assert!(projection.contains("datasource.file_store.bucket"));
+        assert!(!projection.contains("datasource"));
+
+        // A field nested in another requested field is dropped
+        let projection = FieldProjection::parse(Some("datasource.file_store,datasource"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(projection.fields(), &["datasource".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_invalid_fields() {
+        assert_eq!(
+            FieldProjection::parse(Some("app_name,$where")),
+            Err(FieldProjectionError::InvalidField("$where".to_string()))
+        );
+        assert!(FieldProjection::parse(Some("datasource..file_store")).is_err());
+
+        let too_many = (0..=MAX_PROJECTED_FIELDS)
+            .map(|i| format!("field{}", i))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(
+            FieldProjection::parse(Some(&too_many)),
+            Err(FieldProjectionError::TooManyFields {
+                requested: MAX_PROJECTED_FIELDS + 1,
+                max: MAX_PROJECTED_FIELDS,
+            })
+        );
+    }
+
+    #[test]
+    fn test_projection() {
+        let projection = FieldProjection::parse(Some("source,total_page_num"))
+            .unwrap()
+            .unwrap();
+        let expressions = doc! { "total_page_num": "$pages" };
+        assert_eq!(
+            projection.projection(&expressions),
+            doc! { "_id": 0, "source": 1, "total_page_num": "$pages" }
+        );
+
+        let projection = FieldProjection::parse(Some("_id,source")).unwrap().unwrap();
+        assert_eq!(
+            projection.projection(&Document::new()),
+            doc! { "_id": 1, "source": 1 }
+        );
+    }
+}
@@ -0,0 +1,348 @@
+/*
+ * Created Date:  Jul 31, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the registry of the file types supported for ingestion, checked by the connectivity checks of
+//! the filestore datasources. The supported extensions are the `supported_file_types` of the settings, with an
+//! optional maximum size per extension (`file_types.max_size_mb`). The `file_types` of the datasources of an app add
+//! or exclude extensions and override the mode and the maximum sizes for the app.
+//! The file type of an object without extension, e.g. the S3 key `reports/2024-q1`, is sniffed from the magic bytes of
+//! its first bytes: a PDF, image, Office Open XML, Parquet or text content is recognized, the legacy Office formats are not.
+//! Up to `file_types.sniff_sample_size` (10) extensionless objects are sniffed per URL, 0 disabling the sniffing.
+//! In the `error` mode (default) a URL of an unsupported file type or an oversized file fails the onboarding; in the
+//! `warn` mode it is reported as a warning and skipped.
+//!
+
+use crate::configuration::settings::{FileTypesSettings, SupportedFileTypes};
+use crate::onboarding::schema::app_onboarding_request::{FileTypeMode, FileTypeOverrides};
+use std::collections::BTreeMap;
+
+/// Default number of extensionless objects sniffed per URL.
+const DEFAULT_SNIFF_SAMPLE_SIZE: usize = 10;
+/// Number of bytes read from an object to sniff its file type.
+pub const SNIFF_LENGTH: u64 = 4096;
+const BYTES_PER_MB: u64 = 1024 * 1024;
+
+/// MIME types of the extensions, sniffed from the content of the extensionless objects.
+const MIME_TYPES: [(&str, &str); 16] = [
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("bmp", "image/bmp"),
+    ("webp", "image/webp"),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    ),
+    (
+        "xlsx",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    ),
+    (
+        "pptx",
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    ),
+    ("doc", "application/msword"),
+    ("xls", "application/vnd.ms-excel"),
+    ("ppt", "application/vnd.ms-powerpoint"),
+    ("txt", "text/plain"),
+    ("csv", "text/csv"),
+    ("parquet", "application/vnd.apache.parquet"),
+];
+
+/// Outcome of the check of a file against the registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileTypeCheck {
+    Supported,
+    Unsupported,
+    /// The file is above the maximum size of its file type.
+    TooLarge {
+        max_size_bytes: u64,
+    },
+}
+
+/// Registry of the file types supported for ingestion.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileTypes {
+    /// Supported extensions, lowercase, and their maximum size in bytes.
+    extensions: BTreeMap<String, Option<u64>>,
+    pub mode: FileTypeMode,
+    pub sniff_sample_size: usize,
+}
+
+impl FileTypes {
+    /// Builds the registry from the supported file types and the file type settings. Unset options fall back to the
+    /// defaults.
+    pub fn from_settings(
+        supported_file_types: &SupportedFileTypes,
+        settings: Option<&FileTypesSettings>,
+    ) -> Self {
+        let max_size_mb = settings.and_then(|settings| settings.max_size_mb.as_ref());
+        let extensions = supported_file_types
+            .image
+            .iter()
+            .chain(supported_file_types.text.iter())
+            .map(|extension| {
+                let extension = extension.to_lowercase();
+                let max_size_bytes = max_size_mb
+                    .and_then(|max_size_mb| max_size_mb.get(&extension))
+                    .map(|max_size_mb| max_size_mb * BYTES_PER_MB);
+                (extension, max_size_bytes)
+            })
+            .collect();
+        FileTypes {
+            extensions,
+            mode: settings
+                .and_then(|settings| settings.mode)
+                .unwrap_or_default(),
+            sniff_sample_size: settings
+                .and_then(|settings| settings.sniff_sample_size)
+                .unwrap_or(DEFAULT_SNIFF_SAMPLE_SIZE),
+        }
+    }
+
+    /// Applies the file type overrides of an app.
+    pub fn with_overrides(mut self, overrides: Option<&FileTypeOverrides>) -> Self {
+        let Some(overrides) = overrides else {
+            return self;
+        };
+        for extension in &overrides.extensions {
+            self.extensions
+                .entry(extension.trim_start_matches('.').to_lowercase())
+                .or_insert(None);
+        }
+        for extension in &overrides.excluded_extensions {
+            self.extensions
+                .remove(&extension.trim_start_matches('.').to_lowercase());
+        }
+        for (extension, max_size_mb) in &overrides.max_size_mb {
+            if let Some(max_size_bytes) = self
+                .extensions
+                .get_mut(&extension.trim_start_matches('.').to_lowercase())
+            {
+                *max_size_bytes = Some(max_size_mb * BYTES_PER_MB);
+            }
+        }
+        if let Some(mode) = overrides.mode {
+            self.mode = mode;
+        }
+        self
+    }
+
+    /// Returns true if the extension is supported.
+    pub fn is_supported(&self, extension: &str) -> bool {
+        self.extensions.contains_key(&extension.to_lowercase())
+    }
+
+    /// Checks a file of the extension and size against the registry.
+    pub fn check(&self, extension: &str, size: u64) -> FileTypeCheck {
+        match self.extensions.get(&extension.to_lowercase()) {
+            None => FileTypeCheck::Unsupported,
+            Some(Some(max_size_bytes)) if size > *max_size_bytes => FileTypeCheck::TooLarge {
+                max_size_bytes: *max_size_bytes,
+            },
+            Some(_) => FileTypeCheck::Supported,
+        }
+    }
+
+    /// Returns the supported extension of a sniffed MIME type, if any.
+    pub fn sniffed_extension(&self, mime_type: &str) -> Option<&'static str> {
+        MIME_TYPES
+            .iter()
+            .find(|(extension, known)| *known == mime_type && self.is_supported(extension))
+            .map(|(extension, _)| *extension)
+    }
+}
+
+/// Returns the MIME type of an extension, if known.
+fn mime_type(extension: &str) -> Option<&'static str> {
+    MIME_TYPES
+        .iter()
+        .find(|(known, _)| *known == extension)
+        .map(|(_, mime_type)| *mime_type)
+}
+
+/// Returns the extension of the file name of an object key, if any.
+pub fn file_extension(key: &str) -> Option<&str> {
+    let file_name = key.rsplit('/').next().unwrap_or(key);
+    match file_name.rsplit_once('.') {
+        Some((_, extension)) if !extension.is_empty() => Some(extension),
+        _ => None,
+    }
+}
+
+/// Sniffs the MIME type of a content from its first bytes. `None` if the content is not recognized.
+pub fn sniff_mime_type(head: &[u8]) -> Option<&'static str> {
+    if head.starts_with(b"%PDF-") {
+        return Some("application/pdf");
+    }
+    if head.starts_with(b"PAR1") {
+        return Some("application/vnd.apache.parquet");
+    }
+    if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some("image/png");
+    }
+    if head.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Some("image/jpeg");
+    }
+    if head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a") {
+        return Some("image/gif");
+    }
+    // The reserved bytes of a bitmap file header are zero
+    if head.len() >= 14 && head.starts_with(b"BM") && head[6..10] == [0, 0, 0, 0] {
+        return Some("image/bmp");
+    }
+    if head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    // Office Open XML documents are zip archives, recognized by the folder of their first parts
+    if head.starts_with(b"PK\x03\x04") {
+        let contains = |part: &[u8]| head.windows(part.len()).any(|window| window == part);
+        return [("word/", "docx"), ("xl/", "xlsx"), ("ppt/", "pptx")]
+            .into_iter()
+            .find(|(folder, _)| contains(folder.as_bytes()))
+            .and_then(|(_, extension)| mime_type(extension));
+    }
+    sniff_text(head)
+}
+
+/// Sniffs a text content: UTF-8 without control characters, `text/csv` if its first lines have the same number of
+/// commas.
+fn sniff_text(head: &[u8]) -> Option<&'static str> {
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        // The head may end in the middle of a character
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    if text.trim().is_empty()
+        || text
+            .chars()
+            .any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t'))
+    {
+        return None;
+    }
+    let commas: Vec<usize> = text
+        .lines()
+        .take(5)
+        .map(|line| line.matches(',').count())
+        .collect();
+    match commas.as_slice() {
+        [first, _, ..] if *first > 0 && commas.iter().all(|count| count == first) => {
+            Some("text/csv")
+        }
+        _ => Some("text/plain"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn supported_file_types() -> SupportedFileTypes {
+        SupportedFileTypes {
+            image: vec!["png".to_string(), "jpg".to_string()],
+            text: vec!["pdf".to_string(), "csv".to_string(), "docx".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_success_file_types_from_settings() {
+        let file_types = FileTypes::from_settings(&supported_file_types(), None);
+        assert_eq!(file_types.mode, FileTypeMode::Error);
+        assert_eq!(file_types.sniff_sample_size, 10);
+        assert!(file_types.is_supported("pdf"));
+        assert!(file_types.is_supported("PDF"));
+        assert!(!file_types.is_supported("xxx"));
+        assert_eq!(file_types.check("pdf", u64::MAX), FileTypeCheck::Supported);
+
+        let settings = FileTypesSettings {
+            mode: Some(FileTypeMode::Warn),
+            sniff_sample_size: Some(0),
+            max_size_mb: Some(HashMap::from([("pdf".to_string(), 2)])),
+        };
+        let file_types = FileTypes::from_settings(&supported_file_types(), Some(&settings));
+        assert_eq!(file_types.mode, FileTypeMode::Warn);
+        assert_eq!(file_types.sniff_sample_size, 0);
+        assert_eq!(file_types.check("pdf", 1024), FileTypeCheck::Supported);
+        assert_eq!(
+            file_types.check("pdf", 3 * 1024 * 1024),
+            FileTypeCheck::TooLarge {
+                max_size_bytes: 2 * 1024 * 1024
+            }
+        );
+        assert_eq!(file_types.check("xxx", 1), FileTypeCheck::Unsupported);
+    }
+
+    #[test]
+    fn test_success_file_types_with_overrides() {
+        let overrides = FileTypeOverrides {
+            extensions: vec![".md".to_string()],
+            excluded_extensions: vec!["png".to_string()],
+            mode: Some(FileTypeMode::Warn),
+            max_size_mb: BTreeMap::from([("csv".to_string(), 1)]),
+        };
+        let file_types = FileTypes::from_settings(&supported_file_types(), None)
+            .with_overrides(Some(&overrides));
+        assert!(file_types.is_supported("md"));
+        assert!(!file_types.is_supported("png"));
+        assert_eq!(file_types.mode, FileTypeMode::Warn);
+        assert_eq!(
+            file_types.check("csv", 2 * 1024 * 1024),
+            FileTypeCheck::TooLarge {
+                max_size_bytes: 1024 * 1024
+            }
+        );
+        assert_eq!(file_types.sniffed_extension("image/png"), None);
+        assert_eq!(file_types.sniffed_extension("image/jpeg"), Some("jpg"));
+    }
+
+    #[test]
+    fn test_success_file_extension() {
+        assert_eq!(file_extension("folder/a.pdf"), Some("pdf"));
+        assert_eq!(file_extension("folder.v2/report"), None);
+        assert_eq!(file_extension("reports/2024-q1"), None);
+        assert_eq!(file_extension("folder/a."), None);
+    }
+
+    #[test]
+    fn test_success_sniff_mime_type() {
+        assert_eq!(
+            sniff_mime_type(b"%PDF-1.7\n%\xe2\xe3"),
+            Some("application/pdf")
+        );
+        assert_eq!(
+            sniff_mime_type(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some("image/png")
+        );
+        assert_eq!(
+            sniff_mime_type(&[0xFF, 0xD8, 0xFF, 0xE0]),
+            Some("image/jpeg")
+        );
+        assert_eq!(
+            sniff_mime_type(b"PK\x03\x04\x14\0\x06\0[Content_Types].xml...word/document.xml"),
+            Some("application/vnd.openxmlformats-officedocument.wordprocessingml.document")
+        );
+        assert_eq!(sniff_mime_type(b"PK\x03\x04\x14\0\x06\0data.bin"), None);
+        assert_eq!(
+            sniff_mime_type(b"PAR1\x15\x04\x15"),
+            Some("application/vnd.apache.parquet")
+        );
+        assert_eq!(
+            sniff_mime_type(b"id,name\n1,alpha\n2,beta\n"),
+            Some("text/csv")
+        );
+        assert_eq!(
+            sniff_mime_type("Quarterly report, caf\u{e9}".as_bytes()),
+            Some("text/plain")
+        );
+        // A head cut in the middle of a character is still text
+        assert_eq!(sniff_mime_type(b"caf\xc3"), Some("text/plain"));
+        assert_eq!(sniff_mime_type(b"\0\x01\x02binary"), None);
+        assert_eq!(sniff_mime_type(b""), None);
+    }
+}
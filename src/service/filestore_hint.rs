@@ -0,0 +1,257 @@
+/*
+ * Created Date:  Jul 14, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the hints of the filestores of an app, managed after onboarding through the hint endpoints
+//! so the prefix descriptions can be added or edited without resubmitting the whole datasource map.
+//! A hint is identified by the source type and URL of its filestore and by its prefix. The edits are applied to the
+//! stored filestores as-is, so the fields of the filestores unknown to the facade are kept.
+//! Every change is published to the hint change Kafka topic, so the ingestion pipeline re-summarizes the prefix.
+//!
+
+use crate::onboarding::schema::app_onboarding_request::Hint;
+use axum::{http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::debug;
+use utoipa::ToSchema;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum HintError {
+    #[error("No filestore found with source type '{source_type}' and url '{url}'.")]
+    FileStoreNotFound { source_type: String, url: String },
+    #[error("No hint found with prefix '{0}'.")]
+    HintNotFound(String),
+    #[error("A hint with prefix '{0}' already exists.")]
+    HintExists(String),
+    #[error("Invalid hint: {0}")]
+    Invalid(&'static str),
+}
+
+impl From<HintError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: HintError) -> Self {
+        let status_code = match e {
+            HintError::FileStoreNotFound { .. } | HintError::HintNotFound(_) => {
+                StatusCode::NOT_FOUND
+            }
+            HintError::HintExists(_) => StatusCode::CONFLICT,
+            HintError::Invalid(_) => StatusCode::BAD_REQUEST,
+        };
+        let error_message = e.to_string();
+        debug!(message = error_message);
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Hint of a filestore of an app, identified by the source type and URL of the filestore.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct FileStoreHint {
+    pub source_type: String,
+    pub url: String,
+    pub prefix: String,
+    pub descriptions: String,
+}
+
+impl FileStoreHint {
+    /// Validates the source type, URL and prefix identifying the hint.
+    pub fn validate_key(&self) -> Result<(), HintError> {
+        if self.source_type.trim().is_empty() || self.url.trim().is_empty() {
+            return Err(HintError::Invalid("source_type and url are required."));
+        }
+        if self.prefix.trim().is_empty() {
+            return Err(HintError::Invalid("prefix is required."));
+        }
+        Ok(())
+    }
+
+    /// Validates the hint, identified by its key and with descriptions.
+    pub fn validate(&self) -> Result<(), HintError> {
+        self.validate_key()?;
+        if self.descriptions.trim().is_empty() {
+            return Err(HintError::Invalid("descriptions are required."));
+        }
+        Ok(())
+    }
+
+    /// Returns the prefix and descriptions of the hint, as stored in its filestore.
+    pub fn hint(&self) -> Hint {
+        Hint {
+            prefix: self.prefix.clone(),
+            descriptions: self.descriptions.clone(),
+        }
+    }
+}
+
+/// Change of a hint published to Kafka.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HintChangeAction {
+    Added,
+    Updated,
+    Deleted,
+}
+
+/// Hint change event, carrying the plain descriptions of the hint. A deleted hint carries its last descriptions.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct HintChange {
+    pub action: HintChangeAction,
+    pub source_type: String,
+    pub url: String,
+    pub hint: Hint,
+}
+
+/// Returns the hints of the stored filestores of an app, keyed by source type.
+pub fn filestore_hints(
+    filestores: &serde_json::Map<String, serde_json::Value>,
+) -> Vec<FileStoreHint> {
+    let mut hints = Vec::new();
+    for (source_type, filestores) in filestores {
+        for filestore in filestores.as_array().into_iter().flatten() {
+            let url = filestore["url"].as_str().unwrap_or_default();
+            let stored_hints = filestore["hints"].as_array().into_iter().flatten();
+            for hint in stored_hints.filter_map(|hint| Hint::deserialize(hint).ok()) {
+                hints.push(FileStoreHint {
+                    source_type: source_type.clone(),
+                    url: url.to_string(),
+                    prefix: hint.prefix,
+                    descriptions: hint.descriptions,
+                });
+            }
+        }
+    }
+    hints.sort_by(|a, b| {
+        (&a.source_type, &a.url, &a.prefix).cmp(&(&b.source_type, &b.url, &b.prefix))
+    });
+    hints
+}
+
+/// Applies a hint change to the stored filestores of an app and returns the changed hint. For a deletion only the
+/// source type, URL and prefix of `hint` are used, the returned hint carries the descriptions of the deleted hint.
+pub fn apply_hint_change(
+    filestores: &mut serde_json::Map<String, serde_json::Value>,
+    action: HintChangeAction,
+    hint: &FileStoreHint,
+) -> Result<Hint, HintError> {
+    let hints = filestores
+        .get_mut(&hint.source_type)
+        .and_then(serde_json::Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .find(|filestore| filestore["url"] == hint.url.as_str())
+        .and_then(|filestore| filestore.get_mut("hints"))
+        .and_then(serde_json::Value::as_array_mut)
+        .ok_or_else(|| HintError::FileStoreNotFound {
+            source_type: hint.source_type.clone(),
+            url: hint.url.clone(),
+        })?;
+    let position = hints
+        .iter()
+        .position(|stored| stored["prefix"] == hint.prefix.as_str());
+    match (action, position) {
+        (HintChangeAction::Added, None) => {
+            hints.push(json!(hint.hint()));
+            Ok(hint.hint())
+        }
+        (HintChangeAction::Added, Some(_)) => Err(HintError::HintExists(hint.prefix.clone())),
+        (HintChangeAction::Updated, Some(position)) => {
+            hints[position]["descriptions"] = json!(hint.descriptions);
+            Ok(hint.hint())
+        }
+        (HintChangeAction::Deleted, Some(position)) => {
+            let deleted = hints.remove(position);
+            Ok(Hint {
+                prefix: hint.prefix.clone(),
+                descriptions: deleted["descriptions"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+            })
+        }
+        (_, None) => Err(HintError::HintNotFound(hint.prefix.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filestores() -> serde_json::Map<String, serde_json::Value> {
+        json!({
+            "s3": [{
+                "url": "s3://bucket/docs/*",
+                "hints": [{"prefix": "s3://bucket/docs/hr", "descriptions": "HR policies"}],
+                "region": "us-east-1"
+            }]
+        })
+        .as_object()
+        .unwrap()
+        .clone()
+    }
+
+    fn hint(prefix: &str, descriptions: &str) -> FileStoreHint {
+        FileStoreHint {
+            source_type: "s3".to_string(),
+            url: "s3://bucket/docs/*".to_string(),
+            prefix: prefix.to_string(),
+            descriptions: descriptions.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_validate_hint() {
+        assert!(hint("s3://bucket/docs/hr", "HR policies")
+            .validate()
+            .is_ok());
+        assert!(hint(" ", "HR policies").validate().is_err());
+        assert!(hint("s3://bucket/docs/hr", "").validate().is_err());
+        assert!(hint("s3://bucket/docs/hr", "").validate_key().is_ok());
+    }
+
+    #[test]
+    fn test_filestore_hints() {
+        assert_eq!(
+            filestore_hints(&filestores()),
+            vec![hint("s3://bucket/docs/hr", "HR policies")]
+        );
+    }
+
+    #[test]
+    fn test_apply_hint_change() {
+        let mut stored = filestores();
+        let finance = hint("s3://bucket/docs/finance", "Finance reports");
+        assert!(apply_hint_change(&mut stored, HintChangeAction::Added, &finance).is_ok());
+        assert_eq!(
+            apply_hint_change(&mut stored, HintChangeAction::Added, &finance),
+            Err(HintError::HintExists(finance.prefix.clone()))
+        );
+
+        let hr = hint("s3://bucket/docs/hr", "HR handbook");
+        apply_hint_change(&mut stored, HintChangeAction::Updated, &hr).unwrap();
+        assert_eq!(stored["s3"][0]["hints"][0]["descriptions"], "HR handbook");
+        // The fields of the filestore unknown to the facade are kept
+        assert_eq!(stored["s3"][0]["region"], "us-east-1");
+
+        let deleted = apply_hint_change(
+            &mut stored,
+            HintChangeAction::Deleted,
+            &hint(&hr.prefix, ""),
+        );
+        assert_eq!(deleted.unwrap().descriptions, "HR handbook");
+        assert_eq!(filestore_hints(&stored), vec![finance]);
+
+        assert_eq!(
+            apply_hint_change(&mut stored, HintChangeAction::Updated, &hr),
+            Err(HintError::HintNotFound(hr.prefix.clone()))
+        );
+        let mut unknown_url = hr.clone();
+        unknown_url.url = "s3://bucket/other/*".to_string();
+        assert!(matches!(
+            apply_hint_change(&mut stored, HintChangeAction::Added, &unknown_url),
+            Err(HintError::FileStoreNotFound { .. })
+        ));
+    }
+}
@@ -7,15 +7,24 @@
 //! This module contains the helper functions to generate an app document from the incoming payload
 //! and insert it into DocumentDB.
 
+use crate::onboarding::schema::app_onboarding_request::AppDataSource;
 use crate::retrieval::schema::history_document::HistoryDocument;
 use crate::service::app_document::AppDocument;
 use crate::service::app_document::AppDocumentCreationError;
+use crate::service::app_topic::app_topic;
+use crate::service::column_classification::column_classifications;
+use crate::service::encryption::EncryptionError;
 use crate::service::error::TresleFacadeCommonError;
 use crate::service::id_document::IdDocument;
+use crate::service::id_generator::ID_FORMAT_VERSION;
+use crate::service::row_filter::row_filters;
+use crate::service::source_label::source_labels;
+use crate::service::token_usage_document::TokenUsageDocument;
 use crate::service::ui_summary_document::UiSummaryDocument;
 use crate::{
     onboarding::schema::app_onboarding_request::OnboardingRequest, service::state::AppState,
 };
+use chrono::{DateTime, Utc};
 use error_utils::AxumApiError;
 use mongodb::bson::to_bson;
 use serde::Serialize;
@@ -28,10 +37,13 @@ pub enum DocType {
     ID,
     UiSummary,
     History,
+    TokenUsage,
+    ValidationJob,
 }
 
 #[instrument(skip_all)]
 /// Function to insert/create the generated App or ID document in DocumentDB. It is called from both the onboarding and retrieval modules.
+/// History documents are inserted in the cluster of the app residency.
 pub async fn create_document_in_db<T: Serialize>(
     app_state: &Arc<AppState>,
     doc: &T,
@@ -41,11 +53,14 @@ pub async fn create_document_in_db<T: Serialize>(
     reference_id: &String,
     task_id: &String,
 ) -> Result<(), AxumApiError<TresleFacadeCommonError>> {
+    let is_app_specific = matches!(doc_type, DocType::History);
     let doc_type = match doc_type {
         DocType::App => "App",
         DocType::ID => "ID",
         DocType::UiSummary => "UI Summary",
         DocType::History => "History",
+        DocType::TokenUsage => "Token Usage",
+        DocType::ValidationJob => "Validation Job",
     };
 
     let ext_message = app_state.app_settings.general_message.clone();
@@ -81,10 +96,24 @@ pub async fn create_document_in_db<T: Serialize>(
         }
     };
 
+    let db = if is_app_specific {
+        app_state.app_db(app_name).await.map_err(|e| AxumApiError {
+            inner: TresleFacadeCommonError::failed_to_create_document_in_db(
+                app_name,
+                reference_id,
+                task_id,
+                doc_type,
+                e,
+                &ext_message,
+            ),
+        })?
+    } else {
+        &app_state.db
+    };
+
     let message = format!("Creating/inserting {} document in DocumentDB.", doc_type);
     debug!(message = message);
-    match app_state
-        .db
+    match db
         .create_document(collection_name, app_bson)
         .await
         .map_err(|e| {
@@ -127,7 +156,6 @@ pub async fn generate_app_document(
     has_datasource_changed: bool,
 ) -> Result<AppDocument, AppDocumentCreationError> {
     debug!("Generating app document.");
-    let timestamp_format = app_state.app_settings.application.timestamp_format.clone();
     let sqs_key = app_state.app_settings.sqs_key_value.to_string();
     let onboarding_status = if has_datasource_changed {
         app_state.app_settings.onboard_inprogress_status.to_string()
@@ -136,20 +164,49 @@ pub async fn generate_app_document(
     };
     let search_enabled = false;
     let mm_search_enabled = true;
+    let column_classifications = column_classifications(&body.app_datasource);
+    let row_filters = row_filters(&body.app_datasource);
+    let source_labels = source_labels(&body.app_datasource);
+    // The topic name is validated with the onboarding request
+    let kafka_topic = app_topic(app_state, &body.app_name).ok().flatten();
+    let app_datasource =
+        match encrypt_datasource_descriptions(app_state, &body.app_name, body.app_datasource).await
+        {
+            Ok(app_datasource) => app_datasource,
+            Err(e) => {
+                let error_message = format!("Failed to generate app document. Error: {}", e);
+                error!(ext_message = error_message, message = error_message);
+                return Err(AppDocumentCreationError::DataSourceEncryptionFailed(
+                    e.to_string(),
+                ));
+            }
+        };
 
     let app_document = match AppDocument::builder()
         .set_app_name(body.app_name.clone())
         .set_app_description(body.app_description)
         .set_text_embedding_model(body.text_embedding_model)
         .set_multimodal_embedding_model(body.multimodal_embedding_model)
-        .set_app_datasource(body.app_datasource)
+        .set_app_datasource(app_datasource)
         .set_app_id(app_id)
         .set_api_key(api_key)
         .set_api_key_id(api_key_id)
         .set_sqs_key(sqs_key)
         .set_csv_append_same_schema(body.csv_append_same_schema)
         .set_allowed_models(body.allowed_models)
-        .set_create_timestamp(timestamp_format)
+        .set_create_timestamp(Utc::now())
+        .set_vector_store(app_state, &body.app_name)
+        .set_residency(body.residency)
+        .set_user_rate_limit(body.user_rate_limit)
+        .set_tier(body.tier)
+        .set_notification_url(body.notification_url)
+        .set_query_normalization(body.query_normalization)
+        .set_pseudonymize_user_ids(body.pseudonymize_user_ids)
+        .set_metadata(body.metadata)
+        .set_column_classifications(column_classifications)
+        .set_row_filters(row_filters)
+        .set_source_labels(source_labels)
+        .set_kafka_topic(kafka_topic)
         .set_generated_config(app_state, body.app_name)
         .set_onboarding_status(onboarding_status)
         .set_search_enabled(search_enabled)
@@ -167,6 +224,42 @@ pub async fn generate_app_document(
     Ok(app_document)
 }
 
+#[instrument(skip_all)]
+/// Function to encrypt the descriptions of the filestores, datastores, tables and columns of an app
+/// with the data key of the app. The datasources are returned as-is if encryption is disabled.
+pub async fn encrypt_datasource_descriptions(
+    app_state: &Arc<AppState>,
+    app_name: &str,
+    mut app_datasource: AppDataSource,
+) -> Result<AppDataSource, EncryptionError> {
+    if !app_state.is_encryption_enabled() {
+        return Ok(app_datasource);
+    }
+    for filestore in app_datasource.filestore.values_mut().flatten() {
+        for hint in filestore.hints.iter_mut() {
+            hint.descriptions = app_state
+                .encrypt_field(app_name, &hint.descriptions)
+                .await?;
+        }
+    }
+    for datastore in app_datasource.datastore.values_mut().flatten() {
+        if let Some(descriptions) = datastore.descriptions.as_mut() {
+            *descriptions = app_state.encrypt_field(app_name, descriptions).await?;
+        }
+        for table in datastore.tables.iter_mut() {
+            table.descriptions = app_state
+                .encrypt_field(app_name, &table.descriptions)
+                .await?;
+            for column in table.columns.iter_mut().flatten() {
+                column.descriptions = app_state
+                    .encrypt_field(app_name, &column.descriptions)
+                    .await?;
+            }
+        }
+    }
+    Ok(app_datasource)
+}
+
 #[instrument(skip_all)]
 /// Function to generate an ID document
 pub async fn generate_id_document(
@@ -178,6 +271,8 @@ pub async fn generate_id_document(
         app_name: app_name.to_string(),
         reference_id,
         task_id,
+        id_format_version: ID_FORMAT_VERSION,
+        created_at: Some(Utc::now()),
     };
     debug!("ID document generated successfully.");
     id_document
@@ -189,7 +284,7 @@ pub async fn generate_ui_summary_document(
     app_name: &String,
     call_type: &str,
     count: u64,
-    timestamp: String,
+    timestamp: DateTime<Utc>,
 ) -> UiSummaryDocument {
     let ui_summary_document = UiSummaryDocument {
         app_name: app_name.to_string(),
@@ -201,6 +296,36 @@ pub async fn generate_ui_summary_document(
     ui_summary_document
 }
 
+#[instrument(skip_all)]
+/// Function to generate a token usage document from the typed fields of a history document.
+/// Returns `None` if the knowledge engine did not report the token usage.
+pub async fn generate_token_usage_document(
+    app_name: &String,
+    user_id: &String,
+    history_document: &HistoryDocument,
+    timestamp: DateTime<Utc>,
+) -> Option<TokenUsageDocument> {
+    let token_usage = history_document.token_usage.as_ref()?;
+    let token_usage_document = TokenUsageDocument {
+        app_name: app_name.to_string(),
+        user_id: user_id.to_string(),
+        model_used: history_document
+            .model_used
+            .clone()
+            .unwrap_or_else(|| "unknown".to_string()),
+        prompt_tokens: token_usage.prompt_tokens,
+        completion_tokens: token_usage.completion_tokens,
+        // Some models only report the prompt and completion tokens
+        total_tokens: match token_usage.total_tokens {
+            0 => token_usage.prompt_tokens + token_usage.completion_tokens,
+            total_tokens => total_tokens,
+        },
+        timestamp,
+    };
+    debug!("Token usage document generated successfully.");
+    Some(token_usage_document)
+}
+
 #[instrument(skip_all)]
 /// Function to generate a history document
 pub async fn generate_history_document(
@@ -208,7 +333,7 @@ pub async fn generate_history_document(
     task_id: String,
     query: &String,
     response: &String,
-    timestamp: String,
+    timestamp: DateTime<Utc>,
     disclaimer_text: String,
 ) -> HistoryDocument {
     let history_document = HistoryDocument::new(
@@ -223,11 +348,30 @@ pub async fn generate_history_document(
     history_document
 }
 
+#[instrument(skip_all)]
+/// Function to generate the history document of a failed retrieval
+pub async fn generate_failed_history_document(
+    reference_id: String,
+    task_id: String,
+    query: &String,
+    error: &String,
+    disclaimer_text: String,
+) -> HistoryDocument {
+    let history_document = HistoryDocument::failed(
+        reference_id,
+        task_id,
+        query.to_string(),
+        error.to_string(),
+        disclaimer_text,
+    );
+    debug!("History document of the failed retrieval generated successfully.");
+    history_document
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
-    use chrono::Utc;
     use std::fs::File;
     use std::io::Read;
     use tokio::runtime::Runtime;
@@ -357,7 +501,7 @@ mod tests {
             let app_name = "app1".to_string();
             let call_type = "Onboarding";
             let count = 1;
-            let timestamp = Utc::now().to_string();
+            let timestamp = Utc::now();
 
             // Call the function
             let result =
@@ -368,6 +512,55 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_success_generate_token_usage_document() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let app_name = "app100".to_string();
+            let user_id = "user1".to_string();
+            let history_document = generate_history_document(
+                "test_reference_id".to_string(),
+                "test_task_id".to_string(),
+                &"test_query".to_string(),
+                &r#"{"answer": "42", "usage": {"input_tokens": 10, "output_tokens": 2}}"#
+                    .to_string(),
+                Utc::now(),
+                "test_disclaimer_text".to_string(),
+            )
+            .await;
+
+            // Call the function
+            let result =
+                generate_token_usage_document(&app_name, &user_id, &history_document, Utc::now())
+                    .await
+                    .unwrap();
+
+            // Check that the result is as expected
+            assert_eq!(result.model_used, "unknown");
+            assert_eq!(result.total_tokens, 12);
+
+            // No token usage reported by the engine
+            let history_document = generate_history_document(
+                "test_reference_id".to_string(),
+                "test_task_id".to_string(),
+                &"test_query".to_string(),
+                &"test_response".to_string(),
+                Utc::now(),
+                "test_disclaimer_text".to_string(),
+            )
+            .await;
+            assert!(generate_token_usage_document(
+                &app_name,
+                &user_id,
+                &history_document,
+                Utc::now()
+            )
+            .await
+            .is_none());
+        });
+    }
+
     #[test]
     fn test_success_generate_history_document() {
         let rt = Runtime::new().unwrap();
@@ -378,7 +571,7 @@ mod tests {
             let task_id = "test_task_id".to_string();
             let query = "test_query".to_string();
             let response = "test_response".to_string();
-            let timestamp = Utc::now().to_string();
+            let timestamp = Utc::now();
 
             // Call the function
             let result = generate_history_document(
@@ -0,0 +1,421 @@
+/*
+ * Created Date:  Aug 6, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the graph queries of the knowledge graph of an app, forwarded to the graph API of the
+//! knowledge engine (`knowledge_engine.graph_endpoint`, `graph/query` by default) for the admins to explore the graph
+//! the facade provisions the config of.
+//! Two queries are supported: the `neighbors` of a node, optionally through one relationship and in one direction,
+//! up to `depth` hops, and the shortest `path` between two nodes, e.g. two sources, up to `max_depth` hops.
+//! The queries are validated before reaching the engine: the node IDs are required, the relationship is a plain name,
+//! and the depth and the number of nodes returned are capped by `graph_query.max_depth` (3) and
+//! `graph_query.max_results` (200). The engine answers `{"nodes", "edges", "paths"}`, returned typed, with `truncated`
+//! set when the engine returned more nodes than the limit.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{GraphQuerySettings, TresleFacadeServiceSettings};
+use crate::service::state::AppState;
+use axum::{http::StatusCode, Json};
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+use tracing::{debug, error};
+use utoipa::ToSchema;
+
+/// Default endpoint of the graph API of the knowledge engine.
+pub const DEFAULT_GRAPH_ENDPOINT: &str = "graph/query";
+const DEFAULT_MAX_DEPTH: u32 = 3;
+const DEFAULT_MAX_RESULTS: usize = 200;
+/// Number of nodes returned by a query without `limit`, capped by `max_results`.
+const DEFAULT_LIMIT: usize = 50;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum GraphQueryError {
+    #[error("Invalid graph query: {0}")]
+    Invalid(String),
+    #[error("Failed to query the knowledge graph of '{app_name}'. Error: {message}")]
+    Engine { app_name: String, message: String },
+}
+
+impl From<GraphQueryError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: GraphQueryError) -> Self {
+        let status_code = match e {
+            GraphQueryError::Invalid(_) => StatusCode::BAD_REQUEST,
+            GraphQueryError::Engine { .. } => StatusCode::BAD_GATEWAY,
+        };
+        let error_message = e.to_string();
+        match status_code {
+            StatusCode::BAD_GATEWAY => error!(message = error_message),
+            _ => debug!(message = error_message),
+        }
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Knowledge graph query options: depth and result caps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphQueryOptions {
+    pub max_depth: u32,
+    pub max_results: usize,
+}
+
+impl SettingsOptions for GraphQueryOptions {
+    type Settings = GraphQuerySettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&GraphQuerySettings> {
+        settings.graph_query.as_ref()
+    }
+
+    fn from_settings(settings: Option<&GraphQuerySettings>) -> Self {
+        GraphQueryOptions {
+            max_depth: settings
+                .and_then(|settings| settings.max_depth)
+                .filter(|max_depth| *max_depth > 0)
+                .unwrap_or(DEFAULT_MAX_DEPTH),
+            max_results: settings
+                .and_then(|settings| settings.max_results)
+                .filter(|max_results| *max_results > 0)
+                .unwrap_or(DEFAULT_MAX_RESULTS),
+        }
+    }
+}
+
+/// Direction of the relationships followed from a node.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Outgoing,
+    Incoming,
+    #[default]
+    Both,
+}
+
+/// Graph query of the knowledge graph of an app.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GraphQuery {
+    /// Nodes reachable from a node, up to `depth` hops (1 by default).
+    Neighbors {
+        node_id: String,
+        /// Name of the only relationship followed, e.g. `CONTAINS`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        relationship: Option<String>,
+        #[serde(default)]
+        direction: Direction,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        depth: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+    },
+    /// Shortest path between two nodes, up to `max_depth` hops (`graph_query.max_depth` by default).
+    Path {
+        from: String,
+        to: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_depth: Option<u32>,
+    },
+}
+
+impl GraphQuery {
+    /// Validates the query and returns it with its defaults applied, as sent to the engine.
+    pub fn validated(self, options: &GraphQueryOptions) -> Result<Self, GraphQueryError> {
+        let node_id = |name: &str, node_id: String| match node_id.trim() {
+            "" => Err(GraphQueryError::Invalid(format!("'{}' is empty.", name))),
+            node_id => Ok(node_id.to_string()),
+        };
+        let depth = |name: &str, depth: Option<u32>, default: u32| match depth.unwrap_or(default) {
+            depth if (1..=options.max_depth).contains(&depth) => Ok(depth),
+            depth => Err(GraphQueryError::Invalid(format!(
+                "'{}' is {} instead of 1 to {}.",
+                name, depth, options.max_depth
+            ))),
+        };
+        match self {
+            GraphQuery::Neighbors {
+                node_id: id,
+                relationship,
+                direction,
+                depth: neighbors_depth,
+                limit,
+            } => {
+                let relationship = relationship
+                    .map(|relationship| relationship.trim().to_string())
+                    .filter(|relationship| !relationship.is_empty());
+                if let Some(relationship) = &relationship {
+                    if !relationship
+                        .bytes()
+                        .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+                    {
+                        return Err(GraphQueryError::Invalid(format!(
+                            "relationship '{}' can only contain letters, digits and '_'.",
+                            relationship
+                        )));
+                    }
+                }
+                let limit = match limit.unwrap_or(DEFAULT_LIMIT.min(options.max_results)) {
+                    limit if (1..=options.max_results).contains(&limit) => limit,
+                    limit => {
+                        return Err(GraphQueryError::Invalid(format!(
+                            "'limit' is {} instead of 1 to {}.",
+                            limit, options.max_results
+                        )))
+                    }
+                };
+                Ok(GraphQuery::Neighbors {
+                    node_id: node_id("node_id", id)?,
+                    relationship,
+                    direction,
+                    depth: Some(depth("depth", neighbors_depth, 1)?),
+                    limit: Some(limit),
+                })
+            }
+            GraphQuery::Path {
+                from,
+                to,
+                max_depth,
+            } => {
+                let (from, to) = (node_id("from", from)?, node_id("to", to)?);
+                if from == to {
+                    return Err(GraphQueryError::Invalid(
+                        "'from' and 'to' are the same node.".to_string(),
+                    ));
+                }
+                Ok(GraphQuery::Path {
+                    from,
+                    to,
+                    max_depth: Some(depth("max_depth", max_depth, options.max_depth)?),
+                })
+            }
+        }
+    }
+
+    /// Maximum number of nodes returned by the query.
+    fn limit(&self, options: &GraphQueryOptions) -> usize {
+        match self {
+            GraphQuery::Neighbors {
+                limit: Some(limit), ..
+            } => *limit,
+            _ => options.max_results,
+        }
+    }
+}
+
+/// Node of the knowledge graph.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct GraphNode {
+    pub id: String,
+    /// Type of the node, e.g. `FileObject`.
+    #[serde(default, alias = "type")]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Source of the node, `s3://bucket/prefix` for filestores and the table for datastores.
+    #[serde(default)]
+    pub source: Option<String>,
+    #[serde(default)]
+    pub properties: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Relationship between two nodes of the knowledge graph.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct GraphEdge {
+    #[serde(alias = "start")]
+    pub from: String,
+    #[serde(alias = "end")]
+    pub to: String,
+    #[serde(alias = "type")]
+    pub relationship: String,
+    #[serde(default)]
+    pub properties: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Result of a graph query.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct GraphQueryResult {
+    #[serde(default)]
+    pub nodes: Vec<GraphNode>,
+    #[serde(default, alias = "relationships")]
+    pub edges: Vec<GraphEdge>,
+    /// Paths of a path query, as the IDs of their nodes.
+    #[serde(default)]
+    pub paths: Vec<Vec<String>>,
+    /// Whether the engine returned more nodes than the limit of the query.
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+impl GraphQueryResult {
+    /// Keeps the first `limit` nodes and the edges between them.
+    fn truncate(mut self, limit: usize) -> Self {
+        if self.nodes.len() <= limit {
+            return self;
+        }
+        self.nodes.truncate(limit);
+        let node_ids: HashSet<&str> = self.nodes.iter().map(|node| node.id.as_str()).collect();
+        self.edges.retain(|edge| {
+            node_ids.contains(edge.from.as_str()) && node_ids.contains(edge.to.as_str())
+        });
+        self.truncated = true;
+        self
+    }
+}
+
+/// Forwards a validated graph query of an app to the graph API of the knowledge engine.
+pub async fn query_graph(
+    app_state: &AppState,
+    app_name: &str,
+    query: &GraphQuery,
+) -> Result<GraphQueryResult, GraphQueryError> {
+    let engine_error = |message: String| GraphQueryError::Engine {
+        app_name: app_name.to_string(),
+        message,
+    };
+    let url = format!(
+        "{}/{}",
+        app_state.app_settings.tresleai_urls.core_service_url,
+        app_state
+            .app_settings
+            .knowledge_engine
+            .graph_endpoint
+            .as_deref()
+            .unwrap_or(DEFAULT_GRAPH_ENDPOINT)
+    );
+    debug!(
+        "Making a POST request for a graph query of '{}' to the core microservice at URL: {}",
+        app_name, url
+    );
+    let client = app_state.http_clients.for_url(&url);
+    let response = app_state
+        .http_clients
+        .send(
+            client
+                .post(url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(json!({"app_name": app_name, "query": query}).to_string()),
+        )
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| engine_error(e.to_string()))?
+        .text()
+        .await
+        .map_err(|e| engine_error(e.to_string()))?;
+    let result: GraphQueryResult =
+        serde_json::from_str(&response).map_err(|e| engine_error(e.to_string()))?;
+    Ok(result.truncate(query.limit(&app_state.options::<GraphQueryOptions>())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> GraphQueryOptions {
+        GraphQueryOptions::from_settings(None)
+    }
+
+    #[test]
+    fn test_success_graph_query_options() {
+        assert_eq!(options().max_depth, 3);
+        assert_eq!(options().max_results, 200);
+        let options = GraphQueryOptions::from_settings(Some(&GraphQuerySettings {
+            max_depth: Some(5),
+            max_results: Some(0),
+        }));
+        assert_eq!(options.max_depth, 5);
+        assert_eq!(options.max_results, 200);
+    }
+
+    #[test]
+    fn test_success_validate_neighbors_query() {
+        let query: GraphQuery = serde_json::from_value(json!({
+            "type": "neighbors",
+            "node_id": " node-1 ",
+            "relationship": "CONTAINS"
+        }))
+        .unwrap();
+        assert_eq!(
+            query.validated(&options()).unwrap(),
+            GraphQuery::Neighbors {
+                node_id: "node-1".to_string(),
+                relationship: Some("CONTAINS".to_string()),
+                direction: Direction::Both,
+                depth: Some(1),
+                limit: Some(50),
+            }
+        );
+
+        let invalid = |query: serde_json::Value| {
+            matches!(
+                serde_json::from_value::<GraphQuery>(query)
+                    .unwrap()
+                    .validated(&options()),
+                Err(GraphQueryError::Invalid(_))
+            )
+        };
+        assert!(invalid(json!({"type": "neighbors", "node_id": " "})));
+        assert!(invalid(
+            json!({"type": "neighbors", "node_id": "n", "relationship": "A}) DETACH DELETE (n"})
+        ));
+        assert!(invalid(
+            json!({"type": "neighbors", "node_id": "n", "depth": 4})
+        ));
+        assert!(invalid(
+            json!({"type": "neighbors", "node_id": "n", "limit": 201})
+        ));
+    }
+
+    #[test]
+    fn test_success_validate_path_query() {
+        let query = GraphQuery::Path {
+            from: "s3://bucket/hr".to_string(),
+            to: "employees".to_string(),
+            max_depth: None,
+        };
+        assert_eq!(
+            query.validated(&options()).unwrap(),
+            GraphQuery::Path {
+                from: "s3://bucket/hr".to_string(),
+                to: "employees".to_string(),
+                max_depth: Some(3),
+            }
+        );
+        let query = GraphQuery::Path {
+            from: "employees".to_string(),
+            to: "employees".to_string(),
+            max_depth: None,
+        };
+        assert!(matches!(
+            query.validated(&options()),
+            Err(GraphQueryError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_success_graph_query_result() {
+        let result: GraphQueryResult = serde_json::from_value(json!({
+            "nodes": [
+                {"id": "a", "type": "FileObject", "source": "s3://bucket/hr"},
+                {"id": "b", "label": "DatabaseObjectNode"},
+                {"id": "c"}
+            ],
+            "relationships": [
+                {"start": "a", "end": "b", "type": "MENTIONS"},
+                {"from": "b", "to": "c", "relationship": "CONTAINS"}
+            ]
+        }))
+        .unwrap();
+        assert_eq!(result.nodes[0].label.as_deref(), Some("FileObject"));
+        assert_eq!(result.edges[0].relationship, "MENTIONS");
+        assert!(!result.clone().truncate(3).truncated);
+
+        let truncated = result.truncate(2);
+        assert!(truncated.truncated);
+        assert_eq!(truncated.nodes.len(), 2);
+        assert_eq!(truncated.edges.len(), 1);
+    }
+}
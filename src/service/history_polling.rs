@@ -0,0 +1,131 @@
+/*
+ * Created Date:  Jul 30, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the conditional polling of the history endpoint, which the clients poll until the history
+//! document of their retrieval is stored.
+//! A history document is served with an `ETag` computed from the stored document and, once answered, a
+//! `Last-Modified` of its response time. A request with a matching `If-None-Match` is answered with a 304 before the
+//! document is decrypted and parsed. A replaced document, e.g. a late answer of a timed out retrieval, gets a new
+//! `ETag`.
+//! While the retrieval is in progress, the 202 carries a `Retry-After` of `history_polling.retry_after_seconds` (2),
+//! so the clients stop polling every few hundred milliseconds.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{HistoryPollingSettings, TresleFacadeServiceSettings};
+use crate::service::timestamp::timestamp_from_bson;
+use axum::http::{header, HeaderMap, HeaderValue};
+use axum::response::Response;
+use chrono::{DateTime, Utc};
+use mongodb::bson::Bson;
+
+/// Default `Retry-After` of the retrievals in progress, in seconds.
+const DEFAULT_RETRY_AFTER_SECONDS: u64 = 2;
+/// IMF-fixdate format of the HTTP dates.
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// History polling options: `Retry-After` for retrievals in progress.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryPollingOptions {
+    pub retry_after_seconds: u64,
+}
+
+impl SettingsOptions for HistoryPollingOptions {
+    type Settings = HistoryPollingSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&HistoryPollingSettings> {
+        settings.history_polling.as_ref()
+    }
+
+    fn from_settings(settings: Option<&HistoryPollingSettings>) -> Self {
+        HistoryPollingOptions {
+            retry_after_seconds: settings
+                .and_then(|settings| settings.retry_after_seconds)
+                .filter(|retry_after_seconds| *retry_after_seconds > 0)
+                .unwrap_or(DEFAULT_RETRY_AFTER_SECONDS),
+        }
+    }
+}
+
+impl HistoryPollingOptions {
+    /// Adds the `Retry-After` header to the 202 response of a retrieval in progress.
+    pub fn insert_retry_after(&self, response: &mut Response) {
+        response.headers_mut().insert(
+            header::RETRY_AFTER,
+            HeaderValue::from(self.retry_after_seconds),
+        );
+    }
+}
+
+/// Response time of a stored history document, as read from the collection. `None` for a failed retrieval, whose
+/// timestamp is not a date.
+pub fn responded_at(stored: &serde_json::Value) -> Option<DateTime<Utc>> {
+    let timestamp = serde_json::from_value::<Bson>(stored.get("timestamp")?.clone()).ok()?;
+    timestamp_from_bson(&timestamp, None)
+}
+
+/// Adds the `Last-Modified` header of a history document answered at `responded_at`.
+pub fn insert_last_modified(headers: &mut HeaderMap, responded_at: Option<DateTime<Utc>>) {
+    let Some(responded_at) = responded_at else {
+        return;
+    };
+    if let Ok(value) = HeaderValue::from_str(&responded_at.format(HTTP_DATE_FORMAT).to_string()) {
+        headers.insert(header::LAST_MODIFIED, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retrieval::schema::history_document::RETRIEVAL_FAILED_TIMESTAMP;
+    use axum::response::IntoResponse;
+    use serde_json::json;
+
+    #[test]
+    fn test_success_history_polling_options() {
+        assert_eq!(
+            HistoryPollingOptions::from_settings(None).retry_after_seconds,
+            2
+        );
+        let settings = HistoryPollingSettings {
+            retry_after_seconds: Some(5),
+        };
+        let options = HistoryPollingOptions::from_settings(Some(&settings));
+        let mut response = axum::http::StatusCode::ACCEPTED.into_response();
+        options.insert_retry_after(&mut response);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "5");
+    }
+
+    #[test]
+    fn test_success_last_modified() {
+        let stored = json!({"timestamp": {"$date": {"$numberLong": "1721988000123"}}});
+        let timestamp = responded_at(&stored);
+        assert_eq!(timestamp, DateTime::from_timestamp_millis(1721988000123));
+
+        let mut headers = HeaderMap::new();
+        insert_last_modified(&mut headers, timestamp);
+        assert_eq!(
+            headers.get(header::LAST_MODIFIED).unwrap(),
+            "Fri, 26 Jul 2024 10:00:00 GMT"
+        );
+
+        // The legacy string timestamps are read too
+        let stored = json!({"timestamp": "2024-07-26 10:00:00.123 UTC"});
+        assert_eq!(
+            responded_at(&stored),
+            DateTime::from_timestamp_millis(1721988000123)
+        );
+    }
+
+    #[test]
+    fn test_success_last_modified_failed_retrieval() {
+        let stored = json!({"timestamp": RETRIEVAL_FAILED_TIMESTAMP});
+        assert_eq!(responded_at(&stored), None);
+
+        let mut headers = HeaderMap::new();
+        insert_last_modified(&mut headers, None);
+        assert!(headers.get(header::LAST_MODIFIED).is_none());
+    }
+}
@@ -0,0 +1,411 @@
+/*
+ * Created Date:  Jul 23, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the retention of the history documents of the apps.
+//! The history documents older than the retention of their app are deleted by a background job every
+//! `interval_seconds`, on the replica holding the lease of the job (see `scheduler`). The retention is `history_retention.default_retention_days` of the settings, overridden per
+//! app through the history retention endpoints; the documents of an app without retention are kept.
+//! The age of a document is read from its `_id`, so the documents of failed retrievals, stored without timestamp,
+//! expire as well.
+//! A legal hold exempts reference IDs or end users of an app from the deletion. Every change of a legal hold is
+//! recorded in the hold audit collection.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{HistoryRetentionSettings, TresleFacadeServiceSettings};
+use crate::service::metrics::{MetricRecord, APP_NAME_DIMENSION};
+use crate::service::scheduler::{acquire_lease, Job, JobRunStart, JobStatus};
+use crate::service::state::AppState;
+use axum::{http::StatusCode, Json};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use mongodb::bson::{doc, oid::ObjectId, to_document, Document};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info, instrument};
+use utoipa::ToSchema;
+
+/// Field of the history retention of an app in the app document.
+pub const HISTORY_RETENTION_FIELD: &str = "history_retention";
+/// Default collection of the legal hold changes.
+pub const DEFAULT_HOLD_AUDIT_COLLECTION: &str = "history-hold-audit";
+/// Default number of seconds between two retention rounds.
+const DEFAULT_INTERVAL_SECONDS: u64 = 3_600;
+const HISTORY_COLLECTION_SUFFIX: &str = "-history";
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum HistoryRetentionError {
+    #[error("Invalid retention_days {0}. The retention must be at least one day.")]
+    InvalidRetention(u32),
+    #[error("Invalid legal hold: {0}")]
+    InvalidHold(&'static str),
+    #[error("Failed to delete the expired history documents of '{app_name}'. Error: {message}")]
+    Delete { app_name: String, message: String },
+}
+
+impl From<HistoryRetentionError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: HistoryRetentionError) -> Self {
+        let status_code = match e {
+            HistoryRetentionError::InvalidRetention(_) | HistoryRetentionError::InvalidHold(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            HistoryRetentionError::Delete { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let error_message = e.to_string();
+        debug!(message = error_message);
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// History retention of an app, stored on the app document.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema, PartialEq)]
+pub struct HistoryRetention {
+    /// Retention of the history documents in days, overriding the default retention of the settings.
+    #[serde(default)]
+    pub retention_days: Option<u32>,
+    #[serde(default)]
+    pub legal_hold: LegalHold,
+}
+
+/// Reference IDs and end users whose history documents are exempt from the retention.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema, PartialEq)]
+pub struct LegalHold {
+    #[serde(default)]
+    pub reference_ids: Vec<String>,
+    #[serde(default)]
+    pub user_ids: Vec<String>,
+}
+
+impl LegalHold {
+    /// Validates a legal hold change: at least one entry, and no blank entries.
+    pub fn validate(&self) -> Result<(), HistoryRetentionError> {
+        if self.is_empty() {
+            return Err(HistoryRetentionError::InvalidHold(
+                "at least one reference_id or user_id is required.",
+            ));
+        }
+        let mut entries = self.reference_ids.iter().chain(self.user_ids.iter());
+        if entries.any(|entry| entry.trim().is_empty()) {
+            return Err(HistoryRetentionError::InvalidHold(
+                "reference_ids and user_ids can't be blank.",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reference_ids.is_empty() && self.user_ids.is_empty()
+    }
+
+    /// Adds the entries of a change to the hold, keeping the entries sorted and unique.
+    pub fn add(&mut self, change: &LegalHold) {
+        self.reference_ids
+            .extend(change.reference_ids.iter().cloned());
+        self.user_ids.extend(change.user_ids.iter().cloned());
+        for entries in [&mut self.reference_ids, &mut self.user_ids] {
+            entries.sort();
+            entries.dedup();
+        }
+    }
+
+    /// Removes the entries of a change from the hold. Entries not on hold are ignored.
+    pub fn remove(&mut self, change: &LegalHold) {
+        self.reference_ids
+            .retain(|reference_id| !change.reference_ids.contains(reference_id));
+        self.user_ids
+            .retain(|user_id| !change.user_ids.contains(user_id));
+    }
+}
+
+/// Change of a legal hold recorded in the hold audit collection.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HoldChangeAction {
+    Set,
+    Cleared,
+}
+
+/// Legal hold change, carrying the entries of the change.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct HoldChange {
+    pub app_name: String,
+    pub task_id: String,
+    pub action: HoldChangeAction,
+    pub reference_ids: Vec<String>,
+    pub user_ids: Vec<String>,
+    pub timestamp: String,
+}
+
+/// History retention options: default retention, job interval and hold audit collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryRetentionOptions {
+    pub default_retention_days: Option<u32>,
+    pub interval: Duration,
+    pub hold_audit_collection: String,
+}
+
+impl SettingsOptions for HistoryRetentionOptions {
+    type Settings = HistoryRetentionSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&HistoryRetentionSettings> {
+        settings.history_retention.as_ref()
+    }
+
+    fn from_settings(settings: Option<&HistoryRetentionSettings>) -> Self {
+        HistoryRetentionOptions {
+            default_retention_days: settings.and_then(|settings| settings.default_retention_days),
+            interval: Duration::from_secs(
+                settings
+                    .and_then(|settings| settings.interval_seconds)
+                    .unwrap_or(DEFAULT_INTERVAL_SECONDS),
+            ),
+            hold_audit_collection: settings
+                .and_then(|settings| settings.hold_audit_collection.clone())
+                .unwrap_or_else(|| DEFAULT_HOLD_AUDIT_COLLECTION.to_string()),
+        }
+    }
+}
+
+impl HistoryRetentionOptions {
+    /// Returns the retention of an app, its override if set, else the default retention.
+    pub fn retention_days(&self, retention: Option<&HistoryRetention>) -> Option<u32> {
+        retention
+            .and_then(|retention| retention.retention_days)
+            .or(self.default_retention_days)
+    }
+}
+
+/// Validates a retention override. `None` falls back to the default retention.
+pub fn validate_retention_days(retention_days: Option<u32>) -> Result<(), HistoryRetentionError> {
+    match retention_days {
+        Some(0) => Err(HistoryRetentionError::InvalidRetention(0)),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the filter of the history documents of an app expired at `now`: created more than `retention_days` ago,
+/// and neither of a reference ID nor of an end user on hold.
+pub fn expired_history_filter(
+    retention_days: u32,
+    now: DateTime<Utc>,
+    legal_hold: &LegalHold,
+) -> Document {
+    // An ObjectId starts with its creation timestamp, in seconds
+    let cutoff = now - ChronoDuration::days(retention_days.into());
+    let mut cutoff_bytes = [0u8; 12];
+    cutoff_bytes[..4].copy_from_slice(&(cutoff.timestamp().max(0) as u32).to_be_bytes());
+    let mut filter = doc! {"_id": {"$lt": ObjectId::from_bytes(cutoff_bytes)}};
+    if !legal_hold.reference_ids.is_empty() {
+        filter.insert("reference_id", doc! {"$nin": &legal_hold.reference_ids});
+    }
+    if !legal_hold.user_ids.is_empty() {
+        filter.insert("user_id", doc! {"$nin": &legal_hold.user_ids});
+    }
+    filter
+}
+
+/// Deletes the expired history documents of the apps every `interval_seconds`, until the process exits. Only the
+/// replica holding the lease of the job runs it.
+#[instrument(skip_all)]
+pub async fn enforce_history_retention(app_state: Arc<AppState>) {
+    let period = app_state.options::<HistoryRetentionOptions>().interval;
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if !acquire_lease(&app_state, Job::HistoryRetention, period).await {
+            continue;
+        }
+        let run = JobRunStart::new(Job::HistoryRetention);
+        let retentions = match app_state.apps().history_retentions().await {
+            Ok(retentions) => retentions,
+            Err(e) => {
+                let error_message = format!("Failed to list the history retentions. Error: {}", e);
+                error!(message = error_message);
+                run.finish(&app_state, JobStatus::Failure, error_message)
+                    .await;
+                continue;
+            }
+        };
+        let options = app_state.options::<HistoryRetentionOptions>();
+        let mut total_deleted = 0;
+        let mut failed_apps = Vec::new();
+        for (app_name, retention) in retentions {
+            let Some(retention_days) = options.retention_days(retention.as_ref()) else {
+                continue;
+            };
+            let legal_hold = retention.map(|retention| retention.legal_hold);
+            match delete_expired_history(
+                &app_state,
+                &app_name,
+                retention_days,
+                &legal_hold.unwrap_or_default(),
+            )
+            .await
+            {
+                Ok(0) => {}
+                Ok(deleted) => {
+                    info!(
+                        app_name = app_name,
+                        message = format!("Deleted {} expired history documents.", deleted)
+                    );
+                    app_state
+                        .record_metric(
+                            MetricRecord::count("History Documents Expired", deleted as usize)
+                                .dimension(APP_NAME_DIMENSION, &app_name),
+                        )
+                        .await;
+                    total_deleted += deleted;
+                }
+                Err(e) => {
+                    error!(app_name = app_name, message = e.to_string());
+                    failed_apps.push(app_name);
+                }
+            }
+        }
+        let (status, details) = if failed_apps.is_empty() {
+            (
+                JobStatus::Success,
+                format!("Deleted {} expired history documents.", total_deleted),
+            )
+        } else {
+            (
+                JobStatus::Failure,
+                format!(
+                    "Deleted {} expired history documents, failed for {}.",
+                    total_deleted,
+                    failed_apps.join(", ")
+                ),
+            )
+        };
+        run.finish(&app_state, status, details).await;
+    }
+}
+
+/// Deletes the expired history documents of an app. Returns the number of deleted documents.
+pub async fn delete_expired_history(
+    app_state: &AppState,
+    app_name: &str,
+    retention_days: u32,
+    legal_hold: &LegalHold,
+) -> Result<i64, HistoryRetentionError> {
+    let delete_error = |message: String| HistoryRetentionError::Delete {
+        app_name: app_name.to_string(),
+        message,
+    };
+    let db = app_state
+        .app_db(app_name)
+        .await
+        .map_err(|e| delete_error(e.to_string()))?;
+    let history_collection_name = format!("{}{}", app_name, HISTORY_COLLECTION_SUFFIX);
+    let result = db
+        .delete_document(
+            &history_collection_name,
+            expired_history_filter(retention_days, Utc::now(), legal_hold),
+        )
+        .await
+        .map_err(|e| delete_error(e.to_string()))?;
+    Ok(result
+        .get("deletedCount")
+        .and_then(serde_json::Value::as_i64)
+        .unwrap_or_default())
+}
+
+/// Records a legal hold change in the hold audit collection.
+pub async fn record_hold_change(app_state: &AppState, change: &HoldChange) -> Result<(), String> {
+    let document = to_document(change).map_err(|e| e.to_string())?;
+    app_state
+        .db
+        .create_document(
+            &app_state
+                .options::<HistoryRetentionOptions>()
+                .hold_audit_collection,
+            document,
+        )
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hold(reference_ids: &[&str], user_ids: &[&str]) -> LegalHold {
+        LegalHold {
+            reference_ids: reference_ids.iter().map(|id| id.to_string()).collect(),
+            user_ids: user_ids.iter().map(|id| id.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_success_legal_hold_add_and_remove() {
+        let mut legal_hold = hold(&["ref-2"], &[]);
+        legal_hold.add(&hold(&["ref-1", "ref-2"], &["user@example.com"]));
+        assert_eq!(legal_hold, hold(&["ref-1", "ref-2"], &["user@example.com"]));
+
+        legal_hold.remove(&hold(&["ref-2", "ref-3"], &["user@example.com"]));
+        assert_eq!(legal_hold, hold(&["ref-1"], &[]));
+    }
+
+    #[test]
+    fn test_failure_legal_hold_validate() {
+        assert!(hold(&["ref-1"], &[]).validate().is_ok());
+        assert!(matches!(
+            hold(&[], &[]).validate(),
+            Err(HistoryRetentionError::InvalidHold(_))
+        ));
+        assert!(matches!(
+            hold(&["ref-1"], &[" "]).validate(),
+            Err(HistoryRetentionError::InvalidHold(_))
+        ));
+        assert_eq!(
+            validate_retention_days(Some(0)),
+            Err(HistoryRetentionError::InvalidRetention(0))
+        );
+    }
+
+    #[test]
+    fn test_success_retention_days() {
+        let options = HistoryRetentionOptions::from_settings(Some(&HistoryRetentionSettings {
+            default_retention_days: Some(90),
+            interval_seconds: None,
+            hold_audit_collection: None,
+        }));
+        assert_eq!(options.hold_audit_collection, DEFAULT_HOLD_AUDIT_COLLECTION);
+        assert_eq!(options.retention_days(None), Some(90));
+        let retention = HistoryRetention {
+            retention_days: Some(7),
+            legal_hold: LegalHold::default(),
+        };
+        assert_eq!(options.retention_days(Some(&retention)), Some(7));
+        assert_eq!(
+            HistoryRetentionOptions::from_settings(None).retention_days(None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_success_expired_history_filter() {
+        let now = DateTime::parse_from_rfc3339("2024-07-23T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let filter = expired_history_filter(1, now, &hold(&["ref-1"], &[]));
+        let cutoff = filter
+            .get_document("_id")
+            .unwrap()
+            .get_object_id("$lt")
+            .unwrap();
+        assert_eq!(
+            cutoff.timestamp().to_chrono(),
+            now - ChronoDuration::days(1)
+        );
+        assert!(filter.contains_key("reference_id"));
+        assert!(!filter.contains_key("user_id"));
+    }
+}
@@ -0,0 +1,358 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the retry-safe writes of the history documents.
+//! A history document is upserted on its `reference_id`, so a retried background task or a duplicate callback of
+//! the knowledge engine never stores a second document for the same retrieval. The history collections get a
+//! unique index on `reference_id`, created on the first write of an app; a concurrent insert losing the race on the
+//! index is resolved like any other conflict.
+//! When a document already exists for the reference ID, a stored answer is never replaced by a failed retrieval,
+//! the same write repeated by the same task is dropped, and any other write replaces the stored document.
+//! Every conflict is counted by the `History Upsert Conflict Counter` metric, with the outcome as status.
+//! The duplicates stored before the index existed, or while it couldn't be created, are reconciled by the trace
+//! endpoints (`/admin/trace/{reference_id}/replay(s)`): the latest answer is kept and the other documents deleted,
+//! counted with the `reconciled` status.
+//!
+
+use crate::retrieval::schema::history_document::{HistoryDocument, RETRIEVAL_FAILED_TIMESTAMP};
+use crate::service::metrics::{MetricRecord, APP_NAME_DIMENSION, STATUS_DIMENSION};
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::state::AppState;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, to_document};
+use serde::Serialize;
+use tracing::{debug, error, info, warn};
+use utoipa::ToSchema;
+
+/// Name of the unique index on `reference_id` of the history collections.
+pub const HISTORY_REFERENCE_ID_INDEX: &str = "reference_id_unique";
+const HISTORY_COLLECTION_SUFFIX: &str = "-history";
+/// Code of the duplicate key errors of DocumentDB.
+const DUPLICATE_KEY_ERROR_CODE: &str = "E11000";
+
+#[derive(Debug, thiserror::Error)]
+pub enum HistoryUpsertError {
+    #[error("Failed to create the unique index of '{collection}'. Error: {message}")]
+    Index { collection: String, message: String },
+    #[error(
+        "Failed to upsert the history document '{reference_id}' of '{app_name}'. Error: {message}"
+    )]
+    Write {
+        app_name: String,
+        reference_id: String,
+        message: String,
+    },
+    #[error(
+        "Failed to reconcile the history documents '{reference_id}' of '{app_name}'. Error: {message}"
+    )]
+    Reconcile {
+        app_name: String,
+        reference_id: String,
+        message: String,
+    },
+}
+
+/// Outcome of the reconciliation of the history documents of a retrieval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub struct HistoryReconciliation {
+    /// Number of history documents found for the reference ID.
+    pub stored: usize,
+    /// Number of duplicate history documents deleted.
+    pub removed: usize,
+}
+
+/// Outcome of the upsert of a history document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryUpsert {
+    /// No document was stored for the reference ID.
+    Created,
+    /// The same task already stored the same outcome, nothing is written.
+    Duplicate,
+    /// The stored answer is kept over a failed retrieval.
+    Kept,
+    /// The stored document is replaced.
+    Replaced,
+}
+
+impl HistoryUpsert {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HistoryUpsert::Created => "created",
+            HistoryUpsert::Duplicate => "duplicate",
+            HistoryUpsert::Kept => "kept",
+            HistoryUpsert::Replaced => "replaced",
+        }
+    }
+}
+
+/// Resolves the write of a history document over the document stored for its reference ID.
+pub fn resolve_conflict(stored: &serde_json::Value, incoming: &HistoryDocument) -> HistoryUpsert {
+    let stored_failed = stored["timestamp"].as_str() == Some(RETRIEVAL_FAILED_TIMESTAMP);
+    let incoming_failed = incoming.timestamp.is_failed();
+    if !stored_failed && incoming_failed {
+        HistoryUpsert::Kept
+    } else if stored["task_id"].as_str() == Some(incoming.task_id.as_str())
+        && stored_failed == incoming_failed
+    {
+        HistoryUpsert::Duplicate
+    } else {
+        HistoryUpsert::Replaced
+    }
+}
+
+/// Returns the IDs of the duplicate history documents of a retrieval, sorted by descending `_id`, that are not kept.
+/// The latest answer is kept, or the latest failure if the retrieval was never answered.
+pub fn reconciled_duplicates(stored: &[serde_json::Value]) -> Vec<String> {
+    let kept = stored
+        .iter()
+        .position(|document| document["timestamp"].as_str() != Some(RETRIEVAL_FAILED_TIMESTAMP))
+        .unwrap_or(0);
+    stored
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| *index != kept)
+        .filter_map(|(_, document)| document["_id"].as_str().map(str::to_string))
+        .collect()
+}
+
+/// Returns `true` if a write failed on a unique index.
+pub(crate) fn is_duplicate_key_error(message: &str) -> bool {
+    message.contains(DUPLICATE_KEY_ERROR_CODE)
+}
+
+/// Upserts the history document of a retrieval in the history collection of its app.
+pub async fn upsert_history_document(
+    app_state: &AppState,
+    app_name: &str,
+    history_document: &HistoryDocument,
+) -> Result<HistoryUpsert, HistoryUpsertError> {
+    let reference_id = &history_document.reference_id;
+    let write_error = |message: String| HistoryUpsertError::Write {
+        app_name: app_name.to_string(),
+        reference_id: reference_id.to_string(),
+        message,
+    };
+    let history_collection_name = format!("{}{}", app_name, HISTORY_COLLECTION_SUFFIX);
+    let residency = app_state
+        .app_residency(app_name)
+        .await
+        .map_err(|e| write_error(e.to_string()))?;
+    let db = app_state
+        .residency_db(residency.as_deref())
+        .map_err(|e| write_error(e.to_string()))?;
+    if let Some(driver_databases) = app_state.driver_databases.as_ref() {
+        // Without the index the upsert still dedupes the sequential writes
+        if let Err(e) = driver_databases
+            .ensure_unique_index(
+                residency.as_deref(),
+                &history_collection_name,
+                HISTORY_REFERENCE_ID_INDEX,
+                doc! {"reference_id": 1},
+            )
+            .await
+        {
+            error!(
+                app_name = app_name,
+                message = HistoryUpsertError::Index {
+                    collection: history_collection_name.clone(),
+                    message: e.to_string(),
+                }
+                .to_string()
+            );
+        }
+    }
+
+    let document = to_document(history_document).map_err(|e| write_error(e.to_string()))?;
+    let filter = doc! {"reference_id": reference_id};
+    let stored = match db
+        .get_document(&history_collection_name, filter.clone())
+        .await
+        .map_err(|e| write_error(e.to_string()))?
+    {
+        Some(stored) => Some(stored),
+        None => match db
+            .create_document(&history_collection_name, document.clone())
+            .await
+        {
+            Ok(_) => None,
+            // A concurrent write stored the document first
+            Err(e) if is_duplicate_key_error(&e.to_string()) => db
+                .get_document(&history_collection_name, filter.clone())
+                .await
+                .map_err(|e| write_error(e.to_string()))?,
+            Err(e) => return Err(write_error(e.to_string())),
+        },
+    };
+    let Some(stored) = stored else {
+        info!(
+            app_name = app_name,
+            message = "History document created successfully in DocumentDB."
+        );
+        return Ok(HistoryUpsert::Created);
+    };
+
+    let outcome = resolve_conflict(&stored, history_document);
+    if outcome == HistoryUpsert::Replaced {
+        db.update_document(&history_collection_name, filter, document)
+            .await
+            .map_err(|e| write_error(e.to_string()))?;
+    }
+    warn!(
+        app_name = app_name,
+        message = format!(
+            "History document '{}' already stored, resolved as {}.",
+            reference_id,
+            outcome.as_str()
+        )
+    );
+    app_state
+        .record_metric(
+            MetricRecord::counter("History Upsert Conflict Counter")
+                .dimension(APP_NAME_DIMENSION, app_name)
+                .dimension(STATUS_DIMENSION, outcome.as_str()),
+        )
+        .await;
+    Ok(outcome)
+}
+
+/// Deletes the duplicate history documents of a retrieval, stored before the unique index of its collection existed
+/// or while it could not be created. Every deleted document is counted as a `reconciled` conflict.
+pub async fn reconcile_history_documents(
+    app_state: &AppState,
+    app_name: &str,
+    reference_id: &str,
+) -> Result<HistoryReconciliation, HistoryUpsertError> {
+    let reconcile_error = |message: String| HistoryUpsertError::Reconcile {
+        app_name: app_name.to_string(),
+        reference_id: reference_id.to_string(),
+        message,
+    };
+    let history_collection_name = format!("{}{}", app_name, HISTORY_COLLECTION_SUFFIX);
+    let db = app_state
+        .app_db(app_name)
+        .await
+        .map_err(|e| reconcile_error(e.to_string()))?;
+    let pipeline = vec![
+        doc! {"$match": {"reference_id": reference_id}},
+        doc! {"$sort": {"_id": -1}},
+        doc! {"$project": {"_id": {"$toString": "$_id"}, "timestamp": 1}},
+    ];
+    let stored = db
+        .aggregate(
+            &history_collection_name,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await
+        .map_err(|e| reconcile_error(e.to_string()))?;
+    let mut removed = 0;
+    for id in reconciled_duplicates(&stored) {
+        let Ok(id) = ObjectId::parse_str(&id) else {
+            continue;
+        };
+        db.delete_document(&history_collection_name, doc! {"_id": id})
+            .await
+            .map_err(|e| reconcile_error(e.to_string()))?;
+        removed += 1;
+    }
+    if removed > 0 {
+        warn!(
+            app_name = app_name,
+            message = format!(
+                "{} duplicate history document(s) '{}' deleted.",
+                removed, reference_id
+            )
+        );
+        app_state
+            .record_metric(
+                MetricRecord::count("History Upsert Conflict Counter", removed)
+                    .dimension(APP_NAME_DIMENSION, app_name)
+                    .dimension(STATUS_DIMENSION, "reconciled"),
+            )
+            .await;
+    }
+    Ok(HistoryReconciliation {
+        stored: stored.len(),
+        removed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn history_document(task_id: &str) -> HistoryDocument {
+        HistoryDocument::new(
+            "reference_id".to_string(),
+            task_id.to_string(),
+            "What is the leave policy?".to_string(),
+            "20 days per year.".to_string(),
+            chrono::Utc::now(),
+            String::new(),
+        )
+    }
+
+    fn failed_history_document(task_id: &str) -> HistoryDocument {
+        HistoryDocument::failed(
+            "reference_id".to_string(),
+            task_id.to_string(),
+            "What is the leave policy?".to_string(),
+            "Knowledge engine unavailable.".to_string(),
+            String::new(),
+        )
+    }
+
+    #[test]
+    fn test_success_resolve_conflict() {
+        let stored_answer = json!({"task_id": "task-1", "timestamp": "2024-07-26 10:00:00 UTC"});
+        let stored_failure = json!({"task_id": "task-1", "timestamp": RETRIEVAL_FAILED_TIMESTAMP});
+        // The same task writes twice
+        assert_eq!(
+            resolve_conflict(&stored_answer, &history_document("task-1")),
+            HistoryUpsert::Duplicate
+        );
+        // A failed retry never replaces an answer
+        assert_eq!(
+            resolve_conflict(&stored_answer, &failed_history_document("task-2")),
+            HistoryUpsert::Kept
+        );
+        // A successful retry replaces a failure
+        assert_eq!(
+            resolve_conflict(&stored_failure, &history_document("task-1")),
+            HistoryUpsert::Replaced
+        );
+        assert_eq!(
+            resolve_conflict(&stored_answer, &history_document("task-2")),
+            HistoryUpsert::Replaced
+        );
+    }
+
+    #[test]
+    fn test_success_reconciled_duplicates() {
+        let answer = |id: &str| json!({"_id": id, "timestamp": "2024-07-26 10:00:00 UTC"});
+        let failure = |id: &str| json!({"_id": id, "timestamp": RETRIEVAL_FAILED_TIMESTAMP});
+        assert!(reconciled_duplicates(&[]).is_empty());
+        assert!(reconciled_duplicates(&[answer("3")]).is_empty());
+        // The latest answer is kept over a later failure
+        assert_eq!(
+            reconciled_duplicates(&[failure("3"), answer("2"), answer("1")]),
+            vec!["3".to_string(), "1".to_string()]
+        );
+        // The latest failure is kept if the retrieval was never answered
+        assert_eq!(
+            reconciled_duplicates(&[failure("2"), failure("1")]),
+            vec!["1".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_success_is_duplicate_key_error() {
+        assert!(is_duplicate_key_error(
+            "E11000 duplicate key error collection: tresleai.app100-history index: reference_id_unique"
+        ));
+        assert!(!is_duplicate_key_error("connection refused"));
+    }
+}
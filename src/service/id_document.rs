@@ -5,6 +5,8 @@
  */
 //! This module contains the schema for the ID document.
 
+use crate::service::id_generator::LEGACY_ID_FORMAT_VERSION;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -12,6 +14,20 @@ pub struct IdDocument {
     pub app_name: String,
     pub reference_id: String,
     pub task_id: String,
+    /// Format version of the IDs, see `id_generator`.
+    #[serde(default = "legacy_id_format_version")]
+    pub id_format_version: u32,
+    /// Creation time of the IDs, unset for the documents stored before it.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "crate::service::timestamp::option_bson_datetime"
+    )]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+fn legacy_id_format_version() -> u32 {
+    LEGACY_ID_FORMAT_VERSION
 }
 
 #[cfg(test)]
@@ -25,6 +41,8 @@ mod tests {
             app_name: "app_name".to_string(),
             reference_id: "reference_id".to_string(),
             task_id: "task_id".to_string(),
+            id_format_version: 2,
+            created_at: Some(Utc::now()),
         };
         assert_eq!(id_document.app_name, "app_name".to_string());
         assert_eq!(id_document.reference_id, "reference_id".to_string());
@@ -36,4 +54,13 @@ mod tests {
         let id = deserialized_id_document.clone();
         println!("Now {:?} will print!", id);
     }
+
+    #[test]
+    fn test_success_legacy_id_document() {
+        let json_string =
+            r#"{"app_name": "app_name", "reference_id": "reference_id", "task_id": "task_id"}"#;
+        let id_document: IdDocument = serde_json::from_str(json_string).unwrap();
+        assert_eq!(id_document.id_format_version, LEGACY_ID_FORMAT_VERSION);
+        assert!(id_document.created_at.is_none());
+    }
 }
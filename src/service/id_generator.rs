@@ -0,0 +1,116 @@
+/*
+ * Created Date:  Jul 19, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the generation of the reference IDs and task IDs of the requests.
+//! The IDs are built on UUIDv7, which starts with the millisecond timestamp of its creation, so the reference IDs of
+//! the id collection sort in creation order and the time ordered queries of the collection need no extra field.
+//! A task ID is `TSK-{uuid}-{app_name}-{service_type}`.
+//! The generator is held by `AppState`, `DeterministicIdGenerator` can be injected through its builder so the tests
+//! get reproducible IDs. The documents of the id collection record the `id_format_version` of their IDs, the
+//! documents written before UUIDv7 carry none and are version 1.
+//!
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::{Builder, Uuid};
+
+/// Version of the format of the generated IDs: 1 for the random IDs, 2 for the UUIDv7 based IDs.
+pub const ID_FORMAT_VERSION: u32 = 2;
+/// Version of the format of the IDs of the documents written without `id_format_version`.
+pub const LEGACY_ID_FORMAT_VERSION: u32 = 1;
+/// Prefix of the task IDs.
+const TASK_ID_PREFIX: &str = "TSK";
+
+/// Generator of the reference IDs and task IDs.
+pub trait IdGenerator: Send + Sync {
+    /// Returns a new UUIDv7.
+    fn next_uuid(&self) -> Uuid;
+
+    /// Returns a new reference ID.
+    fn reference_id(&self) -> String {
+        self.next_uuid().to_string()
+    }
+
+    /// Returns a new task ID of a service of an app.
+    fn task_id(&self, app_name: &str, service_type: &str) -> String {
+        format!(
+            "{}-{}-{}-{}",
+            TASK_ID_PREFIX,
+            self.next_uuid(),
+            app_name,
+            service_type
+        )
+    }
+}
+
+/// Generates time ordered, random UUIDv7 IDs.
+#[derive(Debug, Default)]
+pub struct UuidV7IdGenerator;
+
+impl IdGenerator for UuidV7IdGenerator {
+    fn next_uuid(&self) -> Uuid {
+        Uuid::now_v7()
+    }
+}
+
+/// Generates reproducible UUIDv7 IDs: the n-th ID carries the timestamp `start_millis + n` and the counter `n`.
+#[derive(Debug, Default)]
+pub struct DeterministicIdGenerator {
+    start_millis: u64,
+    counter: AtomicU64,
+}
+
+impl DeterministicIdGenerator {
+    pub fn new(start_millis: u64) -> Self {
+        DeterministicIdGenerator {
+            start_millis,
+            counter: AtomicU64::new(0),
+        }
+    }
+}
+
+impl IdGenerator for DeterministicIdGenerator {
+    fn next_uuid(&self) -> Uuid {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        let mut counter_bytes = [0u8; 10];
+        counter_bytes[2..].copy_from_slice(&n.to_be_bytes());
+        Builder::from_unix_timestamp_millis(self.start_millis + n, &counter_bytes).into_uuid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_deterministic_id_generator() {
+        let first = DeterministicIdGenerator::new(1_720_000_000_000);
+        let second = DeterministicIdGenerator::new(1_720_000_000_000);
+        assert_eq!(first.reference_id(), second.reference_id());
+        assert_eq!(
+            first.task_id("app100", "Onboarding"),
+            second.task_id("app100", "Onboarding")
+        );
+
+        let uuid = first.next_uuid();
+        assert_eq!(uuid.get_version_num(), 7);
+        assert!(first.task_id("app100", "Onboarding").starts_with("TSK-"));
+        assert!(first
+            .task_id("app100", "Onboarding")
+            .ends_with("-app100-Onboarding"));
+    }
+
+    #[test]
+    fn test_success_ids_sort_in_creation_order() {
+        let generator = DeterministicIdGenerator::new(1_720_000_000_000);
+        let ids: Vec<String> = (0..5).map(|_| generator.reference_id()).collect();
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert_eq!(ids, sorted);
+
+        let generator = UuidV7IdGenerator;
+        assert_eq!(generator.next_uuid().get_version_num(), 7);
+        assert_ne!(generator.reference_id(), generator.reference_id());
+    }
+}
@@ -0,0 +1,62 @@
+/*
+ * Created Date:  Jul 05, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the ingestion state of an app, paused and resumed by the operators through the ingestion
+//! control endpoints so a runaway ingestion can be halted without deleting the app.
+//! The state is published to the ingestion control Kafka topic and stored on the app document. Apps without a stored
+//! state are ingesting.
+//!
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Ingestion state of an app.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum IngestionState {
+    #[default]
+    Running,
+    Paused,
+}
+
+impl IngestionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IngestionState::Running => "running",
+            IngestionState::Paused => "paused",
+        }
+    }
+}
+
+/// Ingestion control of an app, stored on the app document.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct IngestionControl {
+    pub state: IngestionState,
+    /// Timestamp (RFC 3339) of the last pause/resume, unset if the ingestion was never paused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_ingestion_control_serialization() {
+        let ingestion_control = IngestionControl {
+            state: IngestionState::Paused,
+            updated_at: Some("2024-07-05T10:00:00+00:00".to_string()),
+        };
+        let serialized = serde_json::to_value(&ingestion_control).unwrap();
+        assert_eq!(serialized["state"], "paused");
+
+        let deserialized: IngestionControl = serde_json::from_value(serialized).unwrap();
+        assert_eq!(deserialized, ingestion_control);
+        assert_eq!(
+            serde_json::to_value(IngestionControl::default()).unwrap(),
+            serde_json::json!({"state": "running"})
+        );
+    }
+}
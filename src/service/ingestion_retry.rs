@@ -0,0 +1,690 @@
+/*
+ * Created Date:  Jul 29, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the automatic retry of the failed ingestions, so the operators do not re-ingest the failed
+//! files by hand.
+//! The retry policy of an app is stored on its app document and managed through the ingestion retry policy
+//! endpoints: whether the retries are enabled, the attempts per source (3 by default, at most 10), the backoff
+//! before the second attempt (30 minutes by default, doubled for each further attempt) and the file types never
+//! retried, e.g. `xlsx`.
+//! A background job runs every `ingestion_retry.interval_seconds` on the replica holding the lease of the job (see
+//! `scheduler`). For each app with an enabled policy, it reads the sources (the `query`) of the errors of the
+//! `{app_name}-error` collection from the last `ingestion_retry.lookback_hours`, and re-publishes to the
+//! `kafka_client.ingestion_retry_topic` the sources which failed again since their last attempt, once their backoff
+//! elapsed, up to `ingestion_retry.batch_size` sources per app and run.
+//! The attempts of every source are kept in `ingestion_retry.collection` (`ingestion-retries` by default), with the
+//! status `retrying`, `exhausted` once the attempts are spent, or `excluded` for the excluded file types. The counts
+//! are returned by the knowledge node errors endpoint, and every re-published source is counted by the
+//! `Ingestion Retry Counter` metric.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{IngestionRetrySettings, TresleFacadeServiceSettings};
+use crate::service::metrics::{MetricRecord, APP_NAME_DIMENSION};
+use crate::service::publish_to_kafka::app_ingestion_retry_notify_kafka;
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::scheduler::{acquire_lease, Job, JobRunStart, JobStatus};
+use crate::service::state::AppState;
+use crate::service::timestamp::parse_timestamp;
+use axum::{http::StatusCode, Json};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use mongodb::bson::{doc, to_bson};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info, instrument};
+use utoipa::ToSchema;
+
+/// Field of the ingestion retry policy of an app in the app document.
+pub const INGESTION_RETRY_FIELD: &str = "ingestion_retry";
+/// Default number of ingestion attempts of a failed source.
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+/// Maximum number of ingestion attempts of a failed source.
+const MAX_ATTEMPTS_LIMIT: u32 = 10;
+/// Default number of minutes before the second attempt of a failed source.
+const DEFAULT_BACKOFF_MINUTES: u64 = 30;
+/// Default number of seconds between two retry rounds.
+const DEFAULT_INTERVAL_SECONDS: u64 = 900;
+/// Default collection of the attempts of the failed sources.
+const DEFAULT_RETRY_COLLECTION: &str = "ingestion-retries";
+/// Default number of sources re-published per app and round.
+const DEFAULT_BATCH_SIZE: usize = 100;
+/// Default number of hours of ingestion errors looked up.
+const DEFAULT_LOOKBACK_HOURS: i64 = 24;
+const ERROR_COLLECTION_SUFFIX: &str = "-error";
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum IngestionRetryError {
+    #[error("Invalid max_attempts {0}. Between 1 and 10 attempts are allowed.")]
+    InvalidMaxAttempts(u32),
+    #[error("Invalid backoff_minutes {0}. The backoff must be at least one minute.")]
+    InvalidBackoff(u64),
+    #[error("Invalid excluded file type '{0}'. A file extension such as 'xlsx' is expected.")]
+    InvalidFileType(String),
+    #[error("Failed to read the ingestion retries of app '{app_name}'. Error: {message}")]
+    Read { app_name: String, message: String },
+    #[error("Failed to retry the ingestion errors of app '{app_name}'. Error: {message}")]
+    Retry { app_name: String, message: String },
+}
+
+impl From<IngestionRetryError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: IngestionRetryError) -> Self {
+        let status_code = match e {
+            IngestionRetryError::InvalidMaxAttempts(_)
+            | IngestionRetryError::InvalidBackoff(_)
+            | IngestionRetryError::InvalidFileType(_) => StatusCode::BAD_REQUEST,
+            IngestionRetryError::Read { .. } | IngestionRetryError::Retry { .. } => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        let error_message = e.to_string();
+        debug!(message = error_message);
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Ingestion retry options: job interval, collection, batch size and lookback.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IngestionRetryOptions {
+    pub interval: Duration,
+    pub collection: String,
+    pub batch_size: usize,
+    pub lookback: ChronoDuration,
+}
+
+impl SettingsOptions for IngestionRetryOptions {
+    type Settings = IngestionRetrySettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&IngestionRetrySettings> {
+        settings.ingestion_retry.as_ref()
+    }
+
+    fn from_settings(settings: Option<&IngestionRetrySettings>) -> Self {
+        IngestionRetryOptions {
+            interval: Duration::from_secs(
+                settings
+                    .and_then(|settings| settings.interval_seconds)
+                    .unwrap_or(DEFAULT_INTERVAL_SECONDS),
+            ),
+            collection: settings
+                .and_then(|settings| settings.collection.clone())
+                .unwrap_or_else(|| DEFAULT_RETRY_COLLECTION.to_string()),
+            batch_size: settings
+                .and_then(|settings| settings.batch_size)
+                .filter(|batch_size| *batch_size > 0)
+                .unwrap_or(DEFAULT_BATCH_SIZE),
+            lookback: ChronoDuration::hours(
+                settings
+                    .and_then(|settings| settings.lookback_hours)
+                    .filter(|lookback_hours| *lookback_hours > 0)
+                    .unwrap_or(DEFAULT_LOOKBACK_HOURS),
+            ),
+        }
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    DEFAULT_MAX_ATTEMPTS
+}
+
+fn default_backoff_minutes() -> u64 {
+    DEFAULT_BACKOFF_MINUTES
+}
+
+/// Automatic ingestion retry policy of an app, stored on the app document.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema, PartialEq)]
+pub struct IngestionRetryPolicy {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Ingestion attempts of a failed source, the failed ingestion included.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Minutes before the second attempt, doubled for each further attempt.
+    #[serde(default = "default_backoff_minutes")]
+    pub backoff_minutes: u64,
+    /// Extensions of the files never retried, e.g. `xlsx`, matched case-insensitively.
+    #[serde(default)]
+    pub excluded_file_types: Vec<String>,
+}
+
+impl Default for IngestionRetryPolicy {
+    fn default() -> Self {
+        IngestionRetryPolicy {
+            enabled: false,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            backoff_minutes: DEFAULT_BACKOFF_MINUTES,
+            excluded_file_types: Vec::new(),
+        }
+    }
+}
+
+impl IngestionRetryPolicy {
+    /// Validates the policy and normalizes its excluded file types: lowercase, without leading dot, sorted and unique.
+    pub fn validated(mut self) -> Result<Self, IngestionRetryError> {
+        if !(1..=MAX_ATTEMPTS_LIMIT).contains(&self.max_attempts) {
+            return Err(IngestionRetryError::InvalidMaxAttempts(self.max_attempts));
+        }
+        if self.backoff_minutes == 0 {
+            return Err(IngestionRetryError::InvalidBackoff(self.backoff_minutes));
+        }
+        let mut excluded_file_types = Vec::with_capacity(self.excluded_file_types.len());
+        for file_type in &self.excluded_file_types {
+            let normalized = file_type.trim().trim_start_matches('.').to_lowercase();
+            if normalized.is_empty() || !normalized.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(IngestionRetryError::InvalidFileType(file_type.clone()));
+            }
+            excluded_file_types.push(normalized);
+        }
+        excluded_file_types.sort();
+        excluded_file_types.dedup();
+        self.excluded_file_types = excluded_file_types;
+        Ok(self)
+    }
+
+    /// Returns true if the file type of a source is excluded from the retries.
+    pub fn is_excluded(&self, source: &str) -> bool {
+        file_type(source).is_some_and(|file_type| self.excluded_file_types.contains(&file_type))
+    }
+
+    /// Backoff after an attempt, the first attempt being the failed ingestion.
+    pub fn backoff(&self, attempts: u32) -> ChronoDuration {
+        let factor = 2i64.saturating_pow(attempts.saturating_sub(1).min(16));
+        ChronoDuration::minutes((self.backoff_minutes as i64).saturating_mul(factor))
+    }
+}
+
+/// Returns the lowercase extension of the file of a source, `None` without extension.
+fn file_type(source: &str) -> Option<String> {
+    let file_name = source.rsplit('/').next()?;
+    let (_, extension) = file_name.rsplit_once('.')?;
+    (!extension.is_empty()).then(|| extension.to_lowercase())
+}
+
+/// Retry status of a failed source.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryStatus {
+    Retrying,
+    Exhausted,
+    Excluded,
+}
+
+/// Attempts of a failed source, as stored in the collection.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SourceRetry {
+    pub app_name: String,
+    pub source: String,
+    /// Ingestion attempts, the failed ingestion included.
+    pub attempts: u32,
+    pub status: RetryStatus,
+    /// Last re-publish of the source, in RFC 3339.
+    pub last_retry_at: Option<String>,
+}
+
+/// Outcome of the lookup of a failed source by a retry round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// Re-published now.
+    Retry,
+    /// Not failed again since the last attempt, or within its backoff.
+    Wait,
+    Exhausted,
+    Excluded,
+}
+
+/// Decides whether a failed source is re-published, from its last error and its attempts.
+pub fn retry_decision(
+    policy: &IngestionRetryPolicy,
+    source: &str,
+    state: Option<&SourceRetry>,
+    last_error_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> RetryDecision {
+    if policy.is_excluded(source) {
+        return RetryDecision::Excluded;
+    }
+    // The failed ingestion is the first attempt
+    let attempts = state.map(|state| state.attempts).unwrap_or(1).max(1);
+    let Some(last_retry_at) = state
+        .and_then(|state| state.last_retry_at.as_deref())
+        .and_then(|last_retry_at| parse_timestamp(last_retry_at, None))
+    else {
+        return match attempts >= policy.max_attempts {
+            true => RetryDecision::Exhausted,
+            false => RetryDecision::Retry,
+        };
+    };
+    // Without error since the last attempt, the source is being ingested again or was ingested
+    if last_error_at.map_or(true, |last_error_at| last_error_at <= last_retry_at) {
+        return RetryDecision::Wait;
+    }
+    if attempts >= policy.max_attempts {
+        RetryDecision::Exhausted
+    } else if now < last_retry_at + policy.backoff(attempts) {
+        RetryDecision::Wait
+    } else {
+        RetryDecision::Retry
+    }
+}
+
+/// Counts of the failed sources of an app by retry status.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct RetryCounts {
+    pub retrying_sources: i64,
+    pub exhausted_sources: i64,
+    pub excluded_sources: i64,
+    /// Re-publishes of the sources.
+    pub retries: i64,
+}
+
+/// Returns the counts of the failed sources of an app by retry status.
+pub async fn retry_counts(
+    app_state: &AppState,
+    app_name: &str,
+) -> Result<RetryCounts, IngestionRetryError> {
+    let pipeline = vec![
+        doc! { "$match": { "app_name": app_name } },
+        doc! { "$group": {
+            "_id": "$status",
+            "sources": { "$sum": 1 },
+            "retries": { "$sum": { "$cond": [
+                { "$ifNull": [ "$last_retry_at", false ] },
+                { "$subtract": [ "$attempts", 1 ] },
+                0
+            ] } },
+        } },
+    ];
+    let groups = app_state
+        .db
+        .aggregate(
+            &app_state.options::<IngestionRetryOptions>().collection,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await
+        .map_err(|e| IngestionRetryError::Read {
+            app_name: app_name.to_string(),
+            message: e.to_string(),
+        })?;
+    let mut counts = RetryCounts::default();
+    for group in &groups {
+        let sources = group.get("sources").and_then(serde_json::Value::as_i64);
+        let sources = sources.unwrap_or(0);
+        counts.retries += group
+            .get("retries")
+            .and_then(serde_json::Value::as_i64)
+            .unwrap_or(0);
+        match group
+            .get("_id")
+            .and_then(|status| RetryStatus::deserialize(status).ok())
+        {
+            Some(RetryStatus::Retrying) => counts.retrying_sources += sources,
+            Some(RetryStatus::Exhausted) => counts.exhausted_sources += sources,
+            Some(RetryStatus::Excluded) => counts.excluded_sources += sources,
+            None => {}
+        }
+    }
+    Ok(counts)
+}
+
+/// Re-publishes the failed sources of the apps with an enabled policy every `interval_seconds`, until the process
+/// exits.
+#[instrument(skip_all)]
+pub async fn retry_ingestion_errors(app_state: Arc<AppState>) {
+    let period = app_state.options::<IngestionRetryOptions>().interval;
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if !acquire_lease(&app_state, Job::IngestionRetry, period).await {
+            continue;
+        }
+        let run = JobRunStart::new(Job::IngestionRetry);
+        let policies = match app_state.apps().ingestion_retry_policies().await {
+            Ok(policies) => policies,
+            Err(e) => {
+                error!(message = e.to_string());
+                run.finish(&app_state, JobStatus::Failure, e.to_string())
+                    .await;
+                continue;
+            }
+        };
+        let now = Utc::now();
+        let mut retried = 0;
+        let mut failed_apps = Vec::new();
+        for (app_name, policy) in &policies {
+            match retry_app_errors(&app_state, app_name, policy, now).await {
+                Ok(count) => retried += count,
+                Err(e) => {
+                    error!(app_name = app_name, message = e.to_string());
+                    failed_apps.push(app_name.as_str());
+                }
+            }
+        }
+        let (status, details) = match failed_apps.is_empty() {
+            true => (
+                JobStatus::Success,
+                format!(
+                    "Re-published {} failed sources of {} apps.",
+                    retried,
+                    policies.len()
+                ),
+            ),
+            false => (
+                JobStatus::Failure,
+                format!(
+                    "Re-published {} failed sources. Failed to retry the errors of: {}.",
+                    retried,
+                    failed_apps.join(", ")
+                ),
+            ),
+        };
+        run.finish(&app_state, status, details).await;
+    }
+}
+
+/// Re-publishes the failed sources of an app due for a retry. Returns the number of re-published sources.
+pub async fn retry_app_errors(
+    app_state: &Arc<AppState>,
+    app_name: &str,
+    policy: &IngestionRetryPolicy,
+    now: DateTime<Utc>,
+) -> Result<usize, IngestionRetryError> {
+    let retry_error = |message: String| IngestionRetryError::Retry {
+        app_name: app_name.to_string(),
+        message,
+    };
+    let options = app_state.options::<IngestionRetryOptions>();
+    let query_options = app_state.options::<QueryOptions>();
+
+    // Last error of every failed source, the errors are stored in the cluster of the app residency
+    let app_db = app_state
+        .app_db(app_name)
+        .await
+        .map_err(|e| retry_error(e.to_string()))?;
+    let errors_pipeline = vec![
+        doc! { "$match": {
+            "event_time": { "$gte": (now - options.lookback).to_rfc3339() },
+            "query": { "$type": "string" },
+        } },
+        doc! { "$group": { "_id": "$query", "last_error_at": { "$max": "$event_time" } } },
+    ];
+    let failed_sources: Vec<(String, Option<DateTime<Utc>>)> = app_db
+        .aggregate(
+            &format!("{}{}", app_name, ERROR_COLLECTION_SUFFIX),
+            errors_pipeline,
+            &query_options,
+        )
+        .await
+        .map_err(|e| retry_error(e.to_string()))?
+        .iter()
+        .filter_map(|error| {
+            let source = error.get("_id")?.as_str()?.to_string();
+            let last_error_at = error
+                .get("last_error_at")
+                .and_then(serde_json::Value::as_str)
+                .and_then(|last_error_at| parse_timestamp(last_error_at, None));
+            Some((source, last_error_at))
+        })
+        .collect();
+    if failed_sources.is_empty() {
+        return Ok(0);
+    }
+
+    let states: HashMap<String, SourceRetry> = app_state
+        .db
+        .aggregate(
+            &options.collection,
+            vec![
+                doc! { "$match": { "app_name": app_name } },
+                doc! { "$project": { "_id": 0 } },
+            ],
+            &query_options,
+        )
+        .await
+        .map_err(|e| retry_error(e.to_string()))?
+        .into_iter()
+        .filter_map(|state| serde_json::from_value::<SourceRetry>(state).ok())
+        .map(|state| (state.source.clone(), state))
+        .collect();
+
+    let mut retries = Vec::new();
+    let mut settled = Vec::new();
+    for (source, last_error_at) in failed_sources {
+        let state = states.get(&source);
+        let attempts = state.map(|state| state.attempts).unwrap_or(1).max(1);
+        let last_retry_at = state.and_then(|state| state.last_retry_at.clone());
+        match retry_decision(policy, &source, state, last_error_at, now) {
+            RetryDecision::Retry if retries.len() < options.batch_size => {
+                let attempts = attempts + 1;
+                let status = match attempts >= policy.max_attempts {
+                    true => RetryStatus::Exhausted,
+                    false => RetryStatus::Retrying,
+                };
+                retries.push(SourceRetry {
+                    app_name: app_name.to_string(),
+                    source,
+                    attempts,
+                    status,
+                    last_retry_at: Some(now.to_rfc3339()),
+                });
+            }
+            RetryDecision::Retry | RetryDecision::Wait => {}
+            RetryDecision::Exhausted | RetryDecision::Excluded => {
+                let status = match policy.is_excluded(&source) {
+                    true => RetryStatus::Excluded,
+                    false => RetryStatus::Exhausted,
+                };
+                if state.map(|state| state.status) != Some(status) {
+                    settled.push(SourceRetry {
+                        app_name: app_name.to_string(),
+                        source,
+                        attempts,
+                        status,
+                        last_retry_at,
+                    });
+                }
+            }
+        }
+    }
+
+    if !retries.is_empty() {
+        let sources: Vec<String> = retries.iter().map(|retry| retry.source.clone()).collect();
+        let task_id = app_state.id_generator.task_id(app_name, "IngestionRetry");
+        app_ingestion_retry_notify_kafka(app_state, app_name, &sources, task_id)
+            .await
+            .map_err(|(_, Json(body))| retry_error(body["message"].to_string()))?;
+        app_state
+            .record_metric(
+                MetricRecord::count("Ingestion Retry Counter", retries.len())
+                    .dimension(APP_NAME_DIMENSION, app_name),
+            )
+            .await;
+        info!(
+            app_name = app_name,
+            message = format!(
+                "Re-published {} failed sources of app '{}'.",
+                sources.len(),
+                app_name
+            )
+        );
+    }
+    for state in retries.iter().chain(&settled) {
+        if let Err(e) = store_source_retry(app_state, &options.collection, state).await {
+            error!(app_name = app_name, message = e.as_str());
+        }
+    }
+    Ok(retries.len())
+}
+
+/// Stores the attempts of a failed source.
+async fn store_source_retry(
+    app_state: &AppState,
+    collection: &str,
+    state: &SourceRetry,
+) -> Result<(), String> {
+    let filter = doc! {"app_name": &state.app_name, "source": &state.source};
+    let status = to_bson(&state.status).map_err(|e| e.to_string())?;
+    let document = doc! {
+        "app_name": &state.app_name,
+        "source": &state.source,
+        "attempts": state.attempts as i64,
+        "status": status,
+        "last_retry_at": state.last_retry_at.as_deref(),
+    };
+    let exists = app_state
+        .db
+        .get_document(collection, filter.clone())
+        .await
+        .map_err(|e| e.to_string())?
+        .is_some();
+    let stored = if exists {
+        app_state
+            .db
+            .update_document(collection, filter, document)
+            .await
+            .map(|_| ())
+    } else {
+        app_state
+            .db
+            .create_document(collection, document)
+            .await
+            .map(|_| ())
+    };
+    stored.map_err(|e| {
+        format!(
+            "Failed to store the ingestion retry of source '{}'. Error: {}",
+            state.source, e
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(text: &str) -> DateTime<Utc> {
+        parse_timestamp(text, None).unwrap()
+    }
+
+    fn state(attempts: u32, last_retry_at: Option<&str>) -> SourceRetry {
+        SourceRetry {
+            app_name: "app100".to_string(),
+            source: "s3://bucket/docs/report.pdf".to_string(),
+            attempts,
+            status: RetryStatus::Retrying,
+            last_retry_at: last_retry_at.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_success_ingestion_retry_policy() {
+        let policy: IngestionRetryPolicy = serde_json::from_value(
+            json!({"enabled": true, "excluded_file_types": [".XLSX", "csv", "xlsx"]}),
+        )
+        .unwrap();
+        assert_eq!(policy.max_attempts, 3);
+        assert_eq!(policy.backoff_minutes, 30);
+        let policy = policy.validated().unwrap();
+        assert_eq!(policy.excluded_file_types, vec!["csv", "xlsx"]);
+        assert!(policy.is_excluded("s3://bucket/sheets/Q1.XLSX"));
+        assert!(!policy.is_excluded("s3://bucket/sheets.xlsx/Q1.pdf"));
+        assert!(!policy.is_excluded("orders"));
+        assert_eq!(policy.backoff(1), ChronoDuration::minutes(30));
+        assert_eq!(policy.backoff(3), ChronoDuration::minutes(120));
+    }
+
+    #[test]
+    fn test_failure_ingestion_retry_policy() {
+        let policy = |max_attempts, backoff_minutes, file_type: &str| IngestionRetryPolicy {
+            enabled: true,
+            max_attempts,
+            backoff_minutes,
+            excluded_file_types: vec![file_type.to_string()],
+        };
+        assert_eq!(
+            policy(0, 30, "pdf").validated(),
+            Err(IngestionRetryError::InvalidMaxAttempts(0))
+        );
+        assert_eq!(
+            policy(11, 30, "pdf").validated(),
+            Err(IngestionRetryError::InvalidMaxAttempts(11))
+        );
+        assert_eq!(
+            policy(3, 0, "pdf").validated(),
+            Err(IngestionRetryError::InvalidBackoff(0))
+        );
+        assert_eq!(
+            policy(3, 30, "tar.gz").validated(),
+            Err(IngestionRetryError::InvalidFileType("tar.gz".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_success_retry_decision() {
+        let policy = IngestionRetryPolicy {
+            enabled: true,
+            excluded_file_types: vec!["xlsx".to_string()],
+            ..IngestionRetryPolicy::default()
+        };
+        let source = "s3://bucket/docs/report.pdf";
+        let now = timestamp("2024-07-29T12:00:00Z");
+        let failed_at = Some(timestamp("2024-07-29T11:00:00Z"));
+
+        // A source never retried is retried at once
+        assert_eq!(
+            retry_decision(&policy, source, None, failed_at, now),
+            RetryDecision::Retry
+        );
+        assert_eq!(
+            retry_decision(&policy, "s3://bucket/q1.xlsx", None, failed_at, now),
+            RetryDecision::Excluded
+        );
+
+        // No error since the last retry
+        let retried = state(2, Some("2024-07-29T11:30:00Z"));
+        assert_eq!(
+            retry_decision(&policy, source, Some(&retried), failed_at, now),
+            RetryDecision::Wait
+        );
+
+        // Failed again, within then past the 60 minutes backoff of the second attempt
+        let failed_again = Some(timestamp("2024-07-29T11:45:00Z"));
+        assert_eq!(
+            retry_decision(&policy, source, Some(&retried), failed_again, now),
+            RetryDecision::Wait
+        );
+        assert_eq!(
+            retry_decision(
+                &policy,
+                source,
+                Some(&retried),
+                failed_again,
+                timestamp("2024-07-29T12:31:00Z")
+            ),
+            RetryDecision::Retry
+        );
+
+        // The attempts are spent
+        let spent = state(3, Some("2024-07-29T10:00:00Z"));
+        assert_eq!(
+            retry_decision(&policy, source, Some(&spent), failed_at, now),
+            RetryDecision::Exhausted
+        );
+    }
+
+    #[test]
+    fn test_success_ingestion_retry_options() {
+        let options = IngestionRetryOptions::from_settings(None);
+        assert_eq!(options.interval, Duration::from_secs(900));
+        assert_eq!(options.collection, "ingestion-retries");
+        assert_eq!(options.batch_size, 100);
+        assert_eq!(options.lookback, ChronoDuration::hours(24));
+    }
+}
@@ -0,0 +1,453 @@
+/*
+ * Created Date:  Jul 29, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the ingestion SLA of the apps: how long the new sources of a datasource take to become
+//! searchable. Every datasource published to Kafka by an onboarding, update or retry is recorded in
+//! `ingestion_sla.collection` (`ingestion-requests` by default) with its publish time and the sources it adds, keyed
+//! like the readiness sources (the filestore URL for filestores and the table for datastores).
+//! The ingestion latency of a source is the time from the publish to the first knowledge node of the source indexed
+//! after it, a node of a filestore source being any node under its URL. A source breaches the SLA when its latency,
+//! or the time elapsed while it has no node yet, exceeds `ingestion_sla.target_minutes` (60 by default).
+//! The `ingestion_sla.max_requests` (10) most recent publishes of an app are returned by the ingestion SLA endpoint,
+//! with the latency percentiles of their sources.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{IngestionSlaSettings, TresleFacadeServiceSettings};
+use crate::onboarding::schema::app_onboarding_request::AppDataSource;
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::readiness::app_sources;
+use crate::service::state::AppState;
+use crate::service::timestamp::{parse_timestamp, to_bson_datetime};
+use axum::{http::StatusCode, Json};
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::{doc, to_document, Document};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, error};
+use utoipa::ToSchema;
+
+/// Default minutes from the publish of a datasource to the first node of each of its new sources.
+const DEFAULT_TARGET_MINUTES: u64 = 60;
+/// Default collection of the datasource publishes.
+const DEFAULT_INGESTION_REQUEST_COLLECTION: &str = "ingestion-requests";
+/// Default number of publishes returned for an app.
+const DEFAULT_MAX_REQUESTS: usize = 10;
+
+#[derive(Debug, thiserror::Error)]
+pub enum IngestionSlaError {
+    #[error("Failed to record the datasource publish of app '{app_name}'. Error: {message}")]
+    Record { app_name: String, message: String },
+    #[error("Failed to read the ingestion SLA of app '{app_name}'. Error: {message}")]
+    Read { app_name: String, message: String },
+}
+
+impl From<IngestionSlaError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: IngestionSlaError) -> Self {
+        let error_message = e.to_string();
+        error!(message = error_message);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Ingestion SLA options: target, collection and number of publishes returned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IngestionSlaOptions {
+    pub target: Duration,
+    pub collection: String,
+    pub max_requests: usize,
+}
+
+impl SettingsOptions for IngestionSlaOptions {
+    type Settings = IngestionSlaSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&IngestionSlaSettings> {
+        settings.ingestion_sla.as_ref()
+    }
+
+    fn from_settings(settings: Option<&IngestionSlaSettings>) -> Self {
+        let target_minutes = settings
+            .and_then(|settings| settings.target_minutes)
+            .unwrap_or(DEFAULT_TARGET_MINUTES);
+        IngestionSlaOptions {
+            target: Duration::minutes(target_minutes as i64),
+            collection: settings
+                .and_then(|settings| settings.collection.clone())
+                .unwrap_or_else(|| DEFAULT_INGESTION_REQUEST_COLLECTION.to_string()),
+            max_requests: settings
+                .and_then(|settings| settings.max_requests)
+                .filter(|max_requests| *max_requests > 0)
+                .unwrap_or(DEFAULT_MAX_REQUESTS),
+        }
+    }
+}
+
+/// Datasource publish of an app, as stored in the collection.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IngestionRequest {
+    pub app_name: String,
+    pub task_id: String,
+    #[serde(with = "crate::service::timestamp::bson_datetime")]
+    pub published_at: DateTime<Utc>,
+    /// Sources added by the publish.
+    pub sources: Vec<String>,
+}
+
+/// Ingestion latency of a source of a publish.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct SourceIngestion {
+    pub source: String,
+    /// First node of the source indexed after the publish, unset while the source has no node.
+    pub first_indexed_at: Option<String>,
+    pub latency_seconds: Option<i64>,
+    pub sla_breached: bool,
+}
+
+/// Ingestion of the sources of a publish.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct RequestIngestion {
+    pub task_id: String,
+    pub published_at: String,
+    /// Whether every source has a node.
+    pub complete: bool,
+    /// Whether a source breaches the SLA.
+    pub sla_breached: bool,
+    pub sources: Vec<SourceIngestion>,
+}
+
+/// Latency statistics of the sources of the publishes, over the indexed sources.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct IngestionLatencyStats {
+    pub tracked_sources: usize,
+    pub indexed_sources: usize,
+    pub pending_sources: usize,
+    pub breached_sources: usize,
+    pub p50_latency_seconds: Option<i64>,
+    pub p95_latency_seconds: Option<i64>,
+    pub max_latency_seconds: Option<i64>,
+}
+
+/// Ingestion SLA of an app.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct IngestionSlaReport {
+    pub target_minutes: i64,
+    pub stats: IngestionLatencyStats,
+    /// Most recent publishes first.
+    pub requests: Vec<RequestIngestion>,
+}
+
+/// Returns the sources added by a datasource to the existing datasource of the app.
+pub fn published_sources(
+    new_app_datasource: &AppDataSource,
+    existing_app_datasource: Option<&AppDataSource>,
+) -> Vec<String> {
+    let sources = |datasource: Option<&AppDataSource>| {
+        datasource
+            .map(|datasource| app_sources(&json!({ "app_datasource": datasource })))
+            .unwrap_or_default()
+    };
+    let existing_sources = sources(existing_app_datasource);
+    sources(Some(new_app_datasource))
+        .into_iter()
+        .filter(|source| !existing_sources.contains(source))
+        .collect()
+}
+
+/// Records a datasource publish of an app. A publish without new source is not recorded.
+pub async fn record_ingestion_request(
+    app_state: &AppState,
+    app_name: &str,
+    task_id: &str,
+    sources: Vec<String>,
+) -> Result<(), IngestionSlaError> {
+    if sources.is_empty() {
+        return Ok(());
+    }
+    let record_error = |message: String| IngestionSlaError::Record {
+        app_name: app_name.to_string(),
+        message,
+    };
+    let request = IngestionRequest {
+        app_name: app_name.to_string(),
+        task_id: task_id.to_string(),
+        published_at: Utc::now(),
+        sources,
+    };
+    let document = to_document(&request).map_err(|e| record_error(e.to_string()))?;
+    app_state
+        .db
+        .create_document(
+            &app_state.options::<IngestionSlaOptions>().collection,
+            document,
+        )
+        .await
+        .map_err(|e| record_error(e.to_string()))?;
+    debug!(
+        app_name = app_name,
+        message = format!(
+            "Recorded the publish of {} new source(s) of app '{}'.",
+            request.sources.len(),
+            app_name
+        )
+    );
+    Ok(())
+}
+
+/// Pipeline of the first node of a source indexed at or after a publish. The `indexed_at` of the nodes is written
+/// by the ingestion as a string, converted to a date to be compared.
+fn first_node_pipeline(source: &str, published_at: DateTime<Utc>) -> Vec<Document> {
+    let under_source = format!("^{}/", regex::escape(source));
+    vec![
+        doc! { "$match": { "$or": [ { "source": source }, { "source": { "$regex": under_source } } ] } },
+        doc! { "$addFields": { "_indexed_date": { "$convert": {
+            "input": "$indexed_at", "to": "date", "onError": null, "onNull": null
+        } } } },
+        doc! { "$match": { "_indexed_date": { "$gte": to_bson_datetime(published_at) } } },
+        doc! { "$sort": { "_indexed_date": 1 } },
+        doc! { "$limit": 1 },
+        doc! { "$project": { "_id": 0, "indexed_at": 1 } },
+    ]
+}
+
+/// Computes the ingestion of a source from its first node indexed after the publish.
+pub fn source_ingestion(
+    source: &str,
+    published_at: DateTime<Utc>,
+    first_indexed_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    target: Duration,
+) -> SourceIngestion {
+    let latency = first_indexed_at.map(|indexed_at| indexed_at - published_at);
+    let elapsed = latency.unwrap_or(now - published_at);
+    SourceIngestion {
+        source: source.to_string(),
+        first_indexed_at: first_indexed_at.map(|indexed_at| indexed_at.to_rfc3339()),
+        latency_seconds: latency.map(|latency| latency.num_seconds()),
+        sla_breached: elapsed > target,
+    }
+}
+
+/// Nearest-rank percentile of sorted values.
+fn percentile(sorted: &[i64], percent: usize) -> Option<i64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (percent * sorted.len()).div_ceil(100).max(1);
+    sorted.get(rank - 1).copied()
+}
+
+/// Computes the latency statistics of the sources of the publishes.
+pub fn latency_stats(requests: &[RequestIngestion]) -> IngestionLatencyStats {
+    let sources: Vec<&SourceIngestion> = requests
+        .iter()
+        .flat_map(|request| &request.sources)
+        .collect();
+    let mut latencies: Vec<i64> = sources
+        .iter()
+        .filter_map(|source| source.latency_seconds)
+        .collect();
+    latencies.sort_unstable();
+    IngestionLatencyStats {
+        tracked_sources: sources.len(),
+        indexed_sources: latencies.len(),
+        pending_sources: sources.len() - latencies.len(),
+        breached_sources: sources.iter().filter(|source| source.sla_breached).count(),
+        p50_latency_seconds: percentile(&latencies, 50),
+        p95_latency_seconds: percentile(&latencies, 95),
+        max_latency_seconds: latencies.last().copied(),
+    }
+}
+
+/// Returns the ingestion SLA of the most recent datasource publishes of an app.
+pub async fn app_ingestion_sla(
+    app_state: &AppState,
+    app_name: &str,
+) -> Result<IngestionSlaReport, IngestionSlaError> {
+    let read_error = |message: String| IngestionSlaError::Read {
+        app_name: app_name.to_string(),
+        message,
+    };
+    let options = app_state.options::<IngestionSlaOptions>();
+    let query_options = app_state.options::<QueryOptions>();
+    let pipeline = vec![
+        doc! { "$match": { "app_name": app_name } },
+        doc! { "$sort": { "published_at": -1 } },
+        doc! { "$limit": options.max_requests as i64 },
+        doc! { "$project": { "_id": 0 } },
+    ];
+    let requests: Vec<IngestionRequest> = app_state
+        .db
+        .aggregate(&options.collection, pipeline, &query_options)
+        .await
+        .map_err(|e| read_error(e.to_string()))?
+        .into_iter()
+        .filter_map(|request| serde_json::from_value(request).ok())
+        .collect();
+
+    // The nodes are read on the analytics connection of the app residency, when configured
+    let analytics_db = app_state
+        .app_analytics_db(app_name)
+        .await
+        .map_err(|e| read_error(e.to_string()))?;
+    let nodes_collection_name = format!("{}-general", app_name);
+    let now = Utc::now();
+    let mut request_ingestions = Vec::new();
+    for request in requests {
+        let mut sources = Vec::new();
+        for source in &request.sources {
+            let first_indexed_at = analytics_db
+                .aggregate(
+                    &nodes_collection_name,
+                    first_node_pipeline(source, request.published_at),
+                    &query_options,
+                )
+                .await
+                .map_err(|e| read_error(e.to_string()))?
+                .first()
+                .and_then(|node| node.get("indexed_at"))
+                .and_then(serde_json::Value::as_str)
+                .and_then(|indexed_at| parse_timestamp(indexed_at, None));
+            sources.push(source_ingestion(
+                source,
+                request.published_at,
+                first_indexed_at,
+                now,
+                options.target,
+            ));
+        }
+        request_ingestions.push(RequestIngestion {
+            task_id: request.task_id,
+            published_at: request.published_at.to_rfc3339(),
+            complete: sources
+                .iter()
+                .all(|source| source.first_indexed_at.is_some()),
+            sla_breached: sources.iter().any(|source| source.sla_breached),
+            sources,
+        });
+    }
+    Ok(IngestionSlaReport {
+        target_minutes: options.target.num_minutes(),
+        stats: latency_stats(&request_ingestions),
+        requests: request_ingestions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamp(text: &str) -> DateTime<Utc> {
+        parse_timestamp(text, None).unwrap()
+    }
+
+    #[test]
+    fn test_success_ingestion_sla_options() {
+        let options = IngestionSlaOptions::from_settings(None);
+        assert_eq!(options.target, Duration::minutes(60));
+        assert_eq!(options.collection, "ingestion-requests");
+        assert_eq!(options.max_requests, 10);
+
+        let options = IngestionSlaOptions::from_settings(Some(&IngestionSlaSettings {
+            target_minutes: Some(15),
+            collection: None,
+            max_requests: Some(0),
+        }));
+        assert_eq!(options.target, Duration::minutes(15));
+        assert_eq!(options.max_requests, 10);
+    }
+
+    #[test]
+    fn test_success_published_sources() {
+        let existing: AppDataSource = serde_json::from_value(json!({
+            "filestore": {"s3": [{"url": "s3://bucket/docs/", "hints": []}]},
+            "datastore": {}
+        }))
+        .unwrap();
+        let new: AppDataSource = serde_json::from_value(json!({
+            "filestore": {"s3": [
+                {"url": "s3://bucket/docs", "hints": []},
+                {"url": "s3://bucket/images", "hints": []}
+            ]},
+            "datastore": {}
+        }))
+        .unwrap();
+        assert_eq!(
+            published_sources(&new, Some(&existing)),
+            vec!["s3://bucket/images"]
+        );
+        assert_eq!(
+            published_sources(&new, None),
+            vec!["s3://bucket/docs", "s3://bucket/images"]
+        );
+    }
+
+    #[test]
+    fn test_success_source_ingestion() {
+        let published_at = timestamp("2024-07-29T10:00:00Z");
+        let now = timestamp("2024-07-29T12:00:00Z");
+        let target = Duration::minutes(60);
+
+        let indexed = source_ingestion(
+            "orders",
+            published_at,
+            Some(timestamp("2024-07-29T10:20:00Z")),
+            now,
+            target,
+        );
+        assert_eq!(indexed.latency_seconds, Some(1_200));
+        assert!(!indexed.sla_breached);
+
+        let late = source_ingestion(
+            "users",
+            published_at,
+            Some(timestamp("2024-07-29T11:30:00Z")),
+            now,
+            target,
+        );
+        assert!(late.sla_breached);
+
+        // A source without node breaches the SLA once the target has elapsed
+        let pending = source_ingestion("s3://bucket/docs", published_at, None, now, target);
+        assert_eq!(pending.latency_seconds, None);
+        assert!(pending.sla_breached);
+        assert!(
+            !source_ingestion(
+                "s3://bucket/docs",
+                published_at,
+                None,
+                timestamp("2024-07-29T10:30:00Z"),
+                target
+            )
+            .sla_breached
+        );
+
+        let stats = latency_stats(&[RequestIngestion {
+            task_id: "task".to_string(),
+            published_at: published_at.to_rfc3339(),
+            complete: false,
+            sla_breached: true,
+            sources: vec![indexed, late, pending],
+        }]);
+        assert_eq!(stats.tracked_sources, 3);
+        assert_eq!(stats.indexed_sources, 2);
+        assert_eq!(stats.pending_sources, 1);
+        assert_eq!(stats.breached_sources, 2);
+        assert_eq!(stats.p50_latency_seconds, Some(1_200));
+        assert_eq!(stats.p95_latency_seconds, Some(5_400));
+        assert_eq!(stats.max_latency_seconds, Some(5_400));
+    }
+
+    #[test]
+    fn test_success_percentile() {
+        assert_eq!(percentile(&[], 50), None);
+        let latencies: Vec<i64> = (1..=20).collect();
+        assert_eq!(percentile(&latencies, 50), Some(10));
+        assert_eq!(percentile(&latencies, 95), Some(19));
+        assert_eq!(percentile(&latencies, 0), Some(1));
+    }
+}
@@ -0,0 +1,845 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the persistent job queues of the background work, so a pod crashing mid-way doesn't lose it.
+//! A job is stored in `job_queue.collection` with its queue, payload and status (`queued`, `running`, `succeeded` or
+//! `dead_lettered`), and consumed by a worker loop on every replica, running up to `job_queue.concurrency` jobs.
+//! A worker claims a due job by switching it to `running` with its attempt number as guard, so a job is claimed by a
+//! single replica, and renews its `heartbeat_at` every `job_queue.heartbeat_seconds` while running it. A running job
+//! without heartbeat for `job_queue.claim_timeout_seconds`, e.g. after a crash, is claimed again.
+//! A failed job is retried after a backoff doubling from `job_queue.backoff_base_seconds` up to
+//! `job_queue.backoff_max_seconds`, and dead-lettered after `job_queue.max_attempts` attempts, its last error kept for
+//! inspection. The succeeded and dead-lettered jobs carry an `expires_at` `job_queue.retention_days` ahead, for a TTL
+//! index to purge them, and their payload, which can hold user queries or credentials, is cleared.
+//! The payload of a job is stored encrypted as a whole with the data key of its app when encryption is enabled.
+//! The latency-sensitive jobs, e.g. the retrievals, are stored claimed by the replica handling the request and run
+//! right away; the worker loops only pick them up if that replica crashes. A job abandoned at its last attempt is
+//! handed to the consumer of its queue, which records it as failed.
+//! The jobs are listed, by queue and status, by the queued jobs endpoint.
+//!
+
+use crate::admin_ui_api::schema::UpdateResponse;
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{JobQueueSettings, TresleFacadeServiceSettings};
+use crate::onboarding::handler::{abandon_onboarding_job, run_onboarding_job};
+use crate::retrieval::handler::{abandon_retrieval_job, run_retrieval_job};
+use crate::service::metrics::{MetricRecord, QUEUE_DIMENSION, STATUS_DIMENSION};
+use crate::service::query_options::{AggregateExt, QueryError, QueryOptions};
+use crate::service::scheduler::instance_id;
+use crate::service::state::AppState;
+use crate::service::timestamp::to_bson_datetime;
+use axum::{http::StatusCode, Json};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use mongodb::bson::{doc, to_document, Bson, Document};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tracing::{error, info, instrument, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Default collection of the queued jobs.
+pub const DEFAULT_JOB_QUEUE_COLLECTION: &str = "queued_jobs";
+/// Default number of milliseconds between two polls of an idle queue.
+const DEFAULT_POLL_INTERVAL_MS: u64 = 1_000;
+/// Default number of jobs run at once by a replica, per queue.
+const DEFAULT_CONCURRENCY: usize = 4;
+/// Default number of seconds between two heartbeats of a running job.
+const DEFAULT_HEARTBEAT_SECONDS: u64 = 15;
+/// Default number of seconds without heartbeat after which a running job is claimed again.
+const DEFAULT_CLAIM_TIMEOUT_SECONDS: u64 = 120;
+/// Default number of attempts of a job before it is dead-lettered.
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+/// Default delay before the first retry of a failed job.
+const DEFAULT_BACKOFF_BASE_SECONDS: u64 = 30;
+/// Default maximum delay between two attempts of a job.
+const DEFAULT_BACKOFF_MAX_SECONDS: u64 = 1_800;
+/// Default number of days the succeeded jobs are kept.
+const DEFAULT_RETENTION_DAYS: i64 = 7;
+/// Number of due jobs read per claim.
+const CLAIM_CANDIDATES: i64 = 10;
+
+#[derive(Debug, thiserror::Error)]
+pub enum JobQueueError {
+    #[error("Failed to store the queued job. Error: {0}")]
+    Store(String),
+}
+
+impl From<JobQueueError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: JobQueueError) -> Self {
+        let error_message = e.to_string();
+        error!(ext_message = error_message, message = error_message);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Job queue options: collection, polling, concurrency, heartbeats, retries and retention.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobQueueOptions {
+    pub collection: String,
+    pub poll_interval: Duration,
+    pub concurrency: usize,
+    pub heartbeat: Duration,
+    pub claim_timeout: Duration,
+    pub max_attempts: u32,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    pub retention_days: i64,
+}
+
+impl SettingsOptions for JobQueueOptions {
+    type Settings = JobQueueSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&JobQueueSettings> {
+        settings.job_queue.as_ref()
+    }
+
+    fn from_settings(settings: Option<&JobQueueSettings>) -> Self {
+        let seconds =
+            |value: Option<u64>, default: u64| Duration::from_secs(value.unwrap_or(default));
+        JobQueueOptions {
+            collection: settings
+                .and_then(|settings| settings.collection.clone())
+                .unwrap_or_else(|| DEFAULT_JOB_QUEUE_COLLECTION.to_string()),
+            poll_interval: Duration::from_millis(
+                settings
+                    .and_then(|settings| settings.poll_interval_ms)
+                    .unwrap_or(DEFAULT_POLL_INTERVAL_MS),
+            ),
+            concurrency: settings
+                .and_then(|settings| settings.concurrency)
+                .filter(|concurrency| *concurrency > 0)
+                .unwrap_or(DEFAULT_CONCURRENCY),
+            heartbeat: seconds(
+                settings.and_then(|settings| settings.heartbeat_seconds),
+                DEFAULT_HEARTBEAT_SECONDS,
+            ),
+            claim_timeout: seconds(
+                settings.and_then(|settings| settings.claim_timeout_seconds),
+                DEFAULT_CLAIM_TIMEOUT_SECONDS,
+            ),
+            max_attempts: settings
+                .and_then(|settings| settings.max_attempts)
+                .filter(|max_attempts| *max_attempts > 0)
+                .unwrap_or(DEFAULT_MAX_ATTEMPTS),
+            backoff_base: seconds(
+                settings.and_then(|settings| settings.backoff_base_seconds),
+                DEFAULT_BACKOFF_BASE_SECONDS,
+            ),
+            backoff_max: seconds(
+                settings.and_then(|settings| settings.backoff_max_seconds),
+                DEFAULT_BACKOFF_MAX_SECONDS,
+            ),
+            retention_days: settings
+                .and_then(|settings| settings.retention_days)
+                .unwrap_or(DEFAULT_RETENTION_DAYS),
+        }
+    }
+}
+
+impl JobQueueOptions {
+    /// Delay before the next attempt of a job failed at its `attempts`-th attempt.
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.backoff_base
+            .checked_mul(factor)
+            .unwrap_or(self.backoff_max)
+            .min(self.backoff_max)
+    }
+
+    /// Filter of the jobs of a queue which can be claimed at `now`: the due queued jobs, and the running jobs whose
+    /// heartbeat lapsed.
+    fn claimable_filter(&self, queue: JobQueue, now: DateTime<Utc>) -> Document {
+        let heartbeat_before =
+            now - ChronoDuration::from_std(self.claim_timeout).unwrap_or_default();
+        doc! {
+            "queue": queue.as_str(),
+            "$or": [
+                {"status": "queued", "run_after": {"$lte": to_bson_datetime(now)}},
+                {"status": "running", "heartbeat_at": {"$lt": to_bson_datetime(heartbeat_before)}},
+            ],
+        }
+    }
+}
+
+/// Queue of background work.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobQueue {
+    /// Background steps of the onboarding/update requests, see `onboarding::handler`.
+    Onboarding,
+    /// Background operations of the retrievals and their replays, see `retrieval::handler`.
+    Retrieval,
+}
+
+impl JobQueue {
+    pub const ALL: [JobQueue; 2] = [JobQueue::Onboarding, JobQueue::Retrieval];
+
+    /// Returns the queue named `name`.
+    pub fn parse(name: &str) -> Option<JobQueue> {
+        JobQueue::ALL
+            .into_iter()
+            .find(|queue| queue.as_str() == name)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobQueue::Onboarding => "onboarding",
+            JobQueue::Retrieval => "retrieval",
+        }
+    }
+}
+
+/// Status of a queued job.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuedJobStatus {
+    /// Waiting for its first attempt, or for a retry from `run_after`.
+    Queued,
+    Running,
+    Succeeded,
+    /// Failed at its last attempt, or abandoned while running it.
+    DeadLettered,
+}
+
+impl QueuedJobStatus {
+    pub const ALL: [QueuedJobStatus; 4] = [
+        QueuedJobStatus::Queued,
+        QueuedJobStatus::Running,
+        QueuedJobStatus::Succeeded,
+        QueuedJobStatus::DeadLettered,
+    ];
+
+    /// Returns the status named `name`.
+    pub fn parse(name: &str) -> Option<QueuedJobStatus> {
+        QueuedJobStatus::ALL
+            .into_iter()
+            .find(|status| status.as_str() == name)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueuedJobStatus::Queued => "queued",
+            QueuedJobStatus::Running => "running",
+            QueuedJobStatus::Succeeded => "succeeded",
+            QueuedJobStatus::DeadLettered => "dead_lettered",
+        }
+    }
+}
+
+/// Queued job document, stored in the job queue collection.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct QueuedJob {
+    pub job_id: String,
+    pub queue: JobQueue,
+    /// App of the job, whose data key encrypts the payload.
+    #[serde(default)]
+    pub app_name: Option<String>,
+    pub status: QueuedJobStatus,
+    /// Work of the job, read by the consumer of its queue with `QueuedJob::payload`. Cleared once the job succeeded or
+    /// is dead-lettered, and not listed.
+    #[serde(default)]
+    #[schema(value_type = Object)]
+    pub payload: serde_json::Value,
+    /// Number of attempts claimed so far.
+    pub attempts: u32,
+    pub max_attempts: u32,
+    /// Replica running the job, or which ran it last.
+    #[serde(default)]
+    pub claimed_by: Option<String>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    #[serde(with = "crate::service::timestamp::bson_datetime")]
+    #[schema(value_type = String)]
+    pub run_after: DateTime<Utc>,
+    #[serde(default, with = "crate::service::timestamp::option_bson_datetime")]
+    #[schema(value_type = Option<String>)]
+    pub heartbeat_at: Option<DateTime<Utc>>,
+    #[serde(with = "crate::service::timestamp::bson_datetime")]
+    #[schema(value_type = String)]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "crate::service::timestamp::bson_datetime")]
+    #[schema(value_type = String)]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl QueuedJob {
+    fn new(queue: JobQueue, app_name: &str, payload: serde_json::Value, max_attempts: u32) -> Self {
+        let now = Utc::now();
+        QueuedJob {
+            job_id: Uuid::new_v4().to_string(),
+            queue,
+            app_name: Some(app_name.to_string()),
+            status: QueuedJobStatus::Queued,
+            payload,
+            attempts: 0,
+            max_attempts,
+            claimed_by: None,
+            last_error: None,
+            run_after: now,
+            heartbeat_at: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    /// Whether the running attempt is the last one before the job is dead-lettered.
+    pub fn is_last_attempt(&self) -> bool {
+        self.attempts >= self.max_attempts
+    }
+
+    /// Filter of the job as claimed by its running attempt, so an attempt claimed again elsewhere isn't overwritten.
+    fn attempt_filter(&self) -> Document {
+        doc! {"job_id": &self.job_id, "attempts": self.attempts as i64}
+    }
+
+    /// Returns the work of the job, decrypting the payload stored encrypted.
+    pub async fn payload<T: DeserializeOwned>(&self, app_state: &AppState) -> Result<T, String> {
+        let mut payload = self.payload.clone();
+        if let (Some(app_name), serde_json::Value::String(_)) = (&self.app_name, &payload) {
+            app_state
+                .decrypt_fields(app_name, &mut payload)
+                .await
+                .map_err(|e| e.to_string())?;
+            if let serde_json::Value::String(opened) = &payload {
+                payload = serde_json::from_str(opened).map_err(|e| e.to_string())?;
+            }
+        }
+        serde_json::from_value(payload).map_err(|e| e.to_string())
+    }
+}
+
+/// Payload of a job as stored, encrypted as a whole with the data key of its app when encryption is enabled.
+async fn seal_payload<T: Serialize>(
+    app_state: &AppState,
+    app_name: &str,
+    payload: &T,
+) -> Result<serde_json::Value, JobQueueError> {
+    let payload = serde_json::to_value(payload).map_err(|e| JobQueueError::Store(e.to_string()))?;
+    if !app_state.is_encryption_enabled() {
+        return Ok(payload);
+    }
+    let sealed = app_state
+        .encrypt_field(app_name, &payload.to_string())
+        .await
+        .map_err(|e| JobQueueError::Store(e.to_string()))?;
+    Ok(serde_json::Value::String(sealed))
+}
+
+/// Fields of a job dead-lettered at `now`, its payload cleared and expiring after the retention.
+fn dead_letter_fields(
+    options: &JobQueueOptions,
+    error_message: &str,
+    now: DateTime<Utc>,
+) -> Document {
+    let expires_at = now + ChronoDuration::days(options.retention_days);
+    doc! {
+        "status": QueuedJobStatus::DeadLettered.as_str(),
+        "payload": Bson::Null,
+        "last_error": error_message,
+        "updated_at": to_bson_datetime(now),
+        "expires_at": to_bson_datetime(expires_at),
+    }
+}
+
+/// Stores a job in the job queue collection.
+async fn store_job(
+    app_state: &AppState,
+    options: &JobQueueOptions,
+    job: &QueuedJob,
+) -> Result<(), JobQueueError> {
+    let document = to_document(job).map_err(|e| JobQueueError::Store(e.to_string()))?;
+    app_state
+        .db
+        .create_document(&options.collection, document)
+        .await
+        .map_err(|e| JobQueueError::Store(e.to_string()))?;
+    Ok(())
+}
+
+/// Stores a job of an app in a queue, consumed by the worker loop of the queue on one of the replicas.
+#[instrument(skip_all)]
+pub async fn enqueue_job<T: Serialize>(
+    app_state: &AppState,
+    queue: JobQueue,
+    app_name: &str,
+    payload: &T,
+) -> Result<QueuedJob, JobQueueError> {
+    let options = app_state.options::<JobQueueOptions>();
+    let payload = seal_payload(app_state, app_name, payload).await?;
+    let job = QueuedJob::new(queue, app_name, payload, options.max_attempts);
+    store_job(app_state, &options, &job).await?;
+    info!(message = format!("Job '{}' queued in queue '{}'.", job.job_id, queue.as_str()));
+    Ok(job)
+}
+
+/// Stores a job of an app claimed by this replica at its first attempt and runs it right away, without waiting for a
+/// poll of its queue. The worker loops claim it again only if its heartbeat lapses.
+/// The first attempt runs with `payload`, the job is stored with `stored_payload` for the attempts of the worker
+/// loops, e.g. without the personal data they don't need.
+#[instrument(skip_all)]
+pub async fn start_job<T: Serialize>(
+    app_state: &Arc<AppState>,
+    queue: JobQueue,
+    app_name: &str,
+    payload: &T,
+    stored_payload: &T,
+) -> Result<QueuedJob, JobQueueError> {
+    let options = app_state.options::<JobQueueOptions>();
+    let stored_payload = seal_payload(app_state, app_name, stored_payload).await?;
+    let mut job = QueuedJob::new(queue, app_name, stored_payload, options.max_attempts);
+    job.status = QueuedJobStatus::Running;
+    job.attempts = 1;
+    job.claimed_by = Some(instance_id().to_string());
+    job.heartbeat_at = Some(job.created_at);
+    store_job(app_state, &options, &job).await?;
+    let mut running_job = job.clone();
+    running_job.payload =
+        serde_json::to_value(payload).map_err(|e| JobQueueError::Store(e.to_string()))?;
+    tokio::spawn(run_job(Arc::clone(app_state), running_job));
+    Ok(job)
+}
+
+/// Updates the fields of a job matching `filter`. Returns whether the job matched.
+async fn update_job(
+    app_state: &AppState,
+    options: &JobQueueOptions,
+    filter: Document,
+    fields: Document,
+) -> Result<bool, JobQueueError> {
+    let updated = app_state
+        .db
+        .update_document(&options.collection, filter, fields)
+        .await
+        .map_err(|e| JobQueueError::Store(e.to_string()))?;
+    let updated: UpdateResponse =
+        serde_json::from_value(updated).map_err(|e| JobQueueError::Store(e.to_string()))?;
+    Ok(updated.matchedCount > 0)
+}
+
+/// Claims a due job of a queue for this replica. A running job abandoned at its last attempt is dead-lettered, and
+/// handed to the consumer of its queue.
+pub async fn claim_job(
+    app_state: &Arc<AppState>,
+    queue: JobQueue,
+    now: DateTime<Utc>,
+) -> Result<Option<QueuedJob>, JobQueueError> {
+    let options = app_state.options::<JobQueueOptions>();
+    let pipeline = vec![
+        doc! {"$match": options.claimable_filter(queue, now)},
+        doc! {"$sort": {"run_after": 1}},
+        doc! {"$limit": CLAIM_CANDIDATES},
+        doc! {"$project": {"_id": 0}},
+    ];
+    let candidates = app_state
+        .db
+        .aggregate(
+            &options.collection,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await
+        .map_err(|e| JobQueueError::Store(e.to_string()))?;
+    for candidate in candidates {
+        let Ok(mut job) = serde_json::from_value::<QueuedJob>(candidate) else {
+            continue;
+        };
+        // The status guards the claim, along with the attempts: a single replica claims an attempt
+        let mut filter = job.attempt_filter();
+        filter.insert("status", job.status.as_str());
+
+        if job.status == QueuedJobStatus::Running && job.is_last_attempt() {
+            let error_message = format!(
+                "Abandoned by '{}' at its last attempt.",
+                job.claimed_by.as_deref().unwrap_or("unknown")
+            );
+            let fields = dead_letter_fields(&options, &error_message, now);
+            if update_job(app_state, &options, filter, fields).await? {
+                record_outcome(
+                    app_state,
+                    &job,
+                    QueuedJobStatus::DeadLettered,
+                    &error_message,
+                )
+                .await;
+                abandon_job(app_state, &job, &error_message).await;
+            }
+            continue;
+        }
+
+        if job.status == QueuedJobStatus::Running {
+            warn!(
+                message = format!(
+                    "Claiming job '{}' abandoned by '{}'.",
+                    job.job_id,
+                    job.claimed_by.as_deref().unwrap_or("unknown")
+                )
+            );
+        }
+        job.status = QueuedJobStatus::Running;
+        job.attempts += 1;
+        job.claimed_by = Some(instance_id().to_string());
+        job.heartbeat_at = Some(now);
+        job.updated_at = now;
+        let fields = doc! {
+            "status": job.status.as_str(),
+            "attempts": job.attempts as i64,
+            "claimed_by": job.claimed_by.as_deref(),
+            "heartbeat_at": to_bson_datetime(now),
+            "updated_at": to_bson_datetime(now),
+        };
+        if update_job(app_state, &options, filter, fields).await? {
+            return Ok(Some(job));
+        }
+    }
+    Ok(None)
+}
+
+/// Renews the heartbeat of a running job until aborted.
+async fn keep_alive(app_state: Arc<AppState>, job: QueuedJob) {
+    let options = app_state.options::<JobQueueOptions>();
+    let mut interval = tokio::time::interval(options.heartbeat);
+    // The first tick completes immediately, the job was claimed with its heartbeat
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let mut filter = job.attempt_filter();
+        filter.insert("status", QueuedJobStatus::Running.as_str());
+        let fields = doc! {"heartbeat_at": to_bson_datetime(Utc::now())};
+        match update_job(&app_state, &options, filter, fields).await {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!(
+                    message = format!(
+                        "Job '{}' was claimed again, its heartbeat stops.",
+                        job.job_id
+                    )
+                );
+                return;
+            }
+            Err(e) => error!(message = e.to_string()),
+        }
+    }
+}
+
+/// Runs a claimed job with the consumer of its queue.
+async fn handle_job(app_state: &Arc<AppState>, job: &QueuedJob) -> Result<(), String> {
+    match job.queue {
+        JobQueue::Onboarding => run_onboarding_job(app_state, job).await,
+        JobQueue::Retrieval => run_retrieval_job(app_state, job).await,
+    }
+}
+
+/// Hands a job abandoned at its last attempt to the consumer of its queue, which records it as failed.
+async fn abandon_job(app_state: &Arc<AppState>, job: &QueuedJob, error_message: &str) {
+    match job.queue {
+        JobQueue::Onboarding => abandon_onboarding_job(app_state, job).await,
+        JobQueue::Retrieval => abandon_retrieval_job(app_state, job, error_message).await,
+    }
+}
+
+/// Runs a claimed job, renewing its heartbeat, and stores its outcome: succeeded, queued for a retry after a backoff,
+/// or dead-lettered after its last attempt.
+#[instrument(skip_all)]
+async fn run_job(app_state: Arc<AppState>, job: QueuedJob) {
+    let options = app_state.options::<JobQueueOptions>();
+    let start = Instant::now();
+    let heartbeat = tokio::spawn(keep_alive(Arc::clone(&app_state), job.clone()));
+    let result = handle_job(&app_state, &job).await;
+    heartbeat.abort();
+
+    let now = Utc::now();
+    let (status, fields, message) = match &result {
+        Ok(()) => {
+            let expires_at = now + ChronoDuration::days(options.retention_days);
+            (
+                QueuedJobStatus::Succeeded,
+                doc! {
+                    "status": QueuedJobStatus::Succeeded.as_str(),
+                    "payload": Bson::Null,
+                    "updated_at": to_bson_datetime(now),
+                    "expires_at": to_bson_datetime(expires_at),
+                },
+                format!("Job '{}' succeeded.", job.job_id),
+            )
+        }
+        Err(error_message) if job.is_last_attempt() => (
+            QueuedJobStatus::DeadLettered,
+            dead_letter_fields(&options, error_message, now),
+            error_message.clone(),
+        ),
+        Err(error_message) => {
+            let backoff = options.backoff(job.attempts);
+            let run_after = now + ChronoDuration::from_std(backoff).unwrap_or_default();
+            (
+                QueuedJobStatus::Queued,
+                doc! {
+                    "status": QueuedJobStatus::Queued.as_str(),
+                    "last_error": error_message,
+                    "run_after": to_bson_datetime(run_after),
+                    "updated_at": to_bson_datetime(now),
+                },
+                format!(
+                    "Job '{}' failed at attempt {}/{}, retried in {} s. Error: {}",
+                    job.job_id,
+                    job.attempts,
+                    job.max_attempts,
+                    backoff.as_secs(),
+                    error_message
+                ),
+            )
+        }
+    };
+    match update_job(&app_state, &options, job.attempt_filter(), fields).await {
+        Ok(true) => {}
+        Ok(false) => warn!(
+            message = format!(
+                "Job '{}' was claimed again, the outcome of attempt {} is dropped.",
+                job.job_id, job.attempts
+            )
+        ),
+        Err(e) => error!(ext_message = e.to_string(), message = e.to_string()),
+    }
+    app_state
+        .record_metric(
+            MetricRecord::duration_ms("Queued Job Duration", start.elapsed().as_millis() as i64)
+                .dimension(QUEUE_DIMENSION, job.queue.as_str())
+                .dimension(STATUS_DIMENSION, status.as_str()),
+        )
+        .await;
+    record_outcome(&app_state, &job, status, &message).await;
+}
+
+/// Logs the outcome of an attempt. The dead-lettered jobs are logged as errors and counted.
+async fn record_outcome(
+    app_state: &AppState,
+    job: &QueuedJob,
+    status: QueuedJobStatus,
+    message: &str,
+) {
+    match status {
+        QueuedJobStatus::DeadLettered => {
+            let error_message = format!(
+                "Job '{}' of queue '{}' dead-lettered after {} attempt(s). Error: {}",
+                job.job_id,
+                job.queue.as_str(),
+                job.attempts,
+                message
+            );
+            error!(ext_message = error_message, message = error_message);
+            app_state
+                .record_metric(
+                    MetricRecord::counter("Dead-Lettered Job Counter")
+                        .dimension(QUEUE_DIMENSION, job.queue.as_str()),
+                )
+                .await;
+        }
+        QueuedJobStatus::Queued => warn!(message = message),
+        _ => info!(message = message),
+    }
+}
+
+/// Consumes a queue: claims its due jobs and runs them in the background, up to `job_queue.concurrency` at once.
+/// Every replica consumes the queues, a job is run by the replica claiming it.
+pub async fn consume_queue(app_state: Arc<AppState>, queue: JobQueue) {
+    let options = app_state.options::<JobQueueOptions>();
+    let permits = Arc::new(Semaphore::new(options.concurrency));
+    info!(message = format!("Consuming job queue '{}'.", queue.as_str()));
+    loop {
+        let Ok(permit) = Arc::clone(&permits).acquire_owned().await else {
+            return;
+        };
+        match claim_job(&app_state, queue, Utc::now()).await {
+            Ok(Some(job)) => {
+                let app_state = Arc::clone(&app_state);
+                tokio::spawn(async move {
+                    run_job(app_state, job).await;
+                    drop(permit);
+                });
+            }
+            Ok(None) => tokio::time::sleep(options.poll_interval).await,
+            Err(e) => {
+                error!(message = e.to_string());
+                tokio::time::sleep(options.poll_interval).await;
+            }
+        }
+    }
+}
+
+/// Filter of the jobs of a queue and a status, if set.
+fn queued_jobs_filter(queue: Option<JobQueue>, status: Option<QueuedJobStatus>) -> Document {
+    let mut filter = doc! {};
+    if let Some(queue) = queue {
+        filter.insert("queue", queue.as_str());
+    }
+    if let Some(status) = status {
+        filter.insert("status", status.as_str());
+    }
+    filter
+}
+
+/// Returns the latest jobs, of a queue and a status if set, the latest first, without their payload.
+pub async fn queued_jobs(
+    app_state: &AppState,
+    queue: Option<JobQueue>,
+    status: Option<QueuedJobStatus>,
+    limit: i64,
+) -> Result<Vec<QueuedJob>, QueryError> {
+    let pipeline = vec![
+        doc! {"$match": queued_jobs_filter(queue, status)},
+        doc! {"$sort": {"created_at": -1}},
+        doc! {"$limit": limit},
+        doc! {"$project": {"_id": 0, "payload": 0, "expires_at": 0}},
+    ];
+    let jobs = app_state
+        .db
+        .aggregate(
+            &app_state.options::<JobQueueOptions>().collection,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await?;
+    Ok(jobs
+        .into_iter()
+        .filter_map(|job| serde_json::from_value(job).ok())
+        .collect())
+}
+
+/// Returns the number of jobs by queue and status.
+pub async fn queued_job_counts(
+    app_state: &AppState,
+) -> Result<BTreeMap<String, BTreeMap<String, u64>>, QueryError> {
+    let pipeline = vec![doc! {
+        "$group": {
+            "_id": {"queue": "$queue", "status": "$status"},
+            "count": {"$sum": 1},
+        }
+    }];
+    let groups = app_state
+        .db
+        .aggregate(
+            &app_state.options::<JobQueueOptions>().collection,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await?;
+    let mut counts: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+    for group in groups {
+        let (Some(queue), Some(status)) = (
+            group["_id"]["queue"].as_str(),
+            group["_id"]["status"].as_str(),
+        ) else {
+            continue;
+        };
+        counts.entry(queue.to_string()).or_default().insert(
+            status.to_string(),
+            group["count"].as_u64().unwrap_or_default(),
+        );
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_success_job_queue_options_from_settings() {
+        let options = JobQueueOptions::from_settings(None);
+        assert_eq!(options.collection, DEFAULT_JOB_QUEUE_COLLECTION);
+        assert_eq!(options.max_attempts, 5);
+        // The backoff doubles on every retry, up to its maximum
+        assert_eq!(options.backoff(1), Duration::from_secs(30));
+        assert_eq!(options.backoff(3), Duration::from_secs(120));
+        assert_eq!(options.backoff(10), Duration::from_secs(1_800));
+        assert_eq!(options.backoff(u32::MAX), Duration::from_secs(1_800));
+    }
+
+    #[test]
+    fn test_success_claimable_filter() {
+        let options = JobQueueOptions::from_settings(None);
+        let now = Utc.with_ymd_and_hms(2024, 7, 26, 10, 0, 0).unwrap();
+        let filter = options.claimable_filter(JobQueue::Onboarding, now);
+        assert_eq!(filter.get_str("queue").unwrap(), "onboarding");
+        assert_eq!(JobQueue::parse("retrieval"), Some(JobQueue::Retrieval));
+        assert_eq!(
+            QueuedJobStatus::parse("dead_lettered"),
+            Some(QueuedJobStatus::DeadLettered)
+        );
+        assert_eq!(
+            queued_jobs_filter(Some(JobQueue::Retrieval), None),
+            doc! {"queue": "retrieval"}
+        );
+        let branches = filter.get_array("$or").unwrap();
+        let running = branches[1].as_document().unwrap();
+        assert_eq!(
+            running
+                .get_document("heartbeat_at")
+                .unwrap()
+                .get_datetime("$lt")
+                .unwrap()
+                .timestamp_millis(),
+            (now - ChronoDuration::seconds(120)).timestamp_millis()
+        );
+    }
+
+    #[test]
+    fn test_success_queued_job_document() {
+        let mut job = QueuedJob::new(JobQueue::Onboarding, "app", json!({"app_name": "app"}), 2);
+        let document = to_document(&job).unwrap();
+        assert_eq!(document.get_str("status").unwrap(), "queued");
+        assert_eq!(document.get_str("app_name").unwrap(), "app");
+        assert!(document.get_datetime("run_after").is_ok());
+        assert!(!job.is_last_attempt());
+        job.attempts = 2;
+        assert!(job.is_last_attempt());
+        assert_eq!(
+            job.attempt_filter(),
+            doc! {"job_id": &job.job_id, "attempts": 2_i64}
+        );
+    }
+
+    #[test]
+    fn test_success_dead_letter_fields() {
+        let options = JobQueueOptions::from_settings(None);
+        let now = Utc.with_ymd_and_hms(2024, 7, 26, 10, 0, 0).unwrap();
+        let fields = dead_letter_fields(&options, "Engine unavailable.", now);
+        assert_eq!(fields.get_str("status").unwrap(), "dead_lettered");
+        assert_eq!(fields.get("payload"), Some(&Bson::Null));
+        assert_eq!(
+            fields
+                .get_datetime("expires_at")
+                .unwrap()
+                .timestamp_millis(),
+            (now + ChronoDuration::days(7)).timestamp_millis()
+        );
+    }
+
+    #[test]
+    fn test_success_queued_job_payload() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Without encryption the payload is stored as-is
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let payload = json!({"app_name": "app", "query": "what is tresleai?"});
+            let sealed = seal_payload(&app_state, "app", &payload).await.unwrap();
+            assert_eq!(sealed, payload);
+            let job = QueuedJob::new(JobQueue::Retrieval, "app", sealed, 2);
+            assert_eq!(
+                job.payload::<serde_json::Value>(&app_state).await.unwrap(),
+                payload
+            );
+        });
+    }
+}
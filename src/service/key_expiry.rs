@@ -0,0 +1,418 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the expiry job of the API keys, for the customers whose compliance policies require their
+//! keys to be rotated.
+//! Every `api_keys.expiry_interval_seconds` (see `scheduler`), the job looks up the keys of the apps with an expiry
+//! date, primary or additional. A key expiring within `api_keys.expiry_warning_days` is warned once: an
+//! `api_key_expiring` event is POSTed to the `notification_url` of its app, signed and retried like the onboarding
+//! webhook, and an admin notification is recorded. An expired key is deactivated: disabled in API Gateway (outside the
+//! internal API key mode), then marked deactivated on the app document, notified the same way as an
+//! `api_key_deactivated` event, sent to the audit microservice and counted by `API Key Deactivated Counter`.
+//! A key failing to be disabled in API Gateway stays active on the app document and is retried on the next run.
+//! The retrieval endpoints reject an expired key as soon as its expiry date passes, deactivated or not.
+//!
+
+use crate::service::app_keys::{
+    store_key_expiry_fields, KeyExpiry, APP_KEYS_FIELD, PRIMARY_KEY_EXPIRY_FIELD,
+};
+use crate::service::metrics::{MetricRecord, APP_NAME_DIMENSION};
+use crate::service::notification::{record_notification, Notification, NotificationKind};
+use crate::service::onboarding_webhook::deliver_webhook;
+use crate::service::scheduler::{acquire_lease, Job, JobRunStart, JobStatus};
+use crate::service::state::AppState;
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_apigateway::types::{Op, PatchOperation};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info, instrument, warn};
+
+/// API key of an app with an expiry date.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpiringKey {
+    pub app_name: String,
+    /// ID of the key: its ID in API Gateway, or the generated ID in the internal API key mode.
+    pub key_id: String,
+    /// Label of an additional key, `None` for the primary key of the app.
+    pub label: Option<String>,
+    pub expiry: KeyExpiry,
+    pub notification_url: Option<String>,
+}
+
+impl ExpiringKey {
+    /// Names the key in the messages.
+    fn name(&self) -> String {
+        match &self.label {
+            Some(label) => format!("API key '{}'", label),
+            None => "primary API key".to_string(),
+        }
+    }
+}
+
+/// Action of the expiry job on a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpiryAction {
+    Warn,
+    Deactivate,
+}
+
+/// Returns the action due on a key at `now`, `None` if nothing is due.
+pub fn expiry_action(
+    expiry: &KeyExpiry,
+    now: DateTime<Utc>,
+    warning: Duration,
+) -> Option<ExpiryAction> {
+    if expiry.needs_deactivation(now) {
+        Some(ExpiryAction::Deactivate)
+    } else if expiry.needs_warning(now, warning) {
+        Some(ExpiryAction::Warn)
+    } else {
+        None
+    }
+}
+
+/// Returns the keys with an expiry date of an app document, its primary key first.
+pub fn expiring_keys(app: &serde_json::Value) -> Vec<ExpiringKey> {
+    let Some(app_name) = app.get("app_name").and_then(serde_json::Value::as_str) else {
+        return Vec::new();
+    };
+    let notification_url = app
+        .get("notification_url")
+        .and_then(serde_json::Value::as_str)
+        .map(str::to_string);
+    let expiring_key = |key_id: &str, label: Option<&str>, expiry: KeyExpiry| ExpiringKey {
+        app_name: app_name.to_string(),
+        key_id: key_id.to_string(),
+        label: label.map(str::to_string),
+        expiry,
+        notification_url: notification_url.clone(),
+    };
+
+    let mut keys = Vec::new();
+    let primary_expiry = app
+        .get(PRIMARY_KEY_EXPIRY_FIELD)
+        .and_then(|expiry| KeyExpiry::deserialize(expiry).ok());
+    if let (Some(key_id), Some(expiry)) = (
+        app.get("api_key_id").and_then(serde_json::Value::as_str),
+        primary_expiry.filter(|expiry| expiry.expires_at.is_some()),
+    ) {
+        keys.push(expiring_key(key_id, None, expiry));
+    }
+    for app_key in app
+        .get(APP_KEYS_FIELD)
+        .and_then(serde_json::Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        let key_id = app_key.get("key_id").and_then(serde_json::Value::as_str);
+        let label = app_key.get("label").and_then(serde_json::Value::as_str);
+        let expiry = KeyExpiry::deserialize(app_key).ok();
+        if let (Some(key_id), Some(expiry)) =
+            (key_id, expiry.filter(|expiry| expiry.expires_at.is_some()))
+        {
+            keys.push(expiring_key(key_id, label, expiry));
+        }
+    }
+    keys
+}
+
+/// Expiry event of an API key, POSTed to the notification URL of its app.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyExpiryNotification {
+    /// `api_key_expiring` or `api_key_deactivated`.
+    pub event: NotificationKind,
+    pub app_name: String,
+    pub key_id: String,
+    pub label: Option<String>,
+    pub expires_at: Option<String>,
+    pub timestamp: String,
+}
+
+/// Number of keys warned and deactivated by a run of the expiry job.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyExpiryReport {
+    pub warned: usize,
+    pub deactivated: usize,
+    pub failed: usize,
+}
+
+/// Warns and deactivates the expiring API keys every `api_keys.expiry_interval_seconds`, until the process exits.
+/// Only the replica holding the lease of the job runs it.
+#[instrument(skip_all)]
+pub async fn enforce_key_expiry(app_state: Arc<AppState>) {
+    let period = app_state.api_key_options().expiry_interval;
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if !acquire_lease(&app_state, Job::KeyExpiry, period).await {
+            continue;
+        }
+        let run = JobRunStart::new(Job::KeyExpiry);
+        let (status, details) = match expire_api_keys(&app_state, Utc::now()).await {
+            Ok(report) => (
+                match report.failed {
+                    0 => JobStatus::Success,
+                    _ => JobStatus::Failure,
+                },
+                format!(
+                    "Warned {} and deactivated {} API keys, {} failed.",
+                    report.warned, report.deactivated, report.failed
+                ),
+            ),
+            Err(error_message) => (JobStatus::Failure, error_message),
+        };
+        run.finish(&app_state, status, details).await;
+    }
+}
+
+/// Warns the API keys expiring soon and deactivates the expired ones. Returns the error message if the keys could not
+/// be listed.
+pub async fn expire_api_keys(
+    app_state: &AppState,
+    now: DateTime<Utc>,
+) -> Result<KeyExpiryReport, String> {
+    let warning = app_state.api_key_options().expiry_warning;
+    let keys = app_state.apps().expiring_keys().await.map_err(|e| {
+        let error_message = format!("Failed to list the expiring API keys. Error: {}", e);
+        error!(message = error_message);
+        error_message
+    })?;
+    let mut report = KeyExpiryReport::default();
+    for key in keys {
+        match expiry_action(&key.expiry, now, warning) {
+            Some(ExpiryAction::Warn) => match warn_key_expiry(app_state, &key, now).await {
+                Ok(()) => report.warned += 1,
+                Err(_) => report.failed += 1,
+            },
+            Some(ExpiryAction::Deactivate) => match deactivate_key(app_state, &key, now).await {
+                Ok(()) => report.deactivated += 1,
+                Err(_) => report.failed += 1,
+            },
+            None => {}
+        }
+    }
+    Ok(report)
+}
+
+/// Notifies the coming expiry of a key and marks it warned. Returns the error message if it could not be marked.
+async fn warn_key_expiry(
+    app_state: &AppState,
+    key: &ExpiringKey,
+    now: DateTime<Utc>,
+) -> Result<(), String> {
+    let expires_at = key
+        .expiry
+        .expires_at
+        .map(|expires_at| expires_at.to_rfc3339());
+    let message = format!(
+        "The {} of app '{}' expires on {}. Create a new key before it is deactivated.",
+        key.name(),
+        key.app_name,
+        expires_at.as_deref().unwrap_or_default()
+    );
+    warn!(app_name = key.app_name, message = message);
+    notify_key_expiry(
+        app_state,
+        key,
+        NotificationKind::ApiKeyExpiring,
+        message,
+        now,
+    )
+    .await;
+    store_key_expiry_fields(
+        app_state,
+        &key.app_name,
+        key.label.as_ref().map(|_| key.key_id.as_str()),
+        &[("warned_at", Some(now))],
+    )
+    .await
+}
+
+/// Disables an expired key in API Gateway, marks it deactivated and notifies it. Returns the error message if the key
+/// could not be disabled or marked.
+async fn deactivate_key(
+    app_state: &AppState,
+    key: &ExpiringKey,
+    now: DateTime<Utc>,
+) -> Result<(), String> {
+    // In the internal API key mode the key is only known to the app document
+    if !app_state.api_key_options().is_internal() {
+        if let Err(e) = disable_gateway_key(app_state, &key.key_id).await {
+            let error_message = format!(
+                "Failed to disable the expired {} of app '{}' in API Gateway. Error: {}",
+                key.name(),
+                key.app_name,
+                e
+            );
+            error!(
+                app_name = key.app_name,
+                ext_message = error_message,
+                message = error_message
+            );
+            return Err(error_message);
+        }
+    }
+    store_key_expiry_fields(
+        app_state,
+        &key.app_name,
+        key.label.as_ref().map(|_| key.key_id.as_str()),
+        &[("deactivated_at", Some(now))],
+    )
+    .await?;
+
+    let message = format!(
+        "The expired {} of app '{}' was deactivated.",
+        key.name(),
+        key.app_name
+    );
+    info!(app_name = key.app_name, message = message);
+    info!(
+        service = "audit_microservice",
+        task_id = app_state.id_generator.task_id(&key.app_name, "KeyExpiry"),
+        app_name = key.app_name,
+        action = "API key deactivated",
+        details = key.key_id,
+        message = message
+    );
+    app_state
+        .record_metric(
+            MetricRecord::counter("API Key Deactivated Counter")
+                .dimension(APP_NAME_DIMENSION, &key.app_name),
+        )
+        .await;
+    notify_key_expiry(
+        app_state,
+        key,
+        NotificationKind::ApiKeyDeactivated,
+        message,
+        now,
+    )
+    .await;
+    Ok(())
+}
+
+/// Records the admin notification of an expiry event of a key, and POSTs it to the notification URL of its app.
+async fn notify_key_expiry(
+    app_state: &AppState,
+    key: &ExpiringKey,
+    kind: NotificationKind,
+    message: String,
+    now: DateTime<Utc>,
+) {
+    let notification = Notification::new(kind, &key.app_name, message).once_per(&key.key_id);
+    record_notification(app_state, notification).await;
+    let Some(notification_url) = &key.notification_url else {
+        return;
+    };
+    let task_id = app_state.id_generator.task_id(&key.app_name, "KeyExpiry");
+    let notification = KeyExpiryNotification {
+        event: kind,
+        app_name: key.app_name.clone(),
+        key_id: key.key_id.clone(),
+        label: key.label.clone(),
+        expires_at: key
+            .expiry
+            .expires_at
+            .map(|expires_at| expires_at.to_rfc3339()),
+        timestamp: now.to_rfc3339(),
+    };
+    deliver_webhook(
+        app_state,
+        notification_url,
+        &key.app_name,
+        &task_id,
+        "API key expiry notification",
+        &notification,
+    )
+    .await;
+}
+
+/// Disables an API key in API Gateway, so it is rejected without reaching the facade.
+async fn disable_gateway_key(app_state: &AppState, key_id: &str) -> Result<(), String> {
+    let region = app_state.app_settings.aws_api_gateway.region.clone();
+    let region_provider = RegionProviderChain::first_try(Region::new(region));
+    let config = aws_config::defaults(BehaviorVersion::latest())
+        .region(region_provider)
+        .load()
+        .await;
+    let client = aws_sdk_apigateway::Client::new(&config);
+    client
+        .update_api_key()
+        .api_key(key_id)
+        .patch_operations(
+            PatchOperation::builder()
+                .op(Op::Replace)
+                .path("/enabled")
+                .value("false")
+                .build(),
+        )
+        .send()
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_success_expiry_action() {
+        let now = Utc::now();
+        let warning = Duration::days(7);
+        let expiry = |days: i64| KeyExpiry {
+            expires_at: Some(now + Duration::days(days)),
+            ..Default::default()
+        };
+        assert_eq!(expiry_action(&KeyExpiry::default(), now, warning), None);
+        assert_eq!(expiry_action(&expiry(30), now, warning), None);
+        assert_eq!(
+            expiry_action(&expiry(3), now, warning),
+            Some(ExpiryAction::Warn)
+        );
+        assert_eq!(
+            expiry_action(&expiry(-1), now, warning),
+            Some(ExpiryAction::Deactivate)
+        );
+        let deactivated = KeyExpiry {
+            deactivated_at: Some(now),
+            ..expiry(-1)
+        };
+        assert_eq!(expiry_action(&deactivated, now, warning), None);
+    }
+
+    #[test]
+    fn test_success_expiring_keys() {
+        let app = json!({
+            "app_name": "app100",
+            "api_key_id": "primary-id",
+            "notification_url": "https://ci.example.com/hooks",
+            "api_key_expiry": {"expires_at": "2024-08-01T00:00:00Z"},
+            "api_keys": [
+                {"key_id": "billing-id", "label": "billing", "api_key": "key", "created_at": "2024-07-01T00:00:00Z",
+                 "expires_at": "2024-09-01T00:00:00Z", "warned_at": "2024-08-25T00:00:00Z"},
+                {"key_id": "search-id", "label": "search", "api_key": "key", "created_at": "2024-07-01T00:00:00Z"},
+            ],
+        });
+        let keys = expiring_keys(&app);
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].key_id, "primary-id");
+        assert_eq!(keys[0].label, None);
+        assert_eq!(keys[0].name(), "primary API key");
+        assert_eq!(keys[1].label.as_deref(), Some("billing"));
+        assert!(keys[1].expiry.warned_at.is_some());
+        assert_eq!(
+            keys[1].notification_url.as_deref(),
+            Some("https://ci.example.com/hooks")
+        );
+
+        assert!(
+            expiring_keys(&json!({"app_name": "app100", "api_key_id": "primary-id"})).is_empty()
+        );
+    }
+}
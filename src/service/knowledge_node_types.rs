@@ -0,0 +1,226 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the registry of the knowledge node types served by the admin node endpoints: the
+//! `knowledge_node_type` of the requests, the `_node_label` of the stored nodes and the fields listed by default.
+//! The built-in types are the filestore (`FileObject`) and datastore (`DatabaseObjectNode`) nodes, and the web page
+//! (`WebPageObject`), image (`ImageObject`) and email (`EmailObject`) nodes. `knowledge_node_types` adds types or
+//! overrides the built-in ones by name, so a new ingestion type is served without code change.
+//!
+
+use crate::configuration::settings::KnowledgeNodeTypeSettings;
+use axum::{http::StatusCode, Json};
+use mongodb::bson::{doc, Bson, Document};
+use serde_json::json;
+use std::collections::HashMap;
+use tracing::debug;
+
+/// Built-in knowledge node types: name, node label and fields listed by default.
+const BUILT_IN_NODE_TYPES: [(&str, &str, &[&str]); 5] = [
+    (
+        "knowledge_node_file_store",
+        "FileObject",
+        &["indexed_at", "source", "total_page_num"],
+    ),
+    (
+        "knowledge_node_data_store",
+        "DatabaseObjectNode",
+        &["indexed_at", "source"],
+    ),
+    (
+        "knowledge_node_web_page",
+        "WebPageObject",
+        &["indexed_at", "source"],
+    ),
+    (
+        "knowledge_node_image",
+        "ImageObject",
+        &["indexed_at", "source"],
+    ),
+    (
+        "knowledge_node_email",
+        "EmailObject",
+        &["indexed_at", "source"],
+    ),
+];
+/// Fields listed by default for the configured types without projection.
+const DEFAULT_PROJECTION: [&str; 2] = ["indexed_at", "source"];
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum KnowledgeNodeTypeError {
+    #[error("Invalid knowledge_node_type. Supported types are {0}.")]
+    UnknownType(String),
+}
+
+impl From<KnowledgeNodeTypeError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: KnowledgeNodeTypeError) -> Self {
+        let error_message = e.to_string();
+        debug!(message = error_message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// A knowledge node type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnowledgeNodeType {
+    pub name: String,
+    pub node_label: String,
+    /// Fields listed by default.
+    pub projection: Vec<String>,
+}
+
+impl KnowledgeNodeType {
+    /// `$project` stage of the fields listed by default.
+    pub fn projection(&self) -> Document {
+        let mut projection = doc! { "_id": 0 };
+        for field in &self.projection {
+            projection.insert(field.as_str(), 1);
+        }
+        projection
+    }
+}
+
+/// Registry of the knowledge node types, the built-in types first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnowledgeNodeTypes {
+    node_types: Vec<KnowledgeNodeType>,
+}
+
+impl KnowledgeNodeTypes {
+    /// Builds the registry from the built-in types and the configured ones, which override the built-in types of the
+    /// same name.
+    pub fn from_settings(settings: Option<&HashMap<String, KnowledgeNodeTypeSettings>>) -> Self {
+        let mut node_types: Vec<KnowledgeNodeType> = BUILT_IN_NODE_TYPES
+            .iter()
+            .map(|(name, node_label, projection)| KnowledgeNodeType {
+                name: name.to_string(),
+                node_label: node_label.to_string(),
+                projection: projection.iter().map(|field| field.to_string()).collect(),
+            })
+            .collect();
+        let mut configured: Vec<(&String, &KnowledgeNodeTypeSettings)> =
+            settings.into_iter().flatten().collect();
+        configured.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, settings) in configured {
+            let node_type = KnowledgeNodeType {
+                name: name.clone(),
+                node_label: settings.node_label.clone(),
+                projection: settings
+                    .projection
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_PROJECTION.map(String::from).to_vec()),
+            };
+            match node_types.iter_mut().find(|known| known.name == *name) {
+                Some(known) => *known = node_type,
+                None => node_types.push(node_type),
+            }
+        }
+        KnowledgeNodeTypes { node_types }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &KnowledgeNodeType> {
+        self.node_types.iter()
+    }
+
+    /// Returns the type of a `knowledge_node_type`.
+    pub fn by_name(&self, name: &str) -> Result<&KnowledgeNodeType, KnowledgeNodeTypeError> {
+        self.node_types
+            .iter()
+            .find(|node_type| node_type.name == name)
+            .ok_or_else(|| {
+                KnowledgeNodeTypeError::UnknownType(
+                    self.node_types
+                        .iter()
+                        .map(|node_type| node_type.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                )
+            })
+    }
+
+    /// Returns the type of a stored node label.
+    pub fn by_label(&self, node_label: &str) -> Option<&KnowledgeNodeType> {
+        self.node_types
+            .iter()
+            .find(|node_type| node_type.node_label == node_label)
+    }
+
+    /// Expression of the `knowledge_node_type` of a stored node label, `unknown` for the unregistered labels.
+    pub fn name_expression(&self, node_label: &str) -> Document {
+        let branches: Vec<Bson> = self
+            .node_types
+            .iter()
+            .map(|node_type| {
+                Bson::Document(doc! {
+                    "case": { "$eq": [ node_label, node_type.node_label.as_str() ] },
+                    "then": node_type.name.as_str(),
+                })
+            })
+            .collect();
+        doc! { "$switch": { "branches": branches, "default": "unknown" } }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_knowledge_node_types_from_settings() {
+        let node_types = KnowledgeNodeTypes::from_settings(None);
+        assert_eq!(node_types.iter().count(), 5);
+        assert_eq!(
+            node_types
+                .by_name("knowledge_node_data_store")
+                .unwrap()
+                .node_label,
+            "DatabaseObjectNode"
+        );
+        assert_eq!(
+            node_types.by_label("FileObject").unwrap().name,
+            "knowledge_node_file_store"
+        );
+
+        let settings = HashMap::from([
+            (
+                "knowledge_node_email".to_string(),
+                KnowledgeNodeTypeSettings {
+                    node_label: "MailNode".to_string(),
+                    projection: Some(vec!["subject".to_string()]),
+                },
+            ),
+            (
+                "knowledge_node_audio".to_string(),
+                KnowledgeNodeTypeSettings {
+                    node_label: "AudioObject".to_string(),
+                    projection: None,
+                },
+            ),
+        ]);
+        let node_types = KnowledgeNodeTypes::from_settings(Some(&settings));
+        assert_eq!(node_types.iter().count(), 6);
+        let email = node_types.by_name("knowledge_node_email").unwrap();
+        assert_eq!(email.node_label, "MailNode");
+        assert_eq!(email.projection(), doc! { "_id": 0, "subject": 1 });
+        let audio = node_types.by_label("AudioObject").unwrap();
+        assert_eq!(
+            audio.projection(),
+            doc! { "_id": 0, "indexed_at": 1, "source": 1 }
+        );
+    }
+
+    #[test]
+    fn test_failure_knowledge_node_types_by_name() {
+        let node_types = KnowledgeNodeTypes::from_settings(None);
+        let error = node_types.by_name("knowledge_node_video").unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Invalid knowledge_node_type."));
+        assert!(node_types.by_label("VideoObject").is_none());
+    }
+}
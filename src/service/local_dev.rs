@@ -0,0 +1,69 @@
+/*
+ * Created Date:  Jul 19, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the local development mode, turned on with `local_dev.enabled` (see the
+//! `configurations/local_dev.yaml` profile). The AWS and Kafka integrations are swapped for in-process fakes behind
+//! their call sites, so `cargo run` brings up a working facade against a local MongoDB only:
+//! - the filestore connectivity checks read the buckets from the `local_dev.object_store_dir` directory,
+//! - the events are kept in memory and logged instead of being published to Kafka, the app topics are not created,
+//! - the API keys are issued by the facade, as in the internal API key mode, instead of API Gateway.
+//!
+
+use crate::configuration::settings::LocalDevSettings;
+use crate::service::event_producer::{InMemoryEventProducer, DEFAULT_MAX_RECORDED_EVENTS};
+use crate::service::object_store::LocalObjectStore;
+use std::sync::Arc;
+
+/// Default directory of the local buckets, relative to the working directory.
+pub const DEFAULT_OBJECT_STORE_DIR: &str = "local-dev/s3";
+
+/// In-process fakes of the local development mode.
+#[derive(Debug, Clone)]
+pub struct LocalDev {
+    pub object_store: Arc<LocalObjectStore>,
+    pub events: Arc<InMemoryEventProducer>,
+}
+
+impl LocalDev {
+    /// Builds the fakes from the settings, `None` unless the local development mode is enabled.
+    pub fn from_settings(settings: Option<&LocalDevSettings>) -> Option<Self> {
+        let settings = settings.filter(|settings| settings.enabled)?;
+        let object_store_dir = settings
+            .object_store_dir
+            .as_deref()
+            .unwrap_or(DEFAULT_OBJECT_STORE_DIR);
+        Some(LocalDev {
+            object_store: Arc::new(LocalObjectStore::new(object_store_dir)),
+            events: Arc::new(InMemoryEventProducer::new(
+                settings
+                    .max_recorded_events
+                    .unwrap_or(DEFAULT_MAX_RECORDED_EVENTS),
+            )),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_local_dev_from_settings() {
+        assert!(LocalDev::from_settings(None).is_none());
+        let mut settings = LocalDevSettings {
+            enabled: false,
+            object_store_dir: None,
+            max_recorded_events: None,
+        };
+        assert!(LocalDev::from_settings(Some(&settings)).is_none());
+
+        settings.enabled = true;
+        let local_dev = LocalDev::from_settings(Some(&settings)).unwrap();
+        assert_eq!(
+            local_dev.object_store.root,
+            std::path::PathBuf::from(DEFAULT_OBJECT_STORE_DIR)
+        );
+    }
+}
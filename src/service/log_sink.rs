@@ -0,0 +1,373 @@
+/*
+ * Created Date:  Jul 08, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the OpenSearch/Elasticsearch log sink, an alternative export path of the per-app log
+//! documents for the customers running an ELK stack.
+//! A background shipper mirrors the documents of the logging collection into daily indices
+//! `{index_prefix}-{app_name}-{yyyy.MM.dd}` with the `_bulk` API, so an index template and a lifecycle policy
+//! (ILM/ISM) on `{index_prefix}-*` manage their retention. The `_id` of a log document is reused as the id of the
+//! indexed document, so a batch shipped twice (restart, several replicas) is not duplicated.
+//! The `_id` of the last shipped document is stored as a cursor in DocumentDB. A batch rejected by the cluster is
+//! retried on the next round; documents rejected one by one (mapping errors, ...) are logged and skipped.
+//! The shipped documents are labelled with the deployment of the facade (see `crate::service::deployment`).
+//!
+
+use crate::configuration::settings::LogSinkSettings;
+use crate::service::metrics::MetricRecord;
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::scheduler::{acquire_lease, Job, JobRunStart, JobStatus};
+use crate::service::state::AppState;
+use chrono::{DateTime, Utc};
+use mongodb::bson::{doc, oid::ObjectId};
+use secrecy::ExposeSecret;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, instrument};
+
+/// Default prefix of the log indices.
+pub const DEFAULT_LOG_INDEX_PREFIX: &str = "tresleai-logs";
+/// Default collection of the shipping cursor.
+pub const DEFAULT_LOG_SINK_CURSOR_COLLECTION: &str = "log-sink-cursors";
+/// Default number of seconds between two shipping rounds.
+const DEFAULT_INTERVAL_SECONDS: u64 = 60;
+/// Default maximum number of log documents per bulk request.
+const DEFAULT_BATCH_SIZE: i64 = 500;
+/// Name of the cursor document of the sink.
+const CURSOR_SINK_NAME: &str = "opensearch";
+
+#[derive(Debug, thiserror::Error)]
+pub enum LogSinkError {
+    #[error("Failed to read the log documents: {0}")]
+    Source(String),
+    #[error("Bulk request to the log sink failed: {0}")]
+    Request(String),
+    #[error("Failed to store the shipping cursor: {0}")]
+    Cursor(String),
+}
+
+/// Name of the daily index of the logs of an app. The components are lowercased and the characters not allowed in
+/// index names replaced, logs without a valid timestamp go to the index of the current day.
+pub fn index_name(index_prefix: &str, app_name: &str, timestamp: Option<&str>) -> String {
+    let day = timestamp
+        .and_then(|timestamp| DateTime::parse_from_rfc3339(timestamp).ok())
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .unwrap_or_else(Utc::now)
+        .format("%Y.%m.%d");
+    format!(
+        "{}-{}-{}",
+        sanitize_index_component(index_prefix),
+        sanitize_index_component(app_name),
+        day
+    )
+}
+
+/// Lowercases an index name component and replaces the characters not allowed in index names.
+fn sanitize_index_component(component: &str) -> String {
+    component
+        .trim_start_matches(['-', '_', '+'])
+        .chars()
+        .map(|c| match c {
+            '\\' | '/' | '*' | '?' | '"' | '<' | '>' | '|' | ' ' | ',' | '#' | ':' => '_',
+            c => c.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
+/// Builds the NDJSON body of a bulk request indexing the log documents. Logs without an app name go to the index of
+/// the system app. Returns the body and the `_id` of the last log document.
+pub fn bulk_body(
+    index_prefix: &str,
+    system_app_name: &str,
+    logs: &[serde_json::Value],
+) -> (String, Option<String>) {
+    let mut body = String::new();
+    let mut last_id = None;
+    for log in logs {
+        let Some(id) = log.get("_id").and_then(serde_json::Value::as_str) else {
+            continue;
+        };
+        let app_name = log
+            .get("app_name")
+            .and_then(serde_json::Value::as_str)
+            .filter(|app_name| !app_name.is_empty())
+            .unwrap_or(system_app_name);
+        let timestamp = log.get("timestamp").and_then(serde_json::Value::as_str);
+        let mut source = log.clone();
+        if let Some(source) = source.as_object_mut() {
+            source.remove("_id");
+        }
+        let action =
+            json!({"index": {"_index": index_name(index_prefix, app_name, timestamp), "_id": id}});
+        body.push_str(&action.to_string());
+        body.push('\n');
+        body.push_str(&source.to_string());
+        body.push('\n');
+        last_id = Some(id.to_string());
+    }
+    (body, last_id)
+}
+
+/// Ships the log documents to the log sink every `interval_seconds`, until the process exits. Only the replica
+/// holding the lease of the job ships them, so the cursor is moved by a single replica.
+#[instrument(skip_all)]
+pub async fn ship_logs(app_state: Arc<AppState>) {
+    let Some(settings) = app_state.app_settings.log_sink.as_ref() else {
+        return;
+    };
+    let period = Duration::from_secs(
+        settings
+            .interval_seconds
+            .unwrap_or(DEFAULT_INTERVAL_SECONDS),
+    );
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if !acquire_lease(&app_state, Job::LogSink, period).await {
+            continue;
+        }
+        let run = JobRunStart::new(Job::LogSink);
+        let mut total_shipped = 0;
+        // Ship full batches back to back, a partial batch means the sink caught up
+        let (status, details) = loop {
+            match ship_batch(&app_state, settings).await {
+                Ok(shipped) => {
+                    if shipped > 0 {
+                        app_state
+                            .record_metric(MetricRecord::count("Log Documents Shipped", shipped))
+                            .await;
+                    }
+                    total_shipped += shipped;
+                    if (shipped as i64) < batch_size(settings) {
+                        break (
+                            JobStatus::Success,
+                            format!("Shipped {} log documents.", total_shipped),
+                        );
+                    }
+                }
+                Err(e) => {
+                    error!(message = e.to_string());
+                    app_state
+                        .record_metric(MetricRecord::counter("Log Sink Error Counter"))
+                        .await;
+                    break (
+                        JobStatus::Failure,
+                        format!("Shipped {} log documents. Error: {}", total_shipped, e),
+                    );
+                }
+            }
+        };
+        run.finish(&app_state, status, details).await;
+    }
+}
+
+/// Ships the log documents following the cursor in a single bulk request. Returns the number of shipped documents.
+pub async fn ship_batch(
+    app_state: &AppState,
+    settings: &LogSinkSettings,
+) -> Result<usize, LogSinkError> {
+    let cursor_collection = settings
+        .cursor_collection
+        .as_deref()
+        .unwrap_or(DEFAULT_LOG_SINK_CURSOR_COLLECTION);
+    let cursor = app_state
+        .db
+        .get_document(cursor_collection, doc! {"sink": CURSOR_SINK_NAME})
+        .await
+        .map_err(|e| LogSinkError::Cursor(e.to_string()))?;
+    let last_id = cursor
+        .as_ref()
+        .and_then(|cursor| cursor.get("last_id"))
+        .and_then(serde_json::Value::as_str)
+        .and_then(|last_id| ObjectId::parse_str(last_id).ok());
+
+    let mut pipeline = Vec::new();
+    if let Some(last_id) = last_id {
+        pipeline.push(doc! { "$match": { "_id": { "$gt": last_id } } });
+    }
+    pipeline.push(doc! { "$sort": { "_id": 1 } });
+    pipeline.push(doc! { "$limit": batch_size(settings) });
+    pipeline.push(doc! { "$addFields": { "_id": { "$toString": "$_id" } } });
+    let logs_collection = &app_state
+        .app_settings
+        .app_generated_config
+        .knowledge_graph_config
+        .logging
+        .collection;
+    let logs = app_state
+        .db
+        .aggregate(
+            logs_collection,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await
+        .map_err(|e| LogSinkError::Source(e.to_string()))?;
+    // The shipped documents are labelled with the deployment of the facade
+    let labels = app_state.deployment_labels();
+    let logs: Vec<serde_json::Value> = logs.into_iter().map(|log| labels.label_log(log)).collect();
+
+    let index_prefix = settings
+        .index_prefix
+        .as_deref()
+        .unwrap_or(DEFAULT_LOG_INDEX_PREFIX);
+    let (body, Some(shipped_id)) = bulk_body(
+        index_prefix,
+        &app_state.app_settings.tracing_layer_system_app_name,
+        &logs,
+    ) else {
+        return Ok(0);
+    };
+
+    let url = format!("{}/_bulk", settings.url.trim_end_matches('/'));
+    let mut request = app_state
+        .http_clients
+        .for_url(&url)
+        .post(&url)
+        .header("content-type", "application/x-ndjson")
+        .body(body);
+    if let Some(username) = &settings.username {
+        request = request.basic_auth(
+            username,
+            settings
+                .password
+                .as_ref()
+                .map(|password| password.expose_secret()),
+        );
+    }
+    let response = request
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| LogSinkError::Request(e.to_string()))?
+        .json::<serde_json::Value>()
+        .await
+        .map_err(|e| LogSinkError::Request(e.to_string()))?;
+    let rejected = rejected_items(&response);
+    if rejected > 0 {
+        error!(
+            message = format!(
+                "{} of {} log documents rejected by the log sink.",
+                rejected,
+                logs.len()
+            )
+        );
+    }
+
+    let updated_at = Utc::now().to_rfc3339();
+    if cursor.is_some() {
+        app_state
+            .db
+            .update_document(
+                cursor_collection,
+                doc! {"sink": CURSOR_SINK_NAME},
+                doc! {"last_id": &shipped_id, "updated_at": updated_at},
+            )
+            .await
+            .map_err(|e| LogSinkError::Cursor(e.to_string()))?;
+    } else {
+        app_state
+            .db
+            .create_document(
+                cursor_collection,
+                doc! {"sink": CURSOR_SINK_NAME, "last_id": &shipped_id, "updated_at": updated_at},
+            )
+            .await
+            .map_err(|e| LogSinkError::Cursor(e.to_string()))?;
+    }
+    debug!(
+        message = format!(
+            "Shipped {} log documents to the log sink, up to '{}'.",
+            logs.len(),
+            shipped_id
+        )
+    );
+    Ok(logs.len())
+}
+
+/// Maximum number of log documents per bulk request.
+fn batch_size(settings: &LogSinkSettings) -> i64 {
+    settings.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1)
+}
+
+/// Number of documents rejected in a bulk response.
+fn rejected_items(response: &serde_json::Value) -> usize {
+    if !response
+        .get("errors")
+        .and_then(serde_json::Value::as_bool)
+        .unwrap_or(false)
+    {
+        return 0;
+    }
+    response
+        .get("items")
+        .and_then(serde_json::Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter(|item| {
+            item.get("index")
+                .and_then(|index| index.get("error"))
+                .is_some()
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_index_name() {
+        assert_eq!(
+            index_name(
+                "tresleai-logs",
+                "App 100",
+                Some("2024-07-08T23:30:00-02:00")
+            ),
+            "tresleai-logs-app_100-2024.07.09"
+        );
+        assert_eq!(
+            index_name("_Logs", "app100", Some("not a timestamp")),
+            format!("logs-app100-{}", Utc::now().format("%Y.%m.%d"))
+        );
+    }
+
+    #[test]
+    fn test_success_bulk_body() {
+        let logs = vec![
+            json!({"_id": "668b8b9e0000000000000001", "app_name": "app100", "timestamp": "2024-07-08T10:00:00Z", "message": "Onboarding started."}),
+            json!({"app_name": "app100", "message": "No _id, skipped."}),
+            json!({"_id": "668b8b9e0000000000000002", "timestamp": "2024-07-08T10:00:01Z", "message": "System log."}),
+        ];
+        let (body, last_id) = bulk_body("tresleai-logs", "tresleai-system", &logs);
+        let lines: Vec<serde_json::Value> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            json!({"index": {"_index": "tresleai-logs-app100-2024.07.08", "_id": "668b8b9e0000000000000001"}})
+        );
+        assert!(lines[1].get("_id").is_none());
+        assert_eq!(
+            lines[2]["index"]["_index"],
+            "tresleai-logs-tresleai-system-2024.07.08"
+        );
+        assert_eq!(last_id.as_deref(), Some("668b8b9e0000000000000002"));
+        assert_eq!(bulk_body("tresleai-logs", "tresleai-system", &[]).1, None);
+    }
+
+    #[test]
+    fn test_success_rejected_items() {
+        assert_eq!(rejected_items(&json!({"errors": false, "items": []})), 0);
+        let response = json!({"errors": true, "items": [
+            {"index": {"status": 201}},
+            {"index": {"status": 400, "error": {"type": "mapper_parsing_exception"}}},
+        ]});
+        assert_eq!(rejected_items(&response), 1);
+    }
+}
@@ -0,0 +1,168 @@
+/*
+ * Created Date:  Jun 18, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the migration normalizing the stored duration metrics.
+//! Duration metrics used to be stored only as "NNN ms" strings in `metrics_value`, which cannot be
+//! aggregated. The migration backfills the numeric `metrics_value_ms` field for those documents.
+//! New duration metrics carry `metrics_value_ms` when they are emitted.
+//! The migration is idempotent and runs as migration 1 of the startup migrations, see `migration`.
+//!
+
+use crate::service::metrics::MetricRecord;
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::state::AppState;
+use mongodb::bson::{doc, oid::ObjectId, Document};
+use std::sync::Arc;
+use tracing::{debug, error, info, instrument};
+
+/// Numeric duration field of the duration metrics.
+pub const METRIC_DURATION_MS_FIELD: &str = "metrics_value_ms";
+/// Number of metric documents migrated per batch.
+const MIGRATION_BATCH_SIZE: i64 = 500;
+
+/// Parses a duration stored as "NNN ms".
+pub fn parse_duration_ms(value: &str) -> Option<i64> {
+    value.trim().strip_suffix("ms")?.trim().parse().ok()
+}
+
+/// Filter of the duration metrics stored as strings without `metrics_value_ms`.
+fn unmigrated_duration_metrics() -> Document {
+    doc! {
+        "metrics_value": { "$regex": "^\\s*[0-9]+\\s*ms\\s*$" },
+        METRIC_DURATION_MS_FIELD: { "$exists": false },
+    }
+}
+
+/// Counts the duration metrics stored as strings without `metrics_value_ms`.
+pub async fn count_unmigrated_duration_metrics(app_state: &AppState) -> Result<usize, String> {
+    let collection_name = &app_state
+        .app_settings
+        .app_generated_config
+        .knowledge_graph_config
+        .metric
+        .collection;
+    let pipeline = vec![
+        doc! { "$match": unmigrated_duration_metrics() },
+        doc! { "$count": "count" },
+    ];
+    let counts = app_state
+        .db
+        .aggregate(
+            collection_name,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(counts
+        .first()
+        .and_then(|count| count.get("count"))
+        .and_then(serde_json::Value::as_u64)
+        .unwrap_or_default() as usize)
+}
+
+/// Backfills `metrics_value_ms` for the duration metrics stored as strings. Returns the number of migrated documents.
+#[instrument(skip_all)]
+pub async fn migrate_duration_metrics(app_state: Arc<AppState>) -> usize {
+    let collection_name = &app_state
+        .app_settings
+        .app_generated_config
+        .knowledge_graph_config
+        .metric
+        .collection;
+    let mut migrated = 0;
+
+    loop {
+        let batch_pipeline = vec![
+            doc! { "$match": unmigrated_duration_metrics() },
+            doc! { "$limit": MIGRATION_BATCH_SIZE },
+            doc! { "$project": { "_id": { "$toString": "$_id" }, "metrics_value": 1 } },
+        ];
+        let batch = match app_state
+            .db
+            .aggregate(
+                collection_name,
+                batch_pipeline,
+                &app_state.options::<QueryOptions>(),
+            )
+            .await
+        {
+            Ok(batch) => batch,
+            Err(e) => {
+                error!(message = format!("Failed to fetch duration metrics to migrate: {}", e));
+                break;
+            }
+        };
+        if batch.is_empty() {
+            break;
+        }
+
+        let mut batch_migrated = 0;
+        for metric in batch {
+            let id = metric
+                .get("_id")
+                .and_then(serde_json::Value::as_str)
+                .and_then(|id| ObjectId::parse_str(id).ok());
+            let duration_ms = metric
+                .get("metrics_value")
+                .and_then(serde_json::Value::as_str)
+                .and_then(parse_duration_ms);
+            let (Some(id), Some(duration_ms)) = (id, duration_ms) else {
+                continue;
+            };
+            match app_state
+                .db
+                .update_document(
+                    collection_name,
+                    doc! { "_id": id },
+                    doc! { METRIC_DURATION_MS_FIELD: duration_ms },
+                )
+                .await
+            {
+                Ok(_) => batch_migrated += 1,
+                Err(e) => debug!(message = format!("Failed to migrate metric {}: {}", id, e)),
+            }
+        }
+        migrated += batch_migrated;
+        // Stop if nothing in the batch could be migrated, otherwise the same batch is fetched again
+        if batch_migrated == 0 {
+            break;
+        }
+    }
+
+    info!(message = format!("Migrated {} duration metrics.", migrated));
+    app_state
+        .record_metric(MetricRecord::count("Duration Metrics Migrated", migrated))
+        .await;
+    migrated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_parse_duration_ms() {
+        assert_eq!(parse_duration_ms("123 ms"), Some(123));
+        assert_eq!(parse_duration_ms(" 45ms "), Some(45));
+        assert_eq!(parse_duration_ms("1"), None);
+        assert_eq!(parse_duration_ms("abc ms"), None);
+    }
+
+    #[test]
+    fn test_success_migrate_duration_metrics() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Migrating twice leaves nothing to migrate the second time
+            migrate_duration_metrics(app_state.clone()).await;
+            assert_eq!(migrate_duration_metrics(app_state).await, 0);
+        });
+    }
+}
@@ -0,0 +1,412 @@
+/*
+ * Created Date:  Jun 19, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the typed metrics model and the sinks the metrics are written through.
+//! A `MetricRecord` carries a name, a numeric value, a unit and dimensions (app name, task id, ...).
+//! `DocumentDbMetricsSink` stores the records in DocumentDB so they can be aggregated downstream.
+//! `TracingMetricsSink` keeps emitting the legacy string based metric events (`metrics_value = "123 ms"`)
+//! through tracing, and is dual-written with the typed records for one release.
+//! The records are labelled with the deployment of the facade when recorded (see `crate::service::deployment`).
+//! `CloudWatchEmfMetricsSink` writes the records in the CloudWatch Embedded Metric Format, for the deployments
+//! relying on CloudWatch dashboards and alarms. The dimensions of a record become CloudWatch dimensions, except the
+//! task id which is kept as a property.
+//! `PrometheusMetricsSink` observes the duration records in the histograms served at `/metrics`
+//! (see `crate::service::prometheus`).
+//!
+
+use crate::configuration::settings::{CloudWatchEmfSettings, TresleFacadeServiceSettings};
+use crate::service::prometheus::{PrometheusMetricsSink, PrometheusRegistry};
+use async_trait::async_trait;
+use chrono::Utc;
+use mongodb::bson;
+use mongodb_utils::mongodb_client::DBTrait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tracing::info;
+
+/// Default collection of the typed metric records.
+pub const DEFAULT_METRIC_RECORDS_COLLECTION: &str = "metric-records";
+/// Dimension holding the app name.
+pub const APP_NAME_DIMENSION: &str = "app_name";
+/// Dimension holding the task id.
+pub const TASK_ID_DIMENSION: &str = "task_id";
+/// Dimension holding the matched route of a request.
+pub const ROUTE_DIMENSION: &str = "route";
+/// Dimension holding the HTTP method of a request.
+pub const METHOD_DIMENSION: &str = "method";
+/// Dimension holding the status of a response or a background task.
+pub const STATUS_DIMENSION: &str = "status";
+/// Dimension holding the category of a retrieval query.
+pub const QUERY_CATEGORY_DIMENSION: &str = "query_category";
+/// Dimension holding the name of an experiment.
+pub const EXPERIMENT_DIMENSION: &str = "experiment";
+/// Dimension holding the variant of an experiment assigned to a retrieval.
+pub const VARIANT_DIMENSION: &str = "variant";
+/// Dimension holding the name of a startup migration.
+pub const MIGRATION_DIMENSION: &str = "migration";
+/// Dimension holding the name of a background job.
+pub const JOB_DIMENSION: &str = "job";
+/// Dimension holding the name of a backfill.
+pub const BACKFILL_DIMENSION: &str = "backfill";
+/// Dimension holding the name of a persistent job queue.
+pub const QUEUE_DIMENSION: &str = "queue";
+/// Dimension holding the name of the deployment of the facade.
+pub const DEPLOYMENT_DIMENSION: &str = "deployment";
+/// Dimension holding the environment of the deployment of the facade.
+pub const ENVIRONMENT_DIMENSION: &str = "environment";
+/// Dimension holding the region of the deployment of the facade.
+pub const REGION_DIMENSION: &str = "region";
+
+#[derive(Debug, thiserror::Error)]
+pub enum MetricsError {
+    #[error("Failed to serialize metric record: {0}")]
+    Serialize(#[from] bson::ser::Error),
+    #[error("Failed to store metric record: {0}")]
+    Store(String),
+    #[error("Failed to emit metric record: {0}")]
+    Emit(#[from] std::io::Error),
+}
+
+/// Unit of a metric value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricUnit {
+    #[serde(rename = "ms")]
+    Milliseconds,
+    Count,
+    Bytes,
+}
+
+/// A typed metric.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetricRecord {
+    pub name: String,
+    pub value: f64,
+    pub unit: MetricUnit,
+    pub dimensions: BTreeMap<String, String>,
+    pub timestamp: String,
+}
+
+impl MetricRecord {
+    /// Creates a metric record timestamped now.
+    pub fn new(name: &str, value: f64, unit: MetricUnit) -> Self {
+        MetricRecord {
+            name: name.to_string(),
+            value,
+            unit,
+            dimensions: BTreeMap::new(),
+            timestamp: Utc::now().to_rfc3339(),
+        }
+    }
+
+    /// Duration metric in milliseconds.
+    pub fn duration_ms(name: &str, duration_ms: i64) -> Self {
+        Self::new(name, duration_ms as f64, MetricUnit::Milliseconds)
+    }
+
+    /// Counter metric incremented by one.
+    pub fn counter(name: &str) -> Self {
+        Self::new(name, 1.0, MetricUnit::Count)
+    }
+
+    /// Count metric, e.g. the number of documents processed by a background task.
+    pub fn count(name: &str, count: usize) -> Self {
+        Self::new(name, count as f64, MetricUnit::Count)
+    }
+
+    /// Size metric in bytes, e.g. the size of a request payload.
+    pub fn bytes(name: &str, bytes: usize) -> Self {
+        Self::new(name, bytes as f64, MetricUnit::Bytes)
+    }
+
+    /// Adds a dimension to the record.
+    pub fn dimension(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.dimensions.insert(key.to_string(), value.into());
+        self
+    }
+
+    /// Legacy string representation of the value, e.g. "123 ms" or "1".
+    pub fn legacy_value(&self) -> String {
+        match self.unit {
+            MetricUnit::Milliseconds => format!("{} ms", self.value as i64),
+            MetricUnit::Count => format!("{}", self.value as i64),
+            MetricUnit::Bytes => format!("{} bytes", self.value as i64),
+        }
+    }
+}
+
+/// Destination of the metric records.
+#[async_trait]
+pub trait MetricsSink: Send + Sync {
+    async fn record(
+        &self,
+        db: &(dyn DBTrait + Sync + Send),
+        record: &MetricRecord,
+    ) -> Result<(), MetricsError>;
+}
+
+/// Stores the typed metric records in a DocumentDB collection.
+#[derive(Debug, Clone)]
+pub struct DocumentDbMetricsSink {
+    pub collection_name: String,
+}
+
+#[async_trait]
+impl MetricsSink for DocumentDbMetricsSink {
+    async fn record(
+        &self,
+        db: &(dyn DBTrait + Sync + Send),
+        record: &MetricRecord,
+    ) -> Result<(), MetricsError> {
+        let document = bson::to_document(record)?;
+        db.create_document(&self.collection_name, document)
+            .await
+            .map(|_| ())
+            .map_err(|e| MetricsError::Store(e.to_string()))
+    }
+}
+
+/// Emits the legacy string based metric events through tracing.
+#[derive(Debug, Clone, Default)]
+pub struct TracingMetricsSink;
+
+#[async_trait]
+impl MetricsSink for TracingMetricsSink {
+    async fn record(
+        &self,
+        _db: &(dyn DBTrait + Sync + Send),
+        record: &MetricRecord,
+    ) -> Result<(), MetricsError> {
+        let app_name = record
+            .dimensions
+            .get(APP_NAME_DIMENSION)
+            .cloned()
+            .unwrap_or_default();
+        let task_id = record
+            .dimensions
+            .get(TASK_ID_DIMENSION)
+            .cloned()
+            .unwrap_or_default();
+        let dimension = |name: &str| record.dimensions.get(name).cloned().unwrap_or_default();
+        let (deployment, environment, region) = (
+            dimension(DEPLOYMENT_DIMENSION),
+            dimension(ENVIRONMENT_DIMENSION),
+            dimension(REGION_DIMENSION),
+        );
+        match record.unit {
+            MetricUnit::Milliseconds => info!(
+                service = "metric",
+                task_id = task_id,
+                app_name = app_name,
+                deployment = deployment,
+                environment = environment,
+                region = region,
+                metrics_name = record.name,
+                metrics_value = record.legacy_value(),
+                metrics_value_ms = record.value as i64
+            ),
+            MetricUnit::Count | MetricUnit::Bytes => info!(
+                service = "metric",
+                task_id = task_id,
+                app_name = app_name,
+                deployment = deployment,
+                environment = environment,
+                region = region,
+                metrics_name = record.name,
+                metrics_value = record.legacy_value()
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// Writes the metric records in the CloudWatch Embedded Metric Format.
+#[derive(Debug, Clone)]
+pub struct CloudWatchEmfMetricsSink {
+    pub namespace: String,
+    pub log_group: Option<String>,
+    pub agent_address: Option<String>,
+}
+
+impl CloudWatchEmfMetricsSink {
+    pub fn new(settings: &CloudWatchEmfSettings) -> Self {
+        CloudWatchEmfMetricsSink {
+            namespace: settings.namespace.clone(),
+            log_group: settings.log_group.clone(),
+            agent_address: settings.agent_address.clone(),
+        }
+    }
+
+    /// EMF document of a metric record.
+    pub fn emf_document(&self, record: &MetricRecord) -> serde_json::Value {
+        let timestamp = chrono::DateTime::parse_from_rfc3339(&record.timestamp)
+            .map(|timestamp| timestamp.timestamp_millis())
+            .unwrap_or_else(|_| Utc::now().timestamp_millis());
+        let dimensions: Vec<&String> = record
+            .dimensions
+            .keys()
+            .filter(|key| key.as_str() != TASK_ID_DIMENSION)
+            .collect();
+        let unit = match record.unit {
+            MetricUnit::Milliseconds => "Milliseconds",
+            MetricUnit::Count => "Count",
+            MetricUnit::Bytes => "Bytes",
+        };
+        let mut metadata = json!({
+            "Timestamp": timestamp,
+            "CloudWatchMetrics": [{
+                "Namespace": self.namespace,
+                "Dimensions": [dimensions],
+                "Metrics": [{"Name": record.name, "Unit": unit}],
+            }],
+        });
+        if let Some(log_group) = &self.log_group {
+            metadata["LogGroupName"] = json!(log_group);
+        }
+        let mut document = json!({"_aws": metadata});
+        for (key, value) in &record.dimensions {
+            document[key] = json!(value);
+        }
+        document[&record.name] = json!(record.value);
+        document
+    }
+}
+
+#[async_trait]
+impl MetricsSink for CloudWatchEmfMetricsSink {
+    async fn record(
+        &self,
+        _db: &(dyn DBTrait + Sync + Send),
+        record: &MetricRecord,
+    ) -> Result<(), MetricsError> {
+        let line = self.emf_document(record).to_string();
+        match &self.agent_address {
+            Some(agent_address) => {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket
+                    .send_to(format!("{}\n", line).as_bytes(), agent_address)
+                    .await?;
+            }
+            None => println!("{}", line),
+        }
+        Ok(())
+    }
+}
+
+/// Returns the collection of the typed metric records.
+pub fn records_collection(app_settings: &TresleFacadeServiceSettings) -> String {
+    app_settings
+        .metrics
+        .as_ref()
+        .and_then(|metrics| metrics.records_collection.clone())
+        .unwrap_or_else(|| DEFAULT_METRIC_RECORDS_COLLECTION.to_string())
+}
+
+/// Builds the metric sinks from the settings. Typed records are always stored, and the legacy
+/// tracing events are dual-written unless disabled.
+pub fn sinks_from_settings(
+    app_settings: &TresleFacadeServiceSettings,
+    prometheus: Option<&Arc<PrometheusRegistry>>,
+) -> Vec<Box<dyn MetricsSink>> {
+    let metrics = app_settings.metrics.as_ref();
+    let collection_name = records_collection(app_settings);
+    let mut sinks: Vec<Box<dyn MetricsSink>> =
+        vec![Box::new(DocumentDbMetricsSink { collection_name })];
+    if metrics.map_or(true, |metrics| metrics.legacy_string_events) {
+        sinks.push(Box::new(TracingMetricsSink));
+    }
+    if let Some(cloudwatch_emf) = metrics.and_then(|metrics| metrics.cloudwatch_emf.as_ref()) {
+        sinks.push(Box::new(CloudWatchEmfMetricsSink::new(cloudwatch_emf)));
+    }
+    if let Some(registry) = prometheus {
+        sinks.push(Box::new(PrometheusMetricsSink {
+            registry: registry.clone(),
+        }));
+    }
+    sinks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_metric_record() {
+        let record = MetricRecord::duration_ms("Data Retrieval Duration", 123)
+            .dimension(APP_NAME_DIMENSION, "app100")
+            .dimension(TASK_ID_DIMENSION, "task");
+        assert_eq!(record.value, 123.0);
+        assert_eq!(record.legacy_value(), "123 ms");
+        assert_eq!(record.dimensions.get(APP_NAME_DIMENSION).unwrap(), "app100");
+
+        let document = bson::to_document(&record).unwrap();
+        assert_eq!(document.get_str("unit").unwrap(), "ms");
+        assert_eq!(document.get_f64("value").unwrap(), 123.0);
+
+        let record = MetricRecord::bytes("Onboarding Payload Bytes", 2048);
+        assert_eq!(record.legacy_value(), "2048 bytes");
+        assert_eq!(
+            bson::to_document(&record).unwrap().get_str("unit").unwrap(),
+            "bytes"
+        );
+
+        let record = MetricRecord::counter("Data Retrieval Counter");
+        assert_eq!(record.legacy_value(), "1");
+        assert_eq!(
+            bson::to_document(&record).unwrap().get_str("unit").unwrap(),
+            "count"
+        );
+    }
+
+    #[test]
+    fn test_success_cloudwatch_emf_document() {
+        let sink = CloudWatchEmfMetricsSink {
+            namespace: "Tresleai/Facade".to_string(),
+            log_group: Some("tresleai-facade-metrics".to_string()),
+            agent_address: None,
+        };
+        let mut record = MetricRecord::duration_ms("Data Retrieval Duration", 123)
+            .dimension(APP_NAME_DIMENSION, "app100")
+            .dimension(TASK_ID_DIMENSION, "task");
+        record.timestamp = "2024-07-09T10:00:00Z".to_string();
+
+        let document = sink.emf_document(&record);
+        assert_eq!(
+            document["_aws"],
+            json!({
+                "Timestamp": 1720519200000_i64,
+                "CloudWatchMetrics": [{
+                    "Namespace": "Tresleai/Facade",
+                    "Dimensions": [["app_name"]],
+                    "Metrics": [{"Name": "Data Retrieval Duration", "Unit": "Milliseconds"}],
+                }],
+                "LogGroupName": "tresleai-facade-metrics",
+            })
+        );
+        assert_eq!(document["app_name"], "app100");
+        assert_eq!(document["task_id"], "task");
+        assert_eq!(document["Data Retrieval Duration"], 123.0);
+    }
+
+    #[test]
+    fn test_success_record_metric() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            app_state
+                .record_metric(
+                    MetricRecord::counter("Test Counter").dimension(APP_NAME_DIMENSION, "app100"),
+                )
+                .await;
+        });
+    }
+}
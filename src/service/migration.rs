@@ -0,0 +1,742 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the startup migrations of the DocumentDB collections.
+//! A migration evolves the shape of stored documents (e.g. hashing the stored API keys), replacing the manual data
+//! fixes of the releases. The migrations are registered in `registry` with increasing versions, and every applied
+//! migration is recorded in the `migrations.collection` collection, so a migration runs once per deployment.
+//! On startup, the pending migrations run in the background in version order. A lock document in the same collection
+//! makes sure that a single instance runs them; the lock of a crashed instance is taken over after
+//! `migrations.lock_ttl_seconds`. A failed migration stops the run, the migrations after it may rely on it, and is
+//! retried on the next startup.
+//! A migration that doesn't apply to the deployment (e.g. API key hashing with API Gateway keys) is left pending.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{MigrationSettings, TresleFacadeServiceSettings};
+use crate::retrieval::schema::history_document::LEGACY_HISTORY_SCHEMA_VERSION;
+use crate::service::api_key::{hash_api_key, API_KEY_HASH_PREFIX};
+use crate::service::metric_migration::migrate_duration_metrics;
+use crate::service::metrics::{MetricRecord, MIGRATION_DIMENSION, STATUS_DIMENSION};
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::state::AppState;
+use crate::service::timestamp::{timestamp_from_bson, to_bson_datetime};
+use async_trait::async_trait;
+use chrono::Utc;
+use mongodb::bson::{doc, oid::ObjectId, to_document, Bson};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, instrument, warn};
+
+/// Default collection of the applied migrations and of the migration lock.
+pub const DEFAULT_MIGRATIONS_COLLECTION: &str = "migrations";
+/// Default number of seconds after which the migration lock is taken over.
+const DEFAULT_LOCK_TTL_SECONDS: u64 = 600;
+/// `_id` of the migration lock document.
+const MIGRATION_LOCK_ID: &str = "migration-lock";
+/// Number of documents migrated per batch.
+const MIGRATION_BATCH_SIZE: i64 = 500;
+const HISTORY_COLLECTION_SUFFIX: &str = "-history";
+
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+    #[error("Invalid migration registry: {0}")]
+    Registry(String),
+    #[error("Failed to read the applied migrations. Error: {0}")]
+    Applied(String),
+    #[error("Failed to acquire the migration lock. Error: {0}")]
+    Lock(String),
+    #[error("Migration {version} '{name}' failed. Error: {message}")]
+    Failed {
+        version: u32,
+        name: String,
+        message: String,
+    },
+}
+
+/// Startup migration options: activation, collection and lock TTL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationOptions {
+    pub enabled: bool,
+    pub collection: String,
+    pub lock_ttl: Duration,
+}
+
+impl SettingsOptions for MigrationOptions {
+    type Settings = MigrationSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&MigrationSettings> {
+        settings.migrations.as_ref()
+    }
+
+    fn from_settings(settings: Option<&MigrationSettings>) -> Self {
+        MigrationOptions {
+            enabled: settings
+                .and_then(|settings| settings.enabled)
+                .unwrap_or(true),
+            collection: settings
+                .and_then(|settings| settings.collection.clone())
+                .unwrap_or_else(|| DEFAULT_MIGRATIONS_COLLECTION.to_string()),
+            lock_ttl: Duration::from_secs(
+                settings
+                    .and_then(|settings| settings.lock_ttl_seconds)
+                    .unwrap_or(DEFAULT_LOCK_TTL_SECONDS),
+            ),
+        }
+    }
+}
+
+/// A versioned migration of the stored documents. `up` must be idempotent: a migration interrupted by a crash runs
+/// again from the start.
+#[async_trait]
+pub trait Migration: Send + Sync {
+    fn version(&self) -> u32;
+
+    fn name(&self) -> &'static str;
+
+    /// Returns false if the migration doesn't apply to the deployment, it is then left pending.
+    fn applies(&self, _app_state: &AppState) -> bool {
+        true
+    }
+
+    /// Runs the migration and returns the number of migrated documents.
+    async fn up(&self, app_state: &Arc<AppState>) -> Result<usize, String>;
+}
+
+/// Record of an applied migration in the migrations collection.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AppliedMigration {
+    pub version: u32,
+    pub name: String,
+    pub applied_at: String,
+    pub duration_ms: i64,
+    pub documents: i64,
+}
+
+/// Returns the migrations, in version order.
+pub fn registry() -> Vec<Box<dyn Migration>> {
+    vec![
+        Box::new(NormalizeDurationMetrics),
+        Box::new(HashApiKeys),
+        Box::new(HistorySchemaVersion),
+        Box::new(TimestampsAsDates),
+    ]
+}
+
+/// Checks that the versions of the migrations are unique and increasing.
+pub fn validate_registry(migrations: &[Box<dyn Migration>]) -> Result<(), MigrationError> {
+    for pair in migrations.windows(2) {
+        if pair[1].version() <= pair[0].version() {
+            return Err(MigrationError::Registry(format!(
+                "migration {} '{}' is registered after migration {} '{}'.",
+                pair[1].version(),
+                pair[1].name(),
+                pair[0].version(),
+                pair[0].name()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the migrations not applied yet, in version order.
+pub fn pending_migrations<'a>(
+    migrations: &'a [Box<dyn Migration>],
+    applied: &BTreeSet<u32>,
+) -> Vec<&'a dyn Migration> {
+    migrations
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version()))
+        .map(|migration| migration.as_ref())
+        .collect()
+}
+
+/// Runs the pending migrations, if no other instance is running them. Returns the number of applied migrations.
+#[instrument(skip_all)]
+pub async fn run_migrations(app_state: Arc<AppState>) -> Result<usize, MigrationError> {
+    let options = app_state.options::<MigrationOptions>();
+    let migrations = registry();
+    validate_registry(&migrations)?;
+    let owner = app_state.id_generator.reference_id();
+    if !acquire_lock(&app_state, &options, &owner).await? {
+        info!(message = "Migrations are run by another instance.");
+        return Ok(0);
+    }
+    let result = apply_migrations(&app_state, &options, &owner, &migrations).await;
+    release_lock(&app_state, &options, &owner).await;
+    if let Err(e) = &result {
+        error!(message = e.to_string());
+    }
+    result
+}
+
+/// Applies the pending migrations in version order, stopping at the first failure.
+async fn apply_migrations(
+    app_state: &Arc<AppState>,
+    options: &MigrationOptions,
+    owner: &str,
+    migrations: &[Box<dyn Migration>],
+) -> Result<usize, MigrationError> {
+    let applied = applied_versions(app_state, options).await?;
+    let mut count = 0;
+    for migration in pending_migrations(migrations, &applied) {
+        if !migration.applies(app_state) {
+            debug!(
+                message = format!(
+                    "Migration {} '{}' doesn't apply, left pending.",
+                    migration.version(),
+                    migration.name()
+                )
+            );
+            continue;
+        }
+        let start = Instant::now();
+        let result = migration.up(app_state).await;
+        let status = if result.is_ok() { "success" } else { "failure" };
+        app_state
+            .record_metric(
+                MetricRecord::duration_ms("Migration Duration", start.elapsed().as_millis() as i64)
+                    .dimension(MIGRATION_DIMENSION, migration.name())
+                    .dimension(STATUS_DIMENSION, status),
+            )
+            .await;
+        let documents = result.map_err(|message| MigrationError::Failed {
+            version: migration.version(),
+            name: migration.name().to_string(),
+            message,
+        })?;
+        let applied_migration = AppliedMigration {
+            version: migration.version(),
+            name: migration.name().to_string(),
+            applied_at: Utc::now().to_rfc3339(),
+            duration_ms: start.elapsed().as_millis() as i64,
+            documents: documents as i64,
+        };
+        record_applied_migration(app_state, options, &applied_migration).await?;
+        info!(
+            message = format!(
+                "Migration {} '{}' applied to {} documents.",
+                migration.version(),
+                migration.name(),
+                documents
+            )
+        );
+        count += 1;
+        // Long runs keep the lock from being taken over
+        refresh_lock(app_state, options, owner).await;
+    }
+    Ok(count)
+}
+
+/// Returns the versions of the applied migrations.
+async fn applied_versions(
+    app_state: &AppState,
+    options: &MigrationOptions,
+) -> Result<BTreeSet<u32>, MigrationError> {
+    let pipeline = vec![
+        doc! {"$match": {"version": {"$exists": true}}},
+        doc! {"$project": {"_id": 0, "version": 1}},
+    ];
+    let applied = app_state
+        .db
+        .aggregate(
+            &options.collection,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await
+        .map_err(|e| MigrationError::Applied(e.to_string()))?;
+    Ok(applied
+        .iter()
+        .filter_map(|migration| migration.get("version").and_then(serde_json::Value::as_u64))
+        .map(|version| version as u32)
+        .collect())
+}
+
+async fn record_applied_migration(
+    app_state: &AppState,
+    options: &MigrationOptions,
+    applied_migration: &AppliedMigration,
+) -> Result<(), MigrationError> {
+    let failed = |message: String| MigrationError::Failed {
+        version: applied_migration.version,
+        name: applied_migration.name.clone(),
+        message: format!("the migration ran but couldn't be recorded: {}", message),
+    };
+    let document = to_document(applied_migration).map_err(|e| failed(e.to_string()))?;
+    app_state
+        .db
+        .create_document(&options.collection, document)
+        .await
+        .map(|_| ())
+        .map_err(|e| failed(e.to_string()))
+}
+
+/// Returns the expiry of a lock taken now, in milliseconds since the epoch.
+fn lock_expiry(options: &MigrationOptions) -> i64 {
+    Utc::now().timestamp_millis() + options.lock_ttl.as_millis() as i64
+}
+
+/// Takes the migration lock. Returns false if it is held by another instance. An expired lock is taken over.
+async fn acquire_lock(
+    app_state: &AppState,
+    options: &MigrationOptions,
+    owner: &str,
+) -> Result<bool, MigrationError> {
+    let lock =
+        || doc! {"_id": MIGRATION_LOCK_ID, "owner": owner, "expires_at": lock_expiry(options)};
+    let db = &app_state.db;
+    let Err(e) = db.create_document(&options.collection, lock()).await else {
+        return Ok(true);
+    };
+    // The lock exists, or the collection can't be written
+    let held = db
+        .get_document(&options.collection, doc! {"_id": MIGRATION_LOCK_ID})
+        .await
+        .map_err(|e| MigrationError::Lock(e.to_string()))?
+        .ok_or_else(|| MigrationError::Lock(e.to_string()))?;
+    let expires_at = held["expires_at"].as_i64().unwrap_or_default();
+    if expires_at > Utc::now().timestamp_millis() {
+        return Ok(false);
+    }
+    warn!(
+        message = format!(
+            "Taking over the expired migration lock of '{}'.",
+            held["owner"].as_str().unwrap_or_default()
+        )
+    );
+    // Only the expired lock is deleted, not a lock taken over meanwhile by another instance
+    db.delete_document(
+        &options.collection,
+        doc! {"_id": MIGRATION_LOCK_ID, "expires_at": expires_at},
+    )
+    .await
+    .map_err(|e| MigrationError::Lock(e.to_string()))?;
+    Ok(db
+        .create_document(&options.collection, lock())
+        .await
+        .is_ok())
+}
+
+async fn refresh_lock(app_state: &AppState, options: &MigrationOptions, owner: &str) {
+    if let Err(e) = app_state
+        .db
+        .update_document(
+            &options.collection,
+            doc! {"_id": MIGRATION_LOCK_ID, "owner": owner},
+            doc! {"expires_at": lock_expiry(options)},
+        )
+        .await
+    {
+        warn!(message = format!("Failed to refresh the migration lock. Error: {}", e));
+    }
+}
+
+async fn release_lock(app_state: &AppState, options: &MigrationOptions, owner: &str) {
+    if let Err(e) = app_state
+        .db
+        .delete_document(
+            &options.collection,
+            doc! {"_id": MIGRATION_LOCK_ID, "owner": owner},
+        )
+        .await
+    {
+        // The lock expires on its own
+        warn!(message = format!("Failed to release the migration lock. Error: {}", e));
+    }
+}
+
+/// Backfills `schema_version` of the history documents stored without it.
+async fn backfill_history_schema_version(
+    app_state: &AppState,
+    app_name: &str,
+) -> Result<usize, String> {
+    let db = app_state
+        .app_db(app_name)
+        .await
+        .map_err(|e| e.to_string())?;
+    let history_collection_name = format!("{}{}", app_name, HISTORY_COLLECTION_SUFFIX);
+    let mut migrated = 0;
+    loop {
+        let batch_pipeline = vec![
+            doc! {"$match": {"schema_version": {"$exists": false}}},
+            doc! {"$limit": MIGRATION_BATCH_SIZE},
+            doc! {"$project": {"_id": {"$toString": "$_id"}}},
+        ];
+        let batch = db
+            .aggregate(
+                &history_collection_name,
+                batch_pipeline,
+                &app_state.options::<QueryOptions>(),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        let ids: Vec<ObjectId> = batch
+            .iter()
+            .filter_map(|document| document.get("_id").and_then(serde_json::Value::as_str))
+            .filter_map(|id| ObjectId::parse_str(id).ok())
+            .collect();
+        if ids.is_empty() {
+            return Ok(migrated);
+        }
+        let mut batch_migrated = 0;
+        for id in ids {
+            match db
+                .update_document(
+                    &history_collection_name,
+                    doc! {"_id": id},
+                    doc! {"schema_version": LEGACY_HISTORY_SCHEMA_VERSION},
+                )
+                .await
+            {
+                Ok(_) => batch_migrated += 1,
+                Err(e) => debug!(message = format!("Failed to migrate history {}: {}", id, e)),
+            }
+        }
+        // Stop if nothing in the batch could be migrated, otherwise the same batch is fetched again
+        if batch_migrated == 0 {
+            return Err(format!(
+                "no history document of '{}' could be migrated.",
+                app_name
+            ));
+        }
+        migrated += batch_migrated;
+    }
+}
+
+/// A timestamp field of a collection, converted by the `timestamps_as_dates` migration and the `timestamps`
+/// backfill.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TimestampField {
+    /// App of an app specific collection, stored in the cluster of its residency.
+    pub app_name: Option<String>,
+    pub collection_name: String,
+    pub field: &'static str,
+    /// Format of the stored strings, besides the formats read by `timestamp::parse_timestamp`.
+    pub timestamp_format: Option<String>,
+}
+
+/// Returns the timestamp fields of the app, UI summary, token usage and history documents.
+pub(crate) async fn timestamp_fields(app_state: &AppState) -> Result<Vec<TimestampField>, String> {
+    let mongo_db = &app_state.app_settings.mongo_db;
+    let mut timestamp_fields = vec![TimestampField {
+        app_name: None,
+        collection_name: mongo_db.mongo_db_app_collection.clone(),
+        field: "create_timestamp",
+        timestamp_format: Some(app_state.app_settings.application.timestamp_format.clone()),
+    }];
+    for collection_name in [
+        &mongo_db.mongo_db_ui_summary_collection,
+        &mongo_db.mongo_db_token_usage_collection,
+    ] {
+        timestamp_fields.push(TimestampField {
+            app_name: None,
+            collection_name: collection_name.clone(),
+            field: "timestamp",
+            timestamp_format: None,
+        });
+    }
+    let app_names = app_state
+        .apps()
+        .app_names()
+        .await
+        .map_err(|e| e.to_string())?;
+    timestamp_fields.extend(app_names.into_iter().map(|app_name| TimestampField {
+        collection_name: format!("{}{}", app_name, HISTORY_COLLECTION_SUFFIX),
+        app_name: Some(app_name),
+        field: "timestamp",
+        timestamp_format: None,
+    }));
+    Ok(timestamp_fields)
+}
+
+/// Converts the timestamps of a collection stored as strings into BSON dates, in `_id` order, and returns the number
+/// of converted documents. The strings that aren't timestamps, like the timestamp of the failed retrievals, are left
+/// as they are. With `dry_run`, the documents to convert are only counted.
+pub(crate) async fn migrate_timestamps(
+    app_state: &AppState,
+    timestamp_field: &TimestampField,
+    dry_run: bool,
+) -> Result<usize, String> {
+    let db = match &timestamp_field.app_name {
+        Some(app_name) => app_state
+            .app_db(app_name)
+            .await
+            .map_err(|e| e.to_string())?,
+        None => &app_state.db,
+    };
+    let TimestampField {
+        collection_name,
+        field,
+        timestamp_format,
+        ..
+    } = timestamp_field;
+    let field = *field;
+    let mut migrated = 0;
+    let mut last_id: Option<ObjectId> = None;
+    loop {
+        let mut filter = doc! {field: {"$type": "string"}};
+        if let Some(last_id) = last_id {
+            filter.insert("_id", doc! {"$gt": last_id});
+        }
+        let batch_pipeline = vec![
+            doc! {"$match": filter},
+            doc! {"$sort": {"_id": 1}},
+            doc! {"$limit": MIGRATION_BATCH_SIZE},
+            doc! {"$project": {"_id": {"$toString": "$_id"}, field: 1}},
+        ];
+        let batch = db
+            .aggregate(
+                collection_name,
+                batch_pipeline,
+                &app_state.options::<QueryOptions>(),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        let documents: Vec<(ObjectId, &serde_json::Value)> = batch
+            .iter()
+            .filter_map(|document| {
+                let id = document.get("_id").and_then(serde_json::Value::as_str)?;
+                Some((ObjectId::parse_str(id).ok()?, document))
+            })
+            .collect();
+        let Some((batch_last_id, _)) = documents.last() else {
+            return Ok(migrated);
+        };
+        last_id = Some(*batch_last_id);
+        for (id, document) in documents {
+            let timestamp = document
+                .get(field)
+                .and_then(|stored| serde_json::from_value::<Bson>(stored.clone()).ok())
+                .and_then(|stored| timestamp_from_bson(&stored, timestamp_format.as_deref()));
+            let Some(timestamp) = timestamp else {
+                debug!(
+                    message = format!(
+                        "'{}' of {} in '{}' is not a timestamp, left as is.",
+                        field, id, collection_name
+                    )
+                );
+                continue;
+            };
+            if !dry_run {
+                db.update_document(
+                    collection_name,
+                    doc! {"_id": id},
+                    doc! {field: to_bson_datetime(timestamp)},
+                )
+                .await
+                .map_err(|e| {
+                    format!(
+                        "failed to migrate '{}' of {} in '{}': {}",
+                        field, id, collection_name, e
+                    )
+                })?;
+            }
+            migrated += 1;
+        }
+    }
+}
+
+/// Migration 1: backfills the numeric value of the duration metrics stored as strings, see `metric_migration`.
+struct NormalizeDurationMetrics;
+
+#[async_trait]
+impl Migration for NormalizeDurationMetrics {
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn name(&self) -> &'static str {
+        "normalize_duration_metrics"
+    }
+
+    async fn up(&self, app_state: &Arc<AppState>) -> Result<usize, String> {
+        Ok(migrate_duration_metrics(app_state.clone()).await)
+    }
+}
+
+/// Migration 2: hashes the plain API keys of the apps onboarded before the internal API key mode was turned on.
+/// Left pending with API Gateway keys, which are stored in plain.
+struct HashApiKeys;
+
+#[async_trait]
+impl Migration for HashApiKeys {
+    fn version(&self) -> u32 {
+        2
+    }
+
+    fn name(&self) -> &'static str {
+        "hash_api_keys"
+    }
+
+    fn applies(&self, app_state: &AppState) -> bool {
+        app_state.api_key_options().is_internal()
+    }
+
+    async fn up(&self, app_state: &Arc<AppState>) -> Result<usize, String> {
+        let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+        let pipeline = vec![doc! {"$project": {"_id": 0, "app_name": 1, "api_key": 1}}];
+        let apps = app_state
+            .db
+            .aggregate(
+                collection_name,
+                pipeline,
+                &app_state.options::<QueryOptions>(),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut migrated = 0;
+        for app in &apps {
+            let (Some(app_name), Some(api_key)) = (
+                app.get("app_name").and_then(serde_json::Value::as_str),
+                app.get("api_key").and_then(serde_json::Value::as_str),
+            ) else {
+                continue;
+            };
+            if api_key.starts_with(API_KEY_HASH_PREFIX) {
+                continue;
+            }
+            app_state
+                .db
+                .update_document(
+                    collection_name,
+                    doc! {"app_name": app_name},
+                    doc! {"api_key": hash_api_key(api_key)},
+                )
+                .await
+                .map_err(|e| format!("failed to hash the API key of '{}': {}", app_name, e))?;
+            migrated += 1;
+        }
+        Ok(migrated)
+    }
+}
+
+/// Migration 3: stores the `schema_version` of the history documents written before it, so they can be queried by
+/// version.
+struct HistorySchemaVersion;
+
+#[async_trait]
+impl Migration for HistorySchemaVersion {
+    fn version(&self) -> u32 {
+        3
+    }
+
+    fn name(&self) -> &'static str {
+        "history_schema_version"
+    }
+
+    async fn up(&self, app_state: &Arc<AppState>) -> Result<usize, String> {
+        let app_names = app_state
+            .apps()
+            .app_names()
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut migrated = 0;
+        for app_name in app_names {
+            migrated += backfill_history_schema_version(app_state, &app_name).await?;
+        }
+        Ok(migrated)
+    }
+}
+
+/// Migration 4: converts the timestamps of the app, UI summary, token usage and history documents stored as strings
+/// of varying formats into BSON dates, see `timestamp`. `create_timestamp` is read with
+/// `application.timestamp_format`.
+struct TimestampsAsDates;
+
+#[async_trait]
+impl Migration for TimestampsAsDates {
+    fn version(&self) -> u32 {
+        4
+    }
+
+    fn name(&self) -> &'static str {
+        "timestamps_as_dates"
+    }
+
+    async fn up(&self, app_state: &Arc<AppState>) -> Result<usize, String> {
+        let mut migrated = 0;
+        for timestamp_field in timestamp_fields(app_state).await? {
+            migrated += migrate_timestamps(app_state, &timestamp_field, false).await?;
+        }
+        Ok(migrated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    struct TestMigration(u32);
+
+    #[async_trait]
+    impl Migration for TestMigration {
+        fn version(&self) -> u32 {
+            self.0
+        }
+
+        fn name(&self) -> &'static str {
+            "test_migration"
+        }
+
+        async fn up(&self, _app_state: &Arc<AppState>) -> Result<usize, String> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_success_migration_registry() {
+        let migrations = registry();
+        assert!(validate_registry(&migrations).is_ok());
+        let applied = BTreeSet::from([1]);
+        let pending: Vec<u32> = pending_migrations(&migrations, &applied)
+            .iter()
+            .map(|migration| migration.version())
+            .collect();
+        assert_eq!(pending, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn test_failure_validate_registry() {
+        let migrations: Vec<Box<dyn Migration>> =
+            vec![Box::new(TestMigration(2)), Box::new(TestMigration(2))];
+        assert!(matches!(
+            validate_registry(&migrations),
+            Err(MigrationError::Registry(_))
+        ));
+    }
+
+    #[test]
+    fn test_success_migration_options_from_settings() {
+        let options = MigrationOptions::from_settings(None);
+        assert!(options.enabled);
+        assert_eq!(options.collection, DEFAULT_MIGRATIONS_COLLECTION);
+        let settings = MigrationSettings {
+            enabled: Some(false),
+            collection: None,
+            lock_ttl_seconds: Some(60),
+        };
+        let options = MigrationOptions::from_settings(Some(&settings));
+        assert!(!options.enabled);
+        assert_eq!(options.lock_ttl, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_success_run_migrations() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Running twice leaves nothing to apply the second time
+            run_migrations(app_state.clone()).await.unwrap();
+            assert_eq!(run_migrations(app_state).await.unwrap(), 0);
+        });
+    }
+}
@@ -0,0 +1,204 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the cross-check of the knowledge node counts of an app, between the knowledge nodes stored in
+//! DocumentDB (what the dashboard shows) and the nodes indexed by the knowledge engine in the vector store (what the
+//! retrievals search).
+//! The engine reports its counts by source on `knowledge_engine.counts_endpoint` (`nodes/counts` by default), for a
+//! POST of the app name and of its vector store collections, as `{"sources": [{"source", "node_count"}]}`; the sources are keyed like the node
+//! statistics, `s3://bucket/prefix` for filestores and the table for datastores.
+//! Every source is reported with both counts and a status: `match`, `mismatch`, `missing_in_engine` (stored nodes
+//! never indexed, the usual cause of "the dashboard says 10k nodes but search finds nothing") or `missing_in_store`.
+//!
+
+use crate::service::state::AppState;
+use crate::service::vector_store::VectorStoreConfig;
+use axum::{http::StatusCode, Json};
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use tracing::{debug, error};
+use utoipa::ToSchema;
+
+/// Default endpoint of the node counts of the knowledge engine.
+pub const DEFAULT_COUNTS_ENDPOINT: &str = "nodes/counts";
+
+#[derive(Debug, thiserror::Error)]
+pub enum NodeCountCheckError {
+    #[error("Failed to fetch the node counts of '{app_name}' from the knowledge engine. Error: {message}")]
+    Engine { app_name: String, message: String },
+}
+
+impl From<NodeCountCheckError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: NodeCountCheckError) -> Self {
+        let error_message = e.to_string();
+        error!(message = error_message);
+        (
+            StatusCode::BAD_GATEWAY,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Node count of a source.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct SourceNodeCount {
+    pub source: String,
+    pub node_count: i64,
+}
+
+/// Node counts reported by the knowledge engine.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+struct EngineNodeCounts {
+    #[serde(default)]
+    sources: Vec<SourceNodeCount>,
+}
+
+/// Outcome of the cross-check of a source.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CountStatus {
+    Match,
+    Mismatch,
+    MissingInEngine,
+    MissingInStore,
+}
+
+/// Node counts of a source in DocumentDB and in the knowledge engine.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct SourceCountCheck {
+    pub source: String,
+    pub stored_count: i64,
+    pub engine_count: i64,
+    /// Stored count minus engine count.
+    pub difference: i64,
+    pub status: CountStatus,
+}
+
+/// Cross-checks the node counts of the sources, sorted by source.
+pub fn compare_counts(
+    stored: &[SourceNodeCount],
+    engine: &[SourceNodeCount],
+) -> Vec<SourceCountCheck> {
+    let mut counts: BTreeMap<&str, (i64, i64)> = BTreeMap::new();
+    for count in stored {
+        counts.entry(&count.source).or_default().0 += count.node_count;
+    }
+    for count in engine {
+        counts.entry(&count.source).or_default().1 += count.node_count;
+    }
+    counts
+        .into_iter()
+        .map(|(source, (stored_count, engine_count))| {
+            let status = match (stored_count, engine_count) {
+                (stored_count, engine_count) if stored_count == engine_count => CountStatus::Match,
+                (_, 0) => CountStatus::MissingInEngine,
+                (0, _) => CountStatus::MissingInStore,
+                _ => CountStatus::Mismatch,
+            };
+            SourceCountCheck {
+                source: source.to_string(),
+                stored_count,
+                engine_count,
+                difference: stored_count - engine_count,
+                status,
+            }
+        })
+        .collect()
+}
+
+/// Fetches the node counts of an app by source from the knowledge engine.
+pub async fn engine_node_counts(
+    app_state: &AppState,
+    app_name: &str,
+) -> Result<Vec<SourceNodeCount>, NodeCountCheckError> {
+    let engine_error = |message: String| NodeCountCheckError::Engine {
+        app_name: app_name.to_string(),
+        message,
+    };
+    let url = format!(
+        "{}/{}",
+        app_state.app_settings.tresleai_urls.core_service_url,
+        app_state
+            .app_settings
+            .knowledge_engine
+            .counts_endpoint
+            .as_deref()
+            .unwrap_or(DEFAULT_COUNTS_ENDPOINT)
+    );
+    debug!(
+        "Making a POST request for the node counts of '{}' to the core microservice at URL: {}",
+        app_name, url
+    );
+    let client = app_state.http_clients.for_url(&url);
+    let vector_store = VectorStoreConfig::from_settings(&app_state.app_settings, app_name);
+    let response = app_state
+        .http_clients
+        .send(
+            client
+                .post(url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(json!({"app_name": app_name, "vector_store": vector_store}).to_string()),
+        )
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| engine_error(e.to_string()))?
+        .text()
+        .await
+        .map_err(|e| engine_error(e.to_string()))?;
+    let counts: EngineNodeCounts =
+        serde_json::from_str(&response).map_err(|e| engine_error(e.to_string()))?;
+    Ok(counts.sources)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(source: &str, node_count: i64) -> SourceNodeCount {
+        SourceNodeCount {
+            source: source.to_string(),
+            node_count,
+        }
+    }
+
+    #[test]
+    fn test_success_compare_counts() {
+        let stored = [
+            count("s3://bucket/hr", 120),
+            count("s3://bucket/legal", 80),
+            count("db.employees", 10),
+        ];
+        let engine = [
+            count("s3://bucket/hr", 120),
+            count("db.employees", 7),
+            count("s3://bucket/old", 5),
+        ];
+        let checks = compare_counts(&stored, &engine);
+        let statuses: Vec<(&str, CountStatus, i64)> = checks
+            .iter()
+            .map(|check| (check.source.as_str(), check.status, check.difference))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("db.employees", CountStatus::Mismatch, 3),
+                ("s3://bucket/hr", CountStatus::Match, 0),
+                ("s3://bucket/legal", CountStatus::MissingInEngine, 80),
+                ("s3://bucket/old", CountStatus::MissingInStore, -5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_success_engine_node_counts_deserialize() {
+        let counts: EngineNodeCounts = serde_json::from_str(
+            r#"{"sources": [{"source": "s3://bucket/hr", "node_count": 120}], "total": 120}"#,
+        )
+        .unwrap();
+        assert_eq!(counts.sources, vec![count("s3://bucket/hr", 120)]);
+    }
+}
@@ -0,0 +1,326 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the notifications of the admin console, the platform events recorded by the subsystems in the
+//! notifications collection (`admin-notifications` by default) and read through the notifications endpoint:
+//! - `onboarding_completed` and `onboarding_failed`, when the background steps of an onboarding/update finish.
+//! - `deletion_finished`, when an app and its resources are deleted.
+//! - `error_spike`, when the dead retrieval sweeper expires at least `notifications.error_spike_threshold` (10 by
+//!   default) retrievals of an app in a sweep, at most once per app and hour.
+//! - `quota_warning`, when an end user of an app exhausts its rate limit, at most once per app and day.
+//!
+//! Recording a notification never fails the caller, a failure is logged. The read state is shared by the admins.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{NotificationSettings, TresleFacadeServiceSettings};
+use crate::service::history_upsert::is_duplicate_key_error;
+use crate::service::query_options::{AggregateExt, QueryError, QueryOptions};
+use crate::service::state::AppState;
+use chrono::{DateTime, Utc};
+use mongodb::bson::{doc, to_document, Document};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Default collection of the notifications.
+pub const DEFAULT_COLLECTION: &str = "admin-notifications";
+/// Number of unread notifications marked as read per batch.
+const MARK_READ_BATCH_SIZE: i64 = 500;
+/// Default number of dead retrievals of an app in a sweep notified as an error spike.
+const DEFAULT_ERROR_SPIKE_THRESHOLD: usize = 10;
+
+/// Kind of a notification.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    OnboardingCompleted,
+    OnboardingFailed,
+    DeletionFinished,
+    ErrorSpike,
+    QuotaWarning,
+    ApiKeyExpiring,
+    ApiKeyDeactivated,
+}
+
+impl NotificationKind {
+    /// Returns the name of the kind, as stored.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationKind::OnboardingCompleted => "onboarding_completed",
+            NotificationKind::OnboardingFailed => "onboarding_failed",
+            NotificationKind::DeletionFinished => "deletion_finished",
+            NotificationKind::ErrorSpike => "error_spike",
+            NotificationKind::QuotaWarning => "quota_warning",
+            NotificationKind::ApiKeyExpiring => "api_key_expiring",
+            NotificationKind::ApiKeyDeactivated => "api_key_deactivated",
+        }
+    }
+
+    /// Returns the severity of the notifications of the kind.
+    pub fn severity(&self) -> NotificationSeverity {
+        match self {
+            NotificationKind::OnboardingCompleted | NotificationKind::DeletionFinished => {
+                NotificationSeverity::Info
+            }
+            NotificationKind::QuotaWarning
+            | NotificationKind::ApiKeyExpiring
+            | NotificationKind::ApiKeyDeactivated => NotificationSeverity::Warning,
+            NotificationKind::OnboardingFailed | NotificationKind::ErrorSpike => {
+                NotificationSeverity::Error
+            }
+        }
+    }
+}
+
+/// Severity of a notification, for the icon of the admin console.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationSeverity {
+    Info,
+    Warning,
+    Error,
+}
+
+/// Notification of a platform event.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Notification {
+    pub id: String,
+    pub kind: NotificationKind,
+    pub severity: NotificationSeverity,
+    pub app_name: Option<String>,
+    pub message: String,
+    pub created_at: String,
+    pub read: bool,
+    pub read_at: Option<String>,
+}
+
+impl Notification {
+    /// Creates an unread notification of an event of an app.
+    pub fn new(kind: NotificationKind, app_name: &str, message: String) -> Self {
+        Notification {
+            id: Uuid::now_v7().to_string(),
+            kind,
+            severity: kind.severity(),
+            app_name: Some(app_name.to_string()),
+            message,
+            created_at: Utc::now().to_rfc3339(),
+            read: false,
+            read_at: None,
+        }
+    }
+
+    /// Keys the notification by its kind, app and `period`, so a recurring event is notified once per period.
+    pub fn once_per(mut self, period: &str) -> Self {
+        self.id = format!(
+            "{}:{}:{}",
+            self.kind.as_str(),
+            self.app_name.as_deref().unwrap_or_default(),
+            period
+        );
+        self
+    }
+}
+
+/// Returns the hour of a time, the period of the hourly notifications.
+pub fn hourly_period(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%dT%H").to_string()
+}
+
+/// Returns the day of a time, the period of the daily notifications.
+pub fn daily_period(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d").to_string()
+}
+
+/// Admin notification options: collection and error spike threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotificationOptions {
+    pub collection: String,
+    pub error_spike_threshold: usize,
+}
+
+impl SettingsOptions for NotificationOptions {
+    type Settings = NotificationSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&NotificationSettings> {
+        settings.notifications.as_ref()
+    }
+
+    fn from_settings(settings: Option<&NotificationSettings>) -> Self {
+        NotificationOptions {
+            collection: settings
+                .and_then(|settings| settings.collection.clone())
+                .unwrap_or_else(|| DEFAULT_COLLECTION.to_string()),
+            error_spike_threshold: settings
+                .and_then(|settings| settings.error_spike_threshold)
+                .unwrap_or(DEFAULT_ERROR_SPIKE_THRESHOLD),
+        }
+    }
+}
+
+/// Stores a notification. A notification already recorded for its period is skipped, a failure is logged.
+pub async fn record_notification(app_state: &AppState, notification: Notification) {
+    let mut document = match to_document(&notification) {
+        Ok(document) => document,
+        Err(e) => {
+            error!(message = format!("Failed to serialize the notification. Error: {}", e));
+            return;
+        }
+    };
+    document.insert("_id", &notification.id);
+    if let Err(e) = app_state
+        .db
+        .create_document(
+            &app_state.options::<NotificationOptions>().collection,
+            document,
+        )
+        .await
+    {
+        let message = e.to_string();
+        if is_duplicate_key_error(&message) {
+            debug!(
+                message = format!(
+                    "Notification '{}' already recorded for its period.",
+                    notification.id
+                )
+            );
+        } else {
+            error!(
+                app_name = notification.app_name.as_deref().unwrap_or_default(),
+                message = format!(
+                    "Failed to record the {} notification. Error: {}",
+                    notification.kind.as_str(),
+                    message
+                )
+            );
+        }
+    }
+}
+
+/// Filter of the notifications feed.
+pub fn notifications_filter(
+    unread_only: bool,
+    app_name: Option<&str>,
+    kind: Option<NotificationKind>,
+) -> Document {
+    let mut filter = Document::new();
+    if unread_only {
+        filter.insert("read", false);
+    }
+    if let Some(app_name) = app_name {
+        filter.insert("app_name", app_name);
+    }
+    if let Some(kind) = kind {
+        filter.insert("kind", kind.as_str());
+    }
+    filter
+}
+
+/// Marks a notification as read. Returns false if there is no such notification.
+pub async fn mark_read(app_state: &AppState, id: &str) -> Result<bool, String> {
+    let result = app_state
+        .db
+        .update_document(
+            &app_state.options::<NotificationOptions>().collection,
+            doc! {"_id": id},
+            doc! {"read": true, "read_at": Utc::now().to_rfc3339()},
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(result
+        .get("matchedCount")
+        .and_then(serde_json::Value::as_u64)
+        .unwrap_or_default()
+        > 0)
+}
+
+/// Marks the unread notifications as read, of an app or of all the apps. Returns the number of notifications marked.
+pub async fn mark_all_read(
+    app_state: &AppState,
+    app_name: Option<&str>,
+) -> Result<usize, QueryError> {
+    let collection = app_state.options::<NotificationOptions>().collection;
+    let read_at = Utc::now().to_rfc3339();
+    let mut marked = 0;
+    loop {
+        let pipeline = vec![
+            doc! {"$match": notifications_filter(true, app_name, None)},
+            doc! {"$limit": MARK_READ_BATCH_SIZE},
+            doc! {"$project": {"_id": 1}},
+        ];
+        let unread = app_state
+            .db
+            .aggregate(&collection, pipeline, &app_state.options::<QueryOptions>())
+            .await?;
+        let mut batch_marked = 0;
+        for id in unread
+            .iter()
+            .filter_map(|notification| notification["_id"].as_str())
+        {
+            match app_state
+                .db
+                .update_document(
+                    &collection,
+                    doc! {"_id": id},
+                    doc! {"read": true, "read_at": &read_at},
+                )
+                .await
+            {
+                Ok(_) => batch_marked += 1,
+                Err(e) => error!(
+                    message = format!("Failed to mark notification '{}' as read. Error: {}", id, e)
+                ),
+            }
+        }
+        marked += batch_marked;
+        // Stop on the last batch, or if nothing in the batch could be marked, otherwise the same batch is fetched again
+        if batch_marked == 0 || (unread.len() as i64) < MARK_READ_BATCH_SIZE {
+            break;
+        }
+    }
+    Ok(marked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_success_notification_once_per() {
+        let time = Utc.with_ymd_and_hms(2024, 7, 26, 14, 35, 0).unwrap();
+        let notification = Notification::new(
+            NotificationKind::ErrorSpike,
+            "app100",
+            "12 retrievals timed out.".to_string(),
+        );
+        assert_eq!(notification.severity, NotificationSeverity::Error);
+        assert!(!notification.read);
+
+        let notification = notification.once_per(&hourly_period(time));
+        assert_eq!(notification.id, "error_spike:app100:2024-07-26T14");
+        let notification = Notification::new(
+            NotificationKind::QuotaWarning,
+            "app100",
+            "Rate limit exhausted.".to_string(),
+        )
+        .once_per(&daily_period(time));
+        assert_eq!(notification.id, "quota_warning:app100:2024-07-26");
+    }
+
+    #[test]
+    fn test_success_notifications_filter() {
+        assert_eq!(notifications_filter(false, None, None), doc! {});
+        assert_eq!(
+            notifications_filter(
+                true,
+                Some("app100"),
+                Some(NotificationKind::DeletionFinished)
+            ),
+            doc! {"read": false, "app_name": "app100", "kind": "deletion_finished"}
+        );
+    }
+}
@@ -0,0 +1,566 @@
+/*
+ * Created Date:  Jul 19, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the object stores read by the connectivity checks of the filestore datasources.
+//! `S3ObjectStore` reads the buckets of AWS S3, switching to a client of the region of each bucket.
+//! `LocalObjectStore` reads a local directory holding a folder per bucket, so `s3://bucket/folder/file.pdf` is the
+//! file `{root}/bucket/folder/file.pdf`. It stands in for S3 in the local development mode.
+//! The stores also write, sign download links of and delete the artifacts of the apps, such as the full responses of
+//! the oversized answers. The local store links the files themselves.
+//!
+
+use crate::service::state::AppState;
+use async_trait::async_trait;
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::{BehaviorVersion, Region};
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Region of the buckets without location constraint.
+const DEFAULT_BUCKET_REGION: &str = "us-east-1";
+
+/// Page of the objects listed under a prefix.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ObjectPage {
+    /// Keys and sizes of the objects.
+    pub objects: Vec<(String, u64)>,
+    /// Token of the next page, `None` on the last page.
+    pub next_continuation_token: Option<String>,
+}
+
+/// Store of the objects of the filestore datasources.
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    /// Checks the access to a bucket. Returns the store serving the bucket.
+    async fn connect(&self, bucket: &str) -> Result<Arc<dyn ObjectStore>, String>;
+
+    /// Lists a page of the objects of a bucket under a prefix.
+    async fn list_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> Result<ObjectPage, String>;
+
+    /// Returns the size of an object.
+    async fn object_size(&self, bucket: &str, key: &str) -> Result<u64, String>;
+
+    /// Returns the content of an object.
+    async fn read_object(&self, bucket: &str, key: &str) -> Result<Vec<u8>, String>;
+
+    /// Returns the first `length` bytes of an object, or its whole content if shorter.
+    async fn read_object_head(
+        &self,
+        bucket: &str,
+        key: &str,
+        length: u64,
+    ) -> Result<Vec<u8>, String>;
+
+    /// Returns the `length` bytes of an object from `start`, fewer if the object ends before.
+    async fn read_object_range(
+        &self,
+        bucket: &str,
+        key: &str,
+        start: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, String>;
+
+    /// Writes an object, replacing the object of the same key.
+    async fn write_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        content: Vec<u8>,
+        content_type: &str,
+    ) -> Result<(), String>;
+
+    /// Returns a download link of an object, valid for `expires_in`.
+    async fn presign_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, String>;
+
+    /// Deletes an object. Deleting a missing object succeeds.
+    async fn delete_object(&self, bucket: &str, key: &str) -> Result<(), String>;
+}
+
+/// Returns the object store of the connectivity checks, the local directory in the local development mode.
+pub async fn object_store(app_state: &AppState) -> Arc<dyn ObjectStore> {
+    match &app_state.local_dev {
+        Some(local_dev) => local_dev.object_store.clone(),
+        None => Arc::new(S3ObjectStore::new(None).await),
+    }
+}
+
+/// Reads the buckets of AWS S3.
+#[derive(Debug, Clone)]
+pub struct S3ObjectStore {
+    pub client: Arc<aws_sdk_s3::Client>,
+}
+
+impl S3ObjectStore {
+    /// Creates an S3 client with the specified region. If region is not provided, it uses the default region.
+    pub async fn new(region: Option<String>) -> Self {
+        let region_provider = match region {
+            Some(region) => RegionProviderChain::first_try(Region::new(region)),
+            None => RegionProviderChain::default_provider(),
+        };
+        let s3_config = aws_config::defaults(BehaviorVersion::latest())
+            .region(region_provider)
+            .load()
+            .await;
+        S3ObjectStore {
+            client: Arc::new(aws_sdk_s3::Client::new(&s3_config)),
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for S3ObjectStore {
+    async fn connect(&self, bucket: &str) -> Result<Arc<dyn ObjectStore>, String> {
+        // Check the connectivity by fetching region of S3 bucket
+        let response = self
+            .client
+            .get_bucket_location()
+            .bucket(bucket)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let region = match &response.location_constraint {
+            Some(region) if !region.to_string().is_empty() => region.to_string(),
+            _ => DEFAULT_BUCKET_REGION.to_string(),
+        };
+
+        // Create a new S3 client if the region of the bucket is different from the region of the client
+        if self
+            .client
+            .config()
+            .region()
+            .unwrap_or(&Region::new(DEFAULT_BUCKET_REGION))
+            != &Region::new(region.clone())
+        {
+            Ok(Arc::new(S3ObjectStore::new(Some(region)).await))
+        } else {
+            Ok(Arc::new(self.clone()))
+        }
+    }
+
+    async fn list_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> Result<ObjectPage, String> {
+        let output = self
+            .client
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .set_continuation_token(continuation_token)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(ObjectPage {
+            objects: output
+                .contents
+                .unwrap_or_default()
+                .into_iter()
+                .map(|object| {
+                    (
+                        object.key.unwrap_or_default(),
+                        object.size.unwrap_or(0) as u64,
+                    )
+                })
+                .collect(),
+            next_continuation_token: output.next_continuation_token,
+        })
+    }
+
+    async fn object_size(&self, bucket: &str, key: &str) -> Result<u64, String> {
+        let output = self
+            .client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(output.content_length.unwrap_or(0) as u64)
+    }
+
+    async fn read_object(&self, bucket: &str, key: &str) -> Result<Vec<u8>, String> {
+        let output = self
+            .client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let bytes = output.body.collect().await.map_err(|e| e.to_string())?;
+        Ok(bytes.into_bytes().to_vec())
+    }
+
+    async fn read_object_head(
+        &self,
+        bucket: &str,
+        key: &str,
+        length: u64,
+    ) -> Result<Vec<u8>, String> {
+        let output = self
+            .client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .range(format!("bytes=0-{}", length.saturating_sub(1)))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let bytes = output.body.collect().await.map_err(|e| e.to_string())?;
+        Ok(bytes.into_bytes().to_vec())
+    }
+
+    async fn read_object_range(
+        &self,
+        bucket: &str,
+        key: &str,
+        start: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, String> {
+        let output = self
+            .client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .range(format!(
+                "bytes={}-{}",
+                start,
+                (start + length).saturating_sub(1)
+            ))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let bytes = output.body.collect().await.map_err(|e| e.to_string())?;
+        Ok(bytes.into_bytes().to_vec())
+    }
+
+    async fn write_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        content: Vec<u8>,
+        content_type: &str,
+    ) -> Result<(), String> {
+        self.client
+            .put_object()
+            .bucket(bucket)
+            .key(key)
+            .content_type(content_type)
+            .body(aws_sdk_s3::primitives::ByteStream::from(content))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    async fn presign_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, String> {
+        let presigning_config = aws_sdk_s3::presigning::PresigningConfig::expires_in(expires_in)
+            .map_err(|e| e.to_string())?;
+        let request = self
+            .client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .presigned(presigning_config)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(request.uri().to_string())
+    }
+
+    async fn delete_object(&self, bucket: &str, key: &str) -> Result<(), String> {
+        self.client
+            .delete_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+/// Reads the buckets from a local directory, a folder per bucket. The objects are listed in a single page.
+#[derive(Debug, Clone)]
+pub struct LocalObjectStore {
+    pub root: PathBuf,
+}
+
+impl LocalObjectStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalObjectStore { root: root.into() }
+    }
+
+    /// Returns the path of an object, refusing the keys leaving the folder of the bucket.
+    fn path(&self, bucket: &str, key: &str) -> Result<PathBuf, String> {
+        let key = Path::new(key);
+        if Path::new(bucket).components().count() != 1
+            || key
+                .components()
+                .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            return Err(format!(
+                "Invalid object '{}' in bucket '{}'.",
+                key.display(),
+                bucket
+            ));
+        }
+        Ok(self.root.join(bucket).join(key))
+    }
+}
+
+/// Collects the files under a directory, with their keys relative to the folder of the bucket.
+fn collect_files(
+    bucket_dir: &Path,
+    dir: &Path,
+    files: &mut Vec<(String, u64)>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            collect_files(bucket_dir, &entry.path(), files)?;
+        } else if let Ok(relative) = entry.path().strip_prefix(bucket_dir) {
+            let key = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.push((key, metadata.len()));
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl ObjectStore for LocalObjectStore {
+    async fn connect(&self, bucket: &str) -> Result<Arc<dyn ObjectStore>, String> {
+        let bucket_dir = self.path(bucket, "")?;
+        if !bucket_dir.is_dir() {
+            return Err(format!(
+                "No local bucket '{}' in '{}'.",
+                bucket,
+                self.root.display()
+            ));
+        }
+        Ok(Arc::new(self.clone()))
+    }
+
+    async fn list_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        _continuation_token: Option<String>,
+    ) -> Result<ObjectPage, String> {
+        let bucket_dir = self.path(bucket, "")?;
+        let mut objects = Vec::new();
+        collect_files(&bucket_dir, &bucket_dir, &mut objects).map_err(|e| e.to_string())?;
+        objects.retain(|(key, _)| key.starts_with(prefix));
+        objects.sort();
+        Ok(ObjectPage {
+            objects,
+            next_continuation_token: None,
+        })
+    }
+
+    async fn object_size(&self, bucket: &str, key: &str) -> Result<u64, String> {
+        let metadata = tokio::fs::metadata(self.path(bucket, key)?)
+            .await
+            .map_err(|e| e.to_string())?;
+        if !metadata.is_file() {
+            return Err(format!("No object '{}' in bucket '{}'.", key, bucket));
+        }
+        Ok(metadata.len())
+    }
+
+    async fn read_object(&self, bucket: &str, key: &str) -> Result<Vec<u8>, String> {
+        tokio::fs::read(self.path(bucket, key)?)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn read_object_head(
+        &self,
+        bucket: &str,
+        key: &str,
+        length: u64,
+    ) -> Result<Vec<u8>, String> {
+        let file = tokio::fs::File::open(self.path(bucket, key)?)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut head = Vec::new();
+        file.take(length)
+            .read_to_end(&mut head)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(head)
+    }
+
+    async fn read_object_range(
+        &self,
+        bucket: &str,
+        key: &str,
+        start: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, String> {
+        let mut file = tokio::fs::File::open(self.path(bucket, key)?)
+            .await
+            .map_err(|e| e.to_string())?;
+        file.seek(SeekFrom::Start(start))
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut range = Vec::new();
+        file.take(length)
+            .read_to_end(&mut range)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(range)
+    }
+
+    async fn write_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        content: Vec<u8>,
+        _content_type: &str,
+    ) -> Result<(), String> {
+        let path = self.path(bucket, key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        tokio::fs::write(path, content)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn presign_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        _expires_in: Duration,
+    ) -> Result<String, String> {
+        Ok(format!("file://{}", self.path(bucket, key)?.display()))
+    }
+
+    async fn delete_object(&self, bucket: &str, key: &str) -> Result<(), String> {
+        match tokio::fs::remove_file(self.path(bucket, key)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_local_object_store() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let root = tempdir().unwrap();
+            std::fs::create_dir_all(root.path().join("bucket/folder/sub")).unwrap();
+            std::fs::write(root.path().join("bucket/folder/a.pdf"), b"abc").unwrap();
+            std::fs::write(root.path().join("bucket/folder/sub/b.txt"), b"de").unwrap();
+            std::fs::write(root.path().join("bucket/c.csv"), b"f").unwrap();
+            let store = LocalObjectStore::new(root.path());
+
+            let bucket = store.connect("bucket").await.unwrap();
+            assert!(store.connect("missing-bucket").await.is_err());
+
+            let page = bucket
+                .list_objects("bucket", "folder/", None)
+                .await
+                .unwrap();
+            assert_eq!(
+                page.objects,
+                vec![
+                    ("folder/a.pdf".to_string(), 3),
+                    ("folder/sub/b.txt".to_string(), 2)
+                ]
+            );
+            assert_eq!(page.next_continuation_token, None);
+
+            assert_eq!(bucket.object_size("bucket", "folder/a.pdf").await, Ok(3));
+            assert!(bucket.object_size("bucket", "folder").await.is_err());
+            assert_eq!(
+                bucket.read_object("bucket", "c.csv").await,
+                Ok(b"f".to_vec())
+            );
+            assert_eq!(
+                bucket.read_object_head("bucket", "folder/a.pdf", 2).await,
+                Ok(b"ab".to_vec())
+            );
+            assert_eq!(
+                bucket.read_object_head("bucket", "c.csv", 8).await,
+                Ok(b"f".to_vec())
+            );
+            assert_eq!(
+                bucket
+                    .read_object_range("bucket", "folder/a.pdf", 1, 8)
+                    .await,
+                Ok(b"bc".to_vec())
+            );
+            assert!(bucket
+                .read_object("bucket", "../bucket/c.csv")
+                .await
+                .is_err());
+
+            bucket
+                .write_object("bucket", "artifacts/d.txt", b"gh".to_vec(), "text/plain")
+                .await
+                .unwrap();
+            assert_eq!(
+                bucket.read_object("bucket", "artifacts/d.txt").await,
+                Ok(b"gh".to_vec())
+            );
+            assert!(bucket
+                .write_object("bucket", "../e.txt", b"i".to_vec(), "text/plain")
+                .await
+                .is_err());
+            assert!(bucket
+                .presign_object("bucket", "artifacts/d.txt", Duration::from_secs(60))
+                .await
+                .unwrap()
+                .ends_with("bucket/artifacts/d.txt"));
+            assert_eq!(
+                bucket.delete_object("bucket", "artifacts/d.txt").await,
+                Ok(())
+            );
+            assert!(bucket
+                .read_object("bucket", "artifacts/d.txt")
+                .await
+                .is_err());
+            assert_eq!(
+                bucket.delete_object("bucket", "artifacts/d.txt").await,
+                Ok(())
+            );
+        });
+    }
+}
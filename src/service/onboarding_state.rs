@@ -0,0 +1,188 @@
+/*
+ * Created Date:  Jul 17, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the onboarding state of an app, the progress of the background steps of an onboarding or
+//! update request: `validating`, `provisioning`, `notifying`, then `complete`, or `failed_at_<step>` when a step
+//! fails. The state is stored on the app document before each step runs, so a failed onboarding records the step
+//! to resume from, and the retry endpoint resumes it instead of leaving a half-created app.
+//! The state is distinct from the `onboarding_status` of the app, which tracks the ingestion of its datasources.
+//! Apps onboarded before the states were introduced carry no state and are complete.
+//!
+
+use crate::admin_ui_api::schema::UpdateResponse;
+use crate::service::state::AppState;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use chrono::Utc;
+use mongodb::bson::{doc, to_bson};
+use serde::{Deserialize, Serialize};
+use tracing::{error, instrument};
+use utoipa::ToSchema;
+
+/// Name of the field of the app document holding the onboarding state of the app.
+pub const ONBOARDING_STATE_FIELD: &str = "onboarding_state";
+
+/// Background step of an onboarding/update request, in execution order.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OnboardingStep {
+    /// Checks the datasources: change detection of an update, connectivity check of a retry.
+    Validating,
+    /// Creates the Kafka topic of the app and writes the app document.
+    Provisioning,
+    /// Captures the sample rows of the tables and publishes the datasources to Kafka.
+    Notifying,
+}
+
+/// Onboarding state of an app.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OnboardingState {
+    Validating,
+    Provisioning,
+    Notifying,
+    Complete,
+    FailedAtValidating,
+    FailedAtProvisioning,
+    FailedAtNotifying,
+}
+
+impl OnboardingState {
+    /// Returns the state of a running step.
+    pub fn running(step: OnboardingStep) -> Self {
+        match step {
+            OnboardingStep::Validating => OnboardingState::Validating,
+            OnboardingStep::Provisioning => OnboardingState::Provisioning,
+            OnboardingStep::Notifying => OnboardingState::Notifying,
+        }
+    }
+
+    /// Returns the state of a failed step.
+    pub fn failed(step: OnboardingStep) -> Self {
+        match step {
+            OnboardingStep::Validating => OnboardingState::FailedAtValidating,
+            OnboardingStep::Provisioning => OnboardingState::FailedAtProvisioning,
+            OnboardingStep::Notifying => OnboardingState::FailedAtNotifying,
+        }
+    }
+
+    /// Returns the step running or failed, `None` once the onboarding is complete.
+    pub fn step(&self) -> Option<OnboardingStep> {
+        match self {
+            OnboardingState::Validating | OnboardingState::FailedAtValidating => {
+                Some(OnboardingStep::Validating)
+            }
+            OnboardingState::Provisioning | OnboardingState::FailedAtProvisioning => {
+                Some(OnboardingStep::Provisioning)
+            }
+            OnboardingState::Notifying | OnboardingState::FailedAtNotifying => {
+                Some(OnboardingStep::Notifying)
+            }
+            OnboardingState::Complete => None,
+        }
+    }
+
+    /// Returns the step to resume from, `None` unless the onboarding failed.
+    pub fn failed_step(&self) -> Option<OnboardingStep> {
+        match self {
+            OnboardingState::FailedAtValidating => Some(OnboardingStep::Validating),
+            OnboardingState::FailedAtProvisioning => Some(OnboardingStep::Provisioning),
+            OnboardingState::FailedAtNotifying => Some(OnboardingStep::Notifying),
+            _ => None,
+        }
+    }
+}
+
+/// Onboarding state of an app, stored on the app document.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct OnboardingProgress {
+    pub state: OnboardingState,
+    /// Timestamp (RFC 3339) of the last state change.
+    pub updated_at: String,
+}
+
+impl OnboardingProgress {
+    pub fn new(state: OnboardingState) -> Self {
+        OnboardingProgress {
+            state,
+            updated_at: Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+/// Stores the onboarding state of an app. Returns the error message if the state could not be stored.
+#[instrument(skip_all)]
+pub async fn record_onboarding_state(
+    app_state: &AppState,
+    app_name: &str,
+    state: OnboardingState,
+) -> Result<(), String> {
+    let progress = to_bson(&OnboardingProgress::new(state))
+        .map_err(|e| format!("Failed to serialize onboarding state to BSON. Error: {}", e))?;
+    let filter = doc! {"app_name": app_name};
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_app_collection;
+    let result = app_state
+        .db
+        .update_document(
+            collection_name,
+            filter,
+            doc! {ONBOARDING_STATE_FIELD: progress},
+        )
+        .await
+        .map_err(ErrorInterceptor::from)
+        .map_err(|e| {
+            format!(
+                "Failed to store onboarding state of app '{}'. Error: {}",
+                app_name, e
+            )
+        })
+        .and_then(|json_result| {
+            serde_json::from_value::<UpdateResponse>(json_result)
+                .map_err(|e| format!("Failed to deserialize update response. Error: {:?}", e))
+        })
+        .and_then(|result| match result.matchedCount {
+            0 => Err(format!("No app found with name '{}'.", app_name)),
+            _ => Ok(()),
+        });
+    if let Err(error_message) = &result {
+        error!(
+            app_name = app_name,
+            ext_message = error_message,
+            message = error_message
+        );
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_onboarding_state_serialization() {
+        let progress = OnboardingProgress::new(OnboardingState::FailedAtProvisioning);
+        let serialized = serde_json::to_value(&progress).unwrap();
+        assert_eq!(serialized["state"], "failed_at_provisioning");
+
+        let deserialized: OnboardingProgress = serde_json::from_value(serialized).unwrap();
+        assert_eq!(deserialized, progress);
+    }
+
+    #[test]
+    fn test_success_onboarding_state_steps() {
+        for step in [
+            OnboardingStep::Validating,
+            OnboardingStep::Provisioning,
+            OnboardingStep::Notifying,
+        ] {
+            assert_eq!(OnboardingState::failed(step).failed_step(), Some(step));
+            assert_eq!(OnboardingState::running(step).failed_step(), None);
+            assert_eq!(OnboardingState::running(step).step(), Some(step));
+            assert_eq!(OnboardingState::failed(step).step(), Some(step));
+        }
+        assert_eq!(OnboardingState::Complete.failed_step(), None);
+        assert_eq!(OnboardingState::Complete.step(), None);
+        assert!(OnboardingStep::Validating < OnboardingStep::Notifying);
+    }
+}
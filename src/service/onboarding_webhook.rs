@@ -0,0 +1,461 @@
+/*
+ * Created Date:  Jul 22, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the onboarding webhook: when the background steps of an onboarding/update request (or of
+//! its retry) end, the outcome is POSTed to the `notification_url` of the app, so the provisioning pipelines do not
+//! poll the app for its onboarding state.
+//! The payload is signed with the `webhooks.signing_secret` of the settings, without which nothing is delivered: the
+//! `x-tresleai-timestamp` header holds the Unix time of the attempt, and the `x-tresleai-signature` header `sha256=`
+//! and the hex HMAC-SHA256 of `<timestamp>.<body>`, so a receiver can reject replayed deliveries.
+//! The notification URLs must be https URLs of public hosts. The deliveries go through the webhook client, which
+//! never presents the client certificate of the facade, never follows redirects and only connects to the public
+//! addresses of a host: loopback, private, link-local (e.g. the instance metadata endpoint) and other special
+//! addresses are refused when the host is resolved, so a host can't be rebound to them between the validation and
+//! the delivery. A delivery failing with an error or a non 2xx status code is retried with an exponential backoff,
+//! and every attempt is recorded in the delivery collection.
+//! The other notifications of the apps, e.g. the expiry warnings of their API keys, are delivered the same way.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{TresleFacadeServiceSettings, WebhookSettings};
+use crate::service::onboarding_state::OnboardingState;
+use crate::service::state::AppState;
+use axum::{http::StatusCode, Json};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use mongodb::bson::to_document;
+use secrecy::ExposeSecret;
+use serde::Serialize;
+use serde_json::json;
+use sha2::Sha256;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tracing::{error, info, instrument};
+
+/// Header of the signature of the payload.
+pub const SIGNATURE_HEADER: &str = "x-tresleai-signature";
+/// Header of the Unix time the payload was signed at.
+pub const TIMESTAMP_HEADER: &str = "x-tresleai-timestamp";
+/// Prefix of the signature of the payload.
+pub const SIGNATURE_PREFIX: &str = "sha256=";
+/// Default number of delivery attempts of a notification.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+/// Default delay before the first retry, doubled for each further retry.
+pub const DEFAULT_RETRY_BACKOFF_MS: u64 = 1_000;
+/// Default timeout of a delivery attempt.
+pub const DEFAULT_TIMEOUT_SECONDS: u64 = 10;
+/// Default collection of the delivery attempts.
+pub const DEFAULT_DELIVERY_COLLECTION: &str = "webhook_deliveries";
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebhookError {
+    #[error("Invalid notification_url '{0}'. An https URL of a public host is expected.")]
+    InvalidUrl(String),
+}
+
+impl From<WebhookError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: WebhookError) -> Self {
+        let error_message = e.to_string();
+        error!(ext_message = error_message, message = error_message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Onboarding webhook options: signing secret, retries, timeout and delivery collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookOptions {
+    pub signing_secret: Option<String>,
+    pub max_attempts: u32,
+    pub retry_backoff: Duration,
+    pub timeout: Duration,
+    pub delivery_collection: String,
+}
+
+impl Default for WebhookOptions {
+    fn default() -> Self {
+        WebhookOptions {
+            signing_secret: None,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            retry_backoff: Duration::from_millis(DEFAULT_RETRY_BACKOFF_MS),
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECONDS),
+            delivery_collection: DEFAULT_DELIVERY_COLLECTION.to_string(),
+        }
+    }
+}
+
+impl SettingsOptions for WebhookOptions {
+    type Settings = WebhookSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&WebhookSettings> {
+        settings.webhooks.as_ref()
+    }
+
+    fn from_settings(settings: Option<&WebhookSettings>) -> Self {
+        let defaults = WebhookOptions::default();
+        let Some(settings) = settings else {
+            return defaults;
+        };
+        WebhookOptions {
+            signing_secret: settings
+                .signing_secret
+                .as_ref()
+                .map(|secret| secret.expose_secret().clone()),
+            max_attempts: settings
+                .max_attempts
+                .unwrap_or(defaults.max_attempts)
+                .max(1),
+            retry_backoff: settings
+                .retry_backoff_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.retry_backoff),
+            timeout: settings
+                .timeout_seconds
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.timeout),
+            delivery_collection: settings
+                .delivery_collection
+                .clone()
+                .unwrap_or(defaults.delivery_collection),
+        }
+    }
+}
+
+/// Outcome of the background steps of an onboarding/update request, POSTed to the notification URL.
+#[derive(Debug, Clone, Serialize)]
+pub struct OnboardingNotification {
+    pub app_name: String,
+    pub app_id: String,
+    pub task_id: String,
+    pub is_update: bool,
+    /// `complete`, or `failed_at_<step>`.
+    pub state: OnboardingState,
+    pub timestamp: String,
+}
+
+/// Delivery attempt of a notification, recorded in the delivery collection.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryAttempt {
+    pub app_name: String,
+    pub task_id: String,
+    pub url: String,
+    pub attempt: u32,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub delivered: bool,
+    pub timestamp: String,
+}
+
+/// Checks that a notification URL is an https URL, whose host is not a special address. The addresses of the host
+/// names are checked when they are resolved for a delivery.
+pub fn validate_notification_url(notification_url: Option<&str>) -> Result<(), WebhookError> {
+    let Some(notification_url) = notification_url else {
+        return Ok(());
+    };
+    let public_host = |host: url::Host<&str>| match host {
+        url::Host::Domain(_) => true,
+        url::Host::Ipv4(ip) => is_public_ip(IpAddr::V4(ip)),
+        url::Host::Ipv6(ip) => is_public_ip(IpAddr::V6(ip)),
+    };
+    match url::Url::parse(notification_url) {
+        Ok(url) if url.scheme() == "https" && url.host().is_some_and(public_host) => Ok(()),
+        _ => Err(WebhookError::InvalidUrl(notification_url.to_string())),
+    }
+}
+
+/// Returns true if an address is a public unicast address, which a notification may be delivered to. The loopback,
+/// private, shared, link-local, unique local, documentation, unspecified, broadcast and multicast addresses are not.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                || (first == 100 && (64..128).contains(&second))
+                || first >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(ip));
+            }
+            let first_segment = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (first_segment & 0xfe00) == 0xfc00
+                || (first_segment & 0xffc0) == 0xfe80
+                || first_segment == 0x2001 && ip.segments()[1] == 0x0db8)
+        }
+    }
+}
+
+/// Resolver of the webhook client, only returning the public addresses of a host.
+#[derive(Debug, Default)]
+pub struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("'{}' does not resolve to a public address.", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Returns the signature of a payload signed at `timestamp`: `sha256=` and the hex HMAC-SHA256 of
+/// `<timestamp>.<payload>`.
+pub fn sign(secret: &str, timestamp: i64, payload: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(payload);
+    format!("{}{:x}", SIGNATURE_PREFIX, mac.finalize().into_bytes())
+}
+
+/// Delivers an onboarding notification to its URL, retrying the failed attempts. Failures are logged and never fail
+/// the caller.
+#[instrument(skip_all)]
+pub async fn notify_onboarding(
+    app_state: &AppState,
+    notification_url: &str,
+    notification: &OnboardingNotification,
+) {
+    deliver_webhook(
+        app_state,
+        notification_url,
+        &notification.app_name,
+        &notification.task_id,
+        "onboarding notification",
+        notification,
+    )
+    .await;
+}
+
+/// Delivers a notification of an app to its URL, signed and retrying the failed attempts. `description` names the
+/// notification in the logs. Returns whether it was delivered; failures are logged and never fail the caller.
+#[instrument(skip_all)]
+pub async fn deliver_webhook<T: Serialize>(
+    app_state: &AppState,
+    notification_url: &str,
+    app_name: &str,
+    task_id: &str,
+    description: &str,
+    notification: &T,
+) -> bool {
+    let options = app_state.options::<WebhookOptions>();
+    let Some(signing_secret) = options.signing_secret.as_deref() else {
+        let error_message = format!(
+            "The {} is not delivered: webhooks.signing_secret is not set.",
+            description
+        );
+        error!(
+            app_name = app_name,
+            task_id = task_id,
+            ext_message = error_message,
+            message = error_message
+        );
+        return false;
+    };
+    // The URLs stored before the https and public host checks are refused too
+    if let Err(e) = validate_notification_url(Some(notification_url)) {
+        let error_message = format!("The {} is not delivered: {}", description, e);
+        error!(
+            app_name = app_name,
+            task_id = task_id,
+            ext_message = error_message,
+            message = error_message
+        );
+        return false;
+    }
+    let payload = match serde_json::to_vec(notification) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!(
+                app_name = app_name,
+                task_id = task_id,
+                message = format!("Failed to serialize the {}. Error: {}", description, e)
+            );
+            return false;
+        }
+    };
+
+    for attempt in 1..=options.max_attempts {
+        // Every attempt is signed at its own time
+        let timestamp = Utc::now().timestamp();
+        let request = app_state
+            .http_clients
+            .webhook
+            .post(notification_url)
+            .timeout(options.timeout)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(SIGNATURE_HEADER, sign(signing_secret, timestamp, &payload))
+            .body(payload.clone());
+        let (status_code, delivery_error) = match request.send().await {
+            Ok(response) if response.status().is_success() => (Some(response.status()), None),
+            Ok(response) => (
+                Some(response.status()),
+                Some(format!("Unexpected status code {}.", response.status())),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        let delivered = delivery_error.is_none();
+        record_delivery_attempt(
+            app_state,
+            &options,
+            DeliveryAttempt {
+                app_name: app_name.to_string(),
+                task_id: task_id.to_string(),
+                url: notification_url.to_string(),
+                attempt,
+                status_code: status_code.map(|status_code| status_code.as_u16()),
+                error: delivery_error,
+                delivered,
+                timestamp: Utc::now().to_rfc3339(),
+            },
+        )
+        .await;
+        if delivered {
+            info!(
+                app_name = app_name,
+                task_id = task_id,
+                message = format!("The {} was delivered on attempt {}.", description, attempt)
+            );
+            return true;
+        }
+        if attempt < options.max_attempts {
+            tokio::time::sleep(options.retry_backoff * 2u32.saturating_pow(attempt - 1)).await;
+        }
+    }
+    let error_message = format!(
+        "Failed to deliver the {} after {} attempts.",
+        description, options.max_attempts
+    );
+    error!(
+        app_name = app_name,
+        task_id = task_id,
+        ext_message = error_message,
+        message = error_message
+    );
+    false
+}
+
+/// Records a delivery attempt. Failures are logged and never fail the caller.
+async fn record_delivery_attempt(
+    app_state: &AppState,
+    options: &WebhookOptions,
+    attempt: DeliveryAttempt,
+) {
+    let result = match to_document(&attempt) {
+        Ok(document) => app_state
+            .db
+            .create_document(&options.delivery_collection, document)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = result {
+        error!(
+            app_name = attempt.app_name,
+            task_id = attempt.task_id,
+            message = format!(
+                "Failed to record the webhook delivery attempt. Error: {}",
+                e
+            )
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_webhook_options_from_settings() {
+        assert_eq!(
+            WebhookOptions::from_settings(None),
+            WebhookOptions::default()
+        );
+        let options = WebhookOptions::from_settings(Some(&WebhookSettings {
+            signing_secret: None,
+            max_attempts: Some(0),
+            retry_backoff_ms: Some(10),
+            timeout_seconds: None,
+            delivery_collection: None,
+        }));
+        // At least one attempt is made
+        assert_eq!(options.max_attempts, 1);
+        assert_eq!(options.retry_backoff, Duration::from_millis(10));
+        assert_eq!(options.delivery_collection, DEFAULT_DELIVERY_COLLECTION);
+    }
+
+    #[test]
+    fn test_success_validate_notification_url() {
+        assert!(validate_notification_url(None).is_ok());
+        assert!(validate_notification_url(Some("https://ci.example.com/hooks/onboarding")).is_ok());
+        assert!(validate_notification_url(Some("https://203.0.114.10/hooks")).is_ok());
+        assert!(validate_notification_url(Some("http://ci.example.com/hooks")).is_err());
+        assert!(validate_notification_url(Some("ftp://ci.example.com/hooks")).is_err());
+        assert!(validate_notification_url(Some("not a url")).is_err());
+        assert!(validate_notification_url(Some("https://169.254.169.254/latest")).is_err());
+        assert!(validate_notification_url(Some("https://127.0.0.1:8080/hooks")).is_err());
+        assert!(validate_notification_url(Some("https://[::ffff:10.0.0.1]/hooks")).is_err());
+    }
+
+    #[test]
+    fn test_success_is_public_ip() {
+        for ip in ["8.8.8.8", "203.0.114.10", "2606:4700::1111"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00:ec2::254",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_success_sign() {
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"Jefe").unwrap();
+        mac.update(b"1721988000.what do ya want for nothing?");
+        assert_eq!(
+            sign("Jefe", 1721988000, b"what do ya want for nothing?"),
+            format!("sha256={:x}", mac.finalize().into_bytes())
+        );
+        // A replayed payload signed at another time does not match
+        assert_ne!(
+            sign("Jefe", 1721988000, b"what do ya want for nothing?"),
+            sign("Jefe", 1721988001, b"what do ya want for nothing?")
+        );
+    }
+}
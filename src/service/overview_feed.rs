@@ -0,0 +1,409 @@
+/*
+ * Created Date:  Aug 2, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the live updates of the overview of the admin dashboard.
+//! A change-stream watcher per cluster follows the inserts and deletes of the `-history`, `-error` and `-general`
+//! collections of the apps, and accumulates them per app into deltas: new calls, new errors and the change of the
+//! number of knowledge nodes. The sandbox retrievals of the API explorer are not counted as calls.
+//! Every `flush_interval_ms` the accumulated deltas are broadcast to the websocket clients of the admin dashboard
+//! (see `crate::admin_ui_api::overview_feed_handler`), so the dashboard no longer polls the overview and metric
+//! endpoints. A client lagging behind the broadcast channel is told to refetch the overview.
+//! Every replica watches the clusters itself, since each replica serves its own websocket clients.
+//! The watcher is disabled by default: the change streams have to be enabled on the collections of the apps.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{OverviewFeedSettings, TresleFacadeServiceSettings};
+use crate::retrieval::sandbox::SANDBOX_FIELD;
+use crate::service::state::AppState;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::change_stream::event::{ChangeStreamEvent, OperationType, ResumeToken};
+use mongodb::options::ChangeStreamOptions;
+use mongodb::Database;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{debug, error, info};
+use utoipa::ToSchema;
+
+/// Default number of milliseconds between two broadcasts of the accumulated deltas.
+const DEFAULT_FLUSH_INTERVAL_MS: u64 = 1_000;
+/// Default number of updates buffered for the websocket clients before a slow client lags.
+const DEFAULT_CHANNEL_CAPACITY: usize = 64;
+/// Delay before a failed change stream is opened again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const HISTORY_COLLECTION_SUFFIX: &str = "-history";
+const ERROR_COLLECTION_SUFFIX: &str = "-error";
+const GENERAL_COLLECTION_SUFFIX: &str = "-general";
+
+/// Live overview options: activation, flush interval and channel capacity.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverviewFeedOptions {
+    pub enabled: bool,
+    pub flush_interval: Duration,
+    pub channel_capacity: usize,
+}
+
+impl SettingsOptions for OverviewFeedOptions {
+    type Settings = OverviewFeedSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&OverviewFeedSettings> {
+        settings.overview_feed.as_ref()
+    }
+
+    fn from_settings(settings: Option<&OverviewFeedSettings>) -> Self {
+        OverviewFeedOptions {
+            enabled: settings
+                .and_then(|settings| settings.enabled)
+                .unwrap_or(false),
+            flush_interval: Duration::from_millis(
+                settings
+                    .and_then(|settings| settings.flush_interval_ms)
+                    .filter(|flush_interval_ms| *flush_interval_ms > 0)
+                    .unwrap_or(DEFAULT_FLUSH_INTERVAL_MS),
+            ),
+            channel_capacity: settings
+                .and_then(|settings| settings.channel_capacity)
+                .filter(|channel_capacity| *channel_capacity > 0)
+                .unwrap_or(DEFAULT_CHANNEL_CAPACITY),
+        }
+    }
+}
+
+/// Change of the overview of an app since the previous update.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct OverviewDelta {
+    pub app_name: String,
+    /// Number of new calls.
+    pub calls: u64,
+    /// Number of new errors.
+    pub errors: u64,
+    /// Change of the number of knowledge nodes, negative when nodes were deleted.
+    pub node_delta: i64,
+}
+
+/// Deltas of the apps changed since the previous update.
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct OverviewUpdate {
+    pub timestamp: DateTime<Utc>,
+    pub deltas: Vec<OverviewDelta>,
+}
+
+/// Change of an app collection followed by the watcher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverviewChange {
+    Call,
+    Error,
+    NodeInserted,
+    NodeDeleted,
+}
+
+/// Classifies a change of a collection, `None` for the changes not shown on the overview.
+pub fn classify_change<'a>(
+    collection_name: &'a str,
+    operation_type: &OperationType,
+    full_document: Option<&Document>,
+) -> Option<(&'a str, OverviewChange)> {
+    let inserted = matches!(operation_type, OperationType::Insert);
+    let deleted = matches!(operation_type, OperationType::Delete);
+    if let Some(app_name) = collection_name.strip_suffix(HISTORY_COLLECTION_SUFFIX) {
+        let sandbox = full_document
+            .and_then(|document| document.get_bool(SANDBOX_FIELD).ok())
+            .unwrap_or(false);
+        (inserted && !sandbox).then_some((app_name, OverviewChange::Call))
+    } else if let Some(app_name) = collection_name.strip_suffix(ERROR_COLLECTION_SUFFIX) {
+        inserted.then_some((app_name, OverviewChange::Error))
+    } else if let Some(app_name) = collection_name.strip_suffix(GENERAL_COLLECTION_SUFFIX) {
+        if inserted {
+            Some((app_name, OverviewChange::NodeInserted))
+        } else if deleted {
+            Some((app_name, OverviewChange::NodeDeleted))
+        } else {
+            None
+        }
+    } else {
+        None
+    }
+}
+
+/// Deltas accumulated per app between two broadcasts.
+#[derive(Debug, Default)]
+pub struct OverviewDeltas {
+    deltas: HashMap<String, OverviewDelta>,
+}
+
+impl OverviewDeltas {
+    /// Adds a change to the delta of its app.
+    pub fn record(&mut self, app_name: &str, change: OverviewChange) {
+        let delta = self
+            .deltas
+            .entry(app_name.to_string())
+            .or_insert_with(|| OverviewDelta {
+                app_name: app_name.to_string(),
+                ..Default::default()
+            });
+        match change {
+            OverviewChange::Call => delta.calls += 1,
+            OverviewChange::Error => delta.errors += 1,
+            OverviewChange::NodeInserted => delta.node_delta += 1,
+            OverviewChange::NodeDeleted => delta.node_delta -= 1,
+        }
+    }
+
+    /// Takes the accumulated deltas sorted by app name, leaving the accumulator empty.
+    /// The deltas that cancel out are dropped.
+    pub fn drain(&mut self) -> Vec<OverviewDelta> {
+        let mut deltas: Vec<OverviewDelta> = self
+            .deltas
+            .drain()
+            .map(|(_, delta)| delta)
+            .filter(|delta| delta.calls > 0 || delta.errors > 0 || delta.node_delta != 0)
+            .collect();
+        deltas.sort_by(|a, b| a.app_name.cmp(&b.app_name));
+        deltas
+    }
+}
+
+/// Broadcast channel of the overview updates to the websocket clients.
+#[derive(Debug, Clone)]
+pub struct OverviewFeed {
+    sender: broadcast::Sender<OverviewUpdate>,
+}
+
+impl OverviewFeed {
+    pub fn new(channel_capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(channel_capacity.max(1));
+        OverviewFeed { sender }
+    }
+
+    /// Subscribes a websocket client to the updates broadcast from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<OverviewUpdate> {
+        self.sender.subscribe()
+    }
+
+    /// Returns the number of websocket clients subscribed to the updates.
+    pub fn subscriber_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// Broadcasts the deltas to the subscribed clients. Nothing is sent without deltas.
+    /// Returns the number of clients the update was sent to.
+    pub fn publish(&self, deltas: Vec<OverviewDelta>) -> usize {
+        if deltas.is_empty() {
+            return 0;
+        }
+        self.sender
+            .send(OverviewUpdate {
+                timestamp: Utc::now(),
+                deltas,
+            })
+            .unwrap_or(0)
+    }
+}
+
+/// Watches the app collections of a cluster and records their changes, resuming after the last seen change when
+/// the change stream fails.
+async fn watch_database(
+    residency: Option<String>,
+    database: Database,
+    deltas: Arc<Mutex<OverviewDeltas>>,
+) {
+    let pipeline = vec![doc! {
+        "$match": {
+            "operationType": {"$in": ["insert", "delete"]},
+            "ns.coll": {"$regex": "-(history|error|general)$"},
+        }
+    }];
+    let mut resume_token: Option<ResumeToken> = None;
+    loop {
+        let options = ChangeStreamOptions::builder()
+            .resume_after(resume_token.clone())
+            .build();
+        let mut change_stream = match database.watch(pipeline.clone(), options).await {
+            Ok(change_stream) => change_stream,
+            Err(e) => {
+                error!(
+                    message = format!(
+                        "Failed to open the overview change stream of the cluster {:?}. Error: {}",
+                        residency, e
+                    )
+                );
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        debug!(
+            message = format!(
+                "Overview change stream of the cluster {:?} opened.",
+                residency
+            )
+        );
+        while let Some(event) = change_stream.next().await {
+            let event: ChangeStreamEvent<Document> = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    error!(
+                        message = format!(
+                            "Overview change stream of the cluster {:?} failed. Error: {}",
+                            residency, e
+                        )
+                    );
+                    break;
+                }
+            };
+            resume_token = Some(event.id.clone());
+            let Some(collection_name) = event.ns.as_ref().and_then(|ns| ns.coll.as_deref()) else {
+                continue;
+            };
+            if let Some((app_name, change)) = classify_change(
+                collection_name,
+                &event.operation_type,
+                event.full_document.as_ref(),
+            ) {
+                if let Ok(mut deltas) = deltas.lock() {
+                    deltas.record(app_name, change);
+                }
+            }
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Runs the change-stream watchers of the clusters and broadcasts the accumulated deltas every flush interval.
+pub async fn watch_overview_changes(app_state: Arc<AppState>) {
+    let options = app_state.options::<OverviewFeedOptions>();
+    let Some(driver_databases) = app_state.driver_databases.as_ref() else {
+        error!(message = "No driver connections, the admin dashboard gets no live updates.");
+        return;
+    };
+    let deltas = Arc::new(Mutex::new(OverviewDeltas::default()));
+    for (residency, database) in driver_databases.databases() {
+        tokio::spawn(watch_database(
+            residency.map(str::to_string),
+            database.clone(),
+            deltas.clone(),
+        ));
+    }
+    info!(message = "Overview watcher started.");
+    let mut interval = tokio::time::interval(options.flush_interval);
+    loop {
+        interval.tick().await;
+        let drained = match deltas.lock() {
+            Ok(mut deltas) => deltas.drain(),
+            Err(_) => continue,
+        };
+        app_state.overview_feed.publish(drained);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_overview_feed_options() {
+        let options = OverviewFeedOptions::from_settings(None);
+        assert!(!options.enabled);
+        assert_eq!(options.flush_interval, Duration::from_millis(1_000));
+        assert_eq!(options.channel_capacity, 64);
+
+        let options = OverviewFeedOptions::from_settings(Some(&OverviewFeedSettings {
+            enabled: Some(true),
+            flush_interval_ms: Some(0),
+            channel_capacity: Some(8),
+        }));
+        assert!(options.enabled);
+        assert_eq!(options.flush_interval, Duration::from_millis(1_000));
+        assert_eq!(options.channel_capacity, 8);
+    }
+
+    #[test]
+    fn test_success_classify_change() {
+        assert_eq!(
+            classify_change("app1-history", &OperationType::Insert, Some(&doc! {})),
+            Some(("app1", OverviewChange::Call))
+        );
+        // The sandbox retrievals of the API explorer are not calls
+        assert_eq!(
+            classify_change(
+                "app1-history",
+                &OperationType::Insert,
+                Some(&doc! {SANDBOX_FIELD: true})
+            ),
+            None
+        );
+        assert_eq!(
+            classify_change("app1-history", &OperationType::Delete, None),
+            None
+        );
+        assert_eq!(
+            classify_change("app1-error", &OperationType::Insert, Some(&doc! {})),
+            Some(("app1", OverviewChange::Error))
+        );
+        assert_eq!(
+            classify_change("my-app-general", &OperationType::Insert, Some(&doc! {})),
+            Some(("my-app", OverviewChange::NodeInserted))
+        );
+        assert_eq!(
+            classify_change("my-app-general", &OperationType::Delete, None),
+            Some(("my-app", OverviewChange::NodeDeleted))
+        );
+        assert_eq!(
+            classify_change("app1-logs", &OperationType::Insert, Some(&doc! {})),
+            None
+        );
+    }
+
+    #[test]
+    fn test_success_overview_deltas() {
+        let mut deltas = OverviewDeltas::default();
+        deltas.record("app2", OverviewChange::Call);
+        deltas.record("app2", OverviewChange::Call);
+        deltas.record("app2", OverviewChange::NodeDeleted);
+        deltas.record("app1", OverviewChange::Error);
+        // Cancelled out, not sent
+        deltas.record("app3", OverviewChange::NodeInserted);
+        deltas.record("app3", OverviewChange::NodeDeleted);
+
+        let drained = deltas.drain();
+        assert_eq!(
+            drained,
+            vec![
+                OverviewDelta {
+                    app_name: "app1".to_string(),
+                    calls: 0,
+                    errors: 1,
+                    node_delta: 0,
+                },
+                OverviewDelta {
+                    app_name: "app2".to_string(),
+                    calls: 2,
+                    errors: 0,
+                    node_delta: -1,
+                },
+            ]
+        );
+        assert!(deltas.drain().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_success_overview_feed_publish() {
+        let feed = OverviewFeed::new(4);
+        assert_eq!(feed.publish(Vec::new()), 0);
+
+        let mut receiver = feed.subscribe();
+        let delta = OverviewDelta {
+            app_name: "app1".to_string(),
+            calls: 1,
+            ..Default::default()
+        };
+        assert_eq!(feed.publish(vec![delta.clone()]), 1);
+        assert_eq!(receiver.recv().await.unwrap().deltas, vec![delta]);
+    }
+}
@@ -0,0 +1,285 @@
+/*
+ * Created Date:  Jun 12, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the pagination helper shared by the admin list endpoints.
+//! The requested page is clamped between 1 and the total number of pages, and the response
+//! headers carry the total count (`X-Total-Count`) and RFC 5988 `Link` headers for the
+//! first, prev, next and last pages.
+//! For large collections the cursor (keyset) mode pages over `(sort field, _id)` instead of
+//! `$skip`, and returns an opaque `next_cursor`.
+//!
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Uri};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Serialize};
+
+pub const TOTAL_COUNT_HEADER: &str = "x-total-count";
+pub const DEFAULT_PAGE_LIMIT: i64 = 10;
+/// Field projected by the cursor pipelines to carry the stringified `_id`.
+pub const CURSOR_ID_FIELD: &str = "_cursor_id";
+
+/// Returns the requested page limit. A missing or zero limit falls back to `default_limit`.
+pub fn page_limit(limit: Option<usize>, default_limit: i64) -> i64 {
+    match limit {
+        Some(limit) if limit > 0 && limit <= i64::MAX as usize => limit as i64,
+        _ => default_limit,
+    }
+}
+
+/// Pagination of a list endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pagination {
+    pub page: i64,
+    pub limit: i64,
+    pub total_count: i64,
+    pub total_pages: i64,
+}
+
+impl Pagination {
+    /// Creates the pagination for the requested page and limit. A missing or zero limit falls back to `default_limit`.
+    pub fn new(
+        page: Option<usize>,
+        limit: Option<usize>,
+        default_limit: i64,
+        total_count: i64,
+    ) -> Self {
+        let limit = page_limit(limit, default_limit);
+        let total_count = total_count.max(0);
+        let total_pages = (total_count as f64 / limit as f64).ceil() as i64;
+
+        // If page is negative or total_pages is 0, set page to 1. If page is > total_pages, set page to total_pages
+        let page = page
+            .map_or(1, |page| page as i64)
+            .clamp(1, total_pages.max(1));
+
+        Pagination {
+            page,
+            limit,
+            total_count,
+            total_pages,
+        }
+    }
+
+    /// Number of documents to skip for the current page.
+    pub fn skip(&self) -> i64 {
+        (self.page - 1) * self.limit
+    }
+
+    /// Response headers with the total count and the `Link` header for the request URI.
+    pub fn headers(&self, uri: &Uri) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static(TOTAL_COUNT_HEADER),
+            HeaderValue::from(self.total_count),
+        );
+
+        let last_page = self.total_pages.max(1);
+        let mut links = vec![self.link(uri, 1, "first")];
+        if self.page > 1 {
+            links.push(self.link(uri, self.page - 1, "prev"));
+        }
+        if self.page < last_page {
+            links.push(self.link(uri, self.page + 1, "next"));
+        }
+        links.push(self.link(uri, last_page, "last"));
+
+        if let Ok(link) = HeaderValue::from_str(&links.join(", ")) {
+            headers.insert(axum::http::header::LINK, link);
+        }
+        headers
+    }
+
+    /// Builds a single link, keeping every query parameter of the request except `page` and `limit`.
+    fn link(&self, uri: &Uri, page: i64, rel: &str) -> String {
+        let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+        if let Some(query) = uri.query() {
+            for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+                if key != "page" && key != "limit" {
+                    serializer.append_pair(&key, &value);
+                }
+            }
+        }
+        serializer.append_pair("page", &page.to_string());
+        serializer.append_pair("limit", &self.limit.to_string());
+        format!("<{}?{}>; rel=\"{}\"", uri.path(), serializer.finish(), rel)
+    }
+}
+
+/// Opaque keyset cursor pointing at the last document of a page.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub sort_value: String,
+    pub id: String,
+}
+
+impl Cursor {
+    /// Encodes the cursor as URL safe base64 JSON.
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    /// Decodes a cursor previously returned as `next_cursor`.
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+
+    /// `$match` condition selecting the documents sorted after this cursor.
+    pub fn after(&self, sort_field: &str) -> Document {
+        let id = match ObjectId::parse_str(&self.id) {
+            Ok(oid) => Bson::ObjectId(oid),
+            Err(_) => Bson::String(self.id.clone()),
+        };
+        let mut greater = Document::new();
+        greater.insert(sort_field, doc! { "$gt": &self.sort_value });
+        let mut tie = Document::new();
+        tie.insert(sort_field, &self.sort_value);
+        tie.insert("_id", doc! { "$gt": id });
+        doc! { "$or": [greater, tie] }
+    }
+}
+
+/// Splits the documents fetched with `limit + 1` into the page and the cursor for the next page.
+/// The `CURSOR_ID_FIELD` is removed from the returned documents.
+pub fn split_cursor_page(
+    mut documents: Vec<serde_json::Value>,
+    limit: i64,
+    sort_field: &str,
+) -> (Vec<serde_json::Value>, Option<String>) {
+    let has_more = documents.len() as i64 > limit;
+    documents.truncate(limit.max(0) as usize);
+
+    let next_cursor = match (has_more, documents.last()) {
+        (true, Some(last)) => Some(
+            Cursor {
+                sort_value: last
+                    .get(sort_field)
+                    .and_then(serde_json::Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+                id: last
+                    .get(CURSOR_ID_FIELD)
+                    .and_then(serde_json::Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+            }
+            .encode(),
+        ),
+        _ => None,
+    };
+
+    for document in documents.iter_mut() {
+        if let Some(document) = document.as_object_mut() {
+            document.remove(CURSOR_ID_FIELD);
+        }
+    }
+    (documents, next_cursor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_pagination_clamps_page() {
+        let pagination = Pagination::new(Some(5), Some(10), 10, 25);
+        assert_eq!(pagination.total_pages, 3);
+        assert_eq!(pagination.page, 3);
+        assert_eq!(pagination.skip(), 20);
+
+        let pagination = Pagination::new(Some(-1i32 as usize), None, 10, 25);
+        assert_eq!(pagination.page, 1);
+        assert_eq!(pagination.skip(), 0);
+    }
+
+    #[test]
+    fn test_success_pagination_no_results() {
+        let pagination = Pagination::new(Some(2), Some(0), 10, 0);
+        assert_eq!(pagination.limit, 10);
+        assert_eq!(pagination.total_pages, 0);
+        assert_eq!(pagination.page, 1);
+    }
+
+    #[test]
+    fn test_success_pagination_headers() {
+        let uri: Uri = "/api/v1.1/admin/nodes/app1?knowledge_node_type=x&page=2&limit=10"
+            .parse()
+            .unwrap();
+        let headers = Pagination::new(Some(2), Some(10), 10, 35).headers(&uri);
+        assert_eq!(headers.get(TOTAL_COUNT_HEADER).unwrap(), "35");
+        let link = headers
+            .get(axum::http::header::LINK)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(link.contains(
+            "</api/v1.1/admin/nodes/app1?knowledge_node_type=x&page=1&limit=10>; rel=\"prev\""
+        ));
+        assert!(link.contains("page=3&limit=10>; rel=\"next\""));
+        assert!(link.contains("page=4&limit=10>; rel=\"last\""));
+    }
+
+    #[test]
+    fn test_success_pagination_headers_single_page() {
+        let uri: Uri = "/api/v1.1/admin/apps".parse().unwrap();
+        let headers = Pagination::new(None, None, 100, 3).headers(&uri);
+        let link = headers
+            .get(axum::http::header::LINK)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(!link.contains("rel=\"next\""));
+        assert!(!link.contains("rel=\"prev\""));
+    }
+
+    #[test]
+    fn test_success_cursor_encode_decode() {
+        let cursor = Cursor {
+            sort_value: "2024-05-02T00:00:00Z".to_string(),
+            id: "6633a1f0c2a4b5e6f7a8b9c0".to_string(),
+        };
+        assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+        assert_eq!(Cursor::decode("not-a-cursor"), None);
+    }
+
+    #[test]
+    fn test_success_cursor_after_object_id() {
+        let cursor = Cursor {
+            sort_value: "2024-05-02T00:00:00Z".to_string(),
+            id: "6633a1f0c2a4b5e6f7a8b9c0".to_string(),
+        };
+        let condition = cursor.after("indexed_at");
+        let or = condition.get_array("$or").unwrap();
+        assert_eq!(or.len(), 2);
+        let tie = or[1].as_document().unwrap();
+        assert!(matches!(
+            tie.get_document("_id").unwrap().get("$gt"),
+            Some(Bson::ObjectId(_))
+        ));
+    }
+
+    #[test]
+    fn test_success_split_cursor_page() {
+        let documents = vec![
+            serde_json::json!({"indexed_at": "a", "_cursor_id": "1"}),
+            serde_json::json!({"indexed_at": "b", "_cursor_id": "2"}),
+            serde_json::json!({"indexed_at": "c", "_cursor_id": "3"}),
+        ];
+        let (page, next_cursor) = split_cursor_page(documents, 2, "indexed_at");
+        assert_eq!(page.len(), 2);
+        assert!(page[1].get(CURSOR_ID_FIELD).is_none());
+        let cursor = Cursor::decode(&next_cursor.unwrap()).unwrap();
+        assert_eq!(cursor.sort_value, "b");
+        assert_eq!(cursor.id, "2");
+
+        let documents = vec![serde_json::json!({"indexed_at": "a", "_cursor_id": "1"})];
+        let (page, next_cursor) = split_cursor_page(documents, 2, "indexed_at");
+        assert_eq!(page.len(), 1);
+        assert!(next_cursor.is_none());
+    }
+}
@@ -0,0 +1,268 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the Prometheus export of the duration metrics. With `metrics.prometheus` set, every duration
+//! record (e.g. `Retrieval Stage Duration`) is observed in an in-process histogram, named after the record in snake
+//! case with a `_ms` suffix and labelled with its dimensions except the task id. The histograms are served in the
+//! Prometheus text format at `/metrics`, which answers 404 when the export is disabled.
+//! The buckets are `metrics.prometheus.buckets_ms`, from 5 ms to 60 s by default.
+//!
+
+use crate::configuration::settings::PrometheusSettings;
+use crate::service::metrics::{
+    MetricRecord, MetricUnit, MetricsError, MetricsSink, TASK_ID_DIMENSION,
+};
+use crate::service::state::AppState;
+use async_trait::async_trait;
+use axum::{
+    extract::State,
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use mongodb_utils::mongodb_client::DBTrait;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+/// Content type of the Prometheus text format.
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+/// Default upper bounds of the histogram buckets, in milliseconds.
+const DEFAULT_BUCKETS_MS: [f64; 13] = [
+    5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1_000.0, 2_500.0, 5_000.0, 10_000.0, 30_000.0,
+    60_000.0,
+];
+
+/// Histogram of the observations of a metric with a set of labels.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Histogram {
+    /// Observations by bucket, not cumulated.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// Histograms of the duration metrics, by metric name and labels.
+#[derive(Debug)]
+pub struct PrometheusRegistry {
+    buckets_ms: Vec<f64>,
+    histograms: Mutex<BTreeMap<(String, Vec<(String, String)>), Histogram>>,
+}
+
+impl PrometheusRegistry {
+    /// Builds the registry from the settings, `None` when the export is disabled.
+    pub fn from_settings(settings: Option<&PrometheusSettings>) -> Option<Self> {
+        let mut buckets_ms = settings?
+            .buckets_ms
+            .clone()
+            .filter(|buckets_ms| !buckets_ms.is_empty())
+            .unwrap_or_else(|| DEFAULT_BUCKETS_MS.to_vec());
+        buckets_ms.retain(|bound| bound.is_finite());
+        buckets_ms.sort_by(|a, b| a.total_cmp(b));
+        buckets_ms.dedup();
+        Some(PrometheusRegistry {
+            buckets_ms,
+            histograms: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Observes a duration record. The other records are ignored.
+    pub fn observe(&self, record: &MetricRecord) {
+        if record.unit != MetricUnit::Milliseconds {
+            return;
+        }
+        let labels: Vec<(String, String)> = record
+            .dimensions
+            .iter()
+            .filter(|(key, _)| key.as_str() != TASK_ID_DIMENSION)
+            .map(|(key, value)| (metric_name(key), value.clone()))
+            .collect();
+        let name = format!("{}_ms", metric_name(&record.name));
+        let Ok(mut histograms) = self.histograms.lock() else {
+            return;
+        };
+        let histogram = histograms
+            .entry((name, labels))
+            .or_insert_with(|| Histogram {
+                buckets: vec![0; self.buckets_ms.len()],
+                ..Histogram::default()
+            });
+        if let Some(bucket) = self
+            .buckets_ms
+            .iter()
+            .position(|bound| record.value <= *bound)
+        {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.sum += record.value;
+        histogram.count += 1;
+    }
+
+    /// Renders the histograms in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        let Ok(histograms) = self.histograms.lock() else {
+            return output;
+        };
+        let mut last_name = None;
+        for ((name, labels), histogram) in histograms.iter() {
+            if last_name != Some(name) {
+                let _ = writeln!(output, "# TYPE {} histogram", name);
+                last_name = Some(name);
+            }
+            let mut cumulated = 0;
+            for (bound, observations) in self.buckets_ms.iter().zip(&histogram.buckets) {
+                cumulated += observations;
+                let _ = writeln!(
+                    output,
+                    "{}_bucket{} {}",
+                    name,
+                    label_set(labels, Some(&bound.to_string())),
+                    cumulated
+                );
+            }
+            let _ = writeln!(
+                output,
+                "{}_bucket{} {}",
+                name,
+                label_set(labels, Some("+Inf")),
+                histogram.count
+            );
+            let _ = writeln!(
+                output,
+                "{}_sum{} {}",
+                name,
+                label_set(labels, None),
+                histogram.sum
+            );
+            let _ = writeln!(
+                output,
+                "{}_count{} {}",
+                name,
+                label_set(labels, None),
+                histogram.count
+            );
+        }
+        output
+    }
+}
+
+/// Name of a metric or label in snake case, e.g. `retrieval_stage_duration` for `Retrieval Stage Duration`.
+fn metric_name(name: &str) -> String {
+    let mut snake_case = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            snake_case.push(c.to_ascii_lowercase());
+        } else if !snake_case.ends_with('_') {
+            snake_case.push('_');
+        }
+    }
+    snake_case.trim_matches('_').to_string()
+}
+
+/// Label set of a sample, with the `le` label of a bucket.
+fn label_set(labels: &[(String, String)], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+/// Observes the duration records in the Prometheus histograms.
+pub struct PrometheusMetricsSink {
+    pub registry: Arc<PrometheusRegistry>,
+}
+
+#[async_trait]
+impl MetricsSink for PrometheusMetricsSink {
+    async fn record(
+        &self,
+        _db: &(dyn DBTrait + Sync + Send),
+        record: &MetricRecord,
+    ) -> Result<(), MetricsError> {
+        self.registry.observe(record);
+        Ok(())
+    }
+}
+
+/// GET handler serving the Prometheus histograms of the duration metrics.
+pub async fn get_prometheus_metrics(
+    State(app_state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let Some(registry) = &app_state.prometheus else {
+        let error_message = "Prometheus metrics are disabled.".to_string();
+        debug!(message = error_message);
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"status": "error", "message": error_message})),
+        ));
+    };
+    Ok(([(CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE)], registry.render()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_prometheus_registry() {
+        assert!(PrometheusRegistry::from_settings(None).is_none());
+        let registry = PrometheusRegistry::from_settings(Some(&PrometheusSettings {
+            buckets_ms: Some(vec![100.0, 10.0]),
+        }))
+        .unwrap();
+        for duration_ms in [5, 50, 500] {
+            registry.observe(
+                &MetricRecord::duration_ms("Retrieval Stage Duration", duration_ms)
+                    .dimension("stage", "engine_call")
+                    .dimension(TASK_ID_DIMENSION, "task"),
+            );
+        }
+        // The counters are not histograms
+        registry.observe(&MetricRecord::counter("Data Retrieval Counter"));
+
+        assert_eq!(
+            registry.render(),
+            "# TYPE retrieval_stage_duration_ms histogram\n\
+             retrieval_stage_duration_ms_bucket{stage=\"engine_call\",le=\"10\"} 1\n\
+             retrieval_stage_duration_ms_bucket{stage=\"engine_call\",le=\"100\"} 2\n\
+             retrieval_stage_duration_ms_bucket{stage=\"engine_call\",le=\"+Inf\"} 3\n\
+             retrieval_stage_duration_ms_sum{stage=\"engine_call\"} 555\n\
+             retrieval_stage_duration_ms_count{stage=\"engine_call\"} 3\n"
+        );
+    }
+
+    #[test]
+    fn test_success_label_set() {
+        assert_eq!(label_set(&[], None), "");
+        assert_eq!(
+            label_set(
+                &[("app_name".to_string(), "a\"b".to_string())],
+                Some("+Inf")
+            ),
+            "{app_name=\"a\\\"b\",le=\"+Inf\"}"
+        );
+        assert_eq!(
+            metric_name("Dead-Lettered Job Counter"),
+            "dead_lettered_job_counter"
+        );
+    }
+}
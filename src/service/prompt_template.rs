@@ -0,0 +1,340 @@
+/*
+ * Created Date:  Jul 25, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the named prompt templates of an app, stored on the app document and managed through the
+//! prompt template endpoints.
+//! A template is a text with `{{variable}}` placeholders. A retrieval request references a template by name in its
+//! `prompt_template` (`{"name", "variables"}`); the facade renders it into the `additional_prompt` sent to the
+//! knowledge engine. Every placeholder needs a variable and every variable a placeholder; the values are limited in
+//! length and can't hold placeholders or control characters.
+//! Apps with templates only accept rendered prompts: a free-form `additional_prompt` is rejected.
+//!
+
+use api_utils::retrieval_model::RetrievalRequest;
+use axum::{http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use tracing::debug;
+use utoipa::ToSchema;
+
+/// Field of the prompt templates of an app in the app document.
+pub const PROMPT_TEMPLATES_FIELD: &str = "prompt_templates";
+/// Maximum length of a template, in characters.
+const MAX_TEMPLATE_LENGTH: usize = 10_000;
+/// Maximum length of a variable value, in characters.
+const MAX_VARIABLE_LENGTH: usize = 1_000;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum PromptTemplateError {
+    #[error("Invalid prompt template name '{0}'. Names are made of letters, digits, '-' and '_'.")]
+    InvalidName(String),
+    #[error("Invalid prompt template '{name}': {message}")]
+    InvalidTemplate { name: String, message: String },
+    #[error("No prompt template found with name '{0}'.")]
+    UnknownTemplate(String),
+    #[error("Variable '{variable}' of prompt template '{name}' is missing.")]
+    MissingVariable { name: String, variable: String },
+    #[error("Prompt template '{name}' has no variable '{variable}'.")]
+    UnknownVariable { name: String, variable: String },
+    #[error("Invalid value of variable '{variable}': {message}")]
+    InvalidValue { variable: String, message: String },
+    #[error("The app only accepts prompt templates, additional_prompt can't be set.")]
+    FreeFormPrompt,
+}
+
+impl From<PromptTemplateError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: PromptTemplateError) -> Self {
+        let status_code = match e {
+            PromptTemplateError::UnknownTemplate(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        let error_message = e.to_string();
+        debug!(message = error_message);
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Named prompt template of an app.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema, PartialEq)]
+pub struct PromptTemplate {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Text of the prompt, with `{{variable}}` placeholders.
+    pub template: String,
+}
+
+/// Reference of a retrieval request to a prompt template of the app.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema, PartialEq)]
+pub struct PromptTemplateReference {
+    pub name: String,
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+}
+
+/// Prompt fields of a retrieval request body, read next to the `RetrievalRequest`.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RetrievalPrompt {
+    #[serde(default)]
+    pub prompt_template: Option<PromptTemplateReference>,
+    #[serde(default)]
+    pub additional_prompt: Option<String>,
+}
+
+impl PromptTemplate {
+    /// Validates the name, the length and the placeholders of the template.
+    pub fn validate(&self) -> Result<(), PromptTemplateError> {
+        validate_name(&self.name)?;
+        if self.template.trim().is_empty() {
+            return Err(self.invalid("the template can't be empty."));
+        }
+        if self.template.chars().count() > MAX_TEMPLATE_LENGTH {
+            return Err(self.invalid(&format!(
+                "the template is longer than {} characters.",
+                MAX_TEMPLATE_LENGTH
+            )));
+        }
+        self.variables().map(|_| ())
+    }
+
+    /// Returns the names of the placeholders of the template.
+    pub fn variables(&self) -> Result<BTreeSet<String>, PromptTemplateError> {
+        let mut variables = BTreeSet::new();
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find("{{") {
+            let Some(end) = rest[start + 2..].find("}}") else {
+                return Err(self.invalid("unclosed '{{' placeholder."));
+            };
+            let variable = rest[start + 2..start + 2 + end].trim();
+            if validate_name(variable).is_err() {
+                return Err(self.invalid(&format!("invalid placeholder '{{{{{}}}}}'.", variable)));
+            }
+            variables.insert(variable.to_string());
+            rest = &rest[start + 2 + end + 2..];
+        }
+        if rest.contains("}}") {
+            return Err(self.invalid("unopened '}}' placeholder."));
+        }
+        Ok(variables)
+    }
+
+    /// Renders the template with the variables of a retrieval request, in a single pass so the values are never
+    /// read as placeholders.
+    pub fn render(
+        &self,
+        variables: &HashMap<String, String>,
+    ) -> Result<String, PromptTemplateError> {
+        let placeholders = self.variables()?;
+        if let Some(variable) = variables
+            .keys()
+            .find(|variable| !placeholders.contains(*variable))
+        {
+            return Err(PromptTemplateError::UnknownVariable {
+                name: self.name.clone(),
+                variable: variable.clone(),
+            });
+        }
+        for placeholder in &placeholders {
+            let value =
+                variables
+                    .get(placeholder)
+                    .ok_or_else(|| PromptTemplateError::MissingVariable {
+                        name: self.name.clone(),
+                        variable: placeholder.clone(),
+                    })?;
+            validate_value(placeholder, value)?;
+        }
+        let mut rendered = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find("{{") {
+            // The placeholders are validated, every '{{' is closed and has a variable
+            let end = start + 2 + rest[start + 2..].find("}}").unwrap_or(0);
+            rendered.push_str(&rest[..start]);
+            if let Some(value) = variables.get(rest[start + 2..end].trim()) {
+                rendered.push_str(value);
+            }
+            rest = &rest[end + 2..];
+        }
+        rendered.push_str(rest);
+        Ok(rendered)
+    }
+
+    fn invalid(&self, message: &str) -> PromptTemplateError {
+        PromptTemplateError::InvalidTemplate {
+            name: self.name.clone(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Validates the name of a template or of a variable.
+fn validate_name(name: &str) -> Result<(), PromptTemplateError> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(PromptTemplateError::InvalidName(name.to_string()))
+    }
+}
+
+/// Validates the value of a variable: limited length, no placeholders and no control characters but newlines.
+fn validate_value(variable: &str, value: &str) -> Result<(), PromptTemplateError> {
+    let message = if value.chars().count() > MAX_VARIABLE_LENGTH {
+        format!("longer than {} characters.", MAX_VARIABLE_LENGTH)
+    } else if value.contains("{{") || value.contains("}}") {
+        "placeholders are not allowed.".to_string()
+    } else if value.chars().any(|c| c.is_control() && c != '\n') {
+        "control characters are not allowed.".to_string()
+    } else {
+        return Ok(());
+    };
+    Err(PromptTemplateError::InvalidValue {
+        variable: variable.to_string(),
+        message,
+    })
+}
+
+/// Resolves the additional prompt of a retrieval request against the templates of its app.
+/// Returns the rendered prompt if the request references a template, `None` if the request is sent as-is.
+pub fn resolve_additional_prompt(
+    templates: &[PromptTemplate],
+    prompt: &RetrievalPrompt,
+) -> Result<Option<String>, PromptTemplateError> {
+    let free_form = prompt
+        .additional_prompt
+        .as_deref()
+        .is_some_and(|additional_prompt| !additional_prompt.trim().is_empty());
+    if free_form && !templates.is_empty() {
+        return Err(PromptTemplateError::FreeFormPrompt);
+    }
+    match &prompt.prompt_template {
+        Some(reference) => {
+            let template = templates
+                .iter()
+                .find(|template| template.name == reference.name)
+                .ok_or_else(|| PromptTemplateError::UnknownTemplate(reference.name.clone()))?;
+            template.render(&reference.variables).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Replaces the `additional_prompt` of a retrieval request with a rendered prompt.
+pub fn with_additional_prompt(
+    body: RetrievalRequest,
+    additional_prompt: String,
+) -> Result<RetrievalRequest, serde_json::Error> {
+    let mut body = serde_json::to_value(body)?;
+    body["additional_prompt"] = json!(additional_prompt);
+    serde_json::from_value(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template() -> PromptTemplate {
+        PromptTemplate {
+            name: "policy-scope".to_string(),
+            description: None,
+            template: "Answer for the {{ region }} office, in {{language}}.".to_string(),
+        }
+    }
+
+    fn variables(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_success_render_prompt_template() {
+        let template = template();
+        assert!(template.validate().is_ok());
+        assert_eq!(
+            template.render(&variables(&[("region", "Berlin"), ("language", "German")])),
+            Ok("Answer for the Berlin office, in German.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_failure_render_prompt_template() {
+        let template = template();
+        assert_eq!(
+            template.render(&variables(&[("region", "Berlin")])),
+            Err(PromptTemplateError::MissingVariable {
+                name: "policy-scope".to_string(),
+                variable: "language".to_string(),
+            })
+        );
+        assert!(matches!(
+            template.render(&variables(&[
+                ("region", "Berlin"),
+                ("language", "German"),
+                ("tone", "formal")
+            ])),
+            Err(PromptTemplateError::UnknownVariable { .. })
+        ));
+        // Values can't inject placeholders
+        assert!(matches!(
+            template.render(&variables(&[
+                ("region", "{{language}}"),
+                ("language", "German")
+            ])),
+            Err(PromptTemplateError::InvalidValue { .. })
+        ));
+    }
+
+    #[test]
+    fn test_failure_validate_prompt_template() {
+        let mut template = template();
+        template.template = "Answer for the {{ region office.".to_string();
+        assert!(matches!(
+            template.validate(),
+            Err(PromptTemplateError::InvalidTemplate { .. })
+        ));
+        template.template = "Answer for the {{region name}}.".to_string();
+        assert!(template.validate().is_err());
+        template.name = "policy scope".to_string();
+        assert_eq!(
+            template.validate(),
+            Err(PromptTemplateError::InvalidName("policy scope".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_success_resolve_additional_prompt() {
+        let templates = vec![template()];
+        let prompt = RetrievalPrompt {
+            prompt_template: Some(PromptTemplateReference {
+                name: "policy-scope".to_string(),
+                variables: variables(&[("region", "Pune"), ("language", "English")]),
+            }),
+            additional_prompt: None,
+        };
+        assert_eq!(
+            resolve_additional_prompt(&templates, &prompt),
+            Ok(Some("Answer for the Pune office, in English.".to_string()))
+        );
+        // Apps without templates keep their free-form prompts
+        let free_form = RetrievalPrompt {
+            prompt_template: None,
+            additional_prompt: Some("related to policy1".to_string()),
+        };
+        assert_eq!(resolve_additional_prompt(&[], &free_form), Ok(None));
+        assert_eq!(
+            resolve_additional_prompt(&templates, &free_form),
+            Err(PromptTemplateError::FreeFormPrompt)
+        );
+    }
+}
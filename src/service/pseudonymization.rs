@@ -0,0 +1,270 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the pseudonymization of the end user IDs of the apps onboarded with
+//! `pseudonymize_user_ids: true`.
+//! The `user_details.user_id` of a retrieval is replaced, before it is stored in the history and token usage documents
+//! or sent to the audit logs, by `psn_` and the hex HMAC-SHA256 of the user ID under a secret of the app. The secret
+//! is generated on the first retrieval of the app and stored, encrypted when encryption is enabled, in the key
+//! collection. The same user always gets the same pseudonym, so the per-user history, holds and token usage still work.
+//! The user ID behind a pseudonym is kept, encrypted the same way, in the mapping collection. It is only revealed to
+//! the service accounts granted the `identity:reveal` scope.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{PseudonymizationSettings, TresleFacadeServiceSettings};
+use crate::service::encryption::EncryptionError;
+use crate::service::service_account::{Scope, ServiceAccount};
+use crate::service::state::AppState;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use mongodb::bson::doc;
+use sha2::Sha256;
+use tracing::{debug, error};
+
+/// Default collection of the pseudonymization secrets of the apps.
+pub const DEFAULT_KEY_COLLECTION: &str = "user-pseudonym-keys";
+/// Default collection of the user IDs behind the pseudonyms.
+pub const DEFAULT_MAPPING_COLLECTION: &str = "user-pseudonyms";
+/// Prefix of the pseudonyms.
+const PSEUDONYM_PREFIX: &str = "psn_";
+/// Length of the pseudonymization secrets, in bytes.
+const SECRET_LENGTH: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PseudonymizationError {
+    #[error("Failed to access the pseudonymization documents: {0}")]
+    Store(String),
+    #[error("Malformed pseudonymization secret of app '{0}'")]
+    MalformedSecret(String),
+    #[error("{0}")]
+    Encryption(#[from] EncryptionError),
+}
+
+/// Pseudonymization options: secret and mapping collections.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PseudonymizationOptions {
+    pub key_collection: String,
+    pub mapping_collection: String,
+}
+
+impl SettingsOptions for PseudonymizationOptions {
+    type Settings = PseudonymizationSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&PseudonymizationSettings> {
+        settings.pseudonymization.as_ref()
+    }
+
+    fn from_settings(settings: Option<&PseudonymizationSettings>) -> Self {
+        PseudonymizationOptions {
+            key_collection: settings
+                .and_then(|settings| settings.key_collection.clone())
+                .unwrap_or_else(|| DEFAULT_KEY_COLLECTION.to_string()),
+            mapping_collection: settings
+                .and_then(|settings| settings.mapping_collection.clone())
+                .unwrap_or_else(|| DEFAULT_MAPPING_COLLECTION.to_string()),
+        }
+    }
+}
+
+/// Returns true if the authenticated service account of a request may reveal the user ID behind a pseudonym.
+pub fn can_reveal(account: Option<&ServiceAccount>) -> bool {
+    account.is_some_and(|account| account.is_granted(Scope::IdentityReveal))
+}
+
+/// Returns the pseudonym of a user ID under the secret of an app.
+pub fn pseudonym(secret: &[u8], user_id: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(user_id.as_bytes());
+    format!("{}{:x}", PSEUDONYM_PREFIX, mac.finalize().into_bytes())
+}
+
+/// ID of the mapping document of a pseudonym of an app.
+fn mapping_id(app_name: &str, pseudonym: &str) -> String {
+    format!("{}:{}", app_name, pseudonym)
+}
+
+/// Reads the decrypted field of a document of an app from a collection, `None` if there is no such document.
+async fn read_field(
+    app_state: &AppState,
+    collection: &str,
+    id: &str,
+    app_name: &str,
+    field: &str,
+) -> Result<Option<String>, PseudonymizationError> {
+    let document = app_state
+        .db
+        .get_document(collection, doc! {"_id": id})
+        .await
+        .map_err(|e| PseudonymizationError::Store(e.to_string()))?;
+    let Some(mut document) = document else {
+        return Ok(None);
+    };
+    app_state.decrypt_fields(app_name, &mut document).await?;
+    Ok(document
+        .get(field)
+        .and_then(serde_json::Value::as_str)
+        .map(str::to_string))
+}
+
+/// Returns the pseudonymization secret of an app, generating it on first use. A replica losing the race to create
+/// the secret reads the one of the winner.
+async fn app_secret(
+    app_state: &AppState,
+    options: &PseudonymizationOptions,
+    app_name: &str,
+) -> Result<Vec<u8>, PseudonymizationError> {
+    let read_secret = || {
+        read_field(
+            app_state,
+            &options.key_collection,
+            app_name,
+            app_name,
+            "secret",
+        )
+    };
+    let secret = match read_secret().await? {
+        Some(secret) => secret,
+        None => {
+            let secret = STANDARD.encode(rand::random::<[u8; SECRET_LENGTH]>());
+            let key_document = doc! {
+                "_id": app_name,
+                "app_name": app_name,
+                "secret": app_state.encrypt_field(app_name, &secret).await?,
+                "created_at": Utc::now().to_rfc3339(),
+            };
+            match app_state
+                .db
+                .create_document(&options.key_collection, key_document)
+                .await
+            {
+                Ok(_) => secret,
+                Err(e) => {
+                    debug!(
+                        message = format!(
+                            "Pseudonymization secret of '{}' not created, reading it. Error: {}",
+                            app_name, e
+                        )
+                    );
+                    read_secret()
+                        .await?
+                        .ok_or_else(|| PseudonymizationError::Store(e.to_string()))?
+                }
+            }
+        }
+    };
+    STANDARD
+        .decode(secret)
+        .map_err(|_| PseudonymizationError::MalformedSecret(app_name.to_string()))
+}
+
+/// Returns the pseudonym of a user of an app and records the user ID behind it, once. A failure to record the user ID
+/// is logged and does not fail the caller, the next retrieval of the user records it.
+pub async fn pseudonymize_user_id(
+    app_state: &AppState,
+    app_name: &str,
+    user_id: &str,
+) -> Result<String, PseudonymizationError> {
+    let options = app_state.options::<PseudonymizationOptions>();
+    let secret = app_secret(app_state, &options, app_name).await?;
+    let pseudonym = pseudonym(&secret, user_id);
+
+    let id = mapping_id(app_name, &pseudonym);
+    let recorded = app_state
+        .db
+        .get_document(&options.mapping_collection, doc! {"_id": &id})
+        .await
+        .map(|mapping| mapping.is_some())
+        .unwrap_or_else(|e| {
+            error!(
+                app_name = app_name,
+                message = format!("Failed to read the pseudonym mapping. Error: {}", e)
+            );
+            true
+        });
+    if !recorded {
+        let mapping_document = doc! {
+            "_id": &id,
+            "app_name": app_name,
+            "pseudonym": &pseudonym,
+            "user_id": app_state.encrypt_field(app_name, user_id).await?,
+            "created_at": Utc::now().to_rfc3339(),
+        };
+        if let Err(e) = app_state
+            .db
+            .create_document(&options.mapping_collection, mapping_document)
+            .await
+        {
+            error!(
+                app_name = app_name,
+                message = format!(
+                    "Failed to record the user ID behind pseudonym '{}'. Error: {}",
+                    pseudonym, e
+                )
+            );
+        }
+    }
+    Ok(pseudonym)
+}
+
+/// Returns the user ID behind a pseudonym of an app, `None` if the pseudonym is unknown.
+pub async fn reveal_user_id(
+    app_state: &AppState,
+    app_name: &str,
+    pseudonym: &str,
+) -> Result<Option<String>, PseudonymizationError> {
+    let options = app_state.options::<PseudonymizationOptions>();
+    read_field(
+        app_state,
+        &options.mapping_collection,
+        &mapping_id(app_name, pseudonym),
+        app_name,
+        "user_id",
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_pseudonym() {
+        let secret = [7u8; SECRET_LENGTH];
+        let pseudonym_1 = pseudonym(&secret, "user@example.com");
+        assert!(pseudonym_1.starts_with(PSEUDONYM_PREFIX));
+        assert_eq!(pseudonym_1.len(), PSEUDONYM_PREFIX.len() + 64);
+        assert!(!pseudonym_1.contains("user@example.com"));
+        // Stable for the same user and secret, different for another user or another secret
+        assert_eq!(pseudonym(&secret, "user@example.com"), pseudonym_1);
+        assert_ne!(pseudonym(&secret, "other@example.com"), pseudonym_1);
+        assert_ne!(
+            pseudonym(&[8u8; SECRET_LENGTH], "user@example.com"),
+            pseudonym_1
+        );
+    }
+
+    #[test]
+    fn test_success_can_reveal() {
+        let options = PseudonymizationOptions::from_settings(None);
+        assert_eq!(options.mapping_collection, DEFAULT_MAPPING_COLLECTION);
+
+        let account = |scopes: Vec<Scope>| ServiceAccount {
+            client_id: "sa_test".to_string(),
+            name: "identity-team".to_string(),
+            scopes,
+            secret_hash: String::new(),
+            created_at: Utc::now(),
+            secret_rotated_at: None,
+            revoked_at: None,
+        };
+        // Requests without an authenticated service account never reveal a user ID
+        assert!(!can_reveal(None));
+        assert!(!can_reveal(Some(&account(vec![Scope::AppsRead]))));
+        assert!(can_reveal(Some(&account(vec![Scope::IdentityReveal]))));
+        assert!(can_reveal(Some(&account(vec![Scope::Admin]))));
+    }
+}
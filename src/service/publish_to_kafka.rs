@@ -4,32 +4,43 @@
  * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
  */
 
-//! This module contains the function to publish data to Kafka
+//! This module contains the function to publish data to Kafka. In the local development mode, the data is kept by
+//! the in-memory event producer instead.
 
 use crate::onboarding::schema::app_onboarding_request::AppDataSource;
 use crate::onboarding::schema::app_onboarding_request::FileStore;
+use crate::service::app_topic::app_topic;
+use crate::service::event_producer::{EventProducer, KafkaEventProducer};
+use crate::service::filestore_hint::HintChange;
+use crate::service::ingestion_control::IngestionState;
+use crate::service::ingestion_sla::{published_sources, record_ingestion_request};
 use crate::service::state::AppState;
+use crate::service::vector_store::VectorStoreConfig;
 use axum::{http::StatusCode, Json};
-use kafka_utils::kafka_producer_client::KafkaProClient;
 use kafka_utils::kafka_producer_client_builder::KafkaClientProdBuilder;
 use serde::Serialize;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tracing::{debug, error, info, instrument};
 
-/// Asynchronous function to create a Kafka client
+/// Asynchronous function to create a Kafka client, the in-memory event producer in the local development mode
 #[instrument(skip_all)]
 pub async fn create_kafka_client(
     app_state: &Arc<AppState>,
     app_name: &str,
-) -> Result<KafkaProClient, (StatusCode, Json<serde_json::Value>)> {
+) -> Result<Arc<dyn EventProducer>, (StatusCode, Json<serde_json::Value>)> {
+    if let Some(local_dev) = &app_state.local_dev {
+        return Ok(local_dev.events.clone());
+    }
     let brokers = app_state.app_settings.kafka_brokers.clone();
     // Creating a Kafka client instance
     match KafkaClientProdBuilder::default()
         .set_bootstrap_servers(brokers)
         .build()
     {
-        Ok(kafka_client_pro) => Ok(kafka_client_pro),
+        Ok(kafka_client_pro) => Ok(Arc::new(KafkaEventProducer {
+            client: kafka_client_pro,
+        })),
         Err(e) => {
             let error_message = format!("Failed to build Kafka Producer client. Error: {:?}", e);
             error!(
@@ -73,7 +84,7 @@ pub fn serialize_to_json<T: Serialize>(
 /// Asynchronous function to send data to Kafka
 #[instrument(skip_all)]
 pub async fn send_to_kafka(
-    kafka_client: &KafkaProClient,
+    kafka_client: &dyn EventProducer,
     app_name: Option<&str>,
     topic: &str,
     key: &str,
@@ -114,10 +125,21 @@ pub async fn app_onboard_or_update_notify_kafka(
     task_id: String,
 ) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
     let key = app_name;
-    let topic = app_state.app_settings.kafka_client.onboarding_topic.clone();
+    // The apps with their own topic are validated at onboarding, others share the onboarding topic
+    let topic = app_topic(app_state, app_name)
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| app_state.app_settings.kafka_client.onboarding_topic.clone());
     let kafka_client = create_kafka_client(app_state, app_name).await?;
     let trailing_message = &app_state.app_settings.kafka_trailing_message;
-    let message: (String, &AppDataSource, Option<&AppDataSource>, &String);
+    let vector_store = VectorStoreConfig::from_settings(&app_state.app_settings, app_name);
+    let message: (
+        String,
+        &AppDataSource,
+        Option<&AppDataSource>,
+        &VectorStoreConfig,
+        &String,
+    );
 
     // If updating an existing app, send the new and existing datasources to Kafka, only if they are different.
     if let Some(existing_datasource) = existing_app_datasource {
@@ -125,11 +147,18 @@ pub async fn app_onboard_or_update_notify_kafka(
             task_id,
             new_app_datasource,
             Some(existing_datasource),
+            &vector_store,
             trailing_message,
         );
     // If onboarding a new app, send the datasources to Kafka. There's no existing datasource in this case.
     } else {
-        message = (task_id, new_app_datasource, None, trailing_message);
+        message = (
+            task_id,
+            new_app_datasource,
+            None,
+            &vector_store,
+            trailing_message,
+        );
     }
     let serialized_message = serialize_to_json(&message, Some(app_name))?;
 
@@ -141,6 +170,12 @@ pub async fn app_onboard_or_update_notify_kafka(
         &serialized_message,
     )
     .await?;
+
+    // Track the ingestion of the new sources, a failure never fails the publish
+    let sources = published_sources(new_app_datasource, existing_app_datasource);
+    if let Err(e) = record_ingestion_request(app_state, app_name, &message.0, sources).await {
+        error!(app_name = app_name, message = e.to_string());
+    }
     Ok(())
 }
 
@@ -156,12 +191,139 @@ pub async fn app_deletion_notify_kafka(
     let key = app_name;
     let topic = app_state.app_settings.kafka_client.deletion_topic.clone();
     let kafka_client = create_kafka_client(app_state, app_name).await?;
-    let message: (String, &HashMap<String, Vec<FileStore>>, &str) = (task_id, filestore, sqs_key);
+    let vector_store = VectorStoreConfig::from_settings(&app_state.app_settings, app_name);
+    let message: (
+        String,
+        &HashMap<String, Vec<FileStore>>,
+        &str,
+        &VectorStoreConfig,
+    ) = (task_id, filestore, sqs_key, &vector_store);
     let serialized_message = serialize_to_json(&message, None)?;
     send_to_kafka(&kafka_client, None, &topic, key, &serialized_message).await?;
     Ok(())
 }
 
+/// Asynchronous function to notify Kafka about a change of an app's generated config. The message carries
+/// the generated config before the change and the changed fields.
+#[instrument(skip_all)]
+pub async fn app_config_change_notify_kafka(
+    app_state: &Arc<AppState>,
+    app_name: &str,
+    previous_config: &serde_json::Value,
+    changed_fields: &serde_json::Value,
+    task_id: String,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let key = app_name;
+    let topic = app_state
+        .app_settings
+        .kafka_client
+        .config_change_topic
+        .clone();
+    let kafka_client = create_kafka_client(app_state, app_name).await?;
+    let trailing_message = &app_state.app_settings.kafka_trailing_message;
+    let message = (task_id, previous_config, changed_fields, trailing_message);
+    let serialized_message = serialize_to_json(&message, Some(app_name))?;
+    send_to_kafka(
+        &kafka_client,
+        Some(app_name),
+        &topic,
+        key,
+        &serialized_message,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Asynchronous function to notify Kafka about a pause/resume of the ingestion of an app. The message carries the
+/// requested ingestion state.
+#[instrument(skip_all)]
+pub async fn app_ingestion_control_notify_kafka(
+    app_state: &Arc<AppState>,
+    app_name: &str,
+    state: IngestionState,
+    task_id: String,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let key = app_name;
+    let topic = app_state
+        .app_settings
+        .kafka_client
+        .ingestion_control_topic
+        .clone();
+    let kafka_client = create_kafka_client(app_state, app_name).await?;
+    let trailing_message = &app_state.app_settings.kafka_trailing_message;
+    let message = (task_id, state, trailing_message);
+    let serialized_message = serialize_to_json(&message, Some(app_name))?;
+    send_to_kafka(
+        &kafka_client,
+        Some(app_name),
+        &topic,
+        key,
+        &serialized_message,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Asynchronous function to notify Kafka about an added, updated or deleted hint of a filestore of an app, so the
+/// ingestion pipeline re-summarizes the prefix of the hint.
+#[instrument(skip_all)]
+pub async fn app_hint_change_notify_kafka(
+    app_state: &Arc<AppState>,
+    app_name: &str,
+    change: &HintChange,
+    task_id: String,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let key = app_name;
+    let topic = app_state
+        .app_settings
+        .kafka_client
+        .hint_change_topic
+        .clone();
+    let kafka_client = create_kafka_client(app_state, app_name).await?;
+    let trailing_message = &app_state.app_settings.kafka_trailing_message;
+    let message = (task_id, change, trailing_message);
+    let serialized_message = serialize_to_json(&message, Some(app_name))?;
+    send_to_kafka(
+        &kafka_client,
+        Some(app_name),
+        &topic,
+        key,
+        &serialized_message,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Asynchronous function to re-publish the failed sources of an app to Kafka, so the ingestion pipeline ingests them
+/// again. The message carries the sources, keyed like the `query` of the ingestion errors.
+#[instrument(skip_all)]
+pub async fn app_ingestion_retry_notify_kafka(
+    app_state: &Arc<AppState>,
+    app_name: &str,
+    sources: &[String],
+    task_id: String,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let key = app_name;
+    let topic = app_state
+        .app_settings
+        .kafka_client
+        .ingestion_retry_topic
+        .clone();
+    let kafka_client = create_kafka_client(app_state, app_name).await?;
+    let trailing_message = &app_state.app_settings.kafka_trailing_message;
+    let message = (task_id, sources, trailing_message);
+    let serialized_message = serialize_to_json(&message, Some(app_name))?;
+    send_to_kafka(
+        &kafka_client,
+        Some(app_name),
+        &topic,
+        key,
+        &serialized_message,
+    )
+    .await?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
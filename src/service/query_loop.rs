@@ -0,0 +1,252 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the circuit breaking the runaway query loops of the apps, e.g. a buggy integration retrying
+//! the same failed query all night.
+//! The identical retrievals of an end user (same app, user ID and request, hashed) are counted over a fixed window of
+//! `query_loop.window_seconds` (60 by default), opened by the first of them. Once `query_loop.max_repeats` (10 by
+//! default) of them are executed in the window, the next ones are rejected with the reference ID of the last executed
+//! one, whose history document holds their result, and the seconds left until the window ends.
+//! The windows are stored in `query_loop.collection` (`query-loops` by default), which is expected to carry a TTL index
+//! on `expires_at`, and hold the hash of the query rather than the query itself.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{QueryLoopSettings, TresleFacadeServiceSettings};
+use crate::service::state::AppState;
+use api_utils::retrieval_model::RetrievalRequest;
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::{self, doc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Default length of the windows of the identical retrievals, in seconds.
+const DEFAULT_WINDOW_SECONDS: u64 = 60;
+/// Default number of identical retrievals executed in a window.
+const DEFAULT_MAX_REPEATS: u32 = 10;
+/// Default collection of the windows of the identical retrievals.
+const DEFAULT_QUERY_LOOP_COLLECTION: &str = "query-loops";
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueryLoopError {
+    #[error("Failed to read the query loop window: {0}")]
+    Read(String),
+    #[error("Failed to store the query loop window: {0}")]
+    Store(String),
+}
+
+/// Query loop circuit options: window, repeat limit and storage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryLoopOptions {
+    pub enabled: bool,
+    pub window: Duration,
+    pub max_repeats: u32,
+    pub collection: String,
+}
+
+impl SettingsOptions for QueryLoopOptions {
+    type Settings = QueryLoopSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&QueryLoopSettings> {
+        settings.query_loop.as_ref()
+    }
+
+    fn from_settings(settings: Option<&QueryLoopSettings>) -> Self {
+        QueryLoopOptions {
+            enabled: settings
+                .and_then(|settings| settings.enabled)
+                .unwrap_or(true),
+            window: Duration::seconds(
+                settings
+                    .and_then(|settings| settings.window_seconds)
+                    .unwrap_or(DEFAULT_WINDOW_SECONDS) as i64,
+            ),
+            max_repeats: settings
+                .and_then(|settings| settings.max_repeats)
+                .unwrap_or(DEFAULT_MAX_REPEATS),
+            collection: settings
+                .and_then(|settings| settings.collection.clone())
+                .unwrap_or_else(|| DEFAULT_QUERY_LOOP_COLLECTION.to_string()),
+        }
+    }
+}
+
+/// Window of the identical retrievals of an end user, as stored.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueryLoopWindow {
+    /// Hash of the app, the user ID and the request.
+    pub key: String,
+    pub app_name: String,
+    pub window_start: DateTime<Utc>,
+    /// Identical retrievals received in the window, the short-circuited ones included.
+    pub repeats: u32,
+    /// Reference ID of the last executed retrieval.
+    pub reference_id: String,
+}
+
+/// Outcome of the query loop check of a retrieval.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryLoopDecision {
+    /// The retrieval is executed.
+    Allowed,
+    /// The retrieval is answered with the retrieval of `reference_id`, the `repeats`th identical one of the window,
+    /// which ends in `retry_after_secs`.
+    ShortCircuited {
+        reference_id: String,
+        repeats: u32,
+        retry_after_secs: u64,
+    },
+}
+
+/// Returns the key of the identical retrievals of an end user: the hex SHA-256 of the app, user ID and request.
+pub fn query_loop_key(app_name: &str, user_id: &str, body: &RetrievalRequest) -> String {
+    let request = serde_json::to_string(body).unwrap_or_else(|_| body.query.clone());
+    format!(
+        "{:x}",
+        Sha256::digest(format!("{}\0{}\0{}", app_name, user_id, request).as_bytes())
+    )
+}
+
+/// Counts a retrieval in the window of its key. Returns the updated window and the decision for the retrieval.
+pub fn next_window(
+    window: Option<QueryLoopWindow>,
+    key: &str,
+    app_name: &str,
+    reference_id: &str,
+    options: &QueryLoopOptions,
+    now: DateTime<Utc>,
+) -> (QueryLoopWindow, QueryLoopDecision) {
+    match window {
+        Some(mut window) if now < window.window_start + options.window => {
+            window.repeats = window.repeats.saturating_add(1);
+            if window.repeats > options.max_repeats {
+                let remaining = window.window_start + options.window - now;
+                let decision = QueryLoopDecision::ShortCircuited {
+                    reference_id: window.reference_id.clone(),
+                    repeats: window.repeats,
+                    // Rounded up, so the retry lands past the window
+                    retry_after_secs: (remaining.num_milliseconds().max(0) as u64)
+                        .div_ceil(1000)
+                        .max(1),
+                };
+                return (window, decision);
+            }
+            window.reference_id = reference_id.to_string();
+            (window, QueryLoopDecision::Allowed)
+        }
+        _ => (
+            QueryLoopWindow {
+                key: key.to_string(),
+                app_name: app_name.to_string(),
+                window_start: now,
+                repeats: 1,
+                reference_id: reference_id.to_string(),
+            },
+            QueryLoopDecision::Allowed,
+        ),
+    }
+}
+
+/// Counts a retrieval of an end user against its identical retrievals. Always allowed when the circuit is disabled.
+pub async fn check_query_loop(
+    app_state: &AppState,
+    app_name: &str,
+    user_id: &str,
+    body: &RetrievalRequest,
+    reference_id: &str,
+) -> Result<QueryLoopDecision, QueryLoopError> {
+    let options = app_state.options::<QueryLoopOptions>();
+    if !options.enabled {
+        return Ok(QueryLoopDecision::Allowed);
+    }
+    let key = query_loop_key(app_name, user_id, body);
+    let stored = app_state
+        .db
+        .get_document(&options.collection, doc! {"key": &key})
+        .await
+        .map_err(|e| QueryLoopError::Read(e.to_string()))?;
+    let exists = stored.is_some();
+    let window = stored
+        .map(serde_json::from_value::<QueryLoopWindow>)
+        .transpose()
+        .map_err(|e| QueryLoopError::Read(e.to_string()))?;
+
+    let now = Utc::now();
+    let (window, decision) = next_window(window, &key, app_name, reference_id, &options, now);
+    let expires_at = window.window_start + options.window;
+    let mut document =
+        bson::to_document(&window).map_err(|e| QueryLoopError::Store(e.to_string()))?;
+    document.insert(
+        "expires_at",
+        bson::DateTime::from_millis(expires_at.timestamp_millis()),
+    );
+    let stored = if exists {
+        app_state
+            .db
+            .update_document(&options.collection, doc! {"key": &key}, document)
+            .await
+            .map(|_| ())
+    } else {
+        app_state
+            .db
+            .create_document(&options.collection, document)
+            .await
+            .map(|_| ())
+    };
+    stored.map_err(|e| QueryLoopError::Store(e.to_string()))?;
+    Ok(decision)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_next_window() {
+        let options = QueryLoopOptions {
+            max_repeats: 2,
+            ..QueryLoopOptions::from_settings(None)
+        };
+        let now = Utc::now();
+
+        let (window, decision) = next_window(None, "key", "app100", "ref-1", &options, now);
+        assert_eq!(decision, QueryLoopDecision::Allowed);
+        let (window, decision) = next_window(Some(window), "key", "app100", "ref-2", &options, now);
+        assert_eq!(decision, QueryLoopDecision::Allowed);
+        assert_eq!(window.reference_id, "ref-2");
+
+        // The third identical retrieval of the window is answered with the last executed one
+        let (window, decision) = next_window(Some(window), "key", "app100", "ref-3", &options, now);
+        assert_eq!(
+            decision,
+            QueryLoopDecision::ShortCircuited {
+                reference_id: "ref-2".to_string(),
+                repeats: 3,
+                retry_after_secs: options.window.num_seconds() as u64
+            }
+        );
+
+        // A new window is opened once it ends
+        let later = now + options.window;
+        let (window, decision) =
+            next_window(Some(window), "key", "app100", "ref-4", &options, later);
+        assert_eq!(decision, QueryLoopDecision::Allowed);
+        assert_eq!(window.repeats, 1);
+        assert_eq!(window.window_start, later);
+    }
+
+    #[test]
+    fn test_success_query_loop_key() {
+        let body: RetrievalRequest = serde_json::from_str(
+            &std::fs::read_to_string("src/test/retrieval_request.json").unwrap(),
+        )
+        .unwrap();
+        let key = query_loop_key("app100", "user", &body);
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, query_loop_key("app100", "user", &body));
+        assert_ne!(key, query_loop_key("app100", "other_user", &body));
+        assert_ne!(key, query_loop_key("app200", "user", &body));
+    }
+}
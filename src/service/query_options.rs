@@ -0,0 +1,321 @@
+/*
+ * Created Date:  Jul 10, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the shared query options of the DocumentDB aggregations and the guard of the paginated
+//! endpoints, configured by the `query_options` settings.
+//! Every aggregation goes through `AggregateExt::aggregate`, which enforces the time budget `max_time_ms`. An
+//! aggregation exceeding its budget (or rejected by the cluster with `MaxTimeMSExpired`) is answered with a 504
+//! status code instead of the generic 500, so the callers know to narrow their time range or filters.
+//! The aggregations on a `ClusterDb` with a driver database send `maxTimeMS` and `allowDiskUse` to the cluster, which
+//! stops the aggregations exceeding their budget. `DBTrait` does not take aggregate options, so the aggregations on a
+//! bare document client (e.g. without driver connections) keep a budget enforced by the service only.
+//! The guard rejects page limits above `max_page_limit` and `$skip` offsets above `max_page_offset` with a 400 status
+//! code; deeper pages are served by the cursor mode of the endpoints.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{QueryOptionsSettings, TresleFacadeServiceSettings};
+use crate::service::driver::ClusterDb;
+use api_utils::errors::error_interceptor::ErrorInterceptor;
+use async_trait::async_trait;
+use axum::{http::StatusCode, Json};
+use futures::TryStreamExt;
+use mongodb::bson::Document;
+use mongodb::options::AggregateOptions;
+use mongodb_utils::mongodb_client::DBTrait;
+use serde_json::json;
+use std::time::Duration;
+use tracing::error;
+
+/// Default time budget of an aggregation in milliseconds.
+pub const DEFAULT_AGGREGATION_MAX_TIME_MS: u64 = 30_000;
+/// Default maximum `limit` of a page.
+pub const DEFAULT_MAX_PAGE_LIMIT: usize = 1_000;
+/// Default maximum number of documents skipped to reach a page.
+pub const DEFAULT_MAX_PAGE_OFFSET: i64 = 100_000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueryError {
+    #[error("Aggregation on '{collection}' exceeded its time budget of {max_time_ms} ms. Narrow the time range or the filters and try again.")]
+    Timeout {
+        collection: String,
+        max_time_ms: u64,
+    },
+    #[error("{0}")]
+    Db(ErrorInterceptor),
+    #[error("{0}")]
+    Driver(String),
+    #[error("Invalid limit {limit}. The maximum limit is {max_limit}.")]
+    LimitTooLarge { limit: usize, max_limit: usize },
+    #[error("Page offset {offset} exceeds the maximum of {max_offset} documents. Narrow the filters or page with a cursor.")]
+    OffsetTooLarge { offset: i64, max_offset: i64 },
+}
+
+impl QueryError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            QueryError::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            QueryError::Db(_) | QueryError::Driver(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            QueryError::LimitTooLarge { .. } | QueryError::OffsetTooLarge { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+        }
+    }
+}
+
+impl From<QueryError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: QueryError) -> Self {
+        let status_code = e.status_code();
+        let error_message = e.to_string();
+        error!(ext_message = error_message, message = error_message);
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Aggregation time budget and pagination limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueryOptions {
+    /// Time budget of an aggregation in milliseconds, unbounded if `None`.
+    pub max_time_ms: Option<u64>,
+    pub max_page_limit: usize,
+    pub max_page_offset: i64,
+    /// Whether the aggregations may write temporary files on the cluster, for the stages exceeding its memory limit.
+    pub allow_disk_use: bool,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        QueryOptions {
+            max_time_ms: Some(DEFAULT_AGGREGATION_MAX_TIME_MS),
+            max_page_limit: DEFAULT_MAX_PAGE_LIMIT,
+            max_page_offset: DEFAULT_MAX_PAGE_OFFSET,
+            allow_disk_use: true,
+        }
+    }
+}
+
+impl SettingsOptions for QueryOptions {
+    type Settings = QueryOptionsSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&QueryOptionsSettings> {
+        settings.query_options.as_ref()
+    }
+
+    fn from_settings(settings: Option<&QueryOptionsSettings>) -> Self {
+        let defaults = QueryOptions::default();
+        let Some(settings) = settings else {
+            return defaults;
+        };
+        QueryOptions {
+            max_time_ms: settings.max_time_ms.or(defaults.max_time_ms),
+            max_page_limit: settings.max_page_limit.unwrap_or(defaults.max_page_limit),
+            max_page_offset: settings.max_page_offset.unwrap_or(defaults.max_page_offset),
+            allow_disk_use: settings.allow_disk_use.unwrap_or(defaults.allow_disk_use),
+        }
+    }
+}
+
+impl QueryOptions {
+    /// Options without a time budget, for the maintenance tasks reading whole collections.
+    pub fn unbounded(self) -> Self {
+        QueryOptions {
+            max_time_ms: None,
+            ..self
+        }
+    }
+
+    /// Rejects a requested page limit above `max_page_limit`.
+    pub fn check_limit(&self, limit: Option<usize>) -> Result<(), QueryError> {
+        match limit {
+            Some(limit) if limit > self.max_page_limit => Err(QueryError::LimitTooLarge {
+                limit,
+                max_limit: self.max_page_limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Rejects a `$skip` offset above `max_page_offset`.
+    pub fn check_offset(&self, offset: i64) -> Result<(), QueryError> {
+        if offset > self.max_page_offset {
+            return Err(QueryError::OffsetTooLarge {
+                offset,
+                max_offset: self.max_page_offset,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Aggregations running within the time budget of the query options.
+#[async_trait]
+pub trait AggregateExt {
+    async fn aggregate(
+        &self,
+        collection_name: &str,
+        pipeline: Vec<Document>,
+        options: &QueryOptions,
+    ) -> Result<Vec<serde_json::Value>, QueryError>;
+}
+
+#[async_trait]
+impl<T: DBTrait + Sync + Send + ?Sized> AggregateExt for T {
+    async fn aggregate(
+        &self,
+        collection_name: &str,
+        pipeline: Vec<Document>,
+        options: &QueryOptions,
+    ) -> Result<Vec<serde_json::Value>, QueryError> {
+        let timeout = |max_time_ms| QueryError::Timeout {
+            collection: collection_name.to_string(),
+            max_time_ms,
+        };
+        let aggregation = self.aggregation_ops_on_documents(collection_name, pipeline);
+        let result = match options.max_time_ms {
+            Some(max_time_ms) => {
+                tokio::time::timeout(Duration::from_millis(max_time_ms), aggregation)
+                    .await
+                    .map_err(|_| timeout(max_time_ms))?
+            }
+            None => aggregation.await,
+        };
+        result.map_err(|e| {
+            let e = ErrorInterceptor::from(e);
+            if is_time_limit_error(&e.to_string()) {
+                timeout(options.max_time_ms.unwrap_or_default())
+            } else {
+                QueryError::Db(e)
+            }
+        })
+    }
+}
+
+/// Aggregations sending their time budget and disk use to the cluster through the driver database, when connected.
+#[async_trait]
+impl AggregateExt for ClusterDb {
+    async fn aggregate(
+        &self,
+        collection_name: &str,
+        pipeline: Vec<Document>,
+        options: &QueryOptions,
+    ) -> Result<Vec<serde_json::Value>, QueryError> {
+        let Some(database) = self.database() else {
+            return self
+                .as_ref()
+                .aggregate(collection_name, pipeline, options)
+                .await;
+        };
+        let aggregate_options = AggregateOptions::builder()
+            .max_time(options.max_time_ms.map(Duration::from_millis))
+            .allow_disk_use(options.allow_disk_use)
+            .build();
+        let driver_error = |e: mongodb::error::Error| {
+            let message = e.to_string();
+            if is_time_limit_error(&message) {
+                QueryError::Timeout {
+                    collection: collection_name.to_string(),
+                    max_time_ms: options.max_time_ms.unwrap_or_default(),
+                }
+            } else {
+                QueryError::Driver(message)
+            }
+        };
+        let documents: Vec<Document> = database
+            .collection::<Document>(collection_name)
+            .aggregate(pipeline, aggregate_options)
+            .await
+            .map_err(driver_error)?
+            .try_collect()
+            .await
+            .map_err(driver_error)?;
+        // The documents are read as extended JSON, like the document clients read them
+        documents
+            .iter()
+            .map(|document| {
+                serde_json::to_value(document).map_err(|e| QueryError::Driver(e.to_string()))
+            })
+            .collect()
+    }
+}
+
+/// Returns true for the errors of the operations exceeding the time limit of the cluster (code 50).
+fn is_time_limit_error(message: &str) -> bool {
+    message.contains("MaxTimeMSExpired") || message.contains("exceeded time limit")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_query_options_from_settings() {
+        assert_eq!(QueryOptions::from_settings(None), QueryOptions::default());
+        let settings = QueryOptionsSettings {
+            max_time_ms: Some(5_000),
+            max_page_limit: None,
+            max_page_offset: Some(1_000),
+            allow_disk_use: Some(false),
+        };
+        let options = QueryOptions::from_settings(Some(&settings));
+        assert_eq!(options.max_time_ms, Some(5_000));
+        assert_eq!(options.max_page_limit, DEFAULT_MAX_PAGE_LIMIT);
+        assert_eq!(options.max_page_offset, 1_000);
+        assert!(!options.allow_disk_use);
+        assert_eq!(options.unbounded().max_time_ms, None);
+    }
+
+    #[test]
+    fn test_failure_query_options_page_guard() {
+        let options = QueryOptions::default();
+        assert!(options.check_limit(None).is_ok());
+        assert!(options.check_limit(Some(DEFAULT_MAX_PAGE_LIMIT)).is_ok());
+        let e = options.check_limit(Some(1_000_000)).unwrap_err();
+        assert_eq!(e.status_code(), StatusCode::BAD_REQUEST);
+
+        assert!(options.check_offset(DEFAULT_MAX_PAGE_OFFSET).is_ok());
+        let e = options
+            .check_offset(DEFAULT_MAX_PAGE_OFFSET + 1)
+            .unwrap_err();
+        assert!(matches!(e, QueryError::OffsetTooLarge { .. }));
+    }
+
+    #[test]
+    fn test_success_query_error_status_code() {
+        let e = QueryError::Timeout {
+            collection: "metric".to_string(),
+            max_time_ms: 1,
+        };
+        let (status_code, _) = e.into();
+        assert_eq!(status_code, StatusCode::GATEWAY_TIMEOUT);
+        assert!(is_time_limit_error(
+            "Command failed: operation exceeded time limit (MaxTimeMSExpired)"
+        ));
+    }
+
+    #[test]
+    fn test_success_aggregate() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            let result = app_state
+                .db
+                .aggregate(
+                    &app_state.app_settings.mongo_db.mongo_db_app_collection,
+                    vec![doc! { "$limit": 1 }],
+                    &app_state.options::<QueryOptions>(),
+                )
+                .await;
+            assert!(result.is_ok());
+        });
+    }
+}
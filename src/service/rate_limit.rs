@@ -0,0 +1,511 @@
+/*
+ * Created Date:  Jun 29, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the per-user rate limiting of the retrievals.
+//! Apps configure a `UserRateLimit` (max requests per window) for their end users, keyed by
+//! `user_details.user_id`, so a single end user can no longer exhaust the quota of the whole app.
+//! `InMemoryRateLimitStore` keeps the request timestamps in the process, over a sliding window, and suits single
+//! replica deployments. The users idle for a whole window are evicted, so the store doesn't grow with every user ever
+//! seen.
+//! `DocumentDbRateLimitStore` shares the counters between replicas through a DocumentDB collection: a bucket document
+//! per app, user and fixed window of `window_seconds`, counted with a single atomic `$inc` upsert on the driver
+//! connection, so concurrent requests of the replicas can't all pass a nearly full window. The bucket `_id` is derived
+//! from the app, the user and the window, so two first requests can't create two buckets.
+//! The limit applies to a sliding window counter: the count of the current bucket plus the count of the previous one,
+//! weighted by the part of the previous window still in the sliding window, so a user can't send twice the limit
+//! around the end of a bucket. The rejected requests are uncounted. The collection is expected to carry a TTL index on
+//! `expires_at`, a BSON date, set to the end of the window after the bucket so the expired buckets are purged.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{RateLimitStoreKind, TresleFacadeServiceSettings};
+use crate::onboarding::schema::app_onboarding_request::UserRateLimit;
+use crate::service::driver::{ClusterDb, DriverError};
+use crate::service::history_upsert::is_duplicate_key_error;
+use crate::service::query_options::QueryOptions;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use mongodb::bson::{self, doc, Document};
+use mongodb::options::{FindOneAndUpdateOptions, FindOneOptions, ReturnDocument};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Default collection of the requests counted by the DocumentDB rate limit store.
+pub const DEFAULT_RATE_LIMIT_COLLECTION: &str = "user-rate-limits";
+/// Interval between the evictions of the idle users of the in-memory store.
+const EVICTION_INTERVAL_SECONDS: i64 = 60;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RateLimitError {
+    #[error("Failed to read rate limit counters: {0}")]
+    Read(String),
+    #[error("Failed to store rate limit counter: {0}")]
+    Store(String),
+}
+
+/// Outcome of a rate limited request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitDecision {
+    /// The request is counted, `remaining` requests are left in the window.
+    Allowed { remaining: u32 },
+    /// The window is exhausted, a request can be counted again in `retry_after_secs` seconds.
+    Limited { retry_after_secs: u64 },
+}
+
+/// Counters of the requests of the end users of the apps.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Counts a request of `user_id` at `now`, unless the window of the limit is exhausted.
+    async fn hit(
+        &self,
+        db: &ClusterDb,
+        app_name: &str,
+        user_id: &str,
+        limit: &UserRateLimit,
+        now: DateTime<Utc>,
+    ) -> Result<RateLimitDecision, RateLimitError>;
+}
+
+/// Seconds until the oldest request of a full window expires, at least one.
+fn retry_after_secs(oldest: DateTime<Utc>, limit: &UserRateLimit, now: DateTime<Utc>) -> u64 {
+    let expires_at = oldest + Duration::seconds(limit.window_seconds as i64);
+    let millis = (expires_at - now).num_milliseconds().max(0) as u64;
+    millis.div_ceil(1000).max(1)
+}
+
+/// Requests of a user in the window, with the length of the window.
+#[derive(Debug)]
+struct UserWindow {
+    requests: VecDeque<DateTime<Utc>>,
+    length: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Windows {
+    users: HashMap<(String, String), UserWindow>,
+    last_eviction: Option<DateTime<Utc>>,
+}
+
+impl Windows {
+    /// Evicts the users without requests in their window, at most once per eviction interval.
+    fn evict_idle(&mut self, now: DateTime<Utc>) {
+        let last_eviction = *self.last_eviction.get_or_insert(now);
+        if now - last_eviction < Duration::seconds(EVICTION_INTERVAL_SECONDS) {
+            return;
+        }
+        self.users.retain(|_, window| {
+            window
+                .requests
+                .back()
+                .is_some_and(|newest| *newest > now - window.length)
+        });
+        self.last_eviction = Some(now);
+    }
+}
+
+/// Keeps the timestamps of the requests in the window in memory, per app and user.
+#[derive(Debug, Default)]
+pub struct InMemoryRateLimitStore {
+    windows: Mutex<Windows>,
+}
+
+#[async_trait]
+impl RateLimitStore for InMemoryRateLimitStore {
+    async fn hit(
+        &self,
+        _db: &ClusterDb,
+        app_name: &str,
+        user_id: &str,
+        limit: &UserRateLimit,
+        now: DateTime<Utc>,
+    ) -> Result<RateLimitDecision, RateLimitError> {
+        let length = Duration::seconds(limit.window_seconds as i64);
+        let window_start = now - length;
+        let mut windows = self
+            .windows
+            .lock()
+            .map_err(|e| RateLimitError::Read(e.to_string()))?;
+        windows.evict_idle(now);
+        let window = windows
+            .users
+            .entry((app_name.to_string(), user_id.to_string()))
+            .or_insert_with(|| UserWindow {
+                requests: VecDeque::new(),
+                length,
+            });
+        // The limit of the app may have changed since the last request
+        window.length = length;
+        let requests = &mut window.requests;
+        while requests
+            .front()
+            .is_some_and(|oldest| *oldest <= window_start)
+        {
+            requests.pop_front();
+        }
+        match requests.front() {
+            Some(oldest) if requests.len() >= limit.max_requests as usize => {
+                Ok(RateLimitDecision::Limited {
+                    retry_after_secs: retry_after_secs(*oldest, limit, now),
+                })
+            }
+            _ => {
+                requests.push_back(now);
+                Ok(RateLimitDecision::Allowed {
+                    remaining: limit.max_requests.saturating_sub(requests.len() as u32),
+                })
+            }
+        }
+    }
+}
+
+/// `_id` of the bucket document counting the requests of a user in a fixed window.
+fn bucket_id(app_name: &str, user_id: &str, limit: &UserRateLimit, index: i64) -> String {
+    format!(
+        "{}:{}:{}:{}",
+        app_name, limit.window_seconds, index, user_id
+    )
+}
+
+/// Fixed window of a limit holding `now`: its index since the Unix epoch, its start and its end.
+fn fixed_window(limit: &UserRateLimit, now: DateTime<Utc>) -> (i64, DateTime<Utc>, DateTime<Utc>) {
+    let length = (limit.window_seconds as i64).max(1);
+    let index = now.timestamp().div_euclid(length);
+    let start = Utc.timestamp_opt(index * length, 0).single().unwrap_or(now);
+    (index, start, start + Duration::seconds(length))
+}
+
+/// Decides a request from the counts of the previous and the current buckets, the request included in `current`.
+/// The previous count is weighted by the overlap of its window with the sliding window ending at `now`.
+fn sliding_window_decision(
+    previous: i64,
+    current: i64,
+    limit: &UserRateLimit,
+    now: DateTime<Utc>,
+) -> RateLimitDecision {
+    let (_, _, window_end) = fixed_window(limit, now);
+    let length_millis = (limit.window_seconds as i64).max(1) * 1000;
+    let overlap_millis = (window_end - now)
+        .num_milliseconds()
+        .clamp(0, length_millis);
+    let weighted_previous = previous as f64 * overlap_millis as f64 / length_millis as f64;
+    let max_requests = limit.max_requests as i64;
+    if weighted_previous + current as f64 <= max_requests as f64 {
+        let estimate = (weighted_previous + current as f64).ceil() as i64;
+        return RateLimitDecision::Allowed {
+            remaining: (max_requests - estimate).max(0) as u32,
+        };
+    }
+    // Without this request, the next one passes once the weight of the previous bucket leaves room for it, or once
+    // the current bucket becomes the previous one if it is full on its own
+    let room = max_requests - current;
+    let wait_millis = if room < 0 || previous == 0 {
+        overlap_millis
+    } else {
+        overlap_millis - room * length_millis / previous
+    };
+    RateLimitDecision::Limited {
+        retry_after_secs: (wait_millis.max(0) as u64).div_ceil(1000).max(1),
+    }
+}
+
+/// Counts the requests in a bucket document per app, user and fixed window in a DocumentDB collection, shared by the
+/// replicas, and limits them over a sliding window.
+#[derive(Debug, Clone)]
+pub struct DocumentDbRateLimitStore {
+    pub collection_name: String,
+    pub query_options: QueryOptions,
+}
+
+#[async_trait]
+impl RateLimitStore for DocumentDbRateLimitStore {
+    async fn hit(
+        &self,
+        db: &ClusterDb,
+        app_name: &str,
+        user_id: &str,
+        limit: &UserRateLimit,
+        now: DateTime<Utc>,
+    ) -> Result<RateLimitDecision, RateLimitError> {
+        let database = db
+            .database()
+            .ok_or_else(|| RateLimitError::Store(DriverError::NotConnected.to_string()))?;
+        let (index, window_start, window_end) = fixed_window(limit, now);
+        let max_time = self
+            .query_options
+            .max_time_ms
+            .map(std::time::Duration::from_millis);
+        let collection = database.collection::<Document>(&self.collection_name);
+        let previous = collection
+            .find_one(
+                doc! {"_id": bucket_id(app_name, user_id, limit, index - 1)},
+                FindOneOptions::builder().max_time(max_time).build(),
+            )
+            .await
+            .map_err(|e| RateLimitError::Read(e.to_string()))?
+            .map(|bucket| bucket_count(&bucket))
+            .unwrap_or_default();
+
+        let filter = doc! {"_id": bucket_id(app_name, user_id, limit, index)};
+        let update = doc! {
+            "$inc": { "count": 1_i64 },
+            "$setOnInsert": {
+                "app_name": app_name,
+                "user_id": user_id,
+                "window_start": bson::DateTime::from_millis(window_start.timestamp_millis()),
+                // The bucket is the previous one of the next window
+                "expires_at": bson::DateTime::from_millis(
+                    (window_end + Duration::seconds(limit.window_seconds as i64)).timestamp_millis()
+                ),
+            },
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .max_time(max_time)
+            .build();
+        // A single atomic round trip: the concurrent requests each get their own count
+        let bucket = match collection
+            .find_one_and_update(filter.clone(), update.clone(), options.clone())
+            .await
+        {
+            // A concurrent first request inserted the bucket, which is now incremented
+            Err(e) if is_duplicate_key_error(&e.to_string()) => {
+                collection
+                    .find_one_and_update(filter, update, options)
+                    .await
+            }
+            bucket => bucket,
+        };
+        let count = bucket
+            .map_err(|e| RateLimitError::Store(e.to_string()))?
+            .map(|bucket| bucket_count(&bucket))
+            .unwrap_or(1);
+        let decision = sliding_window_decision(previous, count, limit, now);
+        if matches!(decision, RateLimitDecision::Limited { .. }) {
+            // The rejected request doesn't count against the next windows
+            collection
+                .update_one(filter, doc! {"$inc": { "count": -1_i64 }}, None)
+                .await
+                .map_err(|e| RateLimitError::Store(e.to_string()))?;
+        }
+        Ok(decision)
+    }
+}
+
+/// Count of a bucket document, zero if missing.
+fn bucket_count(bucket: &Document) -> i64 {
+    match bucket.get("count") {
+        Some(bson::Bson::Int64(count)) => *count,
+        Some(bson::Bson::Int32(count)) => *count as i64,
+        _ => 0,
+    }
+}
+
+/// Builds the rate limit store from the settings. The counters are kept in memory unless configured otherwise.
+pub fn store_from_settings(app_settings: &TresleFacadeServiceSettings) -> Box<dyn RateLimitStore> {
+    match app_settings.rate_limit.as_ref() {
+        Some(rate_limit) if rate_limit.store == RateLimitStoreKind::DocumentDb => {
+            let collection_name = rate_limit
+                .collection
+                .clone()
+                .unwrap_or_else(|| DEFAULT_RATE_LIMIT_COLLECTION.to_string());
+            Box::new(DocumentDbRateLimitStore {
+                collection_name,
+                query_options: QueryOptions::resolve(app_settings),
+            })
+        }
+        _ => Box::new(InMemoryRateLimitStore::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_retry_after_secs() {
+        let limit = UserRateLimit {
+            max_requests: 1,
+            window_seconds: 60,
+        };
+        let now = Utc::now();
+        assert_eq!(retry_after_secs(now, &limit, now), 60);
+        assert_eq!(
+            retry_after_secs(now - Duration::milliseconds(59_500), &limit, now),
+            1
+        );
+        assert_eq!(
+            retry_after_secs(now - Duration::seconds(120), &limit, now),
+            1
+        );
+    }
+
+    #[test]
+    fn test_success_fixed_window() {
+        let limit = UserRateLimit {
+            max_requests: 1,
+            window_seconds: 60,
+        };
+        let now = Utc.timestamp_opt(1_721_988_030, 0).unwrap();
+        let (index, start, end) = fixed_window(&limit, now);
+        assert_eq!(index, 28_699_800);
+        assert_eq!(start, Utc.timestamp_opt(1_721_988_000, 0).unwrap());
+        assert_eq!(end, Utc.timestamp_opt(1_721_988_060, 0).unwrap());
+        // The next window starts a new bucket
+        assert_eq!(fixed_window(&limit, end).0, index + 1);
+        assert_eq!(
+            bucket_id("app100", "psn_1", &limit, index),
+            "app100:60:28699800:psn_1"
+        );
+    }
+
+    #[test]
+    fn test_success_sliding_window_decision() {
+        let limit = UserRateLimit {
+            max_requests: 2,
+            window_seconds: 60,
+        };
+        let window_start = Utc.timestamp_opt(1_721_988_000, 0).unwrap();
+        let at = |seconds: i64| window_start + Duration::seconds(seconds);
+
+        assert_eq!(
+            sliding_window_decision(0, 1, &limit, at(10)),
+            RateLimitDecision::Allowed { remaining: 1 }
+        );
+        assert_eq!(
+            sliding_window_decision(0, 2, &limit, at(10)),
+            RateLimitDecision::Allowed { remaining: 0 }
+        );
+        // The current bucket is full on its own until the end of its window
+        assert_eq!(
+            sliding_window_decision(0, 3, &limit, at(20)),
+            RateLimitDecision::Limited {
+                retry_after_secs: 40
+            }
+        );
+    }
+
+    #[test]
+    fn test_success_sliding_window_decision_across_buckets() {
+        let limit = UserRateLimit {
+            max_requests: 2,
+            window_seconds: 60,
+        };
+        let window_start = Utc.timestamp_opt(1_721_988_000, 0).unwrap();
+        let at = |seconds: i64| window_start + Duration::seconds(seconds);
+
+        // Two requests at the end of the previous bucket still fill the window just after the boundary, where fixed
+        // buckets would allow two more
+        assert_eq!(
+            sliding_window_decision(2, 1, &limit, at(1)),
+            RateLimitDecision::Limited {
+                retry_after_secs: 29
+            }
+        );
+        // Half way through the bucket, only one of the previous requests is left in the window
+        assert_eq!(
+            sliding_window_decision(2, 1, &limit, at(30)),
+            RateLimitDecision::Allowed { remaining: 0 }
+        );
+        assert_eq!(
+            sliding_window_decision(2, 1, &limit, at(45)),
+            RateLimitDecision::Allowed { remaining: 0 }
+        );
+        assert_eq!(
+            sliding_window_decision(2, 2, &limit, at(50)),
+            RateLimitDecision::Limited {
+                retry_after_secs: 10
+            }
+        );
+    }
+
+    #[test]
+    fn test_success_in_memory_rate_limit_store_evicts_idle_users() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let store = InMemoryRateLimitStore::default();
+            let limit = UserRateLimit {
+                max_requests: 2,
+                window_seconds: 10,
+            };
+            let now = Utc::now();
+            let db = &app_state.db;
+
+            store.hit(db, "app100", "user1", &limit, now).await.unwrap();
+            store
+                .hit(db, "app100", "user2", &limit, now + Duration::seconds(55))
+                .await
+                .unwrap();
+            assert_eq!(store.windows.lock().unwrap().users.len(), 2);
+            // user1 is idle for longer than its window when the eviction runs, user2 is not
+            store
+                .hit(db, "app100", "user3", &limit, now + Duration::seconds(60))
+                .await
+                .unwrap();
+            let windows = store.windows.lock().unwrap();
+            assert_eq!(windows.users.len(), 2);
+            assert!(!windows
+                .users
+                .contains_key(&("app100".to_string(), "user1".to_string())));
+        });
+    }
+
+    #[test]
+    fn test_success_in_memory_rate_limit_store_sliding_window() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState for the DB handle, unused by the in-memory store
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let store = InMemoryRateLimitStore::default();
+            let limit = UserRateLimit {
+                max_requests: 2,
+                window_seconds: 60,
+            };
+            let now = Utc::now();
+            let db = &app_state.db;
+
+            assert_eq!(
+                store.hit(db, "app100", "user1", &limit, now).await.unwrap(),
+                RateLimitDecision::Allowed { remaining: 1 }
+            );
+            assert_eq!(
+                store
+                    .hit(db, "app100", "user1", &limit, now + Duration::seconds(10))
+                    .await
+                    .unwrap(),
+                RateLimitDecision::Allowed { remaining: 0 }
+            );
+            assert_eq!(
+                store
+                    .hit(db, "app100", "user1", &limit, now + Duration::seconds(20))
+                    .await
+                    .unwrap(),
+                RateLimitDecision::Limited {
+                    retry_after_secs: 40
+                }
+            );
+            // Other users of the app keep their own window
+            assert_eq!(
+                store
+                    .hit(db, "app100", "user2", &limit, now + Duration::seconds(20))
+                    .await
+                    .unwrap(),
+                RateLimitDecision::Allowed { remaining: 1 }
+            );
+            // The first request slides out of the window
+            assert_eq!(
+                store
+                    .hit(db, "app100", "user1", &limit, now + Duration::seconds(61))
+                    .await
+                    .unwrap(),
+                RateLimitDecision::Allowed { remaining: 0 }
+            );
+        });
+    }
+}
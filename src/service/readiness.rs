@@ -0,0 +1,414 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the readiness of the apps, the share of their sources indexed by the ingestion.
+//! The ingestion reports the status of every source of an app (`pending`, `ingesting`, `indexed` or `failed`) on the
+//! `readiness.status_topic` Kafka topic, as `{"app_name", "source", "status"}`; the sources are keyed like the node
+//! statistics, the filestore URL (`s3://bucket/prefix`) for filestores and the table for datastores. The consumer
+//! stores the last status of each source in `readiness.collection` (`ingestion-status` by default).
+//! An app is ready once `readiness.threshold_percent` (80 by default) of the sources of its datasource are indexed;
+//! an app without sources is ready. The readiness of an app is returned by the app GET handler, and with
+//! `readiness.mode` set to `warn` or `reject` the retrievals of the apps which are not ready carry a warning or are
+//! rejected, instead of returning empty answers right after onboarding.
+//! The consumer is not started in the local development mode.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{
+    ReadinessMode, ReadinessSettings, TresleFacadeServiceSettings,
+};
+use crate::service::app_repository::AppRepository;
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::state::AppState;
+use chrono::Utc;
+use mongodb::bson::{self, doc};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::error::KafkaError;
+use rdkafka::message::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info};
+use utoipa::ToSchema;
+
+/// Default percentage of the sources of an app to index before the app is ready.
+const DEFAULT_THRESHOLD_PERCENT: u8 = 80;
+/// Default collection of the ingestion status of the sources.
+const DEFAULT_READINESS_COLLECTION: &str = "ingestion-status";
+/// Delay before receiving again after a consumer error.
+const CONSUMER_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReadinessError {
+    #[error("Failed to read the ingestion status of app '{app_name}'. Error: {message}")]
+    Read { app_name: String, message: String },
+    #[error("Failed to store the ingestion status of source '{source_key}' of app '{app_name}'. Error: {message}")]
+    Store {
+        app_name: String,
+        source_key: String,
+        message: String,
+    },
+}
+
+/// App readiness options: gating mode, threshold, ingestion status topic and collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadinessOptions {
+    pub mode: ReadinessMode,
+    pub threshold_percent: u8,
+    pub status_topic: Option<String>,
+    pub collection: String,
+}
+
+impl SettingsOptions for ReadinessOptions {
+    type Settings = ReadinessSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&ReadinessSettings> {
+        settings.readiness.as_ref()
+    }
+
+    fn from_settings(settings: Option<&ReadinessSettings>) -> Self {
+        ReadinessOptions {
+            mode: settings
+                .and_then(|settings| settings.mode)
+                .unwrap_or_default(),
+            threshold_percent: settings
+                .and_then(|settings| settings.threshold_percent)
+                .unwrap_or(DEFAULT_THRESHOLD_PERCENT)
+                .min(100),
+            status_topic: settings.and_then(|settings| settings.status_topic.clone()),
+            collection: settings
+                .and_then(|settings| settings.collection.clone())
+                .unwrap_or_else(|| DEFAULT_READINESS_COLLECTION.to_string()),
+        }
+    }
+}
+
+/// Ingestion status of a source.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceStatus {
+    /// No status reported yet.
+    #[default]
+    Pending,
+    Ingesting,
+    Indexed,
+    Failed,
+}
+
+/// Ingestion status event of a source, as consumed from Kafka.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IngestionStatusEvent {
+    pub app_name: String,
+    pub source: String,
+    pub status: SourceStatus,
+}
+
+/// Ingestion status of a source of an app.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct SourceReadiness {
+    pub source: String,
+    pub status: SourceStatus,
+}
+
+/// Readiness of an app.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct AppReadiness {
+    /// Whether `threshold_percent` of the sources are indexed.
+    pub ready: bool,
+    pub threshold_percent: u8,
+    pub indexed_percent: u8,
+    pub indexed_sources: usize,
+    pub total_sources: usize,
+    pub sources: Vec<SourceReadiness>,
+}
+
+impl AppReadiness {
+    /// Returns the message of an app which is not ready.
+    pub fn message(&self, app_name: &str) -> String {
+        format!(
+            "App '{}' is not ready yet: {} of its {} sources are indexed ({}%, {}% required).",
+            app_name,
+            self.indexed_sources,
+            self.total_sources,
+            self.indexed_percent,
+            self.threshold_percent
+        )
+    }
+}
+
+/// Returns the key of a source, without trailing slash.
+fn source_key(source: &str) -> String {
+    source.trim().trim_end_matches('/').to_string()
+}
+
+/// Returns the sources of an app document: the URLs of its filestores and the tables of its datastores.
+pub fn app_sources(app: &serde_json::Value) -> Vec<String> {
+    let entries = |kind: &str| {
+        app.pointer(&format!("/app_datasource/{}", kind))
+            .and_then(serde_json::Value::as_object)
+            .into_iter()
+            .flat_map(|sources| sources.values())
+            .filter_map(serde_json::Value::as_array)
+            .flatten()
+            .collect::<Vec<_>>()
+    };
+    let filestore_urls = entries("filestore")
+        .into_iter()
+        .filter_map(|filestore| filestore.get("url"))
+        .filter_map(serde_json::Value::as_str);
+    let tables = entries("datastore")
+        .into_iter()
+        .filter_map(|datastore| datastore.get("tables"))
+        .filter_map(serde_json::Value::as_array)
+        .flatten()
+        .filter_map(|table| table.get("name"))
+        .filter_map(serde_json::Value::as_str);
+    let mut sources: Vec<String> = filestore_urls.chain(tables).map(source_key).collect();
+    sources.sort();
+    sources.dedup();
+    sources
+}
+
+/// Computes the readiness of an app from the status of its sources. Sources without status are pending.
+pub fn readiness(
+    sources: &[String],
+    statuses: &HashMap<String, SourceStatus>,
+    threshold_percent: u8,
+) -> AppReadiness {
+    let sources: Vec<SourceReadiness> = sources
+        .iter()
+        .map(|source| SourceReadiness {
+            source: source.clone(),
+            status: statuses.get(source).copied().unwrap_or_default(),
+        })
+        .collect();
+    let indexed_sources = sources
+        .iter()
+        .filter(|source| source.status == SourceStatus::Indexed)
+        .count();
+    let indexed_percent = match sources.len() {
+        0 => 100,
+        total_sources => (indexed_sources * 100 / total_sources) as u8,
+    };
+    AppReadiness {
+        ready: indexed_percent >= threshold_percent,
+        threshold_percent,
+        indexed_percent,
+        indexed_sources,
+        total_sources: sources.len(),
+        sources,
+    }
+}
+
+/// Returns the readiness of an app, from its sources, read with the app lookups `apps`, and their stored status.
+pub async fn app_readiness(
+    app_state: &AppState,
+    apps: &AppRepository<'_>,
+    app_name: &str,
+) -> Result<AppReadiness, ReadinessError> {
+    let read_error = |message: String| ReadinessError::Read {
+        app_name: app_name.to_string(),
+        message,
+    };
+    let options = app_state.options::<ReadinessOptions>();
+    let sources = apps
+        .ingestion_sources(app_name)
+        .await
+        .map_err(|e| read_error(e.to_string()))?;
+    let pipeline = vec![
+        doc! { "$match": { "app_name": app_name } },
+        doc! { "$project": { "_id": 0, "source": 1, "status": 1 } },
+    ];
+    let statuses = app_state
+        .db
+        .aggregate(
+            &options.collection,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await
+        .map_err(|e| read_error(e.to_string()))?
+        .into_iter()
+        .filter_map(|status| serde_json::from_value::<SourceReadiness>(status).ok())
+        .map(|status| (status.source, status.status))
+        .collect();
+    Ok(readiness(&sources, &statuses, options.threshold_percent))
+}
+
+/// Stores the status of a source reported by the ingestion.
+pub async fn record_ingestion_status(
+    app_state: &AppState,
+    event: &IngestionStatusEvent,
+) -> Result<(), ReadinessError> {
+    let source = source_key(&event.source);
+    let store_error = |message: String| ReadinessError::Store {
+        app_name: event.app_name.clone(),
+        source_key: source.clone(),
+        message,
+    };
+    let collection = app_state.options::<ReadinessOptions>().collection;
+    let filter = doc! {"app_name": &event.app_name, "source": &source};
+    let status = bson::to_bson(&event.status).map_err(|e| store_error(e.to_string()))?;
+    let document = doc! {
+        "app_name": &event.app_name,
+        "source": &source,
+        "status": status,
+        "updated_at": Utc::now().to_rfc3339(),
+    };
+    let exists = app_state
+        .db
+        .get_document(&collection, filter.clone())
+        .await
+        .map_err(|e| store_error(e.to_string()))?
+        .is_some();
+    let stored = if exists {
+        app_state
+            .db
+            .update_document(&collection, filter, document)
+            .await
+            .map(|_| ())
+    } else {
+        app_state
+            .db
+            .create_document(&collection, document)
+            .await
+            .map(|_| ())
+    };
+    stored.map_err(|e| store_error(e.to_string()))
+}
+
+/// Consumes the ingestion status events of `readiness.status_topic` and stores the status of the sources.
+pub async fn consume_ingestion_status(app_state: Arc<AppState>) {
+    let Some(topic) = app_state.options::<ReadinessOptions>().status_topic else {
+        return;
+    };
+    let kafka_client = &app_state.app_settings.kafka_client;
+    let consumer: StreamConsumer = match ClientConfig::new()
+        .set("bootstrap.servers", &app_state.app_settings.kafka_brokers)
+        .set("group.id", &kafka_client.group_id)
+        .set(
+            "enable.partition.eof",
+            &kafka_client.kafka_enable_partition_eof,
+        )
+        .set("auto.offset.reset", &kafka_client.kafka_auto_offset_reset)
+        .create()
+    {
+        Ok(consumer) => consumer,
+        Err(e) => {
+            error!(
+                message = format!(
+                    "Failed to build the ingestion status consumer. Error: {}",
+                    e
+                )
+            );
+            return;
+        }
+    };
+    if let Err(e) = consumer.subscribe(&[&topic]) {
+        error!(
+            message = format!(
+                "Failed to subscribe to the ingestion status topic '{}'. Error: {}",
+                topic, e
+            )
+        );
+        return;
+    }
+    info!(message = format!("Consuming the ingestion status events of '{}'.", topic));
+
+    loop {
+        let message = match consumer.recv().await {
+            Ok(message) => message,
+            Err(KafkaError::PartitionEOF(_)) => continue,
+            Err(e) => {
+                error!(
+                    message = format!("Failed to receive an ingestion status event. Error: {}", e)
+                );
+                tokio::time::sleep(CONSUMER_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let event = match message
+            .payload()
+            .map(serde_json::from_slice::<IngestionStatusEvent>)
+        {
+            Some(Ok(event)) => event,
+            Some(Err(e)) => {
+                error!(message = format!("Malformed ingestion status event. Error: {}", e));
+                continue;
+            }
+            None => continue,
+        };
+        match record_ingestion_status(&app_state, &event).await {
+            Ok(()) => debug!(
+                app_name = event.app_name,
+                message = format!(
+                    "Source '{}' of app '{}' is {:?}.",
+                    event.source, event.app_name, event.status
+                )
+            ),
+            Err(e) => error!(app_name = event.app_name, message = e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_success_app_sources() {
+        let app = json!({
+            "app_datasource": {
+                "filestore": {"s3": [{"url": "s3://bucket/docs/"}, {"url": "s3://bucket/images"}]},
+                "datastore": {"rds_mysql": [{"tables": [{"name": "orders"}, {"name": "users"}]}]}
+            }
+        });
+        assert_eq!(
+            app_sources(&app),
+            vec!["orders", "s3://bucket/docs", "s3://bucket/images", "users"]
+        );
+        assert!(app_sources(&json!({"app_name": "app100"})).is_empty());
+    }
+
+    #[test]
+    fn test_success_readiness() {
+        let sources: Vec<String> = ["a", "b", "c", "d", "e"].map(String::from).to_vec();
+        let mut statuses = HashMap::from([
+            ("a".to_string(), SourceStatus::Indexed),
+            ("b".to_string(), SourceStatus::Indexed),
+            ("c".to_string(), SourceStatus::Indexed),
+            ("d".to_string(), SourceStatus::Failed),
+        ]);
+
+        let app_readiness = readiness(&sources, &statuses, 80);
+        assert!(!app_readiness.ready);
+        assert_eq!(app_readiness.indexed_percent, 60);
+        assert_eq!(app_readiness.sources[4].status, SourceStatus::Pending);
+
+        statuses.insert("e".to_string(), SourceStatus::Indexed);
+        let app_readiness = readiness(&sources, &statuses, 80);
+        assert!(app_readiness.ready);
+        assert_eq!(app_readiness.indexed_sources, 4);
+
+        // An app without sources is ready
+        assert!(readiness(&[], &statuses, 80).ready);
+    }
+
+    #[test]
+    fn test_success_ingestion_status_event() {
+        let event: IngestionStatusEvent = serde_json::from_str(
+            r#"{"app_name": "app100", "source": "s3://bucket/docs", "status": "indexed"}"#,
+        )
+        .unwrap();
+        assert_eq!(event.status, SourceStatus::Indexed);
+        assert!(serde_json::from_str::<IngestionStatusEvent>(
+            r#"{"app_name": "app100", "source": "orders", "status": "done"}"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,167 @@
+/*
+ * Created Date:  Jun 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the data residency routing of the app specific collections.
+//! Additional DocumentDB clusters are configured by name (e.g. `eu-west-1`) in the residency settings.
+//! The `residency` of an app is stored in its app document and selects the cluster holding the app
+//! specific collections (history, knowledge nodes, errors, logs, ...). Apps without residency, and the
+//! shared collections (apps, IDs, UI summary), stay in the primary cluster.
+//! `copy_app_collections` copies the app specific collections of an app from one cluster to another.
+//!
+
+use crate::service::driver::ClusterDb;
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use axum::{http::StatusCode, Json};
+use mongodb::bson::{doc, Bson};
+use mongodb_utils::mongodb_client::DBTrait;
+use serde_json::json;
+use tracing::{debug, error, info};
+
+/// Suffixes of the app specific collections, named `{app_name}-{suffix}`.
+pub const APP_COLLECTION_SUFFIXES: [&str; 9] = [
+    "audit-microservices",
+    "general",
+    "error",
+    "history",
+    "logs",
+    "metric",
+    "multimodal",
+    "shadow",
+    "text",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum ResidencyError {
+    #[error("Unknown residency '{0}'. No DocumentDB cluster is configured for it.")]
+    UnknownResidency(String),
+    #[error("Failed to look up the residency of app '{app_name}': {message}")]
+    Lookup { app_name: String, message: String },
+    #[error("The residency of app '{app_name}' is '{existing}'. Use the residency migration to move it to '{requested}'.")]
+    ResidencyChange {
+        app_name: String,
+        existing: String,
+        requested: String,
+    },
+    #[error("Failed to migrate collection '{collection}': {message}")]
+    Migration { collection: String, message: String },
+}
+
+impl From<ResidencyError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: ResidencyError) -> Self {
+        let status_code = match e {
+            ResidencyError::UnknownResidency(_) | ResidencyError::ResidencyChange { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+            ResidencyError::Lookup { .. } | ResidencyError::Migration { .. } => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        let error_message = e.to_string();
+        error!(ext_message = error_message, message = error_message);
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Display name of a residency, the primary cluster when `None`.
+pub fn residency_name(residency: Option<&str>) -> &str {
+    residency.unwrap_or("primary")
+}
+
+/// Copies the app specific collections of an app from the source to the target cluster.
+/// Returns the number of documents copied. The collections are dropped from the target first,
+/// so a failed copy can be retried.
+pub async fn copy_app_collections(
+    source: &ClusterDb,
+    target: &(dyn DBTrait + Sync + Send),
+    app_name: &str,
+) -> Result<usize, ResidencyError> {
+    let mut copied = 0;
+    for suffix in APP_COLLECTION_SUFFIXES {
+        let collection = format!("{}-{}", app_name, suffix);
+        let migration_error = |message: String| ResidencyError::Migration {
+            collection: collection.clone(),
+            message,
+        };
+        // Whole collections are copied, without a time budget
+        let documents = source
+            .aggregate(
+                &collection,
+                vec![doc! { "$match": {} }],
+                &QueryOptions::default().unbounded(),
+            )
+            .await
+            .map_err(|e| migration_error(e.to_string()))?;
+        target
+            .drop_collection(&collection)
+            .await
+            .map_err(|e| migration_error(e.to_string()))?;
+        for document in documents {
+            // The documents are read as extended JSON, so the ids and dates are kept
+            let document = match Bson::try_from(document) {
+                Ok(Bson::Document(document)) => document,
+                Ok(_) => return Err(migration_error("not a document".to_string())),
+                Err(e) => return Err(migration_error(e.to_string())),
+            };
+            target
+                .create_document(&collection, document)
+                .await
+                .map_err(|e| migration_error(e.to_string()))?;
+            copied += 1;
+        }
+        debug!(message = format!("Collection '{}' copied.", collection));
+    }
+    info!(
+        app_name = app_name,
+        message = format!("Copied {} documents.", copied)
+    );
+    Ok(copied)
+}
+
+/// Drops the app specific collections of an app from a cluster. Failures are logged.
+pub async fn drop_app_collections(db: &(dyn DBTrait + Sync + Send), app_name: &str) {
+    for suffix in APP_COLLECTION_SUFFIXES {
+        let collection = format!("{}-{}", app_name, suffix);
+        match db.drop_collection(&collection).await {
+            Ok(_) => debug!(message = format!("Collection '{}' deleted successfully.", collection)),
+            Err(e) => debug!(
+                message = format!("Failed to delete collection '{}'. Error: {}", collection, e)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_residency_error_status_code() {
+        let (status_code, Json(message)) =
+            ResidencyError::UnknownResidency("eu-west-1".to_string()).into();
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        assert!(message
+            .get("message")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .contains("eu-west-1"));
+
+        let (status_code, _) = ResidencyError::Lookup {
+            app_name: "app100".to_string(),
+            message: "timeout".to_string(),
+        }
+        .into();
+        assert_eq!(status_code, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_success_residency_name() {
+        assert_eq!(residency_name(None), "primary");
+        assert_eq!(residency_name(Some("eu-west-1")), "eu-west-1");
+    }
+}
@@ -0,0 +1,409 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the sweeper of the dead retrievals.
+//! A retrieval handed to the knowledge engine has an ID document with a `-Retrieval` task ID, and is answered once
+//! its history document is stored. A retrieval without history document `deadline_seconds` after its ID document was
+//! created is expired: a "timed out" history document is stored for it, so the clients polling the history endpoint
+//! get a terminal status instead of a 202 forever. Every expired retrieval is counted by the `Dead Retrieval Counter`
+//! metric.
+//! The sweeper runs every `interval_seconds` when the `retrieval_sweeper` settings are set, on the replica holding
+//! the lease of the job (see `scheduler`), over the ID documents
+//! created in the `lookback_seconds` before the deadline. A late answer of the knowledge engine still replaces the
+//! timed out document, see `history_upsert`.
+//! The timed out history documents are mirrored to the answer sinks of the app, see `answer_sink`.
+//! An app with at least `notifications.error_spike_threshold` expired retrievals in a sweep gets an `error_spike`
+//! admin notification, once per hour.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{RetrievalSweeperSettings, TresleFacadeServiceSettings};
+use crate::retrieval::schema::history_document::HistoryDocument;
+use crate::service::answer_sink::mirror_answer;
+use crate::service::history_upsert::{upsert_history_document, HistoryUpsert};
+use crate::service::metrics::{MetricRecord, APP_NAME_DIMENSION};
+use crate::service::notification::{
+    hourly_period, record_notification, Notification, NotificationKind, NotificationOptions,
+};
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::scheduler::{acquire_lease, Job, JobRunStart, JobStatus};
+use crate::service::state::AppState;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use mongodb::bson::{doc, oid::ObjectId, Document};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, instrument, warn};
+
+/// Default number of seconds given to a retrieval to store its history document.
+const DEFAULT_DEADLINE_SECONDS: u64 = 900;
+/// Default number of seconds between two sweeps.
+const DEFAULT_INTERVAL_SECONDS: u64 = 60;
+/// Default number of seconds past the deadline the retrievals are looked up.
+const DEFAULT_LOOKBACK_SECONDS: u64 = 86_400;
+/// Default number of ID documents read per batch.
+const DEFAULT_BATCH_SIZE: i64 = 500;
+/// Suffix of the task IDs of the retrievals handed to the knowledge engine.
+const RETRIEVAL_TASK_ID_SUFFIX: &str = "-Retrieval";
+const HISTORY_COLLECTION_SUFFIX: &str = "-history";
+
+/// Dead retrieval sweeper options: deadline, job interval, lookback and batch size.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetrievalSweeperOptions {
+    pub deadline: Duration,
+    pub interval: Duration,
+    pub lookback: Duration,
+    pub batch_size: i64,
+}
+
+impl SettingsOptions for RetrievalSweeperOptions {
+    type Settings = RetrievalSweeperSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&RetrievalSweeperSettings> {
+        settings.retrieval_sweeper.as_ref()
+    }
+
+    fn from_settings(settings: Option<&RetrievalSweeperSettings>) -> Self {
+        let seconds =
+            |value: Option<u64>, default: u64| Duration::from_secs(value.unwrap_or(default));
+        RetrievalSweeperOptions {
+            deadline: seconds(
+                settings.and_then(|settings| settings.deadline_seconds),
+                DEFAULT_DEADLINE_SECONDS,
+            ),
+            interval: seconds(
+                settings.and_then(|settings| settings.interval_seconds),
+                DEFAULT_INTERVAL_SECONDS,
+            ),
+            lookback: seconds(
+                settings.and_then(|settings| settings.lookback_seconds),
+                DEFAULT_LOOKBACK_SECONDS,
+            ),
+            batch_size: settings
+                .and_then(|settings| settings.batch_size)
+                .filter(|batch_size| *batch_size > 0)
+                .unwrap_or(DEFAULT_BATCH_SIZE),
+        }
+    }
+}
+
+/// ID document of a retrieval handed to the knowledge engine.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingRetrieval {
+    pub id: ObjectId,
+    pub app_name: String,
+    pub reference_id: String,
+    pub task_id: String,
+}
+
+impl PendingRetrieval {
+    fn from_value(value: &serde_json::Value) -> Option<Self> {
+        Some(PendingRetrieval {
+            id: ObjectId::parse_str(value.get("_id")?.as_str()?).ok()?,
+            app_name: value.get("app_name")?.as_str()?.to_string(),
+            reference_id: value.get("reference_id")?.as_str()?.to_string(),
+            task_id: value.get("task_id")?.as_str()?.to_string(),
+        })
+    }
+}
+
+/// Returns the smallest ObjectId created at a time. An ObjectId starts with its creation timestamp, in seconds.
+fn object_id_at(time: DateTime<Utc>) -> ObjectId {
+    let mut bytes = [0u8; 12];
+    bytes[..4].copy_from_slice(&(time.timestamp().max(0) as u32).to_be_bytes());
+    ObjectId::from_bytes(bytes)
+}
+
+/// Filter of the ID documents of the retrievals past their deadline, after the `after` ID document if set.
+pub fn pending_retrievals_filter(
+    options: &RetrievalSweeperOptions,
+    now: DateTime<Utc>,
+    after: Option<ObjectId>,
+) -> Document {
+    let deadline = now - ChronoDuration::from_std(options.deadline).unwrap_or_default();
+    let start = deadline - ChronoDuration::from_std(options.lookback).unwrap_or_default();
+    let mut id_range = match after {
+        Some(after) => doc! {"$gt": after},
+        None => doc! {"$gte": object_id_at(start)},
+    };
+    id_range.insert("$lt", object_id_at(deadline));
+    doc! {
+        "_id": id_range,
+        "task_id": {"$regex": format!("{}$", RETRIEVAL_TASK_ID_SUFFIX)},
+    }
+}
+
+/// Expires the dead retrievals every `interval_seconds`, until the process exits.
+#[instrument(skip_all)]
+pub async fn sweep_dead_retrievals(app_state: Arc<AppState>) {
+    let period = app_state.options::<RetrievalSweeperOptions>().interval;
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if !acquire_lease(&app_state, Job::RetrievalSweeper, period).await {
+            continue;
+        }
+        let run = JobRunStart::new(Job::RetrievalSweeper);
+        let now = Utc::now();
+        let expired = expire_dead_retrievals(&app_state, now).await;
+        let error_spike_threshold = app_state
+            .options::<NotificationOptions>()
+            .error_spike_threshold;
+        for (app_name, count) in &expired {
+            warn!(
+                app_name = app_name,
+                message = format!("Expired {} retrievals without history document.", count)
+            );
+            app_state
+                .record_metric(
+                    MetricRecord::count("Dead Retrieval Counter", *count)
+                        .dimension(APP_NAME_DIMENSION, app_name),
+                )
+                .await;
+            if *count >= error_spike_threshold {
+                let notification = Notification::new(
+                    NotificationKind::ErrorSpike,
+                    app_name,
+                    format!(
+                        "{} retrievals of app '{}' timed out without answer from the knowledge engine.",
+                        count, app_name
+                    ),
+                )
+                .once_per(&hourly_period(now));
+                record_notification(&app_state, notification).await;
+            }
+        }
+        let details = format!(
+            "Expired {} retrievals without history document.",
+            expired.values().sum::<usize>()
+        );
+        run.finish(&app_state, JobStatus::Success, details).await;
+    }
+}
+
+/// Stores a timed out history document for each retrieval past its deadline. Returns the number of expired
+/// retrievals by app.
+pub async fn expire_dead_retrievals(
+    app_state: &Arc<AppState>,
+    now: DateTime<Utc>,
+) -> BTreeMap<String, usize> {
+    let options = app_state.options::<RetrievalSweeperOptions>();
+    let collection_name = &app_state.app_settings.mongo_db.mongo_db_id_collection;
+    let mut expired = BTreeMap::new();
+    let mut after = None;
+    loop {
+        let batch_pipeline = vec![
+            doc! { "$match": pending_retrievals_filter(&options, now, after) },
+            doc! { "$sort": { "_id": 1 } },
+            doc! { "$limit": options.batch_size },
+            doc! { "$project": {
+                "_id": { "$toString": "$_id" },
+                "app_name": 1,
+                "reference_id": 1,
+                "task_id": 1,
+            } },
+        ];
+        let batch: Vec<PendingRetrieval> = match app_state
+            .db
+            .aggregate(
+                collection_name,
+                batch_pipeline,
+                &app_state.options::<QueryOptions>(),
+            )
+            .await
+        {
+            Ok(batch) => batch
+                .iter()
+                .filter_map(PendingRetrieval::from_value)
+                .collect(),
+            Err(e) => {
+                error!(message = format!("Failed to fetch the pending retrievals: {}", e));
+                break;
+            }
+        };
+        let Some(last) = batch.last() else {
+            break;
+        };
+        after = Some(last.id);
+
+        let mut by_app: BTreeMap<&str, Vec<&PendingRetrieval>> = BTreeMap::new();
+        for retrieval in &batch {
+            by_app
+                .entry(retrieval.app_name.as_str())
+                .or_default()
+                .push(retrieval);
+        }
+        for (app_name, retrievals) in by_app {
+            let count = expire_app_retrievals(app_state, app_name, &retrievals, &options).await;
+            if count > 0 {
+                *expired.entry(app_name.to_string()).or_default() += count;
+            }
+        }
+        if (batch.len() as i64) < options.batch_size {
+            break;
+        }
+    }
+    expired
+}
+
+/// Stores a timed out history document for the retrievals of an app without history document.
+async fn expire_app_retrievals(
+    app_state: &Arc<AppState>,
+    app_name: &str,
+    retrievals: &[&PendingRetrieval],
+    options: &RetrievalSweeperOptions,
+) -> usize {
+    let db = match app_state.app_db(app_name).await {
+        Ok(db) => db,
+        Err(e) => {
+            error!(app_name = app_name, message = e.to_string());
+            return 0;
+        }
+    };
+    let reference_ids: Vec<&str> = retrievals
+        .iter()
+        .map(|retrieval| retrieval.reference_id.as_str())
+        .collect();
+    let history_collection_name = format!("{}{}", app_name, HISTORY_COLLECTION_SUFFIX);
+    let answered: HashSet<String> = match db
+        .aggregate(
+            &history_collection_name,
+            vec![
+                doc! { "$match": { "reference_id": { "$in": &reference_ids } } },
+                doc! { "$project": { "_id": 0, "reference_id": 1 } },
+            ],
+            &app_state.options::<QueryOptions>(),
+        )
+        .await
+    {
+        Ok(documents) => documents
+            .iter()
+            .filter_map(|document| document.get("reference_id")?.as_str())
+            .map(str::to_string)
+            .collect(),
+        Err(e) => {
+            error!(
+                app_name = app_name,
+                message = format!("Failed to fetch the answered retrievals: {}", e)
+            );
+            return 0;
+        }
+    };
+
+    let mut expired = 0;
+    for retrieval in retrievals
+        .iter()
+        .filter(|retrieval| !answered.contains(&retrieval.reference_id))
+    {
+        let history_document = timed_out_history_document(
+            retrieval,
+            options,
+            app_state.app_settings.disclaimer_text.clone(),
+        );
+        match upsert_history_document(app_state, app_name, &history_document).await {
+            Ok(HistoryUpsert::Created) => {
+                expired += 1;
+                info!(
+                    app_name = app_name,
+                    task_id = retrieval.task_id,
+                    message = format!("Retrieval '{}' timed out.", retrieval.reference_id)
+                );
+                mirror_answer(app_state, app_name, history_document);
+            }
+            // Answered in the meantime
+            Ok(_) => {}
+            Err(e) => error!(app_name = app_name, message = e.to_string()),
+        }
+    }
+    expired
+}
+
+/// History document of a retrieval past its deadline. The query is not kept by the ID document, so it's empty.
+pub fn timed_out_history_document(
+    retrieval: &PendingRetrieval,
+    options: &RetrievalSweeperOptions,
+    disclaimer_text: String,
+) -> HistoryDocument {
+    HistoryDocument::failed(
+        retrieval.reference_id.clone(),
+        retrieval.task_id.clone(),
+        String::new(),
+        format!(
+            "Retrieval timed out: no response from the knowledge engine within {} seconds.",
+            options.deadline.as_secs()
+        ),
+        disclaimer_text,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    #[test]
+    fn test_success_retrieval_sweeper_options_from_settings() {
+        let options = RetrievalSweeperOptions::from_settings(None);
+        assert_eq!(
+            options.deadline,
+            Duration::from_secs(DEFAULT_DEADLINE_SECONDS)
+        );
+        assert_eq!(options.batch_size, DEFAULT_BATCH_SIZE);
+
+        let settings = RetrievalSweeperSettings {
+            deadline_seconds: Some(120),
+            interval_seconds: None,
+            lookback_seconds: Some(3_600),
+            batch_size: Some(0),
+        };
+        let options = RetrievalSweeperOptions::from_settings(Some(&settings));
+        assert_eq!(options.deadline, Duration::from_secs(120));
+        assert_eq!(
+            options.interval,
+            Duration::from_secs(DEFAULT_INTERVAL_SECONDS)
+        );
+        assert_eq!(options.lookback, Duration::from_secs(3_600));
+        assert_eq!(options.batch_size, DEFAULT_BATCH_SIZE);
+    }
+
+    #[test]
+    fn test_success_pending_retrievals_filter() {
+        let options = RetrievalSweeperOptions::from_settings(None);
+        let now = Utc.with_ymd_and_hms(2024, 7, 26, 12, 0, 0).unwrap();
+        let filter = pending_retrievals_filter(&options, now, None);
+        let id_range = filter.get_document("_id").unwrap();
+        let deadline = id_range.get_object_id("$lt").unwrap();
+        assert_eq!(
+            deadline.timestamp().timestamp_millis(),
+            (now - ChronoDuration::seconds(900)).timestamp_millis()
+        );
+        assert!(id_range.get_object_id("$gte").is_ok());
+
+        // The next batches start after the last ID document read
+        let after = ObjectId::new();
+        let filter = pending_retrievals_filter(&options, now, Some(after));
+        let id_range = filter.get_document("_id").unwrap();
+        assert_eq!(id_range.get_object_id("$gt").unwrap(), after);
+        assert!(id_range.get("$gte").is_none());
+    }
+
+    #[test]
+    fn test_success_timed_out_history_document() {
+        let retrieval = PendingRetrieval::from_value(&json!({
+            "_id": "6650e4a1f1b2c3d4e5f60718",
+            "app_name": "app100",
+            "reference_id": "reference_id",
+            "task_id": "TSK-0190f1b2-app100-Retrieval",
+        }))
+        .unwrap();
+        let options = RetrievalSweeperOptions::from_settings(None);
+        let history_document = timed_out_history_document(&retrieval, &options, String::new());
+        assert_eq!(history_document.reference_id, "reference_id");
+        assert!(history_document.timestamp.is_failed());
+        assert!(history_document.response.contains("900 seconds"));
+        assert!(PendingRetrieval::from_value(&json!({"_id": "not-an-id"})).is_none());
+    }
+}
@@ -5,46 +5,316 @@
  */
 //! This module contains the routes/endpoints for the different handlers/APIs.
 
-use crate::service::error::TresleFacadeCommonError;
-use axum::http::StatusCode;
-use error_utils::ApiErrorResponse;
-use error_utils::AxumApiError;
+use crate::configuration::settings::CompressionSettings;
+use crate::service::access_log::log_access;
+use crate::service::ctx::{record_request_context, Ctx, REFERENCE_ID_HEADER};
+use crate::service::error::{FacadeApiError, TresleFacadeCommonError};
+use crate::service::error_code::attach_error_code;
+use crate::service::metrics::{MetricRecord, METHOD_DIMENSION, ROUTE_DIMENSION, STATUS_DIMENSION};
+use crate::service::prometheus::get_prometheus_metrics;
+use crate::service::service_account::authorize_service_account;
+use axum::http::{header::ALLOW, HeaderValue, Method, StatusCode};
 use std::sync::Arc;
-use uuid::Uuid;
+use std::time::Instant;
 
 use crate::AppState;
 use axum::{
+    extract::{MatchedPath, Request, State},
     http::Uri,
-    routing::{delete, get, patch, post, Router},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, patch, post, put, Router},
 };
-use tracing::debug;
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+use tower_http::decompression::RequestDecompressionLayer;
+use tower_http::limit::RequestBodyLimitLayer;
+use tracing::{debug, warn};
 
+use crate::admin_ui_api::app_access_list_handler::{
+    get_access_list_handler, put_access_list_handler,
+};
+use crate::admin_ui_api::app_answer_sinks_handler::{
+    delete_answer_sink_handler, get_answer_sinks_handler, put_answer_sink_handler,
+};
+use crate::admin_ui_api::app_api_key_usage_handler::get_api_key_usage_handler;
+use crate::admin_ui_api::app_artifacts_handler::{
+    delete_app_artifact_handler, get_app_artifacts_handler,
+};
 use crate::admin_ui_api::app_delete_handler::delete_app;
+use crate::admin_ui_api::app_encryption_key_handler::post_rotate_encryption_key_handler;
+use crate::admin_ui_api::app_experiments_handler::{
+    delete_experiment_handler, get_experiment_results_handler, get_experiments_handler,
+    put_experiment_handler,
+};
+use crate::admin_ui_api::app_generated_config_handler::{
+    get_generated_config_handler, patch_generated_config_handler,
+};
 use crate::admin_ui_api::app_get_handler::get_app;
 use crate::admin_ui_api::app_get_logs_handler::get_logs;
+use crate::admin_ui_api::app_hints_handler::{
+    delete_hint_handler, get_hints_handler, post_hint_handler, put_hint_handler,
+};
+use crate::admin_ui_api::app_history_retention_handler::{
+    delete_legal_hold_handler, get_history_retention_handler, post_legal_hold_handler,
+    put_history_retention_handler,
+};
+use crate::admin_ui_api::app_ingestion_control_handler::{
+    post_pause_ingestion_handler, post_resume_ingestion_handler,
+};
+use crate::admin_ui_api::app_ingestion_retry_handler::{
+    get_ingestion_retry_policy_handler, put_ingestion_retry_policy_handler,
+};
+use crate::admin_ui_api::app_ingestion_sla_handler::get_ingestion_sla_handler;
+use crate::admin_ui_api::app_keys_handler::{
+    create_app_key_handler, delete_app_key_handler, get_app_keys_handler,
+    put_app_key_expiry_handler,
+};
+use crate::admin_ui_api::app_knowledge_node_detail_handler::get_knowledge_node_detail_handler;
 use crate::admin_ui_api::app_knowledge_nodes_and_errors_count::get_knowledge_nodes_and_errors_count;
 use crate::admin_ui_api::app_knowledge_nodes_chart_handler::get_knowledge_nodes_chart_handler;
 use crate::admin_ui_api::app_knowledge_nodes_errors_handler::get_knowledge_nodes_errors_handler;
 use crate::admin_ui_api::app_knowledge_nodes_handler::get_knowledge_nodes_handler;
+use crate::admin_ui_api::app_knowledge_nodes_stats_handler::get_knowledge_nodes_stats_handler;
 use crate::admin_ui_api::app_list_handler::get_app_list;
+use crate::admin_ui_api::app_prompt_templates_handler::{
+    delete_prompt_template_handler, get_prompt_templates_handler, put_prompt_template_handler,
+};
+use crate::admin_ui_api::app_residency_handler::post_app_residency_handler;
+use crate::admin_ui_api::app_retry_onboarding_handler::post_retry_onboarding_handler;
 use crate::admin_ui_api::app_search_enabled_handler::update_search_enabled_handler;
+use crate::admin_ui_api::app_shadow_traffic_handler::{
+    delete_shadow_traffic_handler, get_shadow_traffic_handler, get_shadow_traffic_report_handler,
+    put_shadow_traffic_handler,
+};
+use crate::admin_ui_api::app_try_query_handler::post_try_query_handler;
+use crate::admin_ui_api::app_user_pseudonyms_handler::get_user_pseudonym_handler;
+use crate::admin_ui_api::app_verify_counts_handler::post_verify_counts_handler;
 use crate::admin_ui_api::apps_and_calls_overview_handler::get_apps_and_calls_overview_handler;
+use crate::admin_ui_api::backfills_handler::{
+    get_backfill_job_handler, get_backfills_handler, post_backfill_handler,
+};
 use crate::admin_ui_api::capture_tc_handler::post_capture_tc_handler;
+use crate::admin_ui_api::config_handler::get_config_handler;
+use crate::admin_ui_api::dependencies_handler::get_dependencies_handler;
+use crate::admin_ui_api::error_catalog_handler::get_error_catalog_handler;
+use crate::admin_ui_api::graph_query_handler::post_graph_query_handler;
+use crate::admin_ui_api::job_runs_handler::get_job_runs_handler;
 use crate::admin_ui_api::kub_generate_token_handler::get_kubernetes_token;
 use crate::admin_ui_api::metric_calls_handler::get_metric_calls;
 use crate::admin_ui_api::metric_error_handler::get_metric_errors;
+use crate::admin_ui_api::notifications_handler::{
+    get_notifications_handler, post_notification_read_handler, post_notifications_read_handler,
+};
+use crate::admin_ui_api::onboarding_complexity_handler::get_onboarding_complexity_handler;
+use crate::admin_ui_api::overview_feed_handler::get_overview_feed_handler;
+use crate::admin_ui_api::queued_jobs_handler::get_queued_jobs_handler;
+use crate::admin_ui_api::scim_handler::{
+    delete_scim_group_handler, delete_scim_user_handler, get_scim_group_handler,
+    get_scim_groups_handler, get_scim_user_handler, get_scim_users_handler,
+    patch_scim_group_handler, patch_scim_user_handler, post_scim_group_handler,
+    post_scim_user_handler, put_scim_group_handler, put_scim_user_handler,
+};
+use crate::admin_ui_api::selfcheck_handler::get_selfcheck_handler;
+use crate::admin_ui_api::service_accounts_handler::{
+    delete_service_account_handler, get_service_accounts_handler, post_service_account_handler,
+    post_service_account_secret_handler,
+};
+use crate::admin_ui_api::token_usage_handler::get_token_usage_handler;
+use crate::admin_ui_api::trace_replay_handler::{
+    get_trace_replays_handler, post_trace_replay_handler,
+};
+use crate::onboarding::apply::post_app_apply_handler;
 use crate::onboarding::handler::post_app_onboarding_handler;
+use crate::onboarding::validation_job::get_validation_job_handler;
+use crate::retrieval::estimate_handler::post_retrieval_estimate_handler;
 use crate::retrieval::handler::post_retrieval_handler;
 use crate::retrieval::history_handler::get_history_handler;
+use crate::service::scim::ScimOptions;
 
 pub fn create_router(app_state: Arc<AppState>) -> Router {
     Router::new()
         .route("/api/v1.0/retrieval", post(post_retrieval_handler))
+        .route(
+            "/api/v1.0/retrieval/estimate",
+            post(post_retrieval_estimate_handler),
+        )
+        .route("/metrics", get(get_prometheus_metrics))
         .route("/api/v1.0/history/retrieval", get(get_history_handler))
         .route("/api/v1.1/admin/token", get(get_kubernetes_token))
+        .route("/api/v1.1/admin/jobs/runs", get(get_job_runs_handler))
+        .route("/api/v1.1/admin/jobs/queued", get(get_queued_jobs_handler))
+        .route("/api/v1.1/admin/backfills", get(get_backfills_handler))
+        .route(
+            "/api/v1.1/admin/backfills/:backfill",
+            post(post_backfill_handler),
+        )
+        .route(
+            "/api/v1.1/admin/backfills/jobs/:job_id",
+            get(get_backfill_job_handler),
+        )
+        .route("/api/v1.1/admin/config", get(get_config_handler))
+        .route(
+            "/api/v1.1/admin/errors/catalog",
+            get(get_error_catalog_handler),
+        )
+        .route("/api/v1.1/admin/selfcheck", get(get_selfcheck_handler))
+        .route(
+            "/api/v1.1/admin/service-accounts",
+            get(get_service_accounts_handler).post(post_service_account_handler),
+        )
+        .route(
+            "/api/v1.1/admin/service-accounts/:client_id",
+            delete(delete_service_account_handler),
+        )
+        .route(
+            "/api/v1.1/admin/service-accounts/:client_id/secret",
+            post(post_service_account_secret_handler),
+        )
+        .route(
+            "/api/v1.1/admin/dependencies",
+            get(get_dependencies_handler),
+        )
+        .route(
+            "/api/v1.1/admin/notifications",
+            get(get_notifications_handler),
+        )
+        .route(
+            "/api/v1.1/admin/notifications/read",
+            post(post_notifications_read_handler),
+        )
+        .route(
+            "/api/v1.1/admin/notifications/:id/read",
+            post(post_notification_read_handler),
+        )
         .route("/api/v1.1/admin/apps", get(get_app_list))
         .route("/api/v1.1/admin/apps/:app_name", get(get_app))
         .route("/api/v1.1/admin/apps/:app_name", delete(delete_app))
+        .route(
+            "/api/v1.1/admin/apps/:app_name/artifacts",
+            get(get_app_artifacts_handler).delete(delete_app_artifact_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/generated-config",
+            get(get_generated_config_handler).patch(patch_generated_config_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/encryption-key/rotate",
+            post(post_rotate_encryption_key_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/residency",
+            post(post_app_residency_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/access-list",
+            get(get_access_list_handler).put(put_access_list_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/api-key-usage",
+            get(get_api_key_usage_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/hints",
+            get(get_hints_handler)
+                .post(post_hint_handler)
+                .put(put_hint_handler)
+                .delete(delete_hint_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/history-retention",
+            get(get_history_retention_handler).put(put_history_retention_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/history-retention/holds",
+            post(post_legal_hold_handler).delete(delete_legal_hold_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/prompt-templates",
+            get(get_prompt_templates_handler).put(put_prompt_template_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/prompt-templates/:template_name",
+            delete(delete_prompt_template_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/answer-sinks",
+            get(get_answer_sinks_handler).put(put_answer_sink_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/answer-sinks/:sink_name",
+            delete(delete_answer_sink_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/experiments",
+            get(get_experiments_handler).put(put_experiment_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/experiments/:experiment_name",
+            delete(delete_experiment_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/experiments/:experiment_name/results",
+            get(get_experiment_results_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/shadow-traffic",
+            get(get_shadow_traffic_handler)
+                .put(put_shadow_traffic_handler)
+                .delete(delete_shadow_traffic_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/shadow-traffic/report",
+            get(get_shadow_traffic_report_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/keys",
+            get(get_app_keys_handler).post(create_app_key_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/keys/:key_id",
+            delete(delete_app_key_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/keys/:key_id/expiry",
+            put(put_app_key_expiry_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/ingestion/pause",
+            post(post_pause_ingestion_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/ingestion/resume",
+            post(post_resume_ingestion_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/ingestion/retry-policy",
+            get(get_ingestion_retry_policy_handler).put(put_ingestion_retry_policy_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/ingestion/sla",
+            get(get_ingestion_sla_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/retry-onboarding",
+            post(post_retry_onboarding_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/try-query",
+            post(post_try_query_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/user-pseudonyms/:pseudonym",
+            get(get_user_pseudonym_handler),
+        )
+        .route(
+            "/api/v1.1/admin/apps/:app_name/verify-counts",
+            post(post_verify_counts_handler),
+        )
+        .route(
+            "/api/v1.1/admin/graph/:app_name/query",
+            post(post_graph_query_handler),
+        )
         .route(
             "/api/v1.1/admin/search/apps/:app_name",
             patch(update_search_enabled_handler),
@@ -53,15 +323,28 @@ pub fn create_router(app_state: Arc<AppState>) -> Router {
             "/api/v1.1/admin/apps/onboard",
             post(post_app_onboarding_handler),
         )
+        .route("/api/v1.1/admin/apps/apply", post(post_app_apply_handler))
+        .route(
+            "/api/v1.1/admin/validation/:job_id",
+            get(get_validation_job_handler),
+        )
         .route("/api/v1.1/admin/capture_tc", post(post_capture_tc_handler))
         .route(
             "/api/v1.1/admin/overview",
             get(get_apps_and_calls_overview_handler),
         )
+        .route(
+            "/api/v1.1/admin/overview/ws",
+            get(get_overview_feed_handler),
+        )
         .route(
             "/api/v1.1/admin/nodes/:app_name",
             get(get_knowledge_nodes_handler),
         )
+        .route(
+            "/api/v1.1/admin/nodes/:app_name/detail",
+            get(get_knowledge_node_detail_handler),
+        )
         .route(
             "/api/v1.1/admin/nodes/errors/:app_name",
             get(get_knowledge_nodes_errors_handler),
@@ -74,34 +357,240 @@ pub fn create_router(app_state: Arc<AppState>) -> Router {
             "/api/v1.1/admin/nodes/chart/:app_name",
             get(get_knowledge_nodes_chart_handler),
         )
+        .route(
+            "/api/v1.1/admin/nodes/stats/:app_name",
+            get(get_knowledge_nodes_stats_handler),
+        )
+        .merge(scim_router(&app_state))
         .route("/api/v1.1/admin/logs", get(get_logs))
         .route("/api/v1.1/admin/metric/calls", get(get_metric_calls))
         .route("/api/v1.1/admin/metric/logs", get(get_metric_errors))
-        .with_state(app_state)
+        .route(
+            "/api/v1.1/admin/usage/tokens/:app_name",
+            get(get_token_usage_handler),
+        )
+        .route(
+            "/api/v1.1/admin/onboarding/complexity",
+            get(get_onboarding_complexity_handler),
+        )
+        .route(
+            "/api/v1.1/admin/trace/:reference_id/replay",
+            post(post_trace_replay_handler),
+        )
+        .route(
+            "/api/v1.1/admin/trace/:reference_id/replays",
+            get(get_trace_replays_handler),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            authorize_service_account,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            method_not_allowed,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            log_access,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            record_request_context,
+        ))
         .fallback(fallback)
+        .layer(middleware::from_fn(attach_error_code))
+        .with_state(app_state)
+}
+
+/// SCIM routes of the IdP. They are only mounted with `scim.bearer_token` set, as they would otherwise let anyone
+/// reaching the admin network push entitlements.
+fn scim_router(app_state: &AppState) -> Router<Arc<AppState>> {
+    if app_state.options::<ScimOptions>().bearer_token.is_none() {
+        warn!(message = "The SCIM endpoints are not mounted: scim.bearer_token is not set.");
+        return Router::new();
+    }
+    Router::new()
+        .route(
+            "/api/v1.1/admin/scim/Users",
+            get(get_scim_users_handler).post(post_scim_user_handler),
+        )
+        .route(
+            "/api/v1.1/admin/scim/Users/:id",
+            get(get_scim_user_handler)
+                .put(put_scim_user_handler)
+                .patch(patch_scim_user_handler)
+                .delete(delete_scim_user_handler),
+        )
+        .route(
+            "/api/v1.1/admin/scim/Groups",
+            get(get_scim_groups_handler).post(post_scim_group_handler),
+        )
+        .route(
+            "/api/v1.1/admin/scim/Groups/:id",
+            get(get_scim_group_handler)
+                .put(put_scim_group_handler)
+                .patch(patch_scim_group_handler)
+                .delete(delete_scim_group_handler),
+        )
+}
+
+/// Default maximum size of a request body once decompressed.
+pub const DEFAULT_MAX_REQUEST_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+/// Returns the maximum size of a request body once decompressed.
+pub fn max_request_body_bytes(settings: Option<&CompressionSettings>) -> usize {
+    settings
+        .and_then(|settings| settings.max_request_body_bytes)
+        .unwrap_or(DEFAULT_MAX_REQUEST_BODY_BYTES)
+}
+
+/// Compresses responses above the configured size (negotiated through `Accept-Encoding`) and
+/// decompresses gzip/brotli request bodies. Images and event streams are never compressed.
+/// The request bodies are limited to `max_request_body_bytes` after their decompression, so a small compressed body
+/// can't expand into an unbounded one: a body announcing a larger `Content-Length` is answered with a 413 status
+/// code, and the reading of a larger streamed body fails.
+pub fn apply_compression(router: Router, settings: Option<&CompressionSettings>) -> Router {
+    let router = router.layer(RequestBodyLimitLayer::new(max_request_body_bytes(settings)));
+    match settings {
+        Some(settings) if settings.enabled => router
+            .layer(
+                CompressionLayer::new()
+                    .gzip(settings.gzip)
+                    .br(settings.br)
+                    .compress_when(
+                        SizeAbove::new(settings.min_size_bytes)
+                            .and(NotForContentType::GRPC)
+                            .and(NotForContentType::IMAGES)
+                            .and(NotForContentType::SSE),
+                    ),
+            )
+            .layer(
+                RequestDecompressionLayer::new()
+                    .gzip(settings.gzip)
+                    .br(settings.br),
+            ),
+        _ => router,
+    }
+}
+
+/// Records the duration of every request and counts the error responses, when the CloudWatch EMF output or the
+/// Prometheus export of the metrics is configured. The metrics carry the matched route, the method and, for the
+/// errors, the status code.
+pub fn apply_request_metrics(router: Router, app_state: Arc<AppState>) -> Router {
+    let metrics_exported = app_state
+        .app_settings
+        .metrics
+        .as_ref()
+        .is_some_and(|metrics| metrics.cloudwatch_emf.is_some() || metrics.prometheus.is_some());
+    if !metrics_exported {
+        return router;
+    }
+    router.layer(middleware::from_fn_with_state(
+        app_state,
+        record_request_metrics,
+    ))
 }
 
-pub async fn fallback(uri: Uri) -> AxumApiError<TresleFacadeCommonError> {
+/// Middleware recording the request metrics in the background, so the sinks never delay the response.
+async fn record_request_metrics(
+    State(app_state): State<Arc<AppState>>,
+    matched_path: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = matched_path
+        .map(|matched_path| matched_path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = request.method().to_string();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let duration_ms = start.elapsed().as_millis() as i64;
+    let status = response.status();
+    tokio::spawn(async move {
+        app_state
+            .record_metric(
+                MetricRecord::duration_ms("Request Duration", duration_ms)
+                    .dimension(ROUTE_DIMENSION, route.as_str())
+                    .dimension(METHOD_DIMENSION, method.as_str()),
+            )
+            .await;
+        if status.is_client_error() || status.is_server_error() {
+            app_state
+                .record_metric(
+                    MetricRecord::counter("Request Error Counter")
+                        .dimension(ROUTE_DIMENSION, route)
+                        .dimension(METHOD_DIMENSION, method)
+                        .dimension(STATUS_DIMENSION, status.as_u16().to_string()),
+                )
+                .await;
+        }
+    });
+    response
+}
+
+/// Handler of the unknown routes. Returns the standard error body with the reference ID of the request, whose ID
+/// document is recorded.
+pub async fn fallback(
+    State(app_state): State<Arc<AppState>>,
+    method: Method,
+    uri: Uri,
+) -> Response {
     debug!("->> {:<12} - fallback - ", "HANDLER");
-    let reference_id = Uuid::new_v4().to_string();
-    let task_id = Uuid::new_v4().to_string();
-    TresleFacadeCommonError::RouteNotFound {
-        task_id,
-        time_stamp: ApiErrorResponse::time_stamp(),
-        error_code: StatusCode::BAD_REQUEST,
-        source: Box::new(std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            format!("Route not found: {}", uri),
-        )),
-        reference_id,
-        ext_message: "Internal Error. Please contact tresleai support team.".to_string(),
+    // The route layers do not run for the unknown routes, the context is built and recorded here
+    let ctx = Ctx::new(
+        &app_state,
+        &app_state.app_settings.tracing_layer_system_app_name,
+        "RouteNotFound",
+    );
+    ctx.record(&app_state).await;
+    let mut response = FacadeApiError::from(TresleFacadeCommonError::route_not_found(
+        &ctx.reference_id,
+        &ctx.task_id,
+        &method,
+        &uri,
+        &app_state.app_settings.general_message,
+    ))
+    .into_response();
+    if let Ok(reference_id) = HeaderValue::from_str(&ctx.reference_id) {
+        response
+            .headers_mut()
+            .insert(REFERENCE_ID_HEADER, reference_id);
+    }
+    response
+}
+
+/// Middleware replacing the empty 405 response of a known route called with another method by the standard error
+/// body, keeping its `Allow` header. The ID document is recorded by `record_request_context`.
+async fn method_not_allowed(
+    ctx: Ctx,
+    State(app_state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+    let response = next.run(request).await;
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return response;
     }
-    .into()
+    let mut error_response = FacadeApiError::from(TresleFacadeCommonError::method_not_allowed(
+        &ctx.reference_id,
+        &ctx.task_id,
+        &method,
+        &uri,
+        &app_state.app_settings.general_message,
+    ))
+    .into_response();
+    if let Some(allow) = response.headers().get(ALLOW) {
+        error_response.headers_mut().insert(ALLOW, allow.clone());
+    }
+    error_response
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::service::error_code::ErrorCode;
     use tokio::runtime::Runtime;
 
     #[test]
@@ -113,4 +602,46 @@ mod tests {
             let _router = create_router(app_state);
         });
     }
+
+    #[test]
+    fn test_failure_fallback_route_not_found() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            // Call the function
+            let response = fallback(
+                State(app_state),
+                Method::GET,
+                Uri::from_static("/api/v1.1/admin/unknown"),
+            )
+            .await;
+
+            // Check the status code and the reference ID header
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert!(response.headers().contains_key(REFERENCE_ID_HEADER));
+            assert_eq!(
+                response.extensions().get::<ErrorCode>(),
+                Some(&ErrorCode::RouteNotFound)
+            );
+        });
+    }
+
+    #[test]
+    fn test_success_apply_compression() {
+        let settings = CompressionSettings {
+            enabled: true,
+            gzip: true,
+            br: false,
+            min_size_bytes: 1024,
+            max_request_body_bytes: None,
+        };
+        assert_eq!(
+            max_request_body_bytes(Some(&settings)),
+            DEFAULT_MAX_REQUEST_BODY_BYTES
+        );
+        let _router = apply_compression(Router::new(), Some(&settings));
+        let _router = apply_compression(Router::new(), None);
+    }
 }
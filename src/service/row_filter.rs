@@ -0,0 +1,284 @@
+/*
+ * Created Date:  Jul 13, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the row-level security filters of the datastore tables of an app.
+//! A table of the onboarding request can define a `row_filter` template, a SQL condition with named parameters such
+//! as `tenant_id = :user_tenant`. The facade validates the templates, stores them on the app document as a flat
+//! `row_filters` list (the stored datasource only keeps the descriptions of the tables) and passes them to the
+//! knowledge engine with every retrieval. The knowledge engine binds the parameters from the access details of the
+//! user of the retrieval, so each user only reads the rows of their scope.
+//! The templates are never interpolated by the facade. They are rejected if they chain statements or hide comments.
+//!
+
+use crate::onboarding::schema::app_onboarding_request::AppDataSource;
+use crate::service::column_classification::datastore_tables_mut;
+use axum::{http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::error;
+use utoipa::ToSchema;
+
+/// Name of the field of the app document holding the row filters.
+pub const ROW_FILTERS_FIELD: &str = "row_filters";
+/// Maximum length of a row filter template.
+pub const MAX_ROW_FILTER_LENGTH: usize = 1024;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum RowFilterError {
+    #[error("Invalid row_filter of table '{table}': {reason}")]
+    InvalidTemplate { table: String, reason: String },
+}
+
+impl From<RowFilterError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: RowFilterError) -> Self {
+        let error_message = e.to_string();
+        error!(ext_message = error_message, message = error_message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Row filter template of a datastore table, identified by the source type, host and database of its datastore.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct RowFilter {
+    pub source_type: String,
+    pub host: String,
+    pub database: String,
+    pub table: String,
+    pub template: String,
+}
+
+impl RowFilter {
+    /// Returns the names of the parameters of the template, e.g. `user_tenant` for `tenant_id = :user_tenant`.
+    /// Postgres casts (`::text`) are not parameters.
+    pub fn parameters(&self) -> Vec<&str> {
+        template_parameters(&self.template)
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+}
+
+/// Returns the row filters of the tables of a datasource. Tables without a row filter are left out.
+pub fn row_filters(datasource: &AppDataSource) -> Vec<RowFilter> {
+    let mut row_filters = Vec::new();
+    for (source_type, datastores) in &datasource.datastore {
+        for datastore in datastores {
+            for table in &datastore.tables {
+                if let Some(template) = &table.row_filter {
+                    row_filters.push(RowFilter {
+                        source_type: source_type.clone(),
+                        host: datastore.host.clone(),
+                        database: datastore.database.clone(),
+                        table: table.name.clone(),
+                        template: template.clone(),
+                    });
+                }
+            }
+        }
+    }
+    // The datastore map has no order, keep the stored list stable across updates
+    row_filters.sort_by(|a, b| {
+        (&a.source_type, &a.host, &a.database, &a.table).cmp(&(
+            &b.source_type,
+            &b.host,
+            &b.database,
+            &b.table,
+        ))
+    });
+    row_filters
+}
+
+/// Validates the row filter templates of the tables of a datasource.
+pub fn validate_row_filters(datasource: &AppDataSource) -> Result<(), RowFilterError> {
+    for row_filter in row_filters(datasource) {
+        let invalid = |reason: &str| RowFilterError::InvalidTemplate {
+            table: row_filter.table.clone(),
+            reason: reason.to_string(),
+        };
+        let template = row_filter.template.trim();
+        if template.is_empty() {
+            return Err(invalid("the template is empty."));
+        }
+        if template.len() > MAX_ROW_FILTER_LENGTH {
+            return Err(invalid(&format!(
+                "the template exceeds {} characters.",
+                MAX_ROW_FILTER_LENGTH
+            )));
+        }
+        if template.contains(';') {
+            return Err(invalid("the template cannot chain statements with ';'."));
+        }
+        if template.contains("--") || template.contains("/*") {
+            return Err(invalid("the template cannot contain comments."));
+        }
+        if template_parameters(template).contains(&None) {
+            return Err(invalid("a parameter must be named, e.g. ':user_tenant'."));
+        }
+    }
+    Ok(())
+}
+
+/// Merges the stored row filters of an app document into the tables of its `app_datasource`, so the document can be
+/// read in the shape of the onboarding request. Documents without row filters are left as-is.
+pub fn merge_row_filters(app: &mut serde_json::Value) {
+    let Some(row_filters) = app
+        .get(ROW_FILTERS_FIELD)
+        .cloned()
+        .and_then(|value| serde_json::from_value::<Vec<RowFilter>>(value).ok())
+    else {
+        return;
+    };
+    for row_filter in row_filters {
+        let tables = datastore_tables_mut(
+            app,
+            &row_filter.source_type,
+            &row_filter.host,
+            &row_filter.database,
+            &row_filter.table,
+        );
+        for table in tables {
+            if let Some(table) = table.as_object_mut() {
+                table.insert("row_filter".to_string(), json!(row_filter.template));
+            }
+        }
+    }
+}
+
+/// Returns the names of the `:name` parameters of a template, `None` for a `:` not followed by a name.
+fn template_parameters(template: &str) -> Vec<Option<&str>> {
+    let mut parameters = Vec::new();
+    let bytes = template.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b':' {
+            // Skip the Postgres casts, e.g. `tenant_id::text`
+            if bytes.get(i + 1) == Some(&b':') {
+                i += 2;
+                while i < bytes.len() && is_name_byte(bytes[i]) {
+                    i += 1;
+                }
+                continue;
+            }
+            let start = i + 1;
+            let mut end = start;
+            while end < bytes.len() && is_name_byte(bytes[end]) {
+                end += 1;
+            }
+            parameters.push((end > start).then(|| &template[start..end]));
+            i = end;
+        } else {
+            i += 1;
+        }
+    }
+    parameters
+}
+
+fn is_name_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datasource(row_filter: Option<&str>) -> AppDataSource {
+        serde_json::from_value(json!({
+            "filestore": {},
+            "datastore": {
+                "aws_rds": [{
+                    "host": "db.example.com",
+                    "port": "5432",
+                    "username": null,
+                    "secret_name": null,
+                    "aws_service_name": null,
+                    "database": "crm",
+                    "db_type": "postgres",
+                    "descriptions": null,
+                    "region": null,
+                    "fact_phrases": null,
+                    "fact_words": null,
+                    "search_keywords": null,
+                    "summary": null,
+                    "tables": [{
+                        "name": "orders",
+                        "descriptions": "orders",
+                        "schema": null,
+                        "schema_json": null,
+                        "columns": null,
+                        "sample_rows": null,
+                        "fact_phrases": null,
+                        "fact_words": null,
+                        "search_keywords": null,
+                        "summary": null,
+                        "row_filter": row_filter
+                    }]
+                }]
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_row_filters() {
+        let filters = row_filters(&datasource(Some("tenant_id = :user_tenant")));
+        assert_eq!(
+            filters,
+            vec![RowFilter {
+                source_type: "aws_rds".to_string(),
+                host: "db.example.com".to_string(),
+                database: "crm".to_string(),
+                table: "orders".to_string(),
+                template: "tenant_id = :user_tenant".to_string(),
+            }]
+        );
+        assert!(row_filters(&datasource(None)).is_empty());
+    }
+
+    #[test]
+    fn test_parameters() {
+        let row_filter = RowFilter {
+            source_type: "aws_rds".to_string(),
+            host: "db.example.com".to_string(),
+            database: "crm".to_string(),
+            table: "orders".to_string(),
+            template: "tenant_id::text = :user_tenant AND region IN (:region_a, :region_b)"
+                .to_string(),
+        };
+        assert_eq!(
+            row_filter.parameters(),
+            vec!["user_tenant", "region_a", "region_b"]
+        );
+    }
+
+    #[test]
+    fn test_validate_row_filters() {
+        assert!(validate_row_filters(&datasource(None)).is_ok());
+        assert!(validate_row_filters(&datasource(Some("tenant_id = :user_tenant"))).is_ok());
+        assert!(validate_row_filters(&datasource(Some("   "))).is_err());
+        assert!(validate_row_filters(&datasource(Some("1 = 1; DROP TABLE orders"))).is_err());
+        assert!(validate_row_filters(&datasource(Some("tenant_id = :user_tenant -- x"))).is_err());
+        assert!(validate_row_filters(&datasource(Some("tenant_id = :"))).is_err());
+        assert!(validate_row_filters(&datasource(Some("tenant_id = : user_tenant"))).is_err());
+        let too_long = format!("tenant_id = '{}'", "a".repeat(MAX_ROW_FILTER_LENGTH));
+        assert!(validate_row_filters(&datasource(Some(&too_long))).is_err());
+    }
+
+    #[test]
+    fn test_merge_row_filters() {
+        let filtered = datasource(Some("tenant_id = :user_tenant"));
+        let mut app = json!({
+            "app_datasource": datasource(None),
+            "row_filters": row_filters(&filtered),
+        });
+
+        merge_row_filters(&mut app);
+
+        let merged: AppDataSource = serde_json::from_value(app["app_datasource"].clone()).unwrap();
+        assert_eq!(merged, filtered);
+    }
+}
@@ -0,0 +1,405 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the leader election of the background jobs, so every replica of the facade can schedule
+//! them while a single replica runs each job type.
+//! Before every run, a replica takes the lease of the job: a document of `scheduler.lease_collection` with the job as
+//! `_id`, the replica as `owner` and an `expires_at` one interval (plus `scheduler.lease_grace_seconds`) ahead. The
+//! leader renews its lease on every run; the other replicas skip the run while the lease is held, and take it over
+//! once it expired, e.g. after the leader crashed.
+//! Every run of the leader is recorded in `scheduler.run_collection`, with its duration, status and details, and
+//! served by the job runs endpoint. The run documents carry an `expires_at` `scheduler.run_retention_days` ahead,
+//! for a TTL index to purge them.
+//!
+
+use crate::admin_ui_api::schema::UpdateResponse;
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{SchedulerSettings, TresleFacadeServiceSettings};
+use crate::service::metrics::{MetricRecord, JOB_DIMENSION, STATUS_DIMENSION};
+use crate::service::query_options::{AggregateExt, QueryError, QueryOptions};
+use crate::service::state::AppState;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use mongodb::bson::{self, doc, to_document, Document};
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Default collection of the job leases.
+pub const DEFAULT_LEASE_COLLECTION: &str = "job-leases";
+/// Default collection of the job runs.
+pub const DEFAULT_RUN_COLLECTION: &str = "job-runs";
+/// Default number of seconds a lease outlives the interval of its job.
+const DEFAULT_LEASE_GRACE_SECONDS: u64 = 60;
+/// Default number of days the job runs are kept.
+const DEFAULT_RUN_RETENTION_DAYS: i64 = 7;
+
+/// Background job scheduled on every replica and run by the leader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Job {
+    HistoryRetention,
+    RetrievalSweeper,
+    LogSink,
+    KeyExpiry,
+    IngestionRetry,
+}
+
+impl Job {
+    pub const ALL: [Job; 5] = [
+        Job::HistoryRetention,
+        Job::RetrievalSweeper,
+        Job::LogSink,
+        Job::KeyExpiry,
+        Job::IngestionRetry,
+    ];
+
+    /// Returns the job named `name`.
+    pub fn parse(name: &str) -> Option<Job> {
+        Job::ALL.into_iter().find(|job| job.as_str() == name)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Job::HistoryRetention => "history_retention",
+            Job::RetrievalSweeper => "retrieval_sweeper",
+            Job::LogSink => "log_sink",
+            Job::KeyExpiry => "key_expiry",
+            Job::IngestionRetry => "ingestion_retry",
+        }
+    }
+}
+
+/// Scheduler options: lease and run collections, lease grace and run retention.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchedulerOptions {
+    pub lease_collection: String,
+    pub run_collection: String,
+    pub lease_grace: Duration,
+    pub run_retention_days: i64,
+}
+
+impl SettingsOptions for SchedulerOptions {
+    type Settings = SchedulerSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&SchedulerSettings> {
+        settings.scheduler.as_ref()
+    }
+
+    fn from_settings(settings: Option<&SchedulerSettings>) -> Self {
+        SchedulerOptions {
+            lease_collection: settings
+                .and_then(|settings| settings.lease_collection.clone())
+                .unwrap_or_else(|| DEFAULT_LEASE_COLLECTION.to_string()),
+            run_collection: settings
+                .and_then(|settings| settings.run_collection.clone())
+                .unwrap_or_else(|| DEFAULT_RUN_COLLECTION.to_string()),
+            lease_grace: Duration::from_secs(
+                settings
+                    .and_then(|settings| settings.lease_grace_seconds)
+                    .unwrap_or(DEFAULT_LEASE_GRACE_SECONDS),
+            ),
+            run_retention_days: settings
+                .and_then(|settings| settings.run_retention_days)
+                .unwrap_or(DEFAULT_RUN_RETENTION_DAYS),
+        }
+    }
+}
+
+impl SchedulerOptions {
+    /// Returns the expiry of a lease taken at `now` for a job run every `interval`, in milliseconds since the epoch.
+    pub fn lease_expiry(&self, now: DateTime<Utc>, interval: Duration) -> i64 {
+        now.timestamp_millis() + (interval + self.lease_grace).as_millis() as i64
+    }
+}
+
+/// Lease of a job, held by the replica running it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct JobLease {
+    #[serde(rename = "_id")]
+    pub job: String,
+    pub owner: String,
+    /// Expiry of the lease, in milliseconds since the epoch.
+    pub expires_at: i64,
+}
+
+/// Status of a job run.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Success,
+    Failure,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Success => "success",
+            JobStatus::Failure => "failure",
+        }
+    }
+}
+
+/// Run of a job by the leader.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct JobRun {
+    pub job: String,
+    pub owner: String,
+    pub started_at: String,
+    pub finished_at: String,
+    pub duration_ms: i64,
+    pub status: JobStatus,
+    pub details: String,
+}
+
+/// Returns the ID of the replica, the owner of its leases: the host name (the pod name on Kubernetes) and a UUID,
+/// so a restarted pod doesn't resume the leases of its previous process.
+pub fn instance_id() -> &'static str {
+    static INSTANCE_ID: OnceLock<String> = OnceLock::new();
+    INSTANCE_ID.get_or_init(|| {
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "facade".to_string());
+        format!("{}-{}", host, Uuid::now_v7())
+    })
+}
+
+/// Takes or renews the lease of a job run every `interval`. Returns false if another replica holds it, or if the
+/// lease can't be read.
+pub async fn acquire_lease(app_state: &AppState, job: Job, interval: Duration) -> bool {
+    let options = app_state.options::<SchedulerOptions>();
+    let owner = instance_id();
+    match try_acquire_lease(app_state, &options, job, owner, interval).await {
+        Ok(acquired) => acquired,
+        Err(message) => {
+            error!(
+                message = format!(
+                    "Failed to acquire the lease of job '{}'. Error: {}",
+                    job.as_str(),
+                    message
+                )
+            );
+            false
+        }
+    }
+}
+
+async fn try_acquire_lease(
+    app_state: &AppState,
+    options: &SchedulerOptions,
+    job: Job,
+    owner: &str,
+    interval: Duration,
+) -> Result<bool, String> {
+    let db = &app_state.db;
+    let collection_name = &options.lease_collection;
+    let expires_at = options.lease_expiry(Utc::now(), interval);
+
+    // The leader renews its lease
+    let renewed = db
+        .update_document(
+            collection_name,
+            doc! {"_id": job.as_str(), "owner": owner},
+            doc! {"expires_at": expires_at},
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    let renewed: UpdateResponse = serde_json::from_value(renewed).map_err(|e| e.to_string())?;
+    if renewed.matchedCount > 0 {
+        return Ok(true);
+    }
+
+    let lease = || doc! {"_id": job.as_str(), "owner": owner, "expires_at": expires_at};
+    if db.create_document(collection_name, lease()).await.is_ok() {
+        info!(message = format!("Took the lease of job '{}'.", job.as_str()));
+        return Ok(true);
+    }
+    let Some(held) = db
+        .get_document(collection_name, doc! {"_id": job.as_str()})
+        .await
+        .map_err(|e| e.to_string())?
+    else {
+        // The lease was released meanwhile, the next run takes it
+        return Ok(false);
+    };
+    let held: JobLease = serde_json::from_value(held).map_err(|e| e.to_string())?;
+    if held.expires_at > Utc::now().timestamp_millis() {
+        debug!(message = format!("Job '{}' is run by '{}'.", job.as_str(), held.owner));
+        return Ok(false);
+    }
+    warn!(
+        message = format!(
+            "Taking over the expired lease of job '{}' from '{}'.",
+            job.as_str(),
+            held.owner
+        )
+    );
+    // Only the expired lease is deleted, not a lease taken over meanwhile by another replica
+    db.delete_document(
+        collection_name,
+        doc! {"_id": job.as_str(), "expires_at": held.expires_at},
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    Ok(db.create_document(collection_name, lease()).await.is_ok())
+}
+
+/// Start of a job run, recorded by `finish`.
+pub struct JobRunStart {
+    job: Job,
+    started_at: DateTime<Utc>,
+}
+
+impl JobRunStart {
+    pub fn new(job: Job) -> Self {
+        JobRunStart {
+            job,
+            started_at: Utc::now(),
+        }
+    }
+
+    /// Records the run in the run collection and its duration in the `Job Run Duration` metric.
+    pub async fn finish(self, app_state: &AppState, status: JobStatus, details: String) {
+        let finished_at = Utc::now();
+        let job_run = JobRun {
+            job: self.job.as_str().to_string(),
+            owner: instance_id().to_string(),
+            started_at: self.started_at.to_rfc3339(),
+            finished_at: finished_at.to_rfc3339(),
+            duration_ms: (finished_at - self.started_at).num_milliseconds(),
+            status,
+            details,
+        };
+        app_state
+            .record_metric(
+                MetricRecord::duration_ms("Job Run Duration", job_run.duration_ms)
+                    .dimension(JOB_DIMENSION, self.job.as_str())
+                    .dimension(STATUS_DIMENSION, status.as_str()),
+            )
+            .await;
+        let options = app_state.options::<SchedulerOptions>();
+        let document = match run_document(&job_run, &options, finished_at) {
+            Ok(document) => document,
+            Err(e) => {
+                error!(message = format!("Failed to serialize job run. Error: {}", e));
+                return;
+            }
+        };
+        if let Err(e) = app_state
+            .db
+            .create_document(&options.run_collection, document)
+            .await
+        {
+            error!(message = format!("Failed to record job run. Error: {}", e));
+        }
+    }
+}
+
+/// Builds the document of a job run, expiring `run_retention_days` after it finished.
+fn run_document(
+    job_run: &JobRun,
+    options: &SchedulerOptions,
+    finished_at: DateTime<Utc>,
+) -> Result<Document, bson::ser::Error> {
+    let mut document = to_document(job_run)?;
+    let expires_at = finished_at + ChronoDuration::days(options.run_retention_days);
+    document.insert(
+        "expires_at",
+        bson::DateTime::from_millis(expires_at.timestamp_millis()),
+    );
+    Ok(document)
+}
+
+/// Returns the latest runs, of a job if set, the latest first.
+pub async fn job_runs(
+    app_state: &AppState,
+    job: Option<&str>,
+    limit: i64,
+) -> Result<Vec<JobRun>, QueryError> {
+    let filter = match job {
+        Some(job) => doc! {"job": job},
+        None => doc! {},
+    };
+    let pipeline = vec![
+        doc! {"$match": filter},
+        doc! {"$sort": {"finished_at": -1}},
+        doc! {"$limit": limit},
+        doc! {"$project": {"_id": 0, "expires_at": 0}},
+    ];
+    let runs = app_state
+        .db
+        .aggregate(
+            &app_state.options::<SchedulerOptions>().run_collection,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await?;
+    Ok(runs
+        .into_iter()
+        .filter_map(|run| serde_json::from_value(run).ok())
+        .collect())
+}
+
+/// Returns the leases of the jobs.
+pub async fn job_leases(app_state: &AppState) -> Result<Vec<JobLease>, QueryError> {
+    let pipeline = vec![doc! {"$sort": {"_id": 1}}];
+    let leases = app_state
+        .db
+        .aggregate(
+            &app_state.options::<SchedulerOptions>().lease_collection,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await?;
+    Ok(leases
+        .into_iter()
+        .filter_map(|lease| serde_json::from_value(lease).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_success_scheduler_options_from_settings() {
+        let options = SchedulerOptions::from_settings(None);
+        assert_eq!(options.lease_collection, DEFAULT_LEASE_COLLECTION);
+        assert_eq!(options.run_collection, DEFAULT_RUN_COLLECTION);
+        let now = Utc.with_ymd_and_hms(2024, 7, 26, 10, 0, 0).unwrap();
+        // The lease outlives the interval by the grace period
+        assert_eq!(
+            options.lease_expiry(now, Duration::from_secs(60)),
+            now.timestamp_millis() + 120_000
+        );
+        assert_eq!(instance_id(), instance_id());
+        assert_eq!(Job::parse("log_sink"), Some(Job::LogSink));
+        assert_eq!(Job::parse("rollups"), None);
+    }
+
+    #[test]
+    fn test_success_run_document() {
+        let options = SchedulerOptions::from_settings(None);
+        let finished_at = Utc.with_ymd_and_hms(2024, 7, 26, 10, 0, 0).unwrap();
+        let job_run = JobRun {
+            job: Job::RetrievalSweeper.as_str().to_string(),
+            owner: instance_id().to_string(),
+            started_at: finished_at.to_rfc3339(),
+            finished_at: finished_at.to_rfc3339(),
+            duration_ms: 0,
+            status: JobStatus::Success,
+            details: "Expired 0 retrievals.".to_string(),
+        };
+        let document = run_document(&job_run, &options, finished_at).unwrap();
+        assert_eq!(document.get_str("status").unwrap(), "success");
+        assert_eq!(
+            document
+                .get_datetime("expires_at")
+                .unwrap()
+                .timestamp_millis(),
+            (finished_at + ChronoDuration::days(7)).timestamp_millis()
+        );
+    }
+}
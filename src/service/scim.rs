@@ -0,0 +1,1059 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the app entitlements pushed by the identity provider (IdP) of an enterprise customer through
+//! the SCIM 2.0 endpoints (RFC 7643/7644), and their enforcement on the retrievals.
+//! The SCIM users and groups are stored in the entitlements collection (`app-entitlements` by default). A group named
+//! after an app with the group prefix (`tresleai-app-<app_name>` by default) makes the app IdP-managed: only the
+//! active users who are members of the group, or who list the app in their `entitlements`, may query it. Apps without
+//! such a group are not restricted by the entitlements.
+//! The user is matched on its `userName`, which the IdP sets to the `user_details.user_id` of the retrievals.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{ScimSettings, TresleFacadeServiceSettings};
+use crate::service::api_docs::constant_time_eq;
+use crate::service::query_options::{AggregateExt, QueryError, QueryOptions};
+use crate::service::state::AppState;
+use axum::{
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    Json,
+};
+use chrono::Utc;
+use mongodb::bson::{doc, to_document, Document};
+use secrecy::ExposeSecret;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::debug;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Default collection of the SCIM users and groups.
+pub const DEFAULT_COLLECTION: &str = "app-entitlements";
+/// Default prefix of the names of the groups granting access to an app.
+const DEFAULT_GROUP_PREFIX: &str = "tresleai-app-";
+/// Default and maximum number of resources of a list page.
+const DEFAULT_PAGE_SIZE: usize = 100;
+/// Field of the stored documents holding the SCIM resource type.
+const RESOURCE_TYPE_FIELD: &str = "resource_type";
+
+pub const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+pub const LIST_RESPONSE_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+pub const PATCH_OP_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:PatchOp";
+const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScimError {
+    #[error("Missing or invalid SCIM bearer token.")]
+    Unauthorized,
+    #[error("The SCIM endpoints are disabled: no SCIM bearer token is configured.")]
+    Disabled,
+    #[error("{0}")]
+    InvalidValue(String),
+    #[error("Unsupported filter '{0}'. Filters are '<attribute> eq \"<value>\"'.")]
+    InvalidFilter(String),
+    #[error("Unsupported patch operation: {0}")]
+    InvalidPatch(String),
+    #[error("A {resource_type} with {attribute} '{value}' already exists.")]
+    Uniqueness {
+        resource_type: &'static str,
+        attribute: &'static str,
+        value: String,
+    },
+    #[error("No {resource_type} found with id '{id}'.")]
+    NotFound {
+        resource_type: &'static str,
+        id: String,
+    },
+    #[error("The user is not entitled to app '{0}' by the identity provider.")]
+    NotEntitled(String),
+    #[error("Failed to access the entitlements: {0}")]
+    Store(String),
+}
+
+impl ScimError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ScimError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ScimError::NotEntitled(_) => StatusCode::FORBIDDEN,
+            ScimError::InvalidValue(_)
+            | ScimError::InvalidFilter(_)
+            | ScimError::InvalidPatch(_) => StatusCode::BAD_REQUEST,
+            ScimError::Uniqueness { .. } => StatusCode::CONFLICT,
+            ScimError::NotFound { .. } | ScimError::Disabled => StatusCode::NOT_FOUND,
+            ScimError::Store(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// SCIM error type of the bad requests.
+    fn scim_type(&self) -> Option<&'static str> {
+        match self {
+            ScimError::InvalidValue(_) => Some("invalidValue"),
+            ScimError::InvalidFilter(_) => Some("invalidFilter"),
+            ScimError::InvalidPatch(_) => Some("invalidSyntax"),
+            ScimError::Uniqueness { .. } => Some("uniqueness"),
+            _ => None,
+        }
+    }
+}
+
+impl From<QueryError> for ScimError {
+    fn from(e: QueryError) -> Self {
+        ScimError::Store(e.to_string())
+    }
+}
+
+/// Errors are returned as SCIM error messages, which the IdPs display.
+impl From<ScimError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: ScimError) -> Self {
+        let status_code = e.status_code();
+        let error_message = e.to_string();
+        debug!(message = error_message);
+        let mut body = json!({
+            "schemas": [ERROR_SCHEMA],
+            "status": status_code.as_u16().to_string(),
+            "detail": error_message,
+        });
+        if let Some(scim_type) = e.scim_type() {
+            body["scimType"] = json!(scim_type);
+        }
+        (status_code, Json(body))
+    }
+}
+
+/// SCIM options: entitlements collection, app group prefix and bearer token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScimOptions {
+    pub collection: String,
+    pub group_prefix: String,
+    /// Bearer token of the IdP. The SCIM endpoints are neither mounted nor served without it.
+    pub bearer_token: Option<String>,
+}
+
+impl SettingsOptions for ScimOptions {
+    type Settings = ScimSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&ScimSettings> {
+        settings.scim.as_ref()
+    }
+
+    fn from_settings(settings: Option<&ScimSettings>) -> Self {
+        ScimOptions {
+            collection: settings
+                .and_then(|settings| settings.collection.clone())
+                .unwrap_or_else(|| DEFAULT_COLLECTION.to_string()),
+            group_prefix: settings
+                .and_then(|settings| settings.group_prefix.clone())
+                .unwrap_or_else(|| DEFAULT_GROUP_PREFIX.to_string()),
+            bearer_token: settings
+                .and_then(|settings| settings.bearer_token.as_ref())
+                .map(|token| token.expose_secret().trim().to_string())
+                .filter(|token| !token.is_empty()),
+        }
+    }
+}
+
+impl ScimOptions {
+    /// Checks the bearer token of a SCIM request. Every request is refused when no token is configured.
+    pub fn authorize(&self, headers: &HeaderMap) -> Result<(), ScimError> {
+        let Some(bearer_token) = &self.bearer_token else {
+            return Err(ScimError::Disabled);
+        };
+        let authorized = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| {
+                constant_time_eq(token.trim().as_bytes(), bearer_token.as_bytes())
+            });
+        if authorized {
+            Ok(())
+        } else {
+            Err(ScimError::Unauthorized)
+        }
+    }
+
+    /// Returns the name of the group granting access to an app.
+    pub fn group_name(&self, app_name: &str) -> String {
+        format!("{}{}", self.group_prefix, app_name)
+    }
+}
+
+/// Multi-valued attribute of a SCIM resource: an entitlement (app name) of a user or a member (user id) of a group.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ScimValue {
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<String>,
+}
+
+/// Metadata of a SCIM resource.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimMeta {
+    pub resource_type: String,
+    pub created: String,
+    pub last_modified: String,
+}
+
+/// SCIM user. The `entitlements` are the names of the apps the user may query.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimUser {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(default)]
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    pub user_name: String,
+    #[serde(default = "default_active")]
+    pub active: bool,
+    #[serde(default)]
+    pub entitlements: Vec<ScimValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ScimMeta>,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+/// SCIM group. The `members` are the ids of the member users.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimGroup {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(default)]
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    pub display_name: String,
+    #[serde(default)]
+    pub members: Vec<ScimValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ScimMeta>,
+}
+
+/// Operation of a SCIM PATCH request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct PatchOperation {
+    pub op: String,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub value: Option<serde_json::Value>,
+}
+
+/// SCIM PATCH request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct PatchRequest {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(rename = "Operations")]
+    pub operations: Vec<PatchOperation>,
+}
+
+/// Kind of a SCIM PATCH operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PatchOp {
+    Add,
+    Remove,
+    Replace,
+}
+
+impl PatchOperation {
+    fn kind(&self) -> Result<PatchOp, ScimError> {
+        match self.op.to_ascii_lowercase().as_str() {
+            "add" => Ok(PatchOp::Add),
+            "remove" => Ok(PatchOp::Remove),
+            "replace" => Ok(PatchOp::Replace),
+            op => Err(ScimError::InvalidPatch(format!("unknown op '{}'.", op))),
+        }
+    }
+
+    /// Value of the operation, required by the add and replace operations.
+    fn required_value(&self) -> Result<&serde_json::Value, ScimError> {
+        self.value
+            .as_ref()
+            .ok_or_else(|| ScimError::InvalidPatch(format!("'{}' requires a value.", self.op)))
+    }
+}
+
+/// A SCIM resource stored in the entitlements collection.
+pub trait ScimResource: Serialize + DeserializeOwned + Send + Sync {
+    const RESOURCE_TYPE: &'static str;
+    const SCHEMA: &'static str;
+    /// Attribute unique among the resources of the type.
+    const UNIQUE_ATTRIBUTE: &'static str;
+    /// Attributes the list filters can match.
+    const FILTER_ATTRIBUTES: &'static [&'static str];
+
+    fn id(&self) -> &str;
+    fn unique_value(&self) -> &str;
+    /// Sets the server-managed attributes of the resource.
+    fn set_server_attributes(&mut self, id: String, meta: ScimMeta);
+    fn meta(&self) -> Option<&ScimMeta>;
+    /// Applies an operation of a PATCH request.
+    fn apply_patch(&mut self, operation: &PatchOperation) -> Result<(), ScimError>;
+
+    fn validate(&self) -> Result<(), ScimError> {
+        if self.unique_value().trim().is_empty() {
+            return Err(ScimError::InvalidValue(format!(
+                "{} is required.",
+                Self::UNIQUE_ATTRIBUTE
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl ScimResource for ScimUser {
+    const RESOURCE_TYPE: &'static str = "User";
+    const SCHEMA: &'static str = USER_SCHEMA;
+    const UNIQUE_ATTRIBUTE: &'static str = "userName";
+    const FILTER_ATTRIBUTES: &'static [&'static str] = &["userName", "externalId"];
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn unique_value(&self) -> &str {
+        &self.user_name
+    }
+
+    fn set_server_attributes(&mut self, id: String, meta: ScimMeta) {
+        self.schemas = vec![Self::SCHEMA.to_string()];
+        self.id = id;
+        self.meta = Some(meta);
+    }
+
+    fn meta(&self) -> Option<&ScimMeta> {
+        self.meta.as_ref()
+    }
+
+    fn apply_patch(&mut self, operation: &PatchOperation) -> Result<(), ScimError> {
+        let kind = operation.kind()?;
+        match operation.path.as_deref() {
+            // Attributes replaced by value, e.g. `{"op": "replace", "value": {"active": false}}`
+            None => {
+                let mut patched = serde_json::to_value(&*self)
+                    .map_err(|e| ScimError::InvalidPatch(e.to_string()))?;
+                merge_attributes(&mut patched, operation.required_value()?, &["id", "meta"])?;
+                *self = serde_json::from_value(patched)
+                    .map_err(|e| ScimError::InvalidPatch(e.to_string()))?;
+                Ok(())
+            }
+            Some("active") => {
+                self.active = parse_bool(operation.required_value()?)?;
+                Ok(())
+            }
+            Some("userName") => {
+                self.user_name = parse_string(operation.required_value()?)?;
+                Ok(())
+            }
+            Some("externalId") => {
+                self.external_id = match kind {
+                    PatchOp::Remove => None,
+                    _ => Some(parse_string(operation.required_value()?)?),
+                };
+                Ok(())
+            }
+            Some(path) if path.starts_with("entitlements") => patch_values(
+                &mut self.entitlements,
+                "entitlements",
+                kind,
+                path,
+                operation,
+            ),
+            Some(path) => Err(ScimError::InvalidPatch(format!(
+                "unsupported path '{}'.",
+                path
+            ))),
+        }
+    }
+}
+
+impl ScimResource for ScimGroup {
+    const RESOURCE_TYPE: &'static str = "Group";
+    const SCHEMA: &'static str = GROUP_SCHEMA;
+    const UNIQUE_ATTRIBUTE: &'static str = "displayName";
+    const FILTER_ATTRIBUTES: &'static [&'static str] = &["displayName", "externalId"];
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn unique_value(&self) -> &str {
+        &self.display_name
+    }
+
+    fn set_server_attributes(&mut self, id: String, meta: ScimMeta) {
+        self.schemas = vec![Self::SCHEMA.to_string()];
+        self.id = id;
+        self.meta = Some(meta);
+    }
+
+    fn meta(&self) -> Option<&ScimMeta> {
+        self.meta.as_ref()
+    }
+
+    fn apply_patch(&mut self, operation: &PatchOperation) -> Result<(), ScimError> {
+        let kind = operation.kind()?;
+        match operation.path.as_deref() {
+            None => {
+                let mut patched = serde_json::to_value(&*self)
+                    .map_err(|e| ScimError::InvalidPatch(e.to_string()))?;
+                merge_attributes(&mut patched, operation.required_value()?, &["id", "meta"])?;
+                *self = serde_json::from_value(patched)
+                    .map_err(|e| ScimError::InvalidPatch(e.to_string()))?;
+                Ok(())
+            }
+            Some("displayName") => {
+                self.display_name = parse_string(operation.required_value()?)?;
+                Ok(())
+            }
+            Some("externalId") => {
+                self.external_id = match kind {
+                    PatchOp::Remove => None,
+                    _ => Some(parse_string(operation.required_value()?)?),
+                };
+                Ok(())
+            }
+            Some(path) if path.starts_with("members") => {
+                patch_values(&mut self.members, "members", kind, path, operation)
+            }
+            Some(path) => Err(ScimError::InvalidPatch(format!(
+                "unsupported path '{}'.",
+                path
+            ))),
+        }
+    }
+}
+
+/// Merges the attributes of a PATCH value without path into a resource, except the server-managed ones.
+fn merge_attributes(
+    resource: &mut serde_json::Value,
+    value: &serde_json::Value,
+    read_only: &[&str],
+) -> Result<(), ScimError> {
+    let (Some(resource), Some(attributes)) = (resource.as_object_mut(), value.as_object()) else {
+        return Err(ScimError::InvalidPatch(
+            "a value without path must be an object.".to_string(),
+        ));
+    };
+    for (attribute, value) in attributes {
+        if !read_only.contains(&attribute.as_str()) {
+            resource.insert(attribute.clone(), value.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Reads a boolean PATCH value. Some IdPs send booleans as strings, e.g. `"False"`.
+fn parse_bool(value: &serde_json::Value) -> Result<bool, ScimError> {
+    match value {
+        serde_json::Value::Bool(value) => Ok(*value),
+        serde_json::Value::String(value) if value.eq_ignore_ascii_case("true") => Ok(true),
+        serde_json::Value::String(value) if value.eq_ignore_ascii_case("false") => Ok(false),
+        value => Err(ScimError::InvalidPatch(format!(
+            "'{}' is not a boolean.",
+            value
+        ))),
+    }
+}
+
+fn parse_string(value: &serde_json::Value) -> Result<String, ScimError> {
+    value
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| ScimError::InvalidPatch(format!("'{}' is not a string.", value)))
+}
+
+/// Applies a PATCH operation to a multi-valued attribute. `path` is the attribute, or a value filter like
+/// `members[value eq "<id>"]` for a removal.
+fn patch_values(
+    values: &mut Vec<ScimValue>,
+    attribute: &str,
+    kind: PatchOp,
+    path: &str,
+    operation: &PatchOperation,
+) -> Result<(), ScimError> {
+    let filtered_value = match &path[attribute.len()..] {
+        "" => None,
+        filter => Some(
+            filter
+                .strip_prefix('[')
+                .and_then(|filter| filter.strip_suffix(']'))
+                .and_then(|filter| parse_filter(filter, &["value"]).ok())
+                .map(|(_, value)| value)
+                .ok_or_else(|| ScimError::InvalidPatch(format!("unsupported path '{}'.", path)))?,
+        ),
+    };
+    let operation_values = match &operation.value {
+        Some(value) => {
+            let value = match value {
+                serde_json::Value::Array(_) => value.clone(),
+                value => json!([value]),
+            };
+            serde_json::from_value::<Vec<ScimValue>>(value)
+                .map_err(|e| ScimError::InvalidPatch(e.to_string()))?
+        }
+        None => Vec::new(),
+    };
+    match (kind, filtered_value) {
+        (PatchOp::Remove, Some(removed)) => values.retain(|value| value.value != removed),
+        (PatchOp::Remove, None) if operation_values.is_empty() => values.clear(),
+        (PatchOp::Remove, None) => values.retain(|value| {
+            !operation_values
+                .iter()
+                .any(|removed| removed.value == value.value)
+        }),
+        (PatchOp::Add, None) => {
+            for added in operation_values {
+                if !values.iter().any(|value| value.value == added.value) {
+                    values.push(added);
+                }
+            }
+        }
+        (PatchOp::Replace, None) => *values = operation_values,
+        (_, Some(_)) => {
+            return Err(ScimError::InvalidPatch(format!(
+                "'{}' does not support a value filter.",
+                operation.op
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// Parses a SCIM filter `<attribute> eq "<value>"` on one of the supported attributes.
+fn parse_filter(filter: &str, attributes: &[&str]) -> Result<(String, String), ScimError> {
+    let invalid = || ScimError::InvalidFilter(filter.to_string());
+    let mut parts = filter.trim().splitn(3, ' ');
+    let (Some(attribute), Some(operator), Some(value)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid());
+    };
+    let attribute = attributes
+        .iter()
+        .find(|supported| supported.eq_ignore_ascii_case(attribute))
+        .ok_or_else(invalid)?;
+    if !operator.eq_ignore_ascii_case("eq") {
+        return Err(invalid());
+    }
+    let value = value
+        .trim()
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+    Ok((attribute.to_string(), value.replace("\\\"", "\"")))
+}
+
+/// Page of SCIM resources, as a SCIM list response.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ListResponse<R> {
+    pub schemas: Vec<String>,
+    pub total_results: usize,
+    pub start_index: usize,
+    pub items_per_page: usize,
+    #[serde(rename = "Resources")]
+    pub resources: Vec<R>,
+}
+
+/// Reads the resource of a type matching a filter.
+async fn find_resource<R: ScimResource>(
+    app_state: &AppState,
+    mut filter: Document,
+) -> Result<Option<R>, ScimError> {
+    filter.insert(RESOURCE_TYPE_FIELD, R::RESOURCE_TYPE);
+    let options = app_state.options::<ScimOptions>();
+    let document = app_state
+        .db
+        .get_document(&options.collection, filter)
+        .await
+        .map_err(|e| ScimError::Store(e.to_string()))?;
+    document
+        .map(|document| {
+            serde_json::from_value(document).map_err(|e| ScimError::Store(e.to_string()))
+        })
+        .transpose()
+}
+
+/// Reads a resource by id.
+pub async fn get_resource<R: ScimResource>(app_state: &AppState, id: &str) -> Result<R, ScimError> {
+    find_resource(app_state, doc! {"_id": id})
+        .await?
+        .ok_or_else(|| ScimError::NotFound {
+            resource_type: R::RESOURCE_TYPE,
+            id: id.to_string(),
+        })
+}
+
+/// Rejects a resource whose unique attribute is taken by another resource of the type.
+async fn check_unique<R: ScimResource>(
+    app_state: &AppState,
+    resource: &R,
+) -> Result<(), ScimError> {
+    let existing: Option<R> = find_resource(
+        app_state,
+        doc! {R::UNIQUE_ATTRIBUTE: resource.unique_value()},
+    )
+    .await?;
+    match existing {
+        Some(existing) if existing.id() != resource.id() => Err(ScimError::Uniqueness {
+            resource_type: R::RESOURCE_TYPE,
+            attribute: R::UNIQUE_ATTRIBUTE,
+            value: resource.unique_value().to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Stored document of a resource, with its id as `_id`.
+fn resource_document<R: ScimResource>(resource: &R) -> Result<Document, ScimError> {
+    let mut document = to_document(resource).map_err(|e| ScimError::Store(e.to_string()))?;
+    document.insert(RESOURCE_TYPE_FIELD, R::RESOURCE_TYPE);
+    Ok(document)
+}
+
+/// Creates a resource pushed by the IdP, with a new id.
+pub async fn create_resource<R: ScimResource>(
+    app_state: &AppState,
+    mut resource: R,
+) -> Result<R, ScimError> {
+    resource.validate()?;
+    let now = Utc::now().to_rfc3339();
+    resource.set_server_attributes(
+        Uuid::new_v4().to_string(),
+        ScimMeta {
+            resource_type: R::RESOURCE_TYPE.to_string(),
+            created: now.clone(),
+            last_modified: now,
+        },
+    );
+    check_unique(app_state, &resource).await?;
+    let mut document = resource_document(&resource)?;
+    document.insert("_id", resource.id());
+    app_state
+        .db
+        .create_document(&app_state.options::<ScimOptions>().collection, document)
+        .await
+        .map_err(|e| ScimError::Store(e.to_string()))?;
+    Ok(resource)
+}
+
+/// Replaces a resource, keeping its id and creation time.
+pub async fn replace_resource<R: ScimResource>(
+    app_state: &AppState,
+    id: &str,
+    mut resource: R,
+) -> Result<R, ScimError> {
+    resource.validate()?;
+    let existing: R = get_resource(app_state, id).await?;
+    let created = existing
+        .meta()
+        .map(|meta| meta.created.clone())
+        .unwrap_or_else(|| Utc::now().to_rfc3339());
+    resource.set_server_attributes(
+        id.to_string(),
+        ScimMeta {
+            resource_type: R::RESOURCE_TYPE.to_string(),
+            created,
+            last_modified: Utc::now().to_rfc3339(),
+        },
+    );
+    check_unique(app_state, &resource).await?;
+    app_state
+        .db
+        .update_document(
+            &app_state.options::<ScimOptions>().collection,
+            doc! {"_id": id, RESOURCE_TYPE_FIELD: R::RESOURCE_TYPE},
+            resource_document(&resource)?,
+        )
+        .await
+        .map_err(|e| ScimError::Store(e.to_string()))?;
+    Ok(resource)
+}
+
+/// Applies the operations of a PATCH request to a resource.
+pub async fn patch_resource<R: ScimResource>(
+    app_state: &AppState,
+    id: &str,
+    patch: &PatchRequest,
+) -> Result<R, ScimError> {
+    let mut resource: R = get_resource(app_state, id).await?;
+    for operation in &patch.operations {
+        resource.apply_patch(operation)?;
+    }
+    replace_resource(app_state, id, resource).await
+}
+
+/// Deletes a resource.
+pub async fn delete_resource<R: ScimResource>(
+    app_state: &AppState,
+    id: &str,
+) -> Result<(), ScimError> {
+    let deleted = app_state
+        .db
+        .delete_document(
+            &app_state.options::<ScimOptions>().collection,
+            doc! {"_id": id, RESOURCE_TYPE_FIELD: R::RESOURCE_TYPE},
+        )
+        .await
+        .map_err(|e| ScimError::Store(e.to_string()))?;
+    if deleted
+        .get("deletedCount")
+        .and_then(serde_json::Value::as_u64)
+        == Some(0)
+    {
+        return Err(ScimError::NotFound {
+            resource_type: R::RESOURCE_TYPE,
+            id: id.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Lists a page of the resources of a type, optionally filtered. `start_index` is 1-based.
+pub async fn list_resources<R: ScimResource>(
+    app_state: &AppState,
+    filter: Option<&str>,
+    start_index: Option<usize>,
+    count: Option<usize>,
+) -> Result<ListResponse<R>, ScimError> {
+    let mut match_filter = doc! {RESOURCE_TYPE_FIELD: R::RESOURCE_TYPE};
+    if let Some(filter) = filter {
+        let (attribute, value) = parse_filter(filter, R::FILTER_ATTRIBUTES)?;
+        match_filter.insert(attribute, value);
+    }
+    let start_index = start_index.unwrap_or(1).max(1);
+    let count = count.unwrap_or(DEFAULT_PAGE_SIZE).min(DEFAULT_PAGE_SIZE);
+    let query_options = app_state.options::<QueryOptions>();
+    query_options.check_offset(start_index as i64 - 1)?;
+
+    let collection = app_state.options::<ScimOptions>().collection;
+    let page_pipeline = vec![
+        doc! {"$match": match_filter.clone()},
+        doc! {"$sort": {"meta.created": 1, "_id": 1}},
+        doc! {"$skip": (start_index - 1) as i64},
+        doc! {"$limit": count as i64},
+    ];
+    let count_pipeline = vec![doc! {"$match": match_filter}, doc! {"$count": "total"}];
+    let resources = app_state
+        .db
+        .aggregate(&collection, page_pipeline, &query_options)
+        .await?
+        .into_iter()
+        .map(|document| {
+            serde_json::from_value(document).map_err(|e| ScimError::Store(e.to_string()))
+        })
+        .collect::<Result<Vec<R>, ScimError>>()?;
+    let total_results = app_state
+        .db
+        .aggregate(&collection, count_pipeline, &query_options)
+        .await?
+        .first()
+        .and_then(|document| document.get("total"))
+        .and_then(serde_json::Value::as_u64)
+        .unwrap_or_default() as usize;
+    Ok(ListResponse {
+        schemas: vec![LIST_RESPONSE_SCHEMA.to_string()],
+        total_results,
+        start_index,
+        items_per_page: resources.len(),
+        resources,
+    })
+}
+
+/// Outcome of the entitlement check of a retrieval.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntitlementDecision {
+    /// The app has no IdP group, the entitlements do not apply.
+    Unmanaged,
+    Entitled,
+    /// The user is unknown to the IdP, deactivated, or neither a member of the group of the app nor entitled to it.
+    NotEntitled,
+}
+
+/// Checks the entitlement of a user to query an app. The entitlements don't apply, and are not read, while SCIM is
+/// disabled.
+pub async fn check_entitlement(
+    app_state: &AppState,
+    app_name: &str,
+    user_id: &str,
+) -> Result<EntitlementDecision, ScimError> {
+    entitlement(
+        app_state,
+        &app_state.options::<ScimOptions>(),
+        app_name,
+        user_id,
+    )
+    .await
+}
+
+/// Checks the entitlement of a user to query an app with the SCIM options.
+async fn entitlement(
+    app_state: &AppState,
+    options: &ScimOptions,
+    app_name: &str,
+    user_id: &str,
+) -> Result<EntitlementDecision, ScimError> {
+    if options.bearer_token.is_none() {
+        return Ok(EntitlementDecision::Unmanaged);
+    }
+    let group_name = options.group_name(app_name);
+    let Some(group) =
+        find_resource::<ScimGroup>(app_state, doc! {"displayName": &group_name}).await?
+    else {
+        return Ok(EntitlementDecision::Unmanaged);
+    };
+    let user = find_resource::<ScimUser>(app_state, doc! {"userName": user_id}).await?;
+    Ok(entitlement_decision(&group, user.as_ref(), app_name))
+}
+
+/// Decides the entitlement of a user to an app managed by a group.
+fn entitlement_decision(
+    group: &ScimGroup,
+    user: Option<&ScimUser>,
+    app_name: &str,
+) -> EntitlementDecision {
+    let entitled = user.is_some_and(|user| {
+        user.active
+            && (group.members.iter().any(|member| member.value == user.id)
+                || user
+                    .entitlements
+                    .iter()
+                    .any(|entitlement| entitlement.value == app_name))
+    });
+    if entitled {
+        EntitlementDecision::Entitled
+    } else {
+        EntitlementDecision::NotEntitled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: &str, entitlements: &[&str]) -> ScimUser {
+        ScimUser {
+            schemas: vec![USER_SCHEMA.to_string()],
+            id: id.to_string(),
+            external_id: None,
+            user_name: format!("{}@example.com", id),
+            active: true,
+            entitlements: entitlements
+                .iter()
+                .map(|app| ScimValue {
+                    value: app.to_string(),
+                    display: None,
+                })
+                .collect(),
+            meta: None,
+        }
+    }
+
+    fn group(members: &[&str]) -> ScimGroup {
+        ScimGroup {
+            schemas: vec![GROUP_SCHEMA.to_string()],
+            id: "group-1".to_string(),
+            external_id: None,
+            display_name: "tresleai-app-app100".to_string(),
+            members: members
+                .iter()
+                .map(|member| ScimValue {
+                    value: member.to_string(),
+                    display: None,
+                })
+                .collect(),
+            meta: None,
+        }
+    }
+
+    fn operation(op: &str, path: Option<&str>, value: Option<serde_json::Value>) -> PatchOperation {
+        PatchOperation {
+            op: op.to_string(),
+            path: path.map(str::to_string),
+            value,
+        }
+    }
+
+    #[test]
+    fn test_success_entitlement_decision() {
+        let group = group(&["user-1"]);
+        let member = user("user-1", &[]);
+        let entitled = user("user-2", &["app100"]);
+        let other = user("user-3", &["app200"]);
+        let mut inactive = user("user-1", &[]);
+        inactive.active = false;
+
+        assert_eq!(
+            entitlement_decision(&group, Some(&member), "app100"),
+            EntitlementDecision::Entitled
+        );
+        assert_eq!(
+            entitlement_decision(&group, Some(&entitled), "app100"),
+            EntitlementDecision::Entitled
+        );
+        for user in [Some(&other), Some(&inactive), None] {
+            assert_eq!(
+                entitlement_decision(&group, user, "app100"),
+                EntitlementDecision::NotEntitled
+            );
+        }
+    }
+
+    #[test]
+    fn test_success_apply_patch() {
+        // Group membership as sent by Azure AD and Okta
+        let mut group = group(&["user-1"]);
+        group
+            .apply_patch(&operation(
+                "Add",
+                Some("members"),
+                Some(json!([{"value": "user-2"}, {"value": "user-1"}])),
+            ))
+            .unwrap();
+        assert_eq!(group.members.len(), 2);
+        group
+            .apply_patch(&operation(
+                "remove",
+                Some("members[value eq \"user-1\"]"),
+                None,
+            ))
+            .unwrap();
+        assert_eq!(
+            group.members,
+            vec![ScimValue {
+                value: "user-2".to_string(),
+                display: None
+            }]
+        );
+
+        // Deactivation with a path and without a path
+        let mut user = user("user-1", &[]);
+        user.apply_patch(&operation("replace", Some("active"), Some(json!("False"))))
+            .unwrap();
+        assert!(!user.active);
+        user.apply_patch(&operation(
+            "replace",
+            None,
+            Some(json!({"active": true, "id": "other"})),
+        ))
+        .unwrap();
+        assert!(user.active);
+        assert_eq!(user.id, "user-1");
+    }
+
+    #[test]
+    fn test_failure_apply_patch() {
+        let mut user = user("user-1", &[]);
+        assert!(matches!(
+            user.apply_patch(&operation("move", Some("active"), Some(json!(true)))),
+            Err(ScimError::InvalidPatch(_))
+        ));
+        assert!(matches!(
+            user.apply_patch(&operation(
+                "replace",
+                Some("name.givenName"),
+                Some(json!("A"))
+            )),
+            Err(ScimError::InvalidPatch(_))
+        ));
+        assert!(matches!(
+            user.apply_patch(&operation("replace", Some("active"), None)),
+            Err(ScimError::InvalidPatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_success_parse_filter() {
+        assert_eq!(
+            parse_filter(
+                "userName eq \"user@example.com\"",
+                ScimUser::FILTER_ATTRIBUTES
+            )
+            .unwrap(),
+            ("userName".to_string(), "user@example.com".to_string())
+        );
+        assert!(matches!(
+            parse_filter("userName sw \"user\"", ScimUser::FILTER_ATTRIBUTES),
+            Err(ScimError::InvalidFilter(_))
+        ));
+        assert!(matches!(
+            parse_filter("displayName eq \"admins\"", ScimUser::FILTER_ATTRIBUTES),
+            Err(ScimError::InvalidFilter(_))
+        ));
+    }
+
+    #[test]
+    fn test_success_entitlement_without_scim() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            let options = ScimOptions {
+                bearer_token: Some("idp-token".to_string()),
+                ..app_state.options::<ScimOptions>()
+            };
+            let app_name = format!("app-{}", Uuid::new_v4().simple());
+            let mut managed = group(&[]);
+            managed.display_name = options.group_name(&app_name);
+            let managed = create_resource(&app_state, managed).await.unwrap();
+
+            assert_eq!(
+                entitlement(&app_state, &options, &app_name, "user-1")
+                    .await
+                    .unwrap(),
+                EntitlementDecision::NotEntitled
+            );
+            // The group of the app is ignored while SCIM is disabled
+            let disabled = ScimOptions {
+                bearer_token: None,
+                ..options
+            };
+            assert_eq!(
+                entitlement(&app_state, &disabled, &app_name, "user-1")
+                    .await
+                    .unwrap(),
+                EntitlementDecision::Unmanaged
+            );
+
+            // clean up
+            let _ = delete_resource::<ScimGroup>(&app_state, &managed.id).await;
+        });
+    }
+
+    #[test]
+    fn test_success_scim_options_authorize() {
+        let mut options = ScimOptions::from_settings(None);
+        assert_eq!(options.group_name("app100"), "tresleai-app-app100");
+        assert!(matches!(
+            options.authorize(&HeaderMap::new()),
+            Err(ScimError::Disabled)
+        ));
+
+        options.bearer_token = Some("idp-token".to_string());
+        let mut headers = HeaderMap::new();
+        assert!(matches!(
+            options.authorize(&headers),
+            Err(ScimError::Unauthorized)
+        ));
+        headers.insert(AUTHORIZATION, "Bearer idp-tokem".parse().unwrap());
+        assert!(matches!(
+            options.authorize(&headers),
+            Err(ScimError::Unauthorized)
+        ));
+        headers.insert(AUTHORIZATION, "Bearer idp-token".parse().unwrap());
+        assert!(options.authorize(&headers).is_ok());
+    }
+}
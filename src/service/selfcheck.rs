@@ -0,0 +1,193 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the selfcheck of the settings of the service.
+//! The `tresleai_urls`, the `knowledge_engine` endpoints and the `aws` regions are typed settings, validated when the
+//! settings are loaded, so a malformed value fails the startup. The selfcheck reports their resolved values and probes
+//! the reachability of every Tresleai URL: a GET of its `probe_path` (its root by default), within the timeout of its
+//! policy (5 seconds by default). Any HTTP response counts as reachable, its status code is reported.
+//! The URLs are probed on startup, the unreachable ones logged as errors without failing the startup, and on every
+//! call of the selfcheck endpoint.
+//!
+
+use crate::service::node_count_check::DEFAULT_COUNTS_ENDPOINT;
+use crate::service::state::AppState;
+use futures::future::join_all;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+/// Default timeout of the probes of the URLs without timeout.
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reachability probe of a Tresleai URL, with the policy of its calls.
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct UrlProbe {
+    /// Name of the URL in `tresleai_urls`.
+    pub name: String,
+    pub url: String,
+    pub probe_url: String,
+    pub timeout_ms: Option<u64>,
+    pub retries: u32,
+    pub reachable: bool,
+    pub status_code: Option<u16>,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+/// Resolved URLs of the knowledge engine.
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct KnowledgeEngineCheck {
+    pub query_url: String,
+    pub counts_url: String,
+}
+
+/// Resolved AWS regions.
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct AwsRegionsCheck {
+    pub default_region: Option<String>,
+    pub iam_region: String,
+}
+
+/// Selfcheck of the settings.
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct SettingsCheck {
+    /// Whether every Tresleai URL is reachable.
+    pub reachable: bool,
+    pub knowledge_engine: KnowledgeEngineCheck,
+    pub aws: AwsRegionsCheck,
+    pub urls: Vec<UrlProbe>,
+}
+
+/// Probes a Tresleai URL.
+async fn probe_url(app_state: &AppState, name: &str, url: &str) -> UrlProbe {
+    let urls = &app_state.app_settings.tresleai_urls;
+    let probe_path = urls
+        .policies
+        .get(name)
+        .and_then(|policy| policy.probe_path.as_deref())
+        .unwrap_or_default();
+    let probe_url = match probe_path.trim_start_matches('/') {
+        "" => url.to_string(),
+        path => format!("{}/{}", url, path),
+    };
+    let policy = app_state
+        .http_clients
+        .policy_for(url)
+        .cloned()
+        .unwrap_or_default();
+    let request = app_state
+        .http_clients
+        .for_url(&probe_url)
+        .get(&probe_url)
+        .timeout(policy.timeout.unwrap_or(DEFAULT_PROBE_TIMEOUT));
+
+    let start = Instant::now();
+    let response = request.send().await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+    let (status_code, error) = match response {
+        Ok(response) => (Some(response.status().as_u16()), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    UrlProbe {
+        name: name.to_string(),
+        url: url.to_string(),
+        probe_url,
+        timeout_ms: policy.timeout.map(|timeout| timeout.as_millis() as u64),
+        retries: policy.retries,
+        reachable: status_code.is_some(),
+        status_code,
+        latency_ms,
+        error,
+    }
+}
+
+/// Probes the Tresleai URLs concurrently.
+pub async fn probe_service_urls(app_state: &AppState) -> Vec<UrlProbe> {
+    let urls = app_state.app_settings.tresleai_urls.named();
+    join_all(
+        urls.into_iter()
+            .map(|(name, url)| probe_url(app_state, name, url)),
+    )
+    .await
+}
+
+/// Returns the selfcheck of the settings, probing the Tresleai URLs.
+pub async fn settings_check(app_state: &AppState) -> SettingsCheck {
+    let settings = &app_state.app_settings;
+    let core_service_url = &settings.tresleai_urls.core_service_url;
+    let urls = probe_service_urls(app_state).await;
+    SettingsCheck {
+        reachable: urls.iter().all(|probe| probe.reachable),
+        knowledge_engine: KnowledgeEngineCheck {
+            query_url: core_service_url.join(&settings.knowledge_engine.endpoint),
+            counts_url: core_service_url.join(
+                settings
+                    .knowledge_engine
+                    .counts_endpoint
+                    .as_deref()
+                    .unwrap_or(DEFAULT_COUNTS_ENDPOINT),
+            ),
+        },
+        aws: AwsRegionsCheck {
+            default_region: settings
+                .aws
+                .as_ref()
+                .and_then(|aws| aws.default_region.clone())
+                .map(String::from),
+            iam_region: settings.aws_iam.region.to_string(),
+        },
+        urls,
+    }
+}
+
+/// Probes the Tresleai URLs on startup and logs the unreachable ones.
+pub async fn probe_service_urls_on_startup(app_state: Arc<AppState>) {
+    for probe in probe_service_urls(&app_state).await {
+        match &probe.error {
+            Some(e) => error!(
+                message = format!(
+                    "Tresleai URL '{}' ({}) is unreachable. Error: {}",
+                    probe.name, probe.probe_url, e
+                )
+            ),
+            None => info!(
+                message = format!(
+                    "Tresleai URL '{}' ({}) is reachable, status {} in {} ms.",
+                    probe.name,
+                    probe.probe_url,
+                    probe.status_code.unwrap_or_default(),
+                    probe.latency_ms
+                )
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_success_settings_check() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Create a dev AppState
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+
+            let check = settings_check(&app_state).await;
+            assert_eq!(check.urls.len(), 9);
+            assert!(check.knowledge_engine.query_url.ends_with("/query/full"));
+            assert_eq!(
+                check.reachable,
+                check.urls.iter().all(|probe| probe.error.is_none())
+            );
+        });
+    }
+}
@@ -0,0 +1,938 @@
+/*
+ * Created Date:  Aug 2, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the service accounts of the machine-to-machine admin operations, e.g. the CI pipelines
+//! automating the onboarding, so they don't reuse the credentials of a human admin.
+//! A service account has a `client_id` (prefixed with `sa_`), a secret and a list of scopes. Its secret is only
+//! returned when the account is created or its secret rotated, and stored hashed in `service_accounts.collection`
+//! (`service-accounts` by default).
+//! The admin requests carrying the credentials of a service account as `Authorization: Basic` (client ID and secret)
+//! are authenticated by `authorize_service_account` and must be granted the scope of their route (`required_scope`):
+//! `apps:read`, `apps:write`, `apps:delete`, `nodes:read`, `metrics:read`, `identity:reveal` or
+//! `service_accounts:manage`, `admin` granting every scope. Unknown or revoked credentials are rejected with a 401 status code, a missing scope with a
+//! 403 status code. The admin requests without such credentials are left to the authentication of the admin network.
+//! Service accounts are created by a service account granted `service_accounts:manage`, or with the bootstrap token
+//! (`service_accounts.bootstrap_token`), e.g. for the first `admin` account. An account only grants the scopes it holds,
+//! and only an `admin` account grants `admin` or `identity:reveal`.
+//!
+
+use crate::admin_ui_api::schema::UpdateResponse;
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{ServiceAccountSettings, TresleFacadeServiceSettings};
+use crate::service::api_docs::constant_time_eq;
+use crate::service::api_key::hash_api_key;
+use crate::service::query_options::{AggregateExt, QueryOptions};
+use crate::service::state::AppState;
+use crate::service::timestamp::to_bson_datetime;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header::AUTHORIZATION, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use mongodb::bson::{doc, to_document};
+use rand::distributions::{Alphanumeric, DistString};
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+use utoipa::ToSchema;
+
+/// Default collection of the service accounts.
+pub const DEFAULT_COLLECTION: &str = "service-accounts";
+/// Prefix of the client IDs, telling the credentials of a service account apart from other basic credentials.
+pub const CLIENT_ID_PREFIX: &str = "sa_";
+const CLIENT_ID_LENGTH: usize = 20;
+const CLIENT_SECRET_LENGTH: usize = 40;
+/// Maximum length of the name of a service account.
+const MAX_NAME_LENGTH: usize = 64;
+const ADMIN_PATH_PREFIX: &str = "/api/v1.1/admin/";
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ServiceAccountError {
+    #[error("Invalid name '{0}'. Names are 1 to 64 letters, digits, '.', '-' and '_'.")]
+    InvalidName(String),
+    #[error("Invalid scope '{0}'. Scopes are apps:read, apps:write, apps:delete, nodes:read, metrics:read, identity:reveal, service_accounts:manage and admin.")]
+    InvalidScope(String),
+    #[error("A service account needs at least one scope.")]
+    MissingScopes,
+    #[error("An active service account named '{0}' already exists.")]
+    DuplicateName(String),
+    #[error("No service account found with client ID '{0}'.")]
+    NotFound(String),
+    #[error("Service account '{0}' is revoked.")]
+    Revoked(String),
+    #[error("Invalid service account credentials.")]
+    Unauthorized,
+    #[error("Service account '{client_id}' is not granted the '{scope}' scope.")]
+    Forbidden { client_id: String, scope: String },
+    #[error("Service accounts are created by a service account granted service_accounts:manage, or with the bootstrap token.")]
+    MissingGranter,
+    #[error("The caller can't grant the '{0}' scope.")]
+    NotGrantable(String),
+    #[error("Failed to access the service accounts. Error: {0}")]
+    Store(String),
+}
+
+impl From<ServiceAccountError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: ServiceAccountError) -> Self {
+        let status_code = match e {
+            ServiceAccountError::InvalidName(_)
+            | ServiceAccountError::InvalidScope(_)
+            | ServiceAccountError::MissingScopes => StatusCode::BAD_REQUEST,
+            ServiceAccountError::DuplicateName(_) | ServiceAccountError::Revoked(_) => {
+                StatusCode::CONFLICT
+            }
+            ServiceAccountError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServiceAccountError::Unauthorized | ServiceAccountError::MissingGranter => {
+                StatusCode::UNAUTHORIZED
+            }
+            ServiceAccountError::Forbidden { .. } | ServiceAccountError::NotGrantable(_) => {
+                StatusCode::FORBIDDEN
+            }
+            ServiceAccountError::Store(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let error_message = e.to_string();
+        debug!(message = error_message);
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Service account options: account collection and bootstrap token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceAccountOptions {
+    pub collection: String,
+    /// Bearer token creating service accounts of any scope. Only service accounts create service accounts without it.
+    pub bootstrap_token: Option<String>,
+}
+
+impl SettingsOptions for ServiceAccountOptions {
+    type Settings = ServiceAccountSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&ServiceAccountSettings> {
+        settings.service_accounts.as_ref()
+    }
+
+    fn from_settings(settings: Option<&ServiceAccountSettings>) -> Self {
+        ServiceAccountOptions {
+            collection: settings
+                .and_then(|settings| settings.collection.clone())
+                .unwrap_or_else(|| DEFAULT_COLLECTION.to_string()),
+            bootstrap_token: settings
+                .and_then(|settings| settings.bootstrap_token.as_ref())
+                .map(|token| token.expose_secret().trim().to_string())
+                .filter(|token| !token.is_empty()),
+        }
+    }
+}
+
+impl ServiceAccountOptions {
+    /// Returns true if a request carries the bootstrap token as `Authorization: Bearer`.
+    pub fn is_bootstrap(&self, headers: &HeaderMap) -> bool {
+        let Some(bootstrap_token) = &self.bootstrap_token else {
+            return false;
+        };
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| {
+                constant_time_eq(token.trim().as_bytes(), bootstrap_token.as_bytes())
+            })
+    }
+}
+
+/// Scope of the admin operations granted to a service account.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum Scope {
+    #[serde(rename = "apps:read")]
+    AppsRead,
+    #[serde(rename = "apps:write")]
+    AppsWrite,
+    #[serde(rename = "apps:delete")]
+    AppsDelete,
+    #[serde(rename = "nodes:read")]
+    NodesRead,
+    #[serde(rename = "metrics:read")]
+    MetricsRead,
+    /// Reveals the user IDs behind the pseudonyms of the apps.
+    #[serde(rename = "identity:reveal")]
+    IdentityReveal,
+    #[serde(rename = "service_accounts:manage")]
+    ServiceAccountsManage,
+    /// Grants every scope.
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::AppsRead => "apps:read",
+            Scope::AppsWrite => "apps:write",
+            Scope::AppsDelete => "apps:delete",
+            Scope::NodesRead => "nodes:read",
+            Scope::MetricsRead => "metrics:read",
+            Scope::IdentityReveal => "identity:reveal",
+            Scope::ServiceAccountsManage => "service_accounts:manage",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Result<Self, ServiceAccountError> {
+        [
+            Scope::AppsRead,
+            Scope::AppsWrite,
+            Scope::AppsDelete,
+            Scope::NodesRead,
+            Scope::MetricsRead,
+            Scope::IdentityReveal,
+            Scope::ServiceAccountsManage,
+            Scope::Admin,
+        ]
+        .into_iter()
+        .find(|known| known.as_str() == scope.trim())
+        .ok_or_else(|| ServiceAccountError::InvalidScope(scope.to_string()))
+    }
+}
+
+/// Returns the scope needed to call an admin route, `None` for the routes outside the admin API and the SCIM
+/// endpoints, authenticated by their own bearer token.
+/// Every `DELETE` and the backfills, which rewrite the documents of the apps in bulk, need `apps:delete`.
+pub fn required_scope(method: &Method, route: &str) -> Option<Scope> {
+    let path = route.strip_prefix(ADMIN_PATH_PREFIX)?;
+    let reading = method == Method::GET || method == Method::HEAD;
+    if path.starts_with("scim/") {
+        None
+    } else if path.starts_with("service-accounts") {
+        Some(Scope::ServiceAccountsManage)
+    } else if method == Method::DELETE || (path.starts_with("backfills/") && !reading) {
+        Some(Scope::AppsDelete)
+    } else if path.starts_with("nodes/") && reading {
+        Some(Scope::NodesRead)
+    } else if (path.starts_with("metric/") || path.starts_with("usage/") || path == "overview")
+        && reading
+    {
+        Some(Scope::MetricsRead)
+    } else if path == "apps/:app_name/user-pseudonyms/:pseudonym" {
+        Some(Scope::IdentityReveal)
+    } else if reading {
+        Some(Scope::AppsRead)
+    } else {
+        Some(Scope::AppsWrite)
+    }
+}
+
+/// Service account stored in the service account collection.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServiceAccount {
+    pub client_id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Hash of the secret, see `crate::service::api_key::hash_api_key`.
+    pub secret_hash: String,
+    #[serde(with = "crate::service::timestamp::bson_datetime")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default, with = "crate::service::timestamp::option_bson_datetime")]
+    pub secret_rotated_at: Option<DateTime<Utc>>,
+    #[serde(default, with = "crate::service::timestamp::option_bson_datetime")]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ServiceAccount {
+    /// Returns true if the account is granted a scope.
+    pub fn is_granted(&self, scope: Scope) -> bool {
+        self.scopes
+            .iter()
+            .any(|granted| *granted == scope || *granted == Scope::Admin)
+    }
+}
+
+/// Returns the scopes the caller of the creation of a service account holds: those of its service account, or every
+/// scope with the bootstrap token.
+pub fn granter_scopes(
+    options: &ServiceAccountOptions,
+    account: Option<&ServiceAccount>,
+    headers: &HeaderMap,
+) -> Result<Vec<Scope>, ServiceAccountError> {
+    match account {
+        Some(account) if account.is_granted(Scope::ServiceAccountsManage) => {
+            Ok(account.scopes.clone())
+        }
+        Some(account) => Err(ServiceAccountError::Forbidden {
+            client_id: account.client_id.clone(),
+            scope: Scope::ServiceAccountsManage.as_str().to_string(),
+        }),
+        None if options.is_bootstrap(headers) => Ok(vec![Scope::Admin]),
+        None => Err(ServiceAccountError::MissingGranter),
+    }
+}
+
+/// Checks a caller holding the `granted` scopes may grant the `requested` ones. A caller only grants the scopes it
+/// holds, and only an `admin` caller grants `admin` or `identity:reveal`.
+pub fn check_grantable(granted: &[Scope], requested: &[Scope]) -> Result<(), ServiceAccountError> {
+    let admin = granted.contains(&Scope::Admin);
+    match requested.iter().find(|scope| {
+        !admin
+            && (matches!(scope, Scope::Admin | Scope::IdentityReveal) || !granted.contains(scope))
+    }) {
+        Some(scope) => Err(ServiceAccountError::NotGrantable(
+            scope.as_str().to_string(),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Service account returned by the admin endpoints, without its secret.
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ServiceAccountSummary {
+    pub client_id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub secret_rotated_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl From<&ServiceAccount> for ServiceAccountSummary {
+    fn from(account: &ServiceAccount) -> Self {
+        ServiceAccountSummary {
+            client_id: account.client_id.clone(),
+            name: account.name.clone(),
+            scopes: account.scopes.clone(),
+            created_at: account.created_at,
+            secret_rotated_at: account.secret_rotated_at,
+            revoked_at: account.revoked_at,
+        }
+    }
+}
+
+/// Body of the creation of a service account.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct CreateServiceAccountRequest {
+    pub name: String,
+    /// Scopes granted to the account, e.g. `["apps:read", "apps:write"]`.
+    pub scopes: Vec<String>,
+}
+
+impl CreateServiceAccountRequest {
+    /// Validates the name and parses the scopes of the request.
+    pub fn validate(&self) -> Result<Vec<Scope>, ServiceAccountError> {
+        let valid_name = !self.name.is_empty()
+            && self.name.len() <= MAX_NAME_LENGTH
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+        if !valid_name {
+            return Err(ServiceAccountError::InvalidName(self.name.clone()));
+        }
+        let mut scopes = Vec::new();
+        for scope in &self.scopes {
+            let scope = Scope::parse(scope)?;
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+        if scopes.is_empty() {
+            return Err(ServiceAccountError::MissingScopes);
+        }
+        Ok(scopes)
+    }
+}
+
+/// Client ID and secret of a service account sent as basic credentials.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceAccountCredentials {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl ServiceAccountCredentials {
+    /// Reads the credentials of a service account from the `Authorization` header, `None` for the requests without
+    /// basic credentials of a service account.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let credentials = headers
+            .get(AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Basic ")?;
+        let credentials = String::from_utf8(STANDARD.decode(credentials.trim()).ok()?).ok()?;
+        let (client_id, client_secret) = credentials.split_once(':')?;
+        client_id
+            .starts_with(CLIENT_ID_PREFIX)
+            .then(|| ServiceAccountCredentials {
+                client_id: client_id.to_string(),
+                client_secret: client_secret.to_string(),
+            })
+    }
+}
+
+/// Generates a client secret and its hash.
+fn generate_secret() -> (String, String) {
+    let secret = Alphanumeric.sample_string(&mut rand::thread_rng(), CLIENT_SECRET_LENGTH);
+    let secret_hash = hash_api_key(&secret);
+    (secret, secret_hash)
+}
+
+/// Returns a service account by client ID.
+pub async fn get_service_account(
+    app_state: &AppState,
+    client_id: &str,
+) -> Result<ServiceAccount, ServiceAccountError> {
+    app_state
+        .db
+        .get_document(
+            &app_state.options::<ServiceAccountOptions>().collection,
+            doc! {"client_id": client_id},
+        )
+        .await
+        .map_err(|e| ServiceAccountError::Store(e.to_string()))?
+        .ok_or_else(|| ServiceAccountError::NotFound(client_id.to_string()))
+        .and_then(|account| {
+            serde_json::from_value(account).map_err(|e| ServiceAccountError::Store(e.to_string()))
+        })
+}
+
+/// Returns the service accounts sorted by name, revoked ones included.
+pub async fn list_service_accounts(
+    app_state: &AppState,
+) -> Result<Vec<ServiceAccountSummary>, ServiceAccountError> {
+    let pipeline = vec![
+        doc! {"$sort": {"name": 1, "created_at": 1}},
+        doc! {"$project": {"_id": 0}},
+    ];
+    let accounts = app_state
+        .db
+        .aggregate(
+            &app_state.options::<ServiceAccountOptions>().collection,
+            pipeline,
+            &app_state.options::<QueryOptions>(),
+        )
+        .await
+        .map_err(|e| ServiceAccountError::Store(e.to_string()))?;
+    Ok(accounts
+        .into_iter()
+        .filter_map(|account| serde_json::from_value::<ServiceAccount>(account).ok())
+        .map(|account| ServiceAccountSummary::from(&account))
+        .collect())
+}
+
+/// Creates a service account for a caller holding the `granted` scopes (see `granter_scopes`). Returns the account
+/// and its secret, only returned here.
+pub async fn create_service_account(
+    app_state: &AppState,
+    request: &CreateServiceAccountRequest,
+    granted: &[Scope],
+) -> Result<(ServiceAccount, String), ServiceAccountError> {
+    let scopes = request.validate()?;
+    check_grantable(granted, &scopes)?;
+    let collection = app_state.options::<ServiceAccountOptions>().collection;
+    let existing = app_state
+        .db
+        .get_document(
+            &collection,
+            doc! {"name": &request.name, "revoked_at": null},
+        )
+        .await
+        .map_err(|e| ServiceAccountError::Store(e.to_string()))?;
+    if existing.is_some() {
+        return Err(ServiceAccountError::DuplicateName(request.name.clone()));
+    }
+    let (secret, secret_hash) = generate_secret();
+    let account = ServiceAccount {
+        client_id: format!(
+            "{}{}",
+            CLIENT_ID_PREFIX,
+            Alphanumeric
+                .sample_string(&mut rand::thread_rng(), CLIENT_ID_LENGTH)
+                .to_lowercase()
+        ),
+        name: request.name.clone(),
+        scopes,
+        secret_hash,
+        created_at: Utc::now(),
+        secret_rotated_at: None,
+        revoked_at: None,
+    };
+    let document = to_document(&account).map_err(|e| ServiceAccountError::Store(e.to_string()))?;
+    app_state
+        .db
+        .create_document(&collection, document)
+        .await
+        .map_err(|e| ServiceAccountError::Store(e.to_string()))?;
+    Ok((account, secret))
+}
+
+/// Sets fields of an active service account.
+async fn update_active_account(
+    app_state: &AppState,
+    client_id: &str,
+    fields: mongodb::bson::Document,
+) -> Result<ServiceAccount, ServiceAccountError> {
+    let account = get_service_account(app_state, client_id).await?;
+    if account.revoked_at.is_some() {
+        return Err(ServiceAccountError::Revoked(client_id.to_string()));
+    }
+    let result = app_state
+        .db
+        .update_document(
+            &app_state.options::<ServiceAccountOptions>().collection,
+            doc! {"client_id": client_id, "revoked_at": null},
+            fields,
+        )
+        .await
+        .map_err(|e| ServiceAccountError::Store(e.to_string()))?;
+    match serde_json::from_value::<UpdateResponse>(result) {
+        Ok(result) if result.matchedCount == 0 => {
+            Err(ServiceAccountError::Revoked(client_id.to_string()))
+        }
+        Ok(_) => get_service_account(app_state, client_id).await,
+        Err(e) => Err(ServiceAccountError::Store(e.to_string())),
+    }
+}
+
+/// Revokes a service account, rejecting its credentials from now on.
+pub async fn revoke_service_account(
+    app_state: &AppState,
+    client_id: &str,
+) -> Result<ServiceAccount, ServiceAccountError> {
+    update_active_account(
+        app_state,
+        client_id,
+        doc! {"revoked_at": to_bson_datetime(Utc::now())},
+    )
+    .await
+}
+
+/// Rotates the secret of a service account. Returns the account and its new secret, only returned here.
+pub async fn rotate_service_account_secret(
+    app_state: &AppState,
+    client_id: &str,
+) -> Result<(ServiceAccount, String), ServiceAccountError> {
+    let (secret, secret_hash) = generate_secret();
+    let account = update_active_account(
+        app_state,
+        client_id,
+        doc! {"secret_hash": secret_hash, "secret_rotated_at": to_bson_datetime(Utc::now())},
+    )
+    .await?;
+    Ok((account, secret))
+}
+
+/// Authenticates the credentials of a service account and checks it is granted a scope.
+pub async fn authenticate(
+    app_state: &AppState,
+    credentials: &ServiceAccountCredentials,
+    scope: Scope,
+) -> Result<ServiceAccount, ServiceAccountError> {
+    let account = match get_service_account(app_state, &credentials.client_id).await {
+        Ok(account) => account,
+        Err(ServiceAccountError::NotFound(_)) => return Err(ServiceAccountError::Unauthorized),
+        Err(e) => return Err(e),
+    };
+    let secret_hash = hash_api_key(&credentials.client_secret);
+    if account.revoked_at.is_some()
+        || !constant_time_eq(secret_hash.as_bytes(), account.secret_hash.as_bytes())
+    {
+        return Err(ServiceAccountError::Unauthorized);
+    }
+    if !account.is_granted(scope) {
+        return Err(ServiceAccountError::Forbidden {
+            client_id: account.client_id,
+            scope: scope.as_str().to_string(),
+        });
+    }
+    Ok(account)
+}
+
+/// Middleware authenticating the admin requests carrying the credentials of a service account, and checking the
+/// account is granted the scope of the route. The authenticated account is added to the request extensions.
+pub async fn authorize_service_account(
+    State(app_state): State<Arc<AppState>>,
+    matched_path: Option<MatchedPath>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(credentials) = ServiceAccountCredentials::from_headers(request.headers()) else {
+        return next.run(request).await;
+    };
+    let Some(scope) = matched_path
+        .as_ref()
+        .and_then(|matched_path| required_scope(request.method(), matched_path.as_str()))
+    else {
+        return next.run(request).await;
+    };
+    match authenticate(&app_state, &credentials, scope).await {
+        Ok(account) => {
+            info!(
+                message = format!(
+                    "Admin request {} {} of service account '{}' ({}).",
+                    request.method(),
+                    request.uri().path(),
+                    account.name,
+                    account.client_id
+                )
+            );
+            request.extensions_mut().insert(account);
+            next.run(request).await
+        }
+        Err(e) => {
+            warn!(
+                message = format!(
+                    "Rejected admin request {} {} of service account '{}'. Error: {}",
+                    request.method(),
+                    request.uri().path(),
+                    credentials.client_id,
+                    e
+                )
+            );
+            <(StatusCode, Json<serde_json::Value>)>::from(e).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(scopes: Vec<Scope>) -> ServiceAccount {
+        ServiceAccount {
+            client_id: "sa_ci".to_string(),
+            name: "ci".to_string(),
+            scopes,
+            secret_hash: hash_api_key("secret"),
+            created_at: Utc::now(),
+            secret_rotated_at: None,
+            revoked_at: None,
+        }
+    }
+
+    #[test]
+    fn test_success_required_scope() {
+        use Scope::*;
+        // Every admin route of `crate::service::route::create_router`, to be extended with the new routes
+        let routes = [
+            ("GET", "token", AppsRead),
+            ("GET", "jobs/runs", AppsRead),
+            ("GET", "jobs/queued", AppsRead),
+            ("GET", "backfills", AppsRead),
+            ("POST", "backfills/:backfill", AppsDelete),
+            ("GET", "backfills/jobs/:job_id", AppsRead),
+            ("GET", "config", AppsRead),
+            ("GET", "errors/catalog", AppsRead),
+            ("GET", "selfcheck", AppsRead),
+            ("GET", "service-accounts", ServiceAccountsManage),
+            ("POST", "service-accounts", ServiceAccountsManage),
+            (
+                "DELETE",
+                "service-accounts/:client_id",
+                ServiceAccountsManage,
+            ),
+            (
+                "POST",
+                "service-accounts/:client_id/secret",
+                ServiceAccountsManage,
+            ),
+            ("GET", "dependencies", AppsRead),
+            ("GET", "notifications", AppsRead),
+            ("POST", "notifications/read", AppsWrite),
+            ("POST", "notifications/:id/read", AppsWrite),
+            ("GET", "apps", AppsRead),
+            ("GET", "apps/:app_name", AppsRead),
+            ("DELETE", "apps/:app_name", AppsDelete),
+            ("GET", "apps/:app_name/artifacts", AppsRead),
+            ("DELETE", "apps/:app_name/artifacts", AppsDelete),
+            ("GET", "apps/:app_name/generated-config", AppsRead),
+            ("PATCH", "apps/:app_name/generated-config", AppsWrite),
+            ("POST", "apps/:app_name/encryption-key/rotate", AppsWrite),
+            ("POST", "apps/:app_name/residency", AppsWrite),
+            ("GET", "apps/:app_name/access-list", AppsRead),
+            ("PUT", "apps/:app_name/access-list", AppsWrite),
+            ("GET", "apps/:app_name/api-key-usage", AppsRead),
+            ("GET", "apps/:app_name/hints", AppsRead),
+            ("POST", "apps/:app_name/hints", AppsWrite),
+            ("PUT", "apps/:app_name/hints", AppsWrite),
+            ("DELETE", "apps/:app_name/hints", AppsDelete),
+            ("GET", "apps/:app_name/history-retention", AppsRead),
+            ("PUT", "apps/:app_name/history-retention", AppsWrite),
+            ("POST", "apps/:app_name/history-retention/holds", AppsWrite),
+            (
+                "DELETE",
+                "apps/:app_name/history-retention/holds",
+                AppsDelete,
+            ),
+            ("GET", "apps/:app_name/prompt-templates", AppsRead),
+            ("PUT", "apps/:app_name/prompt-templates", AppsWrite),
+            (
+                "DELETE",
+                "apps/:app_name/prompt-templates/:template_name",
+                AppsDelete,
+            ),
+            ("GET", "apps/:app_name/answer-sinks", AppsRead),
+            ("PUT", "apps/:app_name/answer-sinks", AppsWrite),
+            (
+                "DELETE",
+                "apps/:app_name/answer-sinks/:sink_name",
+                AppsDelete,
+            ),
+            ("GET", "apps/:app_name/experiments", AppsRead),
+            ("PUT", "apps/:app_name/experiments", AppsWrite),
+            (
+                "DELETE",
+                "apps/:app_name/experiments/:experiment_name",
+                AppsDelete,
+            ),
+            (
+                "GET",
+                "apps/:app_name/experiments/:experiment_name/results",
+                AppsRead,
+            ),
+            ("GET", "apps/:app_name/shadow-traffic", AppsRead),
+            ("PUT", "apps/:app_name/shadow-traffic", AppsWrite),
+            ("DELETE", "apps/:app_name/shadow-traffic", AppsDelete),
+            ("GET", "apps/:app_name/shadow-traffic/report", AppsRead),
+            ("GET", "apps/:app_name/keys", AppsRead),
+            ("POST", "apps/:app_name/keys", AppsWrite),
+            ("DELETE", "apps/:app_name/keys/:key_id", AppsDelete),
+            ("PUT", "apps/:app_name/keys/:key_id/expiry", AppsWrite),
+            ("POST", "apps/:app_name/ingestion/pause", AppsWrite),
+            ("POST", "apps/:app_name/ingestion/resume", AppsWrite),
+            ("GET", "apps/:app_name/ingestion/retry-policy", AppsRead),
+            ("PUT", "apps/:app_name/ingestion/retry-policy", AppsWrite),
+            ("GET", "apps/:app_name/ingestion/sla", AppsRead),
+            ("POST", "apps/:app_name/retry-onboarding", AppsWrite),
+            ("POST", "apps/:app_name/try-query", AppsWrite),
+            (
+                "GET",
+                "apps/:app_name/user-pseudonyms/:pseudonym",
+                IdentityReveal,
+            ),
+            ("POST", "apps/:app_name/verify-counts", AppsWrite),
+            ("POST", "graph/:app_name/query", AppsWrite),
+            ("PATCH", "search/apps/:app_name", AppsWrite),
+            ("POST", "apps/onboard", AppsWrite),
+            ("POST", "apps/apply", AppsWrite),
+            ("GET", "validation/:job_id", AppsRead),
+            ("POST", "capture_tc", AppsWrite),
+            ("GET", "overview", MetricsRead),
+            ("GET", "overview/ws", AppsRead),
+            ("GET", "nodes/:app_name", NodesRead),
+            ("GET", "nodes/:app_name/detail", NodesRead),
+            ("GET", "nodes/errors/:app_name", NodesRead),
+            ("GET", "nodes/count/:app_name", NodesRead),
+            ("GET", "nodes/chart/:app_name", NodesRead),
+            ("GET", "nodes/stats/:app_name", NodesRead),
+            ("GET", "logs", AppsRead),
+            ("GET", "metric/calls", MetricsRead),
+            ("GET", "metric/logs", MetricsRead),
+            ("GET", "usage/tokens/:app_name", MetricsRead),
+            ("GET", "onboarding/complexity", AppsRead),
+            ("POST", "trace/:reference_id/replay", AppsWrite),
+            ("GET", "trace/:reference_id/replays", AppsRead),
+        ];
+        for (method, path, scope) in routes {
+            let method = Method::from_bytes(method.as_bytes()).unwrap();
+            let route = format!("{}{}", ADMIN_PATH_PREFIX, path);
+            assert_eq!(
+                required_scope(&method, &route),
+                Some(scope),
+                "{} {}",
+                method,
+                route
+            );
+        }
+        for (method, path) in [
+            (Method::GET, "scim/Users"),
+            (Method::DELETE, "scim/Users/:id"),
+            (Method::DELETE, "scim/Groups/:id"),
+        ] {
+            let route = format!("{}{}", ADMIN_PATH_PREFIX, path);
+            assert_eq!(required_scope(&method, &route), None);
+        }
+        assert_eq!(required_scope(&Method::POST, "/api/v1.0/retrieval"), None);
+    }
+
+    #[test]
+    fn test_success_scopes() {
+        assert_eq!(Scope::parse("nodes:read"), Ok(Scope::NodesRead));
+        assert_eq!(
+            Scope::parse("nodes:write"),
+            Err(ServiceAccountError::InvalidScope("nodes:write".to_string()))
+        );
+        assert_eq!(
+            serde_json::to_value(Scope::ServiceAccountsManage).unwrap(),
+            "service_accounts:manage"
+        );
+
+        let ci = account(vec![Scope::AppsRead, Scope::AppsWrite]);
+        assert!(ci.is_granted(Scope::AppsWrite));
+        assert!(!ci.is_granted(Scope::AppsDelete));
+        assert!(account(vec![Scope::Admin]).is_granted(Scope::AppsDelete));
+    }
+
+    #[test]
+    fn test_success_create_request_validate() {
+        let request = |name: &str, scopes: &[&str]| CreateServiceAccountRequest {
+            name: name.to_string(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        };
+        assert_eq!(
+            request("ci-onboarding", &["apps:read", "apps:write", "apps:read"]).validate(),
+            Ok(vec![Scope::AppsRead, Scope::AppsWrite])
+        );
+        assert_eq!(
+            request("ci onboarding", &["apps:read"]).validate(),
+            Err(ServiceAccountError::InvalidName(
+                "ci onboarding".to_string()
+            ))
+        );
+        assert_eq!(
+            request("ci", &[]).validate(),
+            Err(ServiceAccountError::MissingScopes)
+        );
+        assert_eq!(
+            request("ci", &["apps:*"]).validate(),
+            Err(ServiceAccountError::InvalidScope("apps:*".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_success_account_timestamps() {
+        let mut ci = account(vec![Scope::AppsRead]);
+        ci.revoked_at = Some(ci.created_at);
+        let document = to_document(&ci).unwrap();
+        for field in ["created_at", "revoked_at"] {
+            assert!(matches!(
+                document.get(field),
+                Some(mongodb::bson::Bson::DateTime(_))
+            ));
+        }
+        assert_eq!(
+            document.get("secret_rotated_at"),
+            Some(&mongodb::bson::Bson::Null)
+        );
+
+        // Accounts read back as extended JSON, or stored with RFC 3339 strings before the BSON dates
+        let read: ServiceAccount = serde_json::from_value(json!({
+            "client_id": "sa_ci",
+            "name": "ci",
+            "scopes": ["apps:read"],
+            "secret_hash": ci.secret_hash,
+            "created_at": {"$date": "2024-08-02T10:00:00Z"},
+            "secret_rotated_at": "2024-08-02T11:00:00+00:00",
+        }))
+        .unwrap();
+        assert_eq!(read.created_at.to_rfc3339(), "2024-08-02T10:00:00+00:00");
+        assert_eq!(
+            read.secret_rotated_at
+                .map(|timestamp| timestamp.to_rfc3339()),
+            Some("2024-08-02T11:00:00+00:00".to_string())
+        );
+        assert!(read.revoked_at.is_none());
+    }
+
+    #[test]
+    fn test_success_check_grantable() {
+        use Scope::*;
+        assert_eq!(check_grantable(&[Admin], &[Admin, IdentityReveal]), Ok(()));
+        assert_eq!(
+            check_grantable(
+                &[ServiceAccountsManage, AppsRead, AppsWrite],
+                &[AppsRead, AppsWrite]
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_failure_check_grantable() {
+        use Scope::*;
+        let manager = [ServiceAccountsManage, AppsRead];
+        assert_eq!(
+            check_grantable(&manager, &[AppsRead, AppsDelete]),
+            Err(ServiceAccountError::NotGrantable("apps:delete".to_string()))
+        );
+        assert_eq!(
+            check_grantable(&manager, &[Admin]),
+            Err(ServiceAccountError::NotGrantable("admin".to_string()))
+        );
+        // Holding identity:reveal doesn't allow granting it
+        assert_eq!(
+            check_grantable(&[ServiceAccountsManage, IdentityReveal], &[IdentityReveal]),
+            Err(ServiceAccountError::NotGrantable(
+                "identity:reveal".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_success_granter_scopes() {
+        let options = ServiceAccountOptions {
+            collection: DEFAULT_COLLECTION.to_string(),
+            bootstrap_token: Some("b00tstrap".to_string()),
+        };
+        let bearer = |token: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+            headers
+        };
+        let manager = account(vec![Scope::ServiceAccountsManage, Scope::AppsRead]);
+        assert_eq!(
+            granter_scopes(&options, Some(&manager), &HeaderMap::new()),
+            Ok(manager.scopes.clone())
+        );
+        assert_eq!(
+            granter_scopes(&options, None, &bearer("b00tstrap")),
+            Ok(vec![Scope::Admin])
+        );
+        assert_eq!(
+            granter_scopes(&options, None, &bearer("wrong")),
+            Err(ServiceAccountError::MissingGranter)
+        );
+        assert_eq!(
+            granter_scopes(&options, None, &HeaderMap::new()),
+            Err(ServiceAccountError::MissingGranter)
+        );
+        let options = ServiceAccountOptions {
+            bootstrap_token: None,
+            ..options
+        };
+        assert_eq!(
+            granter_scopes(&options, None, &bearer("b00tstrap")),
+            Err(ServiceAccountError::MissingGranter)
+        );
+    }
+
+    #[test]
+    fn test_success_credentials_from_headers() {
+        let headers = |authorization: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+            headers
+        };
+        let basic = format!("Basic {}", STANDARD.encode("sa_ci:s3cret:with-colon"));
+        assert_eq!(
+            ServiceAccountCredentials::from_headers(&headers(&basic)),
+            Some(ServiceAccountCredentials {
+                client_id: "sa_ci".to_string(),
+                client_secret: "s3cret:with-colon".to_string(),
+            })
+        );
+        // Basic credentials of another realm, e.g. the API docs, are not service account credentials
+        let basic = format!("Basic {}", STANDARD.encode("docs:s3cret"));
+        assert!(ServiceAccountCredentials::from_headers(&headers(&basic)).is_none());
+        assert!(ServiceAccountCredentials::from_headers(&headers("Bearer token")).is_none());
+        assert!(ServiceAccountCredentials::from_headers(&HeaderMap::new()).is_none());
+    }
+}
@@ -0,0 +1,562 @@
+/*
+ * Created Date:  Aug 4, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the shadow traffic of an app, stored on the app document and managed through the shadow
+//! traffic endpoints, to validate an upgrade of the knowledge engine against the real traffic of the app.
+//! With shadow traffic enabled, `percent` percent of the retrievals of the app are sent again, once answered, to the
+//! alternate knowledge engine at `url`, with the payload sent to the primary engine (query, user details, row filters,
+//! routing hint and experiments). A retrieval is sampled from the SHA-256 of its reference ID.
+//! The answer of the alternate engine is never returned to the user: it is stored, encrypted like the history
+//! documents, in the `-shadow` collection of the app with its comparison to the answer of the primary engine (same
+//! answer, overlap of the cited sources, tokens and latency), and summarized by URL by the shadow traffic report.
+//! The fan-out retrievals, replays and sandbox retrievals are not shadowed. A shadow call times out after
+//! `shadow_traffic.timeout_ms` (30000); a failure is stored in the results and counted by the
+//! `Shadow Retrieval Counter` metric, it never affects the retrieval.
+//!
+
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{ShadowTrafficSettings, TresleFacadeServiceSettings};
+use crate::retrieval::schema::history_document::EngineResponse;
+use crate::service::metrics::{MetricRecord, APP_NAME_DIMENSION, STATUS_DIMENSION};
+use crate::service::state::AppState;
+use axum::{http::StatusCode, Json};
+use chrono::{DateTime, Utc};
+use mongodb::bson::{doc, to_document, Document};
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, instrument};
+use utoipa::ToSchema;
+
+/// Field of the shadow traffic of an app in the app document.
+pub const SHADOW_TRAFFIC_FIELD: &str = "shadow_traffic";
+/// Suffix of the collection of the shadow results of an app.
+pub const SHADOW_COLLECTION_SUFFIX: &str = "-shadow";
+const DEFAULT_TIMEOUT_MS: u64 = 30_000;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ShadowTrafficError {
+    #[error("Invalid shadow traffic: {0}")]
+    Invalid(String),
+    #[error("No shadow traffic configured for app '{0}'.")]
+    NotConfigured(String),
+}
+
+impl From<ShadowTrafficError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: ShadowTrafficError) -> Self {
+        let status_code = match e {
+            ShadowTrafficError::NotConfigured(_) => StatusCode::NOT_FOUND,
+            ShadowTrafficError::Invalid(_) => StatusCode::BAD_REQUEST,
+        };
+        let error_message = e.to_string();
+        debug!(message = error_message);
+        (
+            status_code,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Shadow traffic options: timeout for the alternate engine calls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShadowTrafficOptions {
+    pub timeout: Duration,
+}
+
+impl SettingsOptions for ShadowTrafficOptions {
+    type Settings = ShadowTrafficSettings;
+
+    fn section(settings: &TresleFacadeServiceSettings) -> Option<&ShadowTrafficSettings> {
+        settings.shadow_traffic.as_ref()
+    }
+
+    fn from_settings(settings: Option<&ShadowTrafficSettings>) -> Self {
+        ShadowTrafficOptions {
+            timeout: Duration::from_millis(
+                settings
+                    .and_then(|settings| settings.timeout_ms)
+                    .filter(|timeout_ms| *timeout_ms > 0)
+                    .unwrap_or(DEFAULT_TIMEOUT_MS),
+            ),
+        }
+    }
+}
+
+/// Shadow traffic of an app, duplicating `percent` percent of its retrievals to an alternate knowledge engine.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ShadowTraffic {
+    /// Retrieval endpoint of the alternate knowledge engine, e.g. `http://engine-v2:8000/retrieve`.
+    pub url: String,
+    /// Percentage of the retrievals shadowed, from 1 to 100.
+    pub percent: u32,
+    /// Disabled shadow traffic keeps its results but shadows no retrieval.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl ShadowTraffic {
+    /// Validates the URL and the percentage of the shadow traffic.
+    pub fn validate(&self) -> Result<(), ShadowTrafficError> {
+        match url::Url::parse(&self.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") && url.host().is_some() => {}
+            _ => {
+                return Err(ShadowTrafficError::Invalid(format!(
+                    "'{}' is not an http(s) URL.",
+                    self.url
+                )))
+            }
+        }
+        if !(1..=100).contains(&self.percent) {
+            return Err(ShadowTrafficError::Invalid(format!(
+                "the percentage is {} instead of 1 to 100.",
+                self.percent
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether a retrieval is shadowed, stable for the same reference ID.
+    pub fn samples(&self, reference_id: &str) -> bool {
+        self.enabled && bucket(reference_id) < self.percent
+    }
+}
+
+/// Bucket of a retrieval, between 0 and 99.
+fn bucket(reference_id: &str) -> u32 {
+    let digest = Sha256::digest(reference_id.as_bytes());
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(prefix) % 100) as u32
+}
+
+/// Answer of a knowledge engine to a shadowed retrieval.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShadowOutcome {
+    pub succeeded: bool,
+    pub latency_ms: u64,
+    /// Answer of the engine, stored for the alternate engine only, encrypted with the data key of the app.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer: Option<String>,
+    /// Sources cited by the answer.
+    #[serde(default)]
+    pub sources: Vec<String>,
+    #[serde(default)]
+    pub total_tokens: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ShadowOutcome {
+    /// Outcome of an engine call, from its response or its error.
+    pub fn new(response: Result<&str, String>, latency: Duration) -> Self {
+        let latency_ms = latency.as_millis() as u64;
+        match response {
+            Ok(response) => {
+                let engine_response = EngineResponse::parse(response);
+                ShadowOutcome {
+                    succeeded: true,
+                    latency_ms,
+                    answer: engine_response.answer,
+                    sources: engine_response
+                        .citations
+                        .into_iter()
+                        .map(|citation| citation.source)
+                        .collect(),
+                    total_tokens: engine_response
+                        .token_usage
+                        .map(|token_usage| token_usage.total_tokens),
+                    error: None,
+                }
+            }
+            Err(error) => ShadowOutcome {
+                succeeded: false,
+                latency_ms,
+                answer: None,
+                sources: Vec::new(),
+                total_tokens: None,
+                error: Some(error),
+            },
+        }
+    }
+}
+
+/// Comparison of the answers of the primary and alternate engines to a retrieval.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShadowComparison {
+    /// Whether the answers are the same, ignoring case and whitespace.
+    pub same_answer: bool,
+    /// Jaccard index of the cited sources, 1 if neither answer cites a source.
+    pub source_overlap: f64,
+    /// Tokens of the alternate engine minus those of the primary one, unset unless both are reported.
+    pub token_delta: Option<i64>,
+    /// Latency of the alternate engine minus that of the primary one.
+    pub latency_delta_ms: i64,
+}
+
+/// Compares the answers of the primary and alternate engines. `None` unless both succeeded.
+pub fn compare(primary: &ShadowOutcome, shadow: &ShadowOutcome) -> Option<ShadowComparison> {
+    if !primary.succeeded || !shadow.succeeded {
+        return None;
+    }
+    let normalize = |answer: &Option<String>| {
+        answer
+            .as_deref()
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase()
+    };
+    let primary_sources: HashSet<&str> = primary.sources.iter().map(String::as_str).collect();
+    let shadow_sources: HashSet<&str> = shadow.sources.iter().map(String::as_str).collect();
+    let union = primary_sources.union(&shadow_sources).count();
+    let source_overlap = match union {
+        0 => 1.0,
+        union => primary_sources.intersection(&shadow_sources).count() as f64 / union as f64,
+    };
+    Some(ShadowComparison {
+        same_answer: normalize(&primary.answer) == normalize(&shadow.answer),
+        source_overlap,
+        token_delta: primary
+            .total_tokens
+            .zip(shadow.total_tokens)
+            .map(|(primary_tokens, shadow_tokens)| shadow_tokens - primary_tokens),
+        latency_delta_ms: shadow.latency_ms as i64 - primary.latency_ms as i64,
+    })
+}
+
+/// Result of a shadowed retrieval, stored in the `-shadow` collection of the app.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShadowResult {
+    pub reference_id: String,
+    /// Endpoint of the alternate knowledge engine.
+    pub url: String,
+    pub primary: ShadowOutcome,
+    pub shadow: ShadowOutcome,
+    pub comparison: Option<ShadowComparison>,
+    #[serde(with = "crate::service::timestamp::bson_datetime")]
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Retrieval to shadow, sent by the retrieval once answered by the primary engine.
+#[derive(Debug, Clone)]
+pub struct ShadowRequest {
+    pub app_name: String,
+    pub reference_id: String,
+    pub task_id: String,
+    /// Payload sent to the primary engine.
+    pub payload: serde_json::Value,
+    pub primary: ShadowOutcome,
+}
+
+/// Summary of the shadowed retrievals of an app to an alternate knowledge engine.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ShadowReport {
+    pub url: String,
+    pub shadowed: i64,
+    pub shadow_failures: i64,
+    pub primary_failures: i64,
+    /// Shadowed retrievals answered by both engines.
+    pub compared: i64,
+    pub same_answers: i64,
+    /// Share of the compared retrievals with the same answer, unset without comparison.
+    pub same_answer_rate: Option<f64>,
+    pub average_source_overlap: Option<f64>,
+    pub average_primary_latency_ms: Option<f64>,
+    pub average_shadow_latency_ms: Option<f64>,
+    pub average_token_delta: Option<f64>,
+}
+
+/// Pipeline summarizing the shadow results of an app by URL of the alternate engine.
+pub fn shadow_report_pipeline() -> Vec<Document> {
+    vec![
+        doc! {
+            "$group": {
+                "_id": "$url",
+                "shadowed": { "$sum": 1 },
+                "shadow_failures": { "$sum": { "$cond": [ "$shadow.succeeded", 0, 1 ] } },
+                "primary_failures": { "$sum": { "$cond": [ "$primary.succeeded", 0, 1 ] } },
+                "compared": {
+                    "$sum": { "$cond": [ { "$eq": [ { "$type": "$comparison" }, "object" ] }, 1, 0 ] }
+                },
+                "same_answers": { "$sum": { "$cond": [ "$comparison.same_answer", 1, 0 ] } },
+                "average_source_overlap": { "$avg": "$comparison.source_overlap" },
+                "average_primary_latency_ms": { "$avg": "$primary.latency_ms" },
+                "average_shadow_latency_ms": { "$avg": "$shadow.latency_ms" },
+                "average_token_delta": { "$avg": "$comparison.token_delta" },
+            }
+        },
+        doc! { "$sort": { "_id": 1 } },
+        doc! {
+            "$project": {
+                "_id": 0,
+                "url": "$_id",
+                "shadowed": 1,
+                "shadow_failures": 1,
+                "primary_failures": 1,
+                "compared": 1,
+                "same_answers": 1,
+                "same_answer_rate": {
+                    "$cond": [
+                        { "$gt": [ "$compared", 0 ] },
+                        { "$divide": [ "$same_answers", "$compared" ] },
+                        null
+                    ]
+                },
+                "average_source_overlap": 1,
+                "average_primary_latency_ms": 1,
+                "average_shadow_latency_ms": 1,
+                "average_token_delta": 1,
+            }
+        },
+    ]
+}
+
+/// Posts the payload of a retrieval to the alternate knowledge engine.
+async fn call_shadow_engine(
+    app_state: &AppState,
+    url: &str,
+    payload: &serde_json::Value,
+    timeout: Duration,
+) -> Result<String, reqwest::Error> {
+    app_state
+        .http_clients
+        .for_url(url)
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .timeout(timeout)
+        .json(payload)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await
+}
+
+/// Stores the result of a shadowed retrieval in the `-shadow` collection of the app, with the answer of the
+/// alternate engine encrypted. The result is not stored if the encryption fails.
+async fn store_shadow_result(
+    app_state: &AppState,
+    app_name: &str,
+    mut result: ShadowResult,
+) -> Result<(), String> {
+    if let Some(answer) = result.shadow.answer.as_mut() {
+        *answer = app_state
+            .encrypt_field(app_name, answer)
+            .await
+            .map_err(|e| format!("Failed to encrypt shadow answer. Error: {}", e))?;
+    }
+    let document = to_document(&result).map_err(|e| e.to_string())?;
+    let db = app_state
+        .app_db(app_name)
+        .await
+        .map_err(|e| e.to_string())?;
+    db.create_document(
+        &format!("{}{}", app_name, SHADOW_COLLECTION_SUFFIX),
+        document,
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Shadows an answered retrieval to the alternate knowledge engine of the app, when the app has shadow traffic
+/// enabled and the retrieval is sampled. Failures are logged and counted, they never affect the retrieval.
+#[instrument(skip_all)]
+pub async fn shadow_retrieval(app_state: Arc<AppState>, request: ShadowRequest) {
+    let ShadowRequest {
+        app_name,
+        reference_id,
+        task_id,
+        payload,
+        mut primary,
+    } = request;
+    let shadow_traffic = match app_state.apps().shadow_traffic(&app_name).await {
+        Ok(Some(shadow_traffic)) if shadow_traffic.samples(&reference_id) => shadow_traffic,
+        Ok(_) => return,
+        Err(e) => {
+            error!(app_name = &app_name, message = e.to_string());
+            return;
+        }
+    };
+
+    let options = app_state.options::<ShadowTrafficOptions>();
+    let start = Instant::now();
+    let response =
+        call_shadow_engine(&app_state, &shadow_traffic.url, &payload, options.timeout).await;
+    let shadow = ShadowOutcome::new(
+        response.as_deref().map_err(|e| e.to_string()),
+        start.elapsed(),
+    );
+    let comparison = compare(&primary, &shadow);
+    let status = match &comparison {
+        Some(comparison) if comparison.same_answer => "same_answer",
+        Some(_) => "different_answer",
+        None => "failed",
+    };
+    // The answer of the primary engine is stored in the history document only
+    primary.answer = None;
+
+    let result = ShadowResult {
+        reference_id: reference_id.clone(),
+        url: shadow_traffic.url,
+        primary,
+        shadow,
+        comparison,
+        timestamp: Utc::now(),
+    };
+    match store_shadow_result(&app_state, &app_name, result).await {
+        Ok(()) => debug!(
+            app_name = &app_name,
+            task_id = &task_id,
+            message = format!("Retrieval '{}' shadowed: {}.", reference_id, status)
+        ),
+        Err(e) => {
+            let error_message = format!(
+                "Failed to store the shadow result of retrieval '{}'. Error: {}",
+                reference_id, e
+            );
+            error!(
+                app_name = &app_name,
+                task_id = &task_id,
+                message = error_message
+            );
+        }
+    }
+    app_state
+        .record_metric(
+            MetricRecord::counter("Shadow Retrieval Counter")
+                .dimension(APP_NAME_DIMENSION, &app_name)
+                .dimension(STATUS_DIMENSION, status),
+        )
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shadow_traffic(url: &str, percent: u32) -> ShadowTraffic {
+        ShadowTraffic {
+            url: url.to_string(),
+            percent,
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn test_success_shadow_traffic_validate() {
+        assert!(shadow_traffic("http://engine-v2:8000/retrieve", 10)
+            .validate()
+            .is_ok());
+        assert!(matches!(
+            shadow_traffic("engine-v2/retrieve", 10).validate(),
+            Err(ShadowTrafficError::Invalid(_))
+        ));
+        assert!(matches!(
+            shadow_traffic("https://engine-v2/retrieve", 0).validate(),
+            Err(ShadowTrafficError::Invalid(_))
+        ));
+        assert!(matches!(
+            shadow_traffic("https://engine-v2/retrieve", 101).validate(),
+            Err(ShadowTrafficError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_success_shadow_traffic_samples() {
+        let all = shadow_traffic("https://engine-v2/retrieve", 100);
+        assert!(all.samples("reference_id"));
+        let mut disabled = all.clone();
+        disabled.enabled = false;
+        assert!(!disabled.samples("reference_id"));
+
+        // About the configured share of the retrievals is sampled, always the same ones
+        let some = shadow_traffic("https://engine-v2/retrieve", 20);
+        let sampled = (0..1000)
+            .filter(|i| some.samples(&format!("reference-{}", i)))
+            .count();
+        assert!((120..280).contains(&sampled), "{} sampled", sampled);
+        assert_eq!(some.samples("reference-1"), some.samples("reference-1"));
+    }
+
+    #[test]
+    fn test_success_compare_outcomes() {
+        let primary = ShadowOutcome::new(
+            Ok(
+                r#"{"answer": "Leave is 20 days.", "citations": [{"source": "s3://hr/leave.pdf"}, {"source": "s3://hr/faq.pdf"}], "token_usage": {"total_tokens": 100}}"#,
+            ),
+            Duration::from_millis(1_000),
+        );
+        let shadow = ShadowOutcome::new(
+            Ok(
+                r#"{"answer": "leave is  20 days.", "citations": [{"source": "s3://hr/leave.pdf"}], "token_usage": {"total_tokens": 80}}"#,
+            ),
+            Duration::from_millis(700),
+        );
+        let comparison = compare(&primary, &shadow).unwrap();
+        assert!(comparison.same_answer);
+        assert_eq!(comparison.source_overlap, 0.5);
+        assert_eq!(comparison.token_delta, Some(-20));
+        assert_eq!(comparison.latency_delta_ms, -300);
+
+        let failed = ShadowOutcome::new(Err("timeout".to_string()), Duration::from_secs(30));
+        assert!(!failed.succeeded);
+        assert!(compare(&primary, &failed).is_none());
+
+        // Plain text answers without citation
+        let plain = ShadowOutcome::new(Ok("Leave is 25 days."), Duration::from_millis(10));
+        let comparison = compare(&plain, &plain.clone()).unwrap();
+        assert_eq!(comparison.source_overlap, 1.0);
+        assert!(comparison.token_delta.is_none());
+    }
+
+    #[test]
+    fn test_success_shadow_report_pipeline() {
+        let pipeline = shadow_report_pipeline();
+        assert_eq!(pipeline.len(), 3);
+        assert_eq!(
+            pipeline[0]
+                .get_document("$group")
+                .unwrap()
+                .get_str("_id")
+                .unwrap(),
+            "$url"
+        );
+        let report: ShadowReport = serde_json::from_value(json!({
+            "url": "https://engine-v2/retrieve",
+            "shadowed": 10,
+            "shadow_failures": 1,
+            "primary_failures": 0,
+            "compared": 9,
+            "same_answers": 6,
+            "same_answer_rate": 0.666,
+            "average_source_overlap": 0.8,
+            "average_primary_latency_ms": 1200.0,
+            "average_shadow_latency_ms": 900.0,
+            "average_token_delta": null,
+        }))
+        .unwrap();
+        assert_eq!(report.compared, 9);
+    }
+
+    #[test]
+    fn test_success_shadow_traffic_options() {
+        let options = ShadowTrafficOptions::from_settings(None);
+        assert_eq!(options.timeout, Duration::from_secs(30));
+        let options = ShadowTrafficOptions::from_settings(Some(&ShadowTrafficSettings {
+            timeout_ms: Some(5_000),
+        }));
+        assert_eq!(options.timeout, Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,391 @@
+/*
+ * Created Date:  Aug 5, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the labels of the filestores and datastores of an app, and the search scope of the
+//! retrievals naming them.
+//! A filestore or datastore of the onboarding request can define `labels`, e.g. `hr-docs` or `finance-db`, shared by
+//! as many sources as needed. The facade validates the labels and stores them on the app document as a flat
+//! `source_labels` list (the stored datasource only keeps the fields known to the knowledge engine).
+//! A retrieval can restrict its search to the sources of some labels with the optional `search_scope` field of its
+//! body. The facade rejects the labels no source of the app carries, and passes the labels and their sources to the
+//! knowledge engine in `search_scope`. A retrieval without `search_scope` searches all the sources of the app.
+//!
+
+use crate::onboarding::schema::app_onboarding_request::AppDataSource;
+use axum::{http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeSet;
+use tracing::error;
+use utoipa::ToSchema;
+
+/// Name of the field of the app document holding the source labels.
+pub const SOURCE_LABELS_FIELD: &str = "source_labels";
+/// Maximum length of a label.
+pub const MAX_LABEL_LENGTH: usize = 64;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum SourceLabelError {
+    #[error("Invalid label '{label}' of source '{source_id}': {reason}")]
+    InvalidLabel {
+        source_id: String,
+        label: String,
+        reason: String,
+    },
+    #[error("The search scope contains an empty label.")]
+    EmptyScopeLabel,
+    #[error("No source of the app is labeled {}.", quoted(.0))]
+    UnknownLabels(Vec<String>),
+}
+
+fn quoted(labels: &[String]) -> String {
+    labels
+        .iter()
+        .map(|label| format!("'{}'", label))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl From<SourceLabelError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: SourceLabelError) -> Self {
+        let error_message = e.to_string();
+        error!(ext_message = error_message, message = error_message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"status": "error", "message": error_message})),
+        )
+    }
+}
+
+/// Kind of a labeled source.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    Filestore,
+    Datastore,
+}
+
+/// Labels of a filestore or datastore, identified by its kind, source type and URL or `{host}/{database}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct LabeledSource {
+    pub kind: SourceKind,
+    pub source_type: String,
+    /// URL of a filestore, `{host}/{database}` of a datastore.
+    pub source: String,
+    pub labels: Vec<String>,
+}
+
+/// Returns the identifier of a datastore in the labeled sources.
+fn datastore_source(host: &str, database: &str) -> String {
+    format!("{}/{}", host, database)
+}
+
+/// Returns the labeled sources of a datasource. Sources without labels are left out.
+pub fn source_labels(datasource: &AppDataSource) -> Vec<LabeledSource> {
+    let labels = |labels: &Option<Vec<String>>| -> Vec<String> {
+        labels
+            .iter()
+            .flatten()
+            .map(|label| label.trim().to_string())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    };
+    let filestores = datasource
+        .filestore
+        .iter()
+        .flat_map(|(source_type, filestores)| {
+            filestores.iter().map(|filestore| LabeledSource {
+                kind: SourceKind::Filestore,
+                source_type: source_type.clone(),
+                source: filestore.url.clone(),
+                labels: labels(&filestore.labels),
+            })
+        });
+    let datastores = datasource
+        .datastore
+        .iter()
+        .flat_map(|(source_type, datastores)| {
+            datastores.iter().map(|datastore| LabeledSource {
+                kind: SourceKind::Datastore,
+                source_type: source_type.clone(),
+                source: datastore_source(&datastore.host, &datastore.database),
+                labels: labels(&datastore.labels),
+            })
+        });
+    let mut source_labels: Vec<LabeledSource> = filestores
+        .chain(datastores)
+        .filter(|labeled_source| !labeled_source.labels.is_empty())
+        .collect();
+    // The datasource maps have no order, keep the stored list stable across updates
+    source_labels.sort_by(|a, b| {
+        (a.kind, &a.source_type, &a.source).cmp(&(b.kind, &b.source_type, &b.source))
+    });
+    source_labels
+}
+
+/// Validates the labels of the sources of a datasource: letters, digits, `-` and `_`, up to `MAX_LABEL_LENGTH`
+/// characters.
+pub fn validate_source_labels(datasource: &AppDataSource) -> Result<(), SourceLabelError> {
+    let filestore_labels = datasource
+        .filestore
+        .values()
+        .flatten()
+        .map(|filestore| (filestore.url.clone(), &filestore.labels));
+    let datastore_labels = datasource.datastore.values().flatten().map(|datastore| {
+        (
+            datastore_source(&datastore.host, &datastore.database),
+            &datastore.labels,
+        )
+    });
+    for (source, labels) in filestore_labels.chain(datastore_labels) {
+        for label in labels.iter().flatten() {
+            let invalid = |reason: &str| SourceLabelError::InvalidLabel {
+                source_id: source.clone(),
+                label: label.clone(),
+                reason: reason.to_string(),
+            };
+            let label = label.trim();
+            if label.is_empty() {
+                return Err(invalid("the label is empty."));
+            }
+            if label.len() > MAX_LABEL_LENGTH {
+                return Err(invalid(&format!(
+                    "the label exceeds {} characters.",
+                    MAX_LABEL_LENGTH
+                )));
+            }
+            if !label
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+            {
+                return Err(invalid(
+                    "a label can only contain letters, digits, '-' and '_'.",
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Merges the stored source labels of an app document into the sources of its `app_datasource`, so the document can
+/// be read in the shape of the onboarding request. Documents without source labels are left as-is.
+pub fn merge_source_labels(app: &mut serde_json::Value) {
+    let Some(source_labels) = app
+        .get(SOURCE_LABELS_FIELD)
+        .cloned()
+        .and_then(|value| serde_json::from_value::<Vec<LabeledSource>>(value).ok())
+    else {
+        return;
+    };
+    for labeled_source in source_labels {
+        let kind = match labeled_source.kind {
+            SourceKind::Filestore => "filestore",
+            SourceKind::Datastore => "datastore",
+        };
+        let sources = app
+            .pointer_mut(&format!("/app_datasource/{}", kind))
+            .and_then(|sources| sources.get_mut(&labeled_source.source_type))
+            .and_then(serde_json::Value::as_array_mut)
+            .into_iter()
+            .flatten()
+            .filter_map(serde_json::Value::as_object_mut);
+        for source in sources {
+            let identifier = match labeled_source.kind {
+                SourceKind::Filestore => source
+                    .get("url")
+                    .and_then(|url| url.as_str())
+                    .map(str::to_string),
+                SourceKind::Datastore => source
+                    .get("host")
+                    .and_then(|host| host.as_str())
+                    .zip(
+                        source
+                            .get("database")
+                            .and_then(|database| database.as_str()),
+                    )
+                    .map(|(host, database)| datastore_source(host, database)),
+            };
+            if identifier.as_deref() == Some(labeled_source.source.as_str()) {
+                source.insert("labels".to_string(), json!(labeled_source.labels));
+            }
+        }
+    }
+}
+
+/// Search scope field of a retrieval request body, read next to the `RetrievalRequest`.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RetrievalScope {
+    /// Labels of the sources to search, all the sources of the app if unset or empty.
+    #[serde(default)]
+    pub search_scope: Option<Vec<String>>,
+}
+
+/// Search scope of a retrieval, passed to the knowledge engine: the labels of the request and the sources carrying
+/// them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct SearchScope {
+    pub labels: Vec<String>,
+    pub sources: Vec<LabeledSource>,
+}
+
+impl RetrievalScope {
+    /// Resolves the search scope of a retrieval against the labeled sources of its app. `None` for a retrieval of
+    /// all the sources.
+    pub fn resolve(
+        &self,
+        source_labels: &[LabeledSource],
+    ) -> Result<Option<SearchScope>, SourceLabelError> {
+        let Some(scope) = self.search_scope.as_ref().filter(|scope| !scope.is_empty()) else {
+            return Ok(None);
+        };
+        let mut labels = BTreeSet::new();
+        for label in scope {
+            match label.trim() {
+                "" => return Err(SourceLabelError::EmptyScopeLabel),
+                label => labels.insert(label.to_string()),
+            };
+        }
+        let known_labels: BTreeSet<&String> = source_labels
+            .iter()
+            .flat_map(|labeled_source| &labeled_source.labels)
+            .collect();
+        let unknown_labels: Vec<String> = labels
+            .iter()
+            .filter(|label| !known_labels.contains(label))
+            .cloned()
+            .collect();
+        if !unknown_labels.is_empty() {
+            return Err(SourceLabelError::UnknownLabels(unknown_labels));
+        }
+        let sources = source_labels
+            .iter()
+            .filter(|labeled_source| {
+                labeled_source
+                    .labels
+                    .iter()
+                    .any(|label| labels.contains(label))
+            })
+            .cloned()
+            .collect();
+        Ok(Some(SearchScope {
+            labels: labels.into_iter().collect(),
+            sources,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datasource(filestore_labels: serde_json::Value) -> AppDataSource {
+        serde_json::from_value(json!({
+            "filestore": {
+                "s3": [
+                    {"url": "s3://hr/policies/", "hints": [], "labels": filestore_labels},
+                    {"url": "s3://hr/archive/", "hints": []}
+                ]
+            },
+            "datastore": {
+                "aws_rds": [{
+                    "host": "db.example.com",
+                    "port": "5432",
+                    "username": null,
+                    "secret_name": null,
+                    "aws_service_name": null,
+                    "database": "finance",
+                    "db_type": "postgres",
+                    "descriptions": null,
+                    "region": null,
+                    "fact_phrases": null,
+                    "fact_words": null,
+                    "search_keywords": null,
+                    "summary": null,
+                    "tables": [],
+                    "labels": ["finance-db"]
+                }]
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_success_source_labels() {
+        let source_labels = source_labels(&datasource(json!(["hr-docs", " policies", "hr-docs"])));
+        assert_eq!(source_labels.len(), 2);
+        assert_eq!(source_labels[0].kind, SourceKind::Filestore);
+        assert_eq!(source_labels[0].source, "s3://hr/policies/");
+        assert_eq!(source_labels[0].labels, vec!["hr-docs", "policies"]);
+        assert_eq!(source_labels[1].source, "db.example.com/finance");
+        assert_eq!(source_labels[1].labels, vec!["finance-db"]);
+    }
+
+    #[test]
+    fn test_success_validate_source_labels() {
+        assert!(validate_source_labels(&datasource(json!(["hr-docs", "hr_2024"]))).is_ok());
+        assert!(matches!(
+            validate_source_labels(&datasource(json!([" "]))),
+            Err(SourceLabelError::InvalidLabel { .. })
+        ));
+        assert!(matches!(
+            validate_source_labels(&datasource(json!(["hr docs"]))),
+            Err(SourceLabelError::InvalidLabel { .. })
+        ));
+        assert!(matches!(
+            validate_source_labels(&datasource(json!(["a".repeat(MAX_LABEL_LENGTH + 1)]))),
+            Err(SourceLabelError::InvalidLabel { .. })
+        ));
+    }
+
+    #[test]
+    fn test_success_merge_source_labels() {
+        let datasource = datasource(json!(["hr-docs"]));
+        let mut stored = datasource.clone();
+        for filestore in stored.filestore.values_mut().flatten() {
+            filestore.labels = None;
+        }
+        for datastore in stored.datastore.values_mut().flatten() {
+            datastore.labels = None;
+        }
+        let mut app = json!({
+            "app_datasource": stored,
+            "source_labels": source_labels(&datasource),
+        });
+        merge_source_labels(&mut app);
+        let merged: AppDataSource = serde_json::from_value(app["app_datasource"].clone()).unwrap();
+        assert_eq!(merged, datasource);
+    }
+
+    #[test]
+    fn test_success_resolve_search_scope() {
+        let source_labels = source_labels(&datasource(json!(["hr-docs"])));
+        let scope = |labels: serde_json::Value| RetrievalScope {
+            search_scope: serde_json::from_value(labels).unwrap(),
+        };
+
+        assert_eq!(scope(json!(null)).resolve(&source_labels), Ok(None));
+        assert_eq!(scope(json!([])).resolve(&source_labels), Ok(None));
+
+        let search_scope = scope(json!(["hr-docs"]))
+            .resolve(&source_labels)
+            .unwrap()
+            .unwrap();
+        assert_eq!(search_scope.labels, vec!["hr-docs"]);
+        assert_eq!(search_scope.sources.len(), 1);
+        assert_eq!(search_scope.sources[0].source, "s3://hr/policies/");
+
+        assert_eq!(
+            scope(json!(["hr-docs", "legal-docs"])).resolve(&source_labels),
+            Err(SourceLabelError::UnknownLabels(vec![
+                "legal-docs".to_string()
+            ]))
+        );
+        assert_eq!(
+            scope(json!([" "])).resolve(&source_labels),
+            Err(SourceLabelError::EmptyScopeLabel)
+        );
+    }
+}
@@ -7,12 +7,54 @@
 //! The `AppState` struct represents the state of the application.
 //! It contains a MongoDB client and the name of the application's collection.
 //!
-//! `db`: A MongoDB client that implements the `DBTrait` trait, and is thread-safe (implements `Sync` and `Send`).
+//! `db`: A MongoDB client that implements the `DBTrait` trait, and is thread-safe (implements `Sync` and `Send`), with the
+//! driver database of the primary cluster through which the aggregations pass their options.
 //! `app_collection`: The name of the application's collection in the MongoDB database.
+//! `http_clients`: The outbound HTTP clients shared by the handlers.
+//! `metrics_sinks`: The sinks the typed metric records are written through.
+//! `encryptor`: The envelope encryptor of the sensitive fields, if encryption is configured.
+//! `residency_dbs`: The DocumentDB clients of the residency clusters, by residency name.
+//! `analytics_dbs`: The read-preference clients of the heavy admin aggregations, by residency (`None` for the primary cluster).
+//! `rate_limiter`: The store of the per-user rate limit counters of the retrievals.
+//! `local_dev`: The in-process fakes of the AWS and Kafka integrations, in the local development mode.
+//! `id_generator`: The generator of the reference IDs and task IDs of the requests.
+//! `driver_databases`: The driver connections of the clusters, shared by the aggregations, indexes, change streams and
+//! atomic writes.
+//! `prometheus`: The histograms of the duration metrics served at `/metrics`, if the Prometheus export is configured.
 
-use crate::configuration::settings::TresleFacadeServiceSettings;
+use crate::configuration::options::SettingsOptions;
+use crate::configuration::settings::{ApiKeyMode, TresleFacadeServiceSettings};
+use crate::service::api_key::ApiKeyOptions;
+use crate::service::app_cache::{AppCache, AppCacheOptions};
+use crate::service::app_repository::AppRepository;
+use crate::service::deployment::DeploymentLabels;
+use crate::service::driver::{ClusterDb, DriverDatabases};
+use crate::service::encryption::{
+    EncryptionError, FieldEncryptor, KeyProvider, DEFAULT_DATA_KEYS_COLLECTION,
+    DEFAULT_LATEST_VERSION_CACHE_SECONDS,
+};
+use crate::service::file_types::FileTypes;
+use crate::service::http_client::{HttpClientError, HttpClients};
+use crate::service::id_generator::{IdGenerator, UuidV7IdGenerator};
+use crate::service::knowledge_node_types::KnowledgeNodeTypes;
+use crate::service::local_dev::LocalDev;
+use crate::service::metrics::{sinks_from_settings, MetricRecord, MetricsSink};
+use crate::service::overview_feed::{OverviewFeed, OverviewFeedOptions};
+use crate::service::prometheus::PrometheusRegistry;
+use crate::service::query_options::QueryOptions;
+use crate::service::rate_limit::{
+    store_from_settings, RateLimitDecision, RateLimitError, RateLimitStore,
+};
+use crate::service::residency::ResidencyError;
+use crate::service::route::max_request_body_bytes;
+use crate::service::tls::PemMaterial;
+use chrono::Utc;
 use mongodb_utils::mongodb_client::DBTrait;
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::error;
 
 #[derive(Debug, thiserror::Error)]
 pub enum AppStateError {
@@ -20,11 +62,25 @@ pub enum AppStateError {
     AppSettingsNotProvided,
     #[error("DB not set")]
     DbNotSet,
+    #[error("Failed to build HTTP clients: {0}")]
+    HttpClients(#[from] HttpClientError),
 }
 
 pub struct AppState {
-    pub db: Box<dyn DBTrait + Sync + Send>,
+    pub db: ClusterDb,
     pub app_settings: TresleFacadeServiceSettings,
+    pub http_clients: HttpClients,
+    pub metrics_sinks: Vec<Box<dyn MetricsSink>>,
+    pub encryptor: Option<FieldEncryptor>,
+    pub residency_dbs: HashMap<String, ClusterDb>,
+    pub analytics_dbs: HashMap<Option<String>, ClusterDb>,
+    pub rate_limiter: Box<dyn RateLimitStore>,
+    pub local_dev: Option<LocalDev>,
+    pub id_generator: Box<dyn IdGenerator>,
+    pub driver_databases: Option<DriverDatabases>,
+    pub prometheus: Option<Arc<PrometheusRegistry>>,
+    pub overview_feed: OverviewFeed,
+    pub app_cache: AppCache,
 }
 
 impl fmt::Debug for AppState {
@@ -32,16 +88,224 @@ impl fmt::Debug for AppState {
         f.debug_struct("AppState")
             .field("db", &"db")
             .field("app_settings", &self.app_settings)
+            .field("http_clients", &self.http_clients)
+            .field("metrics_sinks", &self.metrics_sinks.len())
+            .field("encryptor", &self.encryptor.is_some())
+            .field("residency_dbs", &self.residency_dbs.keys())
+            .field("analytics_dbs", &self.analytics_dbs.keys())
+            .field("local_dev", &self.local_dev.is_some())
+            .field("driver_databases", &self.driver_databases.is_some())
+            .field("prometheus", &self.prometheus.is_some())
+            .field("overview_feed", &self.overview_feed.subscriber_count())
+            .field("app_cache", &self.app_cache.len())
             .finish()
     }
 }
 
 impl AppState {
     pub fn new(
-        db: Box<dyn DBTrait + Sync + Send>,
+        db: ClusterDb,
         app_settings: TresleFacadeServiceSettings,
+        http_clients: HttpClients,
+        metrics_sinks: Vec<Box<dyn MetricsSink>>,
+        encryptor: Option<FieldEncryptor>,
+        residency_dbs: HashMap<String, ClusterDb>,
+        analytics_dbs: HashMap<Option<String>, ClusterDb>,
+        rate_limiter: Box<dyn RateLimitStore>,
+        local_dev: Option<LocalDev>,
+        id_generator: Box<dyn IdGenerator>,
+        driver_databases: Option<DriverDatabases>,
+        prometheus: Option<Arc<PrometheusRegistry>>,
+        overview_feed: OverviewFeed,
+        app_cache: AppCache,
     ) -> Result<Self, AppStateError> {
-        Ok(AppState { db, app_settings })
+        Ok(AppState {
+            db,
+            app_settings,
+            http_clients,
+            metrics_sinks,
+            encryptor,
+            residency_dbs,
+            analytics_dbs,
+            rate_limiter,
+            local_dev,
+            id_generator,
+            driver_databases,
+            prometheus,
+            overview_feed,
+            app_cache,
+        })
+    }
+
+    /// Returns the database of a residency, the primary database if the residency is `None`.
+    pub fn residency_db(&self, residency: Option<&str>) -> Result<&ClusterDb, ResidencyError> {
+        match residency {
+            None => Ok(&self.db),
+            Some(residency) => self
+                .residency_dbs
+                .get(residency)
+                .ok_or_else(|| ResidencyError::UnknownResidency(residency.to_string())),
+        }
+    }
+
+    /// Returns the typed lookups of the app documents.
+    pub fn apps(&self) -> AppRepository<'_> {
+        AppRepository::new(self)
+    }
+
+    /// Returns the typed lookups of the app documents of a request, fetching the document of an app once.
+    pub fn request_apps(&self) -> AppRepository<'_> {
+        AppRepository::per_request(self)
+    }
+
+    /// Returns the residency stored in the app document of an app.
+    /// The lookup is not cached, so a migration applies to all the replicas right away.
+    pub async fn app_residency(&self, app_name: &str) -> Result<Option<String>, ResidencyError> {
+        self.apps()
+            .residency(app_name)
+            .await
+            .map_err(|e| ResidencyError::Lookup {
+                app_name: app_name.to_string(),
+                message: e.to_string(),
+            })
+    }
+
+    /// Returns the database holding the app specific collections of an app.
+    pub async fn app_db(&self, app_name: &str) -> Result<&ClusterDb, ResidencyError> {
+        let residency = self.app_residency(app_name).await?;
+        self.residency_db(residency.as_deref())
+    }
+
+    /// Returns the database of the heavy admin aggregations (charts, counts, overview) of a residency.
+    /// Falls back to the database of the residency if no analytics connection is configured for it.
+    pub fn analytics_db(&self, residency: Option<&str>) -> Result<&ClusterDb, ResidencyError> {
+        match self.analytics_dbs.get(&residency.map(str::to_string)) {
+            Some(db) => Ok(db),
+            None => self.residency_db(residency),
+        }
+    }
+
+    /// Returns the database of the heavy admin aggregations over the app specific collections of an app.
+    /// The residency is looked up on the primary, so a migration is not missed by a lagging replica.
+    pub async fn app_analytics_db(&self, app_name: &str) -> Result<&ClusterDb, ResidencyError> {
+        let residency = self.app_residency(app_name).await?;
+        self.analytics_db(residency.as_deref())
+    }
+
+    /// Returns true if the sensitive fields of new documents are encrypted.
+    pub fn is_encryption_enabled(&self) -> bool {
+        self.encryptor.is_some()
+            && self
+                .app_settings
+                .encryption
+                .as_ref()
+                .is_some_and(|encryption| encryption.enabled)
+    }
+
+    /// Encrypts a sensitive field of an app. The value is returned as-is if encryption is disabled.
+    pub async fn encrypt_field(
+        &self,
+        app_name: &str,
+        value: &str,
+    ) -> Result<String, EncryptionError> {
+        match &self.encryptor {
+            Some(encryptor) if self.is_encryption_enabled() => {
+                encryptor.encrypt(&self.db, app_name, value).await
+            }
+            _ => Ok(value.to_string()),
+        }
+    }
+
+    /// Decrypts, in place, the encrypted fields of a document of an app.
+    /// Documents written while encryption was disabled are left untouched.
+    pub async fn decrypt_fields(
+        &self,
+        app_name: &str,
+        document: &mut serde_json::Value,
+    ) -> Result<(), EncryptionError> {
+        match &self.encryptor {
+            Some(encryptor) => {
+                encryptor
+                    .decrypt_json(self.db.as_ref(), app_name, document)
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Options of a service resolved from its section of the settings, e.g. `options::<QueryOptions>()`.
+    pub fn options<O: SettingsOptions>(&self) -> O {
+        O::resolve(&self.app_settings)
+    }
+
+    /// Issuer of the API keys of the apps and collection of their usage.
+    /// The local development mode always issues the keys itself, without API Gateway.
+    pub fn api_key_options(&self) -> ApiKeyOptions {
+        let mut options = self.options::<ApiKeyOptions>();
+        if self.local_dev.is_some() {
+            options.mode = ApiKeyMode::Internal;
+        }
+        options
+    }
+
+    /// Registry of the file types supported for ingestion, before the overrides of the apps.
+    pub fn file_types(&self) -> FileTypes {
+        FileTypes::from_settings(
+            &self.app_settings.supported_file_types,
+            self.app_settings.file_types.as_ref(),
+        )
+    }
+
+    /// Registry of the knowledge node types served by the admin node endpoints.
+    pub fn knowledge_node_types(&self) -> KnowledgeNodeTypes {
+        KnowledgeNodeTypes::from_settings(self.app_settings.knowledge_node_types.as_ref())
+    }
+
+    /// Maximum size of a request body read by the handlers, once decompressed.
+    pub fn max_request_body_bytes(&self) -> usize {
+        max_request_body_bytes(self.app_settings.compression.as_ref())
+    }
+
+    /// Deployment, environment and region labels of the metrics and log events.
+    pub fn deployment_labels(&self) -> DeploymentLabels {
+        DeploymentLabels::from_settings(
+            self.app_settings.deployment.as_ref(),
+            self.app_settings.aws.as_ref(),
+        )
+    }
+
+    /// Writes a metric record through every metrics sink. Failures are logged and never fail the caller.
+    pub async fn record_metric(&self, record: MetricRecord) {
+        let record = self.deployment_labels().label_record(record);
+        for sink in &self.metrics_sinks {
+            if let Err(e) = sink.record(self.db.as_ref(), &record).await {
+                error!(message = format!("Failed to record metric '{}': {}", record.name, e));
+            }
+        }
+    }
+
+    /// Counts a retrieval of an end user of an app against the rate limit of the app.
+    /// Apps without a `user_rate_limit` are never limited.
+    pub async fn check_user_rate_limit(
+        &self,
+        apps: &AppRepository<'_>,
+        app_name: &str,
+        user_id: &str,
+    ) -> Result<RateLimitDecision, RateLimitError> {
+        let limit = apps
+            .user_rate_limit(app_name)
+            .await
+            .map_err(|e| RateLimitError::Read(e.to_string()))?;
+        match limit {
+            Some(limit) => {
+                self.rate_limiter
+                    .hit(&self.db, app_name, user_id, &limit, Utc::now())
+                    .await
+            }
+            None => Ok(RateLimitDecision::Allowed {
+                remaining: u32::MAX,
+            }),
+        }
     }
 
     /// Returns a new `Builder` for `AppState`.
@@ -49,6 +313,12 @@ impl AppState {
         AppStateBuilder {
             db: None,
             app_settings: None,
+            client_tls_material: None,
+            key_provider: None,
+            residency_dbs: HashMap::new(),
+            analytics_dbs: HashMap::new(),
+            id_generator: None,
+            driver_databases: None,
         }
     }
 }
@@ -60,6 +330,12 @@ impl AppState {
 pub struct AppStateBuilder {
     db: Option<Box<dyn DBTrait + Sync + Send>>,
     app_settings: Option<TresleFacadeServiceSettings>,
+    client_tls_material: Option<PemMaterial>,
+    key_provider: Option<Box<dyn KeyProvider>>,
+    residency_dbs: HashMap<String, Box<dyn DBTrait + Sync + Send>>,
+    analytics_dbs: HashMap<Option<String>, Box<dyn DBTrait + Sync + Send>>,
+    id_generator: Option<Box<dyn IdGenerator>>,
+    driver_databases: Option<DriverDatabases>,
 }
 
 impl AppStateBuilder {
@@ -89,16 +365,135 @@ impl AppStateBuilder {
         self
     }
 
+    /// Sets the client certificate presented on outbound mTLS calls.
+    pub fn client_tls_material(mut self, client_tls_material: Option<PemMaterial>) -> Self {
+        self.client_tls_material = client_tls_material;
+        self
+    }
+
+    /// Sets the key provider of the data keys used to encrypt the sensitive fields.
+    pub fn key_provider(mut self, key_provider: Option<Box<dyn KeyProvider>>) -> Self {
+        self.key_provider = key_provider;
+        self
+    }
+
+    /// Adds the DocumentDB client of a residency cluster.
+    pub fn residency_db(
+        mut self,
+        residency: String,
+        db_client: impl DBTrait + Sync + Send + 'static,
+    ) -> Self {
+        self.residency_dbs.insert(residency, Box::new(db_client));
+        self
+    }
+
+    /// Adds the analytics client of a residency cluster, or of the primary cluster if the residency is `None`.
+    pub fn analytics_db(
+        mut self,
+        residency: Option<String>,
+        db_client: impl DBTrait + Sync + Send + 'static,
+    ) -> Self {
+        self.analytics_dbs.insert(residency, Box::new(db_client));
+        self
+    }
+
+    /// Sets the generator of the reference IDs and task IDs, UUIDv7 based by default.
+    pub fn id_generator(mut self, id_generator: impl IdGenerator + 'static) -> Self {
+        self.id_generator = Some(Box::new(id_generator));
+        self
+    }
+
+    /// Sets the driver connections of the clusters, shared by the features the document clients don't cover.
+    /// The document clients are paired with the driver database of their cluster when the `AppState` is built.
+    pub fn driver_databases(mut self, driver_databases: DriverDatabases) -> Self {
+        self.driver_databases = Some(driver_databases);
+        self
+    }
+
     /// Builds the `AppState` from the `Builder`.
     ///
     /// This method consumes the `Builder` and returns an `AppState`.
     /// It will panic if the `db` or `app_collection` fields of the `Builder` are `None`.
 
     pub fn build(self) -> Result<AppState, AppStateError> {
+        let app_settings = self
+            .app_settings
+            .ok_or(AppStateError::AppSettingsNotProvided)?;
+        let http_clients =
+            HttpClients::from_settings(&app_settings, self.client_tls_material.as_ref())?;
+        let prometheus = PrometheusRegistry::from_settings(
+            app_settings
+                .metrics
+                .as_ref()
+                .and_then(|metrics| metrics.prometheus.as_ref()),
+        )
+        .map(Arc::new);
+        let metrics_sinks = sinks_from_settings(&app_settings, prometheus.as_ref());
+        let rate_limiter = store_from_settings(&app_settings);
+        let local_dev = LocalDev::from_settings(app_settings.local_dev.as_ref());
+        let encryptor = self.key_provider.map(|key_provider| {
+            let encryption = app_settings.encryption.as_ref();
+            let collection_name = encryption
+                .and_then(|encryption| encryption.data_keys_collection.clone())
+                .unwrap_or_else(|| DEFAULT_DATA_KEYS_COLLECTION.to_string());
+            let latest_version_ttl = Duration::from_secs(
+                encryption
+                    .and_then(|encryption| encryption.latest_version_cache_seconds)
+                    .unwrap_or(DEFAULT_LATEST_VERSION_CACHE_SECONDS),
+            );
+            FieldEncryptor::new(key_provider, collection_name)
+                .with_query_options(QueryOptions::resolve(&app_settings))
+                .with_latest_version_ttl(latest_version_ttl)
+        });
+        let overview_feed =
+            OverviewFeed::new(OverviewFeedOptions::resolve(&app_settings).channel_capacity);
+        let app_cache = AppCache::new(&AppCacheOptions::resolve(&app_settings));
+        let driver_databases = self.driver_databases;
+        let driver_database = |residency: Option<&str>| {
+            driver_databases
+                .as_ref()
+                .and_then(|driver_databases| driver_databases.database(residency).ok())
+                .cloned()
+        };
+        let db = ClusterDb::new(self.db.ok_or(AppStateError::DbNotSet)?)
+            .with_database(driver_database(None));
+        let residency_dbs = self
+            .residency_dbs
+            .into_iter()
+            .map(|(residency, client)| {
+                let database = driver_database(Some(residency.as_str()));
+                (residency, ClusterDb::new(client).with_database(database))
+            })
+            .collect();
+        let analytics_dbs = self
+            .analytics_dbs
+            .into_iter()
+            .map(|(residency, client)| {
+                let database = driver_databases
+                    .as_ref()
+                    .and_then(|driver_databases| {
+                        driver_databases.analytics_database(residency.as_deref())
+                    })
+                    .cloned();
+                (residency, ClusterDb::new(client).with_database(database))
+            })
+            .collect();
         let app_state: AppState = AppState::new(
-            self.db.ok_or(AppStateError::DbNotSet)?,
-            self.app_settings
-                .ok_or(AppStateError::AppSettingsNotProvided)?,
+            db,
+            app_settings,
+            http_clients,
+            metrics_sinks,
+            encryptor,
+            residency_dbs,
+            analytics_dbs,
+            rate_limiter,
+            local_dev,
+            self.id_generator
+                .unwrap_or_else(|| Box::new(UuidV7IdGenerator)),
+            driver_databases,
+            prometheus,
+            overview_feed,
+            app_cache,
         )?;
         Ok(app_state)
     }
@@ -121,6 +516,33 @@ mod tests {
         println!("Now {:?} will print!", app_state_error);
     }
 
+    #[test]
+    fn test_success_analytics_db_fallback() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // Without analytics connection, the aggregations go to the residency database
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            assert!(app_state.analytics_dbs.is_empty());
+            assert!(app_state.analytics_db(None).is_ok());
+            assert!(matches!(
+                app_state.analytics_db(Some("unknown-region")),
+                Err(ResidencyError::UnknownResidency(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn test_success_default_id_generator() {
+        let rt = Runtime::new().unwrap();
+
+        rt.block_on(async {
+            // The IDs are UUIDv7 based unless a generator is injected
+            let app_state = crate::tests::test_get_appstate().await.unwrap();
+            assert_eq!(app_state.id_generator.next_uuid().get_version_num(), 7);
+        });
+    }
+
     #[test]
     fn test_success_app_state() {
         let rt = Runtime::new().unwrap();
@@ -0,0 +1,223 @@
+/*
+ * Created Date:  Jul 26, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the canonical storage of the timestamps of the app, ID, history, UI summary and token usage
+//! documents: BSON dates, in UTC with millisecond precision, so the time-range queries compare dates instead of
+//! strings.
+//! The timestamps used to be stored as strings of varying formats (`application.timestamp_format`, RFC 3339 and
+//! `Utc::now().to_string()`), which sort lexicographically and broke the time ranges mixing them. The serde helpers
+//! write BSON dates and read every stored format, until the `timestamps_as_dates` migration converts the stored
+//! strings, see `migration`.
+//! The BSON dates read back as extended JSON (`{"$date": ...}`) are served as RFC 3339 strings by the handlers.
+//!
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Formats of the timestamps stored as strings before the BSON dates, besides RFC 3339.
+const LEGACY_TIMESTAMP_FORMATS: [&str; 3] = [
+    // Utc::now().to_string()
+    "%Y-%m-%d %H:%M:%S%.f UTC",
+    // Default application.timestamp_format
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M:%S%.f",
+];
+
+/// Parses a timestamp stored as a string, in RFC 3339, in one of the legacy formats or in `timestamp_format`.
+/// Timestamps without time zone are read as UTC.
+pub fn parse_timestamp(text: &str, timestamp_format: Option<&str>) -> Option<DateTime<Utc>> {
+    let text = text.trim();
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(text) {
+        return Some(timestamp.with_timezone(&Utc));
+    }
+    timestamp_format
+        .into_iter()
+        .chain(LEGACY_TIMESTAMP_FORMATS)
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .map(|timestamp| timestamp.and_utc())
+}
+
+/// Reads a stored timestamp: a BSON date, a string or milliseconds since the epoch.
+pub fn timestamp_from_bson(value: &Bson, timestamp_format: Option<&str>) -> Option<DateTime<Utc>> {
+    match value {
+        Bson::DateTime(timestamp) => Some(timestamp.to_chrono()),
+        Bson::String(text) => parse_timestamp(text, timestamp_format),
+        Bson::Int64(millis) => DateTime::from_timestamp_millis(*millis),
+        Bson::Int32(millis) => DateTime::from_timestamp_millis(*millis as i64),
+        Bson::Double(millis) => DateTime::from_timestamp_millis(*millis as i64),
+        _ => None,
+    }
+}
+
+/// Converts a timestamp to the BSON date stored.
+pub fn to_bson_datetime(timestamp: DateTime<Utc>) -> mongodb::bson::DateTime {
+    mongodb::bson::DateTime::from_chrono(timestamp)
+}
+
+/// Filter of the stored timestamps between two timestamps, bounds included.
+pub fn timestamp_range(start_timestamp: DateTime<Utc>, end_timestamp: DateTime<Utc>) -> Document {
+    doc! {
+        "$gte": to_bson_datetime(start_timestamp),
+        "$lte": to_bson_datetime(end_timestamp),
+    }
+}
+
+/// Replaces the BSON dates of a stored document read back as extended JSON with RFC 3339 strings.
+pub fn serve_timestamps(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(object) => {
+            if object.len() == 1 && object.contains_key("$date") {
+                let served =
+                    serde_json::from_value::<Bson>(serde_json::Value::Object(object.clone()))
+                        .ok()
+                        .and_then(|date| timestamp_from_bson(&date, None));
+                if let Some(timestamp) = served {
+                    *value = serde_json::Value::String(timestamp.to_rfc3339());
+                }
+                return;
+            }
+            object.values_mut().for_each(serve_timestamps);
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(serve_timestamps),
+        _ => {}
+    }
+}
+
+/// Serde helpers of a timestamp stored as a BSON date, reading the legacy formats.
+/// Use with `#[serde(with = "crate::service::timestamp::bson_datetime")]`.
+pub mod bson_datetime {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        timestamp: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        to_bson_datetime(*timestamp).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        let value = Bson::deserialize(deserializer)?;
+        timestamp_from_bson(&value, None)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid stored timestamp: {}", value)))
+    }
+}
+
+/// Serde helpers of an optional timestamp stored as a BSON date, reading the legacy formats.
+/// Use with `#[serde(default, with = "crate::service::timestamp::option_bson_datetime")]`.
+pub mod option_bson_datetime {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        timestamp: &Option<DateTime<Utc>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        timestamp.map(to_bson_datetime).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Utc>>, D::Error> {
+        match Option::<Bson>::deserialize(deserializer)? {
+            None | Some(Bson::Null) => Ok(None),
+            Some(value) => timestamp_from_bson(&value, None).map(Some).ok_or_else(|| {
+                serde::de::Error::custom(format!("invalid stored timestamp: {}", value))
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct Stored {
+        #[serde(with = "bson_datetime")]
+        timestamp: DateTime<Utc>,
+        #[serde(default, with = "option_bson_datetime")]
+        created_at: Option<DateTime<Utc>>,
+    }
+
+    #[test]
+    fn test_success_parse_timestamp() {
+        let expected = DateTime::parse_from_rfc3339("2024-07-26T10:00:00.500Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        for text in [
+            "2024-07-26T10:00:00.500Z",
+            "2024-07-26T12:00:00.500+02:00",
+            "2024-07-26 10:00:00.500 UTC",
+            "2024-07-26 10:00:00.500",
+        ] {
+            assert_eq!(parse_timestamp(text, None), Some(expected), "{}", text);
+        }
+        assert_eq!(
+            parse_timestamp("26/07/2024 10:00", Some("%d/%m/%Y %H:%M")),
+            parse_timestamp("2024-07-26T10:00:00Z", None)
+        );
+        assert_eq!(
+            parse_timestamp("2024-07-26", None),
+            parse_timestamp("2024-07-26T00:00:00Z", None)
+        );
+    }
+
+    #[test]
+    fn test_failure_parse_timestamp() {
+        assert!(parse_timestamp("Retrieval failed.", None).is_none());
+        assert!(parse_timestamp("26/07/2024", None).is_none());
+        assert!(timestamp_from_bson(&Bson::Boolean(true), None).is_none());
+    }
+
+    #[test]
+    fn test_success_bson_datetime() {
+        let timestamp = parse_timestamp("2024-07-26T10:00:00.500Z", None).unwrap();
+        let stored = Stored {
+            timestamp,
+            created_at: Some(timestamp),
+        };
+        let document = mongodb::bson::to_document(&stored).unwrap();
+        assert!(matches!(document.get("timestamp"), Some(Bson::DateTime(_))));
+        let read: Stored = mongodb::bson::from_document(document).unwrap();
+        assert_eq!(read.timestamp, timestamp);
+
+        // Legacy strings, extended JSON and epoch milliseconds are read as well
+        for stored in [
+            json!({"timestamp": "2024-07-26 10:00:00.500 UTC"}),
+            json!({"timestamp": {"$date": "2024-07-26T10:00:00.500Z"}}),
+            json!({"timestamp": {"$date": {"$numberLong": "1721988000500"}}}),
+            json!({"timestamp": 1721988000500_i64, "created_at": null}),
+        ] {
+            let read: Stored = serde_json::from_value(stored).unwrap();
+            assert_eq!(read.timestamp, timestamp);
+            assert!(read.created_at.is_none());
+        }
+        assert!(serde_json::from_value::<Stored>(json!({"timestamp": "timestamp"})).is_err());
+    }
+
+    #[test]
+    fn test_success_serve_timestamps() {
+        let mut value = json!({
+            "create_timestamp": {"$date": "2024-07-26T10:00:00Z"},
+            "keys": [{"created_at": {"$date": {"$numberLong": "1721988000000"}}}],
+            "timestamp": "Retrieval failed.",
+        });
+        serve_timestamps(&mut value);
+        assert_eq!(value["create_timestamp"], "2024-07-26T10:00:00+00:00");
+        assert_eq!(value["keys"][0]["created_at"], "2024-07-26T10:00:00+00:00");
+        assert_eq!(value["timestamp"], "Retrieval failed.");
+
+        let range = timestamp_range(Utc::now(), Utc::now());
+        assert!(matches!(range.get("$gte"), Some(Bson::DateTime(_))));
+    }
+}
@@ -0,0 +1,184 @@
+/*
+ * Created Date:  Jun 10, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the helpers for mutual TLS between the facade and the internal tresleai services.
+//! The certificate material is read either from PEM files or from an AWS Secrets Manager secret.
+//! The server side config is used by the axum listener, the client side identity is presented on
+//! outbound calls to the URLs configured in `tls.client.mtls_urls`.
+//!
+
+use crate::configuration::settings::{ClientTlsSettings, ServerTlsSettings};
+use aws_config::BehaviorVersion;
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use serde::Deserialize;
+use std::io::BufReader;
+use std::sync::Arc;
+use tracing::{debug, instrument};
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("Failed to read TLS material: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("TLS material missing: {0}")]
+    MissingMaterial(String),
+    #[error("Failed to fetch TLS secret from Secrets Manager: {0}")]
+    SecretsManager(String),
+    #[error("Failed to parse TLS secret: {0}")]
+    SecretFormat(#[from] serde_json::Error),
+    #[error("Invalid TLS configuration: {0}")]
+    Rustls(#[from] rustls::Error),
+    #[error("Failed to build client identity: {0}")]
+    Identity(#[from] reqwest::Error),
+}
+
+/// PEM encoded certificate material.
+#[derive(Debug, Default, Deserialize)]
+pub struct PemMaterial {
+    pub certificate: String,
+    pub private_key: String,
+    pub ca_bundle: Option<String>,
+}
+
+impl PemMaterial {
+    /// Returns the client identity (certificate chain followed by the private key) for reqwest.
+    pub fn identity(&self) -> Result<reqwest::Identity, TlsError> {
+        let pem = format!("{}\n{}", self.certificate, self.private_key);
+        Ok(reqwest::Identity::from_pem(pem.as_bytes())?)
+    }
+}
+
+/// Function to load the server side TLS material from the configured files or secret.
+#[instrument(skip_all)]
+pub async fn load_server_material(settings: &ServerTlsSettings) -> Result<PemMaterial, TlsError> {
+    match &settings.secret_id {
+        Some(secret_id) => load_secret(secret_id).await,
+        None => Ok(PemMaterial {
+            certificate: read_pem_file(&settings.cert_path, "server certificate")?,
+            private_key: read_pem_file(&settings.key_path, "server private key")?,
+            ca_bundle: match &settings.client_ca_path {
+                Some(_) => Some(read_pem_file(&settings.client_ca_path, "client CA bundle")?),
+                None => None,
+            },
+        }),
+    }
+}
+
+/// Function to load the client side TLS material from the configured files or secret.
+#[instrument(skip_all)]
+pub async fn load_client_material(settings: &ClientTlsSettings) -> Result<PemMaterial, TlsError> {
+    match &settings.secret_id {
+        Some(secret_id) => load_secret(secret_id).await,
+        None => Ok(PemMaterial {
+            certificate: read_pem_file(&settings.cert_path, "client certificate")?,
+            private_key: read_pem_file(&settings.key_path, "client private key")?,
+            ca_bundle: None,
+        }),
+    }
+}
+
+/// Function to build the rustls server config. Client certificates are required when a CA bundle is present.
+pub fn build_server_config(material: &PemMaterial) -> Result<Arc<ServerConfig>, TlsError> {
+    let certs = parse_certificates(&material.certificate)?;
+    let key = parse_private_key(&material.private_key)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let config = match &material.ca_bundle {
+        Some(ca_bundle) => {
+            let mut roots = RootCertStore::empty();
+            for cert in parse_certificates(ca_bundle)? {
+                roots.add(&cert)?;
+            }
+            builder
+                .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+                .with_single_cert(certs, key)?
+        }
+        None => builder.with_no_client_auth().with_single_cert(certs, key)?,
+    };
+    Ok(Arc::new(config))
+}
+
+/// Function to read the certificate material from an AWS Secrets Manager secret.
+async fn load_secret(secret_id: &str) -> Result<PemMaterial, TlsError> {
+    debug!("Fetching TLS material from Secrets Manager.");
+    let config = aws_config::defaults(BehaviorVersion::latest()).load().await;
+    let client = aws_sdk_secretsmanager::Client::new(&config);
+    let secret = client
+        .get_secret_value()
+        .secret_id(secret_id)
+        .send()
+        .await
+        .map_err(|e| TlsError::SecretsManager(e.to_string()))?;
+    let secret_string = secret.secret_string().ok_or_else(|| {
+        TlsError::MissingMaterial(format!("secret '{}' has no string value", secret_id))
+    })?;
+    Ok(serde_json::from_str(secret_string)?)
+}
+
+fn read_pem_file(path: &Option<String>, name: &str) -> Result<String, TlsError> {
+    let path = path
+        .as_ref()
+        .ok_or_else(|| TlsError::MissingMaterial(format!("{} path not configured", name)))?;
+    Ok(std::fs::read_to_string(path)?)
+}
+
+fn parse_certificates(pem: &str) -> Result<Vec<Certificate>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(pem.as_bytes()))?;
+    if certs.is_empty() {
+        return Err(TlsError::MissingMaterial(
+            "no certificate found".to_string(),
+        ));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn parse_private_key(pem: &str) -> Result<PrivateKey, TlsError> {
+    for item in rustls_pemfile::read_all(&mut BufReader::new(pem.as_bytes()))? {
+        match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => {}
+        }
+    }
+    Err(TlsError::MissingMaterial(
+        "no private key found".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_parse_certificates_empty() {
+        let result = parse_certificates("");
+        assert!(matches!(result, Err(TlsError::MissingMaterial(_))));
+    }
+
+    #[test]
+    fn test_failure_parse_private_key_empty() {
+        let result = parse_private_key("not a key");
+        assert!(matches!(result, Err(TlsError::MissingMaterial(_))));
+    }
+
+    #[test]
+    fn test_failure_read_pem_file_path_not_configured() {
+        let result = read_pem_file(&None, "server certificate");
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "TLS material missing: server certificate path not configured"
+        );
+    }
+
+    #[test]
+    fn test_success_pem_material_deserialize() {
+        let material: PemMaterial =
+            serde_json::from_str(r#"{"certificate": "cert", "private_key": "key"}"#).unwrap();
+        assert_eq!(material.certificate, "cert");
+        assert!(material.ca_bundle.is_none());
+    }
+}
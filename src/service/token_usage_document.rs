@@ -0,0 +1,47 @@
+/*
+ * Created Date:  Jun 28, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the schema for the token usage document.
+//! A token usage document is stored per successful retrieval, with the tokens reported by the knowledge engine,
+//! and is rolled up per app, user and model by the token usage handler.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenUsageDocument {
+    pub app_name: String,
+    pub user_id: String,
+    pub model_used: String,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+    #[serde(with = "crate::service::timestamp::bson_datetime")]
+    pub timestamp: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_token_usage_document() {
+        let token_usage_document = TokenUsageDocument {
+            app_name: "app_name".to_string(),
+            user_id: "user_id".to_string(),
+            model_used: "model".to_string(),
+            prompt_tokens: 10,
+            completion_tokens: 2,
+            total_tokens: 12,
+            timestamp: Utc::now(),
+        };
+
+        let json_string = serde_json::to_string(&token_usage_document).unwrap();
+        let deserialized_token_usage_document: TokenUsageDocument =
+            serde_json::from_str(&json_string).unwrap();
+        assert_eq!(deserialized_token_usage_document.user_id, "user_id");
+        assert_eq!(deserialized_token_usage_document.total_tokens, 12);
+    }
+}
@@ -5,6 +5,7 @@
  */
 //! This module contains the schema for the UI Summary document.
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -12,7 +13,8 @@ pub struct UiSummaryDocument {
     pub app_name: String,
     pub call_type: String,
     pub count: u64,
-    pub timestamp: String,
+    #[serde(with = "crate::service::timestamp::bson_datetime")]
+    pub timestamp: DateTime<Utc>,
 }
 
 #[cfg(test)]
@@ -21,16 +23,17 @@ mod tests {
 
     #[test]
     fn test_success_ui_summary_document() {
+        let timestamp = Utc::now();
         let ui_summary_document = UiSummaryDocument {
             app_name: "app_name".to_string(),
             call_type: "call_type".to_string(),
             count: 1,
-            timestamp: "timestamp".to_string(),
+            timestamp,
         };
         assert_eq!(ui_summary_document.app_name, "app_name".to_string());
         assert_eq!(ui_summary_document.call_type, "call_type".to_string());
         assert_eq!(ui_summary_document.count, 1);
-        assert_eq!(ui_summary_document.timestamp, "timestamp".to_string());
+        assert_eq!(ui_summary_document.timestamp, timestamp);
 
         let json_string = serde_json::to_string(&ui_summary_document).unwrap();
         let deserialized_ui_summary_document: UiSummaryDocument =
@@ -0,0 +1,117 @@
+/*
+ * Created Date:  Jul 01, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the allowlist and denylist of the end users of an app.
+//! An entry is either a `user_id`, matched exactly, or an email domain prefixed with `@` (e.g. `@tresle.ai`),
+//! matched case-insensitively against the domain of a `user_id` holding an email address.
+//! The denylist always wins. An empty allowlist lets every user not denied through.
+//!
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum UserAccessError {
+    #[error("User '{user_id}' is denied by the entry '{entry}'.")]
+    Denied { user_id: String, entry: String },
+    #[error("User '{0}' is not in the allowlist of the app.")]
+    NotAllowed(String),
+    #[error("Invalid access list entry '{0}'. Entries must be a user_id or an email domain like '@example.com'.")]
+    InvalidEntry(String),
+}
+
+/// Allowlist and denylist of the end users of an app, stored on the app document.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema, PartialEq)]
+pub struct UserAccessList {
+    #[serde(default)]
+    pub allowed: Vec<String>,
+    #[serde(default)]
+    pub denied: Vec<String>,
+}
+
+impl UserAccessList {
+    /// Validates the entries of both lists.
+    pub fn validate(&self) -> Result<(), UserAccessError> {
+        for entry in self.allowed.iter().chain(self.denied.iter()) {
+            let invalid = match entry.strip_prefix('@') {
+                Some(domain) => domain.is_empty() || domain.contains('@'),
+                None => entry.trim().is_empty(),
+            };
+            if invalid {
+                return Err(UserAccessError::InvalidEntry(entry.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a user against the lists. The denylist is checked first.
+    pub fn check(&self, user_id: &str) -> Result<(), UserAccessError> {
+        if let Some(entry) = self.denied.iter().find(|entry| matches(entry, user_id)) {
+            return Err(UserAccessError::Denied {
+                user_id: user_id.to_string(),
+                entry: entry.clone(),
+            });
+        }
+        if !self.allowed.is_empty() && !self.allowed.iter().any(|entry| matches(entry, user_id)) {
+            return Err(UserAccessError::NotAllowed(user_id.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if a user_id matches an entry of a list.
+fn matches(entry: &str, user_id: &str) -> bool {
+    match entry.strip_prefix('@') {
+        Some(domain) => user_id
+            .rsplit_once('@')
+            .is_some_and(|(_, user_domain)| user_domain.eq_ignore_ascii_case(domain)),
+        None => entry == user_id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_user_access_list_check() {
+        let access_list = UserAccessList {
+            allowed: vec!["@tresle.ai".to_string(), "service-account".to_string()],
+            denied: vec!["intern@tresle.ai".to_string()],
+        };
+        assert!(access_list.check("jane@Tresle.AI").is_ok());
+        assert!(access_list.check("service-account").is_ok());
+        assert_eq!(
+            access_list.check("intern@tresle.ai"),
+            Err(UserAccessError::Denied {
+                user_id: "intern@tresle.ai".to_string(),
+                entry: "intern@tresle.ai".to_string(),
+            })
+        );
+        assert_eq!(
+            access_list.check("jane@example.com"),
+            Err(UserAccessError::NotAllowed("jane@example.com".to_string()))
+        );
+        // An empty allowlist lets every user not denied through
+        assert!(UserAccessList::default().check("jane@example.com").is_ok());
+    }
+
+    #[test]
+    fn test_failure_user_access_list_validate() {
+        let access_list = UserAccessList {
+            allowed: vec!["@".to_string()],
+            denied: vec![],
+        };
+        assert_eq!(
+            access_list.validate(),
+            Err(UserAccessError::InvalidEntry("@".to_string()))
+        );
+        let access_list = UserAccessList {
+            allowed: vec![],
+            denied: vec!["@tresle.ai".to_string(), " ".to_string()],
+        };
+        assert!(access_list.validate().is_err());
+    }
+}
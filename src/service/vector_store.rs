@@ -0,0 +1,269 @@
+/*
+ * Created Date:  Jun 24, 2024
+ * -----
+ * Copyright (c) 2024 Tresle.ai or its affiliates. All Rights Reserved.
+ */
+//! This module contains the vector store configuration of the apps.
+//! The vector backend (OpenSearch, Qdrant or pgvector) is selected per deployment through the settings.
+//! Each backend has its own collection naming scheme and limits, which are validated at onboarding.
+//! The resolved configuration is stored in the app document and included in the Kafka events.
+//!
+
+use crate::configuration::settings::TresleFacadeServiceSettings;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Maximum embedding dimension indexable by OpenSearch k-NN.
+const OPENSEARCH_MAX_DIMENSION: i32 = 16000;
+/// Maximum vector size of a Qdrant collection.
+const QDRANT_MAX_DIMENSION: i32 = 65536;
+/// Maximum dimension of a pgvector column with an HNSW/IVFFlat index.
+const PGVECTOR_MAX_DIMENSION: i32 = 2000;
+/// Maximum length of a PostgreSQL identifier.
+const PGVECTOR_MAX_NAME_LENGTH: usize = 63;
+/// Maximum length of an OpenSearch index or Qdrant collection name.
+const MAX_NAME_LENGTH: usize = 255;
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum VectorStoreError {
+    #[error("'{name}' is not a valid {backend} collection name: {reason}")]
+    InvalidCollectionName {
+        name: String,
+        backend: VectorBackend,
+        reason: String,
+    },
+    #[error(
+        "The {model} embedding dimension {dimension} is not supported by {backend} (1 to {max})."
+    )]
+    UnsupportedDimension {
+        model: String,
+        dimension: i32,
+        backend: VectorBackend,
+        max: i32,
+    },
+}
+
+/// Vector database backend of the deployment.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VectorBackend {
+    #[default]
+    OpenSearch,
+    Qdrant,
+    PgVector,
+}
+
+impl fmt::Display for VectorBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VectorBackend::OpenSearch => write!(f, "OpenSearch"),
+            VectorBackend::Qdrant => write!(f, "Qdrant"),
+            VectorBackend::PgVector => write!(f, "pgvector"),
+        }
+    }
+}
+
+impl VectorBackend {
+    /// Returns the backend configured in the settings, OpenSearch if none is configured.
+    pub fn from_settings(app_settings: &TresleFacadeServiceSettings) -> Self {
+        app_settings
+            .vector_store
+            .as_ref()
+            .map(|vector_store| vector_store.backend)
+            .unwrap_or_default()
+    }
+
+    /// Name of the collection (index/collection/table) holding the vectors of an app.
+    pub fn collection_name(&self, app_name: &str, prefix: &str) -> String {
+        match self {
+            VectorBackend::OpenSearch | VectorBackend::Qdrant => format!("{}-{}", app_name, prefix),
+            VectorBackend::PgVector => format!("{}_{}", app_name, prefix)
+                .replace('-', "_")
+                .to_lowercase(),
+        }
+    }
+
+    fn max_dimension(&self) -> i32 {
+        match self {
+            VectorBackend::OpenSearch => OPENSEARCH_MAX_DIMENSION,
+            VectorBackend::Qdrant => QDRANT_MAX_DIMENSION,
+            VectorBackend::PgVector => PGVECTOR_MAX_DIMENSION,
+        }
+    }
+
+    /// Validates a collection name against the naming rules of the backend.
+    pub fn validate_collection_name(&self, name: &str) -> Result<(), VectorStoreError> {
+        let invalid = |reason: &str| {
+            Err(VectorStoreError::InvalidCollectionName {
+                name: name.to_string(),
+                backend: *self,
+                reason: reason.to_string(),
+            })
+        };
+        match self {
+            VectorBackend::OpenSearch => {
+                if name.len() > MAX_NAME_LENGTH {
+                    return invalid("longer than 255 bytes");
+                }
+                if name.chars().any(|c| c.is_uppercase()) {
+                    return invalid("must be lowercase");
+                }
+                if name.starts_with(['_', '-', '+']) {
+                    return invalid("must not start with '_', '-' or '+'");
+                }
+                if name.chars().any(|c| " \\/*?\"<>|,#:".contains(c)) {
+                    return invalid("must not contain spaces or \\ / * ? \" < > | , # :");
+                }
+            }
+            VectorBackend::Qdrant => {
+                if name.len() > MAX_NAME_LENGTH {
+                    return invalid("longer than 255 bytes");
+                }
+                if name.contains(['/', '\0']) {
+                    return invalid("must not contain '/'");
+                }
+            }
+            VectorBackend::PgVector => {
+                if name.len() > PGVECTOR_MAX_NAME_LENGTH {
+                    return invalid("longer than 63 bytes");
+                }
+                if name.starts_with(|c: char| c.is_ascii_digit()) {
+                    return invalid("must not start with a digit");
+                }
+                if !name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+                {
+                    return invalid("must only contain letters, digits, '-' or '_'");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates an embedding dimension against the limits of the backend.
+    pub fn validate_dimension(&self, model: &str, dimension: i32) -> Result<(), VectorStoreError> {
+        if dimension < 1 || dimension > self.max_dimension() {
+            return Err(VectorStoreError::UnsupportedDimension {
+                model: model.to_string(),
+                dimension,
+                backend: *self,
+                max: self.max_dimension(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Vector store configuration of an app, stored in the app document.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VectorStoreConfig {
+    pub backend: VectorBackend,
+    pub text_collection_name: String,
+    pub multimodal_collection_name: String,
+}
+
+impl VectorStoreConfig {
+    /// Resolves the vector store configuration of an app from the settings.
+    pub fn from_settings(app_settings: &TresleFacadeServiceSettings, app_name: &str) -> Self {
+        let backend = VectorBackend::from_settings(app_settings);
+        let vectordb_config = &app_settings
+            .app_generated_config
+            .knowledge_graph_config
+            .vectordb_config;
+        VectorStoreConfig {
+            backend,
+            text_collection_name: backend
+                .collection_name(app_name, &vectordb_config.text_collection_name_prefix),
+            multimodal_collection_name: backend
+                .collection_name(app_name, &vectordb_config.multimodal_collection_name_prefix),
+        }
+    }
+
+    /// Validates the collection names and the embedding dimensions of an app against the backend.
+    pub fn validate(
+        &self,
+        text_dimension: i32,
+        multimodal_dimension: i32,
+    ) -> Result<(), VectorStoreError> {
+        self.backend
+            .validate_collection_name(&self.text_collection_name)?;
+        self.backend
+            .validate_collection_name(&self.multimodal_collection_name)?;
+        self.backend.validate_dimension("text", text_dimension)?;
+        self.backend
+            .validate_dimension("multimodal", multimodal_dimension)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success_collection_name() {
+        assert_eq!(
+            VectorBackend::OpenSearch.collection_name("app-100", "text"),
+            "app-100-text"
+        );
+        assert_eq!(
+            VectorBackend::Qdrant.collection_name("app-100", "text"),
+            "app-100-text"
+        );
+        assert_eq!(
+            VectorBackend::PgVector.collection_name("App-100", "text"),
+            "app_100_text"
+        );
+    }
+
+    #[test]
+    fn test_success_validate_vector_store_config() {
+        let config = VectorStoreConfig {
+            backend: VectorBackend::PgVector,
+            text_collection_name: VectorBackend::PgVector.collection_name("app100", "text"),
+            multimodal_collection_name: VectorBackend::PgVector
+                .collection_name("app100", "multimodal"),
+        };
+        assert!(config.validate(1536, 1024).is_ok());
+
+        // pgvector indexes are limited to 2000 dimensions
+        assert!(matches!(
+            config.validate(3072, 1024),
+            Err(VectorStoreError::UnsupportedDimension { max: 2000, .. })
+        ));
+        let config = VectorStoreConfig {
+            backend: VectorBackend::OpenSearch,
+            ..config
+        };
+        assert!(config.validate(3072, 1024).is_ok());
+    }
+
+    #[test]
+    fn test_failure_validate_collection_name() {
+        assert!(VectorBackend::OpenSearch
+            .validate_collection_name("App100-text")
+            .is_err());
+        assert!(VectorBackend::OpenSearch
+            .validate_collection_name("_app100-text")
+            .is_err());
+        assert!(VectorBackend::Qdrant
+            .validate_collection_name("app/100-text")
+            .is_err());
+        assert!(VectorBackend::PgVector
+            .validate_collection_name("1app_text")
+            .is_err());
+        assert!(VectorBackend::PgVector
+            .validate_collection_name(&"a".repeat(64))
+            .is_err());
+    }
+
+    #[test]
+    fn test_success_vector_backend_deserialization() {
+        let backend: VectorBackend = serde_json::from_str("\"pgvector\"").unwrap();
+        assert_eq!(backend, VectorBackend::PgVector);
+        assert_eq!(
+            serde_json::to_string(&VectorBackend::OpenSearch).unwrap(),
+            "\"opensearch\""
+        );
+    }
+}